@@ -3,44 +3,62 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use phnxtypes::{
-    credentials::{keys::ClientSigningKey, ClientCredentialPayload},
+    credentials::{keys::ClientSigningKey, ClientCredentialPayload, CredentialFingerprint},
     crypto::{
         kdf::keys::RatchetSecret,
         opaque::{
             OpaqueLoginFinish, OpaqueLoginRequest, OpaqueRegistrationRecord,
             OpaqueRegistrationRequest,
         },
-        signatures::signable::Signable,
-        RatchetEncryptionKey,
+        signatures::signable::{Freshness, Signable},
+        DataExportEncryptionKey, RatchetEncryptionKey,
     },
-    endpoint_paths::ENDPOINT_AS,
-    errors::auth_service::AsProcessingError,
-    identifiers::{AsClientId, QualifiedUserName},
+    endpoint_paths::{ENDPOINT_AS, ENDPOINT_AS_ANNOUNCEMENTS},
+    errors::auth_service::{
+        AsProcessingError, HandleRejectionReason, InitUserRegistrationError, UserSettingsError,
+    },
+    identifiers::{AsClientId, ConnectionReservationId, DataExportId, QualifiedUserName},
     messages::{
+        announcements::AnnouncementsResponse,
         client_as::{
             AsCredentialsParams, AsPublishConnectionPackagesParamsTbs, AsRequestParams,
-            ClientConnectionPackageParamsTbs, ClientToAsMessage, ConnectionPackage,
+            ClientConnectionPackageParamsTbs, ClientToAsMessage, CompatibilityInfoParams,
+            ConfirmClientCredentialParamsTbs, ConnectionPackage, DataExportStatusParamsTbs,
             DeleteClientParamsTbs, DeleteUserParamsTbs, DequeueMessagesParamsTbs,
-            EncryptedConnectionEstablishmentPackage, EnqueueMessageParams,
+            DownloadDataExportParamsTbs, EncryptedConnectionEstablishmentPackage,
+            EnqueueMessageParams, EnqueueMessagesParams, EnqueueMessagesResponse,
             FinishClientAdditionParams, FinishClientAdditionParamsTbs,
-            FinishUserRegistrationParamsTbs, Init2FactorAuthParamsTbs, Init2FactorAuthResponse,
-            InitUserRegistrationParams, InitiateClientAdditionParams, IssueTokensParamsTbs,
-            IssueTokensResponse, UserClientsParams, UserConnectionPackagesParams,
+            FinishUserRegistrationParamsTbs, GetUserProfileParams, GetUserSettingsParamsTbs,
+            Init2FactorAuthParamsTbs, Init2FactorAuthResponse, InitUserRegistrationParams,
+            InitiateClientAdditionParams, IntermediateCredentialsParams, IssueTokensParamsTbs,
+            IssueTokensResponse, RefreshClientCredentialParamsTbs, RequestDataExportParamsTbs,
+            ReservedConnectionEstablishmentPackage, SenderReputationAttestation,
+            SenderReputationParamsTbs, ServerInfoParams, UploadUserProfileParamsTbs,
+            UploadUserSettingsParamsTbs, UserClientsParams, UserConnectionPackagesParams,
         },
         client_as_out::{
             AsClientConnectionPackageResponseIn, AsCredentialsResponseIn, AsProcessResponseIn,
             ConnectionPackageIn, InitClientAdditionResponseIn, InitUserRegistrationResponseIn,
-            UserClientsResponseIn, UserConnectionPackagesResponseIn,
+            RefreshClientCredentialResponseIn, UserClientsResponseIn,
+            UserConnectionPackagesResponseIn,
         },
         client_qs::DequeueMessagesResponse,
+        data_export::{DataExportStatus, EncryptedDataExport},
+        server_info::{CompatibilityInfoResponse, ServerInfoResponse},
+        user_profiles::{EncryptedUserProfile, UserProfileFetch},
+        user_settings::{EncryptedUserSettings, VersionedUserSettings},
         AsTokenType,
     },
 };
 use privacypass::batched_tokens_ristretto255::TokenRequest;
+use reqwest::{
+    header::{HeaderValue, ETAG, IF_NONE_MATCH},
+    Method, StatusCode,
+};
 use thiserror::Error;
 use tls_codec::{DeserializeBytes, Serialize};
 
-use crate::{ApiClient, Protocol};
+use crate::{transport::TransportRequest, ApiClient, Protocol};
 
 #[derive(Error, Debug)]
 pub enum AsRequestError {
@@ -52,10 +70,55 @@ pub enum AsRequestError {
     UnexpectedResponse,
     #[error("Network error: {0}")]
     NetworkError(String),
+    /// The server couldn't decode the request, e.g. because it predates the
+    /// request type.
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    /// The server doesn't support the requests of this client version
+    /// anymore. The client must be upgraded.
+    #[error("Client upgrade required")]
+    UpgradeRequired,
     #[error(transparent)]
     AsError(#[from] AsProcessingError),
 }
 
+/// Result of polling the announcements of the AS.
+#[derive(Debug)]
+pub enum AnnouncementsPoll {
+    /// The announcements didn't change since the given entity tag was
+    /// returned.
+    NotModified,
+    Modified {
+        response: AnnouncementsResponse,
+        /// Entity tag to send with the next poll
+        etag: Option<String>,
+    },
+}
+
+impl AsRequestError {
+    /// Returns the reason why the AS refused to register the user name if
+    /// this error is such a refusal.
+    pub fn handle_rejection_reason(&self) -> Option<&HandleRejectionReason> {
+        match self {
+            AsRequestError::AsError(AsProcessingError::InitUserRegistrationError(
+                InitUserRegistrationError::HandleRejected(reason),
+            )) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the AS rejected uploaded user settings because
+    /// another client uploaded newer ones.
+    pub fn is_user_settings_conflict(&self) -> bool {
+        matches!(
+            self,
+            AsRequestError::AsError(AsProcessingError::UserSettingsError(
+                UserSettingsError::VersionConflict
+            ))
+        )
+    }
+}
+
 impl ApiClient {
     async fn prepare_and_send_as_message(
         &self,
@@ -65,38 +128,29 @@ impl ApiClient {
             .tls_serialize_detached()
             .map_err(|_| AsRequestError::LibraryError)?;
         let url = self.build_url(Protocol::Http, ENDPOINT_AS);
-        let res = self
-            .client
-            .post(url.clone())
-            .body(message_bytes)
-            .send()
-            .await;
-        match res {
+        let request = TransportRequest::new(Method::POST, &url).with_body(message_bytes);
+        match self.send(request).await {
             Ok(res) => {
-                match res.status().as_u16() {
+                match res.status.as_u16() {
                     // Success!
                     x if (200..=299).contains(&x) => {
-                        let ds_proc_res_bytes =
-                            res.bytes().await.map_err(|_| AsRequestError::BadResponse)?;
                         let ds_proc_res =
-                            AsProcessResponseIn::tls_deserialize_exact_bytes(&ds_proc_res_bytes)
+                            AsProcessResponseIn::tls_deserialize_exact_bytes(&res.body)
                                 .map_err(|_| AsRequestError::BadResponse)?;
                         Ok(ds_proc_res)
                     }
                     // DS Specific Error
                     418 => {
-                        let ds_proc_err_bytes =
-                            res.bytes().await.map_err(|_| AsRequestError::BadResponse)?;
-                        let ds_proc_err =
-                            AsProcessingError::tls_deserialize_exact_bytes(&ds_proc_err_bytes)
-                                .map_err(|_| AsRequestError::BadResponse)?;
+                        let ds_proc_err = AsProcessingError::tls_deserialize_exact_bytes(&res.body)
+                            .map_err(|_| AsRequestError::BadResponse)?;
                         Err(AsRequestError::AsError(ds_proc_err))
                     }
+                    // The server doesn't serve this client version anymore
+                    426 | 501 => Err(AsRequestError::UpgradeRequired),
+                    400 => Err(AsRequestError::BadRequest(res.text())),
                     // All other errors
                     other_status => {
-                        let error_text =
-                            res.text().await.map_err(|_| AsRequestError::BadResponse)?
-                                + &format!(" (status code {})", other_status);
+                        let error_text = res.text() + &format!(" (status code {})", other_status);
                         Err(AsRequestError::NetworkError(error_text))
                     }
                 }
@@ -145,6 +199,7 @@ impl ApiClient {
         let tbs = Init2FactorAuthParamsTbs {
             client_id,
             opaque_ke1,
+            freshness: Freshness::new(),
         };
         let payload = tbs
             .sign(signing_key)
@@ -177,6 +232,7 @@ impl ApiClient {
             initial_ratchet_secret,
             connection_packages,
             opaque_registration_record,
+            freshness: Freshness::new(),
         };
         let payload = tbs
             .sign(signing_key)
@@ -206,6 +262,7 @@ impl ApiClient {
             client_id,
             user_name,
             opaque_finish,
+            freshness: Freshness::new(),
         };
         let payload = tbs
             .sign(signing_key)
@@ -288,7 +345,10 @@ impl ApiClient {
     ) -> Result<(), AsRequestError> {
         // TODO: This means that clients can only ever delete themselves. Is
         // that what we want here?
-        let tbs = DeleteClientParamsTbs(client_id);
+        let tbs = DeleteClientParamsTbs {
+            client_id,
+            freshness: Freshness::new(),
+        };
         let payload = tbs
             .sign(signing_key)
             .map_err(|_| AsRequestError::LibraryError)?;
@@ -316,6 +376,7 @@ impl ApiClient {
             sender: signing_key.credential().identity(),
             sequence_number_start,
             max_message_number,
+            freshness: Freshness::new(),
         };
         let payload = tbs
             .sign(signing_key)
@@ -325,12 +386,10 @@ impl ApiClient {
         self.prepare_and_send_as_message(message)
             .await
             // Check if the response is what we expected it to be.
-            .and_then(|response| {
-                if let AsProcessResponseIn::DequeueMessages(response) = response {
-                    Ok(response)
-                } else {
-                    Err(AsRequestError::UnexpectedResponse)
-                }
+            .and_then(|response| match response {
+                AsProcessResponseIn::DequeueMessages(response) => Ok(response.into()),
+                AsProcessResponseIn::DequeueMessagesV2(response) => Ok(response),
+                _ => Err(AsRequestError::UnexpectedResponse),
             })
     }
 
@@ -343,6 +402,7 @@ impl ApiClient {
         let tbs = AsPublishConnectionPackagesParamsTbs {
             client_id,
             connection_packages,
+            freshness: Freshness::new(),
         };
         let payload = tbs
             .sign(signing_key)
@@ -369,7 +429,10 @@ impl ApiClient {
         client_id: AsClientId,
         signing_key: &ClientSigningKey,
     ) -> Result<AsClientConnectionPackageResponseIn, AsRequestError> {
-        let tbs = ClientConnectionPackageParamsTbs(client_id);
+        let tbs = ClientConnectionPackageParamsTbs {
+            client_id,
+            freshness: Freshness::new(),
+        };
         let payload = tbs
             .sign(signing_key)
             .map_err(|_| AsRequestError::LibraryError)?;
@@ -397,6 +460,7 @@ impl ApiClient {
             client_id: signing_key.credential().identity(),
             token_type,
             token_request,
+            freshness: Freshness::new(),
         };
         let payload = tbs
             .sign(signing_key)
@@ -415,6 +479,264 @@ impl ApiClient {
             })
     }
 
+    /// Request a new client credential for the given CSR payload. The
+    /// request is signed with the client's current signing key.
+    pub async fn as_refresh_client_credential(
+        &self,
+        client_credential_payload: ClientCredentialPayload,
+        signing_key: &ClientSigningKey,
+    ) -> Result<RefreshClientCredentialResponseIn, AsRequestError> {
+        let tbs = RefreshClientCredentialParamsTbs {
+            client_id: signing_key.credential().identity(),
+            client_credential_payload,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::RefreshClientCredential(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::RefreshClientCredential(response) = response {
+                    Ok(response)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Confirm the credential issued by the last call to
+    /// [`Self::as_refresh_client_credential`]. The request is signed with the
+    /// new signing key. Until it succeeds, the AS only accepts the old one.
+    pub async fn as_confirm_client_credential(
+        &self,
+        signing_key: &ClientSigningKey,
+    ) -> Result<(), AsRequestError> {
+        let tbs = ConfirmClientCredentialParamsTbs {
+            client_id: signing_key.credential().identity(),
+            credential_fingerprint: signing_key
+                .credential()
+                .fingerprint()
+                .map_err(|_| AsRequestError::LibraryError)?,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::ConfirmClientCredential(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if matches!(response, AsProcessResponseIn::Ok) {
+                    Ok(())
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Request an export of the personal data the AS holds about the user.
+    /// The export is encrypted to the given key.
+    pub async fn as_request_data_export(
+        &self,
+        encryption_key: DataExportEncryptionKey,
+        signing_key: &ClientSigningKey,
+    ) -> Result<DataExportId, AsRequestError> {
+        let tbs = RequestDataExportParamsTbs {
+            client_id: signing_key.credential().identity(),
+            encryption_key,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::RequestDataExport(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::RequestDataExport(response) = response {
+                    Ok(response.export_id)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    pub async fn as_data_export_status(
+        &self,
+        export_id: DataExportId,
+        signing_key: &ClientSigningKey,
+    ) -> Result<DataExportStatus, AsRequestError> {
+        let tbs = DataExportStatusParamsTbs {
+            client_id: signing_key.credential().identity(),
+            export_id,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::DataExportStatus(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::DataExportStatus(response) = response {
+                    Ok(response.status)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    pub async fn as_download_data_export(
+        &self,
+        export_id: DataExportId,
+        signing_key: &ClientSigningKey,
+    ) -> Result<EncryptedDataExport, AsRequestError> {
+        let tbs = DownloadDataExportParamsTbs {
+            client_id: signing_key.credential().identity(),
+            export_id,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::DownloadDataExport(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::DownloadDataExport(response) = response {
+                    Ok(response.export)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// The synced settings of the user, or `None` if none were uploaded yet.
+    pub async fn as_get_user_settings(
+        &self,
+        signing_key: &ClientSigningKey,
+    ) -> Result<Option<VersionedUserSettings>, AsRequestError> {
+        let tbs = GetUserSettingsParamsTbs {
+            client_id: signing_key.credential().identity(),
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::GetUserSettings(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::GetUserSettings(response) = response {
+                    Ok(response.settings)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Replace the synced settings of the user. Returns the new version of
+    /// the settings.
+    ///
+    /// Fails with a conflict (see [`AsRequestError::is_user_settings_conflict`])
+    /// if the stored settings are not of the base version.
+    pub async fn as_upload_user_settings(
+        &self,
+        base_version: u64,
+        settings: EncryptedUserSettings,
+        signing_key: &ClientSigningKey,
+    ) -> Result<u64, AsRequestError> {
+        let tbs = UploadUserSettingsParamsTbs {
+            client_id: signing_key.credential().identity(),
+            base_version,
+            settings,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::UploadUserSettings(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::UploadUserSettings(response) = response {
+                    Ok(response.version)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Replace the profile of the user. Returns the new version of the
+    /// profile.
+    pub async fn as_upload_user_profile(
+        &self,
+        profile: EncryptedUserProfile,
+        signing_key: &ClientSigningKey,
+    ) -> Result<u64, AsRequestError> {
+        let tbs = UploadUserProfileParamsTbs {
+            client_id: signing_key.credential().identity(),
+            profile,
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::UploadUserProfile(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::UploadUserProfile(response) = response {
+                    Ok(response.version)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Fetch the profile of the given user. If `cached_version` is the
+    /// version of the stored profile, the AS doesn't send the profile again.
+    pub async fn as_user_profile(
+        &self,
+        user_name: QualifiedUserName,
+        cached_version: Option<u64>,
+    ) -> Result<UserProfileFetch, AsRequestError> {
+        let payload = GetUserProfileParams {
+            user_name,
+            cached_version,
+        };
+        let params = AsRequestParams::GetUserProfile(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::GetUserProfile(response) = response {
+                    Ok(response.profile)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
     pub async fn as_user_clients(
         &self,
         user_name: QualifiedUserName,
@@ -452,14 +774,21 @@ impl ApiClient {
             })
     }
 
+    /// Enqueue a connection establishment package for a client whose
+    /// connection package was served under the given reservation.
+    ///
+    /// Can be retried until the reservation expires. Once it expired, the
+    /// connection packages have to be fetched again.
     pub async fn as_enqueue_message(
         &self,
         client_id: AsClientId,
         connection_establishment_ctxt: EncryptedConnectionEstablishmentPackage,
+        reservation_id: ConnectionReservationId,
     ) -> Result<(), AsRequestError> {
         let payload = EnqueueMessageParams {
             client_id,
             connection_establishment_ctxt,
+            reservation_id,
         };
         let params = AsRequestParams::EnqueueMessage(payload);
         let message = ClientToAsMessage::new(params);
@@ -475,6 +804,32 @@ impl ApiClient {
             })
     }
 
+    /// Enqueue the connection establishment packages for several clients
+    /// whose connection packages were served under the given reservation.
+    ///
+    /// Like [`Self::as_enqueue_message`], the request can be retried until
+    /// the reservation expires. Clients whose reservation expired are
+    /// reported in the response instead of failing the request.
+    pub async fn as_enqueue_messages(
+        &self,
+        reservation_id: ConnectionReservationId,
+        packages: Vec<ReservedConnectionEstablishmentPackage>,
+    ) -> Result<EnqueueMessagesResponse, AsRequestError> {
+        let payload = EnqueueMessagesParams {
+            reservation_id,
+            packages,
+        };
+        let params = AsRequestParams::EnqueueMessages(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| match response {
+                AsProcessResponseIn::EnqueueMessages(response) => Ok(response),
+                _ => Err(AsRequestError::UnexpectedResponse),
+            })
+    }
+
     pub async fn as_as_credentials(&self) -> Result<AsCredentialsResponseIn, AsRequestError> {
         let payload = AsCredentialsParams {};
         let params = AsRequestParams::AsCredentials(payload);
@@ -490,4 +845,125 @@ impl ApiClient {
                 }
             })
     }
+
+    /// Fetch the intermediate credentials with the given fingerprints and the
+    /// AS credentials that signed them with a single request.
+    pub async fn as_intermediate_credentials(
+        &self,
+        fingerprints: Vec<CredentialFingerprint>,
+    ) -> Result<AsCredentialsResponseIn, AsRequestError> {
+        let payload = IntermediateCredentialsParams { fingerprints };
+        let params = AsRequestParams::IntermediateCredentials(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::IntermediateCredentials(response) = response {
+                    Ok(response)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Fetch the information the server publishes to clients, e.g. whether
+    /// new users can register. Doesn't require an account.
+    pub async fn as_server_info(&self) -> Result<ServerInfoResponse, AsRequestError> {
+        let payload = ServerInfoParams {};
+        let params = AsRequestParams::ServerInfo(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::ServerInfo(response) = response {
+                    Ok(response)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Fetch the oldest client version and the deprecated features of the
+    /// server. Doesn't require an account.
+    ///
+    /// Servers that predate this request reject it as a bad request (see
+    /// [`ApiClient::compatibility_info`]).
+    pub async fn as_compatibility_info(&self) -> Result<CompatibilityInfoResponse, AsRequestError> {
+        let payload = CompatibilityInfoParams {};
+        let params = AsRequestParams::CompatibilityInfo(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::CompatibilityInfo(response) = response {
+                    Ok(response)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Fetch an attestation of the coarse reputation of the user, to be
+    /// attached to the user's connection requests.
+    pub async fn as_sender_reputation(
+        &self,
+        signing_key: &ClientSigningKey,
+    ) -> Result<SenderReputationAttestation, AsRequestError> {
+        let tbs = SenderReputationParamsTbs {
+            client_id: signing_key.credential().identity(),
+            freshness: Freshness::new(),
+        };
+        let payload = tbs
+            .sign(signing_key)
+            .map_err(|_| AsRequestError::LibraryError)?;
+        let params = AsRequestParams::SenderReputation(payload);
+        let message = ClientToAsMessage::new(params);
+        self.prepare_and_send_as_message(message)
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let AsProcessResponseIn::SenderReputation(response) = response {
+                    Ok(response.attestation)
+                } else {
+                    Err(AsRequestError::UnexpectedResponse)
+                }
+            })
+    }
+
+    /// Fetch the announcements of the operator. If `etag` is the entity tag
+    /// of the last poll and the announcements didn't change since, the server
+    /// doesn't send them again.
+    pub async fn as_announcements(
+        &self,
+        etag: Option<&str>,
+    ) -> Result<AnnouncementsPoll, AsRequestError> {
+        let url = self.build_url(Protocol::Http, ENDPOINT_AS_ANNOUNCEMENTS);
+        let mut request = TransportRequest::new(Method::GET, url);
+        if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+            request.headers.insert(IF_NONE_MATCH, etag);
+        }
+        let res = self
+            .send(request)
+            .await
+            .map_err(|e| AsRequestError::NetworkError(e.to_string()))?;
+        match res.status {
+            StatusCode::NOT_MODIFIED => Ok(AnnouncementsPoll::NotModified),
+            status if status.is_success() => {
+                let etag = res
+                    .headers
+                    .get(ETAG)
+                    .and_then(|value| value.to_str().ok())
+                    .map(ToOwned::to_owned);
+                let response = AnnouncementsResponse::tls_deserialize_exact_bytes(&res.body)
+                    .map_err(|_| AsRequestError::BadResponse)?;
+                Ok(AnnouncementsPoll::Modified { response, etag })
+            }
+            status => Err(AsRequestError::NetworkError(format!(
+                "Unexpected status code {status}"
+            ))),
+        }
+    }
 }
@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Checks whether this client can still use a server.
+//!
+//! Servers advertise the oldest client version they serve and the features
+//! they are going to remove. Clients check both up front, such that they can
+//! ask the user to upgrade instead of failing on requests the server doesn't
+//! understand anymore. Requests of clients that send an older version (see
+//! [`ApiClient::with_client_version`]) are answered with `426 Upgrade
+//! Required`.
+
+use phnxtypes::messages::server_info::{
+    ClientVersion, CompatibilityInfoResponse, ServerFeature, ServerInfoResponse,
+};
+
+use crate::{as_api::AsRequestError, ApiClient};
+
+/// Whether a client can use a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerCompatibility {
+    Compatible,
+    /// The client works for now, but relies on the given features which the
+    /// server is going to remove.
+    Deprecated {
+        features: Vec<ServerFeature>,
+    },
+    /// The client can't use the server until it is upgraded.
+    UpgradeRequired(UpgradeRequired),
+}
+
+/// Reason why a client must be upgraded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpgradeRequired {
+    /// The server doesn't support any protocol version of the client.
+    UnsupportedProtocol,
+    /// The client is older than the oldest version the server serves.
+    ClientTooOld { min_version: ClientVersion },
+    /// The server rejected a request of the client as unsupported.
+    RequestRejected,
+}
+
+impl ServerCompatibility {
+    /// Evaluate the information published by the server for a client of the
+    /// given version which relies on the features with the given names.
+    pub fn evaluate(
+        server_info: &ServerInfoResponse,
+        compatibility_info: &CompatibilityInfoResponse,
+        client_version: &ClientVersion,
+        used_features: &[&str],
+    ) -> Self {
+        if server_info.negotiate_version().is_none() {
+            return Self::UpgradeRequired(UpgradeRequired::UnsupportedProtocol);
+        }
+        if let Some(min_version) = compatibility_info.min_client_version {
+            if *client_version < min_version {
+                return Self::UpgradeRequired(UpgradeRequired::ClientTooOld { min_version });
+            }
+        }
+        let features: Vec<ServerFeature> = compatibility_info
+            .deprecated_features
+            .iter()
+            .filter(|feature| used_features.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+        if features.is_empty() {
+            Self::Compatible
+        } else {
+            Self::Deprecated { features }
+        }
+    }
+
+    pub fn is_upgrade_required(&self) -> bool {
+        matches!(self, Self::UpgradeRequired(_))
+    }
+}
+
+impl ApiClient {
+    /// Fetch the oldest client version and the deprecated features of the
+    /// server. Servers that predate the request don't restrict clients.
+    pub async fn compatibility_info(&self) -> Result<CompatibilityInfoResponse, AsRequestError> {
+        match self.as_compatibility_info().await {
+            Err(AsRequestError::BadRequest(_)) => Ok(CompatibilityInfoResponse::default()),
+            result => result,
+        }
+    }
+
+    /// Check whether a client of the given version, which relies on the
+    /// features with the given names, can use the server.
+    pub async fn check_compatibility(
+        &self,
+        client_version: &ClientVersion,
+        used_features: &[&str],
+    ) -> Result<ServerCompatibility, AsRequestError> {
+        let info = async {
+            let server_info = self.as_server_info().await?;
+            let compatibility_info = self.compatibility_info().await?;
+            Ok((server_info, compatibility_info))
+        };
+        match info.await {
+            Ok((server_info, compatibility_info)) => Ok(ServerCompatibility::evaluate(
+                &server_info,
+                &compatibility_info,
+                client_version,
+                used_features,
+            )),
+            Err(AsRequestError::UpgradeRequired) => Ok(ServerCompatibility::UpgradeRequired(
+                UpgradeRequired::RequestRejected,
+            )),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::messages::{server_info::RegistrationPolicy, MlsInfraVersion};
+
+    use super::*;
+
+    fn server_info() -> ServerInfoResponse {
+        ServerInfoResponse {
+            supported_versions: MlsInfraVersion::SUPPORTED.to_vec(),
+            registration_policy: RegistrationPolicy::Open,
+            features: vec![ServerFeature::new(ServerFeature::APPLE_PUSH)],
+            motd: None,
+            metrics_endpoint: None,
+            send_rate_limit: None,
+        }
+    }
+
+    #[test]
+    fn old_clients_must_upgrade() {
+        let mut info = server_info();
+        let compatibility_info = CompatibilityInfoResponse {
+            min_client_version: Some(ClientVersion::new(0, 2, 0)),
+            deprecated_features: Vec::new(),
+        };
+
+        assert_eq!(
+            ServerCompatibility::evaluate(
+                &info,
+                &compatibility_info,
+                &ClientVersion::new(0, 1, 9),
+                &[]
+            ),
+            ServerCompatibility::UpgradeRequired(UpgradeRequired::ClientTooOld {
+                min_version: ClientVersion::new(0, 2, 0)
+            })
+        );
+        assert_eq!(
+            ServerCompatibility::evaluate(
+                &info,
+                &compatibility_info,
+                &ClientVersion::new(0, 2, 0),
+                &[]
+            ),
+            ServerCompatibility::Compatible
+        );
+
+        info.supported_versions.clear();
+        assert_eq!(
+            ServerCompatibility::evaluate(
+                &info,
+                &compatibility_info,
+                &ClientVersion::new(0, 2, 0),
+                &[]
+            ),
+            ServerCompatibility::UpgradeRequired(UpgradeRequired::UnsupportedProtocol)
+        );
+    }
+
+    #[test]
+    fn only_used_deprecated_features_are_reported() {
+        let info = server_info();
+        let compatibility_info = CompatibilityInfoResponse {
+            min_client_version: None,
+            deprecated_features: vec![
+                ServerFeature::new(ServerFeature::APPLE_PUSH),
+                ServerFeature::new(ServerFeature::GOOGLE_PUSH),
+            ],
+        };
+        let version = ClientVersion::new(0, 1, 0);
+
+        assert_eq!(
+            ServerCompatibility::evaluate(
+                &info,
+                &compatibility_info,
+                &version,
+                &[ServerFeature::APPLE_PUSH]
+            ),
+            ServerCompatibility::Deprecated {
+                features: vec![ServerFeature::new(ServerFeature::APPLE_PUSH)]
+            }
+        );
+        assert_eq!(
+            ServerCompatibility::evaluate(
+                &info,
+                &compatibility_info,
+                &version,
+                &[ServerFeature::ATTACHMENT_SCANNING]
+            ),
+            ServerCompatibility::Compatible
+        );
+    }
+}
@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Streaming dequeue of the AS and QS queues.
+//!
+//! The AS and the QS return at most [`MAX_DEQUEUE_BATCH_SIZE`] messages per
+//! request together with a continuation. The streams in this module request
+//! the next batch only once the previous one was consumed, such that a
+//! client catching up after a long offline period holds at most one batch in
+//! memory and the speed of processing throttles the downloads.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::{stream, Stream};
+use phnxtypes::{
+    credentials::keys::ClientSigningKey,
+    crypto::signatures::keys::QsClientSigningKey,
+    identifiers::QsClientId,
+    messages::{
+        client_qs::{DequeueMessagesResponse, MAX_DEQUEUE_BATCH_SIZE},
+        QueueMessage,
+    },
+};
+
+use crate::{as_api::AsRequestError, qs_api::QsRequestError, ApiClient};
+
+/// Counters of the dequeued batches since the creation of the [`ApiClient`].
+#[derive(Debug, Default)]
+pub(crate) struct DequeueMetrics {
+    batches: AtomicU64,
+    messages: AtomicU64,
+    largest_batch: AtomicU64,
+    continued_batches: AtomicU64,
+}
+
+/// Snapshot of the [`DequeueMetrics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DequeueMetricsSnapshot {
+    pub batches: u64,
+    pub messages: u64,
+    /// Number of messages in the largest batch
+    pub largest_batch: u64,
+    /// Number of batches after which messages remained in the queue
+    pub continued_batches: u64,
+}
+
+impl DequeueMetrics {
+    fn record_batch(&self, response: &DequeueMessagesResponse) {
+        let batch_size = response.messages.len() as u64;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.messages.fetch_add(batch_size, Ordering::Relaxed);
+        self.largest_batch.fetch_max(batch_size, Ordering::Relaxed);
+        if response.continuation.is_some() {
+            self.continued_batches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> DequeueMetricsSnapshot {
+        DequeueMetricsSnapshot {
+            batches: self.batches.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            largest_batch: self.largest_batch.load(Ordering::Relaxed),
+            continued_batches: self.continued_batches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Stream of the messages of a queue, fetched batch by batch.
+pub struct DequeueStream<S> {
+    inner: Pin<Box<S>>,
+    replenish_key_packages: Arc<AtomicBool>,
+}
+
+impl<S> DequeueStream<S> {
+    /// Whether the QS asked the client to publish new key packages. Only
+    /// meaningful once the stream is exhausted.
+    pub fn replenish_key_packages(&self) -> bool {
+        self.replenish_key_packages.load(Ordering::Relaxed)
+    }
+}
+
+impl<S: Stream> Stream for DequeueStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+struct DequeueState<F> {
+    fetch: F,
+    next_sequence_number: Option<u64>,
+    batch: VecDeque<QueueMessage>,
+    metrics: Arc<DequeueMetrics>,
+    replenish_key_packages: Arc<AtomicBool>,
+}
+
+fn dequeue_stream<F, Fut, E>(
+    sequence_number_start: u64,
+    metrics: Arc<DequeueMetrics>,
+    fetch: F,
+) -> DequeueStream<impl Stream<Item = Result<QueueMessage, E>>>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<DequeueMessagesResponse, E>>,
+{
+    let replenish_key_packages = Arc::new(AtomicBool::new(false));
+    let state = DequeueState {
+        fetch,
+        next_sequence_number: Some(sequence_number_start),
+        batch: VecDeque::new(),
+        metrics,
+        replenish_key_packages: replenish_key_packages.clone(),
+    };
+    let inner = stream::try_unfold(state, |mut state| async move {
+        loop {
+            if let Some(message) = state.batch.pop_front() {
+                return Ok(Some((message, state)));
+            }
+            let Some(sequence_number) = state.next_sequence_number else {
+                return Ok(None);
+            };
+            let response = (state.fetch)(sequence_number).await?;
+            state.metrics.record_batch(&response);
+            state
+                .replenish_key_packages
+                .fetch_or(response.replenish_key_packages, Ordering::Relaxed);
+            // The continuation is only set if the batch isn't empty, so this
+            // terminates.
+            state.next_sequence_number = response.continuation;
+            state.batch = response.messages.into();
+        }
+    });
+    DequeueStream {
+        inner: Box::pin(inner),
+        replenish_key_packages,
+    }
+}
+
+impl ApiClient {
+    /// Stream the messages of the QS queue of the given client, starting at
+    /// the given sequence number.
+    pub fn qs_dequeue_stream<'a>(
+        &'a self,
+        sender: &'a QsClientId,
+        sequence_number_start: u64,
+        signing_key: &'a QsClientSigningKey,
+    ) -> DequeueStream<impl Stream<Item = Result<QueueMessage, QsRequestError>> + 'a> {
+        dequeue_stream(
+            sequence_number_start,
+            self.dequeue_metrics.clone(),
+            move |sequence_number| {
+                self.qs_dequeue_messages(
+                    sender,
+                    sequence_number,
+                    MAX_DEQUEUE_BATCH_SIZE,
+                    signing_key,
+                )
+            },
+        )
+    }
+
+    /// Stream the messages of the AS queue of the client with the given
+    /// signing key, starting at the given sequence number.
+    pub fn as_dequeue_stream<'a>(
+        &'a self,
+        sequence_number_start: u64,
+        signing_key: &'a ClientSigningKey,
+    ) -> DequeueStream<impl Stream<Item = Result<QueueMessage, AsRequestError>> + 'a> {
+        dequeue_stream(
+            sequence_number_start,
+            self.dequeue_metrics.clone(),
+            move |sequence_number| {
+                self.as_dequeue_messages(sequence_number, MAX_DEQUEUE_BATCH_SIZE, signing_key)
+            },
+        )
+    }
+
+    /// Batch sizes of the dequeues of this client so far
+    pub fn dequeue_metrics(&self) -> DequeueMetricsSnapshot {
+        self.dequeue_metrics.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{StreamExt, TryStreamExt};
+    use phnxtypes::messages::QueuePriority;
+
+    use super::*;
+
+    fn message(sequence_number: u64) -> QueueMessage {
+        QueueMessage {
+            sequence_number,
+            ciphertext: Default::default(),
+            priority: QueuePriority::default(),
+        }
+    }
+
+    /// Fetches batches of at most `batch_size` from a queue with `len`
+    /// messages.
+    fn fake_queue(
+        len: u64,
+        batch_size: u64,
+    ) -> impl FnMut(u64) -> std::future::Ready<Result<DequeueMessagesResponse, ()>> {
+        move |start| {
+            let end = (start + batch_size).min(len);
+            let messages = (start..end).map(message).collect();
+            std::future::ready(Ok(DequeueMessagesResponse::new(
+                messages,
+                len - end,
+                end == len,
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_are_fetched_lazily() {
+        let metrics = Arc::new(DequeueMetrics::default());
+        let mut stream = dequeue_stream(0, metrics.clone(), fake_queue(25, 10));
+
+        let first: Vec<_> = stream.by_ref().take(10).try_collect().await.unwrap();
+        assert_eq!(first.len(), 10);
+        assert_eq!(metrics.snapshot().batches, 1);
+
+        let rest: Vec<_> = stream.by_ref().try_collect().await.unwrap();
+        let sequence_numbers: Vec<_> = rest.iter().map(|m| m.sequence_number).collect();
+        assert_eq!(sequence_numbers, (10..25).collect::<Vec<_>>());
+        assert!(stream.replenish_key_packages());
+
+        assert_eq!(
+            metrics.snapshot(),
+            DequeueMetricsSnapshot {
+                batches: 3,
+                messages: 25,
+                largest_batch: 10,
+                continued_batches: 2,
+            }
+        );
+    }
+}
@@ -22,27 +22,37 @@ use phnxtypes::{
         },
     },
     endpoint_paths::ENDPOINT_DS_GROUPS,
-    identifiers::QsClientReference,
+    identifiers::{AttachmentId, QsClientReference},
     messages::{
+        attachments::{
+            AttachmentDownload, AttachmentUrl, DeleteAttachmentsParams, DownloadAttachmentParams,
+            UploadAttachmentParams,
+        },
         client_ds::{
-            ConnectionGroupInfoParams, ExternalCommitInfoParams, UpdateQsClientReferenceParams,
-            WelcomeInfoParams,
+            ConnectionGroupInfoParams, ExternalCommitInfoParams, GroupEpochInfo,
+            GroupEpochInfoParams, UpdateQsClientReferenceParams, WelcomeInfoParams,
         },
         client_ds_out::{
             AddClientsParamsOut, AddUsersParamsOut, ClientToDsMessageOut, ClientToDsMessageTbsOut,
             CreateGroupParamsOut, DeleteGroupParamsOut, DsMessageTypeOut, DsProcessResponseIn,
             DsRequestParamsOut, ExternalCommitInfoIn, JoinConnectionGroupParamsOut,
-            JoinGroupParamsOut, RemoveClientsParamsOut, RemoveUsersParamsOut,
-            ResyncClientParamsOut, SelfRemoveClientParamsOut, SendMessageParamsOut,
-            UpdateClientParamsOut,
+            JoinGroupParamsOut, JoinUpgradedGroupParamsOut, ProposeClientUpdateParamsOut,
+            RemoveClientsParamsOut, RemoveUsersParamsOut, ResyncClientParamsOut,
+            SelfRemoveClientParamsOut, SendMessageParamsOut, UpdateClientParamsOut,
+            UpdateGroupDataParamsOut, UpdateRoomPolicyParamsOut, UpgradeGroupParamsOut,
         },
+        join_request::{JoinRequestParams, RegisterJoinRequestTokenParams},
+        member_profiles::{MemberProfilesPage, MemberProfilesParams},
         welcome_attribution_info::EncryptedWelcomeAttributionInfo,
     },
     time::TimeStamp,
 };
 
+use reqwest::Method;
 use tls_codec::DeserializeBytes;
 
+use crate::transport::TransportRequest;
+
 #[derive(Error, Debug)]
 pub enum DsRequestError {
     #[error("Library Error")]
@@ -53,8 +63,26 @@ pub enum DsRequestError {
     UnexpectedResponse,
     #[error("Network error: {0}")]
     NetworkError(String),
+    /// The server doesn't support the requests of this client version
+    /// anymore. The client must be upgraded.
+    #[error("Client upgrade required")]
+    UpgradeRequired,
     #[error("DS Error: {0}")]
     DsError(String),
+    /// The DS rejected the request because the sender sent too many messages.
+    /// Contains the time after which the DS accepts messages again, if known.
+    #[error("Rate limited by the DS")]
+    RateLimited(Option<std::time::Duration>),
+    /// The DS rejected the message because it was sent in an outdated epoch.
+    /// The client must process its pending messages before retrying.
+    #[error("Message was sent in an outdated epoch")]
+    StaleEpoch,
+    /// The room policy doesn't allow the request.
+    #[error("Not allowed by the room policy: {0}")]
+    NotAllowed(String),
+    /// The group was upgraded. The client must move to the successor group.
+    #[error("Group was upgraded")]
+    GroupUpgraded,
 }
 
 pub enum AuthenticationMethod<'a, T: SigningKeyBehaviour> {
@@ -77,48 +105,53 @@ impl ApiClient {
         let message_bytes = message
             .tls_serialize_detached()
             .map_err(|_| DsRequestError::LibraryError)?;
-        match self
-            .client
-            .post(self.build_url(Protocol::Http, ENDPOINT_DS_GROUPS))
-            .body(message_bytes)
-            .send()
-            .await
-        {
+        let request = TransportRequest::new(
+            Method::POST,
+            self.build_url(Protocol::Http, ENDPOINT_DS_GROUPS),
+        )
+        .with_body(message_bytes);
+        match self.send(request).await {
             Ok(res) => {
-                match res.status().as_u16() {
+                match res.status.as_u16() {
                     // Success!
                     x if (200..=299).contains(&x) => {
-                        let ds_proc_res_bytes =
-                            res.bytes().await.map_err(|_| DsRequestError::BadResponse)?;
-                        let ds_proc_res =
-                            DsProcessResponseIn::tls_deserialize_exact_bytes(&ds_proc_res_bytes)
-                                .map_err(|e| {
-                                    log::warn!("Couldn't deserialize OK response body: {:?}", e);
-                                    DsRequestError::BadResponse
-                                })?;
+                        let ds_proc_res = DsProcessResponseIn::tls_deserialize_exact_bytes(
+                            &res.body,
+                        )
+                        .map_err(|e| {
+                            log::warn!("Couldn't deserialize OK response body: {:?}", e);
+                            DsRequestError::BadResponse
+                        })?;
                         Ok(ds_proc_res)
                     }
                     // DS Specific Error
                     418 => {
-                        let ds_proc_err_bytes = res.bytes().await.map_err(|_| {
-                            log::warn!("No body in DS-error response.");
+                        let ds_proc_err = String::from_utf8(res.body).map_err(|_| {
+                            log::warn!("Couldn't deserialize DS-error response body.");
                             DsRequestError::BadResponse
                         })?;
-                        let ds_proc_err =
-                            String::from_utf8(ds_proc_err_bytes.to_vec()).map_err(|_| {
-                                log::warn!("Couldn't deserialize DS-error response body.");
-                                DsRequestError::BadResponse
-                            })?;
                         Err(DsRequestError::DsError(ds_proc_err))
                     }
-                    // All other errors
-                    _ => {
-                        let error_text = res.text().await.map_err(|_| {
-                            log::warn!("Other network error without body");
-                            DsRequestError::BadResponse
-                        })?;
-                        Err(DsRequestError::NetworkError(error_text))
+                    // Rate limited
+                    429 => {
+                        let retry_after = res
+                            .headers
+                            .get(reqwest::header::RETRY_AFTER)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| value.parse().ok())
+                            .map(std::time::Duration::from_secs);
+                        Err(DsRequestError::RateLimited(retry_after))
                     }
+                    // Sent in an outdated epoch
+                    409 => Err(DsRequestError::StaleEpoch),
+                    // The group moved to its successor
+                    410 => Err(DsRequestError::GroupUpgraded),
+                    // Forbidden by the room policy
+                    403 => Err(DsRequestError::NotAllowed(res.text())),
+                    // The server doesn't serve this client version anymore
+                    426 | 501 => Err(DsRequestError::UpgradeRequired),
+                    // All other errors
+                    _ => Err(DsRequestError::NetworkError(res.text())),
                 }
             }
             // A network error occurred.
@@ -132,7 +165,8 @@ impl ApiClient {
         auth_method: impl Into<AuthenticationMethod<'a, T>>,
         group_state_ear_key: &GroupStateEarKey,
     ) -> Result<DsProcessResponseIn, DsRequestError> {
-        let tbs = ClientToDsMessageTbsOut::new(group_state_ear_key.clone(), request_params);
+        let tbs = ClientToDsMessageTbsOut::new(group_state_ear_key.clone(), request_params)
+            .map_err(|_| DsRequestError::LibraryError)?;
         let message = match auth_method.into() {
             AuthenticationMethod::Signature(signer) => {
                 tbs.sign(signer).map_err(|_| DsRequestError::LibraryError)?
@@ -140,7 +174,15 @@ impl ApiClient {
             AuthenticationMethod::None => ClientToDsMessageOut::without_signature(tbs),
         };
         let message_type = DsMessageTypeOut::Group(message);
-        self.send_ds_message(message_type).await
+        let response = self.send_ds_message(message_type).await?;
+        // If the DS has already applied the request (e.g. because we are
+        // retrying after a timeout), we treat it the same as if the request
+        // was applied just now, such that the caller can merge its pending
+        // commit.
+        if let DsProcessResponseIn::AlreadyApplied(ts) = response {
+            return Ok(DsProcessResponseIn::FanoutTimestamp(ts));
+        }
+        Ok(response)
     }
 
     /// Creates a new group on the DS.
@@ -268,6 +310,33 @@ impl ApiClient {
         })
     }
 
+    /// Get the epoch of a group at the DS.
+    pub async fn ds_group_epoch_info(
+        &self,
+        group_id: GroupId,
+        group_state_ear_key: &GroupStateEarKey,
+        signing_key: &UserAuthSigningKey,
+    ) -> Result<GroupEpochInfo, DsRequestError> {
+        let payload = GroupEpochInfoParams {
+            sender: signing_key.verifying_key().hash(),
+            group_id,
+        };
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::GroupEpochInfo(payload),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::GroupEpochInfo(info) = response {
+                Ok(info)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
     /// Get external commit information for a connection group.
     pub async fn ds_connection_group_info(
         &self,
@@ -291,6 +360,256 @@ impl ApiClient {
         })
     }
 
+    /// Send a join request to the given group. This is only possible if the
+    /// room policy of the group allows join requests.
+    pub async fn ds_join_request(&self, params: JoinRequestParams) -> Result<(), DsRequestError> {
+        // The DS unwraps the EAR key of the group with the key of the join
+        // request token, so the key in the message is ignored.
+        let placeholder_ear_key =
+            GroupStateEarKey::random().map_err(|_| DsRequestError::LibraryError)?;
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::JoinRequest(params),
+            AuthenticationMethod::<InfraCredentialSigningKey>::None,
+            &placeholder_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if matches!(response, DsProcessResponseIn::Ok) {
+                Ok(())
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Register a token with which non-members can send join requests to the
+    /// given group.
+    pub async fn ds_register_join_request_token(
+        &self,
+        params: RegisterJoinRequestTokenParams,
+        signing_key: &UserAuthSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<(), DsRequestError> {
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::RegisterJoinRequestToken(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if matches!(response, DsProcessResponseIn::Ok) {
+                Ok(())
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Upload an attachment to the given group. Returns the id under which
+    /// other members can download it.
+    pub async fn ds_upload_attachment(
+        &self,
+        group_id: GroupId,
+        own_index: LeafNodeIndex,
+        content: Vec<u8>,
+        signing_key: &InfraCredentialSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<AttachmentId, DsRequestError> {
+        let params = UploadAttachmentParams {
+            group_id,
+            sender: own_index,
+            content,
+        };
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::UploadAttachment(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::AttachmentUploaded(attachment_id) = response {
+                Ok(attachment_id)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Upload a message archive to the given group. Archives are stored like
+    /// attachments, but aren't subject to their retention and aren't deleted
+    /// with them. Returns the id under which the archive can be downloaded.
+    pub async fn ds_upload_archive(
+        &self,
+        group_id: GroupId,
+        own_index: LeafNodeIndex,
+        content: Vec<u8>,
+        signing_key: &InfraCredentialSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<AttachmentId, DsRequestError> {
+        let params = UploadAttachmentParams {
+            group_id,
+            sender: own_index,
+            content,
+        };
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::UploadArchive(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::AttachmentUploaded(attachment_id) = response {
+                Ok(attachment_id)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Upload a view-once attachment to the given group. The DS deletes it
+    /// once it was downloaded by a member other than the uploader. Returns
+    /// the id under which the attachment can be downloaded.
+    pub async fn ds_upload_view_once_attachment(
+        &self,
+        group_id: GroupId,
+        own_index: LeafNodeIndex,
+        content: Vec<u8>,
+        signing_key: &InfraCredentialSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<AttachmentId, DsRequestError> {
+        let params = UploadAttachmentParams {
+            group_id,
+            sender: own_index,
+            content,
+        };
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::UploadViewOnceAttachment(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::AttachmentUploaded(attachment_id) = response {
+                Ok(attachment_id)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Download an attachment of the given group. If the DS serves the
+    /// attachment via a URL, the content is fetched from there.
+    pub async fn ds_download_attachment(
+        &self,
+        group_id: GroupId,
+        own_index: LeafNodeIndex,
+        attachment_id: AttachmentId,
+        signing_key: &InfraCredentialSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<AttachmentDownload, DsRequestError> {
+        let params = DownloadAttachmentParams {
+            group_id,
+            sender: own_index,
+            attachment_id,
+        };
+        let download = self
+            .prepare_and_send_ds_group_message(
+                DsRequestParamsOut::DownloadAttachment(params),
+                signing_key,
+                group_state_ear_key,
+            )
+            .await
+            // Check if the response is what we expected it to be.
+            .and_then(|response| {
+                if let DsProcessResponseIn::Attachment(download) = response {
+                    Ok(download)
+                } else {
+                    Err(DsRequestError::UnexpectedResponse)
+                }
+            })?;
+        // Fetch the content if the DS redirected us to the storage provider.
+        let AttachmentDownload::Url(url) = download else {
+            return Ok(download);
+        };
+        let content = self.fetch_attachment_url(&url).await?;
+        Ok(AttachmentDownload::Available(content))
+    }
+
+    /// Fetch the content of an attachment from the short-lived URL the DS
+    /// returned for it.
+    pub async fn fetch_attachment_url(
+        &self,
+        url: &AttachmentUrl,
+    ) -> Result<Vec<u8>, DsRequestError> {
+        let response = self
+            .send(TransportRequest::new(Method::GET, url.as_str()))
+            .await
+            .map_err(|e| DsRequestError::NetworkError(e.to_string()))?;
+        if !response.status.is_success() {
+            return Err(DsRequestError::NetworkError(format!(
+                "Unexpected status code {}",
+                response.status
+            )));
+        }
+        Ok(response.body)
+    }
+
+    /// Delete all attachments the user uploaded to the given group. Returns
+    /// the number of deleted attachments.
+    pub async fn ds_delete_attachments(
+        &self,
+        group_id: GroupId,
+        signing_key: &UserAuthSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<u32, DsRequestError> {
+        let params = DeleteAttachmentsParams {
+            group_id,
+            sender: signing_key.verifying_key().hash(),
+        };
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::DeleteAttachments(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::AttachmentsDeleted(deleted) = response {
+                Ok(deleted)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Fetch a page of the profiles of the members of the given group.
+    pub async fn ds_member_profiles(
+        &self,
+        params: MemberProfilesParams,
+        signing_key: &InfraCredentialSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<MemberProfilesPage, DsRequestError> {
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::MemberProfiles(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::MemberProfiles(page) = response {
+                Ok(page)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
     /// Update your client in this group. Note that the given commit needs to
     /// have [`phnxtypes::messages::client_ds::UpdateClientParamsAad`] in its AAD.
     pub async fn ds_update_client(
@@ -315,6 +634,31 @@ impl ApiClient {
         })
     }
 
+    /// Propose an update of your client in this group. Note that the proposal
+    /// needs to have [`phnxtypes::messages::client_ds::UpdateClientParamsAad`]
+    /// in its AAD.
+    pub async fn ds_propose_client_update(
+        &self,
+        params: ProposeClientUpdateParamsOut,
+        group_state_ear_key: &GroupStateEarKey,
+        signing_key: &InfraCredentialSigningKey,
+    ) -> Result<TimeStamp, DsRequestError> {
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::ProposeClientUpdate(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::FanoutTimestamp(ts) = response {
+                Ok(ts)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
     /// Join the group with a new client.
     pub async fn ds_join_group(
         &self,
@@ -532,6 +876,106 @@ impl ApiClient {
         })
     }
 
+    /// Update the policy of the given group.
+    pub async fn ds_update_room_policy(
+        &self,
+        params: UpdateRoomPolicyParamsOut,
+        signing_key: &UserAuthSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<TimeStamp, DsRequestError> {
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::UpdateRoomPolicy(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::FanoutTimestamp(ts) = response {
+                Ok(ts)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Update the group data of the given group.
+    pub async fn ds_update_group_data(
+        &self,
+        params: UpdateGroupDataParamsOut,
+        signing_key: &InfraCredentialSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<TimeStamp, DsRequestError> {
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::UpdateGroupData(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::FanoutTimestamp(ts) = response {
+                Ok(ts)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Upgrade the given group to the successor group, which must have been
+    /// created before.
+    pub async fn ds_upgrade_group(
+        &self,
+        params: UpgradeGroupParamsOut,
+        signing_key: &UserAuthSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<TimeStamp, DsRequestError> {
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::UpgradeGroup(params),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::FanoutTimestamp(ts) = response {
+                Ok(ts)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Join the successor of an upgraded group with the user auth key the
+    /// user had in the upgraded group.
+    pub async fn ds_join_upgraded_group(
+        &self,
+        external_commit: AssistedMessageOut,
+        qs_client_reference: QsClientReference,
+        signing_key: &UserAuthSigningKey,
+        group_state_ear_key: &GroupStateEarKey,
+    ) -> Result<TimeStamp, DsRequestError> {
+        let payload = JoinUpgradedGroupParamsOut {
+            sender: signing_key.verifying_key().hash(),
+            external_commit,
+            qs_client_reference,
+        };
+        self.prepare_and_send_ds_group_message(
+            DsRequestParamsOut::JoinUpgradedGroup(payload),
+            signing_key,
+            group_state_ear_key,
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let DsProcessResponseIn::FanoutTimestamp(ts) = response {
+                Ok(ts)
+            } else {
+                Err(DsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
     /// Update the client's queue info.
     pub async fn ds_update_queue_info(
         &self,
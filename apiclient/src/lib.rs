@@ -4,16 +4,31 @@
 
 //! HTTP client for the server REST API
 
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::Duration;
 
-use phnxtypes::{endpoint_paths::ENDPOINT_HEALTH_CHECK, DEFAULT_PORT_HTTP, DEFAULT_PORT_HTTPS};
-use reqwest::{Client, ClientBuilder, StatusCode, Url};
+use phnxtypes::{
+    endpoint_paths::ENDPOINT_HEALTH_CHECK, messages::server_info::ClientVersion, DEFAULT_PORT_HTTP,
+    DEFAULT_PORT_HTTPS,
+};
+use reqwest::{Client, ClientBuilder, Method, Response, StatusCode, Url};
 use thiserror::Error;
 use url::ParseError;
 
+use crate::{
+    dequeue::DequeueMetrics,
+    transport::{Transport, TransportError, TransportRequest},
+};
+
 pub mod as_api;
+pub mod compatibility;
+pub mod dequeue;
 pub mod ds_api;
 pub mod qs_api;
+pub mod transport;
+
+pub use reqwest::Client as HttpClient;
 
 /// Defines the type of protocol used for a specific endpoint.
 pub enum Protocol {
@@ -25,6 +40,10 @@ pub enum Protocol {
 // certificates in place.
 const HTTPS_BY_DEFAULT: bool = false;
 
+/// Default maximum size of response bodies. Larger responses are rejected
+/// without reading them completely.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
 #[derive(Error, Debug)]
 pub enum ApiClientInitError {
     #[error(transparent)]
@@ -43,6 +62,10 @@ pub enum ApiClientInitError {
 pub struct ApiClient {
     client: Client,
     url: Url,
+    max_response_size: usize,
+    dequeue_metrics: Arc<DequeueMetrics>,
+    transport: Option<Arc<dyn Transport>>,
+    client_version: Option<ClientVersion>,
 }
 
 impl ApiClient {
@@ -57,6 +80,15 @@ impl ApiClient {
     /// # Returns
     /// A new [`ApiClient`].
     pub fn initialize(domain: impl ToString) -> Result<Self, ApiClientInitError> {
+        Self::with_http_client(domain, Self::new_http_client()?)
+    }
+
+    /// Same as [`ApiClient::initialize`], but uses the given HTTP client
+    /// instead of creating a new one.
+    pub fn with_http_client(
+        domain: impl ToString,
+        client: Client,
+    ) -> Result<Self, ApiClientInitError> {
         let mut domain_string = domain.to_string();
         // We first check if the domain is a valid URL.
         let url = match Url::parse(&domain_string) {
@@ -70,11 +102,92 @@ impl ApiClient {
             }
             Err(_) => return Err(ApiClientInitError::UrlParsingError(domain_string.clone())),
         };
-        let client = ClientBuilder::new()
-            .pool_idle_timeout(Duration::from_secs(4))
-            .user_agent("PhnxClient/0.1")
-            .build()?;
-        Ok(Self { client, url })
+        Ok(Self {
+            client,
+            url,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            dequeue_metrics: Arc::default(),
+            transport: None,
+            client_version: None,
+        })
+    }
+
+    /// Same as [`ApiClient::initialize`], but sends all requests with the
+    /// given transport instead of the network (see [`transport`]).
+    pub fn with_transport(
+        domain: impl ToString,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Self, ApiClientInitError> {
+        let mut api_client = Self::with_http_client(domain, Client::new())?;
+        api_client.transport = Some(transport);
+        Ok(api_client)
+    }
+
+    /// Replace the maximum size of response bodies, which defaults to
+    /// [`DEFAULT_MAX_RESPONSE_SIZE`].
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    /// Send the given client version with every request, such that the
+    /// server can reject the requests of clients it doesn't serve anymore
+    /// with `426 Upgrade Required`.
+    pub fn with_client_version(mut self, client_version: ClientVersion) -> Self {
+        self.client_version = Some(client_version);
+        self
+    }
+
+    /// Read the body of the given response, failing as soon as it exceeds
+    /// the maximum response size.
+    pub(crate) async fn read_body(
+        &self,
+        #[allow(unused_mut)] mut response: Response,
+    ) -> Result<Vec<u8>, TransportError> {
+        let max = self.max_response_size;
+        if response
+            .content_length()
+            .is_some_and(|length| length > max as u64)
+        {
+            return Err(TransportError::TooLarge(max));
+        }
+        // In the browser, the body can only be read at once.
+        #[cfg(target_arch = "wasm32")]
+        let body = {
+            let body = response.bytes().await?;
+            if body.len() > max {
+                return Err(TransportError::TooLarge(max));
+            }
+            body.to_vec()
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        let body = {
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                if body.len() + chunk.len() > max {
+                    return Err(TransportError::TooLarge(max));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            body
+        };
+        Ok(body)
+    }
+
+    /// Creates the underlying HTTP client. A single HTTP client can be shared
+    /// between the [`ApiClient`]s of multiple domains (see
+    /// [`ApiClient::with_http_client`]). It keeps at most one idle connection
+    /// per host, which is multiplexed if the server supports HTTP/2.
+    pub fn new_http_client() -> Result<Client, ApiClientInitError> {
+        let builder = ClientBuilder::new().user_agent("PhnxClient/0.1");
+        // In the browser, connections are managed by the fetch API.
+        #[cfg(not(target_arch = "wasm32"))]
+        let builder = builder
+            .pool_idle_timeout(Duration::from_secs(90))
+            .pool_max_idle_per_host(1)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true);
+        Ok(builder.build()?)
     }
 
     /// Builds a URL for a given endpoint.
@@ -104,29 +217,19 @@ impl ApiClient {
 
     /// Call the health check endpoint
     pub async fn health_check(&self) -> bool {
-        self.client
-            .get(self.build_url(Protocol::Http, ENDPOINT_HEALTH_CHECK))
-            .send()
-            .await
-            .is_ok()
+        let request = TransportRequest::new(
+            Method::GET,
+            self.build_url(Protocol::Http, ENDPOINT_HEALTH_CHECK),
+        );
+        self.send(request).await.is_ok()
     }
 
     /// Call an inexistant endpoint
     pub async fn inexistant_endpoint(&self) -> bool {
-        let res = self
-            .client
-            .post(self.build_url(Protocol::Http, "/null"))
-            .body("test")
-            .send()
-            .await;
-        let status = match res {
-            Ok(r) => Some(r.status()),
-            Err(e) => e.status(),
-        };
-        if let Some(status) = status {
-            status == StatusCode::NOT_FOUND
-        } else {
-            false
-        }
+        let request = TransportRequest::new(Method::POST, self.build_url(Protocol::Http, "/null"))
+            .with_body(b"test".to_vec());
+        self.send(request)
+            .await
+            .is_ok_and(|response| response.status == StatusCode::NOT_FOUND)
     }
 }
@@ -29,15 +29,22 @@ use phnxtypes::{
             ClientToQsMessageOut, ClientToQsMessageTbsOut, CreateClientRecordParamsOut,
             CreateUserRecordParamsOut, PublishKeyPackagesParamsOut, QsRequestParamsOut,
         },
+        presence::{
+            FetchPresenceParams, FetchPresenceResponse, PresenceQuery, PresenceStatus,
+            PresenceToken, PresenceVisibility, PublishPresenceParams,
+        },
         push_token::EncryptedPushToken,
+        self_message::{EncryptedSelfMessage, EnqueueSelfMessageParams},
         FriendshipToken,
     },
 };
+use reqwest::Method;
 use thiserror::Error;
 use tls_codec::{DeserializeBytes, Serialize};
 
-use crate::{ApiClient, Protocol};
+use crate::{transport::TransportRequest, ApiClient, Protocol};
 
+#[cfg(not(target_arch = "wasm32"))]
 pub mod ws;
 
 #[cfg(test)]
@@ -53,6 +60,10 @@ pub enum QsRequestError {
     UnexpectedResponse,
     #[error("Network error: {0}")]
     NetworkError(String),
+    /// The server doesn't support the requests of this client version
+    /// anymore. The client must be upgraded.
+    #[error("Client upgrade required")]
+    UpgradeRequired,
     #[error(transparent)]
     QsError(#[from] QsProcessError),
 }
@@ -81,39 +92,29 @@ impl ApiClient {
         let message_bytes = message
             .tls_serialize_detached()
             .map_err(|_| QsRequestError::LibraryError)?;
-        match self
-            .client
-            .post(self.build_url(Protocol::Http, ENDPOINT_QS))
-            .body(message_bytes)
-            .send()
-            .await
-        {
+        let request =
+            TransportRequest::new(Method::POST, self.build_url(Protocol::Http, ENDPOINT_QS))
+                .with_body(message_bytes);
+        match self.send(request).await {
             Ok(res) => {
-                match res.status().as_u16() {
+                match res.status.as_u16() {
                     // Success!
                     x if (200..=299).contains(&x) => {
-                        let ds_proc_res_bytes =
-                            res.bytes().await.map_err(|_| QsRequestError::BadResponse)?;
                         let ds_proc_res =
-                            QsProcessResponseIn::tls_deserialize_exact_bytes(&ds_proc_res_bytes)
+                            QsProcessResponseIn::tls_deserialize_exact_bytes(&res.body)
                                 .map_err(|_| QsRequestError::BadResponse)?;
                         Ok(ds_proc_res)
                     }
                     // DS Specific Error
                     418 => {
-                        let ds_proc_err_bytes =
-                            res.bytes().await.map_err(|_| QsRequestError::BadResponse)?;
-                        let ds_proc_err =
-                            QsProcessError::tls_deserialize_exact_bytes(&ds_proc_err_bytes)
-                                .map_err(|_| QsRequestError::BadResponse)?;
+                        let ds_proc_err = QsProcessError::tls_deserialize_exact_bytes(&res.body)
+                            .map_err(|_| QsRequestError::BadResponse)?;
                         Err(QsRequestError::QsError(ds_proc_err))
                     }
+                    // The server doesn't serve this client version anymore
+                    426 | 501 => Err(QsRequestError::UpgradeRequired),
                     // All other errors
-                    _ => {
-                        let error_text =
-                            res.text().await.map_err(|_| QsRequestError::BadResponse)?;
-                        Err(QsRequestError::NetworkError(error_text))
-                    }
+                    _ => Err(QsRequestError::NetworkError(res.text())),
                 }
             }
             // A network error occurred.
@@ -347,12 +348,10 @@ impl ApiClient {
         )
         .await
         // Check if the response is what we expected it to be.
-        .and_then(|response| {
-            if let QsProcessResponseIn::DequeueMessages(resp) = response {
-                Ok(resp)
-            } else {
-                Err(QsRequestError::UnexpectedResponse)
-            }
+        .and_then(|response| match response {
+            QsProcessResponseIn::DequeueMessages(resp) => Ok(resp.into()),
+            QsProcessResponseIn::DequeueMessagesV2(resp) => Ok(resp),
+            _ => Err(QsRequestError::UnexpectedResponse),
         })
     }
 
@@ -411,4 +410,85 @@ impl ApiClient {
             }
         })
     }
+
+    /// Publish the presence of the user. Only the holders of the given
+    /// presence tokens can see it if the visibility is
+    /// [`PresenceVisibility::Contacts`]. The connected clients of the users
+    /// with the given friendship tokens are notified of changes.
+    pub async fn qs_publish_presence(
+        &self,
+        sender: QsUserId,
+        status: PresenceStatus,
+        visibility: PresenceVisibility,
+        viewers: Vec<PresenceToken>,
+        contacts: Vec<FriendshipToken>,
+        signing_key: &QsUserSigningKey,
+    ) -> Result<(), QsRequestError> {
+        let payload = PublishPresenceParams {
+            sender,
+            status,
+            visibility,
+            viewers,
+            contacts,
+        };
+        self.prepare_and_send_qs_message(
+            QsRequestParamsOut::PublishPresence(payload),
+            AuthenticationMethod::SigningKey(signing_key),
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if matches!(response, QsProcessResponseIn::Ok) {
+                Ok(())
+            } else {
+                Err(QsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    pub async fn qs_fetch_presence(
+        &self,
+        sender: QsUserId,
+        contacts: Vec<PresenceQuery>,
+        signing_key: &QsUserSigningKey,
+    ) -> Result<FetchPresenceResponse, QsRequestError> {
+        let payload = FetchPresenceParams { sender, contacts };
+        self.prepare_and_send_qs_message(
+            QsRequestParamsOut::FetchPresence(payload),
+            AuthenticationMethod::SigningKey(signing_key),
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if let QsProcessResponseIn::FetchPresence(resp) = response {
+                Ok(resp)
+            } else {
+                Err(QsRequestError::UnexpectedResponse)
+            }
+        })
+    }
+
+    /// Enqueue the given self message in the queues of the other clients of
+    /// the sender's user.
+    pub async fn qs_enqueue_self_message(
+        &self,
+        sender: QsClientId,
+        message: EncryptedSelfMessage,
+        signing_key: &QsClientSigningKey,
+    ) -> Result<(), QsRequestError> {
+        let payload = EnqueueSelfMessageParams { sender, message };
+        self.prepare_and_send_qs_message(
+            QsRequestParamsOut::EnqueueSelfMessage(payload),
+            AuthenticationMethod::SigningKey(signing_key),
+        )
+        .await
+        // Check if the response is what we expected it to be.
+        .and_then(|response| {
+            if matches!(response, QsProcessResponseIn::Ok) {
+                Ok(())
+            } else {
+                Err(QsRequestError::UnexpectedResponse)
+            }
+        })
+    }
 }
@@ -2,11 +2,16 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::{net::TcpListener, time::Duration};
+use std::{
+    net::TcpListener,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
 use actix_web::{
     dev::Server,
+    http::header::{HeaderName, HeaderValue},
     middleware::Logger,
     web::{self},
     App, HttpRequest, HttpResponse, HttpServer, Responder,
@@ -15,9 +20,13 @@ use actix_web_actors::ws;
 use base64::{engine::general_purpose, Engine as _};
 use phnxtypes::{
     codec::PhnxCodec,
+    crypto::signatures::keys::QsClientSigningKey,
     endpoint_paths::ENDPOINT_QS_WS,
     identifiers::QsClientId,
-    messages::{client_ds::QsWsMessage, client_qs::QsOpenWsParams},
+    messages::{
+        client_ds::QsWsMessage,
+        client_qs::{QsOpenWsAuth, QsOpenWsParams, QS_RESUMPTION_TICKET_HEADER},
+    },
 };
 use tls_codec::Serialize;
 use uuid::Uuid;
@@ -25,6 +34,8 @@ use uuid::Uuid;
 use crate::{qs_api::ws::WsEvent, ApiClient};
 
 static QUEUE_ID_VALUE: Uuid = Uuid::nil();
+static TICKET_VALUE: &[u8] = b"ticket";
+static TICKET_PRESENTED: AtomicBool = AtomicBool::new(false);
 
 #[tokio::test]
 async fn ws_lifecycle() {
@@ -49,7 +60,12 @@ async fn ws_lifecycle() {
 
     // Spawn the websocket connection task
     let mut ws = client
-        .spawn_websocket(queue_id, timeout, retry_interval)
+        .spawn_websocket(
+            queue_id,
+            QsClientSigningKey::random().unwrap(),
+            timeout,
+            retry_interval,
+        )
         .await
         .expect("Failed to execute request");
 
@@ -72,6 +88,8 @@ async fn ws_lifecycle() {
     assert_eq!(ws.next().await, Some(WsEvent::DisconnectedEvent));
     // Connected event because the client tried to reconnect to the websocket
     assert_eq!(ws.next().await, Some(WsEvent::ConnectedEvent));
+    // The client reconnected with the ticket issued on the first connection
+    assert!(TICKET_PRESENTED.load(Ordering::SeqCst));
 }
 
 // === Websocket server ===
@@ -128,13 +146,25 @@ pub(crate) async fn upgrade_connection(req: HttpRequest, stream: web::Payload) -
     // Check the queue id value
     assert_eq!(qs_open_ws_params.queue_id.as_uuid(), &QUEUE_ID_VALUE);
 
+    // Check the resumption ticket
+    if let QsOpenWsAuth::Ticket(ticket) = &qs_open_ws_params.auth {
+        assert_eq!(ticket.as_bytes(), TICKET_VALUE);
+        TICKET_PRESENTED.store(true, Ordering::SeqCst);
+    }
+
     // Extract the queue ID
     let qs_ws_connection = QsWsConnection::new();
 
     // Upgrade the connection to a websocket connection
     log::info!("Upgrading HTTP connection to websocket connection...");
     match ws::start(qs_ws_connection, &req, stream) {
-        Ok(res) => res,
+        Ok(mut res) => {
+            res.headers_mut().insert(
+                HeaderName::try_from(QS_RESUMPTION_TICKET_HEADER).unwrap(),
+                HeaderValue::from_str(&general_purpose::STANDARD.encode(TICKET_VALUE)).unwrap(),
+            );
+            res
+        }
         Err(e) => {
             log::error!("Error upgrading connection: {}", e);
             HttpResponse::InternalServerError().body(format!("{}", e))
@@ -7,12 +7,19 @@ use std::time::Duration;
 
 use base64::{engine::general_purpose, Engine as _};
 use futures_util::{pin_mut, SinkExt, StreamExt};
-use http::{HeaderValue, Request};
+use http::{HeaderMap, HeaderValue, Request, StatusCode};
 use phnxtypes::{
     codec::PhnxCodec,
+    crypto::signatures::keys::QsClientSigningKey,
     endpoint_paths::ENDPOINT_QS_WS,
     identifiers::QsClientId,
-    messages::{client_ds::QsWsMessage, client_qs::QsOpenWsParams},
+    messages::{
+        client_ds::QsWsMessage,
+        client_qs::{
+            QsOpenWsParams, QsResumptionTicket, QS_RESUMPTION_TICKET_HEADER,
+            QS_WS_CLOSE_RESUME_REQUIRED,
+        },
+    },
 };
 use thiserror::*;
 use tls_codec::DeserializeBytes;
@@ -24,7 +31,7 @@ use tokio::{
 };
 use tokio_tungstenite::{
     connect_async,
-    tungstenite::{client::IntoClientRequest, protocol::Message},
+    tungstenite::{client::IntoClientRequest, protocol::Message, Error as WsError},
     MaybeTlsStream, WebSocketStream,
 };
 
@@ -168,13 +175,14 @@ impl QsWebSocket {
                                     let _ = ws_stream.close().await;
                                     return;
                                 }
-                                // Try to deserialize the message
-                                if let Ok(QsWsMessage::QueueUpdate) =
-                                    QsWsMessage::tls_deserialize_exact_bytes(&data)
+                                // Try to deserialize the message. We forward new message
+                                // notifications and presence updates of contacts.
+                                if let Ok(
+                                    message @ (QsWsMessage::QueueUpdate | QsWsMessage::Presence(_)),
+                                ) = QsWsMessage::tls_deserialize_exact_bytes(&data)
                                 {
-                                    // We received a new message notification from the QS
                                     // Send the event to the channel
-                                    if tx.send(WsEvent::MessageEvent(QsWsMessage::QueueUpdate)).is_err() {
+                                    if tx.send(WsEvent::MessageEvent(message)).is_err() {
                                         log::info!("Closing the connection because all subscribers are dropped");
                                         // Close the stream if all subscribers of the watch have been dropped
                                         let _ = ws_stream.close().await;
@@ -193,7 +201,16 @@ impl QsWebSocket {
                                     return;
                                 }
                             }
-                            Message::Close(_) => {
+                            Message::Close(frame) => {
+                                // The QS closed the connection because we
+                                // didn't keep up and notifications might have
+                                // been dropped, so we fetch the queue.
+                                if frame.is_some_and(|frame| {
+                                    u16::from(frame.code) == QS_WS_CLOSE_RESUME_REQUIRED
+                                }) {
+                                    log::info!("QS requires to resume the websocket connection");
+                                    let _ = tx.send(WsEvent::MessageEvent(QsWsMessage::QueueUpdate));
+                                }
                                 // Change the status to Disconnected and send an
                                 // event
                                 let _ = connection_status.set_disconnected(tx);
@@ -222,6 +239,8 @@ pub enum SpawnWsError {
     WrongParameters,
     #[error("Malformed URL supplied")]
     WrongUrl,
+    #[error("The websocket is not available with a custom transport")]
+    UnsupportedTransport,
 }
 
 impl ApiClient {
@@ -257,8 +276,14 @@ impl ApiClient {
     /// have been dropped, or when it is manually closed with using the
     /// [`QsWebSocket::abort()`] function.
     ///
+    /// The first connection is authenticated with a signature. When
+    /// reconnecting, the client presents the resumption ticket issued by the
+    /// QS on the previous connection instead, and falls back to a signature
+    /// if the ticket is rejected.
+    ///
     /// # Arguments
     ///  -  `queue_id` - The ID of the queue monitor.
+    ///  - `signing_key` - The signing key of the queue owner.
     ///  - `timeout` - The timeout for the connection in seconds.
     ///  - `retry_interval` - The interval between connection attempts in seconds.
     ///
@@ -267,14 +292,18 @@ impl ApiClient {
     pub async fn spawn_websocket(
         &self,
         queue_id: QsClientId,
+        signing_key: QsClientSigningKey,
         timeout: u64,
         retry_interval: u64,
     ) -> Result<QsWebSocket, SpawnWsError> {
+        if self.transport.is_some() {
+            return Err(SpawnWsError::UnsupportedTransport);
+        }
         // Set the request parameter
-        let qs_ws_open_params = QsOpenWsParams { queue_id };
-        let serialized =
-            PhnxCodec::to_vec(&qs_ws_open_params).map_err(|_| SpawnWsError::WrongParameters)?;
-        let encoded = general_purpose::STANDARD.encode(&serialized);
+        let encoded = encode_open_ws_params(
+            &QsOpenWsParams::signed(queue_id.clone(), &signing_key)
+                .map_err(|_| SpawnWsError::WrongParameters)?,
+        )?;
         // Format the URL
         let address = self.build_url(Protocol::Ws, ENDPOINT_QS_WS);
         // We check if the request builds correctly
@@ -300,7 +329,27 @@ impl ApiClient {
             // Connection loop
             #[cfg(test)]
             let mut counter = 0;
+            // Resumption ticket issued by the QS on the last successful
+            // connection
+            let mut ticket: Option<QsResumptionTicket> = None;
             loop {
+                // Authenticate with the ticket if we have one and with a
+                // signature otherwise
+                let params = match &ticket {
+                    Some(ticket) => Ok(QsOpenWsParams::with_ticket(
+                        queue_id.clone(),
+                        ticket.clone(),
+                    )),
+                    None => QsOpenWsParams::signed(queue_id.clone(), &signing_key)
+                        .map_err(|_| SpawnWsError::WrongParameters),
+                };
+                let encoded = match params.and_then(|params| encode_open_ws_params(&params)) {
+                    Ok(encoded) => encoded,
+                    Err(e) => {
+                        log::error!("Error building parameters: {}", e);
+                        break;
+                    }
+                };
                 // We build the request and set a custom header
                 let req = match address.clone().into_client_request() {
                     Ok(mut req) => {
@@ -317,11 +366,21 @@ impl ApiClient {
                 // Try to establish a connection
                 match connect_async(req).await {
                     // The connection was established
-                    Ok((ws_stream, _)) => {
+                    Ok((ws_stream, response)) => {
                         log::info!("Connected to QS WebSocket");
+                        ticket = resumption_ticket(response.headers());
                         // Hand over the connection to the handler
                         QsWebSocket::handle_connection(ws_stream, &tx, timeout).await;
                     }
+                    // The ticket expired or was revoked, authenticate with a
+                    // signature right away
+                    Err(WsError::Http(response))
+                        if ticket.is_some() && response.status() == StatusCode::UNAUTHORIZED =>
+                    {
+                        log::info!("Resumption ticket was rejected");
+                        ticket = None;
+                        continue;
+                    }
                     // The connection was not established, wait and try again
                     Err(e) => {
                         log::error!("Error connecting to QS WebSocket: {}", e);
@@ -349,3 +408,14 @@ impl ApiClient {
         })
     }
 }
+
+fn encode_open_ws_params(params: &QsOpenWsParams) -> Result<String, SpawnWsError> {
+    let serialized = PhnxCodec::to_vec(params).map_err(|_| SpawnWsError::WrongParameters)?;
+    Ok(general_purpose::STANDARD.encode(serialized))
+}
+
+fn resumption_ticket(headers: &HeaderMap) -> Option<QsResumptionTicket> {
+    let value = headers.get(QS_RESUMPTION_TICKET_HEADER)?;
+    let bytes = general_purpose::STANDARD.decode(value.as_bytes()).ok()?;
+    Some(QsResumptionTicket::from_bytes(bytes))
+}
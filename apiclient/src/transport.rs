@@ -0,0 +1,141 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Pluggable transport for the HTTP requests of the [`ApiClient`].
+//!
+//! By default, the [`ApiClient`] sends its requests with reqwest. Tests can
+//! replace the network with a [`Transport`] (see
+//! [`ApiClient::with_transport`]), e.g. to script the responses of the
+//! server. The QS websocket is not available with a custom transport.
+
+use std::{future::Future, pin::Pin};
+
+use http::HeaderValue;
+pub use http::{HeaderMap, Method, StatusCode};
+use phnxtypes::messages::server_info::CLIENT_VERSION_HEADER;
+use thiserror::Error;
+
+use crate::ApiClient;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type TransportFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<TransportResponse, TransportError>> + Send + 'a>>;
+// Futures of the fetch API are not `Send`.
+#[cfg(target_arch = "wasm32")]
+pub type TransportFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<TransportResponse, TransportError>> + 'a>>;
+
+/// Sends the HTTP requests of an [`ApiClient`].
+pub trait Transport: Send + Sync {
+    fn send(&self, request: TransportRequest) -> TransportFuture<'_>;
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TransportRequest {
+    pub(crate) fn new(method: Method, url: impl ToString) -> Self {
+        Self {
+            method,
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// The path of the URL, e.g. the endpoint of the request.
+    pub fn path(&self) -> &str {
+        let without_scheme = self
+            .url
+            .split_once("://")
+            .map_or(self.url.as_str(), |(_, rest)| rest);
+        without_scheme
+            .find('/')
+            .map_or("/", |index| &without_scheme[index..])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TransportResponse {
+    pub fn new(status: StatusCode, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body,
+        }
+    }
+
+    /// The body as text, replacing invalid UTF-8.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("Response body exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+    #[error("{0}")]
+    Network(String),
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Network(error.to_string())
+    }
+}
+
+impl ApiClient {
+    /// Send the given request with the transport of this client and read the
+    /// complete response.
+    pub(crate) async fn send(
+        &self,
+        mut request: TransportRequest,
+    ) -> Result<TransportResponse, TransportError> {
+        if let Some(value) = self
+            .client_version
+            .and_then(|version| HeaderValue::from_str(&version.to_string()).ok())
+        {
+            request.headers.insert(CLIENT_VERSION_HEADER, value);
+        }
+        if let Some(transport) = &self.transport {
+            let response = transport.send(request).await?;
+            if response.body.len() > self.max_response_size {
+                return Err(TransportError::TooLarge(self.max_response_size));
+            }
+            return Ok(response);
+        }
+        let response = self
+            .client
+            .request(request.method, request.url)
+            .headers(request.headers)
+            .body(request.body)
+            .send()
+            .await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = self.read_body(response).await?;
+        Ok(TransportResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
@@ -7,6 +7,8 @@ package im.phnx.prototype
 import android.app.NotificationChannel
 import android.app.NotificationManager
 import android.content.Context
+import android.media.AudioAttributes
+import android.net.Uri
 import android.os.Build
 import android.util.Log
 import androidx.core.app.NotificationCompat
@@ -31,17 +33,6 @@ class BackgroundFirebaseMessagingService : FirebaseMessagingService() {
         val notificationManager =
             getSystemService(Context.NOTIFICATION_SERVICE) as NotificationManager
 
-        // Create a notification channel for Android 8.0+
-        if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.O) {
-            Log.d(LOGTAG, "Creating notification channel")
-            val channel = NotificationChannel(
-                "default_channel",
-                "Default Channel",
-                NotificationManager.IMPORTANCE_DEFAULT
-            )
-            notificationManager.createNotificationChannel(channel)
-        }
-
         val notificationContent = IncomingNotificationContent(
             title = "",
             body = "",
@@ -55,7 +46,8 @@ class BackgroundFirebaseMessagingService : FirebaseMessagingService() {
 
         // Show the notifications
         notificationBatch?.additions?.forEach {
-            showNotification(notificationManager, it.identifier, it.title, it.body)
+            createNotificationChannel(notificationManager, it)
+            showNotification(notificationManager, it)
         }
 
         // Remove the notifications
@@ -64,14 +56,44 @@ class BackgroundFirebaseMessagingService : FirebaseMessagingService() {
         }
     }
 
-    private fun showNotification(notificationManager: NotificationManager, identifier: String?, title: String?, body: String?) {
-        val notificationBuilder = NotificationCompat.Builder(this, "default_channel")
-            .setContentTitle(title)
-            .setContentText(body)
+    // Create the notification channel of a notification for Android 8.0+.
+    // Channels can't be changed once created, so customized chats get a new
+    // channel id whenever their settings change.
+    private fun createNotificationChannel(notificationManager: NotificationManager, content: NotificationContent) {
+        if (Build.VERSION.SDK_INT < Build.VERSION_CODES.O) {
+            return
+        }
+        if (notificationManager.getNotificationChannel(content.channel_id) != null) {
+            return
+        }
+        Log.d(LOGTAG, "Creating notification channel ${content.channel_id}")
+        val importance = when (content.interruption_level) {
+            "passive" -> NotificationManager.IMPORTANCE_LOW
+            "time_sensitive" -> NotificationManager.IMPORTANCE_HIGH
+            else -> NotificationManager.IMPORTANCE_DEFAULT
+        }
+        val channel = NotificationChannel(content.channel_id, content.channel_name, importance)
+        if (content.silent) {
+            channel.setSound(null, null)
+        } else if (content.sound_name != null) {
+            val sound = Uri.parse("android.resource://$packageName/raw/${content.sound_name}")
+            val attributes = AudioAttributes.Builder()
+                .setUsage(AudioAttributes.USAGE_NOTIFICATION)
+                .build()
+            channel.setSound(sound, attributes)
+        }
+        notificationManager.createNotificationChannel(channel)
+    }
+
+    private fun showNotification(notificationManager: NotificationManager, content: NotificationContent) {
+        val notificationBuilder = NotificationCompat.Builder(this, content.channel_id)
+            .setContentTitle(content.title)
+            .setContentText(content.body)
             .setSmallIcon(android.R.drawable.ic_notification_overlay) // Use your app's icon
             .setAutoCancel(true)
+            .setSilent(content.silent)
 
-        notificationManager.notify(identifier, 0, notificationBuilder.build())
+        notificationManager.notify(content.identifier, 0, notificationBuilder.build())
     }
 
     override fun onNewToken(token: String) {
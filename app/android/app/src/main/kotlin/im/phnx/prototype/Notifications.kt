@@ -10,6 +10,9 @@ import kotlinx.serialization.json.*
 
 private const val LOGTAG = "NativeLib"
 
+const val DEFAULT_CHANNEL_ID = "default_channel"
+const val DEFAULT_CHANNEL_NAME = "Default Channel"
+
 @Serializable
 data class IncomingNotificationContent(
     val title: String,
@@ -23,7 +26,12 @@ data class NotificationContent(
     val identifier: String,
     val title: String,
     val body: String,
-    val data: String
+    val data: String,
+    val channel_id: String = DEFAULT_CHANNEL_ID,
+    val channel_name: String = DEFAULT_CHANNEL_NAME,
+    val sound_name: String? = null,
+    val silent: Boolean = false,
+    val interruption_level: String = "active"
 )
 
 @Serializable
@@ -24,6 +24,32 @@ struct NotificationContent: Codable {
     let title: String
     let body: String
     let data: String
+    let channel_id: String?
+    let channel_name: String?
+    let sound_name: String?
+    let silent: Bool?
+    let interruption_level: String?
+
+    // Sound and interruption level customized for the chat
+    func applyAlert(to content: UNMutableNotificationContent) {
+        if silent == true {
+            content.sound = nil
+        } else if let soundName = sound_name {
+            content.sound = UNNotificationSound(named: UNNotificationSoundName(soundName))
+        } else {
+            content.sound = UNNotificationSound.default
+        }
+        if #available(iOS 15.0, *) {
+            switch interruption_level {
+            case "passive":
+                content.interruptionLevel = .passive
+            case "time_sensitive":
+                content.interruptionLevel = .timeSensitive
+            default:
+                content.interruptionLevel = .active
+            }
+        }
+    }
 }
 
 class NotificationService: UNNotificationServiceExtension {
@@ -113,7 +139,7 @@ class NotificationService: UNNotificationServiceExtension {
                 let newContent = UNMutableNotificationContent()
                 newContent.title = notificationContent.title
                 newContent.body = notificationContent.body
-                newContent.sound = UNNotificationSound.default
+                notificationContent.applyAlert(to: newContent)
                 newContent.userInfo["customData"] = notificationContent.data
                 let request = UNNotificationRequest(identifier: notificationContent.identifier, content: newContent, trigger: nil)
                 center.add(request) { error in
@@ -131,7 +157,7 @@ class NotificationService: UNNotificationServiceExtension {
             if let lastNotification = lastNotification {
                 content.title = lastNotification.title
                 content.body = lastNotification.body
-                content.sound = UNNotificationSound.default
+                lastNotification.applyAlert(to: content)
                 content.userInfo["customData"] = lastNotification.data
             }
             // Add the badge number
@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 import 'package:flutter/material.dart';
+import 'package:prototype/core/api/system_messages.dart';
 import 'package:prototype/core/api/types.dart';
 import 'package:prototype/styles.dart';
 import 'tile_timestamp.dart';
@@ -85,14 +86,17 @@ class SystemMessageContent extends StatelessWidget {
       ),
       padding: const EdgeInsets.all(10),
       margin: const EdgeInsets.fromLTRB(25, 0, 20, 0),
-      child: Text(
-        message.message,
-        style: TextStyle(
-          color: Colors.grey[700],
-          fontVariations: variationBold,
-          letterSpacing: -0.02,
-          fontSize: 10,
-          height: 1.4,
+      child: FutureBuilder(
+        future: renderSystemMessage(message: message),
+        builder: (context, snapshot) => Text(
+          snapshot.data ?? "",
+          style: TextStyle(
+            color: Colors.grey[700],
+            fontVariations: variationBold,
+            letterSpacing: -0.02,
+            fontSize: 10,
+            height: 1.4,
+          ),
         ),
       ),
     );
@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use flutter_rust_bridge::frb;
+use phnxcoreclient::clients::CoreUser;
+use phnxcoreclient::store::{Store, StoreNotifications};
+use phnxcoreclient::{ActivityCursor, NotificationType};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::util::{spawn_from_sync, Cubit, CubitCore};
+use crate::StreamSink;
+
+use super::types::UiActivity;
+use super::user::user_cubit::UserCubitBase;
+
+/// Number of activities loaded at once
+const PAGE_SIZE: usize = 50;
+
+#[frb(dart_metadata = ("freezed"))]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct ActivityCenterState {
+    /// Loaded activities, newest first
+    pub activities: Vec<UiActivity>,
+    pub unread_count: u32,
+    /// Whether older activities can be loaded with
+    /// [`ActivityCenterCubitBase::load_more`]
+    pub has_more: bool,
+}
+
+/// Notification center listing the activity feed across all chats
+#[frb(opaque)]
+pub struct ActivityCenterCubitBase {
+    core: CubitCore<ActivityCenterState>,
+    context: ActivityCenterContext,
+}
+
+impl ActivityCenterCubitBase {
+    #[frb(sync)]
+    pub fn new(user_cubit: &UserCubitBase) -> Self {
+        let core_user = user_cubit.core_user.clone();
+        let core = CubitCore::with_app_lock(user_cubit.app_lock());
+
+        let context = ActivityCenterContext::new(core_user.clone(), core.state_tx().clone());
+        context.clone().spawn(
+            Store::subscribe(&core_user),
+            core.cancellation_token().clone(),
+        );
+
+        Self { core, context }
+    }
+
+    // Cubit interface
+
+    #[frb(getter, sync)]
+    pub fn is_closed(&self) -> bool {
+        self.core.is_closed()
+    }
+
+    pub fn close(&mut self) {
+        self.core.close();
+    }
+
+    #[frb(getter, sync)]
+    pub fn state(&self) -> ActivityCenterState {
+        self.core.state()
+    }
+
+    pub async fn stream(&mut self, sink: StreamSink<ActivityCenterState>) {
+        self.core.stream(sink).await;
+    }
+
+    // Cubit methods
+
+    /// Load the next page of older activities
+    pub async fn load_more(&self) -> anyhow::Result<()> {
+        let last = self.core.borrow_state().activities.last().cloned();
+        let Some(last) = last else {
+            return Ok(());
+        };
+        let older = self
+            .context
+            .core_user
+            .activities(
+                Some(ActivityCursor {
+                    created_at: last.created_at.into(),
+                    activity_id: last.id.into(),
+                }),
+                PAGE_SIZE,
+            )
+            .await?;
+        let has_more = older.len() == PAGE_SIZE;
+        self.context.state_tx.send_modify(|state| {
+            state
+                .activities
+                .extend(older.into_iter().map(UiActivity::from));
+            state.has_more = has_more;
+        });
+        Ok(())
+    }
+
+    pub async fn mark_as_read(&self, activity_id: Uuid) -> anyhow::Result<()> {
+        self.context
+            .core_user
+            .mark_activity_as_read(activity_id.into())
+            .await
+    }
+
+    pub async fn mark_all_as_read(&self) -> anyhow::Result<()> {
+        self.context.core_user.mark_all_activities_as_read().await
+    }
+
+    pub async fn delete(&self, activity_id: Uuid) -> anyhow::Result<()> {
+        self.context
+            .core_user
+            .delete_activity(activity_id.into())
+            .await
+    }
+}
+
+/// Loads the intial state and listen to the changes
+#[frb(ignore)]
+#[derive(Clone)]
+struct ActivityCenterContext {
+    core_user: CoreUser,
+    state_tx: watch::Sender<ActivityCenterState>,
+}
+
+impl ActivityCenterContext {
+    fn new(core_user: CoreUser, state_tx: watch::Sender<ActivityCenterState>) -> Self {
+        Self {
+            core_user,
+            state_tx,
+        }
+    }
+
+    fn spawn(self, store_rx: StoreNotifications, stop: CancellationToken) {
+        spawn_from_sync(async move {
+            self.load_and_emit_state().await;
+            self.store_listen_loop(store_rx, stop).await;
+        });
+    }
+
+    /// Reload the activities, keeping as many of them loaded as before
+    async fn load_and_emit_state(&self) {
+        let loaded = self.state_tx.borrow().activities.len().max(PAGE_SIZE);
+        let activities = self.core_user.activities(None, loaded).await;
+        let unread_count = self.core_user.unread_activities_count().await;
+        let (activities, unread_count) = match (activities, unread_count) {
+            (Ok(activities), Ok(unread_count)) => (activities, unread_count),
+            (Err(error), _) | (_, Err(error)) => {
+                error!(%error, "Failed to load activities");
+                return;
+            }
+        };
+        let state = ActivityCenterState {
+            has_more: activities.len() == loaded,
+            activities: activities.into_iter().map(From::from).collect(),
+            unread_count,
+        };
+        self.state_tx.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+
+    async fn store_listen_loop(self, mut store_rx: StoreNotifications, stop: CancellationToken) {
+        loop {
+            let notification = tokio::select! {
+                _ = stop.cancelled() => return,
+                notification = store_rx.recv() => notification,
+            };
+            match notification {
+                Some(NotificationType::ActivityChange) => self.load_and_emit_state().await,
+                Some(_) => {}
+                None => return,
+            }
+        }
+    }
+}
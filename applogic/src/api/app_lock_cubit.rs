@@ -0,0 +1,260 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use flutter_rust_bridge::frb;
+use phnxcoreclient::clients::CoreUser;
+use tokio::sync::Mutex;
+
+use crate::app_state::app_lock::{
+    AppLock, AppLockHandle, AppLockSettings, AppLockState, PinVerification,
+};
+use crate::util::{Cubit, CubitCore};
+use crate::StreamSink;
+
+use super::key_store::{MasterKeyVault, UiKeyProtection};
+use super::user::user_cubit::UserCubitBase;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UiAppLockState {
+    #[default]
+    Unlocked,
+    /// The app is in the background and is locked if it doesn't return before
+    /// the auto-lock timeout.
+    GracePeriod,
+    Locked,
+}
+
+impl From<AppLockState> for UiAppLockState {
+    fn from(state: AppLockState) -> Self {
+        match state {
+            AppLockState::Unlocked => Self::Unlocked,
+            AppLockState::GracePeriod { .. } => Self::GracePeriod,
+            AppLockState::Locked => Self::Locked,
+        }
+    }
+}
+
+/// Lifecycle state of the app as reported by the platform
+pub enum UiAppLifecycleState {
+    Foreground,
+    Background,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiPinVerification {
+    Valid,
+    Invalid,
+    /// Too many failed attempts. The PIN can be entered again at the given
+    /// time.
+    RetryLater(DateTime<Utc>),
+}
+
+impl From<PinVerification> for UiPinVerification {
+    fn from(verification: PinVerification) -> Self {
+        match verification {
+            PinVerification::Valid => Self::Valid,
+            PinVerification::Invalid => Self::Invalid,
+            PinVerification::RetryLater(retry_at) => Self::RetryLater(retry_at),
+        }
+    }
+}
+
+#[frb(dart_metadata = ("freezed"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppLockCubitState {
+    pub lock_state: UiAppLockState,
+    pub pin_enabled: bool,
+    pub biometrics_enabled: bool,
+    pub auto_lock_timeout_secs: u32,
+    pub failed_attempts: u32,
+    /// Set while PIN attempts are rate limited
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+/// Locks the app with a PIN or biometrics.
+///
+/// While the app is locked, the other cubits of the user don't emit any states. The cubit should
+/// be loaded right after the [`UserCubitBase`], such that the app is locked before any data is
+/// shown.
+#[frb(opaque)]
+pub struct AppLockCubitBase {
+    core: CubitCore<AppLockCubitState>,
+    core_user: CoreUser,
+    app_lock: AppLockHandle,
+    settings: Mutex<AppLockSettings>,
+}
+
+impl AppLockCubitBase {
+    /// Loads the app lock settings and locks the app if the app lock is enabled.
+    pub async fn load(user_cubit: &UserCubitBase) -> Result<Self> {
+        let core_user = user_cubit.core_user.clone();
+        let settings: AppLockSettings = core_user.user_setting().await?;
+        let app_lock = user_cubit.app_lock().clone();
+        app_lock.update(|lock| *lock = AppLock::new(&settings));
+        let this = Self {
+            core: CubitCore::new(),
+            core_user,
+            app_lock,
+            settings: Mutex::new(settings),
+        };
+        this.emit(&*this.settings.lock().await);
+        Ok(this)
+    }
+
+    // Cubit interface
+
+    pub fn close(&mut self) {
+        self.core.close();
+    }
+
+    #[frb(getter, sync)]
+    pub fn is_closed(&self) -> bool {
+        self.core.is_closed()
+    }
+
+    #[frb(getter, sync)]
+    pub fn state(&self) -> AppLockCubitState {
+        self.core.state()
+    }
+
+    pub async fn stream(&mut self, sink: StreamSink<AppLockCubitState>) {
+        self.core.stream(sink).await;
+    }
+
+    // Cubit methods
+
+    /// Called by the platform when the app moves to the foreground or background
+    pub async fn app_lifecycle_changed(&self, lifecycle_state: UiAppLifecycleState) {
+        let now = Instant::now();
+        self.app_lock.update(|lock| match lifecycle_state {
+            UiAppLifecycleState::Foreground => lock.on_foreground(now),
+            UiAppLifecycleState::Background => lock.on_background(now),
+        });
+        self.emit(&*self.settings.lock().await);
+    }
+
+    /// Lock the app immediately
+    pub async fn lock(&self) {
+        self.app_lock.update(AppLock::lock);
+        self.emit(&*self.settings.lock().await);
+    }
+
+    pub async fn unlock_with_pin(&self, pin: String) -> Result<UiPinVerification> {
+        let mut settings = self.settings.lock().await;
+        let verification = settings.verify_pin(&pin, Utc::now());
+        self.core_user.set_user_setting(&*settings).await?;
+        if verification == PinVerification::Valid {
+            self.app_lock.update(AppLock::unlock);
+        }
+        self.emit(&settings);
+        Ok(verification.into())
+    }
+
+    /// Unlock the app by unlocking the biometric-protected master key, which prompts the user for
+    /// biometric authentication.
+    pub async fn unlock_with_biometrics(&self, vault: &MasterKeyVault) -> Result<()> {
+        let settings = self.settings.lock().await;
+        if !settings.biometrics_enabled {
+            bail!("Biometric unlock is not enabled");
+        }
+        if !matches!(
+            vault.protection().await?,
+            Some(UiKeyProtection::HardwareBiometric)
+        ) {
+            bail!("Master key is not protected by biometrics");
+        }
+        // Force a new biometric prompt
+        vault.lock().await;
+        vault.unlock().await?;
+        self.app_lock.update(AppLock::unlock);
+        self.emit(&settings);
+        Ok(())
+    }
+
+    /// Set or change the PIN. Enables the app lock.
+    pub async fn set_pin(&self, pin: String) -> Result<()> {
+        let mut settings = self.settings.lock().await;
+        if self.app_lock.state() == AppLockState::Locked {
+            bail!("App is locked");
+        }
+        settings.set_pin(&pin)?;
+        self.core_user.set_user_setting(&*settings).await?;
+        self.app_lock.update(|lock| lock.set_enabled(true));
+        self.emit(&settings);
+        Ok(())
+    }
+
+    /// Remove the PIN, which disables the app lock including the biometric unlock. Requires the
+    /// current PIN.
+    pub async fn remove_pin(
+        &self,
+        current_pin: String,
+        vault: &MasterKeyVault,
+    ) -> Result<UiPinVerification> {
+        let mut settings = self.settings.lock().await;
+        let verification = settings.verify_pin(&current_pin, Utc::now());
+        if verification == PinVerification::Valid {
+            if settings.biometrics_enabled {
+                vault.set_biometric_unlock(false).await?;
+            }
+            settings.remove_pin();
+            self.app_lock.update(|lock| lock.set_enabled(false));
+        }
+        self.core_user.set_user_setting(&*settings).await?;
+        self.emit(&settings);
+        Ok(verification.into())
+    }
+
+    /// Enable or disable the biometric unlock. Requires a PIN as fallback and an unlocked vault.
+    ///
+    /// Fails if the biometric unlock should be enabled, but the platform has no hardware key store
+    /// or the user didn't pass the biometric prompt. Returns whether the biometric unlock is in the
+    /// requested state.
+    pub async fn set_biometrics_enabled(
+        &self,
+        enabled: bool,
+        vault: &MasterKeyVault,
+    ) -> Result<bool> {
+        let mut settings = self.settings.lock().await;
+        if enabled && !settings.is_enabled() {
+            bail!("Biometric unlock requires a PIN");
+        }
+        let protection = vault.set_biometric_unlock(enabled).await?;
+        settings.biometrics_enabled = matches!(protection, UiKeyProtection::HardwareBiometric);
+        self.core_user.set_user_setting(&*settings).await?;
+        self.emit(&settings);
+        Ok(settings.biometrics_enabled == enabled)
+    }
+
+    /// Set the time the app can stay in the background before it is locked
+    pub async fn set_auto_lock_timeout(&self, timeout_secs: u32) -> Result<()> {
+        let mut settings = self.settings.lock().await;
+        settings.auto_lock_timeout_secs = timeout_secs;
+        self.core_user.set_user_setting(&*settings).await?;
+        self.app_lock
+            .update(|lock| lock.set_auto_lock_timeout(Duration::from_secs(timeout_secs.into())));
+        self.emit(&settings);
+        Ok(())
+    }
+
+    fn emit(&self, settings: &AppLockSettings) {
+        let state = AppLockCubitState {
+            lock_state: self.app_lock.state().into(),
+            pin_enabled: settings.is_enabled(),
+            biometrics_enabled: settings.biometrics_enabled,
+            auto_lock_timeout_secs: settings.auto_lock_timeout_secs,
+            failed_attempts: settings.failed_attempts,
+            retry_at: settings.retry_at.filter(|retry_at| *retry_at > Utc::now()),
+        };
+        self.core.state_tx().send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::Result;
+use phnxcoreclient::{BroadcastListId, MimiContent};
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+use uuid::Uuid;
+
+use super::{
+    types::{UiBroadcastDelivery, UiBroadcastList},
+    user::User,
+};
+
+impl User {
+    pub async fn broadcast_lists(&self) -> Result<Vec<UiBroadcastList>> {
+        let lists = self.user.broadcast_lists().await?;
+        Ok(lists.into_iter().map(|l| l.into()).collect())
+    }
+
+    pub async fn create_broadcast_list(
+        &self,
+        name: String,
+        recipients: Vec<String>,
+    ) -> Result<UiBroadcastList> {
+        let recipients = parse_user_names(recipients)?;
+        let list = self.user.create_broadcast_list(name, recipients).await?;
+        Ok(list.into())
+    }
+
+    pub async fn update_broadcast_list(
+        &self,
+        list_id: Uuid,
+        name: String,
+        recipients: Vec<String>,
+    ) -> Result<UiBroadcastList> {
+        let recipients = parse_user_names(recipients)?;
+        let list = self
+            .user
+            .update_broadcast_list(list_id.into(), name, recipients)
+            .await?;
+        Ok(list.into())
+    }
+
+    pub async fn delete_broadcast_list(&self, list_id: Uuid) -> Result<()> {
+        self.user.delete_broadcast_list(list_id.into()).await
+    }
+
+    /// Send the given message to all recipients of the broadcast list. The
+    /// delivery is reported per recipient.
+    pub async fn send_broadcast(
+        &self,
+        list_id: Uuid,
+        message: String,
+    ) -> Result<Vec<UiBroadcastDelivery>> {
+        let content = MimiContent::simple_markdown_message(self.user.user_name().domain(), message);
+        let deliveries = self
+            .user
+            .send_broadcast(BroadcastListId::from(list_id), content)
+            .await?;
+        Ok(deliveries.into_iter().map(|d| d.into()).collect())
+    }
+
+    pub async fn broadcast_deliveries(
+        &self,
+        broadcast_id: Uuid,
+    ) -> Result<Vec<UiBroadcastDelivery>> {
+        let deliveries = self.user.broadcast_deliveries(broadcast_id).await?;
+        Ok(deliveries.into_iter().map(|d| d.into()).collect())
+    }
+}
+
+fn parse_user_names(user_names: Vec<String>) -> Result<Vec<QualifiedUserName>> {
+    let user_names = user_names
+        .into_iter()
+        .map(<String as SafeTryInto<QualifiedUserName>>::try_into)
+        .collect::<Result<Vec<QualifiedUserName>, _>>()?;
+    Ok(user_names)
+}
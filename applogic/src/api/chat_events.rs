@@ -0,0 +1,403 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Typed lifecycle events of a chat.
+//!
+//! Instead of interpreting system messages, the UI subscribes to the
+//! [`ChatEvent`]s of a chat. Membership and policy changes are derived from
+//! the system messages of the chat. Creation, attribute changes and expiry
+//! are derived by comparing the chat before and after each change
+//! notification of the store.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use phnxcoreclient::{
+    clients::CoreUser,
+    store::{Store, StoreNotifications},
+    Conversation, ConversationId, ConversationMessage, ConversationStatus, EventMessage, Message,
+    NotificationType, SystemMessage,
+};
+use phnxtypes::messages::room_policy::{RoomPolicy, RoomPolicyChange};
+use tokio::sync::broadcast;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::util::{spawn_from_sync, AppEvent, EventBusReceiver};
+use crate::StreamSink;
+
+use super::{
+    messages::FetchedMessages,
+    types::{UiConversationAttributes, UiRoomPolicyChange},
+    user::user_cubit::UserCubitBase,
+};
+
+/// A change in the lifecycle of a chat
+///
+/// User names are fully qualified. If the actor and the target of a
+/// membership change are the same, the user joined or left by themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatEvent {
+    /// The chat was created or the own user joined it
+    Created {
+        title: String,
+    },
+    /// The title or the picture of the chat changed
+    AttributesChanged {
+        old: UiConversationAttributes,
+        new: UiConversationAttributes,
+    },
+    MemberAdded {
+        actor: String,
+        target: String,
+    },
+    MemberRemoved {
+        actor: String,
+        target: String,
+    },
+    /// An admin changed a rule of the room policy
+    ///
+    /// `old` is the previous value of the changed rule, if it is known.
+    PolicyChanged {
+        actor: String,
+        old: Option<UiRoomPolicyChange>,
+        new: UiRoomPolicyChange,
+    },
+    /// The chat became inactive, e.g. because it was deleted or the own user
+    /// was removed. Only its past members are known from now on.
+    Expired {
+        past_members: Vec<String>,
+    },
+}
+
+impl UserCubitBase {
+    /// Streams the lifecycle events of the given chat
+    ///
+    /// Only changes that happen after subscribing are emitted.
+    pub fn stream_chat_events(&self, conversation_id: ConversationId, sink: StreamSink<ChatEvent>) {
+        let core_user = self.core_user.clone();
+        let store_rx = Store::subscribe(&core_user);
+        let events_rx = self.subscribe_to_events();
+        spawn_from_sync(async move {
+            let context = ChatEventsContext::load(core_user, conversation_id).await;
+            context.run(store_rx, events_rx, sink).await;
+        });
+    }
+}
+
+/// The state of a chat that lifecycle events are derived from
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChatSnapshot {
+    attributes: UiConversationAttributes,
+    /// `Some` if the chat is inactive
+    past_members: Option<Vec<String>>,
+}
+
+impl From<&Conversation> for ChatSnapshot {
+    fn from(conversation: &Conversation) -> Self {
+        let past_members = match conversation.status() {
+            ConversationStatus::Active => None,
+            ConversationStatus::Inactive(inactive) => Some(
+                inactive
+                    .past_members()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            ),
+        };
+        Self {
+            attributes: conversation.attributes().clone().into(),
+            past_members,
+        }
+    }
+}
+
+struct ChatEventsContext {
+    core_user: CoreUser,
+    conversation_id: ConversationId,
+    subscribed_at: DateTime<Utc>,
+    /// `None` if the chat doesn't exist (yet)
+    chat: Option<ChatSnapshot>,
+    /// `None` if the chat has no room policy
+    room_policy: Option<RoomPolicy>,
+    /// Messages are received from the store as well as with the fetched
+    /// messages, but must result in a single event.
+    seen_messages: HashSet<Uuid>,
+}
+
+impl ChatEventsContext {
+    async fn load(core_user: CoreUser, conversation_id: ConversationId) -> Self {
+        let subscribed_at = Utc::now();
+        let chat = core_user
+            .conversation(&conversation_id)
+            .await
+            .map(|conversation| ChatSnapshot::from(&conversation));
+        let room_policy = core_user
+            .room_policy(conversation_id)
+            .await
+            .ok()
+            .map(|(room_policy, _)| room_policy);
+        Self {
+            core_user,
+            conversation_id,
+            subscribed_at,
+            chat,
+            room_policy,
+            seen_messages: HashSet::new(),
+        }
+    }
+
+    /// Returns when the sink or the store is closed
+    async fn run(
+        mut self,
+        mut store_rx: StoreNotifications,
+        mut events_rx: EventBusReceiver,
+        sink: StreamSink<ChatEvent>,
+    ) {
+        loop {
+            let notifications = tokio::select! {
+                notification = store_rx.recv() => match notification {
+                    Some(notification) => vec![notification],
+                    None => return,
+                },
+                event = events_rx.recv() => match event {
+                    Ok(AppEvent::Store(notification)) => vec![notification],
+                    Ok(AppEvent::Queue(fetched_messages)) => {
+                        fetched_notifications(&fetched_messages)
+                    }
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!(n, "events lagged");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            for notification in notifications {
+                for event in self.handle_notification(notification).await {
+                    if sink.add(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_notification(&mut self, notification: NotificationType) -> Vec<ChatEvent> {
+        match notification {
+            NotificationType::ConversationChange(conversation_id)
+                if conversation_id == self.conversation_id =>
+            {
+                let Some(conversation) = self.core_user.conversation(&conversation_id).await else {
+                    return Vec::new();
+                };
+                let chat = ChatSnapshot::from(&conversation);
+                let events = chat_events(self.chat.as_ref(), &chat);
+                self.chat = Some(chat);
+                events
+            }
+            NotificationType::Message(message) => self.handle_message(&message),
+            _ => Vec::new(),
+        }
+    }
+
+    fn handle_message(&mut self, message: &ConversationMessage) -> Vec<ChatEvent> {
+        if message.conversation_id() != self.conversation_id
+            || message.received_at() < self.subscribed_at
+            || !self.seen_messages.insert(message.id().to_uuid())
+        {
+            return Vec::new();
+        }
+        let Message::Event(EventMessage::System(system_message)) = message.message() else {
+            return Vec::new();
+        };
+        system_message_events(system_message, self.room_policy.as_mut())
+    }
+}
+
+fn fetched_notifications(fetched_messages: &FetchedMessages) -> Vec<NotificationType> {
+    let FetchedMessages {
+        new_conversations,
+        changed_conversations,
+        new_messages,
+        notifications_content: _,
+    } = fetched_messages;
+    new_conversations
+        .iter()
+        .chain(changed_conversations)
+        .map(|conversation_id| NotificationType::ConversationChange(*conversation_id))
+        .chain(new_messages.iter().cloned().map(NotificationType::Message))
+        .collect()
+}
+
+/// Events between two states of a chat. `old` is `None` if the chat didn't
+/// exist before.
+fn chat_events(old: Option<&ChatSnapshot>, new: &ChatSnapshot) -> Vec<ChatEvent> {
+    let mut events = Vec::new();
+    let Some(old) = old else {
+        events.push(ChatEvent::Created {
+            title: new.attributes.title.clone(),
+        });
+        return events;
+    };
+    if old.attributes != new.attributes {
+        events.push(ChatEvent::AttributesChanged {
+            old: old.attributes.clone(),
+            new: new.attributes.clone(),
+        });
+    }
+    if let (None, Some(past_members)) = (&old.past_members, &new.past_members) {
+        events.push(ChatEvent::Expired {
+            past_members: past_members.clone(),
+        });
+    }
+    events
+}
+
+/// The events of a system message, one per added or removed member. Policy
+/// changes are applied to the given room policy, such that it stays up to
+/// date.
+fn system_message_events(
+    system_message: &SystemMessage,
+    room_policy: Option<&mut RoomPolicy>,
+) -> Vec<ChatEvent> {
+    match system_message {
+        SystemMessage::Add { adder, added } => added
+            .iter()
+            .map(|added| ChatEvent::MemberAdded {
+                actor: adder.to_string(),
+                target: added.to_string(),
+            })
+            .collect(),
+        SystemMessage::Remove { remover, removed } => removed
+            .iter()
+            .map(|removed| ChatEvent::MemberRemoved {
+                actor: remover.to_string(),
+                target: removed.to_string(),
+            })
+            .collect(),
+        SystemMessage::ChangeRoomPolicy { admin, change } => {
+            let old = room_policy.map(|room_policy| {
+                let old = current_rule(room_policy, change);
+                room_policy.apply(*change);
+                old.into()
+            });
+            vec![ChatEvent::PolicyChanged {
+                actor: admin.to_string(),
+                old,
+                new: (*change).into(),
+            }]
+        }
+        SystemMessage::PendingChangeApplied | SystemMessage::PendingChangeDiscarded => Vec::new(),
+    }
+}
+
+/// The current value of the rule that the given change changes
+fn current_rule(room_policy: &RoomPolicy, change: &RoomPolicyChange) -> RoomPolicyChange {
+    match change {
+        RoomPolicyChange::SetJoinRule(_) => RoomPolicyChange::SetJoinRule(room_policy.join_rule),
+        RoomPolicyChange::SetInviteRule(_) => {
+            RoomPolicyChange::SetInviteRule(room_policy.invite_rule)
+        }
+        RoomPolicyChange::SetSendRule(_) => RoomPolicyChange::SetSendRule(room_policy.send_rule),
+        RoomPolicyChange::SetCommitRule(_) => {
+            RoomPolicyChange::SetCommitRule(room_policy.commit_rule)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        identifiers::{QualifiedUserName, SafeTryInto},
+        messages::room_policy::JoinRule,
+    };
+
+    use crate::api::types::UiJoinRule;
+
+    use super::*;
+
+    fn snapshot(title: &str, past_members: Option<Vec<String>>) -> ChatSnapshot {
+        ChatSnapshot {
+            attributes: UiConversationAttributes {
+                title: title.to_owned(),
+                conversation_picture_option: None,
+            },
+            past_members,
+        }
+    }
+
+    #[test]
+    fn chat_events_from_snapshots() {
+        let chat = snapshot("Chat", None);
+        assert_eq!(
+            chat_events(None, &chat),
+            vec![ChatEvent::Created {
+                title: "Chat".to_owned()
+            }]
+        );
+        assert_eq!(chat_events(Some(&chat), &chat), vec![]);
+
+        let past_members = vec!["alice@example.com".to_owned()];
+        let renamed = snapshot("Renamed", Some(past_members.clone()));
+        assert_eq!(
+            chat_events(Some(&chat), &renamed),
+            vec![
+                ChatEvent::AttributesChanged {
+                    old: chat.attributes.clone(),
+                    new: renamed.attributes.clone(),
+                },
+                ChatEvent::Expired { past_members },
+            ]
+        );
+    }
+
+    #[test]
+    fn chat_events_from_system_messages() {
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let carol: QualifiedUserName = SafeTryInto::try_into("carol@example.com").unwrap();
+
+        let added = SystemMessage::Add {
+            adder: alice.clone(),
+            added: vec![bob.clone(), carol.clone()],
+        };
+        assert_eq!(
+            system_message_events(&added, None),
+            vec![
+                ChatEvent::MemberAdded {
+                    actor: alice.to_string(),
+                    target: bob.to_string(),
+                },
+                ChatEvent::MemberAdded {
+                    actor: alice.to_string(),
+                    target: carol.to_string(),
+                },
+            ]
+        );
+
+        let mut room_policy = RoomPolicy::default();
+        let change = RoomPolicyChange::SetJoinRule(JoinRule::Knock);
+        let policy_changed = SystemMessage::ChangeRoomPolicy {
+            admin: alice.clone(),
+            change,
+        };
+        assert_eq!(
+            system_message_events(&policy_changed, Some(&mut room_policy)),
+            vec![ChatEvent::PolicyChanged {
+                actor: alice.to_string(),
+                old: Some(UiRoomPolicyChange::SetJoinRule(UiJoinRule::from(
+                    RoomPolicy::default().join_rule
+                ))),
+                new: UiRoomPolicyChange::SetJoinRule(UiJoinRule::Knock),
+            }]
+        );
+        assert_eq!(room_policy.join_rule, JoinRule::Knock);
+
+        assert_eq!(
+            system_message_events(&SystemMessage::PendingChangeApplied, None),
+            vec![]
+        );
+    }
+}
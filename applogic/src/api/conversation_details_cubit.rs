@@ -2,20 +2,26 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use anyhow::anyhow;
 use flutter_rust_bridge::frb;
 use phnxcoreclient::clients::CoreUser;
-use phnxcoreclient::ConversationId;
+use phnxcoreclient::{ChatColor, ChatMetadataKey, ConversationId, NotificationType};
 use phnxtypes::identifiers::SafeTryInto;
 use tokio::sync::{broadcast, watch};
 use tokio_util::sync::CancellationToken;
 use tracing::{error, warn};
 
-use crate::util::{spawn_from_sync, Cubit, CubitCore};
+use crate::app_state::chat_notifications::ChatNotificationSettingsMap;
+use crate::app_state::screen_security::{OpenConversationGuard, ScreenSecurity};
+use crate::util::{spawn_from_sync, AppEvent, Cubit, CubitCore, EventBus, EventBusReceiver};
 use crate::StreamSink;
 
 use super::conversations::converation_into_ui_details;
-use super::messages::{FetchedMessages, FetchedMessagesBroadcast, FetchedMessagesReceiver};
-use super::types::{UiConversationDetails, UiConversationType, UiUserProfile};
+use super::messages::FetchedMessages;
+use super::types::{
+    ChatSecurityStatus, UiChatNotificationSettings, UiConversationDetails, UiConversationType,
+    UiUserProfile,
+};
 use super::user::user_cubit::UserCubitBase;
 
 #[frb(dart_metadata = ("freezed"))]
@@ -30,22 +36,27 @@ pub struct ConversationDetailsCubitBase {
     core: CubitCore<ConversationDetailsState>,
     conversation_id: ConversationId,
     core_user: CoreUser,
-    fetched_messages_tx: FetchedMessagesBroadcast,
+    event_bus: EventBus,
+    _open_guard: OpenConversationGuard,
 }
 
 impl ConversationDetailsCubitBase {
     #[frb(sync)]
     pub fn new(user_cubit: &UserCubitBase, conversation_id: ConversationId) -> Self {
         let core_user = user_cubit.core_user.clone();
-        let core = CubitCore::new();
+        let core = CubitCore::with_app_lock(user_cubit.app_lock());
+
+        let screen_security = user_cubit.screen_security().clone();
+        let open_guard = screen_security.open(conversation_id);
 
         ConversationDetailsContext::new(
             core_user.clone(),
             core.state_tx().clone(),
             conversation_id,
+            screen_security,
         )
         .spawn(
-            user_cubit.subscribe_to_fetched_messages(),
+            user_cubit.subscribe_to_events(),
             core.cancellation_token().clone(),
         );
 
@@ -53,7 +64,8 @@ impl ConversationDetailsCubitBase {
             core,
             conversation_id,
             core_user,
-            fetched_messages_tx: user_cubit.fetched_messages_tx().clone(),
+            event_bus: user_cubit.event_bus().clone(),
+            _open_guard: open_guard,
         }
     }
 
@@ -83,15 +95,120 @@ impl ConversationDetailsCubitBase {
         self.core_user
             .set_conversation_picture(self.conversation_id, bytes.clone())
             .await?;
-        self.fetched_messages_tx
-            .send(FetchedMessages {
-                changed_conversations: vec![self.conversation_id],
-                ..Default::default()
-            })
-            .await;
+        self.event_bus
+            .publish(AppEvent::Store(NotificationType::ConversationChange(
+                self.conversation_id,
+            )));
+        Ok(())
+    }
+
+    /// Mark the conversation as sensitive or not
+    ///
+    /// Notifications of sensitive conversations don't show any content. While a sensitive
+    /// conversation is open, the screen should be protected, see
+    /// [`UserCubitBase::stream_screen_security`].
+    pub async fn set_sensitive(&self, sensitive: bool) -> anyhow::Result<()> {
+        self.core_user
+            .set_conversation_sensitive(self.conversation_id, sensitive)
+            .await?;
+        self.event_bus
+            .publish(AppEvent::Store(NotificationType::ConversationChange(
+                self.conversation_id,
+            )));
+        Ok(())
+    }
+
+    /// Set the color of the conversation in RGB, which is shared with the other members
+    ///
+    /// Removes the color if `color` is `None`.
+    pub async fn set_chat_color(&self, color: Option<u32>) -> anyhow::Result<()> {
+        let color = color
+            .map(|rgb| ChatColor::from_rgb(rgb).ok_or_else(|| anyhow!("Invalid color {rgb:#x}")))
+            .transpose()?;
+        self.core_user
+            .set_chat_color(self.conversation_id, color)
+            .await?;
+        self.publish_conversation_change();
+        Ok(())
+    }
+
+    /// Set the emoji of the conversation, which is shared with the other members
+    ///
+    /// Removes the emoji if `emoji` is `None`.
+    pub async fn set_chat_emoji(&self, emoji: Option<String>) -> anyhow::Result<()> {
+        self.core_user
+            .set_chat_emoji(self.conversation_id, emoji)
+            .await?;
+        self.publish_conversation_change();
+        Ok(())
+    }
+
+    /// The value of the custom metadata entry of the conversation with the given key
+    pub async fn chat_metadata(
+        &self,
+        namespace: String,
+        name: String,
+    ) -> anyhow::Result<Option<String>> {
+        let key = ChatMetadataKey::new(namespace, name)?;
+        let metadata = self.core_user.chat_metadata(self.conversation_id).await?;
+        Ok(metadata.get(&key).map(ToOwned::to_owned))
+    }
+
+    /// Set the custom metadata entry of the conversation with the given key
+    ///
+    /// Entries in the `shared` namespace are shared with the other members, entries in all other
+    /// namespaces are only stored locally. Removes the entry if `value` is `None`.
+    pub async fn set_chat_metadata(
+        &self,
+        namespace: String,
+        name: String,
+        value: Option<String>,
+    ) -> anyhow::Result<()> {
+        let key = ChatMetadataKey::new(namespace, name)?;
+        self.core_user
+            .set_chat_metadata(self.conversation_id, key, value)
+            .await?;
+        self.publish_conversation_change();
         Ok(())
     }
 
+    fn publish_conversation_change(&self) {
+        self.event_bus
+            .publish(AppEvent::Store(NotificationType::ConversationChange(
+                self.conversation_id,
+            )));
+    }
+
+    /// The notification alert customization of the conversation
+    pub async fn notification_settings(&self) -> anyhow::Result<UiChatNotificationSettings> {
+        let settings: ChatNotificationSettingsMap = self.core_user.user_setting().await?;
+        Ok(settings
+            .get(self.conversation_id)
+            .map(From::from)
+            .unwrap_or_default())
+    }
+
+    /// Customize the notification alerts of the conversation
+    ///
+    /// Resets the alerts to the defaults if `settings` is `None`.
+    pub async fn set_notification_settings(
+        &self,
+        settings: Option<UiChatNotificationSettings>,
+    ) -> anyhow::Result<()> {
+        let mut map: ChatNotificationSettingsMap = self.core_user.user_setting().await?;
+        map.set(self.conversation_id, settings.map(From::from));
+        self.core_user.set_user_setting(&map).await
+    }
+
+    /// Load the encryption health of the conversation
+    pub async fn load_security_status(&self) -> anyhow::Result<ChatSecurityStatus> {
+        let status = self
+            .core_user
+            .conversation_security_status(self.conversation_id)
+            .await?;
+        Ok(status.into())
+    }
+
     /// Load user profile of the conversation (only for non-group conversations)
     pub async fn load_conversation_user_profile(&self) -> anyhow::Result<Option<UiUserProfile>> {
         let conversation_type = self
@@ -120,6 +237,7 @@ struct ConversationDetailsContext {
     core_user: CoreUser,
     state_tx: watch::Sender<ConversationDetailsState>,
     conversation_id: ConversationId,
+    screen_security: ScreenSecurity,
 }
 
 impl ConversationDetailsContext {
@@ -127,24 +245,27 @@ impl ConversationDetailsContext {
         core_user: CoreUser,
         state_tx: watch::Sender<ConversationDetailsState>,
         conversation_id: ConversationId,
+        screen_security: ScreenSecurity,
     ) -> Self {
         Self {
             core_user,
             state_tx,
             conversation_id,
+            screen_security,
         }
     }
 
-    fn spawn(self, fetched_messages_rx: FetchedMessagesReceiver, stop: CancellationToken) {
+    fn spawn(self, events_rx: EventBusReceiver, stop: CancellationToken) {
         spawn_from_sync(async move {
             self.load_and_emit_state().await;
-            self.fetched_messages_listen_loop(fetched_messages_rx, stop)
-                .await;
+            self.events_listen_loop(events_rx, stop).await;
         });
     }
 
     async fn load_and_emit_state(&self) -> Option<()> {
         let details = self.load_conversation_details().await?;
+        self.screen_security
+            .set_sensitive(self.conversation_id, details.sensitive);
         let members = self
             .members_of_conversation()
             .await
@@ -159,7 +280,11 @@ impl ConversationDetailsContext {
 
     async fn load_conversation_details(&self) -> Option<UiConversationDetails> {
         let conversation = self.core_user.conversation(&self.conversation_id).await?;
-        Some(converation_into_ui_details(&self.core_user, conversation).await)
+        let unread_messages = self
+            .core_user
+            .unread_messages_count(self.conversation_id)
+            .await;
+        Some(converation_into_ui_details(&self.core_user, conversation, unread_messages).await)
     }
 
     async fn members_of_conversation(&self) -> anyhow::Result<Vec<String>> {
@@ -174,21 +299,27 @@ impl ConversationDetailsContext {
     }
 
     /// Returns only when `stop` is cancelled
-    async fn fetched_messages_listen_loop(
-        self,
-        mut fetched_messages_rx: FetchedMessagesReceiver,
-        stop: CancellationToken,
-    ) {
+    async fn events_listen_loop(self, mut events_rx: EventBusReceiver, stop: CancellationToken) {
         loop {
             let res = tokio::select! {
-                res = fetched_messages_rx.recv() => res,
+                res = events_rx.recv() => res,
                 _ = stop.cancelled() => return,
             };
             match res {
-                Ok(fetched_messages) => self.handle_fetched_messages(&fetched_messages).await,
+                Ok(AppEvent::Queue(fetched_messages)) => {
+                    self.handle_fetched_messages(&fetched_messages).await
+                }
+                Ok(AppEvent::Store(NotificationType::ConversationChange(conversation_id)))
+                    if conversation_id == self.conversation_id =>
+                {
+                    self.load_and_emit_state().await
+                }
+                Ok(_) => {}
                 Err(broadcast::error::RecvError::Closed) => return,
                 Err(broadcast::error::RecvError::Lagged(n)) => {
-                    warn!(n, "fetched messages lagged");
+                    // Missed changes can't be replayed, so reload the state
+                    warn!(n, "events lagged");
+                    self.load_and_emit_state().await;
                 }
             }
         }
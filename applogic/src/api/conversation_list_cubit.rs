@@ -4,23 +4,28 @@
 
 use flutter_rust_bridge::frb;
 use phnxcoreclient::clients::CoreUser;
-use phnxcoreclient::ConversationId;
+use phnxcoreclient::{ConversationId, NotificationType};
 use tokio::sync::{broadcast, watch};
 use tokio_util::sync::CancellationToken;
 use tracing::error;
 
 use crate::api::user::User;
-use crate::util::{spawn_from_sync, Cubit, CubitCore};
+use crate::util::{spawn_from_sync, AppEvent, Cubit, CubitCore, EventBusReceiver};
 use crate::StreamSink;
 
-use super::messages::{FetchedMessages, FetchedMessagesReceiver};
-use super::types::UiConversationDetails;
+use super::messages::FetchedMessages;
+use super::types::{
+    UiAnnouncement, UiAnnouncementsEntry, UiChatCreationConfig, UiConversationDetails,
+};
 use super::user::user_cubit::UserCubitBase;
 
 #[frb(dart_metadata = ("freezed"))]
 #[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
 pub struct ConversationListState {
     pub conversations: Vec<UiConversationDetails>,
+    /// Entry of the announcements of the server operator, shown as a system
+    /// chat. `None` if there are no announcements.
+    pub announcements: Option<UiAnnouncementsEntry>,
 }
 
 #[frb(opaque)]
@@ -33,11 +38,11 @@ impl ConversationListCubitBase {
     #[frb(sync)]
     pub fn new(user_cubit: &UserCubitBase) -> Self {
         let core_user = user_cubit.core_user.clone();
-        let core = CubitCore::new();
+        let core = CubitCore::with_app_lock(user_cubit.app_lock());
 
         let context = ConversationListContext::new(core_user.clone(), core.state_tx().clone());
         context.clone().spawn(
-            user_cubit.subscribe_to_fetched_messages(),
+            user_cubit.subscribe_to_events(),
             core.cancellation_token().clone(),
         );
 
@@ -81,6 +86,39 @@ impl ConversationListCubitBase {
         self.context.load_and_emit_state().await;
         Ok(id)
     }
+
+    /// Creates a conversation with the given initial permissions
+    pub async fn create_conversation_with_config(
+        &self,
+        group_name: String,
+        config: UiChatCreationConfig,
+    ) -> anyhow::Result<ConversationId> {
+        let id = self
+            .context
+            .core_user
+            .create_chat(&group_name, None, config.into())
+            .await?;
+        self.context.load_and_emit_state().await;
+        Ok(id)
+    }
+
+    /// Announcements of the server operator, newest first
+    pub async fn announcements(&self) -> anyhow::Result<Vec<UiAnnouncement>> {
+        let announcements = self.context.core_user.announcements().await?;
+        Ok(announcements.into_iter().map(From::from).collect())
+    }
+
+    pub async fn mark_announcements_as_read(&self) -> anyhow::Result<()> {
+        self.context.core_user.mark_announcements_as_read().await?;
+        self.context.load_and_emit_announcements().await;
+        Ok(())
+    }
+
+    /// Mark all messages in all conversations as read, also on the other
+    /// devices of the user
+    pub async fn mark_all_as_read(&self) -> anyhow::Result<()> {
+        self.context.core_user.mark_all_as_read().await
+    }
 }
 
 /// Loads the intial state and listen to the changes
@@ -99,11 +137,11 @@ impl ConversationListContext {
         }
     }
 
-    fn spawn(self, fetched_messages_rx: FetchedMessagesReceiver, stop: CancellationToken) {
+    fn spawn(self, events_rx: EventBusReceiver, stop: CancellationToken) {
         spawn_from_sync(async move {
             self.load_and_emit_state().await;
-            self.fetched_messages_listen_loop(fetched_messages_rx, stop)
-                .await;
+            self.load_and_emit_announcements().await;
+            self.events_listen_loop(events_rx, stop).await;
         });
     }
 
@@ -114,22 +152,44 @@ impl ConversationListContext {
             .send_modify(|state| state.conversations = conversations);
     }
 
-    async fn fetched_messages_listen_loop(
-        self,
-        mut rx: FetchedMessagesReceiver,
-        stop: CancellationToken,
-    ) {
+    async fn load_and_emit_announcements(&self) {
+        let announcements: Vec<UiAnnouncement> = match self.core_user.announcements().await {
+            Ok(announcements) => announcements.into_iter().map(From::from).collect(),
+            Err(error) => {
+                error!(%error, "Failed to load announcements");
+                return;
+            }
+        };
+        let entry = UiAnnouncementsEntry::new(&announcements);
+        self.state_tx.send_if_modified(|state| {
+            let modified = state.announcements != entry;
+            state.announcements = entry;
+            modified
+        });
+    }
+
+    async fn events_listen_loop(self, mut rx: EventBusReceiver, stop: CancellationToken) {
         loop {
             let res = tokio::select! {
                 _ = stop.cancelled() => return,
                 res = rx.recv() => res,
             };
             match res {
-                Ok(fetched_messages) => {
+                Ok(AppEvent::Queue(fetched_messages)) => {
                     self.process_fetches_messages(&fetched_messages).await;
                 }
+                Ok(AppEvent::Store(NotificationType::ConversationChange(_))) => {
+                    self.load_and_emit_state().await;
+                }
+                Ok(AppEvent::Announcements) => {
+                    self.load_and_emit_announcements().await;
+                }
+                Ok(_) => {}
                 Err(broadcast::error::RecvError::Lagged(n)) => {
-                    error!(n, "Fetch messages lagged");
+                    // Missed changes can't be replayed, so reload everything
+                    error!(n, "Events lagged");
+                    self.load_and_emit_state().await;
+                    self.load_and_emit_announcements().await;
                 }
                 Err(broadcast::error::RecvError::Closed) => return,
             }
@@ -5,11 +5,15 @@
 use anyhow::{anyhow, Result};
 use phnxcoreclient::{clients::CoreUser, Conversation, ConversationId};
 use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+use tracing::error;
 
 use crate::notifier::dispatch_message_notifications;
 
 use super::{
-    types::{UiContact, UiConversation, UiConversationDetails, UiConversationMessage},
+    types::{
+        UiContact, UiConversation, UiConversationDetails, UiConversationMessage, UiJoinRequest,
+        UiRoomPolicy, UiRoomPolicyChange,
+    },
     user::User,
 };
 
@@ -26,9 +30,16 @@ impl User {
 
     pub async fn get_conversation_details(&self) -> Vec<UiConversationDetails> {
         let conversations = self.user.conversations().await.unwrap_or_default();
+        // The counters of all conversations are loaded at once.
+        let counters = self.user.load_counters().await.unwrap_or_else(|error| {
+            error!(%error, "Failed to load counters");
+            Default::default()
+        });
         let mut conversation_details = Vec::with_capacity(conversations.len());
         for conversation in conversations {
-            let details = converation_into_ui_details(&self.user, conversation).await;
+            let unread_messages = counters.chat(conversation.id()).unread;
+            let details =
+                converation_into_ui_details(&self.user, conversation, unread_messages).await;
             conversation_details.push(details);
         }
         // Sort the conversations by last used timestamp in descending order
@@ -74,6 +85,76 @@ impl User {
         Ok(())
     }
 
+    /// Get the policy of the conversation with the given
+    /// [`phnxcoreclient::ConversationId`].
+    pub async fn room_policy(&self, conversation_id: ConversationId) -> Result<UiRoomPolicy> {
+        let (room_policy, can_edit) = self.user.room_policy(conversation_id).await?;
+        Ok(UiRoomPolicy::new(room_policy, can_edit))
+    }
+
+    /// Change the policy of the conversation with the given
+    /// [`phnxcoreclient::ConversationId`]. Fails if the user is not an admin
+    /// of the conversation.
+    pub async fn update_room_policy(
+        &self,
+        conversation_id: ConversationId,
+        changes: Vec<UiRoomPolicyChange>,
+    ) -> Result<()> {
+        let conversation_messages = self
+            .user
+            .update_room_policy(
+                conversation_id,
+                changes.into_iter().map(|change| change.into()).collect(),
+            )
+            .await?;
+        dispatch_message_notifications(&self.notification_hub, conversation_messages).await;
+        Ok(())
+    }
+
+    /// Get a token that allows non-members to ask to join the conversation
+    /// with the given [`phnxcoreclient::ConversationId`].
+    pub async fn join_request_token(&self, conversation_id: ConversationId) -> Result<Vec<u8>> {
+        self.user.join_request_token(conversation_id).await
+    }
+
+    /// Ask to join the conversation referenced by the given token.
+    pub async fn request_to_join(&self, token: Vec<u8>) -> Result<()> {
+        self.user.request_to_join(&token).await
+    }
+
+    pub async fn pending_join_requests(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Vec<UiJoinRequest>> {
+        let join_requests = self.user.pending_join_requests(conversation_id).await?;
+        Ok(join_requests.into_iter().map(|r| r.into()).collect())
+    }
+
+    pub async fn approve_join_request(
+        &self,
+        conversation_id: ConversationId,
+        user_name: String,
+    ) -> Result<()> {
+        let user_name = <String as SafeTryInto<QualifiedUserName>>::try_into(user_name)?;
+        let conversation_messages = self
+            .user
+            .approve_join_request(conversation_id, user_name)
+            .await?;
+        dispatch_message_notifications(&self.notification_hub, conversation_messages).await;
+        Ok(())
+    }
+
+    pub async fn decline_join_request(
+        &self,
+        conversation_id: ConversationId,
+        user_name: String,
+    ) -> Result<()> {
+        let user_name = <String as SafeTryInto<QualifiedUserName>>::try_into(user_name)?;
+        self.user
+            .decline_join_request(conversation_id, &user_name)
+            .await
+    }
+
     /// Get a list of contacts to be added to the conversation with the given
     /// [`phnxcoreclient::ConversationId`].
     pub async fn member_candidates(
@@ -107,14 +188,18 @@ impl User {
 pub(crate) async fn converation_into_ui_details(
     user: &CoreUser,
     conversation: Conversation,
+    unread_messages: u32,
 ) -> UiConversationDetails {
-    let unread_messages = user.unread_messages_count(conversation.id()).await;
     let last_message = user.last_message(conversation.id()).await.map(|m| m.into());
     let last_used = last_message
         .as_ref()
         .map(|m: &UiConversationMessage| m.timestamp.clone())
         .unwrap_or_default();
     // default is UNIX_EPOCH
+    let chat_metadata = user
+        .chat_metadata(conversation.id())
+        .await
+        .unwrap_or_default();
 
     let conversation = UiConversation::from(conversation);
     UiConversationDetails {
@@ -126,5 +211,8 @@ pub(crate) async fn converation_into_ui_details(
         attributes: conversation.attributes,
         unread_messages,
         last_message,
+        sensitive: conversation.sensitive,
+        color: chat_metadata.color().map(|color| color.rgb()),
+        emoji: chat_metadata.emoji().map(ToOwned::to_owned),
     }
 }
@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Hardware-backed protection of the device's master key.
+//!
+//! The platform key store (Secure Enclave, Android Keystore) is implemented on
+//! the Dart side and passed to Rust as callbacks. If the platform doesn't
+//! provide a hardware key store, the master key falls back to software
+//! protection, except for the biometric unlock, which fails.
+
+use anyhow::{anyhow, bail, Result};
+use flutter_rust_bridge::{frb, DartFnFuture};
+use phnxcoreclient::{KeyProtection, MasterKey, PlatformKeyStore};
+use phnxtypes::crypto::secrets::SecretBytes;
+use tokio::sync::Mutex;
+
+type WrapKeyFn = dyn Fn(Vec<u8>, bool) -> DartFnFuture<Option<Vec<u8>>> + Send + Sync;
+type UnwrapKeyFn = dyn Fn(Vec<u8>, bool) -> DartFnFuture<Option<Vec<u8>>> + Send + Sync;
+type DeleteKeyFn = dyn Fn(bool) -> DartFnFuture<bool> + Send + Sync;
+
+/// Platform key store implemented by the app.
+///
+/// The callbacks return `None` if the platform fails to wrap or unwrap the
+/// key, e.g. because the user cancelled the biometric prompt. The delete
+/// callback returns `false` if the platform fails to delete the key. The
+/// `bool` arguments select the platform key that requires biometrics.
+#[frb(opaque)]
+pub struct DartKeyStore {
+    hardware_available: bool,
+    wrap_key: Option<Box<WrapKeyFn>>,
+    unwrap_key: Option<Box<UnwrapKeyFn>>,
+    delete_key: Option<Box<DeleteKeyFn>>,
+}
+
+impl DartKeyStore {
+    #[frb(sync)]
+    pub fn new(
+        hardware_available: bool,
+        wrap_key: impl Fn(Vec<u8>, bool) -> DartFnFuture<Option<Vec<u8>>> + Send + Sync + 'static,
+        unwrap_key: impl Fn(Vec<u8>, bool) -> DartFnFuture<Option<Vec<u8>>> + Send + Sync + 'static,
+        delete_key: impl Fn(bool) -> DartFnFuture<bool> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            hardware_available,
+            wrap_key: Some(Box::new(wrap_key)),
+            unwrap_key: Some(Box::new(unwrap_key)),
+            delete_key: Some(Box::new(delete_key)),
+        }
+    }
+
+    /// Key store for platforms without a hardware key store.
+    #[frb(sync)]
+    pub fn software() -> Self {
+        Self {
+            hardware_available: false,
+            wrap_key: None,
+            unwrap_key: None,
+            delete_key: None,
+        }
+    }
+}
+
+impl PlatformKeyStore for DartKeyStore {
+    async fn is_available(&self) -> bool {
+        self.hardware_available && self.wrap_key.is_some()
+    }
+
+    async fn wrap_key(&self, key: &[u8], require_biometrics: bool) -> Result<Vec<u8>> {
+        let Some(wrap_key) = &self.wrap_key else {
+            bail!("No hardware key store available");
+        };
+        wrap_key(key.to_vec(), require_biometrics)
+            .await
+            .ok_or_else(|| anyhow!("Platform key store failed to wrap the key"))
+    }
+
+    async fn unwrap_key(
+        &self,
+        wrapped_key: &[u8],
+        require_biometrics: bool,
+    ) -> Result<SecretBytes> {
+        let Some(unwrap_key) = &self.unwrap_key else {
+            bail!("No hardware key store available");
+        };
+        let key = unwrap_key(wrapped_key.to_vec(), require_biometrics)
+            .await
+            .ok_or_else(|| anyhow!("Platform key store failed to unwrap the key"))?;
+        Ok(key.into())
+    }
+
+    async fn delete_key(&self, require_biometrics: bool) -> Result<()> {
+        let Some(delete_key) = &self.delete_key else {
+            return Ok(());
+        };
+        if !delete_key(require_biometrics).await {
+            bail!("Platform key store failed to delete the key");
+        }
+        Ok(())
+    }
+}
+
+pub enum UiKeyProtection {
+    Software,
+    Hardware,
+    HardwareBiometric,
+}
+
+impl From<KeyProtection> for UiKeyProtection {
+    fn from(protection: KeyProtection) -> Self {
+        match protection {
+            KeyProtection::Software => Self::Software,
+            KeyProtection::Hardware => Self::Hardware,
+            KeyProtection::HardwareBiometric => Self::HardwareBiometric,
+        }
+    }
+}
+
+/// Holds the device's master key while the app is unlocked.
+#[frb(opaque)]
+pub struct MasterKeyVault {
+    pub(crate) db_path: String,
+    pub(crate) key_store: DartKeyStore,
+    master_key: Mutex<Option<MasterKey>>,
+}
+
+impl MasterKeyVault {
+    #[frb(sync)]
+    pub fn new(db_path: String, key_store: DartKeyStore) -> Self {
+        Self {
+            db_path,
+            key_store,
+            master_key: Mutex::new(None),
+        }
+    }
+
+    /// How the persisted master key is protected. `None` if there is no
+    /// master key yet.
+    pub async fn protection(&self) -> Result<Option<UiKeyProtection>> {
+        Ok(MasterKey::persisted_protection(&self.db_path)?.map(From::from))
+    }
+
+    /// Unlock the master key, prompting the user for biometric
+    /// authentication if the biometric unlock is enabled. Creates the key if
+    /// there is none yet.
+    pub async fn unlock(&self) -> Result<UiKeyProtection> {
+        let mut master_key = self.master_key.lock().await;
+        if let Some(master_key) = master_key.as_ref() {
+            return Ok(master_key.protection().into());
+        }
+        let unlocked = MasterKey::unlock_or_create(&self.db_path, &self.key_store, false).await?;
+        let protection = unlocked.protection().into();
+        *master_key = Some(unlocked);
+        Ok(protection)
+    }
+
+    /// Drop the master key from memory. It has to be unlocked again before
+    /// it can be used.
+    pub async fn lock(&self) {
+        self.master_key.lock().await.take();
+    }
+
+    pub async fn is_unlocked(&self) -> bool {
+        self.master_key.lock().await.is_some()
+    }
+
+    /// Enable or disable the biometric unlock. The vault must be unlocked.
+    ///
+    /// Returns the resulting protection. Fails if the biometric unlock should
+    /// be enabled, but the platform has no hardware key store.
+    pub async fn set_biometric_unlock(&self, enabled: bool) -> Result<UiKeyProtection> {
+        let mut master_key = self.master_key.lock().await;
+        let unlocked = master_key
+            .take()
+            .ok_or_else(|| anyhow!("Master key is locked"))?;
+        let protected = unlocked
+            .clone()
+            .protect(&self.db_path, &self.key_store, enabled)
+            .await;
+        match protected {
+            Ok(protected) => {
+                let protection = protected.protection().into();
+                *master_key = Some(protected);
+                Ok(protection)
+            }
+            Err(error) => {
+                *master_key = Some(unlocked);
+                Err(error)
+            }
+        }
+    }
+
+    /// Encrypt data of the app with the master key, e.g. secrets that must
+    /// only be readable while the app is unlocked. The vault must be
+    /// unlocked.
+    ///
+    /// The label binds the ciphertext to its purpose and has to be passed to
+    /// [`Self::decrypt`] as well.
+    pub async fn encrypt(&self, label: String, plaintext: Vec<u8>) -> Result<Vec<u8>> {
+        let master_key = self.master_key.lock().await;
+        let master_key = master_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("Master key is locked"))?;
+        master_key.encrypt(&label, &plaintext)
+    }
+
+    /// Decrypt data encrypted by [`Self::encrypt`]. The vault must be
+    /// unlocked.
+    pub async fn decrypt(&self, label: String, ciphertext: Vec<u8>) -> Result<Vec<u8>> {
+        let master_key = self.master_key.lock().await;
+        let master_key = master_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("Master key is locked"))?;
+        Ok(master_key.decrypt(&label, &ciphertext)?.to_vec())
+    }
+}
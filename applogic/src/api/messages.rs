@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -11,13 +11,12 @@ use phnxcoreclient::{
     clients::process::process_qs::ProcessedQsMessages, ConversationId, ConversationMessage,
     Message, MimiContent,
 };
-use tokio::sync::broadcast;
 
 use crate::notifier::{dispatch_conversation_notifications, dispatch_message_notifications};
 
 use super::{
     notifications::LocalNotificationContent,
-    types::{UiConversationMessage, UiMessage},
+    types::{UiConversationMessage, UiConversationMessageId, UiForwardOutcome, UiMessage},
     user::User,
 };
 
@@ -33,24 +32,12 @@ pub(crate) struct FetchedMessages {
 impl User {
     /// Fetch AS messages
     pub(crate) async fn fetch_as_messages(&self) -> Result<Vec<ConversationId>> {
-        let as_messages = self.user.as_fetch_messages().await?;
-
-        // Process each as message individually and dispatch conversation
-        // notifications to the UI in case a new conversation is created.
-        let mut new_connections = vec![];
-        for as_message in as_messages {
-            let as_message_plaintext = self.user.decrypt_as_queue_message(as_message).await?;
-            let conversation_id = self.user.process_as_message(as_message_plaintext).await?;
-            new_connections.push(conversation_id);
-        }
-
-        Ok(new_connections)
+        self.user.as_fetch_and_process_messages().await
     }
 
     /// Fetch QS messages
     pub(crate) async fn fetch_qs_messages(&self) -> Result<ProcessedQsMessages> {
-        let qs_messages = self.user.qs_fetch_messages().await?;
-        self.user.fully_process_qs_messages(qs_messages).await
+        self.user.qs_fetch_and_process_messages().await
     }
 
     /// Fetch both AS and QS messages
@@ -69,6 +56,7 @@ impl User {
             new_conversations,
             changed_conversations,
             new_messages,
+            ..
         } = self.fetch_qs_messages().await?;
 
         notifications.extend(
@@ -76,6 +64,7 @@ impl User {
                 .await,
         );
         notifications.extend(self.new_message_notifications(&new_messages).await);
+        self.auto_download_attachments(&new_messages).await;
 
         Ok(FetchedMessages {
             new_conversations,
@@ -89,9 +78,13 @@ impl User {
     pub async fn fetch_messages(&self) -> Result<()> {
         let fetched_messages = self.fetch_all_messages().await?;
 
-        // Send a notification to the OS (desktop only)
+        // Send a notification to the OS (desktop only). Opening the chat from
+        // a notification requires the user cubit.
         #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
-        crate::notifier::show_desktop_notifications(&fetched_messages.notifications_content);
+        crate::notifier::show_desktop_notifications(
+            &fetched_messages.notifications_content,
+            |_| {},
+        );
 
         // Let the UI know there is new stuff
         tokio::join!(
@@ -115,10 +108,38 @@ impl User {
         message: String,
     ) -> Result<UiConversationMessage> {
         let content = MimiContent::simple_markdown_message(self.user.user_name().domain(), message);
-        self.user
-            .send_message(conversation_id, content)
-            .await
-            .map(|m| m.into())
+        let started_at = Instant::now();
+        let message = self.user.send_message(conversation_id, content).await?;
+        self.metrics.record_send(started_at.elapsed());
+        Ok(message.into())
+    }
+
+    /// Retry sending an own message that couldn't be sent
+    pub async fn retry_failed_message(
+        &self,
+        message_id: UiConversationMessageId,
+    ) -> Result<UiConversationMessage> {
+        let message = self.user.retry_failed_message(message_id.into()).await?;
+        Ok(message.into())
+    }
+
+    /// Delete an own message that couldn't be sent
+    pub async fn delete_failed_message(&self, message_id: UiConversationMessageId) -> Result<()> {
+        self.user.delete_failed_message(message_id.into()).await
+    }
+
+    /// Forward the message with the given id to the given conversations. The
+    /// outcome is reported per conversation.
+    pub async fn forward_message(
+        &self,
+        message_id: UiConversationMessageId,
+        conversation_ids: Vec<ConversationId>,
+    ) -> Result<Vec<UiForwardOutcome>> {
+        let outcomes = self
+            .user
+            .forward_message(message_id.into(), conversation_ids)
+            .await?;
+        Ok(outcomes.into_iter().map(|o| o.into()).collect())
     }
 
     pub async fn get_messages(
@@ -161,8 +182,9 @@ impl User {
     /// Get the unread messages count across all conversations.
     pub async fn global_unread_messages_count(&self) -> u32 {
         self.user
-            .global_unread_messages_count()
+            .load_counters()
             .await
+            .map(|counters| counters.total().unread)
             .unwrap_or_default()
     }
 }
@@ -173,11 +195,22 @@ pub(crate) fn group_messages(messages: Vec<ConversationMessage>) -> Vec<UiConver
 
     while let Some(conversation_message) = iter.next() {
         let mut timestamp = conversation_message.timestamp();
+        let mut received_at = conversation_message.received_at();
+        let mut is_delivered_late = conversation_message.is_delivered_late();
         let Message::Content(content_message) = conversation_message.message() else {
             // Directly add non-content messages
             grouped_messages.push(conversation_message.into());
             continue;
         };
+        if conversation_message.delivery_failure().is_some() {
+            // Failed messages are shown on their own, such that they can be
+            // retried or deleted individually.
+            grouped_messages.push(UiConversationMessage {
+                message: UiMessage::ContentFlight(vec![content_message.clone().into()]),
+                ..conversation_message.into()
+            });
+            continue;
+        }
 
         let mut current_flight = vec![content_message.clone().into()];
         let current_sender = content_message.sender().to_string();
@@ -185,16 +218,22 @@ pub(crate) fn group_messages(messages: Vec<ConversationMessage>) -> Vec<UiConver
         // Keep collecting messages from the same sender
         while let Some(next_message) = iter.peek() {
             let temp_timestamp = next_message.timestamp();
+            let temp_received_at = next_message.received_at();
+            let temp_is_delivered_late = next_message.is_delivered_late();
             let Message::Content(next_content_message) = next_message.message() else {
                 break;
             };
-            if next_content_message.sender() != current_sender {
+            if next_content_message.sender() != current_sender
+                || next_message.delivery_failure().is_some()
+            {
                 break;
             }
             let next_content_message = next_content_message.clone();
             // Consume the next message and add it to the current flight
             let _ = iter.next();
             timestamp = temp_timestamp;
+            received_at = temp_received_at;
+            is_delivered_late |= temp_is_delivered_late;
             current_flight.push(next_content_message.into());
         }
 
@@ -202,49 +241,11 @@ pub(crate) fn group_messages(messages: Vec<ConversationMessage>) -> Vec<UiConver
         grouped_messages.push(UiConversationMessage {
             message: UiMessage::ContentFlight(current_flight),
             timestamp: timestamp.to_rfc3339(),
+            received_at: received_at.to_rfc3339(),
+            is_delivered_late,
             ..conversation_message.into()
         });
     }
 
     grouped_messages
 }
-
-/// An internal broadcast channel for fetched messages
-///
-/// Must not be exposed to the UI.
-#[derive(Debug, Clone)]
-pub(crate) struct FetchedMessagesBroadcast {
-    tx: broadcast::Sender<Arc<FetchedMessages>>,
-}
-
-impl FetchedMessagesBroadcast {
-    pub(crate) fn new() -> Self {
-        Self {
-            tx: broadcast::channel(128).0,
-        }
-    }
-
-    pub(crate) async fn send(&self, fetched_messages: FetchedMessages) {
-        let _no_receivers = self.tx.send(Arc::new(fetched_messages));
-    }
-
-    pub(crate) fn subscribe(&self) -> FetchedMessagesReceiver {
-        let rx = self.tx.subscribe();
-        FetchedMessagesReceiver { rx }
-    }
-}
-
-/// An internal received channel for fetched messages
-///
-/// Must not be exposed to the UI.
-pub(crate) struct FetchedMessagesReceiver {
-    rx: broadcast::Receiver<Arc<FetchedMessages>>,
-}
-
-impl FetchedMessagesReceiver {
-    pub(crate) async fn recv(
-        &mut self,
-    ) -> Result<Arc<FetchedMessages>, broadcast::error::RecvError> {
-        self.rx.recv().await
-    }
-}
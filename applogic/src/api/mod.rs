@@ -7,12 +7,21 @@ use tracing::error;
 
 use crate::logging::init_logger;
 
+pub mod activity_center_cubit;
+pub mod app_lock_cubit;
+pub mod broadcast_lists;
+pub mod chat_events;
 pub mod conversation_details_cubit;
 pub mod conversation_list_cubit;
 pub mod conversations;
+pub mod key_store;
 pub mod logging;
 pub mod messages;
 pub mod notifications;
+pub mod server_probe;
+pub mod share_extension;
+pub mod sync_status_cubit;
+pub mod system_messages;
 pub mod types;
 pub mod user;
 pub mod utils;
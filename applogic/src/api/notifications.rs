@@ -2,45 +2,240 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use chrono::Utc;
+use phnxcoreclient::{
+    clients::QueueMessagePreview, Conversation, ConversationMessageId, EventMessage, Message,
+    MessageRequestState,
+};
 pub(crate) use phnxcoreclient::{ConversationId, ConversationMessage};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
 
-use crate::api::user::User;
+use crate::{
+    api::{system_messages::render_system_message, types::UiConversationMessageId, user::User},
+    app_state::{
+        chat_notifications::{ChatNotificationSettingsMap, NotificationAlert},
+        do_not_disturb::DoNotDisturbSettings,
+    },
+};
+
+/// Body of notifications of sensitive conversations, which don't show the
+/// message content.
+const SENSITIVE_NOTIFICATION_BODY: &str = "New message";
 
 #[derive(Debug)]
 pub(crate) struct LocalNotificationContent {
     pub(crate) title: String,
     pub(crate) body: String,
+    pub(crate) target: NotificationTarget,
+    pub(crate) alert: NotificationAlert,
+}
+
+/// Navigation target of a notification.
+///
+/// Serialized into the payload of every OS notification, such that tapping the
+/// notification opens the right conversation of the right account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct NotificationTarget {
+    account: String,
+    conversation_id: Uuid,
+    message_id: Option<Uuid>,
+}
+
+impl NotificationTarget {
+    pub(crate) fn to_payload(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub(crate) fn from_payload(payload: &str) -> Option<Self> {
+        serde_json::from_str(payload).ok()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn conversation_id(&self) -> ConversationId {
+        ConversationId::from(self.conversation_id)
+    }
+}
+
+/// Where the app should navigate to after a notification was tapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UiNavigationTarget {
+    ConversationList,
+    Conversation {
+        conversation_id: ConversationId,
+        message_id: Option<UiConversationMessageId>,
+    },
 }
 
 impl User {
+    fn notification_target(
+        &self,
+        conversation_id: ConversationId,
+        message_id: Option<ConversationMessageId>,
+    ) -> NotificationTarget {
+        NotificationTarget {
+            account: self.user.as_client_id().to_string(),
+            conversation_id: conversation_id.as_uuid(),
+            message_id: message_id.map(|id| id.to_uuid()),
+        }
+    }
+
+    /// Resolve the payload of a tapped notification into a navigation target.
+    ///
+    /// Payloads that can't be parsed, belong to another account or point to a
+    /// conversation that doesn't exist anymore resolve to the conversation
+    /// list. If only the message doesn't exist anymore, the conversation is
+    /// opened without jumping to the message.
+    pub async fn resolve_notification_payload(&self, payload: String) -> UiNavigationTarget {
+        let Some(target) = NotificationTarget::from_payload(&payload) else {
+            warn!("Invalid notification payload");
+            return UiNavigationTarget::ConversationList;
+        };
+        if target.account != self.user.as_client_id().to_string() {
+            warn!("Notification payload belongs to another account");
+            return UiNavigationTarget::ConversationList;
+        }
+        let conversation_id = ConversationId::from(target.conversation_id);
+        if self.user.conversation(&conversation_id).await.is_none() {
+            return UiNavigationTarget::ConversationList;
+        }
+        let message_id = match target.message_id {
+            Some(message_id) => {
+                let message_id = ConversationMessageId::from_uuid(message_id);
+                self.user
+                    .message(message_id)
+                    .await
+                    .filter(|message| message.conversation_id() == conversation_id)
+                    .map(|_| message_id.into())
+            }
+            None => None,
+        };
+        UiNavigationTarget::Conversation {
+            conversation_id,
+            message_id,
+        }
+    }
+
+    /// The DND settings if DND is currently active.
+    pub(crate) async fn active_do_not_disturb(&self) -> Option<DoNotDisturbSettings> {
+        let settings: DoNotDisturbSettings = match self.user.user_setting().await {
+            Ok(settings) => settings,
+            Err(error) => {
+                warn!(%error, "Failed to load DND settings");
+                return None;
+            }
+        };
+        settings.is_active(Utc::now()).then_some(settings)
+    }
+
+    async fn chat_notification_settings(&self) -> ChatNotificationSettingsMap {
+        self.user.user_setting().await.unwrap_or_else(|error| {
+            warn!(%error, "Failed to load chat notification settings");
+            Default::default()
+        })
+    }
+
     /// Send notifications for new messages.
+    ///
+    /// While DND is active, only messages that are DND exceptions are
+    /// notified.
     pub(crate) async fn new_message_notifications(
         &self,
         conversation_messages: &[ConversationMessage],
     ) -> Vec<LocalNotificationContent> {
         let mut notifications = Vec::new();
+        let do_not_disturb = self.active_do_not_disturb().await;
+        let own_user_name = self.user.user_name();
+        let chat_settings = self.chat_notification_settings().await;
 
         for conversation_message in conversation_messages {
+            if let Some(do_not_disturb) = &do_not_disturb {
+                let is_exception = match conversation_message.message() {
+                    Message::Content(content_message) => do_not_disturb.is_exception(
+                        content_message.sender(),
+                        &content_message.content().string_rendering(),
+                        &own_user_name,
+                    ),
+                    Message::Event(_) => false,
+                };
+                if !is_exception {
+                    continue;
+                }
+            }
             if let Some(conversation) = self
                 .user
                 .conversation(&conversation_message.conversation_id())
                 .await
             {
-                let title = match conversation.conversation_type() {
-                    phnxcoreclient::ConversationType::UnconfirmedConnection(username)
-                    | phnxcoreclient::ConversationType::Connection(username) => {
-                        username.to_string()
-                    }
-                    phnxcoreclient::ConversationType::Group => {
-                        conversation.attributes().title().to_string()
+                let title = notification_title(&conversation);
+                let body = if conversation.is_sensitive() {
+                    SENSITIVE_NOTIFICATION_BODY.to_owned()
+                } else {
+                    match conversation_message.message() {
+                        Message::Event(EventMessage::System(system_message)) => {
+                            render_system_message(system_message.clone().into()).await
+                        }
+                        message => message
+                            .string_representation(conversation.conversation_type())
+                            .unwrap_or_default(),
                     }
                 };
-                let body = conversation_message
-                    .message()
-                    .string_representation(conversation.conversation_type());
+                let alert = chat_settings.alert(conversation.id(), &title);
                 notifications.push(LocalNotificationContent {
                     title: title.to_owned(),
                     body: body.to_owned(),
+                    target: self
+                        .notification_target(conversation.id(), Some(conversation_message.id())),
+                    alert,
+                });
+            }
+        }
+
+        notifications
+    }
+
+    /// Send one notification per conversation with previewed messages, e.g.
+    /// from an app extension that can't process them. The content of the
+    /// messages is unknown, so these are suppressed while DND is active.
+    pub(crate) async fn message_preview_notifications(
+        &self,
+        previews: &[QueueMessagePreview],
+    ) -> Vec<LocalNotificationContent> {
+        let mut notifications = Vec::new();
+        if self.active_do_not_disturb().await.is_some() {
+            return notifications;
+        }
+        let chat_settings = self.chat_notification_settings().await;
+
+        let mut message_counts: Vec<(ConversationId, usize)> = Vec::new();
+        for preview in previews {
+            let QueueMessagePreview::Message(conversation_id) = preview else {
+                continue;
+            };
+            match message_counts
+                .iter_mut()
+                .find(|(id, _)| id == conversation_id)
+            {
+                Some((_, count)) => *count += 1,
+                None => message_counts.push((*conversation_id, 1)),
+            }
+        }
+
+        for (conversation_id, count) in message_counts {
+            if let Some(conversation) = self.user.conversation(&conversation_id).await {
+                let title = notification_title(&conversation);
+                let body = if count == 1 {
+                    SENSITIVE_NOTIFICATION_BODY.to_owned()
+                } else {
+                    format!("{count} new messages")
+                };
+                let alert = chat_settings.alert(conversation_id, &title);
+                notifications.push(LocalNotificationContent {
+                    title,
+                    body,
+                    target: self.notification_target(conversation_id, None),
+                    alert,
                 });
             }
         }
@@ -48,20 +243,29 @@ impl User {
         notifications
     }
 
-    /// Send notifications for new conversations.
+    /// Send notifications for new conversations. Suppressed while DND is
+    /// active.
     pub(crate) async fn new_conversation_notifications(
         &self,
         conversation_ids: &[ConversationId],
     ) -> Vec<LocalNotificationContent> {
         let mut notifications = Vec::new();
+        if self.active_do_not_disturb().await.is_some() {
+            return notifications;
+        }
+        let chat_settings = self.chat_notification_settings().await;
 
         for conversation_id in conversation_ids {
             if let Some(conversation) = self.user.conversation(conversation_id).await {
                 let title = format!("You were added to {}", conversation.attributes().title());
                 let body = "Say hi to everyone".to_owned();
+                let alert =
+                    chat_settings.alert(*conversation_id, conversation.attributes().title());
                 notifications.push(LocalNotificationContent {
                     title: title.to_owned(),
                     body: body.to_owned(),
+                    target: self.notification_target(*conversation_id, None),
+                    alert,
                 });
             }
         }
@@ -69,12 +273,16 @@ impl User {
         notifications
     }
 
-    /// Send notifications for new connection requests.
+    /// Send notifications for new connection requests. Suppressed while DND
+    /// is active.
     pub(crate) async fn new_connection_request_notifications(
         &self,
         connection_conversations: &[ConversationId],
     ) -> Vec<LocalNotificationContent> {
         let mut notifications = Vec::new();
+        if self.active_do_not_disturb().await.is_some() {
+            return notifications;
+        }
 
         for conversation_id in connection_conversations {
             if let Some(conversation) = self.user.conversation(conversation_id).await {
@@ -90,10 +298,40 @@ impl User {
                 notifications.push(LocalNotificationContent {
                     title: title.to_owned(),
                     body: body.to_owned(),
+                    target: self.notification_target(*conversation_id, None),
+                    alert: NotificationAlert::default(),
                 });
+            } else if let Ok(Some(message_request)) =
+                self.user.message_request(*conversation_id).await
+            {
+                // Filtered requests are only shown when the user looks at
+                // them.
+                if message_request.state() == MessageRequestState::Pending
+                    && message_request.filter_reason().is_none()
+                {
+                    notifications.push(LocalNotificationContent {
+                        title: format!("New message request from {}", message_request.user_name()),
+                        body: message_request
+                            .intro()
+                            .unwrap_or("Open to accept or decline")
+                            .to_owned(),
+                        target: self.notification_target(*conversation_id, None),
+                        alert: NotificationAlert::default(),
+                    });
+                }
             }
         }
 
         notifications
     }
 }
+
+/// Title of the notifications of a conversation: the name of the contact or
+/// the title of the group.
+fn notification_title(conversation: &Conversation) -> String {
+    match conversation.conversation_type() {
+        phnxcoreclient::ConversationType::UnconfirmedConnection(username)
+        | phnxcoreclient::ConversationType::Connection(username) => username.to_string(),
+        phnxcoreclient::ConversationType::Group => conversation.attributes().title().to_string(),
+    }
+}
@@ -0,0 +1,180 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Checks of a server entered by the user before registering with it.
+//!
+//! Addresses without a scheme are only tried with HTTPS. Plain HTTP is only
+//! used if the user enters it explicitly, in which case the result is flagged
+//! as not using TLS, such that the UI can warn the user. Certificate errors
+//! are reported as an unreachable server and never lead to a retry without
+//! TLS.
+
+use phnxapiclient::{
+    as_api::AsRequestError,
+    compatibility::{ServerCompatibility, UpgradeRequired},
+    ApiClient,
+};
+use phnxtypes::messages::server_info::{RegistrationPolicy, ServerInfoResponse};
+use url::Url;
+
+use crate::app_state::compatibility::{client_version, USED_SERVER_FEATURES};
+
+/// Result of probing a server.
+pub struct UiServerProbe {
+    /// Normalized URL of the server, to be used for the registration
+    pub url: String,
+    pub uses_tls: bool,
+    pub status: UiServerStatus,
+}
+
+pub enum UiServerStatus {
+    /// The input is not a valid server address
+    InvalidAddress,
+    /// The server could not be reached, e.g. because of a TLS error
+    Unreachable {
+        reason: String,
+    },
+    /// The server doesn't support a protocol version supported by this client
+    Incompatible,
+    /// The server doesn't serve this version of the app anymore. `min_version`
+    /// is the oldest version it serves, if known.
+    UpgradeRequired {
+        min_version: Option<String>,
+    },
+    Available(UiServerInfo),
+}
+
+pub struct UiServerInfo {
+    pub registration_open: bool,
+    /// Names of the optional features enabled on the server
+    pub features: Vec<String>,
+    /// Message of the day set by the operator
+    pub motd: Option<String>,
+}
+
+impl From<ServerInfoResponse> for UiServerInfo {
+    fn from(response: ServerInfoResponse) -> Self {
+        Self {
+            registration_open: response.registration_policy == RegistrationPolicy::Open,
+            features: response
+                .features
+                .iter()
+                .map(|feature| feature.as_str().to_owned())
+                .collect(),
+            motd: response.motd.map(|motd| motd.as_str().to_owned()),
+        }
+    }
+}
+
+/// Validate the server address entered by the user and check that the server
+/// is reachable, compatible and accepts registrations.
+pub async fn probe_server(address: String) -> UiServerProbe {
+    match server_url(&address) {
+        Some(url) => probe_url(&url).await,
+        None => UiServerProbe {
+            url: address,
+            uses_tls: false,
+            status: UiServerStatus::InvalidAddress,
+        },
+    }
+}
+
+async fn probe_url(url: &Url) -> UiServerProbe {
+    let uses_tls = url.scheme() == "https";
+    let status = match ApiClient::initialize(url.as_str()) {
+        Ok(client) if client.health_check().await => match client.as_server_info().await {
+            Ok(response) => {
+                // The server answered, so a failure here doesn't make it
+                // unreachable.
+                let compatibility_info = client.compatibility_info().await.unwrap_or_default();
+                match ServerCompatibility::evaluate(
+                    &response,
+                    &compatibility_info,
+                    &client_version(),
+                    USED_SERVER_FEATURES,
+                ) {
+                    ServerCompatibility::UpgradeRequired(UpgradeRequired::UnsupportedProtocol) => {
+                        UiServerStatus::Incompatible
+                    }
+                    ServerCompatibility::UpgradeRequired(UpgradeRequired::ClientTooOld {
+                        min_version,
+                    }) => UiServerStatus::UpgradeRequired {
+                        min_version: Some(min_version.to_string()),
+                    },
+                    _ => UiServerStatus::Available(response.into()),
+                }
+            }
+            Err(AsRequestError::NetworkError(reason)) => UiServerStatus::Unreachable { reason },
+            Err(AsRequestError::UpgradeRequired) => {
+                UiServerStatus::UpgradeRequired { min_version: None }
+            }
+            // The server doesn't understand the request or sends an unknown
+            // response.
+            Err(_) => UiServerStatus::Incompatible,
+        },
+        Ok(_) => UiServerStatus::Unreachable {
+            reason: "Health check failed".to_owned(),
+        },
+        Err(error) => UiServerStatus::Unreachable {
+            reason: error.to_string(),
+        },
+    };
+    UiServerProbe {
+        url: url.to_string(),
+        uses_tls,
+        status,
+    }
+}
+
+/// The URL of the server with the given address, if the address is valid.
+/// Addresses without a scheme use HTTPS.
+fn server_url(address: &str) -> Option<Url> {
+    let address = address.trim().trim_end_matches('/');
+    if address.is_empty() {
+        return None;
+    }
+    let url = if address.contains("://") {
+        Url::parse(address)
+    } else {
+        Url::parse(&format!("https://{address}"))
+    };
+    url.ok().filter(is_server_url)
+}
+
+fn is_server_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+        && url.host_str().is_some_and(|host| !host.is_empty())
+        && url.username().is_empty()
+        && url.password().is_none()
+        && url.path() == "/"
+        && url.query().is_none()
+        && url.fragment().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(address: &str) -> Option<String> {
+        server_url(address).as_ref().map(ToString::to_string)
+    }
+
+    #[test]
+    fn addresses_are_normalized() {
+        assert_eq!(url(" example.com/ ").unwrap(), "https://example.com/");
+        assert_eq!(
+            url("example.com:9420").unwrap(),
+            "https://example.com:9420/"
+        );
+        assert_eq!(url("https://example.com").unwrap(), "https://example.com/");
+        // Plain HTTP only if requested explicitly
+        assert_eq!(url("http://example.com").unwrap(), "http://example.com/");
+
+        assert!(url("").is_none());
+        assert!(url("ftp://example.com").is_none());
+        assert!(url("https://example.com/path").is_none());
+        assert!(url("https://user@example.com").is_none());
+        assert!(url("example.com?query").is_none());
+    }
+}
@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Slim API for share extensions.
+//!
+//! Share extensions can't afford to load the user, connect to the server or
+//! start any cubits. They only open the client DB and store shared content as
+//! unsent messages, which the main app sends via [`User::flush_outbox`] the
+//! next time it runs.
+
+use anyhow::{anyhow, Result};
+use phnxcoreclient::{
+    clients::store::{ClientRecord, ClientRecordState},
+    ConversationId, MimiContent, Outbox,
+};
+use phnxtypes::identifiers::Fqdn;
+
+use super::{types::UiConversation, user::User};
+
+/// An item shared with the extension
+pub enum UiSharedItem {
+    Text(String),
+    /// Attachment with the given caption
+    Attachment {
+        caption: String,
+        content: Vec<u8>,
+    },
+}
+
+pub struct ShareExtension {
+    /// Outboxes of all users on the device
+    outboxes: Vec<(Outbox, Fqdn)>,
+}
+
+impl ShareExtension {
+    /// Open the DBs of all users at the given path.
+    pub fn open(path: String) -> Result<ShareExtension> {
+        let outboxes = ClientRecord::load_all_from_phnx_db(&path)?
+            .into_iter()
+            .filter(|record| matches!(record.client_record_state, ClientRecordState::Finished))
+            .map(|record| {
+                let domain = record.as_client_id.user_name().domain();
+                let outbox = Outbox::open(record.as_client_id, &path)?;
+                Ok((outbox, domain))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if outboxes.is_empty() {
+            return Err(anyhow!("No user found."));
+        }
+        Ok(Self { outboxes })
+    }
+
+    /// The most recently used conversations of all users, as share targets.
+    pub async fn recent_conversations(&self, limit: u32) -> Result<Vec<UiConversation>> {
+        let mut conversations = Vec::new();
+        for (outbox, _) in &self.outboxes {
+            conversations.extend(outbox.recent_conversations(limit as usize).await?);
+        }
+        conversations.sort_by(|(_, a), (_, b)| b.cmp(a));
+        Ok(conversations
+            .into_iter()
+            .take(limit as usize)
+            .map(|(c, _)| c.into())
+            .collect())
+    }
+
+    /// Enqueue a text message to the given conversation.
+    pub async fn share_text(&self, conversation_id: ConversationId, message: String) -> Result<()> {
+        self.share_items(conversation_id, vec![UiSharedItem::Text(message)])
+            .await
+    }
+
+    /// Enqueue a message with the given attachment to the given conversation.
+    /// The message text is the caption of the attachment.
+    pub async fn share_attachment(
+        &self,
+        conversation_id: ConversationId,
+        caption: String,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        self.share_items(
+            conversation_id,
+            vec![UiSharedItem::Attachment { caption, content }],
+        )
+        .await
+    }
+
+    /// Enqueue one message per shared item to the given conversation, in the
+    /// order of the items.
+    pub async fn share_items(
+        &self,
+        conversation_id: ConversationId,
+        items: Vec<UiSharedItem>,
+    ) -> Result<()> {
+        let (outbox, domain) = self.outbox_of(conversation_id).await?;
+        for item in items {
+            match item {
+                UiSharedItem::Text(message) => {
+                    let content = MimiContent::simple_markdown_message(domain.clone(), message);
+                    outbox.enqueue_message(conversation_id, content).await?;
+                }
+                UiSharedItem::Attachment { caption, content } => {
+                    let message_content =
+                        MimiContent::simple_markdown_message(domain.clone(), caption);
+                    outbox
+                        .enqueue_attachment(conversation_id, message_content, content)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The outbox of the user that is a member of the given conversation
+    async fn outbox_of(&self, conversation_id: ConversationId) -> Result<&(Outbox, Fqdn)> {
+        for entry in &self.outboxes {
+            if entry.0.has_conversation(conversation_id).await? {
+                return Ok(entry);
+            }
+        }
+        Err(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))
+    }
+}
+
+impl User {
+    /// Send all messages that were enqueued by a share extension or failed
+    /// to send previously. The user cubit already does so whenever the app
+    /// is started or comes to the foreground.
+    pub async fn flush_outbox(&self) -> Result<u32> {
+        let sent = self.user.flush_outbox().await?;
+        Ok(sent as u32)
+    }
+}
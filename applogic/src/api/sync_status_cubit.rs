@@ -0,0 +1,262 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Consolidated sync state shown by the UI, e.g. as a "connecting…" banner.
+//!
+//! The state is derived from the events of the background tasks of the
+//! [`UserCubitBase`]: the connection state of the QS websocket, the outcome of
+//! the last fetch of the queues and the number of own messages waiting in the
+//! outbox.
+
+use flutter_rust_bridge::frb;
+use phnxcoreclient::clients::CoreUser;
+use phnxcoreclient::store::{Store, StoreNotifications};
+use phnxcoreclient::NotificationType;
+use tokio::sync::{broadcast, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+use crate::util::{
+    spawn_from_sync, AppEvent, ConnectivityState, Cubit, CubitCore, EventBusReceiver, FetchOutcome,
+};
+use crate::StreamSink;
+
+use super::user::user_cubit::UserCubitBase;
+
+#[frb(dart_metadata = ("freezed"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum SyncStatus {
+    /// Connecting to the server, or own messages are waiting to be sent
+    #[default]
+    Syncing,
+    UpToDate,
+    /// The server can't be reached
+    Offline,
+    /// The server can be reached, but fetching messages failed
+    Error {
+        reason: String,
+    },
+}
+
+/// Streams the [`SyncStatus`] of the logged in user
+#[frb(opaque)]
+pub struct SyncStatusCubitBase {
+    core: CubitCore<SyncStatus>,
+}
+
+impl SyncStatusCubitBase {
+    #[frb(sync)]
+    pub fn new(user_cubit: &UserCubitBase) -> Self {
+        let core_user = user_cubit.core_user.clone();
+        let core = CubitCore::new();
+
+        let context = SyncStatusContext::new(core_user.clone(), core.state_tx().clone());
+        context.spawn(
+            user_cubit.subscribe_to_events(),
+            Store::subscribe(&core_user),
+            core.cancellation_token().clone(),
+        );
+
+        Self { core }
+    }
+
+    // Cubit interface
+
+    #[frb(getter, sync)]
+    pub fn is_closed(&self) -> bool {
+        self.core.is_closed()
+    }
+
+    pub fn close(&mut self) {
+        self.core.close();
+    }
+
+    #[frb(getter, sync)]
+    pub fn state(&self) -> SyncStatus {
+        self.core.state()
+    }
+
+    pub async fn stream(&mut self, sink: StreamSink<SyncStatus>) {
+        self.core.stream(sink).await;
+    }
+}
+
+/// The inputs the [`SyncStatus`] is derived from
+#[derive(Debug, Default)]
+struct SyncInputs {
+    /// `None` until the websocket connected or failed for the first time
+    websocket: Option<ConnectivityState>,
+    /// `None` until the first fetch finished
+    fetch: Option<FetchOutcome>,
+    pending_messages: usize,
+}
+
+impl SyncInputs {
+    /// Messages fetched before the websocket disconnected might be outdated,
+    /// so the outcome of the last fetch is dropped when the connection is
+    /// lost. Failed reconnection attempts keep the outcome of the polling in
+    /// the meantime.
+    fn set_websocket(&mut self, state: ConnectivityState) {
+        if self.websocket == Some(ConnectivityState::Connected)
+            && state == ConnectivityState::Disconnected
+        {
+            self.fetch = None;
+        }
+        self.websocket = Some(state);
+    }
+
+    fn status(&self) -> SyncStatus {
+        match (&self.websocket, &self.fetch) {
+            (Some(ConnectivityState::Disconnected), Some(FetchOutcome::Failed { .. })) => {
+                SyncStatus::Offline
+            }
+            (_, Some(FetchOutcome::Failed { reason })) => SyncStatus::Error {
+                reason: reason.clone(),
+            },
+            // Polling keeps the messages up to date while the websocket is
+            // down.
+            (_, Some(FetchOutcome::Succeeded)) if self.pending_messages == 0 => {
+                SyncStatus::UpToDate
+            }
+            _ => SyncStatus::Syncing,
+        }
+    }
+}
+
+#[frb(ignore)]
+struct SyncStatusContext {
+    core_user: CoreUser,
+    state_tx: watch::Sender<SyncStatus>,
+    inputs: SyncInputs,
+}
+
+impl SyncStatusContext {
+    fn new(core_user: CoreUser, state_tx: watch::Sender<SyncStatus>) -> Self {
+        Self {
+            core_user,
+            state_tx,
+            inputs: SyncInputs::default(),
+        }
+    }
+
+    fn spawn(
+        mut self,
+        events_rx: EventBusReceiver,
+        store_rx: StoreNotifications,
+        stop: CancellationToken,
+    ) {
+        spawn_from_sync(async move {
+            self.load_pending_messages().await;
+            self.emit_state();
+            self.listen_loop(events_rx, store_rx, stop).await;
+        });
+    }
+
+    async fn load_pending_messages(&mut self) {
+        match self.core_user.pending_outbox_messages().await {
+            Ok(pending_messages) => self.inputs.pending_messages = pending_messages,
+            Err(error) => error!(%error, "Failed to load pending outbox messages"),
+        }
+    }
+
+    fn emit_state(&self) {
+        let status = self.inputs.status();
+        self.state_tx.send_if_modified(|current| {
+            let modified = *current != status;
+            *current = status;
+            modified
+        });
+    }
+
+    async fn listen_loop(
+        mut self,
+        mut events_rx: EventBusReceiver,
+        mut store_rx: StoreNotifications,
+        stop: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                _ = stop.cancelled() => return,
+                event = events_rx.recv() => match event {
+                    Ok(AppEvent::Connectivity(state)) => self.inputs.set_websocket(state),
+                    Ok(AppEvent::Fetch(outcome)) => self.inputs.fetch = Some(outcome),
+                    Ok(AppEvent::Queue(_)) => self.load_pending_messages().await,
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        error!(n, "Events lagged");
+                        self.load_pending_messages().await
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                notification = store_rx.recv() => match notification {
+                    // Sending or failing to send a message changes the message
+                    Some(NotificationType::Message(_) | NotificationType::ConversationChange(_)) => {
+                        self.load_pending_messages().await
+                    }
+                    Some(_) => continue,
+                    None => return,
+                },
+            }
+            self.emit_state();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_status_from_inputs() {
+        let mut inputs = SyncInputs::default();
+        assert_eq!(inputs.status(), SyncStatus::Syncing);
+
+        inputs.set_websocket(ConnectivityState::Connected);
+        inputs.fetch = Some(FetchOutcome::Succeeded);
+        assert_eq!(inputs.status(), SyncStatus::UpToDate);
+
+        inputs.pending_messages = 2;
+        assert_eq!(inputs.status(), SyncStatus::Syncing);
+        inputs.pending_messages = 0;
+
+        inputs.fetch = Some(FetchOutcome::Failed {
+            reason: "bad response".to_owned(),
+        });
+        assert_eq!(
+            inputs.status(),
+            SyncStatus::Error {
+                reason: "bad response".to_owned()
+            }
+        );
+
+        inputs.set_websocket(ConnectivityState::Disconnected);
+        assert_eq!(inputs.status(), SyncStatus::Syncing);
+        inputs.fetch = Some(FetchOutcome::Failed {
+            reason: "connection refused".to_owned(),
+        });
+        assert_eq!(inputs.status(), SyncStatus::Offline);
+    }
+
+    #[test]
+    fn polling_while_websocket_is_down() {
+        let mut inputs = SyncInputs::default();
+        inputs.set_websocket(ConnectivityState::Connected);
+        inputs.fetch = Some(FetchOutcome::Succeeded);
+        assert_eq!(inputs.status(), SyncStatus::UpToDate);
+
+        // Losing the connection invalidates the last fetch until the next
+        // poll
+        inputs.set_websocket(ConnectivityState::Disconnected);
+        assert_eq!(inputs.status(), SyncStatus::Syncing);
+        inputs.fetch = Some(FetchOutcome::Succeeded);
+        assert_eq!(inputs.status(), SyncStatus::UpToDate);
+
+        // Failed reconnection attempts don't
+        inputs.set_websocket(ConnectivityState::Disconnected);
+        assert_eq!(inputs.status(), SyncStatus::UpToDate);
+
+        inputs.pending_messages = 1;
+        assert_eq!(inputs.status(), SyncStatus::Syncing);
+    }
+}
@@ -0,0 +1,243 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Display strings of system messages.
+//!
+//! System messages are stored without any language. Their display strings are
+//! generated when they are shown, from templates provided by the app in the
+//! language of the UI. Changing the language therefore also changes the
+//! strings of old system messages.
+//!
+//! Templates contain the placeholders `{actor}`, `{users}` and `{count}`. The
+//! app picks the plural form of a template based on the number of users. If
+//! the app doesn't provide a template, the English one is used.
+
+use std::sync::{Arc, LazyLock};
+
+use flutter_rust_bridge::{frb, DartFnFuture};
+use parking_lot::RwLock;
+
+use crate::api::types::{
+    UiCommitRule, UiInviteRule, UiJoinRule, UiRoomPolicyChange, UiSendRule, UiSystemMessage,
+};
+
+type LocalizeFn =
+    dyn Fn(UiSystemMessageTemplate, u32) -> DartFnFuture<Option<String>> + Send + Sync;
+
+static LOCALIZER: LazyLock<RwLock<Arc<SystemMessageLocalizer>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(SystemMessageLocalizer::english())));
+
+/// Kind of a system message, identifying its template
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiSystemMessageTemplate {
+    Joined,
+    Left,
+    Added,
+    Removed,
+    MadeInviteOnly,
+    AllowedKnocking,
+    MadeMessageRequest,
+    AllowedAllToInvite,
+    AllowedAdminsToInvite,
+    AllowedAllToSend,
+    AllowedAdminsToSend,
+    BroadcastModeOff,
+    BroadcastModeOn,
+    PendingChangeApplied,
+    PendingChangeDiscarded,
+}
+
+impl UiSystemMessageTemplate {
+    fn english(self) -> &'static str {
+        match self {
+            Self::Joined => "{actor} joined the conversation",
+            Self::Left => "{actor} left the conversation",
+            Self::Added => "{actor} added {users} to the conversation",
+            Self::Removed => "{actor} removed {users} from the conversation",
+            Self::MadeInviteOnly => "{actor} made the conversation invite-only",
+            Self::AllowedKnocking => "{actor} allowed users to ask to join",
+            Self::MadeMessageRequest => "{actor} turned the conversation into a message request",
+            Self::AllowedAllToInvite => "{actor} allowed all members to invite others",
+            Self::AllowedAdminsToInvite => "{actor} allowed only admins to invite others",
+            Self::AllowedAllToSend => "{actor} allowed all members to send messages",
+            Self::AllowedAdminsToSend => "{actor} allowed only admins to send messages",
+            Self::BroadcastModeOff => "{actor} turned off broadcast mode",
+            Self::BroadcastModeOn => "{actor} turned on broadcast mode",
+            Self::PendingChangeApplied => "A pending change to the conversation was applied",
+            Self::PendingChangeDiscarded => "A pending change to the conversation was discarded",
+        }
+    }
+}
+
+/// The template of the message with its actor and targets
+struct TemplateInputs<'a> {
+    template: UiSystemMessageTemplate,
+    actor: Option<&'a str>,
+    users: &'a [String],
+}
+
+impl<'a> TemplateInputs<'a> {
+    fn new(message: &'a UiSystemMessage) -> Self {
+        let (template, actor, users): (_, _, &[String]) = match message {
+            UiSystemMessage::Add { adder, added } if added == std::slice::from_ref(adder) => {
+                (UiSystemMessageTemplate::Joined, Some(adder), &[])
+            }
+            UiSystemMessage::Add { adder, added } => {
+                (UiSystemMessageTemplate::Added, Some(adder), added)
+            }
+            UiSystemMessage::Remove { remover, removed }
+                if removed == std::slice::from_ref(remover) =>
+            {
+                (UiSystemMessageTemplate::Left, Some(remover), &[])
+            }
+            UiSystemMessage::Remove { remover, removed } => {
+                (UiSystemMessageTemplate::Removed, Some(remover), removed)
+            }
+            UiSystemMessage::ChangeRoomPolicy { admin, change } => {
+                let template = match change {
+                    UiRoomPolicyChange::SetJoinRule(UiJoinRule::InviteOnly) => {
+                        UiSystemMessageTemplate::MadeInviteOnly
+                    }
+                    UiRoomPolicyChange::SetJoinRule(UiJoinRule::Knock) => {
+                        UiSystemMessageTemplate::AllowedKnocking
+                    }
+                    UiRoomPolicyChange::SetJoinRule(UiJoinRule::MessageRequest) => {
+                        UiSystemMessageTemplate::MadeMessageRequest
+                    }
+                    UiRoomPolicyChange::SetInviteRule(UiInviteRule::AllMembers) => {
+                        UiSystemMessageTemplate::AllowedAllToInvite
+                    }
+                    UiRoomPolicyChange::SetInviteRule(UiInviteRule::AdminsOnly) => {
+                        UiSystemMessageTemplate::AllowedAdminsToInvite
+                    }
+                    UiRoomPolicyChange::SetSendRule(UiSendRule::AllMembers) => {
+                        UiSystemMessageTemplate::AllowedAllToSend
+                    }
+                    UiRoomPolicyChange::SetSendRule(UiSendRule::AdminsOnly) => {
+                        UiSystemMessageTemplate::AllowedAdminsToSend
+                    }
+                    UiRoomPolicyChange::SetCommitRule(UiCommitRule::AllMembers) => {
+                        UiSystemMessageTemplate::BroadcastModeOff
+                    }
+                    UiRoomPolicyChange::SetCommitRule(UiCommitRule::AdminsOnly) => {
+                        UiSystemMessageTemplate::BroadcastModeOn
+                    }
+                };
+                (template, Some(admin), &[])
+            }
+            UiSystemMessage::PendingChangeApplied => {
+                (UiSystemMessageTemplate::PendingChangeApplied, None, &[])
+            }
+            UiSystemMessage::PendingChangeDiscarded => {
+                (UiSystemMessageTemplate::PendingChangeDiscarded, None, &[])
+            }
+        };
+        Self {
+            template,
+            actor: actor.map(String::as_str),
+            users,
+        }
+    }
+
+    fn count(&self) -> u32 {
+        self.users.len().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn fill(&self, template: &str) -> String {
+        template
+            .replace("{actor}", self.actor.unwrap_or_default())
+            .replace("{users}", &self.users.join(", "))
+            .replace("{count}", &self.count().to_string())
+    }
+}
+
+/// Generates the display strings of system messages.
+///
+/// The callback returns the template of the given kind in the plural form for
+/// the given number of users, or `None` if the app has no translation for it.
+#[frb(opaque)]
+pub struct SystemMessageLocalizer {
+    localize: Option<Box<LocalizeFn>>,
+}
+
+impl SystemMessageLocalizer {
+    #[frb(sync)]
+    pub fn new(
+        localize: impl Fn(UiSystemMessageTemplate, u32) -> DartFnFuture<Option<String>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            localize: Some(Box::new(localize)),
+        }
+    }
+
+    /// Localizer using the built-in English templates
+    #[frb(sync)]
+    pub fn english() -> Self {
+        Self { localize: None }
+    }
+
+    pub async fn render(&self, message: UiSystemMessage) -> String {
+        let inputs = TemplateInputs::new(&message);
+        let localized = match &self.localize {
+            Some(localize) => localize(inputs.template, inputs.count()).await,
+            None => None,
+        };
+        let template = localized.as_deref().unwrap_or(inputs.template.english());
+        inputs.fill(template)
+    }
+}
+
+/// Sets the localizer used for system messages generated outside of the UI,
+/// e.g. in notifications. Called by the app on start and whenever the
+/// language of the UI changes.
+pub fn set_system_message_localizer(localizer: SystemMessageLocalizer) {
+    *LOCALIZER.write() = Arc::new(localizer);
+}
+
+/// Renders the given message with the localizer set by the app.
+pub async fn render_system_message(message: UiSystemMessage) -> String {
+    let localizer = LOCALIZER.read().clone();
+    localizer.render(message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn english_rendering() {
+        let localizer = SystemMessageLocalizer::english();
+        let alice = "alice@example.com".to_owned();
+        let bob = "bob@example.com".to_owned();
+        let carol = "carol@example.com".to_owned();
+
+        let joined = UiSystemMessage::Add {
+            adder: alice.clone(),
+            added: vec![alice.clone()],
+        };
+        assert_eq!(
+            localizer.render(joined).await,
+            "alice@example.com joined the conversation"
+        );
+        let added = UiSystemMessage::Add {
+            adder: alice.clone(),
+            added: vec![bob.clone(), carol],
+        };
+        assert_eq!(
+            localizer.render(added).await,
+            "alice@example.com added bob@example.com, carol@example.com to the conversation"
+        );
+        let policy = UiSystemMessage::ChangeRoomPolicy {
+            admin: bob,
+            change: UiRoomPolicyChange::SetCommitRule(UiCommitRule::AdminsOnly),
+        };
+        assert_eq!(
+            localizer.render(policy).await,
+            "bob@example.com turned on broadcast mode"
+        );
+    }
+}
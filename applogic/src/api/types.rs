@@ -4,17 +4,32 @@
 
 use std::fmt;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, NaiveTime, Timelike, Utc, Weekday};
 use flutter_rust_bridge::frb;
+use phnxapiclient::compatibility::{ServerCompatibility, UpgradeRequired};
 pub use phnxcoreclient::ConversationId;
 use phnxcoreclient::{
-    Contact, ContentMessage, Conversation, ConversationAttributes, ConversationMessage,
-    ConversationMessageId, ConversationStatus, ConversationType, ErrorMessage, EventMessage,
-    InactiveConversation, Message, MessageId, MimiContent, NotificationType, SystemMessage,
-    UserProfile,
+    Activity, ActivityKind, Announcement, BroadcastDelivery, BroadcastDeliveryStatus,
+    BroadcastList, ChatCreationConfig, ChatVisibility, Contact, ContactFilter, ContactVerification,
+    ContentMessage, Conversation, ConversationAttributes, ConversationMessage,
+    ConversationMessageId, ConversationSecurityStatus, ConversationStatus, ConversationType,
+    DeliveryFailure, ErrorMessage, EventMessage, ForwardOutcome, ForwardedFrom,
+    InactiveConversation, Message, MessageId, MimiContent, NotificationType, PendingJoinRequest,
+    SystemMessage, UserProfile,
+};
+use phnxtypes::identifiers::QualifiedUserName;
+use phnxtypes::messages::announcements::AnnouncementKind;
+use phnxtypes::messages::presence::{LastSeen, Presence, PresenceStatus, PresenceVisibility};
+use phnxtypes::messages::room_policy::{
+    CommitRule, InviteRule, JoinRule, RoomPolicy, RoomPolicyChange, SendRule,
 };
 use uuid::Uuid;
 
+use crate::app_state::chat_notifications::{
+    ChatNotificationSettings, InterruptionLevel, NotificationSound,
+};
+use crate::app_state::do_not_disturb::{DoNotDisturbSettings, QuietHours, QuietHoursTimeZone};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct _GroupId {
     pub uuid: Uuid,
@@ -54,6 +69,7 @@ pub struct UiConversation {
     pub status: UiConversationStatus,
     pub conversation_type: UiConversationType,
     pub attributes: UiConversationAttributes,
+    pub sensitive: bool,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -67,6 +83,12 @@ pub struct UiConversationDetails {
     pub attributes: UiConversationAttributes,
     pub unread_messages: u32,
     pub last_message: Option<UiConversationMessage>,
+    /// Sensitive conversations must be protected from screenshots
+    pub sensitive: bool,
+    /// Color shared with the other members in RGB
+    pub color: Option<u32>,
+    /// Emoji shared with the other members
+    pub emoji: Option<String>,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone, Hash)]
@@ -164,6 +186,7 @@ impl From<Conversation> for UiConversation {
             status: UiConversationStatus::from(conversation.status().clone()),
             conversation_type: UiConversationType::from(conversation.conversation_type().clone()),
             attributes: UiConversationAttributes::from(conversation.attributes().clone()),
+            sensitive: conversation.is_sensitive(),
         }
     }
 }
@@ -190,6 +213,13 @@ pub struct UiConversationMessage {
     pub conversation_id: ConversationId,
     pub id: UiConversationMessageId,
     pub timestamp: String, // We don't convert this to a DateTime because Dart can't handle nanoseconds.
+    /// Local receive time, see `timestamp` for the format
+    pub received_at: String,
+    /// Whether the message was delivered long after it was sent, e.g. because
+    /// the client was offline
+    pub is_delivered_late: bool,
+    /// Why sending the own message failed, if it did
+    pub delivery_failure: Option<UiDeliveryFailure>,
     pub message: UiMessage,
 }
 
@@ -199,11 +229,52 @@ impl From<ConversationMessage> for UiConversationMessage {
             conversation_id: conversation_message.conversation_id(),
             id: UiConversationMessageId::from(conversation_message.id()),
             timestamp: conversation_message.timestamp().to_rfc3339(),
+            received_at: conversation_message.received_at().to_rfc3339(),
+            is_delivered_late: conversation_message.is_delivered_late(),
+            delivery_failure: conversation_message.delivery_failure().map(From::from),
             message: UiMessage::from(conversation_message.message().clone()),
         }
     }
 }
 
+/// Reason why sending an own message failed
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UiDeliveryFailure {
+    pub kind: UiDeliveryFailureKind,
+    /// Human-readable description of the failure
+    pub description: String,
+    /// Whether retrying can succeed without further action of the user
+    pub is_transient: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum UiDeliveryFailureKind {
+    StaleEpoch,
+    NotAllowed,
+    RateLimited,
+    Network,
+    UpgradeRequired,
+    Rejected,
+}
+
+impl From<DeliveryFailure> for UiDeliveryFailure {
+    fn from(failure: DeliveryFailure) -> Self {
+        let kind = match failure {
+            DeliveryFailure::StaleEpoch => UiDeliveryFailureKind::StaleEpoch,
+            DeliveryFailure::NotAllowed => UiDeliveryFailureKind::NotAllowed,
+            DeliveryFailure::RateLimited => UiDeliveryFailureKind::RateLimited,
+            DeliveryFailure::Network => UiDeliveryFailureKind::Network,
+            DeliveryFailure::UpgradeRequired => UiDeliveryFailureKind::UpgradeRequired,
+            DeliveryFailure::Rejected => UiDeliveryFailureKind::Rejected,
+        };
+        Self {
+            kind,
+            description: failure.to_string(),
+            is_transient: failure.is_transient(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum UiMessage {
     ContentFlight(Vec<UiContentMessage>),
@@ -254,13 +325,33 @@ pub struct UiMimiContent {
     pub expires: Option<DateTime<Utc>>,
     pub in_reply_to: Option<UiReplyToInfo>,
     pub last_seen: Vec<UiMessageId>,
+    pub forwarded_from: Option<UiForwardedFrom>,
     // This will need to become more complex.
     pub body: String,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UiForwardedFrom {
+    pub sender: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl From<ForwardedFrom> for UiForwardedFrom {
+    fn from(forwarded_from: ForwardedFrom) -> Self {
+        Self {
+            sender: forwarded_from.sender.to_string(),
+            timestamp: forwarded_from.timestamp.into(),
+        }
+    }
+}
+
 impl From<MimiContent> for UiMimiContent {
     fn from(mimi_content: MimiContent) -> Self {
         let body = mimi_content.string_rendering();
+        let forwarded_from = mimi_content
+            .forwarded_from()
+            .cloned()
+            .map(UiForwardedFrom::from);
         Self {
             id: UiMessageId::from(mimi_content.id().clone()),
             timestamp: mimi_content.timestamp.into(),
@@ -276,11 +367,35 @@ impl From<MimiContent> for UiMimiContent {
                 .into_iter()
                 .map(UiMessageId::from)
                 .collect(),
+            forwarded_from,
             body,
         }
     }
 }
 
+/// Result of forwarding a message to a single conversation. Exactly one of
+/// `message` and `error` is set.
+#[derive(Debug, Clone)]
+pub struct UiForwardOutcome {
+    pub conversation_id: ConversationId,
+    pub message: Option<UiConversationMessage>,
+    pub error: Option<String>,
+}
+
+impl From<ForwardOutcome> for UiForwardOutcome {
+    fn from(outcome: ForwardOutcome) -> Self {
+        let (message, error) = match outcome.result {
+            Ok(message) => (Some(message.into()), None),
+            Err(error) => (None, Some(error.to_string())),
+        };
+        Self {
+            conversation_id: outcome.conversation_id,
+            message,
+            error,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct UiContentMessage {
     pub sender: String,
@@ -319,15 +434,47 @@ impl From<EventMessage> for UiEventMessage {
     }
 }
 
+/// A language-neutral system message. Display strings are generated with
+/// [`crate::api::system_messages::SystemMessageLocalizer`], such that they
+/// follow the language of the UI.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub struct UiSystemMessage {
-    pub message: String,
+pub enum UiSystemMessage {
+    Add {
+        adder: String,
+        added: Vec<String>,
+    },
+    Remove {
+        remover: String,
+        removed: Vec<String>,
+    },
+    ChangeRoomPolicy {
+        admin: String,
+        change: UiRoomPolicyChange,
+    },
+    PendingChangeApplied,
+    PendingChangeDiscarded,
 }
 
 impl From<SystemMessage> for UiSystemMessage {
     fn from(system_message: SystemMessage) -> Self {
-        Self {
-            message: system_message.to_string(),
+        let to_strings = |users: Vec<QualifiedUserName>| -> Vec<String> {
+            users.iter().map(ToString::to_string).collect()
+        };
+        match system_message {
+            SystemMessage::Add { adder, added } => Self::Add {
+                adder: adder.to_string(),
+                added: to_strings(added),
+            },
+            SystemMessage::Remove { remover, removed } => Self::Remove {
+                remover: remover.to_string(),
+                removed: to_strings(removed),
+            },
+            SystemMessage::ChangeRoomPolicy { admin, change } => Self::ChangeRoomPolicy {
+                admin: admin.to_string(),
+                change: change.into(),
+            },
+            SystemMessage::PendingChangeApplied => Self::PendingChangeApplied,
+            SystemMessage::PendingChangeDiscarded => Self::PendingChangeDiscarded,
         }
     }
 }
@@ -350,32 +497,351 @@ impl From<ErrorMessage> for UiErrorMessage {
 pub enum UiNotificationType {
     ConversationChange(ConversationId), // The id of the changed conversation.
     Message(UiConversationMessage),
+    ActivityChange,                  // The activity feed changed.
+    UserSettingsChange(Vec<String>), // The keys of the settings changed by another client.
+    UserProfileChange(Vec<String>),  // The users whose profiles changed.
 }
 
-impl From<NotificationType> for UiNotificationType {
-    fn from(value: NotificationType) -> Self {
-        match value {
+/// Notifications the UI doesn't know are returned as the error.
+impl TryFrom<NotificationType> for UiNotificationType {
+    type Error = NotificationType;
+
+    fn try_from(value: NotificationType) -> Result<Self, Self::Error> {
+        Ok(match value {
             NotificationType::ConversationChange(conversation_id) => {
                 UiNotificationType::ConversationChange(conversation_id)
             }
             NotificationType::Message(message) => UiNotificationType::Message(message.into()),
-        }
+            NotificationType::ActivityChange => UiNotificationType::ActivityChange,
+            NotificationType::UserSettingsChange(settings) => {
+                UiNotificationType::UserSettingsChange(settings)
+            }
+            NotificationType::UserProfileChange(user_names) => {
+                UiNotificationType::UserProfileChange(
+                    user_names.iter().map(ToString::to_string).collect(),
+                )
+            }
+            notification => return Err(notification),
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct UiContact {
     pub user_name: String,
+    /// Unknown if the contact hides it, is on another server or wasn't
+    /// fetched recently
+    pub presence: Option<UiPresence>,
+    /// Whether the user marked the contact as verified
+    pub verified: bool,
+}
+
+impl UiContact {
+    pub(crate) fn new(contact: Contact, presence: Option<Presence>) -> Self {
+        Self {
+            user_name: contact.user_name().to_string(),
+            presence: presence.map(From::from),
+            verified: contact.verified_at().is_some(),
+        }
+    }
 }
 
 impl From<Contact> for UiContact {
     fn from(contact: Contact) -> Self {
+        Self::new(contact, None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiContactVerification {
+    Verified,
+    Unverified,
+}
+
+impl From<UiContactVerification> for ContactVerification {
+    fn from(verification: UiContactVerification) -> Self {
+        match verification {
+            UiContactVerification::Verified => Self::Verified,
+            UiContactVerification::Unverified => Self::Unverified,
+        }
+    }
+}
+
+/// Conditions for contact list queries. Only contacts that satisfy all
+/// conditions that are set are returned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UiContactFilter {
+    pub verification: Option<UiContactVerification>,
+    pub blocked: Option<bool>,
+    /// Whether the chat with the contact is active
+    pub active_chat: Option<bool>,
+    /// Alphabetical window of user names, e.g. `"a"` to `"d"` for the
+    /// contacts from A to C. The start is inclusive, the end exclusive.
+    pub name_from: Option<String>,
+    pub name_until: Option<String>,
+}
+
+impl From<UiContactFilter> for ContactFilter {
+    fn from(filter: UiContactFilter) -> Self {
         Self {
-            user_name: contact.user_name().to_string(),
+            verification: filter.verification.map(From::from),
+            blocked: filter.blocked,
+            active_chat: filter.active_chat,
+            name_from: filter.name_from,
+            name_until: filter.name_until,
+        }
+    }
+}
+
+/// A page of contacts, ordered by user name.
+#[derive(Debug, Clone)]
+pub struct UiContactsPage {
+    pub contacts: Vec<UiContact>,
+    /// User name to continue the query after. `None` if this is the last
+    /// page.
+    pub next_after: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UiPresence {
+    pub status: UiPresenceStatus,
+    /// Only set if the contact is offline
+    pub last_seen: Option<UiLastSeen>,
+}
+
+impl From<Presence> for UiPresence {
+    fn from(presence: Presence) -> Self {
+        Self {
+            status: presence.status.into(),
+            last_seen: presence.last_seen.map(From::from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiPresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+impl From<PresenceStatus> for UiPresenceStatus {
+    fn from(status: PresenceStatus) -> Self {
+        match status {
+            PresenceStatus::Online => UiPresenceStatus::Online,
+            PresenceStatus::Away => UiPresenceStatus::Away,
+            PresenceStatus::Offline => UiPresenceStatus::Offline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiLastSeen {
+    /// Within the last hour
+    Recently,
+    /// Within the last day
+    Today,
+    /// Within the last week
+    ThisWeek,
+    LongAgo,
+}
+
+impl From<LastSeen> for UiLastSeen {
+    fn from(last_seen: LastSeen) -> Self {
+        match last_seen {
+            LastSeen::Recently => UiLastSeen::Recently,
+            LastSeen::Today => UiLastSeen::Today,
+            LastSeen::ThisWeek => UiLastSeen::ThisWeek,
+            LastSeen::LongAgo => UiLastSeen::LongAgo,
+        }
+    }
+}
+
+/// Who can see the presence of the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiPresenceVisibility {
+    Nobody,
+    Contacts,
+    Everyone,
+}
+
+impl From<PresenceVisibility> for UiPresenceVisibility {
+    fn from(visibility: PresenceVisibility) -> Self {
+        match visibility {
+            PresenceVisibility::Nobody => UiPresenceVisibility::Nobody,
+            PresenceVisibility::Contacts => UiPresenceVisibility::Contacts,
+            PresenceVisibility::Everyone => UiPresenceVisibility::Everyone,
+        }
+    }
+}
+
+impl From<UiPresenceVisibility> for PresenceVisibility {
+    fn from(visibility: UiPresenceVisibility) -> Self {
+        match visibility {
+            UiPresenceVisibility::Nobody => PresenceVisibility::Nobody,
+            UiPresenceVisibility::Contacts => PresenceVisibility::Contacts,
+            UiPresenceVisibility::Everyone => PresenceVisibility::Everyone,
+        }
+    }
+}
+
+/// Do Not Disturb settings and state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiDoNotDisturb {
+    /// Whether DND is active right now
+    pub active: bool,
+    pub quiet_hours: Option<UiQuietHours>,
+    /// Whether DND is switched on manually
+    pub manual: bool,
+    /// End of the manual DND. Until switched off if not set.
+    pub manual_until: Option<DateTime<Utc>>,
+    /// Contacts whose messages are notified during DND
+    pub starred_contacts: Vec<String>,
+    /// Whether messages mentioning the user are notified during DND
+    pub mentions: bool,
+}
+
+impl UiDoNotDisturb {
+    pub(crate) fn new(settings: &DoNotDisturbSettings, now: DateTime<Utc>) -> Self {
+        Self {
+            active: settings.is_active(now),
+            quiet_hours: settings.quiet_hours.as_ref().map(From::from),
+            manual: settings.manual.is_some(),
+            manual_until: settings.manual.and_then(|manual| manual.until),
+            starred_contacts: settings
+                .exceptions
+                .starred_contacts
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            mentions: settings.exceptions.mentions,
+        }
+    }
+}
+
+/// Daily quiet hours
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiQuietHours {
+    /// Minutes since midnight
+    pub start_minute: u16,
+    /// Minutes since midnight. If before the start, the quiet hours end on the next day.
+    pub end_minute: u16,
+    /// Days on which the quiet hours start, with 0 being Monday. Every day if empty.
+    pub days: Vec<u8>,
+    /// Offset from UTC in seconds. The time zone of the device if not set.
+    pub utc_offset_secs: Option<i32>,
+}
+
+impl From<&QuietHours> for UiQuietHours {
+    fn from(quiet_hours: &QuietHours) -> Self {
+        let minute_of_day = |time: NaiveTime| (time.num_seconds_from_midnight() / 60) as u16;
+        Self {
+            start_minute: minute_of_day(quiet_hours.start),
+            end_minute: minute_of_day(quiet_hours.end),
+            days: quiet_hours
+                .days
+                .iter()
+                .map(|day| day.num_days_from_monday() as u8)
+                .collect(),
+            utc_offset_secs: match quiet_hours.time_zone {
+                QuietHoursTimeZone::DeviceLocal => None,
+                QuietHoursTimeZone::FixedOffset(offset) => Some(offset),
+            },
         }
     }
 }
 
+impl TryFrom<UiQuietHours> for QuietHours {
+    type Error = anyhow::Error;
+
+    fn try_from(quiet_hours: UiQuietHours) -> anyhow::Result<Self> {
+        let time = |minute: u16| {
+            NaiveTime::from_hms_opt(u32::from(minute / 60), u32::from(minute % 60), 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid minute of day: {minute}"))
+        };
+        let days = quiet_hours
+            .days
+            .into_iter()
+            .map(|day| Weekday::try_from(day).map_err(|_| anyhow::anyhow!("Invalid day: {day}")))
+            .collect::<anyhow::Result<_>>()?;
+        let time_zone = match quiet_hours.utc_offset_secs {
+            None => QuietHoursTimeZone::DeviceLocal,
+            Some(offset) => {
+                FixedOffset::east_opt(offset)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid UTC offset: {offset}"))?;
+                QuietHoursTimeZone::FixedOffset(offset)
+            }
+        };
+        Ok(Self {
+            start: time(quiet_hours.start_minute)?,
+            end: time(quiet_hours.end_minute)?,
+            days,
+            time_zone,
+        })
+    }
+}
+
+/// Notification alert customization of a chat
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UiChatNotificationSettings {
+    pub sound: UiNotificationSound,
+    pub interruption_level: UiInterruptionLevel,
+}
+
+impl From<&ChatNotificationSettings> for UiChatNotificationSettings {
+    fn from(settings: &ChatNotificationSettings) -> Self {
+        Self {
+            sound: match &settings.sound {
+                NotificationSound::Default => UiNotificationSound::Default,
+                NotificationSound::None => UiNotificationSound::None,
+                NotificationSound::Named(name) => UiNotificationSound::Named(name.clone()),
+            },
+            interruption_level: match settings.interruption_level {
+                InterruptionLevel::Passive => UiInterruptionLevel::Passive,
+                InterruptionLevel::Active => UiInterruptionLevel::Active,
+                InterruptionLevel::TimeSensitive => UiInterruptionLevel::TimeSensitive,
+            },
+        }
+    }
+}
+
+impl From<UiChatNotificationSettings> for (NotificationSound, InterruptionLevel) {
+    fn from(settings: UiChatNotificationSettings) -> Self {
+        let sound = match settings.sound {
+            UiNotificationSound::Default => NotificationSound::Default,
+            UiNotificationSound::None => NotificationSound::None,
+            UiNotificationSound::Named(name) => NotificationSound::Named(name),
+        };
+        let interruption_level = match settings.interruption_level {
+            UiInterruptionLevel::Passive => InterruptionLevel::Passive,
+            UiInterruptionLevel::Active => InterruptionLevel::Active,
+            UiInterruptionLevel::TimeSensitive => InterruptionLevel::TimeSensitive,
+        };
+        (sound, interruption_level)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum UiNotificationSound {
+    #[default]
+    Default,
+    None,
+    /// Sound file bundled with the app
+    Named(String),
+}
+
+/// How urgently notifications interrupt the user
+///
+/// Maps to the interruption level on iOS and macOS and to the channel importance on Android.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UiInterruptionLevel {
+    Passive,
+    #[default]
+    Active,
+    /// Breaks through focus modes
+    TimeSensitive,
+}
+
 pub struct UiUserProfile {
     pub user_name: String,
     pub display_name: Option<String>,
@@ -394,3 +860,476 @@ impl UiUserProfile {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiJoinRule {
+    InviteOnly,
+    Knock,
+    MessageRequest,
+}
+
+impl From<JoinRule> for UiJoinRule {
+    fn from(join_rule: JoinRule) -> Self {
+        match join_rule {
+            JoinRule::InviteOnly => UiJoinRule::InviteOnly,
+            JoinRule::Knock => UiJoinRule::Knock,
+            JoinRule::MessageRequest => UiJoinRule::MessageRequest,
+        }
+    }
+}
+
+impl From<UiJoinRule> for JoinRule {
+    fn from(join_rule: UiJoinRule) -> Self {
+        match join_rule {
+            UiJoinRule::InviteOnly => JoinRule::InviteOnly,
+            UiJoinRule::Knock => JoinRule::Knock,
+            UiJoinRule::MessageRequest => JoinRule::MessageRequest,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiInviteRule {
+    AllMembers,
+    AdminsOnly,
+}
+
+impl From<InviteRule> for UiInviteRule {
+    fn from(invite_rule: InviteRule) -> Self {
+        match invite_rule {
+            InviteRule::AllMembers => UiInviteRule::AllMembers,
+            InviteRule::AdminsOnly => UiInviteRule::AdminsOnly,
+        }
+    }
+}
+
+impl From<UiInviteRule> for InviteRule {
+    fn from(invite_rule: UiInviteRule) -> Self {
+        match invite_rule {
+            UiInviteRule::AllMembers => InviteRule::AllMembers,
+            UiInviteRule::AdminsOnly => InviteRule::AdminsOnly,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiSendRule {
+    AllMembers,
+    AdminsOnly,
+}
+
+impl From<SendRule> for UiSendRule {
+    fn from(send_rule: SendRule) -> Self {
+        match send_rule {
+            SendRule::AllMembers => UiSendRule::AllMembers,
+            SendRule::AdminsOnly => UiSendRule::AdminsOnly,
+        }
+    }
+}
+
+impl From<UiSendRule> for SendRule {
+    fn from(send_rule: UiSendRule) -> Self {
+        match send_rule {
+            UiSendRule::AllMembers => SendRule::AllMembers,
+            UiSendRule::AdminsOnly => SendRule::AdminsOnly,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiCommitRule {
+    AllMembers,
+    /// Broadcast mode
+    AdminsOnly,
+}
+
+impl From<CommitRule> for UiCommitRule {
+    fn from(commit_rule: CommitRule) -> Self {
+        match commit_rule {
+            CommitRule::AllMembers => UiCommitRule::AllMembers,
+            CommitRule::AdminsOnly => UiCommitRule::AdminsOnly,
+        }
+    }
+}
+
+impl From<UiCommitRule> for CommitRule {
+    fn from(commit_rule: UiCommitRule) -> Self {
+        match commit_rule {
+            UiCommitRule::AllMembers => CommitRule::AllMembers,
+            UiCommitRule::AdminsOnly => CommitRule::AdminsOnly,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UiRoomPolicy {
+    pub join_rule: UiJoinRule,
+    pub invite_rule: UiInviteRule,
+    pub send_rule: UiSendRule,
+    pub commit_rule: UiCommitRule,
+    /// Whether the own user is an admin and can change the policy.
+    pub can_edit: bool,
+}
+
+impl UiRoomPolicy {
+    pub(crate) fn new(room_policy: RoomPolicy, can_edit: bool) -> Self {
+        Self {
+            join_rule: room_policy.join_rule.into(),
+            invite_rule: room_policy.invite_rule.into(),
+            send_rule: room_policy.send_rule.into(),
+            commit_rule: room_policy.commit_rule.into(),
+            can_edit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiRoomPolicyChange {
+    SetJoinRule(UiJoinRule),
+    SetInviteRule(UiInviteRule),
+    SetSendRule(UiSendRule),
+    SetCommitRule(UiCommitRule),
+}
+
+impl From<RoomPolicyChange> for UiRoomPolicyChange {
+    fn from(change: RoomPolicyChange) -> Self {
+        match change {
+            RoomPolicyChange::SetJoinRule(join_rule) => {
+                UiRoomPolicyChange::SetJoinRule(join_rule.into())
+            }
+            RoomPolicyChange::SetInviteRule(invite_rule) => {
+                UiRoomPolicyChange::SetInviteRule(invite_rule.into())
+            }
+            RoomPolicyChange::SetSendRule(send_rule) => {
+                UiRoomPolicyChange::SetSendRule(send_rule.into())
+            }
+            RoomPolicyChange::SetCommitRule(commit_rule) => {
+                UiRoomPolicyChange::SetCommitRule(commit_rule.into())
+            }
+        }
+    }
+}
+
+impl From<UiRoomPolicyChange> for RoomPolicyChange {
+    fn from(change: UiRoomPolicyChange) -> Self {
+        match change {
+            UiRoomPolicyChange::SetJoinRule(join_rule) => {
+                RoomPolicyChange::SetJoinRule(join_rule.into())
+            }
+            UiRoomPolicyChange::SetInviteRule(invite_rule) => {
+                RoomPolicyChange::SetInviteRule(invite_rule.into())
+            }
+            UiRoomPolicyChange::SetSendRule(send_rule) => {
+                RoomPolicyChange::SetSendRule(send_rule.into())
+            }
+            UiRoomPolicyChange::SetCommitRule(commit_rule) => {
+                RoomPolicyChange::SetCommitRule(commit_rule.into())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiChatVisibility {
+    Private,
+    Knockable,
+}
+
+impl From<UiChatVisibility> for ChatVisibility {
+    fn from(visibility: UiChatVisibility) -> Self {
+        match visibility {
+            UiChatVisibility::Private => ChatVisibility::Private,
+            UiChatVisibility::Knockable => ChatVisibility::Knockable,
+        }
+    }
+}
+
+/// Initial permissions of a new chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UiChatCreationConfig {
+    pub invite_rule: UiInviteRule,
+    pub send_rule: UiSendRule,
+    pub commit_rule: UiCommitRule,
+    pub visibility: UiChatVisibility,
+}
+
+impl From<UiChatCreationConfig> for ChatCreationConfig {
+    fn from(config: UiChatCreationConfig) -> Self {
+        ChatCreationConfig {
+            invite_rule: config.invite_rule.into(),
+            send_rule: config.send_rule.into(),
+            commit_rule: config.commit_rule.into(),
+            visibility: config.visibility.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UiJoinRequest {
+    pub user_name: String,
+    pub received_at: DateTime<Utc>,
+}
+
+impl From<PendingJoinRequest> for UiJoinRequest {
+    fn from(join_request: PendingJoinRequest) -> Self {
+        Self {
+            user_name: join_request.user_name.to_string(),
+            received_at: join_request.received_at.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiBroadcastList {
+    pub id: Uuid,
+    pub name: String,
+    pub recipients: Vec<String>,
+}
+
+impl From<BroadcastList> for UiBroadcastList {
+    fn from(list: BroadcastList) -> Self {
+        Self {
+            id: *list.id().as_uuid(),
+            name: list.name().to_owned(),
+            recipients: list.recipients().iter().map(|r| r.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiBroadcastDeliveryStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+impl From<BroadcastDeliveryStatus> for UiBroadcastDeliveryStatus {
+    fn from(status: BroadcastDeliveryStatus) -> Self {
+        match status {
+            BroadcastDeliveryStatus::Pending => Self::Pending,
+            BroadcastDeliveryStatus::Sent => Self::Sent,
+            BroadcastDeliveryStatus::Failed => Self::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiBroadcastDelivery {
+    pub broadcast_id: Uuid,
+    pub recipient: String,
+    pub conversation_id: Option<ConversationId>,
+    pub status: UiBroadcastDeliveryStatus,
+}
+
+impl From<BroadcastDelivery> for UiBroadcastDelivery {
+    fn from(delivery: BroadcastDelivery) -> Self {
+        Self {
+            broadcast_id: delivery.broadcast_id(),
+            recipient: delivery.recipient().to_string(),
+            conversation_id: delivery.conversation_id(),
+            status: delivery.status().into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UiAnnouncementKind {
+    Info,
+    Maintenance,
+    PolicyChange,
+}
+
+impl From<AnnouncementKind> for UiAnnouncementKind {
+    fn from(kind: AnnouncementKind) -> Self {
+        match kind {
+            AnnouncementKind::Info => Self::Info,
+            AnnouncementKind::Maintenance => Self::Maintenance,
+            AnnouncementKind::PolicyChange => Self::PolicyChange,
+        }
+    }
+}
+
+/// Announcement of the operator of the user's server
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UiAnnouncement {
+    pub id: String,
+    pub kind: UiAnnouncementKind,
+    pub title: String,
+    pub body: String,
+    pub published_at: DateTime<Utc>,
+    pub read: bool,
+}
+
+impl From<Announcement> for UiAnnouncement {
+    fn from(announcement: Announcement) -> Self {
+        Self {
+            id: announcement.id().to_owned(),
+            kind: announcement.kind().into(),
+            title: announcement.title().to_owned(),
+            body: announcement.body().to_owned(),
+            published_at: announcement.published_at().into(),
+            read: announcement.is_read(),
+        }
+    }
+}
+
+/// Entry summarizing the announcements in the conversation list
+#[frb(dart_metadata = ("freezed"))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UiAnnouncementsEntry {
+    pub unread_count: usize,
+    pub latest_kind: UiAnnouncementKind,
+    pub latest_title: String,
+    pub latest_published_at: DateTime<Utc>,
+}
+
+impl UiAnnouncementsEntry {
+    /// Summary of the given announcements ordered newest first. Returns
+    /// `None` if there are no announcements.
+    pub(crate) fn new(announcements: &[UiAnnouncement]) -> Option<Self> {
+        let latest = announcements.first()?;
+        Some(Self {
+            unread_count: announcements.iter().filter(|a| !a.read).count(),
+            latest_kind: latest.kind,
+            latest_title: latest.title.clone(),
+            latest_published_at: latest.published_at,
+        })
+    }
+}
+
+/// Whether this client can still use the user's server
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UiServerCompatibility {
+    Compatible,
+    /// The client relies on the given features, which the server is going to
+    /// remove. The user should upgrade the app.
+    Deprecated {
+        features: Vec<String>,
+    },
+    /// The app must be upgraded before it can be used. `min_version` is the
+    /// oldest version the server serves, if known.
+    UpgradeRequired {
+        min_version: Option<String>,
+    },
+}
+
+impl From<ServerCompatibility> for UiServerCompatibility {
+    fn from(compatibility: ServerCompatibility) -> Self {
+        match compatibility {
+            ServerCompatibility::Compatible => Self::Compatible,
+            ServerCompatibility::Deprecated { features } => Self::Deprecated {
+                features: features
+                    .iter()
+                    .map(|feature| feature.as_str().to_owned())
+                    .collect(),
+            },
+            ServerCompatibility::UpgradeRequired(reason) => Self::UpgradeRequired {
+                min_version: match reason {
+                    UpgradeRequired::ClientTooOld { min_version } => Some(min_version.to_string()),
+                    UpgradeRequired::UnsupportedProtocol | UpgradeRequired::RequestRejected => None,
+                },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UiActivityKind {
+    Mention {
+        sender: String,
+        message_id: UiConversationMessageId,
+    },
+    Invite,
+    JoinRequest {
+        requester: String,
+    },
+    FailedDelivery {
+        message_id: UiConversationMessageId,
+    },
+    SecurityWarning {
+        reason: String,
+    },
+}
+
+impl From<ActivityKind> for UiActivityKind {
+    fn from(kind: ActivityKind) -> Self {
+        match kind {
+            ActivityKind::Mention { sender, message_id } => Self::Mention {
+                sender: sender.to_string(),
+                message_id: message_id.into(),
+            },
+            ActivityKind::Invite => Self::Invite,
+            ActivityKind::JoinRequest { requester } => Self::JoinRequest {
+                requester: requester.to_string(),
+            },
+            ActivityKind::FailedDelivery { message_id } => Self::FailedDelivery {
+                message_id: message_id.into(),
+            },
+            ActivityKind::SecurityWarning { reason } => Self::SecurityWarning { reason },
+        }
+    }
+}
+
+/// Entry of the activity feed shown in the notification center
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UiActivity {
+    pub id: Uuid,
+    pub kind: UiActivityKind,
+    /// The conversation to navigate to when the entry is tapped, if any
+    pub conversation_id: Option<ConversationId>,
+    pub created_at: DateTime<Utc>,
+    pub read: bool,
+}
+
+impl From<Activity> for UiActivity {
+    fn from(activity: Activity) -> Self {
+        Self {
+            id: *activity.id().as_uuid(),
+            kind: activity.kind().clone().into(),
+            conversation_id: activity.conversation_id(),
+            created_at: activity.created_at().into(),
+            read: activity.is_read(),
+        }
+    }
+}
+
+/// Encryption health of a chat, shown as a shield indicator.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChatSecurityStatus {
+    /// `None` if the time of the last key rotation is unknown
+    pub last_key_rotation: Option<DateTime<Utc>>,
+    pub members_with_expired_credentials: Vec<String>,
+    pub members_with_revoked_credentials: Vec<String>,
+    /// Members whose credentials couldn't be checked
+    pub members_with_unknown_credentials: Vec<String>,
+    pub stale_pending_proposals: u32,
+    pub uses_default_ciphersuite: bool,
+    /// Whether all checks passed
+    pub is_healthy: bool,
+}
+
+impl From<ConversationSecurityStatus> for ChatSecurityStatus {
+    fn from(status: ConversationSecurityStatus) -> Self {
+        Self {
+            is_healthy: status.is_healthy(),
+            last_key_rotation: status.last_key_rotation.map(From::from),
+            members_with_expired_credentials: status
+                .members_with_expired_credentials
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            members_with_revoked_credentials: status
+                .members_with_revoked_credentials
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            members_with_unknown_credentials: status
+                .members_with_unknown_credentials
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            stale_pending_proposals: status.stale_pending_proposals as u32,
+            uses_default_ciphersuite: status.uses_default_ciphersuite,
+        }
+    }
+}
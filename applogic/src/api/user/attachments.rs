@@ -0,0 +1,258 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, Result};
+use flutter_rust_bridge::{frb, DartFnFuture};
+use phnxcoreclient::{
+    AttachmentKind, ConversationId, ConversationMessage, MediaCodec, MediaTranscoding, Message,
+    NetworkType, NoMediaCodec, VideoCodec, VideoTranscodeParams,
+};
+use tracing::warn;
+
+use crate::api::types::UiConversationMessage;
+
+use super::User;
+
+type TranscodeVideoFn =
+    dyn Fn(Vec<u8>, UiVideoTranscodeParams) -> DartFnFuture<Option<Vec<u8>>> + Send + Sync;
+
+pub enum UiNetworkType {
+    Wifi,
+    Cellular,
+    Unknown,
+}
+
+impl From<UiNetworkType> for NetworkType {
+    fn from(network_type: UiNetworkType) -> Self {
+        match network_type {
+            UiNetworkType::Wifi => NetworkType::Wifi,
+            UiNetworkType::Cellular => NetworkType::Cellular,
+            UiNetworkType::Unknown => NetworkType::Unknown,
+        }
+    }
+}
+
+pub enum UiAttachmentKind {
+    Image,
+    Video,
+    Audio,
+    File,
+}
+
+impl From<UiAttachmentKind> for AttachmentKind {
+    fn from(kind: UiAttachmentKind) -> Self {
+        match kind {
+            UiAttachmentKind::Image => AttachmentKind::Image,
+            UiAttachmentKind::Video => AttachmentKind::Video,
+            UiAttachmentKind::Audio => AttachmentKind::Audio,
+            UiAttachmentKind::File => AttachmentKind::File,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiVideoCodec {
+    H264,
+    Hevc,
+}
+
+impl From<VideoCodec> for UiVideoCodec {
+    fn from(codec: VideoCodec) -> Self {
+        match codec {
+            VideoCodec::H264 => Self::H264,
+            VideoCodec::Hevc => Self::Hevc,
+        }
+    }
+}
+
+impl From<UiVideoCodec> for VideoCodec {
+    fn from(codec: UiVideoCodec) -> Self {
+        match codec {
+            UiVideoCodec::H264 => Self::H264,
+            UiVideoCodec::Hevc => Self::Hevc,
+        }
+    }
+}
+
+/// Parameters of a video transcoding requested from the platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UiVideoTranscodeParams {
+    pub codec: UiVideoCodec,
+    /// Maximum size of the transcoded video in bytes. No limit if not set.
+    pub max_size: Option<u64>,
+    /// Maximum width and height of the transcoded video. No limit if not set.
+    pub max_dimension: Option<u32>,
+}
+
+impl From<VideoTranscodeParams> for UiVideoTranscodeParams {
+    fn from(params: VideoTranscodeParams) -> Self {
+        Self {
+            codec: params.codec.into(),
+            max_size: params.max_size,
+            max_dimension: params.max_dimension,
+        }
+    }
+}
+
+/// Platform video codec implemented by the app.
+///
+/// The callback transcodes the given video and returns `None` if the platform fails to transcode
+/// it. The transcoded video must not contain any metadata of the original video.
+#[frb(opaque)]
+pub struct DartMediaCodec {
+    transcode_video: Option<Box<TranscodeVideoFn>>,
+}
+
+impl DartMediaCodec {
+    #[frb(sync)]
+    pub fn new(
+        transcode_video: impl Fn(Vec<u8>, UiVideoTranscodeParams) -> DartFnFuture<Option<Vec<u8>>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            transcode_video: Some(Box::new(transcode_video)),
+        }
+    }
+
+    /// Codec for platforms that can't transcode videos. Videos are sent as they are.
+    #[frb(sync)]
+    pub fn unsupported() -> Self {
+        Self {
+            transcode_video: None,
+        }
+    }
+}
+
+impl MediaCodec for DartMediaCodec {
+    async fn transcode_video(
+        &self,
+        content: Vec<u8>,
+        params: VideoTranscodeParams,
+    ) -> Result<Vec<u8>> {
+        let Some(transcode_video) = &self.transcode_video else {
+            return NoMediaCodec.transcode_video(content, params).await;
+        };
+        transcode_video(content, params.into())
+            .await
+            .ok_or_else(|| anyhow!("Platform codec failed to transcode the video"))
+    }
+}
+
+/// Settings of the transcoding of images and videos before they are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiMediaTranscoding {
+    pub enabled: bool,
+    /// Images whose width or height exceeds this are downscaled
+    pub max_image_dimension: u32,
+    /// JPEG quality between 1 and 100
+    pub image_quality: u8,
+    pub video_codec: UiVideoCodec,
+    pub max_video_size: u64,
+    pub max_video_dimension: u32,
+}
+
+impl From<MediaTranscoding> for UiMediaTranscoding {
+    fn from(settings: MediaTranscoding) -> Self {
+        Self {
+            enabled: settings.enabled,
+            max_image_dimension: settings.max_image_dimension,
+            image_quality: settings.image_quality,
+            video_codec: settings.video_codec.into(),
+            max_video_size: settings.max_video_size,
+            max_video_dimension: settings.max_video_dimension,
+        }
+    }
+}
+
+impl From<UiMediaTranscoding> for MediaTranscoding {
+    fn from(settings: UiMediaTranscoding) -> Self {
+        Self {
+            enabled: settings.enabled,
+            max_image_dimension: settings.max_image_dimension,
+            image_quality: settings.image_quality.clamp(1, 100),
+            video_codec: settings.video_codec.into(),
+            max_video_size: settings.max_video_size,
+            max_video_dimension: settings.max_video_dimension,
+        }
+    }
+}
+
+impl User {
+    /// Called by the platform whenever the type of the network the device is
+    /// connected to changes.
+    pub fn report_network_type(&self, network_type: UiNetworkType) {
+        self.app_state.set_network_type(network_type.into());
+    }
+
+    /// Download the attachments of the given received messages that the
+    /// auto-download policy allows on the currently reported network.
+    pub(crate) async fn auto_download_attachments(&self, messages: &[ConversationMessage]) {
+        let network_type = self.app_state.network_type();
+        for message in messages {
+            if !matches!(message.message(), Message::Content(_)) {
+                continue;
+            }
+            if let Err(error) = self
+                .user
+                .auto_download_message_attachments(message.id(), network_type)
+                .await
+            {
+                warn!(%error, "Failed to download attachments automatically");
+            }
+        }
+    }
+
+    /// Returns true if an attachment of the given kind and size should be
+    /// downloaded automatically on the currently reported network.
+    pub async fn should_auto_download(
+        &self,
+        conversation_id: ConversationId,
+        kind: UiAttachmentKind,
+        size: u64,
+    ) -> Result<bool> {
+        self.user
+            .should_auto_download(
+                conversation_id,
+                kind.into(),
+                size,
+                self.app_state.network_type(),
+            )
+            .await
+    }
+
+    pub async fn media_transcoding(&self) -> Result<UiMediaTranscoding> {
+        let settings: MediaTranscoding = self.user.user_setting().await?;
+        Ok(settings.into())
+    }
+
+    pub async fn set_media_transcoding(&self, settings: UiMediaTranscoding) -> Result<()> {
+        self.user
+            .set_user_setting(&MediaTranscoding::from(settings))
+            .await
+    }
+
+    /// Transcode the given image or video and send it as a view-once attachment.
+    ///
+    /// With `original_quality`, the media is not downscaled, but its metadata is still stripped.
+    pub async fn send_view_once_attachment(
+        &self,
+        conversation_id: ConversationId,
+        kind: UiAttachmentKind,
+        content: Vec<u8>,
+        original_quality: bool,
+        codec: &DartMediaCodec,
+    ) -> Result<UiConversationMessage> {
+        let content = self
+            .user
+            .transcode_attachment(codec, kind.into(), content, original_quality)
+            .await?;
+        let message = self
+            .user
+            .send_view_once_attachment(conversation_id, content)
+            .await?;
+        Ok(message.into())
+    }
+}
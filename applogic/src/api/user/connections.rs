@@ -3,9 +3,10 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use anyhow::Result;
+use phnxcoreclient::ContactListCursor;
 use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
 
-use crate::api::types::{UiContact, UiUserProfile};
+use crate::api::types::{UiContact, UiContactFilter, UiContactsPage, UiUserProfile};
 
 use super::User;
 
@@ -16,13 +17,70 @@ impl User {
             .await
             .unwrap_or_default()
             .into_iter()
-            .map(|c| c.into())
+            .map(|c| {
+                let presence = self.user.contact_presence(&c.user_name);
+                UiContact::new(c, presence)
+            })
             .collect()
     }
 
+    /// Load up to `limit` contacts matching the filter, ordered by user name.
+    ///
+    /// Start with `after` set to `None` and continue with the `next_after` of
+    /// the previous page.
+    pub async fn contacts_page(
+        &self,
+        filter: UiContactFilter,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<UiContactsPage> {
+        let cursor = match after {
+            Some(after) => ContactListCursor::after(filter.into(), SafeTryInto::try_into(after)?),
+            None => ContactListCursor::new(filter.into()),
+        };
+        let page = self.user.contacts_page(cursor, limit).await?;
+        let contacts = page
+            .contacts
+            .into_iter()
+            .map(|c| {
+                let presence = self.user.contact_presence(&c.user_name);
+                UiContact::new(c, presence)
+            })
+            .collect();
+        let next_after = page
+            .next_cursor
+            .and_then(|cursor| cursor.last_user_name().map(ToString::to_string));
+        Ok(UiContactsPage {
+            contacts,
+            next_after,
+        })
+    }
+
+    /// The number of contacts matching the filter
+    pub async fn count_contacts(&self, filter: UiContactFilter) -> Result<u64> {
+        Ok(self.user.count_contacts(&filter.into()).await?)
+    }
+
+    /// Mark the contact as verified, or clear the mark.
+    pub async fn set_contact_verified(&self, user_name: String, verified: bool) -> Result<()> {
+        let user_name = SafeTryInto::try_into(user_name)?;
+        self.user.set_contact_verified(&user_name, verified).await
+    }
+
     pub async fn contact(&self, user_name: String) -> Option<UiContact> {
         let user_name = <String as SafeTryInto<QualifiedUserName>>::try_into(user_name).unwrap();
-        self.user.contact(&user_name).await.map(|c| c.into())
+        let presence = self.user.contact_presence(&user_name);
+        self.user
+            .contact(&user_name)
+            .await
+            .map(|c| UiContact::new(c, presence))
+    }
+
+    /// Fetch the presence of all contacts. Afterwards, the presence is part
+    /// of the contacts returned by [`Self::get_contacts`].
+    pub async fn refresh_contact_presence(&self) -> Result<()> {
+        self.user.refresh_contact_presence().await?;
+        Ok(())
     }
 
     /// Get the user profile of the user with the given [`QualifiedUserName`].
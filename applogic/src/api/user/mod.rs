@@ -15,8 +15,8 @@ use phnxtypes::{
 use tracing::error;
 
 use crate::{
-    api::types::UiNotificationType,
-    app_state::state::AppState,
+    api::{key_store::MasterKeyVault, types::UiNotificationType},
+    app_state::{compatibility::client_version, metrics::MetricsRecorder, state::AppState},
     notifier::{Notifiable, NotificationHub},
     StreamSink,
 };
@@ -24,6 +24,7 @@ use crate::{
 pub(crate) use phnxcoreclient::NotificationType;
 pub(crate) use phnxtypes::messages::push_token::PushToken;
 
+pub mod attachments;
 pub mod connections;
 pub mod user_cubit;
 
@@ -54,8 +55,11 @@ pub struct DartNotifier {
 
 impl Notifiable for DartNotifier {
     fn notify(&self, notification_type: NotificationType) -> bool {
-        let ui_notification_type = UiNotificationType::from(notification_type);
-        self.stream_sink.add(ui_notification_type).is_ok()
+        match UiNotificationType::try_from(notification_type) {
+            Ok(ui_notification_type) => self.stream_sink.add(ui_notification_type).is_ok(),
+            // Skip notifications the UI doesn't handle yet
+            Err(_) => true,
+        }
     }
 }
 
@@ -69,6 +73,7 @@ pub struct User {
     pub(crate) user: CoreUser,
     pub(crate) app_state: AppState,
     pub(crate) notification_hub: NotificationHub<DartNotifier>,
+    pub(crate) metrics: MetricsRecorder,
 }
 
 impl User {
@@ -77,6 +82,7 @@ impl User {
             user: core_user.clone(),
             app_state: AppState::new(core_user),
             notification_hub: Default::default(),
+            metrics: Default::default(),
         }
     }
 
@@ -104,6 +110,7 @@ impl User {
             push_token.map(|p| p.into()),
         )
         .await?;
+        user.set_client_version(client_version())?;
 
         if let Err(error) = CoreUser::set_own_user_profile(&user, user_profile).await {
             error!(%error, "Could not set own user profile");
@@ -113,6 +120,7 @@ impl User {
             user: user.clone(),
             app_state: AppState::new(user),
             notification_hub: NotificationHub::<DartNotifier>::default(),
+            metrics: MetricsRecorder::default(),
         })
     }
 
@@ -129,11 +137,17 @@ impl User {
                     as_client_id.to_string()
                 )
             })?;
+        user.set_client_version(client_version())?;
+
+        if let Err(error) = user.apply_media_retention().await {
+            error!(%error, "Could not apply media retention policy");
+        }
 
         Ok(Self {
             user: user.clone(),
             app_state: AppState::new(user),
             notification_hub: NotificationHub::<DartNotifier>::default(),
+            metrics: MetricsRecorder::default(),
         })
     }
 
@@ -200,4 +214,15 @@ impl User {
             .await?;
         Ok(())
     }
+
+    /// Wipe all local data of the device after a panic gesture. Locks the
+    /// vault and deletes the master key from the platform key store.
+    ///
+    /// The user can't be used anymore afterwards, even if wiping fails.
+    pub async fn panic_wipe(&self, vault: &MasterKeyVault) -> Result<()> {
+        vault.lock().await;
+        self.user
+            .wipe_local_data(&vault.db_path, &vault.key_store)
+            .await
+    }
 }
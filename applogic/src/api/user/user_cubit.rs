@@ -3,21 +3,36 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
+use chrono::{DateTime, Utc};
 use flutter_rust_bridge::frb;
 use phnxapiclient::qs_api::ws::WsEvent;
-use phnxcoreclient::clients::CoreUser;
-use phnxcoreclient::{Asset, UserProfile};
-use phnxtypes::identifiers::QualifiedUserName;
+use phnxcoreclient::clients::{process::process_qs::ProcessedQsMessages, CoreUser};
+use phnxcoreclient::{Asset, ProfileRefreshPolicy, UserProfile, PRESENCE_CACHE_TTL};
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
 use phnxtypes::messages::client_ds::QsWsMessage;
-use tokio::sync::RwLock;
+use phnxtypes::messages::presence::PresenceStatus;
+use tokio::sync::{watch, RwLock};
 use tokio_util::sync::{CancellationToken, DropGuard};
 use tracing::{error, info, warn};
 
-use crate::api::messages::{FetchedMessages, FetchedMessagesBroadcast, FetchedMessagesReceiver};
-use crate::util::{spawn_from_sync, FibonacciBackoff};
+use crate::api::app_lock_cubit::UiAppLifecycleState;
+use crate::api::messages::FetchedMessages;
+use crate::api::notifications::UiNavigationTarget;
+use crate::api::types::{
+    UiDoNotDisturb, UiPresenceVisibility, UiQuietHours, UiServerCompatibility,
+};
+use crate::app_state::app_lock::AppLockHandle;
+use crate::app_state::compatibility::{client_version, USED_SERVER_FEATURES};
+use crate::app_state::do_not_disturb::{DoNotDisturbSettings, ManualDoNotDisturb};
+use crate::app_state::metrics::{MetricsRecorder, MetricsSettings};
+use crate::app_state::screen_security::ScreenSecurity;
+use crate::util::{
+    spawn_from_sync, AppEvent, ConnectivityState, CredentialRenewalState, EventBus,
+    EventBusReceiver, FetchOutcome, FibonacciBackoff,
+};
 
 use super::{StreamSink, User};
 
@@ -92,8 +107,8 @@ impl UiUser {
 /// This cubit should not be created more than once, because the logged in user exists in the
 /// system only once.
 ///
-/// Allows other cubits to listen to the messages fetched from the server. In this regard, it is
-/// special because it is a constuction entry point of other cubits.
+/// Owns the [`EventBus`] other cubits subscribe to. In this regard, it is special because it is a
+/// constuction entry point of other cubits.
 ///
 /// Note: this has a suffix `Base` because the corresponding Dart class does not implement
 /// `StateStreamableSource`, and therefore to impemlement it we need to wrap it Dart.
@@ -103,12 +118,31 @@ pub struct UserCubitBase {
     sinks: Option<Vec<StreamSink<UiUser>>>,
     pub(crate) core_user: CoreUser,
     _background_tasks_cancel: DropGuard,
-    fetched_messages_tx: FetchedMessagesBroadcast,
+    event_bus: EventBus,
+    credential_warning: Arc<parking_lot::Mutex<Option<DateTime<Utc>>>>,
+    app_lock: AppLockHandle,
+    screen_security: ScreenSecurity,
+    metrics: MetricsRecorder,
+    presence_status: watch::Sender<PresenceStatus>,
+    compatibility: watch::Sender<UiServerCompatibility>,
 }
 
 const WEBSOCKET_TIMEOUT: Duration = Duration::from_secs(30);
 const WEBSCOKET_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 const POLLING_INTERVAL: Duration = Duration::from_secs(10);
+const CREDENTIAL_REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Number of consecutive failed credential renewals after which the user is
+/// alerted.
+const CREDENTIAL_REFRESH_MAX_FAILURES: usize = 3;
+const ANNOUNCEMENTS_POLLING_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const GROUP_JANITOR_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const PROFILE_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const BROADCAST_COMMIT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const METRICS_FLUSH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Interval in which the presence is republished. Must be shorter than
+/// [`phnxtypes::messages::presence::PRESENCE_TTL`].
+const PRESENCE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(4 * 60);
+const COMPATIBILITY_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
 
 impl UserCubitBase {
     #[frb(sync)]
@@ -121,35 +155,173 @@ impl UserCubitBase {
         // TODO: Subscribe to the change notifications from the core user.
         // See <https://github.com/phnx-im/infra/issues/254>
 
-        let fetched_messages_tx = FetchedMessagesBroadcast::new();
+        let event_bus = EventBus::new();
         let cancel = CancellationToken::new();
+        let metrics = user.metrics.clone();
         spawn_websocket(
             core_user.clone(),
             cancel.clone(),
-            fetched_messages_tx.clone(),
+            event_bus.clone(),
+            metrics.clone(),
         );
         spawn_polling(
             core_user.clone(),
             cancel.clone(),
-            fetched_messages_tx.clone(),
+            event_bus.clone(),
+            metrics.clone(),
         );
+        let credential_warning = Arc::default();
+        spawn_credential_refresh(
+            core_user.clone(),
+            cancel.clone(),
+            event_bus.clone(),
+            Arc::clone(&credential_warning),
+        );
+        spawn_announcements_polling(core_user.clone(), cancel.clone(), event_bus.clone());
+        spawn_user_settings_sync(core_user.clone(), cancel.clone());
+        spawn_profile_refresh(core_user.clone(), cancel.clone());
+        spawn_group_janitor(core_user.clone(), cancel.clone(), event_bus.clone());
+        spawn_broadcast_room_commits(core_user.clone(), cancel.clone(), event_bus.clone());
+        spawn_metrics(core_user.clone(), cancel.clone(), metrics.clone());
+        let (presence_status, presence_status_rx) = watch::channel(PresenceStatus::Online);
+        spawn_presence(
+            core_user.clone(),
+            cancel.clone(),
+            event_bus.clone(),
+            presence_status_rx,
+        );
+        let compatibility = watch::Sender::new(UiServerCompatibility::Compatible);
+        spawn_compatibility_check(core_user.clone(), cancel.clone(), compatibility.clone());
+        spawn_from_sync(flush_outbox(core_user.clone()));
 
         Self {
             state,
             sinks: Some(Default::default()),
             core_user,
             _background_tasks_cancel: cancel.drop_guard(),
-            fetched_messages_tx,
+            event_bus,
+            credential_warning,
+            app_lock: AppLockHandle::default(),
+            screen_security: ScreenSecurity::default(),
+            metrics,
+            presence_status,
+            compatibility,
         }
     }
 
-    /// Subscribe to the messages fetched from the server
-    pub(crate) fn subscribe_to_fetched_messages(&self) -> FetchedMessagesReceiver {
-        self.fetched_messages_tx.subscribe()
+    /// Subscribe to the application events
+    pub(crate) fn subscribe_to_events(&self) -> EventBusReceiver {
+        self.event_bus.subscribe()
+    }
+
+    pub(crate) fn event_bus(&self) -> &EventBus {
+        &self.event_bus
+    }
+
+    /// The app lock gating the cubits of this user. Configured by the [`AppLockCubitBase`].
+    ///
+    /// [`AppLockCubitBase`]: crate::api::app_lock_cubit::AppLockCubitBase
+    pub(crate) fn app_lock(&self) -> &AppLockHandle {
+        &self.app_lock
+    }
+
+    pub(crate) fn screen_security(&self) -> &ScreenSecurity {
+        &self.screen_security
+    }
+
+    /// Streams `true` while a sensitive conversation is open and `false` otherwise
+    ///
+    /// The platform should prevent screenshots and screen recordings while `true` was emitted last
+    /// (e.g. via `FLAG_SECURE` on Android). The current value is emitted right away.
+    pub fn stream_screen_security(&self, sink: StreamSink<bool>) {
+        let mut secure_rx = self.screen_security.subscribe();
+        spawn_from_sync(async move {
+            loop {
+                let secure = *secure_rx.borrow_and_update();
+                if sink.add(secure).is_err() {
+                    return;
+                }
+                if secure_rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Called by the platform when the app moves to the foreground or background
+    ///
+    /// Contacts see the user as online while the app is in the foreground and as away otherwise.
+    pub fn app_lifecycle_changed(&self, lifecycle_state: UiAppLifecycleState) {
+        // In the background, the queues are handed over to the notification
+        // service extension once the runs in flight are done, such that it
+        // can preview the messages.
+        let status = match lifecycle_state {
+            UiAppLifecycleState::Foreground => {
+                if let Err(error) = self.core_user.reclaim_queues() {
+                    info!(%error, "Queues are not reclaimed yet");
+                }
+                // Connections may have broken while the app was suspended.
+                spawn_from_sync(recycle_unhealthy_api_clients(self.core_user.clone()));
+                // A share extension may have enqueued messages in the meantime.
+                spawn_from_sync(flush_outbox(self.core_user.clone()));
+                PresenceStatus::Online
+            }
+            UiAppLifecycleState::Background => {
+                spawn_from_sync(self.core_user.release_queues());
+                PresenceStatus::Away
+            }
+        };
+        self.presence_status.send_replace(status);
+    }
+
+    /// Streams the user names of contacts whose presence changed
+    pub fn stream_presence_changes(&self, sink: StreamSink<Vec<String>>) {
+        let mut rx = self.event_bus.subscribe();
+        spawn_from_sync(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(AppEvent::Presence(user_names)) => {
+                        let user_names = user_names.iter().map(ToString::to_string).collect();
+                        if sink.add(user_names).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        error!(n, "Events lagged");
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    /// Streams the navigation targets of clicked desktop notifications
+    pub fn stream_opened_notifications(&self, sink: StreamSink<UiNavigationTarget>) {
+        let mut rx = self.event_bus.subscribe();
+        let user = User::with_empty_state(self.core_user.clone());
+        spawn_from_sync(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(AppEvent::NotificationOpened(payload)) => {
+                        let target = user.resolve_notification_payload(payload).await;
+                        if sink.add(target).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        error!(n, "Events lagged");
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
     }
 
-    pub(crate) fn fetched_messages_tx(&self) -> &FetchedMessagesBroadcast {
-        &self.fetched_messages_tx
+    /// Called by the platform when a push notification was received while the app is running.
+    pub fn report_push_received(&self) {
+        self.event_bus.publish(AppEvent::PushReceived);
     }
 
     async fn emit(&mut self, state: UiUser) {
@@ -181,6 +353,38 @@ impl UserCubitBase {
         }
     }
 
+    /// If renewing the client credential failed repeatedly, the time at which
+    /// the credential expires. The user should be alerted in this case.
+    #[frb(getter, sync)]
+    pub fn credential_expiration_warning(&self) -> Option<DateTime<Utc>> {
+        *self.credential_warning.lock()
+    }
+
+    /// Whether this app can still use the user's server
+    ///
+    /// If an upgrade is required, the UI should show a mandatory upgrade screen.
+    #[frb(getter, sync)]
+    pub fn server_compatibility(&self) -> UiServerCompatibility {
+        self.compatibility.borrow().clone()
+    }
+
+    /// Streams the compatibility with the user's server whenever it changes. The current value is
+    /// emitted right away.
+    pub fn stream_server_compatibility(&self, sink: StreamSink<UiServerCompatibility>) {
+        let mut compatibility_rx = self.compatibility.subscribe();
+        spawn_from_sync(async move {
+            loop {
+                let compatibility = compatibility_rx.borrow_and_update().clone();
+                if sink.add(compatibility).is_err() {
+                    return;
+                }
+                if compatibility_rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
     // Cubit methods
 
     /// Set the display name and/or profile picture of the user.
@@ -213,26 +417,154 @@ impl UserCubitBase {
         self.emit(user).await;
         Ok(())
     }
+
+    /// Who can see the presence of the user
+    pub async fn presence_visibility(&self) -> anyhow::Result<UiPresenceVisibility> {
+        Ok(self.core_user.presence_visibility().await?.into())
+    }
+
+    /// Change who can see the presence of the user
+    pub async fn set_presence_visibility(
+        &self,
+        visibility: UiPresenceVisibility,
+    ) -> anyhow::Result<()> {
+        self.core_user
+            .set_presence_visibility(visibility.into())
+            .await
+    }
+
+    /// The Do Not Disturb settings and whether DND is active right now
+    pub async fn do_not_disturb(&self) -> anyhow::Result<UiDoNotDisturb> {
+        let settings: DoNotDisturbSettings = self.core_user.user_setting().await?;
+        Ok(UiDoNotDisturb::new(&settings, Utc::now()))
+    }
+
+    /// Set or remove the daily quiet hours
+    pub async fn set_quiet_hours(
+        &self,
+        quiet_hours: Option<UiQuietHours>,
+    ) -> anyhow::Result<UiDoNotDisturb> {
+        let quiet_hours = quiet_hours.map(TryInto::try_into).transpose()?;
+        self.update_do_not_disturb(|settings| settings.quiet_hours = quiet_hours)
+            .await
+    }
+
+    /// Switch on Do Not Disturb for the given duration, or until it is switched off
+    pub async fn enable_do_not_disturb(
+        &self,
+        duration_secs: Option<u32>,
+    ) -> anyhow::Result<UiDoNotDisturb> {
+        let until = duration_secs.map(|secs| Utc::now() + chrono::Duration::seconds(secs.into()));
+        self.update_do_not_disturb(|settings| settings.manual = Some(ManualDoNotDisturb { until }))
+            .await
+    }
+
+    /// Switch off the manual Do Not Disturb. Quiet hours are not affected.
+    pub async fn disable_do_not_disturb(&self) -> anyhow::Result<UiDoNotDisturb> {
+        self.update_do_not_disturb(|settings| settings.manual = None)
+            .await
+    }
+
+    /// Star or unstar a contact. Messages of starred contacts are notified during DND.
+    pub async fn set_contact_starred(
+        &self,
+        user_name: String,
+        starred: bool,
+    ) -> anyhow::Result<UiDoNotDisturb> {
+        let user_name: QualifiedUserName = SafeTryInto::try_into(user_name)?;
+        self.update_do_not_disturb(|settings| settings.set_starred(user_name, starred))
+            .await
+    }
+
+    /// Whether messages mentioning the user are notified during DND
+    pub async fn set_do_not_disturb_mentions(
+        &self,
+        mentions: bool,
+    ) -> anyhow::Result<UiDoNotDisturb> {
+        self.update_do_not_disturb(|settings| settings.exceptions.mentions = mentions)
+            .await
+    }
+
+    async fn update_do_not_disturb(
+        &self,
+        f: impl FnOnce(&mut DoNotDisturbSettings),
+    ) -> anyhow::Result<UiDoNotDisturb> {
+        let mut settings: DoNotDisturbSettings = self.core_user.user_setting().await?;
+        f(&mut settings);
+        self.core_user.set_user_setting(&settings).await?;
+        Ok(UiDoNotDisturb::new(&settings, Utc::now()))
+    }
+
+    /// Whether the user consented to collecting and uploading client metrics
+    pub async fn metrics_consent(&self) -> anyhow::Result<bool> {
+        let settings: MetricsSettings = self.core_user.user_setting().await?;
+        Ok(settings.consent)
+    }
+
+    /// Give or withdraw the consent to collecting and uploading client metrics
+    ///
+    /// Withdrawing the consent discards all metrics that were not uploaded yet.
+    pub async fn set_metrics_consent(&self, consent: bool) -> anyhow::Result<()> {
+        let mut settings: MetricsSettings = self.core_user.user_setting().await?;
+        settings.set_consent(consent, Utc::now());
+        self.core_user.set_user_setting(&settings).await?;
+        self.metrics.set_enabled(consent);
+        Ok(())
+    }
+
+    /// The metrics report that would be uploaded now, as pretty-printed JSON
+    ///
+    /// Used to show the user what is sent to the operator.
+    pub async fn pending_metrics_report(&self) -> anyhow::Result<String> {
+        let settings = self.metrics.flush(&self.core_user).await?;
+        Ok(serde_json::to_string_pretty(&settings.report(Utc::now()))?)
+    }
 }
 
-fn spawn_websocket(core_user: CoreUser, cancel: CancellationToken, tx: FetchedMessagesBroadcast) {
+fn spawn_websocket(
+    core_user: CoreUser,
+    cancel: CancellationToken,
+    tx: EventBus,
+    metrics: MetricsRecorder,
+) {
     spawn_from_sync(async move {
         let mut backoff = FibonacciBackoff::new();
         while let Err(error) = run_websocket(&core_user, &cancel, &mut backoff, &tx).await {
+            tx.publish(AppEvent::Connectivity(ConnectivityState::Disconnected));
+            metrics.record_reconnect();
             let timeout = backoff.next_backoff();
             info!(%error, retry_in =? timeout, "Websocket failed");
             tokio::time::sleep(timeout).await;
+            recycle_unhealthy_api_clients(core_user.clone()).await;
         }
         info!("Websocket handler stopped normally");
     });
 }
 
+/// Drop the API clients whose connections broke, e.g. after a network change
+async fn recycle_unhealthy_api_clients(core_user: CoreUser) {
+    match core_user.recycle_unhealthy_api_clients().await {
+        Ok(0) => {}
+        Ok(recycled) => info!(recycled, "Recycled API clients"),
+        Err(error) => warn!(%error, "Failed to recycle API clients"),
+    }
+}
+
+/// Send the messages enqueued by a share extension or that failed to send
+async fn flush_outbox(core_user: CoreUser) {
+    match core_user.flush_outbox().await {
+        Ok(0) => {}
+        Ok(sent) => info!(sent, "Sent messages from the outbox"),
+        Err(error) => warn!(%error, "Failed to flush the outbox"),
+    }
+}
+
 /// Normal return means the websocket handler was cancelled
 async fn run_websocket(
     core_user: &CoreUser,
     cancel: &CancellationToken,
     backoff: &mut FibonacciBackoff,
-    tx: &FetchedMessagesBroadcast,
+    tx: &EventBus,
 ) -> anyhow::Result<()> {
     let mut websocket = core_user
         .websocket(
@@ -253,11 +585,17 @@ async fn run_websocket(
     }
 }
 
-fn spawn_polling(core_user: CoreUser, cancel: CancellationToken, tx: FetchedMessagesBroadcast) {
+fn spawn_polling(
+    core_user: CoreUser,
+    cancel: CancellationToken,
+    tx: EventBus,
+    metrics: MetricsRecorder,
+) {
     let user = User::with_empty_state(core_user);
     spawn_from_sync(async move {
         let mut backoff = FibonacciBackoff::new();
         loop {
+            let started_at = Instant::now();
             let res = tokio::select! {
                 _ = cancel.cancelled() => break,
                 res = user.fetch_all_messages() => res,
@@ -265,12 +603,17 @@ fn spawn_polling(core_user: CoreUser, cancel: CancellationToken, tx: FetchedMess
             let mut timeout = POLLING_INTERVAL;
             match res {
                 Ok(fetched_messages) => {
+                    metrics.record_sync(started_at.elapsed());
                     process_fetched_messages(&tx, fetched_messages).await;
+                    tx.publish(AppEvent::Fetch(FetchOutcome::Succeeded));
                     backoff.reset();
                 }
-                Err(_error) => {
+                Err(error) => {
                     timeout = backoff.next_backoff().max(timeout);
                     error!(retry_in =? timeout, "Failed to fetch messages");
+                    tx.publish(AppEvent::Fetch(FetchOutcome::Failed {
+                        reason: error.to_string(),
+                    }));
                 }
             }
             tokio::select! {
@@ -281,17 +624,342 @@ fn spawn_polling(core_user: CoreUser, cancel: CancellationToken, tx: FetchedMess
     });
 }
 
+/// Periodically renews the client credential ahead of its expiration
+fn spawn_credential_refresh(
+    core_user: CoreUser,
+    cancel: CancellationToken,
+    tx: EventBus,
+    warning: Arc<parking_lot::Mutex<Option<DateTime<Utc>>>>,
+) {
+    spawn_from_sync(async move {
+        let mut backoff = FibonacciBackoff::new_extended();
+        let mut failures = 0;
+        loop {
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = refresh_credential_if_needed(&core_user) => res,
+            };
+            let timeout = match res {
+                Ok(()) => {
+                    if failures >= CREDENTIAL_REFRESH_MAX_FAILURES {
+                        *warning.lock() = None;
+                        tx.publish(AppEvent::CredentialRenewal(CredentialRenewalState::Valid));
+                    }
+                    failures = 0;
+                    backoff = FibonacciBackoff::new_extended();
+                    CREDENTIAL_REFRESH_INTERVAL
+                }
+                Err(error) => {
+                    failures += 1;
+                    let timeout = backoff.next_backoff();
+                    error!(%error, failures, retry_in =? timeout, "Failed to renew client credential");
+                    if failures >= CREDENTIAL_REFRESH_MAX_FAILURES {
+                        match core_user.client_credential_expiration().await {
+                            Ok(expires_at) => {
+                                let expires_at = DateTime::<Utc>::from(expires_at);
+                                *warning.lock() = Some(expires_at);
+                                tx.publish(AppEvent::CredentialRenewal(
+                                    CredentialRenewalState::Failing { expires_at },
+                                ));
+                            }
+                            Err(error) => {
+                                error!(%error, "Failed to load client credential expiration");
+                            }
+                        }
+                    }
+                    timeout
+                }
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(timeout) => {},
+            }
+        }
+    });
+}
+
+/// Periodically polls the announcements of the server operator
+fn spawn_announcements_polling(core_user: CoreUser, cancel: CancellationToken, tx: EventBus) {
+    spawn_from_sync(async move {
+        let mut backoff = FibonacciBackoff::new_extended();
+        loop {
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = core_user.poll_announcements() => res,
+            };
+            let timeout = match res {
+                Ok(changed) => {
+                    if changed {
+                        tx.publish(AppEvent::Announcements);
+                    }
+                    backoff = FibonacciBackoff::new_extended();
+                    ANNOUNCEMENTS_POLLING_INTERVAL
+                }
+                Err(error) => {
+                    let timeout = backoff.next_backoff().max(ANNOUNCEMENTS_POLLING_INTERVAL);
+                    warn!(%error, retry_in =? timeout, "Failed to poll announcements");
+                    timeout
+                }
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(timeout) => {},
+            }
+        }
+    });
+}
+
+/// Periodically checks whether the server still serves this client version
+fn spawn_compatibility_check(
+    core_user: CoreUser,
+    cancel: CancellationToken,
+    compatibility: watch::Sender<UiServerCompatibility>,
+) {
+    spawn_from_sync(async move {
+        let client_version = client_version();
+        let mut backoff = FibonacciBackoff::new_extended();
+        loop {
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = core_user.server_compatibility(&client_version, USED_SERVER_FEATURES) => res,
+            };
+            let timeout = match res {
+                Ok(checked) => {
+                    if checked.is_upgrade_required() {
+                        warn!(?checked, %client_version, "Server requires a client upgrade");
+                    }
+                    compatibility.send_if_modified(|current| {
+                        let checked = checked.into();
+                        let changed = *current != checked;
+                        *current = checked;
+                        changed
+                    });
+                    backoff = FibonacciBackoff::new_extended();
+                    COMPATIBILITY_CHECK_INTERVAL
+                }
+                Err(error) => {
+                    let timeout = backoff.next_backoff();
+                    warn!(%error, retry_in =? timeout, "Failed to check server compatibility");
+                    timeout
+                }
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(timeout) => {},
+            }
+        }
+    });
+}
+
+/// Syncs the user settings with the other clients of the user once, retrying
+/// until it succeeds. Later changes are synced when they happen.
+fn spawn_user_settings_sync(core_user: CoreUser, cancel: CancellationToken) {
+    spawn_from_sync(async move {
+        let mut backoff = FibonacciBackoff::new_extended();
+        loop {
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = core_user.sync_user_settings() => res,
+            };
+            let Err(error) = res else {
+                break;
+            };
+            let timeout = backoff.next_backoff();
+            warn!(%error, retry_in =? timeout, "Failed to sync user settings");
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(timeout) => {},
+            }
+        }
+    });
+}
+
+/// Periodically refreshes the profiles of the contacts. Only profiles that
+/// changed since they were fetched last are downloaded.
+fn spawn_profile_refresh(core_user: CoreUser, cancel: CancellationToken) {
+    spawn_from_sync(async move {
+        let policy = ProfileRefreshPolicy::default();
+        loop {
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = core_user.refresh_user_profiles(&policy) => res,
+            };
+            if let Err(error) = res {
+                warn!(%error, "Failed to refresh user profiles");
+            }
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(PROFILE_REFRESH_INTERVAL) => {},
+            }
+        }
+    });
+}
+
+/// Periodically resolves stale pending commits and proposals in the groups
+fn spawn_group_janitor(core_user: CoreUser, cancel: CancellationToken, tx: EventBus) {
+    spawn_from_sync(async move {
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(GROUP_JANITOR_INTERVAL) => {},
+            }
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = core_user.clean_up_stale_groups() => res,
+            };
+            match res {
+                Ok(ProcessedQsMessages {
+                    new_conversations,
+                    changed_conversations,
+                    new_messages,
+                    ..
+                }) => {
+                    let fetched_messages = FetchedMessages {
+                        new_conversations,
+                        changed_conversations,
+                        new_messages,
+                        notifications_content: Vec::new(),
+                    };
+                    tx.publish(AppEvent::Queue(Arc::new(fetched_messages)));
+                }
+                Err(error) => warn!(%error, "Failed to clean up stale groups"),
+            }
+        }
+    });
+}
+
+/// Periodically commits the pending membership changes in the broadcast rooms
+/// administered by the user.
+fn spawn_broadcast_room_commits(core_user: CoreUser, cancel: CancellationToken, tx: EventBus) {
+    spawn_from_sync(async move {
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(BROADCAST_COMMIT_INTERVAL) => {},
+            }
+            let res = tokio::select! {
+                _ = cancel.cancelled() => break,
+                res = core_user.commit_broadcast_room_changes() => res,
+            };
+            match res {
+                Ok(new_messages) if new_messages.is_empty() => {}
+                Ok(new_messages) => {
+                    let mut changed_conversations = Vec::new();
+                    for message in &new_messages {
+                        if !changed_conversations.contains(&message.conversation_id()) {
+                            changed_conversations.push(message.conversation_id());
+                        }
+                    }
+                    let fetched_messages = FetchedMessages {
+                        new_conversations: Vec::new(),
+                        changed_conversations,
+                        new_messages,
+                        notifications_content: Vec::new(),
+                    };
+                    tx.publish(AppEvent::Queue(Arc::new(fetched_messages)));
+                }
+                Err(error) => warn!(%error, "Failed to commit broadcast room changes"),
+            }
+        }
+    });
+}
+
+/// Counts the sessions of the user and periodically uploads the client
+/// metrics if the user consented. See [`crate::app_state::metrics`].
+fn spawn_metrics(core_user: CoreUser, cancel: CancellationToken, metrics: MetricsRecorder) {
+    spawn_from_sync(async move {
+        if let Err(error) = metrics.start_session(&core_user).await {
+            warn!(%error, "Failed to start metrics session");
+        }
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(METRICS_FLUSH_INTERVAL) => {},
+            }
+            if let Err(error) = metrics.upload_if_due(&core_user).await {
+                warn!(%error, "Failed to upload client metrics");
+            }
+        }
+        if let Err(error) = metrics.end_session(&core_user).await {
+            warn!(%error, "Failed to end metrics session");
+        }
+    });
+}
+
+/// Publishes the presence of the user and keeps the presence of the contacts up to date
+///
+/// The presence is republished whenever the status changes and periodically before it expires. When
+/// the task is cancelled, the user goes offline.
+fn spawn_presence(
+    core_user: CoreUser,
+    cancel: CancellationToken,
+    tx: EventBus,
+    mut status_rx: watch::Receiver<PresenceStatus>,
+) {
+    spawn_from_sync(async move {
+        let mut heartbeat = tokio::time::interval(PRESENCE_HEARTBEAT_INTERVAL);
+        let mut refresh = tokio::time::interval(PRESENCE_CACHE_TTL);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = heartbeat.tick() => {}
+                Ok(()) = status_rx.changed() => heartbeat.reset(),
+                _ = refresh.tick() => {
+                    match core_user.refresh_contact_presence().await {
+                        Ok(changed) if changed.is_empty() => {}
+                        Ok(changed) => tx.publish(AppEvent::Presence(Arc::new(changed))),
+                        Err(error) => warn!(%error, "Failed to refresh contact presence"),
+                    }
+                    continue;
+                }
+            }
+            let status = *status_rx.borrow_and_update();
+            if let Err(error) = core_user.publish_presence(status).await {
+                warn!(%error, "Failed to publish presence");
+            }
+        }
+        // Let the contacts know right away instead of when the presence expires
+        if let Err(error) = core_user.publish_presence(PresenceStatus::Offline).await {
+            warn!(%error, "Failed to publish offline presence");
+        }
+    });
+}
+
+async fn refresh_credential_if_needed(core_user: &CoreUser) -> anyhow::Result<()> {
+    if core_user.client_credential_needs_refresh().await? {
+        info!("Renewing client credential");
+        core_user.refresh_client_credential().await?;
+    } else {
+        // Groups that couldn't be updated after a previous renewal
+        core_user.update_group_credentials().await?;
+    }
+    Ok(())
+}
+
 async fn handle_websocket_message(
     event: WsEvent,
-    tx: &FetchedMessagesBroadcast,
+    tx: &EventBus,
     core_user: &CoreUser,
 ) -> anyhow::Result<()> {
     match event {
-        WsEvent::ConnectedEvent => info!("connected to websocket"),
-        WsEvent::DisconnectedEvent => bail!("server disconnect"),
+        WsEvent::ConnectedEvent => {
+            info!("connected to websocket");
+            tx.publish(AppEvent::Connectivity(ConnectivityState::Connected));
+        }
+        WsEvent::DisconnectedEvent => {
+            tx.publish(AppEvent::Connectivity(ConnectivityState::Disconnected));
+            bail!("server disconnect")
+        }
         WsEvent::MessageEvent(QsWsMessage::Event(event)) => {
             warn!("ignoring websocket event: {event:?}")
         }
+        WsEvent::MessageEvent(QsWsMessage::Presence(update)) => {
+            match core_user.apply_presence_update(update).await {
+                Ok(Some(user_name)) => tx.publish(AppEvent::Presence(Arc::new(vec![user_name]))),
+                Ok(None) => {}
+                Err(error) => warn!(%error, "Failed to apply presence update"),
+            }
+        }
         WsEvent::MessageEvent(QsWsMessage::QueueUpdate) => {
             let tx = tx.clone();
             let core_user = core_user.clone();
@@ -299,9 +967,13 @@ async fn handle_websocket_message(
             match user.fetch_all_messages().await {
                 Ok(fetched_messages) => {
                     process_fetched_messages(&tx, fetched_messages).await;
+                    tx.publish(AppEvent::Fetch(FetchOutcome::Succeeded));
                 }
                 Err(error) => {
                     error!(%error, "Failed to fetch messages on queue update");
+                    tx.publish(AppEvent::Fetch(FetchOutcome::Failed {
+                        reason: error.to_string(),
+                    }));
                 }
             }
         }
@@ -309,16 +981,19 @@ async fn handle_websocket_message(
     Ok(())
 }
 
-async fn process_fetched_messages(
-    tx: &FetchedMessagesBroadcast,
-    fetched_messages: FetchedMessages,
-) {
+async fn process_fetched_messages(tx: &EventBus, fetched_messages: FetchedMessages) {
     // Send a notification to the OS (desktop only)
     //
     // TODO: Technically, this is not the responsibility of the user cubit to do this. Better
     // we delegate it to a different place.
     #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
-    crate::notifier::show_desktop_notifications(&fetched_messages.notifications_content);
+    {
+        let tx = tx.clone();
+        crate::notifier::show_desktop_notifications(
+            &fetched_messages.notifications_content,
+            move |payload| tx.publish(AppEvent::NotificationOpened(payload)),
+        );
+    }
 
-    let _no_receivers = tx.send(fetched_messages).await;
+    tx.publish(AppEvent::Queue(Arc::new(fetched_messages)));
 }
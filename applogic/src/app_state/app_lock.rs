@@ -0,0 +1,351 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! App lock protecting the app with a PIN or biometrics.
+//!
+//! When the app goes to the background, the lock enters a grace period. If
+//! the app returns to the foreground after the auto-lock timeout has passed,
+//! the app is locked and has to be unlocked with the PIN or biometrics. While
+//! the app is locked, cubits don't emit any data.
+
+use std::{sync::Arc, time::Instant};
+
+use anyhow::{bail, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Duration, Utc};
+use parking_lot::Mutex;
+use phnxcoreclient::UserSetting;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Number of failed PIN attempts after which further attempts are delayed.
+const FREE_PIN_ATTEMPTS: u32 = 5;
+/// Delay after the first attempt exceeding [`FREE_PIN_ATTEMPTS`]. Doubles with
+/// each further failed attempt.
+const PIN_RETRY_BASE_DELAY: Duration = Duration::seconds(30);
+const PIN_RETRY_MAX_DELAY: Duration = Duration::hours(1);
+const MIN_PIN_LENGTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AppLockState {
+    Unlocked,
+    /// The app is in the background and will be locked if it doesn't return
+    /// to the foreground before the auto-lock timeout.
+    GracePeriod {
+        since: Instant,
+    },
+    Locked,
+}
+
+/// The lock state machine.
+#[derive(Debug)]
+pub(crate) struct AppLock {
+    state: AppLockState,
+    enabled: bool,
+    auto_lock_timeout: std::time::Duration,
+}
+
+impl AppLock {
+    /// A disabled app lock. It never locks the app.
+    pub(crate) fn disabled() -> Self {
+        Self {
+            state: AppLockState::Unlocked,
+            enabled: false,
+            auto_lock_timeout: std::time::Duration::ZERO,
+        }
+    }
+
+    /// An app lock with the given settings. An enabled lock starts locked.
+    pub(crate) fn new(settings: &AppLockSettings) -> Self {
+        let enabled = settings.is_enabled();
+        Self {
+            state: if enabled {
+                AppLockState::Locked
+            } else {
+                AppLockState::Unlocked
+            },
+            enabled,
+            auto_lock_timeout: std::time::Duration::from_secs(
+                settings.auto_lock_timeout_secs.into(),
+            ),
+        }
+    }
+
+    pub(crate) fn state(&self) -> AppLockState {
+        self.state
+    }
+
+    pub(crate) fn is_locked(&self) -> bool {
+        self.state == AppLockState::Locked
+    }
+
+    pub(crate) fn set_auto_lock_timeout(&mut self, timeout: std::time::Duration) {
+        self.auto_lock_timeout = timeout;
+    }
+
+    /// Enabling the lock doesn't lock the app right away. Disabling it
+    /// unlocks the app.
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.state = AppLockState::Unlocked;
+        }
+    }
+
+    pub(crate) fn on_background(&mut self, now: Instant) {
+        if self.enabled && self.state == AppLockState::Unlocked {
+            self.state = AppLockState::GracePeriod { since: now };
+        }
+    }
+
+    pub(crate) fn on_foreground(&mut self, now: Instant) {
+        if let AppLockState::GracePeriod { since } = self.state {
+            self.state = if now.duration_since(since) >= self.auto_lock_timeout {
+                AppLockState::Locked
+            } else {
+                AppLockState::Unlocked
+            };
+        }
+    }
+
+    /// Lock the app immediately.
+    pub(crate) fn lock(&mut self) {
+        if self.enabled {
+            self.state = AppLockState::Locked;
+        }
+    }
+
+    /// Unlock the app. Must only be called after the user was verified.
+    pub(crate) fn unlock(&mut self) {
+        self.state = AppLockState::Unlocked;
+    }
+}
+
+/// Shared app lock that notifies subscribers whether the app is locked.
+#[derive(Debug, Clone)]
+pub(crate) struct AppLockHandle {
+    inner: Arc<AppLockHandleInner>,
+}
+
+#[derive(Debug)]
+struct AppLockHandleInner {
+    lock: Mutex<AppLock>,
+    locked_tx: watch::Sender<bool>,
+}
+
+impl Default for AppLockHandle {
+    fn default() -> Self {
+        Self::new(AppLock::disabled())
+    }
+}
+
+impl AppLockHandle {
+    pub(crate) fn new(lock: AppLock) -> Self {
+        let locked_tx = watch::Sender::new(lock.is_locked());
+        let inner = AppLockHandleInner {
+            lock: Mutex::new(lock),
+            locked_tx,
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Receives `true` while the app is locked.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.inner.locked_tx.subscribe()
+    }
+
+    pub(crate) fn state(&self) -> AppLockState {
+        self.inner.lock.lock().state()
+    }
+
+    /// Apply the given transition and notify subscribers if the app was
+    /// locked or unlocked.
+    pub(crate) fn update(&self, f: impl FnOnce(&mut AppLock)) -> AppLockState {
+        let mut lock = self.inner.lock.lock();
+        f(&mut lock);
+        let is_locked = lock.is_locked();
+        self.inner.locked_tx.send_if_modified(|locked| {
+            let modified = *locked != is_locked;
+            *locked = is_locked;
+            modified
+        });
+        lock.state()
+    }
+}
+
+/// Persisted configuration and PIN attempt counter of the app lock.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct AppLockSettings {
+    /// Argon2 hash of the PIN in PHC string format. The app lock is disabled
+    /// if not set.
+    pin_verifier: Option<String>,
+    pub(crate) biometrics_enabled: bool,
+    pub(crate) auto_lock_timeout_secs: u32,
+    /// Consecutive failed PIN attempts. Persisted, such that restarting the
+    /// app doesn't reset the limit.
+    pub(crate) failed_attempts: u32,
+    pub(crate) retry_at: Option<DateTime<Utc>>,
+}
+
+impl UserSetting for AppLockSettings {
+    const KEY: &'static str = "app_lock";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PinVerification {
+    Valid,
+    Invalid,
+    /// Too many failed attempts. The PIN wasn't checked.
+    RetryLater(DateTime<Utc>),
+}
+
+impl AppLockSettings {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.pin_verifier.is_some()
+    }
+
+    pub(crate) fn set_pin(&mut self, pin: &str) -> Result<()> {
+        if pin.chars().count() < MIN_PIN_LENGTH || !pin.chars().all(|c| c.is_ascii_digit()) {
+            bail!("PIN must consist of at least {MIN_PIN_LENGTH} digits");
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let verifier = Argon2::default()
+            .hash_password(pin.as_bytes(), &salt)
+            .map_err(|error| anyhow::anyhow!("Failed to hash PIN: {error}"))?;
+        self.pin_verifier = Some(verifier.to_string());
+        self.failed_attempts = 0;
+        self.retry_at = None;
+        Ok(())
+    }
+
+    pub(crate) fn remove_pin(&mut self) {
+        self.pin_verifier = None;
+        self.biometrics_enabled = false;
+        self.failed_attempts = 0;
+        self.retry_at = None;
+    }
+
+    /// Verify the given PIN and update the attempt counter. The settings must
+    /// be persisted afterwards.
+    pub(crate) fn verify_pin(&mut self, pin: &str, now: DateTime<Utc>) -> PinVerification {
+        if let Some(retry_at) = self.retry_at.filter(|retry_at| *retry_at > now) {
+            return PinVerification::RetryLater(retry_at);
+        }
+        let valid = self
+            .pin_verifier
+            .as_deref()
+            .and_then(|verifier| PasswordHash::new(verifier).ok())
+            .is_some_and(|verifier| {
+                Argon2::default()
+                    .verify_password(pin.as_bytes(), &verifier)
+                    .is_ok()
+            });
+        if valid {
+            self.failed_attempts = 0;
+            self.retry_at = None;
+            PinVerification::Valid
+        } else {
+            self.failed_attempts += 1;
+            self.retry_at = retry_delay(self.failed_attempts).map(|delay| now + delay);
+            PinVerification::Invalid
+        }
+    }
+}
+
+fn retry_delay(failed_attempts: u32) -> Option<Duration> {
+    let excess = failed_attempts.checked_sub(FREE_PIN_ATTEMPTS)?;
+    let delay = PIN_RETRY_BASE_DELAY
+        .checked_mul(2i32.saturating_pow(excess.min(16)))
+        .unwrap_or(PIN_RETRY_MAX_DELAY);
+    Some(delay.min(PIN_RETRY_MAX_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_lock(timeout_secs: u32) -> AppLock {
+        let mut settings = AppLockSettings {
+            auto_lock_timeout_secs: timeout_secs,
+            ..Default::default()
+        };
+        settings.set_pin("1234").unwrap();
+        AppLock::new(&settings)
+    }
+
+    #[test]
+    fn grace_period() {
+        let mut lock = enabled_lock(60);
+        assert_eq!(lock.state(), AppLockState::Locked);
+        lock.unlock();
+
+        let now = Instant::now();
+        lock.on_background(now);
+        assert_eq!(lock.state(), AppLockState::GracePeriod { since: now });
+        lock.on_foreground(now + std::time::Duration::from_secs(59));
+        assert_eq!(lock.state(), AppLockState::Unlocked);
+
+        lock.on_background(now);
+        lock.on_foreground(now + std::time::Duration::from_secs(60));
+        assert_eq!(lock.state(), AppLockState::Locked);
+
+        // Going to the background while locked stays locked
+        lock.on_background(now);
+        lock.on_foreground(now);
+        assert_eq!(lock.state(), AppLockState::Locked);
+    }
+
+    #[test]
+    fn disabled_lock_never_locks() {
+        let mut lock = AppLock::new(&AppLockSettings::default());
+        let now = Instant::now();
+        lock.on_background(now);
+        lock.on_foreground(now + std::time::Duration::from_secs(3600));
+        lock.lock();
+        assert_eq!(lock.state(), AppLockState::Unlocked);
+    }
+
+    #[test]
+    fn handle_notifies_lock_changes() {
+        let handle = AppLockHandle::new(enabled_lock(0));
+        let rx = handle.subscribe();
+        assert!(*rx.borrow());
+        handle.update(AppLock::unlock);
+        assert!(!*rx.borrow());
+    }
+
+    #[test]
+    fn pin_attempts_are_rate_limited() {
+        let mut settings = AppLockSettings::default();
+        assert!(settings.set_pin("12a4").is_err());
+        settings.set_pin("1234").unwrap();
+
+        let now = Utc::now();
+        assert_eq!(settings.verify_pin("1234", now), PinVerification::Valid);
+        for _ in 0..FREE_PIN_ATTEMPTS {
+            assert_eq!(settings.verify_pin("0000", now), PinVerification::Invalid);
+        }
+        let retry_at = now + PIN_RETRY_BASE_DELAY;
+        assert_eq!(settings.retry_at, Some(retry_at));
+        assert_eq!(
+            settings.verify_pin("1234", now),
+            PinVerification::RetryLater(retry_at)
+        );
+
+        assert_eq!(
+            settings.verify_pin("0000", retry_at),
+            PinVerification::Invalid
+        );
+        assert_eq!(settings.retry_at, Some(retry_at + PIN_RETRY_BASE_DELAY * 2));
+
+        let later = retry_at + PIN_RETRY_MAX_DELAY;
+        assert_eq!(settings.verify_pin("1234", later), PinVerification::Valid);
+        assert_eq!(settings.failed_attempts, 0);
+    }
+}
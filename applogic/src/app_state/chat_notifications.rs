@@ -0,0 +1,195 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Per-chat customization of notification alerts.
+//!
+//! On Android, each customized chat gets its own notification channel. The
+//! sound and importance of a channel can't be changed after the channel was
+//! created, so the channel id contains a revision which is bumped whenever
+//! the settings of the chat change. On iOS and macOS, the sound and
+//! interruption level are set on each notification.
+
+use std::collections::HashMap;
+
+use phnxcoreclient::{ConversationId, UserSetting};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Channel of chats without customized notifications.
+pub(crate) const DEFAULT_CHANNEL_ID: &str = "default_channel";
+const DEFAULT_CHANNEL_NAME: &str = "Default Channel";
+
+/// Sound played when a notification is shown.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotificationSound {
+    #[default]
+    Default,
+    None,
+    /// Sound file bundled with the app
+    Named(String),
+}
+
+/// How urgently a notification interrupts the user. Maps to the interruption
+/// level on iOS and macOS and the channel importance on Android.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InterruptionLevel {
+    /// Added to the notification list without sound or banner
+    Passive,
+    #[default]
+    Active,
+    /// Breaks through focus modes
+    TimeSensitive,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChatNotificationSettings {
+    pub(crate) sound: NotificationSound,
+    pub(crate) interruption_level: InterruptionLevel,
+    /// Bumped on every change, see the module documentation.
+    revision: u32,
+}
+
+impl ChatNotificationSettings {
+    fn is_customized(&self) -> bool {
+        self.sound != NotificationSound::default()
+            || self.interruption_level != InterruptionLevel::default()
+    }
+}
+
+/// Notification settings of all chats with customized notifications.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChatNotificationSettingsMap {
+    chats: HashMap<Uuid, ChatNotificationSettings>,
+}
+
+impl UserSetting for ChatNotificationSettingsMap {
+    const KEY: &'static str = "chat_notifications";
+    const SYNCED: bool = true;
+}
+
+impl ChatNotificationSettingsMap {
+    /// The settings of the given chat if they are customized.
+    pub(crate) fn get(&self, conversation_id: ConversationId) -> Option<&ChatNotificationSettings> {
+        self.chats
+            .get(&conversation_id.as_uuid())
+            .filter(|settings| settings.is_customized())
+    }
+
+    /// Customize the notifications of the given chat. Resets them to the
+    /// defaults if `settings` is `None`.
+    ///
+    /// Reset chats keep their revision, such that customizing them again
+    /// doesn't reuse a channel that was created with other settings.
+    pub(crate) fn set(
+        &mut self,
+        conversation_id: ConversationId,
+        settings: Option<(NotificationSound, InterruptionLevel)>,
+    ) {
+        let (sound, interruption_level) = settings.unwrap_or_default();
+        let entry = self.chats.entry(conversation_id.as_uuid()).or_default();
+        if entry.sound != sound || entry.interruption_level != interruption_level {
+            entry.sound = sound;
+            entry.interruption_level = interruption_level;
+            entry.revision = entry.revision.wrapping_add(1);
+        }
+    }
+
+    /// The alert of notifications of the given chat.
+    pub(crate) fn alert(
+        &self,
+        conversation_id: ConversationId,
+        chat_title: &str,
+    ) -> NotificationAlert {
+        match self.get(conversation_id) {
+            Some(settings) => NotificationAlert {
+                channel_id: format!(
+                    "chat_{}_{}",
+                    conversation_id.as_uuid().simple(),
+                    settings.revision
+                ),
+                channel_name: chat_title.to_owned(),
+                sound_name: match &settings.sound {
+                    NotificationSound::Named(name) => Some(name.clone()),
+                    NotificationSound::Default | NotificationSound::None => None,
+                },
+                silent: settings.sound == NotificationSound::None,
+                interruption_level: settings.interruption_level,
+            },
+            None => NotificationAlert::default(),
+        }
+    }
+}
+
+/// Alert behavior of a single notification as passed to the platform.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct NotificationAlert {
+    /// Android notification channel
+    pub(crate) channel_id: String,
+    pub(crate) channel_name: String,
+    /// Sound file bundled with the app. The default sound if not set.
+    pub(crate) sound_name: Option<String>,
+    pub(crate) silent: bool,
+    pub(crate) interruption_level: InterruptionLevel,
+}
+
+impl Default for NotificationAlert {
+    fn default() -> Self {
+        Self {
+            channel_id: DEFAULT_CHANNEL_ID.to_owned(),
+            channel_name: DEFAULT_CHANNEL_NAME.to_owned(),
+            sound_name: None,
+            silent: false,
+            interruption_level: InterruptionLevel::Active,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_changes_with_settings() {
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        let mut map = ChatNotificationSettingsMap::default();
+        assert_eq!(
+            map.alert(conversation_id, "Chat"),
+            NotificationAlert::default()
+        );
+
+        let quiet = (NotificationSound::None, InterruptionLevel::Passive);
+        map.set(conversation_id, Some(quiet.clone()));
+        let first = map.alert(conversation_id, "Chat");
+        assert_ne!(first.channel_id, DEFAULT_CHANNEL_ID);
+        assert!(first.silent);
+
+        // Unchanged settings keep the channel
+        map.set(conversation_id, Some(quiet.clone()));
+        assert_eq!(map.alert(conversation_id, "Chat"), first);
+
+        map.set(
+            conversation_id,
+            Some((NotificationSound::Default, InterruptionLevel::TimeSensitive)),
+        );
+        assert_ne!(
+            map.alert(conversation_id, "Chat").channel_id,
+            first.channel_id
+        );
+
+        map.set(conversation_id, None);
+        assert_eq!(
+            map.alert(conversation_id, "Chat"),
+            NotificationAlert::default()
+        );
+
+        // Customizing a reset chat creates a new channel
+        map.set(conversation_id, Some(quiet));
+        assert_ne!(
+            map.alert(conversation_id, "Chat").channel_id,
+            first.channel_id
+        );
+    }
+}
@@ -0,0 +1,25 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Version and features of this client, which the server checks against its
+//! minimum client version and deprecated features.
+
+use phnxtypes::messages::server_info::{ClientVersion, ServerFeature};
+
+/// Version of this client
+pub(crate) fn client_version() -> ClientVersion {
+    env!("CARGO_PKG_VERSION")
+        .parse()
+        .expect("crate version is a valid client version")
+}
+
+/// Server features this client relies on on the current platform
+pub(crate) const USED_SERVER_FEATURES: &[&str] =
+    if cfg!(any(target_os = "ios", target_os = "macos")) {
+        &[ServerFeature::APPLE_PUSH]
+    } else if cfg!(target_os = "android") {
+        &[ServerFeature::GOOGLE_PUSH]
+    } else {
+        &[]
+    };
@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Do Not Disturb.
+//!
+//! DND is active during the scheduled quiet hours and while it is switched on
+//! manually. While active, no OS notifications are shown for new messages,
+//! conversations and connection requests, except for messages from starred
+//! contacts and messages mentioning the user. The unread counts and badges
+//! are still updated.
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, NaiveTime, Utc, Weekday};
+use phnxcoreclient::UserSetting;
+use phnxtypes::identifiers::QualifiedUserName;
+use serde::{Deserialize, Serialize};
+
+/// Time zone in which quiet hours are evaluated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum QuietHoursTimeZone {
+    /// The current time zone of the device, including daylight saving time
+    #[default]
+    DeviceLocal,
+    /// A fixed offset from UTC in seconds
+    FixedOffset(i32),
+}
+
+/// Recurring daily period during which DND is active.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct QuietHours {
+    pub(crate) start: NaiveTime,
+    /// If before `start`, the quiet hours end on the next day.
+    pub(crate) end: NaiveTime,
+    /// Days on which the quiet hours start. Every day if empty.
+    pub(crate) days: Vec<Weekday>,
+    pub(crate) time_zone: QuietHoursTimeZone,
+}
+
+impl QuietHours {
+    pub(crate) fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = match self.time_zone {
+            QuietHoursTimeZone::DeviceLocal => now.with_timezone(&Local).naive_local(),
+            QuietHoursTimeZone::FixedOffset(offset) => match FixedOffset::east_opt(offset) {
+                Some(offset) => now.with_timezone(&offset).naive_local(),
+                None => now.naive_utc(),
+            },
+        };
+        self.contains_local(local)
+    }
+
+    fn contains_local(&self, local: NaiveDateTime) -> bool {
+        let time = local.time();
+        let start_day = if self.start <= self.end {
+            if !(self.start..self.end).contains(&time) {
+                return false;
+            }
+            local.weekday()
+        } else if time >= self.start {
+            local.weekday()
+        } else if time < self.end {
+            // Overnight quiet hours that started on the previous day
+            local.weekday().pred()
+        } else {
+            return false;
+        };
+        self.days.is_empty() || self.days.contains(&start_day)
+    }
+}
+
+/// Notifications that are shown even while DND is active.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DoNotDisturbExceptions {
+    pub(crate) starred_contacts: Vec<QualifiedUserName>,
+    /// Messages mentioning the user with `@name`
+    pub(crate) mentions: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DoNotDisturbSettings {
+    pub(crate) quiet_hours: Option<QuietHours>,
+    /// Set while DND is switched on manually
+    pub(crate) manual: Option<ManualDoNotDisturb>,
+    pub(crate) exceptions: DoNotDisturbExceptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ManualDoNotDisturb {
+    /// DND is active until switched off if not set.
+    pub(crate) until: Option<DateTime<Utc>>,
+}
+
+impl UserSetting for DoNotDisturbSettings {
+    const KEY: &'static str = "do_not_disturb";
+    const SYNCED: bool = true;
+}
+
+impl DoNotDisturbSettings {
+    pub(crate) fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let manual = self
+            .manual
+            .is_some_and(|manual| manual.until.is_none_or(|until| now < until));
+        manual
+            || self
+                .quiet_hours
+                .as_ref()
+                .is_some_and(|quiet_hours| quiet_hours.contains(now))
+    }
+
+    /// Whether a message with the given sender and text is shown as a
+    /// notification while DND is active.
+    pub(crate) fn is_exception(&self, sender: &str, text: &str, own: &QualifiedUserName) -> bool {
+        let starred = self
+            .exceptions
+            .starred_contacts
+            .iter()
+            .any(|contact| contact.to_string() == sender);
+        starred || (self.exceptions.mentions && mentions(text, own))
+    }
+
+    pub(crate) fn set_starred(&mut self, contact: QualifiedUserName, starred: bool) {
+        let contacts = &mut self.exceptions.starred_contacts;
+        contacts.retain(|c| c != &contact);
+        if starred {
+            contacts.push(contact);
+        }
+    }
+}
+
+/// Whether the text mentions the user as `@alice` or `@alice@example.com`.
+fn mentions(text: &str, user_name: &QualifiedUserName) -> bool {
+    let short = format!("@{}", user_name.user_name());
+    let qualified = format!("@{user_name}");
+    text.split_whitespace()
+        .map(|word| word.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .any(|word| word == short || word == qualified)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate, TimeZone};
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn overnight(days: Vec<Weekday>) -> QuietHours {
+        QuietHours {
+            start: time(22, 0),
+            end: time(7, 0),
+            days,
+            time_zone: QuietHoursTimeZone::FixedOffset(0),
+        }
+    }
+
+    #[test]
+    fn overnight_quiet_hours() {
+        // 2024-10-25 is a Friday
+        let friday = NaiveDate::from_ymd_opt(2024, 10, 25).unwrap();
+        let saturday = friday.succ_opt().unwrap();
+        let quiet_hours = overnight(Vec::new());
+        assert!(quiet_hours.contains_local(friday.and_time(time(23, 0))));
+        assert!(quiet_hours.contains_local(saturday.and_time(time(6, 59))));
+        assert!(!quiet_hours.contains_local(saturday.and_time(time(7, 0))));
+        assert!(!quiet_hours.contains_local(friday.and_time(time(12, 0))));
+
+        // Quiet hours starting on Friday extend into Saturday
+        let weekdays = overnight(vec![Weekday::Fri]);
+        assert!(weekdays.contains_local(saturday.and_time(time(6, 0))));
+        assert!(!weekdays.contains_local(saturday.and_time(time(23, 0))));
+    }
+
+    #[test]
+    fn quiet_hours_in_fixed_offset() {
+        let quiet_hours = QuietHours {
+            time_zone: QuietHoursTimeZone::FixedOffset(2 * 3600),
+            ..overnight(Vec::new())
+        };
+        // 21:00 UTC is 23:00 at UTC+2
+        let now = Utc.with_ymd_and_hms(2024, 10, 25, 21, 0, 0).unwrap();
+        assert!(quiet_hours.contains(now));
+        assert!(!quiet_hours.contains(now - Duration::hours(2)));
+    }
+
+    #[test]
+    fn manual_dnd_expires() {
+        let now = Utc::now();
+        let mut settings = DoNotDisturbSettings {
+            manual: Some(ManualDoNotDisturb {
+                until: Some(now + Duration::hours(1)),
+            }),
+            ..Default::default()
+        };
+        assert!(settings.is_active(now));
+        assert!(!settings.is_active(now + Duration::hours(1)));
+
+        settings.manual = Some(ManualDoNotDisturb { until: None });
+        assert!(settings.is_active(now + Duration::days(365)));
+    }
+
+    #[test]
+    fn exceptions() {
+        let own: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let mut settings = DoNotDisturbSettings::default();
+        assert!(!settings.is_exception("bob@example.com", "hi @alice!", &own));
+
+        settings.exceptions.mentions = true;
+        assert!(settings.is_exception("carol@example.com", "hi @alice!", &own));
+        assert!(settings.is_exception("carol@example.com", "@alice@example.com", &own));
+        assert!(!settings.is_exception("carol@example.com", "hi @alicia", &own));
+
+        settings.set_starred(bob, true);
+        assert!(settings.is_exception("bob@example.com", "hi", &own));
+    }
+}
@@ -0,0 +1,335 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Opt-in client metrics.
+//!
+//! Only coarse counts are collected: histograms of sync durations and message
+//! send latencies, the number of websocket reconnects and the number of
+//! sessions that ended without a crash. Nothing identifies the user, their
+//! contacts or their conversations. Nothing is recorded without the consent
+//! of the user.
+//!
+//! The metrics are aggregated locally and uploaded once per
+//! [`METRICS_UPLOAD_PERIOD`] to the endpoint announced by the user's server.
+//! A session counts as crash-free if it ended with the user cubit being
+//! dropped. A session killed by the platform in the background counts as
+//! crashed.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use phnxapiclient::ApiClient;
+use phnxcoreclient::{clients::CoreUser, UserSetting};
+use serde::{Deserialize, Serialize};
+
+/// Period over which the metrics are aggregated before they are uploaded.
+pub(crate) const METRICS_UPLOAD_PERIOD: chrono::Duration = chrono::Duration::days(1);
+
+/// Upper bounds of the latency histogram buckets in milliseconds. The last
+/// bucket counts everything above the last bound.
+const LATENCY_BUCKET_BOUNDS_MS: [u64; 6] = [100, 250, 500, 1_000, 5_000, 30_000];
+
+/// Histogram of durations over [`LATENCY_BUCKET_BOUNDS_MS`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LatencyHistogram {
+    counts: Vec<u32>,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, duration: Duration) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; LATENCY_BUCKET_BOUNDS_MS.len() + 1];
+        }
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS_MS.len());
+        self.counts[bucket] = self.counts[bucket].saturating_add(1);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count = count.saturating_add(*other_count);
+        }
+    }
+}
+
+/// Aggregated metrics of a period.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MetricsAggregate {
+    sync_durations: LatencyHistogram,
+    send_latencies: LatencyHistogram,
+    reconnects: u32,
+    sessions: u32,
+    crash_free_sessions: u32,
+}
+
+impl MetricsAggregate {
+    fn merge(&mut self, other: &Self) {
+        self.sync_durations.merge(&other.sync_durations);
+        self.send_latencies.merge(&other.send_latencies);
+        self.reconnects = self.reconnects.saturating_add(other.reconnects);
+        self.sessions = self.sessions.saturating_add(other.sessions);
+        self.crash_free_sessions = self
+            .crash_free_sessions
+            .saturating_add(other.crash_free_sessions);
+    }
+}
+
+/// Consent of the user and the metrics that weren't uploaded yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MetricsSettings {
+    pub(crate) consent: bool,
+    /// Start of the current aggregation period
+    period_start: Option<DateTime<Utc>>,
+    aggregate: MetricsAggregate,
+    /// Set while a session is running. If still set when the next session
+    /// starts, the previous session didn't end cleanly.
+    session_running: bool,
+}
+
+impl UserSetting for MetricsSettings {
+    const KEY: &'static str = "client_metrics";
+}
+
+impl MetricsSettings {
+    /// Give or withdraw the consent. Withdrawing discards the aggregated
+    /// metrics.
+    pub(crate) fn set_consent(&mut self, consent: bool, now: DateTime<Utc>) {
+        if consent == self.consent {
+            return;
+        }
+        *self = Self {
+            consent,
+            period_start: consent.then_some(now),
+            ..Default::default()
+        };
+    }
+
+    fn start_session(&mut self, now: DateTime<Utc>) {
+        self.period_start.get_or_insert(now);
+        self.aggregate.sessions = self.aggregate.sessions.saturating_add(1);
+        self.session_running = true;
+    }
+
+    fn end_session(&mut self) {
+        if self.session_running {
+            self.aggregate.crash_free_sessions =
+                self.aggregate.crash_free_sessions.saturating_add(1);
+            self.session_running = false;
+        }
+    }
+
+    fn is_upload_due(&self, now: DateTime<Utc>) -> bool {
+        self.period_start
+            .is_some_and(|start| now - start >= METRICS_UPLOAD_PERIOD)
+    }
+
+    /// The report that would be uploaded at the given time.
+    pub(crate) fn report(&self, now: DateTime<Utc>) -> MetricsReport {
+        MetricsReport {
+            period_start: self.period_start.unwrap_or(now),
+            period_end: now,
+            latency_bucket_bounds_ms: LATENCY_BUCKET_BOUNDS_MS.to_vec(),
+            sync_durations: self.aggregate.sync_durations.counts.clone(),
+            send_latencies: self.aggregate.send_latencies.counts.clone(),
+            reconnects: self.aggregate.reconnects,
+            sessions: self.aggregate.sessions,
+            crash_free_sessions: self.aggregate.crash_free_sessions,
+        }
+    }
+
+    /// Start a new period after the report was uploaded. The running session
+    /// is carried over.
+    fn start_period(&mut self, now: DateTime<Utc>) {
+        self.period_start = Some(now);
+        self.aggregate = MetricsAggregate::default();
+        if self.session_running {
+            self.aggregate.sessions = 1;
+        }
+    }
+}
+
+/// Metrics of a period as uploaded to the operator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct MetricsReport {
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+    latency_bucket_bounds_ms: Vec<u64>,
+    sync_durations: Vec<u32>,
+    send_latencies: Vec<u32>,
+    reconnects: u32,
+    sessions: u32,
+    crash_free_sessions: u32,
+}
+
+/// Records metrics in memory until they are flushed to the database.
+///
+/// Recording is a no-op while the user hasn't consented.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetricsRecorder {
+    inner: Arc<Mutex<MetricsRecorderInner>>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsRecorderInner {
+    enabled: bool,
+    pending: MetricsAggregate,
+}
+
+impl MetricsRecorder {
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        let mut inner = self.inner.lock();
+        inner.enabled = enabled;
+        if !enabled {
+            inner.pending = MetricsAggregate::default();
+        }
+    }
+
+    pub(crate) fn record_sync(&self, duration: Duration) {
+        self.record(|pending| pending.sync_durations.record(duration));
+    }
+
+    pub(crate) fn record_send(&self, latency: Duration) {
+        self.record(|pending| pending.send_latencies.record(latency));
+    }
+
+    pub(crate) fn record_reconnect(&self) {
+        self.record(|pending| pending.reconnects = pending.reconnects.saturating_add(1));
+    }
+
+    fn record(&self, f: impl FnOnce(&mut MetricsAggregate)) {
+        let mut inner = self.inner.lock();
+        if inner.enabled {
+            f(&mut inner.pending);
+        }
+    }
+
+    fn take_pending(&self) -> MetricsAggregate {
+        std::mem::take(&mut self.inner.lock().pending)
+    }
+
+    /// Load the settings and merge the recorded metrics into them. The
+    /// merged settings are stored.
+    pub(crate) async fn flush(&self, core_user: &CoreUser) -> Result<MetricsSettings> {
+        let mut settings: MetricsSettings = core_user.user_setting().await?;
+        let pending = self.take_pending();
+        if settings.consent {
+            settings.aggregate.merge(&pending);
+            core_user.set_user_setting(&settings).await?;
+        }
+        Ok(settings)
+    }
+
+    /// Start a session if the user consented.
+    pub(crate) async fn start_session(&self, core_user: &CoreUser) -> Result<()> {
+        let mut settings: MetricsSettings = core_user.user_setting().await?;
+        self.set_enabled(settings.consent);
+        if settings.consent {
+            settings.start_session(Utc::now());
+            core_user.set_user_setting(&settings).await?;
+        }
+        Ok(())
+    }
+
+    /// Mark the running session as ended cleanly.
+    pub(crate) async fn end_session(&self, core_user: &CoreUser) -> Result<()> {
+        let mut settings = self.flush(core_user).await?;
+        if settings.consent {
+            settings.end_session();
+            core_user.set_user_setting(&settings).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush the recorded metrics and upload the report if the period is
+    /// over and the server announces a metrics endpoint.
+    pub(crate) async fn upload_if_due(&self, core_user: &CoreUser) -> Result<()> {
+        let mut settings = self.flush(core_user).await?;
+        let now = Utc::now();
+        if !settings.consent || !settings.is_upload_due(now) {
+            return Ok(());
+        }
+        let server_info = core_user.server_info().await?;
+        if let Some(endpoint) = server_info.metrics_endpoint {
+            let body = serde_json::to_vec(&settings.report(now))?;
+            ApiClient::new_http_client()?
+                .post(endpoint.as_str())
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        // Without an endpoint, the metrics of the period are dropped.
+        settings.start_period(now);
+        core_user.set_user_setting(&settings).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_millis(50));
+        histogram.record(Duration::from_millis(100));
+        histogram.record(Duration::from_millis(700));
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.counts, vec![2, 0, 0, 1, 0, 0, 1]);
+
+        let mut merged = LatencyHistogram::default();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.counts, vec![4, 0, 0, 2, 0, 0, 2]);
+    }
+
+    #[test]
+    fn sessions_and_consent() {
+        let now = Utc::now();
+        let mut settings = MetricsSettings::default();
+        settings.set_consent(true, now);
+
+        // A session that didn't end cleanly
+        settings.start_session(now);
+        settings.start_session(now);
+        settings.end_session();
+        let report = settings.report(now);
+        assert_eq!(report.sessions, 2);
+        assert_eq!(report.crash_free_sessions, 1);
+
+        assert!(!settings.is_upload_due(now));
+        assert!(settings.is_upload_due(now + METRICS_UPLOAD_PERIOD));
+
+        settings.start_period(now);
+        assert_eq!(settings.report(now).sessions, 0);
+
+        settings.start_session(now);
+        settings.set_consent(false, now);
+        assert_eq!(settings, MetricsSettings::default());
+    }
+
+    #[test]
+    fn recorder_is_noop_without_consent() {
+        let recorder = MetricsRecorder::default();
+        recorder.record_reconnect();
+        assert_eq!(recorder.take_pending(), MetricsAggregate::default());
+
+        recorder.set_enabled(true);
+        recorder.record_reconnect();
+        recorder.record_send(Duration::from_millis(10));
+        let pending = recorder.take_pending();
+        assert_eq!(pending.reconnects, 1);
+        assert_eq!(pending.send_latencies.counts[0], 1);
+    }
+}
@@ -2,5 +2,11 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+pub(crate) mod app_lock;
+pub(crate) mod chat_notifications;
+pub(crate) mod compatibility;
+pub(crate) mod do_not_disturb;
 pub(crate) mod mark_as_read_debouncer;
+pub(crate) mod metrics;
+pub(crate) mod screen_security;
 pub(crate) mod state;
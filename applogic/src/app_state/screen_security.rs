@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Tracks whether a sensitive conversation is open.
+//!
+//! The platform uses this to prevent screenshots and screen recordings (e.g.
+//! `FLAG_SECURE` on Android) while such a conversation is shown.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use phnxcoreclient::ConversationId;
+use tokio::sync::watch;
+
+/// Cheaply clonable handle to the screen security state.
+#[derive(Debug, Clone)]
+pub(crate) struct ScreenSecurity {
+    inner: Arc<ScreenSecurityInner>,
+}
+
+#[derive(Debug)]
+struct ScreenSecurityInner {
+    conversations: Mutex<OpenConversations>,
+    secure_tx: watch::Sender<bool>,
+}
+
+#[derive(Debug, Default)]
+struct OpenConversations {
+    /// Number of times each conversation is currently open
+    open: HashMap<ConversationId, usize>,
+    sensitive: HashSet<ConversationId>,
+}
+
+impl OpenConversations {
+    fn is_secure(&self) -> bool {
+        self.open.keys().any(|id| self.sensitive.contains(id))
+    }
+}
+
+impl Default for ScreenSecurity {
+    fn default() -> Self {
+        let inner = ScreenSecurityInner {
+            conversations: Default::default(),
+            secure_tx: watch::Sender::new(false),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+}
+
+impl ScreenSecurity {
+    /// Receives `true` while a sensitive conversation is open.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.inner.secure_tx.subscribe()
+    }
+
+    /// Register the conversation as open until the returned guard is dropped.
+    pub(crate) fn open(&self, conversation_id: ConversationId) -> OpenConversationGuard {
+        self.update(|conversations| {
+            *conversations.open.entry(conversation_id).or_default() += 1;
+        });
+        OpenConversationGuard {
+            screen_security: self.clone(),
+            conversation_id,
+        }
+    }
+
+    pub(crate) fn set_sensitive(&self, conversation_id: ConversationId, sensitive: bool) {
+        self.update(|conversations| {
+            if sensitive {
+                conversations.sensitive.insert(conversation_id);
+            } else {
+                conversations.sensitive.remove(&conversation_id);
+            }
+        });
+    }
+
+    fn close(&self, conversation_id: ConversationId) {
+        self.update(|conversations| {
+            if let Some(count) = conversations.open.get_mut(&conversation_id) {
+                *count -= 1;
+                if *count == 0 {
+                    conversations.open.remove(&conversation_id);
+                }
+            }
+        });
+    }
+
+    fn update(&self, f: impl FnOnce(&mut OpenConversations)) {
+        let mut conversations = self.inner.conversations.lock();
+        f(&mut conversations);
+        let is_secure = conversations.is_secure();
+        self.inner.secure_tx.send_if_modified(|secure| {
+            let modified = *secure != is_secure;
+            *secure = is_secure;
+            modified
+        });
+    }
+}
+
+/// Marks a conversation as open while alive.
+#[derive(Debug)]
+pub(crate) struct OpenConversationGuard {
+    screen_security: ScreenSecurity,
+    conversation_id: ConversationId,
+}
+
+impl Drop for OpenConversationGuard {
+    fn drop(&mut self) {
+        self.screen_security.close(self.conversation_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn secure_while_sensitive_conversation_is_open() {
+        let screen_security = ScreenSecurity::default();
+        let rx = screen_security.subscribe();
+        let sensitive_id = ConversationId::from(Uuid::new_v4());
+        let other_id = ConversationId::from(Uuid::new_v4());
+        screen_security.set_sensitive(sensitive_id, true);
+        assert!(!*rx.borrow());
+
+        let other = screen_security.open(other_id);
+        assert!(!*rx.borrow());
+
+        let first = screen_security.open(sensitive_id);
+        let second = screen_security.open(sensitive_id);
+        assert!(*rx.borrow());
+        drop(first);
+        assert!(*rx.borrow());
+        drop(second);
+        assert!(!*rx.borrow());
+
+        // Marking an open conversation as sensitive
+        screen_security.set_sensitive(other_id, true);
+        assert!(*rx.borrow());
+        screen_security.set_sensitive(other_id, false);
+        assert!(!*rx.borrow());
+        drop(other);
+    }
+}
@@ -5,7 +5,8 @@
 use anyhow::Result;
 
 use chrono::{DateTime, Utc};
-use phnxcoreclient::{clients::CoreUser, ConversationId};
+use parking_lot::Mutex;
+use phnxcoreclient::{clients::CoreUser, ConversationId, NetworkType};
 
 use super::mark_as_read_debouncer::MarkAsReadDebouncer;
 
@@ -15,6 +16,9 @@ use super::mark_as_read_debouncer::MarkAsReadDebouncer;
 /// Appstate contains only ephemeral data and does not need to be persisted.
 pub(crate) struct AppState {
     mark_as_read_debouncers: MarkAsReadDebouncer,
+    /// Type of the network the device is connected to, as last reported by
+    /// the platform.
+    network_type: Mutex<NetworkType>,
     user: CoreUser,
 }
 
@@ -24,6 +28,7 @@ impl AppState {
     pub(crate) fn new(user: CoreUser) -> Self {
         Self {
             mark_as_read_debouncers: MarkAsReadDebouncer::new(),
+            network_type: Mutex::new(NetworkType::default()),
             user,
         }
     }
@@ -51,6 +56,15 @@ impl AppState {
     pub(crate) async fn flush_debouncer_state(&self) -> Result<()> {
         self.mark_as_read_debouncers
             .flush_debouncer_state(self.user.clone())
-            .await
+            .await?;
+        self.user.flush_read_markers().await
+    }
+
+    pub(crate) fn network_type(&self) -> NetworkType {
+        *self.network_type.lock()
+    }
+
+    pub(crate) fn set_network_type(&self, network_type: NetworkType) {
+        *self.network_type.lock() = network_type;
     }
 }
@@ -4,6 +4,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::app_state::chat_notifications::NotificationAlert;
+
 #[cfg(target_os = "android")]
 pub mod java_api;
 
@@ -13,6 +15,9 @@ pub mod swift_api;
 #[cfg(any(target_os = "ios", target_os = "android"))]
 pub(crate) mod processing;
 
+#[cfg(target_os = "ios")]
+pub(crate) mod nse;
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct IncomingNotificationContent {
     title: String,
@@ -34,4 +39,6 @@ pub(crate) struct NotificationContent {
     title: String,
     body: String,
     data: String,
+    #[serde(flatten)]
+    alert: NotificationAlert,
 }
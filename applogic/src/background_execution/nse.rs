@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Processing of pushes in the iOS notification service extension (NSE).
+//!
+//! The NSE runs in its own process with a memory limit of 24 MiB and must
+//! finish within a few seconds. Pushes don't carry message content, so the
+//! NSE loads the user read-only and without caches (see
+//! [`CoreUser::load_for_extension`]) and previews a bounded number of messages
+//! without processing them. Processing is left to the app, which fetches the
+//! same messages again. If the user can't be loaded, e.g. because the app has
+//! to migrate the database first or owns the queues, the alert of the push is
+//! shown as is.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use phnxcoreclient::clients::{CoreUser, QueueMessagePreview};
+use serde::Deserialize;
+use tokio::runtime::Builder;
+use tracing::{error, info};
+
+use crate::api::user::User;
+
+use super::{NotificationBatch, NotificationContent};
+
+/// Maximum number of messages fetched from each queue per push
+const NSE_MAX_MESSAGES: u64 = 50;
+
+/// Notification for new conversations and connection requests, which can only
+/// be shown once the app processed them
+const NEW_CONTACT_TITLE: &str = "New contact";
+const NEW_CONTACT_BODY: &str = "Open the app to see who reached out";
+
+/// The parts of the APNs payload used by the NSE
+#[derive(Debug, Default, Deserialize)]
+struct PushPayload {
+    #[serde(default)]
+    aps: Aps,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Aps {
+    #[serde(default)]
+    alert: Alert,
+    badge: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Alert {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    body: String,
+}
+
+impl PushPayload {
+    /// The batch shown if the messages can't be processed
+    fn into_fallback_batch(self) -> NotificationBatch {
+        NotificationBatch {
+            badge_count: self.aps.badge.unwrap_or_default(),
+            removals: Vec::new(),
+            additions: vec![NotificationContent {
+                identifier: "".to_string(),
+                title: self.aps.alert.title,
+                body: self.aps.alert.body,
+                data: "".to_string(),
+                alert: Default::default(),
+            }],
+        }
+    }
+}
+
+/// Process the push on a single-threaded tokio runtime
+pub(crate) fn process_push_sync(payload: &str, db_path: String) -> NotificationBatch {
+    let push_payload: PushPayload = serde_json::from_str(payload).unwrap_or_else(|error| {
+        error!(%error, "Failed to parse push payload");
+        PushPayload::default()
+    });
+
+    let runtime = match Builder::new_current_thread()
+        .thread_name("nse-thread")
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            error!(%error, "Failed to initialize tokio runtime");
+            return push_payload.into_fallback_batch();
+        }
+    };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(process_push(db_path))));
+    match result {
+        Ok(Some(batch)) => batch,
+        Ok(None) => push_payload.into_fallback_batch(),
+        Err(_) => {
+            error!("Failed to execute async function");
+            push_payload.into_fallback_batch()
+        }
+    }
+}
+
+/// Load the user and preview a bounded number of messages. Returns `None` if
+/// the messages could not be previewed.
+async fn process_push(db_path: String) -> Option<NotificationBatch> {
+    info!(db_path, "Processing push with DB path");
+    let core_user = match CoreUser::load_for_extension(&db_path).await {
+        Ok(Some(core_user)) => core_user,
+        Ok(None) => {
+            info!("No user to load in the extension");
+            return None;
+        }
+        Err(error) => {
+            error!(%error, "Failed to load user");
+            return None;
+        }
+    };
+    let user = User::with_empty_state(core_user);
+
+    let previews = match user.user.preview_queue_messages(NSE_MAX_MESSAGES).await {
+        Ok(previews) => previews,
+        Err(error) => {
+            error!(%error, "Failed to preview messages");
+            return None;
+        }
+    };
+
+    let mut additions: Vec<_> = user
+        .message_preview_notifications(&previews)
+        .await
+        .into_iter()
+        .map(|notification| NotificationContent {
+            identifier: "".to_string(),
+            title: notification.title,
+            body: notification.body,
+            data: notification.target.to_payload(),
+            alert: notification.alert,
+        })
+        .collect();
+    let has_new_contacts = previews.iter().any(|preview| {
+        matches!(
+            preview,
+            QueueMessagePreview::NewConversation | QueueMessagePreview::ConnectionRequest
+        )
+    });
+    if has_new_contacts && user.active_do_not_disturb().await.is_none() {
+        additions.push(NotificationContent {
+            identifier: "".to_string(),
+            title: NEW_CONTACT_TITLE.to_string(),
+            body: NEW_CONTACT_BODY.to_string(),
+            data: "".to_string(),
+            alert: Default::default(),
+        });
+    }
+    let previewed_messages = previews
+        .iter()
+        .filter(|preview| matches!(preview, QueueMessagePreview::Message(_)))
+        .count() as u32;
+    let badge_count = user.global_unread_messages_count().await + previewed_messages;
+
+    Some(NotificationBatch {
+        badge_count,
+        removals: Vec::new(),
+        additions,
+    })
+}
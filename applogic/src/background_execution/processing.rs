@@ -20,6 +20,7 @@ pub(crate) fn error_batch(e: String) -> NotificationBatch {
             title: "Error".to_string(),
             body: e,
             data: "".to_string(),
+            alert: Default::default(),
         }],
     }
 }
@@ -71,7 +72,8 @@ pub(crate) async fn retrieve_messages(path: String) -> NotificationBatch {
                     title: m.title,
                     body: m.body,
                     identifier: "".to_string(),
-                    data: "".to_string(),
+                    data: m.target.to_payload(),
+                    alert: m.alert,
                 })
                 .collect()
         }
@@ -80,6 +82,7 @@ pub(crate) async fn retrieve_messages(path: String) -> NotificationBatch {
             title: "Error fetching messages".to_string(),
             body: e.to_string(),
             data: "".to_string(),
+            alert: Default::default(),
         }],
     };
 
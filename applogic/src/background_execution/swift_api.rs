@@ -5,7 +5,7 @@
 use std::ffi::{c_char, CStr, CString};
 
 use crate::background_execution::{
-    processing::retrieve_messages_sync, IncomingNotificationContent,
+    nse::process_push_sync, processing::retrieve_messages_sync, IncomingNotificationContent,
 };
 use crate::logging::init_logger;
 
@@ -32,12 +32,42 @@ pub unsafe extern "C" fn process_new_messages(content: *const c_char) -> *mut c_
     CString::new(response).unwrap().into_raw()
 }
 
+/// Entry point of the iOS NSE. Processes a bounded number of messages for the
+/// push with the given APNs payload (JSON) and returns the notifications to
+/// show as JSON. `db_path` is the path of the databases in the app group
+/// container.
+///
+/// # Safety
+///
+/// The caller must ensure that `payload` and `db_path` are pointers to valid
+/// C strings. The returned string must be freed with `free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn nse_process_push(
+    payload: *const c_char,
+    db_path: *const c_char,
+) -> *mut c_char {
+    assert!(!payload.is_null());
+    assert!(!db_path.is_null());
+
+    let payload = unsafe { CStr::from_ptr(payload) }.to_string_lossy();
+    let db_path = unsafe { CStr::from_ptr(db_path) }
+        .to_string_lossy()
+        .into_owned();
+
+    init_logger();
+
+    let batch = process_push_sync(&payload, db_path);
+
+    let response = serde_json::to_string(&batch).unwrap_or_default();
+    CString::new(response).unwrap().into_raw()
+}
+
 /// This method gets called from the iOS NSE
 ///
 /// # Safety
 ///
 /// The caller must ensure that the input string was previously created by
-/// `process_new_messages`.
+/// `process_new_messages` or `nse_process_push`.
 #[no_mangle]
 pub unsafe extern "C" fn free_string(s: *mut c_char) {
     if s.is_null() {
@@ -107,17 +107,88 @@ pub(crate) fn init_desktop_os_notifications() -> Result<(), notify_rust::error::
     Ok(())
 }
 
+/// Action that is invoked when a desktop notification is clicked.
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+const OPEN_ACTION: &str = "default";
+
+/// Show the given notifications on the desktop. The payload of the target of
+/// a notification is passed to `on_open` when the notification is clicked.
+///
+/// Only XDG notification servers (Linux) report clicks to the app.
 #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
 pub(crate) fn show_desktop_notifications(
     notifications: &[crate::api::notifications::LocalNotificationContent],
+    on_open: impl Fn(String) + Clone + Send + 'static,
 ) {
     for notification in notifications {
-        if let Err(error) = Notification::new()
+        let mut os_notification = Notification::new();
+        os_notification
             .summary(notification.title.as_str())
             .body(notification.body.as_str())
-            .show()
+            .action(OPEN_ACTION, "Open");
+        if let Some(sound_name) = &notification.alert.sound_name {
+            if !notification.alert.silent {
+                os_notification.sound_name(sound_name);
+            }
+        }
+        #[cfg(target_os = "linux")]
         {
+            let payload = notification.target.to_payload();
+            let on_open = on_open.clone();
+            // Waiting for the action blocks until the notification is closed
+            std::thread::spawn(move || match os_notification.show() {
+                Ok(handle) => handle.wait_for_action(|action| {
+                    if let Some(payload) = opened_payload(action, payload) {
+                        on_open(payload);
+                    }
+                }),
+                Err(error) => tracing::error!(%error, "Failed to send desktop notification"),
+            });
+        }
+        #[cfg(not(target_os = "linux"))]
+        if let Err(error) = os_notification.show() {
             tracing::error!(%error, "Failed to send desktop notification");
         }
     }
+    #[cfg(not(target_os = "linux"))]
+    let _ = on_open;
+}
+
+/// The payload to open if the given action was invoked on a notification
+/// with the given payload.
+#[cfg(any(
+    target_os = "linux",
+    all(test, any(target_os = "macos", target_os = "windows"))
+))]
+fn opened_payload(action: &str, payload: String) -> Option<String> {
+    (action == OPEN_ACTION).then_some(payload)
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "macos", target_os = "linux", target_os = "windows")
+))]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::api::notifications::NotificationTarget;
+
+    use super::*;
+
+    #[test]
+    fn clicked_notification_opens_chat() {
+        let conversation_id = Uuid::new_v4();
+        let payload = format!(
+            r#"{{"account":"alice","conversation_id":"{conversation_id}","message_id":null}}"#
+        );
+        let target = NotificationTarget::from_payload(&payload).unwrap();
+
+        let opened = opened_payload(OPEN_ACTION, target.to_payload())
+            .and_then(|payload| NotificationTarget::from_payload(&payload))
+            .map(|target| target.conversation_id());
+        assert_eq!(opened, Some(ConversationId::from(conversation_id)));
+
+        // Dismissing the notification doesn't open anything
+        assert_eq!(opened_payload("__closed", target.to_payload()), None);
+    }
 }
@@ -8,7 +8,7 @@ use tokio::sync::{mpsc, watch};
 use tokio_util::sync::CancellationToken;
 use tracing::trace;
 
-use crate::{SseEncode, StreamSink};
+use crate::{app_state::app_lock::AppLockHandle, SseEncode, StreamSink};
 
 use super::spawn_from_sync;
 
@@ -45,6 +45,9 @@ pub(crate) trait Cubit {
 ///
 /// The cancellation token is used to cancel all pending and background operations. It can be
 /// cancelled by calling [`Cubit::close`] or by dropping the [`CubitCore`].
+///
+/// A core created with [`CubitCore::with_app_lock`] holds back states while the app is locked and
+/// emits the latest state when the app is unlocked.
 pub(crate) struct CubitCore<S> {
     state_tx: watch::Sender<S>,
     sinks_tx: mpsc::Sender<StreamSink<S>>,
@@ -85,11 +88,25 @@ where
 {
     /// Creates a new [`CubitCore`] and spawns the emitter task
     pub(crate) fn new() -> Self {
+        Self::with_locked_rx(None)
+    }
+
+    /// Creates a new [`CubitCore`] that doesn't emit states while the app is locked
+    pub(crate) fn with_app_lock(app_lock: &AppLockHandle) -> Self {
+        Self::with_locked_rx(Some(app_lock.subscribe()))
+    }
+
+    fn with_locked_rx(locked_rx: Option<watch::Receiver<bool>>) -> Self {
         let (state_tx, state_rx) = watch::channel(S::default());
         let (sinks_tx, sinks_rx) = mpsc::channel(16);
         let cancel = CancellationToken::new();
 
-        spawn_from_sync(Self::emitter_loop(state_rx, sinks_rx, cancel.clone()));
+        spawn_from_sync(Self::emitter_loop(
+            state_rx,
+            sinks_rx,
+            locked_rx,
+            cancel.clone(),
+        ));
 
         Self {
             state_tx,
@@ -115,25 +132,44 @@ where
     /// It manages the set of currently active sinks. New sinks are added by calling
     /// [`Cubit::stream`].
     ///
-    /// State is emitted via the [`CubitCore::state_tx`] watch channel.
+    /// State is emitted via the [`CubitCore::state_tx`] watch channel. While `locked_rx` is `true`,
+    /// no states are emitted.
     ///
     /// The task is stopped when the [`CubitCore::cancel`] or [`CubitCore`] is dropped.
     async fn emitter_loop(
         mut state_rx: watch::Receiver<S>,
         mut sinks_rx: mpsc::Receiver<StreamSink<S>>,
+        mut locked_rx: Option<watch::Receiver<bool>>,
         stop: CancellationToken,
     ) {
         let mut sinks = Vec::new();
+        let mut locked = locked_rx.as_ref().is_some_and(|rx| *rx.borrow());
         loop {
             tokio::select! {
                 sink = sinks_rx.recv() => {
                     let Some(sink) = sink else { return };
                     sinks.push(sink);
                 },
+                is_locked = lock_changed(&mut locked_rx) => {
+                    let was_locked = locked;
+                    // The lock is gone together with the user
+                    locked = is_locked.unwrap_or(false);
+                    if is_locked.is_none() {
+                        locked_rx = None;
+                    }
+                    if was_locked && !locked {
+                        let state = state_rx.borrow().clone();
+                        trace!(num_sinks = sinks.len(), "emitting state held back while locked");
+                        sinks.retain(|sink| sink.add(state.clone()).is_ok());
+                    }
+                },
                 changed = state_rx.changed() => {
                     if changed.is_err() {
                         return;
                     };
+                    if locked {
+                        continue;
+                    }
                     let state = state_rx.borrow().clone();
                     trace!(num_sinks = sinks.len(), ?state, "emitting new state");
                     sinks.retain(|sink| sink.add(state.clone()).is_ok());
@@ -145,3 +181,14 @@ where
         }
     }
 }
+
+/// Waits until the app is locked or unlocked. Returns `None` if the lock is gone.
+async fn lock_changed(locked_rx: &mut Option<watch::Receiver<bool>>) -> Option<bool> {
+    match locked_rx {
+        Some(rx) => {
+            rx.changed().await.ok()?;
+            Some(*rx.borrow_and_update())
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Typed broadcast of application events.
+//!
+//! All events that cubits react to (changes in the local store, messages
+//! fetched from the server, connectivity changes and received pushes) are
+//! published on a single [`EventBus`]. Some topics describe a state, e.g. the
+//! connectivity. The bus remembers the latest event of each of these topics
+//! and replays it to new subscribers, such that a cubit created after an
+//! event still learns about the current state. All other events are one-shot
+//! and only delivered to the subscribers at the time they are published.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use phnxcoreclient::NotificationType;
+use phnxtypes::identifiers::QualifiedUserName;
+use tokio::sync::broadcast;
+
+use crate::api::messages::FetchedMessages;
+
+const EVENT_BUS_CAPACITY: usize = 128;
+
+#[derive(Debug, Clone)]
+pub(crate) enum AppEvent {
+    /// The local store has changed.
+    Store(NotificationType),
+    /// Messages were fetched from the server and processed.
+    Queue(Arc<FetchedMessages>),
+    /// The connection to the server has changed.
+    Connectivity(ConnectivityState),
+    /// Fetching messages from the server succeeded or failed.
+    Fetch(FetchOutcome),
+    /// The platform received a push notification.
+    PushReceived,
+    /// The state of the renewal of the client credential has changed.
+    CredentialRenewal(CredentialRenewalState),
+    /// The announcements of the server operator have changed.
+    Announcements,
+    /// The presence of the given contacts has changed.
+    Presence(Arc<Vec<QualifiedUserName>>),
+    /// A desktop notification with the given payload was clicked.
+    NotificationOpened(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum AppEventTopic {
+    Store,
+    Queue,
+    Connectivity,
+    Fetch,
+    Push,
+    CredentialRenewal,
+    Announcements,
+    Presence,
+    NotificationOpened,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectivityState {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FetchOutcome {
+    Succeeded,
+    Failed { reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CredentialRenewalState {
+    /// The client credential is valid and not due for renewal.
+    Valid,
+    /// Renewing the client credential failed repeatedly. The credential
+    /// becomes invalid at the given time.
+    Failing { expires_at: DateTime<Utc> },
+}
+
+impl AppEvent {
+    pub(crate) fn topic(&self) -> AppEventTopic {
+        match self {
+            AppEvent::Store(_) => AppEventTopic::Store,
+            AppEvent::Queue(_) => AppEventTopic::Queue,
+            AppEvent::Connectivity(_) => AppEventTopic::Connectivity,
+            AppEvent::Fetch(_) => AppEventTopic::Fetch,
+            AppEvent::PushReceived => AppEventTopic::Push,
+            AppEvent::CredentialRenewal(_) => AppEventTopic::CredentialRenewal,
+            AppEvent::Announcements => AppEventTopic::Announcements,
+            AppEvent::Presence(_) => AppEventTopic::Presence,
+            AppEvent::NotificationOpened(_) => AppEventTopic::NotificationOpened,
+        }
+    }
+}
+
+/// Cheaply clonable handle to the event bus
+#[derive(Debug, Clone)]
+pub(crate) struct EventBus {
+    inner: Arc<EventBusInner>,
+}
+
+#[derive(Debug)]
+struct EventBusInner {
+    tx: broadcast::Sender<AppEvent>,
+    /// Latest event of each state topic
+    latest: Mutex<HashMap<AppEventTopic, AppEvent>>,
+}
+
+impl AppEventTopic {
+    /// Whether the latest event of the topic describes the current state and
+    /// is replayed to new subscribers.
+    fn is_state(self) -> bool {
+        match self {
+            AppEventTopic::Connectivity
+            | AppEventTopic::Fetch
+            | AppEventTopic::CredentialRenewal => true,
+            AppEventTopic::Store
+            | AppEventTopic::Queue
+            | AppEventTopic::Push
+            | AppEventTopic::Announcements
+            | AppEventTopic::Presence
+            | AppEventTopic::NotificationOpened => false,
+        }
+    }
+}
+
+impl EventBus {
+    pub(crate) fn new() -> Self {
+        let inner = EventBusInner {
+            tx: broadcast::channel(EVENT_BUS_CAPACITY).0,
+            latest: Default::default(),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Publish an event to all subscribers.
+    ///
+    /// Also used by tests to inject synthetic events.
+    pub(crate) fn publish(&self, event: AppEvent) {
+        // Holding the lock while sending guarantees that subscribers either
+        // get the event replayed or receive it from the channel, but not both.
+        let mut latest = self.inner.latest.lock();
+        if event.topic().is_state() {
+            latest.insert(event.topic(), event.clone());
+        }
+        let _no_receivers = self.inner.tx.send(event);
+    }
+
+    /// Subscribe to all events published from now on. The latest event of
+    /// each state topic is replayed first.
+    pub(crate) fn subscribe(&self) -> EventBusReceiver {
+        let latest = self.inner.latest.lock();
+        let rx = self.inner.tx.subscribe();
+        let replay = latest.values().cloned().collect();
+        EventBusReceiver { replay, rx }
+    }
+}
+
+pub(crate) struct EventBusReceiver {
+    replay: VecDeque<AppEvent>,
+    rx: broadcast::Receiver<AppEvent>,
+}
+
+impl EventBusReceiver {
+    pub(crate) async fn recv(&mut self) -> Result<AppEvent, broadcast::error::RecvError> {
+        if let Some(event) = self.replay.pop_front() {
+            return Ok(event);
+        }
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_latest_event_per_topic() {
+        let bus = EventBus::new();
+        bus.publish(AppEvent::Connectivity(ConnectivityState::Connected));
+        bus.publish(AppEvent::Connectivity(ConnectivityState::Disconnected));
+
+        let mut rx = bus.subscribe();
+        let event = rx.recv().await.unwrap();
+        assert!(matches!(
+            event,
+            AppEvent::Connectivity(ConnectivityState::Disconnected)
+        ));
+
+        bus.publish(AppEvent::PushReceived);
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.topic(), AppEventTopic::Push);
+    }
+
+    #[tokio::test]
+    async fn one_shot_events_are_not_replayed() {
+        let bus = EventBus::new();
+        bus.publish(AppEvent::PushReceived);
+        bus.publish(AppEvent::Announcements);
+        bus.publish(AppEvent::Fetch(FetchOutcome::Succeeded));
+
+        let mut rx = bus.subscribe();
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.topic(), AppEventTopic::Fetch);
+        assert!(rx.replay.is_empty());
+        assert!(rx.rx.try_recv().is_err());
+    }
+}
@@ -39,10 +39,6 @@ impl FibonacciBackoff {
         }
     }
 
-    #[allow(
-        dead_code,
-        reason = "will be used when we detect the device is offline"
-    )]
     pub(crate) fn new_extended() -> Self {
         FibonacciBackoff {
             current_idx: 0,
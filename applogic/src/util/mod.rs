@@ -3,9 +3,13 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 mod cubit_core;
+mod event_bus;
 mod fibonacci_backoff;
 mod spawn;
 
 pub(crate) use cubit_core::{Cubit, CubitCore};
+pub(crate) use event_bus::{
+    AppEvent, ConnectivityState, CredentialRenewalState, EventBus, EventBusReceiver, FetchOutcome,
+};
 pub(crate) use fibonacci_backoff::FibonacciBackoff;
 pub(crate) use spawn::spawn_from_sync;
@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+CREATE TABLE qs_federation_key_pins (
+    domain TEXT PRIMARY KEY,
+    fingerprint BYTEA NOT NULL,
+    first_seen timestamptz NOT NULL,
+    last_seen timestamptz NOT NULL
+);
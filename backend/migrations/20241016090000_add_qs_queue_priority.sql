@@ -0,0 +1,6 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Priority lane of a queued message: 0 = high, 1 = normal, 2 = low
+ALTER TABLE qs_queues ADD COLUMN priority SMALLINT NOT NULL DEFAULT 1;
@@ -0,0 +1,14 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Group operations applied by the DS, used to detect retried requests
+CREATE TABLE ds_applied_operations(
+    group_id uuid NOT NULL REFERENCES encrypted_groups(group_id) ON DELETE CASCADE,
+    idempotency_key BYTEA NOT NULL,
+    epoch BIGINT NOT NULL,
+    sender BYTEA NOT NULL,
+    fanout_timestamp timestamptz NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (group_id, idempotency_key)
+);
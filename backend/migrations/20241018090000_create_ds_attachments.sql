@@ -0,0 +1,20 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Attachments uploaded to the DS. Status: 0 = clean, 1 = blocked by the scanner
+CREATE TABLE ds_attachments(
+    attachment_id uuid PRIMARY KEY,
+    group_id uuid NOT NULL REFERENCES encrypted_groups(group_id) ON DELETE CASCADE,
+    size BIGINT NOT NULL,
+    status SMALLINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ds_attachments_group_id ON ds_attachments(group_id);
+
+CREATE TABLE ds_attachment_objects(
+    attachment_id uuid PRIMARY KEY REFERENCES ds_attachments(attachment_id) ON DELETE CASCADE,
+    content BYTEA NOT NULL,
+    quarantined BOOLEAN NOT NULL DEFAULT FALSE
+);
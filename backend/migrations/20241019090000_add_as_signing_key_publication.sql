@@ -0,0 +1,8 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Intermediate credentials that were rotated out are still published until
+-- this point in time, so that clients can verify the client credentials they
+-- signed. NULL means the credential is published indefinitely.
+ALTER TABLE as_signing_keys ADD COLUMN published_until TIMESTAMPTZ;
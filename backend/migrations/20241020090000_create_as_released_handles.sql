@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- User names of deleted users. They can only be registered again once the
+-- cooldown of the handle policy has passed.
+CREATE TABLE as_released_handles (
+    user_name TEXT PRIMARY KEY,
+    released_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,35 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- High-water mark of each notification channel, i.e. the sequence number of
+-- the latest event published on the channel.
+CREATE TABLE pg_listen_channels (
+    channel TEXT PRIMARY KEY,
+    seq BIGINT NOT NULL
+);
+
+-- Events published on notification channels. Listeners that missed a
+-- notification catch up from this table.
+CREATE TABLE pg_listen_events (
+    channel TEXT NOT NULL,
+    seq BIGINT NOT NULL,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (channel, seq)
+);
+
+CREATE INDEX pg_listen_events_created_at_idx ON pg_listen_events (created_at);
+
+-- Notify listeners about each event. The notification carries the sequence
+-- number, such that listeners can detect missed notifications.
+CREATE FUNCTION pg_listen_notify() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify(NEW.channel, NEW.seq || ':' || NEW.payload);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER pg_listen_events_notify
+    AFTER INSERT ON pg_listen_events
+    FOR EACH ROW EXECUTE FUNCTION pg_listen_notify();
@@ -0,0 +1,17 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Exports of the personal data of users. The archive is encrypted to a key of
+-- the requesting client and NULL until the export is assembled.
+CREATE TABLE as_data_exports (
+    export_id uuid PRIMARY KEY,
+    user_name TEXT NOT NULL,
+    requested_at TIMESTAMPTZ NOT NULL,
+    completed_at TIMESTAMPTZ,
+    failed BOOLEAN NOT NULL DEFAULT FALSE,
+    archive BYTEA,
+    FOREIGN KEY (user_name) REFERENCES as_user_records(user_name) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_as_data_exports_user_name ON as_data_exports(user_name);
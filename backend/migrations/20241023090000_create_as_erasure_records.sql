@@ -0,0 +1,18 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Audit trail of deleted users. The counts of groups and attachments are
+-- reported by the client and can't be verified by the AS.
+CREATE TABLE as_erasure_records (
+    id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    user_name TEXT NOT NULL,
+    erased_at TIMESTAMPTZ NOT NULL,
+    deleted_clients INTEGER NOT NULL,
+    deleted_exports INTEGER NOT NULL,
+    groups_left INTEGER NOT NULL,
+    attachments_deleted INTEGER NOT NULL,
+    qs_user_deleted BOOLEAN NOT NULL
+);
+
+CREATE INDEX idx_as_erasure_records_user_name ON as_erasure_records(user_name);
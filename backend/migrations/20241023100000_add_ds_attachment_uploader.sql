@@ -0,0 +1,9 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Hash of the user auth key of the uploader. NULL for attachments uploaded
+-- before it was recorded or by users that hadn't set their user key yet.
+ALTER TABLE ds_attachments ADD COLUMN uploader BYTEA;
+
+CREATE INDEX ds_attachments_group_id_uploader ON ds_attachments(group_id, uploader);
@@ -0,0 +1,24 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Presence published by QS users. `last_active_at` is the last time the user
+-- was online or away, and is only revealed coarsely.
+CREATE TABLE qs_presence (
+    user_id uuid PRIMARY KEY,
+    status SMALLINT NOT NULL,
+    visibility SMALLINT NOT NULL,
+    updated_at timestamptz NOT NULL,
+    last_active_at timestamptz,
+    FOREIGN KEY (user_id) REFERENCES qs_user_records(user_id) ON DELETE CASCADE
+);
+
+-- Friendship tokens of the contacts allowed to see the presence of a user.
+CREATE TABLE qs_presence_viewers (
+    user_id uuid NOT NULL,
+    friendship_token BYTEA NOT NULL,
+    PRIMARY KEY (user_id, friendship_token),
+    FOREIGN KEY (user_id) REFERENCES qs_presence(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX qs_presence_viewers_friendship_token ON qs_presence_viewers(friendship_token);
@@ -0,0 +1,17 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Expiration of connection packages. NULL for packages published before the
+-- column was added until the expiry sweep fills it in from the package.
+ALTER TABLE connection_packages ADD COLUMN expires_at TIMESTAMPTZ;
+
+CREATE INDEX idx_connection_package_expires_at ON connection_packages(expires_at);
+
+-- Clients that were asked to publish fresh connection packages, such that
+-- they are asked only once until they publish new ones.
+CREATE TABLE as_connection_package_refresh_requests (
+    client_id uuid PRIMARY KEY,
+    requested_at TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (client_id) REFERENCES as_client_records(client_id) ON DELETE CASCADE
+);
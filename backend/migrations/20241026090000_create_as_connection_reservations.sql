@@ -0,0 +1,16 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Reservations of served connection packages. A connection establishment
+-- package can be enqueued once per client of a reservation until it expires.
+CREATE TABLE as_connection_reservations (
+    reservation_id uuid NOT NULL,
+    client_id uuid NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    enqueued_at TIMESTAMPTZ,
+    PRIMARY KEY (reservation_id, client_id),
+    FOREIGN KEY (client_id) REFERENCES as_client_records(client_id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_as_connection_reservations_expires_at ON as_connection_reservations(expires_at);
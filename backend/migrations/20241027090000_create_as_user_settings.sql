@@ -0,0 +1,13 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Settings synced between the clients of a user, encrypted under a key shared
+-- by the clients. The version is incremented with each upload.
+CREATE TABLE as_user_settings (
+    user_name TEXT PRIMARY KEY,
+    version BIGINT NOT NULL,
+    settings BYTEA NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (user_name) REFERENCES as_user_records(user_name) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Registration time of users, from which the AS derives the reputation hint
+-- for connection requests. Users registered before the column was added are
+-- considered established.
+ALTER TABLE as_user_records
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT '1970-01-01T00:00:00Z';
+
+ALTER TABLE as_user_records ALTER COLUMN created_at SET DEFAULT now();
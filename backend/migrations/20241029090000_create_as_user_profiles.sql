@@ -0,0 +1,13 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Profiles of users, encrypted under a key the user shares with its contacts.
+-- The version is incremented with each upload.
+CREATE TABLE as_user_profiles (
+    user_name TEXT PRIMARY KEY,
+    version BIGINT NOT NULL,
+    profile BYTEA NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (user_name) REFERENCES as_user_records(user_name) ON DELETE CASCADE
+);
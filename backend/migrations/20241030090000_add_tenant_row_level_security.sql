@@ -0,0 +1,101 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Row-level multi-tenancy, see `phnxbackend::tenancy`.
+--
+-- Every table gets a `tenant` column holding the domain a row belongs to.
+-- Rows inserted by a tenant connection get the tenant's domain, all other
+-- rows get the empty tenant. Connections of a tenant switch to the
+-- `phnx_tenant` role, for which the policies restrict all reads and writes
+-- to the rows of its domain. The owner of the tables, which runs the
+-- migrations and serves single-domain deployments, is not restricted.
+--
+-- Tables created by later migrations must get the column and the policy as
+-- well.
+
+-- Roles are shared by all databases of the cluster, so the role may exist.
+DO $$
+BEGIN
+    CREATE ROLE phnx_tenant NOLOGIN;
+EXCEPTION WHEN duplicate_object OR unique_violation THEN
+    NULL;
+END
+$$;
+
+GRANT phnx_tenant TO CURRENT_USER;
+GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA public TO phnx_tenant;
+GRANT USAGE, SELECT ON ALL SEQUENCES IN SCHEMA public TO phnx_tenant;
+ALTER DEFAULT PRIVILEGES IN SCHEMA public
+    GRANT SELECT, INSERT, UPDATE, DELETE ON TABLES TO phnx_tenant;
+ALTER DEFAULT PRIVILEGES IN SCHEMA public
+    GRANT USAGE, SELECT ON SEQUENCES TO phnx_tenant;
+REVOKE ALL ON _sqlx_migrations FROM phnx_tenant;
+
+DO $$
+DECLARE
+    table_name TEXT;
+BEGIN
+    FOR table_name IN
+        SELECT tablename FROM pg_tables
+        WHERE schemaname = 'public' AND tablename <> '_sqlx_migrations'
+    LOOP
+        EXECUTE format(
+            'ALTER TABLE %I ADD COLUMN tenant TEXT NOT NULL
+                DEFAULT COALESCE(current_setting(''phnx.tenant'', true), '''')',
+            table_name
+        );
+        EXECUTE format('ALTER TABLE %I ENABLE ROW LEVEL SECURITY', table_name);
+        EXECUTE format(
+            'CREATE POLICY tenant_isolation ON %I TO phnx_tenant
+                USING (tenant = current_setting(''phnx.tenant'', true))
+                WITH CHECK (tenant = current_setting(''phnx.tenant'', true))',
+            table_name
+        );
+    END LOOP;
+END
+$$;
+
+-- Keys that are only unique per domain
+ALTER TABLE as_batched_keys DROP CONSTRAINT as_batched_keys_pkey;
+ALTER TABLE as_batched_keys ADD PRIMARY KEY (tenant, token_key_id);
+ALTER TABLE qs_federation_key_pins DROP CONSTRAINT qs_federation_key_pins_pkey;
+ALTER TABLE qs_federation_key_pins ADD PRIMARY KEY (tenant, domain);
+ALTER TABLE pg_listen_channels DROP CONSTRAINT pg_listen_channels_pkey;
+ALTER TABLE pg_listen_channels ADD PRIMARY KEY (tenant, channel);
+ALTER TABLE pg_listen_events DROP CONSTRAINT pg_listen_events_pkey;
+ALTER TABLE pg_listen_events ADD PRIMARY KEY (tenant, channel, seq);
+
+-- Name under which the notifications of a channel are sent. Notifications
+-- are not subject to row-level security, so the channels of tenants are
+-- qualified with a hash of the domain. Postgres limits channel names to 63
+-- bytes, which rules out the domain itself.
+CREATE FUNCTION pg_listen_notification_channel(tenant TEXT, channel TEXT) RETURNS TEXT AS $$
+    SELECT CASE WHEN tenant = '' THEN channel ELSE left(md5(tenant), 16) || ':' || channel END;
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION pg_listen_notify() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify(
+        pg_listen_notification_channel(NEW.tenant, NEW.channel),
+        NEW.seq || ':' || NEW.payload
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Assigns the rows stored before the server served multiple domains to the
+-- given domain.
+CREATE FUNCTION phnx_claim_untenanted_rows(new_tenant TEXT) RETURNS VOID AS $$
+DECLARE
+    table_name TEXT;
+BEGIN
+    FOR table_name IN
+        SELECT tablename FROM pg_tables
+        WHERE schemaname = 'public' AND tablename <> '_sqlx_migrations'
+    LOOP
+        EXECUTE format('UPDATE %I SET tenant = $1 WHERE tenant = ''''', table_name)
+            USING new_tenant;
+    END LOOP;
+END;
+$$ LANGUAGE plpgsql;
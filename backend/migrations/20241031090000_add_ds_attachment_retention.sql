@@ -0,0 +1,7 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Retention class of the attachment. 0 for attachments of messages, 1 for
+-- message archives, which are kept when users delete their attachments.
+ALTER TABLE ds_attachments ADD COLUMN retention SMALLINT NOT NULL DEFAULT 0;
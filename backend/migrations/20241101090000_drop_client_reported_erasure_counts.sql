@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- The AS only records the parts of an erasure it performed itself. Groups,
+-- attachments and the QS user record are erased by the client after the AS
+-- deleted the user.
+ALTER TABLE as_erasure_records DROP COLUMN groups_left;
+ALTER TABLE as_erasure_records DROP COLUMN attachments_deleted;
+ALTER TABLE as_erasure_records DROP COLUMN qs_user_deleted;
@@ -0,0 +1,18 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Tokens with which non-members can send join requests to a group. The group
+-- state EAR key is stored wrapped under the key of the token.
+CREATE TABLE ds_join_request_tokens(
+    token_id BYTEA PRIMARY KEY,
+    group_id uuid NOT NULL REFERENCES encrypted_groups(group_id) ON DELETE CASCADE,
+    wrapped_ear_key BYTEA NOT NULL,
+    expires_at timestamptz NOT NULL,
+    tenant TEXT NOT NULL DEFAULT COALESCE(current_setting('phnx.tenant', true), '')
+);
+
+ALTER TABLE ds_join_request_tokens ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON ds_join_request_tokens TO phnx_tenant
+    USING (tenant = current_setting('phnx.tenant', true))
+    WITH CHECK (tenant = current_setting('phnx.tenant', true));
@@ -0,0 +1,17 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Client credentials issued during a credential refresh that the client
+-- hasn't confirmed yet. Until then, the credential in the client record stays
+-- valid.
+CREATE TABLE as_pending_client_credentials(
+    client_id uuid PRIMARY KEY REFERENCES as_client_records(client_id) ON DELETE CASCADE,
+    credential BYTEA NOT NULL,
+    tenant TEXT NOT NULL DEFAULT COALESCE(current_setting('phnx.tenant', true), '')
+);
+
+ALTER TABLE as_pending_client_credentials ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON as_pending_client_credentials TO phnx_tenant
+    USING (tenant = current_setting('phnx.tenant', true))
+    WITH CHECK (tenant = current_setting('phnx.tenant', true));
@@ -0,0 +1,21 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Fan-outs to clients of federated servers that couldn't be delivered yet.
+-- `message` holds the serialized `DsFanOutMessage`.
+CREATE TABLE qs_pending_fanouts(
+    fanout_id uuid PRIMARY KEY,
+    message BYTEA NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at timestamptz NOT NULL,
+    created_at timestamptz NOT NULL,
+    tenant TEXT NOT NULL DEFAULT COALESCE(current_setting('phnx.tenant', true), '')
+);
+
+CREATE INDEX qs_pending_fanouts_next_attempt_at ON qs_pending_fanouts(next_attempt_at);
+
+ALTER TABLE qs_pending_fanouts ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON qs_pending_fanouts TO phnx_tenant
+    USING (tenant = current_setting('phnx.tenant', true))
+    WITH CHECK (tenant = current_setting('phnx.tenant', true));
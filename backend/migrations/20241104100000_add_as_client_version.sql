@@ -0,0 +1,9 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Version a client announced when it last published connection packages.
+-- The AS only enqueues queue messages the client can decode, see
+-- `AsQueueMessageType::min_client_version`. NULL if the client didn't
+-- announce its version.
+ALTER TABLE as_client_records ADD COLUMN client_version TEXT;
@@ -0,0 +1,19 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Nonces of signed client payloads that are still within the freshness
+-- window, used to reject replays.
+CREATE TABLE replay_nonces(
+    nonce BYTEA NOT NULL,
+    expires_at timestamptz NOT NULL,
+    tenant TEXT NOT NULL DEFAULT COALESCE(current_setting('phnx.tenant', true), ''),
+    PRIMARY KEY (tenant, nonce)
+);
+
+CREATE INDEX replay_nonces_expires_at ON replay_nonces(expires_at);
+
+ALTER TABLE replay_nonces ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON replay_nonces TO phnx_tenant
+    USING (tenant = current_setting('phnx.tenant', true))
+    WITH CHECK (tenant = current_setting('phnx.tenant', true));
@@ -0,0 +1,22 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Viewers of a user's presence are identified by per-contact presence tokens
+-- instead of their friendship tokens, so that the QS doesn't store the
+-- contacts of its users. The viewers are republished with the presence, so
+-- the existing ones are dropped.
+DROP TABLE qs_presence_viewers;
+
+CREATE TABLE qs_presence_viewers (
+    user_id uuid NOT NULL,
+    presence_token BYTEA NOT NULL,
+    tenant TEXT NOT NULL DEFAULT COALESCE(current_setting('phnx.tenant', true), ''),
+    PRIMARY KEY (user_id, presence_token),
+    FOREIGN KEY (user_id) REFERENCES qs_presence(user_id) ON DELETE CASCADE
+);
+
+ALTER TABLE qs_presence_viewers ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON qs_presence_viewers TO phnx_tenant
+    USING (tenant = current_setting('phnx.tenant', true))
+    WITH CHECK (tenant = current_setting('phnx.tenant', true));
@@ -0,0 +1,14 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Erasure records must not keep the names of the erased users. Only the
+-- SHA-256 hash of the name is stored, with which the operator can still check
+-- whether a given user was erased.
+ALTER TABLE as_erasure_records ADD COLUMN user_name_hash BYTEA;
+UPDATE as_erasure_records SET user_name_hash = sha256(convert_to(user_name, 'UTF8'));
+ALTER TABLE as_erasure_records ALTER COLUMN user_name_hash SET NOT NULL;
+
+DROP INDEX idx_as_erasure_records_user_name;
+ALTER TABLE as_erasure_records DROP COLUMN user_name;
+CREATE INDEX idx_as_erasure_records_user_name_hash ON as_erasure_records(user_name_hash);
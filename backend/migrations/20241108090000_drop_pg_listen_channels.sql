@@ -0,0 +1,19 @@
+-- SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+--
+-- SPDX-License-Identifier: AGPL-3.0-or-later
+
+-- Every publish updated the row of its channel in `pg_listen_channels`, so
+-- all publishes of a channel contended for the same row. The high-water mark
+-- is now the highest sequence number in `pg_listen_events`, of which the
+-- latest event of each channel is never pruned.
+--
+-- Channels whose events were all pruned keep their high-water mark through
+-- an empty event. No notification is sent for it, and listeners only catch up
+-- on it if they were behind the high-water mark anyway.
+ALTER TABLE pg_listen_events DISABLE TRIGGER pg_listen_events_notify;
+INSERT INTO pg_listen_events (tenant, channel, seq, payload)
+SELECT tenant, channel, seq, '' FROM pg_listen_channels
+ON CONFLICT DO NOTHING;
+ALTER TABLE pg_listen_events ENABLE TRIGGER pg_listen_events_notify;
+
+DROP TABLE pg_listen_channels;
@@ -0,0 +1,228 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Inspection and repair of the databases of the AS, the DS and the QS for the
+//! operator, see the `airserver-admin` binary of the server.
+//!
+//! The services implement the operations on their own storage. The databases
+//! don't reference each other: the DS and the QS only know pseudonymous
+//! identities of the users at the AS. Therefore, each service checks the
+//! references within its own database and the integrity checks of all three
+//! services together cover the deployment.
+
+use std::{fmt, str::FromStr};
+
+use sqlx::types::chrono::{DateTime, Utc};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{errors::StorageError, qs::cluster::ClusterDispatchError};
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("Can't decrypt the group state with the given key")]
+    GroupStateDecryption,
+    #[error("The QS dispatches notifications locally, so the running server can't be reached")]
+    LocalDispatch,
+    #[error(transparent)]
+    ClusterDispatch(#[from] ClusterDispatchError),
+}
+
+impl From<sqlx::Error> for AdminError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Storage(e.into())
+    }
+}
+
+/// Service whose database contains an [`IntegrityIssue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminService {
+    As,
+    Ds,
+    Qs,
+}
+
+impl fmt::Display for AdminService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminService::As => write!(f, "AS"),
+            AdminService::Ds => write!(f, "DS"),
+            AdminService::Qs => write!(f, "QS"),
+        }
+    }
+}
+
+/// Violated invariant of the stored data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    /// The client has no queue, so messages for it can't be enqueued.
+    MissingQueue {
+        service: AdminService,
+        client_id: Uuid,
+    },
+    /// The sequence number of the next message of the queue is not above the
+    /// ones of the stored messages, so enqueueing fails.
+    QueueCounterBehind {
+        service: AdminService,
+        queue_id: Uuid,
+        sequence_number: i64,
+        max_stored: i64,
+    },
+    /// The QS user has no clients. Users are created right before their first
+    /// client, so this is only repaired by deleting the user via the client.
+    UserWithoutClients { user_id: Uuid },
+    /// The group state expired but wasn't deleted, because no member accessed
+    /// it since.
+    ExpiredGroup {
+        group_id: Uuid,
+        last_used: DateTime<Utc>,
+    },
+}
+
+/// Kind of [`IntegrityIssue`] to repair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    MissingQueue,
+    QueueCounterBehind,
+    UserWithoutClients,
+    ExpiredGroup,
+}
+
+impl IntegrityIssue {
+    pub fn service(&self) -> AdminService {
+        match self {
+            IntegrityIssue::MissingQueue { service, .. }
+            | IntegrityIssue::QueueCounterBehind { service, .. } => *service,
+            IntegrityIssue::UserWithoutClients { .. } => AdminService::Qs,
+            IntegrityIssue::ExpiredGroup { .. } => AdminService::Ds,
+        }
+    }
+
+    pub fn kind(&self) -> IssueKind {
+        match self {
+            IntegrityIssue::MissingQueue { .. } => IssueKind::MissingQueue,
+            IntegrityIssue::QueueCounterBehind { .. } => IssueKind::QueueCounterBehind,
+            IntegrityIssue::UserWithoutClients { .. } => IssueKind::UserWithoutClients,
+            IntegrityIssue::ExpiredGroup { .. } => IssueKind::ExpiredGroup,
+        }
+    }
+
+    /// Returns true if the issue can be repaired by the service.
+    pub fn is_repairable(&self) -> bool {
+        self.kind() != IssueKind::UserWithoutClients
+    }
+}
+
+impl fmt::Display for IntegrityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: ", self.service(), self.kind())?;
+        match self {
+            IntegrityIssue::MissingQueue { client_id, .. } => {
+                write!(f, "client {client_id} has no queue")
+            }
+            IntegrityIssue::QueueCounterBehind {
+                queue_id,
+                sequence_number,
+                max_stored,
+                ..
+            } => write!(
+                f,
+                "queue {queue_id} continues at {sequence_number}, but stores message {max_stored}"
+            ),
+            IntegrityIssue::UserWithoutClients { user_id } => {
+                write!(f, "user {user_id} has no clients")
+            }
+            IntegrityIssue::ExpiredGroup {
+                group_id,
+                last_used,
+            } => write!(f, "group {group_id} was last used at {last_used}"),
+        }
+    }
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IssueKind::MissingQueue => write!(f, "missing-queue"),
+            IssueKind::QueueCounterBehind => write!(f, "queue-counter-behind"),
+            IssueKind::UserWithoutClients => write!(f, "user-without-clients"),
+            IssueKind::ExpiredGroup => write!(f, "expired-group"),
+        }
+    }
+}
+
+impl FromStr for IssueKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "missing-queue" => Ok(IssueKind::MissingQueue),
+            "queue-counter-behind" => Ok(IssueKind::QueueCounterBehind),
+            "user-without-clients" => Ok(IssueKind::UserWithoutClients),
+            "expired-group" => Ok(IssueKind::ExpiredGroup),
+            _ => Err(format!("Unknown issue kind {s}")),
+        }
+    }
+}
+
+/// User at the AS.
+#[derive(Debug, Clone)]
+pub struct UserSummary {
+    pub user_name: String,
+    pub clients: i64,
+    /// Latest activity of any of the user's clients
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+/// Stored data of a group at the DS.
+#[derive(Debug, Clone)]
+pub struct GroupSummary {
+    pub group_id: Uuid,
+    pub last_used: DateTime<Utc>,
+    pub expired: bool,
+    /// Size of the encrypted group state in bytes
+    pub state_size: usize,
+    /// Number of queues of removed clients the DS still tracks
+    pub deleted_queues: usize,
+    /// Only available if the group state could be decrypted
+    pub state: Option<GroupStateSummary>,
+}
+
+/// Content of the encrypted state of a group at the DS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupStateSummary {
+    pub epoch: u64,
+    pub members: usize,
+    pub clients: usize,
+}
+
+/// Queue at the QS whose client hasn't fetched its messages for a while.
+#[derive(Debug, Clone)]
+pub struct StuckQueue {
+    pub queue_id: Uuid,
+    pub last_activity: DateTime<Utc>,
+    pub pending_messages: i64,
+    /// Sequence number of the oldest pending message
+    pub oldest_sequence_number: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issue_kinds_roundtrip() {
+        for kind in [
+            IssueKind::MissingQueue,
+            IssueKind::QueueCounterBehind,
+            IssueKind::UserWithoutClients,
+            IssueKind::ExpiredGroup,
+        ] {
+            assert_eq!(kind.to_string().parse::<IssueKind>(), Ok(kind));
+        }
+        assert!("missing_queue".parse::<IssueKind>().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Operator access to the AS database, see [`crate::admin`].
+
+use phnxtypes::identifiers::{AsClientId, QualifiedUserName, SafeTryInto};
+use uuid::Uuid;
+
+use crate::admin::{AdminError, AdminService, IntegrityIssue, UserSummary};
+
+use super::{queue::Queue, AuthService};
+
+impl AuthService {
+    /// All users with the number of their clients, ordered by name.
+    pub async fn admin_list_users(&self) -> Result<Vec<UserSummary>, AdminError> {
+        Ok(persistence::load_user_summaries(&self.db_pool).await?)
+    }
+
+    pub async fn admin_check_integrity(&self) -> Result<Vec<IntegrityIssue>, AdminError> {
+        let missing_queues = persistence::load_clients_without_queue(&self.db_pool).await?;
+        let queues_behind = persistence::load_queues_behind(&self.db_pool).await?;
+        let issues = missing_queues
+            .into_iter()
+            .map(|client_id| IntegrityIssue::MissingQueue {
+                service: AdminService::As,
+                client_id,
+            })
+            .chain(
+                queues_behind
+                    .into_iter()
+                    .map(|(queue_id, sequence_number, max_stored)| {
+                        IntegrityIssue::QueueCounterBehind {
+                            service: AdminService::As,
+                            queue_id,
+                            sequence_number,
+                            max_stored,
+                        }
+                    }),
+            )
+            .collect();
+        Ok(issues)
+    }
+
+    /// Repair the given issue. Returns false if the issue doesn't concern the
+    /// AS or can't be repaired.
+    pub async fn admin_repair(&self, issue: &IntegrityIssue) -> Result<bool, AdminError> {
+        if issue.service() != AdminService::As {
+            return Ok(false);
+        }
+        match issue {
+            IntegrityIssue::MissingQueue { client_id, .. } => {
+                let Some(user_name) =
+                    persistence::load_client_user_name(&self.db_pool, *client_id).await?
+                else {
+                    return Ok(false);
+                };
+                let Ok(user_name) =
+                    <&str as SafeTryInto<QualifiedUserName>>::try_into(user_name.as_str())
+                else {
+                    tracing::warn!(%client_id, "Invalid user name of client");
+                    return Ok(false);
+                };
+                let mut connection = self.db_pool.acquire().await?;
+                Queue::new_and_store(AsClientId::new(user_name, *client_id), &mut connection)
+                    .await?;
+                Ok(true)
+            }
+            IntegrityIssue::QueueCounterBehind {
+                queue_id,
+                max_stored,
+                ..
+            } => {
+                persistence::advance_queue(&self.db_pool, *queue_id, max_stored + 1).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+mod persistence {
+    use sqlx::PgExecutor;
+
+    use crate::errors::StorageError;
+
+    use super::*;
+
+    pub(super) async fn load_user_summaries(
+        connection: impl PgExecutor<'_>,
+    ) -> Result<Vec<UserSummary>, StorageError> {
+        let records = sqlx::query!(
+            r#"SELECT
+                u.user_name,
+                COUNT(c.client_id) AS "clients!",
+                MAX(c.activity_time) AS last_activity
+            FROM as_user_records u
+            LEFT JOIN as_client_records c ON c.user_name = u.user_name
+            GROUP BY u.user_name
+            ORDER BY u.user_name"#
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| UserSummary {
+                user_name: record.user_name,
+                clients: record.clients,
+                last_activity: record.last_activity,
+            })
+            .collect())
+    }
+
+    pub(super) async fn load_clients_without_queue(
+        connection: impl PgExecutor<'_>,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let client_ids = sqlx::query_scalar!(
+            "SELECT c.client_id FROM as_client_records c
+            LEFT JOIN as_queue_data q ON q.queue_id = c.client_id
+            WHERE q.queue_id IS NULL"
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(client_ids)
+    }
+
+    pub(super) async fn load_client_user_name(
+        connection: impl PgExecutor<'_>,
+        client_id: Uuid,
+    ) -> Result<Option<String>, StorageError> {
+        let user_name = sqlx::query_scalar!(
+            "SELECT user_name FROM as_client_records WHERE client_id = $1",
+            client_id
+        )
+        .fetch_optional(connection)
+        .await?;
+        Ok(user_name)
+    }
+
+    /// Queues whose next sequence number is not above the ones of the stored
+    /// messages, with the next and the highest stored sequence number.
+    pub(super) async fn load_queues_behind(
+        connection: impl PgExecutor<'_>,
+    ) -> Result<Vec<(Uuid, i64, i64)>, StorageError> {
+        let records = sqlx::query!(
+            r#"SELECT q.queue_id, q.sequence_number, MAX(m.sequence_number) AS "max_stored!"
+            FROM as_queue_data q
+            JOIN as_queues m ON m.queue_id = q.queue_id
+            GROUP BY q.queue_id, q.sequence_number
+            HAVING MAX(m.sequence_number) >= q.sequence_number"#
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| (record.queue_id, record.sequence_number, record.max_stored))
+            .collect())
+    }
+
+    /// Never moves the sequence number backwards.
+    pub(super) async fn advance_queue(
+        connection: impl PgExecutor<'_>,
+        queue_id: Uuid,
+        sequence_number: i64,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            "UPDATE as_queue_data SET sequence_number = $2
+            WHERE queue_id = $1 AND sequence_number < $2",
+            queue_id,
+            sequence_number
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+}
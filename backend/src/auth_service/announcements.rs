@@ -0,0 +1,94 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Announcements configured by the operator.
+
+use phnxtypes::{
+    messages::announcements::{Announcement, AnnouncementsResponse},
+    time::TimeStamp,
+};
+
+use crate::settings::AnnouncementSettings;
+
+use super::AuthService;
+
+impl AuthService {
+    /// Replace the announcements served to clients.
+    pub fn with_announcements(mut self, announcements: Vec<AnnouncementSettings>) -> Self {
+        self.announcements = announcements;
+        self
+    }
+
+    /// The announcements that are published and not expired, newest first.
+    pub fn current_announcements(&self) -> AnnouncementsResponse {
+        current_announcements(&self.announcements, TimeStamp::now())
+    }
+}
+
+fn current_announcements(
+    settings: &[AnnouncementSettings],
+    now: TimeStamp,
+) -> AnnouncementsResponse {
+    let mut announcements: Vec<_> = settings
+        .iter()
+        .filter(|announcement| {
+            let expired = matches!(announcement.expires_at, Some(expires_at) if expires_at <= now);
+            announcement.published_at <= now && !expired
+        })
+        .map(|announcement| {
+            Announcement::new(
+                announcement.id.clone(),
+                announcement.kind,
+                announcement.title.clone(),
+                announcement.body.clone(),
+                announcement.published_at,
+            )
+        })
+        .collect();
+    announcements.sort_by_key(|announcement| std::cmp::Reverse(announcement.published_at()));
+    AnnouncementsResponse { announcements }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{messages::announcements::AnnouncementKind, time::Duration};
+
+    use super::*;
+
+    fn announcement(
+        id: &str,
+        published_at: TimeStamp,
+        expires_at: Option<TimeStamp>,
+    ) -> AnnouncementSettings {
+        AnnouncementSettings {
+            id: id.to_owned(),
+            kind: AnnouncementKind::Maintenance,
+            title: "Maintenance".to_owned(),
+            body: "The server is down on Sunday".to_owned(),
+            published_at,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn only_current_announcements_are_served() {
+        let now = TimeStamp::now();
+        let earlier = TimeStamp::from(*now - Duration::hours(1));
+        let later = TimeStamp::from(*now + Duration::hours(1));
+        let settings = [
+            announcement("old", earlier, Some(now)),
+            announcement("current", earlier, Some(later)),
+            announcement("newest", now, None),
+            announcement("scheduled", later, None),
+        ];
+
+        let response = current_announcements(&settings, now);
+        let ids: Vec<_> = response
+            .announcements
+            .iter()
+            .map(|announcement| announcement.id())
+            .collect();
+        assert_eq!(ids, ["newest", "current"]);
+    }
+}
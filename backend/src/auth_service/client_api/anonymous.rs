@@ -2,19 +2,29 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::collections::HashSet;
+
 use phnxtypes::{
     errors::auth_service::{
         AsCredentialsError, EnqueueMessageError, UserClientsError, UserConnectionPackagesError,
     },
-    messages::client_as::{
-        AsCredentialsParams, AsCredentialsResponse, EnqueueMessageParams, UserClientsParams,
-        UserClientsResponse, UserConnectionPackagesParams, UserConnectionPackagesResponse,
+    identifiers::{AsClientId, ConnectionReservationId},
+    messages::{
+        client_as::{
+            AsCredentialsParams, AsCredentialsResponse, AsQueueMessagePayload,
+            CompatibilityInfoParams, EnqueueMessageParams, EnqueueMessagesParams,
+            EnqueueMessagesResponse, IntermediateCredentialsParams,
+            ReservedConnectionEstablishmentPackage, ServerInfoParams, UserClientsParams,
+            UserClientsResponse, UserConnectionPackagesParams, UserConnectionPackagesResponse,
+        },
+        server_info::{CompatibilityInfoResponse, ServerInfoResponse},
     },
+    time::now,
 };
 
 use crate::auth_service::{
     client_record::ClientRecord,
-    connection_package::StorableConnectionPackage,
+    connection_package::{ReservationClaim, StorableConnectionPackage, StoredReservation},
     credentials::{intermediate_signing_key::IntermediateCredential, signing_key::Credential},
     queue::Queue,
     AuthService,
@@ -50,7 +60,7 @@ impl AuthService {
             tracing::warn!("Failed to acquire connection from pool: {:?}", e);
             UserConnectionPackagesError::StorageError
         })?;
-        let connection_packages =
+        let client_packages =
             StorableConnectionPackage::user_connection_packages(&mut connection, &user_name)
                 .await
                 .map_err(|e| {
@@ -60,15 +70,40 @@ impl AuthService {
                     );
                     UserConnectionPackagesError::StorageError
                 })?;
+        drop(connection);
 
-        // If there are no connection packages, we have to conclude that there
-        // is no user.
-        if connection_packages.is_empty() {
+        // If there are no clients, we have to conclude that there is no user.
+        if client_packages.is_empty() {
             return Err(UserConnectionPackagesError::UnknownUser);
         }
 
+        let mut connection_packages = Vec::new();
+        let mut served_clients = Vec::new();
+        for (client_id, served) in client_packages {
+            match served {
+                Some(served) => {
+                    self.connection_package_served(&served).await;
+                    connection_packages.push(served.connection_package);
+                    served_clients.push(served.client_id);
+                }
+                None => self.connection_packages_exhausted(&client_id).await,
+            }
+        }
+        if connection_packages.is_empty() {
+            return Err(UserConnectionPackagesError::NoValidConnectionPackages);
+        }
+
+        let reservation = self
+            .reserve_connection_packages(&served_clients)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to reserve connection packages: {:?}", e);
+                UserConnectionPackagesError::StorageError
+            })?;
+
         let response = UserConnectionPackagesResponse {
             key_packages: connection_packages,
+            reservation,
         };
         Ok(response)
     }
@@ -80,10 +115,112 @@ impl AuthService {
         let EnqueueMessageParams {
             client_id,
             connection_establishment_ctxt,
+            reservation_id,
+        } = params;
+        let params = EnqueueMessagesParams {
+            reservation_id,
+            packages: vec![ReservedConnectionEstablishmentPackage {
+                client_id,
+                connection_establishment_ctxt,
+            }],
+        };
+        let response = self.as_enqueue_messages(params).await?;
+        if !response.expired_clients.is_empty() {
+            return Err(EnqueueMessageError::ReservationExpired);
+        }
+        Ok(())
+    }
+
+    /// Enqueue the connection establishment packages for the clients of a
+    /// reservation.
+    ///
+    /// All clients are claimed before anything is enqueued. Clients for which
+    /// a package was already enqueued under the reservation are skipped and
+    /// clients whose reservation expired are reported in the response, such
+    /// that the sender knows which clients were reached. If enqueueing fails
+    /// part way, the claims of the remaining clients are released, such that
+    /// the request can be retried.
+    pub(crate) async fn as_enqueue_messages(
+        &self,
+        params: EnqueueMessagesParams,
+    ) -> Result<EnqueueMessagesResponse, EnqueueMessageError> {
+        let EnqueueMessagesParams {
+            reservation_id,
+            packages,
         } = params;
 
+        let mut payloads = Vec::with_capacity(packages.len());
+        for package in packages {
+            let payload: AsQueueMessagePayload = package
+                .connection_establishment_ctxt
+                .try_into()
+                .map_err(|_| EnqueueMessageError::LibraryError)?;
+            payloads.push((package.client_id, payload));
+        }
+
+        // Only enqueue once per reserved client, such that retries don't
+        // result in duplicate connection requests.
+        let mut connection = self.db_pool.acquire().await.map_err(|e| {
+            tracing::warn!("Failed to acquire connection from pool: {:?}", e);
+            EnqueueMessageError::StorageError
+        })?;
+        let mut claimed = Vec::with_capacity(payloads.len());
+        let mut expired_clients = Vec::new();
+        for (client_id, payload) in payloads {
+            let claim =
+                StoredReservation::claim(&mut connection, &reservation_id, &client_id, now()).await;
+            match claim {
+                Ok(ReservationClaim::Claimed) => claimed.push((client_id, payload)),
+                Ok(ReservationClaim::AlreadyEnqueued) => {}
+                Ok(ReservationClaim::Expired) => expired_clients.push(client_id),
+                Err(e) => {
+                    tracing::warn!("Failed to claim connection package reservation: {:?}", e);
+                    drop(connection);
+                    self.release_claims(&reservation_id, claimed.iter().map(|(id, _)| id))
+                        .await;
+                    return Err(EnqueueMessageError::StorageError);
+                }
+            }
+        }
+        drop(connection);
+
+        let mut claimed = claimed.into_iter();
+        while let Some((client_id, payload)) = claimed.next() {
+            if let Err(e) = self.enqueue_as_queue_message(&client_id, payload).await {
+                let unsent =
+                    std::iter::once(&client_id).chain(claimed.as_slice().iter().map(|(id, _)| id));
+                self.release_claims(&reservation_id, unsent).await;
+                return Err(e);
+            }
+        }
+        Ok(EnqueueMessagesResponse { expired_clients })
+    }
+
+    /// Release the claims of the given clients if enqueueing failed, such
+    /// that it can be retried.
+    async fn release_claims<'a>(
+        &self,
+        reservation_id: &ConnectionReservationId,
+        client_ids: impl Iterator<Item = &'a AsClientId>,
+    ) {
+        for client_id in client_ids {
+            if let Err(e) =
+                StoredReservation::release(&self.db_pool, reservation_id, client_id).await
+            {
+                tracing::error!("Failed to release connection package reservation: {:?}", e);
+            }
+        }
+    }
+
+    /// Encrypt the payload with the queue ratchet of the client and enqueue
+    /// it in the client's AS queue.
+    pub(in crate::auth_service) async fn enqueue_as_queue_message(
+        &self,
+        client_id: &AsClientId,
+        payload: AsQueueMessagePayload,
+    ) -> Result<(), EnqueueMessageError> {
         // Fetch the client record.
-        let mut client_record = ClientRecord::load(&self.db_pool, &client_id)
+        let mut client_record = ClientRecord::load(&self.db_pool, client_id)
             .await
             .map_err(|e| {
                 tracing::warn!("Failed to load client record: {:?}", e);
@@ -91,10 +228,6 @@ impl AuthService {
             })?
             .ok_or(EnqueueMessageError::ClientNotFound)?;
 
-        let payload = connection_establishment_ctxt
-            .try_into()
-            .map_err(|_| EnqueueMessageError::LibraryError)?;
-
         let queue_message = client_record
             .ratchet_key
             .encrypt(payload)
@@ -107,7 +240,7 @@ impl AuthService {
             tracing::warn!("Failed to acquire connection from pool: {:?}", e);
             EnqueueMessageError::StorageError
         })?;
-        Queue::enqueue(&mut connection, &client_id, queue_message)
+        Queue::enqueue(&mut connection, client_id, queue_message)
             .await
             .map_err(|e| {
                 tracing::warn!("Failed to enqueue message: {:?}", e);
@@ -144,4 +277,235 @@ impl AuthService {
             revoked_credentials: vec![],
         })
     }
+
+    /// Returns the requested intermediate credentials and the AS credentials
+    /// that signed them. Unknown fingerprints are skipped.
+    pub(crate) async fn as_intermediate_credentials(
+        &self,
+        params: IntermediateCredentialsParams,
+    ) -> Result<AsCredentialsResponse, AsCredentialsError> {
+        let fingerprints: HashSet<_> = params.fingerprints.into_iter().collect();
+        let as_intermediate_credentials: Vec<_> = IntermediateCredential::load_all(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading intermediate credentials: {:?}", e);
+                AsCredentialsError::StorageError
+            })?
+            .into_iter()
+            .filter(|credential| fingerprints.contains(credential.fingerprint()))
+            .collect();
+        let signers: HashSet<_> = as_intermediate_credentials
+            .iter()
+            .map(|credential| credential.signer_fingerprint())
+            .collect();
+        let as_credentials = Credential::load_all(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading AS credentials: {:?}", e);
+                AsCredentialsError::StorageError
+            })?
+            .into_iter()
+            .filter(|credential| signers.contains(credential.fingerprint()))
+            .collect();
+        Ok(AsCredentialsResponse {
+            as_credentials,
+            as_intermediate_credentials,
+            // We don't support revocation yet
+            revoked_credentials: vec![],
+        })
+    }
+
+    pub(crate) fn as_server_info(&self, _params: ServerInfoParams) -> ServerInfoResponse {
+        self.server_info.response()
+    }
+
+    pub(crate) fn as_compatibility_info(
+        &self,
+        _params: CompatibilityInfoParams,
+    ) -> CompatibilityInfoResponse {
+        self.server_info.compatibility_info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mls_assist::openmls_traits::types::HpkeCiphertext;
+    use phnxtypes::{
+        identifiers::{Fqdn, QualifiedUserName, SafeTryInto},
+        time::Duration,
+    };
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use crate::infra_service::InfraService;
+
+    use super::*;
+
+    /// Store a client whose queue can't be loaded, such that enqueueing for
+    /// it fails.
+    async fn store_broken_client(pool: &PgPool, user_name: &QualifiedUserName) -> AsClientId {
+        let client_id = AsClientId::new(user_name.clone(), Uuid::new_v4());
+        sqlx::query(
+            "INSERT INTO as_client_records (client_id, user_name, queue_encryption_key, ratchet,
+                activity_time, credential, remaining_tokens)
+            VALUES ($1, $2, $3, $3, now(),
+                ROW(''::bytea, NULL, NULL, NULL, NULL, NULL, NULL)::client_credential, 0)",
+        )
+        .bind(client_id.client_id())
+        .bind(user_name.to_string())
+        .bind(vec![0u8])
+        .execute(pool)
+        .await
+        .unwrap();
+        client_id
+    }
+
+    fn package(client_id: &AsClientId) -> ReservedConnectionEstablishmentPackage {
+        let ciphertext = HpkeCiphertext {
+            kem_output: vec![0u8].into(),
+            ciphertext: vec![0u8].into(),
+        };
+        ReservedConnectionEstablishmentPackage {
+            client_id: client_id.clone(),
+            connection_establishment_ctxt: ciphertext.into(),
+        }
+    }
+
+    async fn claim(
+        pool: &PgPool,
+        reservation_id: &ConnectionReservationId,
+        client_id: &AsClientId,
+    ) -> ReservationClaim {
+        let mut connection = pool.acquire().await.unwrap();
+        StoredReservation::claim(&mut connection, reservation_id, client_id, now())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn batched_enqueue_claims_and_releases_clients(pool: PgPool) {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let auth_service = AuthService::initialize(pool.clone(), domain).await.unwrap();
+        let user_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        sqlx::query("INSERT INTO as_user_records (user_name, password_file) VALUES ($1, $2)")
+            .bind(user_name.to_string())
+            .bind(vec![0u8])
+            .execute(&pool)
+            .await
+            .unwrap();
+        let reached = store_broken_client(&pool, &user_name).await;
+        let failing = store_broken_client(&pool, &user_name).await;
+        let unreserved = store_broken_client(&pool, &user_name).await;
+        let reservation = auth_service
+            .reserve_connection_packages(&[reached.clone(), failing.clone()])
+            .await
+            .unwrap();
+
+        // A client that already received its package is skipped. The claim of
+        // a client for which enqueueing fails is released for a retry.
+        assert_eq!(
+            claim(&pool, &reservation.id, &reached).await,
+            ReservationClaim::Claimed
+        );
+        let params = EnqueueMessagesParams {
+            reservation_id: reservation.id,
+            packages: vec![package(&reached), package(&failing)],
+        };
+        assert!(matches!(
+            auth_service.as_enqueue_messages(params).await,
+            Err(EnqueueMessageError::StorageError)
+        ));
+        assert_eq!(
+            claim(&pool, &reservation.id, &reached).await,
+            ReservationClaim::AlreadyEnqueued
+        );
+        assert_eq!(
+            claim(&pool, &reservation.id, &failing).await,
+            ReservationClaim::Claimed
+        );
+
+        // Clients outside of the reservation are reported as expired, the
+        // others as reached.
+        let params = EnqueueMessagesParams {
+            reservation_id: reservation.id,
+            packages: vec![package(&reached), package(&unreserved)],
+        };
+        let response = auth_service.as_enqueue_messages(params).await.unwrap();
+        assert_eq!(response.expired_clients, vec![unreserved.clone()]);
+
+        // Once the reservation expired, nothing is claimed anymore
+        sqlx::query("UPDATE as_connection_reservations SET expires_at = now(), enqueued_at = NULL")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let params = EnqueueMessagesParams {
+            reservation_id: reservation.id,
+            packages: vec![package(&reached), package(&failing)],
+        };
+        let response = auth_service.as_enqueue_messages(params).await.unwrap();
+        assert_eq!(response.expired_clients, vec![reached, failing]);
+    }
+
+    #[sqlx::test]
+    async fn intermediate_credentials_are_looked_up_in_bulk(pool: PgPool) {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let auth_service = AuthService::initialize(pool.clone(), domain).await.unwrap();
+        let initial_fingerprint = IntermediateCredential::load_all(&pool).await.unwrap()[0]
+            .fingerprint()
+            .clone();
+        let rotated_fingerprint = auth_service
+            .rotate_intermediate_signing_key(Some(Duration::days(1)))
+            .await
+            .unwrap();
+        let as_credential_fingerprints: Vec<_> = Credential::load_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|credential| credential.fingerprint().clone())
+            .collect();
+
+        // Both requested credentials are returned together with their
+        // signer. Unknown fingerprints are skipped.
+        let unknown_fingerprint = as_credential_fingerprints[0].clone();
+        let params = IntermediateCredentialsParams {
+            fingerprints: vec![
+                rotated_fingerprint.clone(),
+                unknown_fingerprint,
+                initial_fingerprint.clone(),
+            ],
+        };
+        let response = auth_service
+            .as_intermediate_credentials(params)
+            .await
+            .unwrap();
+        let fingerprints: HashSet<_> = response
+            .as_intermediate_credentials
+            .iter()
+            .map(|credential| credential.fingerprint().clone())
+            .collect();
+        assert_eq!(
+            fingerprints,
+            HashSet::from([initial_fingerprint.clone(), rotated_fingerprint])
+        );
+        for credential in &response.as_intermediate_credentials {
+            assert!(response
+                .as_credentials
+                .iter()
+                .any(|signer| signer.fingerprint() == credential.signer_fingerprint()));
+        }
+
+        // Only the requested credentials are returned.
+        let params = IntermediateCredentialsParams {
+            fingerprints: vec![initial_fingerprint.clone()],
+        };
+        let response = auth_service
+            .as_intermediate_credentials(params)
+            .await
+            .unwrap();
+        assert_eq!(response.as_intermediate_credentials.len(), 1);
+        assert_eq!(
+            response.as_intermediate_credentials[0].fingerprint(),
+            &initial_fingerprint
+        );
+    }
 }
@@ -5,17 +5,19 @@
 use opaque_ke::{rand::rngs::OsRng, ServerLogin, ServerLoginStartParameters};
 use phnxtypes::{
     credentials::ClientCredential,
-    crypto::{opaque::OpaqueLoginResponse, signatures::signable::Signable, OpaqueCiphersuite},
+    crypto::{opaque::OpaqueLoginResponse, OpaqueCiphersuite},
     errors::auth_service::{
         AsDequeueError, DeleteClientError, FinishClientAdditionError, InitClientAdditionError,
+        RefreshClientCredentialError,
     },
     messages::{
         client_as::{
-            ConnectionPackage, DeleteClientParamsTbs, DequeueMessagesParamsTbs,
-            FinishClientAdditionParamsTbs, InitClientAdditionResponse,
-            InitiateClientAdditionParams,
+            ConfirmClientCredentialParamsTbs, ConnectionPackage, DeleteClientParamsTbs,
+            DequeueMessagesParamsTbs, FinishClientAdditionParamsTbs, InitClientAdditionResponse,
+            InitiateClientAdditionParams, RefreshClientCredentialParamsTbs,
+            RefreshClientCredentialResponse,
         },
-        client_qs::DequeueMessagesResponse,
+        client_qs::{DequeueMessagesResponse, MAX_DEQUEUE_BATCH_SIZE},
     },
     time::TimeStamp,
 };
@@ -26,6 +28,7 @@ use crate::auth_service::{
     connection_package::StorableConnectionPackage,
     credentials::intermediate_signing_key::{IntermediateCredential, IntermediateSigningKey},
     opaque::OpaqueSetup,
+    pending_credential::PendingClientCredential,
     queue::Queue,
     user_record::UserRecord,
     AuthService,
@@ -113,7 +116,7 @@ impl AuthService {
 
         // Sign the credential
         let client_credential: ClientCredential = client_credential_payload
-            .sign(&signing_key)
+            .sign_with_intermediate(&signing_key)
             .map_err(|_| InitClientAdditionError::LibraryError)?;
 
         // Store the client_credential in the ephemeral DB
@@ -185,6 +188,12 @@ impl AuthService {
         let verified_connection_package: ConnectionPackage = cp
             .verify(verifying_credential.verifying_key())
             .map_err(|_| FinishClientAdditionError::InvalidConnectionPackage)?;
+        if !self
+            .connection_packages
+            .check_lifetime(&verified_connection_package)
+        {
+            return Err(FinishClientAdditionError::InvalidConnectionPackage);
+        }
 
         StorableConnectionPackage::store_multiple(
             &self.db_pool,
@@ -208,7 +217,7 @@ impl AuthService {
         &self,
         params: DeleteClientParamsTbs,
     ) -> Result<(), DeleteClientError> {
-        let client_id = params.0;
+        let client_id = params.client_id;
 
         // Delete the client
         ClientRecord::delete(&self.db_pool, &client_id)
@@ -221,6 +230,130 @@ impl AuthService {
         Ok(())
     }
 
+    pub(crate) async fn as_refresh_client_credential(
+        &self,
+        params: RefreshClientCredentialParamsTbs,
+    ) -> Result<RefreshClientCredentialResponse, RefreshClientCredentialError> {
+        let RefreshClientCredentialParamsTbs {
+            client_id,
+            client_credential_payload,
+            freshness: _,
+        } = params;
+
+        // Clients can only refresh their own credential.
+        if client_credential_payload.identity_ref() != &client_id {
+            return Err(RefreshClientCredentialError::ClientIdMismatch);
+        }
+
+        // Validate the client credential payload
+        if !client_credential_payload.validate() {
+            let now = TimeStamp::now();
+            let not_before = client_credential_payload.expiration_data().not_before();
+            let not_after = client_credential_payload.expiration_data().not_after();
+            return Err(RefreshClientCredentialError::InvalidCsr(
+                now, not_before, not_after,
+            ));
+        }
+
+        let mut transaction = self.db_pool.begin().await.map_err(|e| {
+            tracing::error!("Error starting transaction: {:?}", e);
+            RefreshClientCredentialError::StorageError
+        })?;
+
+        ClientRecord::load(&mut *transaction, &client_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading client record: {:?}", e);
+                RefreshClientCredentialError::StorageError
+            })?
+            .ok_or(RefreshClientCredentialError::UnknownClient)?;
+
+        // Sign the new credential with the current intermediate signing key.
+        let signing_key = IntermediateSigningKey::load(&mut *transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading signing key: {:?}", e);
+                RefreshClientCredentialError::StorageError
+            })?
+            .ok_or(RefreshClientCredentialError::SigningKeyNotFound)?;
+        let client_credential: ClientCredential = client_credential_payload
+            .sign_with_intermediate(&signing_key)
+            .map_err(|_| RefreshClientCredentialError::LibraryError)?;
+
+        // The current credential stays valid until the client confirms the new
+        // one. A lost response thus doesn't lock the client out.
+        PendingClientCredential::store(&mut *transaction, &client_credential)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error storing pending client credential: {:?}", e);
+                RefreshClientCredentialError::StorageError
+            })?;
+
+        transaction.commit().await.map_err(|e| {
+            tracing::error!("Error committing transaction: {:?}", e);
+            RefreshClientCredentialError::StorageError
+        })?;
+
+        Ok(RefreshClientCredentialResponse { client_credential })
+    }
+
+    /// Replace the client's credential with its pending one. The request has
+    /// already been verified with the key of the pending credential.
+    pub(crate) async fn as_confirm_client_credential(
+        &self,
+        params: ConfirmClientCredentialParamsTbs,
+    ) -> Result<(), RefreshClientCredentialError> {
+        let ConfirmClientCredentialParamsTbs {
+            client_id,
+            credential_fingerprint,
+            freshness: _,
+        } = params;
+
+        let mut transaction = self.db_pool.begin().await.map_err(|e| {
+            tracing::error!("Error starting transaction: {:?}", e);
+            RefreshClientCredentialError::StorageError
+        })?;
+
+        // A concurrent refresh might have replaced the pending credential
+        // since the request was verified.
+        let client_credential = PendingClientCredential::take(&mut *transaction, &client_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading pending client credential: {:?}", e);
+                RefreshClientCredentialError::StorageError
+            })?
+            .ok_or(RefreshClientCredentialError::NoPendingCredential)?;
+        let pending_fingerprint = client_credential
+            .fingerprint()
+            .map_err(|_| RefreshClientCredentialError::LibraryError)?;
+        if pending_fingerprint != credential_fingerprint {
+            return Err(RefreshClientCredentialError::NoPendingCredential);
+        }
+
+        let mut client_record = ClientRecord::load(&mut *transaction, &client_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading client record: {:?}", e);
+                RefreshClientCredentialError::StorageError
+            })?
+            .ok_or(RefreshClientCredentialError::UnknownClient)?;
+
+        // From now on, requests of the client have to be signed with the key
+        // of the new credential.
+        client_record.credential = client_credential;
+        client_record.update(&mut *transaction).await.map_err(|e| {
+            tracing::error!("Error updating client record: {:?}", e);
+            RefreshClientCredentialError::StorageError
+        })?;
+
+        transaction.commit().await.map_err(|e| {
+            tracing::error!("Error committing transaction: {:?}", e);
+            RefreshClientCredentialError::StorageError
+        })?;
+
+        Ok(())
+    }
+
     pub(crate) async fn as_dequeue_messages(
         &self,
         params: DequeueMessagesParamsTbs,
@@ -229,10 +362,12 @@ impl AuthService {
             sender,
             sequence_number_start,
             max_message_number,
+            freshness: _,
         } = params;
 
-        // TODO: The backend should have its own value for max_messages and use
-        // that one if the client-given one exceeds it.
+        // Larger batches are split, such that responses stay small even after
+        // long offline periods.
+        let max_message_number = max_message_number.min(MAX_DEQUEUE_BATCH_SIZE);
         tracing::trace!("Reading and deleting messages from storage provider");
         let mut connection = self.db_pool.acquire().await.map_err(|e| {
             tracing::error!("Error acquiring connection: {:?}", e);
@@ -250,10 +385,13 @@ impl AuthService {
             AsDequeueError::StorageError
         })?;
 
-        let response = DequeueMessagesResponse {
-            messages,
+        tracing::debug!(
+            batch_size = messages.len(),
             remaining_messages_number,
-        };
+            "Dequeued AS messages"
+        );
+        // Key packages are managed by the QS.
+        let response = DequeueMessagesResponse::new(messages, remaining_messages_number, false);
 
         Ok(response)
     }
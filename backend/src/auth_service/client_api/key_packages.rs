@@ -4,9 +4,12 @@
 
 use phnxtypes::{
     errors::auth_service::{ClientKeyPackageError, PublishConnectionPackageError},
-    messages::client_as::{
-        AsClientConnectionPackageResponse, AsPublishConnectionPackagesParamsTbs,
-        ClientConnectionPackageParamsTbs, ConnectionPackage,
+    messages::{
+        client_as::{
+            AsClientConnectionPackageResponse, AsPublishConnectionPackagesParamsTbs,
+            ClientConnectionPackageParamsTbs, ConnectionPackage,
+        },
+        server_info::ClientVersion,
     },
 };
 
@@ -19,10 +22,12 @@ impl AuthService {
     pub(crate) async fn as_publish_connection_packages(
         &self,
         params: AsPublishConnectionPackagesParamsTbs,
+        client_version: Option<&ClientVersion>,
     ) -> Result<(), PublishConnectionPackageError> {
         let AsPublishConnectionPackagesParamsTbs {
             client_id,
             connection_packages,
+            freshness: _,
         } = params;
 
         let as_intermediate_credentials = IntermediateCredential::load_all(&self.db_pool)
@@ -40,8 +45,13 @@ impl AuthService {
                     .iter()
                     .find(|aic| aic.fingerprint() == cp.client_credential_signer_fingerprint())
                     .ok_or(PublishConnectionPackageError::InvalidKeyPackage)?;
-                cp.verify(verifying_credential.verifying_key())
-                    .map_err(|_| PublishConnectionPackageError::InvalidKeyPackage)
+                let connection_package = cp
+                    .verify(verifying_credential.verifying_key())
+                    .map_err(|_| PublishConnectionPackageError::InvalidKeyPackage)?;
+                if !self.connection_packages.check_lifetime(&connection_package) {
+                    return Err(PublishConnectionPackageError::InvalidLifetime);
+                }
+                Ok(connection_package)
             })
             .collect::<Result<Vec<ConnectionPackage>, PublishConnectionPackageError>>()?;
 
@@ -52,6 +62,12 @@ impl AuthService {
         )
         .await
         .map_err(|_| PublishConnectionPackageError::StorageError)?;
+        self.connection_packages_published(&client_id, client_version)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error clearing connection package refresh: {:?}", e);
+                PublishConnectionPackageError::StorageError
+            })?;
         Ok(())
     }
 
@@ -59,22 +75,29 @@ impl AuthService {
         &self,
         params: ClientConnectionPackageParamsTbs,
     ) -> Result<AsClientConnectionPackageResponse, ClientKeyPackageError> {
-        let client_id = params.0;
+        let client_id = params.client_id;
 
         let mut connection = self.db_pool.acquire().await.map_err(|e| {
             tracing::error!("Can't acquire a connection: {:?}", e);
             ClientKeyPackageError::StorageError
         })?;
-        let connection_package =
+        let served =
             StorableConnectionPackage::client_connection_package(&mut connection, &client_id)
                 .await
                 .map_err(|e| {
                     tracing::error!("Storage provider error: {:?}", e);
                     ClientKeyPackageError::StorageError
                 })?;
+        drop(connection);
+
+        let Some(served) = served else {
+            self.connection_packages_exhausted(&client_id).await;
+            return Err(ClientKeyPackageError::NoValidConnectionPackages);
+        };
+        self.connection_package_served(&served).await;
 
         let response = AsClientConnectionPackageResponse {
-            connection_package: Some(connection_package),
+            connection_package: Some(served.connection_package),
         };
         Ok(response)
     }
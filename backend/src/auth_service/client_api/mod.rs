@@ -4,7 +4,10 @@
 
 use opaque_ke::{rand::rngs::OsRng, ServerLogin, ServerLoginStartParameters};
 use phnxtypes::{
-    crypto::{opaque::OpaqueLoginResponse, OpaqueCiphersuite},
+    crypto::{
+        opaque::{OpaqueIdentifiers, OpaqueLoginResponse},
+        OpaqueCiphersuite,
+    },
     errors::auth_service::Init2FactorAuthError,
     messages::client_as::{Init2FactorAuthParamsTbs, Init2FactorAuthResponse},
 };
@@ -27,6 +30,7 @@ impl AuthService {
         let Init2FactorAuthParamsTbs {
             client_id,
             opaque_ke1,
+            freshness: _,
         } = params;
 
         // Load the server setup from storage
@@ -45,6 +49,10 @@ impl AuthService {
             })?
             .map(|record| record.into_password_file());
 
+        // The identifiers have to match the ones the client used when
+        // registering.
+        let identifiers =
+            OpaqueIdentifiers::new(user_name).map_err(|_| Init2FactorAuthError::LibraryError)?;
         let server_login_result = ServerLogin::<OpaqueCiphersuite>::start(
             &mut OsRng,
             &server_setup,
@@ -53,9 +61,12 @@ impl AuthService {
             &user_name
                 .tls_serialize_detached()
                 .map_err(|_| Init2FactorAuthError::LibraryError)?,
-            // TODO: We probably want to specify a context, as well as a server
-            // and client name here. For now, the default should be okay.
-            ServerLoginStartParameters::default(),
+            // TODO: We probably want to specify a context here. For now, the
+            // default should be okay.
+            ServerLoginStartParameters {
+                context: None,
+                identifiers: identifiers.identifiers(),
+            },
         )
         .map_err(|e| {
             tracing::error!("Opaque startup failed with error {e:?}");
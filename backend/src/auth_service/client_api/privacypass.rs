@@ -25,6 +25,7 @@ impl AuthService {
             // different challenges for different endpoints.
             token_type: _,
             token_request,
+            freshness: _,
         } = params;
         let tokens_requested = token_request.nr() as i32;
 
@@ -8,26 +8,36 @@ use phnxtypes::{
     crypto::{signatures::signable::Signable, OpaqueCiphersuite},
     errors::auth_service::{
         DeleteUserError, FinishUserRegistrationError, InitUserRegistrationError,
+        SenderReputationError,
     },
     messages::{
         client_as::{
             DeleteUserParamsTbs, InitUserRegistrationParams, InitUserRegistrationResponse,
+            SenderReputation, SenderReputationAttestationTbs, SenderReputationParamsTbs,
+            SenderReputationResponse,
         },
         client_as_out::FinishUserRegistrationParamsTbsIn,
     },
-    time::TimeStamp,
+    time::{now, Duration, TimeStamp},
 };
+use sqlx::types::chrono::{DateTime, Utc};
 use tls_codec::Serialize;
 
 use crate::auth_service::{
     client_record::ClientRecord,
     connection_package::StorableConnectionPackage,
     credentials::intermediate_signing_key::{IntermediateCredential, IntermediateSigningKey},
+    erasure::ErasureRecord,
+    handle_policy::ReleasedHandle,
     opaque::OpaqueSetup,
     user_record::UserRecord,
     AuthService,
 };
 
+/// Users registered within this many days are reported as
+/// [`SenderReputation::New`].
+const NEW_USER_PERIOD_DAYS: i64 = 7;
+
 impl AuthService {
     pub(crate) async fn as_init_user_registration(
         &self,
@@ -38,6 +48,16 @@ impl AuthService {
             opaque_registration_request,
         } = params;
 
+        if !self.server_info.registration_open() {
+            return Err(InitUserRegistrationError::RegistrationClosed);
+        }
+
+        // User names are unique across all domains served by the server, so
+        // each AS may only register users of its own domain.
+        if client_payload.identity().user_name().domain() != self.domain {
+            return Err(InitUserRegistrationError::ForeignDomain);
+        }
+
         // Check if a user entry with the name given in the client_csr already exists
         tracing::info!("Checking if user already exists");
         let user_name_exists =
@@ -53,6 +73,23 @@ impl AuthService {
             return Err(InitUserRegistrationError::UserAlreadyExists);
         }
 
+        // Check the user name against the handle policy
+        let user_name = client_payload.identity().user_name();
+        self.handle_policy
+            .check(&user_name.user_name().to_string())
+            .map_err(InitUserRegistrationError::HandleRejected)?;
+        let released_at = ReleasedHandle::load_released_at(&self.db_pool, &user_name)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading released handle: {:?}", e);
+                InitUserRegistrationError::StorageError
+            })?;
+        if let Some(released_at) = released_at {
+            self.handle_policy
+                .check_released(released_at)
+                .map_err(InitUserRegistrationError::HandleRejected)?;
+        }
+
         // Validate the client_csr
         if !client_payload.validate() {
             let now = TimeStamp::now();
@@ -74,7 +111,7 @@ impl AuthService {
 
         // Sign the credential
         let client_credential: ClientCredential = client_payload
-            .sign(&signing_key)
+            .sign_with_intermediate(&signing_key)
             .map_err(|_| InitUserRegistrationError::LibraryError)?;
 
         // Store the client_credential in the ephemeral DB
@@ -124,6 +161,7 @@ impl AuthService {
             initial_ratchet_secret: initial_ratchet_key,
             connection_packages,
             opaque_registration_record,
+            freshness: _,
         } = params;
 
         // Look up the initial client's ClientCredential in the ephemeral DB based on the user_name
@@ -160,8 +198,13 @@ impl AuthService {
                     .iter()
                     .find(|aic| aic.fingerprint() == cp.client_credential_signer_fingerprint())
                     .ok_or(FinishUserRegistrationError::InvalidConnectionPackage)?;
-                cp.verify(verifying_credential.verifying_key())
-                    .map_err(|_| FinishUserRegistrationError::InvalidConnectionPackage)
+                let connection_package = cp
+                    .verify(verifying_credential.verifying_key())
+                    .map_err(|_| FinishUserRegistrationError::InvalidConnectionPackage)?;
+                if !self.connection_packages.check_lifetime(&connection_package) {
+                    return Err(FinishUserRegistrationError::InvalidConnectionPackage);
+                }
+                Ok(connection_package)
             })
             .collect::<Result<Vec<_>, FinishUserRegistrationError>>()?;
 
@@ -211,16 +254,109 @@ impl AuthService {
             user_name,
             client_id: _,
             opaque_finish: _,
+            freshness: _,
         } = params;
 
-        // Delete the user
-        UserRecord::delete(&self.db_pool, &user_name)
+        // The password was checked when verifying the request. Delete the
+        // user, start the cooldown of its user name and record the erasure
+        // for the operator
+        let mut transaction = self.db_pool.begin().await.map_err(|e| {
+            tracing::error!("Error starting transaction: {:?}", e);
+            DeleteUserError::StorageError
+        })?;
+        let erasure_record = ErasureRecord::prepare(&mut *transaction, user_name.clone())
             .await
             .map_err(|e| {
                 tracing::error!("Storage provider error: {:?}", e);
                 DeleteUserError::StorageError
             })?;
+        UserRecord::delete(&mut *transaction, &user_name)
+            .await
+            .map_err(|e| {
+                tracing::error!("Storage provider error: {:?}", e);
+                DeleteUserError::StorageError
+            })?;
+        ReleasedHandle::store(&mut *transaction, &user_name)
+            .await
+            .map_err(|e| {
+                tracing::error!("Storage provider error: {:?}", e);
+                DeleteUserError::StorageError
+            })?;
+        erasure_record.store(&mut *transaction).await.map_err(|e| {
+            tracing::error!("Storage provider error: {:?}", e);
+            DeleteUserError::StorageError
+        })?;
+        transaction.commit().await.map_err(|e| {
+            tracing::error!("Error committing transaction: {:?}", e);
+            DeleteUserError::StorageError
+        })?;
 
         Ok(())
     }
+    /// Attest the reputation of the user of the requesting client. Users
+    /// attach the attestation to their connection requests, so the AS never
+    /// reveals anything about a user to others.
+    pub(crate) async fn as_sender_reputation(
+        &self,
+        params: SenderReputationParamsTbs,
+    ) -> Result<SenderReputationResponse, SenderReputationError> {
+        let user_name = params.client_id.user_name();
+        let created_at = UserRecord::load_created_at(&self.db_pool, &user_name)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load user record: {:?}", e);
+                SenderReputationError::StorageError
+            })?
+            .ok_or(SenderReputationError::UnknownUser)?;
+
+        let signing_key = IntermediateSigningKey::load(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading signing key: {:?}", e);
+                SenderReputationError::StorageError
+            })?
+            .ok_or(SenderReputationError::SigningKeyNotFound)?;
+
+        let attestation = SenderReputationAttestationTbs {
+            user_name,
+            reputation: sender_reputation(Some(created_at), now()),
+            issued_at: TimeStamp::now(),
+            signer_fingerprint: signing_key.credential().fingerprint().clone(),
+        }
+        .sign(&signing_key)
+        .map_err(|_| SenderReputationError::LibraryError)?;
+        Ok(SenderReputationResponse { attestation })
+    }
+}
+
+pub(in crate::auth_service) fn sender_reputation(
+    created_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> SenderReputation {
+    match created_at {
+        None => SenderReputation::Unknown,
+        Some(created_at) if now - created_at < Duration::days(NEW_USER_PERIOD_DAYS) => {
+            SenderReputation::New
+        }
+        Some(_) => SenderReputation::Established,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sender_reputation_from_registration_time() {
+        let now = now();
+        assert_eq!(sender_reputation(None, now), SenderReputation::Unknown);
+        assert_eq!(
+            sender_reputation(Some(now - Duration::days(1)), now),
+            SenderReputation::New
+        );
+        assert_eq!(
+            sender_reputation(Some(now - Duration::days(30)), now),
+            SenderReputation::Established
+        );
+    }
 }
@@ -0,0 +1,396 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Lifecycle of connection packages.
+//!
+//! Connection packages are only accepted if they expire within the configured
+//! maximum lifetime and are never served once they expired. A client whose
+//! valid packages run out is asked via its AS queue to publish fresh ones,
+//! either when its last package is served or by the periodic expiry sweep,
+//! which also deletes expired packages and reservations. A client is asked
+//! again only if it doesn't publish new packages within a day. Only clients
+//! that announced a version which can decode refresh requests are asked.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use phnxtypes::{
+    identifiers::{AsClientId, ConnectionReservationId},
+    messages::{
+        client_as::{
+            AsQueueMessagePayload, AsQueueMessageType, ConnectionPackage, ConnectionReservation,
+        },
+        server_info::ClientVersion,
+    },
+    time::{now, Duration, ExpirationData},
+};
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::task::JoinHandle;
+
+use crate::{auth_service::AuthService, errors::StorageError, settings::ConnectionPackageSettings};
+
+use super::{
+    AnnouncedClientVersion, RefreshRequest, ServedConnectionPackage, StorableConnectionPackage,
+    StoredReservation,
+};
+
+/// Clients that didn't publish fresh connection packages this long after they
+/// were asked to are asked again.
+const REFRESH_REQUEST_TIMEOUT: Duration = Duration::days(1);
+
+#[derive(Debug, Default)]
+struct ConnectionPackageMetrics {
+    rejected_packages: AtomicU64,
+    exhausted_fetches: AtomicU64,
+    swept_packages: AtomicU64,
+    backfilled_packages: AtomicU64,
+    refresh_requests: AtomicU64,
+}
+
+/// Snapshot of the connection package metrics since the start of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionPackageMetricsSnapshot {
+    /// Published packages rejected because of their lifetime
+    pub rejected_packages: u64,
+    /// Fetches of clients without valid packages
+    pub exhausted_fetches: u64,
+    /// Expired packages deleted by the sweep
+    pub swept_packages: u64,
+    /// Packages whose expiration was filled in by the sweep
+    pub backfilled_packages: u64,
+    /// Clients asked to publish fresh packages
+    pub refresh_requests: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(in crate::auth_service) struct ConnectionPackageLifecycle {
+    settings: ConnectionPackageSettings,
+    metrics: Arc<ConnectionPackageMetrics>,
+}
+
+impl ConnectionPackageLifecycle {
+    /// Whether the lifetime of the given connection package complies with the
+    /// policy, i.e. it is valid now and expires within the maximum lifetime.
+    pub(in crate::auth_service) fn check_lifetime(
+        &self,
+        connection_package: &ConnectionPackage,
+    ) -> bool {
+        let compliant = self.lifetime_is_compliant(connection_package.lifetime(), now());
+        if !compliant {
+            self.metrics
+                .rejected_packages
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        compliant
+    }
+
+    /// Both bounds of the lifetime tolerate the configured clock skew, since
+    /// clients derive them from their own clocks.
+    fn lifetime_is_compliant(&self, lifetime: &ExpirationData, now: DateTime<Utc>) -> bool {
+        let max_lifetime = Duration::seconds(self.settings.max_lifetime_secs as i64);
+        let skew = Duration::seconds(self.settings.clock_skew_secs as i64);
+        *lifetime.not_before() <= now + skew
+            && now < *lifetime.not_after()
+            && *lifetime.not_after() <= now + max_lifetime + skew
+    }
+
+    fn metrics(&self) -> ConnectionPackageMetricsSnapshot {
+        let metrics = &self.metrics;
+        ConnectionPackageMetricsSnapshot {
+            rejected_packages: metrics.rejected_packages.load(Ordering::Relaxed),
+            exhausted_fetches: metrics.exhausted_fetches.load(Ordering::Relaxed),
+            swept_packages: metrics.swept_packages.load(Ordering::Relaxed),
+            backfilled_packages: metrics.backfilled_packages.load(Ordering::Relaxed),
+            refresh_requests: metrics.refresh_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl AuthService {
+    /// Replace the default connection package policy.
+    pub fn with_connection_package_settings(mut self, settings: ConnectionPackageSettings) -> Self {
+        self.connection_packages.settings = settings;
+        self
+    }
+
+    pub fn connection_package_metrics(&self) -> ConnectionPackageMetricsSnapshot {
+        self.connection_packages.metrics()
+    }
+
+    /// Periodically delete expired connection packages and ask clients that
+    /// run out of packages to publish fresh ones.
+    pub fn spawn_connection_package_sweep(&self) -> JoinHandle<()> {
+        let auth_service = self.clone();
+        let interval = std::time::Duration::from_secs(
+            auth_service
+                .connection_packages
+                .settings
+                .sweep_interval_secs,
+        );
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = auth_service.sweep_connection_packages().await {
+                    tracing::error!("Error sweeping connection packages: {:?}", e);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    async fn sweep_connection_packages(&self) -> Result<(), StorageError> {
+        let metrics = &self.connection_packages.metrics;
+        let mut connection = self.db_pool.acquire().await?;
+
+        let backfilled = StorableConnectionPackage::backfill_expiration(&mut connection).await?;
+        metrics
+            .backfilled_packages
+            .fetch_add(backfilled, Ordering::Relaxed);
+
+        let deleted = StorableConnectionPackage::delete_expired(&mut *connection, now()).await?;
+        metrics.swept_packages.fetch_add(deleted, Ordering::Relaxed);
+        if deleted > 0 {
+            tracing::info!(deleted, "Deleted expired connection packages");
+        }
+
+        StoredReservation::delete_expired(&mut *connection, now()).await?;
+
+        let clients = StorableConnectionPackage::clients_running_out(
+            &mut *connection,
+            now(),
+            now() - REFRESH_REQUEST_TIMEOUT,
+        )
+        .await?;
+        drop(connection);
+        for client_id in clients {
+            self.request_connection_package_refresh(&client_id).await;
+        }
+        Ok(())
+    }
+
+    /// Reserve the connection packages served to the given clients, such that
+    /// connection establishment packages can be enqueued for them until the
+    /// reservation expires.
+    pub(in crate::auth_service) async fn reserve_connection_packages(
+        &self,
+        client_ids: &[AsClientId],
+    ) -> Result<ConnectionReservation, StorageError> {
+        let ttl = Duration::seconds(self.connection_packages.settings.reservation_ttl_secs as i64);
+        let reservation = ConnectionReservation {
+            id: ConnectionReservationId::random(),
+            expires_at: (now() + ttl).into(),
+        };
+        let mut connection = self.db_pool.acquire().await?;
+        StoredReservation::store(&mut connection, &reservation, client_ids).await?;
+        Ok(reservation)
+    }
+
+    /// Ask the client to publish fresh connection packages if the served
+    /// package was its last valid one.
+    pub(in crate::auth_service) async fn connection_package_served(
+        &self,
+        served: &ServedConnectionPackage,
+    ) {
+        if served.remaining <= 1 {
+            self.request_connection_package_refresh(&served.client_id)
+                .await;
+        }
+    }
+
+    /// Record a fetch of a client without valid connection packages and ask
+    /// it to publish fresh ones.
+    pub(in crate::auth_service) async fn connection_packages_exhausted(
+        &self,
+        client_id: &AsClientId,
+    ) {
+        self.connection_packages
+            .metrics
+            .exhausted_fetches
+            .fetch_add(1, Ordering::Relaxed);
+        self.request_connection_package_refresh(client_id).await;
+    }
+
+    /// Send a refresh request to the client's queue unless it was already
+    /// asked or is too old to decode it. Failures are only logged, since the
+    /// request is repeated by the next sweep.
+    async fn request_connection_package_refresh(&self, client_id: &AsClientId) {
+        match AnnouncedClientVersion::load(&self.db_pool, client_id).await {
+            Ok(client_version) => {
+                let message_type = AsQueueMessageType::ConnectionPackageRefreshRequest;
+                if !message_type.supported_by(client_version.as_ref()) {
+                    return;
+                }
+            }
+            Err(e) => {
+                tracing::error!("Error loading client version: {:?}", e);
+                return;
+            }
+        }
+        match RefreshRequest::record(&self.db_pool, client_id, now() - REFRESH_REQUEST_TIMEOUT)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                tracing::error!("Error recording connection package refresh: {:?}", e);
+                return;
+            }
+        }
+        let payload = AsQueueMessagePayload::connection_package_refresh_request();
+        if let Err(e) = self.enqueue_as_queue_message(client_id, payload).await {
+            tracing::error!("Error requesting fresh connection packages: {:?}", e);
+            // Allow the next sweep to try again
+            if let Err(e) = RefreshRequest::clear(&self.db_pool, client_id).await {
+                tracing::error!("Error clearing connection package refresh: {:?}", e);
+            }
+            return;
+        }
+        self.connection_packages
+            .metrics
+            .refresh_requests
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forget a pending refresh request once the client published fresh
+    /// connection packages and remember the version the client announced.
+    pub(in crate::auth_service) async fn connection_packages_published(
+        &self,
+        client_id: &AsClientId,
+        client_version: Option<&ClientVersion>,
+    ) -> Result<(), StorageError> {
+        RefreshRequest::clear(&self.db_pool, client_id).await?;
+        AnnouncedClientVersion::store(&self.db_pool, client_id, client_version).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        identifiers::{QualifiedUserName, SafeTryInto},
+        time::{set_thread_time_provider, TimeProvider},
+    };
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FixedClock(DateTime<Utc>);
+
+    impl TimeProvider for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    /// Lifetime of a package published by a client whose clock is off by
+    /// `offset`.
+    fn client_lifetime(lifetime: Duration, offset: Duration) -> ExpirationData {
+        set_thread_time_provider(Some(Arc::new(FixedClock(Utc::now() + offset))));
+        let expiration_data = ExpirationData::new(lifetime);
+        set_thread_time_provider(None);
+        expiration_data
+    }
+
+    #[test]
+    fn lifetime_tolerates_clock_skew() {
+        let lifecycle = ConnectionPackageLifecycle::default();
+        let max_lifetime = Duration::seconds(lifecycle.settings.max_lifetime_secs as i64);
+
+        // Lifetimes start 15 minutes in the past, so a clock that is 20
+        // minutes ahead exceeds the maximum lifetime by 5 minutes.
+        let ahead = client_lifetime(max_lifetime, Duration::minutes(20));
+        assert!(lifecycle.lifetime_is_compliant(&ahead, Utc::now()));
+        let far_ahead = client_lifetime(max_lifetime, Duration::minutes(30));
+        assert!(!lifecycle.lifetime_is_compliant(&far_ahead, Utc::now()));
+
+        let not_yet_valid = client_lifetime(Duration::days(1), Duration::minutes(30));
+        assert!(!lifecycle.lifetime_is_compliant(&not_yet_valid, Utc::now()));
+        let expired = client_lifetime(Duration::days(1), Duration::days(-2));
+        assert!(!lifecycle.lifetime_is_compliant(&expired, Utc::now()));
+    }
+
+    #[test]
+    fn refresh_requests_need_a_recent_client() {
+        let message_type = AsQueueMessageType::ConnectionPackageRefreshRequest;
+        assert!(!message_type.supported_by(None));
+        assert!(!message_type.supported_by(Some(&ClientVersion::new(0, 1, 0))));
+        assert!(message_type.supported_by(Some(&ClientVersion::new(0, 2, 0))));
+        let message_type = AsQueueMessageType::EncryptedConnectionEstablishmentPackage;
+        assert!(message_type.supported_by(None));
+    }
+
+    #[sqlx::test]
+    async fn refresh_requests_are_repeated_after_timeout(pool: PgPool) {
+        let user_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let client_id = AsClientId::new(user_name.clone(), Uuid::new_v4());
+        sqlx::query("INSERT INTO as_user_records (user_name, password_file) VALUES ($1, $2)")
+            .bind(user_name.to_string())
+            .bind(vec![0u8])
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO as_client_records (client_id, user_name, queue_encryption_key, ratchet,
+                activity_time, credential, remaining_tokens)
+            VALUES ($1, $2, $3, $3, now(),
+                ROW(''::bytea, NULL, NULL, NULL, NULL, NULL, NULL)::client_credential, 0)",
+        )
+        .bind(client_id.client_id())
+        .bind(user_name.to_string())
+        .bind(vec![0u8])
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The client version is announced with the connection packages
+        assert_eq!(
+            AnnouncedClientVersion::load(&pool, &client_id)
+                .await
+                .unwrap(),
+            None
+        );
+        let client_version = ClientVersion::new(0, 2, 0);
+        AnnouncedClientVersion::store(&pool, &client_id, Some(&client_version))
+            .await
+            .unwrap();
+        assert_eq!(
+            AnnouncedClientVersion::load(&pool, &client_id)
+                .await
+                .unwrap(),
+            Some(client_version)
+        );
+
+        // Without valid packages, the client is running out until it is asked
+        let timeout = now() - REFRESH_REQUEST_TIMEOUT;
+        let running_out = StorableConnectionPackage::clients_running_out(&pool, now(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(running_out, vec![client_id.clone()]);
+        assert!(RefreshRequest::record(&pool, &client_id, timeout)
+            .await
+            .unwrap());
+        assert!(!RefreshRequest::record(&pool, &client_id, timeout)
+            .await
+            .unwrap());
+        assert!(
+            StorableConnectionPackage::clients_running_out(&pool, now(), timeout)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // Clients that didn't react are asked again after the timeout
+        let later = now() + REFRESH_REQUEST_TIMEOUT;
+        let timeout = later - REFRESH_REQUEST_TIMEOUT;
+        let running_out = StorableConnectionPackage::clients_running_out(&pool, later, timeout)
+            .await
+            .unwrap();
+        assert_eq!(running_out, vec![client_id.clone()]);
+        assert!(RefreshRequest::record(&pool, &client_id, timeout)
+            .await
+            .unwrap());
+    }
+}
@@ -2,16 +2,35 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use phnxtypes::messages::client_as::ConnectionPackage;
+use phnxtypes::{
+    identifiers::AsClientId, messages::client_as::ConnectionPackage, time::ExpirationData,
+};
 use serde::{Deserialize, Serialize};
 
+pub(super) use lifecycle::ConnectionPackageLifecycle;
+pub use lifecycle::ConnectionPackageMetricsSnapshot;
+pub(super) use persistence::{AnnouncedClientVersion, RefreshRequest};
+pub(super) use reservation::{ReservationClaim, StoredReservation};
+
+mod lifecycle;
 mod persistence;
+mod reservation;
 
 #[derive(Serialize, Deserialize)]
 pub(in crate::auth_service) enum StorableConnectionPackage {
     CurrentVersion(ConnectionPackage),
 }
 
+impl StorableConnectionPackage {
+    fn lifetime(&self) -> &ExpirationData {
+        match self {
+            StorableConnectionPackage::CurrentVersion(connection_package) => {
+                connection_package.lifetime()
+            }
+        }
+    }
+}
+
 impl From<StorableConnectionPackage> for ConnectionPackage {
     fn from(connection_package: StorableConnectionPackage) -> Self {
         match connection_package {
@@ -25,3 +44,12 @@ impl From<ConnectionPackage> for StorableConnectionPackage {
         StorableConnectionPackage::CurrentVersion(connection_package)
     }
 }
+
+/// A valid connection package served to a contact of its client.
+pub(in crate::auth_service) struct ServedConnectionPackage {
+    pub(in crate::auth_service) client_id: AsClientId,
+    pub(in crate::auth_service) connection_package: ConnectionPackage,
+    /// Number of valid packages of the client before this one was served.
+    /// The last one is served repeatedly as a last resort.
+    pub(in crate::auth_service) remaining: u64,
+}
@@ -4,15 +4,19 @@
 
 use phnxtypes::{
     codec::PhnxCodec,
-    identifiers::{AsClientId, QualifiedUserName},
-    messages::client_as::ConnectionPackage,
+    identifiers::{AsClientId, QualifiedUserName, SafeTryInto},
+    messages::{client_as::ConnectionPackage, server_info::ClientVersion},
+};
+use sqlx::{
+    postgres::PgArguments,
+    types::chrono::{DateTime, Utc},
+    Arguments, Connection, PgConnection, PgExecutor,
 };
-use sqlx::{postgres::PgArguments, Arguments, Connection, PgConnection, PgExecutor};
 use uuid::Uuid;
 
 use crate::errors::StorageError;
 
-use super::StorableConnectionPackage;
+use super::{ServedConnectionPackage, StorableConnectionPackage};
 
 impl StorableConnectionPackage {
     pub(in crate::auth_service) async fn store_multiple(
@@ -21,23 +25,31 @@ impl StorableConnectionPackage {
         client_id: &AsClientId,
     ) -> Result<(), StorageError> {
         let mut query_args = PgArguments::default();
-        let mut query_string =
-            String::from("INSERT INTO connection_packages (client_id, connection_package) VALUES");
+        let mut query_string = String::from(
+            "INSERT INTO connection_packages (client_id, connection_package, expires_at) VALUES",
+        );
 
         for (i, connection_package) in connection_packages.into_iter().enumerate() {
             let connection_package: StorableConnectionPackage = connection_package.into();
+            let expires_at = DateTime::<Utc>::from(connection_package.lifetime().not_after());
             let connection_package_bytes = PhnxCodec::to_vec(&connection_package)?;
 
             // Add values to the query arguments. None of these should throw an error.
             query_args.add(client_id.client_id())?;
             query_args.add(connection_package_bytes)?;
+            query_args.add(expires_at)?;
 
             if i > 0 {
                 query_string.push(',');
             }
 
             // Add placeholders for each value
-            query_string.push_str(&format!(" (${}, ${})", i * 2 + 1, i * 2 + 2,));
+            query_string.push_str(&format!(
+                " (${}, ${}, ${})",
+                i * 3 + 1,
+                i * 3 + 2,
+                i * 3 + 3
+            ));
         }
 
         // Finalize the query string
@@ -51,65 +63,79 @@ impl StorableConnectionPackage {
         Ok(())
     }
 
-    async fn load(connection: &mut PgConnection, client_id: Uuid) -> Result<Vec<u8>, StorageError> {
+    /// Load the next valid connection package of the client. The package is
+    /// deleted unless it is the last valid one, which is kept as a last
+    /// resort.
+    async fn load(
+        connection: &mut PgConnection,
+        client_id: Uuid,
+    ) -> Result<Option<LoadedConnectionPackage>, StorageError> {
         let mut transaction = connection.begin().await?;
 
         // This is to ensure that counting and deletion happen atomically. If we
         // don't do this, two concurrent queries might both count 2 and delete,
         // leaving us with 0 packages.
-        let connection_package_bytes_record = sqlx::query!(
-            "WITH next_connection_package AS (
-                SELECT id, connection_package 
-                FROM connection_packages 
-                WHERE client_id = $1 
-                LIMIT 1 
+        let record = sqlx::query!(
+            r#"WITH next_connection_package AS (
+                SELECT id, connection_package
+                FROM connection_packages
+                WHERE client_id = $1 AND (expires_at IS NULL OR expires_at > $2)
+                ORDER BY expires_at ASC NULLS FIRST -- serve packages before they expire
+                LIMIT 1
                 FOR UPDATE -- make sure two concurrent queries don't return the same package
                 SKIP LOCKED -- skip rows that are already locked by other processes
-            ), 
+            ),
             remaining_packages AS (
-                SELECT COUNT(*) as count 
-                FROM connection_packages 
-                WHERE client_id = $1
+                SELECT COUNT(*) as count
+                FROM connection_packages
+                WHERE client_id = $1 AND (expires_at IS NULL OR expires_at > $2)
             ),
             deleted_package AS (
-                DELETE FROM connection_packages 
+                DELETE FROM connection_packages
                 WHERE id = (
-                    SELECT id 
+                    SELECT id
                     FROM next_connection_package
-                ) 
+                )
                 AND (SELECT count FROM remaining_packages) > 1
                 RETURNING connection_package
             )
-            SELECT connection_package FROM next_connection_package",
+            SELECT connection_package,
+                (SELECT count FROM remaining_packages) AS "remaining!"
+            FROM next_connection_package"#,
             client_id,
+            phnxtypes::time::now(),
         )
-        .fetch_one(&mut *transaction)
+        .fetch_optional(&mut *transaction)
         .await?;
 
         transaction.commit().await?;
 
-        Ok(connection_package_bytes_record.connection_package)
+        Ok(record.map(|record| LoadedConnectionPackage {
+            connection_package_bytes: record.connection_package,
+            remaining: record.remaining as u64,
+        }))
     }
 
-    /// TODO: Last resort key package
+    /// Load the next valid connection package of the client. Returns `None`
+    /// if the client has no valid packages left.
     pub(in crate::auth_service) async fn client_connection_package(
         connection: &mut PgConnection,
         client_id: &AsClientId,
-    ) -> Result<ConnectionPackage, StorageError> {
-        let connection_package_bytes = Self::load(connection, client_id.client_id()).await?;
-
-        let connection_package: StorableConnectionPackage =
-            PhnxCodec::from_slice(&connection_package_bytes)?;
-
-        Ok(connection_package.into())
+    ) -> Result<Option<ServedConnectionPackage>, StorageError> {
+        Self::load(connection, client_id.client_id())
+            .await?
+            .map(|loaded| loaded.decode(client_id.clone()))
+            .transpose()
+            .map(Option::flatten)
     }
 
-    /// Return a connection package for each client of a user referenced by a
-    /// user name.
+    /// Return the next valid connection package of each client of a user
+    /// referenced by a user name. Clients without valid connection packages
+    /// are returned without a package.
     pub(in crate::auth_service) async fn user_connection_packages(
         connection: &mut PgConnection,
         user_name: &QualifiedUserName,
-    ) -> Result<Vec<ConnectionPackage>, StorageError> {
+    ) -> Result<Vec<(AsClientId, Option<ServedConnectionPackage>)>, StorageError> {
         // Start the transaction
         let mut transaction = connection.begin().await?;
 
@@ -126,26 +152,212 @@ impl StorableConnectionPackage {
         .await?;
 
         // First fetch all connection package records from the DB.
-        let mut connection_packages_bytes = Vec::new();
+        let mut loaded_packages = Vec::new();
         for client_id in client_ids_record {
-            let connection_package_bytes =
-                Self::load(&mut transaction, client_id.client_id).await?;
-            connection_packages_bytes.push(connection_package_bytes);
+            let loaded = Self::load(&mut transaction, client_id.client_id).await?;
+            let client_id = AsClientId::new(user_name.clone(), client_id.client_id);
+            loaded_packages.push((client_id, loaded));
         }
 
         // End the transaction.
         transaction.commit().await?;
 
         // Deserialize the connection packages.
-        let connection_packages = connection_packages_bytes
+        loaded_packages
+            .into_iter()
+            .map(|(client_id, loaded)| {
+                let served = match loaded {
+                    Some(loaded) => loaded.decode(client_id.clone())?,
+                    None => None,
+                };
+                Ok((client_id, served))
+            })
+            .collect()
+    }
+
+    /// Delete all connection packages that expired before `now`.
+    pub(in crate::auth_service) async fn delete_expired(
+        connection: impl PgExecutor<'_>,
+        now: DateTime<Utc>,
+    ) -> Result<u64, StorageError> {
+        let result = sqlx::query!(
+            "DELETE FROM connection_packages WHERE expires_at <= $1",
+            now,
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Fill in the expiration of connection packages that were published
+    /// before it was stored alongside them. Returns the number of updated
+    /// packages.
+    pub(in crate::auth_service) async fn backfill_expiration(
+        connection: &mut PgConnection,
+    ) -> Result<u64, StorageError> {
+        let records = sqlx::query!(
+            "SELECT id, connection_package FROM connection_packages
+            WHERE expires_at IS NULL
+            LIMIT 1000",
+        )
+        .fetch_all(&mut *connection)
+        .await?;
+
+        let mut updated = 0;
+        for record in records {
+            let connection_package: StorableConnectionPackage =
+                PhnxCodec::from_slice(&record.connection_package)?;
+            let expires_at = DateTime::<Utc>::from(connection_package.lifetime().not_after());
+            sqlx::query!(
+                "UPDATE connection_packages SET expires_at = $2 WHERE id = $1",
+                record.id,
+                expires_at,
+            )
+            .execute(&mut *connection)
+            .await?;
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    /// Clients that have at most one valid connection package left and were
+    /// not asked to publish fresh ones after `asked_before`.
+    pub(in crate::auth_service) async fn clients_running_out(
+        connection: impl PgExecutor<'_>,
+        now: DateTime<Utc>,
+        asked_before: DateTime<Utc>,
+    ) -> Result<Vec<AsClientId>, StorageError> {
+        let records = sqlx::query!(
+            "SELECT c.client_id, c.user_name
+            FROM as_client_records c
+            WHERE NOT EXISTS (
+                SELECT 1 FROM as_connection_package_refresh_requests r
+                WHERE r.client_id = c.client_id AND r.requested_at > $2
+            )
+            AND (
+                SELECT COUNT(*) FROM connection_packages p
+                WHERE p.client_id = c.client_id
+                AND (p.expires_at IS NULL OR p.expires_at > $1)
+            ) <= 1",
+            now,
+            asked_before,
+        )
+        .fetch_all(connection)
+        .await?;
+        let client_ids = records
             .into_iter()
-            .map(|connection_package_bytes| {
-                let storable: StorableConnectionPackage =
-                    PhnxCodec::from_slice(&connection_package_bytes)?;
-                Ok(storable.into())
+            .filter_map(|record| {
+                match <String as SafeTryInto<QualifiedUserName>>::try_into(record.user_name) {
+                    Ok(user_name) => Some(AsClientId::new(user_name, record.client_id)),
+                    Err(e) => {
+                        tracing::warn!("Invalid user name in client record: {:?}", e);
+                        None
+                    }
+                }
             })
-            .collect::<Result<Vec<ConnectionPackage>, StorageError>>()?;
+            .collect();
+        Ok(client_ids)
+    }
+}
+
+/// Tracks which clients were asked to publish fresh connection packages.
+pub(in crate::auth_service) struct RefreshRequest;
+
+impl RefreshRequest {
+    /// Record that the client is asked to publish fresh connection packages.
+    /// Returns `false` if it was already asked after `asked_before`.
+    pub(in crate::auth_service) async fn record(
+        connection: impl PgExecutor<'_>,
+        client_id: &AsClientId,
+        asked_before: DateTime<Utc>,
+    ) -> Result<bool, StorageError> {
+        let result = sqlx::query!(
+            "INSERT INTO as_connection_package_refresh_requests (client_id, requested_at)
+            VALUES ($1, $2)
+            ON CONFLICT (client_id) DO UPDATE SET requested_at = EXCLUDED.requested_at
+            WHERE as_connection_package_refresh_requests.requested_at <= $3",
+            client_id.client_id(),
+            phnxtypes::time::now(),
+            asked_before,
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Forget the request once the client published fresh packages.
+    pub(in crate::auth_service) async fn clear(
+        connection: impl PgExecutor<'_>,
+        client_id: &AsClientId,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            "DELETE FROM as_connection_package_refresh_requests WHERE client_id = $1",
+            client_id.client_id(),
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Version a client announced when it last published connection packages.
+pub(in crate::auth_service) struct AnnouncedClientVersion;
+
+impl AnnouncedClientVersion {
+    pub(in crate::auth_service) async fn store(
+        connection: impl PgExecutor<'_>,
+        client_id: &AsClientId,
+        client_version: Option<&ClientVersion>,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            "UPDATE as_client_records SET client_version = $2 WHERE client_id = $1",
+            client_id.client_id(),
+            client_version.map(|version| version.to_string()),
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    pub(in crate::auth_service) async fn load(
+        connection: impl PgExecutor<'_>,
+        client_id: &AsClientId,
+    ) -> Result<Option<ClientVersion>, StorageError> {
+        let client_version = sqlx::query_scalar!(
+            "SELECT client_version FROM as_client_records WHERE client_id = $1",
+            client_id.client_id(),
+        )
+        .fetch_optional(connection)
+        .await?
+        .flatten()
+        .and_then(|version| version.parse().ok());
+        Ok(client_version)
+    }
+}
 
-        Ok(connection_packages)
+struct LoadedConnectionPackage {
+    connection_package_bytes: Vec<u8>,
+    /// Number of valid packages before this one was served
+    remaining: u64,
+}
+
+impl LoadedConnectionPackage {
+    /// Decode the package. Packages published before their expiration was
+    /// stored are checked here and treated as missing if they expired.
+    fn decode(
+        self,
+        client_id: AsClientId,
+    ) -> Result<Option<ServedConnectionPackage>, StorageError> {
+        let storable: StorableConnectionPackage =
+            PhnxCodec::from_slice(&self.connection_package_bytes)?;
+        let connection_package: ConnectionPackage = storable.into();
+        if !connection_package.lifetime().validate() {
+            return Ok(None);
+        }
+        Ok(Some(ServedConnectionPackage {
+            client_id,
+            connection_package,
+            remaining: self.remaining,
+        }))
     }
 }
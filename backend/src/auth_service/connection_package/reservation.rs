@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Reservations of served connection packages.
+//!
+//! Establishing a connection takes two requests: fetching the connection
+//! packages of a user and enqueueing a connection establishment package for
+//! each of its clients. The app might be suspended in between, so the AS
+//! holds a reservation for the served packages instead of expecting both in
+//! one go. Each client of a reservation can be enqueued for once until the
+//! reservation expires. Retrying an enqueue that already succeeded is a
+//! no-op.
+
+use phnxtypes::{
+    identifiers::{AsClientId, ConnectionReservationId},
+    messages::client_as::ConnectionReservation,
+};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection, PgExecutor,
+};
+
+use crate::errors::StorageError;
+
+/// Result of claiming a client of a reservation for an enqueue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(in crate::auth_service) enum ReservationClaim {
+    /// The connection establishment package can be enqueued.
+    Claimed,
+    /// A connection establishment package was already enqueued for the
+    /// client under this reservation.
+    AlreadyEnqueued,
+    /// The reservation is unknown, expired or doesn't include the client.
+    Expired,
+}
+
+pub(in crate::auth_service) struct StoredReservation;
+
+impl StoredReservation {
+    /// Store a reservation of the connection packages of the given clients.
+    pub(in crate::auth_service) async fn store(
+        connection: &mut PgConnection,
+        reservation: &ConnectionReservation,
+        client_ids: &[AsClientId],
+    ) -> Result<(), StorageError> {
+        for client_id in client_ids {
+            sqlx::query!(
+                "INSERT INTO as_connection_reservations (reservation_id, client_id, expires_at)
+                VALUES ($1, $2, $3)",
+                reservation.id.as_uuid(),
+                client_id.client_id(),
+                reservation.expires_at.as_ref(),
+            )
+            .execute(&mut *connection)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Claim the given client of the reservation for enqueueing a connection
+    /// establishment package.
+    pub(in crate::auth_service) async fn claim(
+        connection: &mut PgConnection,
+        reservation_id: &ConnectionReservationId,
+        client_id: &AsClientId,
+        now: DateTime<Utc>,
+    ) -> Result<ReservationClaim, StorageError> {
+        let claimed = sqlx::query!(
+            "UPDATE as_connection_reservations SET enqueued_at = $3
+            WHERE reservation_id = $1 AND client_id = $2
+                AND enqueued_at IS NULL AND expires_at > $3",
+            reservation_id.as_uuid(),
+            client_id.client_id(),
+            now,
+        )
+        .execute(&mut *connection)
+        .await?;
+        if claimed.rows_affected() > 0 {
+            return Ok(ReservationClaim::Claimed);
+        }
+        let enqueued = sqlx::query_scalar!(
+            "SELECT enqueued_at FROM as_connection_reservations
+            WHERE reservation_id = $1 AND client_id = $2",
+            reservation_id.as_uuid(),
+            client_id.client_id(),
+        )
+        .fetch_optional(&mut *connection)
+        .await?
+        .flatten();
+        Ok(match enqueued {
+            Some(_) => ReservationClaim::AlreadyEnqueued,
+            None => ReservationClaim::Expired,
+        })
+    }
+
+    /// Release a claim if enqueueing failed, such that it can be retried.
+    pub(in crate::auth_service) async fn release(
+        connection: impl PgExecutor<'_>,
+        reservation_id: &ConnectionReservationId,
+        client_id: &AsClientId,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            "UPDATE as_connection_reservations SET enqueued_at = NULL
+            WHERE reservation_id = $1 AND client_id = $2",
+            reservation_id.as_uuid(),
+            client_id.client_id(),
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Delete the expired reservations. Returns the number of deleted rows.
+    pub(in crate::auth_service) async fn delete_expired(
+        connection: impl PgExecutor<'_>,
+        now: DateTime<Utc>,
+    ) -> Result<u64, StorageError> {
+        let result = sqlx::query!(
+            "DELETE FROM as_connection_reservations WHERE expires_at <= $1",
+            now,
+        )
+        .execute(connection)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
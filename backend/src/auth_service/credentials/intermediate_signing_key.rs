@@ -11,6 +11,7 @@ use phnxtypes::{
         CredentialFingerprint,
     },
     identifiers::Fqdn,
+    time::{self, Duration},
 };
 use serde::{Deserialize, Serialize};
 use sqlx::{Connection, PgConnection};
@@ -88,19 +89,73 @@ impl IntermediateSigningKey {
         Ok(intermediate_signing_key)
     }
 
-    fn fingerprint(&self) -> &CredentialFingerprint {
+    /// Replace the active intermediate signing key with a newly generated one.
+    ///
+    /// The credential of the replaced key is still published for
+    /// `publication_overlap`, so that clients can verify the client credentials
+    /// it signed. If `publication_overlap` is `None`, it is published until it
+    /// expires.
+    pub(in crate::auth_service) async fn rotate(
+        connection: &mut PgConnection,
+        signature_scheme: SignatureScheme,
+        publication_overlap: Option<Duration>,
+    ) -> Result<Self, CredentialGenerationError> {
+        let mut transaction = connection.begin().await.map_err(StorageError::from)?;
+
+        let active_signing_key = Self::load(&mut *transaction)
+            .await?
+            .ok_or(CredentialGenerationError::NoActiveCredential)?;
+        let active_credential = active_signing_key.credential();
+
+        // Limit the publication of the replaced credential to the overlap
+        // window. It's never published beyond its own expiration.
+        let not_after = active_credential.expiration_data().not_after();
+        let published_until = match publication_overlap {
+            Some(overlap) => (*not_after).min(time::now() + overlap).into(),
+            None => not_after,
+        };
+        Self::set_published_until(
+            &mut *transaction,
+            active_credential.fingerprint(),
+            published_until,
+        )
+        .await?;
+
+        let intermediate_signing_key = Self::generate_sign_and_activate(
+            &mut transaction,
+            active_credential.domain().clone(),
+            signature_scheme,
+        )
+        .await?;
+
+        transaction.commit().await.map_err(StorageError::from)?;
+
+        Ok(intermediate_signing_key)
+    }
+
+    pub(in crate::auth_service) fn credential(&self) -> &AsIntermediateCredential {
         match self {
-            IntermediateSigningKey::V1(signing_key) => signing_key.credential().fingerprint(),
+            IntermediateSigningKey::V1(signing_key) => signing_key.credential(),
         }
     }
+
+    fn fingerprint(&self) -> &CredentialFingerprint {
+        self.credential().fingerprint()
+    }
 }
 
 mod persistence {
     use phnxtypes::{
         codec::PhnxCodec,
-        credentials::{keys::AsIntermediateSigningKey, AsIntermediateCredential},
+        credentials::{
+            keys::AsIntermediateSigningKey, AsIntermediateCredential, CredentialFingerprint,
+        },
+        time::{self, TimeStamp},
+    };
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgExecutor,
     };
-    use sqlx::PgExecutor;
 
     use crate::{auth_service::credentials::CredentialType, errors::StorageError};
 
@@ -155,15 +210,38 @@ mod persistence {
             .await?;
             Ok(())
         }
+
+        pub(super) async fn set_published_until(
+            connection: impl PgExecutor<'_>,
+            fingerprint: &CredentialFingerprint,
+            published_until: TimeStamp,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "UPDATE as_signing_keys
+                SET published_until = $2
+                WHERE credential_fingerprint = $1 AND cred_type = 'intermediate'",
+                fingerprint.as_bytes(),
+                DateTime::<Utc>::from(published_until),
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
     }
 
     impl IntermediateCredential {
+        /// Load the credentials of all published intermediate signing keys.
+        ///
+        /// The credential of the active key comes last.
         pub(in crate::auth_service) async fn load_all(
             connection: impl PgExecutor<'_>,
         ) -> Result<Vec<AsIntermediateCredential>, StorageError> {
             let records = sqlx::query!(
-                "SELECT signing_key FROM as_signing_keys WHERE cred_type = $1",
+                "SELECT signing_key FROM as_signing_keys
+                WHERE cred_type = $1 AND (published_until IS NULL OR published_until > $2)
+                ORDER BY currently_active, id",
                 CredentialType::Intermediate as _,
+                time::now(),
             )
             .fetch_all(connection)
             .await?;
@@ -180,3 +258,96 @@ mod persistence {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use phnxtypes::{
+        identifiers::Fqdn,
+        time::{set_thread_time_provider, Duration, TimeProvider},
+    };
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgPool,
+    };
+
+    use crate::{auth_service::AuthService, infra_service::InfraService};
+
+    use super::IntermediateCredential;
+
+    #[derive(Debug)]
+    struct FixedClock(DateTime<Utc>);
+
+    impl TimeProvider for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    #[sqlx::test]
+    async fn rotation_publishes_replaced_credential(pool: PgPool) {
+        let auth_service =
+            AuthService::new_from_pool(pool.clone(), Fqdn::try_from("example.com").unwrap())
+                .await
+                .expect("Error creating ephemeral AS instance.");
+        let initial_credentials = IntermediateCredential::load_all(&pool).await.unwrap();
+        assert_eq!(initial_credentials.len(), 1);
+
+        // The replaced credential is published during the overlap window and
+        // the new one comes last.
+        let second_fingerprint = auth_service
+            .rotate_intermediate_signing_key(Some(Duration::days(1)))
+            .await
+            .unwrap();
+        let credentials = IntermediateCredential::load_all(&pool).await.unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(
+            credentials[0].fingerprint(),
+            initial_credentials[0].fingerprint()
+        );
+        assert_eq!(credentials[1].fingerprint(), &second_fingerprint);
+
+        // With an empty overlap window, the replaced credential is no longer
+        // published.
+        let third_fingerprint = auth_service
+            .rotate_intermediate_signing_key(Some(Duration::zero()))
+            .await
+            .unwrap();
+        let credentials = IntermediateCredential::load_all(&pool).await.unwrap();
+        let fingerprints: Vec<_> = credentials
+            .iter()
+            .map(|credential| credential.fingerprint().clone())
+            .collect();
+        assert_eq!(
+            fingerprints,
+            vec![
+                initial_credentials[0].fingerprint().clone(),
+                third_fingerprint
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn publication_ends_at_the_injected_time(pool: PgPool) {
+        let auth_service =
+            AuthService::new_from_pool(pool.clone(), Fqdn::try_from("example.com").unwrap())
+                .await
+                .expect("Error creating ephemeral AS instance.");
+        auth_service
+            .rotate_intermediate_signing_key(Some(Duration::days(1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            IntermediateCredential::load_all(&pool).await.unwrap().len(),
+            2
+        );
+
+        // Once the overlap window has passed according to the time provider,
+        // only the active credential is published.
+        set_thread_time_provider(Some(Arc::new(FixedClock(Utc::now() + Duration::days(2)))));
+        let credentials = IntermediateCredential::load_all(&pool).await;
+        set_thread_time_provider(None);
+        assert_eq!(credentials.unwrap().len(), 1);
+    }
+}
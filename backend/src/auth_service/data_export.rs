@@ -0,0 +1,473 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Exports of the personal data the AS holds about a user.
+//!
+//! An export is assembled in a background task right after it was requested
+//! and stored encrypted to the key of the requesting client. The key itself is
+//! never stored, so an export that wasn't assembled before the server stopped
+//! is considered failed after the job timeout and has to be requested again.
+//! Exports are deleted once the retention period has passed.
+
+use phnxtypes::{
+    crypto::{hpke::HpkeEncryptable, DataExportEncryptionKey},
+    errors::auth_service::DataExportError,
+    identifiers::{AsClientId, DataExportId, QualifiedUserName},
+    messages::{
+        client_as::{
+            DataExportStatusParamsTbs, DataExportStatusResponse, DownloadDataExportParamsTbs,
+            DownloadDataExportResponse, RequestDataExportParamsTbs, RequestDataExportResponse,
+        },
+        data_export::{
+            ClientDataExport, DataExport, DataExportStatus, EncryptedDataExport, HandleDataExport,
+            QueueDataExport,
+        },
+    },
+    time::{now, Duration, TimeStamp},
+};
+use sqlx::PgExecutor;
+use thiserror::Error;
+use tls_codec::{DeserializeBytes, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{errors::StorageError, settings::DataExportSettings};
+
+use super::{
+    client_api::user::sender_reputation, client_record::ClientRecord, user_record::UserRecord,
+    AuthService,
+};
+
+#[derive(Debug, Error)]
+enum CollectDataExportError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("Unknown user")]
+    UnknownUser,
+    #[error("Missing activity of client {0}")]
+    MissingClientActivity(uuid::Uuid),
+}
+
+#[derive(Debug, Clone)]
+struct DataExportRecord {
+    export_id: DataExportId,
+    user_name: QualifiedUserName,
+    requested_at: TimeStamp,
+    completed_at: Option<TimeStamp>,
+    failed: bool,
+    archive: Option<Vec<u8>>,
+}
+
+impl DataExportRecord {
+    fn new(user_name: QualifiedUserName) -> Self {
+        Self {
+            export_id: DataExportId::random(),
+            user_name,
+            requested_at: TimeStamp::now(),
+            completed_at: None,
+            failed: false,
+            archive: None,
+        }
+    }
+
+    /// The status of the export, or `None` if it has expired.
+    fn status(&self, settings: &DataExportSettings, now: TimeStamp) -> Option<DataExportStatus> {
+        let retention = Duration::seconds(settings.retention_secs as i64);
+        let finished_at = self.completed_at.unwrap_or(self.requested_at);
+        if *finished_at + retention <= *now {
+            return None;
+        }
+        let status = match self.completed_at {
+            Some(completed_at) if !self.failed => {
+                DataExportStatus::Ready(TimeStamp::from(*completed_at + retention))
+            }
+            Some(_) => DataExportStatus::Failed,
+            None => {
+                let timeout = Duration::seconds(settings.job_timeout_secs as i64);
+                if self.failed || *self.requested_at + timeout <= *now {
+                    DataExportStatus::Failed
+                } else {
+                    DataExportStatus::Pending
+                }
+            }
+        };
+        Some(status)
+    }
+}
+
+impl AuthService {
+    /// Replace the default retention and timeouts of data exports.
+    pub fn with_data_export_settings(mut self, settings: DataExportSettings) -> Self {
+        self.data_export = settings;
+        self
+    }
+
+    /// Periodically delete data exports whose retention period has passed.
+    pub fn spawn_data_export_cleanup(&self) -> JoinHandle<()> {
+        let auth_service = self.clone();
+        let interval =
+            std::time::Duration::from_secs(auth_service.data_export.cleanup_interval_secs);
+        tokio::spawn(async move {
+            loop {
+                let retention = Duration::seconds(auth_service.data_export.retention_secs as i64);
+                let cutoff = TimeStamp::from(*TimeStamp::now() - retention);
+                match DataExportRecord::delete_expired(&auth_service.db_pool, cutoff).await {
+                    Ok(0) => {}
+                    Ok(deleted) => tracing::info!(deleted, "Deleted expired data exports"),
+                    Err(e) => tracing::error!("Error deleting expired data exports: {:?}", e),
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    pub(crate) async fn as_request_data_export(
+        &self,
+        params: RequestDataExportParamsTbs,
+    ) -> Result<RequestDataExportResponse, DataExportError> {
+        let RequestDataExportParamsTbs {
+            client_id,
+            encryption_key,
+            freshness: _,
+        } = params;
+        let user_name = client_id.user_name();
+
+        let now = TimeStamp::now();
+        let exports = DataExportRecord::load_all(&self.db_pool, &user_name)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading data exports: {:?}", e);
+                DataExportError::StorageError
+            })?;
+        let in_progress = exports
+            .iter()
+            .any(|export| export.status(&self.data_export, now) == Some(DataExportStatus::Pending));
+        if in_progress {
+            return Err(DataExportError::ExportInProgress);
+        }
+
+        let record = DataExportRecord::new(user_name);
+        record.store(&self.db_pool).await.map_err(|e| {
+            tracing::error!("Error storing data export: {:?}", e);
+            DataExportError::StorageError
+        })?;
+
+        let export_id = record.export_id;
+        let auth_service = self.clone();
+        tokio::spawn(async move {
+            auth_service
+                .assemble_data_export(record, encryption_key)
+                .await
+        });
+
+        Ok(RequestDataExportResponse { export_id })
+    }
+
+    pub(crate) async fn as_data_export_status(
+        &self,
+        params: DataExportStatusParamsTbs,
+    ) -> Result<DataExportStatusResponse, DataExportError> {
+        let record = self
+            .load_own_data_export(&params.client_id, params.export_id)
+            .await?;
+        let status = record
+            .status(&self.data_export, TimeStamp::now())
+            .ok_or(DataExportError::UnknownExport)?;
+        Ok(DataExportStatusResponse { status })
+    }
+
+    pub(crate) async fn as_download_data_export(
+        &self,
+        params: DownloadDataExportParamsTbs,
+    ) -> Result<DownloadDataExportResponse, DataExportError> {
+        let record = self
+            .load_own_data_export(&params.client_id, params.export_id)
+            .await?;
+        match record.status(&self.data_export, TimeStamp::now()) {
+            Some(DataExportStatus::Ready(_)) => {}
+            Some(DataExportStatus::Pending | DataExportStatus::Failed) => {
+                return Err(DataExportError::NotReady)
+            }
+            None => return Err(DataExportError::UnknownExport),
+        }
+        let archive = record.archive.ok_or(DataExportError::LibraryError)?;
+        let export = EncryptedDataExport::tls_deserialize_exact_bytes(&archive).map_err(|e| {
+            tracing::error!("Error deserializing data export: {:?}", e);
+            DataExportError::LibraryError
+        })?;
+        Ok(DownloadDataExportResponse { export })
+    }
+
+    /// Loads the export with the given id if it belongs to the user of the
+    /// given client.
+    async fn load_own_data_export(
+        &self,
+        client_id: &AsClientId,
+        export_id: DataExportId,
+    ) -> Result<DataExportRecord, DataExportError> {
+        DataExportRecord::load(&self.db_pool, export_id, &client_id.user_name())
+            .await
+            .map_err(|e| {
+                tracing::error!("Error loading data export: {:?}", e);
+                DataExportError::StorageError
+            })?
+            .ok_or(DataExportError::UnknownExport)
+    }
+
+    async fn assemble_data_export(
+        &self,
+        mut record: DataExportRecord,
+        encryption_key: DataExportEncryptionKey,
+    ) {
+        let archive = match self.collect_data_export(&record.user_name).await {
+            Ok(export) => export
+                .encrypt(&encryption_key, &[], &[])
+                .tls_serialize_detached()
+                .map_err(|e| tracing::error!("Error serializing data export: {:?}", e))
+                .ok(),
+            Err(e) => {
+                tracing::error!("Error collecting data export: {:?}", e);
+                None
+            }
+        };
+        record.failed = archive.is_none();
+        record.archive = archive;
+        record.completed_at = Some(TimeStamp::now());
+        if let Err(e) = record.update(&self.db_pool).await {
+            tracing::error!("Error storing assembled data export: {:?}", e);
+        }
+    }
+
+    async fn collect_data_export(
+        &self,
+        user_name: &QualifiedUserName,
+    ) -> Result<DataExport, CollectDataExportError> {
+        // All data is read from the same snapshot, so that the records of a
+        // client can't be deleted between the queries.
+        let mut transaction = self.db_pool.begin().await.map_err(StorageError::from)?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .execute(&mut *transaction)
+            .await
+            .map_err(StorageError::from)?;
+        let created_at = UserRecord::load_created_at(&mut *transaction, user_name)
+            .await?
+            .ok_or(CollectDataExportError::UnknownUser)?;
+        let credentials = ClientRecord::load_user_credentials(&mut *transaction, user_name).await?;
+        let activities =
+            DataExportRecord::load_client_activity(&mut *transaction, user_name).await?;
+        transaction.commit().await.map_err(StorageError::from)?;
+
+        let clients = credentials
+            .into_iter()
+            .map(|credential| {
+                let client_id = credential.identity();
+                let activity = activities
+                    .iter()
+                    .find(|activity| activity.client_id == client_id.client_id())
+                    .ok_or(CollectDataExportError::MissingClientActivity(
+                        client_id.client_id(),
+                    ))?;
+                let expiration_data = credential.expiration_data();
+                Ok(ClientDataExport {
+                    client_id,
+                    credential_not_before: expiration_data.not_before(),
+                    credential_not_after: expiration_data.not_after(),
+                    last_activity: activity.activity_time,
+                    remaining_tokens: activity.remaining_tokens.max(0) as u32,
+                    queue: QueueDataExport {
+                        sequence_number: activity.sequence_number.max(0) as u64,
+                        pending_messages: activity.pending_messages.max(0) as u64,
+                    },
+                })
+            })
+            .collect::<Result<_, CollectDataExportError>>()?;
+        Ok(DataExport {
+            exported_at: TimeStamp::now(),
+            user_name: user_name.clone(),
+            handles: vec![HandleDataExport {
+                handle: user_name.clone(),
+                registered_at: created_at.into(),
+            }],
+            sender_reputation: sender_reputation(Some(created_at), now()),
+            clients,
+        })
+    }
+}
+
+/// Activity and queue metadata of a client
+struct ClientActivity {
+    client_id: uuid::Uuid,
+    activity_time: TimeStamp,
+    remaining_tokens: i32,
+    sequence_number: i64,
+    pending_messages: i64,
+}
+
+mod persistence {
+    use sqlx::types::chrono::{DateTime, Utc};
+
+    use super::*;
+
+    impl DataExportRecord {
+        pub(super) async fn store(
+            &self,
+            connection: impl PgExecutor<'_>,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "INSERT INTO as_data_exports (export_id, user_name, requested_at) VALUES ($1, $2, $3)",
+                self.export_id.as_uuid(),
+                self.user_name.to_string(),
+                DateTime::<Utc>::from(self.requested_at),
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        pub(super) async fn update(
+            &self,
+            connection: impl PgExecutor<'_>,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "UPDATE as_data_exports SET completed_at = $2, failed = $3, archive = $4 WHERE export_id = $1",
+                self.export_id.as_uuid(),
+                self.completed_at.map(DateTime::<Utc>::from),
+                self.failed,
+                self.archive,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        /// Loads the export with the given id if it belongs to the given user.
+        pub(super) async fn load(
+            connection: impl PgExecutor<'_>,
+            export_id: DataExportId,
+            user_name: &QualifiedUserName,
+        ) -> Result<Option<Self>, StorageError> {
+            let record = sqlx::query!(
+                "SELECT requested_at, completed_at, failed, archive
+                FROM as_data_exports WHERE export_id = $1 AND user_name = $2",
+                export_id.as_uuid(),
+                user_name.to_string(),
+            )
+            .fetch_optional(connection)
+            .await?;
+            Ok(record.map(|record| Self {
+                export_id,
+                user_name: user_name.clone(),
+                requested_at: record.requested_at.into(),
+                completed_at: record.completed_at.map(From::from),
+                failed: record.failed,
+                archive: record.archive,
+            }))
+        }
+
+        /// Loads all exports of the given user. The archives are not loaded.
+        pub(super) async fn load_all(
+            connection: impl PgExecutor<'_>,
+            user_name: &QualifiedUserName,
+        ) -> Result<Vec<Self>, StorageError> {
+            let records = sqlx::query!(
+                "SELECT export_id, requested_at, completed_at, failed
+                FROM as_data_exports WHERE user_name = $1",
+                user_name.to_string(),
+            )
+            .fetch_all(connection)
+            .await?;
+            Ok(records
+                .into_iter()
+                .map(|record| Self {
+                    export_id: record.export_id.into(),
+                    user_name: user_name.clone(),
+                    requested_at: record.requested_at.into(),
+                    completed_at: record.completed_at.map(From::from),
+                    failed: record.failed,
+                    archive: None,
+                })
+                .collect())
+        }
+
+        /// Deletes all exports that were completed, or requested if they were
+        /// never completed, before the given time.
+        pub(super) async fn delete_expired(
+            connection: impl PgExecutor<'_>,
+            cutoff: TimeStamp,
+        ) -> Result<u64, StorageError> {
+            let result = sqlx::query!(
+                "DELETE FROM as_data_exports WHERE COALESCE(completed_at, requested_at) < $1",
+                DateTime::<Utc>::from(cutoff),
+            )
+            .execute(connection)
+            .await?;
+            Ok(result.rows_affected())
+        }
+
+        pub(super) async fn load_client_activity(
+            connection: impl PgExecutor<'_>,
+            user_name: &QualifiedUserName,
+        ) -> Result<Vec<ClientActivity>, StorageError> {
+            let records = sqlx::query!(
+                r#"SELECT
+                    c.client_id,
+                    c.activity_time,
+                    c.remaining_tokens,
+                    COALESCE(q.sequence_number, 0) AS "sequence_number!",
+                    (SELECT COUNT(*) FROM as_queues m WHERE m.queue_id = c.client_id) AS "pending_messages!"
+                FROM as_client_records c
+                LEFT JOIN as_queue_data q ON q.queue_id = c.client_id
+                WHERE c.user_name = $1"#,
+                user_name.to_string(),
+            )
+            .fetch_all(connection)
+            .await?;
+            Ok(records
+                .into_iter()
+                .map(|record| ClientActivity {
+                    client_id: record.client_id,
+                    activity_time: record.activity_time.into(),
+                    remaining_tokens: record.remaining_tokens,
+                    sequence_number: record.sequence_number,
+                    pending_messages: record.pending_messages,
+                })
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use super::*;
+
+    #[test]
+    fn export_status_follows_job_and_retention() {
+        let settings = DataExportSettings::default();
+        let user_name = SafeTryInto::try_into("alice@example.com").unwrap();
+        let mut record = DataExportRecord::new(user_name);
+        let requested_at = record.requested_at;
+        let at = |secs: u64| TimeStamp::from(*requested_at + Duration::seconds(secs as i64));
+
+        assert_eq!(
+            record.status(&settings, requested_at),
+            Some(DataExportStatus::Pending)
+        );
+        // The job didn't finish in time, e.g. because the server restarted.
+        assert_eq!(
+            record.status(&settings, at(settings.job_timeout_secs)),
+            Some(DataExportStatus::Failed)
+        );
+
+        let completed_at = at(60);
+        record.completed_at = Some(completed_at);
+        record.archive = Some(vec![]);
+        let expires_at = at(60 + settings.retention_secs);
+        assert_eq!(
+            record.status(&settings, at(settings.job_timeout_secs)),
+            Some(DataExportStatus::Ready(expires_at))
+        );
+        assert_eq!(record.status(&settings, expires_at), None);
+    }
+}
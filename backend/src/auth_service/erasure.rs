@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Audit trail of deleted users.
+//!
+//! Deleting a user is the first step of the erasure cascade driven by the
+//! client. The AS checks the user's password before it deletes anything, so a
+//! wrong password leaves the account intact. Deleting the user record deletes
+//! the clients, queues, connection packages, data exports and synced settings
+//! of the user at the AS, which the AS records for the operator. The record
+//! only contains the SHA-256 hash of the user name, so that the operator can
+//! check whether a given user was erased without the AS keeping the names of
+//! erased users.
+//!
+//! Afterwards, the client deletes its attachments and leaves its groups at the
+//! DS and deletes its user record at the QS. The AS doesn't know the
+//! pseudonymous identities of the user at the QS and the DS and only records
+//! what it erased itself.
+
+use phnxtypes::identifiers::QualifiedUserName;
+
+/// Record of a deleted user for the operator.
+#[derive(Debug, Clone)]
+pub(super) struct ErasureRecord {
+    pub(super) user_name: QualifiedUserName,
+    pub(super) deleted_clients: i64,
+    pub(super) deleted_exports: i64,
+}
+
+mod persistence {
+    use phnxtypes::identifiers::QualifiedUserName;
+    use sqlx::PgExecutor;
+
+    use crate::errors::StorageError;
+
+    use super::ErasureRecord;
+
+    impl ErasureRecord {
+        /// Count the data of the given user that is deleted together with
+        /// its user record. Must be called before the user is deleted.
+        pub(in crate::auth_service) async fn prepare(
+            connection: impl PgExecutor<'_>,
+            user_name: QualifiedUserName,
+        ) -> Result<Self, StorageError> {
+            let record = sqlx::query!(
+                r#"SELECT
+                    (SELECT COUNT(*) FROM as_client_records WHERE user_name = $1) AS "clients!",
+                    (SELECT COUNT(*) FROM as_data_exports WHERE user_name = $1) AS "exports!""#,
+                user_name.to_string(),
+            )
+            .fetch_one(connection)
+            .await?;
+            Ok(Self {
+                user_name,
+                deleted_clients: record.clients,
+                deleted_exports: record.exports,
+            })
+        }
+
+        pub(in crate::auth_service) async fn store(
+            &self,
+            connection: impl PgExecutor<'_>,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "INSERT INTO as_erasure_records (user_name_hash, erased_at, deleted_clients,
+                    deleted_exports)
+                VALUES (sha256(convert_to($1, 'UTF8')), now(), $2, $3)",
+                self.user_name.to_string(),
+                self.deleted_clients as i32,
+                self.deleted_exports as i32,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+    use sqlx::PgPool;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn erasure_records_only_store_the_hash_of_the_user_name(pool: PgPool) {
+        let user_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let record = ErasureRecord {
+            user_name: user_name.clone(),
+            deleted_clients: 2,
+            deleted_exports: 1,
+        };
+        record.store(&pool).await.unwrap();
+
+        let (user_name_hash, deleted_clients): (Vec<u8>, i32) =
+            sqlx::query_as("SELECT user_name_hash, deleted_clients FROM as_erasure_records")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(deleted_clients, 2);
+        assert_ne!(user_name_hash, user_name.to_string().into_bytes());
+
+        // The operator can check whether a given user was erased.
+        let erased: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM as_erasure_records
+                WHERE user_name_hash = sha256(convert_to($1, 'UTF8')))",
+        )
+        .bind(user_name.to_string())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(erased);
+    }
+}
@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Rules for the user names (handles) that can be registered with the AS.
+//!
+//! Besides length and character rules, the policy rejects reserved words and
+//! user names of deleted users until a cooldown has passed, such that a
+//! released user name can't immediately be taken over by somebody else.
+
+use std::collections::HashSet;
+
+use phnxtypes::{
+    errors::auth_service::HandleRejectionReason,
+    time::{Duration, TimeStamp},
+};
+
+use crate::settings::HandlePolicySettings;
+
+#[derive(Debug, Clone)]
+pub(super) struct HandlePolicy {
+    settings: HandlePolicySettings,
+    /// Normalized reserved words
+    reserved_words: HashSet<String>,
+}
+
+impl Default for HandlePolicy {
+    fn default() -> Self {
+        Self::new(HandlePolicySettings::default())
+    }
+}
+
+impl HandlePolicy {
+    pub(super) fn new(settings: HandlePolicySettings) -> Self {
+        let reserved_words = settings
+            .reserved_words
+            .iter()
+            .map(|word| normalize(word))
+            .collect();
+        Self {
+            settings,
+            reserved_words,
+        }
+    }
+
+    /// Check the given user name against the length, character and reserved
+    /// word rules.
+    pub(super) fn check(&self, handle: &str) -> Result<(), HandleRejectionReason> {
+        let length = handle.chars().count();
+        if length < self.settings.min_length {
+            return Err(HandleRejectionReason::TooShort(
+                self.settings.min_length as u32,
+            ));
+        }
+        if length > self.settings.max_length {
+            return Err(HandleRejectionReason::TooLong(
+                self.settings.max_length as u32,
+            ));
+        }
+        if !handle
+            .chars()
+            .next()
+            .is_some_and(|c| self.is_allowed_letter(c))
+        {
+            return Err(HandleRejectionReason::InvalidFirstCharacter);
+        }
+        let all_allowed = handle.chars().all(|c| {
+            self.is_allowed_letter(c)
+                || c.is_ascii_digit()
+                || self.settings.allowed_symbols.contains(c)
+        });
+        if !all_allowed {
+            return Err(HandleRejectionReason::InvalidCharacters);
+        }
+        if self.reserved_words.contains(&normalize(handle)) {
+            return Err(HandleRejectionReason::Reserved);
+        }
+        Ok(())
+    }
+
+    /// Check whether a user name that was released at the given time can be
+    /// registered again.
+    pub(super) fn check_released(
+        &self,
+        released_at: TimeStamp,
+    ) -> Result<(), HandleRejectionReason> {
+        let cooldown = Duration::seconds(self.settings.release_cooldown_secs as i64);
+        if released_at.has_expired(cooldown) {
+            Ok(())
+        } else {
+            let available_at = *released_at + cooldown;
+            Err(HandleRejectionReason::RecentlyReleased(available_at.into()))
+        }
+    }
+
+    fn is_allowed_letter(&self, c: char) -> bool {
+        c.is_ascii_lowercase() || (self.settings.allow_uppercase && c.is_ascii_uppercase())
+    }
+}
+
+/// Lower case the given word and drop everything but letters and digits.
+fn normalize(word: &str) -> String {
+    word.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// A user name that was released by deleting its user.
+pub(super) struct ReleasedHandle;
+
+mod persistence {
+    use phnxtypes::{identifiers::QualifiedUserName, time::TimeStamp};
+    use sqlx::PgExecutor;
+
+    use crate::errors::StorageError;
+
+    use super::ReleasedHandle;
+
+    impl ReleasedHandle {
+        /// Record that the given user name was released now.
+        pub(in crate::auth_service) async fn store(
+            connection: impl PgExecutor<'_>,
+            user_name: &QualifiedUserName,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "INSERT INTO as_released_handles (user_name, released_at) VALUES ($1, now())
+                ON CONFLICT (user_name) DO UPDATE SET released_at = now()",
+                user_name.to_string(),
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        /// Returns the time at which the given user name was last released,
+        /// if any.
+        pub(in crate::auth_service) async fn load_released_at(
+            connection: impl PgExecutor<'_>,
+            user_name: &QualifiedUserName,
+        ) -> Result<Option<TimeStamp>, StorageError> {
+            let released_at = sqlx::query_scalar!(
+                r#"SELECT released_at as "released_at: TimeStamp" FROM as_released_handles WHERE user_name = $1"#,
+                user_name.to_string(),
+            )
+            .fetch_optional(connection)
+            .await?;
+            Ok(released_at)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_and_characters() {
+        let policy = HandlePolicy::default();
+        assert_eq!(policy.check("alice"), Ok(()));
+        assert_eq!(policy.check("alice_b-2"), Ok(()));
+        assert_eq!(policy.check("al"), Err(HandleRejectionReason::TooShort(3)));
+        assert_eq!(
+            policy.check(&"a".repeat(33)),
+            Err(HandleRejectionReason::TooLong(32))
+        );
+        assert_eq!(
+            policy.check("2alice"),
+            Err(HandleRejectionReason::InvalidFirstCharacter)
+        );
+        assert_eq!(
+            policy.check("Alice"),
+            Err(HandleRejectionReason::InvalidFirstCharacter)
+        );
+        assert_eq!(
+            policy.check("ali ce"),
+            Err(HandleRejectionReason::InvalidCharacters)
+        );
+        assert_eq!(
+            policy.check("alicé"),
+            Err(HandleRejectionReason::InvalidCharacters)
+        );
+    }
+
+    #[test]
+    fn reserved_words_ignore_case_and_symbols() {
+        let policy = HandlePolicy::new(HandlePolicySettings {
+            allow_uppercase: true,
+            ..Default::default()
+        });
+        assert_eq!(policy.check("admin"), Err(HandleRejectionReason::Reserved));
+        assert_eq!(policy.check("Ad_min"), Err(HandleRejectionReason::Reserved));
+        assert_eq!(policy.check("admins"), Ok(()));
+    }
+
+    #[test]
+    fn released_handles_cool_down() {
+        let policy = HandlePolicy::default();
+        let long_ago: TimeStamp = (*TimeStamp::now() - Duration::days(31)).into();
+        assert_eq!(policy.check_released(long_ago), Ok(()));
+        let recently: TimeStamp = (*TimeStamp::now() - Duration::days(1)).into();
+        assert!(matches!(
+            policy.check_released(recently),
+            Err(HandleRejectionReason::RecentlyReleased(available_at)) if available_at > TimeStamp::now()
+        ));
+    }
+}
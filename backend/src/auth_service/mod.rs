@@ -5,26 +5,38 @@
 use std::{collections::HashMap, sync::Arc};
 
 use async_trait::async_trait;
+use connection_package::ConnectionPackageLifecycle;
 use credentials::{
-    intermediate_signing_key::IntermediateSigningKey, signing_key::StorableSigningKey,
+    intermediate_signing_key::{IntermediateCredential, IntermediateSigningKey},
+    signing_key::{Credential, StorableSigningKey},
     CredentialGenerationError,
 };
+use handle_policy::HandlePolicy;
 use opaque::OpaqueSetup;
 use opaque_ke::{rand::rngs::OsRng, ServerLogin};
 use phnxtypes::{
-    credentials::ClientCredential,
+    credentials::{ClientCredential, CredentialFingerprint},
     crypto::{signatures::DEFAULT_SIGNATURE_SCHEME, OpaqueCiphersuite},
     errors::auth_service::AsProcessingError,
     identifiers::{AsClientId, Fqdn, QualifiedUserName},
     messages::{
         client_as::{
-            AsClientConnectionPackageResponse, AsCredentialsResponse, Init2FactorAuthResponse,
-            InitClientAdditionResponse, InitUserRegistrationResponse, IssueTokensResponse,
-            UserClientsResponse, UserConnectionPackagesResponse, VerifiedAsRequestParams,
+            AsClientConnectionPackageResponse, AsCredentialsResponse, DataExportStatusResponse,
+            DownloadDataExportResponse, EnqueueMessagesResponse, GetUserProfileResponse,
+            GetUserSettingsResponse, Init2FactorAuthResponse, InitClientAdditionResponse,
+            InitUserRegistrationResponse, IssueTokensResponse, RefreshClientCredentialResponse,
+            RequestDataExportResponse, SenderReputationResponse, UploadUserProfileResponse,
+            UploadUserSettingsResponse, UserClientsResponse, UserConnectionPackagesResponse,
+            VerifiedAsRequestParams,
+        },
+        client_qs::{DequeueMessagesResponse, DequeueMessagesResponseV1},
+        server_info::{
+            ClientVersion, CompatibilityInfoResponse, ServerFeature, ServerInfoResponse,
         },
-        client_qs::DequeueMessagesResponse,
     },
+    time::Duration,
 };
+use server_info::ServerInfo;
 use sqlx::PgPool;
 use thiserror::Error;
 use tls_codec::{TlsSerialize, TlsSize};
@@ -33,18 +45,33 @@ use tokio::sync::Mutex;
 use crate::{
     errors::{DatabaseError, StorageError},
     infra_service::{InfraService, ServiceCreationError},
+    replay_cache::ReplayCache,
+    settings::{
+        AnnouncementSettings, DataExportSettings, HandlePolicySettings, SendRateLimitSettings,
+        ServerInfoSettings,
+    },
 };
 
+mod admin;
+mod announcements;
 pub mod client_api;
 mod client_record;
 mod connection_package;
 mod credentials;
+mod data_export;
+mod erasure;
+mod handle_policy;
 mod opaque;
+mod pending_credential;
 mod privacy_pass;
 mod queue;
+mod server_info;
+mod user_profiles;
 mod user_record;
+mod user_settings;
 mod verification;
 
+pub use connection_package::ConnectionPackageMetricsSnapshot;
 pub use verification::VerifiableClientToAsMessage;
 
 /*
@@ -69,6 +96,7 @@ ACTION_AS_USER_CLIENTS
 ACTION_AS_USER_KEY_PACKAGES
 ACTION_AS_ENQUEUE_MESSAGE
 ACTION_AS_CREDENTIALS
+ACTION_AS_SERVER_INFO
 */
 
 #[derive(Clone)]
@@ -76,6 +104,13 @@ pub struct AuthService {
     ephemeral_client_credentials: Arc<Mutex<HashMap<AsClientId, ClientCredential>>>,
     ephemeral_user_logins: Arc<Mutex<HashMap<QualifiedUserName, ServerLogin<OpaqueCiphersuite>>>>,
     ephemeral_client_logins: Arc<Mutex<HashMap<AsClientId, ServerLogin<OpaqueCiphersuite>>>>,
+    replay_cache: ReplayCache,
+    handle_policy: HandlePolicy,
+    server_info: ServerInfo,
+    announcements: Vec<AnnouncementSettings>,
+    data_export: DataExportSettings,
+    connection_packages: ConnectionPackageLifecycle,
+    domain: Fqdn,
     db_pool: PgPool,
 }
 
@@ -101,6 +136,13 @@ impl InfraService for AuthService {
             ephemeral_client_credentials: Arc::new(Mutex::new(HashMap::new())),
             ephemeral_user_logins: Arc::new(Mutex::new(HashMap::new())),
             ephemeral_client_logins: Arc::new(Mutex::new(HashMap::new())),
+            replay_cache: ReplayCache::default(),
+            handle_policy: HandlePolicy::default(),
+            server_info: ServerInfo::default(),
+            announcements: Vec::new(),
+            data_export: DataExportSettings::default(),
+            connection_packages: ConnectionPackageLifecycle::default(),
+            domain: domain.clone(),
         };
 
         // Check if there is an active AS signing key
@@ -145,9 +187,41 @@ impl InfraService for AuthService {
 }
 
 impl AuthService {
+    /// Replace the default rules for the user names that can be registered.
+    pub fn with_handle_policy(mut self, settings: HandlePolicySettings) -> Self {
+        self.handle_policy = HandlePolicy::new(settings);
+        self
+    }
+
+    /// Replace the information published to clients before they register,
+    /// including the optional features enabled on this server.
+    pub fn with_server_info(
+        mut self,
+        settings: ServerInfoSettings,
+        features: Vec<ServerFeature>,
+    ) -> Self {
+        self.server_info.set_settings(settings, features);
+        self
+    }
+
+    /// Oldest client version the server serves, if any.
+    pub fn min_client_version(&self) -> Option<ClientVersion> {
+        self.server_info.min_client_version()
+    }
+
+    /// Publish the rate limit the DS applies to senders, such that clients
+    /// can pace their messages.
+    pub fn with_send_rate_limit(mut self, settings: &SendRateLimitSettings) -> Self {
+        self.server_info.set_send_rate_limit(settings);
+        self
+    }
+
+    /// Process the given request of a client that announced the given
+    /// version, if any.
     pub async fn process(
         &self,
         message: VerifiableClientToAsMessage,
+        client_version: Option<ClientVersion>,
     ) -> Result<AsProcessResponse, AsProcessingError> {
         let verified_params = self.verify(message).await?;
 
@@ -172,12 +246,17 @@ impl AuthService {
                 self.as_delete_client(params).await?;
                 AsProcessResponse::Ok
             }
-            VerifiedAsRequestParams::DequeueMessages(params) => self
-                .as_dequeue_messages(params)
-                .await
-                .map(AsProcessResponse::DequeueMessages)?,
+            VerifiedAsRequestParams::DequeueMessages(params) => {
+                let response = self.as_dequeue_messages(params).await?;
+                if DequeueMessagesResponse::supported_by(client_version.as_ref()) {
+                    AsProcessResponse::DequeueMessagesV2(response)
+                } else {
+                    AsProcessResponse::DequeueMessages(response.into())
+                }
+            }
             VerifiedAsRequestParams::PublishConnectionPackages(params) => {
-                self.as_publish_connection_packages(params).await?;
+                self.as_publish_connection_packages(params, client_version.as_ref())
+                    .await?;
                 AsProcessResponse::Ok
             }
             VerifiedAsRequestParams::ClientConnectionPackage(params) => self
@@ -204,6 +283,10 @@ impl AuthService {
                 .as_credentials(params)
                 .await
                 .map(AsProcessResponse::AsCredentials)?,
+            VerifiedAsRequestParams::IntermediateCredentials(params) => self
+                .as_intermediate_credentials(params)
+                .await
+                .map(AsProcessResponse::IntermediateCredentials)?,
             VerifiedAsRequestParams::EnqueueMessage(params) => {
                 self.as_enqueue_message(params).await?;
                 AsProcessResponse::Ok
@@ -212,9 +295,104 @@ impl AuthService {
                 .as_init_user_registration(params)
                 .await
                 .map(AsProcessResponse::InitUserRegistration)?,
+            VerifiedAsRequestParams::RefreshClientCredential(params) => self
+                .as_refresh_client_credential(params)
+                .await
+                .map(AsProcessResponse::RefreshClientCredential)?,
+            VerifiedAsRequestParams::ConfirmClientCredential(params) => {
+                self.as_confirm_client_credential(params).await?;
+                AsProcessResponse::Ok
+            }
+            VerifiedAsRequestParams::ServerInfo(params) => {
+                AsProcessResponse::ServerInfo(self.as_server_info(params))
+            }
+            VerifiedAsRequestParams::CompatibilityInfo(params) => {
+                AsProcessResponse::CompatibilityInfo(self.as_compatibility_info(params))
+            }
+            VerifiedAsRequestParams::RequestDataExport(params) => self
+                .as_request_data_export(params)
+                .await
+                .map(AsProcessResponse::RequestDataExport)?,
+            VerifiedAsRequestParams::DataExportStatus(params) => self
+                .as_data_export_status(params)
+                .await
+                .map(AsProcessResponse::DataExportStatus)?,
+            VerifiedAsRequestParams::DownloadDataExport(params) => self
+                .as_download_data_export(params)
+                .await
+                .map(AsProcessResponse::DownloadDataExport)?,
+            VerifiedAsRequestParams::EnqueueMessages(params) => self
+                .as_enqueue_messages(params)
+                .await
+                .map(AsProcessResponse::EnqueueMessages)?,
+            VerifiedAsRequestParams::GetUserSettings(params) => self
+                .as_get_user_settings(params)
+                .await
+                .map(AsProcessResponse::GetUserSettings)?,
+            VerifiedAsRequestParams::UploadUserSettings(params) => self
+                .as_upload_user_settings(params)
+                .await
+                .map(AsProcessResponse::UploadUserSettings)?,
+            VerifiedAsRequestParams::SenderReputation(params) => self
+                .as_sender_reputation(params)
+                .await
+                .map(AsProcessResponse::SenderReputation)?,
+            VerifiedAsRequestParams::UploadUserProfile(params) => self
+                .as_upload_user_profile(params)
+                .await
+                .map(AsProcessResponse::UploadUserProfile)?,
+            VerifiedAsRequestParams::GetUserProfile(params) => self
+                .as_get_user_profile(params)
+                .await
+                .map(AsProcessResponse::GetUserProfile)?,
         };
         Ok(response)
     }
+
+    /// Replace the active intermediate signing key with a new one signed by
+    /// the active AS signing key. Client credentials issued from now on are
+    /// signed with the new key.
+    ///
+    /// The credential of the replaced key is still served to clients for
+    /// `publication_overlap` (or until it expires if `None`), so that they can
+    /// verify the client credentials it signed until these are refreshed.
+    ///
+    /// Returns the fingerprint of the new intermediate credential.
+    pub async fn rotate_intermediate_signing_key(
+        &self,
+        publication_overlap: Option<Duration>,
+    ) -> Result<CredentialFingerprint, CredentialGenerationError> {
+        let mut connection = self.db_pool.acquire().await?;
+        let signing_key = IntermediateSigningKey::rotate(
+            &mut connection,
+            DEFAULT_SIGNATURE_SCHEME,
+            publication_overlap,
+        )
+        .await?;
+        let fingerprint = signing_key.credential().fingerprint().clone();
+        tracing::info!("Activated new AS intermediate credential {}", fingerprint);
+        Ok(fingerprint)
+    }
+
+    /// Fingerprints of the AS credentials and the AS intermediate
+    /// credentials, e.g. for operators to compare with the fingerprints
+    /// pinned by clients.
+    pub async fn credential_fingerprints(
+        &self,
+    ) -> Result<(Vec<CredentialFingerprint>, Vec<CredentialFingerprint>), StorageError> {
+        let as_credentials = Credential::load_all(&self.db_pool).await?;
+        let intermediate_credentials = IntermediateCredential::load_all(&self.db_pool).await?;
+        Ok((
+            as_credentials
+                .iter()
+                .map(|credential| credential.fingerprint().clone())
+                .collect(),
+            intermediate_credentials
+                .iter()
+                .map(|credential| credential.fingerprint().clone())
+                .collect(),
+        ))
+    }
 }
 
 #[derive(Debug, TlsSerialize, TlsSize)]
@@ -222,7 +400,7 @@ impl AuthService {
 pub enum AsProcessResponse {
     Ok,
     Init2FactorAuth(Init2FactorAuthResponse),
-    DequeueMessages(DequeueMessagesResponse),
+    DequeueMessages(DequeueMessagesResponseV1),
     ClientKeyPackage(AsClientConnectionPackageResponse),
     IssueTokens(IssueTokensResponse),
     UserKeyPackages(UserConnectionPackagesResponse),
@@ -230,4 +408,18 @@ pub enum AsProcessResponse {
     UserClients(UserClientsResponse),
     AsCredentials(AsCredentialsResponse),
     InitUserRegistration(InitUserRegistrationResponse),
+    RefreshClientCredential(RefreshClientCredentialResponse),
+    ServerInfo(ServerInfoResponse),
+    RequestDataExport(RequestDataExportResponse),
+    DataExportStatus(DataExportStatusResponse),
+    DownloadDataExport(DownloadDataExportResponse),
+    GetUserSettings(GetUserSettingsResponse),
+    UploadUserSettings(UploadUserSettingsResponse),
+    SenderReputation(SenderReputationResponse),
+    UploadUserProfile(UploadUserProfileResponse),
+    GetUserProfile(GetUserProfileResponse),
+    CompatibilityInfo(CompatibilityInfoResponse),
+    DequeueMessagesV2(DequeueMessagesResponse),
+    EnqueueMessages(EnqueueMessagesResponse),
+    IntermediateCredentials(AsCredentialsResponse),
 }
@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Client credentials that were issued during a credential refresh, but not
+//! yet confirmed by the client.
+//!
+//! A refreshed credential only replaces the one in the client record once the
+//! client has signed a confirmation with the new key. If the client never
+//! receives the response to its refresh request, it can thus keep using its
+//! current credential and simply request a new one.
+
+use phnxtypes::{codec::PhnxCodec, credentials::ClientCredential, identifiers::AsClientId};
+use sqlx::PgExecutor;
+
+use crate::errors::StorageError;
+
+pub(super) struct PendingClientCredential;
+
+impl PendingClientCredential {
+    /// Store the pending credential of a client, replacing any earlier one.
+    pub(super) async fn store(
+        connection: impl PgExecutor<'_>,
+        credential: &ClientCredential,
+    ) -> Result<(), StorageError> {
+        let client_id = credential.identity();
+        let credential_bytes = PhnxCodec::to_vec(credential)?;
+        sqlx::query!(
+            "INSERT INTO as_pending_client_credentials (client_id, credential) VALUES ($1, $2)
+            ON CONFLICT (client_id) DO UPDATE SET credential = EXCLUDED.credential",
+            client_id.client_id(),
+            credential_bytes,
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn load(
+        connection: impl PgExecutor<'_>,
+        client_id: &AsClientId,
+    ) -> Result<Option<ClientCredential>, StorageError> {
+        sqlx::query_scalar!(
+            "SELECT credential FROM as_pending_client_credentials WHERE client_id = $1",
+            client_id.client_id(),
+        )
+        .fetch_optional(connection)
+        .await?
+        .map(|bytes| Ok(PhnxCodec::from_slice(&bytes)?))
+        .transpose()
+    }
+
+    /// Remove and return the pending credential of a client.
+    pub(super) async fn take(
+        connection: impl PgExecutor<'_>,
+        client_id: &AsClientId,
+    ) -> Result<Option<ClientCredential>, StorageError> {
+        sqlx::query_scalar!(
+            "DELETE FROM as_pending_client_credentials WHERE client_id = $1 RETURNING credential",
+            client_id.client_id(),
+        )
+        .fetch_optional(connection)
+        .await?
+        .map(|bytes| Ok(PhnxCodec::from_slice(&bytes)?))
+        .transpose()
+    }
+}
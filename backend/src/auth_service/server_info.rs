@@ -0,0 +1,88 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Information about the server that clients query before registering.
+
+use phnxtypes::messages::{
+    server_info::{
+        ClientVersion, CompatibilityInfoResponse, MetricsEndpoint, RegistrationPolicy,
+        SendRateLimit, ServerFeature, ServerInfoResponse, ServerMotd,
+    },
+    MlsInfraVersion,
+};
+
+use crate::settings::{SendRateLimitSettings, ServerInfoSettings};
+
+#[derive(Debug, Clone)]
+pub(super) struct ServerInfo {
+    settings: ServerInfoSettings,
+    features: Vec<ServerFeature>,
+    /// Rate limit the DS applies to senders
+    send_rate_limit: Option<SendRateLimit>,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self {
+            settings: ServerInfoSettings::default(),
+            features: Vec::new(),
+            send_rate_limit: SendRateLimitSettings::default().published_limit(),
+        }
+    }
+}
+
+impl ServerInfo {
+    pub(super) fn set_settings(
+        &mut self,
+        settings: ServerInfoSettings,
+        features: Vec<ServerFeature>,
+    ) {
+        self.settings = settings;
+        self.features = features;
+    }
+
+    pub(super) fn set_send_rate_limit(&mut self, settings: &SendRateLimitSettings) {
+        self.send_rate_limit = settings.published_limit();
+    }
+
+    pub(super) fn registration_open(&self) -> bool {
+        self.settings.registration_open
+    }
+
+    pub(super) fn response(&self) -> ServerInfoResponse {
+        let registration_policy = if self.settings.registration_open {
+            RegistrationPolicy::Open
+        } else {
+            RegistrationPolicy::Closed
+        };
+        ServerInfoResponse {
+            supported_versions: MlsInfraVersion::SUPPORTED.to_vec(),
+            registration_policy,
+            features: self.features.clone(),
+            motd: self.settings.motd.clone().map(ServerMotd::new),
+            metrics_endpoint: self
+                .settings
+                .metrics_endpoint
+                .clone()
+                .map(MetricsEndpoint::new),
+            send_rate_limit: self.send_rate_limit,
+        }
+    }
+
+    pub(super) fn min_client_version(&self) -> Option<ClientVersion> {
+        self.settings.min_client_version
+    }
+
+    pub(super) fn compatibility_info(&self) -> CompatibilityInfoResponse {
+        CompatibilityInfoResponse {
+            min_client_version: self.settings.min_client_version,
+            deprecated_features: self
+                .settings
+                .deprecated_features
+                .iter()
+                .map(ServerFeature::new)
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Encrypted profiles of users.
+//!
+//! The AS stores the encrypted profile of each user together with a version
+//! that is incremented with each upload. Clients fetching a profile send the
+//! version they have cached and only receive the profile if it changed.
+
+use phnxtypes::{
+    errors::auth_service::UserProfileError,
+    identifiers::QualifiedUserName,
+    messages::{
+        client_as::{
+            GetUserProfileParams, GetUserProfileResponse, UploadUserProfileParamsTbs,
+            UploadUserProfileResponse,
+        },
+        user_profiles::{
+            EncryptedUserProfile, UserProfileFetch, VersionedUserProfile, MAX_USER_PROFILE_BYTES,
+        },
+    },
+};
+use sqlx::PgExecutor;
+use tls_codec::{DeserializeBytes, Serialize};
+
+use crate::errors::StorageError;
+
+use super::AuthService;
+
+impl AuthService {
+    pub(crate) async fn as_get_user_profile(
+        &self,
+        params: GetUserProfileParams,
+    ) -> Result<GetUserProfileResponse, UserProfileError> {
+        let GetUserProfileParams {
+            user_name,
+            cached_version,
+        } = params;
+        let record = load(&self.db_pool, &user_name).await.map_err(|e| {
+            tracing::error!("Error loading user profile: {:?}", e);
+            UserProfileError::StorageError
+        })?;
+        let stored = record
+            .map(|(version, profile)| {
+                let profile =
+                    EncryptedUserProfile::tls_deserialize_exact_bytes(&profile).map_err(|e| {
+                        tracing::error!("Error deserializing user profile: {:?}", e);
+                        UserProfileError::LibraryError
+                    })?;
+                Ok(VersionedUserProfile {
+                    version: version as u64,
+                    profile,
+                })
+            })
+            .transpose()?;
+        Ok(GetUserProfileResponse {
+            profile: UserProfileFetch::new(stored, cached_version),
+        })
+    }
+
+    pub(crate) async fn as_upload_user_profile(
+        &self,
+        params: UploadUserProfileParamsTbs,
+    ) -> Result<UploadUserProfileResponse, UserProfileError> {
+        let UploadUserProfileParamsTbs {
+            client_id,
+            profile,
+            freshness: _,
+        } = params;
+        let profile = profile.tls_serialize_detached().map_err(|e| {
+            tracing::error!("Error serializing user profile: {:?}", e);
+            UserProfileError::LibraryError
+        })?;
+        if profile.len() > MAX_USER_PROFILE_BYTES {
+            return Err(UserProfileError::TooLarge);
+        }
+
+        let version = store(&self.db_pool, &client_id.user_name(), &profile)
+            .await
+            .map_err(|e| {
+                tracing::error!("Error storing user profile: {:?}", e);
+                UserProfileError::StorageError
+            })?;
+        Ok(UploadUserProfileResponse {
+            version: version as u64,
+        })
+    }
+}
+
+/// Loads the version and the encrypted profile of the given user.
+async fn load(
+    connection: impl PgExecutor<'_>,
+    user_name: &QualifiedUserName,
+) -> Result<Option<(i64, Vec<u8>)>, StorageError> {
+    let record = sqlx::query!(
+        "SELECT version, profile FROM as_user_profiles WHERE user_name = $1",
+        user_name.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?;
+    Ok(record.map(|record| (record.version, record.profile)))
+}
+
+/// Stores the profile of the given user with the next version and returns
+/// that version.
+async fn store(
+    connection: impl PgExecutor<'_>,
+    user_name: &QualifiedUserName,
+    profile: &[u8],
+) -> Result<i64, StorageError> {
+    let version = sqlx::query_scalar!(
+        "INSERT INTO as_user_profiles (user_name, version, profile, updated_at)
+        VALUES ($1, 1, $2, now())
+        ON CONFLICT (user_name) DO UPDATE
+        SET version = as_user_profiles.version + 1, profile = EXCLUDED.profile, updated_at = now()
+        RETURNING version",
+        user_name.to_string(),
+        profile,
+    )
+    .fetch_one(connection)
+    .await?;
+    Ok(version)
+}
@@ -44,7 +44,10 @@ mod persistence {
         codec::PhnxCodec,
         identifiers::{QualifiedUserName, UserName},
     };
-    use sqlx::PgExecutor;
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgExecutor,
+    };
 
     use crate::errors::StorageError;
 
@@ -70,6 +73,21 @@ mod persistence {
             .transpose()
         }
 
+        /// Registration time of the user with the given name. Returns None if
+        /// the user doesn't exist.
+        pub(in crate::auth_service) async fn load_created_at(
+            connection: impl PgExecutor<'_>,
+            user_name: &QualifiedUserName,
+        ) -> Result<Option<DateTime<Utc>>, StorageError> {
+            let created_at = sqlx::query_scalar!(
+                "SELECT created_at FROM as_user_records WHERE user_name = $1",
+                user_name.to_string(),
+            )
+            .fetch_optional(connection)
+            .await?;
+            Ok(created_at)
+        }
+
         /// Create a new user with the given user name. If a user with the given user
         /// name already exists, an error is returned.
         pub(super) async fn store(
@@ -0,0 +1,144 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Settings synced between the clients of a user.
+//!
+//! The AS stores the encrypted settings blob of each user and can't read it.
+//! Uploads are versioned: an upload only succeeds if it is based on the
+//! version currently stored, so that clients never overwrite changes they
+//! haven't merged yet.
+
+use phnxtypes::{
+    errors::auth_service::UserSettingsError,
+    identifiers::QualifiedUserName,
+    messages::{
+        client_as::{
+            GetUserSettingsParamsTbs, GetUserSettingsResponse, UploadUserSettingsParamsTbs,
+            UploadUserSettingsResponse,
+        },
+        user_settings::{EncryptedUserSettings, VersionedUserSettings, MAX_USER_SETTINGS_BYTES},
+    },
+};
+use sqlx::PgExecutor;
+use tls_codec::{DeserializeBytes, Serialize};
+
+use crate::errors::StorageError;
+
+use super::AuthService;
+
+impl AuthService {
+    pub(crate) async fn as_get_user_settings(
+        &self,
+        params: GetUserSettingsParamsTbs,
+    ) -> Result<GetUserSettingsResponse, UserSettingsError> {
+        let user_name = params.client_id.user_name();
+        let record = load(&self.db_pool, &user_name).await.map_err(|e| {
+            tracing::error!("Error loading user settings: {:?}", e);
+            UserSettingsError::StorageError
+        })?;
+        let settings = record
+            .map(|(version, settings)| {
+                let settings = EncryptedUserSettings::tls_deserialize_exact_bytes(&settings)
+                    .map_err(|e| {
+                        tracing::error!("Error deserializing user settings: {:?}", e);
+                        UserSettingsError::LibraryError
+                    })?;
+                Ok(VersionedUserSettings {
+                    version: version as u64,
+                    settings,
+                })
+            })
+            .transpose()?;
+        Ok(GetUserSettingsResponse { settings })
+    }
+
+    pub(crate) async fn as_upload_user_settings(
+        &self,
+        params: UploadUserSettingsParamsTbs,
+    ) -> Result<UploadUserSettingsResponse, UserSettingsError> {
+        let UploadUserSettingsParamsTbs {
+            client_id,
+            base_version,
+            settings,
+            freshness: _,
+        } = params;
+        let settings = settings.tls_serialize_detached().map_err(|e| {
+            tracing::error!("Error serializing user settings: {:?}", e);
+            UserSettingsError::LibraryError
+        })?;
+        if settings.len() > MAX_USER_SETTINGS_BYTES {
+            return Err(UserSettingsError::TooLarge);
+        }
+
+        let version = base_version
+            .checked_add(1)
+            .ok_or(UserSettingsError::VersionConflict)?;
+        let stored = store(
+            &self.db_pool,
+            &client_id.user_name(),
+            base_version,
+            version,
+            &settings,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Error storing user settings: {:?}", e);
+            UserSettingsError::StorageError
+        })?;
+        if !stored {
+            return Err(UserSettingsError::VersionConflict);
+        }
+        Ok(UploadUserSettingsResponse { version })
+    }
+}
+
+/// Loads the version and the encrypted settings of the given user.
+async fn load(
+    connection: impl PgExecutor<'_>,
+    user_name: &QualifiedUserName,
+) -> Result<Option<(i64, Vec<u8>)>, StorageError> {
+    let record = sqlx::query!(
+        "SELECT version, settings FROM as_user_settings WHERE user_name = $1",
+        user_name.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?;
+    Ok(record.map(|record| (record.version, record.settings)))
+}
+
+/// Stores the settings of the given user as the given version if the stored
+/// settings have the base version, or if there are none and the base version
+/// is `0`. Returns `false` if the settings were not stored.
+async fn store(
+    connection: impl PgExecutor<'_>,
+    user_name: &QualifiedUserName,
+    base_version: u64,
+    version: u64,
+    settings: &[u8],
+) -> Result<bool, StorageError> {
+    let result = if base_version == 0 {
+        sqlx::query!(
+            "INSERT INTO as_user_settings (user_name, version, settings, updated_at)
+            VALUES ($1, $2, $3, now())
+            ON CONFLICT (user_name) DO NOTHING",
+            user_name.to_string(),
+            version as i64,
+            settings,
+        )
+        .execute(connection)
+        .await?
+    } else {
+        sqlx::query!(
+            "UPDATE as_user_settings SET version = $3, settings = $4, updated_at = now()
+            WHERE user_name = $1 AND version = $2",
+            user_name.to_string(),
+            base_version as i64,
+            version as i64,
+            settings,
+        )
+        .execute(connection)
+        .await?
+    };
+    Ok(result.rows_affected() == 1)
+}
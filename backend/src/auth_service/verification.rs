@@ -9,7 +9,12 @@ use phnxtypes::{
 };
 use tls_codec::TlsDeserializeBytes;
 
-use super::{client_record::ClientRecord, AuthService, TlsSize, VerifiedAsRequestParams};
+use crate::replay_cache::ReplayError;
+
+use super::{
+    client_record::ClientRecord, pending_credential::PendingClientCredential, AuthService, TlsSize,
+    VerifiedAsRequestParams,
+};
 
 /// Wrapper struct around a message from a client to the AS. It does not
 /// implement the [`Verifiable`] trait, but instead is verified depending on the
@@ -21,6 +26,10 @@ impl VerifiableClientToAsMessage {
     fn into_auth_method(self) -> AsAuthMethod {
         self.0.auth_method()
     }
+
+    pub fn is_server_info_request(&self) -> bool {
+        self.0.is_server_info_request()
+    }
 }
 
 impl AuthService {
@@ -37,8 +46,8 @@ impl AuthService {
             // request.
             AsAuthMethod::ClientCredential(cca) => {
                 // Depending on the request type, we either load the client
-                // credential from the persistend storage, or the ephemeral
-                // storage.
+                // credential from the persistend storage, the ephemeral
+                // storage, or the client's pending credential.
                 if cca.is_finish_user_registration_request() {
                     let client_credentials = self.ephemeral_client_credentials.lock().await;
                     let client_credential = client_credentials
@@ -46,6 +55,17 @@ impl AuthService {
                         .ok_or(AsVerificationError::UnknownClient)?;
                     cca.verify(client_credential.verifying_key())
                         .map_err(|_| AsVerificationError::AuthenticationFailed)?
+                } else if cca.is_confirm_client_credential_request() {
+                    let client_credential =
+                        PendingClientCredential::load(&self.db_pool, cca.client_id())
+                            .await
+                            .map_err(|e| {
+                                tracing::error!("Error loading pending client credential: {:?}", e);
+                                AsVerificationError::UnknownClient
+                            })?
+                            .ok_or(AsVerificationError::UnknownClient)?;
+                    cca.verify(client_credential.verifying_key())
+                        .map_err(|_| AsVerificationError::AuthenticationFailed)?
                 } else {
                     let client_record = ClientRecord::load(&self.db_pool, cca.client_id())
                         .await
@@ -115,6 +135,23 @@ impl AuthService {
                 *user_auth.payload
             }
         };
+        // Reject stale and replayed requests. This happens after verification
+        // so that only authenticated payloads can occupy the nonce cache.
+        if let Some(freshness) = parameters.freshness() {
+            self.replay_cache
+                .check(&self.db_pool, freshness)
+                .await
+                .map_err(|e| match e {
+                    ReplayError::Storage(e) => {
+                        tracing::error!("Failed to check the replay cache: {e}");
+                        AsVerificationError::StorageError
+                    }
+                    e => {
+                        tracing::warn!("Rejected AS request: {e}");
+                        AsVerificationError::ReplayedRequest
+                    }
+                })?;
+        }
         Ok(parameters)
     }
 }
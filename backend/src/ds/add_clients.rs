@@ -16,8 +16,11 @@ use phnxtypes::{
     },
     errors::ClientAdditionError,
     identifiers::{QsClientReference, QS_CLIENT_REFERENCE_EXTENSION_TYPE},
-    messages::client_ds::{
-        AddClientsParams, DsJoinerInformation, InfraAadMessage, InfraAadPayload, WelcomeBundle,
+    messages::{
+        client_ds::{
+            AddClientsParams, DsJoinerInformation, InfraAadMessage, InfraAadPayload, WelcomeBundle,
+        },
+        QueuePriority,
     },
     time::{Duration, TimeStamp},
 };
@@ -25,7 +28,10 @@ use tls_codec::DeserializeBytes;
 
 use crate::messages::intra_backend::{DsFanOutMessage, DsFanOutPayload};
 
-use super::{group_state::ClientProfile, process::USER_EXPIRATION_DAYS};
+use super::{
+    group_state::ClientProfile, process::USER_EXPIRATION_DAYS,
+    update_room_policy::changes_group_context,
+};
 
 use super::group_state::DsGroupState;
 
@@ -73,6 +79,14 @@ impl DsGroupState {
         } else {
             return Err(ClientAdditionError::InvalidMessage);
         };
+        let update_proposals: Vec<_> = staged_commit.update_proposals().collect();
+        let client_updates = self
+            .referenced_client_updates(&update_proposals)
+            .map_err(|_| ClientAdditionError::InvalidMessage)?;
+        if changes_group_context(staged_commit) {
+            tracing::warn!("Group context extensions can only be changed by admins");
+            return Err(ClientAdditionError::InvalidMessage);
+        }
 
         // Check if sender index and user profile match.
         if let Sender::Member(leaf_index) = processed_message.sender() {
@@ -128,6 +142,7 @@ impl DsGroupState {
             processed_assisted_message_plus.processed_assisted_message,
             Duration::days(USER_EXPIRATION_DAYS),
         )?;
+        self.apply_client_updates(client_updates);
 
         // ... s.t. it's easier to update the user profile.
         let mut fan_out_messages: Vec<DsFanOutMessage> = vec![];
@@ -201,6 +216,7 @@ impl DsGroupState {
             let fan_out_message = DsFanOutMessage {
                 payload: DsFanOutPayload::QueueMessage(queue_message_payload),
                 client_reference: client_queue_config,
+                priority: QueuePriority::High,
             };
             fan_out_messages.push(fan_out_message);
         }
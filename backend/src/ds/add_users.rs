@@ -24,8 +24,12 @@ use phnxtypes::{
     errors::AddUsersError,
     identifiers::{Fqdn, QsClientReference, QS_CLIENT_REFERENCE_EXTENSION_TYPE},
     keypackage_batch::{KeyPackageBatch, KEYPACKAGEBATCH_EXPIRATION, VERIFIED},
-    messages::client_ds::{
-        AddUsersParams, DsJoinerInformation, InfraAadMessage, InfraAadPayload, WelcomeBundle,
+    messages::{
+        client_ds::{
+            AddUsersParams, DsJoinerInformation, InfraAadMessage, InfraAadPayload, WelcomeBundle,
+        },
+        room_policy::RoomPolicy,
+        QueuePriority,
     },
     time::{Duration, TimeStamp},
 };
@@ -36,7 +40,10 @@ use crate::{
     qs::QsConnector,
 };
 
-use super::{group_state::ClientProfile, process::USER_EXPIRATION_DAYS};
+use super::{
+    group_state::ClientProfile, process::USER_EXPIRATION_DAYS,
+    update_room_policy::changes_group_context,
+};
 
 use super::group_state::DsGroupState;
 
@@ -87,6 +94,14 @@ impl DsGroupState {
         } else {
             return Err(AddUsersError::InvalidMessage);
         };
+        let update_proposals: Vec<_> = staged_commit.update_proposals().collect();
+        let client_updates = self
+            .referenced_client_updates(&update_proposals)
+            .map_err(|_| AddUsersError::InvalidMessage)?;
+        if changes_group_context(staged_commit) {
+            tracing::warn!("Group context extensions can only be changed by admins");
+            return Err(AddUsersError::InvalidMessage);
+        }
 
         // Check if sender index and user profile match.
         if let Sender::Member(leaf_index) = processed_message.sender() {
@@ -102,6 +117,15 @@ impl DsGroupState {
             };
         }
 
+        // Check if the room policy allows the sender to invite users.
+        let room_policy =
+            RoomPolicy::from_extensions(self.group().group_info().group_context().extensions())
+                .map_err(|_| AddUsersError::LibraryError)?;
+        if !room_policy.can_invite(&params.sender) {
+            tracing::warn!("Sender is not allowed to invite users");
+            return Err(AddUsersError::NotAllowedToInvite);
+        }
+
         // Check if we have enough encrypted credential chains.
         if staged_commit.add_proposals().count()
             != aad_payload.encrypted_credential_information.len()
@@ -220,6 +244,7 @@ impl DsGroupState {
             processed_assisted_message_plus.processed_assisted_message,
             Duration::days(USER_EXPIRATION_DAYS),
         )?;
+        self.apply_client_updates(client_updates);
 
         // ... s.t. it's easier to update the user and client profiles.
 
@@ -306,6 +331,7 @@ impl DsGroupState {
                             .map_err(|_| AddUsersError::LibraryError)?,
                     ),
                     client_reference: client_queue_config,
+                    priority: QueuePriority::High,
                 };
                 fan_out_messages.push(fan_out_message);
             }
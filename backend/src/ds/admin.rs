@@ -0,0 +1,118 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Operator access to the DS database, see [`crate::admin`].
+//!
+//! Group states are encrypted with a key only the members know. Without it,
+//! the DS can only tell when a group was last used. With the key, e.g.
+//! provided by a member for debugging, the epoch and the members of the
+//! group can be inspected as well.
+
+use phnxtypes::{
+    codec::PhnxCodec, crypto::ear::keys::GroupStateEarKey, identifiers::QualifiedGroupId,
+};
+use uuid::Uuid;
+
+use crate::{
+    admin::{AdminError, AdminService, GroupStateSummary, GroupSummary, IntegrityIssue},
+    errors::StorageError,
+};
+
+use super::{
+    group_state::{DsGroupState, StorableDsGroupData},
+    Ds, GROUP_STATE_EXPIRATION,
+};
+
+impl Ds {
+    /// Returns `None` if the group doesn't exist. The state of the group is
+    /// only included if a key is given.
+    pub async fn admin_group_summary(
+        &self,
+        group_id: Uuid,
+        ear_key: Option<&GroupStateEarKey>,
+    ) -> Result<Option<GroupSummary>, AdminError> {
+        let qgid = QualifiedGroupId::new(group_id, self.own_domain.clone());
+        let Some(group_data) = StorableDsGroupData::load(&self.db_pool, &qgid).await? else {
+            return Ok(None);
+        };
+        let state = ear_key
+            .map(|ear_key| {
+                let group_state = DsGroupState::decrypt(&group_data.encrypted_group_state, ear_key)
+                    .map_err(|_| AdminError::GroupStateDecryption)?;
+                Ok::<_, AdminError>(GroupStateSummary {
+                    epoch: group_state.group().epoch().as_u64(),
+                    members: group_state.user_profiles.len(),
+                    clients: group_state.client_profiles.len(),
+                })
+            })
+            .transpose()?;
+        let state_size = PhnxCodec::to_vec(&group_data.encrypted_group_state)
+            .map_err(StorageError::from)?
+            .len();
+        Ok(Some(GroupSummary {
+            group_id,
+            last_used: **group_data.last_used(),
+            expired: group_data.has_expired(),
+            state_size,
+            deleted_queues: group_data.deleted_queues().len(),
+            state,
+        }))
+    }
+
+    pub async fn admin_check_integrity(&self) -> Result<Vec<IntegrityIssue>, AdminError> {
+        let expired_since = phnxtypes::time::now() - GROUP_STATE_EXPIRATION;
+        let issues = persistence::load_expired_groups(&self.db_pool, expired_since)
+            .await?
+            .into_iter()
+            .map(|(group_id, last_used)| IntegrityIssue::ExpiredGroup {
+                group_id,
+                last_used,
+            })
+            .collect();
+        Ok(issues)
+    }
+
+    /// Repair the given issue. Returns false if the issue doesn't concern the
+    /// DS or can't be repaired.
+    pub async fn admin_repair(&self, issue: &IntegrityIssue) -> Result<bool, AdminError> {
+        if issue.service() != AdminService::Ds {
+            return Ok(false);
+        }
+        match issue {
+            IntegrityIssue::ExpiredGroup { group_id, .. } => {
+                let qgid = QualifiedGroupId::new(*group_id, self.own_domain.clone());
+                // Deletes the attachments and applied operations of the group
+                // as well.
+                StorableDsGroupData::delete(&self.db_pool, &qgid).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+mod persistence {
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgExecutor,
+    };
+
+    use super::*;
+
+    pub(super) async fn load_expired_groups(
+        connection: impl PgExecutor<'_>,
+        expired_since: DateTime<Utc>,
+    ) -> Result<Vec<(Uuid, DateTime<Utc>)>, StorageError> {
+        let records = sqlx::query!(
+            "SELECT group_id, last_used FROM encrypted_groups WHERE last_used < $1",
+            expired_since
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| (record.group_id, record.last_used))
+            .collect())
+    }
+}
@@ -10,7 +10,7 @@ use mls_assist::{
 };
 use phnxtypes::{errors::GroupDeletionError, messages::client_ds::DeleteGroupParams};
 
-use super::group_state::DsGroupState;
+use super::{group_state::DsGroupState, update_room_policy::changes_group_context};
 
 impl DsGroupState {
     pub(crate) fn delete_group(
@@ -62,6 +62,7 @@ impl DsGroupState {
             // Check that the commit only contains removes.
             if staged_commit.add_proposals().count() > 0
                 || staged_commit.update_proposals().count() > 0
+                || changes_group_context(staged_commit)
             {
                 tracing::warn!("Found add or update proposals in delete group commit");
                 return Err(GroupDeletionError::InvalidMessage);
@@ -8,7 +8,7 @@ use mls_assist::{
     group::Group,
     openmls::{
         group::GroupId,
-        prelude::{GroupEpoch, LeafNodeIndex, QueuedRemoveProposal, Sender},
+        prelude::{GroupEpoch, LeafNodeIndex, QueuedRemoveProposal, QueuedUpdateProposal, Sender},
         treesync::RatchetTree,
     },
     provider_traits::MlsAssistProvider,
@@ -27,7 +27,7 @@ use phnxtypes::{
     },
     errors::{CborMlsAssistStorage, UpdateQueueConfigError, ValidationError},
     identifiers::{QsClientReference, SealedClientReference},
-    messages::client_ds::{UpdateQsClientReferenceParams, WelcomeInfoParams},
+    messages::client_ds::{GroupEpochInfo, UpdateQsClientReferenceParams, WelcomeInfoParams},
     time::TimeStamp,
 };
 use serde::{Deserialize, Serialize};
@@ -57,6 +57,15 @@ pub(super) struct ClientProfile {
     pub(super) activity_epoch: GroupEpoch,
 }
 
+/// The client information a client sent along with a proposal to update its
+/// leaf. It replaces the one in the client's profile once the proposal is
+/// committed.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct PendingClientUpdate {
+    pub(super) epoch: GroupEpoch,
+    pub(super) encrypted_client_information: (EncryptedClientCredential, EncryptedSignatureEarKey),
+}
+
 /// The `DsGroupState` is the per-group state that the DS persists.
 /// It is encrypted-at-rest with a roster key.
 ///
@@ -69,6 +78,15 @@ pub(crate) struct DsGroupState {
     // Here we keep users that haven't set their user key yet.
     pub(super) unmerged_users: Vec<Vec<LeafNodeIndex>>,
     pub(super) client_profiles: BTreeMap<LeafNodeIndex, ClientProfile>,
+    // If this group is the successor of an upgraded group, we keep the users
+    // of the upgraded group here until they have joined.
+    pub(super) migrating_users: HashMap<UserKeyHash, UserAuthVerifyingKey>,
+    // The epoch in which a client last proposed to remove itself. Proposals
+    // are only valid in their epoch.
+    pub(super) self_removal_epoch: Option<GroupEpoch>,
+    // The client information of update proposals. Like the self removal, they
+    // are only valid in their epoch.
+    pub(super) pending_client_updates: BTreeMap<LeafNodeIndex, PendingClientUpdate>,
 }
 
 impl DsGroupState {
@@ -105,6 +123,9 @@ impl DsGroupState {
             user_profiles,
             client_profiles,
             unmerged_users: vec![],
+            migrating_users: HashMap::new(),
+            self_removal_epoch: None,
+            pending_client_updates: BTreeMap::new(),
         }
     }
 
@@ -137,6 +158,28 @@ impl DsGroupState {
         self.user_profiles
             .get(user_key_hash)
             .map(|user_profile| &user_profile.user_auth_key)
+            .or_else(|| self.migrating_users.get(user_key_hash))
+    }
+
+    /// The hash of the user key of the user owning the given leaf. Returns
+    /// `None` if the user hasn't set their user key yet.
+    pub(super) fn user_key_hash(&self, leaf_index: LeafNodeIndex) -> Option<&UserKeyHash> {
+        self.user_profiles
+            .iter()
+            .find(|(_, user_profile)| user_profile.clients.contains(&leaf_index))
+            .map(|(user_key_hash, _)| user_key_hash)
+    }
+
+    /// Returns true if a client proposed to remove itself or to update its
+    /// leaf in the current epoch. A commit that doesn't include the proposal
+    /// discards it.
+    pub(super) fn has_pending_proposals(&self) -> bool {
+        let epoch = self.group().epoch();
+        self.self_removal_epoch == Some(epoch)
+            || self
+                .pending_client_updates
+                .values()
+                .any(|pending_update| pending_update.epoch == epoch)
     }
 
     pub(super) fn welcome_info(
@@ -158,6 +201,14 @@ impl DsGroupState {
         }
     }
 
+    pub(super) fn group_epoch_info(&self) -> GroupEpochInfo {
+        let group_context = self.group().group_info().group_context();
+        GroupEpochInfo {
+            epoch: group_context.epoch().as_u64(),
+            confirmed_transcript_hash: group_context.confirmed_transcript_hash().to_vec().into(),
+        }
+    }
+
     pub(super) fn process_referenced_remove_proposals(
         &mut self,
         remove_proposals: &[QueuedRemoveProposal],
@@ -221,6 +272,49 @@ impl DsGroupState {
         Ok(())
     }
 
+    /// Check that the given update proposals were proposed in the current
+    /// epoch via [`Self::propose_client_update`] and return their senders.
+    /// The client information they proposed is applied with
+    /// [`Self::apply_client_updates`] once the commit is accepted.
+    pub(super) fn referenced_client_updates(
+        &self,
+        update_proposals: &[QueuedUpdateProposal],
+    ) -> Result<Vec<LeafNodeIndex>, ValidationError> {
+        let epoch = self.group().epoch();
+        update_proposals
+            .iter()
+            .map(|update_proposal| {
+                let Sender::Member(proposer) = update_proposal.sender() else {
+                    return Err(ValidationError::InvalidMessage);
+                };
+                if !self
+                    .pending_client_updates
+                    .get(proposer)
+                    .is_some_and(|pending_update| pending_update.epoch == epoch)
+                {
+                    return Err(ValidationError::InvalidMessage);
+                }
+                Ok(*proposer)
+            })
+            .collect()
+    }
+
+    /// Replace the client information of the given proposers with the one
+    /// they proposed and drop the proposals of the previous epoch.
+    pub(super) fn apply_client_updates(&mut self, proposers: Vec<LeafNodeIndex>) {
+        for proposer in proposers {
+            let Some(pending_update) = self.pending_client_updates.remove(&proposer) else {
+                continue;
+            };
+            // The proposer might have removed itself in the same commit.
+            if let Some(client_profile) = self.client_profiles.get_mut(&proposer) {
+                client_profile.encrypted_client_information =
+                    pending_update.encrypted_client_information;
+            }
+        }
+        self.pending_client_updates.clear();
+    }
+
     /// Create vector of encrypted client credentials options from the current
     /// list of client records.
     pub(super) fn client_information(
@@ -300,6 +394,14 @@ impl StorableDsGroupData {
     pub(super) fn has_expired(&self) -> bool {
         self.last_used.has_expired(GROUP_STATE_EXPIRATION)
     }
+
+    pub(super) fn last_used(&self) -> &TimeStamp {
+        &self.last_used
+    }
+
+    pub(super) fn deleted_queues(&self) -> &[SealedClientReference] {
+        &self.deleted_queues
+    }
 }
 
 impl From<Ciphertext> for EncryptedDsGroupState {
@@ -321,6 +423,13 @@ pub(crate) struct SerializableDsGroupState {
     user_profiles: Vec<(UserKeyHash, UserProfile)>,
     unmerged_users: Vec<Vec<LeafNodeIndex>>,
     client_profiles: Vec<(LeafNodeIndex, ClientProfile)>,
+    // Group states stored before room upgrades don't contain this field.
+    #[serde(default)]
+    migrating_users: Vec<(UserKeyHash, UserAuthVerifyingKey)>,
+    #[serde(default)]
+    self_removal_epoch: Option<GroupEpoch>,
+    #[serde(default)]
+    pending_client_updates: Vec<(LeafNodeIndex, PendingClientUpdate)>,
 }
 
 impl SerializableDsGroupState {
@@ -335,6 +444,7 @@ impl SerializableDsGroupState {
             .clone();
         let user_profiles = group_state.user_profiles.into_iter().collect();
         let client_profiles = group_state.client_profiles.into_iter().collect();
+        let migrating_users = group_state.migrating_users.into_iter().collect();
         let serialized_provider = group_state.provider.storage().serialize()?;
         Ok(Self {
             group_id,
@@ -342,6 +452,9 @@ impl SerializableDsGroupState {
             user_profiles,
             unmerged_users: group_state.unmerged_users,
             client_profiles,
+            migrating_users,
+            self_removal_epoch: group_state.self_removal_epoch,
+            pending_client_updates: group_state.pending_client_updates.into_iter().collect(),
         })
     }
 
@@ -351,6 +464,7 @@ impl SerializableDsGroupState {
         let group = Group::load(&storage, &self.group_id)?.unwrap();
         let user_profiles = self.user_profiles.into_iter().collect();
         let client_profiles = self.client_profiles.into_iter().collect();
+        let migrating_users = self.migrating_users.into_iter().collect();
         let provider = MlsAssistRustCrypto::from(storage);
         Ok(DsGroupState {
             provider,
@@ -358,6 +472,9 @@ impl SerializableDsGroupState {
             user_profiles,
             unmerged_users: self.unmerged_users,
             client_profiles,
+            migrating_users,
+            self_removal_epoch: self.self_removal_epoch,
+            pending_client_updates: self.pending_client_updates.into_iter().collect(),
         })
     }
 }
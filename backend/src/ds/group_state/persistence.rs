@@ -6,12 +6,12 @@ use phnxtypes::codec::PhnxCodec;
 use phnxtypes::identifiers::QualifiedGroupId;
 use sqlx::{
     types::chrono::{DateTime, Utc},
-    PgExecutor,
+    PgExecutor, PgPool,
 };
 
 use crate::errors::StorageError;
 
-use super::StorableDsGroupData;
+use super::{EncryptedDsGroupState, StorableDsGroupData};
 
 impl StorableDsGroupData {
     pub(super) async fn store(&self, connection: impl PgExecutor<'_>) -> Result<(), StorageError> {
@@ -59,6 +59,36 @@ impl StorableDsGroupData {
         Ok(Some(storable_group_data))
     }
 
+    /// Same as [`Self::load`], but locks the group state until the end of the
+    /// transaction, such that concurrent updates can't be lost.
+    pub(crate) async fn load_for_update(
+        connection: impl PgExecutor<'_>,
+        qgid: &QualifiedGroupId,
+    ) -> Result<Option<StorableDsGroupData>, StorageError> {
+        let Some(group_data_record) = sqlx::query!(
+            "SELECT 
+                group_id, ciphertext, last_used, deleted_queues
+            FROM 
+                encrypted_groups
+            WHERE 
+                group_id = $1
+            FOR UPDATE",
+            qgid.group_uuid()
+        )
+        .fetch_optional(connection)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let storable_group_data = Self {
+            group_id: group_data_record.group_id,
+            encrypted_group_state: PhnxCodec::from_slice(&group_data_record.ciphertext)?,
+            last_used: group_data_record.last_used.into(),
+            deleted_queues: PhnxCodec::from_slice(&group_data_record.deleted_queues)?,
+        };
+        Ok(Some(storable_group_data))
+    }
+
     pub(crate) async fn update(&self, connection: impl PgExecutor<'_>) -> Result<(), StorageError> {
         sqlx::query!(
             "UPDATE 
@@ -77,6 +107,32 @@ impl StorableDsGroupData {
         Ok(())
     }
 
+    /// Store the group state, unless it changed since `loaded_state` was
+    /// loaded, e.g. because a commit was processed concurrently. Returns
+    /// `false` if the group state wasn't stored.
+    ///
+    /// Used for updates that can be dropped, such as the recorded activity of
+    /// members, which must not overwrite a newer epoch.
+    pub(crate) async fn update_if_unchanged(
+        &self,
+        db_pool: &PgPool,
+        qgid: &QualifiedGroupId,
+        loaded_state: &EncryptedDsGroupState,
+    ) -> Result<bool, StorageError> {
+        let mut transaction = db_pool.begin().await?;
+        let current = Self::load_for_update(&mut *transaction, qgid).await?;
+        if current
+            .map(|current| current.encrypted_group_state)
+            .as_ref()
+            != Some(loaded_state)
+        {
+            return Ok(false);
+        }
+        self.update(&mut *transaction).await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+
     pub(crate) async fn delete(
         connection: impl PgExecutor<'_>,
         qgid: &QualifiedGroupId,
@@ -109,6 +165,7 @@ mod test {
             Ds,
         },
         infra_service::InfraService,
+        tenancy,
     };
 
     #[sqlx::test]
@@ -181,4 +238,103 @@ mod test {
             storable_group_data.encrypted_group_state
         );
     }
+
+    #[sqlx::test]
+    async fn concurrent_changes_are_not_overwritten(pool: PgPool) {
+        let ds = Ds::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .expect("Error creating ephemeral Ds instance.");
+
+        let group_uuid = Uuid::new_v4();
+        assert!(ds.reserve_group_id(group_uuid).await);
+        let qgid = QualifiedGroupId::new(group_uuid, ds.own_domain.clone());
+        let reserved_group_id = ds.claim_reserved_group_id(group_uuid).await.unwrap();
+        StorableDsGroupData::new_and_store(
+            &ds.db_pool,
+            reserved_group_id,
+            Ciphertext::dummy().into(),
+        )
+        .await
+        .unwrap();
+
+        // Two requests load the same group state
+        let mut activity_update = StorableDsGroupData::load(&ds.db_pool, &qgid)
+            .await
+            .unwrap()
+            .unwrap();
+        let loaded_state = activity_update.encrypted_group_state.clone();
+        let mut commit = StorableDsGroupData::load(&ds.db_pool, &qgid)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The commit is stored first, so the activity update is dropped
+        commit.encrypted_group_state.0.flip_bit();
+        commit.update(&ds.db_pool).await.unwrap();
+        activity_update.encrypted_group_state = Ciphertext::default().into();
+        assert!(!activity_update
+            .update_if_unchanged(&ds.db_pool, &qgid, &loaded_state)
+            .await
+            .unwrap());
+        let stored = StorableDsGroupData::load(&ds.db_pool, &qgid)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.encrypted_group_state, commit.encrypted_group_state);
+
+        // Without a concurrent change, the update is stored
+        let loaded_state = stored.encrypted_group_state.clone();
+        let mut activity_update = stored;
+        activity_update.encrypted_group_state = Ciphertext::default().into();
+        assert!(activity_update
+            .update_if_unchanged(&ds.db_pool, &qgid, &loaded_state)
+            .await
+            .unwrap());
+        let stored = StorableDsGroupData::load(&ds.db_pool, &qgid)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            stored.encrypted_group_state,
+            activity_update.encrypted_group_state
+        );
+    }
+
+    #[sqlx::test]
+    async fn group_states_are_isolated_between_tenants(pool: PgPool) {
+        let connect_options = pool.connect_options().as_ref().clone();
+        let domain_a = Fqdn::try_from("a.example.com").unwrap();
+        let domain_b = Fqdn::try_from("b.example.com").unwrap();
+        let pool_a = tenancy::connect_tenant_pool(connect_options.clone(), &domain_a)
+            .await
+            .unwrap();
+        let pool_b = tenancy::connect_tenant_pool(connect_options, &domain_b)
+            .await
+            .unwrap();
+        let ds_a = Ds::initialize(pool_a, domain_a.clone()).await.unwrap();
+        let ds_b = Ds::initialize(pool_b, domain_b).await.unwrap();
+
+        // Create a group on the DS of domain a
+        let group_uuid = Uuid::new_v4();
+        assert!(ds_a.reserve_group_id(group_uuid).await);
+        let reserved_group_id = ds_a.claim_reserved_group_id(group_uuid).await.unwrap();
+        StorableDsGroupData::new_and_store(
+            &ds_a.db_pool,
+            reserved_group_id,
+            Ciphertext::dummy().into(),
+        )
+        .await
+        .unwrap();
+
+        // Only the DS of domain a can load it
+        let qgid = QualifiedGroupId::new(group_uuid, domain_a);
+        assert!(StorableDsGroupData::load(&ds_a.db_pool, &qgid)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(StorableDsGroupData::load(&ds_b.db_pool, &qgid)
+            .await
+            .unwrap()
+            .is_none());
+    }
 }
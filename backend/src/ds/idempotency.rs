@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Deduplication of retried group operations.
+//!
+//! Clients attach an idempotency key to each request. When the DS applies a
+//! group operation, it records the key together with the epoch of the group
+//! after the operation was applied and the sender. If the same sender sends a
+//! request with the same key again while the group is still in that epoch
+//! (e.g. because the client timed out waiting for the response), the DS does
+//! not process the request a second time, but responds with the fanout
+//! timestamp of the original request. Requests with the same key in later
+//! epochs are processed as usual.
+
+use mls_assist::openmls::prelude::GroupEpoch;
+use phnxtypes::{
+    messages::client_ds::{DsSender, IdempotencyKey},
+    time::{self, TimeStamp},
+};
+use sqlx::{
+    types::chrono::{DateTime, Duration, Utc},
+    PgExecutor,
+};
+use tls_codec::Serialize;
+use uuid::Uuid;
+
+use crate::errors::StorageError;
+
+/// Number of days after which applied operations are forgotten.
+const APPLIED_OPERATION_RETENTION_DAYS: i64 = 7;
+
+pub(super) struct AppliedOperation {
+    group_id: Uuid,
+    idempotency_key: IdempotencyKey,
+    /// The epoch of the group after the operation was applied
+    epoch: GroupEpoch,
+    sender: Vec<u8>,
+    fanout_timestamp: TimeStamp,
+}
+
+impl AppliedOperation {
+    pub(super) fn new(
+        group_id: Uuid,
+        idempotency_key: IdempotencyKey,
+        epoch: GroupEpoch,
+        sender: &DsSender,
+        fanout_timestamp: TimeStamp,
+    ) -> Result<Self, StorageError> {
+        Ok(Self {
+            group_id,
+            idempotency_key,
+            epoch,
+            sender: serialize_sender(sender)?,
+            fanout_timestamp,
+        })
+    }
+
+    /// Store the operation, replacing an operation with the same key from an
+    /// earlier epoch, and prune operations of the same group that are older
+    /// than the retention period.
+    pub(super) async fn store(
+        &self,
+        connection: impl PgExecutor<'_> + Copy,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            "INSERT INTO 
+                ds_applied_operations 
+                (group_id, idempotency_key, epoch, sender, fanout_timestamp)
+            VALUES 
+                ($1, $2, $3, $4, $5)
+            ON CONFLICT (group_id, idempotency_key) DO UPDATE SET 
+                epoch = EXCLUDED.epoch, 
+                sender = EXCLUDED.sender, 
+                fanout_timestamp = EXCLUDED.fanout_timestamp, 
+                created_at = NOW()",
+            self.group_id,
+            self.idempotency_key.as_slice(),
+            self.epoch.as_u64() as i64,
+            self.sender,
+            DateTime::<Utc>::from(self.fanout_timestamp),
+        )
+        .execute(connection)
+        .await?;
+        let cutoff = time::now() - Duration::days(APPLIED_OPERATION_RETENTION_DAYS);
+        sqlx::query!(
+            "DELETE FROM 
+                ds_applied_operations 
+            WHERE 
+                group_id = $1 AND created_at < $2",
+            self.group_id,
+            cutoff,
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the fanout timestamp of the operation with the given key if it
+    /// was sent by the given sender and the group is still in the epoch in
+    /// which the operation left it.
+    pub(super) async fn load_fanout_timestamp(
+        connection: impl PgExecutor<'_>,
+        group_id: Uuid,
+        idempotency_key: &IdempotencyKey,
+        epoch: GroupEpoch,
+        sender: &DsSender,
+    ) -> Result<Option<TimeStamp>, StorageError> {
+        let sender = serialize_sender(sender)?;
+        let record = sqlx::query!(
+            "SELECT 
+                fanout_timestamp
+            FROM 
+                ds_applied_operations
+            WHERE 
+                group_id = $1 AND idempotency_key = $2 AND epoch = $3 AND sender = $4",
+            group_id,
+            idempotency_key.as_slice(),
+            epoch.as_u64() as i64,
+            sender,
+        )
+        .fetch_optional(connection)
+        .await?;
+        Ok(record.map(|record| record.fanout_timestamp.into()))
+    }
+}
+
+fn serialize_sender(sender: &DsSender) -> Result<Vec<u8>, StorageError> {
+    sender
+        .tls_serialize_detached()
+        .map_err(|e| Box::<dyn std::error::Error + Send + Sync>::from(e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{crypto::ear::Ciphertext, identifiers::Fqdn};
+    use sqlx::PgPool;
+
+    use crate::ds::{group_state::StorableDsGroupData, Ds};
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn operations_are_bound_to_the_epoch(pool: PgPool) {
+        let ds = Ds::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .unwrap();
+        let group_uuid = Uuid::new_v4();
+        assert!(ds.reserve_group_id(group_uuid).await);
+        let reserved_group_id = ds.claim_reserved_group_id(group_uuid).await.unwrap();
+        StorableDsGroupData::new_and_store(
+            &ds.db_pool,
+            reserved_group_id,
+            Ciphertext::dummy().into(),
+        )
+        .await
+        .unwrap();
+
+        let key = IdempotencyKey::derive(b"request");
+        let sender = DsSender::Anonymous;
+        // Postgres stores timestamps with microsecond precision
+        let timestamp: TimeStamp = DateTime::<Utc>::from_timestamp(1_700_000_000, 0)
+            .unwrap()
+            .into();
+        AppliedOperation::new(group_uuid, key.clone(), 3.into(), &sender, timestamp)
+            .unwrap()
+            .store(&ds.db_pool)
+            .await
+            .unwrap();
+
+        // Retries in the same epoch are recognized ...
+        let loaded = AppliedOperation::load_fanout_timestamp(
+            &ds.db_pool,
+            group_uuid,
+            &key,
+            3.into(),
+            &sender,
+        )
+        .await
+        .unwrap();
+        assert_eq!(loaded, Some(timestamp));
+        // ... requests in later epochs are not.
+        let loaded = AppliedOperation::load_fanout_timestamp(
+            &ds.db_pool,
+            group_uuid,
+            &key,
+            4.into(),
+            &sender,
+        )
+        .await
+        .unwrap();
+        assert_eq!(loaded, None);
+
+        // The same key can be applied again in a later epoch.
+        AppliedOperation::new(group_uuid, key.clone(), 5.into(), &sender, timestamp)
+            .unwrap()
+            .store(&ds.db_pool)
+            .await
+            .unwrap();
+        let loaded = AppliedOperation::load_fanout_timestamp(
+            &ds.db_pool,
+            group_uuid,
+            &key,
+            5.into(),
+            &sender,
+        )
+        .await
+        .unwrap();
+        assert_eq!(loaded, Some(timestamp));
+    }
+}
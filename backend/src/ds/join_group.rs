@@ -16,6 +16,7 @@ use tls_codec::DeserializeBytes;
 use super::{
     group_state::{ClientProfile, DsGroupState},
     process::USER_EXPIRATION_DAYS,
+    update_room_policy::changes_group_context,
 };
 
 impl DsGroupState {
@@ -48,6 +49,7 @@ impl DsGroupState {
             if staged_commit.add_proposals().count() > 0
                 || staged_commit.update_proposals().count() > 0
                 || staged_commit.remove_proposals().count() > 0
+                || changes_group_context(staged_commit)
             {
                 return Err(JoinGroupError::InvalidMessage);
             }
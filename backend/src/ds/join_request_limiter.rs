@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Limits the number of join requests a group receives.
+//!
+//! Join requests are sent anonymously, so the DS can't tell requesters apart.
+//! Instead, requests are limited per join request token, which is handed to
+//! individual requesters, and per group, which protects the members of a
+//! group from being flooded with requests via many tokens. Members
+//! additionally ignore repeated requests of the same user.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Length of the window in which join requests are counted.
+const JOIN_REQUEST_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Maximum number of join requests per group and window.
+const MAX_JOIN_REQUESTS_PER_WINDOW: u32 = 20;
+/// Maximum number of join requests per token and window.
+const MAX_JOIN_REQUESTS_PER_TOKEN_AND_WINDOW: u32 = 3;
+/// Number of tracked groups or tokens above which the ones with expired
+/// windows are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Default)]
+pub(super) struct JoinRequestLimiter {
+    groups: Mutex<HashMap<Uuid, (Instant, u32)>>,
+    tokens: Mutex<HashMap<Vec<u8>, (Instant, u32)>>,
+}
+
+impl JoinRequestLimiter {
+    /// Record a join request to the given group with the token with the
+    /// given id. Returns `false` if the group or the token were used for too
+    /// many join requests recently.
+    pub(super) async fn check(&self, group_id: Uuid, token_id: &[u8]) -> bool {
+        let now = Instant::now();
+        // The token is checked first, such that requests with an exhausted
+        // token don't count towards the limit of the group.
+        let mut tokens = self.tokens.lock().await;
+        if !record(
+            &mut tokens,
+            token_id.to_vec(),
+            now,
+            MAX_JOIN_REQUESTS_PER_TOKEN_AND_WINDOW,
+        ) {
+            return false;
+        }
+        let mut groups = self.groups.lock().await;
+        record(&mut groups, group_id, now, MAX_JOIN_REQUESTS_PER_WINDOW)
+    }
+}
+
+/// Count a request for the given key. Returns `false` if the key has reached
+/// the given limit in the current window.
+fn record<K: Eq + Hash>(
+    windows: &mut HashMap<K, (Instant, u32)>,
+    key: K,
+    now: Instant,
+    limit: u32,
+) -> bool {
+    if windows.len() > PRUNE_THRESHOLD {
+        windows
+            .retain(|_, (window_start, _)| now.duration_since(*window_start) < JOIN_REQUEST_WINDOW);
+    }
+    let (window_start, count) = windows.entry(key).or_insert((now, 0));
+    if now.duration_since(*window_start) >= JOIN_REQUEST_WINDOW {
+        *window_start = now;
+        *count = 0;
+    }
+    if *count >= limit {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_requests_per_token_and_group() {
+        let limiter = JoinRequestLimiter::default();
+        let group_id = Uuid::new_v4();
+        for _ in 0..MAX_JOIN_REQUESTS_PER_TOKEN_AND_WINDOW {
+            assert!(limiter.check(group_id, b"token").await);
+        }
+        assert!(!limiter.check(group_id, b"token").await);
+
+        // Other tokens can still be used until the group limit is reached.
+        let remaining = MAX_JOIN_REQUESTS_PER_WINDOW - MAX_JOIN_REQUESTS_PER_TOKEN_AND_WINDOW;
+        for i in 0..remaining {
+            assert!(limiter.check(group_id, &i.to_be_bytes()).await);
+        }
+        assert!(!limiter.check(group_id, b"other token").await);
+    }
+}
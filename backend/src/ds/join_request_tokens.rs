@@ -0,0 +1,123 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Join request tokens registered by the members of a group.
+//!
+//! Members that can invite others register tokens for groups that allow
+//! knocking. The DS stores the group state EAR key of the group wrapped under
+//! the key of the token and unwraps it when a non-member sends a join request
+//! with the token, such that non-members never learn the EAR key. Tokens
+//! expire after [`JOIN_REQUEST_TOKEN_LIFETIME_DAYS`] days.
+
+use phnxtypes::{
+    crypto::ear::{
+        keys::{EncryptedGroupStateEarKey, GroupStateEarKey},
+        EarDecryptable, EarEncryptable,
+    },
+    errors::DsProcessingError,
+    identifiers::QualifiedGroupId,
+    messages::join_request::{JoinRequestParams, RegisterJoinRequestTokenParams},
+    time,
+};
+use sqlx::types::chrono::{DateTime, Duration, Utc};
+use tls_codec::{DeserializeBytes, Serialize};
+
+use crate::errors::StorageError;
+
+use super::Ds;
+
+/// Number of days after which join request tokens expire.
+const JOIN_REQUEST_TOKEN_LIFETIME_DAYS: i64 = 7;
+
+impl Ds {
+    /// Register the token of the given parameters for the group with the
+    /// given id.
+    pub(super) async fn register_join_request_token(
+        &self,
+        qgid: &QualifiedGroupId,
+        params: &RegisterJoinRequestTokenParams,
+        ear_key: &GroupStateEarKey,
+    ) -> Result<(), DsProcessingError> {
+        let wrapped_ear_key = ear_key
+            .encrypt(&params.token_key)
+            .map_err(|_| DsProcessingError::CouldNotEncrypt)?
+            .tls_serialize_detached()
+            .map_err(|_| DsProcessingError::CouldNotEncrypt)?;
+        let expires_at = time::now() + Duration::days(JOIN_REQUEST_TOKEN_LIFETIME_DAYS);
+        self.store_join_request_token(qgid, params, &wrapped_ear_key, expires_at)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Could not store join request token: {:?}", e);
+                DsProcessingError::StorageError
+            })
+    }
+
+    async fn store_join_request_token(
+        &self,
+        qgid: &QualifiedGroupId,
+        params: &RegisterJoinRequestTokenParams,
+        wrapped_ear_key: &[u8],
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        let mut transaction = self.db_pool.begin().await?;
+        sqlx::query!(
+            "DELETE FROM 
+                ds_join_request_tokens 
+            WHERE 
+                group_id = $1 AND expires_at < $2",
+            qgid.group_uuid(),
+            time::now(),
+        )
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO 
+                ds_join_request_tokens 
+                (token_id, group_id, wrapped_ear_key, expires_at)
+            VALUES 
+                ($1, $2, $3, $4)",
+            params.token_id.as_slice(),
+            qgid.group_uuid(),
+            wrapped_ear_key,
+            expires_at,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the group state EAR key of the group the given join request
+    /// is sent to. Fails if the token of the request is unknown, expired,
+    /// belongs to another group or if the request doesn't contain the key of
+    /// the token.
+    pub(super) async fn join_request_ear_key(
+        &self,
+        qgid: &QualifiedGroupId,
+        params: &JoinRequestParams,
+    ) -> Result<GroupStateEarKey, DsProcessingError> {
+        let record = sqlx::query!(
+            "SELECT 
+                group_id, wrapped_ear_key, expires_at
+            FROM 
+                ds_join_request_tokens
+            WHERE 
+                token_id = $1",
+            params.token_id.as_slice(),
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Could not load join request token: {:?}", e);
+            DsProcessingError::StorageError
+        })?
+        .filter(|record| record.group_id == qgid.group_uuid() && record.expires_at > time::now())
+        .ok_or(DsProcessingError::InvalidJoinRequestToken)?;
+        let wrapped_ear_key =
+            EncryptedGroupStateEarKey::tls_deserialize_exact_bytes(&record.wrapped_ear_key)
+                .map_err(|_| DsProcessingError::InvalidJoinRequestToken)?;
+        GroupStateEarKey::decrypt(&params.token_key, &wrapped_ear_key)
+            .map_err(|_| DsProcessingError::InvalidJoinRequestToken)
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use mls_assist::{
+    group::ProcessedAssistedMessage, messages::SerializedMlsMessage,
+    openmls::prelude::ProcessedMessageContent, provider_traits::MlsAssistProvider,
+};
+use phnxtypes::{
+    errors::JoinUpgradedGroupError,
+    messages::client_ds::{InfraAadMessage, InfraAadPayload, JoinUpgradedGroupParams},
+    time::{Duration, TimeStamp},
+};
+use tls_codec::DeserializeBytes;
+
+use super::{
+    group_state::{ClientProfile, DsGroupState, UserProfile},
+    process::USER_EXPIRATION_DAYS,
+    update_room_policy::changes_group_context,
+};
+
+impl DsGroupState {
+    /// Join a client of a member of an upgraded group into the successor
+    /// group.
+    pub(super) fn join_upgraded_group(
+        &mut self,
+        params: JoinUpgradedGroupParams,
+    ) -> Result<SerializedMlsMessage, JoinUpgradedGroupError> {
+        // Process message (but don't apply it yet). This performs mls-assist-level validations.
+        let processed_assisted_message_plus = self
+            .group()
+            .process_assisted_message(self.provider.crypto(), params.external_commit)
+            .map_err(|_| JoinUpgradedGroupError::ProcessingError)?;
+
+        // Perform DS-level validation
+        // Make sure that we have the right message type.
+        let processed_message =
+            if let ProcessedAssistedMessage::Commit(ref processed_message, ref _group_info) =
+                &processed_assisted_message_plus.processed_assisted_message
+            {
+                processed_message
+            } else {
+                // This should be a commit.
+                return Err(JoinUpgradedGroupError::InvalidMessage);
+            };
+
+        // The external commit joining the client into the group should contain only the path.
+        if let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
+            processed_message.content()
+        {
+            if staged_commit.add_proposals().count() > 0
+                || staged_commit.update_proposals().count() > 0
+                || staged_commit.remove_proposals().count() > 0
+                || changes_group_context(staged_commit)
+            {
+                return Err(JoinUpgradedGroupError::InvalidMessage);
+            }
+        } else {
+            return Err(JoinUpgradedGroupError::InvalidMessage);
+        };
+
+        let aad_message = InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())
+            .map_err(|_| JoinUpgradedGroupError::InvalidMessage)?;
+        let InfraAadPayload::JoinUpgradedGroup(aad_payload) = aad_message.into_payload() else {
+            return Err(JoinUpgradedGroupError::InvalidMessage);
+        };
+
+        // The user must have been a member of the upgraded group. Its first
+        // client to join moves it from the migrating users to the members.
+        if !self.user_profiles.contains_key(&params.sender)
+            && !self.migrating_users.contains_key(&params.sender)
+        {
+            return Err(JoinUpgradedGroupError::UnknownSender);
+        }
+
+        // Get the sender's credential s.t. we can identify them later.
+        let sender_credential = processed_message.credential().clone();
+
+        // Finalize processing.
+        self.group.accept_processed_message(
+            self.provider.storage(),
+            processed_assisted_message_plus.processed_assisted_message,
+            Duration::days(USER_EXPIRATION_DAYS),
+        )?;
+
+        // Let's figure out the leaf index of the new member.
+        let sender = self
+            .group()
+            .members()
+            .find_map(|m| {
+                if m.credential == sender_credential {
+                    Some(m.index)
+                } else {
+                    None
+                }
+            })
+            .ok_or(JoinUpgradedGroupError::ProcessingError)?;
+
+        if let Some(user_auth_key) = self.migrating_users.remove(&params.sender) {
+            self.user_profiles.insert(
+                params.sender.clone(),
+                UserProfile {
+                    clients: vec![],
+                    user_auth_key,
+                },
+            );
+        }
+        let user_profile = self
+            .user_profiles
+            .get_mut(&params.sender)
+            .ok_or(JoinUpgradedGroupError::LibraryError)?;
+        user_profile.clients.push(sender);
+
+        let client_profile = ClientProfile {
+            leaf_index: sender,
+            encrypted_client_information: aad_payload.encrypted_client_information,
+            client_queue_config: params.qs_client_reference,
+            activity_time: TimeStamp::now(),
+            activity_epoch: self.group().epoch(),
+        };
+        self.client_profiles.insert(sender, client_profile);
+
+        Ok(processed_assisted_message_plus.serialized_mls_message)
+    }
+}
@@ -0,0 +1,209 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::cmp::Reverse;
+
+use mls_assist::openmls::prelude::LeafNodeIndex;
+use phnxtypes::{
+    messages::{
+        member_profiles::{
+            MemberOrder, MemberProfile, MemberProfilesPage, MemberProfilesParams,
+            MAX_MEMBER_PROFILES_PAGE_SIZE,
+        },
+        room_policy::RoomPolicy,
+    },
+    time::{Duration, TimeStamp},
+};
+
+use super::group_state::{ClientProfile, DsGroupState};
+
+/// Minimum age of a client's recorded activity before a request that doesn't
+/// change the group state updates it. This keeps the DS from rewriting the
+/// group state for every message.
+const ACTIVITY_UPDATE_INTERVAL: Duration = Duration::minutes(15);
+
+impl DsGroupState {
+    /// Records activity of the client with the given leaf index. Returns
+    /// `true` if the client's profile was updated.
+    ///
+    /// Unless `force` is set, the activity is only updated if the recorded one
+    /// is older than [`ACTIVITY_UPDATE_INTERVAL`].
+    pub(super) fn record_activity(&mut self, leaf_index: LeafNodeIndex, force: bool) -> bool {
+        let epoch = self.group().epoch();
+        let Some(client_profile) = self.client_profiles.get_mut(&leaf_index) else {
+            return false;
+        };
+        if !force
+            && !client_profile
+                .activity_time
+                .has_expired(ACTIVITY_UPDATE_INTERVAL)
+        {
+            return false;
+        }
+        client_profile.activity_time = TimeStamp::now();
+        client_profile.activity_epoch = epoch;
+        true
+    }
+
+    /// Returns a page of the profiles of the group's members, aggregated per
+    /// user and sorted in the requested order.
+    pub(super) fn member_profiles(&self, params: &MemberProfilesParams) -> MemberProfilesPage {
+        let admins =
+            RoomPolicy::from_extensions(self.group().group_info().group_context().extensions())
+                .map(|room_policy| room_policy.admins)
+                .unwrap_or_default();
+
+        // Users that haven't set their user key yet can't be admins.
+        let users = self
+            .user_profiles
+            .iter()
+            .map(|(user_key_hash, user_profile)| {
+                (admins.contains(user_key_hash), &user_profile.clients)
+            })
+            .chain(self.unmerged_users.iter().map(|clients| (false, clients)));
+        let members = users
+            .filter_map(|(is_admin, clients)| self.member_profile(is_admin, clients))
+            .collect();
+        sort_and_page(members, params)
+    }
+
+    /// Aggregates the profiles of the given clients. Returns `None` if none of
+    /// the clients has a profile.
+    fn member_profile(&self, is_admin: bool, clients: &[LeafNodeIndex]) -> Option<MemberProfile> {
+        let client_profiles: Vec<&ClientProfile> = clients
+            .iter()
+            .filter_map(|leaf_index| self.client_profiles.get(leaf_index))
+            .collect();
+        let latest_activity = client_profiles
+            .iter()
+            .max_by_key(|client_profile| client_profile.activity_time)?;
+        let mut clients: Vec<LeafNodeIndex> = client_profiles
+            .iter()
+            .map(|client_profile| client_profile.leaf_index)
+            .collect();
+        clients.sort();
+        Some(MemberProfile {
+            clients,
+            is_admin,
+            last_activity_time: latest_activity.activity_time,
+            last_activity_epoch: latest_activity.activity_epoch,
+        })
+    }
+}
+
+/// Sorts the members in the requested order and returns the requested page.
+fn sort_and_page(
+    mut members: Vec<MemberProfile>,
+    params: &MemberProfilesParams,
+) -> MemberProfilesPage {
+    match params.order {
+        MemberOrder::Role => members.sort_by_key(|member| {
+            (
+                !member.is_admin,
+                Reverse(member.last_activity_time),
+                member.clients[0],
+            )
+        }),
+        MemberOrder::RecentActivity => {
+            members.sort_by_key(|member| (Reverse(member.last_activity_time), member.clients[0]))
+        }
+    }
+
+    let offset = params.offset as usize;
+    let limit = params.limit.min(MAX_MEMBER_PROFILES_PAGE_SIZE) as usize;
+    let end = offset.saturating_add(limit).min(members.len());
+    let next_offset = (end < members.len()).then_some(end as u32);
+    let members = members
+        .into_iter()
+        .skip(offset)
+        .take(end.saturating_sub(offset))
+        .collect();
+    MemberProfilesPage {
+        members,
+        next_offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mls_assist::openmls::prelude::{GroupEpoch, GroupId};
+
+    use super::*;
+
+    fn member(leaf_index: u32, is_admin: bool, last_activity: i64) -> MemberProfile {
+        MemberProfile {
+            clients: vec![LeafNodeIndex::new(leaf_index)],
+            is_admin,
+            last_activity_time: TimeStamp::from(last_activity),
+            last_activity_epoch: GroupEpoch::from(0),
+        }
+    }
+
+    fn params(order: MemberOrder, offset: u32, limit: u32) -> MemberProfilesParams {
+        MemberProfilesParams {
+            group_id: GroupId::from_slice(b"group"),
+            sender: LeafNodeIndex::new(0),
+            order,
+            offset,
+            limit,
+        }
+    }
+
+    fn leaf_indices(page: &MemberProfilesPage) -> Vec<u32> {
+        page.members
+            .iter()
+            .map(|member| member.clients[0].u32())
+            .collect()
+    }
+
+    fn members() -> Vec<MemberProfile> {
+        vec![
+            member(0, false, 10),
+            member(1, true, 5),
+            member(2, false, 30),
+            member(3, true, 20),
+            // Ties are broken by the leaf index
+            member(4, false, 30),
+        ]
+    }
+
+    #[test]
+    fn members_are_sorted_by_role_and_activity() {
+        let page = sort_and_page(members(), &params(MemberOrder::Role, 0, 10));
+        assert_eq!(leaf_indices(&page), [3, 1, 2, 4, 0]);
+        assert_eq!(page.next_offset, None);
+
+        let page = sort_and_page(members(), &params(MemberOrder::RecentActivity, 0, 10));
+        assert_eq!(leaf_indices(&page), [2, 4, 3, 0, 1]);
+    }
+
+    #[test]
+    fn members_are_paged() {
+        let order = MemberOrder::RecentActivity;
+        let page = sort_and_page(members(), &params(order, 0, 2));
+        assert_eq!(leaf_indices(&page), [2, 4]);
+        assert_eq!(page.next_offset, Some(2));
+        let page = sort_and_page(members(), &params(order, 2, 2));
+        assert_eq!(leaf_indices(&page), [3, 0]);
+        assert_eq!(page.next_offset, Some(4));
+        let page = sort_and_page(members(), &params(order, 4, 2));
+        assert_eq!(leaf_indices(&page), [1]);
+        assert_eq!(page.next_offset, None);
+
+        // Offsets past the end return an empty page
+        let page = sort_and_page(members(), &params(order, 7, 2));
+        assert!(page.members.is_empty());
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn page_size_is_capped() {
+        let members = (0..MAX_MEMBER_PROFILES_PAGE_SIZE + 10)
+            .map(|leaf_index| member(leaf_index, false, 0))
+            .collect();
+        let page = sort_and_page(members, &params(MemberOrder::Role, 0, u32::MAX));
+        assert_eq!(page.members.len(), MAX_MEMBER_PROFILES_PAGE_SIZE as usize);
+        assert_eq!(page.next_offset, Some(MAX_MEMBER_PROFILES_PAGE_SIZE));
+    }
+}
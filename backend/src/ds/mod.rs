@@ -10,20 +10,53 @@ use sqlx::PgPool;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-use crate::infra_service::{InfraService, ServiceCreationError};
+use crate::{
+    infra_service::{InfraService, ServiceCreationError},
+    replay_cache::ReplayCache,
+    settings::{
+        AttachmentScanningSettings, SendRateLimitSettings, SpamThrottleSettings,
+        WelcomeInfoCacheSettings,
+    },
+};
 
 mod add_clients;
 mod add_users;
+mod admin;
 mod delete_group;
 pub mod group_state;
+mod idempotency;
 mod join_connection_group;
 mod join_group;
+mod join_request_limiter;
+mod join_request_tokens;
+mod join_upgraded_group;
+mod member_profiles;
 pub mod process;
+mod propose_client_update;
 mod remove_clients;
 mod remove_users;
 mod resync_client;
 mod self_remove_client;
+mod send_rate_limit;
+mod spam_throttle;
+mod storage;
 mod update_client;
+mod update_group_data;
+mod update_room_policy;
+mod upgrade_group;
+mod welcome_info_cache;
+
+use join_request_limiter::JoinRequestLimiter;
+use send_rate_limit::SendRateLimiter;
+use spam_throttle::SpamThrottle;
+pub use spam_throttle::SpamThrottleMetricsSnapshot;
+pub use storage::{
+    scanner::{AttachmentScanner, ClamAvScanner, ScanError, ScanVerdict},
+    ObjectStoreError, Retention, StorageBackend, StorageProvider,
+};
+use storage::{AttachmentStorage, PostgresStorageProvider};
+use welcome_info_cache::WelcomeInfoCache;
+pub use welcome_info_cache::WelcomeInfoCacheMetricsSnapshot;
 
 /// Number of days after its last use upon which a group state is considered
 /// expired.
@@ -33,6 +66,12 @@ pub struct Ds {
     own_domain: Fqdn,
     reserved_group_ids: Arc<Mutex<HashSet<Uuid>>>,
     db_pool: PgPool,
+    spam_throttle: SpamThrottle,
+    send_rate_limiter: SendRateLimiter,
+    join_request_limiter: JoinRequestLimiter,
+    welcome_info_cache: WelcomeInfoCache,
+    attachment_storage: AttachmentStorage,
+    replay_cache: ReplayCache,
 }
 
 #[derive(Debug)]
@@ -44,7 +83,15 @@ impl InfraService for Ds {
         let ds = Self {
             own_domain: domain,
             reserved_group_ids: Arc::new(Mutex::new(HashSet::new())),
+            attachment_storage: AttachmentStorage::new(Arc::new(PostgresStorageProvider::new(
+                db_pool.clone(),
+            ))),
             db_pool,
+            spam_throttle: SpamThrottle::default(),
+            send_rate_limiter: SendRateLimiter::default(),
+            join_request_limiter: JoinRequestLimiter::default(),
+            welcome_info_cache: WelcomeInfoCache::default(),
+            replay_cache: ReplayCache::default(),
         };
 
         Ok(ds)
@@ -52,6 +99,56 @@ impl InfraService for Ds {
 }
 
 impl Ds {
+    /// Replace the default thresholds of the sender throttle.
+    pub fn with_spam_throttle(mut self, settings: SpamThrottleSettings) -> Self {
+        self.spam_throttle = SpamThrottle::new(settings);
+        self
+    }
+
+    /// Replace the default rate limit for senders.
+    pub fn with_send_rate_limit(mut self, settings: SendRateLimitSettings) -> Self {
+        self.send_rate_limiter = SendRateLimiter::new(settings);
+        self
+    }
+
+    /// Returns the counters of the sender throttle.
+    pub async fn spam_throttle_metrics(&self) -> SpamThrottleMetricsSnapshot {
+        self.spam_throttle.metrics().await
+    }
+
+    /// Replace the default configuration of the welcome info cache.
+    pub fn with_welcome_info_cache(mut self, settings: WelcomeInfoCacheSettings) -> Self {
+        self.welcome_info_cache = WelcomeInfoCache::new(settings);
+        self
+    }
+
+    /// Returns the counters of the welcome info cache.
+    pub async fn welcome_info_cache_metrics(&self) -> WelcomeInfoCacheMetricsSnapshot {
+        self.welcome_info_cache.metrics().await
+    }
+
+    /// Store the content of attachments in the given storage.
+    pub fn with_storage(mut self, storage: &StorageBackend) -> Self {
+        let provider = storage.provider(&self.db_pool);
+        self.attachment_storage.set_provider(provider);
+        self
+    }
+
+    /// Scan uploaded attachments with clamd if enabled in the settings.
+    pub fn with_attachment_scanning(self, settings: AttachmentScanningSettings) -> Self {
+        let scanner = settings
+            .enabled
+            .then(|| Arc::new(ClamAvScanner::new(&settings)) as Arc<dyn AttachmentScanner>);
+        self.with_attachment_scanner(scanner)
+    }
+
+    /// Replace the scanner for uploaded attachments. `None` disables
+    /// scanning.
+    pub fn with_attachment_scanner(mut self, scanner: Option<Arc<dyn AttachmentScanner>>) -> Self {
+        self.attachment_storage.set_scanner(scanner);
+        self
+    }
+
     async fn reserve_group_id(&self, group_id: Uuid) -> bool {
         let mut reserved_group_ids = self.reserved_group_ids.lock().await;
         reserved_group_ids.insert(group_id)
@@ -166,11 +166,19 @@ use phnxtypes::{
         ear::keys::EncryptedSignatureEarKey,
         signatures::{keys::LeafVerifyingKey, signable::Verifiable},
     },
-    errors::DsProcessingError,
-    identifiers::QualifiedGroupId,
-    messages::client_ds::{
-        CreateGroupParams, DsMessageTypeIn, DsRequestParams, DsSender, QsQueueMessagePayload,
-        VerifiableClientToDsMessage,
+    errors::{ClientUpdateError, DsProcessingError},
+    identifiers::{AttachmentId, QualifiedGroupId},
+    messages::{
+        attachments::{AttachmentBlocked, AttachmentDownload},
+        client_ds::{
+            CreateGroupParams, DsMessageTypeIn, DsRequestParams, DsSender, GroupEpochInfo,
+            QsQueueMessagePayload, VerifiableClientToDsMessage,
+        },
+        member_profiles::MemberProfilesPage,
+        room_policy::{JoinRule, RoomPolicy},
+        room_upgrade::GroupPredecessor,
+        server_info::ClientVersion,
+        QueuePriority,
     },
     time::TimeStamp,
 };
@@ -178,27 +186,45 @@ use phnxtypes::{
 use crate::{
     ds::ReservedGroupId,
     errors::StorageError,
-    messages::intra_backend::{DsFanOutMessage, DsFanOutPayload},
+    messages::intra_backend::{DsFanOutBatch, DsFanOutMessage, DsFanOutPayload},
     qs::QsConnector,
+    replay_cache::ReplayError,
 };
 
 use super::{
     group_state::{DsGroupState, StorableDsGroupData},
+    idempotency::AppliedOperation,
+    spam_throttle::ThrottleDecision,
+    storage::{AttachmentStorageError, ObjectStoreError, Retention},
+    welcome_info_cache::SerializedRatchetTree,
     Ds,
 };
 
 pub const USER_EXPIRATION_DAYS: i64 = 90;
 pub(super) type Provider = MlsAssistRustCrypto<PhnxCodec>;
 
+/// Oldest client version that handles [`DsProcessingError::StaleEpoch`] by
+/// catching up and sending again. Application messages of past epochs are
+/// still accepted from older clients and from clients that don't announce
+/// their version.
+pub const STALE_EPOCH_MIN_CLIENT_VERSION: ClientVersion = ClientVersion::new(0, 2, 0);
+
+/// Whether application messages of past epochs are rejected for a client that
+/// announced the given version.
+fn rejects_stale_epochs(client_version: Option<&ClientVersion>) -> bool {
+    client_version.is_some_and(|version| *version >= STALE_EPOCH_MIN_CLIENT_VERSION)
+}
+
 impl Ds {
     pub async fn process<Q: QsConnector>(
         &self,
         qs_connector: &Q,
         message: DsMessageTypeIn,
+        client_version: Option<ClientVersion>,
     ) -> Result<DsProcessResponse, DsProcessingError> {
         match message {
             DsMessageTypeIn::Group(group_message) => {
-                self.process_group_message(qs_connector, group_message)
+                self.process_group_message(qs_connector, group_message, client_version.as_ref())
                     .await
             }
             DsMessageTypeIn::NonGroup => self.request_group_id().await.map_err(|e| {
@@ -212,8 +238,10 @@ impl Ds {
         &self,
         qs_connector: &Q,
         message: VerifiableClientToDsMessage,
+        client_version: Option<&ClientVersion>,
     ) -> Result<DsProcessResponse, DsProcessingError> {
-        let ear_key = message.ear_key().clone();
+        let idempotency_key = message.idempotency_key().clone();
+        let freshness = message.freshness().clone();
 
         // Verify group id
         let qgid = QualifiedGroupId::try_from(message.group_id().clone()).map_err(|_| {
@@ -226,6 +254,16 @@ impl Ds {
             return Err(DsProcessingError::GroupNotFound);
         }
 
+        // Non-members don't know the EAR key of the group, so join requests
+        // carry the key of a join request token with which the DS unwraps it.
+        let ear_key = match message.join_request_params() {
+            Some(join_request_params) => {
+                self.join_request_ear_key(&qgid, join_request_params)
+                    .await?
+            }
+            None => message.ear_key().clone(),
+        };
+
         enum GroupData {
             ExistingGroup(StorableDsGroupData),
             NewGroup(ReservedGroupId),
@@ -255,6 +293,25 @@ impl Ds {
             let provider = Provider::default();
             let group = Group::new(&provider, group_info.clone(), leaf_node.clone())
                 .map_err(|_| DsProcessingError::InvalidMessage)?;
+            // The creator must be the only admin of the new room. The
+            // successor of an upgraded room keeps the policy of the upgraded
+            // room instead, which is checked when the rooms are linked.
+            let extensions = group.group_info().group_context().extensions();
+            let room_policy = RoomPolicy::from_extensions(extensions)
+                .map_err(|_| DsProcessingError::InvalidRoomPolicy)?;
+            let is_successor = GroupPredecessor::from_extensions(extensions)
+                .map_err(|_| DsProcessingError::InvalidMessage)?
+                .is_some();
+            let creator = creator_user_auth_key.hash();
+            let valid_policy = if is_successor {
+                room_policy.is_admin(&creator)
+            } else {
+                room_policy.is_valid_initial_policy(&creator)
+            };
+            if !valid_policy {
+                tracing::warn!("Invalid room policy for new group");
+                return Err(DsProcessingError::InvalidRoomPolicy);
+            }
             let group_state = DsGroupState::new(
                 provider,
                 group,
@@ -330,6 +387,42 @@ impl Ds {
                 .ok_or(DsProcessingError::InvalidSenderType)?,
         };
 
+        // Signed requests must be fresh and must not be replayed. Anonymous
+        // requests are not signed, so their freshness can't be trusted.
+        if !matches!(verified_message.ds_sender(), DsSender::Anonymous) {
+            self.replay_cache
+                .check(&self.db_pool, &freshness)
+                .await
+                .map_err(|e| match e {
+                    ReplayError::Storage(e) => {
+                        tracing::warn!("Could not check replay cache: {:?}", e);
+                        DsProcessingError::StorageError
+                    }
+                    _ => DsProcessingError::ReplayedRequest,
+                })?;
+        }
+
+        // If the sender has already sent this request and it was applied, we
+        // don't process it again, but tell the sender that it was applied.
+        let ds_sender = verified_message.ds_sender();
+        if !idempotency_key.is_empty() && matches!(group_data, GroupData::ExistingGroup(_)) {
+            let applied_timestamp = AppliedOperation::load_fanout_timestamp(
+                &self.db_pool,
+                qgid.group_uuid(),
+                &idempotency_key,
+                group_state.group().epoch(),
+                &ds_sender,
+            )
+            .await
+            .map_err(|e| {
+                tracing::warn!("Could not load applied operation: {:?}", e);
+                DsProcessingError::StorageError
+            })?;
+            if let Some(fanout_timestamp) = applied_timestamp {
+                return Ok(DsProcessResponse::AlreadyApplied(fanout_timestamp));
+            }
+        }
+
         let sender = verified_message.mls_sender().cloned();
 
         // We always want to distribute to all members that are group members
@@ -377,18 +470,106 @@ impl Ds {
             })
             .collect();
 
+        // Upgraded groups only point to their successor. Members can still
+        // catch up and access the history, but the group doesn't change
+        // anymore.
+        if matches!(
+            verified_message,
+            DsRequestParams::AddUsers(_)
+                | DsRequestParams::RemoveUsers(_)
+                | DsRequestParams::UpdateClient(_)
+                | DsRequestParams::JoinGroup(_)
+                | DsRequestParams::JoinConnectionGroup(_)
+                | DsRequestParams::AddClients(_)
+                | DsRequestParams::RemoveClients(_)
+                | DsRequestParams::ResyncClient(_)
+                | DsRequestParams::SelfRemoveClient(_)
+                | DsRequestParams::ProposeClientUpdate(_)
+                | DsRequestParams::SendMessage(_)
+                | DsRequestParams::DispatchEvent(_)
+                | DsRequestParams::UpdateRoomPolicy(_)
+                | DsRequestParams::JoinRequest(_)
+                | DsRequestParams::RegisterJoinRequestToken(_)
+                | DsRequestParams::UploadAttachment(_)
+                | DsRequestParams::UploadArchive(_)
+                | DsRequestParams::UploadViewOnceAttachment(_)
+                | DsRequestParams::UpdateGroupData(_)
+                | DsRequestParams::UpgradeGroup(_)
+                | DsRequestParams::JoinUpgradedGroup(_)
+        ) && group_state.is_upgraded()?
+        {
+            return Err(DsProcessingError::GroupUpgraded);
+        }
+
+        // In broadcast rooms, only admins can commit. Externally committing
+        // endpoints are exempt, since the joining client isn't a member yet.
+        // Other members can still update their own leaf (see
+        // `DsGroupState::update_client`).
+        if matches!(
+            verified_message,
+            DsRequestParams::AddUsers(_)
+                | DsRequestParams::RemoveUsers(_)
+                | DsRequestParams::AddClients(_)
+                | DsRequestParams::RemoveClients(_)
+                | DsRequestParams::DeleteGroup(_)
+                | DsRequestParams::UpdateGroupData(_)
+        ) {
+            let sender_index = sender_index_option.ok_or(DsProcessingError::UnknownSender)?;
+            if !group_state.can_commit(sender_index)? {
+                return Err(DsProcessingError::CommittingNotAllowed);
+            }
+        }
+
         let mut group_state_has_changed = true;
+        // Handshake messages and welcomes always go into the high priority
+        // lane. Only application messages are tagged by the sender.
+        let mut fan_out_priority = QueuePriority::High;
         // For now, we just process directly.
         // TODO: We might want to realize this via a trait.
         let (ds_fanout_payload, response, fan_out_messages) = match verified_message {
             // ======= Non-Commiting Endpoints =======
             DsRequestParams::WelcomeInfo(welcome_info_params) => {
-                let ratchet_tree = group_state
-                    .welcome_info(welcome_info_params)
-                    .ok_or(DsProcessingError::NoWelcomeInfoFound)?;
+                let epoch = welcome_info_params.epoch;
+                let cached_tree = self
+                    .welcome_info_cache
+                    .get(
+                        qgid.group_uuid(),
+                        epoch,
+                        welcome_info_params.sender.as_slice(),
+                    )
+                    .await;
+                let serialized_tree = match cached_tree {
+                    Some(serialized_tree) => {
+                        // The past group states are only consulted on a miss,
+                        // so the group state doesn't change.
+                        group_state_has_changed = false;
+                        serialized_tree
+                    }
+                    None => {
+                        // Only trees of the current epoch are cached, since
+                        // its members are known.
+                        let members = (epoch == group_state.group().epoch()).then(|| {
+                            group_state
+                                .group()
+                                .members()
+                                .map(|member| member.signature_key)
+                                .collect()
+                        });
+                        let ratchet_tree = group_state
+                            .welcome_info(welcome_info_params)
+                            .ok_or(DsProcessingError::NoWelcomeInfoFound)?;
+                        self.welcome_info_cache
+                            .insert(qgid.group_uuid(), epoch, ratchet_tree, members)
+                            .await
+                            .map_err(|e| {
+                                tracing::error!("Could not serialize ratchet tree: {:?}", e);
+                                DsProcessingError::ProcessingError
+                            })?
+                    }
+                };
                 (
                     None,
-                    DsProcessResponse::WelcomeInfo(ratchet_tree.clone()),
+                    DsProcessResponse::WelcomeInfo(serialized_tree),
                     vec![],
                 )
             }
@@ -407,6 +588,14 @@ impl Ds {
                     vec![],
                 )
             }
+            DsRequestParams::GroupEpochInfo(_) => {
+                group_state_has_changed = false;
+                (
+                    None,
+                    DsProcessResponse::GroupEpochInfo(group_state.group_epoch_info()),
+                    vec![],
+                )
+            }
             DsRequestParams::ConnectionGroupInfo(_) => {
                 group_state_has_changed = false;
                 (
@@ -415,6 +604,115 @@ impl Ds {
                     vec![],
                 )
             }
+            // ======= Attachments =======
+            DsRequestParams::UploadAttachment(ref upload_attachment_params)
+            | DsRequestParams::UploadArchive(ref upload_attachment_params)
+            | DsRequestParams::UploadViewOnceAttachment(ref upload_attachment_params) => {
+                group_state_has_changed = false;
+                let retention = match verified_message {
+                    DsRequestParams::UploadArchive(_) => Retention::Archive,
+                    DsRequestParams::UploadViewOnceAttachment(_) => Retention::ViewOnce,
+                    _ => Retention::Ephemeral,
+                };
+                let uploader = group_state.user_key_hash(upload_attachment_params.sender);
+                let uploaded = self
+                    .attachment_storage
+                    .upload(
+                        &self.db_pool,
+                        qgid.group_uuid(),
+                        uploader,
+                        &upload_attachment_params.content,
+                        retention,
+                    )
+                    .await
+                    .map_err(|e| match e {
+                        AttachmentStorageError::Scan(e) => {
+                            tracing::warn!("Could not scan attachment: {:?}", e);
+                            DsProcessingError::AttachmentScanFailed
+                        }
+                        AttachmentStorageError::ObjectStore(ObjectStoreError::QuotaExceeded) => {
+                            tracing::warn!("Attachment storage quota exceeded");
+                            DsProcessingError::AttachmentQuotaExceeded
+                        }
+                        AttachmentStorageError::ObjectStore(e) => {
+                            tracing::error!("Could not store attachment: {:?}", e);
+                            DsProcessingError::StorageError
+                        }
+                    })?;
+                let mut notifications = vec![];
+                if let Some(reason) = uploaded.blocked {
+                    tracing::info!(
+                        attachment_id = %uploaded.attachment_id,
+                        reason = %reason,
+                        "Blocked attachment"
+                    );
+                    // Let the uploader know that the attachment won't be
+                    // delivered.
+                    let client_reference = group_state
+                        .client_profiles
+                        .get(&upload_attachment_params.sender)
+                        .ok_or(DsProcessingError::UnknownSender)?
+                        .client_queue_config
+                        .clone();
+                    let payload = QsQueueMessagePayload::try_from(AttachmentBlocked {
+                        group_id: upload_attachment_params.group_id.clone(),
+                        attachment_id: uploaded.attachment_id,
+                    })
+                    .map_err(|_| DsProcessingError::ProcessingError)?;
+                    notifications.push(DsFanOutMessage {
+                        payload: DsFanOutPayload::QueueMessage(payload),
+                        client_reference,
+                        priority: QueuePriority::Normal,
+                    });
+                }
+                (
+                    None,
+                    DsProcessResponse::AttachmentUploaded(uploaded.attachment_id),
+                    notifications,
+                )
+            }
+            DsRequestParams::DownloadAttachment(download_attachment_params) => {
+                group_state_has_changed = false;
+                let downloader = group_state.user_key_hash(download_attachment_params.sender);
+                let download = self
+                    .attachment_storage
+                    .download(
+                        &self.db_pool,
+                        qgid.group_uuid(),
+                        download_attachment_params.attachment_id,
+                        downloader,
+                    )
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("Could not load attachment: {:?}", e);
+                        DsProcessingError::StorageError
+                    })?
+                    .ok_or(DsProcessingError::AttachmentNotFound)?;
+                (None, DsProcessResponse::Attachment(download), vec![])
+            }
+            DsRequestParams::DeleteAttachments(delete_attachments_params) => {
+                group_state_has_changed = false;
+                // The sender was authenticated via its user key above.
+                let deleted = self
+                    .attachment_storage
+                    .delete_uploaded_by(
+                        &self.db_pool,
+                        qgid.group_uuid(),
+                        &delete_attachments_params.sender,
+                    )
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("Could not delete attachments: {:?}", e);
+                        DsProcessingError::StorageError
+                    })?;
+                (None, DsProcessResponse::AttachmentsDeleted(deleted), vec![])
+            }
+            // ======= Member profiles =======
+            DsRequestParams::MemberProfiles(member_profiles_params) => {
+                group_state_has_changed = false;
+                let page = group_state.member_profiles(&member_profiles_params);
+                (None, DsProcessResponse::MemberProfiles(page), vec![])
+            }
             // ======= Committing Endpoints =======
             DsRequestParams::AddUsers(add_users_params) => {
                 // This function is async and needs the qs provider, because it
@@ -430,7 +728,16 @@ impl Ds {
                 prepare_result(group_message, vec![])
             }
             DsRequestParams::UpdateClient(update_client_params) => {
-                let group_message = group_state.update_client(update_client_params)?;
+                let sender_index = sender_index_option.ok_or(DsProcessingError::UnknownSender)?;
+                let sender_can_commit = group_state.can_commit(sender_index)?;
+                let group_message = group_state
+                    .update_client(update_client_params, sender_can_commit)
+                    .map_err(|error| match error {
+                        ClientUpdateError::CommittingNotAllowed => {
+                            DsProcessingError::CommittingNotAllowed
+                        }
+                        error => error.into(),
+                    })?;
                 prepare_result(group_message, vec![])
             }
             DsRequestParams::AddClients(add_clients_params) => {
@@ -460,19 +767,146 @@ impl Ds {
                 let group_message = group_state.delete_group(delete_group)?;
                 prepare_result(group_message, vec![])
             }
+            DsRequestParams::UpdateRoomPolicy(update_room_policy_params) => {
+                let group_message = group_state.update_room_policy(update_room_policy_params)?;
+                prepare_result(group_message, vec![])
+            }
+            DsRequestParams::UpdateGroupData(update_group_data_params) => {
+                let group_message = group_state.update_group_data(update_group_data_params)?;
+                prepare_result(group_message, vec![])
+            }
+            DsRequestParams::UpgradeGroup(upgrade_group_params) => {
+                let successor_ear_key = upgrade_group_params.successor_ear_key.clone();
+                let (group_message, tombstone) = group_state.upgrade_group(upgrade_group_params)?;
+                self.link_successor_group(&group_state, &tombstone, &successor_ear_key)
+                    .await?;
+                prepare_result(group_message, vec![])
+            }
+            DsRequestParams::JoinUpgradedGroup(join_upgraded_group_params) => {
+                let group_message = group_state.join_upgraded_group(join_upgraded_group_params)?;
+                prepare_result(group_message, vec![])
+            }
             // ======= Proposal Endpoints =======
             DsRequestParams::SelfRemoveClient(self_remove_client_params) => {
                 let group_message = group_state.self_remove_client(self_remove_client_params)?;
                 prepare_result(group_message, vec![])
             }
+            DsRequestParams::ProposeClientUpdate(propose_client_update_params) => {
+                let group_message =
+                    group_state.propose_client_update(propose_client_update_params)?;
+                prepare_result(group_message, vec![])
+            }
             // ======= Sending messages =======
             DsRequestParams::SendMessage(send_message_params) => {
                 // There is nothing to process here, so we just stick the
                 // message into a QueueMessagePayload for distribution.
                 group_state_has_changed = false;
+                fan_out_priority = send_message_params.priority;
+                let sender_index = sender_index_option.ok_or(DsProcessingError::UnknownSender)?;
+                if !group_state.can_send(sender_index, send_message_params.priority)? {
+                    return Err(DsProcessingError::SendingNotAllowed);
+                }
+                // Members can't decrypt messages of past epochs for long, so
+                // the sender has to catch up first.
+                if send_message_params.message.epoch() != group_state.group().epoch()
+                    && rejects_stale_epochs(client_version)
+                {
+                    return Err(DsProcessingError::StaleEpoch);
+                }
+                let sender_key = group_state
+                    .group()
+                    .leaf(sender_index)
+                    .ok_or(DsProcessingError::UnknownSender)?
+                    .signature_key()
+                    .as_slice()
+                    .to_vec();
+                self.send_rate_limiter
+                    .check(&sender_key)
+                    .await
+                    .map_err(DsProcessingError::RateLimited)?;
+                let decision = self
+                    .spam_throttle
+                    .check(
+                        &sender_key,
+                        qgid.group_uuid(),
+                        group_state.user_profiles.len(),
+                        destination_clients.len(),
+                    )
+                    .await;
+                match decision {
+                    ThrottleDecision::Allow => {}
+                    ThrottleDecision::Delay(delay) => {
+                        return Err(DsProcessingError::RateLimited(delay))
+                    }
+                    ThrottleDecision::Reject => return Err(DsProcessingError::SenderThrottled),
+                }
                 let group_message = send_message_params.message.into_serialized_mls_message();
                 prepare_result(group_message, vec![])
             }
+            // ======= Join requests =======
+            DsRequestParams::JoinRequest(join_request_params) => {
+                group_state_has_changed = false;
+                fan_out_priority = QueuePriority::Normal;
+                let room_policy = RoomPolicy::from_extensions(
+                    group_state
+                        .group()
+                        .group_info()
+                        .group_context()
+                        .extensions(),
+                )
+                .map_err(|_| DsProcessingError::ProcessingError)?;
+                if room_policy.join_rule != JoinRule::Knock {
+                    return Err(DsProcessingError::JoinRequestsNotAllowed);
+                }
+                if !self
+                    .join_request_limiter
+                    .check(qgid.group_uuid(), join_request_params.token_id.as_slice())
+                    .await
+                {
+                    return Err(DsProcessingError::TooManyJoinRequests);
+                }
+                let payload = QsQueueMessagePayload::try_from(join_request_params)
+                    .map_err(|_| DsProcessingError::ProcessingError)?;
+                if room_policy.is_broadcast() {
+                    // Only admins can add the user in broadcast rooms, so
+                    // there is no need to bother the other members.
+                    let admin_messages = group_state
+                        .admin_client_references(&room_policy)
+                        .into_iter()
+                        .map(|client_reference| DsFanOutMessage {
+                            payload: DsFanOutPayload::QueueMessage(payload.clone()),
+                            client_reference,
+                            priority: QueuePriority::Normal,
+                        })
+                        .collect();
+                    (None, DsProcessResponse::Ok, admin_messages)
+                } else {
+                    (
+                        Some(DsFanOutPayload::QueueMessage(payload)),
+                        DsProcessResponse::Ok,
+                        vec![],
+                    )
+                }
+            }
+            DsRequestParams::RegisterJoinRequestToken(register_token_params) => {
+                group_state_has_changed = false;
+                let room_policy = RoomPolicy::from_extensions(
+                    group_state
+                        .group()
+                        .group_info()
+                        .group_context()
+                        .extensions(),
+                )
+                .map_err(|_| DsProcessingError::ProcessingError)?;
+                if room_policy.join_rule != JoinRule::Knock
+                    || !room_policy.can_invite(&register_token_params.sender)
+                {
+                    return Err(DsProcessingError::JoinRequestsNotAllowed);
+                }
+                self.register_join_request_token(&qgid, &register_token_params, &ear_key)
+                    .await?;
+                (None, DsProcessResponse::Ok, vec![])
+            }
             // ======= Events =======
             DsRequestParams::DispatchEvent(dispatch_event_params) => {
                 group_state_has_changed = false;
@@ -481,7 +915,13 @@ impl Ds {
             }
         };
 
-        if group_state_has_changed {
+        // Record the sender's activity. Requests that don't change the group
+        // state otherwise only update it if the recorded activity is stale.
+        let activity_has_changed = sender_index_option.is_some_and(|sender_index| {
+            group_state.record_activity(sender_index, group_state_has_changed)
+        });
+
+        if group_state_has_changed || activity_has_changed {
             // ... before we distribute the message, we encrypt ...
             let encrypted_group_state = group_state.encrypt(&ear_key).map_err(|e| {
                 tracing::error!("Could not serialize group state: {:?}", e);
@@ -490,6 +930,26 @@ impl Ds {
 
             // ... and store the modified group state.
             match group_data {
+                // Only the activity changed. The group state was loaded
+                // without a lock, so it is only stored if no other request,
+                // e.g. a commit, changed it in the meantime. Otherwise the
+                // activity is recorded with a later request.
+                GroupData::ExistingGroup(mut group_data) if !group_state_has_changed => {
+                    let loaded_state = std::mem::replace(
+                        &mut group_data.encrypted_group_state,
+                        encrypted_group_state,
+                    );
+                    let stored = group_data
+                        .update_if_unchanged(&self.db_pool, &qgid, &loaded_state)
+                        .await
+                        .map_err(|e| {
+                            tracing::error!("Could not update group state: {:?}", e);
+                            DsProcessingError::StorageError
+                        })?;
+                    if !stored {
+                        tracing::debug!("Group state changed concurrently, dropping activity");
+                    }
+                }
                 GroupData::ExistingGroup(mut group_data) => {
                     group_data.encrypted_group_state = encrypted_group_state;
                     group_data.update(&self.db_pool).await.map_err(|e| {
@@ -512,18 +972,50 @@ impl Ds {
             };
         }
 
+        if group_state_has_changed {
+            // Cached welcome info of this group might be stale now.
+            self.welcome_info_cache.invalidate(qgid.group_uuid()).await;
+
+            // Remember the operation so that retries of the request are not
+            // applied twice.
+            if let DsProcessResponse::FanoutTimestamp(fanout_timestamp) = &response {
+                if !idempotency_key.is_empty() {
+                    let record_result = match AppliedOperation::new(
+                        qgid.group_uuid(),
+                        idempotency_key,
+                        group_state.group().epoch(),
+                        &ds_sender,
+                        *fanout_timestamp,
+                    ) {
+                        Ok(applied_operation) => applied_operation.store(&self.db_pool).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = record_result {
+                        // The operation was applied, so we don't fail the
+                        // request.
+                        tracing::warn!("Could not record applied operation: {:?}", e);
+                    }
+                }
+            }
+        }
+
         // Distribute FanOutMessages
         if let Some(c2c_message) = ds_fanout_payload {
-            for client_reference in destination_clients {
-                let ds_fan_out_msg = DsFanOutMessage {
-                    payload: c2c_message.clone(),
-                    client_reference,
-                };
-
-                qs_connector.dispatch(ds_fan_out_msg).await.map_err(|e| {
-                    tracing::warn!("Could not distribute message: {:?}", e);
-                    DsProcessingError::DistributionError
-                })?;
+            let batch = DsFanOutBatch {
+                payload: c2c_message,
+                client_references: destination_clients,
+                priority: fan_out_priority,
+            };
+            let failed = qs_connector.dispatch_batch(batch).await.map_err(|e| {
+                tracing::warn!("Could not distribute message: {:?}", e);
+                DsProcessingError::DistributionError
+            })?;
+            if !failed.is_empty() {
+                tracing::warn!(
+                    failed = failed.len(),
+                    "Could not distribute message to all clients"
+                );
+                return Err(DsProcessingError::DistributionError);
             }
         }
 
@@ -565,9 +1057,17 @@ pub struct ExternalCommitInfo {
 pub enum DsProcessResponse {
     Ok,
     FanoutTimestamp(TimeStamp),
-    WelcomeInfo(RatchetTree),
+    WelcomeInfo(SerializedRatchetTree),
     ExternalCommitInfo(ExternalCommitInfo),
     GroupId(GroupId),
+    /// The request was already applied. Contains the fanout timestamp of the
+    /// original request.
+    AlreadyApplied(TimeStamp),
+    AttachmentUploaded(AttachmentId),
+    Attachment(AttachmentDownload),
+    MemberProfiles(MemberProfilesPage),
+    AttachmentsDeleted(u32),
+    GroupEpochInfo(GroupEpochInfo),
 }
 
 fn prepare_result(
@@ -587,3 +1087,16 @@ fn prepare_result(
         welcome_bundles,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_recent_clients_get_stale_epochs_rejected() {
+        assert!(!rejects_stale_epochs(None));
+        assert!(!rejects_stale_epochs(Some(&ClientVersion::new(0, 1, 9))));
+        assert!(rejects_stale_epochs(Some(&STALE_EPOCH_MIN_CLIENT_VERSION)));
+        assert!(rejects_stale_epochs(Some(&ClientVersion::new(1, 0, 0))));
+    }
+}
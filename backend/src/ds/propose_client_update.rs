@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use mls_assist::{
+    group::ProcessedAssistedMessage,
+    messages::SerializedMlsMessage,
+    openmls::prelude::{ProcessedMessageContent, Proposal, Sender},
+    provider_traits::MlsAssistProvider,
+};
+use phnxtypes::{
+    errors::ClientUpdateError,
+    messages::client_ds::{InfraAadMessage, InfraAadPayload, ProposeClientUpdateParams},
+    time::Duration,
+};
+use tls_codec::DeserializeBytes;
+
+use super::{
+    group_state::{DsGroupState, PendingClientUpdate},
+    process::USER_EXPIRATION_DAYS,
+};
+
+impl DsGroupState {
+    /// Propose to update the leaf of the sending client.
+    ///
+    /// Clients that aren't allowed to commit by the room policy can't update
+    /// their leaf by commit while there are pending proposals. They propose
+    /// the update instead, which is committed by a client that can commit.
+    /// The new client information is applied once the proposal is committed.
+    pub(super) fn propose_client_update(
+        &mut self,
+        params: ProposeClientUpdateParams,
+    ) -> Result<SerializedMlsMessage, ClientUpdateError> {
+        // Process message (but don't apply it yet). This performs
+        // mls-assist-level validations and puts the proposal into mls-assist's
+        // proposal store.
+        let processed_assisted_message_plus = self
+            .group()
+            .process_assisted_message(self.provider.crypto(), params.update_proposal)
+            .map_err(|_| ClientUpdateError::ProcessingError)?;
+
+        // Perform DS-level validation
+        // Make sure that we have the right message type.
+        let ProcessedAssistedMessage::NonCommit(ref processed_message) =
+            &processed_assisted_message_plus.processed_assisted_message
+        else {
+            tracing::warn!("Invalid message type");
+            return Err(ClientUpdateError::InvalidMessage);
+        };
+
+        let Sender::Member(sender_index) = *processed_message.sender() else {
+            // The update proposal should come from a member.
+            return Err(ClientUpdateError::InvalidMessage);
+        };
+        if sender_index != params.sender {
+            return Err(ClientUpdateError::InvalidMessage);
+        }
+
+        let ProcessedMessageContent::ProposalMessage(queued_proposal) = processed_message.content()
+        else {
+            return Err(ClientUpdateError::InvalidMessage);
+        };
+        let Proposal::Update(update_proposal) = queued_proposal.proposal() else {
+            return Err(ClientUpdateError::InvalidMessage);
+        };
+
+        let aad_message = InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())
+            .map_err(|_| {
+                tracing::warn!("Error deserializing AAD payload");
+                ClientUpdateError::InvalidMessage
+            })?;
+        let InfraAadPayload::UpdateClient(aad_payload) = aad_message.into_payload() else {
+            tracing::warn!("Invalid AAD payload");
+            return Err(ClientUpdateError::InvalidMessage);
+        };
+
+        // The client information only changes if the sender's credential does.
+        let client_profile = self
+            .client_profiles
+            .get(&sender_index)
+            .ok_or(ClientUpdateError::UnknownSender)?;
+        let mut encrypted_client_information = client_profile.encrypted_client_information.clone();
+        let old_sender_credential = self
+            .group()
+            .leaf(sender_index)
+            .ok_or(ClientUpdateError::UnknownSender)?
+            .credential();
+        if update_proposal.leaf_node().credential() != old_sender_credential {
+            let Some(encrypted_signature_ear_key) = aad_payload.option_encrypted_signature_ear_key
+            else {
+                tracing::warn!("No encrypted signature EAR key in AAD payload");
+                return Err(ClientUpdateError::InvalidMessage);
+            };
+            encrypted_client_information.1 = encrypted_signature_ear_key;
+            if let Some(ecc) = aad_payload.option_encrypted_client_credential {
+                encrypted_client_information.0 = ecc;
+            }
+        }
+
+        // Everything seems to be okay.
+        // Now we have to update the group state and distribute.
+
+        // We first accept the message into the group state ...
+        self.group.accept_processed_message(
+            self.provider.storage(),
+            processed_assisted_message_plus.processed_assisted_message,
+            Duration::days(USER_EXPIRATION_DAYS),
+        )?;
+
+        // ... and keep the client information until the proposal is committed.
+        let epoch = self.group().epoch();
+        self.pending_client_updates
+            .retain(|_, pending_update| pending_update.epoch == epoch);
+        self.pending_client_updates.insert(
+            sender_index,
+            PendingClientUpdate {
+                epoch,
+                encrypted_client_information,
+            },
+        );
+
+        // Finally, we create the message for distribution.
+        Ok(processed_assisted_message_plus.serialized_mls_message)
+    }
+}
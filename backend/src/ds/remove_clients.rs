@@ -12,7 +12,7 @@ use phnxtypes::{
     errors::ClientRemovalError, messages::client_ds::RemoveClientsParams, time::Duration,
 };
 
-use super::process::USER_EXPIRATION_DAYS;
+use super::{process::USER_EXPIRATION_DAYS, update_room_policy::changes_group_context};
 
 use super::group_state::DsGroupState;
 
@@ -57,13 +57,12 @@ impl DsGroupState {
             return Err(ClientRemovalError::InvalidMessage);
         };
 
-        let removed_clients: Vec<LeafNodeIndex> =
+        let (removed_clients, client_updates): (Vec<LeafNodeIndex>, _) =
             if let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
                 processed_message.content()
             {
-                // Check that the commit only contains removes.
-                if staged_commit.add_proposals().count() > 0
-                    || staged_commit.update_proposals().count() > 0
+                // Check that the commit only contains removes and updates.
+                if staged_commit.add_proposals().count() > 0 || changes_group_context(staged_commit)
                 {
                     return Err(ClientRemovalError::InvalidMessage);
                 }
@@ -84,8 +83,13 @@ impl DsGroupState {
                     .collect();
                 self.process_referenced_remove_proposals(&by_reference_removes)
                     .map_err(|_| ClientRemovalError::InvalidMessage)?;
+                // Committed update proposals are applied after the commit.
+                let update_proposals: Vec<_> = staged_commit.update_proposals().collect();
+                let client_updates = self
+                    .referenced_client_updates(&update_proposals)
+                    .map_err(|_| ClientRemovalError::InvalidMessage)?;
                 // Let's gather the inline remove proposals. We've already processed the non-inline ones.
-                staged_commit
+                let removed_clients = staged_commit
                     .remove_proposals()
                     .filter_map(|remove_proposal| {
                         if let Sender::Member(leaf_index) = remove_proposal.sender() {
@@ -98,7 +102,8 @@ impl DsGroupState {
                             None
                         }
                     })
-                    .collect()
+                    .collect();
+                (removed_clients, client_updates)
             } else {
                 return Err(ClientRemovalError::InvalidMessage);
             };
@@ -112,6 +117,7 @@ impl DsGroupState {
             processed_assisted_message_plus.processed_assisted_message,
             Duration::days(USER_EXPIRATION_DAYS),
         )?;
+        self.apply_client_updates(client_updates);
 
         // ... then we update the client profiles and the user profile.
         let user_profile = self
@@ -15,7 +15,7 @@ use phnxtypes::{
     messages::client_ds::RemoveUsersParams, time::Duration,
 };
 
-use super::process::USER_EXPIRATION_DAYS;
+use super::{process::USER_EXPIRATION_DAYS, update_room_policy::changes_group_context};
 
 use super::group_state::DsGroupState;
 
@@ -60,13 +60,12 @@ impl DsGroupState {
             return Err(UserRemovalError::InvalidMessage);
         };
 
-        let removed_clients: Vec<LeafNodeIndex> =
+        let (removed_clients, client_updates): (Vec<LeafNodeIndex>, _) =
             if let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
                 processed_message.content()
             {
-                // Check that the commit only contains removes.
-                if staged_commit.add_proposals().count() > 0
-                    || staged_commit.update_proposals().count() > 0
+                // Check that the commit only contains removes and updates.
+                if staged_commit.add_proposals().count() > 0 || changes_group_context(staged_commit)
                 {
                     return Err(UserRemovalError::InvalidMessage);
                 }
@@ -87,8 +86,13 @@ impl DsGroupState {
                     .collect();
                 self.process_referenced_remove_proposals(&by_reference_removes)
                     .map_err(|_| UserRemovalError::InvalidMessage)?;
+                // Committed update proposals are applied after the commit.
+                let update_proposals: Vec<_> = staged_commit.update_proposals().collect();
+                let client_updates = self
+                    .referenced_client_updates(&update_proposals)
+                    .map_err(|_| UserRemovalError::InvalidMessage)?;
                 // Let's gather the inline remove proposals. We've already processed the non-inline ones.
-                staged_commit
+                let removed_clients = staged_commit
                     .remove_proposals()
                     .filter_map(|remove_proposal| {
                         if let Sender::Member(leaf_index) = remove_proposal.sender() {
@@ -101,7 +105,8 @@ impl DsGroupState {
                             None
                         }
                     })
-                    .collect()
+                    .collect();
+                (removed_clients, client_updates)
             } else {
                 return Err(UserRemovalError::InvalidMessage);
             };
@@ -157,6 +162,7 @@ impl DsGroupState {
             processed_assisted_message_plus.processed_assisted_message,
             Duration::days(USER_EXPIRATION_DAYS),
         )?;
+        self.apply_client_updates(client_updates);
 
         Ok(processed_assisted_message_plus.serialized_mls_message)
     }
@@ -2,6 +2,8 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::collections::HashSet;
+
 use mls_assist::{
     group::ProcessedAssistedMessage,
     messages::SerializedMlsMessage,
@@ -12,7 +14,7 @@ use phnxtypes::{
     errors::ResyncClientError, messages::client_ds::ResyncClientParams, time::Duration,
 };
 
-use super::process::USER_EXPIRATION_DAYS;
+use super::{process::USER_EXPIRATION_DAYS, update_room_policy::changes_group_context};
 
 use super::group_state::DsGroupState;
 
@@ -45,25 +47,25 @@ impl DsGroupState {
             // Check that the commit only contains removes.
             if staged_commit.add_proposals().count() > 0
                 || staged_commit.update_proposals().count() > 0
+                || changes_group_context(staged_commit)
             {
                 return Err(ResyncClientError::InvalidMessage);
             }
 
-            if let Some(leaf_index) = staged_commit
+            // The commit removes exactly the old client.
+            let mut removed = staged_commit
                 .remove_proposals()
-                .map(|remove_proposal| remove_proposal.remove_proposal().removed())
-                .next()
-            {
-                leaf_index
-            } else {
-                return Err(ResyncClientError::InvalidMessage);
+                .map(|remove_proposal| remove_proposal.remove_proposal().removed());
+            match (removed.next(), removed.next()) {
+                (Some(leaf_index), None) => leaf_index,
+                _ => return Err(ResyncClientError::InvalidMessage),
             }
         } else {
             return Err(ResyncClientError::InvalidMessage);
         };
 
         // Check if it's an external commit.
-        if let Sender::NewMemberCommit = processed_message.sender() {
+        if !matches!(processed_message.sender(), Sender::NewMemberCommit) {
             return Err(ResyncClientError::InvalidMessage);
         }
 
@@ -79,6 +81,32 @@ impl DsGroupState {
             return Err(ResyncClientError::InvalidMessage);
         };
 
+        // Check that the old client and the rejoining client share the
+        // credential, i.e. that it's the same client.
+        let removed_leaf = self
+            .group()
+            .leaf(removed_client)
+            .ok_or(ResyncClientError::InvalidMessage)?;
+        if removed_leaf.credential() != processed_message.credential() {
+            return Err(ResyncClientError::InvalidMessage);
+        }
+
+        // The joiner takes the leftmost free leaf after the removal. It has
+        // to be the leaf of the old client, so that the client profile and
+        // the leaf indices in the user profiles stay valid.
+        let occupied_leaves = self
+            .group()
+            .members()
+            .map(|member| member.index.u32())
+            .filter(|index| *index != removed_client.u32())
+            .collect::<HashSet<_>>();
+        let joiner_leaf = (0u32..)
+            .find(|index| !occupied_leaves.contains(index))
+            .ok_or(ResyncClientError::LibraryError)?;
+        if joiner_leaf != removed_client.u32() {
+            return Err(ResyncClientError::InvalidMessage);
+        }
+
         // Everything seems to be okay.
         // Now we have to update the group state and distribute.
 
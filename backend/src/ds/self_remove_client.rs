@@ -80,6 +80,7 @@ impl DsGroupState {
         )?;
 
         // We remove the user and client profile only when the proposal is committed.
+        self.self_removal_epoch = Some(self.group().epoch());
 
         // Finally, we create the message for distribution.
         Ok(processed_assisted_message_plus.serialized_mls_message)
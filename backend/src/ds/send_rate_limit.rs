@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Limits the rate at which a single sender can send messages.
+//!
+//! Unlike the [`super::spam_throttle`], which looks at the reach of a sender
+//! over a longer window, this is a plain token bucket per sender that
+//! protects the DS from bursts, e.g. of bots. Senders are identified by their
+//! leaf signature key.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use phnxtypes::messages::server_info::SendRateLimit;
+use tokio::sync::Mutex;
+
+use crate::settings::SendRateLimitSettings;
+
+/// Number of tracked senders above which senders with full buckets are
+/// pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct SendRateLimiter {
+    settings: SendRateLimitSettings,
+    senders: Mutex<HashMap<Vec<u8>, Bucket>>,
+}
+
+impl SendRateLimiter {
+    pub(super) fn new(settings: SendRateLimitSettings) -> Self {
+        Self {
+            settings,
+            senders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of the given sender. Returns the time
+    /// after which the next message will be accepted if the bucket is empty.
+    pub(super) async fn check(&self, sender: &[u8]) -> Result<(), Duration> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut senders = self.senders.lock().await;
+        if senders.len() > PRUNE_THRESHOLD {
+            senders.retain(|_, bucket| self.settings.refill(*bucket, now).tokens < self.burst());
+        }
+        let bucket = senders.entry(sender.to_vec()).or_insert(Bucket {
+            tokens: self.burst(),
+            updated_at: now,
+        });
+        *bucket = self.settings.refill(*bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(
+                missing / self.settings.messages_per_second.max(1) as f64,
+            ))
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        self.settings.burst.max(1) as f64
+    }
+}
+
+impl SendRateLimitSettings {
+    fn refill(&self, bucket: Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        let tokens = bucket.tokens + elapsed.as_secs_f64() * self.messages_per_second as f64;
+        Bucket {
+            tokens: tokens.min(self.burst.max(1) as f64),
+            updated_at: now,
+        }
+    }
+
+    /// The limit as published to clients, if enabled.
+    pub(crate) fn published_limit(&self) -> Option<SendRateLimit> {
+        self.enabled.then_some(SendRateLimit {
+            messages_per_second: self.messages_per_second,
+            burst: self.burst,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn senders_are_limited_independently() {
+        let limiter = SendRateLimiter::new(SendRateLimitSettings {
+            enabled: true,
+            messages_per_second: 1,
+            burst: 2,
+        });
+        assert_eq!(limiter.check(b"alice").await, Ok(()));
+        assert_eq!(limiter.check(b"alice").await, Ok(()));
+        let retry_after = limiter.check(b"alice").await.unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        assert_eq!(limiter.check(b"bob").await, Ok(()));
+    }
+
+    #[test]
+    fn buckets_refill_up_to_the_burst() {
+        let settings = SendRateLimitSettings {
+            enabled: true,
+            messages_per_second: 2,
+            burst: 3,
+        };
+        let start = Instant::now();
+        let empty = Bucket {
+            tokens: 0.0,
+            updated_at: start,
+        };
+        let refilled = settings.refill(empty, start + Duration::from_millis(500));
+        assert_eq!(refilled.tokens, 1.0);
+        let full = settings.refill(empty, start + Duration::from_secs(10));
+        assert_eq!(full.tokens, 3.0);
+    }
+}
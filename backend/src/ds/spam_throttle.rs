@@ -0,0 +1,273 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Content-agnostic throttling of senders that distribute unusually many
+//! messages.
+//!
+//! The DS can't inspect the content of messages. Instead, it tracks how many
+//! recipients each sender reaches and how many different groups it sends to
+//! within a time window. Senders that exceed the soft limits have their
+//! messages rejected with a progressively growing delay after which they may
+//! send them again, senders that exceed the hard limit are rejected until the
+//! window has passed. Requests are not held on the DS while the sender
+//! waits.
+//!
+//! Senders are identified by their leaf signature key. Messages in groups
+//! with at most [`SpamThrottleSettings::exempt_max_group_size`] users, i.e.
+//! in groups between established contacts, are exempt.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::settings::SpamThrottleSettings;
+
+/// Number of tracked senders above which senders with expired windows are
+/// pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ThrottleDecision {
+    Allow,
+    /// The message may be sent again after the given delay.
+    Delay(Duration),
+    Reject,
+}
+
+#[derive(Debug)]
+struct SenderActivity {
+    window_start: Instant,
+    fanout: u64,
+    groups: HashSet<Uuid>,
+    /// End of the delay of the last delayed message. The message was counted
+    /// when it was delayed, so the first message after the delay is allowed
+    /// without counting it again.
+    delayed_until: Option<Instant>,
+}
+
+impl SenderActivity {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            fanout: 0,
+            groups: HashSet::new(),
+            delayed_until: None,
+        }
+    }
+}
+
+/// Counters that allow tuning the throttle thresholds.
+#[derive(Debug, Default)]
+struct SpamThrottleMetrics {
+    delayed_messages: AtomicU64,
+    rejected_messages: AtomicU64,
+    total_delay_ms: AtomicU64,
+}
+
+/// Snapshot of the throttle metrics since the start of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SpamThrottleMetricsSnapshot {
+    pub delayed_messages: u64,
+    pub rejected_messages: u64,
+    pub total_delay_ms: u64,
+    pub tracked_senders: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct SpamThrottle {
+    settings: SpamThrottleSettings,
+    senders: Mutex<HashMap<Vec<u8>, SenderActivity>>,
+    metrics: SpamThrottleMetrics,
+}
+
+impl SpamThrottle {
+    pub(super) fn new(settings: SpamThrottleSettings) -> Self {
+        Self {
+            settings,
+            senders: Mutex::new(HashMap::new()),
+            metrics: SpamThrottleMetrics::default(),
+        }
+    }
+
+    /// Record a message from the given sender to `recipients` recipients in
+    /// the given group and decide whether the message may be distributed.
+    pub(super) async fn check(
+        &self,
+        sender: &[u8],
+        group_id: Uuid,
+        group_size: usize,
+        recipients: usize,
+    ) -> ThrottleDecision {
+        if !self.settings.enabled || group_size <= self.settings.exempt_max_group_size {
+            return ThrottleDecision::Allow;
+        }
+        let now = Instant::now();
+        let window = Duration::from_secs(self.settings.window_secs);
+        let mut senders = self.senders.lock().await;
+        if senders.len() > PRUNE_THRESHOLD {
+            senders.retain(|_, activity| now.duration_since(activity.window_start) < window);
+        }
+        let activity = senders
+            .entry(sender.to_vec())
+            .or_insert_with(|| SenderActivity::new(now));
+        let decision = self.settings.evaluate(activity, now, group_id, recipients);
+        drop(senders);
+
+        match decision {
+            ThrottleDecision::Allow => {}
+            ThrottleDecision::Delay(delay) => {
+                self.metrics
+                    .delayed_messages
+                    .fetch_add(1, Ordering::Relaxed);
+                self.metrics
+                    .total_delay_ms
+                    .fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+                tracing::info!(delay_ms = delay.as_millis() as u64, "Delaying message");
+            }
+            ThrottleDecision::Reject => {
+                self.metrics
+                    .rejected_messages
+                    .fetch_add(1, Ordering::Relaxed);
+                tracing::info!("Rejecting message of throttled sender");
+            }
+        }
+        decision
+    }
+
+    pub(super) async fn metrics(&self) -> SpamThrottleMetricsSnapshot {
+        SpamThrottleMetricsSnapshot {
+            delayed_messages: self.metrics.delayed_messages.load(Ordering::Relaxed),
+            rejected_messages: self.metrics.rejected_messages.load(Ordering::Relaxed),
+            total_delay_ms: self.metrics.total_delay_ms.load(Ordering::Relaxed),
+            tracked_senders: self.senders.lock().await.len() as u64,
+        }
+    }
+}
+
+impl SpamThrottleSettings {
+    fn evaluate(
+        &self,
+        activity: &mut SenderActivity,
+        now: Instant,
+        group_id: Uuid,
+        recipients: usize,
+    ) -> ThrottleDecision {
+        if now.duration_since(activity.window_start) >= Duration::from_secs(self.window_secs) {
+            *activity = SenderActivity::new(now);
+        }
+        if let Some(delayed_until) = activity.delayed_until {
+            if now < delayed_until {
+                return ThrottleDecision::Delay(delayed_until - now);
+            }
+            activity.delayed_until = None;
+            return ThrottleDecision::Allow;
+        }
+        activity.fanout += recipients as u64;
+        activity.groups.insert(group_id);
+
+        if activity.fanout > self.fanout_hard_limit {
+            return ThrottleDecision::Reject;
+        }
+
+        // The delay grows with the share by which the sender exceeds the
+        // respective soft limit.
+        let fanout_excess = excess_ratio(activity.fanout, self.fanout_soft_limit);
+        let group_excess = excess_ratio(activity.groups.len() as u64, self.group_soft_limit);
+        let excess = fanout_excess.max(group_excess);
+        if excess <= 0.0 {
+            return ThrottleDecision::Allow;
+        }
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+        let delay = max_delay.mul_f64(excess.min(1.0));
+        activity.delayed_until = Some(now + delay);
+        ThrottleDecision::Delay(delay)
+    }
+}
+
+fn excess_ratio(value: u64, soft_limit: u64) -> f64 {
+    if value <= soft_limit {
+        0.0
+    } else {
+        (value - soft_limit) as f64 / soft_limit.max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SpamThrottleSettings {
+        SpamThrottleSettings {
+            enabled: true,
+            window_secs: 60,
+            fanout_soft_limit: 10,
+            fanout_hard_limit: 20,
+            group_soft_limit: 2,
+            max_delay_ms: 1000,
+            exempt_max_group_size: 2,
+        }
+    }
+
+    #[test]
+    fn progressive_delay_and_rejection() {
+        let settings = settings();
+        let now = Instant::now();
+        let group_id = Uuid::new_v4();
+        let mut activity = SenderActivity::new(now);
+
+        let decision = settings.evaluate(&mut activity, now, group_id, 10);
+        assert_eq!(decision, ThrottleDecision::Allow);
+
+        let decision = settings.evaluate(&mut activity, now, group_id, 5);
+        assert_eq!(
+            decision,
+            ThrottleDecision::Delay(Duration::from_millis(500))
+        );
+
+        // Messages sent during the delay are rejected for the rest of it.
+        let during = now + Duration::from_millis(200);
+        let decision = settings.evaluate(&mut activity, during, group_id, 5);
+        assert_eq!(
+            decision,
+            ThrottleDecision::Delay(Duration::from_millis(300))
+        );
+
+        // The delayed message was already counted, so sending it again after
+        // the delay is allowed.
+        let after = now + Duration::from_millis(500);
+        let decision = settings.evaluate(&mut activity, after, group_id, 5);
+        assert_eq!(decision, ThrottleDecision::Allow);
+
+        let decision = settings.evaluate(&mut activity, after, group_id, 6);
+        assert_eq!(decision, ThrottleDecision::Reject);
+
+        // A new window resets the sender's activity.
+        let later = now + Duration::from_secs(60);
+        let decision = settings.evaluate(&mut activity, later, group_id, 1);
+        assert_eq!(decision, ThrottleDecision::Allow);
+    }
+
+    #[test]
+    fn group_diversity() {
+        let settings = settings();
+        let now = Instant::now();
+        let mut activity = SenderActivity::new(now);
+
+        for _ in 0..2 {
+            let decision = settings.evaluate(&mut activity, now, Uuid::new_v4(), 1);
+            assert_eq!(decision, ThrottleDecision::Allow);
+        }
+        let decision = settings.evaluate(&mut activity, now, Uuid::new_v4(), 1);
+        assert_eq!(
+            decision,
+            ThrottleDecision::Delay(Duration::from_millis(500))
+        );
+    }
+}
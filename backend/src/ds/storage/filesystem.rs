@@ -0,0 +1,356 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Storage provider backed by the local filesystem.
+//!
+//! Objects are stored in `objects/` below the configured directory and are
+//! sharded by the first two bytes of their id to keep directories small.
+//! Writes go to a temporary file that is renamed into place once it was
+//! flushed, so readers never see partially written objects. Each write uses
+//! its own temporary file, and temporary files left over by interrupted
+//! writes are removed on startup. Quarantined objects are moved to
+//! `quarantine/`. Deleting or overwriting an object releases its size from
+//! the quota.
+//!
+//! If an encryption key is configured, objects are encrypted at rest and bound
+//! to the id of their attachment, so that the content of one attachment can't
+//! be served as that of another.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use phnxtypes::{
+    crypto::{
+        ear::{keys::AttachmentStorageEarKey, Ciphertext, EarKey},
+        secrets::SecretBytes,
+    },
+    identifiers::AttachmentId,
+};
+use tls_codec::{DeserializeBytes, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use uuid::Uuid;
+
+use crate::settings::FilesystemStorageSettings;
+
+use super::{ObjectStoreError, Retention, StorageProvider};
+
+const OBJECTS_DIRECTORY: &str = "objects";
+const QUARANTINE_DIRECTORY: &str = "quarantine";
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+#[derive(Debug, Clone)]
+pub(super) struct FilesystemStorageProvider {
+    root: PathBuf,
+    max_bytes: Option<u64>,
+    used_bytes: Arc<AtomicU64>,
+    /// Serializes the changes of stored objects, so that the size of a
+    /// replaced or deleted object is released exactly once.
+    changes: Arc<Mutex<()>>,
+    ear_key: Option<AttachmentStorageEarKey>,
+}
+
+impl FilesystemStorageProvider {
+    pub(super) async fn new(
+        settings: &FilesystemStorageSettings,
+    ) -> Result<Self, ObjectStoreError> {
+        let root = settings.path.clone();
+        fs::create_dir_all(root.join(OBJECTS_DIRECTORY)).await?;
+        fs::create_dir_all(root.join(QUARANTINE_DIRECTORY)).await?;
+        let ear_key = match &settings.encryption_key_path {
+            Some(path) => {
+                let key_bytes = SecretBytes::from(fs::read(path).await?);
+                let ear_key = AttachmentStorageEarKey::try_from(key_bytes.expose_secret())
+                    .map_err(|_| {
+                        ObjectStoreError::Backend(
+                            "Attachment encryption key must be 32 bytes".to_owned(),
+                        )
+                    })?;
+                Some(ear_key)
+            }
+            None => None,
+        };
+        let used_bytes = clean_up_and_measure(&root).await?;
+        tracing::info!(used_bytes, "Initialized filesystem attachment storage");
+        Ok(Self {
+            root,
+            max_bytes: settings.max_bytes,
+            used_bytes: Arc::new(AtomicU64::new(used_bytes)),
+            changes: Arc::new(Mutex::new(())),
+            ear_key,
+        })
+    }
+
+    fn object_path(&self, id: &AttachmentId) -> PathBuf {
+        let bytes = id.as_uuid().as_bytes();
+        self.root
+            .join(OBJECTS_DIRECTORY)
+            .join(format!("{:02x}", bytes[0]))
+            .join(format!("{:02x}", bytes[1]))
+            .join(id.to_string())
+    }
+
+    fn quarantine_path(&self, id: &AttachmentId) -> PathBuf {
+        self.root.join(QUARANTINE_DIRECTORY).join(id.to_string())
+    }
+
+    /// Account for the given number of bytes, failing if that would exceed
+    /// the quota.
+    fn reserve(&self, size: u64) -> Result<(), ObjectStoreError> {
+        let previous = self.used_bytes.fetch_add(size, Ordering::SeqCst);
+        if self
+            .max_bytes
+            .is_some_and(|max_bytes| previous + size > max_bytes)
+        {
+            self.release(size);
+            return Err(ObjectStoreError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    fn release(&self, size: u64) {
+        self.used_bytes.fetch_sub(size, Ordering::SeqCst);
+    }
+
+    /// Size of the object at the given path, if there is one.
+    async fn stored_size(path: &Path) -> Result<Option<u64>, std::io::Error> {
+        match fs::metadata(path).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the content to a temporary file next to `path` and move it into
+    /// place once it is on disk. Concurrent writes use different temporary
+    /// files. Returns the size of the replaced object, if any.
+    async fn write_atomically(
+        &self,
+        path: &Path,
+        content: &[u8],
+    ) -> Result<Option<u64>, std::io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let temporary_path =
+            path.with_extension(format!("{}.{TEMPORARY_EXTENSION}", Uuid::new_v4()));
+        let result = async {
+            let mut file = fs::File::create(&temporary_path).await?;
+            file.write_all(content).await?;
+            file.sync_all().await?;
+            let _changes = self.changes.lock().await;
+            let previous_size = Self::stored_size(path).await?;
+            fs::rename(&temporary_path, path).await?;
+            Ok(previous_size)
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary_path).await;
+        }
+        result
+    }
+
+    fn seal(&self, id: &AttachmentId, content: &[u8]) -> Result<Vec<u8>, ObjectStoreError> {
+        let Some(ear_key) = &self.ear_key else {
+            return Ok(content.to_vec());
+        };
+        let ciphertext = ear_key
+            .encrypt_with_aad(content, id.as_uuid().as_bytes())
+            .map_err(|e| ObjectStoreError::Backend(e.to_string()))?;
+        ciphertext
+            .tls_serialize_detached()
+            .map_err(|e| ObjectStoreError::Backend(e.to_string()))
+    }
+
+    fn unseal(&self, id: &AttachmentId, stored: Vec<u8>) -> Result<Vec<u8>, ObjectStoreError> {
+        let Some(ear_key) = &self.ear_key else {
+            return Ok(stored);
+        };
+        let ciphertext = Ciphertext::tls_deserialize_exact_bytes(&stored)
+            .map_err(|e| ObjectStoreError::Backend(e.to_string()))?;
+        ear_key
+            .decrypt_with_aad(&ciphertext, id.as_uuid().as_bytes())
+            .map_err(|e| ObjectStoreError::Backend(e.to_string()))
+    }
+}
+
+/// Remove the temporary files of interrupted writes below the given directory
+/// and return the total size of the remaining files.
+async fn clean_up_and_measure(root: &Path) -> Result<u64, std::io::Error> {
+    let mut size = 0;
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let mut entries = fs::read_dir(&directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let path = entry.path();
+            if metadata.is_dir() {
+                directories.push(path);
+            } else if path.extension().is_some_and(|e| e == TEMPORARY_EXTENSION) {
+                fs::remove_file(&path).await?;
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[async_trait]
+impl StorageProvider for FilesystemStorageProvider {
+    async fn put(
+        &self,
+        id: &AttachmentId,
+        content: &[u8],
+        _retention: Retention,
+    ) -> Result<(), ObjectStoreError> {
+        let stored = self.seal(id, content)?;
+        let size = stored.len() as u64;
+        self.reserve(size)?;
+        match self.write_atomically(&self.object_path(id), &stored).await {
+            Ok(previous_size) => {
+                // The object replaced a previous version of itself
+                if let Some(previous_size) = previous_size {
+                    self.release(previous_size);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.release(size);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn get(&self, id: &AttachmentId) -> Result<Option<Vec<u8>>, ObjectStoreError> {
+        let stored = match fs::read(self.object_path(id)).await {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        self.unseal(id, stored).map(Some)
+    }
+
+    async fn quarantine(&self, id: &AttachmentId) -> Result<(), ObjectStoreError> {
+        let _changes = self.changes.lock().await;
+        fs::rename(self.object_path(id), self.quarantine_path(id)).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &AttachmentId) -> Result<(), ObjectStoreError> {
+        let _changes = self.changes.lock().await;
+        for path in [self.object_path(id), self.quarantine_path(id)] {
+            let Some(size) = Self::stored_size(&path).await? else {
+                continue;
+            };
+            fs::remove_file(&path).await?;
+            self.release(size);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_is_enforced() {
+        let provider = FilesystemStorageProvider {
+            root: PathBuf::new(),
+            max_bytes: Some(100),
+            used_bytes: Arc::new(AtomicU64::new(60)),
+            changes: Arc::new(Mutex::new(())),
+            ear_key: None,
+        };
+        assert!(provider.reserve(40).is_ok());
+        assert!(matches!(
+            provider.reserve(1),
+            Err(ObjectStoreError::QuotaExceeded)
+        ));
+        provider.release(40);
+        assert!(provider.reserve(30).is_ok());
+        assert_eq!(provider.used_bytes.load(Ordering::SeqCst), 90);
+    }
+
+    async fn test_provider(ear_key: Option<AttachmentStorageEarKey>) -> FilesystemStorageProvider {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let settings = FilesystemStorageSettings {
+            path: root,
+            max_bytes: Some(1000),
+            encryption_key_path: None,
+        };
+        let mut provider = FilesystemStorageProvider::new(&settings).await.unwrap();
+        provider.ear_key = ear_key;
+        provider
+    }
+
+    #[tokio::test]
+    async fn overwrites_and_interrupted_writes_are_accounted_once() {
+        let provider = test_provider(None).await;
+        let id = AttachmentId::from(Uuid::new_v4());
+
+        // Concurrent writes of the same object don't share a temporary file
+        let (first, second) = tokio::join!(
+            provider.put(&id, &[1; 100], Retention::Ephemeral),
+            provider.put(&id, &[2; 100], Retention::Ephemeral),
+        );
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(provider.used_bytes.load(Ordering::SeqCst), 100);
+        provider
+            .put(&id, &[3; 50], Retention::Ephemeral)
+            .await
+            .unwrap();
+        assert_eq!(provider.used_bytes.load(Ordering::SeqCst), 50);
+
+        // Temporary files of interrupted writes are removed on startup
+        let leftover = provider.object_path(&id).with_extension("leftover.tmp");
+        fs::write(&leftover, [0; 100]).await.unwrap();
+        let settings = FilesystemStorageSettings {
+            path: provider.root.clone(),
+            max_bytes: None,
+            encryption_key_path: None,
+        };
+        let restarted = FilesystemStorageProvider::new(&settings).await.unwrap();
+        assert_eq!(restarted.used_bytes.load(Ordering::SeqCst), 50);
+        assert!(!leftover.exists());
+
+        restarted.delete(&id).await.unwrap();
+        restarted.delete(&id).await.unwrap();
+        assert_eq!(restarted.used_bytes.load(Ordering::SeqCst), 0);
+        fs::remove_dir_all(&provider.root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_objects_are_bound_to_their_id() {
+        let ear_key = AttachmentStorageEarKey::try_from([7; 32].as_slice()).unwrap();
+        let provider = test_provider(Some(ear_key)).await;
+        let id = AttachmentId::from(Uuid::new_v4());
+        let other_id = AttachmentId::from(Uuid::new_v4());
+        provider
+            .put(&id, b"content", Retention::Ephemeral)
+            .await
+            .unwrap();
+        assert_eq!(
+            provider.get(&id).await.unwrap().as_deref(),
+            Some(b"content".as_slice())
+        );
+
+        // Content moved to the path of another attachment can't be decrypted
+        fs::create_dir_all(provider.object_path(&other_id).parent().unwrap())
+            .await
+            .unwrap();
+        fs::copy(provider.object_path(&id), provider.object_path(&other_id))
+            .await
+            .unwrap();
+        assert!(provider.get(&other_id).await.is_err());
+        fs::remove_dir_all(&provider.root).await.unwrap();
+    }
+}
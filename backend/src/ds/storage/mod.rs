@@ -0,0 +1,652 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Storage of attachments uploaded to the DS.
+//!
+//! The metadata of each attachment is kept in the DS database, while the
+//! content is handed to a [`StorageProvider`]. If an [`AttachmentScanner`] is
+//! configured, the content is scanned before the upload is acknowledged.
+//! Flagged attachments are quarantined by the provider and can no longer be
+//! downloaded. The DS records the user key hash of the uploader, so that users
+//! can delete their attachments.
+//!
+//! Attachments belong to a [`Retention`] class. Message archives that clients
+//! offload to the DS are kept separately from regular attachments: they are
+//! tagged differently by providers that support lifecycle rules and aren't
+//! deleted when users delete their attachments. View-once attachments are
+//! deleted as soon as a member other than the uploader downloaded them.
+//!
+//! Note that attachments of end-to-end encrypted messages are encrypted by
+//! the clients before upload, so scanners only ever see ciphertext. Scanning
+//! is only meaningful for content that is uploaded in the clear, e.g. on
+//! deployments where the operator is expected to inspect attachments.
+
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use phnxtypes::{
+    crypto::signatures::keys::UserKeyHash,
+    identifiers::AttachmentId,
+    messages::attachments::{AttachmentDownload, AttachmentUrl},
+};
+use sqlx::{PgExecutor, PgPool};
+use thiserror::Error;
+use tls_codec::Serialize;
+use uuid::Uuid;
+
+use crate::{errors::StorageError, settings::StorageSettings};
+
+mod filesystem;
+mod s3;
+pub(crate) mod scanner;
+
+use filesystem::FilesystemStorageProvider;
+use s3::S3StorageProvider;
+use scanner::{AttachmentScanner, ScanError, ScanVerdict};
+
+#[derive(Debug, Error)]
+pub enum ObjectStoreError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+    #[error("Storage quota exceeded")]
+    QuotaExceeded,
+}
+
+impl From<sqlx::Error> for ObjectStoreError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Storage(e.into())
+    }
+}
+
+/// Retention class of an attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i16)]
+pub enum Retention {
+    /// Attachments of messages, which can expire once all members had a
+    /// chance to download them.
+    Ephemeral = 0,
+    /// Messages archived by a client, which are kept until the client
+    /// restores them.
+    Archive = 1,
+    /// Attachments that can be downloaded once by members other than the
+    /// uploader.
+    ViewOnce = 2,
+}
+
+impl From<i16> for Retention {
+    fn from(value: i16) -> Self {
+        match value {
+            1 => Self::Archive,
+            2 => Self::ViewOnce,
+            _ => Self::Ephemeral,
+        }
+    }
+}
+
+/// A backend that stores the content of attachments.
+#[async_trait]
+pub trait StorageProvider: Send + Sync + Debug {
+    /// Store the content of the attachment with the given id.
+    async fn put(
+        &self,
+        id: &AttachmentId,
+        content: &[u8],
+        retention: Retention,
+    ) -> Result<(), ObjectStoreError>;
+
+    /// Load the content of the attachment with the given id. Returns `None`
+    /// if no such attachment exists or if it was quarantined.
+    async fn get(&self, id: &AttachmentId) -> Result<Option<Vec<u8>>, ObjectStoreError>;
+
+    /// Move the attachment out of reach of downloads while keeping it for
+    /// inspection by the operator.
+    async fn quarantine(&self, id: &AttachmentId) -> Result<(), ObjectStoreError>;
+
+    /// Delete the content of the attachment with the given id, including
+    /// quarantined content. Deleting a missing attachment is not an error.
+    async fn delete(&self, id: &AttachmentId) -> Result<(), ObjectStoreError>;
+
+    /// Create a short-lived URL from which clients can download the
+    /// attachment directly. Returns `None` if the provider doesn't support
+    /// direct downloads.
+    async fn presigned_url(&self, _id: &AttachmentId) -> Result<Option<String>, ObjectStoreError> {
+        Ok(None)
+    }
+}
+
+/// Default provider that stores attachments in the DS database.
+#[derive(Debug, Clone)]
+pub(super) struct PostgresStorageProvider {
+    db_pool: PgPool,
+}
+
+impl PostgresStorageProvider {
+    pub(super) fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait]
+impl StorageProvider for PostgresStorageProvider {
+    async fn put(
+        &self,
+        id: &AttachmentId,
+        content: &[u8],
+        _retention: Retention,
+    ) -> Result<(), ObjectStoreError> {
+        sqlx::query!(
+            "INSERT INTO ds_attachment_objects (attachment_id, content) VALUES ($1, $2)",
+            id.as_uuid(),
+            content,
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &AttachmentId) -> Result<Option<Vec<u8>>, ObjectStoreError> {
+        let content = sqlx::query_scalar!(
+            "SELECT content FROM ds_attachment_objects
+            WHERE attachment_id = $1 AND NOT quarantined",
+            id.as_uuid(),
+        )
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(content)
+    }
+
+    async fn quarantine(&self, id: &AttachmentId) -> Result<(), ObjectStoreError> {
+        sqlx::query!(
+            "UPDATE ds_attachment_objects SET quarantined = TRUE WHERE attachment_id = $1",
+            id.as_uuid(),
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &AttachmentId) -> Result<(), ObjectStoreError> {
+        sqlx::query!(
+            "DELETE FROM ds_attachment_objects WHERE attachment_id = $1",
+            id.as_uuid(),
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Attachment storage selected in the settings.
+///
+/// Servers that serve several domains create it once and share it between
+/// the DSs of all domains, such that providers that keep state in the
+/// server, like the quota of the filesystem provider, account for the
+/// attachments of all domains. Attachments stored in the database are kept
+/// in the rows of the respective domain instead.
+#[derive(Debug, Clone)]
+pub struct StorageBackend {
+    shared_provider: Option<Arc<dyn StorageProvider>>,
+}
+
+impl StorageBackend {
+    pub async fn new(settings: &StorageSettings) -> Result<Self, ObjectStoreError> {
+        let shared_provider: Option<Arc<dyn StorageProvider>> = match settings {
+            StorageSettings::Postgres => None,
+            StorageSettings::S3(s3_settings) => {
+                Some(Arc::new(S3StorageProvider::new(s3_settings).await))
+            }
+            StorageSettings::Filesystem(filesystem_settings) => Some(Arc::new(
+                FilesystemStorageProvider::new(filesystem_settings).await?,
+            )),
+        };
+        Ok(Self { shared_provider })
+    }
+
+    /// The provider used by the DS with the given database pool.
+    pub(super) fn provider(&self, db_pool: &PgPool) -> Arc<dyn StorageProvider> {
+        self.shared_provider
+            .clone()
+            .unwrap_or_else(|| Arc::new(PostgresStorageProvider::new(db_pool.clone())))
+    }
+}
+
+#[derive(Debug, Error)]
+pub(super) enum AttachmentStorageError {
+    #[error(transparent)]
+    ObjectStore(#[from] ObjectStoreError),
+    #[error("Scanning failed: {0}")]
+    Scan(#[from] ScanError),
+}
+
+impl From<sqlx::Error> for AttachmentStorageError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::ObjectStore(e.into())
+    }
+}
+
+impl From<tls_codec::Error> for AttachmentStorageError {
+    fn from(e: tls_codec::Error) -> Self {
+        Self::ObjectStore(ObjectStoreError::Backend(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i16)]
+enum AttachmentStatus {
+    Clean = 0,
+    Blocked = 1,
+}
+
+impl From<i16> for AttachmentStatus {
+    fn from(value: i16) -> Self {
+        match value {
+            0 => Self::Clean,
+            // Treat unknown values as blocked to fail closed.
+            _ => Self::Blocked,
+        }
+    }
+}
+
+/// Result of uploading an attachment.
+pub(super) struct UploadedAttachment {
+    pub(super) attachment_id: AttachmentId,
+    /// Reason the attachment was blocked, if it was flagged by the scanner.
+    pub(super) blocked: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct AttachmentStorage {
+    provider: Arc<dyn StorageProvider>,
+    scanner: Option<Arc<dyn AttachmentScanner>>,
+}
+
+impl AttachmentStorage {
+    pub(super) fn new(provider: Arc<dyn StorageProvider>) -> Self {
+        Self {
+            provider,
+            scanner: None,
+        }
+    }
+
+    pub(super) fn set_provider(&mut self, provider: Arc<dyn StorageProvider>) {
+        self.provider = provider;
+    }
+
+    pub(super) fn set_scanner(&mut self, scanner: Option<Arc<dyn AttachmentScanner>>) {
+        self.scanner = scanner;
+    }
+
+    /// Scan and store the given content as an attachment of the group.
+    pub(super) async fn upload(
+        &self,
+        db_pool: &PgPool,
+        group_id: Uuid,
+        uploader: Option<&UserKeyHash>,
+        content: &[u8],
+        retention: Retention,
+    ) -> Result<UploadedAttachment, AttachmentStorageError> {
+        let verdict = match &self.scanner {
+            Some(scanner) => scanner.scan(content).await?,
+            None => ScanVerdict::Clean,
+        };
+        let (status, blocked) = match verdict {
+            ScanVerdict::Clean => (AttachmentStatus::Clean, None),
+            ScanVerdict::Flagged(reason) => (AttachmentStatus::Blocked, Some(reason)),
+        };
+
+        let attachment_id = AttachmentId::from(Uuid::new_v4());
+        let uploader = uploader.map(|u| u.tls_serialize_detached()).transpose()?;
+        store_metadata(
+            db_pool,
+            attachment_id,
+            group_id,
+            uploader.as_deref(),
+            content.len(),
+            status,
+            retention,
+        )
+        .await?;
+        self.provider
+            .put(&attachment_id, content, retention)
+            .await?;
+        if blocked.is_some() {
+            self.provider.quarantine(&attachment_id).await?;
+        }
+
+        Ok(UploadedAttachment {
+            attachment_id,
+            blocked,
+        })
+    }
+
+    /// Load the attachment with the given id on behalf of the given user.
+    /// Returns `None` if the group has no such attachment.
+    ///
+    /// View-once attachments are deleted once they were downloaded by a user
+    /// other than the uploader.
+    pub(super) async fn download(
+        &self,
+        db_pool: &PgPool,
+        group_id: Uuid,
+        attachment_id: AttachmentId,
+        downloader: Option<&UserKeyHash>,
+    ) -> Result<Option<AttachmentDownload>, AttachmentStorageError> {
+        let Some(metadata) = load_metadata(db_pool, attachment_id, group_id).await? else {
+            return Ok(None);
+        };
+        if metadata.status == AttachmentStatus::Blocked {
+            return Ok(Some(AttachmentDownload::Blocked));
+        }
+        if metadata.retention == Retention::ViewOnce {
+            let downloader = downloader.map(|d| d.tls_serialize_detached()).transpose()?;
+            if metadata.uploader.is_none() || metadata.uploader != downloader {
+                return self.consume(db_pool, group_id, attachment_id).await;
+            }
+        }
+        if let Some(url) = self.provider.presigned_url(&attachment_id).await? {
+            return Ok(Some(AttachmentDownload::Url(AttachmentUrl::new(url))));
+        }
+        let download = self
+            .provider
+            .get(&attachment_id)
+            .await?
+            .map(AttachmentDownload::Available);
+        Ok(download)
+    }
+
+    /// Hand out the content of the given view-once attachment and delete it.
+    /// The metadata stays locked until the attachment is deleted, so that
+    /// concurrent downloads can't obtain the content as well.
+    async fn consume(
+        &self,
+        db_pool: &PgPool,
+        group_id: Uuid,
+        attachment_id: AttachmentId,
+    ) -> Result<Option<AttachmentDownload>, AttachmentStorageError> {
+        let mut transaction = db_pool.begin().await?;
+        if !lock_metadata(&mut *transaction, attachment_id, group_id).await? {
+            // A concurrent download consumed the attachment.
+            return Ok(None);
+        }
+        // Presigned URLs would stay valid after the deletion, so the content
+        // is always passed through the DS.
+        let download = self
+            .provider
+            .get(&attachment_id)
+            .await?
+            .map(AttachmentDownload::Available);
+        self.provider.delete(&attachment_id).await?;
+        delete_metadata(&mut *transaction, attachment_id).await?;
+        transaction.commit().await?;
+        Ok(download)
+    }
+
+    /// Delete all attachments of the group uploaded by the given user.
+    /// Archives are kept. Returns the number of deleted attachments.
+    pub(super) async fn delete_uploaded_by(
+        &self,
+        db_pool: &PgPool,
+        group_id: Uuid,
+        uploader: &UserKeyHash,
+    ) -> Result<u32, AttachmentStorageError> {
+        let uploader = uploader.tls_serialize_detached()?;
+        let attachment_ids = load_uploaded_by(db_pool, group_id, &uploader).await?;
+        // The content is deleted first, so that a failure leaves the metadata
+        // in place and the deletion can be retried.
+        for attachment_id in &attachment_ids {
+            self.provider.delete(attachment_id).await?;
+            delete_metadata(db_pool, *attachment_id).await?;
+        }
+        Ok(attachment_ids.len() as u32)
+    }
+}
+
+async fn store_metadata(
+    connection: impl PgExecutor<'_>,
+    attachment_id: AttachmentId,
+    group_id: Uuid,
+    uploader: Option<&[u8]>,
+    size: usize,
+    status: AttachmentStatus,
+    retention: Retention,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO ds_attachments (attachment_id, group_id, uploader, size, status, retention)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+        attachment_id.as_uuid(),
+        group_id,
+        uploader,
+        size as i64,
+        status as i16,
+        retention as i16,
+    )
+    .execute(connection)
+    .await?;
+    Ok(())
+}
+
+struct AttachmentMetadata {
+    status: AttachmentStatus,
+    retention: Retention,
+    uploader: Option<Vec<u8>>,
+}
+
+async fn load_metadata(
+    connection: impl PgExecutor<'_>,
+    attachment_id: AttachmentId,
+    group_id: Uuid,
+) -> Result<Option<AttachmentMetadata>, sqlx::Error> {
+    let metadata = sqlx::query!(
+        "SELECT status, retention, uploader FROM ds_attachments
+        WHERE attachment_id = $1 AND group_id = $2",
+        attachment_id.as_uuid(),
+        group_id,
+    )
+    .fetch_optional(connection)
+    .await?
+    .map(|record| AttachmentMetadata {
+        status: AttachmentStatus::from(record.status),
+        retention: Retention::from(record.retention),
+        uploader: record.uploader,
+    });
+    Ok(metadata)
+}
+
+/// Lock the metadata of the given attachment. Returns false if the
+/// attachment doesn't exist (anymore).
+async fn lock_metadata(
+    connection: impl PgExecutor<'_>,
+    attachment_id: AttachmentId,
+    group_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let locked = sqlx::query_scalar!(
+        "SELECT attachment_id FROM ds_attachments
+        WHERE attachment_id = $1 AND group_id = $2
+        FOR UPDATE",
+        attachment_id.as_uuid(),
+        group_id,
+    )
+    .fetch_optional(connection)
+    .await?;
+    Ok(locked.is_some())
+}
+
+async fn load_uploaded_by(
+    connection: impl PgExecutor<'_>,
+    group_id: Uuid,
+    uploader: &[u8],
+) -> Result<Vec<AttachmentId>, sqlx::Error> {
+    let attachment_ids = sqlx::query_scalar!(
+        "SELECT attachment_id FROM ds_attachments
+        WHERE group_id = $1 AND uploader = $2 AND retention <> $3",
+        group_id,
+        uploader,
+        Retention::Archive as i16,
+    )
+    .fetch_all(connection)
+    .await?;
+    Ok(attachment_ids.into_iter().map(AttachmentId::from).collect())
+}
+
+async fn delete_metadata(
+    connection: impl PgExecutor<'_>,
+    attachment_id: AttachmentId,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM ds_attachments WHERE attachment_id = $1",
+        attachment_id.as_uuid(),
+    )
+    .execute(connection)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{crypto::ear::Ciphertext, identifiers::Fqdn};
+    use tls_codec::{DeserializeBytes, VLBytes};
+
+    use crate::{
+        ds::{group_state::StorableDsGroupData, Ds},
+        infra_service::InfraService,
+    };
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn archives_are_kept_when_attachments_are_deleted(pool: PgPool) {
+        let ds = Ds::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .expect("Error creating ephemeral Ds instance.");
+        let group_uuid = Uuid::new_v4();
+        assert!(ds.reserve_group_id(group_uuid).await);
+        let group_id = ds.claim_reserved_group_id(group_uuid).await.unwrap();
+        StorableDsGroupData::new_and_store(&ds.db_pool, group_id, Ciphertext::dummy().into())
+            .await
+            .unwrap();
+
+        let storage =
+            AttachmentStorage::new(Arc::new(PostgresStorageProvider::new(ds.db_pool.clone())));
+        let uploader = VLBytes::new(vec![1; 32]).tls_serialize_detached().unwrap();
+        let uploader = UserKeyHash::tls_deserialize_exact_bytes(&uploader).unwrap();
+        let attachment = storage
+            .upload(
+                &ds.db_pool,
+                group_uuid,
+                Some(&uploader),
+                b"attachment",
+                Retention::Ephemeral,
+            )
+            .await
+            .unwrap();
+        let archive = storage
+            .upload(
+                &ds.db_pool,
+                group_uuid,
+                Some(&uploader),
+                b"archive",
+                Retention::Archive,
+            )
+            .await
+            .unwrap();
+
+        let deleted = storage
+            .delete_uploaded_by(&ds.db_pool, group_uuid, &uploader)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+        let download = storage
+            .download(&ds.db_pool, group_uuid, attachment.attachment_id, None)
+            .await
+            .unwrap();
+        assert_eq!(download, None);
+        let download = storage
+            .download(&ds.db_pool, group_uuid, archive.attachment_id, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            download,
+            Some(AttachmentDownload::Available(b"archive".to_vec()))
+        );
+    }
+
+    #[sqlx::test]
+    async fn view_once_attachments_are_deleted_after_first_download(pool: PgPool) {
+        let ds = Ds::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .expect("Error creating ephemeral Ds instance.");
+        let group_uuid = Uuid::new_v4();
+        assert!(ds.reserve_group_id(group_uuid).await);
+        let group_id = ds.claim_reserved_group_id(group_uuid).await.unwrap();
+        StorableDsGroupData::new_and_store(&ds.db_pool, group_id, Ciphertext::dummy().into())
+            .await
+            .unwrap();
+
+        let storage =
+            AttachmentStorage::new(Arc::new(PostgresStorageProvider::new(ds.db_pool.clone())));
+        let uploader = VLBytes::new(vec![1; 32]).tls_serialize_detached().unwrap();
+        let uploader = UserKeyHash::tls_deserialize_exact_bytes(&uploader).unwrap();
+        let recipient = VLBytes::new(vec![2; 32]).tls_serialize_detached().unwrap();
+        let recipient = UserKeyHash::tls_deserialize_exact_bytes(&recipient).unwrap();
+        let attachment = storage
+            .upload(
+                &ds.db_pool,
+                group_uuid,
+                Some(&uploader),
+                b"view once",
+                Retention::ViewOnce,
+            )
+            .await
+            .unwrap();
+        let expected = Some(AttachmentDownload::Available(b"view once".to_vec()));
+
+        // Downloads by the uploader don't consume the attachment
+        let download = storage
+            .download(
+                &ds.db_pool,
+                group_uuid,
+                attachment.attachment_id,
+                Some(&uploader),
+            )
+            .await
+            .unwrap();
+        assert_eq!(download, expected);
+
+        // The first download by a recipient does
+        let download = storage
+            .download(
+                &ds.db_pool,
+                group_uuid,
+                attachment.attachment_id,
+                Some(&recipient),
+            )
+            .await
+            .unwrap();
+        assert_eq!(download, expected);
+        let download = storage
+            .download(
+                &ds.db_pool,
+                group_uuid,
+                attachment.attachment_id,
+                Some(&recipient),
+            )
+            .await
+            .unwrap();
+        assert_eq!(download, None);
+        let download = storage
+            .download(
+                &ds.db_pool,
+                group_uuid,
+                attachment.attachment_id,
+                Some(&uploader),
+            )
+            .await
+            .unwrap();
+        assert_eq!(download, None);
+    }
+}
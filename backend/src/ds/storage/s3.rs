@@ -0,0 +1,302 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Storage provider backed by an S3-compatible object store.
+//!
+//! Objects are stored under the `attachments/` prefix. Quarantined objects are
+//! moved to the `quarantine/` prefix, so that they can't be reached via
+//! presigned URLs anymore. Objects are tagged with the lifecycle tag of their
+//! retention class, which operators can use to apply bucket expiration rules.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::{retry::RetryConfig, BehaviorVersion, Credentials, Region},
+    error::DisplayErrorContext,
+    operation::get_object::GetObjectError,
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart, TaggingDirective},
+    Client,
+};
+use phnxtypes::identifiers::AttachmentId;
+
+use crate::settings::S3StorageSettings;
+
+use super::{ObjectStoreError, Retention, StorageProvider};
+
+/// S3 rejects parts smaller than 5 MiB, except for the last one.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+const QUARANTINE_TAG: &str = "phnx-status=quarantined";
+
+#[derive(Debug, Clone)]
+pub(super) struct S3StorageProvider {
+    client: Client,
+    bucket: String,
+    multipart_threshold: usize,
+    part_size: usize,
+    presigned_url_expiry: Option<Duration>,
+    lifecycle_tag: Option<String>,
+    archive_lifecycle_tag: Option<String>,
+}
+
+fn backend_error(e: impl std::error::Error) -> ObjectStoreError {
+    ObjectStoreError::Backend(DisplayErrorContext(e).to_string())
+}
+
+fn object_key(id: &AttachmentId) -> String {
+    format!("attachments/{}", id)
+}
+
+fn quarantine_key(id: &AttachmentId) -> String {
+    format!("quarantine/{}", id)
+}
+
+impl S3StorageProvider {
+    pub(super) async fn new(settings: &S3StorageSettings) -> Self {
+        // Transient errors (throttling, timeouts, 5xx responses) are retried
+        // by the SDK with exponential backoff and jitter.
+        let retry_config = RetryConfig::standard()
+            .with_max_attempts(settings.max_attempts)
+            .with_initial_backoff(Duration::from_millis(settings.initial_backoff_ms));
+        let mut loader = aws_config::defaults(BehaviorVersion::latest())
+            .region(Region::new(settings.region.clone()))
+            .retry_config(retry_config);
+        if let Some(endpoint) = &settings.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (&settings.access_key_id, &settings.secret_access_key)
+        {
+            loader = loader.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                None,
+                None,
+                "phnx-settings",
+            ));
+        }
+        let sdk_config = loader.load().await;
+        let config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(settings.force_path_style)
+            .build();
+        Self {
+            client: Client::from_conf(config),
+            bucket: settings.bucket.clone(),
+            multipart_threshold: settings.multipart_threshold_bytes,
+            part_size: settings.multipart_part_size_bytes.max(MIN_PART_SIZE),
+            presigned_url_expiry: settings.presigned_url_expiry_secs.map(Duration::from_secs),
+            lifecycle_tag: settings.lifecycle_tag.clone(),
+            archive_lifecycle_tag: settings.archive_lifecycle_tag.clone(),
+        }
+    }
+
+    fn lifecycle_tag(&self, retention: Retention) -> Option<String> {
+        match retention {
+            Retention::Ephemeral | Retention::ViewOnce => self.lifecycle_tag.clone(),
+            Retention::Archive => self.archive_lifecycle_tag.clone(),
+        }
+    }
+
+    async fn put_single(
+        &self,
+        key: &str,
+        content: &[u8],
+        tag: Option<String>,
+    ) -> Result<(), ObjectStoreError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_tagging(tag)
+            .body(ByteStream::from(content.to_vec()))
+            .send()
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn put_multipart(
+        &self,
+        key: &str,
+        content: &[u8],
+        tag: Option<String>,
+    ) -> Result<(), ObjectStoreError> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_tagging(tag)
+            .send()
+            .await
+            .map_err(backend_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| ObjectStoreError::Backend("Missing multipart upload id".to_owned()))?;
+
+        match self.upload_parts(key, upload_id, content).await {
+            Ok(parts) => {
+                self.client
+                    .complete_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await
+                    .map_err(backend_error)?;
+                Ok(())
+            }
+            Err(e) => {
+                // Abort the upload so that the uploaded parts don't linger in
+                // the bucket.
+                if let Err(abort_error) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    tracing::warn!(
+                        "Could not abort multipart upload: {}",
+                        DisplayErrorContext(abort_error)
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        content: &[u8],
+    ) -> Result<Vec<CompletedPart>, ObjectStoreError> {
+        let mut parts = Vec::new();
+        for (index, chunk) in content.chunks(self.part_size).enumerate() {
+            // Part numbers start at 1.
+            let part_number = index as i32 + 1;
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(chunk.to_vec()))
+                .send()
+                .await
+                .map_err(backend_error)?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(ToOwned::to_owned))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+        Ok(parts)
+    }
+}
+
+#[async_trait]
+impl StorageProvider for S3StorageProvider {
+    async fn put(
+        &self,
+        id: &AttachmentId,
+        content: &[u8],
+        retention: Retention,
+    ) -> Result<(), ObjectStoreError> {
+        let key = object_key(id);
+        let tag = self.lifecycle_tag(retention);
+        if content.len() > self.multipart_threshold {
+            self.put_multipart(&key, content, tag).await
+        } else {
+            self.put_single(&key, content, tag).await
+        }
+    }
+
+    async fn get(&self, id: &AttachmentId) -> Result<Option<Vec<u8>>, ObjectStoreError> {
+        let output = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(object_key(id))
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                return match e.into_service_error() {
+                    GetObjectError::NoSuchKey(_) => Ok(None),
+                    e => Err(backend_error(e)),
+                }
+            }
+        };
+        let content = output.body.collect().await.map_err(backend_error)?;
+        Ok(Some(content.into_bytes().to_vec()))
+    }
+
+    async fn quarantine(&self, id: &AttachmentId) -> Result<(), ObjectStoreError> {
+        let key = object_key(id);
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{}", self.bucket, key))
+            .key(quarantine_key(id))
+            .tagging_directive(TaggingDirective::Replace)
+            .tagging(QUARANTINE_TAG)
+            .send()
+            .await
+            .map_err(backend_error)?;
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &AttachmentId) -> Result<(), ObjectStoreError> {
+        // Deleting a missing key succeeds, so we don't need to know whether
+        // the object was quarantined.
+        for key in [object_key(id), quarantine_key(id)] {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await
+                .map_err(backend_error)?;
+        }
+        Ok(())
+    }
+
+    async fn presigned_url(&self, id: &AttachmentId) -> Result<Option<String>, ObjectStoreError> {
+        let Some(expiry) = self.presigned_url_expiry else {
+            return Ok(None);
+        };
+        let presigning_config = PresigningConfig::expires_in(expiry).map_err(backend_error)?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(object_key(id))
+            .presigned(presigning_config)
+            .await
+            .map_err(backend_error)?;
+        Ok(Some(request.uri().to_owned()))
+    }
+}
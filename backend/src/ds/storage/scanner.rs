@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Scanners for uploaded attachments.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::settings::AttachmentScanningSettings;
+
+/// Verdict of a scanner on an attachment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// The attachment was flagged. Contains the reason given by the scanner,
+    /// e.g. the name of the detected signature.
+    Flagged(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ScanError {
+    #[error("Could not reach scanner: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Scanner timed out")]
+    Timeout,
+    #[error("Unexpected scanner response: {0}")]
+    InvalidResponse(String),
+}
+
+/// A scanner that inspects attachments after their upload completed.
+#[async_trait]
+pub trait AttachmentScanner: Send + Sync + std::fmt::Debug {
+    async fn scan(&self, content: &[u8]) -> Result<ScanVerdict, ScanError>;
+}
+
+/// Size of the chunks in which content is streamed to clamd.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Scanner adapter for the ClamAV daemon (clamd), using its `INSTREAM`
+/// command over TCP.
+#[derive(Debug, Clone)]
+pub struct ClamAvScanner {
+    address: String,
+    timeout: Duration,
+}
+
+impl ClamAvScanner {
+    pub fn new(settings: &AttachmentScanningSettings) -> Self {
+        Self {
+            address: settings.clamd_address.clone(),
+            timeout: Duration::from_secs(settings.timeout_secs),
+        }
+    }
+
+    async fn instream(&self, content: &[u8]) -> Result<String, ScanError> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in content.chunks(CLAMD_CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        // A chunk of length zero terminates the stream.
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let response = String::from_utf8_lossy(&response);
+        Ok(response.trim_end_matches('\0').trim().to_owned())
+    }
+}
+
+#[async_trait]
+impl AttachmentScanner for ClamAvScanner {
+    async fn scan(&self, content: &[u8]) -> Result<ScanVerdict, ScanError> {
+        let response = tokio::time::timeout(self.timeout, self.instream(content))
+            .await
+            .map_err(|_| ScanError::Timeout)??;
+        parse_clamd_response(&response)
+    }
+}
+
+fn parse_clamd_response(response: &str) -> Result<ScanVerdict, ScanError> {
+    let Some(result) = response.strip_prefix("stream: ") else {
+        return Err(ScanError::InvalidResponse(response.to_owned()));
+    };
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Flagged(signature.to_owned()))
+    } else {
+        Err(ScanError::InvalidResponse(response.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamd_responses() {
+        assert_eq!(
+            parse_clamd_response("stream: OK").unwrap(),
+            ScanVerdict::Clean
+        );
+        assert_eq!(
+            parse_clamd_response("stream: Eicar-Test-Signature FOUND").unwrap(),
+            ScanVerdict::Flagged("Eicar-Test-Signature".to_owned())
+        );
+        assert!(parse_clamd_response("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+}
@@ -18,12 +18,22 @@ use tls_codec::DeserializeBytes;
 use super::{
     group_state::{DsGroupState, UserProfile},
     process::USER_EXPIRATION_DAYS,
+    update_room_policy::changes_group_context,
 };
 
 impl DsGroupState {
+    /// Update the leaf of the sending client.
+    ///
+    /// Clients that aren't allowed to commit by the room policy can still
+    /// update their own leaf, e.g. to rotate their keys or credential. Their
+    /// commits must neither include pending proposals nor discard them, so
+    /// they are rejected while there are any. Instead, they propose the update
+    /// (see [`DsGroupState::propose_client_update`]) and a client that can
+    /// commit includes it in its commit.
     pub(super) fn update_client(
         &mut self,
         params: UpdateClientParams,
+        sender_can_commit: bool,
     ) -> Result<SerializedMlsMessage, ClientUpdateError> {
         // Process message (but don't apply it yet). This performs mls-assist-level validations.
         let processed_assisted_message_plus = self
@@ -44,21 +54,34 @@ impl DsGroupState {
                 return Err(ClientUpdateError::InvalidMessage);
             };
 
-        if let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
+        let client_updates = if let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
             processed_message.content()
         {
-            if staged_commit.add_proposals().count() > 0
-                || staged_commit.update_proposals().count() > 0
-            {
-                tracing::warn!("Client update message contained add or update proposals");
+            if staged_commit.add_proposals().count() > 0 || changes_group_context(staged_commit) {
+                tracing::warn!("Client update message contained add proposals");
                 return Err(ClientUpdateError::InvalidMessage);
             }
             let remove_proposals: Vec<_> = staged_commit.remove_proposals().collect();
+            let update_proposals: Vec<_> = staged_commit.update_proposals().collect();
+            if !sender_can_commit
+                && (!remove_proposals.is_empty()
+                    || !update_proposals.is_empty()
+                    || self.has_pending_proposals())
+            {
+                return Err(ClientUpdateError::CommittingNotAllowed);
+            }
+            let client_updates =
+                self.referenced_client_updates(&update_proposals)
+                    .map_err(|e| {
+                        tracing::warn!("Error processing referenced update proposals: {:?}", e);
+                        ClientUpdateError::InvalidMessage
+                    })?;
             self.process_referenced_remove_proposals(&remove_proposals)
                 .map_err(|e| {
                     tracing::warn!("Error processing referenced remove proposals: {:?}", e);
                     ClientUpdateError::InvalidMessage
                 })?;
+            client_updates
         } else {
             tracing::warn!("Client update message was not acommit");
             return Err(ClientUpdateError::InvalidMessage);
@@ -134,6 +157,8 @@ impl DsGroupState {
             }
         }
 
+        self.apply_client_updates(client_updates);
+
         // We update the client profile only if the update has changed the sender's credential.
         let new_sender_credential = self
             .group()
@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use mls_assist::{
+    group::ProcessedAssistedMessage,
+    messages::SerializedMlsMessage,
+    openmls::prelude::{Extension, Extensions, ProcessedMessageContent, Proposal, Sender},
+    provider_traits::MlsAssistProvider,
+};
+use phnxtypes::{
+    errors::GroupDataUpdateError,
+    identifiers::GROUP_DATA_EXTENSION_TYPE,
+    messages::{
+        client_ds::{InfraAadMessage, InfraAadPayload, UpdateGroupDataParams},
+        group_data::attributes_unchanged,
+    },
+    time::Duration,
+};
+use tls_codec::DeserializeBytes;
+
+use super::{group_state::DsGroupState, process::USER_EXPIRATION_DAYS};
+
+impl DsGroupState {
+    /// Update the group data extension, which holds room state that is opaque
+    /// to the DS. Every member that is allowed to commit can update it, so
+    /// the commit must leave all other extensions, in particular the room
+    /// policy, untouched. The conversation attributes in the group data must
+    /// stay the same as well, since members only take them from the group
+    /// data when they join.
+    pub(super) fn update_group_data(
+        &mut self,
+        params: UpdateGroupDataParams,
+    ) -> Result<SerializedMlsMessage, GroupDataUpdateError> {
+        // Process message (but don't apply it yet). This performs mls-assist-level validations.
+        let processed_assisted_message_plus = self
+            .group()
+            .process_assisted_message(self.provider.crypto(), params.commit)
+            .map_err(|_| GroupDataUpdateError::ProcessingError)?;
+
+        // Perform DS-level validation
+        // Make sure that we have the right message type.
+        let processed_message =
+            if let ProcessedAssistedMessage::Commit(ref processed_message, ref _group_info) =
+                &processed_assisted_message_plus.processed_assisted_message
+            {
+                processed_message
+            } else {
+                // This should be a commit.
+                tracing::warn!("Received non-commit message for update_group_data operation");
+                return Err(GroupDataUpdateError::InvalidMessage);
+            };
+
+        let aad_message = InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())
+            .map_err(|_| GroupDataUpdateError::InvalidMessage)?;
+        if !matches!(aad_message.into_payload(), InfraAadPayload::UpdateGroupData) {
+            return Err(GroupDataUpdateError::InvalidMessage);
+        }
+
+        if *processed_message.sender() != Sender::Member(params.sender) {
+            tracing::warn!("Invalid sender");
+            return Err(GroupDataUpdateError::InvalidMessage);
+        }
+
+        let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
+            processed_message.content()
+        else {
+            tracing::warn!("Invalid message content");
+            return Err(GroupDataUpdateError::InvalidMessage);
+        };
+
+        // The commit must consist of exactly one group context extensions
+        // proposal.
+        let mut proposals = staged_commit.queued_proposals();
+        let (Some(queued_proposal), None) = (proposals.next(), proposals.next()) else {
+            tracing::warn!("Unexpected proposals in update group data commit");
+            return Err(GroupDataUpdateError::InvalidMessage);
+        };
+        let Proposal::GroupContextExtensions(extensions_proposal) = queued_proposal.proposal()
+        else {
+            tracing::warn!("Unexpected proposal in update group data commit");
+            return Err(GroupDataUpdateError::InvalidMessage);
+        };
+
+        // Apart from the group data, the extensions must stay the same.
+        let is_group_data = |extension: &&Extension| {
+            matches!(extension, Extension::Unknown(GROUP_DATA_EXTENSION_TYPE, _))
+        };
+        let current_extensions = self.group().group_info().group_context().extensions();
+        let new_extensions = extensions_proposal.extensions();
+        if !new_extensions
+            .iter()
+            .any(|extension| is_group_data(&extension))
+            || !current_extensions
+                .iter()
+                .filter(|extension| !is_group_data(extension))
+                .eq(new_extensions
+                    .iter()
+                    .filter(|extension| !is_group_data(extension)))
+        {
+            tracing::warn!("Update group data commit changes other extensions");
+            return Err(GroupDataUpdateError::InvalidExtensions);
+        }
+        let group_data = |extensions: &Extensions| {
+            extensions.iter().find_map(|extension| match extension {
+                Extension::Unknown(GROUP_DATA_EXTENSION_TYPE, extension_bytes) => {
+                    Some(extension_bytes.0.clone())
+                }
+                _ => None,
+            })
+        };
+        let attributes_unchanged =
+            match (group_data(current_extensions), group_data(new_extensions)) {
+                (Some(current), Some(new)) => attributes_unchanged(&current, &new),
+                _ => false,
+            };
+        if !attributes_unchanged {
+            tracing::warn!("Update group data commit changes the conversation attributes");
+            return Err(GroupDataUpdateError::AttributesChanged);
+        }
+
+        // Finalize processing.
+        self.group.accept_processed_message(
+            self.provider.storage(),
+            processed_assisted_message_plus.processed_assisted_message,
+            Duration::days(USER_EXPIRATION_DAYS),
+        )?;
+
+        Ok(processed_assisted_message_plus.serialized_mls_message)
+    }
+}
@@ -0,0 +1,210 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use mls_assist::{
+    group::ProcessedAssistedMessage,
+    messages::SerializedMlsMessage,
+    openmls::prelude::{LeafNodeIndex, ProcessedMessageContent, Proposal, Sender, StagedCommit},
+    provider_traits::MlsAssistProvider,
+};
+use phnxtypes::{
+    errors::{DsProcessingError, RoomPolicyUpdateError},
+    identifiers::QsClientReference,
+    messages::{
+        client_ds::{InfraAadMessage, InfraAadPayload, UpdateRoomPolicyParams},
+        room_policy::{CommitRule, JoinRule, RoomPolicy, SendRule},
+        QueuePriority,
+    },
+    time::Duration,
+};
+use tls_codec::DeserializeBytes;
+
+use super::{group_state::DsGroupState, process::USER_EXPIRATION_DAYS};
+
+/// Returns true if the commit changes the group context extensions, which
+/// hold the room policy. Only admins can change them, so commits of all
+/// other operations that do are rejected.
+pub(super) fn changes_group_context(staged_commit: &StagedCommit) -> bool {
+    staged_commit.queued_proposals().any(|queued_proposal| {
+        matches!(
+            queued_proposal.proposal(),
+            Proposal::GroupContextExtensions(_)
+        )
+    })
+}
+
+impl DsGroupState {
+    pub(super) fn update_room_policy(
+        &mut self,
+        params: UpdateRoomPolicyParams,
+    ) -> Result<SerializedMlsMessage, RoomPolicyUpdateError> {
+        // Process message (but don't apply it yet). This performs mls-assist-level validations.
+        let processed_assisted_message_plus = self
+            .group()
+            .process_assisted_message(self.provider.crypto(), params.commit)
+            .map_err(|_| RoomPolicyUpdateError::ProcessingError)?;
+
+        // Perform DS-level validation
+        // Make sure that we have the right message type.
+        let processed_message =
+            if let ProcessedAssistedMessage::Commit(ref processed_message, ref _group_info) =
+                &processed_assisted_message_plus.processed_assisted_message
+            {
+                processed_message
+            } else {
+                // This should be a commit.
+                tracing::warn!("Received non-commit message for update_room_policy operation");
+                return Err(RoomPolicyUpdateError::InvalidMessage);
+            };
+
+        let aad_message = InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())
+            .map_err(|_| RoomPolicyUpdateError::InvalidMessage)?;
+        if !matches!(
+            aad_message.into_payload(),
+            InfraAadPayload::UpdateRoomPolicy
+        ) {
+            return Err(RoomPolicyUpdateError::InvalidMessage);
+        }
+
+        // Check if sender index and user profile match.
+        if let Sender::Member(leaf_index) = processed_message.sender() {
+            // There should be a user profile. If there wasn't, verification should have failed.
+            if !self
+                .user_profiles
+                .get(&params.sender)
+                .ok_or(RoomPolicyUpdateError::LibraryError)?
+                .clients
+                .contains(leaf_index)
+            {
+                tracing::warn!("Missing user profile");
+                return Err(RoomPolicyUpdateError::InvalidMessage);
+            };
+        } else {
+            tracing::warn!("Invalid sender");
+            return Err(RoomPolicyUpdateError::InvalidMessage);
+        }
+
+        // Only admins under the current policy can change the policy.
+        let current_policy =
+            RoomPolicy::from_extensions(self.group().group_info().group_context().extensions())
+                .map_err(|_| RoomPolicyUpdateError::LibraryError)?;
+        if !current_policy.is_admin(&params.sender) {
+            tracing::warn!("Sender is not an admin of the room");
+            return Err(RoomPolicyUpdateError::NotAdmin);
+        }
+
+        let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
+            processed_message.content()
+        else {
+            tracing::warn!("Invalid message content");
+            return Err(RoomPolicyUpdateError::InvalidMessage);
+        };
+
+        // The commit must consist of exactly one group context extensions
+        // proposal.
+        let mut proposals = staged_commit.queued_proposals();
+        let (Some(queued_proposal), None) = (proposals.next(), proposals.next()) else {
+            tracing::warn!("Unexpected proposals in update room policy commit");
+            return Err(RoomPolicyUpdateError::InvalidMessage);
+        };
+        let Proposal::GroupContextExtensions(extensions_proposal) = queued_proposal.proposal()
+        else {
+            tracing::warn!("Unexpected proposal in update room policy commit");
+            return Err(RoomPolicyUpdateError::InvalidMessage);
+        };
+
+        // The new policy must be valid and must leave at least one admin.
+        let new_policy = RoomPolicy::from_extensions(extensions_proposal.extensions())
+            .map_err(|_| RoomPolicyUpdateError::InvalidPolicy)?;
+        if new_policy.admins.is_empty()
+            || new_policy
+                .admins
+                .iter()
+                .any(|admin| !self.user_profiles.contains_key(admin))
+        {
+            tracing::warn!("Room policy without valid admins");
+            return Err(RoomPolicyUpdateError::InvalidPolicy);
+        }
+        // Message requests are limited rooms by construction. A room can't be
+        // turned into one later on, and a message request can't be turned
+        // into a regular room, e.g. to invite others before it is accepted.
+        if (new_policy.join_rule == JoinRule::MessageRequest)
+            != (current_policy.join_rule == JoinRule::MessageRequest)
+        {
+            tracing::warn!("Room policy can't be changed to or from a message request");
+            return Err(RoomPolicyUpdateError::InvalidPolicy);
+        }
+
+        // Finalize processing.
+        self.group.accept_processed_message(
+            self.provider.storage(),
+            processed_assisted_message_plus.processed_assisted_message,
+            Duration::days(USER_EXPIRATION_DAYS),
+        )?;
+
+        Ok(processed_assisted_message_plus.serialized_mls_message)
+    }
+
+    /// Returns true if the room policy allows the client with the given leaf
+    /// index to send a message with the given priority.
+    ///
+    /// The send rule only applies to chat content, which clients send with
+    /// [`QueuePriority::High`]. Receipts and profile keys of all members are
+    /// always delivered.
+    pub(super) fn can_send(
+        &self,
+        sender: LeafNodeIndex,
+        priority: QueuePriority,
+    ) -> Result<bool, DsProcessingError> {
+        if priority != QueuePriority::High {
+            return Ok(true);
+        }
+        let room_policy =
+            RoomPolicy::from_extensions(self.group().group_info().group_context().extensions())
+                .map_err(|_| DsProcessingError::ProcessingError)?;
+        if room_policy.send_rule == SendRule::AllMembers {
+            return Ok(true);
+        }
+        // Clients of users that haven't set their user key yet can't be
+        // admins.
+        let can_send = self
+            .user_profiles
+            .iter()
+            .find(|(_, user_profile)| user_profile.clients.contains(&sender))
+            .is_some_and(|(user_key_hash, _)| room_policy.can_send(user_key_hash));
+        Ok(can_send)
+    }
+
+    /// Returns true if the room policy allows the client with the given leaf
+    /// index to commit.
+    pub(super) fn can_commit(&self, sender: LeafNodeIndex) -> Result<bool, DsProcessingError> {
+        let room_policy =
+            RoomPolicy::from_extensions(self.group().group_info().group_context().extensions())
+                .map_err(|_| DsProcessingError::ProcessingError)?;
+        if room_policy.commit_rule == CommitRule::AllMembers {
+            return Ok(true);
+        }
+        let can_commit = self
+            .user_profiles
+            .iter()
+            .find(|(_, user_profile)| user_profile.clients.contains(&sender))
+            .is_some_and(|(user_key_hash, _)| room_policy.can_commit(user_key_hash));
+        Ok(can_commit)
+    }
+
+    /// Returns the queue references of all clients of the admins under the
+    /// given policy.
+    pub(super) fn admin_client_references(
+        &self,
+        room_policy: &RoomPolicy,
+    ) -> Vec<QsClientReference> {
+        self.user_profiles
+            .iter()
+            .filter(|(user_key_hash, _)| room_policy.is_admin(user_key_hash))
+            .flat_map(|(_, user_profile)| user_profile.clients.iter())
+            .filter_map(|client_index| self.client_profiles.get(client_index))
+            .map(|client_profile| client_profile.client_queue_config.clone())
+            .collect()
+    }
+}
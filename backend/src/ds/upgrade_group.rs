@@ -0,0 +1,254 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use mls_assist::{
+    group::ProcessedAssistedMessage,
+    messages::SerializedMlsMessage,
+    openmls::prelude::{ProcessedMessageContent, Proposal, Sender},
+    provider_traits::MlsAssistProvider,
+};
+use phnxtypes::{
+    crypto::ear::keys::GroupStateEarKey,
+    errors::{DsProcessingError, GroupUpgradeError},
+    identifiers::QualifiedGroupId,
+    messages::{
+        client_ds::{InfraAadMessage, InfraAadPayload, UpgradeGroupParams},
+        room_policy::RoomPolicy,
+        room_upgrade::{added_tombstone, GroupPredecessor, GroupTombstone},
+    },
+    time::Duration,
+};
+use tls_codec::DeserializeBytes;
+
+use super::{
+    group_state::{DsGroupState, StorableDsGroupData},
+    process::USER_EXPIRATION_DAYS,
+    Ds,
+};
+
+impl DsGroupState {
+    /// Returns true if a tombstone was committed to the group, i.e. if the
+    /// group was upgraded to a successor group.
+    pub(super) fn is_upgraded(&self) -> Result<bool, DsProcessingError> {
+        let tombstone =
+            GroupTombstone::from_extensions(self.group().group_info().group_context().extensions())
+                .map_err(|_| DsProcessingError::ProcessingError)?;
+        Ok(tombstone.is_some())
+    }
+
+    /// Process a commit that adds a tombstone to the group. Returns the
+    /// tombstone, such that the successor group can be linked.
+    pub(super) fn upgrade_group(
+        &mut self,
+        params: UpgradeGroupParams,
+    ) -> Result<(SerializedMlsMessage, GroupTombstone), GroupUpgradeError> {
+        let current_extensions = self.group().group_info().group_context().extensions();
+        if GroupTombstone::from_extensions(current_extensions)
+            .map_err(|_| GroupUpgradeError::LibraryError)?
+            .is_some()
+        {
+            return Err(GroupUpgradeError::AlreadyUpgraded);
+        }
+
+        // Process message (but don't apply it yet). This performs mls-assist-level validations.
+        let processed_assisted_message_plus = self
+            .group()
+            .process_assisted_message(self.provider.crypto(), params.commit)
+            .map_err(|_| GroupUpgradeError::ProcessingError)?;
+
+        // Perform DS-level validation
+        // Make sure that we have the right message type.
+        let processed_message =
+            if let ProcessedAssistedMessage::Commit(ref processed_message, ref _group_info) =
+                &processed_assisted_message_plus.processed_assisted_message
+            {
+                processed_message
+            } else {
+                // This should be a commit.
+                tracing::warn!("Received non-commit message for upgrade_group operation");
+                return Err(GroupUpgradeError::InvalidMessage);
+            };
+
+        let aad_message = InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())
+            .map_err(|_| GroupUpgradeError::InvalidMessage)?;
+        let InfraAadPayload::UpgradeGroup(authorization) = aad_message.into_payload() else {
+            return Err(GroupUpgradeError::InvalidMessage);
+        };
+        // Members check the authorization instead of the sender, which only
+        // the DS sees.
+        if authorization.admin_key.hash() != params.sender {
+            tracing::warn!("Upgrade authorization doesn't match the sender");
+            return Err(GroupUpgradeError::InvalidMessage);
+        }
+
+        // Check if sender index and user profile match.
+        if let Sender::Member(leaf_index) = processed_message.sender() {
+            // There should be a user profile. If there wasn't, verification should have failed.
+            if !self
+                .user_profiles
+                .get(&params.sender)
+                .ok_or(GroupUpgradeError::LibraryError)?
+                .clients
+                .contains(leaf_index)
+            {
+                tracing::warn!("Missing user profile");
+                return Err(GroupUpgradeError::InvalidMessage);
+            };
+        } else {
+            tracing::warn!("Invalid sender");
+            return Err(GroupUpgradeError::InvalidMessage);
+        }
+
+        // Only admins can upgrade the room.
+        let room_policy = RoomPolicy::from_extensions(current_extensions)
+            .map_err(|_| GroupUpgradeError::LibraryError)?;
+        if !room_policy.is_admin(&params.sender) {
+            tracing::warn!("Sender is not an admin of the room");
+            return Err(GroupUpgradeError::NotAdmin);
+        }
+
+        let ProcessedMessageContent::StagedCommitMessage(staged_commit) =
+            processed_message.content()
+        else {
+            tracing::warn!("Invalid message content");
+            return Err(GroupUpgradeError::InvalidMessage);
+        };
+
+        // The commit must consist of exactly one group context extensions
+        // proposal, which only adds the tombstone.
+        let mut proposals = staged_commit.queued_proposals();
+        let (Some(queued_proposal), None) = (proposals.next(), proposals.next()) else {
+            tracing::warn!("Unexpected proposals in upgrade group commit");
+            return Err(GroupUpgradeError::InvalidMessage);
+        };
+        let Proposal::GroupContextExtensions(extensions_proposal) = queued_proposal.proposal()
+        else {
+            tracing::warn!("Unexpected proposal in upgrade group commit");
+            return Err(GroupUpgradeError::InvalidMessage);
+        };
+        let Some(tombstone) = added_tombstone(current_extensions, extensions_proposal.extensions())
+        else {
+            tracing::warn!("Upgrade group commit changes more than the tombstone");
+            return Err(GroupUpgradeError::InvalidMessage);
+        };
+        let group_context = self.group().group_info().group_context();
+        if &tombstone.successor == group_context.group_id() {
+            return Err(GroupUpgradeError::InvalidSuccessor);
+        }
+        if authorization
+            .verify(group_context.group_id(), group_context.epoch(), &tombstone)
+            .is_err()
+        {
+            tracing::warn!("Invalid upgrade authorization");
+            return Err(GroupUpgradeError::InvalidMessage);
+        }
+
+        // All members have to be able to join the successor group.
+        let all_members_support_target = self.group().members().all(|member| {
+            self.group()
+                .leaf(member.index)
+                .is_some_and(|leaf| tombstone.target.supported_by(leaf.capabilities()))
+        });
+        if !all_members_support_target {
+            tracing::warn!("Upgrade target is not supported by all members");
+            return Err(GroupUpgradeError::UnsupportedTarget);
+        }
+
+        // Finalize processing.
+        self.group.accept_processed_message(
+            self.provider.storage(),
+            processed_assisted_message_plus.processed_assisted_message,
+            Duration::days(USER_EXPIRATION_DAYS),
+        )?;
+
+        Ok((
+            processed_assisted_message_plus.serialized_mls_message,
+            tombstone,
+        ))
+    }
+}
+
+impl Ds {
+    /// Link the successor of an upgraded group to the upgraded group, such
+    /// that the users of the upgraded group can join the successor. Linking
+    /// the same groups again only updates the users that can join.
+    pub(super) async fn link_successor_group(
+        &self,
+        predecessor: &DsGroupState,
+        tombstone: &GroupTombstone,
+        successor_ear_key: &GroupStateEarKey,
+    ) -> Result<(), DsProcessingError> {
+        let qgid = QualifiedGroupId::try_from(tombstone.successor.clone())
+            .map_err(|_| GroupUpgradeError::InvalidSuccessor)?;
+        if qgid.owning_domain() != self.own_domain() {
+            tracing::warn!("Successor group belongs to another domain");
+            return Err(GroupUpgradeError::InvalidSuccessor.into());
+        }
+        // The successor is locked until it is updated, such that joins and
+        // other links of the successor in the meantime aren't lost.
+        let mut transaction = self.db_pool.begin().await.map_err(|e| {
+            tracing::warn!("Could not start transaction: {:?}", e);
+            DsProcessingError::StorageError
+        })?;
+        let mut successor_data = StorableDsGroupData::load_for_update(&mut *transaction, &qgid)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Could not load successor group state: {:?}", e);
+                DsProcessingError::StorageError
+            })?
+            .ok_or(GroupUpgradeError::InvalidSuccessor)?;
+        let mut successor =
+            DsGroupState::decrypt(&successor_data.encrypted_group_state, successor_ear_key)
+                .map_err(|_| GroupUpgradeError::InvalidSuccessor)?;
+
+        // The successor has to point back to the upgraded group, use the
+        // negotiated parameters and keep the room policy.
+        let predecessor_context = predecessor.group().group_info().group_context();
+        let successor_context = successor.group().group_info().group_context();
+        let predecessor_pointer = GroupPredecessor::from_extensions(successor_context.extensions())
+            .map_err(|_| GroupUpgradeError::InvalidSuccessor)?;
+        let successor_policy = RoomPolicy::from_extensions(successor_context.extensions())
+            .map_err(|_| GroupUpgradeError::InvalidSuccessor)?;
+        let predecessor_policy = RoomPolicy::from_extensions(predecessor_context.extensions())
+            .map_err(|_| DsProcessingError::ProcessingError)?;
+        if predecessor_pointer.map(|pointer| pointer.predecessor)
+            != Some(predecessor_context.group_id().clone())
+            || successor_context.ciphersuite() != tombstone.target.ciphersuite
+            || successor_context.protocol_version() != tombstone.target.version
+            || successor_policy != predecessor_policy
+        {
+            tracing::warn!("Successor group doesn't match the upgraded group");
+            return Err(GroupUpgradeError::InvalidSuccessor.into());
+        }
+
+        // Users that haven't set their user key yet can't prove that they
+        // were members and have to be invited again.
+        successor.migrating_users = predecessor
+            .user_profiles
+            .iter()
+            .filter(|(user_key_hash, _)| !successor.user_profiles.contains_key(user_key_hash))
+            .map(|(user_key_hash, user_profile)| {
+                (user_key_hash.clone(), user_profile.user_auth_key.clone())
+            })
+            .collect();
+
+        successor_data.encrypted_group_state =
+            successor.encrypt(successor_ear_key).map_err(|e| {
+                tracing::error!("Could not serialize group state: {:?}", e);
+                DsProcessingError::CouldNotEncrypt
+            })?;
+        successor_data
+            .update(&mut *transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Could not update successor group state: {:?}", e);
+                DsProcessingError::StorageError
+            })?;
+        transaction.commit().await.map_err(|e| {
+            tracing::error!("Could not commit transaction: {:?}", e);
+            DsProcessingError::StorageError
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Cache of serialized welcome info.
+//!
+//! When many users are added to a large group in a single commit, each of
+//! the new members requests the ratchet tree of the same epoch. Instead of
+//! looking up the past group state and serializing the tree for every
+//! request, the DS caches the serialized tree per group and epoch. Only trees
+//! of the current epoch are cached and the entries of a group are invalidated
+//! whenever a commit is applied to the group.
+//!
+//! A cached tree is only served to clients that are members in its epoch,
+//! i.e. whose leaf is in the tree anyway. Other clients are checked against
+//! the past group states as without the cache.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use mls_assist::openmls::{prelude::GroupEpoch, treesync::RatchetTree};
+use tls_codec::{Serialize, Size};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::settings::WelcomeInfoCacheSettings;
+
+/// A TLS-serialized ratchet tree.
+///
+/// Serializes to the raw bytes of the tree, i.e. it is wire-compatible with
+/// [`RatchetTree`].
+#[derive(Debug, Clone)]
+pub struct SerializedRatchetTree(Arc<Vec<u8>>);
+
+impl SerializedRatchetTree {
+    fn new(ratchet_tree: &RatchetTree) -> Result<Self, tls_codec::Error> {
+        Ok(Self(Arc::new(ratchet_tree.tls_serialize_detached()?)))
+    }
+}
+
+impl Size for SerializedRatchetTree {
+    fn tls_serialized_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Serialize for SerializedRatchetTree {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len())
+    }
+}
+
+type CacheKey = (Uuid, GroupEpoch);
+
+/// A cached tree and the signature keys of the leaves that may fetch it.
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    tree: SerializedRatchetTree,
+    members: Arc<HashSet<Vec<u8>>>,
+}
+
+/// Counters that allow tuning the cache size.
+#[derive(Debug, Default)]
+struct WelcomeInfoCacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Snapshot of the cache metrics since the start of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct WelcomeInfoCacheMetricsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub cached_entries: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    trees: HashMap<CacheKey, CacheEntry>,
+    // Insertion order of the keys in `trees`, oldest first.
+    order: VecDeque<CacheKey>,
+}
+
+impl CacheEntries {
+    /// Insert the tree and return the number of evicted entries.
+    fn insert(&mut self, key: CacheKey, tree: CacheEntry, max_entries: usize) -> u64 {
+        if self.trees.insert(key, tree).is_none() {
+            self.order.push_back(key);
+        }
+        let mut evicted = 0;
+        while self.trees.len() > max_entries {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.trees.remove(&oldest);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn invalidate(&mut self, group_id: Uuid) {
+        self.trees
+            .retain(|(entry_group_id, _), _| *entry_group_id != group_id);
+        self.order
+            .retain(|(entry_group_id, _)| *entry_group_id != group_id);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct WelcomeInfoCache {
+    settings: WelcomeInfoCacheSettings,
+    entries: Mutex<CacheEntries>,
+    metrics: WelcomeInfoCacheMetrics,
+}
+
+impl WelcomeInfoCache {
+    pub(super) fn new(settings: WelcomeInfoCacheSettings) -> Self {
+        Self {
+            settings,
+            entries: Mutex::new(CacheEntries::default()),
+            metrics: WelcomeInfoCacheMetrics::default(),
+        }
+    }
+
+    /// Return the cached tree of the given group and epoch if the client
+    /// with the given leaf signature key is a member in the epoch.
+    pub(super) async fn get(
+        &self,
+        group_id: Uuid,
+        epoch: GroupEpoch,
+        signature_key: &[u8],
+    ) -> Option<SerializedRatchetTree> {
+        if !self.settings.enabled || self.settings.max_entries == 0 {
+            return None;
+        }
+        let tree = self
+            .entries
+            .lock()
+            .await
+            .trees
+            .get(&(group_id, epoch))
+            .filter(|entry| entry.members.contains(signature_key))
+            .map(|entry| entry.tree.clone());
+        let counter = match tree {
+            Some(_) => &self.metrics.hits,
+            None => &self.metrics.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        tree
+    }
+
+    /// Serialize the given tree of the given group and epoch. If `members`,
+    /// the signature keys of the leaves in the epoch, are given, the tree is
+    /// cached for them.
+    pub(super) async fn insert(
+        &self,
+        group_id: Uuid,
+        epoch: GroupEpoch,
+        ratchet_tree: &RatchetTree,
+        members: Option<HashSet<Vec<u8>>>,
+    ) -> Result<SerializedRatchetTree, tls_codec::Error> {
+        let tree = SerializedRatchetTree::new(ratchet_tree)?;
+        let Some(members) = members else {
+            return Ok(tree);
+        };
+        if !self.settings.enabled || self.settings.max_entries == 0 {
+            return Ok(tree);
+        }
+        let entry = CacheEntry {
+            tree: tree.clone(),
+            members: Arc::new(members),
+        };
+        let evicted =
+            self.entries
+                .lock()
+                .await
+                .insert((group_id, epoch), entry, self.settings.max_entries);
+        self.metrics.evictions.fetch_add(evicted, Ordering::Relaxed);
+        Ok(tree)
+    }
+
+    /// Remove all entries of the given group. Called whenever a commit is
+    /// applied to the group.
+    pub(super) async fn invalidate(&self, group_id: Uuid) {
+        if !self.settings.enabled {
+            return;
+        }
+        self.entries.lock().await.invalidate(group_id);
+    }
+
+    pub(super) async fn metrics(&self) -> WelcomeInfoCacheMetricsSnapshot {
+        WelcomeInfoCacheMetricsSnapshot {
+            hits: self.metrics.hits.load(Ordering::Relaxed),
+            misses: self.metrics.misses.load(Ordering::Relaxed),
+            evictions: self.metrics.evictions.load(Ordering::Relaxed),
+            cached_entries: self.entries.lock().await.trees.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(byte: u8) -> CacheEntry {
+        CacheEntry {
+            tree: SerializedRatchetTree(Arc::new(vec![byte])),
+            members: Arc::new(HashSet::from([vec![byte]])),
+        }
+    }
+
+    #[test]
+    fn eviction_and_invalidation() {
+        let mut entries = CacheEntries::default();
+        let group_a = Uuid::new_v4();
+        let group_b = Uuid::new_v4();
+
+        assert_eq!(entries.insert((group_a, 1u64.into()), tree(1), 2), 0);
+        assert_eq!(entries.insert((group_b, 1u64.into()), tree(2), 2), 0);
+        // Re-inserting an existing key doesn't evict anything.
+        assert_eq!(entries.insert((group_b, 1u64.into()), tree(2), 2), 0);
+        // The oldest entry is evicted first.
+        assert_eq!(entries.insert((group_b, 2u64.into()), tree(3), 2), 1);
+        assert!(!entries.trees.contains_key(&(group_a, 1u64.into())));
+
+        entries.invalidate(group_b);
+        assert!(entries.trees.is_empty());
+        assert!(entries.order.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_members_get_cached_trees() {
+        let cache = WelcomeInfoCache::new(WelcomeInfoCacheSettings::default());
+        let group_id = Uuid::new_v4();
+        let epoch = GroupEpoch::from(1u64);
+        let entry = tree(1);
+        cache
+            .entries
+            .lock()
+            .await
+            .insert((group_id, epoch), entry.clone(), 1);
+
+        let cached = cache.get(group_id, epoch, &[1]).await.unwrap();
+        assert_eq!(cached.0, entry.tree.0);
+        assert!(cache.get(group_id, epoch, &[2]).await.is_none());
+        assert!(cache.get(group_id, 2u64.into(), &[1]).await.is_none());
+
+        let metrics = cache.metrics().await;
+        assert_eq!((metrics.hits, metrics.misses), (1, 2));
+    }
+}
@@ -7,7 +7,7 @@ use phnxtypes::identifiers::Fqdn;
 use sqlx::{Executor, PgPool};
 use thiserror::Error;
 
-use crate::{errors::StorageError, settings::DatabaseSettings};
+use crate::{errors::StorageError, settings::DatabaseSettings, tenancy};
 
 #[derive(Debug, Error)]
 pub enum ServiceCreationError {
@@ -24,44 +24,79 @@ impl<T: Into<sqlx::Error>> From<T> for ServiceCreationError {
 }
 
 #[async_trait]
-pub trait InfraService: Sized {
+pub trait InfraService: Sized + Send {
     async fn new(
         database_settings: &DatabaseSettings,
         domain: Fqdn,
     ) -> Result<Self, ServiceCreationError> {
-        let connection =
-            PgPool::connect(&database_settings.connection_string_without_database()).await?;
+        let db_pool = connect_database(database_settings).await?;
 
-        let db_name = database_settings.name.as_str();
-        let db_exists = sqlx::query!(
-            "select exists (
-                SELECT datname FROM pg_catalog.pg_database WHERE datname = $1
-            )",
-            db_name,
-        )
-        .fetch_one(&connection)
-        .await?;
-
-        if !db_exists.exists.unwrap_or(false) {
-            connection
-                .execute(format!(r#"CREATE DATABASE "{}";"#, db_name).as_str())
-                .await?;
-        }
-
-        tracing::info!("Successfully created database {}", db_name);
+        Self::new_from_pool(db_pool, domain).await
+    }
 
-        let db_pool = PgPool::connect(&database_settings.connection_string()).await?;
+    /// Create one instance of the service per domain, all sharing the same
+    /// database. Each instance only has access to the rows of its domain (see
+    /// [`tenancy`]).
+    ///
+    /// Rows stored before the server served multiple domains are assigned to
+    /// the first domain.
+    async fn new_tenants(
+        database_settings: &DatabaseSettings,
+        domains: &[Fqdn],
+    ) -> Result<Vec<Self>, ServiceCreationError> {
+        let owner_pool = connect_database(database_settings).await?;
+        run_migrations(&owner_pool).await?;
+        if let Some(primary_domain) = domains.first() {
+            tenancy::claim_untenanted_rows(&owner_pool, primary_domain).await?;
+        }
 
-        Self::new_from_pool(db_pool, domain).await
+        let connect_options = owner_pool.connect_options().as_ref().clone();
+        let mut services = Vec::with_capacity(domains.len());
+        for domain in domains {
+            let db_pool = tenancy::connect_tenant_pool(connect_options.clone(), domain).await?;
+            services.push(Self::initialize(db_pool, domain.clone()).await?);
+        }
+        Ok(services)
     }
 
     async fn new_from_pool(db_pool: PgPool, domain: Fqdn) -> Result<Self, ServiceCreationError> {
-        tracing::info!("Running database migration");
-        sqlx::migrate!("./migrations").run(&db_pool).await?;
-        tracing::info!("Database migration successful");
+        run_migrations(&db_pool).await?;
 
         Self::initialize(db_pool, domain).await
     }
 
     async fn initialize(db_pool: PgPool, domain: Fqdn) -> Result<Self, ServiceCreationError>;
 }
+
+/// Connect to the database, creating it if it doesn't exist yet.
+async fn connect_database(database_settings: &DatabaseSettings) -> Result<PgPool, sqlx::Error> {
+    let connection =
+        PgPool::connect(&database_settings.connection_string_without_database()).await?;
+
+    let db_name = database_settings.name.as_str();
+    let db_exists = sqlx::query!(
+        "select exists (
+                SELECT datname FROM pg_catalog.pg_database WHERE datname = $1
+            )",
+        db_name,
+    )
+    .fetch_one(&connection)
+    .await?;
+
+    if !db_exists.exists.unwrap_or(false) {
+        connection
+            .execute(format!(r#"CREATE DATABASE "{}";"#, db_name).as_str())
+            .await?;
+    }
+
+    tracing::info!("Successfully created database {}", db_name);
+
+    PgPool::connect(&database_settings.connection_string()).await
+}
+
+async fn run_migrations(db_pool: &PgPool) -> Result<(), sqlx::migrate::MigrateError> {
+    tracing::info!("Running database migration");
+    sqlx::migrate!("./migrations").run(db_pool).await?;
+    tracing::info!("Database migration successful");
+    Ok(())
+}
@@ -6,12 +6,16 @@
 
 #![deny(unreachable_pub)]
 
+pub mod admin;
 pub mod auth_service;
 pub mod ds;
 pub mod errors;
 pub mod infra_service;
 pub mod messages;
+pub mod pg_listen;
 pub mod qs;
+mod replay_cache;
 pub mod settings;
+pub mod tenancy;
 
 pub use mls_assist::messages::{AssistedGroupInfo, AssistedMessageOut};
@@ -7,7 +7,10 @@
 
 use phnxtypes::{
     identifiers::QsClientReference,
-    messages::client_ds::{DsEventMessage, QsQueueMessagePayload},
+    messages::{
+        client_ds::{DsEventMessage, QsQueueMessagePayload},
+        QueuePriority,
+    },
 };
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
@@ -19,6 +22,16 @@ pub type QsInputMessage = DsFanOutMessage;
 pub struct DsFanOutMessage {
     pub payload: DsFanOutPayload,
     pub client_reference: QsClientReference,
+    pub priority: QueuePriority,
+}
+
+/// The same payload for a set of clients. Remote clients are reached with one
+/// request per domain, see [`crate::qs::Qs::enqueue_batch`].
+#[derive(Clone)]
+pub struct DsFanOutBatch {
+    pub payload: DsFanOutPayload,
+    pub client_references: Vec<QsClientReference>,
+    pub priority: QueuePriority,
 }
 
 #[derive(Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
@@ -2,16 +2,29 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use phnxtypes::{identifiers::Fqdn, messages::MlsInfraVersion};
+use phnxtypes::{
+    identifiers::{Fqdn, SealedClientReference},
+    messages::{MlsInfraVersion, QueuePriority},
+};
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
-use super::intra_backend::DsFanOutMessage;
+use super::intra_backend::{DsFanOutMessage, DsFanOutPayload};
 
 #[derive(TlsSerialize, TlsDeserializeBytes, TlsSize)]
 #[repr(u8)]
 pub enum QsToQsPayload {
     FanOutMessageRequest(DsFanOutMessage),
     VerificationKeyRequest,
+    FanOutBatchRequest(FanOutBatchRequest),
+}
+
+/// Fan-out of one payload to multiple clients of the recipient QS. The
+/// recipient answers with a [`crate::qs::qs_api::FanOutBatchReport`].
+#[derive(TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct FanOutBatchRequest {
+    pub payload: DsFanOutPayload,
+    pub sealed_references: Vec<SealedClientReference>,
+    pub priority: QueuePriority,
 }
 
 #[derive(TlsSerialize, TlsDeserializeBytes, TlsSize)]
@@ -0,0 +1,476 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Reliable notifications via Postgres LISTEN/NOTIFY.
+//!
+//! NOTIFY alone is fire-and-forget: notifications sent while the listening
+//! connection is down, e.g. during a failover, are lost without the listener
+//! noticing. Therefore, events are published by writing them to the
+//! `pg_listen_events` table with a per-channel sequence number and a trigger
+//! sends the notification. The sequence number of the latest event of each
+//! channel is its high-water mark. The latest event is therefore never pruned.
+//!
+//! A [`ReliableListener`] remembers the sequence number of the last event it
+//! delivered. When it sees a gap in the sequence numbers, after reconnecting,
+//! and periodically while idle, it catches up on missed events from the table.
+//! Events are delivered in order and at most once.
+
+use std::{convert::Infallible, time::Duration};
+
+use serde::Serialize;
+use sqlx::{postgres::PgListener, PgPool};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{interval, sleep, MissedTickBehavior},
+};
+
+use crate::settings::PgListenSettings;
+
+/// Number of events buffered for the consumer of a listener.
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// Publish an event on the given channel. Returns the sequence number of the
+/// event.
+///
+/// Publishes on the same channel are serialized by an advisory lock, such that
+/// the sequence numbers are committed in order and without gaps. The lock is
+/// kept in memory, so unlike a counter row, it doesn't leave a dead row
+/// version behind with each publish.
+pub async fn publish(pool: &PgPool, channel: &str, payload: &str) -> Result<i64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query(
+        "SELECT pg_advisory_xact_lock(hashtextextended(
+            pg_listen_notification_channel(
+                COALESCE(current_setting('phnx.tenant', true), ''), $1
+            ),
+            0
+        ))",
+    )
+    .bind(channel)
+    .execute(&mut *transaction)
+    .await?;
+    let seq = sqlx::query_scalar!(
+        "INSERT INTO pg_listen_events (channel, seq, payload)
+        SELECT $1, COALESCE(MAX(seq), 0) + 1, $2 FROM pg_listen_events
+        WHERE tenant = COALESCE(current_setting('phnx.tenant', true), '')
+            AND channel = $1
+        RETURNING seq",
+        channel,
+        payload,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(seq)
+}
+
+/// Sequence number of the latest event published on the given channel.
+async fn high_water_mark(pool: &PgPool, channel: &str) -> Result<i64, sqlx::Error> {
+    let seq = sqlx::query_scalar!(
+        "SELECT MAX(seq) FROM pg_listen_events
+        WHERE tenant = COALESCE(current_setting('phnx.tenant', true), '')
+            AND channel = $1",
+        channel
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(seq.unwrap_or(0))
+}
+
+/// Name under which the notifications of the given channel are sent. The
+/// channels of tenants are qualified, see [`crate::tenancy`].
+async fn notification_channel(pool: &PgPool, channel: &str) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT pg_listen_notification_channel(
+            COALESCE(current_setting('phnx.tenant', true), ''), $1
+        )",
+    )
+    .bind(channel)
+    .fetch_one(pool)
+    .await
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenEvent {
+    pub seq: i64,
+    pub payload: String,
+}
+
+/// Health of a [`ReliableListener`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PgListenHealth {
+    /// Whether the listen connection is established
+    pub connected: bool,
+    /// Sequence number of the last delivered event
+    pub last_seq: i64,
+    pub reconnects: u64,
+    /// Number of events that were delivered by catching up instead of via a
+    /// notification
+    pub caught_up_events: u64,
+    /// Number of events that were pruned before the listener could catch up
+    /// on them
+    pub lost_events: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Error)]
+enum ListenError {
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error("Listen connection lost")]
+    ConnectionLost,
+    #[error("Event receiver dropped")]
+    Closed,
+}
+
+/// Listens for events on a channel, reconnecting and catching up on missed
+/// events as necessary. Stops listening when dropped.
+#[derive(Debug)]
+pub struct ReliableListener {
+    health: watch::Receiver<PgListenHealth>,
+    task: JoinHandle<()>,
+}
+
+impl ReliableListener {
+    /// Start listening on the given channel. Only events published after the
+    /// start are delivered.
+    pub async fn start(
+        pool: PgPool,
+        channel: impl Into<String>,
+        settings: PgListenSettings,
+    ) -> Result<(Self, mpsc::Receiver<ListenEvent>), sqlx::Error> {
+        let channel = channel.into();
+        let last_seq = high_water_mark(&pool, &channel).await?;
+        Ok(Self::start_after(pool, channel, settings, last_seq))
+    }
+
+    fn start_after(
+        pool: PgPool,
+        channel: String,
+        settings: PgListenSettings,
+        last_seq: i64,
+    ) -> (Self, mpsc::Receiver<ListenEvent>) {
+        let (events_tx, events_rx) = mpsc::channel(EVENT_BUFFER_SIZE);
+        let (health_tx, health) = watch::channel(PgListenHealth {
+            last_seq,
+            ..Default::default()
+        });
+        let task = ListenerTask {
+            pool,
+            channel,
+            settings,
+            last_seq,
+            events_tx,
+            health_tx,
+        };
+        let listener = Self {
+            health,
+            task: tokio::spawn(task.run()),
+        };
+        (listener, events_rx)
+    }
+
+    pub fn health(&self) -> PgListenHealth {
+        self.health.borrow().clone()
+    }
+
+    /// Receives the health of the listener whenever it changes.
+    pub fn subscribe_health(&self) -> watch::Receiver<PgListenHealth> {
+        self.health.clone()
+    }
+}
+
+impl Drop for ReliableListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct ListenerTask {
+    pool: PgPool,
+    channel: String,
+    settings: PgListenSettings,
+    last_seq: i64,
+    events_tx: mpsc::Sender<ListenEvent>,
+    health_tx: watch::Sender<PgListenHealth>,
+}
+
+impl ListenerTask {
+    async fn run(mut self) {
+        let min_delay = Duration::from_millis(self.settings.reconnect_min_delay_ms);
+        let max_delay = Duration::from_millis(self.settings.reconnect_max_delay_ms);
+        let mut delay = min_delay;
+        loop {
+            let error = match self.listen(&mut delay).await {
+                Ok(never) => match never {},
+                Err(ListenError::Closed) => return,
+                Err(error) => error,
+            };
+            tracing::warn!(
+                channel = %self.channel,
+                %error,
+                "Postgres listener failed, reconnecting in {delay:?}"
+            );
+            self.health_tx.send_modify(|health| {
+                health.connected = false;
+                health.reconnects += 1;
+                health.last_error = Some(error.to_string());
+            });
+            sleep(delay).await;
+            delay = (delay * 2).min(max_delay);
+        }
+    }
+
+    async fn listen(&mut self, delay: &mut Duration) -> Result<Infallible, ListenError> {
+        let notification_channel = notification_channel(&self.pool, &self.channel).await?;
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(&notification_channel).await?;
+        // From here on, notifications are received, so catching up now
+        // doesn't miss any events.
+        self.catch_up().await?;
+        self.health_tx.send_modify(|health| {
+            health.connected = true;
+            health.last_error = None;
+        });
+        *delay = Duration::from_millis(self.settings.reconnect_min_delay_ms);
+
+        let mut catch_up_interval = interval(Duration::from_secs(
+            self.settings.catch_up_interval_secs.max(1),
+        ));
+        catch_up_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        catch_up_interval.tick().await;
+        loop {
+            tokio::select! {
+                notification = listener.try_recv() => {
+                    let notification = notification?.ok_or(ListenError::ConnectionLost)?;
+                    self.handle_notification(notification.payload()).await?;
+                }
+                _ = catch_up_interval.tick() => {
+                    self.catch_up().await?;
+                    self.prune().await?;
+                }
+            }
+        }
+    }
+
+    async fn handle_notification(&mut self, notification: &str) -> Result<(), ListenError> {
+        let Some((seq, payload)) = notification
+            .split_once(':')
+            .and_then(|(seq, payload)| Some((seq.parse::<i64>().ok()?, payload)))
+        else {
+            tracing::warn!(channel = %self.channel, "Received malformed notification");
+            return Ok(());
+        };
+        if seq > self.last_seq + 1 {
+            // We missed a notification. The events are committed before their
+            // notifications are sent, so catching up includes this event.
+            self.catch_up().await?;
+        }
+        if seq > self.last_seq {
+            let event = ListenEvent {
+                seq,
+                payload: payload.to_owned(),
+            };
+            self.deliver(event).await?;
+        }
+        Ok(())
+    }
+
+    /// Deliver all events after the last delivered one.
+    async fn catch_up(&mut self) -> Result<(), ListenError> {
+        let events = sqlx::query_as!(
+            ListenEvent,
+            "SELECT seq, payload FROM pg_listen_events
+            WHERE channel = $1 AND seq > $2
+            ORDER BY seq",
+            self.channel,
+            self.last_seq,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let caught_up = events.len() as u64;
+        for event in events {
+            let lost = event.seq - self.last_seq - 1;
+            if lost > 0 {
+                tracing::warn!(
+                    channel = %self.channel,
+                    lost,
+                    "Events were pruned before the listener could catch up"
+                );
+                self.health_tx
+                    .send_modify(|health| health.lost_events += lost as u64);
+            }
+            self.deliver(event).await?;
+        }
+        if caught_up > 0 {
+            tracing::info!(channel = %self.channel, caught_up, "Caught up on missed events");
+            self.health_tx
+                .send_modify(|health| health.caught_up_events += caught_up);
+        }
+        Ok(())
+    }
+
+    async fn deliver(&mut self, event: ListenEvent) -> Result<(), ListenError> {
+        let seq = event.seq;
+        self.events_tx
+            .send(event)
+            .await
+            .map_err(|_| ListenError::Closed)?;
+        self.last_seq = seq;
+        self.health_tx.send_modify(|health| health.last_seq = seq);
+        Ok(())
+    }
+
+    /// Delete events older than the retention period, except for the latest
+    /// one, which holds the high-water mark of the channel.
+    async fn prune(&self) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM pg_listen_events
+            WHERE tenant = COALESCE(current_setting('phnx.tenant', true), '')
+                AND channel = $1
+                AND created_at < now() - make_interval(secs => $2)
+                AND seq < $3",
+            self.channel,
+            self.settings.retention_secs as f64,
+            self.last_seq,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next_event(events: &mut mpsc::Receiver<ListenEvent>) -> ListenEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("Timed out waiting for event")
+            .expect("Listener stopped")
+    }
+
+    #[sqlx::test]
+    async fn delivers_events_in_order_and_catches_up(pool: PgPool) {
+        publish(&pool, "test", "before start").await.unwrap();
+        let (listener, mut events) =
+            ReliableListener::start(pool.clone(), "test", PgListenSettings::default())
+                .await
+                .unwrap();
+        while !listener.health().connected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        publish(&pool, "test", "first").await.unwrap();
+        let event = next_event(&mut events).await;
+        assert_eq!((event.seq, event.payload.as_str()), (2, "first"));
+
+        // Simulate an event whose notification was missed
+        sqlx::raw_sql(
+            "ALTER TABLE pg_listen_events DISABLE TRIGGER pg_listen_events_notify;
+            INSERT INTO pg_listen_events (channel, seq, payload) VALUES ('test', 3, 'missed');
+            ALTER TABLE pg_listen_events ENABLE TRIGGER pg_listen_events_notify;",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        publish(&pool, "test", "second").await.unwrap();
+
+        let event = next_event(&mut events).await;
+        assert_eq!((event.seq, event.payload.as_str()), (3, "missed"));
+        let event = next_event(&mut events).await;
+        assert_eq!((event.seq, event.payload.as_str()), (4, "second"));
+
+        let health = listener.health();
+        assert_eq!(health.last_seq, 4);
+        assert!(health.caught_up_events >= 1);
+        assert_eq!(health.lost_events, 0);
+    }
+
+    #[sqlx::test]
+    async fn reconnects_and_catches_up(pool: PgPool) {
+        let settings = PgListenSettings {
+            reconnect_min_delay_ms: 10,
+            ..Default::default()
+        };
+        let (listener, mut events) = ReliableListener::start(pool.clone(), "test", settings)
+            .await
+            .unwrap();
+        let mut health = listener.subscribe_health();
+        health.wait_for(|health| health.connected).await.unwrap();
+
+        // Kill the listen connection, e.g. as in a failover
+        let terminated: i64 = sqlx::query_scalar(
+            "WITH listeners AS MATERIALIZED (
+                SELECT pid FROM pg_stat_activity
+                WHERE datname = current_database()
+                    AND pid <> pg_backend_pid()
+                    AND query ILIKE 'LISTEN%'
+            )
+            SELECT COUNT(*) FROM listeners WHERE pg_terminate_backend(pid)",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(terminated, 1);
+        publish(&pool, "test", "while reconnecting").await.unwrap();
+
+        let event = next_event(&mut events).await;
+        assert_eq!(
+            (event.seq, event.payload.as_str()),
+            (1, "while reconnecting")
+        );
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            health.wait_for(|health| health.reconnects >= 1 && health.connected),
+        )
+        .await
+        .expect("Listener didn't reconnect")
+        .unwrap();
+        assert_eq!(listener.health().last_error, None);
+
+        publish(&pool, "test", "after reconnecting").await.unwrap();
+        let event = next_event(&mut events).await;
+        assert_eq!(
+            (event.seq, event.payload.as_str()),
+            (2, "after reconnecting")
+        );
+    }
+
+    #[sqlx::test]
+    async fn pruning_keeps_the_high_water_mark(pool: PgPool) {
+        let settings = PgListenSettings {
+            catch_up_interval_secs: 1,
+            retention_secs: 0,
+            ..Default::default()
+        };
+        let (_listener, mut events) = ReliableListener::start(pool.clone(), "test", settings)
+            .await
+            .unwrap();
+        publish(&pool, "test", "first").await.unwrap();
+        publish(&pool, "test", "second").await.unwrap();
+        next_event(&mut events).await;
+        next_event(&mut events).await;
+
+        // Wait for the next catch up, which prunes all but the latest event
+        let count_events = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pg_listen_events")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while count_events().await > 1 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("Events weren't pruned");
+        assert_eq!(high_water_mark(&pool, "test").await.unwrap(), 2);
+        assert_eq!(publish(&pool, "test", "third").await.unwrap(), 3);
+    }
+}
@@ -18,10 +18,18 @@ impl Deref for StorableEncryptedAddPackage {
     }
 }
 
+/// A key package loaded for a key package batch.
+pub(super) struct FetchedAddPackage {
+    pub(super) encrypted_add_package: StorableEncryptedAddPackage,
+    pub(super) is_last_resort: bool,
+    /// Number of regular key packages the owning client has left.
+    pub(super) remaining: u32,
+}
+
 mod persistence {
     use phnxtypes::{
         identifiers::{QsClientId, QsUserId},
-        messages::FriendshipToken,
+        messages::{client_qs::KeyPackageAvailability, FriendshipToken},
     };
     use sqlx::{postgres::PgArguments, Arguments, Connection, PgConnection, PgExecutor};
 
@@ -118,13 +126,34 @@ mod persistence {
             Ok(encrypted_add_package_option)
         }
 
+        /// Number of regular (i.e. not last-resort) key packages of the given
+        /// client.
+        pub(in crate::qs) async fn count_regular(
+            connection: impl PgExecutor<'_>,
+            client_id: &QsClientId,
+        ) -> Result<u32, StorageError> {
+            let count = sqlx::query_scalar!(
+                "SELECT COUNT(*) FROM key_packages WHERE client_id = $1 AND is_last_resort = FALSE",
+                client_id as &QsClientId
+            )
+            .fetch_one(connection)
+            .await?
+            .unwrap_or_default();
+            Ok(u32::try_from(count).unwrap_or(u32::MAX))
+        }
+
+        /// Load one key package for each of the user's clients. Regular key
+        /// packages are deleted, last-resort key packages are kept.
+        ///
+        /// Also returns how many regular key packages remain after the fetch
+        /// and whether any of the returned packages is a last-resort one.
         pub(in crate::qs) async fn load_user_key_packages(
             connection: &mut PgConnection,
             friendship_token: &FriendshipToken,
-        ) -> Result<Vec<Self>, StorageError> {
+        ) -> Result<(Vec<FetchedAddPackage>, KeyPackageAvailability), StorageError> {
             let mut transaction = connection.begin().await?;
 
-            let encrypted_add_packages = sqlx::query_scalar!(
+            let records = sqlx::query!(
                 r#"WITH user_info AS (
                     -- Step 1: Fetch the user_id based on the friendship token.
                     SELECT user_id FROM qs_user_records WHERE friendship_token = $1
@@ -136,16 +165,17 @@ mod persistence {
                 ),
 
                 ranked_packages AS (
-                    -- Step 3: Rank key packages for each client.
+                    -- Step 3: Rank key packages for each client and count the regular ones.
                     SELECT p.id, p.encrypted_add_package, p.is_last_resort,
-                           ROW_NUMBER() OVER (PARTITION BY p.client_id ORDER BY p.is_last_resort ASC) AS rn
+                           ROW_NUMBER() OVER (PARTITION BY p.client_id ORDER BY p.is_last_resort ASC) AS rn,
+                           COUNT(*) FILTER (WHERE NOT p.is_last_resort) OVER (PARTITION BY p.client_id) AS regular_count
                     FROM key_packages p
                     INNER JOIN client_ids c ON p.client_id = c.client_id
                 ),
 
                 selected_add_packages AS (
                     -- Step 4: Select the best-ranked package per client (rn = 1), skipping locked rows.
-                    SELECT id, encrypted_add_package, is_last_resort
+                    SELECT id, encrypted_add_package, is_last_resort, regular_count
                     FROM ranked_packages
                     WHERE rn = 1
                     FOR UPDATE SKIP LOCKED
@@ -159,13 +189,43 @@ mod persistence {
                 )
 
                 -- Step 6: Return the encrypted_add_package from the selected packages.
-                SELECT encrypted_add_package as "eap: _" FROM selected_add_packages"#,
+                SELECT
+                    encrypted_add_package as "eap: StorableEncryptedAddPackage",
+                    is_last_resort,
+                    regular_count
+                FROM selected_add_packages"#,
                 friendship_token as &FriendshipToken
             ).fetch_all(&mut *transaction).await?;
 
             transaction.commit().await?;
 
-            Ok(encrypted_add_packages)
+            let mut availability = KeyPackageAvailability {
+                remaining: u32::MAX,
+                last_resort_used: false,
+            };
+            let mut add_packages = Vec::with_capacity(records.len());
+            for record in records {
+                let regular_count = record.regular_count.unwrap_or_default();
+                // The selected package is deleted if it's a regular one.
+                let remaining = if record.is_last_resort {
+                    regular_count
+                } else {
+                    regular_count - 1
+                };
+                let remaining = u32::try_from(remaining).unwrap_or_default();
+                availability.remaining = availability.remaining.min(remaining);
+                availability.last_resort_used |= record.is_last_resort;
+                add_packages.push(FetchedAddPackage {
+                    encrypted_add_package: record.eap,
+                    is_last_resort: record.is_last_resort,
+                    remaining,
+                });
+            }
+            if add_packages.is_empty() {
+                availability.remaining = 0;
+            }
+
+            Ok((add_packages, availability))
         }
     }
 }
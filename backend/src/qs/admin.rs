@@ -0,0 +1,306 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Operator access to the QS database, see [`crate::admin`].
+//!
+//! The QS doesn't know when a message was enqueued. A queue is considered
+//! stuck if it holds messages while its client hasn't been active for a
+//! while. Stuck messages can be requeued, i.e. the client is notified again
+//! via the channel shared by the replicas of the QS, or purged.
+
+use phnxtypes::{identifiers::QsClientId, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    admin::{AdminError, AdminService, IntegrityIssue, StuckQueue},
+    settings::ClusterDispatchSettings,
+};
+
+use super::{cluster::ClusterNotification, queue::Queue, Qs, WsNotification};
+
+impl Qs {
+    /// Queues with messages whose client hasn't been active for the given
+    /// duration, least recently active first.
+    pub async fn admin_stuck_queues(
+        &self,
+        inactive_for: Duration,
+    ) -> Result<Vec<StuckQueue>, AdminError> {
+        let inactive_since = phnxtypes::time::now() - inactive_for;
+        Ok(persistence::load_stuck_queues(&self.db_pool, inactive_since).await?)
+    }
+
+    /// Notify the client of the queue that it has pending messages. The
+    /// notification reaches the replica holding the client's websocket
+    /// connection via the channel shared by the replicas, so this fails if
+    /// the QS dispatches notifications locally.
+    pub async fn admin_requeue(
+        &self,
+        settings: &ClusterDispatchSettings,
+        queue_id: Uuid,
+    ) -> Result<(), AdminError> {
+        let Some((channel, _notifications)) = self.connect_cluster_channel(settings).await? else {
+            return Err(AdminError::LocalDispatch);
+        };
+        let notification =
+            ClusterNotification::new(QsClientId::from(queue_id), WsNotification::QueueUpdate);
+        channel.publish(&notification).await?;
+        Ok(())
+    }
+
+    /// Delete all messages of the queue. Returns the number of deleted
+    /// messages. The sequence number of the queue is kept, such that the
+    /// client continues to accept new messages.
+    pub async fn admin_purge_queue(&self, queue_id: Uuid) -> Result<u64, AdminError> {
+        Ok(persistence::delete_queue_messages(&self.db_pool, queue_id).await?)
+    }
+
+    pub async fn admin_check_integrity(&self) -> Result<Vec<IntegrityIssue>, AdminError> {
+        let mut issues: Vec<IntegrityIssue> =
+            persistence::load_clients_without_queue(&self.db_pool)
+                .await?
+                .into_iter()
+                .map(|client_id| IntegrityIssue::MissingQueue {
+                    service: AdminService::Qs,
+                    client_id,
+                })
+                .collect();
+        issues.extend(
+            persistence::load_queues_behind(&self.db_pool)
+                .await?
+                .into_iter()
+                .map(|(queue_id, sequence_number, max_stored)| {
+                    IntegrityIssue::QueueCounterBehind {
+                        service: AdminService::Qs,
+                        queue_id,
+                        sequence_number,
+                        max_stored,
+                    }
+                }),
+        );
+        issues.extend(
+            persistence::load_users_without_clients(&self.db_pool)
+                .await?
+                .into_iter()
+                .map(|user_id| IntegrityIssue::UserWithoutClients { user_id }),
+        );
+        Ok(issues)
+    }
+
+    /// Repair the given issue. Returns false if the issue doesn't concern the
+    /// QS or can't be repaired.
+    pub async fn admin_repair(&self, issue: &IntegrityIssue) -> Result<bool, AdminError> {
+        if issue.service() != AdminService::Qs {
+            return Ok(false);
+        }
+        match issue {
+            IntegrityIssue::MissingQueue { client_id, .. } => {
+                Queue::new_and_store(QsClientId::from(*client_id), &self.db_pool).await?;
+                Ok(true)
+            }
+            IntegrityIssue::QueueCounterBehind {
+                queue_id,
+                max_stored,
+                ..
+            } => {
+                persistence::advance_queue(&self.db_pool, *queue_id, max_stored + 1).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+mod persistence {
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgExecutor,
+    };
+
+    use crate::errors::StorageError;
+
+    use super::*;
+
+    pub(super) async fn load_stuck_queues(
+        connection: impl PgExecutor<'_>,
+        inactive_since: DateTime<Utc>,
+    ) -> Result<Vec<StuckQueue>, StorageError> {
+        let records = sqlx::query!(
+            r#"SELECT
+                c.client_id,
+                c.activity_time,
+                COUNT(*) AS "pending_messages!",
+                MIN(m.sequence_number) AS "oldest_sequence_number!"
+            FROM qs_client_records c
+            JOIN qs_queues m ON m.queue_id = c.client_id
+            WHERE c.activity_time < $1
+            GROUP BY c.client_id, c.activity_time
+            ORDER BY c.activity_time"#,
+            inactive_since
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| StuckQueue {
+                queue_id: record.client_id,
+                last_activity: record.activity_time,
+                pending_messages: record.pending_messages,
+                oldest_sequence_number: record.oldest_sequence_number,
+            })
+            .collect())
+    }
+
+    pub(super) async fn delete_queue_messages(
+        connection: impl PgExecutor<'_>,
+        queue_id: Uuid,
+    ) -> Result<u64, StorageError> {
+        let result = sqlx::query!("DELETE FROM qs_queues WHERE queue_id = $1", queue_id)
+            .execute(connection)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub(super) async fn load_clients_without_queue(
+        connection: impl PgExecutor<'_>,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let client_ids = sqlx::query_scalar!(
+            "SELECT c.client_id FROM qs_client_records c
+            LEFT JOIN qs_queue_data q ON q.queue_id = c.client_id
+            WHERE q.queue_id IS NULL"
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(client_ids)
+    }
+
+    pub(super) async fn load_users_without_clients(
+        connection: impl PgExecutor<'_>,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        let user_ids = sqlx::query_scalar!(
+            "SELECT u.user_id FROM qs_user_records u
+            LEFT JOIN qs_client_records c ON c.user_id = u.user_id
+            WHERE c.client_id IS NULL"
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(user_ids)
+    }
+
+    /// Queues whose next sequence number is not above the ones of the stored
+    /// messages, with the next and the highest stored sequence number.
+    pub(super) async fn load_queues_behind(
+        connection: impl PgExecutor<'_>,
+    ) -> Result<Vec<(Uuid, i64, i64)>, StorageError> {
+        let records = sqlx::query!(
+            r#"SELECT q.queue_id, q.sequence_number, MAX(m.sequence_number) AS "max_stored!"
+            FROM qs_queue_data q
+            JOIN qs_queues m ON m.queue_id = q.queue_id
+            GROUP BY q.queue_id, q.sequence_number
+            HAVING MAX(m.sequence_number) >= q.sequence_number"#
+        )
+        .fetch_all(connection)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| (record.queue_id, record.sequence_number, record.max_stored))
+            .collect())
+    }
+
+    /// Never moves the sequence number backwards.
+    pub(super) async fn advance_queue(
+        connection: impl PgExecutor<'_>,
+        queue_id: Uuid,
+        sequence_number: i64,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            "UPDATE qs_queue_data SET sequence_number = $2
+            WHERE queue_id = $1 AND sequence_number < $2",
+            queue_id,
+            sequence_number
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::Fqdn;
+    use sqlx::PgPool;
+
+    use crate::infra_service::InfraService;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn queue_counter_behind_is_repaired(pool: PgPool) {
+        let qs = Qs::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .unwrap();
+        let user_id = Uuid::new_v4();
+        let client_id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO qs_user_records (user_id, friendship_token, verifying_key)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(user_id)
+        .bind(vec![1u8])
+        .bind(vec![2u8])
+        .execute(&qs.db_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO qs_client_records (client_id, user_id, owner_public_key,
+                owner_signature_key, ratchet, activity_time)
+            VALUES ($1, $2, $3, $3, $3, now() - interval '30 days')",
+        )
+        .bind(client_id)
+        .bind(user_id)
+        .bind(vec![3u8])
+        .execute(&qs.db_pool)
+        .await
+        .unwrap();
+
+        let issues = qs.admin_check_integrity().await.unwrap();
+        let missing_queue = IntegrityIssue::MissingQueue {
+            service: AdminService::Qs,
+            client_id,
+        };
+        assert_eq!(issues, vec![missing_queue.clone()]);
+        assert!(qs.admin_repair(&missing_queue).await.unwrap());
+
+        // A message at the sequence number the counter still points to
+        sqlx::query(
+            "INSERT INTO qs_queues (queue_id, sequence_number, message_bytes, priority)
+            VALUES ($1, 0, $2, 0)",
+        )
+        .bind(client_id)
+        .bind(vec![4u8])
+        .execute(&qs.db_pool)
+        .await
+        .unwrap();
+        let issues = qs.admin_check_integrity().await.unwrap();
+        let counter_behind = IntegrityIssue::QueueCounterBehind {
+            service: AdminService::Qs,
+            queue_id: client_id,
+            sequence_number: 0,
+            max_stored: 0,
+        };
+        assert_eq!(issues, vec![counter_behind.clone()]);
+        assert!(qs.admin_repair(&counter_behind).await.unwrap());
+        assert!(qs.admin_check_integrity().await.unwrap().is_empty());
+
+        let stuck = qs.admin_stuck_queues(Duration::days(7)).await.unwrap();
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].queue_id, client_id);
+        assert_eq!(stuck[0].pending_messages, 1);
+        assert_eq!(qs.admin_purge_queue(client_id).await.unwrap(), 1);
+        assert!(qs
+            .admin_stuck_queues(Duration::days(7))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use mls_assist::{
-    openmls::prelude::{OpenMlsProvider, ProtocolVersion},
+    openmls::prelude::{Extension, OpenMlsProvider, ProtocolVersion},
     openmls_rust_crypto::OpenMlsRustCrypto,
 };
 use phnxtypes::{
@@ -15,6 +15,7 @@ use phnxtypes::{
         QsClientKeyPackageError, QsEncryptionKeyError, QsKeyPackageBatchError,
         QsPublishKeyPackagesError, QsVerifyingKeyError,
     },
+    identifiers::{QsClientReference, QS_CLIENT_REFERENCE_EXTENSION_TYPE},
     keypackage_batch::{AddPackage, AddPackageIn, KeyPackageBatchTbs},
     messages::client_qs::{
         ClientKeyPackageParams, ClientKeyPackageResponse, EncryptionKeyResponse,
@@ -24,9 +25,12 @@ use phnxtypes::{
     time::TimeStamp,
 };
 
+use tls_codec::DeserializeBytes;
+
 use crate::qs::{
     add_package::StorableEncryptedAddPackage,
     client_id_decryption_key::StorableClientIdDecryptionKey, signing_key::StorableQsSigningKey, Qs,
+    QsConnector, KEY_PACKAGE_LOW_WATERMARK,
 };
 
 impl Qs {
@@ -121,9 +125,14 @@ impl Qs {
     }
 
     /// Retrieve a key package batch for a given client.
+    ///
+    /// Clients whose key packages drop below [`KEY_PACKAGE_LOW_WATERMARK`]
+    /// with this fetch, or that had to hand out their last-resort key
+    /// package, are woken up, such that they publish new key packages.
     #[tracing::instrument(skip_all, err)]
-    pub(crate) async fn qs_key_package_batch(
+    pub(crate) async fn qs_key_package_batch<Qc: QsConnector>(
         &self,
+        qs_connector: &Qc,
         params: KeyPackageBatchParams,
     ) -> Result<KeyPackageBatchResponse, QsKeyPackageBatchError> {
         let KeyPackageBatchParams {
@@ -136,7 +145,7 @@ impl Qs {
             QsKeyPackageBatchError::StorageError
         })?;
 
-        let encrypted_key_packages =
+        let (encrypted_key_packages, availability) =
             StorableEncryptedAddPackage::load_user_key_packages(&mut connection, &sender)
                 .await
                 .map_err(|e| {
@@ -144,20 +153,27 @@ impl Qs {
                     QsKeyPackageBatchError::StorageError
                 })?;
 
-        let add_packages = encrypted_key_packages
-            .into_iter()
-            .map(|encrypted_key_package| {
-                AddPackageIn::decrypt(&friendship_ear_key, &encrypted_key_package)
-                    .map_err(|_| QsKeyPackageBatchError::DecryptionError)
-                    .and_then(|ap| {
-                        ap.validate(
-                            OpenMlsRustCrypto::default().crypto(),
-                            ProtocolVersion::default(),
-                        )
-                        .map_err(|_| QsKeyPackageBatchError::InvalidKeyPackage)
-                    })
-            })
-            .collect::<Result<Vec<_>, _>>()?;
+        let mut add_packages = Vec::with_capacity(encrypted_key_packages.len());
+        let mut drained_clients = Vec::new();
+        for fetched in encrypted_key_packages {
+            let add_package =
+                AddPackageIn::decrypt(&friendship_ear_key, &fetched.encrypted_add_package)
+                    .map_err(|_| QsKeyPackageBatchError::DecryptionError)?
+                    .validate(
+                        OpenMlsRustCrypto::default().crypto(),
+                        ProtocolVersion::default(),
+                    )
+                    .map_err(|_| QsKeyPackageBatchError::InvalidKeyPackage)?;
+            // Only wake up the client once, when the fetch crosses the
+            // watermark, and whenever its last-resort package is handed out.
+            if fetched.is_last_resort || fetched.remaining + 1 == KEY_PACKAGE_LOW_WATERMARK {
+                match qs_client_reference(&add_package) {
+                    Some(client_reference) => drained_clients.push(client_reference),
+                    None => tracing::warn!("Key package without QS client reference"),
+                }
+            }
+            add_packages.push(add_package);
+        }
 
         let key_package_refs = add_packages
             .iter()
@@ -184,9 +200,24 @@ impl Qs {
             .sign(&*signing_key)
             .map_err(|_| QsKeyPackageBatchError::LibraryError)?;
 
+        if availability.last_resort_used {
+            tracing::info!("Key package batch contains last-resort key packages");
+        }
+
+        // Waking up the clients is best effort.
+        for client_reference in drained_clients {
+            if let Err(e) = qs_connector.wake_up(client_reference).await {
+                tracing::warn!(
+                    "Failed to wake up client running out of key packages: {:?}",
+                    e
+                );
+            }
+        }
+
         let response = KeyPackageBatchResponse {
             add_packages,
             key_package_batch,
+            availability,
         };
         Ok(response)
     }
@@ -227,3 +258,18 @@ impl Qs {
             .ok_or(QsEncryptionKeyError::LibraryError)
     }
 }
+
+/// The reference to the queue of the client that published the given key
+/// package.
+fn qs_client_reference(add_package: &AddPackage) -> Option<QsClientReference> {
+    add_package
+        .key_package()
+        .leaf_node()
+        .extensions()
+        .iter()
+        .find_map(|e| match e {
+            Extension::Unknown(QS_CLIENT_REFERENCE_EXTENSION_TYPE, ref bytes) => Some(&bytes.0),
+            _ => None,
+        })
+        .and_then(|bytes| QsClientReference::tls_deserialize_exact_bytes(bytes.as_slice()).ok())
+}
@@ -5,24 +5,38 @@
 use phnxtypes::{
     crypto::signatures::signable::Verifiable,
     errors::qs::{QsDequeueError, QsProcessError},
-    messages::client_qs::{
-        DequeueMessagesParams, DequeueMessagesResponse, QsProcessResponse, QsRequestParams,
-        QsSender, VerifiableClientToQsMessage,
+    messages::{
+        client_qs::{
+            DequeueMessagesParams, DequeueMessagesResponse, QsProcessResponse, QsRequestParams,
+            QsSender, VerifiableClientToQsMessage, MAX_DEQUEUE_BATCH_SIZE,
+        },
+        server_info::ClientVersion,
     },
 };
 
-use super::{client_record::QsClientRecord, queue::Queue, user_record::UserRecord, Qs};
+use crate::replay_cache::ReplayError;
+
+use super::{
+    add_package::StorableEncryptedAddPackage, client_record::QsClientRecord, queue::Queue,
+    user_record::UserRecord, Qs, QsConnector, WebsocketNotifier, KEY_PACKAGE_LOW_WATERMARK,
+};
 
 pub(crate) mod client_records;
 pub(crate) mod key_packages;
 pub(crate) mod user_records;
 
 impl Qs {
-    pub async fn process(
+    pub async fn process<W: WebsocketNotifier, Qc: QsConnector>(
         &self,
+        websocket_notifier: &W,
+        qs_connector: &Qc,
         message: VerifiableClientToQsMessage,
+        client_version: Option<ClientVersion>,
     ) -> Result<QsProcessResponse, QsProcessError> {
-        let request_params = match message.sender() {
+        let sender = message.sender();
+        let freshness = message.freshness().clone();
+        let authenticated = !matches!(sender, QsSender::Anonymous);
+        let request_params = match sender {
             QsSender::User(user_id) => {
                 let Some(user) = UserRecord::load(&self.db_pool, &user_id)
                     .await
@@ -71,6 +85,24 @@ impl Qs {
             })?,
         };
 
+        // Reject stale and replayed requests. Anonymous requests only fetch
+        // public key material, so they don't need to occupy the nonce cache.
+        if authenticated {
+            self.replay_cache
+                .check(&self.db_pool, &freshness)
+                .await
+                .map_err(|e| match e {
+                    ReplayError::Storage(e) => {
+                        tracing::error!("Failed to check the replay cache: {e}");
+                        QsProcessError::StorageError
+                    }
+                    e => {
+                        tracing::warn!("Rejected QS request: {e}");
+                        QsProcessError::ReplayedRequest
+                    }
+                })?;
+        }
+
         Ok(match request_params {
             QsRequestParams::CreateUser(params) => {
                 QsProcessResponse::CreateUser(self.qs_create_user_record(params).await?)
@@ -101,11 +133,16 @@ impl Qs {
             QsRequestParams::ClientKeyPackage(params) => {
                 QsProcessResponse::ClientKeyPackage(self.qs_client_key_package(params).await?)
             }
-            QsRequestParams::KeyPackageBatch(params) => {
-                QsProcessResponse::KeyPackageBatch(self.qs_key_package_batch(params).await?)
-            }
+            QsRequestParams::KeyPackageBatch(params) => QsProcessResponse::KeyPackageBatch(
+                self.qs_key_package_batch(qs_connector, params).await?,
+            ),
             QsRequestParams::DequeueMessages(params) => {
-                QsProcessResponse::DequeueMessages(self.qs_dequeue_messages(params).await?)
+                let response = self.qs_dequeue_messages(params).await?;
+                if DequeueMessagesResponse::supported_by(client_version.as_ref()) {
+                    QsProcessResponse::DequeueMessagesV2(response)
+                } else {
+                    QsProcessResponse::DequeueMessages(response.into())
+                }
             }
             QsRequestParams::VerifyingKey => {
                 QsProcessResponse::VerifyingKey(self.qs_verifying_key().await?)
@@ -113,6 +150,18 @@ impl Qs {
             QsRequestParams::EncryptionKey => {
                 QsProcessResponse::EncryptionKey(self.qs_encryption_key().await?)
             }
+            QsRequestParams::PublishPresence(params) => {
+                self.qs_publish_presence(websocket_notifier, params).await?;
+                QsProcessResponse::Ok
+            }
+            QsRequestParams::FetchPresence(params) => {
+                QsProcessResponse::FetchPresence(self.qs_fetch_presence(params).await?)
+            }
+            QsRequestParams::EnqueueSelfMessage(params) => {
+                self.qs_enqueue_self_message(websocket_notifier, params)
+                    .await?;
+                QsProcessResponse::Ok
+            }
         })
     }
 
@@ -130,8 +179,9 @@ impl Qs {
             max_message_number,
         } = params;
 
-        // TODO: The backend should have its own value for max_messages and use
-        // that one if the client-given one exceeds it.
+        // Larger batches are split, such that responses stay small even after
+        // long offline periods.
+        let max_message_number = max_message_number.min(MAX_DEQUEUE_BATCH_SIZE);
         let mut connection = self.db_pool.acquire().await.map_err(|e| {
             tracing::warn!("Failed to acquire connection: {:?}", e);
             QsDequeueError::StorageError
@@ -148,10 +198,32 @@ impl Qs {
             QsDequeueError::StorageError
         })?;
 
-        let response = DequeueMessagesResponse {
+        // Once the queue is drained, let the client know if it should publish
+        // new key packages. The client is typically woken up by a push
+        // notification for the welcome that consumed its last key packages.
+        let replenish_key_packages = if remaining_messages_number == 0 {
+            let regular_key_packages =
+                StorableEncryptedAddPackage::count_regular(&mut *connection, &sender)
+                    .await
+                    .map_err(|e| {
+                        tracing::warn!("Storage provider error: {:?}", e);
+                        QsDequeueError::StorageError
+                    })?;
+            regular_key_packages < KEY_PACKAGE_LOW_WATERMARK
+        } else {
+            false
+        };
+
+        tracing::debug!(
+            batch_size = messages.len(),
+            remaining_messages_number,
+            "Dequeued QS messages"
+        );
+        let response = DequeueMessagesResponse::new(
             messages,
             remaining_messages_number,
-        };
+            replenish_key_packages,
+        );
 
         Ok(response)
     }
@@ -18,7 +18,7 @@ use phnxtypes::{
     messages::{
         client_ds::QsQueueMessagePayload,
         push_token::{EncryptedPushToken, PushToken},
-        EncryptedQsQueueMessage, QueueMessage,
+        EncryptedQsQueueMessage, QueueMessage, QueuePriority,
     },
     time::TimeStamp,
 };
@@ -223,6 +223,7 @@ impl QsClientRecord {
         websocket_notifier: &W,
         push_notification_provider: &P,
         msg: DsFanOutPayload,
+        priority: QueuePriority,
         push_token_key_option: Option<PushTokenEarKey>,
     ) -> Result<(), EnqueueError> {
         match msg {
@@ -230,10 +231,11 @@ impl QsClientRecord {
             // Serialize the message so that we can put it in the queue.
             DsFanOutPayload::QueueMessage(queue_message) => {
                 // Encrypt the message under the current ratchet key.
-                let queue_message = self
+                let mut queue_message = self
                     .ratchet_key
                     .encrypt(queue_message)
                     .map_err(|_| EnqueueError::LibraryError)?;
+                queue_message.priority = priority;
 
                 // TODO: Future work: PCS
 
@@ -251,64 +253,8 @@ impl QsClientRecord {
                     .await
                     .is_err()
                 {
-                    // Send a push notification under the following conditions:
-                    // - there is a push token associated with the queue
-                    // - there is a push token decryption key
-                    // - the decryption is successful
-                    if let Some(ref encrypted_push_token) = self.encrypted_push_token {
-                        if let Some(ref ear_key) = push_token_key_option {
-                            // Attempt to decrypt the push token.
-                            match PushToken::decrypt(ear_key, encrypted_push_token) {
-                                Err(e) => {
-                                    tracing::error!("Push token decryption failed: {}", e);
-                                }
-                                Ok(push_token) => {
-                                    // Send the push notification.
-                                    if let Err(e) =
-                                        push_notification_provider.push(push_token).await
-                                    {
-                                        match e {
-                                    // The push notification failed for some other reason.
-                                    PushNotificationError::Other(error_description) => {
-                                        tracing::error!(
-                                            "Push notification failed unexpectedly: {}",
-                                            error_description
-                                        )
-                                    }
-                                    // The token is no longer valid and should be deleted.
-                                    PushNotificationError::InvalidToken(error_description) => {
-                                        tracing::info!(
-                                            "Push notification failed because the token is invalid: {}",
-                                            error_description
-                                        );
-                                        self.encrypted_push_token = None;
-                                    }
-                                    // There was a network error when trying to send the push notification.
-                                    PushNotificationError::NetworkError(e) => tracing::info!(
-                                        "Push notification failed because of a network error: {}",
-                                        e
-                                    ),
-                                    PushNotificationError::UnsupportedType => tracing::warn!(
-                                        "Push notification failed because the push token type is unsupported",
-                                    ),
-                                    PushNotificationError::JwtCreationError(e) => tracing::error!(
-                                        "Push notification failed because the JWT token could not be created: {}",
-                                        e
-                                    ),
-                                    PushNotificationError::OAuthError(e) => tracing::error!(
-                                        "Push notification failed because of an OAuth error: {}",
-                                        e
-                                    ),
-                                    PushNotificationError::InvalidConfiguration(e) => tracing::error!(
-                                        "Push notification failed because of an invalid configuration: {}",
-                                        e
-                                    ),
-                                }
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    self.push(push_notification_provider, push_token_key_option)
+                        .await;
                 }
 
                 // We also update th client record in the storage provider,
@@ -331,4 +277,99 @@ impl QsClientRecord {
         // Success!
         Ok(())
     }
+
+    /// Notify the owner of the queue without enqueueing a message, e.g.
+    /// because it is running out of key packages. Sends a notification over
+    /// the websocket, otherwise a push notification if possible.
+    pub(crate) async fn wake_up<W: WebsocketNotifier, P: PushNotificationProvider>(
+        &mut self,
+        connection: &mut PgConnection,
+        websocket_notifier: &W,
+        push_notification_provider: &P,
+        push_token_key_option: Option<PushTokenEarKey>,
+    ) -> Result<(), EnqueueError> {
+        if websocket_notifier
+            .notify(&self.client_id, WsNotification::QueueUpdate)
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        let had_push_token = self.encrypted_push_token.is_some();
+        self.push(push_notification_provider, push_token_key_option)
+            .await;
+        // The push token is deleted if it turned out to be invalid.
+        if had_push_token && self.encrypted_push_token.is_none() {
+            self.update(connection).await.map_err(|e| {
+                tracing::error!("Failed to update client record: {:?}", e);
+                EnqueueError::Storage
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Send a push notification under the following conditions:
+    /// - there is a push token associated with the queue
+    /// - there is a push token decryption key
+    /// - the decryption is successful
+    ///
+    /// Deletes the push token from the record if it is no longer valid.
+    async fn push<P: PushNotificationProvider>(
+        &mut self,
+        push_notification_provider: &P,
+        push_token_key_option: Option<PushTokenEarKey>,
+    ) {
+        let (Some(encrypted_push_token), Some(ear_key)) =
+            (&self.encrypted_push_token, push_token_key_option)
+        else {
+            return;
+        };
+        // Attempt to decrypt the push token.
+        let push_token = match PushToken::decrypt(&ear_key, encrypted_push_token) {
+            Ok(push_token) => push_token,
+            Err(e) => {
+                tracing::error!("Push token decryption failed: {}", e);
+                return;
+            }
+        };
+        // Send the push notification.
+        let Err(e) = push_notification_provider.push(push_token).await else {
+            return;
+        };
+        match e {
+            // The push notification failed for some other reason.
+            PushNotificationError::Other(error_description) => {
+                tracing::error!(
+                    "Push notification failed unexpectedly: {}",
+                    error_description
+                )
+            }
+            // The token is no longer valid and should be deleted.
+            PushNotificationError::InvalidToken(error_description) => {
+                tracing::info!(
+                    "Push notification failed because the token is invalid: {}",
+                    error_description
+                );
+                self.encrypted_push_token = None;
+            }
+            // There was a network error when trying to send the push notification.
+            PushNotificationError::NetworkError(e) => {
+                tracing::info!("Push notification failed because of a network error: {}", e)
+            }
+            PushNotificationError::UnsupportedType => tracing::warn!(
+                "Push notification failed because the push token type is unsupported",
+            ),
+            PushNotificationError::JwtCreationError(e) => tracing::error!(
+                "Push notification failed because the JWT token could not be created: {}",
+                e
+            ),
+            PushNotificationError::OAuthError(e) => {
+                tracing::error!("Push notification failed because of an OAuth error: {}", e)
+            }
+            PushNotificationError::InvalidConfiguration(e) => tracing::error!(
+                "Push notification failed because of an invalid configuration: {}",
+                e
+            ),
+        }
+    }
 }
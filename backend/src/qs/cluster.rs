@@ -0,0 +1,305 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Websocket notifications across replicas of the QS.
+//!
+//! A client's websocket connection is held by a single replica, while
+//! messages for the client can be enqueued on any replica. If the client is
+//! not connected to the enqueueing replica, the notification is published on
+//! a channel shared by all replicas, either via Postgres LISTEN/NOTIFY or via
+//! Redis pub/sub. Each replica delivers the notifications it receives to its
+//! local connections.
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::StreamExt;
+use phnxtypes::{identifiers::QsClientId, messages::client_ds::QsWsMessage};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use sqlx::PgPool;
+use thiserror::Error;
+use tls_codec::{DeserializeBytes, Serialize, TlsDeserializeBytes, TlsSerialize, TlsSize};
+use tokio::{sync::mpsc, task::JoinHandle, time::sleep};
+
+use crate::{
+    pg_listen::{self, PgListenHealth, ReliableListener},
+    settings::{ClusterDispatchSettings, PgListenSettings, RedisDispatchSettings},
+};
+
+use super::{Qs, WsNotification};
+
+/// Name of the Postgres or Redis channel
+const CLUSTER_CHANNEL: &str = "qs_ws_dispatch";
+/// Number of notifications buffered for the local dispatch
+const NOTIFICATION_BUFFER_SIZE: usize = 1024;
+const REDIS_RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+const REDIS_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// A websocket notification for a client connected to any replica.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ClusterNotification {
+    pub client_id: QsClientId,
+    pub message: QsWsMessage,
+}
+
+impl ClusterNotification {
+    pub fn new(client_id: QsClientId, notification: WsNotification) -> Self {
+        let message = match notification {
+            WsNotification::QueueUpdate => QsWsMessage::QueueUpdate,
+            WsNotification::Event(event) => QsWsMessage::Event(event),
+            WsNotification::Presence(presence) => QsWsMessage::Presence(presence),
+        };
+        Self { client_id, message }
+    }
+
+    fn encode(&self) -> Result<String, ClusterDispatchError> {
+        let bytes = self.tls_serialize_detached()?;
+        Ok(general_purpose::STANDARD.encode(bytes))
+    }
+
+    fn decode(payload: &str) -> Option<Self> {
+        let bytes = general_purpose::STANDARD.decode(payload).ok()?;
+        Self::tls_deserialize_exact_bytes(&bytes).ok()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClusterDispatchError {
+    #[error(transparent)]
+    Postgres(#[from] sqlx::Error),
+    #[error(transparent)]
+    Redis(#[from] redis::RedisError),
+    #[error(transparent)]
+    Codec(#[from] tls_codec::Error),
+}
+
+/// Channel shared by all replicas of the QS. Stops receiving notifications
+/// when dropped.
+pub struct ClusterChannel {
+    backend: ClusterBackend,
+}
+
+enum ClusterBackend {
+    Postgres {
+        pool: PgPool,
+        listener: ReliableListener,
+        forwarder: JoinHandle<()>,
+    },
+    Redis {
+        connection: ConnectionManager,
+        subscriber: JoinHandle<()>,
+    },
+}
+
+impl std::fmt::Debug for ClusterChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let backend = match self.backend {
+            ClusterBackend::Postgres { .. } => "postgres",
+            ClusterBackend::Redis { .. } => "redis",
+        };
+        f.debug_struct("ClusterChannel")
+            .field("backend", &backend)
+            .finish()
+    }
+}
+
+impl Drop for ClusterChannel {
+    fn drop(&mut self) {
+        match &self.backend {
+            ClusterBackend::Postgres { forwarder, .. } => forwarder.abort(),
+            ClusterBackend::Redis { subscriber, .. } => subscriber.abort(),
+        }
+    }
+}
+
+impl Qs {
+    /// Connect to the channel shared by the replicas of the QS. Returns
+    /// `None` if the QS runs as a single replica.
+    ///
+    /// Notifications published by any replica, including this one, are
+    /// received on the returned receiver.
+    pub async fn connect_cluster_channel(
+        &self,
+        settings: &ClusterDispatchSettings,
+    ) -> Result<Option<(ClusterChannel, mpsc::Receiver<ClusterNotification>)>, ClusterDispatchError>
+    {
+        let channel = match settings {
+            ClusterDispatchSettings::Local => return Ok(None),
+            ClusterDispatchSettings::Postgres(settings) => {
+                ClusterChannel::postgres(self.db_pool.clone(), settings.clone()).await?
+            }
+            ClusterDispatchSettings::Redis(settings) => ClusterChannel::redis(settings).await?,
+        };
+        Ok(Some(channel))
+    }
+}
+
+impl ClusterChannel {
+    async fn postgres(
+        pool: PgPool,
+        settings: PgListenSettings,
+    ) -> Result<(Self, mpsc::Receiver<ClusterNotification>), ClusterDispatchError> {
+        let (listener, mut events) =
+            ReliableListener::start(pool.clone(), CLUSTER_CHANNEL, settings).await?;
+        let (notifications_tx, notifications_rx) = mpsc::channel(NOTIFICATION_BUFFER_SIZE);
+        let forwarder = tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let Some(notification) = ClusterNotification::decode(&event.payload) else {
+                    tracing::warn!(seq = event.seq, "Received malformed cluster notification");
+                    continue;
+                };
+                if notifications_tx.send(notification).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let backend = ClusterBackend::Postgres {
+            pool,
+            listener,
+            forwarder,
+        };
+        Ok((Self { backend }, notifications_rx))
+    }
+
+    async fn redis(
+        settings: &RedisDispatchSettings,
+    ) -> Result<(Self, mpsc::Receiver<ClusterNotification>), ClusterDispatchError> {
+        let client = redis::Client::open(settings.url.as_str())?;
+        let connection = ConnectionManager::new(client.clone()).await?;
+        let (notifications_tx, notifications_rx) = mpsc::channel(NOTIFICATION_BUFFER_SIZE);
+        let subscriber = tokio::spawn(async move {
+            let mut delay = REDIS_RECONNECT_MIN_DELAY;
+            loop {
+                match redis_subscribe(&client, &notifications_tx, &mut delay).await {
+                    Ok(()) => return,
+                    Err(error) => tracing::warn!(
+                        %error,
+                        "Redis subscription failed, reconnecting in {delay:?}"
+                    ),
+                }
+                sleep(delay).await;
+                delay = (delay * 2).min(REDIS_RECONNECT_MAX_DELAY);
+            }
+        });
+        let backend = ClusterBackend::Redis {
+            connection,
+            subscriber,
+        };
+        Ok((Self { backend }, notifications_rx))
+    }
+
+    /// Health of the Postgres listener. Returns `None` for Redis, which
+    /// doesn't report it.
+    pub fn health(&self) -> Option<PgListenHealth> {
+        match &self.backend {
+            ClusterBackend::Postgres { listener, .. } => Some(listener.health()),
+            ClusterBackend::Redis { .. } => None,
+        }
+    }
+
+    /// Publish the notification to all replicas.
+    pub async fn publish(
+        &self,
+        notification: &ClusterNotification,
+    ) -> Result<(), ClusterDispatchError> {
+        let payload = notification.encode()?;
+        match &self.backend {
+            ClusterBackend::Postgres { pool, .. } => {
+                pg_listen::publish(pool, CLUSTER_CHANNEL, &payload).await?;
+            }
+            ClusterBackend::Redis { connection, .. } => {
+                let _: () = connection.clone().publish(CLUSTER_CHANNEL, payload).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receive notifications from Redis until the receiver is dropped (`Ok`) or
+/// the subscription fails (`Err`).
+///
+/// Redis pub/sub doesn't keep messages, so notifications published while the
+/// subscription is down are lost. Clients fetch their queue when they
+/// reconnect, so only the wakeup is lost, not the message.
+async fn redis_subscribe(
+    client: &redis::Client,
+    notifications_tx: &mpsc::Sender<ClusterNotification>,
+    delay: &mut Duration,
+) -> Result<(), redis::RedisError> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(CLUSTER_CHANNEL).await?;
+    *delay = REDIS_RECONNECT_MIN_DELAY;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        let Some(notification) = ClusterNotification::decode(&payload) else {
+            tracing::warn!("Received malformed cluster notification");
+            continue;
+        };
+        if notifications_tx.send(notification).await.is_err() {
+            return Ok(());
+        }
+    }
+    Err(redis::RedisError::from((
+        redis::ErrorKind::IoError,
+        "Subscription closed",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    async fn next_notification(
+        notifications: &mut mpsc::Receiver<ClusterNotification>,
+    ) -> ClusterNotification {
+        tokio::time::timeout(Duration::from_secs(5), notifications.recv())
+            .await
+            .expect("Timed out waiting for notification")
+            .expect("Channel closed")
+    }
+
+    #[sqlx::test]
+    async fn delivers_notifications_across_replicas(pool: PgPool) {
+        // Two replicas sharing the database
+        let (replica_a, mut notifications_a) =
+            ClusterChannel::postgres(pool.clone(), PgListenSettings::default())
+                .await
+                .unwrap();
+        let (replica_b, mut notifications_b) =
+            ClusterChannel::postgres(pool.clone(), PgListenSettings::default())
+                .await
+                .unwrap();
+        for replica in [&replica_a, &replica_b] {
+            while !replica.health().unwrap().connected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let notification =
+            ClusterNotification::new(Uuid::new_v4().into(), WsNotification::QueueUpdate);
+        replica_a.publish(&notification).await.unwrap();
+        assert_eq!(next_notification(&mut notifications_b).await, notification);
+        assert_eq!(next_notification(&mut notifications_a).await, notification);
+
+        let notification =
+            ClusterNotification::new(Uuid::new_v4().into(), WsNotification::QueueUpdate);
+        replica_b.publish(&notification).await.unwrap();
+        assert_eq!(next_notification(&mut notifications_a).await, notification);
+        assert_eq!(next_notification(&mut notifications_b).await, notification);
+
+        assert_eq!(replica_b.health().unwrap().last_seq, 2);
+    }
+
+    #[test]
+    fn notification_roundtrip() {
+        let notification =
+            ClusterNotification::new(Uuid::new_v4().into(), WsNotification::QueueUpdate);
+        let payload = notification.encode().unwrap();
+        assert_eq!(ClusterNotification::decode(&payload), Some(notification));
+        assert_eq!(ClusterNotification::decode("not base64"), None);
+    }
+}
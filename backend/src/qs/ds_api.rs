@@ -5,7 +5,7 @@
 use phnxtypes::{
     crypto::{hpke::HpkeDecryptable, signatures::keys::QsVerifyingKey},
     errors::qs::QsVerifyingKeyError,
-    identifiers::{ClientConfig, Fqdn},
+    identifiers::{ClientConfig, Fqdn, QsClientReference},
     messages::MlsInfraVersion,
 };
 use tls_codec::Serialize;
@@ -44,29 +44,7 @@ impl Qs {
     ) -> Result<(), QsEnqueueError<N>> {
         let own_domain = self.domain.clone();
         if message.client_reference.client_homeserver_domain != own_domain {
-            let qs_to_qs_message = QsToQsMessage {
-                protocol_version: MlsInfraVersion::Alpha,
-                sender: own_domain.clone(),
-                recipient: message.client_reference.client_homeserver_domain.clone(),
-                payload: QsToQsPayload::FanOutMessageRequest(message.clone()),
-            };
-            let serialized_message = qs_to_qs_message
-                .tls_serialize_detached()
-                .map_err(|_| QsEnqueueError::LibraryError)?;
-            network_provider
-                .deliver(
-                    serialized_message,
-                    message.client_reference.client_homeserver_domain,
-                )
-                .await
-                .map_err(QsEnqueueError::NetworkError)
-                .and_then(|result| {
-                    if matches!(result, FederatedProcessingResult::Ok) {
-                        Ok(())
-                    } else {
-                        Err(QsEnqueueError::InvalidResponse)
-                    }
-                })?
+            self.deliver_remote(network_provider, message).await?
         } else {
             let decryption_key = StorableClientIdDecryptionKey::load(&self.db_pool)
                 .await
@@ -102,6 +80,7 @@ impl Qs {
                     websocket_notifier,
                     push_notification_provider,
                     message.payload,
+                    message.priority,
                     client_config.push_token_ear_key,
                 )
                 .await?;
@@ -114,6 +93,55 @@ impl Qs {
         Ok(())
     }
 
+    /// Notify the local client with the given reference that its queue needs
+    /// attention without enqueueing a message, e.g. because it is running out
+    /// of key packages. The client is notified over its websocket or, if it
+    /// isn't connected, with a push notification.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn wake_up_client<
+        W: WebsocketNotifier,
+        P: PushNotificationProvider,
+        N: NetworkProvider,
+    >(
+        &self,
+        websocket_notifier: &W,
+        push_notification_provider: &P,
+        client_reference: QsClientReference,
+    ) -> Result<(), QsEnqueueError<N>> {
+        if client_reference.client_homeserver_domain != self.domain {
+            return Err(QsEnqueueError::QueueNotFound);
+        }
+        let decryption_key = StorableClientIdDecryptionKey::load(&self.db_pool)
+            .await
+            .map_err(|_| QsEnqueueError::StorageError)?
+            // There should always be a decryption key in the database.
+            .ok_or(QsEnqueueError::LibraryError)?;
+        let client_config =
+            ClientConfig::decrypt(client_reference.sealed_reference, &decryption_key, &[], &[])?;
+
+        let mut connection = self.db_pool.acquire().await.map_err(|e| {
+            tracing::warn!("Failed to acquire connection: {:?}", e);
+            QsEnqueueError::StorageError
+        })?;
+        let mut client_record = QsClientRecord::load(&mut *connection, &client_config.client_id)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load client record: {:?}", e);
+                QsEnqueueError::StorageError
+            })?
+            .ok_or(QsEnqueueError::QueueNotFound)?;
+
+        client_record
+            .wake_up(
+                &mut connection,
+                websocket_notifier,
+                push_notification_provider,
+                client_config.push_token_ear_key,
+            )
+            .await?;
+        Ok(())
+    }
+
     /// Fetch the verifying key of the server with the given domain
     #[tracing::instrument(skip_all, err)]
     pub async fn verifying_key<N: NetworkProvider>(
@@ -133,14 +161,15 @@ impl Qs {
                 .tls_serialize_detached()
                 .map_err(|_| QsVerifyingKeyError::LibraryError)?;
             let result = network_provider
-                .deliver(serialized_message, domain)
+                .deliver(serialized_message, domain.clone())
                 .await
                 .map_err(|_| QsVerifyingKeyError::InvalidResponse)?;
-            if let FederatedProcessingResult::VerifyingKey(verifying_key) = result {
-                verifying_key
-            } else {
+            let FederatedProcessingResult::VerifyingKey(verifying_key) = result else {
                 return Err(QsVerifyingKeyError::InvalidResponse);
-            }
+            };
+            self.verify_federation_key(network_provider, &domain, &verifying_key)
+                .await?;
+            verifying_key
         } else {
             StorableQsSigningKey::load(&self.db_pool)
                 .await
@@ -0,0 +1,911 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Fan-out of a payload to many clients across federated servers.
+//!
+//! Instead of one federation request per remote recipient, the clients of a
+//! batch are grouped by their home server and each remote QS receives a single
+//! [`FanOutBatchRequest`] carrying the sealed references of all of its
+//! recipients. The remote QS reports the recipients it couldn't enqueue for
+//! and those that don't exist. The domains are served concurrently.
+//!
+//! Each batch is only tried once while the sender waits. Messages that
+//! couldn't be delivered are persisted and retried in the background (see
+//! [`Qs::spawn_pending_fanout_retry`]) with exponential backoff until they
+//! expire. Remote QSs that never answer a batch request, e.g. because they
+//! predate batching, get one [`QsToQsPayload::FanOutMessageRequest`] per
+//! recipient instead. Recipients that don't exist are dropped.
+
+use std::collections::HashMap;
+
+use futures_util::future::join_all;
+use phnxtypes::{
+    identifiers::{Fqdn, QsClientReference},
+    messages::{MlsInfraVersion, QueuePriority},
+    time::{now, Duration},
+};
+use sqlx::types::chrono::{DateTime, Utc};
+use tls_codec::Serialize;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{
+    errors::StorageError,
+    messages::{
+        intra_backend::{DsFanOutBatch, DsFanOutMessage, DsFanOutPayload},
+        qs_qs::{FanOutBatchRequest, QsToQsMessage, QsToQsPayload},
+    },
+};
+
+use super::{
+    errors::QsEnqueueError,
+    network_provider_trait::NetworkProvider,
+    qs_api::{FanOutBatchReport, FederatedProcessingResult},
+    PushNotificationProvider, Qs, WebsocketNotifier,
+};
+
+/// Number of batch requests to a remote QS, including the first one, before
+/// falling back to single requests.
+const FEDERATED_BATCH_ATTEMPTS: u32 = 3;
+
+/// Interval in which persisted fan-outs are retried if no new ones were
+/// persisted in the meantime.
+const PENDING_FANOUT_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Maximum number of persisted fan-outs retried at once.
+const PENDING_FANOUT_BATCH_SIZE: i64 = 100;
+/// Delay before the second retry of a persisted fan-out. Doubles with every
+/// attempt, up to [`PENDING_FANOUT_MAX_BACKOFF_EXPONENT`].
+const PENDING_FANOUT_RETRY_DELAY: Duration = Duration::minutes(1);
+const PENDING_FANOUT_MAX_BACKOFF_EXPONENT: u32 = 8;
+/// Time for which a persisted fan-out is claimed by a retry. If the server
+/// stops during the retry, the fan-out is retried again afterwards.
+const PENDING_FANOUT_LEASE: Duration = Duration::minutes(5);
+/// Persisted fan-outs older than this are dropped.
+const PENDING_FANOUT_EXPIRATION: Duration = Duration::days(7);
+
+/// Message to a remote client that couldn't be delivered yet.
+struct PendingFanOut {
+    fanout_id: Uuid,
+    message: DsFanOutMessage,
+    attempts: u32,
+}
+
+/// Result of the delivery to a single recipient of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    Delivered,
+    /// The message couldn't be delivered, but might be on a retry.
+    Failed,
+    /// The recipient doesn't exist on the remote QS.
+    Invalid,
+}
+
+impl Qs {
+    /// Enqueue the payload of the given batch for all of its clients. Local
+    /// clients are enqueued directly and remote clients with one request per
+    /// domain. All domains are served concurrently.
+    ///
+    /// Returns the clients whose messages couldn't be enqueued and couldn't
+    /// be persisted for a later retry either.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn enqueue_batch<
+        W: WebsocketNotifier,
+        N: NetworkProvider,
+        P: PushNotificationProvider,
+    >(
+        &self,
+        websocket_notifier: &W,
+        push_notification_provider: &P,
+        network_provider: &N,
+        batch: DsFanOutBatch,
+    ) -> Result<Vec<QsClientReference>, QsEnqueueError<N>> {
+        let DsFanOutBatch {
+            payload,
+            client_references,
+            priority,
+        } = batch;
+        let mut by_domain = group_by_domain(client_references);
+        let local_client_references = by_domain.remove(&self.domain).unwrap_or_default();
+
+        let local_delivery = async {
+            let mut failed = Vec::new();
+            for client_reference in local_client_references {
+                let message = DsFanOutMessage {
+                    payload: payload.clone(),
+                    client_reference: client_reference.clone(),
+                    priority,
+                };
+                if let Err(e) = self
+                    .enqueue_message(
+                        websocket_notifier,
+                        push_notification_provider,
+                        network_provider,
+                        message,
+                    )
+                    .await
+                {
+                    tracing::warn!("Could not enqueue message: {:?}", e);
+                    failed.push(client_reference);
+                }
+            }
+            failed
+        };
+        let remote_deliveries =
+            join_all(by_domain.into_iter().map(|(domain, client_references)| {
+                self.deliver_to_domain(
+                    network_provider,
+                    domain,
+                    &payload,
+                    priority,
+                    client_references,
+                )
+            }));
+
+        let (mut failed, remote_results) =
+            futures_util::future::join(local_delivery, remote_deliveries).await;
+        for result in remote_results {
+            failed.extend(result?);
+        }
+        Ok(failed)
+    }
+
+    /// Deliver the payload to the given clients of the remote QS with the
+    /// given domain with a single batch request. Clients that can't be
+    /// reached are persisted and retried in the background.
+    ///
+    /// Returns the clients that couldn't be persisted either.
+    async fn deliver_to_domain<N: NetworkProvider>(
+        &self,
+        network_provider: &N,
+        domain: Fqdn,
+        payload: &DsFanOutPayload,
+        priority: QueuePriority,
+        client_references: Vec<QsClientReference>,
+    ) -> Result<Vec<QsClientReference>, QsEnqueueError<N>> {
+        let deliveries = self
+            .deliver_batch(
+                network_provider,
+                &domain,
+                payload,
+                priority,
+                &client_references,
+            )
+            .await?
+            .unwrap_or_else(|| vec![Delivery::Failed; client_references.len()]);
+
+        let mut failed = Vec::new();
+        let mut invalid = 0;
+        let mut persisted = false;
+        for (client_reference, delivery) in client_references.into_iter().zip(deliveries) {
+            match delivery {
+                Delivery::Delivered => continue,
+                Delivery::Invalid => {
+                    invalid += 1;
+                    continue;
+                }
+                Delivery::Failed => {}
+            }
+            let message = DsFanOutMessage {
+                payload: payload.clone(),
+                client_reference: client_reference.clone(),
+                priority,
+            };
+            match PendingFanOut::store(&self.db_pool, &message, now()).await {
+                Ok(()) => persisted = true,
+                Err(e) => {
+                    tracing::error!(%domain, "Could not persist undelivered fan-out: {:?}", e);
+                    failed.push(client_reference);
+                }
+            }
+        }
+        if invalid > 0 {
+            tracing::warn!(%domain, invalid, "Remote QS doesn't know all recipients");
+        }
+        if persisted {
+            self.pending_fanouts.notify_one();
+        }
+        if !failed.is_empty() {
+            tracing::warn!(%domain, failed = failed.len(), "Fan-outs were lost");
+        }
+        Ok(failed)
+    }
+
+    /// Deliver the payload to the given clients of the remote QS with the
+    /// given domain with a batch request. Returns the result for each client
+    /// or `None` if the remote QS didn't answer the request.
+    async fn deliver_batch<N: NetworkProvider>(
+        &self,
+        network_provider: &N,
+        domain: &Fqdn,
+        payload: &DsFanOutPayload,
+        priority: QueuePriority,
+        client_references: &[QsClientReference],
+    ) -> Result<Option<Vec<Delivery>>, QsEnqueueError<N>> {
+        let qs_to_qs_message = QsToQsMessage {
+            protocol_version: MlsInfraVersion::Alpha,
+            sender: self.domain.clone(),
+            recipient: domain.clone(),
+            payload: QsToQsPayload::FanOutBatchRequest(FanOutBatchRequest {
+                payload: payload.clone(),
+                sealed_references: client_references
+                    .iter()
+                    .map(|client_reference| client_reference.sealed_reference.clone())
+                    .collect(),
+                priority,
+            }),
+        };
+        let serialized_message = qs_to_qs_message
+            .tls_serialize_detached()
+            .map_err(|_| QsEnqueueError::LibraryError)?;
+        let report = match network_provider
+            .deliver(serialized_message, domain.clone())
+            .await
+        {
+            Ok(FederatedProcessingResult::FanOutBatch(report)) => report,
+            Ok(_) => {
+                tracing::warn!(%domain, "Invalid response to batched fan-out");
+                return Ok(None);
+            }
+            Err(e) => {
+                tracing::warn!(%domain, "Could not deliver batched fan-out: {:?}", e);
+                return Ok(None);
+            }
+        };
+        let FanOutBatchReport {
+            failed_recipients,
+            invalid_recipients,
+        } = report;
+        let mut deliveries = vec![Delivery::Delivered; client_references.len()];
+        for (indices, delivery) in [
+            (failed_recipients, Delivery::Failed),
+            (invalid_recipients, Delivery::Invalid),
+        ] {
+            for index in indices {
+                if let Some(slot) = deliveries.get_mut(index as usize) {
+                    *slot = delivery;
+                }
+            }
+        }
+        let failed = deliveries
+            .iter()
+            .filter(|delivery| **delivery == Delivery::Failed)
+            .count();
+        if failed > 0 {
+            tracing::warn!(
+                %domain,
+                failed,
+                "Remote QS couldn't enqueue all batched messages"
+            );
+        }
+        Ok(Some(deliveries))
+    }
+
+    /// Deliver the given message to the remote QS of its client with a
+    /// single request.
+    pub(super) async fn deliver_remote<N: NetworkProvider>(
+        &self,
+        network_provider: &N,
+        message: DsFanOutMessage,
+    ) -> Result<(), QsEnqueueError<N>> {
+        let domain = message.client_reference.client_homeserver_domain.clone();
+        let qs_to_qs_message = QsToQsMessage {
+            protocol_version: MlsInfraVersion::Alpha,
+            sender: self.domain.clone(),
+            recipient: domain.clone(),
+            payload: QsToQsPayload::FanOutMessageRequest(message),
+        };
+        let serialized_message = qs_to_qs_message
+            .tls_serialize_detached()
+            .map_err(|_| QsEnqueueError::LibraryError)?;
+        let result = network_provider
+            .deliver(serialized_message, domain)
+            .await
+            .map_err(QsEnqueueError::NetworkError)?;
+        if !matches!(result, FederatedProcessingResult::Ok) {
+            return Err(QsEnqueueError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Retry the delivery of persisted fan-outs periodically and whenever
+    /// new ones were persisted.
+    pub fn spawn_pending_fanout_retry<N: NetworkProvider>(
+        &self,
+        network_provider: N,
+    ) -> JoinHandle<()> {
+        let qs = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = qs.retry_pending_fanouts(&network_provider, now()).await {
+                    tracing::error!("Error retrying pending fan-outs: {:?}", e);
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(PENDING_FANOUT_RETRY_INTERVAL) => {}
+                    _ = qs.pending_fanouts.notified() => {}
+                }
+            }
+        })
+    }
+
+    /// Drop expired persisted fan-outs and retry those that are due. Fan-outs
+    /// with the same payload and domain are retried with a single batch
+    /// request. Fan-outs that still can't be delivered are rescheduled with
+    /// exponential backoff.
+    async fn retry_pending_fanouts<N: NetworkProvider>(
+        &self,
+        network_provider: &N,
+        now: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        let expired =
+            PendingFanOut::delete_expired(&self.db_pool, now - PENDING_FANOUT_EXPIRATION).await?;
+        if expired > 0 {
+            tracing::error!(expired, "Dropped expired fan-outs");
+        }
+
+        let due = PendingFanOut::claim_due(
+            &self.db_pool,
+            now,
+            now + PENDING_FANOUT_LEASE,
+            PENDING_FANOUT_BATCH_SIZE,
+        )
+        .await?;
+        let mut batches: HashMap<_, Vec<PendingFanOut>> = HashMap::new();
+        for pending in due {
+            let message = &pending.message;
+            let Ok(payload) = message.payload.tls_serialize_detached() else {
+                tracing::error!("Could not serialize pending fan-out");
+                continue;
+            };
+            let key = (
+                message.client_reference.client_homeserver_domain.clone(),
+                message.priority,
+                payload,
+            );
+            batches.entry(key).or_default().push(pending);
+        }
+        let retries = join_all(batches.into_iter().map(|((domain, ..), batch)| async move {
+            let deliveries = self
+                .retry_pending_batch(network_provider, &domain, &batch)
+                .await;
+            (domain, batch, deliveries)
+        }));
+        for (domain, batch, deliveries) in retries.await {
+            for (pending, delivery) in batch.into_iter().zip(deliveries) {
+                match delivery {
+                    Delivery::Delivered => {
+                        PendingFanOut::delete(&self.db_pool, pending.fanout_id).await?
+                    }
+                    Delivery::Invalid => {
+                        tracing::warn!(%domain, "Dropped fan-out to unknown recipient");
+                        PendingFanOut::delete(&self.db_pool, pending.fanout_id).await?
+                    }
+                    Delivery::Failed => {
+                        let exponent = pending.attempts.min(PENDING_FANOUT_MAX_BACKOFF_EXPONENT);
+                        let next_attempt_at = now + PENDING_FANOUT_RETRY_DELAY * 2i32.pow(exponent);
+                        PendingFanOut::reschedule(
+                            &self.db_pool,
+                            pending.fanout_id,
+                            next_attempt_at,
+                        )
+                        .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Retry the delivery of the given fan-outs, which share their payload
+    /// and domain. Domains that didn't answer the first batch requests get
+    /// one request per fan-out instead.
+    async fn retry_pending_batch<N: NetworkProvider>(
+        &self,
+        network_provider: &N,
+        domain: &Fqdn,
+        batch: &[PendingFanOut],
+    ) -> Vec<Delivery> {
+        let Some(first) = batch.first() else {
+            return Vec::new();
+        };
+        // The first batch request was sent before the fan-outs were
+        // persisted.
+        if first.attempts + 1 < FEDERATED_BATCH_ATTEMPTS {
+            let client_references: Vec<_> = batch
+                .iter()
+                .map(|pending| pending.message.client_reference.clone())
+                .collect();
+            let result = self
+                .deliver_batch(
+                    network_provider,
+                    domain,
+                    &first.message.payload,
+                    first.message.priority,
+                    &client_references,
+                )
+                .await;
+            return match result {
+                Ok(Some(deliveries)) => deliveries,
+                Ok(None) => vec![Delivery::Failed; batch.len()],
+                Err(e) => {
+                    tracing::error!(%domain, "Could not retry batched fan-out: {:?}", e);
+                    vec![Delivery::Failed; batch.len()]
+                }
+            };
+        }
+        let deliveries = join_all(batch.iter().map(|pending| async move {
+            let result = self
+                .deliver_remote(network_provider, pending.message.clone())
+                .await;
+            match result {
+                Ok(()) => Delivery::Delivered,
+                Err(e) => {
+                    tracing::warn!(
+                        %domain,
+                        attempts = pending.attempts + 1,
+                        "Could not deliver pending fan-out: {:?}",
+                        e
+                    );
+                    Delivery::Failed
+                }
+            }
+        }));
+        deliveries.await
+    }
+}
+
+/// Group the given client references by the domain of their home server.
+fn group_by_domain(
+    client_references: Vec<QsClientReference>,
+) -> HashMap<Fqdn, Vec<QsClientReference>> {
+    let mut by_domain: HashMap<Fqdn, Vec<QsClientReference>> = HashMap::new();
+    for client_reference in client_references {
+        by_domain
+            .entry(client_reference.client_homeserver_domain.clone())
+            .or_default()
+            .push(client_reference);
+    }
+    by_domain
+}
+
+mod persistence {
+    use sqlx::PgExecutor;
+    use tls_codec::DeserializeBytes;
+
+    use super::*;
+
+    fn codec_error(e: tls_codec::Error) -> StorageError {
+        StorageError::from(Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+    }
+
+    impl PendingFanOut {
+        pub(super) async fn store(
+            connection: impl PgExecutor<'_>,
+            message: &DsFanOutMessage,
+            now: DateTime<Utc>,
+        ) -> Result<(), StorageError> {
+            let message = message.tls_serialize_detached().map_err(codec_error)?;
+            sqlx::query!(
+                "INSERT INTO qs_pending_fanouts (fanout_id, message, next_attempt_at, created_at)
+                VALUES ($1, $2, $3, $3)",
+                Uuid::new_v4(),
+                message,
+                now,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        /// Load the fan-outs that are due at `now` and postpone them until
+        /// `lease_until`, so that no other server instance retries them
+        /// concurrently.
+        pub(super) async fn claim_due(
+            connection: impl PgExecutor<'_>,
+            now: DateTime<Utc>,
+            lease_until: DateTime<Utc>,
+            limit: i64,
+        ) -> Result<Vec<Self>, StorageError> {
+            let records = sqlx::query!(
+                "UPDATE qs_pending_fanouts SET next_attempt_at = $2
+                WHERE fanout_id IN (
+                    SELECT fanout_id FROM qs_pending_fanouts
+                    WHERE next_attempt_at <= $1
+                    ORDER BY next_attempt_at
+                    LIMIT $3
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING fanout_id, message, attempts",
+                now,
+                lease_until,
+                limit,
+            )
+            .fetch_all(connection)
+            .await?;
+            records
+                .into_iter()
+                .map(|record| {
+                    Ok(Self {
+                        fanout_id: record.fanout_id,
+                        message: DsFanOutMessage::tls_deserialize_exact_bytes(&record.message)
+                            .map_err(codec_error)?,
+                        attempts: record.attempts.try_into().unwrap_or_default(),
+                    })
+                })
+                .collect()
+        }
+
+        pub(super) async fn reschedule(
+            connection: impl PgExecutor<'_>,
+            fanout_id: Uuid,
+            next_attempt_at: DateTime<Utc>,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "UPDATE qs_pending_fanouts SET attempts = attempts + 1, next_attempt_at = $2
+                WHERE fanout_id = $1",
+                fanout_id,
+                next_attempt_at,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        pub(super) async fn delete(
+            connection: impl PgExecutor<'_>,
+            fanout_id: Uuid,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "DELETE FROM qs_pending_fanouts WHERE fanout_id = $1",
+                fanout_id,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        /// Delete the fan-outs created before `created_before`. Returns the
+        /// number of deleted fan-outs.
+        pub(super) async fn delete_expired(
+            connection: impl PgExecutor<'_>,
+            created_before: DateTime<Utc>,
+        ) -> Result<u64, StorageError> {
+            let result = sqlx::query!(
+                "DELETE FROM qs_pending_fanouts WHERE created_at <= $1",
+                created_before,
+            )
+            .execute(connection)
+            .await?;
+            Ok(result.rows_affected())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex};
+
+    use async_trait::async_trait;
+    use phnxtypes::{
+        crypto::signatures::keys::FederationKeyRecord,
+        identifiers::SealedClientReference,
+        messages::client_ds::{QsQueueMessagePayload, QsQueueMessageType},
+        time::TimeStamp,
+    };
+    use sqlx::PgPool;
+    use thiserror::Error;
+    use tls_codec::DeserializeBytes;
+    use tokio::sync::Barrier;
+
+    use crate::infra_service::InfraService;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Domain unreachable")]
+    struct Unreachable;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Request {
+        Batch(usize),
+        Single,
+    }
+
+    #[derive(Debug, Default)]
+    struct MockFederation {
+        unreachable: Mutex<HashSet<Fqdn>>,
+        /// Domains that don't support batch requests
+        legacy: HashSet<Fqdn>,
+        /// Batch requests wait here until all domains received theirs
+        barrier: Option<Barrier>,
+        /// Answer to batch requests
+        report: FanOutBatchReport,
+        requests: Mutex<Vec<(Fqdn, Request)>>,
+    }
+
+    impl MockFederation {
+        fn set_reachable(&self, domain: &Fqdn, reachable: bool) {
+            let mut unreachable = self.unreachable.lock().unwrap();
+            if reachable {
+                unreachable.remove(domain);
+            } else {
+                unreachable.insert(domain.clone());
+            }
+        }
+
+        fn requests(&self) -> Vec<(Fqdn, Request)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl NetworkProvider for MockFederation {
+        type NetworkError = Unreachable;
+
+        async fn deliver(
+            &self,
+            bytes: Vec<u8>,
+            destination: Fqdn,
+        ) -> Result<FederatedProcessingResult, Self::NetworkError> {
+            let message = QsToQsMessage::tls_deserialize_exact_bytes(&bytes).unwrap();
+            let request = match &message.payload {
+                QsToQsPayload::FanOutBatchRequest(request) => {
+                    Request::Batch(request.sealed_references.len())
+                }
+                QsToQsPayload::FanOutMessageRequest(_) => Request::Single,
+                QsToQsPayload::VerificationKeyRequest => unreachable!(),
+            };
+            self.requests
+                .lock()
+                .unwrap()
+                .push((destination.clone(), request));
+            if let (Request::Batch(_), Some(barrier)) = (request, &self.barrier) {
+                barrier.wait().await;
+            }
+            if self.unreachable.lock().unwrap().contains(&destination) {
+                return Err(Unreachable);
+            }
+            match request {
+                Request::Batch(_) if self.legacy.contains(&destination) => Err(Unreachable),
+                Request::Batch(_) => {
+                    Ok(FederatedProcessingResult::FanOutBatch(self.report.clone()))
+                }
+                Request::Single => Ok(FederatedProcessingResult::Ok),
+            }
+        }
+
+        async fn fetch_key_record(
+            &self,
+            _domain: Fqdn,
+        ) -> Result<FederationKeyRecord, Self::NetworkError> {
+            Err(Unreachable)
+        }
+    }
+
+    #[derive(Debug)]
+    struct NoNotifications;
+
+    #[async_trait]
+    impl WebsocketNotifier for NoNotifications {
+        async fn notify(
+            &self,
+            _client_id: &phnxtypes::identifiers::QsClientId,
+            _ws_notification: super::super::WsNotification,
+        ) -> Result<(), super::super::WebsocketNotifierError> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl PushNotificationProvider for NoNotifications {
+        async fn push(
+            &self,
+            _push_token: phnxtypes::messages::push_token::PushToken,
+        ) -> Result<(), super::super::PushNotificationError> {
+            Ok(())
+        }
+    }
+
+    fn client_reference(domain: &Fqdn, index: u8) -> QsClientReference {
+        QsClientReference {
+            client_homeserver_domain: domain.clone(),
+            sealed_reference: SealedClientReference::tls_deserialize_exact_bytes(&[1, index, 0])
+                .unwrap(),
+        }
+    }
+
+    fn batch(client_references: Vec<QsClientReference>) -> DsFanOutBatch {
+        DsFanOutBatch {
+            payload: DsFanOutPayload::QueueMessage(QsQueueMessagePayload {
+                timestamp: TimeStamp::now(),
+                message_type: QsQueueMessageType::MlsMessage,
+                payload: vec![1, 2, 3],
+            }),
+            client_references,
+            priority: QueuePriority::default(),
+        }
+    }
+
+    async fn pending_attempts(pool: &PgPool) -> Vec<i32> {
+        sqlx::query_scalar("SELECT attempts FROM qs_pending_fanouts")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn qs(pool: PgPool) -> Qs {
+        Qs::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn domains_are_served_concurrently(pool: PgPool) {
+        let qs = qs(pool.clone()).await;
+        let alpha = Fqdn::try_from("alpha.com").unwrap();
+        let beta = Fqdn::try_from("beta.com").unwrap();
+        let federation = MockFederation {
+            barrier: Some(Barrier::new(2)),
+            ..Default::default()
+        };
+
+        // Sequential deliveries would never pass the barrier
+        let batch = batch(vec![
+            client_reference(&alpha, 0),
+            client_reference(&beta, 1),
+            client_reference(&alpha, 2),
+        ]);
+        let failed = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            qs.enqueue_batch(&NoNotifications, &NoNotifications, &federation, batch),
+        )
+        .await
+        .expect("deliveries are concurrent")
+        .unwrap();
+
+        assert!(failed.is_empty());
+        let mut requests = federation.requests();
+        requests.sort_by_key(|(domain, _)| domain.to_string());
+        assert_eq!(
+            requests,
+            vec![(alpha, Request::Batch(2)), (beta, Request::Batch(1))]
+        );
+        assert!(pending_attempts(&pool).await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn legacy_domains_get_single_requests(pool: PgPool) {
+        let qs = qs(pool.clone()).await;
+        let legacy = Fqdn::try_from("legacy.com").unwrap();
+        let federation = MockFederation {
+            legacy: [legacy.clone()].into(),
+            ..Default::default()
+        };
+
+        // The sender only waits for the first batch request
+        let batch = batch(vec![
+            client_reference(&legacy, 0),
+            client_reference(&legacy, 1),
+        ]);
+        let failed = qs
+            .enqueue_batch(&NoNotifications, &NoNotifications, &federation, batch)
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(federation.requests(), vec![(legacy, Request::Batch(2))]);
+        assert_eq!(pending_attempts(&pool).await, vec![0, 0]);
+
+        // The background retries fall back to single requests after the
+        // batch requests went unanswered
+        let start = now();
+        for hours in 0..3 {
+            qs.retry_pending_fanouts(&federation, start + Duration::hours(hours))
+                .await
+                .unwrap();
+        }
+        let requests: Vec<_> = federation
+            .requests()
+            .into_iter()
+            .map(|(_, request)| request)
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                Request::Batch(2),
+                Request::Batch(2),
+                Request::Batch(2),
+                Request::Single,
+                Request::Single,
+            ]
+        );
+        assert!(pending_attempts(&pool).await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn invalid_recipients_are_not_persisted(pool: PgPool) {
+        let qs = qs(pool.clone()).await;
+        let remote = Fqdn::try_from("remote.com").unwrap();
+        let federation = MockFederation {
+            report: FanOutBatchReport {
+                failed_recipients: vec![1],
+                invalid_recipients: vec![0],
+            },
+            ..Default::default()
+        };
+
+        let batch = batch(vec![
+            client_reference(&remote, 0),
+            client_reference(&remote, 1),
+            client_reference(&remote, 2),
+        ]);
+        let failed = qs
+            .enqueue_batch(&NoNotifications, &NoNotifications, &federation, batch)
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(
+            federation.requests(),
+            vec![(remote.clone(), Request::Batch(3))]
+        );
+
+        // Only the recipient that failed temporarily is retried
+        assert_eq!(pending_attempts(&pool).await, vec![0]);
+        qs.retry_pending_fanouts(&federation, now()).await.unwrap();
+        assert_eq!(federation.requests()[1..], [(remote, Request::Batch(1))]);
+    }
+
+    #[sqlx::test]
+    async fn undelivered_fanouts_are_persisted_and_retried(pool: PgPool) {
+        let qs = qs(pool.clone()).await;
+        let remote = Fqdn::try_from("remote.com").unwrap();
+        let federation = MockFederation::default();
+        federation.set_reachable(&remote, false);
+
+        let batch = batch(vec![
+            client_reference(&remote, 0),
+            client_reference(&remote, 1),
+        ]);
+        let failed = qs
+            .enqueue_batch(
+                &NoNotifications,
+                &NoNotifications,
+                &federation,
+                batch.clone(),
+            )
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(pending_attempts(&pool).await, vec![0, 0]);
+
+        // Failed retries are rescheduled
+        let start = now();
+        qs.retry_pending_fanouts(&federation, start).await.unwrap();
+        assert_eq!(pending_attempts(&pool).await, vec![1, 1]);
+
+        // Nothing is retried before the backoff passed
+        federation.set_reachable(&remote, true);
+        let requests = federation.requests().len();
+        qs.retry_pending_fanouts(&federation, start).await.unwrap();
+        assert_eq!(federation.requests().len(), requests);
+
+        qs.retry_pending_fanouts(&federation, start + Duration::hours(1))
+            .await
+            .unwrap();
+        assert!(pending_attempts(&pool).await.is_empty());
+        let retried = &federation.requests()[requests..];
+        assert_eq!(retried, [(remote.clone(), Request::Batch(2))]);
+
+        // Expired fan-outs are dropped without another attempt
+        federation.set_reachable(&remote, false);
+        qs.enqueue_batch(&NoNotifications, &NoNotifications, &federation, batch)
+            .await
+            .unwrap();
+        assert_eq!(pending_attempts(&pool).await.len(), 2);
+        let requests = federation.requests().len();
+        qs.retry_pending_fanouts(&federation, now() + PENDING_FANOUT_EXPIRATION)
+            .await
+            .unwrap();
+        assert!(pending_attempts(&pool).await.is_empty());
+        assert_eq!(federation.requests().len(), requests);
+    }
+}
@@ -0,0 +1,313 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Trust-on-first-use pinning of the QS verifying keys of federated servers.
+//!
+//! On first contact with a remote domain, the QS checks the verifying key
+//! returned by the remote QS against the fingerprint the domain publishes in
+//! DNS (see [`FederationKeyRecord`]) and pins the fingerprint. On subsequent
+//! contacts, the key has to match the pinned fingerprint.
+
+use phnxtypes::{
+    crypto::signatures::keys::{FederationKeyRecord, QsVerifyingKey, QsVerifyingKeyFingerprint},
+    errors::qs::QsVerifyingKeyError,
+    identifiers::Fqdn,
+    time::TimeStamp,
+};
+use sqlx::PgExecutor;
+
+use super::{network_provider_trait::NetworkProvider, Qs};
+
+#[derive(Debug, Clone)]
+pub(super) struct FederationKeyPin {
+    domain: Fqdn,
+    fingerprint: QsVerifyingKeyFingerprint,
+    first_seen: TimeStamp,
+}
+
+impl FederationKeyPin {
+    fn new(domain: Fqdn, fingerprint: QsVerifyingKeyFingerprint) -> Self {
+        Self {
+            domain,
+            fingerprint,
+            first_seen: TimeStamp::now(),
+        }
+    }
+}
+
+impl Qs {
+    /// Returns the record with which this QS publishes the fingerprint of its
+    /// verifying key.
+    pub async fn federation_key_record(&self) -> Result<FederationKeyRecord, QsVerifyingKeyError> {
+        let fingerprint = super::signing_key::StorableQsSigningKey::load(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load signing key: {:?}", e);
+                QsVerifyingKeyError::StorageError
+            })?
+            .ok_or(QsVerifyingKeyError::LibraryError)?
+            .verifying_key()
+            .fingerprint()
+            .map_err(|_| QsVerifyingKeyError::LibraryError)?;
+        Ok(FederationKeyRecord::new(fingerprint))
+    }
+
+    /// Check the verifying key of the remote QS of the given domain against
+    /// the pinned fingerprint. If there is no pin yet, the key is checked
+    /// against the fingerprint published by the domain and pinned afterwards.
+    pub(super) async fn verify_federation_key<N: NetworkProvider>(
+        &self,
+        network_provider: &N,
+        domain: &Fqdn,
+        verifying_key: &QsVerifyingKey,
+    ) -> Result<(), QsVerifyingKeyError> {
+        let fingerprint = verifying_key
+            .fingerprint()
+            .map_err(|_| QsVerifyingKeyError::LibraryError)?;
+        let pin = FederationKeyPin::load(&self.db_pool, domain)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load federation key pin: {:?}", e);
+                QsVerifyingKeyError::StorageError
+            })?;
+
+        if let Some(pin) = pin {
+            if pin.fingerprint != fingerprint {
+                tracing::error!(
+                    %domain,
+                    pinned = %pin.fingerprint,
+                    received = %fingerprint,
+                    first_seen = ?pin.first_seen,
+                    "QS verifying key of federated domain changed"
+                );
+                return Err(QsVerifyingKeyError::PinnedKeyMismatch);
+            }
+            FederationKeyPin::touch(&self.db_pool, domain)
+                .await
+                .map_err(|e| {
+                    tracing::warn!("Failed to update federation key pin: {:?}", e);
+                    QsVerifyingKeyError::StorageError
+                })?;
+            return Ok(());
+        }
+
+        let record = network_provider
+            .fetch_key_record(domain.clone())
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to fetch federation key record: {:?}", e);
+                QsVerifyingKeyError::InvalidResponse
+            })?;
+        if record.qs_key_fingerprint() != &fingerprint {
+            tracing::error!(
+                %domain,
+                published = %record.qs_key_fingerprint(),
+                received = %fingerprint,
+                "QS verifying key of federated domain doesn't match published fingerprint"
+            );
+            return Err(QsVerifyingKeyError::UnpublishedKey);
+        }
+
+        tracing::info!(%domain, %fingerprint, "Pinning QS verifying key of federated domain");
+        FederationKeyPin::new(domain.clone(), fingerprint)
+            .store(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to store federation key pin: {:?}", e);
+                QsVerifyingKeyError::StorageError
+            })?;
+        Ok(())
+    }
+}
+
+mod persistence {
+    use crate::errors::StorageError;
+
+    use super::*;
+
+    impl FederationKeyPin {
+        pub(super) async fn store(
+            &self,
+            connection: impl PgExecutor<'_>,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "INSERT INTO qs_federation_key_pins (domain, fingerprint, first_seen, last_seen)
+                VALUES ($1, $2, $3, $3)
+                ON CONFLICT (tenant, domain) DO NOTHING",
+                self.domain as _,
+                self.fingerprint as _,
+                self.first_seen as _,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        pub(super) async fn load(
+            connection: impl PgExecutor<'_>,
+            domain: &Fqdn,
+        ) -> Result<Option<Self>, StorageError> {
+            let pin = sqlx::query!(
+                r#"SELECT
+                    fingerprint as "fingerprint: QsVerifyingKeyFingerprint",
+                    first_seen as "first_seen: TimeStamp"
+                FROM qs_federation_key_pins WHERE domain = $1"#,
+                domain as _,
+            )
+            .fetch_optional(connection)
+            .await?
+            .map(|record| Self {
+                domain: domain.clone(),
+                fingerprint: record.fingerprint,
+                first_seen: record.first_seen,
+            });
+            Ok(pin)
+        }
+
+        pub(super) async fn touch(
+            connection: impl PgExecutor<'_>,
+            domain: &Fqdn,
+        ) -> Result<(), StorageError> {
+            sqlx::query!(
+                "UPDATE qs_federation_key_pins SET last_seen = now() WHERE domain = $1",
+                domain as _,
+            )
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use phnxtypes::crypto::signatures::keys::QsSigningKey;
+    use sqlx::PgPool;
+    use thiserror::Error;
+
+    use crate::qs::qs_api::FederatedProcessingResult;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Error)]
+    #[error("No key record")]
+    struct NoRecord;
+
+    /// Publishes the given key record for every domain.
+    #[derive(Debug, Default)]
+    struct MockDns {
+        record: Mutex<Option<FederationKeyRecord>>,
+    }
+
+    impl MockDns {
+        fn publish(&self, verifying_key: &QsVerifyingKey) {
+            let record = FederationKeyRecord::new(verifying_key.fingerprint().unwrap());
+            *self.record.lock().unwrap() = Some(record);
+        }
+    }
+
+    #[async_trait]
+    impl NetworkProvider for MockDns {
+        type NetworkError = NoRecord;
+
+        async fn deliver(
+            &self,
+            _bytes: Vec<u8>,
+            _destination: Fqdn,
+        ) -> Result<FederatedProcessingResult, Self::NetworkError> {
+            unreachable!()
+        }
+
+        async fn fetch_key_record(
+            &self,
+            _domain: Fqdn,
+        ) -> Result<FederationKeyRecord, Self::NetworkError> {
+            self.record.lock().unwrap().clone().ok_or(NoRecord)
+        }
+    }
+
+    async fn qs(pool: PgPool) -> Qs {
+        Qs::new_from_pool(pool, Fqdn::try_from("example.com").unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn verifying_key() -> QsVerifyingKey {
+        QsSigningKey::generate().unwrap().verifying_key()
+    }
+
+    #[sqlx::test]
+    async fn first_contact_pins_published_key(pool: PgPool) {
+        let qs = qs(pool).await;
+        let domain = Fqdn::try_from("remote.com").unwrap();
+        let dns = MockDns::default();
+        let key = verifying_key();
+
+        // Without a published record, the key can't be checked
+        let result = qs.verify_federation_key(&dns, &domain, &key).await;
+        assert!(matches!(result, Err(QsVerifyingKeyError::InvalidResponse)));
+        let pin = FederationKeyPin::load(&qs.db_pool, &domain).await.unwrap();
+        assert!(pin.is_none());
+
+        dns.publish(&key);
+        qs.verify_federation_key(&dns, &domain, &key).await.unwrap();
+        let pin = FederationKeyPin::load(&qs.db_pool, &domain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pin.fingerprint, key.fingerprint().unwrap());
+
+        // The pinned key is accepted without a published record
+        *dns.record.lock().unwrap() = None;
+        qs.verify_federation_key(&dns, &domain, &key).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn unpublished_key_is_not_pinned(pool: PgPool) {
+        let qs = qs(pool).await;
+        let domain = Fqdn::try_from("remote.com").unwrap();
+        let dns = MockDns::default();
+        dns.publish(&verifying_key());
+
+        let result = qs
+            .verify_federation_key(&dns, &domain, &verifying_key())
+            .await;
+        assert!(matches!(result, Err(QsVerifyingKeyError::UnpublishedKey)));
+        let pin = FederationKeyPin::load(&qs.db_pool, &domain).await.unwrap();
+        assert!(pin.is_none());
+    }
+
+    #[sqlx::test]
+    async fn changed_key_does_not_match_pin(pool: PgPool) {
+        let qs = qs(pool).await;
+        let domain = Fqdn::try_from("remote.com").unwrap();
+        let dns = MockDns::default();
+        let key = verifying_key();
+        dns.publish(&key);
+        qs.verify_federation_key(&dns, &domain, &key).await.unwrap();
+
+        // A new key is rejected, even if the domain publishes it
+        let new_key = verifying_key();
+        dns.publish(&new_key);
+        let result = qs.verify_federation_key(&dns, &domain, &new_key).await;
+        assert!(matches!(
+            result,
+            Err(QsVerifyingKeyError::PinnedKeyMismatch)
+        ));
+        let pin = FederationKeyPin::load(&qs.db_pool, &domain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pin.fingerprint, key.fingerprint().unwrap());
+
+        // Other domains are pinned independently
+        let other_domain = Fqdn::try_from("other.com").unwrap();
+        qs.verify_federation_key(&dns, &other_domain, &new_key)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Authentication of clients opening a websocket to listen to their queue.
+//!
+//! A client authenticates the first time with a signature by its QS auth key.
+//! On success, the QS issues a resumption ticket, which the client presents
+//! instead of a signature when it reconnects. Tickets are tagged with a key
+//! held in memory, so verifying them is cheap. Every successful
+//! authentication issues a fresh ticket.
+//!
+//! Tickets are single-use. Each ticket carries a unique id and the ids of
+//! presented tickets are remembered until the tickets expire, so a leaked
+//! ticket can't be replayed once it was used.
+//!
+//! Tickets expire after [`TICKET_LIFETIME`]. The ticket key is rotated every
+//! [`TICKET_KEY_ROTATION_INTERVAL`] and tickets tagged with the previous key
+//! remain valid until they expire. A ticket contains the auth key of the
+//! client at the time it was issued, so it is revoked when the client's auth
+//! key changes or the client is deleted.
+//!
+//! Ticket keys are not shared between replicas of the QS. A ticket presented
+//! to another replica (or after a restart) is rejected and the client falls
+//! back to a signature.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration as StdDuration, Instant},
+};
+
+use phnxtypes::{
+    crypto::{
+        errors::RandomnessError,
+        mac::{keys::QsResumptionTicketKey, traits::MacKey, MacTag},
+        signatures::{keys::QsClientVerifyingKey, signable::Verifiable},
+    },
+    identifiers::QsClientId,
+    messages::client_qs::{QsOpenWsAuth, QsOpenWsParams, QsResumptionTicket, VerifiedQsOpenWs},
+    time::{Duration, TimeStamp},
+};
+use thiserror::Error;
+use tls_codec::{DeserializeBytes, Serialize, TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::replay_cache::ReplayError;
+
+use super::{client_record::QsClientRecord, Qs};
+
+/// Time for which a ticket is accepted after it was issued.
+pub const TICKET_LIFETIME: Duration = Duration::hours(1);
+/// Interval after which a new ticket key is generated. Must not be shorter
+/// than [`TICKET_LIFETIME`], since only the previous key is kept.
+const TICKET_KEY_ROTATION_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+#[derive(Debug, Error)]
+pub enum QsListenAuthError {
+    #[error("Unknown client")]
+    UnknownClient,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Replayed or stale request")]
+    ReplayedRequest,
+    #[error("Invalid or expired ticket")]
+    InvalidTicket,
+    #[error("Storage provider error")]
+    StorageError,
+    #[error("Library error")]
+    LibraryError,
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+struct TicketContent {
+    key_epoch: u64,
+    ticket_id: u64,
+    client_id: QsClientId,
+    expires_at: TimeStamp,
+    auth_key: QsClientVerifyingKey,
+}
+
+#[derive(TlsSerialize, TlsDeserializeBytes, TlsSize)]
+struct Ticket {
+    content: TicketContent,
+    tag: MacTag,
+}
+
+/// Keys used to tag resumption tickets.
+#[derive(Debug, Clone)]
+pub(super) struct ResumptionTicketKeys {
+    inner: Arc<Mutex<TicketKeyRing>>,
+}
+
+#[derive(Debug)]
+struct TicketKeyRing {
+    epoch: u64,
+    current: QsResumptionTicketKey,
+    previous: Option<QsResumptionTicketKey>,
+    rotated_at: Instant,
+    next_ticket_id: u64,
+    /// Ids of the tickets that were presented, with their expiry
+    spent_tickets: HashMap<u64, TimeStamp>,
+}
+
+impl ResumptionTicketKeys {
+    pub(super) fn new() -> Result<Self, RandomnessError> {
+        let ring = TicketKeyRing {
+            epoch: 0,
+            current: QsResumptionTicketKey::random()?,
+            previous: None,
+            rotated_at: Instant::now(),
+            next_ticket_id: 0,
+            spent_tickets: HashMap::new(),
+        };
+        Ok(Self {
+            inner: Arc::new(Mutex::new(ring)),
+        })
+    }
+
+    fn issue(
+        &self,
+        client_id: QsClientId,
+        auth_key: QsClientVerifyingKey,
+    ) -> Result<QsResumptionTicket, QsListenAuthError> {
+        let mut ring = self.lock();
+        ring.rotate().map_err(|e| {
+            tracing::error!("Failed to rotate the resumption ticket key: {e}");
+            QsListenAuthError::LibraryError
+        })?;
+        let content = TicketContent {
+            key_epoch: ring.epoch,
+            ticket_id: ring.next_ticket_id,
+            client_id,
+            expires_at: TimeStamp::from(*TimeStamp::now() + TICKET_LIFETIME),
+            auth_key,
+        };
+        ring.next_ticket_id += 1;
+        let payload = content
+            .tls_serialize_detached()
+            .map_err(|_| QsListenAuthError::LibraryError)?;
+        let tag = ring.current.mac(&payload);
+        let ticket = Ticket { content, tag }
+            .tls_serialize_detached()
+            .map_err(|_| QsListenAuthError::LibraryError)?;
+        Ok(QsResumptionTicket::from_bytes(ticket))
+    }
+
+    /// Returns the content of the ticket if it was issued by this QS, has
+    /// not expired and was not presented before.
+    fn open(&self, ticket: &QsResumptionTicket) -> Option<TicketContent> {
+        let Ticket { content, tag } =
+            Ticket::tls_deserialize_exact_bytes(ticket.as_bytes()).ok()?;
+        let payload = content.tls_serialize_detached().ok()?;
+        if content.expires_at < TimeStamp::now() {
+            return None;
+        }
+        let mut ring = self.lock();
+        if let Err(e) = ring.rotate() {
+            // Tickets of the current key can still be verified.
+            tracing::error!("Failed to rotate the resumption ticket key: {e}");
+        }
+        let key = if content.key_epoch == ring.epoch {
+            &ring.current
+        } else if content.key_epoch + 1 == ring.epoch {
+            ring.previous.as_ref()?
+        } else {
+            return None;
+        };
+        key.verify(&payload, &tag).ok()?;
+        if ring
+            .spent_tickets
+            .insert(content.ticket_id, content.expires_at)
+            .is_some()
+        {
+            tracing::warn!("Rejected replayed resumption ticket");
+            return None;
+        }
+        Some(content)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TicketKeyRing> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl TicketKeyRing {
+    fn rotate(&mut self) -> Result<(), RandomnessError> {
+        self.rotate_at(Instant::now())
+    }
+
+    fn rotate_at(&mut self, now: Instant) -> Result<(), RandomnessError> {
+        let elapsed = now.saturating_duration_since(self.rotated_at);
+        if elapsed < TICKET_KEY_ROTATION_INTERVAL {
+            return Ok(());
+        }
+        let current = std::mem::replace(&mut self.current, QsResumptionTicketKey::random()?);
+        // Tickets tagged with a key older than the previous one have expired.
+        self.previous = (elapsed < 2 * TICKET_KEY_ROTATION_INTERVAL).then_some(current);
+        self.epoch += 1;
+        self.rotated_at = now;
+        let timestamp = TimeStamp::now();
+        self.spent_tickets
+            .retain(|_, expires_at| *expires_at >= timestamp);
+        Ok(())
+    }
+}
+
+impl Qs {
+    /// Authenticate a client opening a websocket, either via a signature or
+    /// via a resumption ticket. Returns a fresh ticket for the next
+    /// connection.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn authenticate_listen(
+        &self,
+        params: QsOpenWsParams,
+    ) -> Result<QsResumptionTicket, QsListenAuthError> {
+        let QsOpenWsParams { queue_id, auth } = params;
+        let client = QsClientRecord::load(&self.db_pool, &queue_id)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load client record: {:?}", e);
+                QsListenAuthError::StorageError
+            })?
+            .ok_or(QsListenAuthError::UnknownClient)?;
+
+        match auth {
+            QsOpenWsAuth::Ticket(ticket) => {
+                let content = self
+                    .ticket_keys
+                    .open(&ticket)
+                    .ok_or(QsListenAuthError::InvalidTicket)?;
+                // Tickets issued before the auth key changed are revoked.
+                if content.client_id != queue_id || content.auth_key != client.auth_key {
+                    return Err(QsListenAuthError::InvalidTicket);
+                }
+            }
+            auth @ QsOpenWsAuth::Signature { .. } => {
+                let verifiable = auth
+                    .into_verifiable(queue_id.clone())
+                    .ok_or(QsListenAuthError::LibraryError)?;
+                let verified: VerifiedQsOpenWs =
+                    verifiable.verify(&client.auth_key).map_err(|e| {
+                        tracing::warn!("Failed to verify listen request: {:?}", e);
+                        QsListenAuthError::InvalidSignature
+                    })?;
+                self.replay_cache
+                    .check(&self.db_pool, verified.freshness())
+                    .await
+                    .map_err(|e| match e {
+                        ReplayError::Storage(e) => {
+                            tracing::error!("Failed to check the replay cache: {e}");
+                            QsListenAuthError::StorageError
+                        }
+                        e => {
+                            tracing::warn!("Rejected listen request: {e}");
+                            QsListenAuthError::ReplayedRequest
+                        }
+                    })?;
+            }
+        }
+
+        self.ticket_keys.issue(queue_id, client.auth_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::crypto::signatures::keys::QsClientSigningKey;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn tickets_are_bound_to_keys() {
+        let keys = ResumptionTicketKeys::new().unwrap();
+        let client_id: QsClientId = Uuid::new_v4().into();
+        let auth_key = QsClientSigningKey::random().unwrap().verifying_key();
+        let issue = || keys.issue(client_id.clone(), auth_key.clone()).unwrap();
+
+        let ticket = issue();
+        // Tampered ticket
+        let mut bytes = ticket.as_bytes().to_vec();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(keys.open(&QsResumptionTicket::from_bytes(bytes)).is_none());
+
+        // Ticket of another QS
+        let other_keys = ResumptionTicketKeys::new().unwrap();
+        assert!(other_keys.open(&ticket).is_none());
+
+        let content = keys.open(&ticket).unwrap();
+        assert_eq!(content.client_id, client_id);
+        assert_eq!(content.auth_key, auth_key);
+
+        // Tickets remain valid after one rotation, but not after two
+        let ticket = issue();
+        let second_ticket = issue();
+        let next_rotation = Instant::now() + TICKET_KEY_ROTATION_INTERVAL;
+        keys.lock().rotate_at(next_rotation).unwrap();
+        assert!(keys.open(&ticket).is_some());
+        keys.lock()
+            .rotate_at(next_rotation + TICKET_KEY_ROTATION_INTERVAL)
+            .unwrap();
+        assert!(keys.open(&second_ticket).is_none());
+    }
+
+    #[test]
+    fn tickets_are_single_use() {
+        let keys = ResumptionTicketKeys::new().unwrap();
+        let client_id: QsClientId = Uuid::new_v4().into();
+        let auth_key = QsClientSigningKey::random().unwrap().verifying_key();
+
+        let ticket = keys.issue(client_id.clone(), auth_key.clone()).unwrap();
+        let next_ticket = keys.issue(client_id, auth_key).unwrap();
+        assert!(keys.open(&ticket).is_some());
+        assert!(keys.open(&ticket).is_none());
+        // Other tickets of the client are not affected.
+        assert!(keys.open(&next_ticket).is_some());
+    }
+}
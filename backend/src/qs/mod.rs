@@ -61,40 +61,61 @@
 //! smaller than the smalles requested one and responds with the requested
 //! messages.
 
+use std::sync::Arc;
+
 use client_id_decryption_key::StorableClientIdDecryptionKey;
+use listen_auth::ResumptionTicketKeys;
 use phnxtypes::{
     crypto::signatures::keys::QsVerifyingKey,
-    identifiers::{Fqdn, QsClientId},
-    messages::{client_ds::DsEventMessage, push_token::PushToken},
+    identifiers::{Fqdn, QsClientId, QsClientReference},
+    messages::{client_ds::DsEventMessage, presence::ContactPresence, push_token::PushToken},
 };
 
 use async_trait::*;
 use signing_key::StorableQsSigningKey;
 use sqlx::PgPool;
 use thiserror::Error;
+use tokio::sync::Notify;
 
 use crate::{
     errors::StorageError,
     infra_service::{InfraService, ServiceCreationError},
-    messages::intra_backend::DsFanOutMessage,
+    messages::intra_backend::{DsFanOutBatch, DsFanOutMessage},
+    replay_cache::ReplayCache,
 };
 
 mod add_package;
+mod admin;
 pub mod client_api;
 mod client_id_decryption_key;
 mod client_record;
+pub mod cluster;
 pub mod ds_api;
 pub mod errors;
+mod federated_fanout;
+mod federation_key_pin;
+pub mod listen_auth;
 pub mod network_provider_trait;
+mod presence;
 pub mod qs_api;
 mod queue;
+mod self_message;
 mod signing_key;
 mod user_record;
 
+/// Number of regular key packages below which a client is asked to publish
+/// new ones.
+pub const KEY_PACKAGE_LOW_WATERMARK: u32 = 10;
+
 #[derive(Debug, Clone)]
 pub struct Qs {
     domain: Fqdn,
     db_pool: PgPool,
+    replay_cache: ReplayCache,
+    ticket_keys: ResumptionTicketKeys,
+    /// Notified whenever undelivered fan-outs were persisted, such that they
+    /// are retried right away.
+    pending_fanouts: Arc<Notify>,
 }
 
 #[derive(Debug, Error)]
@@ -129,13 +150,23 @@ impl InfraService for Qs {
                 .map_err(|e| ServiceCreationError::InitializationFailed(Box::new(e)))?;
         }
 
-        Ok(Self { domain, db_pool })
+        let ticket_keys = ResumptionTicketKeys::new()
+            .map_err(|e| ServiceCreationError::InitializationFailed(Box::new(e)))?;
+
+        Ok(Self {
+            domain,
+            db_pool,
+            replay_cache: ReplayCache::default(),
+            ticket_keys,
+            pending_fanouts: Arc::default(),
+        })
     }
 }
 
 pub enum WsNotification {
     Event(DsEventMessage),
     QueueUpdate,
+    Presence(ContactPresence),
 }
 
 #[derive(Debug)]
@@ -181,5 +212,28 @@ pub trait QsConnector: Sync + Send + std::fmt::Debug + 'static {
     type EnqueueError: std::fmt::Debug;
     type VerifyingKeyError;
     async fn dispatch(&self, message: DsFanOutMessage) -> Result<(), Self::EnqueueError>;
+    /// Dispatch the payload of the batch to all of its clients. Returns the
+    /// clients whose messages couldn't be enqueued.
+    async fn dispatch_batch(
+        &self,
+        batch: DsFanOutBatch,
+    ) -> Result<Vec<QsClientReference>, Self::EnqueueError> {
+        let mut failed = Vec::new();
+        for client_reference in batch.client_references {
+            let message = DsFanOutMessage {
+                payload: batch.payload.clone(),
+                client_reference: client_reference.clone(),
+                priority: batch.priority,
+            };
+            if let Err(e) = self.dispatch(message).await {
+                tracing::warn!("Could not dispatch message: {:?}", e);
+                failed.push(client_reference);
+            }
+        }
+        Ok(failed)
+    }
     async fn verifying_key(&self, domain: Fqdn) -> Result<QsVerifyingKey, Self::VerifyingKeyError>;
+    /// Notify the local client with the given reference that its queue needs
+    /// attention, e.g. because it is running out of key packages.
+    async fn wake_up(&self, client_reference: QsClientReference) -> Result<(), Self::EnqueueError>;
 }
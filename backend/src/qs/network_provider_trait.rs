@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use async_trait::async_trait;
+use phnxtypes::crypto::signatures::keys::FederationKeyRecord;
 use std::error::Error;
 use std::fmt::Debug;
 
@@ -17,4 +18,13 @@ pub trait NetworkProvider: Sync + Send + Debug + 'static {
         bytes: Vec<u8>,
         destination: Fqdn,
     ) -> Result<FederatedProcessingResult, Self::NetworkError>;
+
+    /// Fetch the record in which the given domain publishes the fingerprint
+    /// of its QS verifying key. The record authenticates the servers of the
+    /// domain, so it must come from a channel they don't control on their
+    /// own, i.e. from the DNS TXT record of the domain.
+    async fn fetch_key_record(
+        &self,
+        domain: Fqdn,
+    ) -> Result<FederationKeyRecord, Self::NetworkError>;
 }
@@ -0,0 +1,377 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Presence of QS users.
+//!
+//! Users publish their status together with a presence token for each of
+//! their contacts. The QS enforces the visibility chosen by the user when the
+//! presence is fetched and only pushes changes to the connected clients of
+//! the user's contacts. The QS only stores the presence tokens, which can't
+//! be linked to the contacts they belong to. Presence is only shared between
+//! users of this QS.
+
+use phnxtypes::{
+    errors::qs::QsPresenceError,
+    identifiers::{QsClientId, QsUserId},
+    messages::{
+        presence::{
+            ContactPresence, FetchPresenceParams, FetchPresenceResponse, LastSeen, Presence,
+            PresenceQuery, PresenceStatus, PresenceToken, PresenceVisibility,
+            PublishPresenceParams, MAX_PRESENCE_CONTACTS, PRESENCE_TTL,
+        },
+        FriendshipToken,
+    },
+    time,
+};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Connection, PgConnection, PgExecutor,
+};
+
+use crate::errors::StorageError;
+
+use super::{user_record::UserRecord, Qs, WebsocketNotifier, WsNotification};
+
+#[derive(Debug, Clone, PartialEq)]
+struct StoredPresence {
+    status: PresenceStatus,
+    visibility: PresenceVisibility,
+    updated_at: DateTime<Utc>,
+    last_active_at: Option<DateTime<Utc>>,
+}
+
+impl StoredPresence {
+    /// The presence as revealed to users who are allowed to see it. Online
+    /// and away statuses that weren't republished in time are shown as
+    /// offline.
+    fn presence(&self, now: DateTime<Utc>) -> Presence {
+        if self.status != PresenceStatus::Offline && now - self.updated_at < PRESENCE_TTL {
+            Presence {
+                status: self.status,
+                last_seen: None,
+            }
+        } else {
+            Presence {
+                status: PresenceStatus::Offline,
+                last_seen: self
+                    .last_active_at
+                    .map(|last_active_at| LastSeen::from_elapsed(now - last_active_at)),
+            }
+        }
+    }
+
+    /// The presence as revealed to the given viewer.
+    fn presence_for(&self, now: DateTime<Utc>, is_contact: bool) -> Presence {
+        match self.visibility {
+            PresenceVisibility::Everyone => self.presence(now),
+            PresenceVisibility::Contacts if is_contact => self.presence(now),
+            PresenceVisibility::Contacts | PresenceVisibility::Nobody => Presence::hidden(),
+        }
+    }
+}
+
+impl Qs {
+    /// Store the presence of the sender and push it to the connected clients
+    /// of its contacts if it changed.
+    #[tracing::instrument(skip_all, err)]
+    pub(crate) async fn qs_publish_presence<W: WebsocketNotifier>(
+        &self,
+        websocket_notifier: &W,
+        params: PublishPresenceParams,
+    ) -> Result<(), QsPresenceError> {
+        let PublishPresenceParams {
+            sender,
+            status,
+            visibility,
+            viewers,
+            contacts,
+        } = params;
+        if viewers.len() > MAX_PRESENCE_CONTACTS || contacts.len() > MAX_PRESENCE_CONTACTS {
+            return Err(QsPresenceError::TooManyContacts);
+        }
+
+        let mut connection = self.db_pool.acquire().await.map_err(|e| {
+            tracing::warn!("Failed to acquire connection: {:?}", e);
+            QsPresenceError::StorageError
+        })?;
+        let user_record = UserRecord::load(&mut *connection, &sender)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load user record: {:?}", e);
+                QsPresenceError::StorageError
+            })?
+            .ok_or(QsPresenceError::StorageError)?;
+
+        let now = time::now();
+        let presence = StoredPresence {
+            status,
+            visibility,
+            updated_at: now,
+            // Publishing any status means the user was active just now.
+            last_active_at: Some(now),
+        };
+        let previous = presence
+            .replace(&mut connection, &sender, &viewers)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to store presence: {:?}", e);
+                QsPresenceError::StorageError
+            })?;
+
+        let changed = previous.is_none_or(|previous| {
+            previous.visibility != visibility || previous.presence(now) != presence.presence(now)
+        });
+        if !changed || contacts.is_empty() {
+            return Ok(());
+        }
+
+        // Contacts see the presence unless the user hides it.
+        let contact_presence = ContactPresence {
+            friendship_token: user_record.friendship_token,
+            presence: presence.presence_for(now, true),
+        };
+        let client_ids = load_contact_clients(&mut *connection, &contacts)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load contact clients: {:?}", e);
+                QsPresenceError::StorageError
+            })?;
+        for client_id in client_ids {
+            // Clients that aren't connected fetch the presence when they are.
+            let _ = websocket_notifier
+                .notify(
+                    &client_id,
+                    WsNotification::Presence(contact_presence.clone()),
+                )
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Load the presence of the given contacts as revealed to the sender.
+    #[tracing::instrument(skip_all, err)]
+    pub(crate) async fn qs_fetch_presence(
+        &self,
+        params: FetchPresenceParams,
+    ) -> Result<FetchPresenceResponse, QsPresenceError> {
+        // The sender is authenticated by the signature of the request. Whether
+        // it may see a presence only depends on the presence tokens.
+        let FetchPresenceParams {
+            sender: _,
+            contacts,
+        } = params;
+        if contacts.len() > MAX_PRESENCE_CONTACTS {
+            return Err(QsPresenceError::TooManyContacts);
+        }
+
+        let now = time::now();
+        let presences = StoredPresence::load_for_viewer(&self.db_pool, &contacts)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load presence: {:?}", e);
+                QsPresenceError::StorageError
+            })?
+            .into_iter()
+            .map(|(friendship_token, presence, is_contact)| ContactPresence {
+                friendship_token,
+                presence: presence.presence_for(now, is_contact),
+            })
+            .collect();
+        Ok(FetchPresenceResponse { presences })
+    }
+}
+
+/// Ids of the clients of the users with the given friendship tokens.
+async fn load_contact_clients(
+    connection: impl PgExecutor<'_>,
+    contacts: &[FriendshipToken],
+) -> Result<Vec<QsClientId>, StorageError> {
+    let tokens: Vec<Vec<u8>> = contacts
+        .iter()
+        .map(|token| token.token().to_vec())
+        .collect();
+    let client_ids = sqlx::query_scalar!(
+        r#"SELECT c.client_id AS "client_id: QsClientId"
+        FROM qs_client_records c
+        JOIN qs_user_records u ON u.user_id = c.user_id
+        WHERE u.friendship_token = ANY($1)"#,
+        &tokens,
+    )
+    .fetch_all(connection)
+    .await?;
+    Ok(client_ids)
+}
+
+impl StoredPresence {
+    /// Store the presence and viewers of the given user, replacing the
+    /// previous ones. Returns the previous presence.
+    async fn replace(
+        &self,
+        connection: &mut PgConnection,
+        user_id: &QsUserId,
+        viewers: &[PresenceToken],
+    ) -> Result<Option<Self>, StorageError> {
+        let mut transaction = connection.begin().await?;
+        let previous = sqlx::query!(
+            r#"SELECT status, visibility, updated_at, last_active_at
+            FROM qs_presence WHERE user_id = $1 FOR UPDATE"#,
+            user_id as &QsUserId,
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .and_then(|row| {
+            Some(Self {
+                status: PresenceStatus::try_from(row.status).ok()?,
+                visibility: PresenceVisibility::try_from(row.visibility).ok()?,
+                updated_at: row.updated_at,
+                last_active_at: row.last_active_at,
+            })
+        });
+        sqlx::query!(
+            "INSERT INTO qs_presence
+                (user_id, status, visibility, updated_at, last_active_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id) DO UPDATE SET
+                status = EXCLUDED.status,
+                visibility = EXCLUDED.visibility,
+                updated_at = EXCLUDED.updated_at,
+                last_active_at = EXCLUDED.last_active_at",
+            user_id as &QsUserId,
+            self.status as i16,
+            self.visibility as i16,
+            self.updated_at,
+            self.last_active_at,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM qs_presence_viewers WHERE user_id = $1",
+            user_id as &QsUserId,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        let tokens: Vec<Vec<u8>> = viewers
+            .iter()
+            .map(|token| token.as_slice().to_vec())
+            .collect();
+        sqlx::query!(
+            "INSERT INTO qs_presence_viewers (user_id, presence_token)
+            SELECT $1, token FROM UNNEST($2::bytea[]) AS token
+            ON CONFLICT DO NOTHING",
+            user_id as &QsUserId,
+            &tokens,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(previous)
+    }
+
+    /// Load the presence of the requested users together with whether they
+    /// reveal it to the holder of the given presence token. Users who never
+    /// published their presence are omitted.
+    async fn load_for_viewer(
+        connection: impl PgExecutor<'_>,
+        contacts: &[PresenceQuery],
+    ) -> Result<Vec<(FriendshipToken, Self, bool)>, StorageError> {
+        let (friendship_tokens, presence_tokens): (Vec<Vec<u8>>, Vec<Vec<u8>>) = contacts
+            .iter()
+            .map(|query| {
+                (
+                    query.friendship_token.token().to_vec(),
+                    query.presence_token.as_slice().to_vec(),
+                )
+            })
+            .unzip();
+        let rows = sqlx::query!(
+            r#"SELECT
+                u.friendship_token AS "friendship_token: FriendshipToken",
+                p.status,
+                p.visibility,
+                p.updated_at,
+                p.last_active_at,
+                EXISTS (
+                    SELECT 1 FROM qs_presence_viewers v
+                    WHERE v.user_id = p.user_id AND v.presence_token = q.presence_token
+                ) AS "is_contact!"
+            FROM UNNEST($1::bytea[], $2::bytea[]) AS q(friendship_token, presence_token)
+            JOIN qs_user_records u ON u.friendship_token = q.friendship_token
+            JOIN qs_presence p ON p.user_id = u.user_id"#,
+            &friendship_tokens,
+            &presence_tokens,
+        )
+        .fetch_all(connection)
+        .await?;
+        let presences = rows
+            .into_iter()
+            .filter_map(|row| {
+                let presence = Self {
+                    status: PresenceStatus::try_from(row.status).ok()?,
+                    visibility: PresenceVisibility::try_from(row.visibility).ok()?,
+                    updated_at: row.updated_at,
+                    last_active_at: row.last_active_at,
+                };
+                Some((row.friendship_token, presence, row.is_contact))
+            })
+            .collect();
+        Ok(presences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::chrono::Duration;
+
+    use super::*;
+
+    fn stored(status: PresenceStatus, visibility: PresenceVisibility) -> StoredPresence {
+        let now = Utc::now();
+        StoredPresence {
+            status,
+            visibility,
+            updated_at: now,
+            last_active_at: Some(now),
+        }
+    }
+
+    #[test]
+    fn expired_presence_is_offline() {
+        let presence = stored(PresenceStatus::Online, PresenceVisibility::Everyone);
+        let now = presence.updated_at;
+        assert_eq!(
+            presence.presence(now),
+            Presence {
+                status: PresenceStatus::Online,
+                last_seen: None
+            }
+        );
+        assert_eq!(
+            presence.presence(now + PRESENCE_TTL + Duration::hours(2)),
+            Presence {
+                status: PresenceStatus::Offline,
+                last_seen: Some(LastSeen::Today)
+            }
+        );
+    }
+
+    #[test]
+    fn visibility_is_enforced() {
+        let now = Utc::now();
+        let contacts = stored(PresenceStatus::Away, PresenceVisibility::Contacts);
+        assert_eq!(
+            contacts.presence_for(now, true).status,
+            PresenceStatus::Away
+        );
+        assert_eq!(contacts.presence_for(now, false), Presence::hidden());
+
+        let everyone = stored(PresenceStatus::Away, PresenceVisibility::Everyone);
+        assert_eq!(
+            everyone.presence_for(now, false).status,
+            PresenceStatus::Away
+        );
+
+        let nobody = stored(PresenceStatus::Away, PresenceVisibility::Nobody);
+        assert_eq!(nobody.presence_for(now, true), Presence::hidden());
+    }
+}
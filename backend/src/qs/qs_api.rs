@@ -2,11 +2,14 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use phnxtypes::errors::qs::QsVerifyingKeyError;
+use phnxtypes::{errors::qs::QsVerifyingKeyError, identifiers::QsClientReference};
 use thiserror::Error;
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
-use crate::messages::qs_qs::{QsToQsMessage, QsToQsPayload};
+use crate::messages::{
+    intra_backend::DsFanOutMessage,
+    qs_qs::{QsToQsMessage, QsToQsPayload},
+};
 
 use super::{
     errors::QsEnqueueError, network_provider_trait::NetworkProvider, Qs, QsConnector,
@@ -28,6 +31,17 @@ pub enum FederatedProcessingError<N: NetworkProvider> {
 pub enum FederatedProcessingResult {
     Ok,
     VerifyingKey(QsVerifyingKey),
+    FanOutBatch(FanOutBatchReport),
+}
+
+/// Result of a [`crate::messages::qs_qs::FanOutBatchRequest`].
+#[derive(Debug, Clone, Default, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+pub struct FanOutBatchReport {
+    /// Indices of the recipients whose messages couldn't be enqueued.
+    pub failed_recipients: Vec<u32>,
+    /// Indices of the recipients that don't exist, e.g. because their
+    /// queue was deleted. Their messages can't be enqueued on a retry either.
+    pub invalid_recipients: Vec<u32>,
 }
 
 impl Qs {
@@ -61,6 +75,36 @@ impl Qs {
                 let verifying_key_response = self.qs_verifying_key().await?;
                 FederatedProcessingResult::VerifyingKey(verifying_key_response.verifying_key)
             }
+            QsToQsPayload::FanOutBatchRequest(request) => {
+                // The references are completed with the own domain, such
+                // that a batch can't be routed through this QS.
+                let mut failed_recipients = Vec::new();
+                let mut invalid_recipients = Vec::new();
+                for (index, sealed_reference) in request.sealed_references.into_iter().enumerate() {
+                    let fan_out_message = DsFanOutMessage {
+                        payload: request.payload.clone(),
+                        client_reference: QsClientReference {
+                            client_homeserver_domain: self.domain.clone(),
+                            sealed_reference,
+                        },
+                        priority: request.priority,
+                    };
+                    match qs_connector.dispatch(fan_out_message).await {
+                        Ok(()) => {}
+                        Err(QsEnqueueError::QueueNotFound | QsEnqueueError::UnsealError(_)) => {
+                            invalid_recipients.push(index as u32);
+                        }
+                        Err(e) => {
+                            tracing::warn!("Could not enqueue batched message: {:?}", e);
+                            failed_recipients.push(index as u32);
+                        }
+                    }
+                }
+                FederatedProcessingResult::FanOutBatch(FanOutBatchReport {
+                    failed_recipients,
+                    invalid_recipients,
+                })
+            }
         };
         Ok(result)
     }
@@ -2,7 +2,11 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use phnxtypes::{codec::PhnxCodec, identifiers::QsClientId, messages::QueueMessage};
+use phnxtypes::{
+    codec::PhnxCodec,
+    identifiers::QsClientId,
+    messages::{QueueMessage, QueuePriority},
+};
 use sqlx::{Connection, PgConnection, PgExecutor};
 
 use crate::errors::{QueueError, StorageError};
@@ -47,12 +51,13 @@ impl Queue {
                 RETURNING sequence_number - 1 as sequence_number
             )
             -- Step 2: Insert the message with the new sequence number.
-            INSERT INTO qs_queues (queue_id, sequence_number, message_bytes) 
-            SELECT $1, sequence_number, $2 FROM updated_sequence
+            INSERT INTO qs_queues (queue_id, sequence_number, message_bytes, priority) 
+            SELECT $1, sequence_number, $2, $3 FROM updated_sequence
             RETURNING sequence_number
             "#,
             queue_id as &QsClientId,
             message_bytes,
+            message.priority as i16,
         )
         .fetch_one(&mut *transaction)
         .await?;
@@ -92,7 +97,7 @@ impl Queue {
                 RETURNING *
             ),
             fetched AS (
-                SELECT message_bytes FROM qs_queues
+                SELECT message_bytes, priority FROM qs_queues
                 WHERE queue_id = $1 AND sequence_number >= $2
                 ORDER BY sequence_number ASC
                 LIMIT $3
@@ -104,6 +109,7 @@ impl Queue {
             )
             SELECT 
                 fetched.message_bytes,
+                fetched.priority,
                 remaining.count
             FROM fetched, remaining
             "#,
@@ -120,7 +126,14 @@ impl Queue {
         let messages = rows
             .iter()
             .map(|row| {
-                let message = PhnxCodec::from_slice(&row.message_bytes)?;
+                let mut message: QueueMessage = PhnxCodec::from_slice(&row.message_bytes)?;
+                // Messages are returned in sequence order, since the client
+                // can only decrypt them in that order. The client uses the
+                // priority to decide the order of processing.
+                message.priority = QueuePriority::try_from(row.priority).map_err(|priority| {
+                    tracing::warn!("Invalid queue priority in storage: {}", priority);
+                    QueueError::LibraryError
+                })?;
                 Ok(message)
             })
             .collect::<Result<Vec<_>, QueueError>>()?;
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Messages between the clients of a user.
+//!
+//! A client sends an encrypted self message to the QS, which puts it into the
+//! queues of all other clients of the same user. The QS can't read self
+//! messages; it only makes sure they stay within the user.
+
+use phnxtypes::{
+    errors::qs::QsSelfMessageError,
+    identifiers::{QsClientId, QsUserId},
+    messages::{client_ds::QsQueueMessagePayload, self_message::EnqueueSelfMessageParams},
+};
+use sqlx::PgExecutor;
+
+use crate::errors::StorageError;
+
+use super::{client_record::QsClientRecord, queue::Queue, Qs, WebsocketNotifier, WsNotification};
+
+impl Qs {
+    /// Enqueue the self message of the sender in the queues of the other
+    /// clients of its user and notify the connected ones.
+    #[tracing::instrument(skip_all, err)]
+    pub(crate) async fn qs_enqueue_self_message<W: WebsocketNotifier>(
+        &self,
+        websocket_notifier: &W,
+        params: EnqueueSelfMessageParams,
+    ) -> Result<(), QsSelfMessageError> {
+        let EnqueueSelfMessageParams { sender, message } = params;
+        let payload = QsQueueMessagePayload::try_from(message).map_err(|e| {
+            tracing::error!("Failed to serialize self message: {:?}", e);
+            QsSelfMessageError::LibraryError
+        })?;
+
+        let mut transaction = self.db_pool.begin().await.map_err(|e| {
+            tracing::warn!("Failed to start transaction: {:?}", e);
+            QsSelfMessageError::StorageError
+        })?;
+        let sender_record = QsClientRecord::load(&mut *transaction, &sender)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load client record: {:?}", e);
+                QsSelfMessageError::StorageError
+            })?
+            .ok_or(QsSelfMessageError::StorageError)?;
+        let client_ids = load_user_clients(&mut *transaction, &sender_record.user_id)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to load user clients: {:?}", e);
+                QsSelfMessageError::StorageError
+            })?;
+
+        let mut recipients = Vec::with_capacity(client_ids.len());
+        for client_id in client_ids {
+            if client_id == sender {
+                continue;
+            }
+            // The client might have been deleted in the meantime.
+            let Some(mut client_record) = QsClientRecord::load(&mut *transaction, &client_id)
+                .await
+                .map_err(|e| {
+                    tracing::warn!("Failed to load client record: {:?}", e);
+                    QsSelfMessageError::StorageError
+                })?
+            else {
+                continue;
+            };
+            let queue_message = client_record
+                .ratchet_key
+                .encrypt(payload.clone())
+                .map_err(|_| QsSelfMessageError::LibraryError)?;
+            Queue::enqueue(&mut transaction, &client_id, queue_message)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to enqueue self message: {:?}", e);
+                    QsSelfMessageError::StorageError
+                })?;
+            // Store the new ratchet key.
+            client_record.update(&mut transaction).await.map_err(|e| {
+                tracing::error!("Failed to update client record: {:?}", e);
+                QsSelfMessageError::StorageError
+            })?;
+            recipients.push(client_id);
+        }
+
+        transaction.commit().await.map_err(|e| {
+            tracing::warn!("Failed to commit transaction: {:?}", e);
+            QsSelfMessageError::StorageError
+        })?;
+
+        // Self messages sync state between the clients of the user and are
+        // not worth a push notification. Clients that aren't connected fetch
+        // them with their next dequeue.
+        for client_id in recipients {
+            let _ = websocket_notifier
+                .notify(&client_id, WsNotification::QueueUpdate)
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// Ids of the clients of the given user.
+async fn load_user_clients(
+    connection: impl PgExecutor<'_>,
+    user_id: &QsUserId,
+) -> Result<Vec<QsClientId>, StorageError> {
+    let client_ids = sqlx::query_scalar!(
+        r#"SELECT client_id AS "client_id: QsClientId"
+        FROM qs_client_records
+        WHERE user_id = $1"#,
+        user_id as &QsUserId,
+    )
+    .fetch_all(connection)
+    .await?;
+    Ok(client_ids)
+}
@@ -0,0 +1,187 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Cache of nonces used to reject replays of signed client payloads.
+//!
+//! Payloads carry a [`Freshness`] with a time stamp and a random nonce. A
+//! payload is only accepted if its time stamp lies within the freshness window
+//! and its nonce hasn't been seen before. Nonces only need to be remembered
+//! for as long as the window is open, since older payloads are rejected based
+//! on their time stamp alone.
+//!
+//! The nonces are stored in the database, such that they survive restarts
+//! and are shared by all instances of the server.
+
+use std::sync::{Arc, Mutex};
+
+use phnxtypes::{
+    crypto::signatures::signable::{Freshness, FreshnessError, FRESHNESS_MAX_AGE},
+    time::{now, TimeStamp},
+};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgExecutor, PgPool,
+};
+use thiserror::Error;
+
+use crate::errors::StorageError;
+
+#[derive(Debug, Error)]
+pub(crate) enum ReplayError {
+    #[error(transparent)]
+    Freshness(#[from] FreshnessError),
+    #[error("Nonce was already used")]
+    Replayed,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReplayCache {
+    /// When this instance last deleted the expired nonces
+    last_pruned: Arc<Mutex<Option<TimeStamp>>>,
+}
+
+impl ReplayCache {
+    /// Check that the payload is fresh and that its nonce hasn't been used
+    /// before. Marks the nonce as used on success.
+    pub(crate) async fn check(
+        &self,
+        db_pool: &PgPool,
+        freshness: &Freshness,
+    ) -> Result<(), ReplayError> {
+        freshness.validate()?;
+        if self.prune_due() {
+            prune(db_pool, now()).await?;
+        }
+        let expires_at = **freshness.timestamp() + FRESHNESS_MAX_AGE;
+        if !store_nonce(db_pool, freshness.nonce(), expires_at).await? {
+            return Err(ReplayError::Replayed);
+        }
+        Ok(())
+    }
+
+    /// Pruning happens at most once per window to keep checks cheap.
+    fn prune_due(&self) -> bool {
+        let mut last_pruned = self.last_pruned.lock().unwrap_or_else(|e| e.into_inner());
+        if last_pruned.is_some_and(|last_pruned| !last_pruned.has_expired(FRESHNESS_MAX_AGE)) {
+            return false;
+        }
+        *last_pruned = Some(TimeStamp::now());
+        true
+    }
+}
+
+/// Store the nonce. Returns false if it was already stored.
+async fn store_nonce(
+    connection: impl PgExecutor<'_>,
+    nonce: &[u8],
+    expires_at: DateTime<Utc>,
+) -> Result<bool, StorageError> {
+    let result = sqlx::query!(
+        "INSERT INTO replay_nonces (nonce, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        nonce,
+        expires_at,
+    )
+    .execute(connection)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Delete the nonces of payloads that are outside the freshness window.
+async fn prune(connection: impl PgExecutor<'_>, now: DateTime<Utc>) -> Result<(), StorageError> {
+    sqlx::query!("DELETE FROM replay_nonces WHERE expires_at < $1", now)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        crypto::signatures::signable::FRESHNESS_MAX_CLOCK_SKEW,
+        time::{set_thread_time_provider, Duration, TimeProvider},
+    };
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FixedClock(DateTime<Utc>);
+
+    impl TimeProvider for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    /// Freshness of a payload signed by a client whose clock is off by
+    /// `offset`.
+    fn freshness_with_offset(offset: Duration) -> Freshness {
+        set_thread_time_provider(Some(Arc::new(FixedClock(Utc::now() + offset))));
+        let freshness = Freshness::new();
+        set_thread_time_provider(None);
+        freshness
+    }
+
+    #[sqlx::test]
+    async fn replayed_nonce_is_rejected(pool: PgPool) {
+        let cache = ReplayCache::default();
+        let freshness = Freshness::new();
+        cache.check(&pool, &freshness).await.unwrap();
+        assert!(matches!(
+            cache.check(&pool, &freshness).await,
+            Err(ReplayError::Replayed)
+        ));
+        cache.check(&pool, &Freshness::new()).await.unwrap();
+
+        // The nonce is also known after a restart and to other instances
+        let other_instance = ReplayCache::default();
+        assert!(matches!(
+            other_instance.check(&pool, &freshness).await,
+            Err(ReplayError::Replayed)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn payloads_outside_the_window_are_rejected(pool: PgPool) {
+        let cache = ReplayCache::default();
+        let stale = freshness_with_offset(-FRESHNESS_MAX_AGE - Duration::seconds(1));
+        assert!(matches!(
+            cache.check(&pool, &stale).await,
+            Err(ReplayError::Freshness(FreshnessError::Stale))
+        ));
+        let from_the_future =
+            freshness_with_offset(FRESHNESS_MAX_CLOCK_SKEW + Duration::seconds(1));
+        assert!(matches!(
+            cache.check(&pool, &from_the_future).await,
+            Err(ReplayError::Freshness(FreshnessError::FromTheFuture))
+        ));
+
+        // Small clock skew is tolerated
+        let skewed = freshness_with_offset(FRESHNESS_MAX_CLOCK_SKEW - Duration::seconds(1));
+        cache.check(&pool, &skewed).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn expired_nonces_are_pruned(pool: PgPool) {
+        let old = freshness_with_offset(-FRESHNESS_MAX_AGE + Duration::seconds(10));
+        let recent = Freshness::new();
+        for freshness in [&old, &recent] {
+            let expires_at = **freshness.timestamp() + FRESHNESS_MAX_AGE;
+            assert!(store_nonce(&pool, freshness.nonce(), expires_at)
+                .await
+                .unwrap());
+        }
+
+        prune(&pool, Utc::now() + Duration::seconds(20))
+            .await
+            .unwrap();
+        let recent_expiry = **recent.timestamp() + FRESHNESS_MAX_AGE;
+        let old_expiry = **old.timestamp() + FRESHNESS_MAX_AGE;
+        assert!(store_nonce(&pool, old.nonce(), old_expiry).await.unwrap());
+        assert!(!store_nonce(&pool, recent.nonce(), recent_expiry)
+            .await
+            .unwrap());
+    }
+}
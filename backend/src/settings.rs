@@ -2,6 +2,12 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::{collections::BTreeMap, path::PathBuf};
+
+use phnxtypes::{
+    messages::{announcements::AnnouncementKind, server_info::ClientVersion},
+    time::TimeStamp,
+};
 use serde::Deserialize;
 
 /// Configuration for the server.
@@ -15,6 +21,38 @@ pub struct Settings {
     // If this isn't present, the provider will not send push notifications to
     // android devices.
     pub fcm: Option<FcmSettings>,
+    #[serde(default)]
+    pub spam_throttle: SpamThrottleSettings,
+    #[serde(default)]
+    pub send_rate_limit: SendRateLimitSettings,
+    #[serde(default)]
+    pub welcome_info_cache: WelcomeInfoCacheSettings,
+    #[serde(default)]
+    pub attachment_scanning: AttachmentScanningSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub handle_policy: HandlePolicySettings,
+    #[serde(default)]
+    pub server_info: ServerInfoSettings,
+    #[serde(default)]
+    pub announcements: Vec<AnnouncementSettings>,
+    #[serde(default)]
+    pub data_export: DataExportSettings,
+    #[serde(default)]
+    pub connection_packages: ConnectionPackageSettings,
+    #[serde(default)]
+    pub request_limits: RequestLimitSettings,
+    #[serde(default)]
+    pub dispatch: DispatchSettings,
+    #[serde(default)]
+    pub logging: LoggingSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    /// Additional domains served by the server. The domain of the
+    /// application settings is always served.
+    #[serde(default)]
+    pub tenants: Vec<TenantSettings>,
 }
 
 /// Configuration for the application.
@@ -25,6 +63,17 @@ pub struct ApplicationSettings {
     pub domain: String,
 }
 
+/// An additional domain served by the server. Settings that aren't given are
+/// taken from the configuration of the primary domain.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TenantSettings {
+    pub domain: String,
+    pub apns: Option<ApnsSettings>,
+    pub fcm: Option<FcmSettings>,
+    pub spam_throttle: Option<SpamThrottleSettings>,
+    pub send_rate_limit: Option<SendRateLimitSettings>,
+}
+
 /// Configuration for the database.
 #[derive(Deserialize, Clone, Debug)]
 pub struct DatabaseSettings {
@@ -49,6 +98,543 @@ pub struct ApnsSettings {
     pub privatekeypath: String,
 }
 
+/// Thresholds for throttling senders on the DS.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SpamThrottleSettings {
+    pub enabled: bool,
+    /// Length of the window in which the activity of a sender is tracked.
+    pub window_secs: u64,
+    /// Number of recipients a sender can reach per window before its
+    /// messages are delayed.
+    pub fanout_soft_limit: u64,
+    /// Number of recipients a sender can reach per window before its
+    /// messages are rejected.
+    pub fanout_hard_limit: u64,
+    /// Number of distinct groups a sender can send to per window before its
+    /// messages are delayed.
+    pub group_soft_limit: u64,
+    /// Maximum delay applied to a single message.
+    pub max_delay_ms: u64,
+    /// Groups with at most this many users are exempt from throttling.
+    pub exempt_max_group_size: usize,
+}
+
+impl Default for SpamThrottleSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_secs: 60,
+            fanout_soft_limit: 1_000,
+            fanout_hard_limit: 5_000,
+            group_soft_limit: 50,
+            max_delay_ms: 5_000,
+            exempt_max_group_size: 2,
+        }
+    }
+}
+
+/// Token bucket limiting the rate at which a single sender can send messages
+/// via the DS. The limit is published to clients in the server info, such
+/// that they can pace their messages accordingly.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SendRateLimitSettings {
+    pub enabled: bool,
+    /// Number of messages a sender can send per second on average.
+    pub messages_per_second: u32,
+    /// Number of messages a sender can send at once.
+    pub burst: u32,
+}
+
+impl Default for SendRateLimitSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            messages_per_second: 10,
+            burst: 50,
+        }
+    }
+}
+
+/// Configuration of the welcome info cache on the DS.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WelcomeInfoCacheSettings {
+    pub enabled: bool,
+    /// Maximum number of cached (group, epoch) entries.
+    pub max_entries: usize,
+}
+
+impl Default for WelcomeInfoCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 1_000,
+        }
+    }
+}
+
+/// Configuration of the websocket dispatch on the QS.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DispatchSettings {
+    /// Maximum number of notifications buffered per websocket connection. A
+    /// connection that doesn't keep up is closed and has to resume by fetching
+    /// its queue.
+    pub buffer_size: usize,
+    /// How notifications reach clients connected to other replicas
+    pub cluster: ClusterDispatchSettings,
+}
+
+impl Default for DispatchSettings {
+    fn default() -> Self {
+        Self {
+            buffer_size: 64,
+            cluster: ClusterDispatchSettings::default(),
+        }
+    }
+}
+
+/// Channel shared by the replicas of the QS to deliver websocket
+/// notifications to clients connected to another replica.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum ClusterDispatchSettings {
+    /// Single replica. Notifications are only delivered to local connections.
+    #[default]
+    Local,
+    /// Postgres LISTEN/NOTIFY on the QS database.
+    Postgres(PgListenSettings),
+    /// Redis pub/sub.
+    Redis(RedisDispatchSettings),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedisDispatchSettings {
+    /// Connection URL, e.g. `redis://127.0.0.1:6379`
+    pub url: String,
+}
+
+/// Configuration of the reliable Postgres LISTEN/NOTIFY listeners.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PgListenSettings {
+    /// Delay before the first reconnection attempt. Doubles with each failed
+    /// attempt.
+    pub reconnect_min_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    /// Interval in which listeners check for missed events, even if they
+    /// didn't detect a gap.
+    pub catch_up_interval_secs: u64,
+    /// How long published events are kept for listeners to catch up.
+    pub retention_secs: u64,
+}
+
+impl Default for PgListenSettings {
+    fn default() -> Self {
+        Self {
+            reconnect_min_delay_ms: 500,
+            reconnect_max_delay_ms: 30_000,
+            catch_up_interval_secs: 30,
+            retention_secs: 60 * 60,
+        }
+    }
+}
+
+/// Limits for requests received by the server.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RequestLimitSettings {
+    /// Maximum size of request bodies. Larger requests are rejected before
+    /// they are read completely.
+    pub max_request_bytes: usize,
+}
+
+impl Default for RequestLimitSettings {
+    fn default() -> Self {
+        Self {
+            max_request_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+/// Rules for the user names that can be registered with the AS.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HandlePolicySettings {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Whether upper case letters are allowed in addition to lower case
+    /// letters and digits.
+    pub allow_uppercase: bool,
+    /// Symbols allowed in addition to letters and digits.
+    pub allowed_symbols: String,
+    /// User names that can't be registered. Matching ignores case and
+    /// symbols, such that e.g. `Ad_min` is rejected if `admin` is reserved.
+    pub reserved_words: Vec<String>,
+    /// Time after the deletion of a user before its user name can be
+    /// registered again.
+    pub release_cooldown_secs: u64,
+}
+
+impl Default for HandlePolicySettings {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            max_length: 32,
+            allow_uppercase: false,
+            allowed_symbols: "_-".to_owned(),
+            reserved_words: [
+                "admin",
+                "administrator",
+                "moderator",
+                "root",
+                "support",
+                "system",
+            ]
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect(),
+            release_cooldown_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Information about the server that the AS publishes to clients, including
+/// clients without an account.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerInfoSettings {
+    /// Whether new users can register with the AS.
+    pub registration_open: bool,
+    /// Message of the day, e.g. shown to users before they register.
+    pub motd: Option<String>,
+    /// URL to which clients upload their metrics if the user opted in. Clients
+    /// don't collect metrics if not set.
+    pub metrics_endpoint: Option<String>,
+    /// Oldest client version the server serves. Older clients show a
+    /// mandatory upgrade screen instead of failing on unknown requests.
+    pub min_client_version: Option<ClientVersion>,
+    /// Names of features that will be removed. Clients relying on them ask
+    /// the user to upgrade.
+    pub deprecated_features: Vec<String>,
+}
+
+impl Default for ServerInfoSettings {
+    fn default() -> Self {
+        Self {
+            registration_open: true,
+            motd: None,
+            metrics_endpoint: None,
+            min_client_version: None,
+            deprecated_features: Vec::new(),
+        }
+    }
+}
+
+/// Exports of the personal data the AS holds about a user.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DataExportSettings {
+    /// Time after which a completed export is deleted.
+    pub retention_secs: u64,
+    /// Time after which an export that is still being assembled is considered
+    /// failed, e.g. because the server restarted in the meantime.
+    pub job_timeout_secs: u64,
+    /// Interval in which expired exports are deleted.
+    pub cleanup_interval_secs: u64,
+}
+
+impl Default for DataExportSettings {
+    fn default() -> Self {
+        Self {
+            retention_secs: 7 * 24 * 60 * 60,
+            job_timeout_secs: 10 * 60,
+            cleanup_interval_secs: 60 * 60,
+        }
+    }
+}
+
+/// Lifecycle of the connection packages the AS serves to new contacts.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConnectionPackageSettings {
+    /// Connection packages that expire later than this after their
+    /// publication are rejected.
+    pub max_lifetime_secs: u64,
+    /// Interval in which expired connection packages are deleted.
+    pub sweep_interval_secs: u64,
+    /// Time during which connection establishment packages can be enqueued
+    /// for the clients whose connection packages were served.
+    pub reservation_ttl_secs: u64,
+    /// Tolerated deviation of the clocks of clients from the clock of the
+    /// server when checking the lifetime of published connection packages.
+    pub clock_skew_secs: u64,
+}
+
+impl Default for ConnectionPackageSettings {
+    fn default() -> Self {
+        Self {
+            max_lifetime_secs: 30 * 24 * 60 * 60,
+            sweep_interval_secs: 60 * 60,
+            reservation_ttl_secs: 15 * 60,
+            clock_skew_secs: 5 * 60,
+        }
+    }
+}
+
+/// An announcement of the operator, served to clients between its publication
+/// and its expiration.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AnnouncementSettings {
+    /// Unique id, used by clients to track which announcements were read.
+    pub id: String,
+    #[serde(default)]
+    pub kind: AnnouncementKind,
+    pub title: String,
+    pub body: String,
+    pub published_at: TimeStamp,
+    pub expires_at: Option<TimeStamp>,
+}
+
+/// Configuration of the scanner for attachments uploaded to the DS.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AttachmentScanningSettings {
+    pub enabled: bool,
+    /// Address of the clamd daemon, e.g. `127.0.0.1:3310`.
+    pub clamd_address: String,
+    /// Maximum time to wait for a verdict before rejecting the upload.
+    pub timeout_secs: u64,
+}
+
+impl Default for AttachmentScanningSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            clamd_address: "127.0.0.1:3310".to_owned(),
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// Storage provider for attachments uploaded to the DS.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum StorageSettings {
+    /// Store attachments in the DS database.
+    #[default]
+    Postgres,
+    S3(S3StorageSettings),
+    Filesystem(FilesystemStorageSettings),
+}
+
+/// Configuration of an S3-compatible object store.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct S3StorageSettings {
+    pub bucket: String,
+    pub region: String,
+    /// Endpoint of the object store. Uses the AWS endpoint of the region if
+    /// unset.
+    pub endpoint: Option<String>,
+    /// Address buckets by path instead of by subdomain, as required by most
+    /// self-hosted object stores.
+    pub force_path_style: bool,
+    /// Static credentials. If unset, credentials are taken from the
+    /// environment.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Objects larger than this are uploaded in multiple parts.
+    pub multipart_threshold_bytes: usize,
+    /// Size of the parts of multipart uploads. At least 5 MiB.
+    pub multipart_part_size_bytes: usize,
+    /// Maximum number of attempts per request, including the first one.
+    pub max_attempts: u32,
+    /// Backoff before the first retry. Doubles with each further retry.
+    pub initial_backoff_ms: u64,
+    /// If set, downloads are served via presigned URLs that are valid for
+    /// the given time.
+    pub presigned_url_expiry_secs: Option<u64>,
+    /// Tag applied to the objects of attachments, in the form `key=value`.
+    /// Can be used to scope bucket lifecycle rules to the objects of the DS.
+    pub lifecycle_tag: Option<String>,
+    /// Tag applied to the objects of message archives, which must not expire
+    /// with the attachments.
+    pub archive_lifecycle_tag: Option<String>,
+}
+
+// The secret access key is redacted, so that it doesn't end up in logs.
+impl std::fmt::Debug for S3StorageSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            bucket,
+            region,
+            endpoint,
+            force_path_style,
+            access_key_id,
+            secret_access_key,
+            multipart_threshold_bytes,
+            multipart_part_size_bytes,
+            max_attempts,
+            initial_backoff_ms,
+            presigned_url_expiry_secs,
+            lifecycle_tag,
+            archive_lifecycle_tag,
+        } = self;
+        f.debug_struct("S3StorageSettings")
+            .field("bucket", bucket)
+            .field("region", region)
+            .field("endpoint", endpoint)
+            .field("force_path_style", force_path_style)
+            .field("access_key_id", access_key_id)
+            .field(
+                "secret_access_key",
+                &secret_access_key.as_ref().map(|_| "[[REDACTED]]"),
+            )
+            .field("multipart_threshold_bytes", multipart_threshold_bytes)
+            .field("multipart_part_size_bytes", multipart_part_size_bytes)
+            .field("max_attempts", max_attempts)
+            .field("initial_backoff_ms", initial_backoff_ms)
+            .field("presigned_url_expiry_secs", presigned_url_expiry_secs)
+            .field("lifecycle_tag", lifecycle_tag)
+            .field("archive_lifecycle_tag", archive_lifecycle_tag)
+            .finish()
+    }
+}
+
+impl Default for S3StorageSettings {
+    fn default() -> Self {
+        Self {
+            bucket: "phnx-attachments".to_owned(),
+            region: "us-east-1".to_owned(),
+            endpoint: None,
+            force_path_style: false,
+            access_key_id: None,
+            secret_access_key: None,
+            multipart_threshold_bytes: 16 * 1024 * 1024,
+            multipart_part_size_bytes: 8 * 1024 * 1024,
+            max_attempts: 3,
+            initial_backoff_ms: 100,
+            presigned_url_expiry_secs: None,
+            lifecycle_tag: Some("phnx-retention=ephemeral".to_owned()),
+            archive_lifecycle_tag: Some("phnx-retention=archive".to_owned()),
+        }
+    }
+}
+
+/// Configuration of the local filesystem storage provider.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct FilesystemStorageSettings {
+    /// Directory in which attachments are stored.
+    pub path: PathBuf,
+    /// Maximum number of bytes stored on disk. Unlimited if unset.
+    pub max_bytes: Option<u64>,
+    /// Path to a file containing a raw 32 byte key. If set, attachments are
+    /// encrypted at rest.
+    pub encryption_key_path: Option<PathBuf>,
+}
+
+impl Default for FilesystemStorageSettings {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("attachments"),
+            max_bytes: None,
+            encryption_key_path: None,
+        }
+    }
+}
+
+/// Logging of the server.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoggingSettings {
+    /// Default level, e.g. `info`. The `RUST_LOG` environment variable
+    /// overrides the level and the module levels.
+    pub level: String,
+    /// Levels of individual modules, e.g. `phnxbackend::qs: debug`.
+    pub modules: BTreeMap<String, String>,
+    /// Whether logs are written to stdout.
+    pub stdout: bool,
+    pub stdout_format: LogFormat,
+    /// If set, logs are also written to files in the given directory.
+    pub file: Option<LogFileSettings>,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".to_owned(),
+            modules: BTreeMap::new(),
+            stdout: true,
+            stdout_format: LogFormat::Json,
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Bunyan-style JSON, one object per line
+    #[default]
+    Json,
+    /// Human-readable lines
+    Human,
+}
+
+/// Configuration of the rotated log files.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogFileSettings {
+    pub directory: PathBuf,
+    /// Name of the current log file. Rotated files get the time of their
+    /// rotation appended.
+    pub file_name: String,
+    pub format: LogFormat,
+    /// Start a new file in the given interval.
+    pub rotation: LogRotation,
+    /// Start a new file once the current one exceeds this size. Unlimited if
+    /// unset.
+    pub max_file_bytes: Option<u64>,
+    /// Number of rotated files that are kept. Older files are deleted. All
+    /// files are kept if unset.
+    pub max_files: Option<usize>,
+}
+
+impl Default for LogFileSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("logs"),
+            file_name: "phnxserver.log".to_owned(),
+            format: LogFormat::Human,
+            rotation: LogRotation::Daily,
+            max_file_bytes: Some(100 * 1024 * 1024),
+            max_files: Some(14),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+/// Configuration of the admin endpoints.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AdminSettings {
+    /// Bearer token required by the admin endpoints. The admin endpoints are
+    /// disabled if unset.
+    pub token: Option<String>,
+}
+
 impl DatabaseSettings {
     /// Add the TLS mode to the connection string if the CA certificate path is
     /// set.
@@ -86,3 +672,20 @@ impl DatabaseSettings {
         self.add_tls_mode(connection_string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s3_secret_is_redacted() {
+        let settings = S3StorageSettings {
+            access_key_id: Some("access-key-id".to_owned()),
+            secret_access_key: Some("secret-access-key".to_owned()),
+            ..Default::default()
+        };
+        let debug = format!("{settings:?}");
+        assert!(debug.contains("access-key-id"));
+        assert!(!debug.contains("secret-access-key"));
+    }
+}
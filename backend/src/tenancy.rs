@@ -0,0 +1,335 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Row-level multi-tenancy for serving several domains from one server.
+//!
+//! All domains (tenants) share the databases of the server. Each row carries
+//! the domain it belongs to in its `tenant` column. The services of a domain
+//! use a connection pool whose connections switch to the [`TENANT_ROLE`] and
+//! set the [`TENANT_SETTING`] to the domain. Row-level security policies
+//! then restrict every query of the services to the rows of their domain,
+//! without the queries having to filter by domain themselves.
+//!
+//! Primary keys remain unique across tenants. Natural keys like user names
+//! contain the domain and each AS only registers users of its own domain, so
+//! they can't collide. All other keys are random UUIDs or are scoped by the
+//! tenant column.
+//!
+//! Servers that only serve a single domain connect as the owner of the tables
+//! and are not affected by the policies.
+
+use phnxtypes::identifiers::Fqdn;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Executor, PgPool,
+};
+
+/// Role that the connections of a tenant switch to. It is subject to the
+/// row-level security policies.
+pub const TENANT_ROLE: &str = "phnx_tenant";
+/// Setting that holds the domain of a tenant connection.
+pub const TENANT_SETTING: &str = "phnx.tenant";
+
+/// Connect a pool whose connections only have access to the rows of the
+/// given domain.
+pub async fn connect_tenant_pool(
+    connect_options: PgConnectOptions,
+    domain: &Fqdn,
+) -> Result<PgPool, sqlx::Error> {
+    let domain = domain.to_string();
+    PgPoolOptions::new()
+        .after_connect(move |connection, _metadata| {
+            let domain = domain.clone();
+            Box::pin(async move {
+                connection
+                    .execute(format!("SET ROLE {TENANT_ROLE}").as_str())
+                    .await?;
+                sqlx::query("SELECT set_config($1, $2, false)")
+                    .bind(TENANT_SETTING)
+                    .bind(domain)
+                    .execute(connection)
+                    .await?;
+                Ok(())
+            })
+        })
+        .connect_with(connect_options)
+        .await
+}
+
+/// Assign all rows that were stored before the server served multiple
+/// domains to the given domain. Must be called with a pool of the owner of
+/// the tables.
+pub async fn claim_untenanted_rows(owner_pool: &PgPool, domain: &Fqdn) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT phnx_claim_untenanted_rows($1)")
+        .bind(domain.to_string())
+        .execute(owner_pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use opaque_ke::{rand::rngs::OsRng, ClientRegistration};
+    use phnxtypes::{
+        codec::PhnxCodec,
+        credentials::{AsCredential, ClientCredentialCsr, ClientCredentialPayload},
+        crypto::{
+            ear::Ciphertext,
+            kdf::keys::RatchetSecret,
+            opaque::{OpaqueCiphersuite, OpaqueRegistrationRequest},
+            signatures::keys::{QsClientSigningKey, QsUserSigningKey},
+            RatchetDecryptionKey,
+        },
+        errors::auth_service::{InitUserRegistrationError, UserConnectionPackagesError},
+        identifiers::{AsClientId, QualifiedUserName, SafeTryInto},
+        messages::{
+            client_as::{InitUserRegistrationParams, UserConnectionPackagesParams},
+            client_qs::{CreateUserRecordParams, DequeueMessagesParams},
+            FriendshipToken, QueueMessage,
+        },
+        DEFAULT_SIGNATURE_SCHEME,
+    };
+
+    use crate::{auth_service::AuthService, infra_service::InfraService, pg_listen, qs::Qs};
+
+    use super::*;
+
+    async fn tenant_pool(pool: &PgPool, domain: &str) -> PgPool {
+        let domain = Fqdn::try_from(domain).unwrap();
+        connect_tenant_pool(pool.connect_options().as_ref().clone(), &domain)
+            .await
+            .unwrap()
+    }
+
+    /// Tables without a tenant column or without row-level security would
+    /// leak data between tenants.
+    #[sqlx::test]
+    async fn every_table_is_tenant_scoped(pool: PgPool) {
+        let unscoped: Vec<String> = sqlx::query_scalar(
+            "SELECT c.relname::TEXT FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE n.nspname = 'public' AND c.relkind = 'r'
+                AND c.relname <> '_sqlx_migrations'
+                AND (
+                    NOT c.relrowsecurity
+                    OR NOT EXISTS (
+                        SELECT FROM pg_attribute a
+                        WHERE a.attrelid = c.oid AND a.attname = 'tenant'
+                    )
+                    OR NOT EXISTS (
+                        SELECT FROM pg_policy p
+                        WHERE p.polrelid = c.oid AND p.polname = 'tenant_isolation'
+                    )
+                )",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert!(
+            unscoped.is_empty(),
+            "Tables without tenant scope: {unscoped:?}"
+        );
+    }
+
+    #[sqlx::test]
+    async fn tenants_only_see_their_own_rows(pool: PgPool) {
+        let domain_a = Fqdn::try_from("a.example.com").unwrap();
+        let domain_b = Fqdn::try_from("b.example.com").unwrap();
+        let pool_a = tenant_pool(&pool, "a.example.com").await;
+        let pool_b = tenant_pool(&pool, "b.example.com").await;
+
+        // Both tenants initialize their own keys.
+        let qs_a = Qs::initialize(pool_a.clone(), domain_a.clone())
+            .await
+            .unwrap();
+        let qs_b = Qs::initialize(pool_b.clone(), domain_b.clone())
+            .await
+            .unwrap();
+        assert_ne!(
+            qs_a.federation_key_record().await.unwrap().to_string(),
+            qs_b.federation_key_record().await.unwrap().to_string()
+        );
+
+        // Channels of different tenants are independent.
+        assert_eq!(pg_listen::publish(&pool_a, "test", "a").await.unwrap(), 1);
+        assert_eq!(pg_listen::publish(&pool_a, "test", "a").await.unwrap(), 2);
+        assert_eq!(pg_listen::publish(&pool_b, "test", "b").await.unwrap(), 1);
+
+        // No table shows rows of the other tenant.
+        let tables: Vec<String> = sqlx::query_scalar(
+            "SELECT tablename::TEXT FROM pg_tables
+            WHERE schemaname = 'public' AND tablename <> '_sqlx_migrations'",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        for table in tables {
+            for (tenant_pool, domain) in [(&pool_a, &domain_a), (&pool_b, &domain_b)] {
+                let foreign_rows: i64 = sqlx::query_scalar(&format!(
+                    r#"SELECT COUNT(*) FROM "{table}" WHERE tenant <> $1"#
+                ))
+                .bind(domain.to_string())
+                .fetch_one(tenant_pool)
+                .await
+                .unwrap();
+                assert_eq!(foreign_rows, 0, "{table} leaks rows to {domain}");
+            }
+        }
+
+        // Tenants can't write rows for other tenants.
+        let result = sqlx::query(
+            "INSERT INTO qs_federation_key_pins (domain, fingerprint, first_seen, last_seen, tenant)
+            VALUES ('c.example.com', '\\x00', now(), now(), 'a.example.com')",
+        )
+        .execute(&pool_b)
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[sqlx::test]
+    async fn untenanted_rows_are_claimed(pool: PgPool) {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        // Published by a single-domain server
+        pg_listen::publish(&pool, "test", "before").await.unwrap();
+
+        let tenant_pool = tenant_pool(&pool, "example.com").await;
+        let count_events = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pg_listen_events")
+                .fetch_one(&tenant_pool)
+                .await
+                .unwrap()
+        };
+        assert_eq!(count_events().await, 0);
+
+        claim_untenanted_rows(&pool, &domain).await.unwrap();
+        assert_eq!(count_events().await, 1);
+        assert_eq!(
+            pg_listen::publish(&tenant_pool, "test", "after")
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    fn init_user_registration_params(user_name: &str) -> InitUserRegistrationParams {
+        let user_name: QualifiedUserName = SafeTryInto::try_into(user_name).unwrap();
+        let client_id = AsClientId::random(user_name).unwrap();
+        let (csr, _) = ClientCredentialCsr::new(client_id, DEFAULT_SIGNATURE_SCHEME).unwrap();
+        // The AS signs the payload with its own key regardless of the
+        // fingerprint.
+        let (as_credential, _) = AsCredential::new(
+            DEFAULT_SIGNATURE_SCHEME,
+            Fqdn::try_from("example.com").unwrap(),
+            None,
+        )
+        .unwrap();
+        let client_payload =
+            ClientCredentialPayload::new(csr, None, as_credential.fingerprint().clone());
+        let registration_start =
+            ClientRegistration::<OpaqueCiphersuite>::start(&mut OsRng, b"password").unwrap();
+        InitUserRegistrationParams {
+            client_payload,
+            opaque_registration_request: OpaqueRegistrationRequest {
+                client_message: registration_start.message,
+            },
+        }
+    }
+
+    /// The AS of a domain neither registers nor serves users of other
+    /// domains.
+    #[sqlx::test]
+    async fn auth_services_are_isolated(pool: PgPool) {
+        let domain_a = Fqdn::try_from("a.example.com").unwrap();
+        let domain_b = Fqdn::try_from("b.example.com").unwrap();
+        let pool_a = tenant_pool(&pool, "a.example.com").await;
+        let pool_b = tenant_pool(&pool, "b.example.com").await;
+        let as_a = AuthService::initialize(pool_a.clone(), domain_a)
+            .await
+            .unwrap();
+        let as_b = AuthService::initialize(pool_b, domain_b).await.unwrap();
+
+        // User names are unique across domains, so only the AS of the
+        // domain of the user name may register it.
+        as_a.as_init_user_registration(init_user_registration_params("alice@a.example.com"))
+            .await
+            .unwrap();
+        let result = as_b
+            .as_init_user_registration(init_user_registration_params("alice@a.example.com"))
+            .await;
+        assert!(matches!(
+            result,
+            Err(InitUserRegistrationError::ForeignDomain)
+        ));
+        as_b.as_init_user_registration(init_user_registration_params("bob@b.example.com"))
+            .await
+            .unwrap();
+
+        // Users of one domain can't be connected to via the AS of another.
+        sqlx::query("INSERT INTO as_user_records (user_name, password_file) VALUES ($1, $2)")
+            .bind("alice@a.example.com")
+            .bind(vec![0u8])
+            .execute(&pool_a)
+            .await
+            .unwrap();
+        let result = as_b
+            .as_user_connection_packages(UserConnectionPackagesParams {
+                user_name: SafeTryInto::try_into("alice@a.example.com").unwrap(),
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(UserConnectionPackagesError::UnknownUser)
+        ));
+    }
+
+    /// Queues of clients of one domain can only be dequeued from the QS of
+    /// that domain.
+    #[sqlx::test]
+    async fn queuing_services_are_isolated(pool: PgPool) {
+        let pool_a = tenant_pool(&pool, "a.example.com").await;
+        let pool_b = tenant_pool(&pool, "b.example.com").await;
+        let qs_a = Qs::initialize(pool_a.clone(), Fqdn::try_from("a.example.com").unwrap())
+            .await
+            .unwrap();
+        let qs_b = Qs::initialize(pool_b, Fqdn::try_from("b.example.com").unwrap())
+            .await
+            .unwrap();
+
+        let response = qs_a
+            .qs_create_user_record(CreateUserRecordParams {
+                user_record_auth_key: QsUserSigningKey::generate().unwrap().verifying_key(),
+                friendship_token: FriendshipToken::random().unwrap(),
+                client_record_auth_key: QsClientSigningKey::random().unwrap().verifying_key(),
+                queue_encryption_key: RatchetDecryptionKey::generate().unwrap().encryption_key(),
+                encrypted_push_token: None,
+                initial_ratchet_secret: RatchetSecret::random().unwrap(),
+            })
+            .await
+            .unwrap();
+        let message = QueueMessage {
+            sequence_number: 0,
+            ciphertext: Ciphertext::dummy(),
+            priority: Default::default(),
+        };
+        sqlx::query(
+            "INSERT INTO qs_queues (queue_id, sequence_number, message_bytes, priority)
+            VALUES ($1, 0, $2, 1)",
+        )
+        .bind(&response.client_id)
+        .bind(PhnxCodec::to_vec(&message).unwrap())
+        .execute(&pool_a)
+        .await
+        .unwrap();
+
+        let dequeue_params = || DequeueMessagesParams {
+            sender: response.client_id.clone(),
+            sequence_number_start: 0,
+            max_message_number: 10,
+        };
+        let result = qs_b.qs_dequeue_messages(dequeue_params()).await;
+        assert!(result.map_or(true, |response| response.messages.is_empty()));
+        let response = qs_a.qs_dequeue_messages(dequeue_params()).await.unwrap();
+        assert_eq!(response.messages, vec![message]);
+    }
+}
@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Runs the reference echo bridge.
+//!
+//! Usage: `echo-bridge DATA_DIR SERVER_URL RELAY_USER_NAME [MEMBER...]`.
+//! Opens a portal to the echo room and invites the given members, who must be
+//! contacts of the relay. Every message posted into the portal is answered by
+//! the puppet of the echo user. Stops on Ctrl-C.
+//!
+//! The password of the relay account is read from the
+//! [`PASSWORD_ENV_VAR`] environment variable or, if it isn't set, from the
+//! first line of stdin. It isn't accepted as argument, since arguments show
+//! up in process listings and the shell history.
+
+use std::{io::BufRead, path::PathBuf, process::ExitCode, time::Duration};
+
+use anyhow::{anyhow, Result};
+use phnxbridge::{echo::EchoProtocol, Bridge, BridgeConfig, ExternalProtocol, ExternalRoomId};
+use phnxcoreclient::SyncTrigger;
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+
+const USAGE: &str = "Usage: echo-bridge DATA_DIR SERVER_URL RELAY_USER_NAME [MEMBER...]
+
+The password of the relay account is read from ECHO_BRIDGE_PASSWORD or stdin.";
+
+const PASSWORD_ENV_VAR: &str = "ECHO_BRIDGE_PASSWORD";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut next_arg = || args.next().ok_or_else(|| anyhow!(USAGE));
+    let data_dir = PathBuf::from(next_arg()?);
+    let server_url = next_arg()?;
+    let relay_user_name: QualifiedUserName = SafeTryInto::try_into(next_arg()?)?;
+    let members = args
+        .map(SafeTryInto::try_into)
+        .collect::<Result<Vec<QualifiedUserName>, _>>()?;
+    let relay_password = read_password()?;
+
+    let config = BridgeConfig {
+        data_dir,
+        server_url,
+        relay_user_name,
+        relay_password,
+        sync_trigger: SyncTrigger::Listen {
+            poll_interval: Duration::from_secs(30),
+        },
+    };
+    let (protocol, mut events) = EchoProtocol::new();
+    let bridge = Bridge::start(config, protocol).await?;
+    let room = ExternalRoomId("echo".to_owned());
+    let conversation_id = bridge.open_portal(room, "Echo", &members).await?;
+    log::info!("Bridging conversation {}", conversation_id.as_uuid());
+
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    let external = async {
+        while let Some(message) = events.recv().await {
+            if let Err(e) = bridge.handle_external_message(message).await {
+                log::warn!("Failed to bridge {} message: {e}", bridge.protocol().name());
+            }
+        }
+    };
+    tokio::select! {
+        result = bridge.run(shutdown) => result,
+        _ = external => Ok(()),
+    }
+}
+
+/// Read the password of the relay account from the environment or, if it
+/// isn't set there, from the first line of stdin.
+fn read_password() -> Result<String> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV_VAR) {
+        return Ok(password);
+    }
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(anyhow!("No password given\n\n{USAGE}"));
+    }
+    Ok(password.to_owned())
+}
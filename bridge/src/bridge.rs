@@ -0,0 +1,283 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{collections::BTreeSet, future::Future, path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use phnxcoreclient::{
+    clients::CoreUser, ConversationId, ConversationMessage, HeadlessSyncClient, Message,
+    MimiContent, SyncHooks, SyncTrigger,
+};
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    portals::Portals,
+    puppets::{accept_offer, Puppet, Puppets},
+    DedupCache, ExternalMessage, ExternalMessageId, ExternalProtocol, ExternalRoomId,
+    OutboundMessage, Portal, PuppetNaming,
+};
+
+const RELAY_DIR: &str = "relay";
+const PUPPETS_DIR: &str = "puppets";
+const PORTALS_FILE: &str = "portals.json";
+
+/// Number of external message ids remembered to drop duplicates
+const DEDUP_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// Directory of the databases of the relay and the puppets and of the
+    /// list of portals
+    pub data_dir: PathBuf,
+    pub server_url: String,
+    /// User name of the relay, the service account of the bridge. Puppets
+    /// are registered on the same domain.
+    pub relay_user_name: QualifiedUserName,
+    /// Only used to register the relay on the first start
+    pub relay_password: String,
+    pub sync_trigger: SyncTrigger,
+}
+
+/// Forwards the messages the relay receives to [`Bridge::run`].
+struct RelayHooks {
+    messages: mpsc::UnboundedSender<ConversationMessage>,
+}
+
+impl SyncHooks for RelayHooks {
+    fn on_message(&self, message: &ConversationMessage) {
+        // The receiver is only dropped when the bridge stops.
+        let _ = self.messages.send(message.clone());
+    }
+}
+
+pub struct Bridge<P> {
+    protocol: P,
+    relay: HeadlessSyncClient<RelayHooks>,
+    sync_trigger: SyncTrigger,
+    puppets: Puppets,
+    portals: Mutex<Portals>,
+    seen: std::sync::Mutex<DedupCache<ExternalMessageId>>,
+    relay_messages: Mutex<Option<mpsc::UnboundedReceiver<ConversationMessage>>>,
+}
+
+impl<P: ExternalProtocol> Bridge<P> {
+    /// Load the relay from the data directory, or register it on the first
+    /// start.
+    pub async fn start(config: BridgeConfig, protocol: P) -> Result<Self> {
+        let BridgeConfig {
+            data_dir,
+            server_url,
+            relay_user_name,
+            relay_password,
+            sync_trigger,
+        } = config;
+        std::fs::create_dir_all(&data_dir)?;
+
+        let (messages, relay_messages) = mpsc::unbounded_channel();
+        let relay_dir = data_dir.join(RELAY_DIR);
+        let hooks = RelayHooks {
+            messages: messages.clone(),
+        };
+        let relay = match HeadlessSyncClient::load(&relay_dir, hooks).await? {
+            Some(relay) => relay,
+            None => {
+                log::info!("Registering relay {relay_user_name}");
+                HeadlessSyncClient::register(
+                    relay_dir,
+                    relay_user_name.clone(),
+                    &relay_password,
+                    &server_url,
+                    RelayHooks { messages },
+                )
+                .await?
+            }
+        };
+        if relay.user().user_name() != relay_user_name {
+            return Err(anyhow!(
+                "The data directory belongs to the relay {}",
+                relay.user().user_name()
+            ));
+        }
+
+        let naming = PuppetNaming::new(protocol.name(), relay_user_name.domain());
+        let puppets = Puppets::new(naming, data_dir.join(PUPPETS_DIR), server_url)?;
+        let portals = Portals::load(data_dir.join(PORTALS_FILE))?;
+
+        Ok(Self {
+            protocol,
+            relay,
+            sync_trigger,
+            puppets,
+            portals: Mutex::new(portals),
+            seen: std::sync::Mutex::new(DedupCache::new(DEDUP_CAPACITY)),
+            relay_messages: Mutex::new(Some(relay_messages)),
+        })
+    }
+
+    /// The service account of the bridge, e.g. to add the contacts that are
+    /// invited into portals.
+    pub fn relay(&self) -> &CoreUser {
+        self.relay.user()
+    }
+
+    pub fn protocol(&self) -> &P {
+        &self.protocol
+    }
+
+    pub async fn portals(&self) -> Vec<Portal> {
+        self.portals.lock().await.all().to_vec()
+    }
+
+    /// Open a portal for the given room, inviting the given users, who must
+    /// be contacts of the relay. Returns the existing portal's chat if the
+    /// room is already bridged.
+    pub async fn open_portal(
+        &self,
+        room: ExternalRoomId,
+        title: &str,
+        members: &[QualifiedUserName],
+    ) -> Result<ConversationId> {
+        let mut portals = self.portals.lock().await;
+        if let Some(portal) = portals.by_room(&room) {
+            return Ok(portal.conversation_id);
+        }
+        let conversation_id = self.relay().create_conversation(title, None).await?;
+        if !members.is_empty() {
+            self.relay().invite_users(conversation_id, members).await?;
+        }
+        portals.insert(Portal {
+            room,
+            conversation_id,
+            puppets: BTreeSet::new(),
+        })?;
+        Ok(conversation_id)
+    }
+
+    /// Post a message of the external network into the room's portal, via the
+    /// puppet of its sender. Messages that were handled before, or that the
+    /// bridge delivered to the external network itself, are dropped.
+    pub async fn handle_external_message(&self, message: ExternalMessage) -> Result<()> {
+        if !self.seen_lock().insert(message.id.clone()) {
+            log::debug!("Dropping duplicate message {}", message.id);
+            return Ok(());
+        }
+        let conversation_id = self
+            .portals
+            .lock()
+            .await
+            .by_room(&message.room)
+            .map(|portal| portal.conversation_id)
+            .ok_or_else(|| anyhow!("No portal for room {}", message.room))?;
+
+        let puppet = self.puppets.get(&message.sender).await?;
+        self.ensure_joined(&message.room, conversation_id, &puppet)
+            .await?;
+        // Catch up with the group, such that the message is sent in the
+        // current epoch.
+        puppet.sync().await?;
+        let content =
+            MimiContent::simple_markdown_message(puppet.user().user_name().domain(), message.text);
+        puppet.user().send_message(conversation_id, content).await?;
+        Ok(())
+    }
+
+    /// Invite the puppet into the portal, adding it as a contact of the relay
+    /// first if needed.
+    async fn ensure_joined(
+        &self,
+        room: &ExternalRoomId,
+        conversation_id: ConversationId,
+        puppet: &Arc<Puppet>,
+    ) -> Result<()> {
+        let user_name = puppet.user().user_name();
+        // Held until the puppet joined, such that it is only invited once
+        let mut portals = self.portals.lock().await;
+        let joined = portals
+            .by_room(room)
+            .is_some_and(|portal| portal.puppets.contains(&user_name.to_string()));
+        if joined {
+            return Ok(());
+        }
+
+        if self.relay().contact(&user_name).await.is_none() {
+            let connection_conversation = self.relay().add_contact(user_name.clone()).await?;
+            // The puppet joins the connection group, after which the relay
+            // learns the puppet's keys.
+            puppet.sync().await?;
+            accept_offer(puppet, connection_conversation).await?;
+            self.relay.sync().await?;
+        }
+        self.relay()
+            .invite_users(conversation_id, &[user_name.clone()])
+            .await?;
+        puppet.sync().await?;
+        portals.add_puppet(room, &user_name)
+    }
+
+    /// Sync the relay and deliver the messages posted into portals to the
+    /// external network, until `shutdown` completes.
+    pub async fn run(&self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let mut relay_messages = self
+            .relay_messages
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| anyhow!("The bridge is already running"))?;
+        let relay = self.relay.run(self.sync_trigger, std::future::pending());
+        tokio::pin!(shutdown, relay);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                _ = &mut relay => return Ok(()),
+                message = relay_messages.recv() => match message {
+                    Some(message) => {
+                        if let Err(error) = self.deliver(message).await {
+                            log::warn!("Failed to deliver message to {}: {error}", self.protocol.name());
+                        }
+                    }
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    async fn deliver(&self, message: ConversationMessage) -> Result<()> {
+        let Message::Content(content) = message.message() else {
+            return Ok(());
+        };
+        let Some(room) = self
+            .portals
+            .lock()
+            .await
+            .by_conversation(message.conversation_id())
+            .map(|portal| portal.room.clone())
+        else {
+            return Ok(());
+        };
+        let sender: QualifiedUserName = SafeTryInto::try_into(content.sender())?;
+        // Messages of puppets came from the external network.
+        if self.puppets.is_puppet(&sender).await {
+            return Ok(());
+        }
+
+        let id = self
+            .protocol
+            .send(OutboundMessage {
+                room,
+                sender,
+                text: content.content().string_rendering(),
+            })
+            .await?;
+        // The external network may report the message back.
+        self.seen_lock().insert(id);
+        Ok(())
+    }
+
+    fn seen_lock(&self) -> std::sync::MutexGuard<'_, DedupCache<ExternalMessageId>> {
+        self.seen
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::Hash,
+};
+
+/// Remembers the most recent keys, e.g. the ids of bridged messages, to drop
+/// messages that are reported more than once.
+///
+/// Once the capacity is reached, the oldest key is forgotten.
+#[derive(Debug)]
+pub struct DedupCache<K> {
+    capacity: usize,
+    order: VecDeque<K>,
+    keys: HashSet<K>,
+}
+
+impl<K: Clone + Eq + Hash> DedupCache<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            keys: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Returns `true` if the key wasn't seen before.
+    pub fn insert(&mut self, key: K) -> bool {
+        if self.keys.contains(&key) {
+            return false;
+        }
+        if self.capacity == 0 {
+            return true;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_oldest_keys() {
+        let mut cache = DedupCache::new(2);
+        assert!(cache.insert(1));
+        assert!(!cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(cache.insert(3));
+        assert!(!cache.contains(&1));
+        assert!(cache.contains(&2));
+        assert!(cache.contains(&3));
+        assert!(cache.insert(1));
+    }
+}
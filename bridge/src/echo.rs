@@ -0,0 +1,101 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Reference bridge whose external network echoes every message.
+//!
+//! Like many real networks, the echo network reports each delivered message
+//! back to the bridge, which drops it as a duplicate. The echo user then
+//! replies with the message, which its puppet posts into the portal.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use crate::{
+    ExternalMessage, ExternalMessageId, ExternalProtocol, ExternalUserId, OutboundMessage,
+};
+
+/// The user of the echo network that replies to every message
+pub const ECHO_USER: &str = "echo";
+
+pub struct EchoProtocol {
+    next_id: AtomicU64,
+    events: mpsc::UnboundedSender<ExternalMessage>,
+}
+
+impl EchoProtocol {
+    /// Returns the protocol and the messages of the echo network, which have
+    /// to be passed to [`Bridge::handle_external_message`](crate::Bridge::handle_external_message).
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<ExternalMessage>) {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let protocol = Self {
+            next_id: AtomicU64::new(0),
+            events,
+        };
+        (protocol, receiver)
+    }
+}
+
+impl ExternalProtocol for EchoProtocol {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    async fn send(&self, message: OutboundMessage) -> Result<ExternalMessageId> {
+        let n = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let id = ExternalMessageId(format!("echo-{n}"));
+        let sender = ExternalUserId(message.sender.to_string());
+        let delivered = ExternalMessage {
+            id: id.clone(),
+            room: message.room.clone(),
+            sender,
+            text: message.text.clone(),
+        };
+        let reply = ExternalMessage {
+            id: ExternalMessageId(format!("echo-{n}-reply")),
+            room: message.room,
+            sender: ExternalUserId(ECHO_USER.to_owned()),
+            text: format!("{}: {}", message.sender, message.text),
+        };
+        for event in [delivered, reply] {
+            self.events.send(event)?;
+        }
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use crate::{DedupCache, ExternalRoomId};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn echo_is_dropped_and_reply_is_bridged() {
+        let (protocol, mut events) = EchoProtocol::new();
+        let room = ExternalRoomId("lobby".to_owned());
+        let id = protocol
+            .send(OutboundMessage {
+                room: room.clone(),
+                sender: SafeTryInto::try_into("alice@example.com").unwrap(),
+                text: "Hello".to_owned(),
+            })
+            .await
+            .unwrap();
+
+        // The bridge remembers the ids of the messages it delivered.
+        let mut seen = DedupCache::new(16);
+        seen.insert(id);
+        let echoed = events.recv().await.unwrap();
+        assert!(!seen.insert(echoed.id));
+        let reply = events.recv().await.unwrap();
+        assert!(seen.insert(reply.id));
+        assert_eq!(reply.room, room);
+        assert_eq!(reply.sender, ExternalUserId(ECHO_USER.to_owned()));
+        assert_eq!(reply.text, "alice@example.com: Hello");
+    }
+}
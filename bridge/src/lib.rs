@@ -0,0 +1,41 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Framework for bridging external protocols, e.g. IRC or XMPP gateway
+//! accounts, into chats.
+//!
+//! A bridge connects rooms of an external network with chats, called portals.
+//! It runs two kinds of headless accounts:
+//!
+//! - The relay is the service account of the bridge. It creates the portals,
+//!   invites the users of the deployment and receives their messages, which
+//!   the bridge delivers to the external network.
+//! - Puppets represent the users of the external network. Each of them is a
+//!   regular account, registered by the bridge on first use, that posts the
+//!   messages of its external user into the portals.
+//!
+//! Messages are bridged exactly once in each direction: messages posted by
+//! puppets are not delivered back to the external network, and messages the
+//! external network reports again, e.g. because it echoes the messages the
+//! bridge delivered, are dropped, see [`DedupCache`].
+//!
+//! Integrations implement [`ExternalProtocol`] and pass the messages of the
+//! external network to [`Bridge::handle_external_message`]. The [`echo`]
+//! module contains a reference implementation.
+
+mod bridge;
+mod dedup;
+pub mod echo;
+mod portals;
+mod protocol;
+mod puppets;
+
+pub use bridge::{Bridge, BridgeConfig};
+pub use dedup::DedupCache;
+pub use portals::Portal;
+pub use protocol::{
+    ExternalMessage, ExternalMessageId, ExternalProtocol, ExternalRoomId, ExternalUserId,
+    OutboundMessage,
+};
+pub use puppets::PuppetNaming;
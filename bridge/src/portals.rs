@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use phnxcoreclient::ConversationId;
+use phnxtypes::identifiers::QualifiedUserName;
+use serde::{Deserialize, Serialize};
+
+use crate::ExternalRoomId;
+
+/// A chat bridged to a room of the external network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Portal {
+    pub room: ExternalRoomId,
+    pub conversation_id: ConversationId,
+    /// The puppets that were invited into the chat
+    pub puppets: BTreeSet<String>,
+}
+
+/// The portals of a bridge, stored as a JSON file.
+pub(crate) struct Portals {
+    path: PathBuf,
+    portals: Vec<Portal>,
+}
+
+impl Portals {
+    /// Load the portals from the given file. A missing file means that no
+    /// portals were opened yet.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let portals = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, portals })
+    }
+
+    /// Replaces the file atomically, such that a crash doesn't lose the
+    /// portals.
+    fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.portals)?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub(crate) fn all(&self) -> &[Portal] {
+        &self.portals
+    }
+
+    pub(crate) fn by_room(&self, room: &ExternalRoomId) -> Option<&Portal> {
+        self.portals.iter().find(|portal| &portal.room == room)
+    }
+
+    pub(crate) fn by_conversation(&self, conversation_id: ConversationId) -> Option<&Portal> {
+        self.portals
+            .iter()
+            .find(|portal| portal.conversation_id == conversation_id)
+    }
+
+    pub(crate) fn insert(&mut self, portal: Portal) -> Result<()> {
+        self.portals.push(portal);
+        self.save()
+    }
+
+    pub(crate) fn add_puppet(
+        &mut self,
+        room: &ExternalRoomId,
+        puppet: &QualifiedUserName,
+    ) -> Result<()> {
+        if let Some(portal) = self.portals.iter_mut().find(|portal| &portal.room == room) {
+            portal.puppets.insert(puppet.to_string());
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn portals_are_persisted() {
+        let dir = std::env::temp_dir().join(format!("phnxbridge-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("portals.json");
+
+        let mut portals = Portals::load(&path).unwrap();
+        assert!(portals.all().is_empty());
+        let room = ExternalRoomId("#general".to_owned());
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        portals
+            .insert(Portal {
+                room: room.clone(),
+                conversation_id,
+                puppets: BTreeSet::new(),
+            })
+            .unwrap();
+        let puppet: QualifiedUserName = SafeTryInto::try_into("irc_bob@example.com").unwrap();
+        portals.add_puppet(&room, &puppet).unwrap();
+
+        let portals = Portals::load(&path).unwrap();
+        let portal = portals.by_conversation(conversation_id).unwrap();
+        assert_eq!(portal.room, room);
+        assert!(portal.puppets.contains("irc_bob@example.com"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{fmt, future::Future};
+
+use anyhow::Result;
+use phnxtypes::identifiers::QualifiedUserName;
+use serde::{Deserialize, Serialize};
+
+/// Identifier of a user on the external network
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ExternalUserId(pub String);
+
+/// Identifier of a room on the external network
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ExternalRoomId(pub String);
+
+/// Identifier the external network assigned to a message
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ExternalMessageId(pub String);
+
+impl fmt::Display for ExternalUserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for ExternalRoomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for ExternalMessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A message of the external network, to be posted into a portal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalMessage {
+    pub id: ExternalMessageId,
+    pub room: ExternalRoomId,
+    pub sender: ExternalUserId,
+    pub text: String,
+}
+
+/// A message posted into a portal, to be delivered to the external network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundMessage {
+    pub room: ExternalRoomId,
+    pub sender: QualifiedUserName,
+    pub text: String,
+}
+
+/// The external side of a bridge.
+pub trait ExternalProtocol: Send + Sync {
+    /// Short name of the protocol, e.g. `irc`. Prefixes the user names of
+    /// the puppets of the protocol's users.
+    fn name(&self) -> &str;
+
+    /// Deliver a message to the external network. Returns the id the external
+    /// network assigned to it, such that the bridge recognizes the message if
+    /// the network reports it back.
+    fn send(
+        &self,
+        message: OutboundMessage,
+    ) -> impl Future<Output = Result<ExternalMessageId>> + Send;
+}
@@ -0,0 +1,180 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Accounts that represent the users of the external network.
+//!
+//! Each puppet keeps its databases in a directory named after its external
+//! user. The puppets registered in earlier runs are found by listing these
+//! directories, and are loaded once they are needed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use phnxcoreclient::{ConversationId, HeadlessSyncClient, MessageRequestState, SyncHooks};
+use phnxtypes::identifiers::{Fqdn, QualifiedUserName, SafeTryInto};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::ExternalUserId;
+
+/// Derives the user names of puppets from the ids of their external users.
+#[derive(Debug, Clone)]
+pub struct PuppetNaming {
+    protocol: String,
+    domain: Fqdn,
+}
+
+impl PuppetNaming {
+    pub fn new(protocol: &str, domain: Fqdn) -> Self {
+        Self {
+            protocol: escape(protocol),
+            domain,
+        }
+    }
+
+    /// `<protocol>_<external id>@<domain>`, with all characters of the
+    /// external id except lower case letters, digits, `-` and `_` escaped as
+    /// `=` followed by their hex code.
+    pub fn user_name(&self, external_id: &ExternalUserId) -> Result<QualifiedUserName> {
+        self.user_name_of_escaped(&escape(&external_id.0))
+    }
+
+    fn user_name_of_escaped(&self, escaped_id: &str) -> Result<QualifiedUserName> {
+        let user_name = format!("{}_{escaped_id}@{}", self.protocol, self.domain);
+        Ok(SafeTryInto::try_into(user_name)?)
+    }
+}
+
+fn escape(id: &str) -> String {
+    id.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => char::from(byte).to_string(),
+            _ => format!("={byte:02x}"),
+        })
+        .collect()
+}
+
+/// Puppets don't react to what they receive. They only sync to join portals
+/// and to keep up with the portals' groups.
+pub(crate) struct PuppetHooks;
+
+impl SyncHooks for PuppetHooks {}
+
+pub(crate) type Puppet = HeadlessSyncClient<PuppetHooks>;
+
+/// Accept the relay's connection offer for the given connection conversation
+/// if the puppet's screening held it as a message request, e.g. because the
+/// relay registered recently.
+pub(crate) async fn accept_offer(puppet: &Puppet, conversation_id: ConversationId) -> Result<()> {
+    let user = puppet.user();
+    let pending = user
+        .message_request(conversation_id)
+        .await?
+        .is_some_and(|request| request.state() == MessageRequestState::Pending);
+    if pending {
+        user.accept_message_request(conversation_id).await?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct PuppetState {
+    loaded: HashMap<ExternalUserId, Arc<Puppet>>,
+    user_names: HashSet<QualifiedUserName>,
+}
+
+pub(crate) struct Puppets {
+    naming: PuppetNaming,
+    dir: PathBuf,
+    server_url: String,
+    state: Mutex<PuppetState>,
+}
+
+impl Puppets {
+    pub(crate) fn new(naming: PuppetNaming, dir: PathBuf, server_url: String) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut state = PuppetState::default();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(escaped_id) = entry.file_name().to_str() {
+                state
+                    .user_names
+                    .insert(naming.user_name_of_escaped(escaped_id)?);
+            }
+        }
+        Ok(Self {
+            naming,
+            dir,
+            server_url,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Returns `true` if the user is one of the puppets of this bridge.
+    pub(crate) async fn is_puppet(&self, user_name: &QualifiedUserName) -> bool {
+        self.state.lock().await.user_names.contains(user_name)
+    }
+
+    /// The puppet of the given external user. It is registered if it doesn't
+    /// exist yet.
+    pub(crate) async fn get(&self, external_id: &ExternalUserId) -> Result<Arc<Puppet>> {
+        let mut state = self.state.lock().await;
+        if let Some(puppet) = state.loaded.get(external_id) {
+            return Ok(puppet.clone());
+        }
+
+        let user_name = self.naming.user_name(external_id)?;
+        let dir = self.dir.join(escape(&external_id.0));
+        let puppet = match Puppet::load(&dir, PuppetHooks).await? {
+            Some(puppet) => puppet,
+            None => {
+                log::info!("Registering puppet {user_name} for {external_id}");
+                // The password isn't needed again, since the puppet keeps its
+                // keys in its directory.
+                let password = Uuid::new_v4().to_string();
+                Puppet::register(
+                    dir,
+                    user_name.clone(),
+                    &password,
+                    &self.server_url,
+                    PuppetHooks,
+                )
+                .await?
+            }
+        };
+        let puppet = Arc::new(puppet);
+        state.user_names.insert(user_name);
+        state.loaded.insert(external_id.clone(), puppet.clone());
+        Ok(puppet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn puppet_user_names() {
+        let naming = PuppetNaming::new("irc", Fqdn::try_from("example.com").unwrap());
+        let user_name = naming
+            .user_name(&ExternalUserId("Bob.Smith@libera".to_owned()))
+            .unwrap();
+        assert_eq!(
+            user_name.to_string(),
+            "irc_=42ob=2e=53mith=40libera@example.com"
+        );
+        // Escaping is injective
+        let other = naming
+            .user_name(&ExternalUserId("=42ob.Smith@libera".to_owned()))
+            .unwrap();
+        assert_ne!(user_name, other);
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::time::Duration;
+
+use phnxbridge::{
+    echo::EchoProtocol, Bridge, BridgeConfig, ExternalMessage, ExternalMessageId, ExternalRoomId,
+    ExternalUserId, PuppetNaming,
+};
+use phnxcoreclient::{clients::CoreUser, ConversationId, Message, SyncTrigger};
+use phnxserver_test_harness::utils::setup::TestBackend;
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+use uuid::Uuid;
+
+const ALICE: &str = "alice@example.com";
+const RELAY: &str = "relay@example.com";
+
+/// Process the connection offer of the relay, accepting it if the user's
+/// screening held it as a message request.
+async fn accept_relay_offer(user: &CoreUser, conversation_id: ConversationId) {
+    let as_messages = user.as_fetch_messages().await.unwrap();
+    user.fully_process_as_messages(as_messages).await.unwrap();
+    if user
+        .message_request(conversation_id)
+        .await
+        .unwrap()
+        .is_some()
+    {
+        user.accept_message_request(conversation_id).await.unwrap();
+    }
+}
+
+#[actix_rt::test]
+async fn external_messages_are_bridged_into_portals() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    let alice = setup.get_user(ALICE).user();
+    let alice_name: QualifiedUserName = SafeTryInto::try_into(ALICE).unwrap();
+
+    let data_dir = std::env::temp_dir().join(format!("phnxbridge-{}", Uuid::new_v4()));
+    let (protocol, _events) = EchoProtocol::new();
+    let bridge = Bridge::start(
+        BridgeConfig {
+            data_dir: data_dir.clone(),
+            server_url: format!("http://{}", setup.url().unwrap()),
+            relay_user_name: SafeTryInto::try_into(RELAY).unwrap(),
+            relay_password: "password".to_owned(),
+            sync_trigger: SyncTrigger::Interval(Duration::from_secs(1)),
+        },
+        protocol,
+    )
+    .await
+    .unwrap();
+
+    // The relay is new, so Alice accepts its offer herself.
+    let connection_conversation = bridge
+        .relay()
+        .add_contact(alice_name.clone())
+        .await
+        .unwrap();
+    accept_relay_offer(alice, connection_conversation).await;
+    bridge
+        .relay()
+        .qs_fetch_and_process_messages()
+        .await
+        .unwrap();
+
+    let room = ExternalRoomId("#lobby".to_owned());
+    let portal = bridge
+        .open_portal(room.clone(), "Lobby", &[alice_name])
+        .await
+        .unwrap();
+    alice.qs_fetch_and_process_messages().await.unwrap();
+
+    // The puppet of Bob screens the relay like any other user. The bridge
+    // must get it into the portal regardless.
+    let bob = ExternalUserId("bob".to_owned());
+    bridge
+        .handle_external_message(ExternalMessage {
+            id: ExternalMessageId("1".to_owned()),
+            room: room.clone(),
+            sender: bob.clone(),
+            text: "Hello from the other side".to_owned(),
+        })
+        .await
+        .unwrap();
+
+    let puppet_name = PuppetNaming::new("echo", SafeTryInto::try_into("example.com").unwrap())
+        .user_name(&bob)
+        .unwrap();
+    let portals = bridge.portals().await;
+    assert_eq!(portals.len(), 1);
+    assert!(portals[0].puppets.contains(&puppet_name.to_string()));
+
+    let processed = alice.qs_fetch_and_process_messages().await.unwrap();
+    let bridged = processed
+        .new_messages
+        .iter()
+        .filter(|message| message.conversation_id() == portal)
+        .find_map(|message| match message.message() {
+            Message::Content(content_message) => Some(content_message),
+            _ => None,
+        })
+        .expect("Alice should have received the bridged message");
+    assert_eq!(bridged.sender(), puppet_name.to_string());
+    assert_eq!(
+        bridged.content().string_rendering(),
+        "Hello from the other side"
+    );
+
+    // The message is bridged only once.
+    bridge
+        .handle_external_message(ExternalMessage {
+            id: ExternalMessageId("1".to_owned()),
+            room,
+            sender: bob,
+            text: "Hello from the other side".to_owned(),
+        })
+        .await
+        .unwrap();
+    let processed = alice.qs_fetch_and_process_messages().await.unwrap();
+    assert!(processed
+        .new_messages
+        .iter()
+        .all(|message| message.conversation_id() != portal));
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
@@ -0,0 +1,107 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Benchmarks of the MLS group operations performed by the client.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use openmls::prelude::*;
+use openmls_basic_credential::SignatureKeyPair;
+use openmls_rust_crypto::OpenMlsRustCrypto;
+use openmls_traits::OpenMlsProvider;
+use phnxcoreclient::bench_utils::DEFAULT_CIPHERSUITE;
+
+const GROUP_SIZES: [usize; 3] = [10, 100, 1000];
+
+struct Member {
+    signer: SignatureKeyPair,
+    credential_with_key: CredentialWithKey,
+}
+
+impl Member {
+    fn new(identity: &str) -> Self {
+        let signer = SignatureKeyPair::new(DEFAULT_CIPHERSUITE.signature_algorithm()).unwrap();
+        let credential_with_key = CredentialWithKey {
+            credential: BasicCredential::new(identity.as_bytes().to_vec()).into(),
+            signature_key: signer.public().into(),
+        };
+        Self {
+            signer,
+            credential_with_key,
+        }
+    }
+
+    fn key_package(&self, provider: &OpenMlsRustCrypto) -> KeyPackage {
+        KeyPackage::builder()
+            .build(
+                DEFAULT_CIPHERSUITE,
+                provider,
+                &self.signer,
+                self.credential_with_key.clone(),
+            )
+            .unwrap()
+            .key_package()
+            .clone()
+    }
+
+    fn create_group(&self, provider: &OpenMlsRustCrypto) -> MlsGroup {
+        MlsGroup::builder()
+            .ciphersuite(DEFAULT_CIPHERSUITE)
+            .use_ratchet_tree_extension(true)
+            .build(provider, &self.signer, self.credential_with_key.clone())
+            .unwrap()
+    }
+}
+
+fn key_packages(provider: &OpenMlsRustCrypto, count: usize) -> Vec<KeyPackage> {
+    (0..count)
+        .map(|i| Member::new(&format!("member-{i}")).key_package(provider))
+        .collect()
+}
+
+fn group_creation(c: &mut Criterion) {
+    let provider = OpenMlsRustCrypto::default();
+    let creator = Member::new("creator");
+    c.bench_function("group_creation", |b| {
+        b.iter(|| creator.create_group(&provider))
+    });
+}
+
+/// Commit that invites a single new member to a group of the given size.
+fn invite_commit(c: &mut Criterion) {
+    let mut bench_group = c.benchmark_group("invite_commit");
+    bench_group.sample_size(10);
+    for group_size in GROUP_SIZES {
+        let provider = OpenMlsRustCrypto::default();
+        let creator = Member::new("creator");
+        let mut group = creator.create_group(&provider);
+        group
+            .add_members(
+                &provider,
+                &creator.signer,
+                &key_packages(&provider, group_size - 1),
+            )
+            .unwrap();
+        group.merge_pending_commit(&provider).unwrap();
+        let invitee = Member::new("invitee").key_package(&provider);
+
+        bench_group.bench_with_input(
+            BenchmarkId::from_parameter(group_size),
+            &invitee,
+            |b, invitee| {
+                b.iter(|| {
+                    let messages = group
+                        .add_members(&provider, &creator.signer, &[invitee.clone()])
+                        .unwrap();
+                    // Discard the commit, so that the group keeps its size.
+                    group.clear_pending_commit(provider.storage()).unwrap();
+                    messages
+                })
+            },
+        );
+    }
+    bench_group.finish();
+}
+
+criterion_group!(benches, group_creation, invite_commit);
+criterion_main!(benches);
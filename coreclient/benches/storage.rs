@@ -0,0 +1,25 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Benchmarks of the client database.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use phnxcoreclient::bench_utils::MessageStore;
+
+const BATCH_SIZES: [usize; 2] = [1, 100];
+
+fn message_insert(c: &mut Criterion) {
+    let mut bench_group = c.benchmark_group("message_insert");
+    for batch_size in BATCH_SIZES {
+        let mut store = MessageStore::new().unwrap();
+        bench_group.throughput(Throughput::Elements(batch_size as u64));
+        bench_group.bench_function(BenchmarkId::from_parameter(batch_size), |b| {
+            b.iter(|| store.insert_messages(batch_size).unwrap())
+        });
+    }
+    bench_group.finish();
+}
+
+criterion_group!(benches, message_insert);
+criterion_main!(benches);
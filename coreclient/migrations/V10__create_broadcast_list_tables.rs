@@ -0,0 +1,19 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{
+    broadcast_lists::{
+        persistence::BROADCAST_LIST_RECIPIENTS_TABLE, BroadcastDelivery, BroadcastList,
+    },
+    utils::persistence::Storable,
+};
+
+pub fn migration() -> String {
+    [
+        <BroadcastList as Storable>::CREATE_TABLE_STATEMENT,
+        BROADCAST_LIST_RECIPIENTS_TABLE,
+        <BroadcastDelivery as Storable>::CREATE_TABLE_STATEMENT,
+    ]
+    .join("\n")
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::conversations::persistence::CONVERSATION_SENSITIVE_COLUMN;
+
+pub fn migration() -> String {
+    CONVERSATION_SENSITIVE_COLUMN.to_owned()
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::groups::persistence::GROUP_SECURITY_COLUMNS;
+
+pub fn migration() -> String {
+    GROUP_SECURITY_COLUMNS.to_owned()
+}
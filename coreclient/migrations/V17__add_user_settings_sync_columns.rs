@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::user_settings::persistence::USER_SETTINGS_SYNC_COLUMNS;
+
+pub fn migration() -> String {
+    USER_SETTINGS_SYNC_COLUMNS.to_owned()
+}
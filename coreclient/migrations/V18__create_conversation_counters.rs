@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::counters::persistence::{
+    backfill_conversation_counters, conversation_counters_triggers, CONVERSATION_COUNTERS_TABLE,
+};
+
+pub fn migration() -> String {
+    [
+        CONVERSATION_COUNTERS_TABLE.to_owned(),
+        conversation_counters_triggers(),
+        backfill_conversation_counters(),
+    ]
+    .join("\n")
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::chat_metadata::persistence::CHAT_METADATA_TABLE;
+
+pub fn migration() -> String {
+    CHAT_METADATA_TABLE.to_owned()
+}
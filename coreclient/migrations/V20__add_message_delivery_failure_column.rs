@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::conversations::messages::persistence::CONVERSATION_MESSAGE_DELIVERY_FAILURE_COLUMN;
+
+pub fn migration() -> String {
+    CONVERSATION_MESSAGE_DELIVERY_FAILURE_COLUMN.to_owned()
+}
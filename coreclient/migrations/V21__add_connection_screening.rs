@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{
+    user_handles::{BlockedUser, MESSAGE_REQUEST_SCREENING_COLUMNS},
+    utils::persistence::Storable,
+};
+
+pub fn migration() -> String {
+    [
+        MESSAGE_REQUEST_SCREENING_COLUMNS,
+        <BlockedUser as Storable>::CREATE_TABLE_STATEMENT,
+    ]
+    .join("\n")
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+pub fn migration() -> String {
+    // Stored system messages are converted to the language-neutral format in
+    // `post_process`, since the conversion needs to decode them.
+    "-- Legacy system messages are converted after the schema migrations.".to_owned()
+}
@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{
+    contacts::persistence::CONTACT_USER_PROFILE_EAR_KEY_COLUMN,
+    user_profiles::USER_PROFILE_VERSION_COLUMNS,
+};
+
+pub fn migration() -> String {
+    [
+        CONTACT_USER_PROFILE_EAR_KEY_COLUMN,
+        USER_PROFILE_VERSION_COLUMNS,
+    ]
+    .join("\n")
+}
@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{
+    archive::persistence::{MessageArchive, MESSAGE_ARCHIVE_ENTRIES_TABLE},
+    utils::persistence::Storable,
+};
+
+pub fn migration() -> String {
+    [
+        <MessageArchive as Storable>::CREATE_TABLE_STATEMENT,
+        MESSAGE_ARCHIVE_ENTRIES_TABLE,
+    ]
+    .join("\n")
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::key_stores::signing_key::PENDING_CLIENT_SIGNING_KEY_TABLE;
+
+pub fn migration() -> String {
+    PENDING_CLIENT_SIGNING_KEY_TABLE.to_owned()
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::contacts::persistence::CONTACT_USER_PROFILE_KEY_SHARED_COLUMN;
+
+pub fn migration() -> String {
+    CONTACT_USER_PROFILE_KEY_SHARED_COLUMN.to_owned()
+}
@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{
+    attachments::persistence::{AttachmentReference, ATTACHMENT_BLOBS_TABLE},
+    utils::persistence::Storable,
+};
+
+pub fn migration() -> String {
+    [
+        ATTACHMENT_BLOBS_TABLE,
+        <AttachmentReference as Storable>::CREATE_TABLE_STATEMENT,
+    ]
+    .join("\n")
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::attachments::persistence::ATTACHMENT_THUMBNAIL_COLUMN;
+
+pub fn migration() -> String {
+    ATTACHMENT_THUMBNAIL_COLUMN.to_owned()
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::groups::client_auth_info::persistence::PROPOSED_CLIENT_UPDATES_TABLE;
+
+pub fn migration() -> String {
+    PROPOSED_CLIENT_UPDATES_TABLE.to_owned()
+}
@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{
+    attachments::persistence::ATTACHMENT_MEDIA_DELETED_COLUMN,
+    user_settings::persistence::StorableUserSetting, utils::persistence::Storable,
+};
+
+pub fn migration() -> String {
+    [
+        <StorableUserSetting as Storable>::CREATE_TABLE_STATEMENT,
+        ATTACHMENT_MEDIA_DELETED_COLUMN,
+    ]
+    .join("\n")
+}
@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{key_stores::signing_key::StorableClientSigningKey, utils::persistence::Storable};
+
+pub fn migration() -> String {
+    <StorableClientSigningKey as Storable>::CREATE_TABLE_STATEMENT.to_owned()
+}
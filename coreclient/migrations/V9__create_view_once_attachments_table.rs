@@ -0,0 +1,9 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::attachments::persistence::VIEW_ONCE_ATTACHMENTS_TABLE;
+
+pub fn migration() -> String {
+    VIEW_ONCE_ATTACHMENTS_TABLE.to_owned()
+}
@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Activity feed, i.e. a log of notable events across all conversations that
+//! the user should look at, e.g. in a notification center.
+//!
+//! Activities are recorded while processing messages and sending fails, and
+//! are kept until they are deleted by the user. Each activity is either read
+//! or unread, independently of the read state of the conversation it refers
+//! to.
+
+use phnxtypes::{crypto::rng, identifiers::QualifiedUserName, time::TimeStamp};
+use rusqlite::{
+    types::{FromSql, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+use uuid::Uuid;
+
+use crate::{ConversationId, ConversationMessageId};
+
+pub(crate) mod persistence;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ActivityId(Uuid);
+
+impl ActivityId {
+    fn random() -> Self {
+        Self(rng::random_uuid())
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for ActivityId {
+    fn from(value: Uuid) -> Self {
+        Self(value)
+    }
+}
+
+impl ToSql for ActivityId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for ActivityId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Uuid::column_result(value).map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActivityKind {
+    /// The user was mentioned in a message
+    Mention {
+        sender: QualifiedUserName,
+        message_id: ConversationMessageId,
+    },
+    /// The user was added to a group conversation
+    Invite,
+    /// A user asked to join a conversation administered by the user
+    JoinRequest { requester: QualifiedUserName },
+    /// A message of the user couldn't be sent
+    FailedDelivery { message_id: ConversationMessageId },
+    /// Something that might compromise the security of the user happened,
+    /// e.g. an invitation was invalid
+    SecurityWarning { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Activity {
+    id: ActivityId,
+    kind: ActivityKind,
+    conversation_id: Option<ConversationId>,
+    created_at: TimeStamp,
+    read: bool,
+}
+
+impl Activity {
+    pub(crate) fn new(kind: ActivityKind, conversation_id: Option<ConversationId>) -> Self {
+        Self {
+            id: ActivityId::random(),
+            kind,
+            conversation_id,
+            created_at: TimeStamp::now(),
+            read: false,
+        }
+    }
+
+    pub fn id(&self) -> ActivityId {
+        self.id
+    }
+
+    pub fn kind(&self) -> &ActivityKind {
+        &self.kind
+    }
+
+    /// The conversation the activity refers to, if any
+    pub fn conversation_id(&self) -> Option<ConversationId> {
+        self.conversation_id
+    }
+
+    pub fn created_at(&self) -> TimeStamp {
+        self.created_at
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+
+    /// The cursor to load the activities after this one.
+    pub fn cursor(&self) -> ActivityCursor {
+        ActivityCursor {
+            created_at: self.created_at,
+            activity_id: self.id,
+        }
+    }
+}
+
+/// Position in the activities ordered newest first. Activities created at the
+/// same time are ordered by their id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivityCursor {
+    pub created_at: TimeStamp,
+    pub activity_id: ActivityId,
+}
+
+/// Whether the given text mentions the user, either with the fully qualified
+/// or the plain user name prefixed with `@`.
+pub(crate) fn mentions(text: &str, user_name: &QualifiedUserName) -> bool {
+    let qualified = format!("@{user_name}");
+    let plain = format!("@{}", user_name.user_name());
+    [qualified, plain].iter().any(|mention| {
+        text.match_indices(mention.as_str()).any(|(start, _)| {
+            let before = text[..start].chars().next_back();
+            // A trailing dot ends the sentence rather than the user name
+            let after = text[start + mention.len()..]
+                .trim_start_matches('.')
+                .chars()
+                .next();
+            let after_dots = text[start + mention.len()..].starts_with('.');
+            before.is_none_or(is_boundary)
+                && after.is_none_or(|c| is_boundary(c) && (!after_dots || c.is_whitespace()))
+        })
+    })
+}
+
+fn is_boundary(c: char) -> bool {
+    !(c.is_alphanumeric() || matches!(c, '_' | '.' | '@' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use super::*;
+
+    #[test]
+    fn mentions_need_word_boundaries() {
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        assert!(mentions("Hi @alice!", &alice));
+        assert!(mentions("@alice@example.com, look", &alice));
+        assert!(mentions("Thanks @alice.", &alice));
+        assert!(!mentions("Hi alice", &alice));
+        assert!(!mentions("Hi @alicia", &alice));
+        assert!(!mentions("Hi @alice@example.org", &alice));
+        assert!(!mentions("mail@alice.com", &alice));
+        assert!(!mentions("Hi @alice.smith", &alice));
+    }
+}
@@ -0,0 +1,302 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use phnxtypes::{identifiers::QualifiedUserName, time::TimeStamp};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{utils::persistence::Storable, ConversationMessageId};
+
+use super::{Activity, ActivityCursor, ActivityId, ActivityKind};
+
+/// Columns of the activities table in the order of [`Activity::from_row`].
+const ACTIVITY_COLUMNS: &str =
+    "activity_id, kind, conversation_id, actor, message_id, details, created_at, read";
+
+impl ActivityKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ActivityKind::Mention { .. } => "mention",
+            ActivityKind::Invite => "invite",
+            ActivityKind::JoinRequest { .. } => "join_request",
+            ActivityKind::FailedDelivery { .. } => "failed_delivery",
+            ActivityKind::SecurityWarning { .. } => "security_warning",
+        }
+    }
+
+    /// The actor, message id and details columns
+    fn columns(
+        &self,
+    ) -> (
+        Option<&QualifiedUserName>,
+        Option<ConversationMessageId>,
+        Option<&str>,
+    ) {
+        match self {
+            ActivityKind::Mention { sender, message_id } => (Some(sender), Some(*message_id), None),
+            ActivityKind::Invite => (None, None, None),
+            ActivityKind::JoinRequest { requester } => (Some(requester), None, None),
+            ActivityKind::FailedDelivery { message_id } => (None, Some(*message_id), None),
+            ActivityKind::SecurityWarning { reason } => (None, None, Some(reason)),
+        }
+    }
+
+    fn from_columns(
+        kind: &str,
+        actor: Option<QualifiedUserName>,
+        message_id: Option<ConversationMessageId>,
+        details: Option<String>,
+    ) -> Option<Self> {
+        let kind = match kind {
+            "mention" => ActivityKind::Mention {
+                sender: actor?,
+                message_id: message_id?,
+            },
+            "invite" => ActivityKind::Invite,
+            "join_request" => ActivityKind::JoinRequest { requester: actor? },
+            "failed_delivery" => ActivityKind::FailedDelivery {
+                message_id: message_id?,
+            },
+            "security_warning" => ActivityKind::SecurityWarning { reason: details? },
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+impl Storable for Activity {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS activities (
+            activity_id BLOB PRIMARY KEY,
+            kind TEXT NOT NULL,
+            conversation_id BLOB,
+            actor TEXT,
+            message_id BLOB,
+            details TEXT,
+            created_at TEXT NOT NULL,
+            read BOOLEAN NOT NULL DEFAULT FALSE
+        );
+        CREATE INDEX IF NOT EXISTS activities_created_at ON activities (created_at);";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let kind: String = row.get(1)?;
+        let kind = ActivityKind::from_columns(&kind, row.get(3)?, row.get(4)?, row.get(5)?)
+            .ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    1,
+                    rusqlite::types::Type::Text,
+                    format!("Invalid activity of kind {kind}").into(),
+                )
+            })?;
+        Ok(Self {
+            id: row.get(0)?,
+            kind,
+            conversation_id: row.get(2)?,
+            created_at: row.get(6)?,
+            read: row.get(7)?,
+        })
+    }
+}
+
+impl Activity {
+    /// Store the activity. A previous activity about the failed delivery of
+    /// the same message is replaced, such that retrying doesn't pile up
+    /// activities.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        if let ActivityKind::FailedDelivery { message_id } = &self.kind {
+            Self::delete_failed_delivery(connection, *message_id)?;
+        }
+        let (actor, message_id, details) = self.kind.columns();
+        connection.execute(
+            &format!("INSERT INTO activities ({ACTIVITY_COLUMNS}) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"),
+            params![
+                self.id,
+                self.kind.as_str(),
+                self.conversation_id,
+                actor,
+                message_id,
+                details,
+                self.created_at,
+                self.read,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load(
+        connection: &Connection,
+        activity_id: ActivityId,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        connection
+            .query_row(
+                &format!("SELECT {ACTIVITY_COLUMNS} FROM activities WHERE activity_id = ?"),
+                params![activity_id],
+                Self::from_row,
+            )
+            .optional()
+    }
+
+    /// At most `limit` activities after the cursor `after`, or the latest
+    /// ones if `after` is `None`, newest first.
+    pub(crate) fn load_page(
+        connection: &Connection,
+        after: Option<ActivityCursor>,
+        limit: usize,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(&format!(
+            "SELECT {ACTIVITY_COLUMNS} FROM activities
+            WHERE ?1 IS NULL OR (created_at, activity_id) < (?1, ?2)
+            ORDER BY created_at DESC, activity_id DESC
+            LIMIT ?3"
+        ))?;
+        let (created_at, activity_id) = after
+            .map(|cursor| (cursor.created_at, cursor.activity_id))
+            .unzip();
+        let activities = statement
+            .query_map(params![created_at, activity_id, limit], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(activities)
+    }
+
+    pub(crate) fn unread_count(connection: &Connection) -> Result<u32, rusqlite::Error> {
+        connection.query_row(
+            "SELECT COUNT(*) FROM activities WHERE read = FALSE",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    pub(crate) fn mark_as_read(
+        connection: &Connection,
+        activity_id: ActivityId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE activities SET read = TRUE WHERE activity_id = ?",
+            params![activity_id],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn mark_all_as_read(connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute("UPDATE activities SET read = TRUE WHERE read = FALSE", [])?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(
+        connection: &Connection,
+        activity_id: ActivityId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM activities WHERE activity_id = ?",
+            params![activity_id],
+        )?;
+        Ok(())
+    }
+
+    /// Delete the activity about the failed delivery of the given message,
+    /// e.g. because it was sent after all. Returns true if there was one.
+    pub(crate) fn delete_failed_delivery(
+        connection: &Connection,
+        message_id: ConversationMessageId,
+    ) -> Result<bool, rusqlite::Error> {
+        let deleted = connection.execute(
+            "DELETE FROM activities WHERE kind = 'failed_delivery' AND message_id = ?",
+            params![message_id],
+        )?;
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+    use uuid::Uuid;
+
+    use crate::{utils::migration::run_migrations, ConversationId};
+
+    use super::*;
+
+    #[test]
+    fn activities_are_paginated_newest_first() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        let message_id = ConversationMessageId::new();
+        let kinds = [
+            ActivityKind::Invite,
+            ActivityKind::JoinRequest {
+                requester: alice.clone(),
+            },
+            ActivityKind::Mention {
+                sender: alice,
+                message_id,
+            },
+        ];
+        let mut activities = Vec::new();
+        for (i, kind) in kinds.into_iter().enumerate() {
+            let mut activity = Activity::new(kind, Some(conversation_id));
+            activity.created_at =
+                TimeStamp::from(*activity.created_at + chrono::Duration::seconds(i as i64));
+            activity.store(&connection).unwrap();
+            activities.push(activity);
+        }
+        activities.reverse();
+
+        let first_page = Activity::load_page(&connection, None, 2).unwrap();
+        assert_eq!(first_page, activities[..2]);
+        let second_page =
+            Activity::load_page(&connection, Some(first_page[1].cursor()), 2).unwrap();
+        assert_eq!(second_page, activities[2..]);
+
+        assert_eq!(Activity::unread_count(&connection).unwrap(), 3);
+        Activity::mark_as_read(&connection, activities[0].id()).unwrap();
+        assert_eq!(Activity::unread_count(&connection).unwrap(), 2);
+        Activity::mark_all_as_read(&connection).unwrap();
+        assert_eq!(Activity::unread_count(&connection).unwrap(), 0);
+    }
+
+    #[test]
+    fn activities_with_the_same_timestamp_are_paginated() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let created_at = TimeStamp::now();
+        for _ in 0..5 {
+            let mut activity = Activity::new(ActivityKind::Invite, None);
+            activity.created_at = created_at;
+            activity.store(&connection).unwrap();
+        }
+
+        let mut loaded = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = Activity::load_page(&connection, cursor, 2).unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            cursor = Some(last.cursor());
+            loaded.extend(page.iter().map(Activity::id));
+        }
+        assert_eq!(loaded.len(), 5);
+        loaded.sort_by_key(|id| *id.as_uuid());
+        loaded.dedup();
+        assert_eq!(loaded.len(), 5);
+    }
+
+    #[test]
+    fn failed_deliveries_are_replaced() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let message_id = ConversationMessageId::new();
+        let failed = || Activity::new(ActivityKind::FailedDelivery { message_id }, None);
+        failed().store(&connection).unwrap();
+        let latest = failed();
+        latest.store(&connection).unwrap();
+
+        let activities = Activity::load_page(&connection, None, 10).unwrap();
+        assert_eq!(activities, [latest]);
+    }
+}
@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Announcements of the operator of the user's server, e.g. maintenance
+//! windows or policy changes.
+//!
+//! The client polls the announcements from the AS and stores them together
+//! with whether the user has read them. Announcements that the server no
+//! longer serves, e.g. because they expired, are removed.
+
+use phnxtypes::{messages::announcements::AnnouncementKind, time::TimeStamp};
+use serde::{Deserialize, Serialize};
+
+use crate::user_settings::UserSetting;
+
+pub(crate) mod persistence;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    id: String,
+    kind: AnnouncementKind,
+    title: String,
+    body: String,
+    published_at: TimeStamp,
+    read: bool,
+}
+
+impl Announcement {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn kind(&self) -> AnnouncementKind {
+        self.kind
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn published_at(&self) -> TimeStamp {
+        self.published_at
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+}
+
+impl From<phnxtypes::messages::announcements::Announcement> for Announcement {
+    fn from(announcement: phnxtypes::messages::announcements::Announcement) -> Self {
+        Self {
+            id: announcement.id().to_owned(),
+            kind: announcement.kind(),
+            title: announcement.title().to_owned(),
+            body: announcement.body().to_owned(),
+            published_at: announcement.published_at(),
+            read: false,
+        }
+    }
+}
+
+/// Entity tag of the announcements stored on the client
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct AnnouncementsEtag(pub(crate) Option<String>);
+
+impl UserSetting for AnnouncementsEtag {
+    const KEY: &'static str = "announcements_etag";
+}
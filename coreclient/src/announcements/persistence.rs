@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use phnxtypes::messages::announcements::AnnouncementKind;
+use rusqlite::{
+    params, params_from_iter,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, ToSql,
+};
+
+use crate::utils::persistence::Storable;
+
+use super::Announcement;
+
+/// Wrapper to store the kind of an announcement as text.
+struct StorableKind(AnnouncementKind);
+
+impl ToSql for StorableKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let kind = match self.0 {
+            AnnouncementKind::Info => "info",
+            AnnouncementKind::Maintenance => "maintenance",
+            AnnouncementKind::PolicyChange => "policy_change",
+        };
+        Ok(ToSqlOutput::Owned(Value::Text(kind.to_owned())))
+    }
+}
+
+impl FromSql for StorableKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let kind = match value.as_str()? {
+            "info" => AnnouncementKind::Info,
+            "maintenance" => AnnouncementKind::Maintenance,
+            "policy_change" => AnnouncementKind::PolicyChange,
+            _ => return Err(FromSqlError::InvalidType),
+        };
+        Ok(Self(kind))
+    }
+}
+
+impl Storable for Announcement {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS announcements (
+            announcement_id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            title TEXT NOT NULL,
+            body TEXT NOT NULL,
+            published_at TEXT NOT NULL,
+            read BOOLEAN NOT NULL DEFAULT FALSE
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get(0)?,
+            kind: row.get::<_, StorableKind>(1)?.0,
+            title: row.get(2)?,
+            body: row.get(3)?,
+            published_at: row.get(4)?,
+            read: row.get(5)?,
+        })
+    }
+}
+
+impl Announcement {
+    /// Replace the stored announcements with the given ones. Announcements
+    /// that were stored before keep their read state.
+    pub(crate) fn replace_all(
+        connection: &Connection,
+        announcements: &[Announcement],
+    ) -> Result<(), rusqlite::Error> {
+        let placeholders = vec!["?"; announcements.len()].join(", ");
+        connection.execute(
+            &format!("DELETE FROM announcements WHERE announcement_id NOT IN ({placeholders})"),
+            params_from_iter(announcements.iter().map(|announcement| &announcement.id)),
+        )?;
+        let mut statement = connection.prepare(
+            "INSERT INTO announcements (announcement_id, kind, title, body, published_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (announcement_id) DO UPDATE SET
+                kind = excluded.kind,
+                title = excluded.title,
+                body = excluded.body,
+                published_at = excluded.published_at",
+        )?;
+        for announcement in announcements {
+            statement.execute(params![
+                announcement.id,
+                StorableKind(announcement.kind),
+                announcement.title,
+                announcement.body,
+                announcement.published_at,
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// All stored announcements, newest first.
+    pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT announcement_id, kind, title, body, published_at, read
+            FROM announcements ORDER BY published_at DESC",
+        )?;
+        let announcements = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(announcements)
+    }
+
+    pub(crate) fn mark_all_as_read(connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE announcements SET read = TRUE WHERE read = FALSE",
+            [],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::time::TimeStamp;
+
+    use crate::utils::migration::run_migrations;
+
+    use super::*;
+
+    fn announcement(id: &str) -> Announcement {
+        Announcement {
+            id: id.to_owned(),
+            kind: AnnouncementKind::PolicyChange,
+            title: "New terms".to_owned(),
+            body: "Our terms of service changed".to_owned(),
+            published_at: TimeStamp::now(),
+            read: false,
+        }
+    }
+
+    #[test]
+    fn replacing_keeps_read_state() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        Announcement::replace_all(&connection, &[announcement("a"), announcement("b")]).unwrap();
+        Announcement::mark_all_as_read(&connection).unwrap();
+        Announcement::replace_all(&connection, &[announcement("b"), announcement("c")]).unwrap();
+
+        let mut announcements = Announcement::load_all(&connection).unwrap();
+        announcements.sort_by(|a, b| a.id.cmp(&b.id));
+        let state: Vec<_> = announcements
+            .iter()
+            .map(|announcement| (announcement.id(), announcement.is_read()))
+            .collect();
+        assert_eq!(state, [("b", true), ("c", false)]);
+
+        Announcement::replace_all(&connection, &[]).unwrap();
+        assert!(Announcement::load_all(&connection).unwrap().is_empty());
+    }
+}
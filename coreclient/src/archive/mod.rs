@@ -0,0 +1,110 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Offloading of old messages to the DS.
+//!
+//! Messages that exceed the age configured in [`MessageArchiving`] are
+//! serialized in batches, encrypted with a fresh key and uploaded to the
+//! attachment storage of the conversation's DS. Locally, each archived
+//! message is replaced by a stub that only records the archive it is part
+//! of. The key and the hash of the uploaded ciphertext never leave the
+//! client.
+//!
+//! When the user scrolls back past the local messages of a conversation,
+//! the archives are downloaded, verified, decrypted and the messages are
+//! restored. Restored messages are offloaded again without another upload
+//! once they haven't been looked at for a while.
+//!
+//! Archives are uploaded with their own retention class, so they neither
+//! expire with the attachments of the DS nor are they deleted together with
+//! the user's attachments. Local messages are only deleted once the uploaded
+//! archive was read back and verified.
+//!
+//! Archives are stored in the DS group of the conversation, so they can only
+//! be restored while the user is a member of the conversation. When the
+//! group of a conversation is upgraded, all of its archives are restored
+//! before the client moves to the successor group.
+
+use phnxtypes::time::TimeStamp;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::user_settings::UserSetting;
+
+pub(crate) mod persistence;
+
+/// Maximum number of messages in one archive.
+pub(crate) const ARCHIVE_BATCH_SIZE: u32 = 500;
+/// Minimum number of messages in one archive. Conversations with fewer
+/// archivable messages are left alone, since offloading them wouldn't free
+/// a relevant amount of storage.
+pub(crate) const MIN_ARCHIVE_BATCH_SIZE: u32 = 50;
+
+/// Policy for offloading old messages to the DS to save local storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageArchiving {
+    /// Number of days after which read messages are offloaded. If `None`,
+    /// all messages are kept locally.
+    pub archive_after_days: Option<u32>,
+    /// Number of most recent messages of each conversation that are always
+    /// kept locally, regardless of their age.
+    pub keep_recent: u32,
+}
+
+impl Default for MessageArchiving {
+    fn default() -> Self {
+        Self {
+            archive_after_days: None,
+            keep_recent: 200,
+        }
+    }
+}
+
+impl UserSetting for MessageArchiving {
+    const KEY: &'static str = "message_archiving";
+}
+
+/// Overview of the messages offloaded to the DS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveUsage {
+    /// Number of uploaded archives.
+    pub archive_count: u64,
+    /// Number of messages that are currently only stored on the DS.
+    pub archived_messages: u64,
+    /// Local storage in bytes freed by the offloaded messages.
+    pub archived_size: u64,
+    /// Number of archives whose messages are currently restored.
+    pub restored_count: u64,
+}
+
+/// Identifies an archive uploaded by this client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ArchiveId(Uuid);
+
+impl ArchiveId {
+    pub(crate) fn random() -> Self {
+        Self(phnxtypes::crypto::rng::random_uuid())
+    }
+}
+
+/// Message as stored in an archive. The content is kept in its stored
+/// encoding, such that restoring a message yields exactly the stored row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ArchivedMessage {
+    pub(crate) message_id: Uuid,
+    pub(crate) timestamp: TimeStamp,
+    pub(crate) sender: String,
+    pub(crate) content: Vec<u8>,
+    pub(crate) sent: bool,
+    pub(crate) received_at: Option<TimeStamp>,
+}
+
+/// Plaintext of an archive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ArchivePayload {
+    /// Checked when restoring, as a safeguard against stubs pointing to the
+    /// wrong archive.
+    pub(crate) conversation_id: Uuid,
+    pub(crate) messages: Vec<ArchivedMessage>,
+}
@@ -0,0 +1,495 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use phnxtypes::{
+    crypto::ear::keys::MessageArchiveEarKey, messages::attachments::QualifiedAttachmentUrl,
+    time::TimeStamp,
+};
+use rusqlite::{
+    named_params, params,
+    types::{FromSql, FromSqlResult, ToSqlOutput, Type, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use uuid::Uuid;
+
+use crate::{attachments::AttachmentHash, utils::persistence::Storable, ConversationId};
+
+use super::{ArchiveId, ArchiveUsage, ArchivedMessage};
+
+/// Creates the table of per-message stubs, which record the archive each
+/// offloaded message is part of.
+pub(crate) const MESSAGE_ARCHIVE_ENTRIES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS message_archive_entries (
+        message_id BLOB PRIMARY KEY,
+        archive_id BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS message_archive_entries_archive_id
+        ON message_archive_entries (archive_id);";
+
+impl ToSql for ArchiveId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for ArchiveId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Uuid::column_result(value).map(Self)
+    }
+}
+
+/// Archive uploaded to the DS.
+pub(crate) struct MessageArchive {
+    pub(crate) archive_id: ArchiveId,
+    pub(crate) conversation_id: ConversationId,
+    pub(crate) url: QualifiedAttachmentUrl,
+    pub(crate) ear_key: MessageArchiveEarKey,
+    /// Hash of the uploaded ciphertext, which is verified before decrypting
+    /// a downloaded archive.
+    pub(crate) ciphertext_hash: AttachmentHash,
+    pub(crate) oldest_timestamp: TimeStamp,
+    pub(crate) newest_timestamp: TimeStamp,
+    pub(crate) message_count: u32,
+    /// Size of the archived messages in the local database.
+    pub(crate) size: u64,
+    /// Set while the messages of the archive are restored locally.
+    pub(crate) restored_at: Option<TimeStamp>,
+}
+
+impl Storable for MessageArchive {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS message_archives (
+            archive_id BLOB PRIMARY KEY,
+            conversation_id BLOB NOT NULL,
+            url TEXT NOT NULL,
+            ear_key BLOB NOT NULL,
+            ciphertext_hash BLOB NOT NULL,
+            oldest_timestamp TEXT NOT NULL,
+            newest_timestamp TEXT NOT NULL,
+            message_count INTEGER NOT NULL,
+            size INTEGER NOT NULL,
+            restored_at TEXT
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let url: String = row.get(2)?;
+        let url = url
+            .parse()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(e)))?;
+        let size: i64 = row.get(8)?;
+        Ok(Self {
+            archive_id: row.get(0)?,
+            conversation_id: row.get(1)?,
+            url,
+            ear_key: row.get(3)?,
+            ciphertext_hash: row.get(4)?,
+            oldest_timestamp: row.get(5)?,
+            newest_timestamp: row.get(6)?,
+            message_count: row.get(7)?,
+            size: size as u64,
+            restored_at: row.get(9)?,
+        })
+    }
+}
+
+const SELECT_ARCHIVE: &str = "SELECT archive_id, conversation_id, url, ear_key, ciphertext_hash,
+    oldest_timestamp, newest_timestamp, message_count, size, restored_at
+    FROM message_archives";
+
+impl MessageArchive {
+    /// Store the archive together with the stubs of its messages.
+    pub(crate) fn store(
+        &self,
+        connection: &Connection,
+        message_ids: impl IntoIterator<Item = Uuid>,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT INTO message_archives (archive_id, conversation_id, url, ear_key,
+                ciphertext_hash, oldest_timestamp, newest_timestamp, message_count, size,
+                restored_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                self.archive_id,
+                self.conversation_id,
+                self.url.to_string(),
+                self.ear_key,
+                self.ciphertext_hash,
+                self.oldest_timestamp,
+                self.newest_timestamp,
+                self.message_count,
+                self.size as i64,
+                self.restored_at,
+            ],
+        )?;
+        let mut statement = connection.prepare(
+            "INSERT OR REPLACE INTO message_archive_entries (message_id, archive_id) VALUES (?, ?)",
+        )?;
+        for message_id in message_ids {
+            statement.execute(params![message_id, self.archive_id])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the most recent archive of the conversation that is not
+    /// restored and is needed to show messages down to `oldest_loaded`.
+    ///
+    /// If all requested messages were loaded locally, i.e. `oldest_loaded`
+    /// is `Some`, only archives reaching into the loaded time span are
+    /// needed. Otherwise, any archive is.
+    pub(crate) fn load_next_to_restore(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        oldest_loaded: Option<TimeStamp>,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(&format!(
+            "{SELECT_ARCHIVE}
+            WHERE conversation_id = :conversation_id AND restored_at IS NULL
+                AND (:oldest_loaded IS NULL OR newest_timestamp >= :oldest_loaded)
+            ORDER BY newest_timestamp DESC
+            LIMIT 1"
+        ))?;
+        statement
+            .query_row(
+                named_params! {
+                    ":conversation_id": conversation_id,
+                    ":oldest_loaded": oldest_loaded,
+                },
+                Self::from_row,
+            )
+            .optional()
+    }
+
+    /// Load all archives of the conversation.
+    pub(crate) fn load_all_of_conversation(
+        connection: &Connection,
+        conversation_id: ConversationId,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement =
+            connection.prepare(&format!("{SELECT_ARCHIVE} WHERE conversation_id = ?"))?;
+        let archives = statement
+            .query_map(params![conversation_id], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(archives)
+    }
+
+    /// Load the archives whose messages were restored before the given time.
+    pub(crate) fn load_restored_before(
+        connection: &Connection,
+        restored_before: TimeStamp,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(&format!(
+            "{SELECT_ARCHIVE} WHERE restored_at IS NOT NULL AND restored_at < ?"
+        ))?;
+        let archives = statement
+            .query_map(params![restored_before], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(archives)
+    }
+
+    /// Mark the messages of the archive as restored or, with `None`, as
+    /// offloaded again.
+    pub(crate) fn set_restored_at(
+        &mut self,
+        connection: &Connection,
+        restored_at: Option<TimeStamp>,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE message_archives SET restored_at = ? WHERE archive_id = ?",
+            params![restored_at, self.archive_id],
+        )?;
+        self.restored_at = restored_at;
+        Ok(())
+    }
+
+    /// Delete the local messages that are part of the archive.
+    pub(crate) fn delete_local_messages(
+        &self,
+        connection: &Connection,
+    ) -> Result<usize, rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM conversation_messages WHERE message_id IN (
+                SELECT message_id FROM message_archive_entries WHERE archive_id = ?
+            )",
+            params![self.archive_id],
+        )
+    }
+
+    /// Delete the archive together with the stubs of its messages. The
+    /// messages themselves are kept if they are stored locally.
+    pub(crate) fn delete(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM message_archive_entries WHERE archive_id = ?",
+            params![self.archive_id],
+        )?;
+        connection.execute(
+            "DELETE FROM message_archives WHERE archive_id = ?",
+            params![self.archive_id],
+        )?;
+        Ok(())
+    }
+
+    /// Delete the archives of conversations that no longer exist together
+    /// with their stubs.
+    pub(crate) fn delete_orphaned(connection: &Connection) -> Result<usize, rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM message_archive_entries WHERE archive_id IN (
+                SELECT archive_id FROM message_archives
+                WHERE conversation_id NOT IN (SELECT conversation_id FROM conversations)
+            )",
+            [],
+        )?;
+        connection.execute(
+            "DELETE FROM message_archives
+            WHERE conversation_id NOT IN (SELECT conversation_id FROM conversations)",
+            [],
+        )
+    }
+}
+
+impl ArchivedMessage {
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            message_id: row.get(0)?,
+            timestamp: row.get(1)?,
+            sender: row.get(2)?,
+            content: row.get(3)?,
+            sent: row.get(4)?,
+            received_at: row.get(5)?,
+        })
+    }
+
+    /// Load up to `limit` of the oldest messages of the conversation that
+    /// can be offloaded, oldest first.
+    ///
+    /// Only messages older than `older_than` that were sent and read are
+    /// offloaded. The `keep_recent` most recent messages of the
+    /// conversation, messages that were offloaded before and messages
+    /// referenced by activities or attachments are kept.
+    pub(crate) fn load_archivable(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        older_than: TimeStamp,
+        keep_recent: u32,
+        limit: u32,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT m.message_id, m.timestamp, m.sender, m.content, m.sent, m.received_at
+            FROM conversation_messages m
+            JOIN conversations c ON c.conversation_id = m.conversation_id
+            WHERE m.conversation_id = :conversation_id
+                AND m.timestamp < :older_than
+                AND m.timestamp <= c.last_read
+                AND m.sent = 1
+                AND m.delivery_failure IS NULL
+                AND m.message_id NOT IN (
+                    SELECT message_id FROM conversation_messages
+                    WHERE conversation_id = :conversation_id
+                    ORDER BY timestamp DESC, received_at DESC
+                    LIMIT :keep_recent
+                )
+                AND m.message_id NOT IN (SELECT message_id FROM message_archive_entries)
+                AND m.message_id NOT IN (
+                    SELECT message_id FROM attachment_references WHERE media_deleted_at IS NULL
+                )
+                AND m.message_id NOT IN (SELECT message_id FROM activities)
+            ORDER BY m.timestamp ASC, m.received_at ASC
+            LIMIT :limit",
+        )?;
+        let messages = statement
+            .query_map(
+                named_params! {
+                    ":conversation_id": conversation_id,
+                    ":older_than": older_than,
+                    ":keep_recent": keep_recent,
+                    ":limit": limit,
+                },
+                Self::from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    /// Size of the message in the local database.
+    pub(crate) fn size(&self) -> u64 {
+        self.content.len() as u64
+    }
+
+    /// Store the restored message unless it exists locally.
+    pub(crate) fn restore(
+        &self,
+        connection: &Connection,
+        conversation_id: ConversationId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR IGNORE INTO conversation_messages
+                (message_id, conversation_id, timestamp, sender, content, sent, received_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                self.message_id,
+                conversation_id,
+                self.timestamp,
+                self.sender,
+                self.content,
+                self.sent,
+                self.received_at,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl ArchiveUsage {
+    pub(crate) fn load(connection: &Connection) -> Result<Self, rusqlite::Error> {
+        connection.query_row(
+            "SELECT
+                COUNT(*),
+                COALESCE(SUM(CASE WHEN restored_at IS NULL THEN message_count END), 0),
+                COALESCE(SUM(CASE WHEN restored_at IS NULL THEN size END), 0),
+                COUNT(restored_at)
+            FROM message_archives",
+            [],
+            |row| {
+                let archive_count: i64 = row.get(0)?;
+                let archived_messages: i64 = row.get(1)?;
+                let archived_size: i64 = row.get(2)?;
+                let restored_count: i64 = row.get(3)?;
+                Ok(Self {
+                    archive_count: archive_count as u64,
+                    archived_messages: archived_messages as u64,
+                    archived_size: archived_size as u64,
+                    restored_count: restored_count as u64,
+                })
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use openmls::prelude::GroupId;
+    use phnxtypes::identifiers::{AttachmentId, QualifiedGroupId, QualifiedUserName, SafeTryInto};
+
+    use crate::{
+        activity::{Activity, ActivityKind},
+        conversations::messages::TimestampedMessage,
+        utils::migration::run_migrations,
+        Conversation, ConversationAttributes, ConversationMessage, MimiContent,
+    };
+
+    use super::*;
+
+    #[test]
+    fn messages_are_offloaded_and_restored() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let group_id = GroupId::from(QualifiedGroupId::new(Uuid::new_v4(), alice.domain()));
+        let conversation = Conversation::new_group_conversation(
+            group_id,
+            ConversationAttributes::new("Group".to_owned(), None),
+        );
+        conversation.store(&connection).unwrap();
+        let conversation_id = conversation.id();
+
+        // Messages from five hours to one hour ago
+        let now = TimeStamp::now();
+        let messages: Vec<_> = (1..=5)
+            .rev()
+            .map(|hours| {
+                let content = MimiContent::simple_markdown_message(alice.domain(), "Hi".to_owned());
+                let timestamp = TimeStamp::from(*now - Duration::hours(hours));
+                let message = ConversationMessage::from_timestamped_message(
+                    conversation_id,
+                    TimestampedMessage::from_content(content, timestamp, bob.clone()),
+                );
+                message.store(&connection).unwrap();
+                message
+            })
+            .collect();
+        let mut transaction = connection.transaction().unwrap();
+        Conversation::mark_as_read(
+            &mut transaction,
+            [(conversation_id, *now - Duration::minutes(90))],
+        )
+        .unwrap();
+        transaction.commit().unwrap();
+        Activity::new(
+            ActivityKind::Mention {
+                sender: bob.clone(),
+                message_id: messages[2].id(),
+            },
+            Some(conversation_id),
+        )
+        .store(&connection)
+        .unwrap();
+
+        // The most recent message is kept and unread, the mentioned message
+        // is referenced by an activity.
+        let archivable =
+            ArchivedMessage::load_archivable(&connection, conversation_id, now, 1, 10).unwrap();
+        let archivable_ids: Vec<_> = archivable.iter().map(|m| m.message_id).collect();
+        assert_eq!(
+            archivable_ids,
+            [0, 1, 3].map(|index| messages[index].id().to_uuid())
+        );
+
+        let archive = MessageArchive {
+            archive_id: ArchiveId::random(),
+            conversation_id,
+            url: QualifiedAttachmentUrl::new(AttachmentId::from(Uuid::new_v4())),
+            ear_key: MessageArchiveEarKey::random().unwrap(),
+            ciphertext_hash: AttachmentHash::of(b"ciphertext").unwrap(),
+            oldest_timestamp: archivable[0].timestamp,
+            newest_timestamp: archivable[2].timestamp,
+            message_count: 3,
+            size: archivable.iter().map(|m| m.size()).sum(),
+            restored_at: None,
+        };
+        archive.store(&connection, archivable_ids).unwrap();
+        assert_eq!(archive.delete_local_messages(&connection).unwrap(), 3);
+        let local = ConversationMessage::load_multiple(&connection, conversation_id, 10).unwrap();
+        assert_eq!(local.len(), 2);
+        let usage = ArchiveUsage::load(&connection).unwrap();
+        assert_eq!(usage.archived_messages, 3);
+        assert_eq!(usage.archived_size, archive.size);
+
+        // The archive reaches into the time span of the local messages.
+        let oldest_local = TimeStamp::from(local[0].timestamp());
+        let mut to_restore =
+            MessageArchive::load_next_to_restore(&connection, conversation_id, Some(oldest_local))
+                .unwrap()
+                .unwrap();
+        assert_eq!(to_restore.archive_id, archive.archive_id);
+        assert_eq!(to_restore.url, archive.url);
+
+        for message in &archivable {
+            message.restore(&connection, conversation_id).unwrap();
+        }
+        to_restore
+            .set_restored_at(&connection, Some(TimeStamp::now()))
+            .unwrap();
+        let local = ConversationMessage::load_multiple(&connection, conversation_id, 10).unwrap();
+        assert_eq!(
+            local.iter().map(|m| m.id()).collect::<Vec<_>>(),
+            messages.iter().map(|m| m.id()).collect::<Vec<_>>()
+        );
+        assert!(
+            MessageArchive::load_next_to_restore(&connection, conversation_id, None)
+                .unwrap()
+                .is_none()
+        );
+        // Restored messages are not archived again.
+        assert!(
+            ArchivedMessage::load_archivable(&connection, conversation_id, now, 1, 10)
+                .unwrap()
+                .is_empty()
+        );
+        let usage = ArchiveUsage::load(&connection).unwrap();
+        assert_eq!(usage.archived_messages, 0);
+        assert_eq!(usage.restored_count, 1);
+    }
+}
@@ -0,0 +1,266 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Rules for automatically downloading attachments.
+//!
+//! Before an attachment is fetched without an explicit request by the user,
+//! the download is checked against the [`AutoDownloadPolicy`]. The policy
+//! depends on the kind and size of the attachment and on the type of network
+//! the device is currently connected to, which is reported by the platform.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{user_settings::UserSetting, ConversationId};
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AttachmentKind {
+    Image,
+    Video,
+    Audio,
+    #[default]
+    File,
+}
+
+impl AttachmentKind {
+    /// Detect the kind of the given attachment content from the signature of
+    /// its format. Content of unknown formats is a file.
+    #[cfg(feature = "attachments")]
+    pub(crate) fn of(content: &[u8]) -> Self {
+        let starts_with = |signature: &[u8]| content.starts_with(signature);
+        let riff_format = (starts_with(b"RIFF") && content.len() >= 12).then(|| &content[8..12]);
+        // MP4 and QuickTime containers, including HEIF images and M4A audio
+        let ftyp_brand =
+            (content.len() >= 12 && &content[4..8] == b"ftyp").then(|| &content[8..12]);
+
+        if starts_with(&[0xFF, 0xD8, 0xFF])
+            || starts_with(b"\x89PNG")
+            || starts_with(b"GIF8")
+            || matches!(riff_format, Some(b"WEBP"))
+            || matches!(
+                ftyp_brand,
+                Some(b"heic" | b"heix" | b"mif1" | b"msf1" | b"avif")
+            )
+        {
+            Self::Image
+        } else if matches!(ftyp_brand, Some(b"M4A " | b"M4B "))
+            || starts_with(b"ID3")
+            || starts_with(b"OggS")
+            || starts_with(b"fLaC")
+            || matches!(riff_format, Some(b"WAVE"))
+            || (content.len() >= 2 && content[0] == 0xFF && content[1] & 0xE0 == 0xE0)
+        {
+            Self::Audio
+        } else if ftyp_brand.is_some() || starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+            Self::Video
+        } else {
+            Self::File
+        }
+    }
+}
+
+/// Type of the network the device is currently connected to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetworkType {
+    Wifi,
+    Cellular,
+    /// The platform did not report a network type (yet). Treated like a
+    /// metered connection.
+    #[default]
+    Unknown,
+}
+
+/// Networks on which an attachment kind is downloaded automatically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AutoDownloadCondition {
+    Never,
+    WifiOnly,
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoDownloadRule {
+    pub condition: AutoDownloadCondition,
+    /// Attachments larger than this size in bytes are never downloaded
+    /// automatically.
+    pub max_size: Option<u64>,
+}
+
+impl AutoDownloadRule {
+    fn allows(&self, size: u64, network_type: NetworkType) -> bool {
+        let network_allowed = match self.condition {
+            AutoDownloadCondition::Never => false,
+            AutoDownloadCondition::WifiOnly => network_type == NetworkType::Wifi,
+            AutoDownloadCondition::Always => true,
+        };
+        network_allowed && self.max_size.map_or(true, |max_size| size <= max_size)
+    }
+}
+
+/// Auto-download rules for each kind of attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoDownloadRules {
+    pub images: AutoDownloadRule,
+    pub videos: AutoDownloadRule,
+    pub audio: AutoDownloadRule,
+    pub files: AutoDownloadRule,
+}
+
+impl Default for AutoDownloadRules {
+    fn default() -> Self {
+        Self {
+            images: AutoDownloadRule {
+                condition: AutoDownloadCondition::WifiOnly,
+                max_size: Some(10 * MEGABYTE),
+            },
+            videos: AutoDownloadRule {
+                condition: AutoDownloadCondition::Never,
+                max_size: None,
+            },
+            audio: AutoDownloadRule {
+                condition: AutoDownloadCondition::Always,
+                max_size: Some(5 * MEGABYTE),
+            },
+            files: AutoDownloadRule {
+                condition: AutoDownloadCondition::WifiOnly,
+                max_size: Some(20 * MEGABYTE),
+            },
+        }
+    }
+}
+
+impl AutoDownloadRules {
+    pub fn rule(&self, kind: AttachmentKind) -> &AutoDownloadRule {
+        match kind {
+            AttachmentKind::Image => &self.images,
+            AttachmentKind::Video => &self.videos,
+            AttachmentKind::Audio => &self.audio,
+            AttachmentKind::File => &self.files,
+        }
+    }
+}
+
+/// User setting that controls which attachments are downloaded
+/// automatically.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoDownloadPolicy {
+    pub default_rules: AutoDownloadRules,
+    /// Rules that replace the default rules in individual conversations.
+    pub conversation_overrides: HashMap<ConversationId, AutoDownloadRules>,
+}
+
+impl UserSetting for AutoDownloadPolicy {
+    const KEY: &'static str = "auto_download";
+    const SYNCED: bool = true;
+}
+
+impl AutoDownloadPolicy {
+    /// Returns the rules that apply in the conversation with the given id.
+    pub fn rules(&self, conversation_id: ConversationId) -> &AutoDownloadRules {
+        self.conversation_overrides
+            .get(&conversation_id)
+            .unwrap_or(&self.default_rules)
+    }
+
+    /// Returns true if an attachment of the given kind and size in the
+    /// conversation with the given id should be downloaded automatically on
+    /// the given network.
+    pub fn should_download(
+        &self,
+        conversation_id: ConversationId,
+        kind: AttachmentKind,
+        size: u64,
+        network_type: NetworkType,
+    ) -> bool {
+        self.rules(conversation_id)
+            .rule(kind)
+            .allows(size, network_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = AutoDownloadPolicy::default();
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+
+        let image = |size, network_type| {
+            policy.should_download(conversation_id, AttachmentKind::Image, size, network_type)
+        };
+        assert!(image(MEGABYTE, NetworkType::Wifi));
+        assert!(!image(MEGABYTE, NetworkType::Cellular));
+        assert!(!image(MEGABYTE, NetworkType::Unknown));
+        assert!(!image(11 * MEGABYTE, NetworkType::Wifi));
+
+        assert!(!policy.should_download(
+            conversation_id,
+            AttachmentKind::Video,
+            1,
+            NetworkType::Wifi
+        ));
+        assert!(policy.should_download(
+            conversation_id,
+            AttachmentKind::Audio,
+            MEGABYTE,
+            NetworkType::Cellular
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "attachments")]
+    fn attachment_kind_of_content() {
+        let mp4 = [b"\0\0\0\x18ftypmp42".as_slice(), &[0u8; 8][..]].concat();
+        let heic = [b"\0\0\0\x18ftypheic".as_slice(), &[0u8; 8][..]].concat();
+        let m4a = [b"\0\0\0\x18ftypM4A ".as_slice(), &[0u8; 8][..]].concat();
+        let cases: [(&[u8], AttachmentKind); 9] = [
+            (&[0xFF, 0xD8, 0xFF, 0xE0], AttachmentKind::Image),
+            (b"\x89PNG\r\n\x1a\n", AttachmentKind::Image),
+            (b"RIFF\0\0\0\0WEBPVP8 ", AttachmentKind::Image),
+            (&heic, AttachmentKind::Image),
+            (&mp4, AttachmentKind::Video),
+            (&m4a, AttachmentKind::Audio),
+            (b"OggS\0\x02", AttachmentKind::Audio),
+            (b"%PDF-1.7", AttachmentKind::File),
+            (b"", AttachmentKind::File),
+        ];
+        for (content, kind) in cases {
+            assert_eq!(AttachmentKind::of(content), kind, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn conversation_override() {
+        let mut policy = AutoDownloadPolicy::default();
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        let other_conversation_id = ConversationId::from(Uuid::new_v4());
+
+        let mut rules = AutoDownloadRules::default();
+        rules.videos = AutoDownloadRule {
+            condition: AutoDownloadCondition::Always,
+            max_size: None,
+        };
+        policy.conversation_overrides.insert(conversation_id, rules);
+
+        assert!(policy.should_download(
+            conversation_id,
+            AttachmentKind::Video,
+            100 * MEGABYTE,
+            NetworkType::Cellular
+        ));
+        assert!(!policy.should_download(
+            other_conversation_id,
+            AttachmentKind::Video,
+            MEGABYTE,
+            NetworkType::Wifi
+        ));
+    }
+}
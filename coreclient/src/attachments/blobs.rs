@@ -0,0 +1,408 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Storage of attachment blobs and deletion of the media of old messages.
+
+use chrono::{DateTime, Utc};
+use phnxtypes::time::TimeStamp;
+use rusqlite::{named_params, params, Connection, OptionalExtension};
+
+use crate::{utils::persistence::Storable, ConversationId};
+
+use super::{
+    persistence::{AttachmentReference, ATTACHMENT_BLOBS_TABLE},
+    AttachmentHash, ConversationStorage, StorageBreakdown,
+};
+
+pub(crate) struct AttachmentBlob {
+    pub(crate) hash: AttachmentHash,
+    pub(crate) content: Vec<u8>,
+}
+
+impl Storable for AttachmentBlob {
+    const CREATE_TABLE_STATEMENT: &'static str = ATTACHMENT_BLOBS_TABLE;
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let hash = row.get(0)?;
+        let content = row.get(1)?;
+        Ok(Self { hash, content })
+    }
+}
+
+impl AttachmentBlob {
+    pub(crate) fn new(content: Vec<u8>) -> anyhow::Result<Self> {
+        let hash = AttachmentHash::of(&content)?;
+        Ok(Self { hash, content })
+    }
+
+    /// Store the blob unless a blob with the same content already exists.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR IGNORE INTO attachment_blobs (content_hash, content, size, created_at) VALUES (?, ?, ?, ?)",
+            params![
+                self.hash,
+                self.content,
+                self.content.len() as i64,
+                TimeStamp::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load(
+        connection: &Connection,
+        hash: &AttachmentHash,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        let mut statement = connection
+            .prepare("SELECT content_hash, content FROM attachment_blobs WHERE content_hash = ?")?;
+        statement
+            .query_row(params![hash], Self::from_row)
+            .optional()
+    }
+
+    /// Delete all blobs that are not referenced by any existing message.
+    /// References of messages that no longer exist are removed first.
+    /// References whose media was deleted don't keep a blob alive.
+    ///
+    /// Returns the number of bytes freed.
+    pub(crate) fn delete_unreferenced(connection: &Connection) -> Result<u64, rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM attachment_references
+            WHERE message_id NOT IN (SELECT message_id FROM conversation_messages)",
+            [],
+        )?;
+        let freed: i64 = connection.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM attachment_blobs
+            WHERE content_hash NOT IN (
+                SELECT content_hash FROM attachment_references WHERE media_deleted_at IS NULL
+            )",
+            [],
+            |row| row.get(0),
+        )?;
+        connection.execute(
+            "DELETE FROM attachment_blobs
+            WHERE content_hash NOT IN (
+                SELECT content_hash FROM attachment_references WHERE media_deleted_at IS NULL
+            )",
+            [],
+        )?;
+        Ok(freed as u64)
+    }
+
+    /// Delete the media of all messages older than `older_than`, keeping the
+    /// messages and their references as stubs. If a conversation id is
+    /// given, only messages of that conversation are affected.
+    ///
+    /// Blobs that are still used by other messages are kept. Returns the
+    /// number of bytes freed by deleting the media of these messages.
+    pub(crate) fn delete_media(
+        connection: &Connection,
+        conversation_id: Option<ConversationId>,
+        older_than: DateTime<Utc>,
+    ) -> Result<u64, rusqlite::Error> {
+        let hashes =
+            AttachmentReference::mark_media_deleted(connection, conversation_id, older_than)?;
+        let mut statement = connection.prepare(
+            "DELETE FROM attachment_blobs
+            WHERE content_hash = ?
+                AND content_hash NOT IN (
+                    SELECT content_hash FROM attachment_references WHERE media_deleted_at IS NULL
+                )
+            RETURNING size",
+        )?;
+        let mut freed = 0;
+        for hash in hashes {
+            // Blobs referenced by several of the messages are only deleted once
+            let size: Option<i64> = statement
+                .query_row(params![hash], |row| row.get(0))
+                .optional()?;
+            freed += size.unwrap_or_default() as u64;
+        }
+        Ok(freed)
+    }
+}
+
+impl AttachmentReference {
+    /// Mark the media of all messages older than `older_than` as deleted. If
+    /// a conversation id is given, only messages of that conversation are
+    /// affected.
+    ///
+    /// Returns the hashes of the blobs of the affected references.
+    fn mark_media_deleted(
+        connection: &Connection,
+        conversation_id: Option<ConversationId>,
+        older_than: DateTime<Utc>,
+    ) -> Result<Vec<AttachmentHash>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "UPDATE attachment_references
+            SET media_deleted_at = :now
+            WHERE media_deleted_at IS NULL
+                AND (:conversation_id IS NULL OR conversation_id = :conversation_id)
+                AND message_id IN (
+                    SELECT message_id FROM conversation_messages WHERE timestamp < :older_than
+                )
+            RETURNING content_hash",
+        )?;
+        let hashes = statement
+            .query_map(
+                named_params! {
+                    ":now": TimeStamp::now(),
+                    ":conversation_id": conversation_id,
+                    ":older_than": older_than,
+                },
+                |row| row.get(0),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hashes)
+    }
+}
+
+impl StorageBreakdown {
+    pub(crate) fn load(connection: &Connection) -> Result<Self, rusqlite::Error> {
+        let (total_size, blob_count): (i64, i64) = connection.query_row(
+            "SELECT COALESCE(SUM(size), 0), COUNT(*) FROM attachment_blobs",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let referenced_size: i64 = connection.query_row(
+            "SELECT COALESCE(SUM(b.size), 0)
+            FROM attachment_references r
+            JOIN attachment_blobs b ON b.content_hash = r.content_hash
+            WHERE r.media_deleted_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        let unreferenced_size: i64 = connection.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM attachment_blobs
+            WHERE content_hash NOT IN (
+                SELECT r.content_hash FROM attachment_references r
+                JOIN conversation_messages m ON m.message_id = r.message_id
+                WHERE r.media_deleted_at IS NULL
+            )",
+            [],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = connection.prepare(
+            "SELECT
+                c.conversation_id,
+                (
+                    SELECT COALESCE(SUM(LENGTH(m.content)), 0)
+                    FROM conversation_messages m
+                    WHERE m.conversation_id = c.conversation_id
+                ) AS messages_size,
+                (
+                    SELECT COALESCE(SUM(b.size), 0)
+                    FROM attachment_references r
+                    JOIN attachment_blobs b ON b.content_hash = r.content_hash
+                    WHERE r.conversation_id = c.conversation_id
+                        AND r.media_deleted_at IS NULL
+                        AND NOT r.thumbnail
+                ) AS attachments_size,
+                (
+                    SELECT COALESCE(SUM(b.size), 0)
+                    FROM attachment_references r
+                    JOIN attachment_blobs b ON b.content_hash = r.content_hash
+                    WHERE r.conversation_id = c.conversation_id
+                        AND r.media_deleted_at IS NULL
+                        AND r.thumbnail
+                ) AS thumbnails_size,
+                (
+                    SELECT COUNT(*)
+                    FROM attachment_references r
+                    WHERE r.conversation_id = c.conversation_id
+                        AND r.media_deleted_at IS NULL
+                        AND NOT r.thumbnail
+                ) AS attachment_count
+            FROM conversations c
+            ORDER BY messages_size + attachments_size + thumbnails_size DESC",
+        )?;
+        let conversations = statement
+            .query_map([], |row| {
+                let messages_size: i64 = row.get(1)?;
+                let attachments_size: i64 = row.get(2)?;
+                let thumbnails_size: i64 = row.get(3)?;
+                let attachment_count: i64 = row.get(4)?;
+                Ok(ConversationStorage {
+                    conversation_id: row.get(0)?,
+                    messages_size: messages_size as u64,
+                    attachments_size: attachments_size as u64,
+                    thumbnails_size: thumbnails_size as u64,
+                    attachment_count: attachment_count as u64,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            total_size: total_size as u64,
+            blob_count: blob_count as u64,
+            deduplicated_size: referenced_size.saturating_sub(total_size).max(0) as u64,
+            unreferenced_size: unreferenced_size as u64,
+            conversations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{utils::migration::run_migrations, ConversationMessageId};
+
+    use super::*;
+
+    #[test]
+    fn blobs_are_deduplicated_and_cleaned_up() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let conversation_id = ConversationId::from(uuid::Uuid::new_v4());
+        let content = b"attachment".to_vec();
+        for _ in 0..2 {
+            let blob = AttachmentBlob::new(content.clone()).unwrap();
+            blob.store(&connection).unwrap();
+            AttachmentReference {
+                hash: blob.hash,
+                message_id: ConversationMessageId::new(),
+                conversation_id,
+            }
+            .store(&connection)
+            .unwrap();
+        }
+
+        let breakdown = StorageBreakdown::load(&connection).unwrap();
+        assert_eq!(breakdown.blob_count, 1);
+        assert_eq!(breakdown.total_size, content.len() as u64);
+        assert_eq!(breakdown.deduplicated_size, content.len() as u64);
+        // The referencing messages don't exist, so the blob is unreferenced.
+        assert_eq!(breakdown.unreferenced_size, content.len() as u64);
+
+        let freed = AttachmentBlob::delete_unreferenced(&connection).unwrap();
+        assert_eq!(freed, content.len() as u64);
+        let hash = AttachmentHash::of(&content).unwrap();
+        assert!(AttachmentBlob::load(&connection, &hash).unwrap().is_none());
+    }
+
+    fn store_conversation(connection: &Connection) -> ConversationId {
+        let conversation_id = ConversationId::from(uuid::Uuid::new_v4());
+        connection
+            .execute(
+                "INSERT INTO conversations (conversation_id, conversation_title, group_id, last_read, conversation_status, conversation_type)
+                VALUES (?, 'title', x'00', ?, 'active', 'group')",
+                params![conversation_id, TimeStamp::now()],
+            )
+            .unwrap();
+        conversation_id
+    }
+
+    fn store_message(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        timestamp: DateTime<Utc>,
+    ) -> ConversationMessageId {
+        let message_id = ConversationMessageId::new();
+        connection
+            .execute(
+                "INSERT INTO conversation_messages (message_id, conversation_id, timestamp, sender, content, sent)
+                VALUES (?, ?, ?, 'system', x'', TRUE)",
+                params![message_id, conversation_id, timestamp],
+            )
+            .unwrap();
+        message_id
+    }
+
+    fn store_blob(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        message_id: ConversationMessageId,
+        content: &[u8],
+        thumbnail: bool,
+    ) {
+        let blob = AttachmentBlob::new(content.to_vec()).unwrap();
+        blob.store(connection).unwrap();
+        let reference = AttachmentReference {
+            hash: blob.hash,
+            message_id,
+            conversation_id,
+        };
+        if thumbnail {
+            reference.store_thumbnail(connection).unwrap();
+        } else {
+            reference.store(connection).unwrap();
+        }
+    }
+
+    #[test]
+    fn media_is_cleared_per_conversation() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let old = Utc::now() - chrono::Duration::days(2);
+        let shared = b"shared".to_vec();
+        let image = b"image of a".to_vec();
+        let thumbnail = b"thumb".to_vec();
+        let other_image = b"image of b".to_vec();
+
+        // Conversation a has an image with a thumbnail and a blob shared with b
+        let a = store_conversation(&connection);
+        let a_message = store_message(&connection, a, old);
+        store_blob(&connection, a, a_message, &image, false);
+        store_blob(&connection, a, a_message, &thumbnail, true);
+        store_blob(&connection, a, a_message, &shared, false);
+        let b = store_conversation(&connection);
+        let b_message = store_message(&connection, b, old);
+        store_blob(&connection, b, b_message, &shared, false);
+        store_blob(&connection, b, b_message, &other_image, false);
+        // Unrelated blob that isn't referenced anymore
+        store_blob(
+            &connection,
+            b,
+            ConversationMessageId::new(),
+            b"unreferenced",
+            false,
+        );
+
+        let storage = StorageBreakdown::load(&connection).unwrap();
+        let a_storage = storage
+            .conversations
+            .iter()
+            .find(|storage| storage.conversation_id == a)
+            .unwrap();
+        assert_eq!(
+            a_storage.attachments_size,
+            (image.len() + shared.len()) as u64
+        );
+        assert_eq!(a_storage.thumbnails_size, thumbnail.len() as u64);
+        assert_eq!(a_storage.attachment_count, 2);
+
+        // Recent media is kept
+        let freed = AttachmentBlob::delete_media(&connection, Some(a), old).unwrap();
+        assert_eq!(freed, 0);
+
+        // Only the blobs no other conversation uses are freed, including the
+        // thumbnail
+        let freed = AttachmentBlob::delete_media(&connection, Some(a), Utc::now()).unwrap();
+        assert_eq!(freed, (image.len() + thumbnail.len()) as u64);
+        let attachments = AttachmentReference::load_all_of_message(&connection, a_message).unwrap();
+        assert_eq!(attachments.len(), 3);
+        assert!(attachments
+            .iter()
+            .all(|attachment| attachment.media_deleted_at.is_some()));
+        let hash = AttachmentHash::of(&shared).unwrap();
+        assert!(AttachmentBlob::load(&connection, &hash).unwrap().is_some());
+
+        let storage = StorageBreakdown::load(&connection).unwrap();
+        let a_storage = storage
+            .conversations
+            .iter()
+            .find(|storage| storage.conversation_id == a)
+            .unwrap();
+        assert_eq!(a_storage.attachments_size, 0);
+        assert_eq!(a_storage.thumbnails_size, 0);
+        assert_eq!(a_storage.attachment_count, 0);
+
+        // The shared blob is freed once the last conversation drops it
+        let freed = AttachmentBlob::delete_media(&connection, Some(b), Utc::now()).unwrap();
+        assert_eq!(freed, (shared.len() + other_image.len()) as u64);
+        assert!(AttachmentBlob::load(&connection, &hash).unwrap().is_none());
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Content-addressed store for attachment blobs.
+//!
+//! Blobs are keyed by the hash of their content, such that content referenced
+//! by several messages (e.g. when forwarded to multiple conversations) is only
+//! stored once. Each message that uses a blob holds a reference to it and
+//! blobs that are no longer referenced can be cleaned up.
+//!
+//! The media of a message can be deleted while keeping the message itself. In
+//! that case, the message keeps a stub of the reference which marks the media
+//! as deleted, such that it can be rendered as a placeholder.
+
+use std::fmt::Display;
+
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::HashType};
+use phnxtypes::time::TimeStamp;
+use rusqlite::{
+    types::{FromSql, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{user_settings::UserSetting, ConversationId};
+
+mod auto_download;
+#[cfg(feature = "attachments")]
+pub(crate) mod blobs;
+pub(crate) mod persistence;
+#[cfg(feature = "attachments")]
+mod transcoding;
+#[cfg(feature = "attachments")]
+pub(crate) mod view_once;
+
+pub use auto_download::{
+    AttachmentKind, AutoDownloadCondition, AutoDownloadPolicy, AutoDownloadRule, AutoDownloadRules,
+    NetworkType,
+};
+#[cfg(feature = "attachments")]
+pub use transcoding::{
+    MediaCodec, MediaTranscoding, NoMediaCodec, VideoCodec, VideoTranscodeParams,
+};
+#[cfg(feature = "attachments")]
+pub use view_once::ViewOnceState;
+
+/// Hash of the content of an attachment, which identifies the attachment's
+/// blob in the local store.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AttachmentHash(Vec<u8>);
+
+impl AttachmentHash {
+    pub(crate) fn of(content: &[u8]) -> anyhow::Result<Self> {
+        let hash = RustCrypto::default().hash(HashType::Sha2_256, content)?;
+        Ok(Self(hash))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for AttachmentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToSql for AttachmentHash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for AttachmentHash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(Self(value.as_blob()?.to_vec()))
+    }
+}
+
+/// Attachment of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageAttachment {
+    pub hash: AttachmentHash,
+    /// Set if the media of the attachment was deleted to free up storage.
+    /// The message itself is kept.
+    pub media_deleted_at: Option<TimeStamp>,
+    /// Whether the attachment is the thumbnail of another attachment of the
+    /// message.
+    pub thumbnail: bool,
+}
+
+/// Policy for automatically deleting the media of old messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaRetention {
+    /// Number of days after which the media of a message is deleted. If
+    /// `None`, media is kept indefinitely.
+    pub keep_days: Option<u32>,
+}
+
+impl UserSetting for MediaRetention {
+    const KEY: &'static str = "media_retention";
+    const SYNCED: bool = true;
+}
+
+/// Overview of the local storage used by attachments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageBreakdown {
+    /// Total size of all stored blobs in bytes. Each blob is counted once,
+    /// no matter how many messages reference it.
+    pub total_size: u64,
+    /// Number of stored blobs.
+    pub blob_count: u64,
+    /// Number of bytes saved by storing shared blobs only once.
+    pub deduplicated_size: u64,
+    /// Size of blobs that are no longer referenced by any message. These are
+    /// removed by the next cleanup.
+    pub unreferenced_size: u64,
+    /// Storage used by each conversation. Blobs shared between
+    /// conversations count towards each of them.
+    pub conversations: Vec<ConversationStorage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversationStorage {
+    pub conversation_id: ConversationId,
+    /// Size of the stored messages in bytes
+    pub messages_size: u64,
+    /// Size of the attachments with media in bytes, without thumbnails
+    pub attachments_size: u64,
+    /// Size of the thumbnails with media in bytes
+    pub thumbnails_size: u64,
+    pub attachment_count: u64,
+}
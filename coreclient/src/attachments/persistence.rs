@@ -0,0 +1,107 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use rusqlite::{params, Connection};
+
+use crate::{utils::persistence::Storable, ConversationId, ConversationMessageId};
+
+use super::{AttachmentHash, MessageAttachment};
+
+/// Adds the column marking references whose media was deleted. The
+/// reference is kept as a stub, but no longer keeps the blob alive.
+pub(crate) const ATTACHMENT_MEDIA_DELETED_COLUMN: &str =
+    "ALTER TABLE attachment_references ADD COLUMN media_deleted_at TEXT;";
+
+/// Adds the column marking references to the thumbnail of an attachment
+/// rather than to the attachment itself.
+pub(crate) const ATTACHMENT_THUMBNAIL_COLUMN: &str =
+    "ALTER TABLE attachment_references ADD COLUMN thumbnail BOOLEAN NOT NULL DEFAULT FALSE;";
+
+/// Blobs keyed by the hash of their content.
+pub(crate) const ATTACHMENT_BLOBS_TABLE: &str = "
+        CREATE TABLE IF NOT EXISTS attachment_blobs (
+            content_hash BLOB PRIMARY KEY,
+            content BLOB NOT NULL,
+            size INTEGER NOT NULL,
+            created_at TEXT NOT NULL
+        );";
+
+/// View state of view-once attachments sent or received by this client.
+pub(crate) const VIEW_ONCE_ATTACHMENTS_TABLE: &str = "
+        CREATE TABLE IF NOT EXISTS view_once_attachments (
+            message_id BLOB PRIMARY KEY,
+            conversation_id BLOB NOT NULL,
+            mimi_id BLOB NOT NULL,
+            sent BOOLEAN NOT NULL,
+            viewed_at TEXT,
+            FOREIGN KEY (message_id) REFERENCES conversation_messages(message_id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS view_once_attachments_mimi_id
+            ON view_once_attachments (conversation_id, mimi_id);";
+
+pub(crate) struct AttachmentReference {
+    pub(crate) hash: AttachmentHash,
+    pub(crate) message_id: ConversationMessageId,
+    pub(crate) conversation_id: ConversationId,
+}
+
+impl Storable for AttachmentReference {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS attachment_references (
+            content_hash BLOB NOT NULL,
+            message_id BLOB NOT NULL,
+            conversation_id BLOB NOT NULL,
+            PRIMARY KEY (content_hash, message_id),
+            FOREIGN KEY (content_hash) REFERENCES attachment_blobs(content_hash) DEFERRABLE INITIALLY DEFERRED
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let hash = row.get(0)?;
+        let message_id = row.get(1)?;
+        let conversation_id = row.get(2)?;
+        Ok(Self {
+            hash,
+            message_id,
+            conversation_id,
+        })
+    }
+}
+
+impl AttachmentReference {
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR IGNORE INTO attachment_references (content_hash, message_id, conversation_id) VALUES (?, ?, ?)",
+            params![self.hash, self.message_id, self.conversation_id],
+        )?;
+        Ok(())
+    }
+
+    /// Store the reference as the reference to a thumbnail.
+    pub(crate) fn store_thumbnail(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR IGNORE INTO attachment_references (content_hash, message_id, conversation_id, thumbnail) VALUES (?, ?, ?, TRUE)",
+            params![self.hash, self.message_id, self.conversation_id],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load_all_of_message(
+        connection: &Connection,
+        message_id: ConversationMessageId,
+    ) -> Result<Vec<MessageAttachment>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT content_hash, media_deleted_at, thumbnail FROM attachment_references WHERE message_id = ?",
+        )?;
+        let attachments = statement
+            .query_map(params![message_id], |row| {
+                Ok(MessageAttachment {
+                    hash: row.get(0)?,
+                    media_deleted_at: row.get(1)?,
+                    thumbnail: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(attachments)
+    }
+}
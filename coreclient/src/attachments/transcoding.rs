@@ -0,0 +1,419 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Transcoding of media attachments before they are uploaded.
+//!
+//! Images are decoded, rotated according to their EXIF orientation, downscaled
+//! if they exceed the configured resolution and encoded again. Re-encoding
+//! drops all metadata of the original, e.g. the location the image was taken
+//! at. Sending an image in original quality skips the downscaling, but still
+//! re-encodes the image.
+//!
+//! Images keep their container where the format has features JPEG lacks: PNG
+//! for transparency, GIF for animation (every frame is downscaled and encoded
+//! again) and WebP. WebP images are not re-encoded, since only lossless still
+//! images can be encoded as WebP. Their metadata chunks are dropped instead.
+//!
+//! Videos are transcoded by a platform-backed [`MediaCodec`], since
+//! hardware-accelerated video encoders are only available through the
+//! platform APIs.
+
+use std::{future::Future, io::Cursor};
+
+use anyhow::{bail, Result};
+use exif::{In, Reader, Tag};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        jpeg::JpegEncoder,
+    },
+    imageops::{self, FilterType},
+    AnimationDecoder, DynamicImage, Frame, ImageFormat,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{user_settings::UserSetting, utils::rt};
+
+use super::AttachmentKind;
+
+/// Quality of images sent in original quality.
+const ORIGINAL_IMAGE_QUALITY: u8 = 100;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    Hevc,
+}
+
+/// Parameters passed to the [`MediaCodec`] to transcode a video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoTranscodeParams {
+    pub codec: VideoCodec,
+    /// Maximum size of the transcoded video in bytes. No limit if not set.
+    pub max_size: Option<u64>,
+    /// Maximum width and height of the transcoded video. No limit if not set.
+    pub max_dimension: Option<u32>,
+}
+
+/// Platform codec used to transcode videos.
+pub trait MediaCodec: Send + Sync {
+    /// Transcode the given video according to the given parameters. The
+    /// result must not contain any metadata of the original video.
+    fn transcode_video(
+        &self,
+        content: Vec<u8>,
+        params: VideoTranscodeParams,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send;
+}
+
+/// Codec for platforms that can't transcode videos. Videos are sent as they
+/// are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMediaCodec;
+
+impl MediaCodec for NoMediaCodec {
+    async fn transcode_video(
+        &self,
+        content: Vec<u8>,
+        _params: VideoTranscodeParams,
+    ) -> Result<Vec<u8>> {
+        Ok(content)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaTranscoding {
+    /// If disabled, attachments are sent as they are.
+    pub enabled: bool,
+    /// Images whose width or height exceeds this are downscaled.
+    pub max_image_dimension: u32,
+    /// JPEG quality of transcoded images
+    pub image_quality: u8,
+    pub video_codec: VideoCodec,
+    pub max_video_size: u64,
+    pub max_video_dimension: u32,
+}
+
+impl Default for MediaTranscoding {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_image_dimension: 2048,
+            image_quality: 85,
+            video_codec: VideoCodec::H264,
+            max_video_size: 16 * 1024 * 1024,
+            max_video_dimension: 1280,
+        }
+    }
+}
+
+impl UserSetting for MediaTranscoding {
+    const KEY: &'static str = "media_transcoding";
+}
+
+impl MediaTranscoding {
+    /// Transcode the given attachment content before it is uploaded.
+    ///
+    /// Attachments other than images and videos are returned as they are.
+    pub async fn transcode(
+        &self,
+        codec: &impl MediaCodec,
+        kind: AttachmentKind,
+        content: Vec<u8>,
+        original_quality: bool,
+    ) -> Result<Vec<u8>> {
+        if !self.enabled {
+            return Ok(content);
+        }
+        match kind {
+            AttachmentKind::Image => {
+                let max_dimension = (!original_quality).then_some(self.max_image_dimension);
+                let quality = if original_quality {
+                    ORIGINAL_IMAGE_QUALITY
+                } else {
+                    self.image_quality
+                };
+                rt::spawn_blocking(move || transcode_image(&content, max_dimension, quality))
+                    .await?
+            }
+            AttachmentKind::Video => {
+                let params = VideoTranscodeParams {
+                    codec: self.video_codec,
+                    max_size: (!original_quality).then_some(self.max_video_size),
+                    max_dimension: (!original_quality).then_some(self.max_video_dimension),
+                };
+                let transcoded = codec.transcode_video(content, params).await?;
+                if let Some(max_size) = params.max_size {
+                    if transcoded.len() as u64 > max_size {
+                        bail!(
+                            "Transcoded video exceeds the maximum size: {} > {max_size}",
+                            transcoded.len()
+                        );
+                    }
+                }
+                Ok(transcoded)
+            }
+            AttachmentKind::Audio | AttachmentKind::File => Ok(content),
+        }
+    }
+}
+
+/// Re-encode the given image, downscaling it to fit into `max_dimension` if
+/// set. PNG and GIF images keep their format to keep their transparency and
+/// animation, WebP images only lose their metadata (see the module docs); all
+/// other formats are encoded as JPEG.
+pub(crate) fn transcode_image(
+    content: &[u8],
+    max_dimension: Option<u32>,
+    quality: u8,
+) -> Result<Vec<u8>> {
+    let format = image::guess_format(content)?;
+    let buf = match format {
+        ImageFormat::Gif => transcode_gif(content, max_dimension)?,
+        ImageFormat::WebP => strip_webp_metadata(content)?,
+        _ => {
+            let image = image::load_from_memory_with_format(content, format)?;
+            let image = apply_orientation(image, content);
+            let image = match max_dimension {
+                Some(max) if image.width() > max || image.height() > max => {
+                    image.resize(max, max, FilterType::Triangle)
+                }
+                _ => image,
+            };
+
+            let mut buf = Vec::new();
+            let mut cursor = Cursor::new(&mut buf);
+            if format == ImageFormat::Png {
+                image.write_to(&mut cursor, ImageFormat::Png)?;
+            } else {
+                // JPEG doesn't support an alpha channel
+                let image = DynamicImage::ImageRgb8(image.into_rgb8());
+                JpegEncoder::new_with_quality(&mut cursor, quality).encode_image(&image)?;
+            }
+            buf
+        }
+    };
+    log::info!(
+        "Transcoded image from {} to {} bytes",
+        content.len(),
+        buf.len()
+    );
+    Ok(buf)
+}
+
+/// Re-encode all frames of the given GIF, downscaling them to fit into
+/// `max_dimension` if set.
+fn transcode_gif(content: &[u8], max_dimension: Option<u32>) -> Result<Vec<u8>> {
+    let frames = GifDecoder::new(Cursor::new(content))?
+        .into_frames()
+        .collect_frames()?;
+    let animated = frames.len() > 1;
+    // The decoder composes every frame onto the full canvas.
+    let frames = frames.into_iter().map(|frame| {
+        let delay = frame.delay();
+        let buffer = frame.into_buffer();
+        let (width, height) = buffer.dimensions();
+        let buffer = match max_dimension {
+            Some(max) if width > max || height > max => {
+                let scale = f64::from(max) / f64::from(width.max(height));
+                let width = ((f64::from(width) * scale).round() as u32).max(1);
+                let height = ((f64::from(height) * scale).round() as u32).max(1);
+                imageops::resize(&buffer, width, height, FilterType::Triangle)
+            }
+            _ => buffer,
+        };
+        Frame::from_parts(buffer, 0, 0, delay)
+    });
+
+    let mut buf = Vec::new();
+    {
+        // The encoder writes the trailer of the GIF when it is dropped.
+        let mut encoder = GifEncoder::new(&mut buf);
+        if animated {
+            encoder.set_repeat(Repeat::Infinite)?;
+        }
+        encoder.encode_frames(frames)?;
+    }
+    Ok(buf)
+}
+
+/// Flags of the VP8X chunk that announce EXIF and XMP chunks.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Drop the EXIF and XMP chunks of the given WebP image and clear their flags
+/// in the VP8X chunk. All other chunks, e.g. the frames of animations, are
+/// kept as they are.
+fn strip_webp_metadata(content: &[u8]) -> Result<Vec<u8>> {
+    if content.len() < 12 || &content[..4] != b"RIFF" || &content[8..12] != b"WEBP" {
+        bail!("Invalid WebP header");
+    }
+    let mut buf = content[..12].to_vec();
+    let mut rest = &content[12..];
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            bail!("Truncated WebP chunk header");
+        }
+        let fourcc = &rest[..4];
+        let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        // Chunks are padded to an even size
+        let padded_size = size + (size & 1);
+        if rest.len() - 8 < size {
+            bail!("Truncated WebP chunk");
+        }
+        let chunk_len = (8 + padded_size).min(rest.len());
+        let (chunk, tail) = rest.split_at(chunk_len);
+        rest = tail;
+        match fourcc {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if size > 0 => {
+                let flags_offset = buf.len() + 8;
+                buf.extend_from_slice(chunk);
+                buf[flags_offset] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+            _ => buf.extend_from_slice(chunk),
+        }
+    }
+    let riff_size = u32::try_from(buf.len() - 8)?;
+    buf[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(buf)
+}
+
+/// Rotate/flip the image according to its EXIF orientation, since the
+/// orientation is dropped when re-encoding.
+fn apply_orientation(image: DynamicImage, content: &[u8]) -> DynamicImage {
+    let orientation = Reader::new()
+        .read_from_container(&mut Cursor::new(content))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1);
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        codecs::webp::WebPEncoder, Delay, ExtendedColorType, GenericImageView, RgbImage, Rgba,
+        RgbaImage,
+    };
+
+    use super::*;
+
+    fn jpeg_with_exif(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+        let mut jpeg = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        // Insert an APP1 segment with EXIF data after the SOI marker
+        let payload = b"Exif\0\0secret location";
+        let length = (payload.len() + 2) as u16;
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&length.to_be_bytes());
+        segment.extend_from_slice(payload);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn images_are_downscaled_and_stripped() {
+        let content = jpeg_with_exif(4000, 1000);
+        let settings = MediaTranscoding::default();
+
+        let transcoded = transcode_image(&content, Some(settings.max_image_dimension), 85).unwrap();
+        let image = image::load_from_memory(&transcoded).unwrap();
+        assert_eq!(image.dimensions(), (2048, 512));
+        assert!(!contains_secret(&transcoded));
+
+        // Original quality keeps the resolution but still strips metadata
+        let original = transcode_image(&content, None, ORIGINAL_IMAGE_QUALITY).unwrap();
+        let image = image::load_from_memory(&original).unwrap();
+        assert_eq!(image.dimensions(), (4000, 1000));
+        assert!(!contains_secret(&original));
+    }
+
+    fn contains_secret(content: &[u8]) -> bool {
+        content
+            .windows(b"secret".len())
+            .any(|window| window == b"secret")
+    }
+
+    #[test]
+    fn gifs_stay_animated() {
+        let mut content = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut content);
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+            let frames = [Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])].map(|color| {
+                Frame::from_parts(
+                    RgbaImage::from_pixel(4000, 1000, color),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                )
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+
+        let transcoded = transcode_image(&content, Some(2048), 85).unwrap();
+        assert_eq!(image::guess_format(&transcoded).unwrap(), ImageFormat::Gif);
+        let frames = GifDecoder::new(Cursor::new(&transcoded))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        for frame in &frames {
+            assert_eq!(frame.buffer().dimensions(), (2048, 512));
+        }
+    }
+
+    #[test]
+    fn webp_images_keep_their_container() {
+        let (width, height) = (64u32, 32u32);
+        let pixels = RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 128]));
+        let mut lossless = Vec::new();
+        WebPEncoder::new_lossless(&mut lossless)
+            .encode(&pixels, width, height, ExtendedColorType::Rgba8)
+            .unwrap();
+
+        // Extended WebP with an alpha channel and an EXIF chunk
+        let mut vp8x = vec![0x10 | WEBP_EXIF_FLAG, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        let exif = b"secret location";
+        let mut content = b"RIFF\0\0\0\0WEBP".to_vec();
+        content.extend_from_slice(b"VP8X");
+        content.extend_from_slice(&(vp8x.len() as u32).to_le_bytes());
+        content.extend_from_slice(&vp8x);
+        content.extend_from_slice(&lossless[12..]);
+        content.extend_from_slice(b"EXIF");
+        content.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+        content.extend_from_slice(exif);
+        content.push(0);
+        let riff_size = (content.len() - 8) as u32;
+        content[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        assert!(contains_secret(&content));
+
+        let transcoded = transcode_image(&content, Some(16), 85).unwrap();
+        assert_eq!(image::guess_format(&transcoded).unwrap(), ImageFormat::WebP);
+        assert!(!contains_secret(&transcoded));
+        let image = image::load_from_memory(&transcoded).unwrap();
+        assert_eq!(image.dimensions(), (width, height));
+        assert!(image.color().has_alpha());
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! View-once attachments.
+//!
+//! The content of a view-once attachment stays on the DS and is downloaded
+//! when the user opens it. It is never written to the shared attachment
+//! store, such that it can't be referenced by other messages. After the first
+//! successful view, the attachment is marked as viewed, is rendered as a
+//! placeholder and can't be opened again. The sender learns about the view
+//! through a receipt.
+//!
+//! Both limits are enforced by the clients only. The UI is expected to show
+//! view-once media with screenshot protection enabled.
+
+use phnxtypes::time::TimeStamp;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::{utils::persistence::Storable, ConversationId, ConversationMessageId};
+
+use super::persistence::VIEW_ONCE_ATTACHMENTS_TABLE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewOnceState {
+    Unviewed,
+    Viewed(TimeStamp),
+}
+
+/// View state of a view-once attachment sent or received by this client.
+///
+/// Received attachments only get a record when they are viewed. Sent
+/// attachments get one when they are sent, which is updated when a receipt
+/// from one of the recipients arrives.
+pub(crate) struct ViewOnceRecord {
+    message_id: ConversationMessageId,
+    conversation_id: ConversationId,
+    mimi_id: Uuid,
+    sent: bool,
+    viewed_at: Option<TimeStamp>,
+}
+
+impl Storable for ViewOnceRecord {
+    const CREATE_TABLE_STATEMENT: &'static str = VIEW_ONCE_ATTACHMENTS_TABLE;
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            message_id: row.get(0)?,
+            conversation_id: row.get(1)?,
+            mimi_id: row.get(2)?,
+            sent: row.get(3)?,
+            viewed_at: row.get(4)?,
+        })
+    }
+}
+
+impl ViewOnceRecord {
+    pub(crate) fn new(
+        message_id: ConversationMessageId,
+        conversation_id: ConversationId,
+        mimi_id: Uuid,
+        sent: bool,
+        viewed_at: Option<TimeStamp>,
+    ) -> Self {
+        Self {
+            message_id,
+            conversation_id,
+            mimi_id,
+            sent,
+            viewed_at,
+        }
+    }
+
+    pub(crate) fn state(&self) -> ViewOnceState {
+        match self.viewed_at {
+            Some(viewed_at) => ViewOnceState::Viewed(viewed_at),
+            None => ViewOnceState::Unviewed,
+        }
+    }
+
+    /// Store the record unless one for the same message already exists.
+    /// Returns `false` if the record already existed.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<bool, rusqlite::Error> {
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO view_once_attachments
+                (message_id, conversation_id, mimi_id, sent, viewed_at)
+            VALUES (?, ?, ?, ?, ?)",
+            params![
+                self.message_id,
+                self.conversation_id,
+                self.mimi_id,
+                self.sent,
+                self.viewed_at
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    pub(crate) fn load(
+        connection: &Connection,
+        message_id: ConversationMessageId,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT message_id, conversation_id, mimi_id, sent, viewed_at
+            FROM view_once_attachments WHERE message_id = ?",
+        )?;
+        statement
+            .query_row(params![message_id], Self::from_row)
+            .optional()
+    }
+
+    /// Mark the attachments sent by this client with the given MIMI ids as
+    /// viewed. Attachments that were already viewed keep their original view
+    /// time.
+    ///
+    /// Returns the number of updated attachments.
+    pub(crate) fn mark_sent_as_viewed(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        mimi_ids: &[Uuid],
+        viewed_at: TimeStamp,
+    ) -> Result<usize, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "UPDATE view_once_attachments SET viewed_at = ?
+            WHERE conversation_id = ? AND mimi_id = ? AND sent = 1 AND viewed_at IS NULL",
+        )?;
+        let mut updated = 0;
+        for mimi_id in mimi_ids {
+            updated += statement.execute(params![viewed_at, conversation_id, mimi_id])?;
+        }
+        Ok(updated)
+    }
+}
@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Access to crate internals for the benchmarks in `benches/`.
+//!
+//! Only available with the `bench` feature. Not part of the public API.
+
+use anyhow::Result;
+use openmls::prelude::GroupId;
+use phnxtypes::identifiers::{Fqdn, QualifiedGroupId};
+use rusqlite::Connection;
+use uuid::Uuid;
+
+use crate::{
+    conversations::{messages::ConversationMessage, Conversation, ConversationAttributes},
+    utils::migration::run_migrations,
+    ConversationId, MimiContent,
+};
+
+pub use crate::groups::DEFAULT_CIPHERSUITE;
+
+/// In-memory client database with a single conversation.
+pub struct MessageStore {
+    connection: Connection,
+    conversation_id: ConversationId,
+    domain: Fqdn,
+}
+
+impl MessageStore {
+    pub fn new() -> Result<Self> {
+        let mut connection = Connection::open_in_memory()?;
+        run_migrations(&mut connection)?;
+        let domain = Fqdn::try_from("example.com")?;
+        let group_id = GroupId::from(QualifiedGroupId::new(Uuid::new_v4(), domain.clone()));
+        let conversation = Conversation::new_group_conversation(
+            group_id,
+            ConversationAttributes::new("Benchmark".to_owned(), None),
+        );
+        conversation.store(&connection)?;
+        Ok(Self {
+            connection,
+            conversation_id: conversation.id(),
+            domain,
+        })
+    }
+
+    /// Insert `count` text messages into the conversation in a single
+    /// transaction, as is done for a batch of messages fetched from the queue.
+    pub fn insert_messages(&mut self, count: usize) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        for i in 0..count {
+            let content =
+                MimiContent::simple_markdown_message(self.domain.clone(), format!("Message {i}"));
+            ConversationMessage::new_unsent_message(
+                "alice@example.com".to_owned(),
+                self.conversation_id,
+                content,
+            )
+            .store(&transaction)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
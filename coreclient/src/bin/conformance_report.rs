@@ -0,0 +1,36 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Checks the MIMI content encoding and the room policy decisions against a
+//! vector file published by another MIMI implementation and prints a
+//! conformance report.
+//!
+//! Usage: `conformance-report VECTOR_FILE`. Exits with a failure if our
+//! behavior diverges from any vector.
+
+use std::process::ExitCode;
+
+use phnxcoreclient::mimi_interop::InteropVectors;
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("Usage: conformance-report VECTOR_FILE");
+        return ExitCode::FAILURE;
+    };
+    let vectors = match InteropVectors::load(&path) {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let report = vectors.check();
+    println!("{report}");
+    if report.is_conformant() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,57 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Compares the trust stores exported by two devices and prints the
+//! differences.
+//!
+//! Usage: `trust-store-diff THIS_EXPORT OTHER_EXPORT`. The signature of each
+//! export is checked against the credential of the client that exported it,
+//! but that credential isn't verified against the AS. Exits with a failure if
+//! an export is invalid or the trust stores differ.
+
+use std::process::ExitCode;
+
+use phnxcoreclient::trust_store::{SignedTrustStore, TrustStore};
+
+fn load(path: &str) -> Result<TrustStore, String> {
+    let export = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    SignedTrustStore::from_json(&export)
+        .and_then(SignedTrustStore::verify_self_signed)
+        .map_err(|e| format!("{path}: {e}"))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [this_path, other_path] = args.as_slice() else {
+        eprintln!("Usage: trust-store-diff THIS_EXPORT OTHER_EXPORT");
+        return ExitCode::FAILURE;
+    };
+    let (this, other) = match (load(this_path), load(other_path)) {
+        (Ok(this), Ok(other)) => (this, other),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if this.user_name != other.user_name {
+        eprintln!(
+            "The exports belong to different users: {} and {}",
+            this.user_name, other.user_name
+        );
+        return ExitCode::FAILURE;
+    }
+
+    println!("This export: {} at {}", this.client_id, *this.exported_at);
+    println!(
+        "Other export: {} at {}",
+        other.client_id, *other.exported_at
+    );
+    let diff = this.diff(&other);
+    println!("{diff}");
+    if diff.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,198 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Broadcast lists, i.e. named sets of contacts that receive the same message
+//! in their 1:1 conversations.
+//!
+//! Broadcast lists only exist on the client. Recipients don't learn about the
+//! list and receive a regular message in their connection conversation. The
+//! delivery to each recipient is tracked individually, such that failed
+//! deliveries can be shown and retried.
+
+use phnxtypes::{crypto::rng, identifiers::QualifiedUserName, time::TimeStamp};
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    ToSql,
+};
+use uuid::Uuid;
+
+use crate::{ConversationId, ConversationMessageId};
+
+pub(crate) mod persistence;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BroadcastListId(Uuid);
+
+impl BroadcastListId {
+    pub(crate) fn random() -> Self {
+        Self(rng::random_uuid())
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for BroadcastListId {
+    fn from(value: Uuid) -> Self {
+        Self(value)
+    }
+}
+
+impl ToSql for BroadcastListId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for BroadcastListId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Uuid::column_result(value).map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastList {
+    id: BroadcastListId,
+    name: String,
+    recipients: Vec<QualifiedUserName>,
+    created_at: TimeStamp,
+}
+
+impl BroadcastList {
+    pub(crate) fn new(name: String, recipients: Vec<QualifiedUserName>) -> Self {
+        Self {
+            id: BroadcastListId::random(),
+            name,
+            recipients,
+            created_at: TimeStamp::now(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, name: String, recipients: Vec<QualifiedUserName>) {
+        self.name = name;
+        self.recipients = recipients;
+    }
+
+    pub fn id(&self) -> BroadcastListId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn recipients(&self) -> &[QualifiedUserName] {
+        &self.recipients
+    }
+
+    pub fn created_at(&self) -> TimeStamp {
+        self.created_at
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastDeliveryStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+impl ToSql for BroadcastDeliveryStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let status = match self {
+            Self::Pending => "pending",
+            Self::Sent => "sent",
+            Self::Failed => "failed",
+        };
+        Ok(ToSqlOutput::Owned(Value::Text(status.to_owned())))
+    }
+}
+
+impl FromSql for BroadcastDeliveryStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "pending" => Ok(Self::Pending),
+            "sent" => Ok(Self::Sent),
+            "failed" => Ok(Self::Failed),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Delivery of a broadcast to a single recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastDelivery {
+    broadcast_id: Uuid,
+    list_id: BroadcastListId,
+    recipient: QualifiedUserName,
+    /// The message in the connection conversation with the recipient. `None`
+    /// if the recipient is no longer a contact.
+    conversation_id: Option<ConversationId>,
+    message_id: Option<ConversationMessageId>,
+    status: BroadcastDeliveryStatus,
+    updated_at: TimeStamp,
+}
+
+impl BroadcastDelivery {
+    pub(crate) fn pending(
+        broadcast_id: Uuid,
+        list_id: BroadcastListId,
+        recipient: QualifiedUserName,
+    ) -> Self {
+        Self {
+            broadcast_id,
+            list_id,
+            recipient,
+            conversation_id: None,
+            message_id: None,
+            status: BroadcastDeliveryStatus::Pending,
+            updated_at: TimeStamp::now(),
+        }
+    }
+
+    pub(crate) fn set_conversation_id(&mut self, conversation_id: ConversationId) {
+        self.conversation_id = Some(conversation_id);
+    }
+
+    pub(crate) fn mark_sent(&mut self, message_id: ConversationMessageId) {
+        self.message_id = Some(message_id);
+        self.status = BroadcastDeliveryStatus::Sent;
+        self.updated_at = TimeStamp::now();
+    }
+
+    pub(crate) fn mark_failed(&mut self) {
+        self.status = BroadcastDeliveryStatus::Failed;
+        self.updated_at = TimeStamp::now();
+    }
+
+    /// Id shared by the deliveries of the same broadcast.
+    pub fn broadcast_id(&self) -> Uuid {
+        self.broadcast_id
+    }
+
+    pub fn list_id(&self) -> BroadcastListId {
+        self.list_id
+    }
+
+    pub fn recipient(&self) -> &QualifiedUserName {
+        &self.recipient
+    }
+
+    pub fn conversation_id(&self) -> Option<ConversationId> {
+        self.conversation_id
+    }
+
+    pub fn message_id(&self) -> Option<ConversationMessageId> {
+        self.message_id
+    }
+
+    pub fn status(&self) -> BroadcastDeliveryStatus {
+        self.status
+    }
+
+    pub fn updated_at(&self) -> TimeStamp {
+        self.updated_at
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use phnxtypes::identifiers::QualifiedUserName;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::utils::persistence::Storable;
+
+use super::{BroadcastDelivery, BroadcastList, BroadcastListId};
+
+/// Recipients of broadcast lists. Kept in their own table, such that a list
+/// can have any number of recipients.
+pub(crate) const BROADCAST_LIST_RECIPIENTS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS broadcast_list_recipients (
+        list_id BLOB NOT NULL,
+        user_name TEXT NOT NULL,
+        PRIMARY KEY (list_id, user_name)
+    );";
+
+impl Storable for BroadcastList {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS broadcast_lists (
+            list_id BLOB PRIMARY KEY,
+            name TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );";
+
+    /// Recipients are loaded separately.
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            recipients: Vec::new(),
+            created_at: row.get(2)?,
+        })
+    }
+}
+
+impl BroadcastList {
+    /// Store the list, replacing the name and recipients of an existing list
+    /// with the same id.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO broadcast_lists (list_id, name, created_at) VALUES (?, ?, ?)",
+            params![self.id, self.name, self.created_at],
+        )?;
+        connection.execute(
+            "DELETE FROM broadcast_list_recipients WHERE list_id = ?",
+            params![self.id],
+        )?;
+        let mut statement = connection.prepare(
+            "INSERT OR IGNORE INTO broadcast_list_recipients (list_id, user_name) VALUES (?, ?)",
+        )?;
+        for recipient in &self.recipients {
+            statement.execute(params![self.id, recipient])?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load(
+        connection: &Connection,
+        list_id: BroadcastListId,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        let list = connection
+            .query_row(
+                "SELECT list_id, name, created_at FROM broadcast_lists WHERE list_id = ?",
+                params![list_id],
+                Self::from_row,
+            )
+            .optional()?;
+        list.map(|list| list.with_recipients(connection))
+            .transpose()
+    }
+
+    pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection
+            .prepare("SELECT list_id, name, created_at FROM broadcast_lists ORDER BY name")?;
+        let lists = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        lists
+            .into_iter()
+            .map(|list| list.with_recipients(connection))
+            .collect()
+    }
+
+    fn with_recipients(mut self, connection: &Connection) -> Result<Self, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT user_name FROM broadcast_list_recipients WHERE list_id = ? ORDER BY user_name",
+        )?;
+        self.recipients = statement
+            .query_map(params![self.id], |row| row.get::<_, QualifiedUserName>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self)
+    }
+
+    /// Delete the list with the given id together with its recipients and
+    /// the delivery records of its broadcasts. The sent messages are kept.
+    pub(crate) fn delete(
+        connection: &Connection,
+        list_id: BroadcastListId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM broadcast_deliveries WHERE list_id = ?",
+            params![list_id],
+        )?;
+        connection.execute(
+            "DELETE FROM broadcast_list_recipients WHERE list_id = ?",
+            params![list_id],
+        )?;
+        connection.execute(
+            "DELETE FROM broadcast_lists WHERE list_id = ?",
+            params![list_id],
+        )?;
+        Ok(())
+    }
+}
+
+impl Storable for BroadcastDelivery {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS broadcast_deliveries (
+            broadcast_id BLOB NOT NULL,
+            list_id BLOB NOT NULL,
+            recipient TEXT NOT NULL,
+            conversation_id BLOB,
+            message_id BLOB,
+            status TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            PRIMARY KEY (broadcast_id, recipient)
+        );
+        CREATE INDEX IF NOT EXISTS broadcast_deliveries_list_id
+            ON broadcast_deliveries (list_id, updated_at);";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            broadcast_id: row.get(0)?,
+            list_id: row.get(1)?,
+            recipient: row.get(2)?,
+            conversation_id: row.get(3)?,
+            message_id: row.get(4)?,
+            status: row.get(5)?,
+            updated_at: row.get(6)?,
+        })
+    }
+}
+
+impl BroadcastDelivery {
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO broadcast_deliveries
+                (broadcast_id, list_id, recipient, conversation_id, message_id, status, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                self.broadcast_id,
+                self.list_id,
+                self.recipient,
+                self.conversation_id,
+                self.message_id,
+                self.status,
+                self.updated_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load_broadcast(
+        connection: &Connection,
+        broadcast_id: Uuid,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT broadcast_id, list_id, recipient, conversation_id, message_id, status, updated_at
+            FROM broadcast_deliveries WHERE broadcast_id = ? ORDER BY recipient",
+        )?;
+        let deliveries = statement
+            .query_map(params![broadcast_id], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(deliveries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use crate::utils::migration::run_migrations;
+
+    use super::*;
+
+    #[test]
+    fn lists_are_stored_with_recipients() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let mut list = BroadcastList::new("Friends".to_owned(), vec![bob.clone(), alice.clone()]);
+        list.store(&connection).unwrap();
+
+        let loaded = BroadcastList::load(&connection, list.id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.name(), "Friends");
+        assert_eq!(loaded.recipients(), &[alice.clone(), bob]);
+
+        list.update("Family".to_owned(), vec![alice.clone()]);
+        list.store(&connection).unwrap();
+        let lists = BroadcastList::load_all(&connection).unwrap();
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[0].recipients(), &[alice]);
+
+        BroadcastList::delete(&connection, list.id()).unwrap();
+        assert!(BroadcastList::load(&connection, list.id())
+            .unwrap()
+            .is_none());
+    }
+}
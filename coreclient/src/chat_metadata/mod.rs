@@ -0,0 +1,259 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Custom metadata of chats, e.g. their color.
+//!
+//! Metadata entries are key-value pairs stored per conversation. Keys are
+//! namespaced: entries in the [`SHARED_NAMESPACE`] are shared with the other
+//! members via the group data of the conversation, entries in all other
+//! namespaces are only stored locally. Apps can keep their own entries in a
+//! namespace of their choice.
+//!
+//! Shared keys must be known and their values valid, such that members can't
+//! push arbitrary data to each other.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use thiserror::Error;
+
+pub(crate) mod persistence;
+
+/// Namespace of the entries that are shared with the other members.
+pub const SHARED_NAMESPACE: &str = "shared";
+/// Maximum length of namespaces and key names in bytes.
+pub const MAX_KEY_LENGTH: usize = 64;
+/// Maximum size of a value in bytes.
+pub const MAX_VALUE_SIZE: usize = 4 * 1024;
+
+const COLOR_KEY: &str = "color";
+const EMOJI_KEY: &str = "emoji";
+/// Emoji with modifiers and joiners take up to a few dozen bytes.
+const MAX_EMOJI_SIZE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ChatMetadataError {
+    #[error("Invalid metadata key {0:?}")]
+    InvalidKey(String),
+    #[error("Unknown shared metadata key {0}")]
+    UnknownSharedKey(ChatMetadataKey),
+    #[error("Invalid value for metadata key {0}")]
+    InvalidValue(ChatMetadataKey),
+    #[error("Metadata value of {size} bytes exceeds the maximum of {max} bytes")]
+    ValueTooLarge { size: usize, max: usize },
+}
+
+/// Namespaced key of a metadata entry.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChatMetadataKey {
+    namespace: String,
+    name: String,
+}
+
+impl ChatMetadataKey {
+    /// Namespaces and names consist of 1 to [`MAX_KEY_LENGTH`] lowercase
+    /// ASCII letters, digits, `_` and `-`.
+    pub fn new(
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Result<Self, ChatMetadataError> {
+        let key = Self {
+            namespace: namespace.into(),
+            name: name.into(),
+        };
+        if !is_valid_key_part(&key.namespace) || !is_valid_key_part(&key.name) {
+            return Err(ChatMetadataError::InvalidKey(key.to_string()));
+        }
+        Ok(key)
+    }
+
+    /// Shared color of the chat, see [`ChatColor`]
+    pub fn color() -> Self {
+        Self {
+            namespace: SHARED_NAMESPACE.to_owned(),
+            name: COLOR_KEY.to_owned(),
+        }
+    }
+
+    /// Shared emoji of the chat
+    pub fn emoji() -> Self {
+        Self {
+            namespace: SHARED_NAMESPACE.to_owned(),
+            name: EMOJI_KEY.to_owned(),
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if the entry is shared with the other members.
+    pub fn is_shared(&self) -> bool {
+        self.namespace == SHARED_NAMESPACE
+    }
+}
+
+fn is_valid_key_part(part: &str) -> bool {
+    !part.is_empty()
+        && part.len() <= MAX_KEY_LENGTH
+        && part
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+}
+
+impl fmt::Display for ChatMetadataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.namespace, self.name)
+    }
+}
+
+/// Color of a chat in RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChatColor(u32);
+
+impl ChatColor {
+    /// Returns `None` if the value doesn't fit into 24 bits.
+    pub fn from_rgb(rgb: u32) -> Option<Self> {
+        (rgb <= 0x00ff_ffff).then_some(Self(rgb))
+    }
+
+    pub fn rgb(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Formats the color as `#rrggbb`.
+impl fmt::Display for ChatColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:06x}", self.0)
+    }
+}
+
+impl FromStr for ChatColor {
+    type Err = ChatMetadataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .map(Self)
+            .ok_or_else(|| ChatMetadataError::InvalidValue(ChatMetadataKey::color()))
+    }
+}
+
+fn is_valid_emoji(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_EMOJI_SIZE
+        && value.chars().any(|c| !c.is_ascii())
+        && !value.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Check that the value can be stored under the given key.
+pub(crate) fn validate(key: &ChatMetadataKey, value: &str) -> Result<(), ChatMetadataError> {
+    if value.len() > MAX_VALUE_SIZE {
+        return Err(ChatMetadataError::ValueTooLarge {
+            size: value.len(),
+            max: MAX_VALUE_SIZE,
+        });
+    }
+    if !key.is_shared() {
+        return Ok(());
+    }
+    let valid = match key.name() {
+        COLOR_KEY => value.parse::<ChatColor>().is_ok(),
+        EMOJI_KEY => is_valid_emoji(value),
+        _ => return Err(ChatMetadataError::UnknownSharedKey(key.clone())),
+    };
+    if !valid {
+        return Err(ChatMetadataError::InvalidValue(key.clone()));
+    }
+    Ok(())
+}
+
+/// The shared entries as stored in the group data, keyed by name.
+pub(crate) type SharedChatMetadata = BTreeMap<String, String>;
+
+/// Check the shared entries of a group data extension.
+pub(crate) fn validate_shared(shared: &SharedChatMetadata) -> Result<(), ChatMetadataError> {
+    for (name, value) in shared {
+        let key = ChatMetadataKey::new(SHARED_NAMESPACE, name.clone())?;
+        validate(&key, value)?;
+    }
+    Ok(())
+}
+
+/// All metadata entries of a chat.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatMetadata {
+    entries: BTreeMap<ChatMetadataKey, String>,
+}
+
+impl ChatMetadata {
+    pub fn get(&self, key: &ChatMetadataKey) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn color(&self) -> Option<ChatColor> {
+        self.get(&ChatMetadataKey::color())?.parse().ok()
+    }
+
+    pub fn emoji(&self) -> Option<&str> {
+        self.get(&ChatMetadataKey::emoji())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ChatMetadataKey, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key, value.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_validated() {
+        assert!(ChatMetadataKey::new("app", "wallpaper").is_ok());
+        assert!(ChatMetadataKey::new("app", "").is_err());
+        assert!(ChatMetadataKey::new("App", "wallpaper").is_err());
+        assert!(ChatMetadataKey::new("app", "wall.paper").is_err());
+        assert!(ChatMetadataKey::new("app", "x".repeat(MAX_KEY_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn shared_values_are_validated() {
+        let color = ChatMetadataKey::color();
+        assert_eq!(validate(&color, "#1a2b3c"), Ok(()));
+        assert!(validate(&color, "1a2b3c").is_err());
+        assert!(validate(&color, "#1a2b3").is_err());
+
+        let emoji = ChatMetadataKey::emoji();
+        assert_eq!(validate(&emoji, "🔥"), Ok(()));
+        assert_eq!(validate(&emoji, "👩‍👩‍👧"), Ok(()));
+        assert!(validate(&emoji, "fire").is_err());
+        assert!(validate(&emoji, "🔥 🔥").is_err());
+
+        let unknown = ChatMetadataKey::new(SHARED_NAMESPACE, "wallpaper").unwrap();
+        assert_eq!(
+            validate(&unknown, "x"),
+            Err(ChatMetadataError::UnknownSharedKey(unknown.clone()))
+        );
+        // Local entries can hold anything up to the maximum size.
+        let local = ChatMetadataKey::new("app", "wallpaper").unwrap();
+        assert_eq!(validate(&local, "x"), Ok(()));
+        assert!(validate(&local, &"x".repeat(MAX_VALUE_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn color_roundtrip() {
+        let color = ChatColor::from_rgb(0x00ff80).unwrap();
+        assert_eq!(color.to_string(), "#00ff80");
+        assert_eq!("#00FF80".parse::<ChatColor>().unwrap(), color);
+        assert!(ChatColor::from_rgb(0x0100_0000).is_none());
+    }
+}
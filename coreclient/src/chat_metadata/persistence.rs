@@ -0,0 +1,123 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use rusqlite::{params, Connection};
+
+use crate::ConversationId;
+
+use super::{ChatMetadata, ChatMetadataKey, SharedChatMetadata, SHARED_NAMESPACE};
+
+/// The entries are keyed by conversation id instead of referencing the
+/// conversations table, because conversations are replaced when a group is
+/// rejoined.
+pub(crate) const CHAT_METADATA_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS chat_metadata (
+        conversation_id BLOB NOT NULL,
+        namespace TEXT NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (conversation_id, namespace, name)
+    );";
+
+impl ChatMetadata {
+    pub(crate) fn load(
+        connection: &Connection,
+        conversation_id: ConversationId,
+    ) -> Result<Self, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT namespace, name, value FROM chat_metadata WHERE conversation_id = ?",
+        )?;
+        let entries = statement
+            .query_map(params![conversation_id], |row| {
+                let key = ChatMetadataKey {
+                    namespace: row.get(0)?,
+                    name: row.get(1)?,
+                };
+                Ok((key, row.get(2)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+
+    /// Store the value of the given entry. Removes the entry if the value is
+    /// `None`.
+    pub(crate) fn store_entry(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        key: &ChatMetadataKey,
+        value: Option<&str>,
+    ) -> Result<(), rusqlite::Error> {
+        match value {
+            Some(value) => connection.execute(
+                "INSERT OR REPLACE INTO chat_metadata (conversation_id, namespace, name, value)
+                VALUES (?, ?, ?, ?)",
+                params![conversation_id, key.namespace, key.name, value],
+            )?,
+            None => connection.execute(
+                "DELETE FROM chat_metadata
+                WHERE conversation_id = ? AND namespace = ? AND name = ?",
+                params![conversation_id, key.namespace, key.name],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Replace the shared entries with the ones from the group data.
+    pub(crate) fn store_shared(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        shared: &SharedChatMetadata,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM chat_metadata WHERE conversation_id = ? AND namespace = ?",
+            params![conversation_id, SHARED_NAMESPACE],
+        )?;
+        for (name, value) in shared {
+            connection.execute(
+                "INSERT INTO chat_metadata (conversation_id, namespace, name, value)
+                VALUES (?, ?, ?, ?)",
+                params![conversation_id, SHARED_NAMESPACE, name, value],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::utils::migration::run_migrations;
+
+    use super::*;
+
+    #[test]
+    fn shared_entries_are_replaced() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+
+        let local = ChatMetadataKey::new("app", "wallpaper").unwrap();
+        ChatMetadata::store_entry(&connection, conversation_id, &local, Some("beach")).unwrap();
+        let shared = SharedChatMetadata::from([
+            ("color".to_owned(), "#ff0000".to_owned()),
+            ("emoji".to_owned(), "🔥".to_owned()),
+        ]);
+        ChatMetadata::store_shared(&connection, conversation_id, &shared).unwrap();
+
+        let metadata = ChatMetadata::load(&connection, conversation_id).unwrap();
+        assert_eq!(metadata.get(&local), Some("beach"));
+        assert_eq!(metadata.color().unwrap().rgb(), 0xff0000);
+        assert_eq!(metadata.emoji(), Some("🔥"));
+
+        let shared = SharedChatMetadata::from([("color".to_owned(), "#00ff00".to_owned())]);
+        ChatMetadata::store_shared(&connection, conversation_id, &shared).unwrap();
+        ChatMetadata::store_entry(&connection, conversation_id, &local, None).unwrap();
+
+        let metadata = ChatMetadata::load(&connection, conversation_id).unwrap();
+        assert_eq!(metadata.get(&local), None);
+        assert_eq!(metadata.color().unwrap().rgb(), 0x00ff00);
+        assert_eq!(metadata.emoji(), None);
+    }
+}
@@ -0,0 +1,256 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Deletion of the user's account across all services.
+//!
+//! The AS, QS and DS don't share identities of the user, so the client drives
+//! the cascade. It first deletes the user at the AS, which checks the user's
+//! password before erasing anything. Only then does the client delete its
+//! attachments in and leave all of its groups at the DS and finally delete its
+//! user record at the QS. The QS record goes last, because the DS fans out
+//! the commits leaving the groups to the queues of the user's other clients.
+//!
+//! Once the AS deleted the user, the progress of the cascade is stored in the
+//! client DB. If the client is interrupted, or erasing the user from a group
+//! fails, the deletion is resumed by [`CoreUser::resume_account_deletion`],
+//! which is also called after processing QS messages.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use opaque_ke::{ClientLogin, ClientLoginFinishParameters};
+use phnxapiclient::ApiClient;
+use phnxtypes::crypto::{
+    opaque::{OpaqueIdentifiers, OpaqueLoginFinish, OpaqueLoginRequest},
+    rng::PhnxRng,
+    OpaqueCiphersuite,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conversations::{Conversation, ConversationStatus},
+    user_settings::UserSetting,
+    ConversationId,
+};
+
+use super::CoreUser;
+
+/// Number of attempts to erase the user from a conversation before the
+/// conversation is given up on.
+const MAX_ERASURE_ATTEMPTS: u32 = 3;
+
+/// Summary of the erasure of the user's account from the DS.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountErasure {
+    pub groups_left: u32,
+    pub attachments_deleted: u32,
+    /// Number of groups the user couldn't be erased from, even after
+    /// retrying.
+    pub groups_failed: u32,
+}
+
+/// Progress of an account deletion after the user was deleted at the AS.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct AccountDeletionProgress {
+    pub(crate) in_progress: bool,
+    /// Conversations the user was erased from.
+    pub(crate) erased_conversations: HashSet<ConversationId>,
+    /// Number of failed attempts to erase the user per conversation.
+    pub(crate) failed_attempts: HashMap<ConversationId, u32>,
+    pub(crate) erasure: AccountErasure,
+}
+
+impl UserSetting for AccountDeletionProgress {
+    const KEY: &'static str = "account_deletion_progress";
+}
+
+impl CoreUser {
+    /// Delete the user's account.
+    ///
+    /// Nothing is erased if the password is wrong. If a deletion was started
+    /// before, it is resumed without checking the password again.
+    ///
+    /// Erasing the user from a group is retried by later calls, until it
+    /// failed [`MAX_ERASURE_ATTEMPTS`] times. As long as it can be retried, an
+    /// error is returned and the user record at the QS is kept. The local
+    /// databases are not deleted, see [`crate::delete_databases`].
+    pub async fn delete_account(&self, password: &str) -> Result<AccountErasure> {
+        let progress: AccountDeletionProgress = self.user_setting().await?;
+        if progress.in_progress {
+            return self.continue_account_deletion(progress).await;
+        }
+
+        // Phase 1: Authenticate with the password and delete the user at the
+        // AS
+        let api_client = self.inner.api_clients.default_client()?;
+        let opaque_finish = self.password_login(&api_client, password).await?;
+        let client_id = self.as_client_id();
+        api_client
+            .as_delete_user(
+                client_id.user_name(),
+                client_id,
+                opaque_finish,
+                &self.inner.key_store.signing_key(),
+            )
+            .await?;
+        let progress = AccountDeletionProgress {
+            in_progress: true,
+            ..Default::default()
+        };
+        self.set_user_setting(&progress).await?;
+
+        self.continue_account_deletion(progress).await
+    }
+
+    /// Resume an account deletion that was interrupted after the user was
+    /// deleted at the AS. Returns `None` if no deletion is in progress.
+    pub async fn resume_account_deletion(&self) -> Result<Option<AccountErasure>> {
+        let progress: AccountDeletionProgress = self.user_setting().await?;
+        if !progress.in_progress {
+            return Ok(None);
+        }
+        self.continue_account_deletion(progress).await.map(Some)
+    }
+
+    async fn continue_account_deletion(
+        &self,
+        mut progress: AccountDeletionProgress,
+    ) -> Result<AccountErasure> {
+        // Phase 2: Delete our attachments and leave all groups
+        let connection = self.inner.connection.lock().await;
+        let conversations = Conversation::load_all(&connection)?;
+        drop(connection);
+        let mut retry = false;
+        for conversation in conversations {
+            let conversation_id = conversation.id();
+            if !matches!(conversation.status(), ConversationStatus::Active)
+                || progress.erased_conversations.contains(&conversation_id)
+            {
+                continue;
+            }
+            let attempts = progress
+                .failed_attempts
+                .get(&conversation_id)
+                .copied()
+                .unwrap_or_default();
+            if attempts >= MAX_ERASURE_ATTEMPTS {
+                continue;
+            }
+            match self.erase_from_conversation(&conversation).await {
+                Ok((attachments_deleted, left)) => {
+                    progress.erasure.attachments_deleted += attachments_deleted;
+                    progress.erasure.groups_left += left as u32;
+                    progress.erased_conversations.insert(conversation_id);
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to erase user from conversation {}: {:?}",
+                        conversation_id.as_uuid(),
+                        e
+                    );
+                    progress
+                        .failed_attempts
+                        .insert(conversation_id, attempts + 1);
+                    if attempts + 1 < MAX_ERASURE_ATTEMPTS {
+                        retry = true;
+                    } else {
+                        progress.erasure.groups_failed += 1;
+                    }
+                }
+            }
+            // The progress is stored after each group, so that a resumed
+            // deletion doesn't leave a group twice.
+            self.set_user_setting(&progress).await?;
+        }
+        if retry {
+            bail!("Failed to erase the user from some groups, the deletion has to be resumed");
+        }
+
+        // Phase 3: Delete the user record at the QS, including its clients
+        // and queues
+        self.inner
+            .api_clients
+            .default_client()?
+            .qs_delete_user(
+                self.inner.qs_user_id.clone(),
+                &self.inner.key_store.qs_user_signing_key,
+            )
+            .await?;
+        self.set_user_setting(&AccountDeletionProgress::default())
+            .await?;
+
+        Ok(progress.erasure)
+    }
+
+    /// Run an OPAQUE login with the user's password as the second factor of
+    /// a request to the AS.
+    async fn password_login(
+        &self,
+        api_client: &ApiClient,
+        password: &str,
+    ) -> Result<OpaqueLoginFinish> {
+        let client_id = self.as_client_id();
+        let login_start =
+            ClientLogin::<OpaqueCiphersuite>::start(&mut PhnxRng, password.as_bytes())
+                .map_err(|e| anyhow!("Error starting OPAQUE login: {:?}", e))?;
+        let response = api_client
+            .as_initiate_2fa_auth(
+                client_id.clone(),
+                OpaqueLoginRequest {
+                    client_message: login_start.message,
+                },
+                &self.inner.key_store.signing_key(),
+            )
+            .await?;
+        let identifiers = OpaqueIdentifiers::new(&client_id.user_name())?;
+        let login_finish = login_start
+            .state
+            .finish(
+                password.as_bytes(),
+                response.opaque_ke2.server_message,
+                ClientLoginFinishParameters::new(None, identifiers.identifiers(), None),
+            )
+            .map_err(|e| anyhow!("Error finishing OPAQUE login: {:?}", e))?;
+        Ok(OpaqueLoginFinish {
+            client_message: login_finish.message,
+        })
+    }
+
+    /// Delete our attachments in the conversation's group and leave it.
+    /// Returns the number of deleted attachments and whether we left the
+    /// group. Groups in which we are the only member are not left; they
+    /// expire at the DS.
+    async fn erase_from_conversation(&self, conversation: &Conversation) -> Result<(u32, bool)> {
+        let connection = self.inner.connection.lock().await;
+        let group = self
+            .inner
+            .groups
+            .load(&connection, conversation.group_id())?
+            .ok_or(anyhow!(
+                "Can't find group with id {:?}",
+                conversation.group_id()
+            ))?;
+        let is_only_member = group.members(&connection).len() == 1;
+        drop(connection);
+
+        let attachments_deleted = self
+            .inner
+            .api_clients
+            .get(&conversation.owner_domain())?
+            .ds_delete_attachments(
+                conversation.group_id().clone(),
+                group.user_auth_key().ok_or(anyhow!("No user auth key"))?,
+                group.group_state_ear_key(),
+            )
+            .await?;
+        // The group is loaded again for leaving.
+        drop(group);
+
+        if is_only_member {
+            return Ok((attachments_deleted, false));
+        }
+        self.leave_conversation(conversation.id()).await?;
+        Ok((attachments_deleted, true))
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::Result;
+use phnxtypes::identifiers::SafeTryInto;
+use rusqlite::Connection;
+
+use crate::{
+    activity::{mentions, Activity, ActivityCursor, ActivityId, ActivityKind},
+    ConversationMessage, DeliveryFailure, Message, NotificationType,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// At most `limit` activities after the cursor `after`, or the latest
+    /// ones if `after` is `None`, newest first.
+    ///
+    /// Pass the cursor of the last activity of a page to load the next page.
+    pub async fn activities(
+        &self,
+        after: Option<ActivityCursor>,
+        limit: usize,
+    ) -> Result<Vec<Activity>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(Activity::load_page(&connection, after, limit)?)
+    }
+
+    pub async fn activity(&self, activity_id: ActivityId) -> Result<Option<Activity>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(Activity::load(&connection, activity_id)?)
+    }
+
+    pub async fn unread_activities_count(&self) -> Result<u32> {
+        let connection = self.inner.connection.lock().await;
+        Ok(Activity::unread_count(&connection)?)
+    }
+
+    pub async fn mark_activity_as_read(&self, activity_id: ActivityId) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        Activity::mark_as_read(&connection, activity_id)?;
+        self.store_notifier()
+            .notify(NotificationType::ActivityChange);
+        Ok(())
+    }
+
+    pub async fn mark_all_activities_as_read(&self) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        Activity::mark_all_as_read(&connection)?;
+        self.store_notifier()
+            .notify(NotificationType::ActivityChange);
+        Ok(())
+    }
+
+    pub async fn delete_activity(&self, activity_id: ActivityId) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        Activity::delete(&connection, activity_id)?;
+        self.store_notifier()
+            .notify(NotificationType::ActivityChange);
+        Ok(())
+    }
+
+    /// Store the given activity and notify the subscribers of the store.
+    pub(crate) fn record_activity(
+        &self,
+        connection: &Connection,
+        activity: Activity,
+    ) -> Result<(), rusqlite::Error> {
+        activity.store(connection)?;
+        self.store_notifier()
+            .notify(NotificationType::ActivityChange);
+        Ok(())
+    }
+
+    /// Record that the given message of the user couldn't be sent and why.
+    pub(crate) async fn record_failed_delivery(
+        &self,
+        message: &mut ConversationMessage,
+        delivery_failure: DeliveryFailure,
+    ) -> Result<()> {
+        let kind = ActivityKind::FailedDelivery {
+            message_id: message.id(),
+        };
+        let activity = Activity::new(kind, Some(message.conversation_id()));
+        let connection = self.inner.connection.lock().await;
+        message.set_delivery_failure(&connection, delivery_failure)?;
+        self.record_activity(&connection, activity)?;
+        self.store_notifier()
+            .notify(NotificationType::Message(message.clone()));
+        Ok(())
+    }
+
+    /// Record the mentions of the user in the given received messages.
+    pub(crate) fn record_mentions(
+        &self,
+        connection: &Connection,
+        messages: &[ConversationMessage],
+    ) -> Result<(), rusqlite::Error> {
+        let user_name = self.user_name();
+        for message in messages {
+            let Message::Content(content_message) = message.message() else {
+                continue;
+            };
+            let Ok(sender) = SafeTryInto::try_into(content_message.sender()) else {
+                continue;
+            };
+            if sender == user_name
+                || !mentions(&content_message.content().string_rendering(), &user_name)
+            {
+                continue;
+            }
+            let kind = ActivityKind::Mention {
+                sender,
+                message_id: message.id(),
+            };
+            let activity = Activity::new(kind, Some(message.conversation_id()));
+            self.record_activity(connection, activity)?;
+        }
+        Ok(())
+    }
+}
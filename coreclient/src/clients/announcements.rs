@@ -0,0 +1,57 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::Result;
+use phnxapiclient::as_api::AnnouncementsPoll;
+
+use crate::{
+    announcements::{Announcement, AnnouncementsEtag},
+    user_settings::persistence::StorableUserSetting,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Poll the announcements of the operator of the user's server. Returns
+    /// whether the announcements changed since the last poll.
+    ///
+    /// The poll is conditional on the entity tag of the last poll, so polling
+    /// unchanged announcements is cheap.
+    pub async fn poll_announcements(&self) -> Result<bool> {
+        let AnnouncementsEtag(etag) = {
+            let connection = self.inner.connection.lock().await;
+            StorableUserSetting::load(&connection)?
+        };
+        let poll = self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_announcements(etag.as_deref())
+            .await?;
+        let AnnouncementsPoll::Modified { response, etag } = poll else {
+            return Ok(false);
+        };
+        let announcements: Vec<Announcement> =
+            response.announcements.into_iter().map(From::from).collect();
+
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        Announcement::replace_all(&transaction, &announcements)?;
+        StorableUserSetting::store(&transaction, &AnnouncementsEtag(etag))?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    /// All announcements served by the user's server, newest first.
+    pub async fn announcements(&self) -> Result<Vec<Announcement>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(Announcement::load_all(&connection)?)
+    }
+
+    pub async fn mark_announcements_as_read(&self) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        Announcement::mark_all_as_read(&connection)?;
+        Ok(())
+    }
+}
@@ -2,12 +2,17 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::collections::hash_map::Entry;
 use std::{collections::HashMap, sync::Mutex};
 
-use phnxtypes::identifiers::Fqdn;
+use phnxapiclient::{transport::Transport, HttpClient};
+use phnxtypes::{identifiers::Fqdn, messages::server_info::ClientVersion};
 
 use super::*;
+use crate::utils::rt::Instant;
+
+/// Maximum number of API clients for domains other than our own. If more
+/// clients are needed, the least recently used one is evicted.
+const MAX_REMOTE_CLIENTS: usize = 32;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct ApiClients {
@@ -18,7 +23,121 @@ pub(crate) struct ApiClients {
     own_domain: Fqdn,
     own_domain_or_address: String,
     #[serde(skip)]
-    clients: Arc<Mutex<HashMap<String, ApiClient>>>,
+    pool: Arc<Mutex<ApiClientPool>>,
+}
+
+struct PooledClient {
+    client: ApiClient,
+    last_used: Instant,
+}
+
+/// The API clients of all domains share a single HTTP client, such that
+/// connections are reused across requests.
+#[derive(Default)]
+struct ApiClientPool {
+    http_client: Option<HttpClient>,
+    clients: HashMap<String, PooledClient>,
+    /// URLs to use instead of the domain itself, see
+    /// [`CoreUser::route_domain`].
+    routes: HashMap<Fqdn, String>,
+    /// Transport of all clients instead of the network, see
+    /// [`ApiClient::with_transport`].
+    transport: Option<Arc<dyn Transport>>,
+    /// Version sent with all requests, see [`CoreUser::set_client_version`].
+    client_version: Option<ClientVersion>,
+    created: u64,
+    evicted: u64,
+    recycled: u64,
+}
+
+/// Statistics of the API client pool for diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiClientPoolStats {
+    /// Number of API clients currently in the pool.
+    pub clients: usize,
+    /// Number of API clients created since the pool was created.
+    pub created: u64,
+    /// Number of API clients evicted because the pool was full.
+    pub evicted: u64,
+    /// Number of API clients dropped because they failed a health check.
+    pub recycled: u64,
+}
+
+impl ApiClientPool {
+    fn get_or_create(
+        &mut self,
+        lookup_domain: String,
+        own_domain_or_address: &str,
+    ) -> Result<ApiClient, ApiClientInitError> {
+        let now = Instant::now();
+        if let Some(pooled_client) = self.clients.get_mut(&lookup_domain) {
+            pooled_client.last_used = now;
+            return Ok(pooled_client.client.clone());
+        }
+        let client = if let Some(transport) = &self.transport {
+            ApiClient::with_transport(lookup_domain.clone(), transport.clone())?
+        } else {
+            let http_client = match &self.http_client {
+                Some(http_client) => http_client.clone(),
+                None => {
+                    let http_client = ApiClient::new_http_client()?;
+                    self.http_client = Some(http_client.clone());
+                    http_client
+                }
+            };
+            ApiClient::with_http_client(lookup_domain.clone(), http_client)?
+        };
+        let client = match self.client_version {
+            Some(client_version) => client.with_client_version(client_version),
+            None => client,
+        };
+        self.evict_remote_clients(own_domain_or_address);
+        self.clients.insert(
+            lookup_domain,
+            PooledClient {
+                client: client.clone(),
+                last_used: now,
+            },
+        );
+        self.created += 1;
+        Ok(client)
+    }
+
+    /// Evict the least recently used clients of remote domains until there is
+    /// room for one more remote client. The client of our own domain is
+    /// never evicted.
+    fn evict_remote_clients(&mut self, own_domain_or_address: &str) {
+        loop {
+            let remote_clients = self
+                .clients
+                .keys()
+                .filter(|domain| domain.as_str() != own_domain_or_address)
+                .count();
+            if remote_clients < MAX_REMOTE_CLIENTS {
+                return;
+            }
+            let Some(least_recently_used) = self
+                .clients
+                .iter()
+                .filter(|(domain, _)| domain.as_str() != own_domain_or_address)
+                .min_by_key(|(_, pooled_client)| pooled_client.last_used)
+                .map(|(domain, _)| domain.clone())
+            else {
+                return;
+            };
+            self.clients.remove(&least_recently_used);
+            self.evicted += 1;
+        }
+    }
+
+    fn stats(&self) -> ApiClientPoolStats {
+        ApiClientPoolStats {
+            clients: self.clients.len(),
+            created: self.created,
+            evicted: self.evicted,
+            recycled: self.recycled,
+        }
+    }
 }
 
 impl ApiClients {
@@ -27,33 +146,136 @@ impl ApiClients {
         Self {
             own_domain,
             own_domain_or_address,
-            clients: Arc::new(Mutex::new(HashMap::new())),
+            pool: Arc::new(Mutex::new(ApiClientPool::default())),
         }
     }
 
+    /// API clients that send all requests with the given transport instead of
+    /// the network.
+    #[cfg(test)]
+    pub(super) fn with_transport(own_domain: Fqdn, transport: Arc<dyn Transport>) -> Self {
+        let api_clients = Self::new(own_domain.clone(), own_domain);
+        api_clients
+            .pool
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .transport = Some(transport);
+        api_clients
+    }
+
     pub(crate) fn get(&self, domain: &Fqdn) -> Result<ApiClient, ApiClientsError> {
+        let mut pool = self
+            .pool
+            .lock()
+            .map_err(|_| ApiClientsError::MutexPoisonError)?;
         let lookup_domain = if domain == &self.own_domain {
             self.own_domain_or_address.clone()
+        } else if let Some(address) = pool.routes.get(domain) {
+            address.clone()
         } else {
             domain.clone().to_string()
         };
-        let mut clients = self
+        let client = pool.get_or_create(lookup_domain, &self.own_domain_or_address)?;
+        Ok(client)
+    }
+
+    pub(super) fn default_client(&self) -> Result<ApiClient, ApiClientsError> {
+        let own_domain = self.own_domain.clone();
+        self.get(&own_domain)
+    }
+
+    /// Run a health check against the server of each pooled client. If any
+    /// client fails it, the shared HTTP client and thus all of its
+    /// connections are dropped, together with all pooled clients, such that
+    /// none of them keeps using the old HTTP client. Dropped clients are
+    /// re-created with fresh connections the next time they are needed.
+    ///
+    /// Returns the number of clients that failed the health check.
+    pub(super) async fn recycle_unhealthy(&self) -> Result<usize, ApiClientsError> {
+        let clients: Vec<(String, ApiClient)> = self
+            .pool
+            .lock()
+            .map_err(|_| ApiClientsError::MutexPoisonError)?
             .clients
+            .iter()
+            .map(|(domain, pooled_client)| (domain.clone(), pooled_client.client.clone()))
+            .collect();
+        let mut unhealthy_domains = Vec::new();
+        for (domain, client) in clients {
+            if !client.health_check().await {
+                log::info!("Recycling API client for {domain} after failed health check");
+                unhealthy_domains.push(domain);
+            }
+        }
+        if unhealthy_domains.is_empty() {
+            return Ok(0);
+        }
+        let mut pool = self
+            .pool
             .lock()
             .map_err(|_| ApiClientsError::MutexPoisonError)?;
-        match clients.entry(lookup_domain.clone()) {
-            Entry::Occupied(entry) => Ok(entry.get().clone()),
-            Entry::Vacant(entry) => {
-                let client = ApiClient::initialize(lookup_domain.clone())?;
-                entry.insert(client.clone());
-                Ok(client)
+        for domain in &unhealthy_domains {
+            if pool.clients.contains_key(domain) {
+                pool.recycled += 1;
             }
         }
+        // Broken connections live in the shared HTTP client, so we replace it
+        // and re-create the healthy clients with the new one as well.
+        pool.http_client = None;
+        pool.clients.clear();
+        Ok(unhealthy_domains.len())
     }
 
-    pub(super) fn default_client(&self) -> Result<ApiClient, ApiClientsError> {
-        let own_domain = self.own_domain.clone();
-        self.get(&own_domain)
+    pub(super) fn stats(&self) -> Result<ApiClientPoolStats, ApiClientsError> {
+        let pool = self
+            .pool
+            .lock()
+            .map_err(|_| ApiClientsError::MutexPoisonError)?;
+        Ok(pool.stats())
+    }
+}
+
+impl CoreUser {
+    /// Send all requests for the given domain to the server at the given URL
+    /// instead of resolving the domain. Allows tests to run the backends of
+    /// several domains in one process.
+    #[cfg(feature = "test_utils")]
+    pub fn route_domain(&self, domain: Fqdn, url: impl ToString) -> Result<()> {
+        let mut pool = self
+            .inner
+            .api_clients
+            .pool
+            .lock()
+            .map_err(|_| ApiClientsError::MutexPoisonError)?;
+        pool.routes.insert(domain, url.to_string());
+        Ok(())
+    }
+
+    /// Send the given client version with all requests, such that servers
+    /// that don't serve it anymore reject them with `426 Upgrade Required`.
+    pub fn set_client_version(&self, client_version: ClientVersion) -> Result<()> {
+        let mut pool = self
+            .inner
+            .api_clients
+            .pool
+            .lock()
+            .map_err(|_| ApiClientsError::MutexPoisonError)?;
+        pool.client_version = Some(client_version);
+        // Pooled clients were created without the version.
+        pool.clients.clear();
+        Ok(())
+    }
+
+    /// Drop API clients whose server doesn't pass a health check, such that
+    /// they are re-created with fresh connections when next needed. Returns
+    /// the number of dropped clients.
+    pub async fn recycle_unhealthy_api_clients(&self) -> Result<usize> {
+        Ok(self.inner.api_clients.recycle_unhealthy().await?)
+    }
+
+    /// Statistics of the API client pool for diagnostics.
+    pub fn api_client_pool_stats(&self) -> Result<ApiClientPoolStats> {
+        Ok(self.inner.api_clients.stats()?)
     }
 }
 
@@ -64,3 +286,65 @@ pub(crate) enum ApiClientsError {
     #[error("Mutex poisoned")]
     MutexPoisonError,
 }
+
+#[cfg(test)]
+mod tests {
+    use phnxserver_test_harness::utils::setup::TestBackend;
+
+    use super::*;
+
+    fn fqdn(domain: &str) -> Fqdn {
+        Fqdn::try_from(domain).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn unhealthy_clients_are_recycled() {
+        let setup = TestBackend::single().await;
+        let api_clients = ApiClients::new(fqdn("example.com"), setup.url().unwrap());
+        let unreachable = fqdn("unreachable.example.com");
+        api_clients
+            .pool
+            .lock()
+            .unwrap()
+            .routes
+            .insert(unreachable.clone(), "127.0.0.1:1".to_owned());
+
+        // Healthy clients are kept.
+        api_clients.default_client().unwrap();
+        assert_eq!(api_clients.recycle_unhealthy().await.unwrap(), 0);
+        let stats = api_clients.stats().unwrap();
+        assert_eq!((stats.clients, stats.created, stats.recycled), (1, 1, 0));
+
+        // Once a client fails, all clients are dropped together with the
+        // shared HTTP client.
+        api_clients.get(&unreachable).unwrap();
+        assert_eq!(api_clients.recycle_unhealthy().await.unwrap(), 1);
+        let stats = api_clients.stats().unwrap();
+        assert_eq!((stats.clients, stats.created, stats.recycled), (0, 2, 1));
+        assert!(api_clients.pool.lock().unwrap().http_client.is_none());
+
+        // The healthy client is re-created with a new HTTP client.
+        assert!(api_clients.default_client().unwrap().health_check().await);
+        assert!(api_clients.pool.lock().unwrap().http_client.is_some());
+        let stats = api_clients.stats().unwrap();
+        assert_eq!((stats.clients, stats.created), (1, 3));
+    }
+
+    #[test]
+    fn least_recently_used_remote_clients_are_evicted() {
+        let api_clients = ApiClients::new(fqdn("example.com"), "127.0.0.1:1");
+        api_clients.default_client().unwrap();
+        for index in 0..=MAX_REMOTE_CLIENTS {
+            api_clients
+                .get(&fqdn(&format!("remote{index}.example.com")))
+                .unwrap();
+        }
+
+        let stats = api_clients.stats().unwrap();
+        assert_eq!(stats.clients, MAX_REMOTE_CLIENTS + 1);
+        assert_eq!(stats.evicted, 1);
+        let pool = api_clients.pool.lock().unwrap();
+        assert!(pool.clients.contains_key("127.0.0.1:1"));
+        assert!(!pool.clients.contains_key("remote0.example.com"));
+    }
+}
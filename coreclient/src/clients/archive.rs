@@ -0,0 +1,308 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{bail, ensure, Result};
+use chrono::Duration;
+use phnxtypes::{
+    codec::PhnxCodec,
+    crypto::ear::{keys::MessageArchiveEarKey, Ciphertext, EarKey},
+    identifiers::Fqdn,
+    messages::attachments::{AttachmentDownload, QualifiedAttachmentUrl},
+    time::{self, TimeStamp},
+};
+
+use crate::{
+    archive::{
+        persistence::MessageArchive, ArchiveId, ArchivePayload, ArchiveUsage, ArchivedMessage,
+        MessageArchiving, ARCHIVE_BATCH_SIZE, MIN_ARCHIVE_BATCH_SIZE,
+    },
+    attachments::AttachmentHash,
+    conversations::{messages::ConversationMessage, Conversation, ConversationStatus},
+    groups::Group,
+    user_settings::persistence::StorableUserSetting,
+    ConversationId,
+};
+
+use super::CoreUser;
+
+/// Time after which restored messages are offloaded again.
+const RESTORED_RETENTION: Duration = Duration::days(1);
+
+impl CoreUser {
+    /// Apply the [`MessageArchiving`] policy from the user settings by
+    /// offloading old messages of all active conversations to the DS.
+    ///
+    /// Returns the number of offloaded messages.
+    pub async fn archive_old_messages(&self) -> Result<usize> {
+        let connection = self.inner.connection.lock().await;
+        let policy: MessageArchiving = StorableUserSetting::load(&connection)?;
+        MessageArchive::delete_orphaned(&connection)?;
+        let conversations = Conversation::load_all(&connection)?;
+        drop(connection);
+        let Some(archive_after_days) = policy.archive_after_days else {
+            return Ok(0);
+        };
+
+        let mut archived = self.offload_restored_archives().await?;
+        let older_than = TimeStamp::from(time::now() - Duration::days(archive_after_days.into()));
+        for conversation in conversations {
+            if !matches!(conversation.status(), ConversationStatus::Active) {
+                continue;
+            }
+            loop {
+                let count = self
+                    .archive_messages(conversation.id(), older_than, policy.keep_recent)
+                    .await
+                    .inspect_err(|error| {
+                        log::warn!(
+                            "Failed to archive messages of conversation {}: {error}",
+                            conversation.id().as_uuid()
+                        )
+                    })
+                    .unwrap_or(0);
+                archived += count;
+                if count < ARCHIVE_BATCH_SIZE as usize {
+                    break;
+                }
+            }
+        }
+        Ok(archived)
+    }
+
+    /// Upload one batch of the archivable messages of the conversation and
+    /// replace them with stubs. Returns the number of offloaded messages.
+    async fn archive_messages(
+        &self,
+        conversation_id: ConversationId,
+        older_than: TimeStamp,
+        keep_recent: u32,
+    ) -> Result<usize> {
+        let connection = self.inner.connection.lock().await;
+        let messages = ArchivedMessage::load_archivable(
+            &connection,
+            conversation_id,
+            older_than,
+            keep_recent,
+            ARCHIVE_BATCH_SIZE,
+        )?;
+        drop(connection);
+        let (Some(oldest), Some(newest)) = (messages.first(), messages.last()) else {
+            return Ok(0);
+        };
+        if messages.len() < MIN_ARCHIVE_BATCH_SIZE as usize {
+            return Ok(0);
+        }
+        let oldest_timestamp = oldest.timestamp;
+        let newest_timestamp = newest.timestamp;
+        let size = messages.iter().map(|message| message.size()).sum();
+        let message_ids: Vec<_> = messages.iter().map(|message| message.message_id).collect();
+
+        let payload = ArchivePayload {
+            conversation_id: conversation_id.as_uuid(),
+            messages,
+        };
+        let ear_key = MessageArchiveEarKey::random()?;
+        let ciphertext = ear_key.encrypt(&PhnxCodec::to_vec(&payload)?)?;
+        let content = PhnxCodec::to_vec(&ciphertext)?;
+        let ciphertext_hash = AttachmentHash::of(&content)?;
+
+        let (conversation, group) = self.load_conversation_and_group(conversation_id).await?;
+        let owner_domain = conversation.owner_domain();
+        let attachment_id = self
+            .inner
+            .api_clients
+            .get(&owner_domain)?
+            .ds_upload_archive(
+                group.group_id().clone(),
+                group.own_index(),
+                content,
+                group.leaf_signer(),
+                group.group_state_ear_key(),
+            )
+            .await?;
+
+        let archive = MessageArchive {
+            archive_id: ArchiveId::random(),
+            conversation_id,
+            url: QualifiedAttachmentUrl::new(attachment_id),
+            ear_key,
+            ciphertext_hash,
+            oldest_timestamp,
+            newest_timestamp,
+            message_count: message_ids.len() as u32,
+            size,
+            restored_at: None,
+        };
+        // The local messages are only deleted once the archive can be read
+        // back from the DS.
+        self.fetch_archive(&archive, &group, &owner_domain).await?;
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        archive.store(&transaction, message_ids)?;
+        let deleted = archive.delete_local_messages(&transaction)?;
+        transaction.commit()?;
+        log::info!(
+            "Archived {deleted} messages of conversation {}",
+            conversation_id.as_uuid()
+        );
+        Ok(deleted)
+    }
+
+    /// Delete the messages of archives that were restored a while ago. The
+    /// archives are still on the DS, so they don't need to be uploaded again.
+    async fn offload_restored_archives(&self) -> Result<usize> {
+        let restored_before = TimeStamp::from(time::now() - RESTORED_RETENTION);
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let mut offloaded = 0;
+        for mut archive in MessageArchive::load_restored_before(&transaction, restored_before)? {
+            offloaded += archive.delete_local_messages(&transaction)?;
+            archive.set_restored_at(&transaction, None)?;
+        }
+        transaction.commit()?;
+        Ok(offloaded)
+    }
+
+    /// Restore the next archive that is needed to show the given number of
+    /// most recent messages of the conversation, given the messages that are
+    /// stored locally.
+    ///
+    /// Returns `true` if an archive was restored.
+    pub(super) async fn restore_archived_messages(
+        &self,
+        conversation_id: ConversationId,
+        loaded: &[ConversationMessage],
+        number_of_messages: usize,
+    ) -> Result<bool> {
+        let oldest_loaded = (loaded.len() >= number_of_messages)
+            .then(|| loaded.first().map(|message| message.timestamp()))
+            .flatten()
+            .map(TimeStamp::from);
+        let connection = self.inner.connection.lock().await;
+        let archive =
+            MessageArchive::load_next_to_restore(&connection, conversation_id, oldest_loaded)?;
+        drop(connection);
+        let Some(archive) = archive else {
+            return Ok(false);
+        };
+        self.restore_archive(archive).await?;
+        Ok(true)
+    }
+
+    /// Download, verify and decrypt the archive and restore its messages.
+    async fn restore_archive(&self, mut archive: MessageArchive) -> Result<()> {
+        let (conversation, group) = self
+            .load_conversation_and_group(archive.conversation_id)
+            .await?;
+        let payload = self
+            .fetch_archive(&archive, &group, &conversation.owner_domain())
+            .await?;
+
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        for message in &payload.messages {
+            message.restore(&transaction, archive.conversation_id)?;
+        }
+        archive.set_restored_at(&transaction, Some(TimeStamp::now()))?;
+        transaction.commit()?;
+        log::info!(
+            "Restored {} archived messages of conversation {}",
+            payload.messages.len(),
+            archive.conversation_id.as_uuid()
+        );
+        Ok(())
+    }
+
+    /// Restore the messages of all archives of the conversation and delete
+    /// the archives.
+    ///
+    /// Archives are stored in the DS group they were uploaded to. Since the
+    /// client leaves the given group when the conversation moves to the
+    /// successor of an upgraded group, this must be called before. The
+    /// restored messages are archived again in the successor.
+    pub(super) async fn restore_archives_before_upgrade(
+        &self,
+        conversation: &Conversation,
+        group: &Group,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let archives = MessageArchive::load_all_of_conversation(&connection, conversation.id())?;
+        drop(connection);
+        if archives.is_empty() {
+            return Ok(());
+        }
+
+        let owner_domain = conversation.owner_domain();
+        let mut payloads = Vec::new();
+        for archive in &archives {
+            // Messages of restored archives are stored locally.
+            if archive.restored_at.is_none() {
+                payloads.push(self.fetch_archive(archive, group, &owner_domain).await?);
+            }
+        }
+
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        for message in payloads.iter().flat_map(|payload| &payload.messages) {
+            message.restore(&transaction, conversation.id())?;
+        }
+        for archive in &archives {
+            archive.delete(&transaction)?;
+        }
+        transaction.commit()?;
+        log::info!(
+            "Restored {} archives of upgraded conversation {}",
+            archives.len(),
+            conversation.id().as_uuid()
+        );
+        Ok(())
+    }
+
+    /// Download the archive from the DS of the given group, verify it
+    /// against its stub and decrypt it.
+    async fn fetch_archive(
+        &self,
+        archive: &MessageArchive,
+        group: &Group,
+        owner_domain: &Fqdn,
+    ) -> Result<ArchivePayload> {
+        let api_client = self.inner.api_clients.get(owner_domain)?;
+        let download = api_client
+            .ds_download_attachment(
+                group.group_id().clone(),
+                group.own_index(),
+                archive.url.attachment_id(),
+                group.leaf_signer(),
+                group.group_state_ear_key(),
+            )
+            .await?;
+        let content = match download {
+            AttachmentDownload::Available(content) => content,
+            AttachmentDownload::Blocked => bail!("Archive {} was blocked by the DS", archive.url),
+            AttachmentDownload::Url(url) => api_client.fetch_attachment_url(&url).await?,
+        };
+
+        ensure!(
+            AttachmentHash::of(&content)? == archive.ciphertext_hash,
+            "Archive {} doesn't match its hash",
+            archive.url
+        );
+        let ciphertext: Ciphertext = PhnxCodec::from_slice(&content)?;
+        let payload: ArchivePayload =
+            PhnxCodec::from_slice(&archive.ear_key.decrypt(&ciphertext)?)?;
+        ensure!(
+            payload.conversation_id == archive.conversation_id.as_uuid()
+                && payload.messages.len() == archive.message_count as usize,
+            "Archive {} doesn't match its stub",
+            archive.url
+        );
+        Ok(payload)
+    }
+
+    /// Returns an overview of the messages offloaded to the DS.
+    pub async fn archive_usage(&self) -> Result<ArchiveUsage> {
+        let connection = self.inner.connection.lock().await;
+        Ok(ArchiveUsage::load(&connection)?)
+    }
+}
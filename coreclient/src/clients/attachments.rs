@@ -0,0 +1,444 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use phnxtypes::{
+    codec::PhnxCodec,
+    crypto::ear::{keys::AttachmentEarKey, EarKey},
+    messages::attachments::{AttachmentBlocked, AttachmentDownload, QualifiedAttachmentUrl},
+    time,
+};
+
+use crate::{
+    attachments::{
+        blobs::AttachmentBlob, persistence::AttachmentReference, AttachmentHash, AttachmentKind,
+        AutoDownloadPolicy, MediaCodec, MediaRetention, MediaTranscoding, MessageAttachment,
+        NetworkType, StorageBreakdown,
+    },
+    conversations::{
+        messages::{ConversationMessage, Message},
+        Conversation,
+    },
+    mimi_content::{EncryptedAttachment, MimiContent},
+    user_settings::persistence::StorableUserSetting,
+    ConversationId, ConversationMessageId,
+};
+
+use super::{process::process_qs::ProcessQsMessageResult, view_once::decrypt_attachment, CoreUser};
+
+impl CoreUser {
+    /// Store the given attachment content for the message with the given id.
+    ///
+    /// If the same content is already stored (e.g. because it was forwarded
+    /// from another conversation), the existing blob is referenced instead of
+    /// storing the content again.
+    pub async fn store_attachment(
+        &self,
+        message_id: ConversationMessageId,
+        content: Vec<u8>,
+    ) -> Result<AttachmentHash> {
+        self.store_blob(message_id, content, false).await
+    }
+
+    /// Store the given thumbnail of an attachment of the message with the
+    /// given id. Thumbnails are accounted separately in the
+    /// [`StorageBreakdown`] and deleted together with the media of the
+    /// message.
+    pub async fn store_attachment_thumbnail(
+        &self,
+        message_id: ConversationMessageId,
+        content: Vec<u8>,
+    ) -> Result<AttachmentHash> {
+        self.store_blob(message_id, content, true).await
+    }
+
+    async fn store_blob(
+        &self,
+        message_id: ConversationMessageId,
+        content: Vec<u8>,
+        thumbnail: bool,
+    ) -> Result<AttachmentHash> {
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let message = ConversationMessage::load(&transaction, &message_id.to_uuid())?
+            .ok_or_else(|| anyhow!("Can't find message with id {}", message_id.to_uuid()))?;
+        let blob = AttachmentBlob::new(content)?;
+        blob.store(&transaction)?;
+        let reference = AttachmentReference {
+            hash: blob.hash.clone(),
+            message_id,
+            conversation_id: message.conversation_id(),
+        };
+        if thumbnail {
+            reference.store_thumbnail(&transaction)?;
+        } else {
+            reference.store(&transaction)?;
+        }
+        transaction.commit()?;
+        Ok(blob.hash)
+    }
+
+    /// Load the content of the attachment with the given hash.
+    pub async fn attachment(&self, hash: &AttachmentHash) -> Result<Option<Vec<u8>>> {
+        let connection = self.inner.connection.lock().await;
+        let blob = AttachmentBlob::load(&connection, hash)?;
+        Ok(blob.map(|blob| blob.content))
+    }
+
+    /// Returns all attachments of the message with the given id, including
+    /// the ones whose media was deleted.
+    pub async fn message_attachments(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> Result<Vec<MessageAttachment>> {
+        let connection = self.inner.connection.lock().await;
+        let attachments = AttachmentReference::load_all_of_message(&connection, message_id)?;
+        Ok(attachments)
+    }
+
+    /// Returns true if an attachment of the given kind and size in the
+    /// conversation with the given id should be downloaded without an explicit
+    /// request by the user. Downloads are checked against the
+    /// [`AutoDownloadPolicy`] from the user settings before fetching.
+    pub async fn should_auto_download(
+        &self,
+        conversation_id: ConversationId,
+        kind: AttachmentKind,
+        size: u64,
+        network_type: NetworkType,
+    ) -> Result<bool> {
+        let connection = self.inner.connection.lock().await;
+        let policy: AutoDownloadPolicy = StorableUserSetting::load(&connection)?;
+        Ok(policy.should_download(conversation_id, kind, size, network_type))
+    }
+
+    /// Returns an overview of the local storage used by attachments.
+    pub async fn storage_breakdown(&self) -> Result<StorageBreakdown> {
+        let connection = self.inner.connection.lock().await;
+        Ok(StorageBreakdown::load(&connection)?)
+    }
+
+    /// Delete the media of all messages in the conversation with the given id
+    /// that are older than `older_than`. The messages themselves are kept and
+    /// their attachments are marked as deleted.
+    ///
+    /// Returns the number of bytes freed. Blobs that are still used by other
+    /// conversations are kept and don't count towards it.
+    pub async fn clear_conversation_media(
+        &self,
+        conversation_id: ConversationId,
+        older_than: DateTime<Utc>,
+    ) -> Result<u64> {
+        self.clear_media(Some(conversation_id), older_than).await
+    }
+
+    /// Apply the [`MediaRetention`] policy from the user settings by deleting
+    /// the media of all messages that exceed the configured age.
+    ///
+    /// Returns the number of bytes freed.
+    pub async fn apply_media_retention(&self) -> Result<u64> {
+        let connection = self.inner.connection.lock().await;
+        let retention: MediaRetention = StorableUserSetting::load(&connection)?;
+        drop(connection);
+        let Some(keep_days) = retention.keep_days else {
+            return Ok(0);
+        };
+        let older_than = time::now() - Duration::days(keep_days.into());
+        self.clear_media(None, older_than).await
+    }
+
+    async fn clear_media(
+        &self,
+        conversation_id: Option<ConversationId>,
+        older_than: DateTime<Utc>,
+    ) -> Result<u64> {
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let freed = AttachmentBlob::delete_media(&transaction, conversation_id, older_than)?;
+        transaction.commit()?;
+        Ok(freed)
+    }
+
+    /// Delete all attachment blobs that are no longer referenced by any
+    /// message.
+    ///
+    /// Returns the number of bytes freed.
+    pub async fn delete_unreferenced_attachments(&self) -> Result<u64> {
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let freed = AttachmentBlob::delete_unreferenced(&transaction)?;
+        transaction.commit()?;
+        log::info!("Deleted unreferenced attachments, freed {} bytes", freed);
+        Ok(freed)
+    }
+
+    /// Transcode the given attachment content according to the user's
+    /// [`MediaTranscoding`] settings before it is uploaded.
+    ///
+    /// With `original_quality`, images and videos are not downscaled, but
+    /// their metadata is still stripped.
+    pub async fn transcode_attachment(
+        &self,
+        codec: &impl MediaCodec,
+        kind: AttachmentKind,
+        content: Vec<u8>,
+        original_quality: bool,
+    ) -> Result<Vec<u8>> {
+        let settings: MediaTranscoding = self.user_setting().await?;
+        settings
+            .transcode(codec, kind, content, original_quality)
+            .await
+    }
+
+    /// Upload the given content as an attachment to the DS of the
+    /// conversation with the given id. Returns the URL from which other
+    /// members of the conversation can download it.
+    pub async fn upload_attachment(
+        &self,
+        conversation_id: ConversationId,
+        content: Vec<u8>,
+    ) -> Result<QualifiedAttachmentUrl> {
+        self.upload_to_ds(conversation_id, content, false).await
+    }
+
+    /// Upload the given content to the DS of the conversation with the given
+    /// id. View-once attachments are deleted by the DS once a recipient
+    /// downloaded them.
+    async fn upload_to_ds(
+        &self,
+        conversation_id: ConversationId,
+        content: Vec<u8>,
+        view_once: bool,
+    ) -> Result<QualifiedAttachmentUrl> {
+        let (conversation, group) = self.load_conversation_and_group(conversation_id).await?;
+        let owner_domain = conversation.owner_domain();
+        let api_client = self.inner.api_clients.get(&owner_domain)?;
+        let attachment_id = if view_once {
+            api_client
+                .ds_upload_view_once_attachment(
+                    group.group_id().clone(),
+                    group.own_index(),
+                    content,
+                    group.leaf_signer(),
+                    group.group_state_ear_key(),
+                )
+                .await?
+        } else {
+            api_client
+                .ds_upload_attachment(
+                    group.group_id().clone(),
+                    group.own_index(),
+                    content,
+                    group.leaf_signer(),
+                    group.group_state_ear_key(),
+                )
+                .await?
+        };
+        Ok(QualifiedAttachmentUrl::new(attachment_id))
+    }
+
+    /// Encrypt the given content under a fresh key and upload it to the DS
+    /// of the conversation with the given id. The key is only shared with the
+    /// members of the conversation as part of a message, such that the DS
+    /// never learns the content. The kind and size of the content are shared
+    /// along with the key, such that recipients can apply their
+    /// [`AutoDownloadPolicy`].
+    pub(super) async fn upload_encrypted_attachment(
+        &self,
+        conversation_id: ConversationId,
+        content: &[u8],
+        view_once: bool,
+    ) -> Result<EncryptedAttachment> {
+        let key = AttachmentEarKey::random()?;
+        let ciphertext = PhnxCodec::to_vec(&key.encrypt(content)?)?;
+        let ciphertext_hash = AttachmentHash::of(&ciphertext)?;
+        let url = self
+            .upload_to_ds(conversation_id, ciphertext, view_once)
+            .await?;
+        Ok(EncryptedAttachment {
+            url,
+            key,
+            ciphertext_hash: ciphertext_hash.as_bytes().to_vec(),
+            kind: AttachmentKind::of(content),
+            size: content.len() as u64,
+        })
+    }
+
+    /// Upload the attachments stored for the given unsent message and add
+    /// them to its content. Returns the content to send.
+    ///
+    /// If the content already references the attachments, e.g. because
+    /// sending failed after the upload, they aren't uploaded again.
+    pub(super) async fn upload_unsent_attachments(
+        &self,
+        message: &mut ConversationMessage,
+        content: MimiContent,
+    ) -> Result<MimiContent> {
+        if !content.encrypted_attachments().is_empty() {
+            return Ok(content);
+        }
+        let connection = self.inner.connection.lock().await;
+        // Thumbnails are generated locally by each client
+        let blobs = AttachmentReference::load_all_of_message(&connection, message.id())?
+            .into_iter()
+            .filter(|attachment| !attachment.thumbnail)
+            .map(|attachment| {
+                AttachmentBlob::load(&connection, &attachment.hash)?
+                    .ok_or_else(|| anyhow!("Can't find content of attachment {}", attachment.hash))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        drop(connection);
+        if blobs.is_empty() {
+            return Ok(content);
+        }
+
+        let mut attachments = Vec::with_capacity(blobs.len());
+        for blob in blobs {
+            let attachment = self
+                .upload_encrypted_attachment(message.conversation_id(), &blob.content, false)
+                .await?;
+            attachments.push(attachment);
+        }
+        let content = content.with_attachments(attachments);
+        let connection = self.inner.connection.lock().await;
+        message.set_content(&connection, content.clone())?;
+        Ok(content)
+    }
+
+    /// Download, decrypt and store the attachments of the received message
+    /// with the given id. Returns the hashes of the attachments, with which
+    /// their content can be loaded via [`Self::attachment`]. Attachments that
+    /// were blocked by the DS are skipped.
+    pub async fn download_message_attachments(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> Result<Vec<AttachmentHash>> {
+        self.download_attachments_where(message_id, |_| true).await
+    }
+
+    /// Download the attachments of the received message with the given id
+    /// that the [`AutoDownloadPolicy`] from the user settings allows on the
+    /// given network, without an explicit request by the user. The other
+    /// attachments are left for [`Self::download_message_attachments`].
+    /// Returns the hashes of the downloaded attachments.
+    pub async fn auto_download_message_attachments(
+        &self,
+        message_id: ConversationMessageId,
+        network_type: NetworkType,
+    ) -> Result<Vec<AttachmentHash>> {
+        let connection = self.inner.connection.lock().await;
+        let policy: AutoDownloadPolicy = StorableUserSetting::load(&connection)?;
+        let conversation_id = ConversationMessage::load(&connection, &message_id.to_uuid())?
+            .ok_or_else(|| anyhow!("Can't find message with id {}", message_id.to_uuid()))?
+            .conversation_id();
+        drop(connection);
+        self.download_attachments_where(message_id, |attachment| {
+            policy.should_download(
+                conversation_id,
+                attachment.kind,
+                attachment.size,
+                network_type,
+            )
+        })
+        .await
+    }
+
+    async fn download_attachments_where(
+        &self,
+        message_id: ConversationMessageId,
+        filter: impl Fn(&EncryptedAttachment) -> bool,
+    ) -> Result<Vec<AttachmentHash>> {
+        let connection = self.inner.connection.lock().await;
+        let message = ConversationMessage::load(&connection, &message_id.to_uuid())?
+            .ok_or_else(|| anyhow!("Can't find message with id {}", message_id.to_uuid()))?;
+        drop(connection);
+        let Message::Content(content_message) = message.message() else {
+            bail!("Message {} is not a content message", message_id.to_uuid());
+        };
+
+        let mut hashes = Vec::new();
+        for attachment in content_message.content().encrypted_attachments() {
+            if !filter(attachment) {
+                continue;
+            }
+            let download = self
+                .download_attachment(message.conversation_id(), &attachment.url)
+                .await?;
+            let ciphertext = match download {
+                AttachmentDownload::Available(ciphertext) => ciphertext,
+                AttachmentDownload::Blocked => {
+                    log::warn!("Attachment {} was blocked", attachment.url);
+                    continue;
+                }
+                AttachmentDownload::Url(_) => {
+                    bail!("Attachment {} wasn't fetched", attachment.url)
+                }
+            };
+            let content = decrypt_attachment(attachment, &ciphertext)?;
+            hashes.push(self.store_attachment(message_id, content).await?);
+        }
+        Ok(hashes)
+    }
+
+    /// Download the attachment at the given URL, which was shared in the
+    /// conversation with the given id.
+    ///
+    /// Attachments are fetched from the DS of the conversation, since the
+    /// request is authenticated with the keys of the group.
+    pub async fn download_attachment(
+        &self,
+        conversation_id: ConversationId,
+        attachment_url: &QualifiedAttachmentUrl,
+    ) -> Result<AttachmentDownload> {
+        // Both generations so far are served by the attachment endpoint of
+        // the DS.
+        if attachment_url.generation() > QualifiedAttachmentUrl::CURRENT_GENERATION {
+            bail!(
+                "Unsupported storage generation {} of attachment {}",
+                attachment_url.generation(),
+                attachment_url.attachment_id()
+            );
+        }
+        let (conversation, group) = self.load_conversation_and_group(conversation_id).await?;
+        let owner_domain = conversation.owner_domain();
+        let download = self
+            .inner
+            .api_clients
+            .get(&owner_domain)?
+            .ds_download_attachment(
+                group.group_id().clone(),
+                group.own_index(),
+                attachment_url.attachment_id(),
+                group.leaf_signer(),
+                group.group_state_ear_key(),
+            )
+            .await?;
+        Ok(download)
+    }
+
+    /// Process the notification that an attachment uploaded by this client
+    /// was blocked by the DS.
+    pub(super) async fn handle_attachment_blocked(
+        &self,
+        attachment_blocked: AttachmentBlocked,
+    ) -> Result<ProcessQsMessageResult> {
+        log::warn!(
+            "Attachment {} was blocked by the DS",
+            attachment_blocked.attachment_id
+        );
+        let connection = self.inner.connection.lock().await;
+        let conversation =
+            Conversation::load_by_group_id(&connection, &attachment_blocked.group_id)?.ok_or(
+                anyhow!(
+                    "Can't find conversation with group id {:?}",
+                    attachment_blocked.group_id
+                ),
+            )?;
+        Ok(ProcessQsMessageResult::ConversationChanged(
+            conversation.id(),
+            vec![],
+        ))
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::Result;
+use phnxtypes::identifiers::QualifiedUserName;
+
+use crate::user_handles::{BlockedUser, MessageRequest, MessageRequestState};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Block the given user. Connection offers of blocked users are dropped
+    /// and their pending message requests are declined.
+    pub async fn block_user(&self, user_name: &QualifiedUserName) -> Result<()> {
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        BlockedUser::block(&transaction, user_name)?;
+        for mut message_request in MessageRequest::load_pending_from(&transaction, user_name)? {
+            message_request.set_state(&transaction, MessageRequestState::Declined)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Unblock the given user. Requests declined while the user was blocked
+    /// stay declined, so further message requests of the user are still
+    /// ignored.
+    pub async fn unblock_user(&self, user_name: &QualifiedUserName) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        BlockedUser::unblock(&connection, user_name)?;
+        Ok(())
+    }
+
+    /// Returns the blocked users, most recently blocked first.
+    pub async fn blocked_users(&self) -> Result<Vec<BlockedUser>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(BlockedUser::load_all(&connection)?)
+    }
+
+    pub(super) async fn is_blocked(&self, user_name: &QualifiedUserName) -> Result<bool> {
+        let connection = self.inner.connection.lock().await;
+        Ok(BlockedUser::is_blocked(&connection, user_name)?)
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use phnxtypes::{crypto::rng, identifiers::QualifiedUserName};
+use rusqlite::Connection;
+use uuid::Uuid;
+
+use crate::{
+    broadcast_lists::{BroadcastDelivery, BroadcastList, BroadcastListId},
+    mimi_content::{MessageId, MimiContent},
+    utils::rt,
+    Contact,
+};
+
+use super::CoreUser;
+
+/// Pause between the messages of a broadcast, such that large lists don't
+/// flood the DS.
+const BROADCAST_SEND_INTERVAL: Duration = Duration::from_millis(250);
+
+impl CoreUser {
+    /// Create a broadcast list with the given name. All recipients must be
+    /// contacts.
+    pub async fn create_broadcast_list(
+        &self,
+        name: String,
+        recipients: Vec<QualifiedUserName>,
+    ) -> Result<BroadcastList> {
+        let connection = self.inner.connection.lock().await;
+        ensure_contacts(&connection, &recipients)?;
+        let list = BroadcastList::new(name, recipients);
+        list.store(&connection)?;
+        Ok(list)
+    }
+
+    /// Replace the name and recipients of the broadcast list with the given
+    /// id. All recipients must be contacts.
+    pub async fn update_broadcast_list(
+        &self,
+        list_id: BroadcastListId,
+        name: String,
+        recipients: Vec<QualifiedUserName>,
+    ) -> Result<BroadcastList> {
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let mut list = BroadcastList::load(&transaction, list_id)?
+            .ok_or_else(|| anyhow!("Can't find broadcast list with id {}", list_id.as_uuid()))?;
+        ensure_contacts(&transaction, &recipients)?;
+        list.update(name, recipients);
+        list.store(&transaction)?;
+        transaction.commit()?;
+        Ok(list)
+    }
+
+    /// Delete the broadcast list with the given id. Messages that were sent
+    /// to the list are kept in the conversations of the recipients.
+    pub async fn delete_broadcast_list(&self, list_id: BroadcastListId) -> Result<()> {
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        BroadcastList::delete(&transaction, list_id)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub async fn broadcast_lists(&self) -> Result<Vec<BroadcastList>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(BroadcastList::load_all(&connection)?)
+    }
+
+    /// Send the given content to the 1:1 conversations of all recipients of
+    /// the broadcast list with the given id.
+    ///
+    /// Messages are sent one after the other with a short pause in between.
+    /// The delivery to each recipient is recorded, such that the state of the
+    /// broadcast can be queried with [`Self::broadcast_deliveries`] later on.
+    /// Failing to deliver to a recipient doesn't stop the broadcast; the
+    /// unsent message stays in the recipient's conversation and can be
+    /// re-sent from there.
+    pub async fn send_broadcast(
+        &self,
+        list_id: BroadcastListId,
+        content: MimiContent,
+    ) -> Result<Vec<BroadcastDelivery>> {
+        let broadcast_id = rng::random_uuid();
+        let mut deliveries = {
+            let mut connection = self.inner.connection.lock().await;
+            let transaction = connection.transaction()?;
+            let list = BroadcastList::load(&transaction, list_id)?.ok_or_else(|| {
+                anyhow!("Can't find broadcast list with id {}", list_id.as_uuid())
+            })?;
+            let deliveries = list
+                .recipients()
+                .iter()
+                .map(|recipient| {
+                    let delivery =
+                        BroadcastDelivery::pending(broadcast_id, list_id, recipient.clone());
+                    delivery.store(&transaction)?;
+                    Ok(delivery)
+                })
+                .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+            transaction.commit()?;
+            deliveries
+        };
+
+        for (index, delivery) in deliveries.iter_mut().enumerate() {
+            if index > 0 {
+                rt::sleep(BROADCAST_SEND_INTERVAL).await;
+            }
+            // Every recipient gets a message with its own id.
+            let mut content = content.clone();
+            content.id = MessageId::new(self.user_name().domain());
+            if let Err(error) = self.send_broadcast_message(delivery, content).await {
+                log::warn!(
+                    "Failed to deliver broadcast to {}: {}",
+                    delivery.recipient(),
+                    error
+                );
+                delivery.mark_failed();
+            }
+            let connection = self.inner.connection.lock().await;
+            delivery.store(&connection)?;
+        }
+        Ok(deliveries)
+    }
+
+    async fn send_broadcast_message(
+        &self,
+        delivery: &mut BroadcastDelivery,
+        content: MimiContent,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let contact = Contact::load(&connection, delivery.recipient())?
+            .ok_or_else(|| anyhow!("{} is no longer a contact", delivery.recipient()))?;
+        drop(connection);
+        delivery.set_conversation_id(contact.conversation_id);
+        let message = self.send_message(contact.conversation_id, content).await?;
+        delivery.mark_sent(message.id());
+        Ok(())
+    }
+
+    /// Returns the deliveries of the broadcast with the given id.
+    pub async fn broadcast_deliveries(&self, broadcast_id: Uuid) -> Result<Vec<BroadcastDelivery>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(BroadcastDelivery::load_broadcast(
+            &connection,
+            broadcast_id,
+        )?)
+    }
+}
+
+fn ensure_contacts(connection: &Connection, user_names: &[QualifiedUserName]) -> Result<()> {
+    for user_name in user_names {
+        if Contact::load(connection, user_name)?.is_none() {
+            bail!("{} is not a contact", user_name);
+        }
+    }
+    Ok(())
+}
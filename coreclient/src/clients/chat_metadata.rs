@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    chat_metadata::{self, ChatColor, ChatMetadata, ChatMetadataKey, SHARED_NAMESPACE},
+    conversations::Conversation,
+    ConversationId,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Returns the metadata of the conversation with the given id.
+    pub async fn chat_metadata(&self, conversation_id: ConversationId) -> Result<ChatMetadata> {
+        let connection = self.inner.connection.lock().await;
+        Ok(ChatMetadata::load(&connection, conversation_id)?)
+    }
+
+    /// Set the metadata entry with the given key of the conversation with the
+    /// given id. The entry is removed if the value is `None`.
+    ///
+    /// Shared entries are sent to the other members, which requires that the
+    /// room policy allows the own user to commit. All other entries are only
+    /// stored locally.
+    pub async fn set_chat_metadata(
+        &self,
+        conversation_id: ConversationId,
+        key: ChatMetadataKey,
+        value: Option<String>,
+    ) -> Result<()> {
+        if let Some(value) = &value {
+            chat_metadata::validate(&key, value)?;
+        }
+        if !key.is_shared() {
+            let connection = self.inner.connection.lock().await;
+            ChatMetadata::store_entry(&connection, conversation_id, &key, value.as_deref())?;
+            return Ok(());
+        }
+
+        // Phase 1: Load the conversation and the group and create the commit
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let mut shared_metadata = ChatMetadata::load(&connection, conversation_id)?
+            .iter()
+            .filter(|(key, _)| key.namespace() == SHARED_NAMESPACE)
+            .map(|(key, value)| (key.name().to_owned(), value.to_owned()))
+            .collect::<chat_metadata::SharedChatMetadata>();
+        match value {
+            Some(value) => shared_metadata.insert(key.name().to_owned(), value),
+            None => shared_metadata.remove(key.name()),
+        };
+        let params = group.update_shared_chat_metadata(&connection, &shared_metadata)?;
+        drop(connection);
+
+        // Phase 2: Send the commit to the DS
+        let owner_domain = conversation.owner_domain();
+        let ds_timestamp = self
+            .inner
+            .api_clients
+            .get(&owner_domain)?
+            .ds_update_group_data(params, group.leaf_signer(), group.group_state_ear_key())
+            .await?;
+
+        // Phase 3: Merge the commit into the group, which also stores the
+        // shared metadata
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        group.merge_pending_commit(&transaction, None, ds_timestamp)?;
+        group.store_update(&transaction)?;
+        transaction.commit()?;
+        group.check_in(&connection)?;
+        Ok(())
+    }
+
+    /// Set the color of the conversation with the given id, which is shared
+    /// with the other members.
+    pub async fn set_chat_color(
+        &self,
+        conversation_id: ConversationId,
+        color: Option<ChatColor>,
+    ) -> Result<()> {
+        let value = color.map(|color| color.to_string());
+        self.set_chat_metadata(conversation_id, ChatMetadataKey::color(), value)
+            .await
+    }
+
+    /// Set the emoji of the conversation with the given id, which is shared
+    /// with the other members.
+    pub async fn set_chat_emoji(
+        &self,
+        conversation_id: ConversationId,
+        emoji: Option<String>,
+    ) -> Result<()> {
+        self.set_chat_metadata(conversation_id, ChatMetadataKey::emoji(), emoji)
+            .await
+    }
+}
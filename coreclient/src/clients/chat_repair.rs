@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    conversations::{messages::ConversationMessage, Conversation},
+    groups::{persistence::StorableGroup, Group},
+    ConversationId,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Repair the conversation with the given [`ConversationId`] if the MLS
+    /// state of its group can't be loaded anymore, e.g. after a disk error or
+    /// a failed migration. See [`crate::groups::repair`].
+    ///
+    /// The client rejoins the group with an external commit and then sends an
+    /// update to the group. The conversation and its messages are preserved.
+    /// Fails if the state of the group is intact.
+    ///
+    /// Returns the messages resulting from the update. Note that these
+    /// returned message have already been persisted.
+    pub async fn repair_chat(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Vec<ConversationMessage>> {
+        // Phase 1: Check that the group state is actually broken
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id().clone();
+        match self.inner.groups.load(&connection, &group_id) {
+            Ok(Some(group)) => {
+                group.check_in(&connection)?;
+                bail!(
+                    "The group of conversation {} is intact",
+                    conversation_id.as_uuid()
+                );
+            }
+            Ok(None) => log::warn!("The MLS state of the group is missing"),
+            Err(error) => log::warn!("The MLS state of the group is corrupted: {error}"),
+        }
+        let stored_group = StorableGroup::load(&connection, &group_id)?
+            .ok_or(anyhow!("Can't repair a group without its keys"))?;
+        drop(connection);
+
+        // Phase 2: Fetch the external commit info from the DS
+        let api_client = self.inner.api_clients.get(&conversation.owner_domain())?;
+        let external_commit_info = api_client
+            .ds_external_commit_info(
+                group_id.clone(),
+                stored_group.group_state_ear_key(),
+                stored_group
+                    .user_auth_key()
+                    .ok_or(anyhow!("No user auth key"))?,
+            )
+            .await?;
+
+        // Phase 3: Rejoin the group locally and resync with the DS
+        let (group, commit) = Group::rejoin_externally(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            stored_group,
+            external_commit_info,
+            self.inner.key_store.signing_key().credential(),
+        )
+        .await?;
+        let ds_timestamp = match api_client
+            .ds_resync_client(
+                commit,
+                group.user_auth_key().ok_or(anyhow!("No user auth key"))?,
+                group.group_state_ear_key(),
+            )
+            .await
+        {
+            Ok(ds_timestamp) => ds_timestamp,
+            Err(error) => {
+                let connection = self.inner.connection.lock().await;
+                Group::discard_repair(&connection, &group_id)?;
+                return Err(error.into());
+            }
+        };
+
+        // Phase 4: Store the repaired group
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        group.store_update(&transaction)?;
+        group.store_epoch_change(&transaction, ds_timestamp)?;
+        transaction.commit()?;
+        self.inner.groups.invalidate(&group_id);
+        drop(connection);
+
+        // Phase 5: Let the other members know about the new leaf. Members of
+        // broadcast rooms can't commit, but the external commit already
+        // replaced their leaf.
+        if !group.own_can_commit()? {
+            return Ok(vec![]);
+        }
+        self.update(conversation_id).await
+    }
+}
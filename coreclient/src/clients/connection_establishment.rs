@@ -2,6 +2,8 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::fmt::Display;
+
 use openmls::group::GroupId;
 use phnxtypes::{
     credentials::{keys::AsIntermediateVerifyingKey, ClientCredential, VerifiableClientCredential},
@@ -9,7 +11,8 @@ use phnxtypes::{
         ear::{
             keys::{
                 AddPackageEarKey, ClientCredentialEarKey, FriendshipPackageEarKey,
-                GroupStateEarKey, SignatureEarKeyWrapperKey, WelcomeAttributionInfoEarKey,
+                GroupStateEarKey, SignatureEarKeyWrapperKey, UserProfileEarKey,
+                WelcomeAttributionInfoEarKey,
             },
             EarDecryptable, EarEncryptable, GenericDeserializable, GenericSerializable,
         },
@@ -21,16 +24,95 @@ use phnxtypes::{
         ConnectionDecryptionKey, ConnectionEncryptionKey,
     },
     messages::{
-        client_as::{EncryptedConnectionEstablishmentPackage, EncryptedFriendshipPackage},
+        client_as::{
+            EncryptedConnectionEstablishmentPackage, EncryptedFriendshipPackage,
+            SenderReputationAttestation,
+        },
         FriendshipToken,
     },
 };
+use thiserror::Error;
 use tls_codec::{
     DeserializeBytes, Serialize as TlsSerializeTrait, TlsDeserializeBytes, TlsSerialize, TlsSize,
 };
 
 use crate::user_profiles::UserProfile;
 
+/// Short message the sender attaches to a connection request to introduce
+/// themselves, e.g. "We met at the conference yesterday".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionIntro {
+    intro: String,
+}
+
+impl ConnectionIntro {
+    /// Maximum length of an intro in bytes
+    pub const MAX_SIZE: usize = 280;
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectionIntroError {
+    #[error("Intro is empty")]
+    Empty,
+    #[error("Intro is longer than {} bytes", ConnectionIntro::MAX_SIZE)]
+    TooLong,
+}
+
+impl TryFrom<String> for ConnectionIntro {
+    type Error = ConnectionIntroError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let intro = value.trim();
+        if intro.is_empty() {
+            return Err(ConnectionIntroError::Empty);
+        }
+        if intro.len() > Self::MAX_SIZE {
+            return Err(ConnectionIntroError::TooLong);
+        }
+        Ok(Self {
+            intro: intro.to_owned(),
+        })
+    }
+}
+
+impl Display for ConnectionIntro {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.intro)
+    }
+}
+
+impl AsRef<str> for ConnectionIntro {
+    fn as_ref(&self) -> &str {
+        &self.intro
+    }
+}
+
+impl tls_codec::Size for ConnectionIntro {
+    fn tls_serialized_len(&self) -> usize {
+        self.intro.as_bytes().tls_serialized_len()
+    }
+}
+
+impl tls_codec::Serialize for ConnectionIntro {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        self.intro.as_bytes().tls_serialize(writer)
+    }
+}
+
+impl tls_codec::DeserializeBytes for ConnectionIntro {
+    fn tls_deserialize_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (intro_bytes, bytes): (Vec<u8>, &[u8]) =
+            tls_codec::DeserializeBytes::tls_deserialize_bytes(bytes)?;
+        let intro = String::from_utf8(intro_bytes).map_err(|_| {
+            tls_codec::Error::DecodingError("Couldn't convert bytes to UTF-8 string".to_string())
+        })?;
+        // Senders might not enforce the limits, so we check them again.
+        let intro =
+            Self::try_from(intro).map_err(|e| tls_codec::Error::DecodingError(e.to_string()))?;
+        Ok((intro, bytes))
+    }
+}
+
 #[derive(Debug, TlsSerialize, TlsSize, Clone)]
 pub struct ConnectionEstablishmentPackageTbs {
     pub(crate) sender_client_credential: ClientCredential,
@@ -40,6 +122,14 @@ pub struct ConnectionEstablishmentPackageTbs {
     pub(crate) connection_group_signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
     pub(crate) friendship_package_ear_key: FriendshipPackageEarKey,
     pub(crate) friendship_package: FriendshipPackage,
+    /// Whether the sender asks for a restricted connection that the recipient
+    /// has to accept explicitly before joining the connection group.
+    pub(crate) message_request: bool,
+    /// Intro shown to the recipient together with the request
+    pub(crate) intro: Option<ConnectionIntro>,
+    /// Reputation of the sender as attested by the sender's AS, such that
+    /// the recipient doesn't have to ask the AS about the sender.
+    pub(crate) sender_reputation: Option<SenderReputationAttestation>,
 }
 
 impl Signable for ConnectionEstablishmentPackageTbs {
@@ -80,6 +170,44 @@ impl SignedStruct<ConnectionEstablishmentPackageTbs> for ConnectionEstablishment
     }
 }
 
+/// A signed [`ConnectionEstablishmentPackage`] in its serialized form, as it
+/// is persisted until it was sent off.
+#[derive(Debug, Clone)]
+pub(crate) struct SerializedConnectionEstablishmentPackage(Vec<u8>);
+
+impl SerializedConnectionEstablishmentPackage {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SerializedConnectionEstablishmentPackage {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl TryFrom<&ConnectionEstablishmentPackage> for SerializedConnectionEstablishmentPackage {
+    type Error = tls_codec::Error;
+
+    fn try_from(package: &ConnectionEstablishmentPackage) -> Result<Self, Self::Error> {
+        Ok(Self(package.tls_serialize_detached()?))
+    }
+}
+
+impl GenericSerializable for SerializedConnectionEstablishmentPackage {
+    type Error = tls_codec::Error;
+
+    fn serialize(&self) -> Result<Vec<u8>, Self::Error> {
+        Ok(self.0.clone())
+    }
+}
+
+impl HpkeEncryptable<ConnectionEncryptionKey, EncryptedConnectionEstablishmentPackage>
+    for SerializedConnectionEstablishmentPackage
+{
+}
+
 mod private_mod {
     #[derive(Default)]
     pub struct Seal;
@@ -94,6 +222,39 @@ pub struct ConnectionEstablishmentPackageTbsIn {
     connection_group_signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
     friendship_package_ear_key: FriendshipPackageEarKey,
     friendship_package: FriendshipPackage,
+    message_request: bool,
+    intro: Option<ConnectionIntro>,
+    sender_reputation: Option<SenderReputationAttestation>,
+}
+
+impl ConnectionEstablishmentPackageTbsIn {
+    pub fn sender_credential(&self) -> &VerifiableClientCredential {
+        &self.sender_client_credential
+    }
+
+    /// Verify the sender's client credential. The signature of the package
+    /// has to be verified before, i.e. this is only meant for payloads that
+    /// were verified and then persisted.
+    pub(crate) fn verify_sender_credential(
+        self,
+        verifying_key: &AsIntermediateVerifyingKey,
+    ) -> Result<ConnectionEstablishmentPackageTbs, SignatureVerificationError> {
+        let sender_client_credential: ClientCredential =
+            self.sender_client_credential.verify(verifying_key)?;
+        Ok(ConnectionEstablishmentPackageTbs {
+            sender_client_credential,
+            connection_group_id: self.connection_group_id,
+            connection_group_ear_key: self.connection_group_ear_key,
+            connection_group_credential_key: self.connection_group_credential_key,
+            connection_group_signature_ear_key_wrapper_key: self
+                .connection_group_signature_ear_key_wrapper_key,
+            friendship_package_ear_key: self.friendship_package_ear_key,
+            friendship_package: self.friendship_package,
+            message_request: self.message_request,
+            intro: self.intro,
+            sender_reputation: self.sender_reputation,
+        })
+    }
 }
 
 impl VerifiedStruct<ConnectionEstablishmentPackageIn> for ConnectionEstablishmentPackageTbsIn {
@@ -131,21 +292,7 @@ impl ConnectionEstablishmentPackageIn {
         self,
         verifying_key: &AsIntermediateVerifyingKey,
     ) -> Result<ConnectionEstablishmentPackageTbs, SignatureVerificationError> {
-        let sender_client_credential: ClientCredential = self
-            .payload
-            .sender_client_credential
-            .verify(verifying_key)?;
-        Ok(ConnectionEstablishmentPackageTbs {
-            sender_client_credential,
-            connection_group_id: self.payload.connection_group_id,
-            connection_group_ear_key: self.payload.connection_group_ear_key,
-            connection_group_credential_key: self.payload.connection_group_credential_key,
-            connection_group_signature_ear_key_wrapper_key: self
-                .payload
-                .connection_group_signature_ear_key_wrapper_key,
-            friendship_package_ear_key: self.payload.friendship_package_ear_key,
-            friendship_package: self.payload.friendship_package,
-        })
+        self.payload.verify_sender_credential(verifying_key)
     }
 }
 
@@ -176,6 +323,8 @@ pub(crate) struct FriendshipPackage {
     pub(crate) signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
     pub(crate) wai_ear_key: WelcomeAttributionInfoEarKey,
     pub(crate) user_profile: UserProfile,
+    /// Key of the profile stored on the AS
+    pub(crate) user_profile_ear_key: UserProfileEarKey,
 }
 
 impl GenericSerializable for FriendshipPackage {
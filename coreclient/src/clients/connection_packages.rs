@@ -0,0 +1,62 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Replenishing the connection packages the AS serves to new contacts.
+//!
+//! The AS never serves expired connection packages and asks the client via
+//! its AS queue to publish fresh ones when they run out.
+
+use anyhow::Result;
+use phnxtypes::{
+    crypto::signatures::signable::Signable,
+    messages::{
+        client_as::{ConnectionPackage, ConnectionPackageTbs},
+        MlsInfraVersion,
+    },
+    time::ExpirationData,
+};
+
+use crate::key_stores::MemoryUserKeyStore;
+
+use super::{CoreUser, CONNECTION_PACKAGES, CONNECTION_PACKAGE_EXPIRATION};
+
+/// Create [`CONNECTION_PACKAGES`] connection packages valid for
+/// [`CONNECTION_PACKAGE_EXPIRATION`].
+pub(super) fn new_connection_packages(
+    key_store: &MemoryUserKeyStore,
+) -> Result<Vec<ConnectionPackage>> {
+    // TODO: For now, we use the same ConnectionDecryptionKey for all
+    // connection packages.
+    let signing_key = key_store.signing_key();
+    (0..CONNECTION_PACKAGES)
+        .map(|_| {
+            let lifetime = ExpirationData::new(CONNECTION_PACKAGE_EXPIRATION);
+            let connection_package_tbs = ConnectionPackageTbs::new(
+                MlsInfraVersion::default(),
+                key_store.connection_decryption_key.encryption_key(),
+                lifetime,
+                signing_key.credential().clone(),
+            );
+            Ok(connection_package_tbs.sign(&signing_key)?)
+        })
+        .collect()
+}
+
+impl CoreUser {
+    /// Publish fresh connection packages, e.g. when asked to by the AS.
+    pub async fn publish_connection_packages(&self) -> Result<()> {
+        let connection_packages = new_connection_packages(&self.inner.key_store)?;
+        self.inner
+            .api_clients
+            .default_client()?
+            .as_publish_connection_packages(
+                self.as_client_id(),
+                connection_packages.into_iter().map(Into::into).collect(),
+                &self.inner.key_store.signing_key(),
+            )
+            .await?;
+        log::info!("Published fresh connection packages");
+        Ok(())
+    }
+}
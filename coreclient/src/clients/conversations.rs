@@ -2,24 +2,94 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::collections::HashSet;
+
 use anyhow::{anyhow, Result};
-use phnxtypes::{codec::PhnxCodec, crypto::ear::EarEncryptable};
+use phnxtypes::{
+    crypto::ear::EarEncryptable,
+    messages::room_policy::{CommitRule, InviteRule, JoinRule, RoomPolicy, SendRule},
+};
 
 use crate::{
-    conversations::{messages::ConversationMessage, Conversation, ConversationAttributes},
-    groups::Group,
+    conversations::{
+        messages::ConversationMessage, Conversation, ConversationAttributes, ConversationExport,
+    },
+    groups::{
+        membership_history::MembershipChange, security::ConversationSecurityStatus, Group,
+        GroupData,
+    },
+    key_stores::as_credentials::AsCredentials,
+    ConversationMessageId,
 };
 
 use super::{ConversationId, CoreUser};
 
+/// Determines who can find and join a chat.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChatVisibility {
+    /// Users can only join when invited by a member.
+    #[default]
+    Private,
+    /// Users can request to join and are admitted by an admin.
+    Knockable,
+}
+
+impl From<ChatVisibility> for JoinRule {
+    fn from(visibility: ChatVisibility) -> Self {
+        match visibility {
+            ChatVisibility::Private => JoinRule::InviteOnly,
+            ChatVisibility::Knockable => JoinRule::Knock,
+        }
+    }
+}
+
+/// Initial permissions of a newly created chat. The creator is always the
+/// only admin of the chat.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChatCreationConfig {
+    pub invite_rule: InviteRule,
+    pub send_rule: SendRule,
+    pub commit_rule: CommitRule,
+    pub visibility: ChatVisibility,
+}
+
+impl From<ChatCreationConfig> for RoomPolicy {
+    fn from(config: ChatCreationConfig) -> Self {
+        RoomPolicy {
+            join_rule: config.visibility.into(),
+            invite_rule: config.invite_rule,
+            send_rule: config.send_rule,
+            commit_rule: config.commit_rule,
+            ..Default::default()
+        }
+    }
+}
+
 impl CoreUser {
-    /// Create new conversation.
+    /// Create new conversation with the default permissions.
     ///
     /// Returns the id of the newly created conversation.
     pub async fn create_conversation(
         &self,
         title: &str,
         conversation_picture_option: Option<Vec<u8>>,
+    ) -> Result<ConversationId> {
+        self.create_chat(
+            title,
+            conversation_picture_option,
+            ChatCreationConfig::default(),
+        )
+        .await
+    }
+
+    /// Create new conversation with the permissions given by the config.
+    ///
+    /// Returns the id of the newly created conversation.
+    pub async fn create_chat(
+        &self,
+        title: &str,
+        conversation_picture_option: Option<Vec<u8>>,
+        config: ChatCreationConfig,
     ) -> Result<ConversationId> {
         let group_id = self
             .inner
@@ -31,15 +101,16 @@ impl CoreUser {
         // Store the conversation attributes in the group's aad
         let conversation_attributes =
             ConversationAttributes::new(title.to_string(), conversation_picture_option);
-        let group_data = PhnxCodec::to_vec(&conversation_attributes)?.into();
+        let group_data = GroupData::encode(&conversation_attributes)?;
 
         // Phase 1: Create and store the group in the OpenMLS provider
         let mut connection = self.inner.connection.lock().await;
         let (group, partial_params) = Group::create_group(
             &mut connection,
-            &self.inner.key_store.signing_key,
+            &self.inner.key_store.signing_key(),
             group_id.clone(),
             group_data,
+            config.into(),
         )?;
         group.store(&connection)?;
         let conversation = Conversation::new_group_conversation(group_id, conversation_attributes);
@@ -51,7 +122,7 @@ impl CoreUser {
         let encrypted_client_credential = self
             .inner
             .key_store
-            .signing_key
+            .signing_key()
             .credential()
             .encrypt(group.credential_ear_key())?;
         let params = partial_params.into_params(encrypted_client_credential, client_reference);
@@ -68,6 +139,7 @@ impl CoreUser {
         Ok(conversation.id())
     }
 
+    #[cfg(feature = "profiles")]
     pub async fn set_conversation_picture(
         &self,
         conversation_id: ConversationId,
@@ -84,6 +156,23 @@ impl CoreUser {
         Ok(())
     }
 
+    /// Mark the conversation with the given id as sensitive or not. Sensitive
+    /// conversations should be protected from screenshots and their
+    /// notifications don't show any content. The flag is only stored locally.
+    pub async fn set_conversation_sensitive(
+        &self,
+        conversation_id: ConversationId,
+        sensitive: bool,
+    ) -> Result<()> {
+        let connection = &self.inner.connection.lock().await;
+        let mut conversation = Conversation::load(connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        conversation.set_sensitive(connection, sensitive)?;
+        Ok(())
+    }
+
     pub async fn last_message(
         &self,
         conversation_id: ConversationId,
@@ -108,19 +197,118 @@ impl CoreUser {
             .flatten()
     }
 
+    /// Load the message with the given id.
+    pub async fn message(&self, message_id: ConversationMessageId) -> Option<ConversationMessage> {
+        let connection = self.inner.connection.lock().await;
+        ConversationMessage::load(&connection, &message_id.to_uuid())
+            .ok()
+            .flatten()
+    }
+
     /// Get the most recent `number_of_messages` messages from the conversation
     /// with the given [`ConversationId`].
+    ///
+    /// Messages that were offloaded to the DS are restored if needed. If that
+    /// fails, only the messages stored locally are returned.
     pub async fn get_messages(
         &self,
         conversation_id: ConversationId,
         number_of_messages: usize,
     ) -> Result<Vec<ConversationMessage>> {
+        loop {
+            let connection = self.inner.connection.lock().await;
+            let messages = ConversationMessage::load_multiple(
+                &connection,
+                conversation_id,
+                number_of_messages as u32,
+            )?;
+            drop(connection);
+            match self
+                .restore_archived_messages(conversation_id, &messages, number_of_messages)
+                .await
+            {
+                Ok(true) => continue,
+                Ok(false) => return Ok(messages),
+                Err(error) => {
+                    log::warn!("Failed to restore archived messages: {error}");
+                    return Ok(messages);
+                }
+            }
+        }
+    }
+
+    /// Get a page of the membership history of the conversation with the
+    /// given [`ConversationId`], most recent changes first.
+    pub async fn membership_history(
+        &self,
+        conversation_id: ConversationId,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<MembershipChange>> {
         let connection = self.inner.connection.lock().await;
-        let messages = ConversationMessage::load_multiple(
-            &connection,
-            conversation_id,
-            number_of_messages as u32,
-        )?;
-        Ok(messages)
+        let history = MembershipChange::load_page(&connection, conversation_id, offset, limit)?;
+        Ok(history)
+    }
+
+    /// Returns the encryption health of the conversation with the given
+    /// [`ConversationId`].
+    ///
+    /// The credentials of ASs that signed members' credentials with an
+    /// intermediate credential that isn't cached are fetched first.
+    pub async fn conversation_security_status(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<ConversationSecurityStatus> {
+        let (group, uncached_signer_domains) = {
+            let connection = self.inner.connection.lock().await;
+            let conversation =
+                Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+                    "Can't find conversation with id {}",
+                    conversation_id.as_uuid()
+                ))?;
+            let group_id = conversation.group_id();
+            let group = Group::load(&connection, group_id)?
+                .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+            let domains = group.uncached_signer_domains(&connection)?;
+            (group, domains)
+        };
+
+        let mut unreachable_domains = HashSet::new();
+        for domain in uncached_signer_domains {
+            if let Err(error) = AsCredentials::fetch_credentials(
+                self.inner.connection.clone(),
+                &domain,
+                &self.inner.api_clients,
+            )
+            .await
+            {
+                log::warn!("Failed to fetch the AS credentials of {domain}: {error}");
+                unreachable_domains.insert(domain);
+            }
+        }
+
+        let connection = self.inner.connection.lock().await;
+        group.security_status(&connection, &unreachable_domains)
+    }
+
+    /// Export the conversation with the given [`ConversationId`] including
+    /// all of its messages and its membership history.
+    pub async fn export_conversation(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<ConversationExport> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let messages = ConversationMessage::load_multiple(&connection, conversation_id, u32::MAX)?;
+        let membership_history =
+            MembershipChange::load_page(&connection, conversation_id, 0, u32::MAX)?;
+        Ok(ConversationExport {
+            conversation,
+            messages,
+            membership_history,
+        })
     }
 }
@@ -3,11 +3,18 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use crate::{
-    groups::client_auth_info::StorableClientCredential,
+    clients::{
+        connection_packages::new_connection_packages, process::worker_pool::WorkerPool,
+        send_queue::SendQueue,
+    },
+    groups::{cache::GroupCache, client_auth_info::StorableClientCredential},
     key_stores::{
         as_credentials::AsCredentials,
         queue_ratchets::{StorableAsQueueRatchet, StorableQsQueueRatchet},
+        signing_key::RotatableSigningKey,
     },
+    presence::PresenceCache,
+    store::StoreNotifier,
 };
 use mls_assist::openmls::prelude::tls_codec::*;
 use opaque_ke::{RegistrationRequest, RegistrationResponse};
@@ -18,7 +25,10 @@ use phnxtypes::{
     crypto::{
         ear::{EarKey, GenericSerializable},
         hpke::ClientIdEncryptionKey,
-        opaque::{OpaqueRegistrationRecord, OpaqueRegistrationRequest},
+        kdf::keys::UserKeySecret,
+        opaque::{OpaqueIdentifiers, OpaqueRegistrationRecord, OpaqueRegistrationRequest},
+        rng::PhnxRng,
+        secrets::SecretBytes,
         signatures::{signable::Verifiable, DEFAULT_SIGNATURE_SCHEME},
     },
     messages::{
@@ -26,9 +36,7 @@ use phnxtypes::{
         client_qs::CreateUserRecordResponse,
         push_token::{EncryptedPushToken, PushToken},
     },
-    time::ExpirationData,
 };
-use rand_chacha::rand_core::OsRng;
 
 use super::*;
 
@@ -89,7 +97,7 @@ impl BasicUserData {
 
         // Let's do OPAQUE registration.
         // First get the server setup information.
-        let mut client_rng = OsRng;
+        let mut client_rng = PhnxRng;
         let client_registration_start_result: ClientRegistrationStartResult<OpaqueCiphersuite> =
             ClientRegistration::<OpaqueCiphersuite>::start(
                 &mut client_rng,
@@ -104,7 +112,11 @@ impl BasicUserData {
                 .message
                 .serialize()
                 .to_vec(),
-            opaque_state: client_registration_start_result.state.serialize().to_vec(),
+            opaque_state: client_registration_start_result
+                .state
+                .serialize()
+                .to_vec()
+                .into(),
             server_url: self.server_url,
             password: self.password,
             qs_encryption_key,
@@ -124,7 +136,8 @@ pub(crate) struct InitialUserState {
     client_credential_payload: ClientCredentialPayload,
     prelim_signing_key: PreliminaryClientSigningKey,
     opaque_message: Vec<u8>,
-    opaque_state: Vec<u8>,
+    // Contains the password-derived blinding secret
+    opaque_state: SecretBytes,
     server_url: String,
     password: String,
     qs_encryption_key: ClientIdEncryptionKey,
@@ -203,22 +216,18 @@ impl PostRegistrationInitState {
         } = self.initial_user_state;
 
         let user_name = client_credential_payload.identity().user_name();
-        let domain = user_name.domain();
 
         // Complete the OPAQUE registration.
-        let user_name_bytes = user_name.tls_serialize_detached()?;
-        let domain_bytes = domain.tls_serialize_detached()?;
-        let identifiers = Identifiers {
-            client: Some(&user_name_bytes),
-            server: Some(&domain_bytes),
-        };
+        let identifiers = OpaqueIdentifiers::new(&user_name)?;
         let opaque_server_response =
             RegistrationResponse::<OpaqueCiphersuite>::deserialize(&self.opaque_server_response)
                 .map_err(|e| anyhow!("Error deserializing OPAQUE response: {:?}", e))?;
-        let response_parameters = ClientRegistrationFinishParameters::new(identifiers, None);
-        let opaque_state = ClientRegistration::<OpaqueCiphersuite>::deserialize(&opaque_state)
-            .map_err(|e| anyhow!("Error deserializing OPAQUE state: {:?}", e))?;
-        let mut client_rng = OsRng;
+        let response_parameters =
+            ClientRegistrationFinishParameters::new(identifiers.identifiers(), None);
+        let opaque_state =
+            ClientRegistration::<OpaqueCiphersuite>::deserialize(opaque_state.expose_secret())
+                .map_err(|e| anyhow!("Error deserializing OPAQUE state: {:?}", e))?;
+        let mut client_rng = PhnxRng;
         let client_registration_finish_result: ClientRegistrationFinishResult<OpaqueCiphersuite> =
             opaque_state
                 .finish(
@@ -240,64 +249,27 @@ impl PostRegistrationInitState {
         // Store the own client credential in the DB
         StorableClientCredential::new(client_credential.clone()).store(connection)?;
 
-        let as_queue_decryption_key = RatchetDecryptionKey::generate()?;
         let as_initial_ratchet_secret = RatchetSecret::random()?;
         StorableAsQueueRatchet::initialize(connection, as_initial_ratchet_secret.clone())?;
         let qs_initial_ratchet_secret = RatchetSecret::random()?;
         StorableQsQueueRatchet::initialize(connection, qs_initial_ratchet_secret.clone())?;
-        let qs_queue_decryption_key = RatchetDecryptionKey::generate()?;
-        let qs_client_signing_key = QsClientSigningKey::random()?;
-        let qs_user_signing_key = QsUserSigningKey::generate()?;
-
-        // TODO: The following five keys should be derived from a single
-        // friendship key. Once that's done, remove the random constructors.
-        let friendship_token = FriendshipToken::random()?;
-        let add_package_ear_key = AddPackageEarKey::random()?;
-        let client_credential_ear_key = ClientCredentialEarKey::random()?;
-        let signature_ear_key_wrapper_key = SignatureEarKeyWrapperKey::random()?;
-        let wai_ear_key: WelcomeAttributionInfoEarKey = WelcomeAttributionInfoEarKey::random()?;
-        let push_token_ear_key = PushTokenEarKey::random()?;
 
-        let connection_decryption_key = ConnectionDecryptionKey::generate()?;
+        // Keys shared by all clients of the user are derived from the OPAQUE
+        // export key, which the user's other clients obtain when logging in.
+        let user_key_secret =
+            UserKeySecret::from_opaque_export_key(&client_registration_finish_result.export_key);
+        let key_store = generate_key_store(signing_key, qs_encryption_key, &user_key_secret)?;
 
         let encrypted_push_token = match push_token {
             Some(push_token) => Some(EncryptedPushToken::from(
-                push_token_ear_key.encrypt(&push_token.serialize()?)?,
+                key_store
+                    .push_token_ear_key
+                    .encrypt(&push_token.serialize()?)?,
             )),
             None => None,
         };
 
-        let key_store = MemoryUserKeyStore {
-            signing_key,
-            as_queue_decryption_key,
-            connection_decryption_key,
-            qs_client_signing_key,
-            qs_user_signing_key,
-            qs_queue_decryption_key,
-            push_token_ear_key,
-            friendship_token,
-            add_package_ear_key,
-            client_credential_ear_key,
-            signature_ear_key_wrapper_key,
-            wai_ear_key,
-            qs_client_id_encryption_key: qs_encryption_key,
-        };
-
-        // TODO: For now, we use the same ConnectionDecryptionKey for all
-        // connection packages.
-
-        let mut connection_packages = vec![];
-        for _ in 0..CONNECTION_PACKAGES {
-            let lifetime = ExpirationData::new(CONNECTION_PACKAGE_EXPIRATION);
-            let connection_package_tbs = ConnectionPackageTbs::new(
-                MlsInfraVersion::default(),
-                key_store.connection_decryption_key.encryption_key(),
-                lifetime,
-                key_store.signing_key.credential().clone(),
-            );
-            let connection_package = connection_package_tbs.sign(&key_store.signing_key)?;
-            connection_packages.push(connection_package);
-        }
+        let connection_packages = new_connection_packages(&key_store)?;
 
         let unfinalized_registration_state = UnfinalizedRegistrationState {
             key_store,
@@ -324,6 +296,52 @@ impl PostRegistrationInitState {
     }
 }
 
+/// Generate the keys of a new client with the given signing key. Keys shared
+/// by all clients of the user are derived from the given secret.
+fn generate_key_store(
+    signing_key: ClientSigningKey,
+    qs_client_id_encryption_key: ClientIdEncryptionKey,
+    user_key_secret: &UserKeySecret,
+) -> Result<MemoryUserKeyStore> {
+    let as_queue_decryption_key = RatchetDecryptionKey::generate()?;
+    let qs_queue_decryption_key = RatchetDecryptionKey::generate()?;
+    let qs_client_signing_key = QsClientSigningKey::random()?;
+    let qs_user_signing_key = QsUserSigningKey::generate()?;
+
+    // TODO: The following five keys should be derived from a single
+    // friendship key. Once that's done, remove the random constructors.
+    let friendship_token = FriendshipToken::random()?;
+    let add_package_ear_key = AddPackageEarKey::random()?;
+    let client_credential_ear_key = ClientCredentialEarKey::random()?;
+    let signature_ear_key_wrapper_key = SignatureEarKeyWrapperKey::random()?;
+    let wai_ear_key: WelcomeAttributionInfoEarKey = WelcomeAttributionInfoEarKey::random()?;
+    let push_token_ear_key = PushTokenEarKey::random()?;
+    let self_message_ear_key = SelfMessageEarKey::derive_from(user_key_secret)?;
+    let user_settings_ear_key = UserSettingsEarKey::derive_from(user_key_secret)?;
+    let user_profile_ear_key = UserProfileEarKey::derive_from(user_key_secret)?;
+
+    let connection_decryption_key = ConnectionDecryptionKey::generate()?;
+
+    Ok(MemoryUserKeyStore {
+        signing_key: RotatableSigningKey::new(signing_key),
+        as_queue_decryption_key,
+        connection_decryption_key,
+        qs_client_signing_key,
+        qs_user_signing_key,
+        qs_queue_decryption_key,
+        push_token_ear_key,
+        friendship_token,
+        add_package_ear_key,
+        client_credential_ear_key,
+        signature_ear_key_wrapper_key,
+        wai_ear_key,
+        user_profile_ear_key: Some(user_profile_ear_key),
+        qs_client_id_encryption_key,
+        self_message_ear_key: Some(self_message_ear_key),
+        user_settings_ear_key: Some(user_settings_ear_key),
+    })
+}
+
 // State after server response to OPAKE initialization
 //
 // WARNING: This type is stored in sqlite as a blob. If any changes are made
@@ -368,7 +386,7 @@ impl UnfinalizedRegistrationState {
                 as_initial_ratchet_secret,
                 connection_packages,
                 opaque_registration_record,
-                &key_store.signing_key,
+                &key_store.signing_key(),
             )
             .await?;
         let as_registered_user_state = AsRegisteredUserState {
@@ -381,7 +399,7 @@ impl UnfinalizedRegistrationState {
     }
 
     pub(super) fn client_id(&self) -> &AsClientId {
-        self.key_store.signing_key.credential().identity_ref()
+        self.key_store.client_id()
     }
 
     pub(super) fn server_url(&self) -> &str {
@@ -436,7 +454,7 @@ impl AsRegisteredUserState {
     }
 
     pub(super) fn client_id(&self) -> &AsClientId {
-        self.key_store.signing_key.credential().identity_ref()
+        self.key_store.client_id()
     }
 
     pub(super) fn server_url(&self) -> &str {
@@ -511,7 +529,7 @@ impl QsRegisteredUserState {
     }
 
     pub(super) fn client_id(&self) -> &AsClientId {
-        self.key_store.signing_key.credential().identity_ref()
+        self.key_store.client_id()
     }
 
     pub(super) fn server_url(&self) -> &str {
@@ -529,10 +547,54 @@ pub(crate) struct PersistedUserState {
 }
 
 impl PersistedUserState {
+    /// Generate the profile key of users that were created before profiles
+    /// were stored on the AS. Returns `true` if a key was generated, in which
+    /// case the state has to be stored again. The key is shared with existing
+    /// contacts with the next profile upload or refresh.
+    pub(super) fn ensure_user_profile_ear_key(&mut self) -> Result<bool> {
+        let key_store = &mut self.state.key_store;
+        if key_store.user_profile_ear_key.is_some() {
+            return Ok(false);
+        }
+        key_store.user_profile_ear_key = Some(UserProfileEarKey::random()?);
+        Ok(true)
+    }
+
     pub(super) fn into_self_user(
         self,
         connection: SqliteConnection,
         api_clients: ApiClients,
+    ) -> CoreUser {
+        self.into_user(
+            connection,
+            api_clients,
+            GroupCache::default(),
+            WorkerPool::default(),
+        )
+    }
+
+    /// Like [`Self::into_self_user`], but without caching groups and
+    /// processing messages on a single thread to keep the memory footprint of
+    /// app extensions low.
+    pub(super) fn into_extension_user(
+        self,
+        connection: SqliteConnection,
+        api_clients: ApiClients,
+    ) -> CoreUser {
+        self.into_user(
+            connection,
+            api_clients,
+            GroupCache::new(0),
+            WorkerPool::new(1),
+        )
+    }
+
+    fn into_user(
+        self,
+        connection: SqliteConnection,
+        api_clients: ApiClients,
+        groups: GroupCache,
+        worker_pool: WorkerPool,
     ) -> CoreUser {
         let QsRegisteredUserState {
             key_store,
@@ -543,15 +605,23 @@ impl PersistedUserState {
         let inner = Arc::new(CoreUserInner {
             connection,
             key_store,
-            _qs_user_id: qs_user_id,
+            qs_user_id,
             qs_client_id,
             api_clients: api_clients.clone(),
+            groups,
+            worker_pool,
+            presence: PresenceCache::default(),
+            store_notifier: StoreNotifier::default(),
+            send_queue: SendQueue::default(),
+            read_marker_outbox: Default::default(),
+            queue_lock: Default::default(),
+            connections_in_flight: Default::default(),
         });
         CoreUser { inner }
     }
 
     pub(super) fn client_id(&self) -> &AsClientId {
-        self.state.key_store.signing_key.credential().identity_ref()
+        self.state.key_store.client_id()
     }
 
     pub(super) fn server_url(&self) -> &str {
@@ -566,3 +636,98 @@ impl PersistedUserState {
         &self.state.qs_client_id
     }
 }
+
+#[cfg(test)]
+impl PersistedUserState {
+    /// A registered user whose client credential is signed by a locally
+    /// generated AS instead of the AS of its domain. Allows tests to run a
+    /// [`CoreUser`] against a scripted server.
+    pub(super) fn new_simulated(
+        connection: &Connection,
+        as_client_id: AsClientId,
+        server_url: String,
+    ) -> Result<Self> {
+        use phnxtypes::{
+            credentials::{
+                keys::AsIntermediateSigningKey, AsCredential, AsIntermediateCredentialCsr,
+            },
+            crypto::hpke::ClientIdDecryptionKey,
+        };
+
+        let domain = as_client_id.user_name().domain();
+        let (_, as_signing_key) =
+            AsCredential::new(DEFAULT_SIGNATURE_SCHEME, domain.clone(), None)?;
+        let (intermediate_csr, prelim_intermediate_key) =
+            AsIntermediateCredentialCsr::new(DEFAULT_SIGNATURE_SCHEME, domain)?;
+        let intermediate_credential = intermediate_csr.sign(&as_signing_key, None)?;
+        let intermediate_signing_key = AsIntermediateSigningKey::from_prelim_key(
+            prelim_intermediate_key,
+            intermediate_credential,
+        )?;
+
+        let (client_credential_csr, prelim_signing_key) =
+            ClientCredentialCsr::new(as_client_id, DEFAULT_SIGNATURE_SCHEME)?;
+        let client_credential = ClientCredentialPayload::new(
+            client_credential_csr,
+            None,
+            intermediate_signing_key.credential().fingerprint().clone(),
+        )
+        .sign_with_intermediate(&intermediate_signing_key)?;
+        StorableClientCredential::new(client_credential.clone()).store(connection)?;
+        let signing_key = ClientSigningKey::from_prelim_key(prelim_signing_key, client_credential)?;
+
+        StorableAsQueueRatchet::initialize(connection, RatchetSecret::random()?)?;
+        StorableQsQueueRatchet::initialize(connection, RatchetSecret::random()?)?;
+        let qs_encryption_key = ClientIdDecryptionKey::generate()?.encryption_key();
+        // The simulated user has no OPAQUE registration.
+        let user_key_secret = UserKeySecret::from_opaque_export_key(&[]);
+        let key_store = generate_key_store(signing_key, qs_encryption_key, &user_key_secret)?;
+
+        Ok(Self {
+            state: QsRegisteredUserState {
+                key_store,
+                server_url,
+                qs_user_id: QsUserId::random(),
+                qs_client_id: QsClientId::random(&mut PhnxRng),
+            },
+        })
+    }
+
+    /// The contact that other users store for this user once they are
+    /// connected, together with the client credential of this user.
+    pub(super) fn simulated_contact(
+        &self,
+        conversation_id: ConversationId,
+    ) -> (crate::Contact, ClientCredential) {
+        let key_store = &self.state.key_store;
+        let contact = crate::Contact {
+            user_name: key_store.client_id().user_name(),
+            clients: vec![key_store.client_id().clone()],
+            wai_ear_key: key_store.wai_ear_key.clone(),
+            friendship_token: key_store.friendship_token.clone(),
+            add_package_ear_key: key_store.add_package_ear_key.clone(),
+            client_credential_ear_key: key_store.client_credential_ear_key.clone(),
+            signature_ear_key_wrapper_key: key_store.signature_ear_key_wrapper_key.clone(),
+            conversation_id,
+            user_profile_ear_key: key_store.user_profile_ear_key.clone(),
+            verified_at: None,
+        };
+        (contact, key_store.signing_key().credential().clone())
+    }
+
+    /// Generate an add package of this user like the ones the QS hands out
+    /// to contacts. The private keys are stored in the given DB.
+    pub(super) fn simulated_add_package(
+        &self,
+        connection: &Connection,
+    ) -> Result<phnxtypes::keypackage_batch::AddPackage> {
+        let key_store = &self.state.key_store;
+        let encrypted_client_credential = key_store.encrypt_client_credential()?;
+        key_store.generate_add_package(
+            connection,
+            &self.state.qs_client_id,
+            &encrypted_client_credential,
+            false,
+        )
+    }
+}
@@ -0,0 +1,345 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Renewal of the client credential ahead of its expiration.
+//!
+//! A new credential is issued by the AS for a fresh signing key. The request is
+//! signed with the current key, which the AS keeps accepting until the client
+//! has confirmed the new credential with a request signed by the new key. The
+//! new key is persisted as pending before the confirmation and only replaces
+//! the current key once the AS has acknowledged it. Afterwards, the own leaves
+//! in all groups are updated to the new credential. In groups in which the own
+//! client can't commit, the update is proposed and applied once an admin
+//! commits it.
+
+use anyhow::{anyhow, bail, Result};
+use phnxtypes::{
+    credentials::{
+        keys::ClientSigningKey, AsIntermediateCredential, ClientCredential, ClientCredentialCsr,
+        ClientCredentialPayload,
+    },
+    crypto::signatures::DEFAULT_SIGNATURE_SCHEME,
+    time::{Duration, TimeStamp},
+};
+
+use crate::{
+    conversations::{messages::ConversationMessage, Conversation, ConversationStatus},
+    groups::client_auth_info::StorableClientCredential,
+    key_stores::{as_credentials::AsCredentials, signing_key::StorableClientSigningKey},
+};
+
+use super::CoreUser;
+
+/// The client credential is renewed when it, or the AS intermediate credential
+/// that signed it, expires within this period.
+pub(crate) const CREDENTIAL_REFRESH_THRESHOLD: Duration = Duration::days(14);
+
+impl CoreUser {
+    /// Apply a signing key that replaced the one created during registration.
+    pub(super) async fn load_rotated_signing_key(&self) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        if let Some(signing_key) = StorableClientSigningKey::load(&connection)? {
+            self.inner.key_store.rotate_signing_key(signing_key);
+        }
+        Ok(())
+    }
+
+    async fn signer_credential(
+        &self,
+        credential: &ClientCredential,
+    ) -> Result<AsIntermediateCredential> {
+        let intermediate_credential = AsCredentials::get(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            &credential.identity_ref().user_name().domain(),
+            credential.signer_fingerprint(),
+        )
+        .await?;
+        Ok(intermediate_credential)
+    }
+
+    /// The point in time at which the own client credential becomes invalid,
+    /// i.e. the earlier of its own expiration and the expiration of the AS
+    /// intermediate credential that signed it.
+    pub async fn client_credential_expiration(&self) -> Result<TimeStamp> {
+        let signing_key = self.inner.key_store.signing_key();
+        let credential = signing_key.credential();
+        let intermediate_credential = self.signer_credential(credential).await?;
+        let own_expiration = credential.expiration_data().not_after();
+        let signer_expiration = intermediate_credential.expiration_data().not_after();
+        Ok((*own_expiration).min(*signer_expiration).into())
+    }
+
+    /// Returns `true` if the own client credential or the AS intermediate
+    /// credential that signed it expires within
+    /// [`CREDENTIAL_REFRESH_THRESHOLD`].
+    pub async fn client_credential_needs_refresh(&self) -> Result<bool> {
+        let signing_key = self.inner.key_store.signing_key();
+        let credential = signing_key.credential();
+        if credential
+            .expiration_data()
+            .expires_within(CREDENTIAL_REFRESH_THRESHOLD)
+        {
+            return Ok(true);
+        }
+        let intermediate_credential = self.signer_credential(credential).await?;
+        Ok(intermediate_credential
+            .expiration_data()
+            .expires_within(CREDENTIAL_REFRESH_THRESHOLD))
+    }
+
+    /// Have the AS issue a new client credential for a fresh signing key,
+    /// rotate the signing key and update the own leaves in all groups.
+    ///
+    /// If the confirmation of the new credential fails, the current key stays
+    /// in use and the next call retries the confirmation. If updating some of
+    /// the groups fails, the key is still rotated and the remaining groups can
+    /// be updated later via [`Self::update_group_credentials`].
+    pub async fn refresh_client_credential(&self) -> Result<Vec<ConversationMessage>> {
+        // Phase 0: Finish an earlier refresh whose confirmation didn't go
+        // through.
+        if self.confirm_leftover_signing_key().await? {
+            return self.update_group_credentials().await;
+        }
+
+        // Phase 1: Have the AS issue a credential for a fresh key.
+        let signing_key = self.request_pending_signing_key().await?;
+
+        // Phase 2: Confirm the new credential and rotate the signing key.
+        self.confirm_pending_signing_key(signing_key).await?;
+
+        // Phase 3: Update the own leaves in all groups.
+        self.update_group_credentials().await
+    }
+
+    /// Have the AS issue a credential for a fresh signing key and persist the
+    /// key as pending.
+    async fn request_pending_signing_key(&self) -> Result<ClientSigningKey> {
+        let client_id = self.inner.key_store.client_id().clone();
+        let domain = client_id.user_name().domain();
+
+        // Create a CSR for the currently active AS intermediate credential.
+        let as_intermediate_credential = AsCredentials::get_intermediate_credential(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            &domain,
+        )
+        .await?;
+        let (client_credential_csr, prelim_signing_key) =
+            ClientCredentialCsr::new(client_id, DEFAULT_SIGNATURE_SCHEME)?;
+        let client_credential_payload = ClientCredentialPayload::new(
+            client_credential_csr,
+            None,
+            as_intermediate_credential.fingerprint().clone(),
+        );
+
+        // Have the AS sign the new credential.
+        let response = self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_refresh_client_credential(
+                client_credential_payload,
+                &self.inner.key_store.signing_key(),
+            )
+            .await?;
+
+        // Verify the credential and persist the new key as pending.
+        let client_credential = AsCredentials::verify_client_credential(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            response.client_credential,
+        )
+        .await?;
+        let signing_key = ClientSigningKey::from_prelim_key(prelim_signing_key, client_credential)?;
+        let connection = self.inner.connection.lock().await;
+        StorableClientSigningKey::store_pending(&connection, &signing_key)?;
+        drop(connection);
+        Ok(signing_key)
+    }
+
+    /// Have the AS issue a new client credential without confirming it, as if
+    /// the confirmation was lost.
+    #[cfg(feature = "test_utils")]
+    pub async fn refresh_client_credential_unconfirmed(&self) -> Result<()> {
+        self.request_pending_signing_key().await?;
+        Ok(())
+    }
+
+    /// Returns `true` if the own leaf in the given conversation still uses a
+    /// client credential other than the current one.
+    #[cfg(feature = "test_utils")]
+    pub async fn has_outdated_group_credential(
+        &self,
+        conversation_id: crate::ConversationId,
+    ) -> Result<bool> {
+        let signing_key = self.inner.key_store.signing_key();
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let outdated = group.has_outdated_client_credential(
+            &connection,
+            &signing_key.credential().fingerprint()?,
+        )?;
+        group.check_in(&connection)?;
+        Ok(outdated)
+    }
+
+    /// Confirm a pending signing key left over from an interrupted refresh.
+    ///
+    /// Returns `false` if there is no such key or the AS didn't accept it. In
+    /// the latter case, the key is kept, since the AS might have processed the
+    /// confirmation without the response reaching the client. If the AS never
+    /// issued the key, the next refresh replaces it.
+    async fn confirm_leftover_signing_key(&self) -> Result<bool> {
+        let connection = self.inner.connection.lock().await;
+        let pending_signing_key = StorableClientSigningKey::load_pending(&connection)?;
+        drop(connection);
+        let Some(signing_key) = pending_signing_key else {
+            return Ok(false);
+        };
+        match self.confirm_pending_signing_key(signing_key).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                log::warn!("Failed to confirm pending signing key: {e:?}");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Have the AS switch to the credential of the given pending key, then
+    /// persist the key as the current one and rotate it in memory.
+    async fn confirm_pending_signing_key(&self, signing_key: ClientSigningKey) -> Result<()> {
+        self.inner
+            .api_clients
+            .default_client()?
+            .as_confirm_client_credential(&signing_key)
+            .await?;
+
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        StorableClientCredential::new(signing_key.credential().clone()).store(&transaction)?;
+        StorableClientSigningKey::store(&transaction, &signing_key)?;
+        StorableClientSigningKey::delete_pending(&transaction)?;
+        transaction.commit()?;
+        self.inner.key_store.rotate_signing_key(signing_key);
+        Ok(())
+    }
+
+    /// Update the own leaf in all active groups in which it still uses a
+    /// client credential other than the current one.
+    ///
+    /// Returns the resulting system messages. Fails if any of the groups
+    /// couldn't be updated, after attempting to update all of them.
+    pub async fn update_group_credentials(&self) -> Result<Vec<ConversationMessage>> {
+        let signing_key = self.inner.key_store.signing_key();
+        let connection = self.inner.connection.lock().await;
+        let conversations = Conversation::load_all(&connection)?;
+        drop(connection);
+
+        let mut messages = Vec::new();
+        let mut failures = 0;
+        for conversation in conversations {
+            if !matches!(conversation.status(), ConversationStatus::Active) {
+                continue;
+            }
+            match self
+                .update_group_credential(&conversation, &signing_key)
+                .await
+            {
+                Ok(conversation_messages) => messages.extend(conversation_messages),
+                Err(e) => {
+                    log::error!(
+                        "Failed to update client credential in conversation {}: {:?}",
+                        conversation.id().as_uuid(),
+                        e
+                    );
+                    failures += 1;
+                }
+            }
+        }
+        if failures > 0 {
+            bail!("Failed to update client credential in {failures} conversation(s)");
+        }
+        Ok(messages)
+    }
+
+    async fn update_group_credential(
+        &self,
+        conversation: &Conversation,
+        signing_key: &ClientSigningKey,
+    ) -> Result<Vec<ConversationMessage>> {
+        // Phase 1: Load the group and create the commit if necessary
+        let connection = self.inner.connection.lock().await;
+        let group_id = conversation.group_id();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        if !group
+            .has_outdated_client_credential(&connection, &signing_key.credential().fingerprint()?)?
+        {
+            group.check_in(&connection)?;
+            return Ok(vec![]);
+        }
+        // Members of broadcast rooms can't commit pending proposals. They
+        // propose the update instead and an admin commits it.
+        if !group.own_can_self_update()? {
+            if group.has_own_pending_update() {
+                group.check_in(&connection)?;
+                return Ok(vec![]);
+            }
+            let params = group.propose_client_credential_update(&connection, signing_key)?;
+            drop(connection);
+            let result = self
+                .inner
+                .api_clients
+                .get(&conversation.owner_domain())?
+                .ds_propose_client_update(params, group.group_state_ear_key(), group.leaf_signer())
+                .await;
+            let connection = self.inner.connection.lock().await;
+            if result.is_err() {
+                group.discard_own_update_proposal(&connection)?;
+            }
+            group.store_update(&connection)?;
+            group.check_in(&connection)?;
+            result?;
+            return Ok(vec![]);
+        }
+        let params = group.update_client_credential(&connection, signing_key)?;
+        drop(connection);
+
+        // Phase 2: Send the update to the DS. The commit is still signed with
+        // the old leaf signer.
+        let owner_domain = conversation.owner_domain();
+        let ds_timestamp = self
+            .inner
+            .api_clients
+            .get(&owner_domain)?
+            .ds_update_client(params, group.group_state_ear_key(), group.leaf_signer())
+            .await?;
+
+        // Phase 3: Merge the commit into the group
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        let group_messages = group.merge_pending_commit(&transaction, None, ds_timestamp)?;
+        group.store_update(&transaction)?;
+        let conversation_messages =
+            Self::store_messages(&mut transaction, conversation.id(), group_messages)?;
+        transaction.commit()?;
+        group.check_in(&connection)?;
+        drop(connection);
+
+        Ok(conversation_messages)
+    }
+}
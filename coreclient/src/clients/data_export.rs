@@ -0,0 +1,59 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, Result};
+use phnxtypes::{
+    crypto::{hpke::HpkeDecryptable, DataExportDecryptionKey},
+    identifiers::DataExportId,
+    messages::data_export::{DataExport, DataExportStatus},
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Request an export of the personal data the AS holds about the user.
+    ///
+    /// The export is encrypted to the returned key, which is needed to read
+    /// it after downloading. The key is not stored.
+    pub async fn request_data_export(&self) -> Result<(DataExportId, DataExportDecryptionKey)> {
+        let decryption_key = DataExportDecryptionKey::generate()?;
+        let export_id = self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_request_data_export(
+                decryption_key.encryption_key(),
+                &self.inner.key_store.signing_key(),
+            )
+            .await?;
+        Ok((export_id, decryption_key))
+    }
+
+    pub async fn data_export_status(&self, export_id: DataExportId) -> Result<DataExportStatus> {
+        let status = self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_data_export_status(export_id, &self.inner.key_store.signing_key())
+            .await?;
+        Ok(status)
+    }
+
+    /// Download and decrypt the export with the given id. The export must be
+    /// ready, see [`Self::data_export_status`].
+    pub async fn download_data_export(
+        &self,
+        export_id: DataExportId,
+        decryption_key: &DataExportDecryptionKey,
+    ) -> Result<DataExport> {
+        let encrypted = self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_download_data_export(export_id, &self.inner.key_store.signing_key())
+            .await?;
+        DataExport::decrypt(encrypted, decryption_key, &[], &[])
+            .map_err(|e| anyhow!("Failed to decrypt data export: {e:?}"))
+    }
+}
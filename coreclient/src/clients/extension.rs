@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Loading the user in app extensions, e.g. the iOS notification service
+//! extension (NSE).
+//!
+//! Extensions run in their own process next to the app, share the databases
+//! with it via the app group container and are subject to tight memory limits
+//! (24 MiB for the NSE). Therefore, an extension never creates or migrates
+//! databases, never finishes the user registration and doesn't cache groups.
+//!
+//! Extensions open the client DB read-only and never process queue messages,
+//! since that would advance the queue ratchets and MLS groups the app holds
+//! in memory. Instead, they peek at the queues (see
+//! [`CoreUser::preview_queue_messages`]) and leave the messages to the app. The
+//! previews only require the QS queue ratchet, which the app stores in the
+//! shared client DB. Extensions only show previews while the app doesn't own
+//! the queues (see [`queue_lock`]); otherwise, the app shows the messages
+//! itself.
+//!
+//! [`queue_lock`]: super::queue_lock
+
+use std::time::Duration;
+
+use openmls::prelude::{MlsMessageBodyIn, ProtocolMessage};
+use phnxtypes::messages::client_ds::ExtractedQsQueueMessagePayload;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    key_stores::queue_ratchets::StorableQsQueueRatchet,
+    utils::{
+        migration::has_pending_migrations,
+        persistence::{open_client_db_read_only, PHNX_DB_NAME},
+    },
+};
+
+use super::{store::ClientRecordState, *};
+
+/// Time an extension waits for the app to finish a write transaction on the
+/// phnx.db.
+const EXTENSION_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message in the queues of the client, as far as an extension can tell
+/// without processing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueMessagePreview {
+    /// A message in the conversation with the given id. Its content is only
+    /// known once the app processed it.
+    Message(ConversationId),
+    /// An invitation to a new conversation.
+    NewConversation,
+    /// A connection request or a message request.
+    ConnectionRequest,
+}
+
+impl CoreUser {
+    /// Load the user of the device for an app extension. Returns `None` if
+    /// there is no fully registered user, if the app has not migrated the
+    /// database to the current version yet or if the app owns the queues.
+    ///
+    /// The client DB is opened read-only.
+    pub async fn load_for_extension(db_path: &str) -> Result<Option<CoreUser>> {
+        let phnx_db_connection = Connection::open_with_flags(
+            format!("{db_path}/{PHNX_DB_NAME}"),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        phnx_db_connection.busy_timeout(EXTENSION_BUSY_TIMEOUT)?;
+        let Some(client_record) = ClientRecord::load_all(&phnx_db_connection)?
+            .into_iter()
+            .rev()
+            .find(|record| matches!(record.client_record_state, ClientRecordState::Finished))
+        else {
+            return Ok(None);
+        };
+        drop(phnx_db_connection);
+
+        let as_client_id = client_record.as_client_id;
+        let mut client_db_connection = open_client_db_read_only(&as_client_id, db_path)?;
+        if has_pending_migrations(&mut client_db_connection)? {
+            log::info!("Database needs to be migrated by the app first");
+            return Ok(None);
+        }
+
+        let Some(UserCreationState::FinalUserState(final_state)) =
+            UserCreationState::load(&client_db_connection, &as_client_id)?
+        else {
+            return Ok(None);
+        };
+
+        let api_clients =
+            ApiClients::new(as_client_id.user_name().domain(), final_state.server_url());
+        let self_user = final_state
+            .into_extension_user(SqliteConnection::new(client_db_connection), api_clients);
+        self_user.inner.queue_lock.set_path(&as_client_id, db_path);
+        if self_user.inner.queue_lock.is_held_elsewhere()? {
+            log::info!("The app is processing the queues");
+            return Ok(None);
+        }
+        self_user.load_rotated_signing_key().await?;
+
+        Ok(Some(self_user))
+    }
+
+    /// Peek at at most `max_messages` messages of each queue without
+    /// processing them. Nothing is written to the client DB and the messages
+    /// stay in the queues for the app.
+    ///
+    /// Messages of the QS queue are decrypted with the queue ratchet to learn
+    /// the conversations they belong to. Application messages themselves are
+    /// not decrypted, and commits and other messages that aren't shown to the
+    /// user are skipped.
+    pub async fn preview_queue_messages(
+        &self,
+        max_messages: u64,
+    ) -> Result<Vec<QueueMessagePreview>> {
+        let batch_size = max_messages.min(MAX_DEQUEUE_BATCH_SIZE);
+        let api_client = self.inner.api_clients.default_client()?;
+        let connection = self.inner.connection.lock().await;
+        let as_sequence_number = QueueType::As.load_sequence_number(&connection)?;
+        let qs_sequence_number = QueueType::Qs.load_sequence_number(&connection)?;
+        drop(connection);
+
+        let as_messages = api_client
+            .as_dequeue_messages(
+                as_sequence_number,
+                batch_size,
+                &self.inner.key_store.signing_key(),
+            )
+            .await?
+            .messages;
+        let mut previews = vec![QueueMessagePreview::ConnectionRequest; as_messages.len()];
+
+        let qs_messages = api_client
+            .qs_dequeue_messages(
+                &self.inner.qs_client_id,
+                qs_sequence_number,
+                batch_size,
+                &self.inner.key_store.qs_client_signing_key,
+            )
+            .await?
+            .messages;
+        let connection = self.inner.connection.lock().await;
+        // The ratchet is only advanced in memory.
+        let mut qs_queue_ratchet = StorableQsQueueRatchet::load(&connection)?;
+        for qs_message in qs_messages {
+            let payload = match qs_queue_ratchet.decrypt(qs_message) {
+                Ok(payload) => payload.extract()?.payload,
+                Err(error) => {
+                    log::warn!("Failed to decrypt QS queue message: {error:?}");
+                    break;
+                }
+            };
+            let preview = match payload {
+                ExtractedQsQueueMessagePayload::WelcomeBundle(_) => {
+                    QueueMessagePreview::NewConversation
+                }
+                ExtractedQsQueueMessagePayload::MlsMessage(mls_message) => {
+                    // Only application messages are private.
+                    let MlsMessageBodyIn::PrivateMessage(private_message) =
+                        (*mls_message).extract()
+                    else {
+                        continue;
+                    };
+                    let protocol_message = ProtocolMessage::from(private_message);
+                    let Some(conversation) =
+                        Conversation::load_by_group_id(&connection, protocol_message.group_id())?
+                    else {
+                        continue;
+                    };
+                    QueueMessagePreview::Message(conversation.id())
+                }
+                ExtractedQsQueueMessagePayload::JoinRequest(_)
+                | ExtractedQsQueueMessagePayload::AttachmentBlocked(_)
+                | ExtractedQsQueueMessagePayload::SelfMessage(_) => continue,
+            };
+            previews.push(preview);
+        }
+
+        Ok(previews)
+    }
+}
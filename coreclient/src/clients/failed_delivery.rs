@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Handling of own messages the DS didn't accept.
+//!
+//! The reason of a failed delivery is stored with the message and shown to
+//! the user, who can retry sending the message or delete it. Messages that
+//! were sent in an outdated epoch are sent again right away after the pending
+//! messages of the group were processed.
+
+use anyhow::{anyhow, bail, Result};
+use phnxapiclient::ds_api::DsRequestError;
+
+use crate::{
+    activity::Activity,
+    conversations::messages::{ConversationMessage, ConversationMessageId},
+    DeliveryFailure, NotificationType,
+};
+
+use super::CoreUser;
+
+impl From<&DsRequestError> for DeliveryFailure {
+    fn from(error: &DsRequestError) -> Self {
+        match error {
+            // Catching up moves the conversation to the successor group, so
+            // that the message can be sent there.
+            DsRequestError::StaleEpoch | DsRequestError::GroupUpgraded => {
+                DeliveryFailure::StaleEpoch
+            }
+            DsRequestError::NotAllowed(_) => DeliveryFailure::NotAllowed,
+            DsRequestError::RateLimited(_) => DeliveryFailure::RateLimited,
+            DsRequestError::NetworkError(_) => DeliveryFailure::Network,
+            DsRequestError::UpgradeRequired => DeliveryFailure::UpgradeRequired,
+            DsRequestError::LibraryError
+            | DsRequestError::BadResponse
+            | DsRequestError::UnexpectedResponse
+            | DsRequestError::DsError(_) => DeliveryFailure::Rejected,
+        }
+    }
+}
+
+impl DeliveryFailure {
+    /// Classify the error of a failed attempt to send a message.
+    pub(crate) fn from_error(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<DsRequestError>()
+            .map(DeliveryFailure::from)
+            .unwrap_or(DeliveryFailure::Rejected)
+    }
+}
+
+impl CoreUser {
+    /// Retry sending an own message whose delivery failed. Returns the sent
+    /// message.
+    pub async fn retry_failed_message(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> Result<ConversationMessage> {
+        match self.re_send_message(message_id.to_uuid()).await {
+            Ok(()) => self.load_message(message_id).await,
+            Err(error) => self.recover_failed_delivery(message_id, error).await,
+        }
+    }
+
+    /// Delete an own message that couldn't be sent, together with the
+    /// activity about its failed delivery.
+    pub async fn delete_failed_message(&self, message_id: ConversationMessageId) -> Result<()> {
+        let mut connection = self.inner.connection.lock().await;
+        let message = ConversationMessage::load(&connection, &message_id.to_uuid())?.ok_or(
+            anyhow!("Can't find message with id {}", message_id.to_uuid()),
+        )?;
+        if message.was_sent() {
+            bail!("Message with id {} was already sent", message_id.to_uuid());
+        }
+        let transaction = connection.transaction()?;
+        // Delete the message first, such that the counters of the
+        // conversation are updated by the triggers.
+        ConversationMessage::delete(&transaction, message_id)?;
+        Activity::delete_failed_delivery(&transaction, message_id)?;
+        transaction.commit()?;
+        self.store_notifier()
+            .notify(NotificationType::ConversationChange(
+                message.conversation_id(),
+            ));
+        self.store_notifier()
+            .notify(NotificationType::ActivityChange);
+        Ok(())
+    }
+
+    /// Handle a failed attempt to send the message with the given id.
+    ///
+    /// If the message was sent in an outdated epoch, the pending messages are
+    /// processed and the message is sent once more. Otherwise, or if that
+    /// fails as well, the failure is recorded and the error returned.
+    pub(crate) async fn recover_failed_delivery(
+        &self,
+        message_id: ConversationMessageId,
+        error: anyhow::Error,
+    ) -> Result<ConversationMessage> {
+        let error = if DeliveryFailure::from_error(&error) == DeliveryFailure::StaleEpoch {
+            log::info!(
+                "Message {} was sent in an outdated epoch, catching up",
+                message_id.to_uuid()
+            );
+            match self.resend_after_catching_up(message_id).await {
+                Ok(()) => return self.load_message(message_id).await,
+                Err(error) => error,
+            }
+        } else {
+            error
+        };
+
+        let connection = self.inner.connection.lock().await;
+        let message = ConversationMessage::load(&connection, &message_id.to_uuid())?;
+        drop(connection);
+        if let Some(mut message) = message {
+            self.record_failed_delivery(&mut message, DeliveryFailure::from_error(&error))
+                .await?;
+        }
+        Err(error)
+    }
+
+    async fn resend_after_catching_up(&self, message_id: ConversationMessageId) -> Result<()> {
+        self.qs_fetch_and_process_messages().await?;
+        self.re_send_message(message_id.to_uuid()).await
+    }
+
+    pub(super) async fn load_message(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> Result<ConversationMessage> {
+        let connection = self.inner.connection.lock().await;
+        ConversationMessage::load(&connection, &message_id.to_uuid())?.ok_or(anyhow!(
+            "Can't find message with id {}",
+            message_id.to_uuid()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ds_errors_are_classified() {
+        let stale = anyhow::Error::from(DsRequestError::StaleEpoch);
+        assert_eq!(
+            DeliveryFailure::from_error(&stale),
+            DeliveryFailure::StaleEpoch
+        );
+        let forbidden = anyhow::Error::from(DsRequestError::NotAllowed("Admins only".to_owned()));
+        assert_eq!(
+            DeliveryFailure::from_error(&forbidden),
+            DeliveryFailure::NotAllowed
+        );
+        assert!(!DeliveryFailure::from_error(&forbidden).is_transient());
+        let other = anyhow!("Can't find group");
+        assert_eq!(
+            DeliveryFailure::from_error(&other),
+            DeliveryFailure::Rejected
+        );
+    }
+}
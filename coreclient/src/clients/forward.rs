@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+
+use crate::{
+    attachments::{persistence::AttachmentReference, AttachmentHash},
+    conversations::messages::{ConversationMessage, Message},
+    mimi_content::MimiContent,
+    ConversationId, ConversationMessageId,
+};
+
+use super::CoreUser;
+
+/// Result of forwarding a message to a single conversation.
+#[derive(Debug)]
+pub struct ForwardOutcome {
+    pub conversation_id: ConversationId,
+    /// The forwarded copy of the message or the reason why forwarding to the
+    /// conversation failed.
+    pub result: Result<ConversationMessage>,
+}
+
+impl CoreUser {
+    /// Forward the message with the given id to the conversations with the
+    /// given ids.
+    ///
+    /// Each conversation receives a copy of the message content, attributed
+    /// to the sender of the original message. Attachments are stored by the
+    /// DS of a single group, so they are uploaded again to each target
+    /// conversation when the copy is sent. Attachments of the message that
+    /// weren't downloaded yet are downloaded first. The local content is
+    /// referenced by the copies instead of being stored again. View-once
+    /// attachments can't be forwarded.
+    ///
+    /// The copies are sent via the send queue, such that forwarding to many
+    /// conversations doesn't exceed the rate limit of the server.
+    ///
+    /// Forwarding to one conversation doesn't depend on the others, so the
+    /// outcome is returned per conversation. An error is only returned if the
+    /// message itself can't be forwarded.
+    pub async fn forward_message(
+        &self,
+        source_message_id: ConversationMessageId,
+        target_chat_ids: Vec<ConversationId>,
+    ) -> Result<Vec<ForwardOutcome>> {
+        let connection = self.inner.connection.lock().await;
+        let source_message = ConversationMessage::load(&connection, &source_message_id.to_uuid())?
+            .ok_or_else(|| anyhow!("Can't find message with id {}", source_message_id.to_uuid()))?;
+        let attachments: Vec<AttachmentHash> =
+            AttachmentReference::load_all_of_message(&connection, source_message_id)?
+                .into_iter()
+                .filter(|attachment| attachment.media_deleted_at.is_none() && !attachment.thumbnail)
+                .map(|attachment| attachment.hash)
+                .collect();
+        drop(connection);
+
+        let Message::Content(content_message) = source_message.message() else {
+            bail!("Only content messages can be forwarded");
+        };
+        if content_message.content().is_view_once_attachment() {
+            bail!("View-once attachments can't be forwarded");
+        }
+        #[cfg(feature = "attachments")]
+        let attachments =
+            if attachments.len() < content_message.content().encrypted_attachments().len() {
+                self.download_message_attachments(source_message_id).await?
+            } else {
+                attachments
+            };
+        let sender: QualifiedUserName = SafeTryInto::try_into(content_message.sender())?;
+
+        let mut outcomes = Vec::with_capacity(target_chat_ids.len());
+        for conversation_id in target_chat_ids {
+            let content = MimiContent::forwarded(
+                self.user_name().domain(),
+                content_message.content(),
+                sender.clone(),
+            );
+            let result = self
+                .forward_to(conversation_id, content, &attachments)
+                .await;
+            if let Err(error) = &result {
+                log::warn!(
+                    "Failed to forward message to conversation {}: {}",
+                    conversation_id.as_uuid(),
+                    error
+                );
+            }
+            outcomes.push(ForwardOutcome {
+                conversation_id,
+                result,
+            });
+        }
+
+        self.process_send_queue().await?;
+        // Report the copies that couldn't be sent after all.
+        let connection = self.inner.connection.lock().await;
+        for outcome in &mut outcomes {
+            let Ok(message) = &outcome.result else {
+                continue;
+            };
+            outcome.result = match ConversationMessage::load(&connection, &message.id().to_uuid())?
+            {
+                Some(message) if message.was_sent() => Ok(message),
+                _ => Err(anyhow!("Failed to send the forwarded message")),
+            };
+        }
+        Ok(outcomes)
+    }
+
+    async fn forward_to(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+        attachments: &[AttachmentHash],
+    ) -> Result<ConversationMessage> {
+        let message = self.queue_message(conversation_id, content).await?;
+        let mut connection = self.inner.connection.lock().await;
+        let transaction = connection.transaction()?;
+        for hash in attachments {
+            AttachmentReference {
+                hash: hash.clone(),
+                message_id: message.id(),
+                conversation_id,
+            }
+            .store(&transaction)?;
+        }
+        transaction.commit()?;
+        Ok(message)
+    }
+}
@@ -0,0 +1,111 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    conversations::{messages::ConversationMessage, Conversation, ConversationStatus},
+    groups::janitor::StalePendingState,
+};
+
+use super::{process::process_qs::ProcessedQsMessages, CoreUser};
+
+impl CoreUser {
+    /// Resolve stale pending commits and proposals in all active
+    /// conversations, see [`crate::groups::janitor`].
+    ///
+    /// Fetches and processes all messages from the QS first. The returned
+    /// messages include the processed QS messages and the system messages
+    /// recording the recoveries. Failures in individual conversations are
+    /// logged and don't stop the cleanup.
+    pub async fn clean_up_stale_groups(&self) -> Result<ProcessedQsMessages> {
+        // Phase 1: Merge the commits of other members first
+        let mut processed_messages = self.qs_fetch_and_process_messages().await?;
+
+        // Phase 2: Resolve the stale pending state of each group
+        let connection = self.inner.connection.lock().await;
+        let conversations = Conversation::load_all(&connection)?;
+        drop(connection);
+        for conversation in conversations {
+            if !matches!(conversation.status(), ConversationStatus::Active) {
+                continue;
+            }
+            match self.clean_up_stale_group(&conversation).await {
+                Ok(messages) if messages.is_empty() => (),
+                Ok(messages) => {
+                    let conversation_id = conversation.id();
+                    if !processed_messages
+                        .changed_conversations
+                        .contains(&conversation_id)
+                    {
+                        processed_messages
+                            .changed_conversations
+                            .push(conversation_id);
+                    }
+                    processed_messages.new_messages.extend(messages);
+                }
+                Err(e) => log::error!(
+                    "Failed to clean up group of conversation {}: {:?}",
+                    conversation.id().as_uuid(),
+                    e
+                ),
+            }
+        }
+        Ok(processed_messages)
+    }
+
+    async fn clean_up_stale_group(
+        &self,
+        conversation: &Conversation,
+    ) -> Result<Vec<ConversationMessage>> {
+        // Phase 1: Check the group for stale pending state
+        let connection = self.inner.connection.lock().await;
+        let group_id = conversation.group_id();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let Some(stale_pending_state) = group.stale_pending_state(&connection)? else {
+            group.check_in(&connection)?;
+            return Ok(vec![]);
+        };
+        drop(connection);
+
+        // Phase 2: Learn the epoch of the group at the DS
+        let ds_epoch_info = match stale_pending_state {
+            StalePendingState::Proposals => None,
+            StalePendingState::Commit => Some(
+                self.inner
+                    .api_clients
+                    .get(&conversation.owner_domain())?
+                    .ds_group_epoch_info(
+                        group_id.clone(),
+                        group.group_state_ear_key(),
+                        group.user_auth_key().ok_or(anyhow!("No user auth key"))?,
+                    )
+                    .await?,
+            ),
+        };
+
+        // Phase 3: Discard or merge the pending state
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        let group_messages = match ds_epoch_info {
+            None => group.discard_pending_proposals(&transaction)?,
+            Some(ds_epoch_info) => {
+                match group.resolve_pending_commit(&transaction, &ds_epoch_info)? {
+                    Some(group_messages) => group_messages,
+                    None => return Ok(vec![]),
+                }
+            }
+        };
+        group.store_update(&transaction)?;
+        let conversation_messages =
+            Self::store_messages(&mut transaction, conversation.id(), group_messages)?;
+        transaction.commit()?;
+        group.check_in(&connection)?;
+        Ok(conversation_messages)
+    }
+}
@@ -0,0 +1,278 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Headless sync mode for server-side integrations.
+//!
+//! A [`HeadlessSyncClient`] runs a user without the app, e.g. for a bridge or
+//! an archival bot that a user operates with the consent of the members of
+//! its conversations. It keeps its databases in a directory of its own, shows
+//! no notifications and reports what it receives to the [`SyncHooks`] of the
+//! integration.
+//!
+//! Hooks are called after the received messages were processed and stored.
+//! If the process stops in between, the hooks of these messages are not
+//! called again, but the messages can be loaded from the databases.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use phnxapiclient::qs_api::ws::{QsWebSocket, WsEvent};
+use phnxtypes::{
+    identifiers::{QualifiedUserName, SafeTryInto},
+    messages::client_ds::QsWsMessage,
+};
+use tokio::{sync::Mutex, time::MissedTickBehavior};
+
+use crate::{
+    groups::membership_history::{MembershipChange, MembershipHistoryCursor},
+    ConversationId, ConversationMessage,
+};
+
+use super::{
+    process::process_qs::ProcessedQsMessages,
+    store::{ClientRecord, ClientRecordState},
+    CoreUser,
+};
+
+/// Seconds after which an unresponsive websocket is considered disconnected
+const WEBSOCKET_TIMEOUT: u64 = 30;
+/// Seconds between attempts to reconnect the websocket
+const WEBSOCKET_RETRY_INTERVAL: u64 = 10;
+
+/// When a [`HeadlessSyncClient`] fetches messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncTrigger {
+    /// Fetch messages on a fixed interval.
+    Interval(Duration),
+    /// Fetch messages whenever the QS announces new ones via the websocket
+    /// and when the websocket (re)connects. Messages are additionally fetched
+    /// on the given interval, which picks up connection requests, since the
+    /// AS doesn't announce them, and covers periods without a connection.
+    Listen { poll_interval: Duration },
+}
+
+/// Callbacks of an integration, called for everything a sync received.
+///
+/// Hooks should return quickly, e.g. by forwarding to a channel, since the
+/// next sync only starts once they returned.
+pub trait SyncHooks: Send + Sync {
+    /// The user was added to a new conversation or a connection was
+    /// established.
+    fn on_new_conversation(&self, _conversation_id: ConversationId) {}
+
+    /// A client was added to or removed from a conversation.
+    fn on_membership_change(&self, _change: &MembershipChange) {}
+
+    /// A message was received, including system messages.
+    fn on_message(&self, _message: &ConversationMessage) {}
+
+    /// A sync triggered by [`HeadlessSyncClient::run`] failed. It is retried
+    /// on the next trigger.
+    fn on_sync_error(&self, _error: &anyhow::Error) {}
+}
+
+/// What a single sync received and sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    pub new_conversations: usize,
+    pub membership_changes: usize,
+    pub new_messages: usize,
+    /// Own messages from the outbox that were sent
+    pub sent_messages: usize,
+}
+
+pub struct HeadlessSyncClient<H> {
+    user: CoreUser,
+    hooks: H,
+    data_dir: PathBuf,
+    /// Position of the last membership change reported to the hooks. Also
+    /// ensures that only one sync runs at a time.
+    membership_cursor: Mutex<MembershipHistoryCursor>,
+}
+
+impl<H: SyncHooks> HeadlessSyncClient<H> {
+    /// Register a new user whose databases are stored in `data_dir`. The
+    /// directory is created if it doesn't exist.
+    pub async fn register(
+        data_dir: impl Into<PathBuf>,
+        user_name: impl SafeTryInto<QualifiedUserName>,
+        password: &str,
+        server_url: impl ToString,
+        hooks: H,
+    ) -> Result<Self> {
+        let data_dir = data_dir.into();
+        std::fs::create_dir_all(&data_dir)?;
+        let user =
+            CoreUser::new(user_name, password, server_url, db_path(&data_dir)?, None).await?;
+        Self::with_user(user, data_dir, hooks).await
+    }
+
+    /// Load the user registered in `data_dir`. Returns `None` if there is no
+    /// fully registered user.
+    pub async fn load(data_dir: impl Into<PathBuf>, hooks: H) -> Result<Option<Self>> {
+        let data_dir = data_dir.into();
+        if !data_dir.is_dir() {
+            return Ok(None);
+        }
+        let db_path = db_path(&data_dir)?;
+        let Some(client_record) = ClientRecord::load_all_from_phnx_db(db_path)?
+            .into_iter()
+            .rev()
+            .find(|record| matches!(record.client_record_state, ClientRecordState::Finished))
+        else {
+            return Ok(None);
+        };
+        let Some(user) = CoreUser::load(client_record.as_client_id, db_path).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::with_user(user, data_dir, hooks).await?))
+    }
+
+    async fn with_user(user: CoreUser, data_dir: PathBuf, hooks: H) -> Result<Self> {
+        // Changes processed before are not reported again.
+        let connection = user.inner.connection.lock().await;
+        let membership_cursor = MembershipChange::latest_cursor(&connection)?;
+        drop(connection);
+        Ok(Self {
+            user,
+            hooks,
+            data_dir,
+            membership_cursor: Mutex::new(membership_cursor),
+        })
+    }
+
+    /// The user, e.g. to send messages.
+    pub fn user(&self) -> &CoreUser {
+        &self.user
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Fetch and process the messages of the AS and QS queues, report them to
+    /// the hooks and send the messages waiting in the outbox.
+    pub async fn sync(&self) -> Result<SyncSummary> {
+        let mut membership_cursor = self.membership_cursor.lock().await;
+
+        let mut new_conversations = self.user.as_fetch_and_process_messages().await?;
+        let ProcessedQsMessages {
+            new_conversations: new_group_conversations,
+            new_messages,
+            ..
+        } = self.user.qs_fetch_and_process_messages().await?;
+        new_conversations.extend(new_group_conversations);
+
+        let connection = self.user.inner.connection.lock().await;
+        let (membership_changes, cursor) =
+            MembershipChange::load_after(&connection, *membership_cursor)?;
+        drop(connection);
+        *membership_cursor = cursor;
+
+        for conversation_id in &new_conversations {
+            self.hooks.on_new_conversation(*conversation_id);
+        }
+        for change in &membership_changes {
+            self.hooks.on_membership_change(change);
+        }
+        for message in &new_messages {
+            self.hooks.on_message(message);
+        }
+
+        let sent_messages = self.user.flush_outbox().await?;
+
+        Ok(SyncSummary {
+            new_conversations: new_conversations.len(),
+            membership_changes: membership_changes.len(),
+            new_messages: new_messages.len(),
+            sent_messages,
+        })
+    }
+
+    /// Sync whenever the trigger fires, until `shutdown` completes.
+    pub async fn run(&self, trigger: SyncTrigger, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        match trigger {
+            SyncTrigger::Interval(interval) => {
+                let mut ticker = tokio::time::interval(interval);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    tokio::select! {
+                        _ = &mut shutdown => return,
+                        _ = ticker.tick() => self.sync_and_report_errors().await,
+                    }
+                }
+            }
+            SyncTrigger::Listen { poll_interval } => loop {
+                let websocket = tokio::select! {
+                    _ = &mut shutdown => return,
+                    websocket = self.user.websocket(WEBSOCKET_TIMEOUT, WEBSOCKET_RETRY_INTERVAL) => websocket,
+                };
+                match websocket {
+                    Ok(websocket) => {
+                        if self
+                            .listen(websocket, poll_interval, shutdown.as_mut())
+                            .await
+                        {
+                            return;
+                        }
+                        self.hooks
+                            .on_sync_error(&anyhow!("The websocket closed unexpectedly"));
+                    }
+                    Err(error) => self.hooks.on_sync_error(&error),
+                }
+                tokio::select! {
+                    _ = &mut shutdown => return,
+                    _ = tokio::time::sleep(poll_interval) => self.sync_and_report_errors().await,
+                }
+            },
+        }
+    }
+
+    /// Returns `true` if `shutdown` completed and `false` if the websocket
+    /// closed.
+    async fn listen(
+        &self,
+        mut websocket: QsWebSocket,
+        poll_interval: Duration,
+        mut shutdown: Pin<&mut impl Future<Output = ()>>,
+    ) -> bool {
+        let mut ticker = tokio::time::interval(poll_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return true,
+                event = websocket.next() => match event {
+                    Some(
+                        WsEvent::ConnectedEvent | WsEvent::MessageEvent(QsWsMessage::QueueUpdate),
+                    ) => self.sync_and_report_errors().await,
+                    Some(_) => {}
+                    None => return false,
+                },
+                _ = ticker.tick() => self.sync_and_report_errors().await,
+            }
+        }
+    }
+
+    async fn sync_and_report_errors(&self) {
+        match self.sync().await {
+            Ok(summary) => log::debug!("Headless sync finished: {summary:?}"),
+            Err(error) => {
+                log::warn!("Headless sync failed: {error}");
+                self.hooks.on_sync_error(&error);
+            }
+        }
+    }
+}
+
+fn db_path(data_dir: &Path) -> Result<&str> {
+    data_dir
+        .to_str()
+        .ok_or_else(|| anyhow!("The data directory must be valid UTF-8"))
+}
@@ -0,0 +1,224 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+use phnxtypes::{
+    crypto::{
+        ear::{
+            keys::{JoinRequestEarKey, JoinRequestTokenKey},
+            EarDecryptable, EarEncryptable,
+        },
+        kdf::keys::{JoinRequestSecret, JoinRequestTokenId},
+        signatures::signable::Signable,
+    },
+    identifiers::{QualifiedGroupId, QualifiedUserName},
+    messages::{
+        join_request::{JoinRequestParams, RegisterJoinRequestTokenParams},
+        room_policy::JoinRule,
+    },
+    time::TimeStamp,
+};
+use tls_codec::{DeserializeBytes, Serialize as _};
+
+use crate::{
+    activity::{Activity, ActivityKind},
+    conversations::{messages::ConversationMessage, Conversation},
+    groups::{
+        join_request::{JoinRequestIn, JoinRequestTbs, JoinRequestToken, PendingJoinRequest},
+        Group,
+    },
+    key_stores::as_credentials::AsCredentials,
+    ConversationId,
+};
+
+use super::{process::process_qs::ProcessQsMessageResult, CoreUser};
+
+impl CoreUser {
+    /// Create a token that allows non-members to ask to join the conversation
+    /// with the given id. The token is registered with the DS and has to be
+    /// shared out of band. It expires after a week.
+    ///
+    /// Fails if the room policy of the conversation doesn't allow join
+    /// requests or doesn't allow the own user to invite others.
+    pub async fn join_request_token(&self, conversation_id: ConversationId) -> Result<Vec<u8>> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let group = Group::load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        drop(connection);
+
+        if group.room_policy()?.join_rule != JoinRule::Knock {
+            bail!("The conversation doesn't allow join requests")
+        }
+        if !group.own_can_invite()? {
+            bail!("The room policy doesn't allow us to invite others")
+        }
+        let user_auth_key = group.user_auth_key().ok_or(anyhow!("No user auth key"))?;
+        let token_id = JoinRequestTokenId::random()?;
+        let secret = JoinRequestSecret::new(&token_id, group.credential_ear_key());
+        let params = RegisterJoinRequestTokenParams {
+            group_id: group_id.clone(),
+            sender: user_auth_key.verifying_key().hash(),
+            token_id: token_id.clone(),
+            token_key: JoinRequestTokenKey::derive_from(&secret, group_id)?,
+        };
+        self.inner
+            .api_clients
+            .get(&conversation.owner_domain())?
+            .ds_register_join_request_token(params, user_auth_key, group.group_state_ear_key())
+            .await?;
+
+        let token = JoinRequestToken {
+            group_id: group_id.clone(),
+            token_id,
+            secret,
+        };
+        Ok(token.tls_serialize_detached()?)
+    }
+
+    /// Ask to join the group referenced by the given token. The request is
+    /// delivered to the members of the group, which can approve it by
+    /// inviting the user.
+    pub async fn request_to_join(&self, token: &[u8]) -> Result<()> {
+        let token = JoinRequestToken::tls_deserialize_exact_bytes(token)?;
+        let qgid = QualifiedGroupId::try_from(token.group_id.clone())?;
+
+        let join_request = JoinRequestTbs {
+            group_id: token.group_id.clone(),
+            sender_client_credential: self.inner.key_store.signing_key().credential().clone(),
+            timestamp: TimeStamp::now(),
+        }
+        .sign(&self.inner.key_store.signing_key())?;
+        let request_ear_key = JoinRequestEarKey::derive_from(&token.secret, &token.group_id)?;
+        let params = JoinRequestParams {
+            token_key: JoinRequestTokenKey::derive_from(&token.secret, &token.group_id)?,
+            group_id: token.group_id,
+            token_id: token.token_id,
+            encrypted_join_request: join_request.encrypt(&request_ear_key)?,
+        };
+
+        self.inner
+            .api_clients
+            .get(qgid.owning_domain())?
+            .ds_join_request(params)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the pending join requests of the conversation with the given
+    /// id.
+    pub async fn pending_join_requests(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Vec<PendingJoinRequest>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(PendingJoinRequest::load_all(&connection, conversation_id)?)
+    }
+
+    /// Approve the join request of the given user by inviting them to the
+    /// conversation. The user has to be a contact.
+    pub async fn approve_join_request(
+        &self,
+        conversation_id: ConversationId,
+        user_name: QualifiedUserName,
+    ) -> Result<Vec<ConversationMessage>> {
+        let conversation_messages = self
+            .invite_users(conversation_id, &[user_name.clone()])
+            .await?;
+        let connection = self.inner.connection.lock().await;
+        PendingJoinRequest::delete(&connection, conversation_id, &user_name)?;
+        Ok(conversation_messages)
+    }
+
+    /// Decline the join request of the given user.
+    pub async fn decline_join_request(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &QualifiedUserName,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        PendingJoinRequest::delete(&connection, conversation_id, user_name)?;
+        Ok(())
+    }
+
+    /// Process a join request received via the QS. Join requests are sent
+    /// anonymously, so invalid requests are dropped instead of failing the
+    /// processing of the remaining messages.
+    pub(super) async fn handle_join_request(
+        &self,
+        params: JoinRequestParams,
+    ) -> Result<ProcessQsMessageResult> {
+        match self.store_join_request(params).await {
+            Ok(Some(conversation_id)) => Ok(ProcessQsMessageResult::ConversationChanged(
+                conversation_id,
+                vec![],
+            )),
+            Ok(None) => Ok(ProcessQsMessageResult::ConversationMessages(vec![])),
+            Err(error) => {
+                log::warn!("Dropping invalid join request: {}", error);
+                Ok(ProcessQsMessageResult::ConversationMessages(vec![]))
+            }
+        }
+    }
+
+    /// Verify and store the given join request. Returns the id of the
+    /// conversation if the request was stored.
+    async fn store_join_request(
+        &self,
+        params: JoinRequestParams,
+    ) -> Result<Option<ConversationId>> {
+        // Phase 1: Load the conversation and the group and decrypt the request
+        let connection = self.inner.connection.lock().await;
+        let conversation =
+            Conversation::load_by_group_id(&connection, &params.group_id)?.ok_or(anyhow!(
+                "Can't find conversation with group id {:?}",
+                params.group_id
+            ))?;
+        let group = Group::load(&connection, &params.group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", params.group_id))?;
+        drop(connection);
+
+        // Only members that can invite others have to decide on requests.
+        if !group.own_can_invite()? {
+            return Ok(None);
+        }
+        let secret = JoinRequestSecret::new(&params.token_id, group.credential_ear_key());
+        let request_ear_key = JoinRequestEarKey::derive_from(&secret, &params.group_id)?;
+        let join_request =
+            JoinRequestIn::decrypt(&request_ear_key, &params.encrypted_join_request)?;
+        if join_request.group_id() != &params.group_id {
+            bail!("Join request for a different group")
+        }
+
+        // Phase 2: Verify the credential and the signature of the sender
+        let client_credential = AsCredentials::verify_client_credential(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            join_request.sender_credential().clone(),
+        )
+        .await?;
+        join_request.verify_with_credential(&client_credential)?;
+        let user_name = client_credential.identity().user_name();
+
+        // Phase 3: Store the request unless the user is already a member or
+        // has a pending request. Repeated requests of the same user are
+        // ignored, such that a single user can't flood the members.
+        let connection = self.inner.connection.lock().await;
+        if group.members(&connection).contains(&user_name)
+            || PendingJoinRequest::load(&connection, conversation.id(), &user_name)?.is_some()
+        {
+            return Ok(None);
+        }
+        PendingJoinRequest::new(conversation.id(), user_name.clone()).store(&connection)?;
+        let kind = ActivityKind::JoinRequest {
+            requester: user_name,
+        };
+        self.record_activity(&connection, Activity::new(kind, Some(conversation.id())))?;
+        Ok(Some(conversation.id()))
+    }
+}
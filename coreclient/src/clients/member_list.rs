@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Paginated member lists of conversations.
+//!
+//! Roles and activity are taken from the member profiles kept by the DS of
+//! the conversation, which sorts and pages them. The identities of the members
+//! are resolved locally, since the DS doesn't know them.
+
+use anyhow::Result;
+use phnxtypes::{
+    identifiers::QualifiedUserName,
+    messages::member_profiles::{MemberOrder, MemberProfilesParams},
+    time::TimeStamp,
+};
+
+use crate::{groups::membership_history::MembershipChange, ConversationId};
+
+use super::CoreUser;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatMemberRole {
+    Admin,
+    Member,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMember {
+    pub user_name: QualifiedUserName,
+    pub role: ChatMemberRole,
+    /// When the member was last added to the conversation. `None` if the
+    /// member was added before the own client joined.
+    pub joined_at: Option<TimeStamp>,
+    pub last_activity: TimeStamp,
+    /// Epoch of the group at the time of the member's last activity.
+    pub last_activity_epoch: u64,
+}
+
+/// Position in the member list of a conversation.
+///
+/// Start with [`MemberListCursor::new`] and continue with the cursor returned
+/// alongside each page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemberListCursor {
+    order: MemberOrder,
+    offset: u32,
+}
+
+impl MemberListCursor {
+    pub fn new(order: MemberOrder) -> Self {
+        Self { order, offset: 0 }
+    }
+
+    pub fn order(&self) -> MemberOrder {
+        self.order
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMembersPage {
+    pub members: Vec<ChatMember>,
+    /// `None` if this is the last page.
+    pub next_cursor: Option<MemberListCursor>,
+}
+
+impl CoreUser {
+    /// Returns up to `limit` members of the conversation with the given id,
+    /// starting at the given cursor.
+    ///
+    /// Every page is fetched from the DS of the conversation. Members whose
+    /// clients are not (yet) known locally are skipped.
+    pub async fn chat_members_page(
+        &self,
+        chat_id: ConversationId,
+        cursor: MemberListCursor,
+        limit: u32,
+    ) -> Result<ChatMembersPage> {
+        let (conversation, group) = self.load_conversation_and_group(chat_id).await?;
+        let params = MemberProfilesParams {
+            group_id: group.group_id().clone(),
+            sender: group.own_index(),
+            order: cursor.order,
+            offset: cursor.offset,
+            limit,
+        };
+        let page = self
+            .inner
+            .api_clients
+            .get(&conversation.owner_domain())?
+            .ds_member_profiles(params, group.leaf_signer(), group.group_state_ear_key())
+            .await?;
+
+        let connection = self.inner.connection.lock().await;
+        let mut members = Vec::with_capacity(page.members.len());
+        for profile in page.members {
+            let Some(client_id) = profile
+                .clients
+                .first()
+                .and_then(|leaf_index| group.client_by_index(&connection, *leaf_index))
+            else {
+                log::warn!("Skipping member with unknown clients {:?}", profile.clients);
+                continue;
+            };
+            let user_name = client_id.user_name();
+            let joined_at = MembershipChange::last_joined(&connection, chat_id, &user_name)?;
+            let role = if profile.is_admin {
+                ChatMemberRole::Admin
+            } else {
+                ChatMemberRole::Member
+            };
+            members.push(ChatMember {
+                user_name,
+                role,
+                joined_at,
+                last_activity: profile.last_activity_time,
+                last_activity_epoch: profile.last_activity_epoch.as_u64(),
+            });
+        }
+        drop(connection);
+
+        let next_cursor = page.next_offset.map(|offset| MemberListCursor {
+            order: cursor.order,
+            offset,
+        });
+        Ok(ChatMembersPage {
+            members,
+            next_cursor,
+        })
+    }
+}
@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+#[cfg(feature = "spam-reporting")]
+use phnxtypes::{
+    crypto::signatures::signable::Verifiable,
+    messages::client_as::{SenderReputation, SenderReputationAttestationTbs},
+};
+use phnxtypes::{identifiers::QualifiedUserName, messages::client_as::SenderReputationAttestation};
+use tls_codec::{DeserializeBytes, Serialize as _};
+
+use crate::{
+    clients::connection_establishment::{
+        ConnectionEstablishmentPackageTbs, ConnectionEstablishmentPackageTbsIn,
+    },
+    conversations::Conversation,
+    key_stores::as_credentials::AsCredentials,
+    user_handles::{FilterReason, MessageRequest, MessageRequestState},
+    ConversationId,
+};
+#[cfg(feature = "spam-reporting")]
+use crate::{contacts::Contact, user_handles::screening};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Returns the pending message requests, most recent first. Requests
+    /// that were filtered as suspicious are not included, see
+    /// [`Self::filtered_message_requests`].
+    pub async fn message_requests(&self) -> Result<Vec<MessageRequest>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(MessageRequest::load_pending(&connection)?)
+    }
+
+    /// Returns the pending message requests that were filtered as
+    /// suspicious, most recent first. They can be accepted and declined like
+    /// other requests.
+    #[cfg(feature = "spam-reporting")]
+    pub async fn filtered_message_requests(&self) -> Result<Vec<MessageRequest>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(MessageRequest::load_filtered(&connection)?)
+    }
+
+    /// Returns the message request for the conversation with the given id, if
+    /// any.
+    pub async fn message_request(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Option<MessageRequest>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(MessageRequest::load(&connection, conversation_id)?)
+    }
+
+    /// Accept the message request with the given id. This joins the
+    /// connection group and turns the request into a regular connection with
+    /// the sender.
+    ///
+    /// Returns the [`ConversationId`] of the connection conversation.
+    pub async fn accept_message_request(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<ConversationId> {
+        let mut message_request = self.pending_message_request(conversation_id).await?;
+
+        // The package was verified when we received it, but the sender's
+        // credential might have been issued under an AS credential that we
+        // no longer have cached.
+        let cep_tbs_in = ConnectionEstablishmentPackageTbsIn::tls_deserialize_exact_bytes(
+            message_request.connection_package(),
+        )?;
+        let sender_domain = cep_tbs_in.sender_credential().domain();
+        let as_intermediate_credential = AsCredentials::get(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            &sender_domain,
+            cep_tbs_in.sender_credential().signer_fingerprint(),
+        )
+        .await?;
+        let cep_tbs =
+            cep_tbs_in.verify_sender_credential(as_intermediate_credential.verifying_key())?;
+
+        let conversation_id = self.join_connection_group(cep_tbs).await?;
+
+        let connection = self.inner.connection.lock().await;
+        message_request.set_state(&connection, MessageRequestState::Accepted)?;
+        Ok(conversation_id)
+    }
+
+    /// Decline the message request with the given id. The secrets of the
+    /// connection group are discarded, so the request can't be accepted
+    /// later. Further requests from the same user are ignored.
+    pub async fn decline_message_request(&self, conversation_id: ConversationId) -> Result<()> {
+        let mut message_request = self.pending_message_request(conversation_id).await?;
+        let connection = self.inner.connection.lock().await;
+        message_request.set_state(&connection, MessageRequestState::Declined)?;
+        Ok(())
+    }
+
+    async fn pending_message_request(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<MessageRequest> {
+        let message_request = self.message_request(conversation_id).await?.ok_or(anyhow!(
+            "Can't find message request with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        if message_request.state() != MessageRequestState::Pending {
+            bail!("Message request was already {:?}", message_request.state())
+        }
+        Ok(message_request)
+    }
+
+    /// Screen a connection offer of the given sender, see
+    /// [`crate::user_handles::screening`]. Returns why the offer should be
+    /// filtered, if it should be. Offers of contacts are never filtered.
+    #[cfg(feature = "spam-reporting")]
+    pub(super) async fn screen_connection_offer(
+        &self,
+        sender: &QualifiedUserName,
+        sender_reputation: Option<SenderReputationAttestation>,
+    ) -> Result<Option<FilterReason>> {
+        {
+            let connection = self.inner.connection.lock().await;
+            if Contact::load(&connection, sender)?.is_some() {
+                return Ok(None);
+            }
+        }
+        let reputation = match sender_reputation {
+            Some(attestation) => Some(self.verify_sender_reputation(sender, attestation).await),
+            // The attestation is optional, e.g. older servers don't provide
+            // it.
+            None => None,
+        };
+        let connection = self.inner.connection.lock().await;
+        Ok(screening::screen(
+            &connection,
+            sender,
+            &self.user_name().domain(),
+            reputation,
+        )?)
+    }
+
+    /// Verify the attestation of the sender's reputation. Attestations that
+    /// don't verify or that were issued for another user are treated like
+    /// the sender's AS not knowing the sender.
+    #[cfg(feature = "spam-reporting")]
+    async fn verify_sender_reputation(
+        &self,
+        sender: &QualifiedUserName,
+        attestation: SenderReputationAttestation,
+    ) -> SenderReputation {
+        let as_intermediate_credential = match AsCredentials::get(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            &sender.domain(),
+            attestation.signer_fingerprint(),
+        )
+        .await
+        {
+            Ok(credential) => credential,
+            Err(error) => {
+                log::warn!("Failed to verify the reputation of {sender}: {error}");
+                return SenderReputation::Unknown;
+            }
+        };
+        let attestation: SenderReputationAttestationTbs =
+            match attestation.verify(as_intermediate_credential.verifying_key()) {
+                Ok(attestation) => attestation,
+                Err(error) => {
+                    log::warn!("Invalid reputation attestation of {sender}: {error}");
+                    return SenderReputation::Unknown;
+                }
+            };
+        if &attestation.user_name != sender || attestation.has_expired() {
+            log::warn!("Reputation attestation of {sender} is stale or for another user");
+            return SenderReputation::Unknown;
+        }
+        attestation.reputation
+    }
+
+    /// Builds without spam reporting don't filter connection offers.
+    #[cfg(not(feature = "spam-reporting"))]
+    pub(super) async fn screen_connection_offer(
+        &self,
+        _sender: &QualifiedUserName,
+        _sender_reputation: Option<SenderReputationAttestation>,
+    ) -> Result<Option<FilterReason>> {
+        Ok(None)
+    }
+
+    /// Store a message request received via the AS until the user decides
+    /// whether to accept it. Requests with a filter reason are stored in the
+    /// filtered requests.
+    ///
+    /// Returns the [`ConversationId`] the conversation will have once the
+    /// request is accepted.
+    pub(super) async fn store_message_request(
+        &self,
+        cep_tbs: ConnectionEstablishmentPackageTbs,
+        filter_reason: Option<FilterReason>,
+    ) -> Result<ConversationId> {
+        let conversation_id = ConversationId::try_from(&cep_tbs.connection_group_id)?;
+        let user_name = cep_tbs.sender_client_credential.identity().user_name();
+        let connection = self.inner.connection.lock().await;
+        if Conversation::load(&connection, &conversation_id)?.is_some() {
+            log::warn!("Ignoring message request for an existing conversation");
+            return Ok(conversation_id);
+        }
+        let message_request = MessageRequest::new(
+            conversation_id,
+            user_name,
+            cep_tbs.intro.as_ref().map(ToString::to_string),
+            filter_reason,
+            cep_tbs.tls_serialize_detached()?,
+        );
+        if !message_request.store(&connection)? {
+            log::info!(
+                "Ignoring message request from {}",
+                message_request.user_name()
+            );
+        }
+        Ok(conversation_id)
+    }
+}
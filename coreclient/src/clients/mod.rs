@@ -2,20 +2,26 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
 use std::{collections::HashSet, sync::Arc};
 
 use anyhow::{anyhow, bail, Result};
-use chrono::{DateTime, Duration, Utc};
+use chrono::Duration;
+#[cfg(feature = "profiles")]
 use exif::{Reader, Tag};
 use opaque_ke::{
     ClientRegistration, ClientRegistrationFinishParameters, ClientRegistrationFinishResult,
-    ClientRegistrationStartResult, Identifiers, RegistrationUpload,
+    ClientRegistrationStartResult, RegistrationUpload,
 };
-use openmls::prelude::Ciphersuite;
+use openmls::prelude::{Ciphersuite, GroupId};
 use own_client_info::OwnClientInfo;
-use phnxapiclient::{qs_api::ws::QsWebSocket, ApiClient, ApiClientInitError};
+#[cfg(not(target_arch = "wasm32"))]
+use phnxapiclient::qs_api::ws::QsWebSocket;
+use phnxapiclient::{
+    compatibility::ServerCompatibility, ds_api::DsRequestError, ApiClientInitError,
+};
 use phnxtypes::{
-    codec::PhnxCodec,
     credentials::{
         keys::{ClientSigningKey, InfraCredentialSigningKey},
         ClientCredential, ClientCredentialCsr, ClientCredentialPayload,
@@ -24,7 +30,8 @@ use phnxtypes::{
         ear::{
             keys::{
                 AddPackageEarKey, ClientCredentialEarKey, FriendshipPackageEarKey, PushTokenEarKey,
-                SignatureEarKey, SignatureEarKeyWrapperKey, WelcomeAttributionInfoEarKey,
+                SelfMessageEarKey, SignatureEarKey, SignatureEarKeyWrapperKey, UserProfileEarKey,
+                UserSettingsEarKey, WelcomeAttributionInfoEarKey,
             },
             EarEncryptable, EarKey, GenericSerializable,
         },
@@ -41,10 +48,14 @@ use phnxtypes::{
         SafeTryInto,
     },
     messages::{
-        client_as::{ConnectionPackageTbs, UserConnectionPackagesParams},
+        client_as::{ConnectionPackage, ConnectionReservation, UserConnectionPackagesParams},
+        client_qs::MAX_DEQUEUE_BATCH_SIZE,
         push_token::{EncryptedPushToken, PushToken},
-        FriendshipToken, MlsInfraVersion, QueueMessage,
+        room_policy::{JoinRule, RoomPolicy},
+        server_info::{ClientVersion, ServerInfoResponse},
+        FriendshipToken, QueueMessage, QueuePriority,
     },
+    time::TimeStamp,
 };
 use rusqlite::{Connection, Transaction};
 use serde::{Deserialize, Serialize};
@@ -52,43 +63,100 @@ use store::ClientRecord;
 use thiserror::Error;
 use uuid::Uuid;
 
+use crate::groups::{
+    cache::GroupCache, client_auth_info::StorableClientCredential, Group, GroupData,
+};
 use crate::mimi_content::MimiContent;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::utils::{legacy_migration::migrate_legacy_client_db, persistence::client_db_name};
 use crate::{
-    clients::connection_establishment::{ConnectionEstablishmentPackageTbs, FriendshipPackage},
-    contacts::{Contact, ContactAddInfos, PartialContact},
+    activity::Activity,
+    clients::connection_establishment::{
+        ConnectionEstablishmentPackageTbs, ConnectionIntro, FriendshipPackage,
+    },
+    contacts::{
+        Contact, ContactAddInfos, ContactFilter, ContactListCursor, ContactsPage, PartialContact,
+    },
     conversations::{
         messages::{ConversationMessage, TimestampedMessage},
         Conversation, ConversationAttributes,
     },
     key_stores::{queue_ratchets::QueueType, MemoryUserKeyStore},
+    presence::PresenceCache,
+    store::StoreNotifier,
     user_profiles::UserProfile,
-    utils::{
-        migration::run_migrations,
-        persistence::{open_client_db, open_phnx_db},
-    },
+    utils::{migration::run_migrations, persistence::open_db_connections},
 };
-use crate::{
-    groups::{client_auth_info::StorableClientCredential, Group},
-    Asset,
-};
-use crate::{key_stores::as_credentials::AsCredentials, ConversationId};
+use crate::{key_stores::as_credentials::AsCredentials, ConversationId, Counters};
 use crate::{
     utils::persistence::{SqliteConnection, Storable},
-    Message,
+    Message, NotificationType,
+};
+
+use self::{
+    api_clients::ApiClients,
+    create_user::InitialUserState,
+    pending_connections::{
+        is_network_error, ConnectionsInFlight, PendingConnection, ReservedConnectionPackages,
+    },
+    process::worker_pool::WorkerPool,
+    queue_lock::QueueLock,
+    read_markers::ReadMarkerOutbox,
+    send_queue::SendQueue,
+    store::UserCreationState,
 };
 
-use self::{api_clients::ApiClients, create_user::InitialUserState, store::UserCreationState};
+pub use self::{extension::QueueMessagePreview, queue_lock::QueuesNotOwned};
 
+pub(crate) mod account_deletion;
+mod activity;
+mod announcements;
 pub(crate) mod api_clients;
+mod archive;
+#[cfg(feature = "attachments")]
+mod attachments;
+mod blocked_users;
+mod broadcast_lists;
+mod chat_metadata;
+mod chat_repair;
 pub(crate) mod connection_establishment;
+mod connection_packages;
 pub mod conversations;
 mod create_user;
+pub(crate) mod credential_refresh;
+mod data_export;
+mod extension;
+mod failed_delivery;
+pub(crate) mod forward;
+mod group_janitor;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod headless;
+mod join_requests;
+pub(crate) mod member_list;
+#[cfg(feature = "user-handles")]
+mod message_requests;
+pub(crate) mod outbox;
 pub(crate) mod own_client_info;
+pub(crate) mod pending_connections;
 mod persistence;
+mod presence;
 pub mod process;
+mod queue_lock;
+mod queue_sync;
+mod read_markers;
+mod room_policy;
+mod room_upgrade;
+mod send_queue;
 pub mod store;
 #[cfg(test)]
 mod tests;
+mod trust_store;
+#[cfg(feature = "profiles")]
+mod user_profiles;
+mod user_settings;
+#[cfg(feature = "attachments")]
+mod view_once;
+pub(crate) mod wipe;
 
 pub(crate) const CIPHERSUITE: Ciphersuite =
     Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
@@ -105,9 +173,17 @@ pub struct CoreUser {
 struct CoreUserInner {
     connection: SqliteConnection,
     api_clients: ApiClients,
-    _qs_user_id: QsUserId,
+    qs_user_id: QsUserId,
     qs_client_id: QsClientId,
     key_store: MemoryUserKeyStore,
+    groups: GroupCache,
+    worker_pool: WorkerPool,
+    presence: PresenceCache,
+    store_notifier: StoreNotifier,
+    send_queue: SendQueue,
+    read_marker_outbox: ReadMarkerOutbox,
+    queue_lock: QueueLock,
+    connections_in_flight: ConnectionsInFlight,
 }
 
 impl CoreUser {
@@ -122,21 +198,24 @@ impl CoreUser {
     ) -> Result<Self> {
         let user_name = user_name.try_into()?;
         let as_client_id = AsClientId::random(user_name)?;
-        // Open the phnx db to store the client record
-        let phnx_db_connection = open_phnx_db(db_path)?;
+        // Open the phnx db to store the client record and the client specific
+        // db
+        let (phnx_db_connection, client_db_connection) =
+            open_db_connections(&as_client_id, db_path).await?;
 
-        // Open client specific db
-        let client_db_connection = open_client_db(&as_client_id, db_path)?;
-
-        Self::new_with_connections(
-            as_client_id,
+        let self_user = Self::new_with_connections(
+            as_client_id.clone(),
             password,
             server_url,
             push_token,
-            SqliteConnection::new(phnx_db_connection),
-            SqliteConnection::new(client_db_connection),
+            phnx_db_connection,
+            client_db_connection,
         )
-        .await
+        .await?;
+        #[cfg(not(target_arch = "wasm32"))]
+        self_user.inner.queue_lock.set_path(&as_client_id, db_path);
+
+        Ok(self_user)
     }
 
     async fn new_with_connections(
@@ -223,10 +302,15 @@ impl CoreUser {
     /// matching `AsClientId` was interrupted before, this will resume that
     /// process.
     pub async fn load(as_client_id: AsClientId, db_path: &str) -> Result<Option<CoreUser>> {
-        let phnx_db_connection = open_phnx_db(db_path)?;
-
-        let mut client_db_connection = open_client_db(&as_client_id, db_path)?;
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let client_db_file = Path::new(db_path).join(client_db_name(&as_client_id));
+            migrate_legacy_client_db(&client_db_file)?;
+        }
+        let (phnx_db_connection_mutex, client_db_connection_mutex) =
+            open_db_connections(&as_client_id, db_path).await?;
 
+        let mut client_db_connection = client_db_connection_mutex.lock().await;
         run_migrations(&mut client_db_connection)?;
 
         let Some(user_creation_state) =
@@ -234,43 +318,58 @@ impl CoreUser {
         else {
             return Ok(None);
         };
+        drop(client_db_connection);
 
         let api_clients = ApiClients::new(
             as_client_id.user_name().domain(),
             user_creation_state.server_url(),
         );
 
-        let client_db_connection_mutex = SqliteConnection::new(client_db_connection);
-        let phnx_db_connection_mutex = SqliteConnection::new(phnx_db_connection);
-
-        let final_state = user_creation_state
+        let mut final_state = user_creation_state
             .complete_user_creation(
                 phnx_db_connection_mutex,
                 client_db_connection_mutex.clone(),
                 &api_clients,
             )
             .await?;
+        if final_state.ensure_user_profile_ear_key()? {
+            let state = UserCreationState::FinalUserState(final_state);
+            state.store(&*client_db_connection_mutex.lock().await)?;
+            final_state = state.final_state()?;
+        }
 
         let self_user = final_state.into_self_user(client_db_connection_mutex, api_clients);
+        #[cfg(not(target_arch = "wasm32"))]
+        self_user.inner.queue_lock.set_path(&as_client_id, db_path);
+        self_user.load_rotated_signing_key().await?;
 
         Ok(Some(self_user))
     }
 
+    #[cfg(feature = "profiles")]
     pub async fn set_own_user_profile(&self, mut user_profile: UserProfile) -> Result<()> {
         if user_profile.user_name() != &self.user_name() {
             bail!("Can't set user profile for users other than the current user.",);
         }
         if let Some(profile_picture) = user_profile.profile_picture() {
             let new_image = match profile_picture {
-                Asset::Value(image_bytes) => self.resize_image(image_bytes)?,
+                crate::Asset::Value(image_bytes) => self.resize_image(image_bytes)?,
             };
-            user_profile.set_profile_picture(Some(Asset::Value(new_image)));
+            user_profile.set_profile_picture(Some(crate::Asset::Value(new_image)));
+        }
+        let connection = self.inner.connection.lock().await;
+        user_profile.update(&connection)?;
+        drop(connection);
+
+        // A failure to upload is only logged: the local profile is updated
+        // and contacts keep the previous version until the next upload.
+        if let Err(error) = self.upload_own_user_profile().await {
+            log::warn!("Failed to upload user profile: {error}");
         }
-        let connection = &self.inner.connection.lock().await;
-        user_profile.update(connection)?;
         Ok(())
     }
 
+    #[cfg(feature = "profiles")]
     fn resize_image(&self, mut image_bytes: &[u8]) -> Result<Vec<u8>> {
         let image = image::load_from_memory(image_bytes)?;
 
@@ -391,12 +490,15 @@ impl CoreUser {
 
         // Phase 3: Load the group and create the commit to add the new members
         let connection = self.inner.connection.lock().await;
-        let mut group = Group::load(&connection, &group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, &group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
         // Adds new member and staged commit
         let params = group.invite(
             &connection,
-            &self.inner.key_store.signing_key,
+            &self.inner.key_store.signing_key(),
             contact_add_infos,
             contact_wai_keys,
             client_credentials,
@@ -426,6 +528,7 @@ impl CoreUser {
         let conversation_messages =
             Self::store_messages(&mut transaction, conversation_id, group_messages)?;
         transaction.commit()?;
+        group.check_in(&connection)?;
         Ok(conversation_messages)
     }
 
@@ -447,7 +550,10 @@ impl CoreUser {
             conversation_id.as_uuid()
         ))?;
         let group_id = conversation.group_id();
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
         let clients = target_users
             .iter()
@@ -477,6 +583,7 @@ impl CoreUser {
         let conversation_messages =
             Self::store_messages(&mut transaction, conversation_id, group_messages)?;
         transaction.commit()?;
+        group.check_in(&connection)?;
         drop(connection);
 
         Ok(conversation_messages)
@@ -484,13 +591,20 @@ impl CoreUser {
 
     /// Send a message and return it. Note that the message has already been
     /// sent to the DS and has internally been stored in the conversation store.
+    ///
+    /// The message is sent via the send queue (see [`send_queue`]), i.e.
+    /// after the messages queued before it and paced according to the rate
+    /// limit of the server.
+    ///
+    /// [`send_queue`]: self::send_queue
     pub async fn send_message(
         &self,
         conversation_id: ConversationId,
         content: MimiContent,
     ) -> Result<ConversationMessage> {
-        // Phase 1: Load the conversation and group
-        let (group, params, conversation, mut conversation_message) = {
+        // Phase 1: Store the message as unsent so that we don't lose it in
+        // case something goes wrong.
+        let conversation_message = {
             let mut connection = self.inner.connection.lock().await;
             let mut transaction = connection.transaction()?;
             let conversation =
@@ -499,49 +613,40 @@ impl CoreUser {
                     conversation_id.as_uuid()
                 ))?;
             let group_id = conversation.group_id();
-            // Store the message as unsent so that we don't lose it in case
-            // something goes wrong.
+            let group = self
+                .inner
+                .groups
+                .load(&transaction, group_id)?
+                .ok_or(anyhow!("Can't find group with id {group_id:?}"))?;
+            if !group.can_own_send()? {
+                bail!("Only admins can send messages in this conversation");
+            }
             let conversation_message = ConversationMessage::new_unsent_message(
                 self.user_name().to_string(),
                 conversation_id,
-                content.clone(),
+                content,
             );
             conversation_message.store(&transaction)?;
-            let mut group = Group::load(&transaction, group_id)?
-                .ok_or(anyhow!("Can't find group with id {group_id:?}"))?;
-            let params = group.create_message(&transaction, content)?;
-            // Immediately write the group back. No need to wait for the DS to
-            // confirm as this is just an application message.
-            group.store_update(&transaction)?;
             // Also, mark the message (and all messages preceeding it) as read.
-            Conversation::mark_as_read(
+            let advanced = Conversation::mark_as_read(
                 &mut transaction,
                 vec![(conversation.id(), conversation_message.timestamp())].into_iter(),
             )?;
             transaction.commit()?;
-            drop(connection);
-            (group, params, conversation, conversation_message)
+            group.check_in(&connection)?;
+            if !advanced.is_empty() {
+                self.store_notifier()
+                    .notify(NotificationType::ConversationChange(conversation_id));
+            }
+            // The sent message is notified again once the DS accepted it.
+            self.store_notifier()
+                .notify(NotificationType::Message(conversation_message.clone()));
+            conversation_message
         };
 
-        // Phase 2: Send message to DS
-        let ds_timestamp = self
-            .inner
-            .api_clients
-            .get(&conversation.owner_domain())?
-            .ds_send_message(params, group.leaf_signer(), group.group_state_ear_key())
-            .await?;
-
-        // Phase 3: Mark the message as sent and read (again).
-        let mut connection = self.inner.connection.lock().await;
-        conversation_message.mark_as_sent(&connection, ds_timestamp)?;
-        let mut transaction = connection.transaction()?;
-        Conversation::mark_as_read(
-            &mut transaction,
-            vec![(conversation.id(), conversation_message.timestamp())].into_iter(),
-        )?;
-        transaction.commit()?;
-
-        Ok(conversation_message)
+        // Phase 2: Send the message to the DS
+        self.send_via_queue(conversation_id, conversation_message.id())
+            .await
     }
 
     /// Re-try sending a message, where sending previously failed.
@@ -557,15 +662,27 @@ impl CoreUser {
             }
             _ => bail!("Message with id {} was already sent", local_message_id),
         };
+        // Attachments enqueued via the outbox are uploaded before sending
+        #[cfg(feature = "attachments")]
+        let (connection, content) = {
+            drop(connection);
+            let content = self
+                .upload_unsent_attachments(&mut unsent_message, content)
+                .await?;
+            (self.inner.connection.lock().await, content)
+        };
         let conversation_id = unsent_message.conversation_id();
         let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
             "Can't find conversation with id {}",
             conversation_id.as_uuid()
         ))?;
         let group_id = conversation.group_id();
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
-        let params = group.create_message(&connection, content)?;
+        let params = group.create_message(&connection, content, QueuePriority::High)?;
         drop(connection);
 
         // Phase 2: Send message to DS
@@ -580,13 +697,57 @@ impl CoreUser {
         let mut connection = self.inner.connection.lock().await;
         unsent_message.mark_as_sent(&connection, ds_timestamp)?;
         group.store_update(&connection)?;
+        group.check_in(&connection)?;
         let mut transaction = connection.transaction()?;
         Conversation::mark_as_read(
             &mut transaction,
             vec![(conversation.id(), unsent_message.timestamp())].into_iter(),
         )?;
+        let had_failed = Activity::delete_failed_delivery(&transaction, unsent_message.id())?;
         transaction.commit()?;
+        if had_failed {
+            self.store_notifier()
+                .notify(NotificationType::ActivityChange);
+        }
+        self.store_notifier()
+            .notify(NotificationType::Message(unsent_message));
+
+        Ok(())
+    }
 
+    /// Send the given content to the conversation with the given id without
+    /// storing it as a message of the conversation, e.g. receipts.
+    #[cfg(any(feature = "attachments", feature = "profiles"))]
+    async fn send_unstored_content(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> Result<()> {
+        let (conversation, params, leaf_signer, group_state_ear_key) = {
+            let connection = self.inner.connection.lock().await;
+            let conversation =
+                Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+                    "Can't find conversation with id {}",
+                    conversation_id.as_uuid()
+                ))?;
+            let group_id = conversation.group_id();
+            let mut group = self
+                .inner
+                .groups
+                .load(&connection, group_id)?
+                .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+            let params = group.create_message(&connection, content, QueuePriority::Low)?;
+            group.store_update(&connection)?;
+            let leaf_signer = group.leaf_signer().clone();
+            let group_state_ear_key = group.group_state_ear_key().clone();
+            group.check_in(&connection)?;
+            (conversation, params, leaf_signer, group_state_ear_key)
+        };
+        self.inner
+            .api_clients
+            .get(&conversation.owner_domain())?
+            .ds_send_message(params, &leaf_signer, &group_state_ear_key)
+            .await?;
         Ok(())
     }
 
@@ -598,39 +759,44 @@ impl CoreUser {
         &self,
         user_name: impl SafeTryInto<QualifiedUserName>,
     ) -> Result<ConversationId> {
-        let user_name = user_name.try_into()?;
-        let params = UserConnectionPackagesParams {
-            user_name: user_name.clone(),
-        };
-        // Phase 1: Fetch connection key packages from the AS
-        let user_domain = user_name.domain();
-        log::info!("Adding contact {}", user_name);
-        let user_key_packages = self
-            .inner
-            .api_clients
-            .get(&user_domain)?
-            .as_user_connection_packages(params)
-            .await?;
+        self.connect(user_name.try_into()?, false, None).await
+    }
 
-        // The AS should return an error if the user does not exist, but we
-        // check here locally just to be sure.
-        if user_key_packages.connection_packages.is_empty() {
-            return Err(anyhow!("User {} does not exist", user_name));
-        }
-        // Phase 2: Verify the connection key packages
-        log::info!("Verifying connection packages");
-        let mut verified_connection_packages = vec![];
-        for connection_package in user_key_packages.connection_packages.into_iter() {
-            let as_intermediate_credential = AsCredentials::get(
-                self.inner.connection.clone(),
-                &self.inner.api_clients,
-                &user_domain,
-                connection_package.client_credential_signer_fingerprint(),
-            )
-            .await?;
-            let verifying_key = as_intermediate_credential.verifying_key();
-            verified_connection_packages.push(connection_package.verify(verifying_key)?)
-        }
+    /// Send a message request to the user with the given user name.
+    ///
+    /// Unlike [`Self::add_contact`], the recipient doesn't join the
+    /// connection group right away. The request shows up in their message
+    /// request inbox until they accept or decline it. The connection group
+    /// doesn't allow inviting other users. The optional intro is shown to the
+    /// recipient together with the request.
+    ///
+    /// Returns the [`ConversationId`] of the newly created connection
+    /// conversation.
+    #[cfg(feature = "user-handles")]
+    pub async fn send_message_request(
+        &self,
+        user_name: impl SafeTryInto<QualifiedUserName>,
+        intro: Option<ConnectionIntro>,
+    ) -> Result<ConversationId> {
+        self.connect(user_name.try_into()?, true, intro).await
+    }
+
+    async fn connect(
+        &self,
+        user_name: QualifiedUserName,
+        message_request: bool,
+        intro: Option<ConnectionIntro>,
+    ) -> Result<ConversationId> {
+        // Connections that are being established must not be resumed or
+        // discarded in parallel.
+        let _in_flight = self.inner.connections_in_flight.enter().await;
+
+        // Phase 1: Fetch and verify the connection packages from the AS. They
+        // are reserved for us until we sent off the connection establishment
+        // packages.
+        log::info!("Adding contact {}", user_name);
+        let (verified_connection_packages, reservation) =
+            self.fetch_connection_packages(&user_name).await?;
 
         // TODO: Connection Package Validation
         // * Version
@@ -645,19 +811,104 @@ impl CoreUser {
             .ds_request_group_id()
             .await?;
 
+        // Phase 4 and 5: Prepare the connection locally and create the
+        // connection group on the DS. If this fails, the local state is
+        // discarded again, such that no half-created connection remains and
+        // adding the contact can be retried.
+        let reserved_packages =
+            ReservedConnectionPackages::new(reservation, verified_connection_packages);
+        let result = self
+            .create_connection(
+                &user_name,
+                group_id.clone(),
+                message_request,
+                intro,
+                reserved_packages,
+            )
+            .await;
+        let mut pending_connection = match result {
+            Ok(pending_connection) => pending_connection,
+            Err(error) => {
+                self.discard_connection_after_error(&user_name, &group_id)
+                    .await;
+                return Err(error);
+            }
+        };
+
+        // Phase 6: Send off the connection establishment packages. On network
+        // errors, or if a client of the contact might already have received
+        // the package, the connection stays pending and sending is resumed
+        // later, see [`pending_connections`].
+        let conversation_id = pending_connection.conversation_id;
+        if let Err(error) = self.send_pending_connection(&mut pending_connection).await {
+            if !is_network_error(&error) && !pending_connection.may_have_reached_contact() {
+                self.discard_connection_after_error(&user_name, &group_id)
+                    .await;
+                return Err(error);
+            }
+            log::warn!("Failed to send connection to {user_name}, resuming later: {error}");
+        }
+        Ok(conversation_id)
+    }
+
+    async fn discard_connection_after_error(
+        &self,
+        user_name: &QualifiedUserName,
+        group_id: &GroupId,
+    ) {
+        if let Err(error) = self.discard_connection(user_name, group_id).await {
+            log::error!("Failed to discard connection with {user_name}: {error}");
+        }
+    }
+
+    /// Create the connection group locally and on the DS and persist the
+    /// connection as pending until the connection establishment packages
+    /// were sent.
+    async fn create_connection(
+        &self,
+        user_name: &QualifiedUserName,
+        group_id: GroupId,
+        message_request: bool,
+        intro: Option<ConnectionIntro>,
+        reserved_packages: ReservedConnectionPackages,
+    ) -> Result<PendingConnection> {
+        // Recipients screen the request based on our reputation as attested
+        // by our AS. Servers that don't attest reputations yet don't keep us
+        // from connecting.
+        let sender_reputation = match self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_sender_reputation(&self.inner.key_store.signing_key())
+            .await
+        {
+            Ok(attestation) => Some(attestation),
+            Err(error) => {
+                log::warn!("Failed to fetch the attestation of our reputation: {error}");
+                None
+            }
+        };
+
         // Phase 4: Prepare the connection locally
         log::info!("Creating local connection group");
         let title = format!("Connection group: {} - {}", self.user_name(), user_name);
         let conversation_attributes = ConversationAttributes::new(title.to_string(), None);
-        let group_data = PhnxCodec::to_vec(&conversation_attributes)?.into();
+        let group_data = GroupData::encode(&conversation_attributes)?;
         let mut connection = self.inner.connection.lock().await;
         let (connection_group, partial_params) = Group::create_group(
             &mut connection,
-            &self.inner.key_store.signing_key,
+            &self.inner.key_store.signing_key(),
             group_id.clone(),
             group_data,
+            RoomPolicy {
+                join_rule: if message_request {
+                    JoinRule::MessageRequest
+                } else {
+                    JoinRule::default()
+                },
+                ..Default::default()
+            },
         )?;
-        connection_group.store(&connection)?;
 
         // TODO: Once we allow multi-client, invite all our other clients to the
         // connection group.
@@ -672,7 +923,6 @@ impl CoreUser {
             user_name.clone(),
             conversation_attributes,
         )?;
-        conversation.store(&connection)?;
 
         let friendship_package = FriendshipPackage {
             friendship_token: self.inner.key_store.friendship_token.clone(),
@@ -685,49 +935,62 @@ impl CoreUser {
                 .clone(),
             wai_ear_key: self.inner.key_store.wai_ear_key.clone(),
             user_profile: own_user_profile,
+            user_profile_ear_key: self.inner.key_store.user_profile_ear_key()?.clone(),
         };
 
         let friendship_package_ear_key = FriendshipPackageEarKey::random()?;
 
-        // Create and persist a new partial contact
-        PartialContact::new(
-            user_name.clone(),
-            conversation.id(),
-            friendship_package_ear_key.clone(),
-        )
-        .store(&connection)?;
-
-        // Store the user profile of the partial contact (we don't have a
-        // display name or a profile picture yet)
-        UserProfile::new(user_name, None, None).store(&connection)?;
-
-        drop(connection);
-
         // Create a connection establishment package
         let connection_establishment_package = ConnectionEstablishmentPackageTbs {
-            sender_client_credential: self.inner.key_store.signing_key.credential().clone(),
+            sender_client_credential: self.inner.key_store.signing_key().credential().clone(),
             connection_group_id: group_id,
             connection_group_ear_key: connection_group.group_state_ear_key().clone(),
             connection_group_credential_key: connection_group.credential_ear_key().clone(),
             connection_group_signature_ear_key_wrapper_key: connection_group
                 .signature_ear_key_wrapper_key()
                 .clone(),
-            friendship_package_ear_key,
+            friendship_package_ear_key: friendship_package_ear_key.clone(),
             friendship_package,
+            message_request,
+            intro,
+            sender_reputation,
         }
-        .sign(&self.inner.key_store.signing_key)?;
+        .sign(&self.inner.key_store.signing_key())?;
+        let mut pending_connection = PendingConnection {
+            conversation_id: conversation.id(),
+            user_name: user_name.clone(),
+            connection_establishment_package: (&connection_establishment_package).try_into()?,
+            group_created: false,
+            reserved_packages,
+        };
+
+        // Persist the connection together with the new partial contact and
+        // its user profile (we don't have a display name or a profile picture
+        // yet), such that it can be resumed or discarded if the app is killed.
+        let transaction = connection.transaction()?;
+        connection_group.store(&transaction)?;
+        conversation.store(&transaction)?;
+        PartialContact::new(
+            user_name.clone(),
+            conversation.id(),
+            friendship_package_ear_key,
+        )
+        .store(&transaction)?;
+        UserProfile::new(user_name.clone(), None, None).store(&transaction)?;
+        pending_connection.store(&transaction)?;
+        transaction.commit()?;
+        drop(connection);
 
         let client_reference = self.create_own_client_reference();
         let encrypted_client_credential = self
             .inner
             .key_store
-            .signing_key
+            .signing_key()
             .credential()
             .encrypt(connection_group.credential_ear_key())?;
         let params = partial_params.into_params(encrypted_client_credential, client_reference);
 
-        // Phase 5: Create the connection group on the DS and send off the
-        // connection establishment packages
+        // Phase 5: Create the connection group on the DS
         log::info!("Creating connection group on DS");
         self.inner
             .api_clients
@@ -741,23 +1004,74 @@ impl CoreUser {
             )
             .await?;
 
-        // Encrypt the connection establishment package for each connection and send it off.
-        for connection_package in verified_connection_packages {
-            let ciphertext = connection_establishment_package.encrypt(
-                connection_package.encryption_key(),
-                &[],
-                &[],
-            );
-            let client_id = connection_package.client_credential().identity();
+        pending_connection.group_created = true;
+        let connection = self.inner.connection.lock().await;
+        pending_connection.store(&connection)?;
 
-            self.inner
-                .api_clients
-                .get(&user_domain)?
-                .as_enqueue_message(client_id, ciphertext)
-                .await?;
+        Ok(pending_connection)
+    }
+
+    /// Discard the local state of a connection that couldn't be established:
+    /// the connection conversation, the partial contact and the connection
+    /// group. Like when deleting a conversation in which the user is the only
+    /// member, the group on the DS is left alone.
+    async fn discard_connection(
+        &self,
+        user_name: &QualifiedUserName,
+        group_id: &GroupId,
+    ) -> Result<()> {
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        if let Some(conversation) = Conversation::load_by_group_id(&transaction, group_id)? {
+            // Don't discard a pending connection with the same user
+            if let Some(partial_contact) = PartialContact::load(&transaction, user_name)? {
+                if partial_contact.conversation_id == conversation.id() {
+                    partial_contact.delete(&transaction)?;
+                }
+            }
+            PendingConnection::delete(&transaction, conversation.id())?;
+            Conversation::delete(&transaction, conversation.id())?;
         }
+        self.inner.groups.delete(&mut transaction, group_id)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Fetch the connection packages of the given user and verify them.
+    async fn fetch_connection_packages(
+        &self,
+        user_name: &QualifiedUserName,
+    ) -> Result<(Vec<ConnectionPackage>, ConnectionReservation)> {
+        let params = UserConnectionPackagesParams {
+            user_name: user_name.clone(),
+        };
+        let user_domain = user_name.domain();
+        let user_key_packages = self
+            .inner
+            .api_clients
+            .get(&user_domain)?
+            .as_user_connection_packages(params)
+            .await?;
 
-        Ok(conversation.id())
+        // The AS should return an error if the user does not exist, but we
+        // check here locally just to be sure.
+        if user_key_packages.connection_packages.is_empty() {
+            return Err(anyhow!("User {} does not exist", user_name));
+        }
+        log::info!("Verifying connection packages");
+        let mut verified_connection_packages = vec![];
+        for connection_package in user_key_packages.connection_packages.into_iter() {
+            let as_intermediate_credential = AsCredentials::get(
+                self.inner.connection.clone(),
+                &self.inner.api_clients,
+                &user_domain,
+                connection_package.client_credential_signer_fingerprint(),
+            )
+            .await?;
+            let verifying_key = as_intermediate_credential.verifying_key();
+            verified_connection_packages.push(connection_package.verify(verifying_key)?)
+        }
+        Ok((verified_connection_packages, user_key_packages.reservation))
     }
 
     /// Update the user's user auth key in the conversation with the given
@@ -779,7 +1093,10 @@ impl CoreUser {
         ))?;
         let group_id = conversation.group_id();
         // Generate ciphertext
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
         let params = group.update_user_key(&connection)?;
         drop(connection);
@@ -805,6 +1122,7 @@ impl CoreUser {
         let conversation_messages =
             Self::store_messages(&mut transaction, *conversation_id, group_messages)?;
         transaction.commit()?;
+        group.check_in(&connection)?;
         drop(connection);
 
         Ok(conversation_messages)
@@ -829,7 +1147,10 @@ impl CoreUser {
             ))?;
         let group_id = conversation.group_id();
         // Generate ciphertext
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
         let past_members = group.members(&connection);
         drop(connection);
@@ -877,12 +1198,15 @@ impl CoreUser {
         Ok(conversation_messages)
     }
 
+    /// Fetch the messages from the given queue. The caller must hold the
+    /// queues, see [`queue_lock`].
     async fn fetch_messages_from_queue(&self, queue_type: QueueType) -> Result<Vec<QueueMessage>> {
         let connection = self.inner.connection.lock().await;
         let mut remaining_messages = 1;
         let mut messages: Vec<QueueMessage> = Vec::new();
         let mut sequence_number = queue_type.load_sequence_number(&connection)?;
         drop(connection);
+        let mut replenish_key_packages = false;
 
         while remaining_messages > 0 {
             let api_client = self.inner.api_clients.default_client()?;
@@ -891,8 +1215,8 @@ impl CoreUser {
                     api_client
                         .as_dequeue_messages(
                             sequence_number,
-                            1_000_000,
-                            &self.inner.key_store.signing_key,
+                            MAX_DEQUEUE_BATCH_SIZE,
+                            &self.inner.key_store.signing_key(),
                         )
                         .await?
                 }
@@ -901,7 +1225,7 @@ impl CoreUser {
                         .qs_dequeue_messages(
                             &self.inner.qs_client_id,
                             sequence_number,
-                            1_000_000,
+                            MAX_DEQUEUE_BATCH_SIZE,
                             &self.inner.key_store.qs_client_signing_key,
                         )
                         .await?
@@ -909,6 +1233,7 @@ impl CoreUser {
             };
 
             remaining_messages = response.remaining_messages_number;
+            replenish_key_packages |= response.replenish_key_packages;
             messages.append(&mut response.messages);
 
             let connection = self.inner.connection.lock().await;
@@ -918,14 +1243,55 @@ impl CoreUser {
             }
             drop(connection);
         }
+
+        if replenish_key_packages {
+            // Failing to publish key packages shouldn't prevent the fetched
+            // messages from being processed. We'll be asked again on the next
+            // fetch.
+            if let Err(e) = self.replenish_key_packages().await {
+                log::error!("Failed to replenish key packages: {e:?}");
+            }
+        }
         Ok(messages)
     }
 
+    /// Generate and publish a fresh set of key packages, e.g. because the QS
+    /// reported that the existing ones are running out.
+    pub async fn replenish_key_packages(&self) -> Result<()> {
+        let encrypted_client_credential = self.inner.key_store.encrypt_client_credential()?;
+        let connection = self.inner.connection.lock().await;
+        let add_packages = (0..ADD_PACKAGES)
+            .map(|_| {
+                self.inner.key_store.generate_add_package(
+                    &connection,
+                    &self.inner.qs_client_id,
+                    &encrypted_client_credential,
+                    false,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        drop(connection);
+
+        self.inner
+            .api_clients
+            .default_client()?
+            .qs_publish_key_packages(
+                self.inner.qs_client_id.clone(),
+                add_packages,
+                self.inner.key_store.add_package_ear_key.clone(),
+                &self.inner.key_store.qs_client_signing_key,
+            )
+            .await?;
+        Ok(())
+    }
+
     pub async fn as_fetch_messages(&self) -> Result<Vec<QueueMessage>> {
+        let _in_flight = self.inner.queue_lock.hold().await?;
         self.fetch_messages_from_queue(QueueType::As).await
     }
 
     pub async fn qs_fetch_messages(&self) -> Result<Vec<QueueMessage>> {
+        let _in_flight = self.inner.queue_lock.hold().await?;
         self.fetch_messages_from_queue(QueueType::Qs).await
     }
 
@@ -937,7 +1303,10 @@ impl CoreUser {
             conversation_id.as_uuid()
         ))?;
         let group_id = conversation.group_id();
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
 
         let params = group.leave_group(&connection)?;
@@ -982,7 +1351,10 @@ impl CoreUser {
             conversation_id.as_uuid()
         ))?;
         let group_id = conversation.group_id();
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
         let params = group.update(&connection)?;
         drop(connection);
@@ -990,12 +1362,24 @@ impl CoreUser {
         let owner_domain = conversation.owner_domain();
 
         // Phase 2: Send the update to the DS
-        let ds_timestamp = self
+        let result = self
             .inner
             .api_clients
             .get(&owner_domain)?
             .ds_update_client(params, group.group_state_ear_key(), group.leaf_signer())
-            .await?;
+            .await;
+        let ds_timestamp = match result {
+            Ok(ds_timestamp) => ds_timestamp,
+            Err(error @ DsRequestError::NotAllowed(_)) => {
+                // The DS didn't apply the commit, e.g. because another member
+                // proposed a change that only an admin can commit.
+                let connection = self.inner.connection.lock().await;
+                group.discard_rejected_commit(&connection)?;
+                group.check_in(&connection)?;
+                return Err(error.into());
+            }
+            Err(error) => return Err(error.into()),
+        };
 
         // Phase 3: Merge the commit into the group
         let mut connection = self.inner.connection.lock().await;
@@ -1008,17 +1392,95 @@ impl CoreUser {
         let conversation_messages =
             Self::store_messages(&mut transaction, conversation_id, group_messages)?;
         transaction.commit()?;
+        group.check_in(&connection)?;
         drop(connection);
 
         Ok(conversation_messages)
     }
 
+    /// Fetch the information the user's server publishes to clients.
+    pub async fn server_info(&self) -> Result<ServerInfoResponse> {
+        Ok(self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_server_info()
+            .await?)
+    }
+
+    /// Check whether a client of the given version, which relies on the
+    /// server features with the given names, can still use the user's server.
+    pub async fn server_compatibility(
+        &self,
+        client_version: &ClientVersion,
+        used_features: &[&str],
+    ) -> Result<ServerCompatibility> {
+        Ok(self
+            .inner
+            .api_clients
+            .default_client()?
+            .check_compatibility(client_version, used_features)
+            .await?)
+    }
+
     pub async fn contacts(&self) -> Result<Vec<Contact>, rusqlite::Error> {
         let connection = &self.inner.connection.lock().await;
         let contacts = Contact::load_all(connection)?;
         Ok(contacts)
     }
 
+    /// Returns up to `limit` contacts matching the filter of the cursor,
+    /// starting at the cursor.
+    pub async fn contacts_page(
+        &self,
+        cursor: ContactListCursor,
+        limit: u32,
+    ) -> Result<ContactsPage, rusqlite::Error> {
+        let connection = &self.inner.connection.lock().await;
+        // Load one more contact to know whether there is a next page.
+        let mut contacts = Contact::load_filtered(
+            connection,
+            &cursor.filter,
+            cursor.after.as_ref(),
+            limit.saturating_add(1),
+        )?;
+        let next_cursor = if contacts.len() > limit as usize {
+            contacts.truncate(limit as usize);
+            contacts.last().map(|contact| ContactListCursor {
+                filter: cursor.filter,
+                after: Some(contact.user_name.clone()),
+            })
+        } else {
+            None
+        };
+        Ok(ContactsPage {
+            contacts,
+            next_cursor,
+        })
+    }
+
+    /// Returns the number of contacts matching the filter, e.g. to size the
+    /// sections of a contact picker.
+    pub async fn count_contacts(&self, filter: &ContactFilter) -> Result<u64, rusqlite::Error> {
+        let connection = &self.inner.connection.lock().await;
+        Contact::count_filtered(connection, filter)
+    }
+
+    /// Mark the contact as verified, e.g. after the user compared the
+    /// fingerprints of its clients in person, or clear the mark.
+    pub async fn set_contact_verified(
+        &self,
+        user_name: &QualifiedUserName,
+        verified: bool,
+    ) -> Result<()> {
+        let connection = &self.inner.connection.lock().await;
+        let verified_at = verified.then(TimeStamp::now);
+        if !Contact::set_verified_at(connection, user_name, verified_at)? {
+            bail!("{user_name} is not a contact");
+        }
+        Ok(())
+    }
+
     pub async fn contact(&self, user_name: &QualifiedUserName) -> Option<Contact> {
         let connection = &self.inner.connection.lock().await;
         Contact::load(connection, user_name).ok().flatten()
@@ -1042,15 +1504,34 @@ impl CoreUser {
         }
     }
 
+    pub(crate) fn store_notifier(&self) -> &StoreNotifier {
+        &self.inner.store_notifier
+    }
+
     pub fn user_name(&self) -> QualifiedUserName {
         self.inner
             .key_store
-            .signing_key
+            .signing_key()
             .credential()
             .identity()
             .user_name()
     }
 
+    pub(super) async fn load_conversation_and_group(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<(Conversation, Group)> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let group = Group::load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        Ok((conversation, group))
+    }
+
     /// Returns None if there is no conversation with the given id.
     pub async fn conversation_participants(
         &self,
@@ -1076,26 +1557,19 @@ impl CoreUser {
             .map(|group| group.pending_removes(connection))
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     pub async fn websocket(&self, timeout: u64, retry_interval: u64) -> Result<QsWebSocket> {
         let api_client = self.inner.api_clients.default_client();
         Ok(api_client?
-            .spawn_websocket(self.inner.qs_client_id.clone(), timeout, retry_interval)
+            .spawn_websocket(
+                self.inner.qs_client_id.clone(),
+                self.inner.key_store.qs_client_signing_key.clone(),
+                timeout,
+                retry_interval,
+            )
             .await?)
     }
 
-    /// Mark all messages in the conversation with the given conversation id and
-    /// with a timestamp older than the given timestamp as read.
-    pub async fn mark_as_read<T: IntoIterator<Item = (ConversationId, DateTime<Utc>)>>(
-        &self,
-        mark_as_read_data: T,
-    ) -> Result<(), rusqlite::Error> {
-        let mut connection = self.inner.connection.lock().await;
-        let mut transaction = connection.transaction()?;
-        Conversation::mark_as_read(&mut transaction, mark_as_read_data)?;
-        transaction.commit()?;
-        Ok(())
-    }
-
     /// Returns how many messages are marked as unread across all conversations.
     pub async fn global_unread_messages_count(&self) -> Result<u32, rusqlite::Error> {
         let connection = &self.inner.connection.lock().await;
@@ -1103,6 +1577,12 @@ impl CoreUser {
         Ok(count)
     }
 
+    /// The unread, mention and send counters of all conversations.
+    pub async fn load_counters(&self) -> Result<Counters, rusqlite::Error> {
+        let connection = &self.inner.connection.lock().await;
+        Counters::load(connection)
+    }
+
     /// Returns how many messages in the conversation with the given ID are
     /// marked as unread.
     pub async fn unread_messages_count(&self, conversation_id: ConversationId) -> u32 {
@@ -1152,10 +1632,17 @@ impl CoreUser {
         Ok(())
     }
 
+    /// Set how many groups are kept in memory. Loading a group from the
+    /// database is expensive, so frequently used groups are cached. A capacity
+    /// of 0 disables the cache.
+    pub fn set_group_cache_capacity(&self, capacity: usize) {
+        self.inner.groups.set_capacity(capacity);
+    }
+
     pub fn as_client_id(&self) -> AsClientId {
         self.inner
             .key_store
-            .signing_key
+            .signing_key()
             .credential()
             .identity()
             .clone()
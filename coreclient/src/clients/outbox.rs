@@ -0,0 +1,165 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Outbox for messages composed outside of the main app.
+//!
+//! Share extensions run under tight memory limits and can't load a full
+//! [`CoreUser`]. Instead, they open the client DB via [`Outbox`], which only
+//! allows listing conversations and storing messages as unsent. The main app
+//! sends all unsent messages with [`CoreUser::flush_outbox`] the next time it
+//! runs.
+//!
+//! Migrating the DB is left to the main app, since an extension can be
+//! terminated at any time.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use phnxtypes::identifiers::AsClientId;
+
+#[cfg(feature = "attachments")]
+use crate::attachments::{blobs::AttachmentBlob, persistence::AttachmentReference, AttachmentHash};
+use crate::{
+    conversations::messages::ConversationMessage,
+    utils::{
+        migration::has_pending_migrations,
+        persistence::{open_client_db, SqliteConnection},
+    },
+    Conversation, ConversationId, MimiContent,
+};
+
+use super::CoreUser;
+
+/// Minimal access to a client's DB that allows enqueueing messages without
+/// loading the client.
+pub struct Outbox {
+    as_client_id: AsClientId,
+    connection: SqliteConnection,
+}
+
+impl Outbox {
+    /// Open the DB of the client with the given id.
+    ///
+    /// Fails if the DB was not migrated to the current version by the main
+    /// app yet.
+    pub fn open(as_client_id: AsClientId, db_path: &str) -> Result<Self> {
+        let mut connection = open_client_db(&as_client_id, db_path)?;
+        if has_pending_migrations(&mut connection)? {
+            return Err(anyhow!("Database needs to be migrated by the app first"));
+        }
+        Ok(Self {
+            as_client_id,
+            connection: SqliteConnection::new(connection),
+        })
+    }
+
+    /// Returns at most `limit` conversations together with the time of their
+    /// most recent content message, ordered by that time.
+    pub async fn recent_conversations(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(Conversation, Option<DateTime<Utc>>)>> {
+        let connection = self.connection.lock().await;
+        let mut conversations = Conversation::load_all(&connection)?
+            .into_iter()
+            .map(|conversation| {
+                let last_message =
+                    ConversationMessage::last_content_message(&connection, conversation.id())?
+                        .map(|message| message.timestamp());
+                Ok((conversation, last_message))
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        conversations.sort_by(|(_, a), (_, b)| b.cmp(a));
+        conversations.truncate(limit);
+        Ok(conversations)
+    }
+
+    /// Returns true if the client is a member of the conversation with the
+    /// given id.
+    pub async fn has_conversation(&self, conversation_id: ConversationId) -> Result<bool> {
+        let connection = self.connection.lock().await;
+        Ok(Conversation::load(&connection, &conversation_id)?.is_some())
+    }
+
+    /// Store a message in the conversation with the given id as unsent.
+    pub async fn enqueue_message(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> Result<ConversationMessage> {
+        let connection = self.connection.lock().await;
+        self.store_unsent_message(&connection, conversation_id, content)
+    }
+
+    /// Store a message with an attachment in the conversation with the given
+    /// id as unsent.
+    #[cfg(feature = "attachments")]
+    pub async fn enqueue_attachment(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+        attachment: Vec<u8>,
+    ) -> Result<(ConversationMessage, AttachmentHash)> {
+        let mut connection = self.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let message = self.store_unsent_message(&transaction, conversation_id, content)?;
+        let blob = AttachmentBlob::new(attachment)?;
+        blob.store(&transaction)?;
+        AttachmentReference {
+            hash: blob.hash.clone(),
+            message_id: message.id(),
+            conversation_id,
+        }
+        .store(&transaction)?;
+        transaction.commit()?;
+        Ok((message, blob.hash))
+    }
+
+    fn store_unsent_message(
+        &self,
+        connection: &rusqlite::Connection,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> Result<ConversationMessage> {
+        Conversation::load(connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let message = ConversationMessage::new_unsent_message(
+            self.as_client_id.user_name().to_string(),
+            conversation_id,
+            content,
+        );
+        message.store(connection)?;
+        Ok(message)
+    }
+}
+
+impl CoreUser {
+    /// Send all messages that are stored as unsent, e.g. because they were
+    /// enqueued via the [`Outbox`] or sending them failed previously.
+    ///
+    /// The messages are sent via the send queue, paced according to the rate
+    /// limit of the server.
+    ///
+    /// Returns the number of messages that were sent.
+    pub async fn flush_outbox(&self) -> Result<usize> {
+        let unsent_messages = {
+            let connection = self.inner.connection.lock().await;
+            ConversationMessage::load_unsent(&connection)?
+        };
+        for message in unsent_messages {
+            self.inner
+                .send_queue
+                .push(message.conversation_id(), message.id().to_uuid());
+        }
+        self.process_send_queue().await
+    }
+
+    /// Number of own messages waiting to be sent. Messages whose delivery
+    /// failed are not counted.
+    pub async fn pending_outbox_messages(&self) -> Result<usize> {
+        let connection = self.inner.connection.lock().await;
+        Ok(ConversationMessage::count_pending(&connection)?)
+    }
+}
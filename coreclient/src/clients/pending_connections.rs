@@ -0,0 +1,367 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Connections whose connection establishment packages weren't sent yet.
+//!
+//! Establishing a connection creates the connection group locally and on the
+//! DS and then sends a connection establishment package to each client of
+//! the contact via the AS. The app might be suspended or killed in between,
+//! so the signed package and the reservation of the contact's connection
+//! packages are persisted as a [`PendingConnection`] together with the local
+//! state of the connection.
+//!
+//! Sending is resumed by [`CoreUser::resume_pending_connections`] under the
+//! persisted reservation, such that the contact doesn't receive the request
+//! twice. If the reservation expired, the packages are only sent to the
+//! clients that didn't receive them under the earlier reservation.
+//! Connections whose group might not exist on the DS, and connections that
+//! fail for other reasons than network errors before any client of the
+//! contact might have received the package, are discarded instead, like when
+//! establishing the connection fails right away.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use phnxapiclient::{as_api::AsRequestError, ApiClient};
+use phnxtypes::{
+    codec::PhnxCodec,
+    crypto::hpke::HpkeEncryptable,
+    identifiers::{AsClientId, ConnectionReservationId, QualifiedUserName},
+    messages::client_as::{
+        ConnectionPackage, ConnectionReservation, EnqueueMessagesResponse,
+        ReservedConnectionEstablishmentPackage,
+    },
+};
+use rusqlite::{params, types::Type, Connection};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+use crate::{
+    utils::{persistence::Storable, rt},
+    Conversation, ConversationId,
+};
+
+use super::{connection_establishment::SerializedConnectionEstablishmentPackage, CoreUser};
+
+/// Number of attempts to enqueue the connection establishment packages before
+/// giving up on network errors.
+const ENQUEUE_ATTEMPTS: u32 = 3;
+
+/// Connection packages of the contact's clients and their reservation on the
+/// AS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ReservedConnectionPackages {
+    pub(crate) reservation: ConnectionReservation,
+    pub(crate) connection_packages: Vec<ConnectionPackage>,
+    /// Clients that received the connection establishment package under an
+    /// earlier reservation.
+    #[serde(default)]
+    pub(crate) reached_clients: Vec<AsClientId>,
+    /// Whether the connection establishment packages were sent to the AS,
+    /// i.e. whether any client of the contact might have received them.
+    #[serde(default)]
+    pub(crate) sent: bool,
+}
+
+impl ReservedConnectionPackages {
+    pub(crate) fn new(
+        reservation: ConnectionReservation,
+        connection_packages: Vec<ConnectionPackage>,
+    ) -> Self {
+        Self {
+            reservation,
+            connection_packages,
+            reached_clients: Vec::new(),
+            sent: false,
+        }
+    }
+}
+
+/// A connection whose connection establishment packages weren't sent yet.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingConnection {
+    pub(crate) conversation_id: ConversationId,
+    pub(crate) user_name: QualifiedUserName,
+    pub(crate) connection_establishment_package: SerializedConnectionEstablishmentPackage,
+    /// Whether the connection group was created on the DS
+    pub(crate) group_created: bool,
+    pub(crate) reserved_packages: ReservedConnectionPackages,
+}
+
+impl Storable for PendingConnection {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS pending_connections (
+            conversation_id BLOB PRIMARY KEY,
+            user_name TEXT NOT NULL,
+            connection_establishment_package BLOB NOT NULL,
+            group_created BOOLEAN NOT NULL,
+            reserved_packages BLOB NOT NULL,
+            FOREIGN KEY (conversation_id) REFERENCES conversations(conversation_id) ON DELETE CASCADE
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let connection_establishment_package: Vec<u8> = row.get(2)?;
+        let reserved_packages: Vec<u8> = row.get(4)?;
+        let reserved_packages = PhnxCodec::from_slice(&reserved_packages)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(4, Type::Blob, Box::new(e)))?;
+        Ok(Self {
+            conversation_id: row.get(0)?,
+            user_name: row.get(1)?,
+            connection_establishment_package: connection_establishment_package.into(),
+            group_created: row.get(3)?,
+            reserved_packages,
+        })
+    }
+}
+
+impl PendingConnection {
+    /// Whether any client of the contact might have received the connection
+    /// establishment package. The connection must not be discarded anymore
+    /// in that case.
+    pub(crate) fn may_have_reached_contact(&self) -> bool {
+        self.reserved_packages.sent
+    }
+
+    /// Store the pending connection. Replaces the previously stored state of
+    /// the connection.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO pending_connections (conversation_id, user_name, connection_establishment_package, group_created, reserved_packages) VALUES (?, ?, ?, ?, ?)",
+            params![
+                self.conversation_id,
+                self.user_name,
+                self.connection_establishment_package.as_slice(),
+                self.group_created,
+                PhnxCodec::to_vec(&self.reserved_packages)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, user_name, connection_establishment_package, group_created, reserved_packages FROM pending_connections",
+        )?;
+        let pending_connections = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(pending_connections)
+    }
+
+    pub(crate) fn delete(
+        connection: &Connection,
+        conversation_id: ConversationId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM pending_connections WHERE conversation_id = ?",
+            params![conversation_id],
+        )?;
+        Ok(())
+    }
+}
+
+/// Held while connections are established, such that they aren't resumed or
+/// discarded at the same time. Any number of connections can be established
+/// concurrently.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionsInFlight(RwLock<()>);
+
+impl ConnectionsInFlight {
+    pub(crate) async fn enter(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.read().await
+    }
+}
+
+impl CoreUser {
+    /// Send off the connection establishment packages of the given pending
+    /// connection and delete it.
+    ///
+    /// The request is retried on network errors. If the reservation of the
+    /// connection packages expired in the meantime, e.g. because the app was
+    /// suspended, the connection packages are fetched and reserved once more
+    /// and the request is tried again for the clients that weren't reached
+    /// yet. The new reservation is persisted before it is used.
+    ///
+    /// The pending connection is updated in place, such that the caller can
+    /// tell whether the contact might have been reached if sending fails.
+    pub(super) async fn send_pending_connection(
+        &self,
+        pending_connection: &mut PendingConnection,
+    ) -> Result<()> {
+        let user_name = pending_connection.user_name.clone();
+        let api_client = self.inner.api_clients.get(&user_name.domain())?;
+        let connection_establishment_package =
+            Arc::new(pending_connection.connection_establishment_package.clone());
+        let mut refetched = false;
+        loop {
+            let ReservedConnectionPackages {
+                reservation,
+                connection_packages,
+                mut reached_clients,
+                sent,
+            } = pending_connection.reserved_packages.clone();
+            let connection_packages = connection_packages
+                .into_iter()
+                .filter(|package| {
+                    !reached_clients.contains(&package.client_credential().identity())
+                })
+                .collect();
+            let packages = self
+                .encrypt_connection_establishment_packages(
+                    &connection_establishment_package,
+                    connection_packages,
+                )
+                .await?;
+            if !sent {
+                pending_connection.reserved_packages.sent = true;
+                let connection = self.inner.connection.lock().await;
+                pending_connection.store(&connection)?;
+            }
+            let response = enqueue_with_retries(&api_client, reservation.id, &packages).await?;
+            if response.expired_clients.is_empty() {
+                break;
+            }
+            if refetched {
+                bail!("Reservation of the connection packages of {user_name} expired");
+            }
+            log::info!("Connection package reservation expired, fetching packages again");
+            refetched = true;
+            reached_clients.extend(
+                packages
+                    .into_iter()
+                    .map(|package| package.client_id)
+                    .filter(|client_id| !response.expired_clients.contains(client_id)),
+            );
+            let (connection_packages, reservation) =
+                self.fetch_connection_packages(&user_name).await?;
+            pending_connection.reserved_packages = ReservedConnectionPackages {
+                reservation,
+                connection_packages,
+                reached_clients,
+                sent: true,
+            };
+            let connection = self.inner.connection.lock().await;
+            pending_connection.store(&connection)?;
+        }
+
+        let connection = self.inner.connection.lock().await;
+        PendingConnection::delete(&connection, pending_connection.conversation_id)?;
+        Ok(())
+    }
+
+    /// Encrypt the connection establishment package to each of the given
+    /// connection packages. The encryption runs on the blocking thread pool,
+    /// such that a contact with many clients doesn't block the runtime.
+    async fn encrypt_connection_establishment_packages(
+        &self,
+        connection_establishment_package: &Arc<SerializedConnectionEstablishmentPackage>,
+        connection_packages: Vec<ConnectionPackage>,
+    ) -> Result<Vec<ReservedConnectionEstablishmentPackage>> {
+        let connection_establishment_package = connection_establishment_package.clone();
+        self.inner
+            .worker_pool
+            .map_blocking(connection_packages, move |connection_package| {
+                ReservedConnectionEstablishmentPackage {
+                    client_id: connection_package.client_credential().identity(),
+                    connection_establishment_ctxt: connection_establishment_package.encrypt(
+                        connection_package.encryption_key(),
+                        &[],
+                        &[],
+                    ),
+                }
+            })
+            .await
+    }
+
+    /// Resume sending the connection establishment packages of connections
+    /// that were interrupted, e.g. because the app was killed.
+    ///
+    /// Connections that fail because of network errors, or after a client of
+    /// the contact might have received the package, are resumed on the next
+    /// call. Connections whose group might not exist on the DS and
+    /// connections that fail for other reasons are discarded. Returns the ids
+    /// of the discarded connection conversations. Nothing is resumed while
+    /// connections are being established.
+    pub(crate) async fn resume_pending_connections(&self) -> Result<Vec<ConversationId>> {
+        let Ok(_guard) = self.inner.connections_in_flight.0.try_write() else {
+            return Ok(Vec::new());
+        };
+        let connection = self.inner.connection.lock().await;
+        let pending_connections = PendingConnection::load_all(&connection)?;
+        drop(connection);
+
+        let mut discarded = Vec::new();
+        for mut pending_connection in pending_connections {
+            let conversation_id = pending_connection.conversation_id;
+            let user_name = pending_connection.user_name.clone();
+            let result = if pending_connection.group_created {
+                log::info!("Resuming connection with {user_name}");
+                self.send_pending_connection(&mut pending_connection).await
+            } else {
+                Err(anyhow!("Connection group might not exist on the DS"))
+            };
+            let Err(error) = result else {
+                continue;
+            };
+            if is_network_error(&error) || pending_connection.may_have_reached_contact() {
+                log::warn!("Failed to resume connection with {user_name}: {error}");
+                continue;
+            }
+            log::error!("Discarding connection with {user_name}: {error}");
+            self.discard_pending_connection(conversation_id, &user_name)
+                .await?;
+            discarded.push(conversation_id);
+        }
+        Ok(discarded)
+    }
+
+    async fn discard_pending_connection(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &QualifiedUserName,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?;
+        let Some(conversation) = conversation else {
+            PendingConnection::delete(&connection, conversation_id)?;
+            return Ok(());
+        };
+        drop(connection);
+        self.discard_connection(user_name, conversation.group_id())
+            .await
+    }
+}
+
+/// Whether the error is a network error, i.e. whether sending can be
+/// resumed later.
+pub(super) fn is_network_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<AsRequestError>(),
+        Some(AsRequestError::NetworkError(_))
+    )
+}
+
+/// Enqueue the connection establishment packages, retrying on network
+/// errors. Retrying is safe, because the AS enqueues at most once per
+/// reservation and client.
+async fn enqueue_with_retries(
+    api_client: &ApiClient,
+    reservation_id: ConnectionReservationId,
+    packages: &[ReservedConnectionEstablishmentPackage],
+) -> Result<EnqueueMessagesResponse, AsRequestError> {
+    let mut attempt = 1;
+    loop {
+        let result = api_client
+            .as_enqueue_messages(reservation_id, packages.to_vec())
+            .await;
+        match result {
+            Err(AsRequestError::NetworkError(error)) if attempt < ENQUEUE_ATTEMPTS => {
+                log::warn!("Failed to enqueue connection establishment packages: {error}");
+                rt::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::Result;
+use phnxtypes::{
+    identifiers::QualifiedUserName,
+    messages::presence::{
+        ContactPresence, Presence, PresenceQuery, PresenceStatus, PresenceVisibility,
+    },
+};
+
+use crate::{contacts::Contact, presence::PresenceSettings};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Publish the presence of the user to its contacts, see
+    /// [`crate::presence`].
+    pub async fn publish_presence(&self, status: PresenceStatus) -> Result<()> {
+        let PresenceSettings { visibility } = self.user_setting().await?;
+        let own_wai_ear_key = &self.inner.key_store.wai_ear_key;
+        let mut viewers = Vec::new();
+        let mut contacts = Vec::new();
+        for contact in self.presence_contacts().await? {
+            viewers.push(own_wai_ear_key.presence_token(&contact.wai_ear_key)?);
+            contacts.push(contact.friendship_token);
+        }
+        self.inner
+            .api_clients
+            .default_client()?
+            .qs_publish_presence(
+                self.inner.qs_user_id.clone(),
+                status,
+                visibility,
+                viewers,
+                contacts,
+                &self.inner.key_store.qs_user_signing_key,
+            )
+            .await?;
+        self.inner.presence.set_own_status(status);
+        Ok(())
+    }
+
+    /// Change who can see the presence of the user. The change is enforced by
+    /// the QS right away.
+    pub async fn set_presence_visibility(&self, visibility: PresenceVisibility) -> Result<()> {
+        self.set_user_setting(&PresenceSettings { visibility })
+            .await?;
+        self.publish_presence(self.inner.presence.own_status())
+            .await
+    }
+
+    pub async fn presence_visibility(&self) -> Result<PresenceVisibility> {
+        let PresenceSettings { visibility } = self.user_setting().await?;
+        Ok(visibility)
+    }
+
+    /// The presence of the given contact, if it is known and up to date.
+    pub fn contact_presence(&self, user_name: &QualifiedUserName) -> Option<Presence> {
+        self.inner.presence.get(user_name)
+    }
+
+    /// Fetch the presence of all contacts on the user's server. Returns the
+    /// contacts whose presence changed.
+    pub async fn refresh_contact_presence(&self) -> Result<Vec<QualifiedUserName>> {
+        let own_wai_ear_key = &self.inner.key_store.wai_ear_key;
+        let contacts = self
+            .presence_contacts()
+            .await?
+            .into_iter()
+            .map(|contact| {
+                // The contact reveals the presence under the token derived
+                // from its own key and the key of the user.
+                let presence_token = contact.wai_ear_key.presence_token(own_wai_ear_key)?;
+                Ok((contact.user_name, contact.friendship_token, presence_token))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if contacts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let response = self
+            .inner
+            .api_clients
+            .default_client()?
+            .qs_fetch_presence(
+                self.inner.qs_user_id.clone(),
+                contacts
+                    .iter()
+                    .map(|(_, friendship_token, presence_token)| PresenceQuery {
+                        friendship_token: friendship_token.clone(),
+                        presence_token: presence_token.clone(),
+                    })
+                    .collect(),
+                &self.inner.key_store.qs_user_signing_key,
+            )
+            .await?;
+        let mut changed = Vec::new();
+        for (user_name, friendship_token, _) in contacts {
+            // Contacts that never published their presence are shown as
+            // offline.
+            let presence = response
+                .presences
+                .iter()
+                .find(|presence| presence.friendship_token == friendship_token)
+                .map(|presence| presence.presence)
+                .unwrap_or_default();
+            if self.inner.presence.insert(user_name.clone(), presence) {
+                changed.push(user_name);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Cache the presence pushed by the QS. Returns the contact it belongs
+    /// to, if it changed.
+    pub async fn apply_presence_update(
+        &self,
+        update: ContactPresence,
+    ) -> Result<Option<QualifiedUserName>> {
+        let ContactPresence {
+            friendship_token,
+            presence,
+        } = update;
+        let user_name = self
+            .presence_contacts()
+            .await?
+            .into_iter()
+            .find_map(|contact| {
+                (contact.friendship_token == friendship_token).then_some(contact.user_name)
+            });
+        let Some(user_name) = user_name else {
+            return Ok(None);
+        };
+        let changed = self.inner.presence.insert(user_name.clone(), presence);
+        Ok(changed.then_some(user_name))
+    }
+
+    /// The contacts on the user's server. Presence isn't exchanged across
+    /// servers.
+    async fn presence_contacts(&self) -> Result<Vec<Contact>> {
+        let own_domain = self.user_name().domain();
+        let connection = self.inner.connection.lock().await;
+        let contacts = Contact::load_all(&connection)?
+            .into_iter()
+            .filter(|contact| contact.user_name.domain() == own_domain)
+            .collect();
+        Ok(contacts)
+    }
+}
@@ -10,3 +10,4 @@ use super::{
 
 pub mod process_as;
 pub mod process_qs;
+pub(crate) mod worker_pool;
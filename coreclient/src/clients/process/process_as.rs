@@ -13,6 +13,7 @@ use phnxtypes::{
         client_ds_out::ExternalCommitInfoIn,
         QueueMessage,
     },
+    time::TimeStamp,
 };
 use tls_codec::DeserializeBytes;
 
@@ -50,11 +51,11 @@ impl CoreUser {
 
     /// Process a decrypted message received from the AS queue.
     ///
-    /// Returns the [`ConversationId`] of any newly created conversations.
+    /// Returns the [`ConversationId`] of a newly created conversation, if any.
     pub async fn process_as_message(
         &self,
         as_message_plaintext: ExtractedAsQueueMessagePayload,
-    ) -> Result<ConversationId> {
+    ) -> Result<Option<ConversationId>> {
         match as_message_plaintext {
             ExtractedAsQueueMessagePayload::EncryptedConnectionEstablishmentPackage(ecep) => {
                 // Parse & verify connection establishment package
@@ -62,40 +63,85 @@ impl CoreUser {
                     .parse_and_verify_connection_establishment_package(ecep)
                     .await?;
 
-                // Load user profile
-                let own_user_profile = self.load_own_user_profile().await?;
+                let sender = cep_tbs.sender_client_credential.identity().user_name();
+                if self.is_blocked(&sender).await? {
+                    log::info!("Dropping connection offer of blocked user {sender}");
+                    return Ok(None);
+                }
+                // Suspicious offers and message requests wait for the user's
+                // decision before we join the connection group, even if the
+                // sender asked for a regular connection.
+                #[cfg(feature = "user-handles")]
+                let conversation_id = {
+                    let filter_reason = self
+                        .screen_connection_offer(&sender, cep_tbs.sender_reputation.clone())
+                        .await?;
+                    if filter_reason.is_some() || cep_tbs.message_request {
+                        self.store_message_request(cep_tbs, filter_reason).await?
+                    } else {
+                        self.join_connection_group(cep_tbs).await?
+                    }
+                };
+                // Builds without user handles can't hold message requests
+                // until the user decides about them.
+                #[cfg(not(feature = "user-handles"))]
+                let conversation_id = if cep_tbs.message_request {
+                    log::info!("Ignoring message request of {sender}");
+                    return Ok(None);
+                } else {
+                    self.join_connection_group(cep_tbs).await?
+                };
+                Ok(Some(conversation_id))
+            }
+            ExtractedAsQueueMessagePayload::ConnectionPackageRefreshRequest => {
+                // The AS asks again later, so a failure must not keep the
+                // remaining messages of the queue from being processed.
+                if let Err(error) = self.publish_connection_packages().await {
+                    log::error!("Failed to publish connection packages: {error:#}");
+                }
+                Ok(None)
+            }
+        }
+    }
 
-                // Create signature ear key
-                let signature_ear_key = SignatureEarKey::random()?;
+    /// Join the connection group described by the given (verified) connection
+    /// establishment package and create the corresponding conversation and
+    /// contact.
+    pub(crate) async fn join_connection_group(
+        &self,
+        cep_tbs: ConnectionEstablishmentPackageTbs,
+    ) -> Result<ConversationId> {
+        // Load user profile
+        let own_user_profile = self.load_own_user_profile().await?;
 
-                // Prepare group
-                let (leaf_signer, aad, qgid) =
-                    self.prepare_group(&signature_ear_key, &cep_tbs, own_user_profile)?;
+        // Create signature ear key
+        let signature_ear_key = SignatureEarKey::random()?;
 
-                // Fetch external commit info
-                let eci = self.fetch_external_commit_info(&cep_tbs, &qgid).await?;
+        // Prepare group
+        let (leaf_signer, aad, qgid) =
+            self.prepare_group(&signature_ear_key, &cep_tbs, own_user_profile)?;
 
-                // Join group
-                let (group, commit, group_info) = self
-                    .join_group_externally(signature_ear_key, eci, &cep_tbs, leaf_signer, aad)
-                    .await?;
+        // Fetch external commit info
+        let eci = self.fetch_external_commit_info(&cep_tbs, &qgid).await?;
 
-                // Create conversation
-                let (mut conversation, contact) =
-                    self.create_connection_conversation(&group, &cep_tbs)?;
+        // Join group
+        let (group, commit, group_info) = self
+            .join_group_externally(signature_ear_key, eci, &cep_tbs, leaf_signer, aad)
+            .await?;
 
-                // Store group, conversation & contact
-                self.store_group_conversation_contact(&group, &mut conversation, contact, &cep_tbs)
-                    .await?;
+        // Create conversation
+        let (mut conversation, contact) = self.create_connection_conversation(&group, &cep_tbs)?;
 
-                // Send confirmation
-                self.send_confirmation_to_ds(&group, commit, group_info, &cep_tbs, qgid)
-                    .await?;
+        // Store group, conversation & contact
+        self.store_group_conversation_contact(&group, &mut conversation, contact, &cep_tbs)
+            .await?;
 
-                // Return the conversation ID
-                Ok(conversation.id())
-            }
-        }
+        // Send confirmation
+        self.send_confirmation_to_ds(&group, commit, group_info, &cep_tbs, qgid)
+            .await?;
+
+        // Return the conversation ID
+        Ok(conversation.id())
     }
 
     /// Parse and verify the connection establishment package.
@@ -141,7 +187,7 @@ impl CoreUser {
         // connection.
 
         let leaf_signer = InfraCredentialSigningKey::generate(
-            &self.inner.key_store.signing_key,
+            &self.inner.key_store.signing_key(),
             signature_ear_key,
         );
         let esek =
@@ -158,12 +204,13 @@ impl CoreUser {
                 .clone(),
             wai_ear_key: self.inner.key_store.wai_ear_key.clone(),
             user_profile: own_user_profile,
+            user_profile_ear_key: self.inner.key_store.user_profile_ear_key()?.clone(),
         }
         .encrypt(&cep_tbs.friendship_package_ear_key)?;
         let ecc = self
             .inner
             .key_store
-            .signing_key
+            .signing_key()
             .credential()
             .encrypt(&cep_tbs.connection_group_credential_key)?;
 
@@ -222,7 +269,7 @@ impl CoreUser {
                 .clone(),
             cep_tbs.connection_group_credential_key.clone(),
             aad,
-            self.inner.key_store.signing_key.credential(),
+            self.inner.key_store.signing_key().credential(),
         )
         .await?;
         Ok((group, commit, group_info))
@@ -267,6 +314,7 @@ impl CoreUser {
         let connection = self.inner.connection.lock().await;
         group.store(&connection)?;
         conversation.store(&connection)?;
+        group.store_own_join(&connection, conversation.id(), None, TimeStamp::now())?;
         // Store the user profile of the sender.
         cep_tbs.friendship_package.user_profile.store(&connection)?;
         // TODO: For now, we automatically confirm conversations.
@@ -304,12 +352,23 @@ impl CoreUser {
     pub async fn fully_process_as_messages(
         &self,
         as_messages: Vec<QueueMessage>,
+    ) -> Result<Vec<ConversationId>> {
+        let _in_flight = self.inner.queue_lock.hold_for_processing().await?;
+        self.process_as_messages(as_messages).await
+    }
+
+    /// Like [`Self::fully_process_as_messages`] for callers that already hold
+    /// the queues.
+    pub(crate) async fn process_as_messages(
+        &self,
+        as_messages: Vec<QueueMessage>,
     ) -> Result<Vec<ConversationId>> {
         let mut conversation_ids = vec![];
         for as_message in as_messages {
             let as_message_plaintext = self.decrypt_as_queue_message(as_message).await?;
-            let conversation_id = self.process_as_message(as_message_plaintext).await?;
-            conversation_ids.push(conversation_id);
+            if let Some(conversation_id) = self.process_as_message(as_message_plaintext).await? {
+                conversation_ids.push(conversation_id);
+            }
         }
         Ok(conversation_ids)
     }
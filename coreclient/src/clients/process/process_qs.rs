@@ -2,37 +2,46 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::ops::Deref;
+use std::{collections::VecDeque, ops::Deref};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 use openmls::{
     group::QueuedProposal,
     prelude::{
-        KeyPackage, MlsMessageBodyIn, MlsMessageIn, ProcessedMessageContent, ProtocolMessage,
-        ProtocolVersion, Sender,
+        GroupId, KeyPackage, MlsMessageBodyIn, MlsMessageIn, ProcessedMessageContent,
+        ProtocolMessage, ProtocolVersion, Sender,
     },
 };
 use openmls_rust_crypto::RustCrypto;
 use phnxtypes::{
-    codec::PhnxCodec,
-    crypto::ear::EarDecryptable,
+    crypto::{ear::EarDecryptable, ratchet::QueueRatchet},
     identifiers::AsClientId,
     messages::{
         client_ds::{
             ExtractedQsQueueMessage, ExtractedQsQueueMessagePayload, InfraAadMessage,
-            InfraAadPayload, WelcomeBundle,
+            InfraAadPayload, QsQueueMessagePayload, WelcomeBundle,
         },
-        QueueMessage,
+        EncryptedQsQueueMessage, QueueMessage, QueuePriority,
     },
     time::TimeStamp,
 };
-use tls_codec::DeserializeBytes;
-
-use crate::{conversations::ConversationType, groups::Group, ConversationMessage, PartialContact};
+use tls_codec::{DeserializeBytes, Serialize as _};
+use uuid::Uuid;
+
+use crate::{
+    activity::{Activity, ActivityKind},
+    chat_metadata::ChatMetadata,
+    conversations::ConversationType,
+    groups::{quarantine::QuarantinedWelcome, Group, WelcomeProcessingError},
+    mimi_content::MimiContent,
+    ConversationMessage, NotificationType, PartialContact,
+};
 
 use super::{
-    anyhow, Asset, ContactAddInfos, Conversation, ConversationAttributes, ConversationId, CoreUser,
-    FriendshipPackage, SignatureEarKey, TimestampedMessage, UserProfile, Verifiable,
+    anyhow,
+    worker_pool::{into_segments, Segment, WorkerPool},
+    Asset, ContactAddInfos, Conversation, ConversationId, CoreUser, FriendshipPackage,
+    SignatureEarKey, TimestampedMessage, UserProfile, Verifiable,
 };
 use crate::key_stores::{
     qs_verifying_keys::StorableQsVerifyingKey, queue_ratchets::StorableQsQueueRatchet,
@@ -44,10 +53,38 @@ pub enum ProcessQsMessageResult {
     ConversationMessages(Vec<ConversationMessage>),
 }
 
+type QsQueueRatchet = QueueRatchet<EncryptedQsQueueMessage, QsQueueMessagePayload>;
+
+type DecryptedQsQueueMessages = Vec<(QueuePriority, Option<GroupId>, ExtractedQsQueueMessage)>;
+
+enum QueueMessageError {
+    /// The message couldn't be decrypted. The queue ratchet doesn't move past
+    /// it.
+    Decryption(anyhow::Error),
+    /// The message was decrypted, but is invalid. The queue ratchet moves past
+    /// it.
+    Invalid(anyhow::Error),
+}
+
+#[derive(Default)]
 pub struct ProcessedQsMessages {
     pub new_conversations: Vec<ConversationId>,
     pub changed_conversations: Vec<ConversationId>,
     pub new_messages: Vec<ConversationMessage>,
+    /// Number of messages that couldn't be decrypted or processed. They are
+    /// dropped, since the queue ratchet can't decrypt them again.
+    pub failed_messages: usize,
+}
+
+impl ProcessedQsMessages {
+    /// Add the results of processing a later batch of messages.
+    pub(crate) fn append(&mut self, other: ProcessedQsMessages) {
+        self.new_conversations.extend(other.new_conversations);
+        self.changed_conversations
+            .extend(other.changed_conversations);
+        self.new_messages.extend(other.new_messages);
+        self.failed_messages += other.failed_messages;
+    }
 }
 
 impl CoreUser {
@@ -56,6 +93,16 @@ impl CoreUser {
         &self,
         qs_message_ciphertext: QueueMessage,
     ) -> Result<ExtractedQsQueueMessage> {
+        let payload = self
+            .decrypt_qs_queue_message_payload(qs_message_ciphertext)
+            .await?;
+        Ok(payload.extract()?)
+    }
+
+    async fn decrypt_qs_queue_message_payload(
+        &self,
+        qs_message_ciphertext: QueueMessage,
+    ) -> Result<QsQueueMessagePayload> {
         let mut connection = self.inner.connection.lock().await;
         let transaction = connection.transaction()?;
         let mut qs_queue_ratchet = StorableQsQueueRatchet::load(&transaction)?;
@@ -65,7 +112,74 @@ impl CoreUser {
         qs_queue_ratchet.update_ratchet(&transaction)?;
         transaction.commit()?;
 
-        Ok(payload.extract()?)
+        Ok(payload)
+    }
+
+    /// Decrypt the given `QueueMessage`s on the worker pool and determine the
+    /// group each message belongs to, such that they can be ordered by
+    /// [`drain_by_priority`].
+    ///
+    /// If a message can't be decrypted, the messages before it are returned
+    /// together with the error. The queue ratchet only moves past the returned
+    /// messages and messages that were decrypted, but are invalid.
+    async fn decrypt_qs_queue_messages(
+        &self,
+        pool: &WorkerPool,
+        qs_messages: Vec<QueueMessage>,
+    ) -> Result<(DecryptedQsQueueMessages, Option<anyhow::Error>)> {
+        let connection = self.inner.connection.lock().await;
+        let mut qs_queue_ratchet = StorableQsQueueRatchet::load(&connection)?;
+        let keyed_messages = qs_messages
+            .into_iter()
+            .map(|qs_message| Ok((qs_queue_ratchet.next_key()?, qs_message)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let outcomes = pool
+            .map_blocking(
+                keyed_messages,
+                |(key, qs_message)| -> Result<_, QueueMessageError> {
+                    let priority = qs_message.priority;
+                    let payload = QsQueueRatchet::decrypt_with_key(&key, qs_message)
+                        .map_err(|error| QueueMessageError::Decryption(error.into()))?;
+                    let group_id = payload
+                        .group_id()
+                        .map_err(|error| QueueMessageError::Invalid(error.into()))?;
+                    let message = payload
+                        .extract()
+                        .map_err(|error| QueueMessageError::Invalid(error.into()))?;
+                    Ok((priority, group_id, message))
+                },
+            )
+            .await?;
+
+        let mut decrypted_messages = Vec::with_capacity(outcomes.len());
+        let mut consumed = 0;
+        let mut decryption_error = None;
+        for outcome in outcomes {
+            match outcome {
+                Ok(decrypted_message) => {
+                    decrypted_messages.push(decrypted_message);
+                    consumed += 1;
+                }
+                Err(QueueMessageError::Decryption(error)) => {
+                    decryption_error = Some(error);
+                    break;
+                }
+                Err(QueueMessageError::Invalid(error)) => {
+                    consumed += 1;
+                    decryption_error = Some(error);
+                    break;
+                }
+            }
+        }
+
+        let mut qs_queue_ratchet = StorableQsQueueRatchet::load(&connection)?;
+        for _ in 0..consumed {
+            qs_queue_ratchet.next_key()?;
+        }
+        qs_queue_ratchet.update_ratchet(&connection)?;
+
+        Ok((decrypted_messages, decryption_error))
     }
 
     /// Process a decrypted message received from the QS queue.
@@ -102,16 +216,61 @@ impl CoreUser {
             ExtractedQsQueueMessagePayload::MlsMessage(mls_message) => {
                 self.handle_mls_message(*mls_message, ds_timestamp).await
             }
+            ExtractedQsQueueMessagePayload::JoinRequest(join_request) => {
+                self.handle_join_request(join_request).await
+            }
+            #[cfg(feature = "attachments")]
+            ExtractedQsQueueMessagePayload::AttachmentBlocked(attachment_blocked) => {
+                self.handle_attachment_blocked(attachment_blocked).await
+            }
+            // Builds without attachments never upload any.
+            #[cfg(not(feature = "attachments"))]
+            ExtractedQsQueueMessagePayload::AttachmentBlocked(_) => {
+                Ok(ProcessQsMessageResult::ConversationMessages(vec![]))
+            }
+            ExtractedQsQueueMessagePayload::SelfMessage(self_message) => {
+                self.handle_self_message(self_message).await
+            }
         }
     }
 
+    /// Process a welcome bundle. If the welcome bundle can't be processed, it
+    /// is put into quarantine, such that processing of the remaining messages
+    /// can continue.
     async fn handle_welcome_bundle(
         &self,
         welcome_bundle: WelcomeBundle,
     ) -> Result<ProcessQsMessageResult> {
+        let serialized_welcome_bundle = welcome_bundle.tls_serialize_detached()?;
+        match self.join_group_from_welcome(welcome_bundle).await {
+            Ok(conversation_id) => {
+                let connection = self.inner.connection.lock().await;
+                let invite = Activity::new(ActivityKind::Invite, Some(conversation_id));
+                self.record_activity(&connection, invite)?;
+                Ok(ProcessQsMessageResult::NewConversation(conversation_id))
+            }
+            Err(error) => {
+                log::error!("Quarantining invalid welcome bundle: {}", error);
+                let quarantined_welcome =
+                    QuarantinedWelcome::new(serialized_welcome_bundle, error.to_string());
+                let connection = self.inner.connection.lock().await;
+                quarantined_welcome.store(&connection)?;
+                let warning = ActivityKind::SecurityWarning {
+                    reason: format!("Received an invalid invitation: {error}"),
+                };
+                self.record_activity(&connection, Activity::new(warning, None))?;
+                Ok(ProcessQsMessageResult::ConversationMessages(vec![]))
+            }
+        }
+    }
+
+    async fn join_group_from_welcome(
+        &self,
+        welcome_bundle: WelcomeBundle,
+    ) -> Result<ConversationId, WelcomeProcessingError> {
         // WelcomeBundle Phase 1: Join the group. This might involve
         // loading AS credentials or fetching them from the AS.
-        let group = Group::join_group(
+        let (group, adder_credential) = Group::join_group(
             welcome_bundle,
             &self.inner.key_store.wai_ear_key,
             self.inner.connection.clone(),
@@ -120,6 +279,10 @@ impl CoreUser {
         .await?;
         let group_id = group.group_id().clone();
 
+        // Set the conversation attributes according to the group's
+        // group data.
+        let attributes = group.conversation_attributes()?;
+
         // WelcomeBundle Phase 2: Store the user profiles of the group
         // members if they don't exist yet and store the group and the
         // new conversation.
@@ -132,22 +295,65 @@ impl CoreUser {
                 UserProfile::new(user_name, None, None).store(&transaction)
             })?;
 
-        // Set the conversation attributes according to the group's
-        // group data.
-        let group_data = group.group_data().context("No group data")?;
-        let attributes: ConversationAttributes = PhnxCodec::from_slice(group_data.bytes())?;
-
         let conversation = Conversation::new_group_conversation(group_id.clone(), attributes);
         // If we've been in that conversation before, we delete the old
         // conversation (and the corresponding MLS group) first and then
         // create a new one. We do leave the messages intact, though.
         Conversation::delete(&transaction, conversation.id())?;
-        Group::delete_from_db(&mut transaction, &group_id)?;
-        group.store(&transaction)?;
+        self.inner.groups.delete(&mut transaction, &group_id)?;
+        self.inner.groups.store(&transaction, &group)?;
         conversation.store(&transaction)?;
+        ChatMetadata::store_shared(
+            &transaction,
+            conversation.id(),
+            &group.shared_chat_metadata(),
+        )?;
+        group
+            .store_own_join(
+                &transaction,
+                conversation.id(),
+                Some(&adder_credential),
+                TimeStamp::now(),
+            )
+            .map_err(WelcomeProcessingError::InvalidClientInformation)?;
         transaction.commit()?;
 
-        Ok(ProcessQsMessageResult::NewConversation(conversation.id()))
+        Ok(conversation.id())
+    }
+
+    /// Returns all welcome bundles that could not be processed.
+    pub async fn quarantined_welcomes(&self) -> Result<Vec<QuarantinedWelcome>> {
+        let connection = self.inner.connection.lock().await;
+        Ok(QuarantinedWelcome::load_all(&connection)?)
+    }
+
+    /// Retry processing the quarantined welcome bundle with the given id,
+    /// e.g. after a transient failure. The welcome bundle is removed from
+    /// quarantine if it is processed successfully.
+    pub async fn retry_quarantined_welcome(&self, id: Uuid) -> Result<ConversationId> {
+        let connection = self.inner.connection.lock().await;
+        let quarantined_welcome = QuarantinedWelcome::load(&connection, id)?
+            .ok_or_else(|| anyhow!("Can't find quarantined welcome with id {}", id))?;
+        drop(connection);
+        if !quarantined_welcome.has_welcome_bundle() {
+            bail!("Quarantined welcome {id} was too large to be kept");
+        }
+
+        let welcome_bundle =
+            WelcomeBundle::tls_deserialize_exact_bytes(&quarantined_welcome.welcome_bundle)?;
+        let conversation_id = self.join_group_from_welcome(welcome_bundle).await?;
+        self.update_user_key(&conversation_id).await?;
+
+        let connection = self.inner.connection.lock().await;
+        QuarantinedWelcome::delete(&connection, id)?;
+        Ok(conversation_id)
+    }
+
+    /// Delete the quarantined welcome bundle with the given id.
+    pub async fn delete_quarantined_welcome(&self, id: Uuid) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        QuarantinedWelcome::delete(&connection, id)?;
+        Ok(())
     }
 
     async fn handle_mls_message(
@@ -172,7 +378,10 @@ impl CoreUser {
             .ok_or_else(|| anyhow!("No conversation found for group ID {:?}", group_id))?;
         let conversation_id = conversation.id();
 
-        let mut group = Group::load(&connection, group_id)?
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
             .ok_or_else(|| anyhow!("No group found for group ID {:?}", group_id))?;
         drop(connection);
 
@@ -190,8 +399,15 @@ impl CoreUser {
 
         // `conversation_changed` indicates whether the state of the conversation was updated
         let (group_messages, conversation_changed) = match processed_message.into_content() {
-            ProcessedMessageContent::ApplicationMessage(application_message) => self
-                .handle_application_message(application_message, ds_timestamp, &sender_client_id)?,
+            ProcessedMessageContent::ApplicationMessage(application_message) => {
+                self.handle_application_message(
+                    conversation_id,
+                    application_message,
+                    ds_timestamp,
+                    &sender_client_id,
+                )
+                .await?
+            }
             ProcessedMessageContent::ProposalMessage(proposal) => {
                 self.handle_proposal_message(&mut group, *proposal).await?
             }
@@ -221,6 +437,7 @@ impl CoreUser {
         let conversation_messages =
             Self::store_messages(&mut transaction, conversation_id, group_messages)?;
         transaction.commit()?;
+        group.check_in(&connection)?;
         Ok(match (conversation_messages, conversation_changed) {
             (messages, true) => {
                 ProcessQsMessageResult::ConversationChanged(conversation_id, messages)
@@ -229,17 +446,38 @@ impl CoreUser {
         })
     }
 
-    fn handle_application_message(
+    async fn handle_application_message(
         &self,
+        conversation_id: ConversationId,
         application_message: openmls::prelude::ApplicationMessage,
         ds_timestamp: TimeStamp,
         sender_client_id: &AsClientId,
     ) -> anyhow::Result<(Vec<TimestampedMessage>, bool)> {
-        let group_messages = vec![TimestampedMessage::from_application_message(
-            application_message,
+        let content = MimiContent::tls_deserialize_exact_bytes(&application_message.into_bytes())?;
+        // Shared profile keys only update the contact.
+        if let Some(key) = content.shared_user_profile_key() {
+            #[cfg(feature = "profiles")]
+            self.handle_user_profile_key(conversation_id, &sender_client_id.user_name(), key)
+                .await?;
+            // Builds without profiles don't refresh the profiles of contacts.
+            #[cfg(not(feature = "profiles"))]
+            let _ = key;
+            return Ok((vec![], false));
+        }
+        // Receipts only update the state of existing messages.
+        if content.is_receipt() {
+            #[cfg(feature = "attachments")]
+            let conversation_changed = self.handle_receipt(conversation_id, &content).await?;
+            // Builds without attachments never send view-once attachments.
+            #[cfg(not(feature = "attachments"))]
+            let conversation_changed = false;
+            return Ok((vec![], conversation_changed));
+        }
+        let group_messages = vec![TimestampedMessage::from_content(
+            content,
             ds_timestamp,
             sender_client_id.user_name(),
-        )?];
+        )];
         Ok((group_messages, false))
     }
 
@@ -331,6 +569,8 @@ impl CoreUser {
                         friendship_package.add_package_ear_key.clone(),
                     )
                     .await?;
+                let key_package_batch_response_availability =
+                    key_package_batch_response.availability;
                 let key_packages: Vec<(KeyPackage, SignatureEarKey)> = key_package_batch_response
                     .add_packages
                     .into_iter()
@@ -359,6 +599,13 @@ impl CoreUser {
                     key_packages,
                 };
                 add_infos.push(add_info);
+                // Once the contact's clients are down to their last-resort key
+                // packages, further batches would only contain the same
+                // packages again.
+                if key_package_batch_response_availability.last_resort_used {
+                    log::warn!("Contact {user_name} is out of key packages");
+                    break;
+                }
             }
 
             // UnconfirmedConnection Phase 3: Store the user profile of the sender and the contact.
@@ -409,17 +656,81 @@ impl CoreUser {
 
     /// Convenience function that takes a list of `QueueMessage`s retrieved from
     /// the QS, decrypts them, and processes them.
+    ///
+    /// Messages are decrypted in sequence order (as required by the queue
+    /// ratchet), but processed such that higher priority lanes are drained
+    /// first (see [`drain_by_priority`]).
+    ///
+    /// The queue ratchet moves past the messages before they are processed,
+    /// so a message that fails is logged and counted in
+    /// [`ProcessedQsMessages::failed_messages`] and processing continues with
+    /// the next one. The results of all other messages are always returned.
     pub async fn fully_process_qs_messages(
         &self,
         qs_messages: Vec<QueueMessage>,
     ) -> Result<ProcessedQsMessages> {
-        // Process each qs message individually
+        let _in_flight = self.inner.queue_lock.hold_for_processing().await?;
+        self.process_qs_messages(qs_messages).await
+    }
+
+    /// Like [`Self::fully_process_qs_messages`] for callers that already hold
+    /// the queues.
+    pub(crate) async fn process_qs_messages(
+        &self,
+        qs_messages: Vec<QueueMessage>,
+    ) -> Result<ProcessedQsMessages> {
+        let pool = self.inner.worker_pool;
+        let mut failed_messages = 0;
+
+        // Decrypt all messages. If decryption fails, we still process the
+        // messages decrypted so far, since the ratchet has already moved past
+        // them.
+        let number_of_messages = qs_messages.len();
+        let (decrypted_messages, decryption_error) =
+            self.decrypt_qs_queue_messages(&pool, qs_messages).await?;
+        if let Some(error) = decryption_error {
+            log::error!("Failed to decrypt QS queue message: {error:?}");
+            failed_messages += number_of_messages - decrypted_messages.len();
+        }
+
+        // Process the messages of different groups concurrently, keeping the
+        // order within each group.
+        let decrypted_messages = decrypted_messages
+            .into_iter()
+            .map(|(priority, group_id, message)| (priority, group_id.clone(), (group_id, message)))
+            .collect();
+        let mut results = vec![];
+        for segment in into_segments(drain_by_priority(decrypted_messages)) {
+            match segment {
+                Segment::Barrier(qs_message_plaintext) => {
+                    match self.process_qs_message(qs_message_plaintext).await {
+                        Ok(result) => results.push(result),
+                        Err(error) => {
+                            log::error!("Failed to process QS queue message: {error:?}");
+                            failed_messages += 1;
+                        }
+                    }
+                }
+                Segment::Lanes(lanes) => {
+                    let (lane_results, errors) = pool
+                        .process_lanes(lanes, |qs_message_plaintext| {
+                            self.process_qs_message(qs_message_plaintext)
+                        })
+                        .await;
+                    results.extend(lane_results);
+                    for error in &errors {
+                        log::error!("Failed to process QS queue message: {error:?}");
+                    }
+                    failed_messages += errors.len();
+                }
+            }
+        }
+
         let mut new_conversations = vec![];
         let mut changed_conversations = vec![];
         let mut new_messages = vec![];
-        for qs_message in qs_messages {
-            let qs_message_plaintext = self.decrypt_qs_queue_message(qs_message).await?;
-            match self.process_qs_message(qs_message_plaintext).await? {
+        for result in results {
+            match result {
                 ProcessQsMessageResult::ConversationMessages(conversation_messages) => {
                     new_messages.extend(conversation_messages);
                 }
@@ -436,16 +747,140 @@ impl CoreUser {
             };
         }
 
+        // The messages are stored at this point, so failures of the follow-up
+        // work are logged instead of discarding the results.
+
         // Update user auth keys of newly created conversations.
         for conversation_id in &new_conversations {
-            let messages = self.update_user_key(conversation_id).await?;
-            new_messages.extend(messages);
+            match self.update_user_key(conversation_id).await {
+                Ok(messages) => new_messages.extend(messages),
+                Err(error) => log::error!("Failed to update user auth key: {error:?}"),
+            }
+        }
+
+        // Move the conversations of upgraded groups to their successors.
+        match self.migrate_upgraded_groups().await {
+            Ok(conversation_ids) => {
+                for conversation_id in conversation_ids {
+                    if !changed_conversations.contains(&conversation_id) {
+                        changed_conversations.push(conversation_id);
+                    }
+                }
+            }
+            Err(error) => log::error!("Failed to migrate upgraded groups: {error:?}"),
+        }
+
+        // Resume connections that were interrupted and drop the ones that
+        // can't be established anymore.
+        match self.resume_pending_connections().await {
+            Ok(conversation_ids) => {
+                for conversation_id in conversation_ids {
+                    if !changed_conversations.contains(&conversation_id) {
+                        changed_conversations.push(conversation_id);
+                    }
+                }
+            }
+            Err(error) => log::error!("Failed to resume pending connections: {error:?}"),
+        }
+
+        // Finish an account deletion that was interrupted.
+        if let Err(error) = self.resume_account_deletion().await {
+            log::error!("Failed to resume account deletion: {error:?}");
+        }
+
+        {
+            let connection = self.inner.connection.lock().await;
+            if let Err(error) = self.record_mentions(&connection, &new_messages) {
+                log::error!("Failed to record mentions: {error:?}");
+            }
+        }
+
+        let notifier = self.store_notifier();
+        for conversation_id in new_conversations.iter().chain(&changed_conversations) {
+            notifier.notify(NotificationType::ConversationChange(*conversation_id));
+        }
+        for message in &new_messages {
+            notifier.notify(NotificationType::Message(message.clone()));
         }
 
         Ok(ProcessedQsMessages {
             new_conversations,
             changed_conversations,
             new_messages,
+            failed_messages,
         })
     }
 }
+
+/// Order the given messages such that higher priority lanes are drained first.
+///
+/// MLS requires the messages of a group to be processed in order, so messages
+/// are only moved ahead of messages of other groups. Messages without a group
+/// (i.e. welcome bundles) act as barriers across which no message is moved.
+fn drain_by_priority<T>(messages: Vec<(QueuePriority, Option<GroupId>, T)>) -> Vec<T> {
+    let mut drained = Vec::with_capacity(messages.len());
+    let mut segment = Vec::new();
+    for (priority, group_id, message) in messages {
+        match group_id {
+            Some(group_id) => segment.push((priority, group_id, message)),
+            None => {
+                drain_segment(&mut segment, &mut drained);
+                drained.push(message);
+            }
+        }
+    }
+    drain_segment(&mut segment, &mut drained);
+    drained
+}
+
+fn drain_segment<T>(segment: &mut Vec<(QueuePriority, GroupId, T)>, drained: &mut Vec<T>) {
+    // Split the segment into one lane per group, keeping the order within
+    // each group.
+    let mut groups: Vec<(GroupId, VecDeque<(QueuePriority, usize, T)>)> = Vec::new();
+    for (index, (priority, group_id, message)) in segment.drain(..).enumerate() {
+        match groups.iter_mut().find(|(id, _)| id == &group_id) {
+            Some((_, lane)) => lane.push_back((priority, index, message)),
+            None => groups.push((group_id, VecDeque::from([(priority, index, message)]))),
+        }
+    }
+
+    // Repeatedly pick the group whose next message has the highest priority.
+    // Ties are broken by the original sequence order.
+    loop {
+        let next = groups
+            .iter_mut()
+            .filter_map(|(_, lane)| {
+                let &(priority, index, _) = lane.front()?;
+                Some(((priority, index), lane))
+            })
+            .min_by_key(|(key, _)| *key);
+        let Some((_, lane)) = next else {
+            break;
+        };
+        if let Some((_, _, message)) = lane.pop_front() {
+            drained.push(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_by_priority_keeps_group_order() {
+        let group_a = GroupId::from_slice(b"a");
+        let group_b = GroupId::from_slice(b"b");
+        let messages = vec![
+            (QueuePriority::Normal, Some(group_a.clone()), 0),
+            (QueuePriority::Normal, Some(group_a.clone()), 1),
+            (QueuePriority::High, Some(group_b.clone()), 2),
+            (QueuePriority::High, Some(group_a.clone()), 3),
+            (QueuePriority::Low, Some(group_b.clone()), 4),
+            (QueuePriority::High, None, 5),
+            (QueuePriority::High, Some(group_b.clone()), 6),
+        ];
+        let drained = drain_by_priority(messages);
+        assert_eq!(drained, vec![2, 0, 1, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Worker pool for catching up on large backlogs of queue messages.
+//!
+//! Decrypting and decoding queue messages is CPU-bound and blocks the async
+//! runtime if done inline. The pool runs this work on the blocking thread pool
+//! in at most one batch per core. In the browser, there is no such pool and
+//! the work runs inline.
+//!
+//! MLS requires the messages of a group to be processed in order, so messages
+//! are processed in one lane per group. Lanes of different groups are
+//! processed concurrently with bounded parallelism.
+
+use std::{future::Future, num::NonZeroUsize, sync::Arc};
+
+use anyhow::Result;
+use futures_util::{
+    future::try_join_all,
+    stream::{self, StreamExt},
+};
+
+use crate::utils::rt;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WorkerPool {
+    parallelism: usize,
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        Self::new(parallelism)
+    }
+}
+
+impl WorkerPool {
+    pub(crate) fn new(parallelism: usize) -> Self {
+        Self {
+            parallelism: parallelism.max(1),
+        }
+    }
+
+    /// Apply `f` to all items on the blocking thread pool. The results are
+    /// returned in the order of the items.
+    pub(crate) async fn map_blocking<T, R, F>(&self, items: Vec<T>, f: F) -> Result<Vec<R>>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let batch_size = items.len().div_ceil(self.parallelism).max(1);
+        let mut items = items.into_iter();
+        let mut batches = Vec::new();
+        loop {
+            let batch: Vec<T> = items.by_ref().take(batch_size).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let f = f.clone();
+            batches.push(rt::spawn_blocking(move || {
+                batch.into_iter().map(|item| f(item)).collect::<Vec<_>>()
+            }));
+        }
+        Ok(try_join_all(batches).await?.into_iter().flatten().collect())
+    }
+
+    /// Process the given lanes concurrently, at most one lane per core at a
+    /// time. Lanes are started in the given order and the items of a lane are
+    /// processed in order. An item that fails doesn't stop the remaining
+    /// items of its lane.
+    ///
+    /// Returns the results in lane order and the errors of the failed items.
+    pub(crate) async fn process_lanes<T, R, F, Fut>(
+        &self,
+        lanes: Vec<Vec<T>>,
+        f: F,
+    ) -> (Vec<R>, Vec<anyhow::Error>)
+    where
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let f = &f;
+        let lane_results: Vec<_> = stream::iter(lanes)
+            .map(|lane| async move {
+                let mut results = Vec::with_capacity(lane.len());
+                let mut errors = Vec::new();
+                for item in lane {
+                    match f(item).await {
+                        Ok(result) => results.push(result),
+                        Err(error) => errors.push(error),
+                    }
+                }
+                (results, errors)
+            })
+            .buffered(self.parallelism)
+            .collect()
+            .await;
+
+        let mut results = Vec::new();
+        let mut errors = Vec::new();
+        for (lane_results, lane_errors) in lane_results {
+            results.extend(lane_results);
+            errors.extend(lane_errors);
+        }
+        (results, errors)
+    }
+}
+
+pub(crate) enum Segment<T> {
+    /// Must be processed on its own, after all preceding and before all
+    /// following items.
+    Barrier(T),
+    /// Lanes that can be processed concurrently
+    Lanes(Vec<Vec<T>>),
+}
+
+/// Split the given items into lanes by their key, keeping the order within
+/// each lane. Lanes are ordered by their first item. Items without a key act
+/// as barriers, across which no item is moved.
+pub(crate) fn into_segments<K: PartialEq, T>(
+    items: impl IntoIterator<Item = (Option<K>, T)>,
+) -> Vec<Segment<T>> {
+    let mut segments = Vec::new();
+    let mut lanes: Vec<(K, Vec<T>)> = Vec::new();
+    for (key, item) in items {
+        match key {
+            Some(key) => match lanes.iter_mut().find(|(lane_key, _)| lane_key == &key) {
+                Some((_, lane)) => lane.push(item),
+                None => lanes.push((key, vec![item])),
+            },
+            None => {
+                if !lanes.is_empty() {
+                    let lanes = lanes.drain(..).map(|(_, lane)| lane).collect();
+                    segments.push(Segment::Lanes(lanes));
+                }
+                segments.push(Segment::Barrier(item));
+            }
+        }
+    }
+    if !lanes.is_empty() {
+        segments.push(Segment::Lanes(
+            lanes.into_iter().map(|(_, lane)| lane).collect(),
+        ));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use super::*;
+
+    #[test]
+    fn segments_keep_lane_order() {
+        let items = vec![
+            (Some('a'), 0),
+            (Some('b'), 1),
+            (Some('a'), 2),
+            (None, 3),
+            (Some('b'), 4),
+        ];
+        let segments: Vec<_> = into_segments(items)
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Barrier(item) => vec![vec![item]],
+                Segment::Lanes(lanes) => lanes,
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            vec![vec![vec![0, 2], vec![1]], vec![vec![3]], vec![vec![4]]]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn map_blocking_keeps_order() {
+        let pool = WorkerPool::new(3);
+        let results = pool
+            .map_blocking((0..10).collect(), |i: u32| i * 2)
+            .await
+            .unwrap();
+        assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[actix_rt::test]
+    async fn failing_item_doesnt_stop_its_lane() {
+        let pool = WorkerPool::new(2);
+        let lanes = vec![vec![0, 1, 2], vec![3, 4], vec![5]];
+        let (results, errors) = pool
+            .process_lanes(lanes, |i| async move {
+                if i == 1 {
+                    bail!("failed")
+                }
+                Ok(i)
+            })
+            .await;
+        assert_eq!(results, vec![0, 2, 3, 4, 5]);
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Ownership of the queues of a client among the processes that share its
+//! DB, i.e. the app and its extensions.
+//!
+//! Processing queue messages advances the queue ratchets and the MLS groups
+//! stored in the DB, so two processes must never fetch and process messages
+//! at the same time. Only the process that holds the queue lock does. A
+//! process takes the lock with its first fetch, without waiting, and keeps it
+//! until it calls [`CoreUser::release_queues`] or exits. The app releases the
+//! queues when it moves to the background and reclaims them with
+//! [`CoreUser::reclaim_queues`] when it returns to the foreground. Releasing
+//! the queues stops new fetches right away, but the lock is only handed over
+//! once the fetches and processing runs in flight are done. Extensions don't
+//! process the queues, but only show previews while the app doesn't own them.
+//!
+//! The lock is an advisory lock on a file next to the client DB. Clients with
+//! in-memory DBs are never shared and don't need it.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    future::Future,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::Result;
+use phnxtypes::identifiers::AsClientId;
+use thiserror::Error;
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+use crate::utils::persistence::client_db_name;
+
+use super::CoreUser;
+
+/// Another process (the app or one of its extensions) owns the queues, or
+/// this process released them.
+#[derive(Debug, Error)]
+#[error("The queues of the client are not owned by this process")]
+pub struct QueuesNotOwned;
+
+#[derive(Debug, Default)]
+pub(crate) struct QueueLock {
+    state: Mutex<QueueLockState>,
+    /// Read while the queues are fetched or processed, such that handing the
+    /// lock to other processes waits for the runs in flight.
+    in_flight: RwLock<()>,
+}
+
+#[derive(Debug, Default)]
+struct QueueLockState {
+    /// `None` for clients with in-memory DBs
+    path: Option<PathBuf>,
+    file: Option<File>,
+    released: bool,
+}
+
+impl QueueLock {
+    fn lock(&self) -> MutexGuard<'_, QueueLockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Use the lock file of the client with the given id under `db_path`.
+    pub(crate) fn set_path(&self, as_client_id: &AsClientId, db_path: &str) {
+        let path = PathBuf::from(db_path).join(format!("{}.lock", client_db_name(as_client_id)));
+        self.lock().path = Some(path);
+    }
+
+    /// Hold the queues to fetch messages until the returned guard is
+    /// dropped. Fails like [`Self::ensure_held`].
+    pub(crate) async fn hold(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        let in_flight = self.in_flight.read().await;
+        self.ensure_held()?;
+        Ok(in_flight)
+    }
+
+    /// Hold the queues to process fetched messages until the returned guard
+    /// is dropped. Unlike [`Self::hold`], this succeeds after the queues were
+    /// released, as long as the lock wasn't handed over yet, such that the
+    /// messages fetched before can still be processed.
+    pub(crate) async fn hold_for_processing(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        let in_flight = self.in_flight.read().await;
+        let handing_over = {
+            let state = self.lock();
+            state.released && state.file.is_some()
+        };
+        if !handing_over {
+            self.ensure_held()?;
+        }
+        Ok(in_flight)
+    }
+
+    /// Take the lock unless this process already holds it. Fails with
+    /// [`QueuesNotOwned`] if another process holds it or if this process
+    /// released it.
+    pub(crate) fn ensure_held(&self) -> Result<()> {
+        let mut state = self.lock();
+        if state.released {
+            return Err(QueuesNotOwned.into());
+        }
+        let Some(path) = &state.path else {
+            return Ok(());
+        };
+        if state.file.is_some() {
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => {
+                state.file = Some(file);
+                Ok(())
+            }
+            Err(TryLockError::WouldBlock) => Err(QueuesNotOwned.into()),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
+    /// Whether another process holds the lock. Doesn't take the lock.
+    pub(crate) fn is_held_elsewhere(&self) -> Result<bool> {
+        let state = self.lock();
+        let Some(path) = &state.path else {
+            return Ok(false);
+        };
+        if state.file.is_some() {
+            return Ok(false);
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => {
+                file.unlock()?;
+                Ok(false)
+            }
+            Err(TryLockError::WouldBlock) => Ok(true),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
+    /// Stop fetching messages. The lock is kept until
+    /// [`Self::unlock_when_idle`] hands it over.
+    pub(crate) fn release(&self) {
+        self.lock().released = true;
+    }
+
+    /// Hand the lock to other processes once the fetches and processing runs
+    /// in flight are done, unless the queues were reclaimed in the meantime.
+    pub(crate) async fn unlock_when_idle(&self) {
+        let _idle = self.in_flight.write().await;
+        let mut state = self.lock();
+        if !state.released {
+            return;
+        }
+        if let Some(file) = state.file.take() {
+            if let Err(error) = file.unlock() {
+                log::warn!("Failed to release the queue lock: {error}");
+            }
+        }
+    }
+
+    pub(crate) fn reclaim(&self) -> Result<()> {
+        self.lock().released = false;
+        self.ensure_held()
+    }
+}
+
+impl CoreUser {
+    /// Let other processes (e.g. the notification service extension) access
+    /// the queues of this client. Must be called by the app when it moves to
+    /// the background. Fetching messages fails with [`QueuesNotOwned`] right
+    /// away until the queues are reclaimed.
+    ///
+    /// The returned future hands the queues over once the fetches and
+    /// processing runs in flight are done and must be awaited or spawned.
+    pub fn release_queues(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.inner.queue_lock.release();
+        let inner = self.inner.clone();
+        async move { inner.queue_lock.unlock_when_idle().await }
+    }
+
+    /// Take back the queues released with [`Self::release_queues`]. Fails
+    /// with [`QueuesNotOwned`] while an extension is processing them; fetching
+    /// messages takes them as soon as the extension is done.
+    pub fn reclaim_queues(&self) -> Result<()> {
+        self.inner.queue_lock.reclaim()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn only_one_owner_at_a_time() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.to_str().unwrap();
+        let user_name = SafeTryInto::try_into("alice@example.com").unwrap();
+        let as_client_id = AsClientId::random(user_name).unwrap();
+
+        // Locks on different open file descriptions exclude each other, also
+        // within one process.
+        let app = QueueLock::default();
+        let extension = QueueLock::default();
+        app.set_path(&as_client_id, db_path);
+        extension.set_path(&as_client_id, db_path);
+
+        app.ensure_held().unwrap();
+        app.ensure_held().unwrap();
+        let error = extension.ensure_held().unwrap_err();
+        assert!(error.is::<QueuesNotOwned>());
+
+        // Released queues stay released until they are reclaimed
+        app.release();
+        app.unlock_when_idle().await;
+        assert!(app.ensure_held().unwrap_err().is::<QueuesNotOwned>());
+        extension.ensure_held().unwrap();
+        assert!(app.reclaim().unwrap_err().is::<QueuesNotOwned>());
+
+        // The app takes the queues with the next fetch after the extension is
+        // done
+        extension.release();
+        extension.unlock_when_idle().await;
+        app.ensure_held().unwrap();
+
+        // In-memory clients always own their queues
+        QueueLock::default().ensure_held().unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn release_waits_for_runs_in_flight() {
+        use futures_util::FutureExt;
+
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.to_str().unwrap();
+        let user_name = SafeTryInto::try_into("alice@example.com").unwrap();
+        let as_client_id = AsClientId::random(user_name).unwrap();
+        let app = QueueLock::default();
+        let extension = QueueLock::default();
+        app.set_path(&as_client_id, db_path);
+        extension.set_path(&as_client_id, db_path);
+
+        // A fetch is in flight when the app moves to the background
+        let fetch = app.hold().await.unwrap();
+        app.release();
+        assert!(app.hold().await.unwrap_err().is::<QueuesNotOwned>());
+
+        // The fetched messages can still be processed
+        let processing = app.hold_for_processing().await.unwrap();
+        drop(fetch);
+        let unlock = app.unlock_when_idle();
+        futures_util::pin_mut!(unlock);
+        assert!(unlock.as_mut().now_or_never().is_none());
+        assert!(extension.is_held_elsewhere().unwrap());
+        assert!(extension.ensure_held().unwrap_err().is::<QueuesNotOwned>());
+
+        // The lock is handed over once processing is done
+        drop(processing);
+        unlock.await;
+        assert!(app
+            .hold_for_processing()
+            .await
+            .unwrap_err()
+            .is::<QueuesNotOwned>());
+        assert!(!extension.is_held_elsewhere().unwrap());
+        extension.ensure_held().unwrap();
+        assert!(app.is_held_elsewhere().unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
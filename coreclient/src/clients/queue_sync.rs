@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::Result;
+use futures_util::{stream::TryReadyChunksError, TryStreamExt};
+use phnxtypes::messages::client_qs::MAX_DEQUEUE_BATCH_SIZE;
+
+use crate::{key_stores::queue_ratchets::QueueType, ConversationId};
+
+use super::{process::process_qs::ProcessedQsMessages, CoreUser};
+
+impl CoreUser {
+    /// Fetch and process all messages of the AS queue. Returns the ids of the
+    /// conversations of new connections.
+    ///
+    /// Unlike [`Self::as_fetch_messages`] followed by
+    /// [`Self::fully_process_as_messages`], the queues are held throughout,
+    /// such that releasing them waits until the fetched messages are
+    /// processed.
+    pub async fn as_fetch_and_process_messages(&self) -> Result<Vec<ConversationId>> {
+        let _in_flight = self.inner.queue_lock.hold().await?;
+        let as_messages = self.fetch_messages_from_queue(QueueType::As).await?;
+        self.process_as_messages(as_messages).await
+    }
+
+    /// Fetch and process all messages of the QS queue.
+    ///
+    /// Unlike [`Self::qs_fetch_messages`] followed by
+    /// [`Self::fully_process_qs_messages`], the messages are processed batch by
+    /// batch while they are fetched. The next batch is only requested once the
+    /// previous one was processed, such that catching up after a long offline
+    /// period doesn't hold the whole queue in memory.
+    pub async fn qs_fetch_and_process_messages(&self) -> Result<ProcessedQsMessages> {
+        let _in_flight = self.inner.queue_lock.hold().await?;
+        let api_client = self.inner.api_clients.default_client()?;
+        let sequence_number = {
+            let connection = self.inner.connection.lock().await;
+            QueueType::Qs.load_sequence_number(&connection)?
+        };
+
+        let mut stream = api_client.qs_dequeue_stream(
+            &self.inner.qs_client_id,
+            sequence_number,
+            &self.inner.key_store.qs_client_signing_key,
+        );
+        let mut batches = (&mut stream).try_ready_chunks(MAX_DEQUEUE_BATCH_SIZE as usize);
+
+        let mut processed = ProcessedQsMessages::default();
+        while let Some(batch) = batches
+            .try_next()
+            .await
+            .map_err(|TryReadyChunksError(_, error)| error)?
+        {
+            if let Some(message) = batch.last() {
+                let connection = self.inner.connection.lock().await;
+                QueueType::Qs.update_sequence_number(&connection, message.sequence_number + 1)?;
+            }
+            processed.append(self.process_qs_messages(batch).await?);
+        }
+        drop(batches);
+
+        log::debug!("Dequeue metrics: {:?}", api_client.dequeue_metrics());
+
+        if stream.replenish_key_packages() {
+            // Failing to publish key packages shouldn't fail the fetch. We'll
+            // be asked again on the next fetch.
+            if let Err(e) = self.replenish_key_packages().await {
+                log::error!("Failed to replenish key packages: {e:?}");
+            }
+        }
+        Ok(processed)
+    }
+}
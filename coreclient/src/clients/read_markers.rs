@@ -0,0 +1,232 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Sync of read markers between the clients of a user.
+//!
+//! When the user reads a chat on one client, the client sends the new read
+//! marker of the chat to the user's other clients as a [`SelfMessage`]. Chats
+//! are identified by their group id, which is the same on all clients.
+//! Markers only ever move forward, so the clients converge on the latest
+//! marker of each chat regardless of the order in which markers arrive.
+//!
+//! To keep the traffic low, only markers that actually moved are sent.
+//! Markers are collected in a [`ReadMarkerOutbox`] and sent at most once per
+//! [`READ_MARKER_SYNC_INTERVAL`], with all collected markers in a single self
+//! message. Markers that are still due when the user leaves the app are sent
+//! via [`CoreUser::flush_read_markers`].
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use openmls::group::GroupId;
+use phnxtypes::{
+    crypto::ear::{EarDecryptable, EarEncryptable},
+    messages::self_message::{EncryptedSelfMessage, ReadMarker, SelfMessage, MAX_READ_MARKERS},
+    time::TimeStamp,
+};
+use rusqlite::Connection;
+
+use crate::{utils::rt::Instant, Conversation, ConversationId, NotificationType};
+
+use super::{process::process_qs::ProcessQsMessageResult, CoreUser};
+
+impl CoreUser {
+    /// Mark all messages in the conversation with the given conversation id and
+    /// with a timestamp older than the given timestamp as read.
+    ///
+    /// The markers that moved are sent to the other clients of the user once
+    /// [`READ_MARKER_SYNC_INTERVAL`] has passed since the last batch. A
+    /// failure to send them is only logged: they are retried with the next
+    /// batch.
+    pub async fn mark_as_read<T: IntoIterator<Item = (ConversationId, DateTime<Utc>)>>(
+        &self,
+        mark_as_read_data: T,
+    ) -> Result<(), rusqlite::Error> {
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        let advanced = Conversation::mark_as_read(&mut transaction, mark_as_read_data)?;
+        let advanced_conversations: Vec<ConversationId> =
+            advanced.iter().map(|(id, _)| *id).collect();
+        let markers = read_markers(&transaction, advanced)?;
+        transaction.commit()?;
+        drop(connection);
+        for conversation_id in advanced_conversations {
+            self.store_notifier()
+                .notify(NotificationType::ConversationChange(conversation_id));
+        }
+
+        self.inner.read_marker_outbox.push(markers);
+        if let Err(error) = self.send_read_markers(false).await {
+            log::warn!("Failed to sync read markers: {error}");
+        }
+        Ok(())
+    }
+
+    /// Send the collected read markers to the other clients of the user right
+    /// away, e.g. before the app is suspended.
+    pub async fn flush_read_markers(&self) -> Result<()> {
+        self.send_read_markers(true).await
+    }
+
+    /// Mark all messages in all conversations as read.
+    pub async fn mark_all_as_read(&self) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let timestamps = Conversation::latest_unread_timestamps(&connection)?;
+        drop(connection);
+
+        self.mark_as_read(timestamps).await?;
+        Ok(())
+    }
+
+    /// Send the collected read markers if they are due or `force` is set.
+    /// Markers that couldn't be sent are put back into the outbox.
+    async fn send_read_markers(&self, force: bool) -> Result<()> {
+        let markers = self.inner.read_marker_outbox.take_due(force);
+        if markers.is_empty() {
+            return Ok(());
+        }
+        let result = self.send_read_marker_batches(&markers).await;
+        if result.is_err() {
+            self.inner.read_marker_outbox.push(markers);
+        }
+        result
+    }
+
+    async fn send_read_marker_batches(&self, markers: &[ReadMarker]) -> Result<()> {
+        let api_client = self.inner.api_clients.default_client()?;
+        let ear_key = self.inner.key_store.self_message_ear_key()?;
+        for markers in markers.chunks(MAX_READ_MARKERS) {
+            let message = SelfMessage::ReadMarkers(markers.to_vec()).encrypt(ear_key)?;
+            api_client
+                .qs_enqueue_self_message(
+                    self.inner.qs_client_id.clone(),
+                    message,
+                    &self.inner.key_store.qs_client_signing_key,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Process a self message sent by another client of the user.
+    pub(super) async fn handle_self_message(
+        &self,
+        message: EncryptedSelfMessage,
+    ) -> Result<ProcessQsMessageResult> {
+        let message = SelfMessage::decrypt(self.inner.key_store.self_message_ear_key()?, &message)?;
+        match message {
+            SelfMessage::ReadMarkers(markers) => self.apply_read_markers(markers).await?,
+            SelfMessage::UserSettingsChanged { version } => {
+                // The settings are synced again with the next change, so this
+                // must not fail the processing of the queue.
+                if let Err(error) = self.handle_user_settings_changed(version).await {
+                    log::warn!("Failed to sync user settings: {error}");
+                }
+            }
+        }
+        Ok(ProcessQsMessageResult::ConversationMessages(vec![]))
+    }
+
+    /// Apply the read markers received from another client of the user. The
+    /// markers are not sent on, since all other clients received them as
+    /// well.
+    async fn apply_read_markers(&self, markers: Vec<ReadMarker>) -> Result<()> {
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        let mut mark_as_read_data = Vec::with_capacity(markers.len());
+        for ReadMarker {
+            group_id,
+            read_until,
+        } in markers
+        {
+            // Chats this client isn't part of (yet) are skipped.
+            if let Some(conversation) = Conversation::load_by_group_id(&transaction, &group_id)? {
+                mark_as_read_data.push((conversation.id(), *read_until));
+            }
+        }
+        let advanced = Conversation::mark_as_read(&mut transaction, mark_as_read_data)?;
+        transaction.commit()?;
+        drop(connection);
+
+        for (conversation_id, _) in advanced {
+            self.store_notifier()
+                .notify(NotificationType::ConversationChange(conversation_id));
+        }
+        Ok(())
+    }
+}
+
+/// Read markers are sent to the other clients of the user at most once per
+/// this interval.
+pub(crate) const READ_MARKER_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Read markers waiting to be sent to the other clients of the user. Only the
+/// latest marker of each chat is kept.
+#[derive(Debug, Default)]
+pub(crate) struct ReadMarkerOutbox {
+    state: Mutex<ReadMarkerOutboxState>,
+}
+
+#[derive(Debug, Default)]
+struct ReadMarkerOutboxState {
+    pending: HashMap<GroupId, TimeStamp>,
+    last_sent: Option<Instant>,
+}
+
+impl ReadMarkerOutbox {
+    fn push(&self, markers: Vec<ReadMarker>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for ReadMarker {
+            group_id,
+            read_until,
+        } in markers
+        {
+            let pending = state.pending.entry(group_id).or_insert(read_until);
+            *pending = (*pending).max(read_until);
+        }
+    }
+
+    /// Take the pending markers if the last batch was sent at least
+    /// [`READ_MARKER_SYNC_INTERVAL`] ago or `force` is set.
+    fn take_due(&self, force: bool) -> Vec<ReadMarker> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let is_due = state
+            .last_sent
+            .is_none_or(|last_sent| last_sent.elapsed() >= READ_MARKER_SYNC_INTERVAL);
+        if state.pending.is_empty() || !(force || is_due) {
+            return Vec::new();
+        }
+        state.last_sent = Some(Instant::now());
+        state
+            .pending
+            .drain()
+            .map(|(group_id, read_until)| ReadMarker {
+                group_id,
+                read_until,
+            })
+            .collect()
+    }
+}
+
+/// The read markers of the given conversations
+fn read_markers(
+    connection: &Connection,
+    mark_as_read_data: Vec<(ConversationId, DateTime<Utc>)>,
+) -> Result<Vec<ReadMarker>, rusqlite::Error> {
+    let mut markers = Vec::with_capacity(mark_as_read_data.len());
+    for (conversation_id, read_until) in mark_as_read_data {
+        if let Some(conversation) = Conversation::load(connection, &conversation_id)? {
+            markers.push(ReadMarker {
+                group_id: conversation.group_id().clone(),
+                read_until: read_until.into(),
+            });
+        }
+    }
+    Ok(markers)
+}
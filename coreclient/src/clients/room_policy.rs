@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, Result};
+use phnxtypes::messages::room_policy::{RoomPolicy, RoomPolicyChange};
+#[cfg(feature = "test_utils")]
+use phnxtypes::messages::QueuePriority;
+
+#[cfg(feature = "test_utils")]
+use crate::MimiContent;
+use crate::{
+    conversations::{messages::ConversationMessage, Conversation, ConversationStatus},
+    groups::Group,
+    ConversationId,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Returns the policy of the conversation with the given id and whether
+    /// the own user is allowed to change it.
+    pub async fn room_policy(&self, conversation_id: ConversationId) -> Result<(RoomPolicy, bool)> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let group = Group::load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        Ok((group.room_policy()?, group.is_own_admin()?))
+    }
+
+    /// Change the policy of the conversation with the given id. Only admins
+    /// of the conversation can change its policy.
+    ///
+    /// Returns the resulting system messages.
+    pub async fn update_room_policy(
+        &self,
+        conversation_id: ConversationId,
+        changes: Vec<RoomPolicyChange>,
+    ) -> Result<Vec<ConversationMessage>> {
+        // Phase 1: Load the conversation and the group and create the commit
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let Some(params) = group.update_room_policy(&connection, changes)? else {
+            // Nothing to do
+            return Ok(vec![]);
+        };
+        drop(connection);
+
+        // Phase 2: Send the commit to the DS
+        let owner_domain = conversation.owner_domain();
+        let ds_timestamp = self
+            .inner
+            .api_clients
+            .get(&owner_domain)?
+            .ds_update_room_policy(
+                params,
+                group.user_auth_key().ok_or(anyhow!("No user auth key"))?,
+                group.group_state_ear_key(),
+            )
+            .await?;
+
+        // Phase 3: Merge the commit into the group and store the messages
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        let messages = group.merge_pending_commit(&transaction, None, ds_timestamp)?;
+        group.store_update(&transaction)?;
+        let conversation_messages =
+            Self::store_messages(&mut transaction, conversation_id, messages)?;
+        transaction.commit()?;
+        group.check_in(&connection)?;
+        drop(connection);
+
+        Ok(conversation_messages)
+    }
+
+    /// Send the given policy changes to the DS without checking that the own
+    /// user is an admin, like a client that ignores the policy would. The
+    /// commit is discarded afterwards, so this is only meant for changes the
+    /// DS rejects.
+    #[cfg(feature = "test_utils")]
+    pub async fn update_room_policy_unchecked(
+        &self,
+        conversation_id: ConversationId,
+        changes: Vec<RoomPolicyChange>,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let params = group
+            .room_policy_commit(&connection, changes)?
+            .ok_or(anyhow!("The changes don't alter the room policy"))?;
+        drop(connection);
+
+        let result = self
+            .inner
+            .api_clients
+            .get(&conversation.owner_domain())?
+            .ds_update_room_policy(
+                params,
+                group.user_auth_key().ok_or(anyhow!("No user auth key"))?,
+                group.group_state_ear_key(),
+            )
+            .await;
+        let connection = self.inner.connection.lock().await;
+        group.discard_rejected_commit(&connection)?;
+        group.check_in(&connection)?;
+        result?;
+        Ok(())
+    }
+
+    /// Send the given content to the conversation with the given id with the
+    /// given priority, without storing it and without checking the room
+    /// policy, like a client that ignores the policy would.
+    #[cfg(feature = "test_utils")]
+    pub async fn send_content_unchecked(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+        priority: QueuePriority,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let group_id = conversation.group_id();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let params = group.create_message(&connection, content, priority)?;
+        group.store_update(&connection)?;
+        let leaf_signer = group.leaf_signer().clone();
+        let group_state_ear_key = group.group_state_ear_key().clone();
+        group.check_in(&connection)?;
+        drop(connection);
+
+        self.inner
+            .api_clients
+            .get(&conversation.owner_domain())?
+            .ds_send_message(params, &leaf_signer, &group_state_ear_key)
+            .await?;
+        Ok(())
+    }
+
+    /// Commit the pending membership changes, i.e. the leaves of members, in
+    /// all broadcast rooms administered by the own user. Members of broadcast
+    /// rooms can't commit, so their changes are batched until the next call.
+    ///
+    /// The app calls this periodically while it is running, so the changes
+    /// are only committed while a client of an admin is online. Until then,
+    /// other members can't update their leaf by commit either. They propose
+    /// the refresh of their credential instead, which is committed with the
+    /// other changes.
+    ///
+    /// Returns the resulting messages. Failures in individual conversations
+    /// are logged and don't stop the other commits.
+    pub async fn commit_broadcast_room_changes(&self) -> Result<Vec<ConversationMessage>> {
+        let connection = self.inner.connection.lock().await;
+        let conversations = Conversation::load_all(&connection)?;
+        let mut pending_conversations = Vec::new();
+        for conversation in conversations {
+            if !matches!(conversation.status(), ConversationStatus::Active) {
+                continue;
+            }
+            let group_id = conversation.group_id();
+            let group = self
+                .inner
+                .groups
+                .load(&connection, group_id)?
+                .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+            let has_pending_changes = group.mls_group().pending_commit().is_none()
+                && group.mls_group().pending_proposals().next().is_some();
+            if has_pending_changes
+                && group.room_policy()?.is_broadcast()
+                && group.own_can_commit()?
+            {
+                pending_conversations.push(conversation.id());
+            }
+            group.check_in(&connection)?;
+        }
+        drop(connection);
+
+        let mut messages = Vec::new();
+        for conversation_id in pending_conversations {
+            match self.update(conversation_id).await {
+                Ok(conversation_messages) => messages.extend(conversation_messages),
+                Err(e) => log::error!(
+                    "Failed to commit changes to broadcast room {}: {:?}",
+                    conversation_id.as_uuid(),
+                    e
+                ),
+            }
+        }
+        Ok(messages)
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+use mls_assist::messages::AssistedMessageOut;
+use phnxapiclient::{ds_api::DsRequestError, ApiClient};
+use phnxtypes::{
+    crypto::ear::EarEncryptable,
+    identifiers::QualifiedGroupId,
+    messages::room_upgrade::{GroupTombstone, UpgradeTarget},
+    time::TimeStamp,
+};
+
+use crate::{
+    conversations::{messages::ConversationMessage, Conversation},
+    groups::{upgrade::PendingGroupUpgrade, Group},
+    ConversationId,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Upgrade the conversation with the given id to a new group with the
+    /// first of the given targets that all members support. Only admins of
+    /// the conversation can upgrade it. The other members move to the new
+    /// group when they process the upgrade.
+    ///
+    /// Returns the resulting system messages.
+    pub async fn upgrade_conversation(
+        &self,
+        conversation_id: ConversationId,
+        preferences: &[UpgradeTarget],
+    ) -> Result<Vec<ConversationMessage>> {
+        // Phase 1: Load the conversation and the group, negotiate the target
+        // and restore the archives, which are only reachable via the group
+        let connection = self.inner.connection.lock().await;
+        let mut conversation =
+            Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+                "Can't find conversation with id {}",
+                conversation_id.as_uuid()
+            ))?;
+        let group_id = conversation.group_id().clone();
+        let mut group = self
+            .inner
+            .groups
+            .load(&connection, &group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        if !group.is_own_admin()? {
+            bail!("Only admins can upgrade the conversation")
+        }
+        let target = group.negotiate_upgrade_target(preferences).ok_or(anyhow!(
+            "None of the upgrade targets is supported by all members"
+        ))?;
+        drop(connection);
+        self.restore_archives_before_upgrade(&conversation, &group)
+            .await?;
+
+        // Phase 2: Create the successor group with the keys derived from the
+        // current epoch
+        let owner_domain = conversation.owner_domain();
+        let api_client = self.inner.api_clients.get(&owner_domain)?;
+        let successor_group_id = api_client.ds_request_group_id().await?;
+        let mut connection = self.inner.connection.lock().await;
+        let keys = group.successor_keys(&connection, &successor_group_id)?;
+        let (successor, partial_params) = group.create_successor(
+            &mut connection,
+            &self.inner.key_store.signing_key(),
+            successor_group_id.clone(),
+            target,
+            keys,
+        )?;
+        self.inner.groups.store(&connection, &successor)?;
+        drop(connection);
+
+        let encrypted_client_credential = self
+            .inner
+            .key_store
+            .signing_key()
+            .credential()
+            .encrypt(successor.credential_ear_key())?;
+        let params = partial_params.into_params(
+            encrypted_client_credential,
+            self.create_own_client_reference(),
+        );
+        api_client
+            .ds_create_group(
+                params,
+                successor.group_state_ear_key(),
+                successor
+                    .user_auth_key()
+                    .ok_or(anyhow!("No user auth key"))?,
+            )
+            .await?;
+
+        // Phase 3: Commit the tombstone to the old group
+        let tombstone = GroupTombstone {
+            successor: successor_group_id.clone(),
+            target,
+        };
+        let ds_timestamp = match self
+            .commit_tombstone(&api_client, &mut group, &tombstone, &successor)
+            .await
+        {
+            Ok(ds_timestamp) => ds_timestamp,
+            Err(error) => {
+                // Unless the DS might have accepted the tombstone, nothing
+                // points to the successor, so we don't leave it behind.
+                let accepted = matches!(
+                    error.downcast_ref::<DsRequestError>(),
+                    Some(
+                        DsRequestError::NetworkError(_)
+                            | DsRequestError::BadResponse
+                            | DsRequestError::UnexpectedResponse
+                    )
+                );
+                if !accepted {
+                    if let Err(e) = self.delete_orphaned_successor(&api_client, successor).await {
+                        log::error!("Failed to delete successor of failed upgrade: {:?}", e);
+                    }
+                }
+                return Err(error);
+            }
+        };
+
+        // Phase 4: Merge the commit, store the messages and move the
+        // conversation to the successor
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        let messages = group.merge_pending_commit(&transaction, None, ds_timestamp)?;
+        let conversation_messages =
+            Self::store_messages(&mut transaction, conversation_id, messages)?;
+        conversation.set_group_id(&transaction, successor_group_id)?;
+        PendingGroupUpgrade::delete(&transaction, &group_id)?;
+        self.inner.groups.delete(&mut transaction, &group_id)?;
+        transaction.commit()?;
+        drop(connection);
+
+        Ok(conversation_messages)
+    }
+
+    async fn commit_tombstone(
+        &self,
+        api_client: &ApiClient,
+        group: &mut Group,
+        tombstone: &GroupTombstone,
+        successor: &Group,
+    ) -> Result<TimeStamp> {
+        let connection = self.inner.connection.lock().await;
+        let params = group.upgrade(
+            &connection,
+            tombstone,
+            successor.group_state_ear_key().clone(),
+        )?;
+        drop(connection);
+        let ds_timestamp = api_client
+            .ds_upgrade_group(
+                params,
+                group.user_auth_key().ok_or(anyhow!("No user auth key"))?,
+                group.group_state_ear_key(),
+            )
+            .await?;
+        Ok(ds_timestamp)
+    }
+
+    /// Delete the successor created for an upgrade that failed, both on the
+    /// DS and locally. The own client is its only member.
+    async fn delete_orphaned_successor(
+        &self,
+        api_client: &ApiClient,
+        mut successor: Group,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let params = successor.delete(&connection)?;
+        drop(connection);
+        api_client
+            .ds_delete_group(
+                params,
+                successor
+                    .user_auth_key()
+                    .ok_or(anyhow!("No user auth key"))?,
+                successor.group_state_ear_key(),
+            )
+            .await?;
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        self.inner
+            .groups
+            .delete(&mut transaction, successor.group_id())?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Join the successors of all upgraded groups that the own client hasn't
+    /// joined yet and move their conversations to the successors.
+    ///
+    /// Returns the ids of the moved conversations. Failures in individual
+    /// conversations are logged and the migration is retried on the next
+    /// call.
+    pub(crate) async fn migrate_upgraded_groups(&self) -> Result<Vec<ConversationId>> {
+        let connection = self.inner.connection.lock().await;
+        let upgrades = PendingGroupUpgrade::load_all(&connection)?;
+        drop(connection);
+
+        let mut conversation_ids = Vec::new();
+        for upgrade in upgrades {
+            let group_id = upgrade.group_id.clone();
+            match self.migrate_upgraded_group(upgrade).await {
+                Ok(conversation_id) => conversation_ids.push(conversation_id),
+                Err(e) => log::error!(
+                    "Failed to join the successor of group {:?}: {:?}",
+                    group_id,
+                    e
+                ),
+            }
+        }
+        Ok(conversation_ids)
+    }
+
+    async fn migrate_upgraded_group(&self, upgrade: PendingGroupUpgrade) -> Result<ConversationId> {
+        // Phase 1: Load the conversation and the user auth key of the
+        // upgraded group and restore the archives, which are only reachable
+        // via the upgraded group
+        let group_id = upgrade.group_id.clone();
+        let connection = self.inner.connection.lock().await;
+        let mut conversation = Conversation::load_by_group_id(&connection, &group_id)?
+            .ok_or(anyhow!("Can't find conversation of group {:?}", group_id))?;
+        let group = Group::load(&connection, &group_id)?
+            .ok_or(anyhow!("Can't find group with id {:?}", group_id))?;
+        let user_auth_key = group
+            .user_auth_key()
+            .ok_or(anyhow!("No user auth key"))?
+            .clone();
+        drop(connection);
+        self.restore_archives_before_upgrade(&conversation, &group)
+            .await?;
+
+        // Phase 2: Join the successor with an external commit
+        let successor_group_id = upgrade.successor_group_id.clone();
+        let qgid = QualifiedGroupId::try_from(successor_group_id.clone())?;
+        let api_client = self.inner.api_clients.get(qgid.owning_domain())?;
+        let group_state_ear_key = upgrade.keys.group_state_ear_key.clone();
+        let external_commit_info = api_client
+            .ds_external_commit_info(
+                successor_group_id.clone(),
+                &group_state_ear_key,
+                &user_auth_key,
+            )
+            .await?;
+        let (successor, commit, group_info) = Group::join_successor(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            external_commit_info,
+            upgrade,
+            user_auth_key.clone(),
+            &self.inner.key_store.signing_key(),
+            self.inner.key_store.signing_key().credential(),
+        )
+        .await?;
+        let external_commit = AssistedMessageOut::new(commit, Some(group_info))?;
+        api_client
+            .ds_join_upgraded_group(
+                external_commit,
+                self.create_own_client_reference(),
+                &user_auth_key,
+                &group_state_ear_key,
+            )
+            .await?;
+
+        // Phase 3: Move the conversation to the successor and delete the
+        // upgraded group
+        let mut connection = self.inner.connection.lock().await;
+        let mut transaction = connection.transaction()?;
+        self.inner.groups.store(&transaction, &successor)?;
+        conversation.set_group_id(&transaction, successor_group_id)?;
+        successor.store_own_join(&transaction, conversation.id(), None, TimeStamp::now())?;
+        PendingGroupUpgrade::delete(&transaction, &group_id)?;
+        self.inner.groups.delete(&mut transaction, &group_id)?;
+        transaction.commit()?;
+        drop(connection);
+
+        Ok(conversation.id())
+    }
+}
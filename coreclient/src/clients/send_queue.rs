@@ -0,0 +1,457 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Paced sending of messages.
+//!
+//! The DS limits the rate at which a single sender can send messages and
+//! rejects messages above the limit. Bots or forwarding a message to many
+//! chats easily exceed it. Therefore, all messages are sent via the send
+//! queue, which paces them with a token bucket matching the [`SendRateLimit`]
+//! published by the server. [`CoreUser::send_message`] waits until its
+//! message was sent, while messages stored as unsent with
+//! [`CoreUser::queue_message`] are sent by [`CoreUser::process_send_queue`].
+//!
+//! Each chat has its own FIFO queue and chats take turns, such that a chat
+//! with many queued messages doesn't hold back the others. Within a chat,
+//! messages are sent in the order they were queued: if the DS rejects a
+//! message because of the rate limit anyway, sending is paused and the same
+//! message is retried before any later message of the chat. After
+//! [`MAX_SEND_ATTEMPTS`] rejections, the message is recorded as a failed
+//! delivery and dropped from the queue.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use phnxapiclient::ds_api::DsRequestError;
+use phnxtypes::messages::server_info::SendRateLimit;
+use uuid::Uuid;
+
+use crate::{
+    conversations::messages::{ConversationMessage, ConversationMessageId},
+    utils::rt::{self, Instant},
+    Conversation, ConversationId, MimiContent,
+};
+
+use super::CoreUser;
+
+/// Rate limit assumed until the limit of the server is known. Below the
+/// default limit of the server.
+const DEFAULT_SEND_RATE_LIMIT: SendRateLimit = SendRateLimit {
+    messages_per_second: 5,
+    burst: 10,
+};
+
+/// Pause after the DS rejected a message without telling when to retry. It
+/// doubles with each consecutive rejection up to [`MAX_BACKOFF`].
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Number of times a message is sent before it is given up because of the
+/// rate limit.
+const MAX_SEND_ATTEMPTS: u32 = 5;
+
+#[derive(Clone, Default)]
+pub(crate) struct SendQueue {
+    inner: Arc<Mutex<SendQueueInner>>,
+    /// Held while the queue is processed, such that messages are sent one at
+    /// a time.
+    processing: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Default)]
+struct SendQueueInner {
+    schedule: SendSchedule,
+    bucket: TokenBucket,
+    /// Whether the rate limit of the server was fetched
+    rate_limit_known: bool,
+    /// Pause after the next rejection without a retry time
+    backoff: Option<Duration>,
+    /// Rejected attempts to send the next message
+    attempts: u32,
+}
+
+impl SendQueue {
+    fn lock(&self) -> MutexGuard<'_, SendQueueInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(super) fn push(&self, conversation_id: ConversationId, message_id: Uuid) {
+        self.lock().schedule.push(conversation_id, message_id);
+    }
+
+    /// Wait until the rate limit allows sending the next message.
+    async fn wait_for_turn(&self) {
+        loop {
+            let wait = match self.lock().bucket.take(Instant::now()) {
+                Ok(()) => return,
+                Err(wait) => wait,
+            };
+            rt::sleep(wait).await;
+        }
+    }
+
+    fn sent(&self) {
+        let mut inner = self.lock();
+        inner.schedule.complete();
+        inner.backoff = None;
+        inner.attempts = 0;
+    }
+
+    /// Drop the next message from the queue without sending it.
+    fn give_up(&self) {
+        let mut inner = self.lock();
+        inner.schedule.complete();
+        inner.attempts = 0;
+    }
+
+    /// Pause sending after the DS rejected a message. If the DS doesn't tell
+    /// when to retry, the pause grows exponentially.
+    ///
+    /// Returns whether to retry the message, which then stays at the head of
+    /// its chat's queue.
+    fn rate_limited(&self, retry_after: Option<Duration>) -> bool {
+        let mut inner = self.lock();
+        let backoff = inner.backoff.unwrap_or(INITIAL_BACKOFF);
+        let pause = retry_after
+            .filter(|retry_after| !retry_after.is_zero())
+            .unwrap_or(backoff);
+        inner.backoff = Some((backoff * 2).min(MAX_BACKOFF));
+        inner.bucket.pause(Instant::now(), pause);
+        inner.attempts += 1;
+        inner.attempts < MAX_SEND_ATTEMPTS
+    }
+
+    /// Use the given rate limit instead of fetching it from the server.
+    #[cfg(test)]
+    pub(super) fn set_rate_limit(&self, limit: Option<SendRateLimit>) {
+        let mut inner = self.lock();
+        inner.bucket.set_limit(limit, Instant::now());
+        inner.rate_limit_known = true;
+    }
+}
+
+/// Per-chat FIFO queues of message ids. Chats take turns in the order in
+/// which they were queued.
+#[derive(Debug, Default)]
+struct SendSchedule {
+    chats: HashMap<ConversationId, VecDeque<Uuid>>,
+    turns: VecDeque<ConversationId>,
+}
+
+impl SendSchedule {
+    fn push(&mut self, conversation_id: ConversationId, message_id: Uuid) {
+        match self.chats.entry(conversation_id) {
+            Entry::Occupied(mut entry) => {
+                if !entry.get().contains(&message_id) {
+                    entry.get_mut().push_back(message_id);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(VecDeque::from([message_id]));
+                self.turns.push_back(conversation_id);
+            }
+        }
+    }
+
+    /// The next message to send
+    fn next(&self) -> Option<Uuid> {
+        let conversation_id = self.turns.front()?;
+        self.chats.get(conversation_id)?.front().copied()
+    }
+
+    /// Remove the next message and move its chat to the end of the line.
+    fn complete(&mut self) {
+        let Some(conversation_id) = self.turns.pop_front() else {
+            return;
+        };
+        let Entry::Occupied(mut entry) = self.chats.entry(conversation_id) else {
+            return;
+        };
+        entry.get_mut().pop_front();
+        if entry.get().is_empty() {
+            entry.remove();
+        } else {
+            self.turns.push_back(conversation_id);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// `None` if the server doesn't limit the rate
+    limit: Option<SendRateLimit>,
+    tokens: f64,
+    updated_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new(Some(DEFAULT_SEND_RATE_LIMIT), Instant::now())
+    }
+}
+
+impl TokenBucket {
+    fn new(limit: Option<SendRateLimit>, now: Instant) -> Self {
+        Self {
+            tokens: limit.map(|limit| limit.burst as f64).unwrap_or_default(),
+            limit,
+            updated_at: now,
+            paused_until: None,
+        }
+    }
+
+    fn set_limit(&mut self, limit: Option<SendRateLimit>, now: Instant) {
+        let paused_until = self.paused_until;
+        *self = Self::new(limit, now);
+        self.paused_until = paused_until;
+    }
+
+    /// Take a token. Returns how long to wait for the next token if there is
+    /// none.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        if let Some(paused_until) = self.paused_until {
+            if now < paused_until {
+                return Err(paused_until - now);
+            }
+            self.paused_until = None;
+        }
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let rate = limit.messages_per_second.max(1) as f64;
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(limit.burst.max(1) as f64);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// Don't hand out tokens for the given duration, and start with an empty
+    /// bucket afterwards.
+    fn pause(&mut self, now: Instant, duration: Duration) {
+        self.paused_until = Some(now + duration);
+        self.tokens = 0.0;
+        self.updated_at = now + duration;
+    }
+}
+
+enum SendOutcome {
+    Sent,
+    /// The message was rate limited and stays queued
+    Retry,
+    Failed(anyhow::Error),
+}
+
+impl CoreUser {
+    /// Store a message in the conversation with the given id as unsent and
+    /// queue it for sending with [`Self::process_send_queue`].
+    pub async fn queue_message(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> Result<ConversationMessage> {
+        let connection = self.inner.connection.lock().await;
+        Conversation::load(&connection, &conversation_id)?.ok_or(anyhow!(
+            "Can't find conversation with id {}",
+            conversation_id.as_uuid()
+        ))?;
+        let message = ConversationMessage::new_unsent_message(
+            self.user_name().to_string(),
+            conversation_id,
+            content,
+        );
+        message.store(&connection)?;
+        drop(connection);
+        self.inner
+            .send_queue
+            .push(conversation_id, message.id().to_uuid());
+        Ok(message)
+    }
+
+    /// Send all queued messages, paced according to the rate limit of the
+    /// server. Returns once the queue is empty.
+    ///
+    /// Messages that fail for other reasons than the rate limit, or are
+    /// rejected because of the rate limit [`MAX_SEND_ATTEMPTS`] times, are
+    /// dropped from the queue and recorded as failed deliveries, unless they
+    /// were sent in an outdated epoch and succeed after catching up. They
+    /// stay unsent and are retried by [`Self::flush_outbox`].
+    ///
+    /// Returns the number of messages that were sent.
+    pub async fn process_send_queue(&self) -> Result<usize> {
+        let _processing = self.inner.send_queue.processing.lock().await;
+        self.fetch_send_rate_limit().await;
+
+        let mut sent = 0;
+        while let Some((message_id, outcome)) = self.send_next().await {
+            match outcome {
+                SendOutcome::Sent => sent += 1,
+                SendOutcome::Retry => {}
+                SendOutcome::Failed(error) => {
+                    log::warn!("Failed to send queued message {message_id}: {error}")
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Queue the unsent message with the given id and send the queue until
+    /// the message was sent or failed. Messages queued before it are sent
+    /// first.
+    pub(super) async fn send_via_queue(
+        &self,
+        conversation_id: ConversationId,
+        message_id: ConversationMessageId,
+    ) -> Result<ConversationMessage> {
+        let send_queue = &self.inner.send_queue;
+        send_queue.push(conversation_id, message_id.to_uuid());
+        let _processing = send_queue.processing.lock().await;
+        self.fetch_send_rate_limit().await;
+
+        while let Some((next_id, outcome)) = self.send_next().await {
+            if next_id != message_id.to_uuid() {
+                continue;
+            }
+            match outcome {
+                SendOutcome::Sent => return self.load_message(message_id).await,
+                SendOutcome::Retry => {}
+                SendOutcome::Failed(error) => return Err(error),
+            }
+        }
+        // Sent by a concurrent call of this function
+        self.load_message(message_id).await
+    }
+
+    /// Send the next queued message as soon as the rate limit allows it.
+    /// Returns `None` if the queue is empty.
+    async fn send_next(&self) -> Option<(Uuid, SendOutcome)> {
+        let send_queue = &self.inner.send_queue;
+        let message_id = send_queue.lock().schedule.next()?;
+        send_queue.wait_for_turn().await;
+        let error = match self.re_send_message(message_id).await {
+            Ok(()) => {
+                send_queue.sent();
+                return Some((message_id, SendOutcome::Sent));
+            }
+            Err(error) => error,
+        };
+        if let Some(DsRequestError::RateLimited(retry_after)) = error.downcast_ref() {
+            if send_queue.rate_limited(*retry_after) {
+                log::info!("Sending message {message_id} was rate limited");
+                return Some((message_id, SendOutcome::Retry));
+            }
+            log::warn!("Giving up on message {message_id} after {MAX_SEND_ATTEMPTS} attempts");
+        }
+        send_queue.give_up();
+        let outcome = match self
+            .recover_failed_delivery(ConversationMessageId::from_uuid(message_id), error)
+            .await
+        {
+            Ok(_) => SendOutcome::Sent,
+            Err(error) => SendOutcome::Failed(error),
+        };
+        Some((message_id, outcome))
+    }
+
+    /// Adopt the rate limit published by the server, unless it is already
+    /// known.
+    async fn fetch_send_rate_limit(&self) {
+        if self.inner.send_queue.lock().rate_limit_known {
+            return;
+        }
+        match self.server_info().await {
+            Ok(server_info) => {
+                let mut inner = self.inner.send_queue.lock();
+                inner
+                    .bucket
+                    .set_limit(server_info.send_rate_limit, Instant::now());
+                inner.rate_limit_known = true;
+            }
+            Err(error) => log::warn!("Failed to fetch the send rate limit: {error}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chats_take_turns_and_keep_their_order() {
+        let chat_a = ConversationId::from(Uuid::new_v4());
+        let chat_b = ConversationId::from(Uuid::new_v4());
+        let [a1, a2, a3, b1] = [(); 4].map(|_| Uuid::new_v4());
+
+        let mut schedule = SendSchedule::default();
+        schedule.push(chat_a, a1);
+        schedule.push(chat_a, a2);
+        schedule.push(chat_a, a3);
+        schedule.push(chat_b, b1);
+        // Queueing a message twice doesn't send it twice
+        schedule.push(chat_a, a2);
+
+        let mut order = Vec::new();
+        while let Some(message_id) = schedule.next() {
+            order.push(message_id);
+            schedule.complete();
+        }
+        assert_eq!(order, [a1, b1, a2, a3]);
+    }
+
+    #[test]
+    fn rate_limited_messages_are_retried_a_limited_number_of_times() {
+        let send_queue = SendQueue::default();
+        send_queue.push(ConversationId::from(Uuid::new_v4()), Uuid::new_v4());
+        for _ in 1..MAX_SEND_ATTEMPTS {
+            assert!(send_queue.rate_limited(None));
+        }
+        assert!(!send_queue.rate_limited(Some(Duration::from_secs(1))));
+        send_queue.give_up();
+        assert_eq!(send_queue.lock().schedule.next(), None);
+        assert_eq!(send_queue.lock().attempts, 0);
+
+        // Without a retry time, the pause doubles with each rejection
+        let send_queue = SendQueue::default();
+        for backoff in [1, 2, 4] {
+            let now = Instant::now();
+            send_queue.rate_limited(Some(Duration::ZERO));
+            let paused_until = send_queue.lock().bucket.paused_until.unwrap();
+            assert!(paused_until >= now + Duration::from_secs(backoff));
+        }
+    }
+
+    #[test]
+    fn bucket_paces_after_burst() {
+        let start = Instant::now();
+        let limit = SendRateLimit {
+            messages_per_second: 2,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::new(Some(limit), start);
+        assert_eq!(bucket.take(start), Ok(()));
+        assert_eq!(bucket.take(start), Ok(()));
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+        assert_eq!(bucket.take(start + Duration::from_millis(500)), Ok(()));
+
+        let later = start + Duration::from_secs(10);
+        bucket.pause(later, Duration::from_secs(3));
+        assert_eq!(bucket.take(later), Err(Duration::from_secs(3)));
+        assert_eq!(
+            bucket.take(later + Duration::from_secs(3)),
+            Err(Duration::from_millis(500))
+        );
+
+        let mut unlimited = TokenBucket::new(None, start);
+        assert!((0..100).all(|_| unlimited.take(start).is_ok()));
+    }
+}
@@ -2,20 +2,32 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use super::api_clients::ApiClients;
+use super::{api_clients::ApiClients, CoreUser, CIPHERSUITE};
 use crate::{
-    clients::store::{ClientRecord, ClientRecordState, UserCreationState},
+    clients::{
+        pending_connections::{PendingConnection, ReservedConnectionPackages},
+        store::{ClientRecord, ClientRecordState, UserCreationState},
+    },
+    conversations::messages::{ConversationMessage, EventMessage, Message, SystemMessage},
+    groups::upgrade::PendingGroupUpgrade,
+    mimi_content::MimiContent,
     utils::{
         migration::run_migrations,
         persistence::{SqliteConnection, Storable},
     },
+    ConversationId,
 };
+use mls_assist::openmls::prelude::ProtocolVersion;
 use phnxserver_test_harness::utils::setup::TestBackend;
 use phnxtypes::{
     codec::PhnxCodec,
-    identifiers::{AsClientId, SafeTryInto},
+    identifiers::{AsClientId, ConnectionReservationId, QualifiedUserName, SafeTryInto},
+    messages::room_upgrade::UpgradeTarget,
+    time::{Duration, TimeStamp},
 };
-use rusqlite::Connection;
+use rusqlite::{params, Connection};
+
+mod simulation;
 
 #[actix_rt::test]
 async fn user_stages() {
@@ -214,3 +226,1165 @@ async fn user_stages() {
         PhnxCodec::to_vec(&loaded_state).unwrap()
     );
 }
+
+/// Create a commit in the user's only conversation without merging it. If
+/// `send` is set, the commit is sent to the DS, but the response is dropped.
+async fn create_lost_commit(user: &CoreUser, send: bool) {
+    let conversation = user.conversations().await.unwrap().pop().unwrap();
+    let connection = user.inner.connection.lock().await;
+    let mut group = user
+        .inner
+        .groups
+        .load(&connection, conversation.group_id())
+        .unwrap()
+        .unwrap();
+    let params = group.update(&connection).unwrap();
+    group.check_in(&connection).unwrap();
+    drop(connection);
+    if send {
+        user.inner
+            .api_clients
+            .default_client()
+            .unwrap()
+            .ds_update_client(params, group.group_state_ear_key(), group.leaf_signer())
+            .await
+            .unwrap();
+    }
+}
+
+/// Pretend that the janitor first saw the pending commit two hours ago.
+async fn backdate_pending_commit(user: &CoreUser) {
+    // The first run records the pending commit, but leaves it alone.
+    let processed_messages = user.clean_up_stale_groups().await.unwrap();
+    assert!(processed_messages.new_messages.is_empty());
+    let connection = user.inner.connection.lock().await;
+    let pending_since = TimeStamp::from(*TimeStamp::now() - Duration::hours(2));
+    connection
+        .execute(
+            "UPDATE groups SET commit_pending_since = ?",
+            params![pending_since],
+        )
+        .unwrap();
+}
+
+fn contains_system_message(messages: &[ConversationMessage], expected: SystemMessage) -> bool {
+    messages.iter().any(|message| {
+        matches!(message.message(), Message::Event(EventMessage::System(system_message)) if *system_message == expected)
+    })
+}
+
+#[actix_rt::test]
+async fn janitor_merges_commit_with_lost_response() {
+    let setup = TestBackend::single().await;
+    let user_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let user = CoreUser::new_ephemeral(user_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let conversation_id = user.create_conversation("Lost", None).await.unwrap();
+
+    create_lost_commit(&user, true).await;
+    // The group is wedged until the pending commit is resolved.
+    assert!(user.update(conversation_id).await.is_err());
+
+    backdate_pending_commit(&user).await;
+    let messages = user.clean_up_stale_groups().await.unwrap().new_messages;
+    assert!(contains_system_message(
+        &messages,
+        SystemMessage::PendingChangeApplied
+    ));
+
+    // The group is in sync with the DS again.
+    user.update(conversation_id).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn janitor_discards_commit_that_never_reached_the_ds() {
+    let setup = TestBackend::single().await;
+    let user_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let user = CoreUser::new_ephemeral(user_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let conversation_id = user.create_conversation("Lost", None).await.unwrap();
+
+    create_lost_commit(&user, false).await;
+    assert!(user.update(conversation_id).await.is_err());
+
+    backdate_pending_commit(&user).await;
+    let messages = user.clean_up_stale_groups().await.unwrap().new_messages;
+    assert!(contains_system_message(
+        &messages,
+        SystemMessage::PendingChangeDiscarded
+    ));
+
+    user.update(conversation_id).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn janitor_keeps_commit_superseded_at_the_ds() {
+    let setup = TestBackend::single().await;
+    let user_name: QualifiedUserName = SafeTryInto::try_into("carol@example.com").unwrap();
+    let user = CoreUser::new_ephemeral(user_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let conversation_id = user.create_conversation("Lost", None).await.unwrap();
+
+    // The DS applies a commit that differs from the pending one.
+    create_lost_commit(&user, true).await;
+    let conversation = user.conversations().await.unwrap().pop().unwrap();
+    let connection = user.inner.connection.lock().await;
+    let mut group = user
+        .inner
+        .groups
+        .load(&connection, conversation.group_id())
+        .unwrap()
+        .unwrap();
+    group.discard_pending_proposals(&connection).unwrap();
+    group.update(&connection).unwrap();
+    group.check_in(&connection).unwrap();
+    drop(connection);
+
+    backdate_pending_commit(&user).await;
+    let messages = user.clean_up_stale_groups().await.unwrap().new_messages;
+    assert!(!contains_system_message(
+        &messages,
+        SystemMessage::PendingChangeApplied
+    ));
+    assert!(user.update(conversation_id).await.is_err());
+}
+
+#[actix_rt::test]
+async fn repair_chat_with_corrupted_group_state() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    // Connect alice and bob
+    let conversation_id = alice.add_contact(bob_name).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // The group state is intact, so there is nothing to repair.
+    assert!(alice.repair_chat(conversation_id).await.is_err());
+
+    // Corrupt the MLS state of alice's group
+    let group_id = alice
+        .conversation(&conversation_id)
+        .await
+        .unwrap()
+        .group_id()
+        .clone();
+    let connection = alice.inner.connection.lock().await;
+    connection
+        .execute(
+            "UPDATE group_data SET group_data = X'00' WHERE data_type = 'group_state'",
+            [],
+        )
+        .unwrap();
+    alice.inner.groups.invalidate(&group_id);
+    drop(connection);
+    assert!(alice.update(conversation_id).await.is_err());
+
+    alice.repair_chat(conversation_id).await.unwrap();
+    // The conversation and its history are preserved.
+    assert!(!alice
+        .get_messages(conversation_id, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Bob processes the resync and the update
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Alice and bob can talk again.
+    let content = MimiContent::simple_markdown_message(alice_name.domain(), "Back".to_owned());
+    alice
+        .send_message(conversation_id, content.clone())
+        .await
+        .unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let processed_messages = bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert!(processed_messages.new_messages.iter().any(|message| {
+        matches!(message.message(), Message::Content(content_message) if content_message.content() == &content)
+    }));
+}
+
+#[actix_rt::test]
+async fn sending_and_reading_notify_the_store() {
+    use futures_util::FutureExt;
+
+    use crate::NotificationType;
+
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let conversation_id = alice.add_contact(bob_name).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Sending notifies the unsent and the sent message
+    let mut notifications = alice.store_notifier().subscribe();
+    let content = MimiContent::simple_markdown_message(alice_name.domain(), "Hi".to_owned());
+    let message = alice.send_message(conversation_id, content).await.unwrap();
+    let mut notified_messages = Vec::new();
+    while let Some(Some(notification)) = notifications.recv().now_or_never() {
+        if let NotificationType::Message(notified) = notification {
+            notified_messages.push(notified.id());
+        }
+    }
+    assert_eq!(notified_messages, [message.id(), message.id()]);
+
+    // Bob reads the message
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let processed_messages = bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    let received = processed_messages.new_messages.last().unwrap();
+    let bob_conversation_id = received.conversation_id();
+    let mut notifications = bob.store_notifier().subscribe();
+    bob.mark_as_read([(bob_conversation_id, received.timestamp())])
+        .await
+        .unwrap();
+    assert!(matches!(
+        notifications.recv().now_or_never(),
+        Some(Some(NotificationType::ConversationChange(id))) if id == bob_conversation_id
+    ));
+    // Nothing changes when reading again
+    bob.mark_as_read([(bob_conversation_id, received.timestamp())])
+        .await
+        .unwrap();
+    assert!(notifications.recv().now_or_never().is_none());
+}
+
+#[actix_rt::test]
+async fn failing_message_doesnt_stop_the_batch() {
+    use futures_util::FutureExt;
+
+    use crate::{groups::Group, NotificationType};
+
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    // Connect alice and bob and have alice invite bob to a room
+    let connection_id = alice.add_contact(bob_name.clone()).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let room_id = alice.create_conversation("Room", None).await.unwrap();
+    alice.invite_users(room_id, &[bob_name]).await.unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Alice sends a message to the room between two messages to bob
+    let first = MimiContent::simple_markdown_message(alice_name.domain(), "First".to_owned());
+    alice
+        .send_message(connection_id, first.clone())
+        .await
+        .unwrap();
+    let lost = MimiContent::simple_markdown_message(alice_name.domain(), "Lost".to_owned());
+    alice.send_message(room_id, lost).await.unwrap();
+    let last = MimiContent::simple_markdown_message(alice_name.domain(), "Last".to_owned());
+    alice
+        .send_message(connection_id, last.clone())
+        .await
+        .unwrap();
+
+    // Bob lost the state of the room, so processing its message fails
+    let room_group_id = alice
+        .conversation(&room_id)
+        .await
+        .unwrap()
+        .group_id()
+        .clone();
+    let mut connection = bob.inner.connection.lock().await;
+    let mut transaction = connection.transaction().unwrap();
+    Group::delete_from_db(&mut transaction, &room_group_id).unwrap();
+    transaction.commit().unwrap();
+    bob.inner.groups.invalidate(&room_group_id);
+    drop(connection);
+
+    let mut notifications = bob.store_notifier().subscribe();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let processed_messages = bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert_eq!(processed_messages.failed_messages, 1);
+    let received: Vec<_> = processed_messages
+        .new_messages
+        .iter()
+        .filter_map(|message| match message.message() {
+            Message::Content(content_message) => Some(content_message.content()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received, [&first, &last]);
+
+    // The messages before and after the failed one are notified
+    let mut notified_messages = 0;
+    while let Some(Some(notification)) = notifications.recv().now_or_never() {
+        if let NotificationType::Message(_) = notification {
+            notified_messages += 1;
+        }
+    }
+    assert_eq!(notified_messages, processed_messages.new_messages.len());
+}
+
+#[actix_rt::test]
+async fn chat_members_are_paged() {
+    use phnxtypes::messages::member_profiles::MemberOrder;
+
+    use crate::{ChatMemberRole, MemberListCursor};
+
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    alice.add_contact(bob_name.clone()).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let room_id = alice.create_conversation("Room", None).await.unwrap();
+    alice
+        .invite_users(room_id, &[bob_name.clone()])
+        .await
+        .unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // The admin comes first, one member per page
+    let cursor = MemberListCursor::new(MemberOrder::Role);
+    let page = alice.chat_members_page(room_id, cursor, 1).await.unwrap();
+    let [admin] = page.members.as_slice() else {
+        panic!("Expected one member, got {:?}", page.members);
+    };
+    assert_eq!(admin.user_name, alice_name);
+    assert_eq!(admin.role, ChatMemberRole::Admin);
+    // Alice created the room, so she wasn't added
+    assert_eq!(admin.joined_at, None);
+    let cursor = page.next_cursor.expect("Expected a second page");
+    assert_eq!(cursor.order(), MemberOrder::Role);
+
+    let page = alice.chat_members_page(room_id, cursor, 1).await.unwrap();
+    let [member] = page.members.as_slice() else {
+        panic!("Expected one member, got {:?}", page.members);
+    };
+    assert_eq!(member.user_name, bob_name);
+    assert_eq!(member.role, ChatMemberRole::Member);
+    assert!(member.joined_at.is_some());
+    assert!(page.next_cursor.is_none());
+
+    // Members see the same list
+    let cursor = MemberListCursor::new(MemberOrder::RecentActivity);
+    let page = bob.chat_members_page(room_id, cursor, 10).await.unwrap();
+    let mut user_names: Vec<_> = page
+        .members
+        .iter()
+        .map(|member| member.user_name.clone())
+        .collect();
+    user_names.sort_by_key(ToString::to_string);
+    assert_eq!(user_names, [alice_name, bob_name]);
+    assert!(page.next_cursor.is_none());
+}
+
+#[actix_rt::test]
+async fn upgrade_room_and_migrate_members() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    // Connect alice and bob and have alice invite bob to a room
+    alice.add_contact(bob_name.clone()).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let conversation_id = alice.create_conversation("Room", None).await.unwrap();
+    alice
+        .invite_users(conversation_id, &[bob_name])
+        .await
+        .unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    let old_group_id = alice
+        .conversation(&conversation_id)
+        .await
+        .unwrap()
+        .group_id()
+        .clone();
+    let target = UpgradeTarget {
+        version: ProtocolVersion::Mls10,
+        ciphersuite: CIPHERSUITE,
+    };
+
+    // Only admins can upgrade the room
+    assert!(bob
+        .upgrade_conversation(conversation_id, &[target])
+        .await
+        .is_err());
+
+    alice
+        .upgrade_conversation(conversation_id, &[target])
+        .await
+        .unwrap();
+    let new_group_id = alice
+        .conversation(&conversation_id)
+        .await
+        .unwrap()
+        .group_id()
+        .clone();
+    assert_ne!(new_group_id, old_group_id);
+
+    // Bob processes the tombstone and joins the successor
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert_eq!(
+        bob.conversation(&conversation_id).await.unwrap().group_id(),
+        &new_group_id
+    );
+    let connection = bob.inner.connection.lock().await;
+    assert!(PendingGroupUpgrade::load_all(&connection)
+        .unwrap()
+        .is_empty());
+    drop(connection);
+
+    // Alice and bob can talk in the successor
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let content = MimiContent::simple_markdown_message(alice_name.domain(), "Upgraded".to_owned());
+    alice
+        .send_message(conversation_id, content.clone())
+        .await
+        .unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let processed_messages = bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert!(processed_messages.new_messages.iter().any(|message| {
+        matches!(message.message(), Message::Content(content_message) if content_message.content() == &content)
+    }));
+}
+
+#[cfg(feature = "attachments")]
+#[actix_rt::test]
+async fn outbox_sends_attachments() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let db_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&db_dir).unwrap();
+    let db_path = db_dir.to_str().unwrap();
+    let alice = CoreUser::new(
+        alice_name.clone(),
+        "password",
+        setup.url().unwrap(),
+        db_path,
+        None,
+    )
+    .await
+    .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    let conversation_id = alice.add_contact(bob_name).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Share extensions don't migrate the DB
+    let unmigrated_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&unmigrated_dir).unwrap();
+    assert!(
+        super::outbox::Outbox::open(alice.as_client_id(), unmigrated_dir.to_str().unwrap())
+            .is_err()
+    );
+
+    // A share extension enqueues a text and an attachment
+    let outbox = super::outbox::Outbox::open(alice.as_client_id(), db_path).unwrap();
+    let text = MimiContent::simple_markdown_message(alice_name.domain(), "Look".to_owned());
+    outbox
+        .enqueue_message(conversation_id, text.clone())
+        .await
+        .unwrap();
+    let caption = MimiContent::simple_markdown_message(alice_name.domain(), "Photo".to_owned());
+    let attachment = vec![42; 1024];
+    outbox
+        .enqueue_attachment(conversation_id, caption.clone(), attachment.clone())
+        .await
+        .unwrap();
+    drop(outbox);
+
+    assert_eq!(alice.flush_outbox().await.unwrap(), 2);
+    assert_eq!(alice.pending_outbox_messages().await.unwrap(), 0);
+
+    // Bob receives both messages, including the content of the attachment
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let new_messages = bob
+        .fully_process_qs_messages(qs_messages)
+        .await
+        .unwrap()
+        .new_messages;
+    let received: Vec<_> = new_messages
+        .iter()
+        .filter_map(|message| match message.message() {
+            Message::Content(content_message) => Some((message.id(), content_message.content())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].1, &text);
+    let (message_id, content) = received[1];
+    assert_eq!(content.id(), caption.id());
+    assert_eq!(content.string_rendering(), "Photo");
+    let hashes = bob.download_message_attachments(message_id).await.unwrap();
+    assert_eq!(hashes.len(), 1);
+    assert_eq!(bob.attachment(&hashes[0]).await.unwrap(), Some(attachment));
+
+    std::fs::remove_dir_all(db_dir).unwrap();
+}
+
+#[cfg(feature = "attachments")]
+#[actix_rt::test]
+async fn forwarded_attachment_can_be_downloaded() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let charlie_name: QualifiedUserName = SafeTryInto::try_into("charlie@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let charlie =
+        CoreUser::new_ephemeral(charlie_name.clone(), "password", setup.url().unwrap(), None)
+            .await
+            .unwrap();
+    let alice_bob_id = alice.add_contact(bob_name.clone()).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let bob_charlie_id = bob.add_contact(charlie_name).await.unwrap();
+    let as_messages = charlie.as_fetch_messages().await.unwrap();
+    charlie
+        .fully_process_as_messages(as_messages)
+        .await
+        .unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Alice sends an attachment to bob
+    let caption = MimiContent::simple_markdown_message(alice_name.domain(), "Photo".to_owned());
+    let attachment = vec![42; 1024];
+    let message = alice.queue_message(alice_bob_id, caption).await.unwrap();
+    alice
+        .store_attachment(message.id(), attachment.clone())
+        .await
+        .unwrap();
+    assert_eq!(alice.process_send_queue().await.unwrap(), 1);
+
+    // Bob forwards it to charlie without downloading it first
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let received = bob
+        .fully_process_qs_messages(qs_messages)
+        .await
+        .unwrap()
+        .new_messages
+        .into_iter()
+        .find(|message| matches!(message.message(), Message::Content(_)))
+        .unwrap();
+    let outcomes = bob
+        .forward_message(received.id(), vec![bob_charlie_id])
+        .await
+        .unwrap();
+    assert_eq!(outcomes.len(), 1);
+    assert!(outcomes[0].result.is_ok());
+
+    // Charlie downloads the attachment from the DS of the chat with bob
+    let qs_messages = charlie.qs_fetch_messages().await.unwrap();
+    let forwarded = charlie
+        .fully_process_qs_messages(qs_messages)
+        .await
+        .unwrap()
+        .new_messages
+        .into_iter()
+        .find(|message| matches!(message.message(), Message::Content(_)))
+        .unwrap();
+    let Message::Content(content_message) = forwarded.message() else {
+        unreachable!()
+    };
+    let content = content_message.content();
+    assert_eq!(content.forwarded_from().unwrap().sender, alice_name);
+    assert_eq!(content.string_rendering(), "Photo");
+    assert_eq!(content.encrypted_attachments().len(), 1);
+    let hashes = charlie
+        .download_message_attachments(forwarded.id())
+        .await
+        .unwrap();
+    assert_eq!(hashes.len(), 1);
+    assert_eq!(
+        charlie.attachment(&hashes[0]).await.unwrap(),
+        Some(attachment)
+    );
+}
+
+#[cfg(feature = "attachments")]
+#[actix_rt::test]
+async fn auto_download_follows_the_policy() {
+    use crate::{
+        AttachmentHash, AttachmentKind, AutoDownloadCondition, AutoDownloadPolicy,
+        AutoDownloadRule, NetworkType,
+    };
+
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let conversation_id = alice.add_contact(bob_name).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Alice sends an image and a video
+    let image = [b"\x89PNG\r\n\x1a\n".as_slice(), &[1; 1024]].concat();
+    let video = [b"\0\0\0\x18ftypmp42".as_slice(), &[2; 1024]].concat();
+    let caption = MimiContent::simple_markdown_message(alice_name.domain(), "Media".to_owned());
+    let message = alice.queue_message(conversation_id, caption).await.unwrap();
+    alice
+        .store_attachment(message.id(), image.clone())
+        .await
+        .unwrap();
+    alice
+        .store_attachment(message.id(), video.clone())
+        .await
+        .unwrap();
+    assert_eq!(alice.process_send_queue().await.unwrap(), 1);
+
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let received = bob
+        .fully_process_qs_messages(qs_messages)
+        .await
+        .unwrap()
+        .new_messages
+        .into_iter()
+        .find(|message| matches!(message.message(), Message::Content(_)))
+        .unwrap();
+    let Message::Content(content_message) = received.message() else {
+        unreachable!()
+    };
+    let mut kinds: Vec<_> = content_message
+        .content()
+        .encrypted_attachments()
+        .into_iter()
+        .map(|attachment| (attachment.kind, attachment.size))
+        .collect();
+    kinds.sort_by_key(|(kind, _)| *kind as u8);
+    assert_eq!(
+        kinds,
+        [(AttachmentKind::Image, 1032), (AttachmentKind::Video, 1036)]
+    );
+
+    let downloaded = |hashes: Vec<AttachmentHash>| {
+        let bob = &bob;
+        async move {
+            let mut contents = Vec::new();
+            for hash in hashes {
+                contents.push(bob.attachment(&hash).await.unwrap().unwrap());
+            }
+            contents
+        }
+    };
+
+    // By default, images are only downloaded on Wi-Fi and videos never
+    let hashes = bob
+        .auto_download_message_attachments(received.id(), NetworkType::Cellular)
+        .await
+        .unwrap();
+    assert!(hashes.is_empty());
+    let hashes = bob
+        .auto_download_message_attachments(received.id(), NetworkType::Wifi)
+        .await
+        .unwrap();
+    assert_eq!(downloaded(hashes).await, [image.clone()]);
+
+    // Videos up to a size
+    let mut policy = AutoDownloadPolicy::default();
+    policy.default_rules.videos = AutoDownloadRule {
+        condition: AutoDownloadCondition::Always,
+        max_size: Some(1024),
+    };
+    bob.set_user_setting(&policy).await.unwrap();
+    let hashes = bob
+        .auto_download_message_attachments(received.id(), NetworkType::Cellular)
+        .await
+        .unwrap();
+    assert!(hashes.is_empty());
+    policy.default_rules.videos.max_size = Some(2048);
+    bob.set_user_setting(&policy).await.unwrap();
+    let hashes = bob
+        .auto_download_message_attachments(received.id(), NetworkType::Cellular)
+        .await
+        .unwrap();
+    assert_eq!(downloaded(hashes).await, [video]);
+
+    // An explicit request downloads all attachments
+    let hashes = bob
+        .download_message_attachments(received.id())
+        .await
+        .unwrap();
+    assert_eq!(hashes.len(), 2);
+}
+
+/// Prepare a connection from `user` to `contact` as if the app was killed
+/// right after the connection group was created on the DS.
+#[actix_rt::test]
+async fn client_credentials_are_verified_in_bulk() {
+    use crate::key_stores::as_credentials::AsCredentials;
+    use phnxtypes::credentials::VerifiableClientCredential;
+
+    let setup = TestBackend::single().await;
+    let mut users = Vec::new();
+    for name in ["alice@example.com", "bob@example.com", "carol@example.com"] {
+        let user_name: QualifiedUserName = SafeTryInto::try_into(name).unwrap();
+        let user = CoreUser::new_ephemeral(user_name, "password", setup.url().unwrap(), None)
+            .await
+            .unwrap();
+        users.push(user);
+    }
+    let [alice, bob, carol] = users.as_slice() else {
+        unreachable!()
+    };
+    let credentials =
+        [bob, carol, bob].map(|user| user.inner.key_store.signing_key().credential().clone());
+    let fingerprints: Vec<_> = credentials
+        .iter()
+        .map(|credential| credential.fingerprint().unwrap())
+        .collect();
+    let verifiable_credentials = || {
+        credentials
+            .iter()
+            .cloned()
+            .map(VerifiableClientCredential::from)
+            .collect::<Vec<_>>()
+    };
+    let cached_intermediates = || async move {
+        let connection = alice.inner.connection.lock().await;
+        AsCredentials::load_all_intermediates(&connection)
+            .unwrap()
+            .len()
+    };
+
+    // Without cached intermediate credentials, they are fetched from the AS
+    // and the client credentials are returned in the given order.
+    alice
+        .inner
+        .connection
+        .lock()
+        .await
+        .execute("DELETE FROM as_credentials", [])
+        .unwrap();
+    assert_eq!(cached_intermediates().await, 0);
+    let verified = AsCredentials::verify_client_credentials(
+        alice.inner.connection.clone(),
+        &alice.inner.api_clients,
+        verifiable_credentials(),
+    )
+    .await
+    .unwrap();
+    let verified_fingerprints: Vec<_> = verified
+        .iter()
+        .map(|credential| credential.fingerprint().unwrap())
+        .collect();
+    assert_eq!(verified_fingerprints, fingerprints);
+    assert_eq!(cached_intermediates().await, 1);
+
+    // Afterwards, the cached intermediate credential is used, even if the AS
+    // can't be reached.
+    let unreachable_api_clients =
+        ApiClients::new(alice.user_name().domain(), "127.0.0.1:1".to_owned());
+    let verified = AsCredentials::verify_client_credentials(
+        alice.inner.connection.clone(),
+        &unreachable_api_clients,
+        verifiable_credentials(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(verified.len(), fingerprints.len());
+
+    // Nothing is fetched for an empty batch.
+    let verified = AsCredentials::verify_client_credentials(
+        alice.inner.connection.clone(),
+        &unreachable_api_clients,
+        Vec::new(),
+    )
+    .await
+    .unwrap();
+    assert!(verified.is_empty());
+}
+
+async fn interrupt_connection(user: &CoreUser, contact: &QualifiedUserName) -> ConversationId {
+    let (connection_packages, reservation) = user.fetch_connection_packages(contact).await.unwrap();
+    let group_id = user
+        .inner
+        .api_clients
+        .default_client()
+        .unwrap()
+        .ds_request_group_id()
+        .await
+        .unwrap();
+    let reserved_packages = ReservedConnectionPackages::new(reservation, connection_packages);
+    user.create_connection(contact, group_id, false, None, reserved_packages)
+        .await
+        .unwrap()
+        .conversation_id
+}
+
+async fn pending_connections(user: &CoreUser) -> Vec<PendingConnection> {
+    let connection = user.inner.connection.lock().await;
+    PendingConnection::load_all(&connection).unwrap()
+}
+
+#[actix_rt::test]
+async fn interrupted_connection_is_resumed() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    let conversation_id = interrupt_connection(&alice, &bob_name).await;
+    assert_eq!(pending_connections(&alice).await.len(), 1);
+    assert!(bob.as_fetch_messages().await.unwrap().is_empty());
+
+    // Resuming sends the connection exactly once
+    assert!(alice.resume_pending_connections().await.unwrap().is_empty());
+    assert!(alice.resume_pending_connections().await.unwrap().is_empty());
+    assert!(pending_connections(&alice).await.is_empty());
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    assert_eq!(as_messages.len(), 1);
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+
+    // The connection is established as usual
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert!(alice.conversation(&conversation_id).await.is_some());
+    assert!(alice
+        .contacts()
+        .await
+        .unwrap()
+        .iter()
+        .any(|contact| contact.user_name == bob_name));
+}
+
+#[actix_rt::test]
+async fn interrupted_connection_with_expired_reservation_is_resumed() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    interrupt_connection(&alice, &bob_name).await;
+
+    // The AS doesn't know the reservation anymore
+    let mut pending_connection = pending_connections(&alice).await.pop().unwrap();
+    let expired_id = ConnectionReservationId::random();
+    pending_connection.reserved_packages.reservation.id = expired_id;
+    pending_connection
+        .store(&*alice.inner.connection.lock().await)
+        .unwrap();
+
+    // The packages are reserved anew and the new reservation is persisted
+    // until the connection was sent
+    assert!(alice.resume_pending_connections().await.unwrap().is_empty());
+    assert!(pending_connections(&alice).await.is_empty());
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    assert_eq!(as_messages.len(), 1);
+    assert_eq!(
+        bob.fully_process_as_messages(as_messages)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[actix_rt::test]
+async fn connection_interrupted_before_group_creation_is_discarded() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    // Pretend that the app was killed before the group was created on the DS
+    let conversation_id = interrupt_connection(&alice, &bob_name).await;
+    let mut pending_connection = pending_connections(&alice).await.pop().unwrap();
+    pending_connection.group_created = false;
+    pending_connection
+        .store(&*alice.inner.connection.lock().await)
+        .unwrap();
+
+    // The connection is rolled back without contacting bob
+    assert_eq!(
+        alice.resume_pending_connections().await.unwrap(),
+        vec![conversation_id]
+    );
+    assert!(pending_connections(&alice).await.is_empty());
+    assert!(alice.conversation(&conversation_id).await.is_none());
+    assert!(alice.partial_contacts().await.unwrap().is_empty());
+    assert!(bob.as_fetch_messages().await.unwrap().is_empty());
+
+    // Adding the contact can be retried
+    alice.add_contact(bob_name).await.unwrap();
+    assert_eq!(bob.as_fetch_messages().await.unwrap().len(), 1);
+}
+
+#[actix_rt::test]
+async fn connection_that_might_have_reached_the_contact_is_kept() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    // The reservation expired after the packages were sent and fetching the
+    // packages again fails
+    let conversation_id = interrupt_connection(&alice, &bob_name).await;
+    let mut pending_connection = pending_connections(&alice).await.pop().unwrap();
+    pending_connection.reserved_packages.reservation.id = ConnectionReservationId::random();
+    pending_connection.reserved_packages.sent = true;
+    pending_connection
+        .store(&*alice.inner.connection.lock().await)
+        .unwrap();
+    bob.delete_account("password").await.unwrap();
+
+    // Bob might have received the connection, so it stays pending
+    assert!(alice.resume_pending_connections().await.unwrap().is_empty());
+    assert_eq!(pending_connections(&alice).await.len(), 1);
+    assert!(alice.conversation(&conversation_id).await.is_some());
+
+    // Connections that weren't sent yet are rolled back
+    let mut pending_connection = pending_connections(&alice).await.pop().unwrap();
+    pending_connection.reserved_packages.sent = false;
+    pending_connection
+        .store(&*alice.inner.connection.lock().await)
+        .unwrap();
+    assert_eq!(
+        alice.resume_pending_connections().await.unwrap(),
+        vec![conversation_id]
+    );
+    assert!(pending_connections(&alice).await.is_empty());
+    assert!(alice.conversation(&conversation_id).await.is_none());
+}
+
+#[actix_rt::test]
+async fn resumed_connection_skips_reached_clients() {
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+
+    // Bob's client received the connection under a reservation that expired
+    interrupt_connection(&alice, &bob_name).await;
+    let mut pending_connection = pending_connections(&alice).await.pop().unwrap();
+    pending_connection.reserved_packages.reservation.id = ConnectionReservationId::random();
+    pending_connection.reserved_packages.reached_clients = vec![bob.as_client_id()];
+    pending_connection.reserved_packages.sent = true;
+    pending_connection
+        .store(&*alice.inner.connection.lock().await)
+        .unwrap();
+
+    // The packages are reserved anew, but not sent to bob's client again
+    assert!(alice.resume_pending_connections().await.unwrap().is_empty());
+    assert!(pending_connections(&alice).await.is_empty());
+    assert!(bob.as_fetch_messages().await.unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn interrupted_account_deletion_is_resumed() {
+    use super::account_deletion::{AccountDeletionProgress, AccountErasure};
+
+    let setup = TestBackend::single().await;
+    let alice_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+    let alice = CoreUser::new_ephemeral(alice_name, "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    let bob = CoreUser::new_ephemeral(bob_name.clone(), "password", setup.url().unwrap(), None)
+        .await
+        .unwrap();
+    alice.add_contact(bob_name.clone()).await.unwrap();
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let room_id = alice.create_conversation("Room", None).await.unwrap();
+    alice.invite_users(room_id, &[bob_name]).await.unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    assert_eq!(alice.resume_account_deletion().await.unwrap(), None);
+
+    // The client was interrupted after the AS deleted the user and erasing
+    // the user from the room
+    let progress = AccountDeletionProgress {
+        in_progress: true,
+        erased_conversations: [room_id].into(),
+        ..Default::default()
+    };
+    alice.set_user_setting(&progress).await.unwrap();
+
+    // Only the connection is left, then the user is deleted at the QS
+    let erasure = alice.resume_account_deletion().await.unwrap();
+    assert_eq!(
+        erasure,
+        Some(AccountErasure {
+            groups_left: 1,
+            attachments_deleted: 0,
+            groups_failed: 0,
+        })
+    );
+    assert!(alice.qs_fetch_messages().await.is_err());
+    assert_eq!(alice.resume_account_deletion().await.unwrap(), None);
+}
+
+#[actix_rt::test]
+async fn owner_is_woken_up_when_running_out_of_key_packages() {
+    use phnxapiclient::qs_api::ws::WsEvent;
+    use phnxtypes::messages::{client_ds::QsWsMessage, client_qs::KeyPackageAvailability};
+    use tokio::time::timeout;
+
+    // Low watermark of the QS below which clients are asked to publish new
+    // key packages.
+    const KEY_PACKAGE_LOW_WATERMARK: u32 = 10;
+
+    let setup = TestBackend::single().await;
+    let alice = CoreUser::new_ephemeral(
+        SafeTryInto::try_into("alice@example.com").unwrap(),
+        "password",
+        setup.url().unwrap(),
+        None,
+    )
+    .await
+    .unwrap();
+    let bob = CoreUser::new_ephemeral(
+        SafeTryInto::try_into("bob@example.com").unwrap(),
+        "password",
+        setup.url().unwrap(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    let mut websocket = bob.websocket(10, 1).await.unwrap();
+    assert_eq!(websocket.next().await, Some(WsEvent::ConnectedEvent));
+
+    async fn fetch_key_packages(alice: &CoreUser, bob: &CoreUser) -> KeyPackageAvailability {
+        alice
+            .inner
+            .api_clients
+            .default_client()
+            .unwrap()
+            .qs_key_package_batch(
+                bob.inner.key_store.friendship_token.clone(),
+                bob.inner.key_store.add_package_ear_key.clone(),
+            )
+            .await
+            .unwrap()
+            .availability
+    }
+
+    // Bob isn't woken up as long as enough key packages are left.
+    let mut availability = fetch_key_packages(&alice, &bob).await;
+    while availability.remaining > KEY_PACKAGE_LOW_WATERMARK {
+        availability = fetch_key_packages(&alice, &bob).await;
+    }
+    assert_eq!(availability.remaining, KEY_PACKAGE_LOW_WATERMARK);
+    assert!(
+        timeout(std::time::Duration::from_millis(500), websocket.next())
+            .await
+            .is_err()
+    );
+
+    // The fetch that drops below the watermark wakes up Bob, although
+    // nothing was enqueued for him.
+    let availability = fetch_key_packages(&alice, &bob).await;
+    assert_eq!(availability.remaining, KEY_PACKAGE_LOW_WATERMARK - 1);
+    assert_eq!(
+        timeout(std::time::Duration::from_secs(5), websocket.next())
+            .await
+            .unwrap(),
+        Some(WsEvent::MessageEvent(QsWsMessage::QueueUpdate))
+    );
+    assert!(bob.qs_fetch_messages().await.unwrap().is_empty());
+
+    // Bob is only woken up once.
+    fetch_key_packages(&alice, &bob).await;
+    assert!(
+        timeout(std::time::Duration::from_millis(500), websocket.next())
+            .await
+            .is_err()
+    );
+}
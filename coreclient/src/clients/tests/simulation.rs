@@ -0,0 +1,627 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Deterministic simulation of a client against a scripted server.
+//!
+//! A [`Simulation`] runs a [`CoreUser`] whose requests are answered by a
+//! [`ScriptedServer`] instead of a backend. The RNG is seeded and time only
+//! moves when the test advances the [`VirtualClock`], so the same script
+//! produces the same keys, identifiers and requests on every run.
+//!
+//! The RNG and the clock are replaced for the current thread only, so
+//! simulations don't affect tests running in parallel. `actix_rt::test` runs
+//! the client on a single-threaded runtime. Queue messages are decrypted on
+//! the blocking thread pool, which sees neither, so the scripts don't deliver
+//! any.
+//!
+//! Openmls takes the lifetimes of leaf nodes from the system clock and the
+//! HPKE and AEAD randomness from its own RNG. Requests that carry MLS messages
+//! or ciphertexts are therefore only identical in size across runs.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+};
+
+use chrono::{DateTime, DurationRound, Utc};
+use openmls::prelude::{GroupId, KeyPackageRef};
+use openmls_rust_crypto::RustCrypto;
+use phnxapiclient::transport::{
+    StatusCode, Transport, TransportError, TransportFuture, TransportRequest, TransportResponse,
+};
+use phnxtypes::{
+    crypto::{
+        rng,
+        signatures::{keys::QsSigningKey, signable::Signable},
+    },
+    endpoint_paths::{ENDPOINT_DS_GROUPS, ENDPOINT_QS},
+    identifiers::{AsClientId, QualifiedGroupId, QualifiedUserName, SafeTryInto},
+    keypackage_batch::{AddPackage, KeyPackageBatchTbs},
+    messages::{
+        client_ds::GroupEpochInfo,
+        client_qs::{
+            DequeueMessagesResponseV1, KeyPackageAvailability, KeyPackageBatchResponse,
+            VerifyingKeyResponse,
+        },
+    },
+    time::{self, Duration, TimeProvider, TimeStamp},
+};
+use rusqlite::Connection;
+use tls_codec::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    clients::{api_clients::ApiClients, create_user::PersistedUserState, CoreUser},
+    conversations::Conversation,
+    groups::client_auth_info::StorableClientCredential,
+    mimi_content::MimiContent,
+    utils::{migration::run_migrations, persistence::SqliteConnection},
+    ConversationId, DeliveryFailure,
+};
+
+/// A clock that stands still unless it is advanced.
+#[derive(Debug)]
+struct VirtualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl TimeProvider for VirtualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl VirtualClock {
+    /// All simulations of a test run start at the same time.
+    fn start() -> DateTime<Utc> {
+        static START: OnceLock<DateTime<Utc>> = OnceLock::new();
+        *START.get_or_init(|| {
+            Utc::now()
+                .duration_trunc(Duration::hours(1))
+                .unwrap_or_default()
+        })
+    }
+
+    fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+}
+
+/// What the scripted server does with a request.
+enum Reply {
+    Respond(StatusCode, Vec<u8>),
+    /// The request doesn't reach the server.
+    NetworkFailure,
+}
+
+/// A server that answers requests with a script of replies. Every request
+/// must go to the endpoint of the next step of the script.
+#[derive(Default)]
+struct ScriptedServer {
+    script: Mutex<VecDeque<(&'static str, Reply)>>,
+    transcript: Mutex<Vec<String>>,
+    bodies: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ScriptedServer {
+    fn expect(&self, endpoint: &'static str, reply: Reply) {
+        self.script
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back((endpoint, reply));
+    }
+
+    /// The requests received so far together with the replies.
+    fn transcript(&self) -> Vec<String> {
+        self.transcript
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The bodies of the requests received so far.
+    fn request_bodies(&self) -> Vec<Vec<u8>> {
+        self.bodies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn assert_done(&self) {
+        let script = self.script.lock().unwrap_or_else(PoisonError::into_inner);
+        let pending: Vec<_> = script.iter().map(|(endpoint, _)| *endpoint).collect();
+        assert!(pending.is_empty(), "Expected requests to {pending:?}");
+    }
+}
+
+impl Transport for ScriptedServer {
+    fn send(&self, request: TransportRequest) -> TransportFuture<'_> {
+        let path = request.path().to_owned();
+        let (endpoint, reply) = self
+            .script
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front()
+            .unwrap_or_else(|| panic!("Unexpected request to {path}"));
+        assert_eq!(endpoint, path, "Request to unexpected endpoint");
+        let (outcome, result) = match reply {
+            Reply::Respond(status, body) => {
+                (status.to_string(), Ok(TransportResponse::new(status, body)))
+            }
+            Reply::NetworkFailure => (
+                "network failure".to_owned(),
+                Err(TransportError::Network(
+                    "Simulated network failure".to_owned(),
+                )),
+            ),
+        };
+        self.transcript
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(format!("{} {path} -> {outcome}", request.method));
+        self.bodies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(request.body);
+        Box::pin(async move { result })
+    }
+}
+
+/// Replies of the DS and the QS, encoded like the responses of the backend.
+mod replies {
+    use super::*;
+
+    // Variants of `DsProcessResponseIn`
+    const DS_OK: u8 = 0;
+    const DS_FANOUT_TIMESTAMP: u8 = 1;
+    const DS_GROUP_ID: u8 = 4;
+    const DS_GROUP_EPOCH_INFO: u8 = 10;
+    // Variants of `QsProcessResponseIn`
+    const QS_KEY_PACKAGE_BATCH: u8 = 4;
+    const QS_DEQUEUE_MESSAGES: u8 = 5;
+    const QS_VERIFYING_KEY: u8 = 6;
+
+    fn ok(variant: u8, payload: impl Serialize) -> Reply {
+        let mut body = vec![variant];
+        payload.tls_serialize(&mut body).unwrap();
+        Reply::Respond(StatusCode::OK, body)
+    }
+
+    pub(super) fn ds_ok() -> Reply {
+        Reply::Respond(StatusCode::OK, vec![DS_OK])
+    }
+
+    pub(super) fn ds_group_id(group_id: &GroupId) -> Reply {
+        ok(DS_GROUP_ID, group_id.clone())
+    }
+
+    pub(super) fn ds_fanout_timestamp(timestamp: TimeStamp) -> Reply {
+        ok(DS_FANOUT_TIMESTAMP, timestamp)
+    }
+
+    pub(super) fn ds_group_epoch_info(epoch: u64, confirmed_transcript_hash: Vec<u8>) -> Reply {
+        let epoch_info = GroupEpochInfo {
+            epoch,
+            confirmed_transcript_hash: confirmed_transcript_hash.into(),
+        };
+        ok(DS_GROUP_EPOCH_INFO, epoch_info)
+    }
+
+    pub(super) fn ds_stale_epoch() -> Reply {
+        Reply::Respond(StatusCode::CONFLICT, Vec::new())
+    }
+
+    pub(super) fn qs_empty_queue() -> Reply {
+        let response = DequeueMessagesResponseV1 {
+            messages: Vec::new(),
+            remaining_messages_number: 0,
+            replenish_key_packages: false,
+        };
+        ok(QS_DEQUEUE_MESSAGES, response)
+    }
+
+    pub(super) fn qs_verifying_key(signing_key: &QsSigningKey) -> Reply {
+        let response = VerifyingKeyResponse {
+            verifying_key: signing_key.verifying_key(),
+        };
+        ok(QS_VERIFYING_KEY, response)
+    }
+
+    /// A key package batch with the given add packages, signed by the QS.
+    pub(super) fn qs_key_package_batch(
+        signing_key: &QsSigningKey,
+        user_name: &QualifiedUserName,
+        add_packages: Vec<AddPackage>,
+    ) -> Reply {
+        let key_package_refs: Vec<KeyPackageRef> = add_packages
+            .iter()
+            .map(|add_package| {
+                add_package
+                    .key_package()
+                    .hash_ref(&RustCrypto::default())
+                    .unwrap()
+            })
+            .collect();
+        let key_package_batch =
+            KeyPackageBatchTbs::new(user_name.domain(), key_package_refs, TimeStamp::now())
+                .sign(signing_key)
+                .unwrap();
+        let response = KeyPackageBatchResponse {
+            add_packages,
+            key_package_batch,
+            availability: KeyPackageAvailability {
+                remaining: 10,
+                last_resort_used: false,
+            },
+        };
+        ok(QS_KEY_PACKAGE_BATCH, response)
+    }
+}
+
+/// Another user of the same domain that is a contact of the simulated user.
+struct SimulatedContact {
+    state: PersistedUserState,
+    /// The DB of the contact, which holds the private keys of its add
+    /// packages.
+    connection: Connection,
+}
+
+impl SimulatedContact {
+    fn user_name(&self) -> QualifiedUserName {
+        self.state.client_id().user_name()
+    }
+
+    fn add_package(&self) -> AddPackage {
+        self.state.simulated_add_package(&self.connection).unwrap()
+    }
+}
+
+struct Simulation {
+    user: CoreUser,
+    server: Arc<ScriptedServer>,
+    clock: Arc<VirtualClock>,
+}
+
+impl Simulation {
+    /// Create a registered user, without contacting the server.
+    fn new(seed: u64) -> Self {
+        rng::set_rng_seed(seed);
+        let clock = Arc::new(VirtualClock {
+            now: Mutex::new(VirtualClock::start()),
+        });
+        time::set_thread_time_provider(Some(clock.clone()));
+
+        let user_name = SafeTryInto::try_into("alice@example.com").unwrap();
+        let as_client_id = AsClientId::random(user_name).unwrap();
+        let domain = as_client_id.user_name().domain();
+        let server = Arc::new(ScriptedServer::default());
+        let api_clients = ApiClients::with_transport(domain.clone(), server.clone());
+
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let user_state =
+            PersistedUserState::new_simulated(&connection, as_client_id, domain.to_string())
+                .unwrap();
+        let user = user_state.into_self_user(SqliteConnection::new(connection), api_clients);
+        // The rate limit would otherwise be fetched from the AS.
+        user.inner.send_queue.set_rate_limit(None);
+
+        Self {
+            user,
+            server,
+            clock,
+        }
+    }
+
+    /// Create another user of the same domain and store it as a contact of
+    /// the simulated user.
+    async fn add_contact(&self, name: &str, conversation_id: ConversationId) -> SimulatedContact {
+        let user_name = SafeTryInto::try_into(format!("{name}@example.com")).unwrap();
+        let as_client_id = AsClientId::random(user_name).unwrap();
+        let domain = as_client_id.user_name().domain();
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let state =
+            PersistedUserState::new_simulated(&connection, as_client_id, domain.to_string())
+                .unwrap();
+
+        let (contact, client_credential) = state.simulated_contact(conversation_id);
+        let own_connection = self.user.inner.connection.lock().await;
+        contact.store(&own_connection).unwrap();
+        StorableClientCredential::new(client_credential)
+            .store(&own_connection)
+            .unwrap();
+        SimulatedContact { state, connection }
+    }
+
+    /// The own epoch of the group of the given conversation and the
+    /// confirmed transcript hash of its pending commit, if any.
+    async fn group_state(&self, conversation_id: ConversationId) -> (u64, Option<Vec<u8>>) {
+        let connection = self.user.inner.connection.lock().await;
+        let conversation = Conversation::load(&connection, &conversation_id)
+            .unwrap()
+            .unwrap();
+        let group = self
+            .user
+            .inner
+            .groups
+            .load(&connection, conversation.group_id())
+            .unwrap()
+            .unwrap();
+        let group_state = group.epoch_and_pending_commit_hash();
+        group.check_in(&connection).unwrap();
+        group_state
+    }
+
+    fn message(&self, text: &str) -> MimiContent {
+        MimiContent::simple_markdown_message(self.user.user_name().domain(), text.to_owned())
+    }
+
+    /// Create a conversation, scripting the replies of the DS.
+    async fn create_conversation(&self) -> ConversationId {
+        let group_id = GroupId::from(QualifiedGroupId::new(
+            rng::random_uuid(),
+            self.user.user_name().domain(),
+        ));
+        self.server
+            .expect(ENDPOINT_DS_GROUPS, replies::ds_group_id(&group_id));
+        self.server.expect(ENDPOINT_DS_GROUPS, replies::ds_ok());
+        self.user
+            .create_conversation("Simulation", None)
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        time::set_thread_time_provider(None);
+        rng::reset_rng();
+    }
+}
+
+#[actix_rt::test]
+async fn retry_after_network_failure() {
+    let simulation = Simulation::new(1);
+    let conversation_id = simulation.create_conversation().await;
+
+    // The connection drops while the message is sent.
+    simulation
+        .server
+        .expect(ENDPOINT_DS_GROUPS, Reply::NetworkFailure);
+    let content = simulation.message("Hello");
+    assert!(simulation
+        .user
+        .send_message(conversation_id, content)
+        .await
+        .is_err());
+    let failed_message = simulation.user.last_message(conversation_id).await.unwrap();
+    assert!(!failed_message.was_sent());
+    assert_eq!(
+        failed_message.delivery_failure(),
+        Some(DeliveryFailure::Network)
+    );
+
+    // Once the client is back online, the retry goes through.
+    simulation.clock.advance(Duration::minutes(5));
+    let ds_timestamp = TimeStamp::now();
+    simulation.server.expect(
+        ENDPOINT_DS_GROUPS,
+        replies::ds_fanout_timestamp(ds_timestamp),
+    );
+    let message = simulation
+        .user
+        .retry_failed_message(failed_message.id())
+        .await
+        .unwrap();
+    assert_eq!(message.id(), failed_message.id());
+    assert!(message.was_sent());
+    assert_eq!(message.delivery_failure(), None);
+    assert_eq!(message.timestamp(), *ds_timestamp);
+
+    simulation.server.assert_done();
+}
+
+/// Outcome of [`send_in_stale_epoch`]
+#[derive(Debug, PartialEq)]
+struct StaleEpochRun {
+    transcript: Vec<String>,
+    request_bodies: Vec<Vec<u8>>,
+    client_id: AsClientId,
+    message_id: Uuid,
+}
+
+/// Send a message in an epoch that another member concurrently moved on
+/// from.
+async fn send_in_stale_epoch(seed: u64) -> StaleEpochRun {
+    let simulation = Simulation::new(seed);
+    let conversation_id = simulation.create_conversation().await;
+
+    // The DS rejects the message. The client catches up with the queue and
+    // sends the message again.
+    simulation
+        .server
+        .expect(ENDPOINT_DS_GROUPS, replies::ds_stale_epoch());
+    simulation
+        .server
+        .expect(ENDPOINT_QS, replies::qs_empty_queue());
+    simulation.server.expect(
+        ENDPOINT_DS_GROUPS,
+        replies::ds_fanout_timestamp(TimeStamp::now()),
+    );
+    let content = simulation.message("Hello");
+    let message = simulation
+        .user
+        .send_message(conversation_id, content)
+        .await
+        .unwrap();
+    assert!(message.was_sent());
+    simulation.server.assert_done();
+
+    StaleEpochRun {
+        transcript: simulation.server.transcript(),
+        request_bodies: simulation.server.request_bodies(),
+        client_id: simulation.user.as_client_id(),
+        message_id: message.id().to_uuid(),
+    }
+}
+
+#[actix_rt::test]
+async fn resend_after_stale_epoch() {
+    let run = send_in_stale_epoch(2).await;
+    assert_eq!(
+        run.transcript,
+        [
+            "POST /ds_groups -> 200 OK",
+            "POST /ds_groups -> 200 OK",
+            "POST /ds_groups -> 409 Conflict",
+            "POST /qs -> 200 OK",
+            "POST /ds_groups -> 200 OK",
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn simulation_is_deterministic() {
+    // The request for a group id and the fetch from the QS
+    const PLAINTEXT_REQUESTS: [usize; 2] = [0, 3];
+
+    let first = send_in_stale_epoch(3).await;
+    let second = send_in_stale_epoch(3).await;
+    assert_eq!(first.transcript, second.transcript);
+    assert_eq!(first.client_id, second.client_id);
+    assert_eq!(first.message_id, second.message_id);
+    let body_sizes =
+        |run: &StaleEpochRun| -> Vec<usize> { run.request_bodies.iter().map(Vec::len).collect() };
+    assert_eq!(body_sizes(&first), body_sizes(&second));
+    for index in PLAINTEXT_REQUESTS {
+        assert_eq!(first.request_bodies[index], second.request_bodies[index]);
+    }
+
+    let other_seed = send_in_stale_epoch(4).await;
+    assert_eq!(first.transcript, other_seed.transcript);
+    assert_ne!(first.client_id, other_seed.client_id);
+    assert_ne!(first.message_id, other_seed.message_id);
+    for index in PLAINTEXT_REQUESTS {
+        assert_ne!(
+            first.request_bodies[index],
+            other_seed.request_bodies[index]
+        );
+    }
+}
+
+/// Invite a contact, remove it while the connection drops and invite another
+/// contact once the client is back online.
+#[actix_rt::test]
+async fn invite_remove_and_reconnect() {
+    let simulation = Simulation::new(5);
+    let conversation_id = simulation.create_conversation().await;
+    let bob = simulation.add_contact("bob", conversation_id).await;
+    let charlie = simulation.add_contact("charlie", conversation_id).await;
+    let qs_signing_key = QsSigningKey::generate().unwrap();
+
+    // Bob joins.
+    simulation.server.expect(
+        ENDPOINT_QS,
+        replies::qs_key_package_batch(&qs_signing_key, &bob.user_name(), vec![bob.add_package()]),
+    );
+    simulation
+        .server
+        .expect(ENDPOINT_QS, replies::qs_verifying_key(&qs_signing_key));
+    simulation.server.expect(
+        ENDPOINT_DS_GROUPS,
+        replies::ds_fanout_timestamp(TimeStamp::now()),
+    );
+    simulation
+        .user
+        .invite_users(conversation_id, &[bob.user_name()])
+        .await
+        .unwrap();
+    let members = simulation
+        .user
+        .conversation_participants(conversation_id)
+        .await
+        .unwrap();
+    assert!(members.contains(&bob.user_name()));
+
+    // The connection drops while Bob is removed. The client doesn't know
+    // whether the DS applied the commit.
+    simulation
+        .server
+        .expect(ENDPOINT_DS_GROUPS, Reply::NetworkFailure);
+    assert!(simulation
+        .user
+        .remove_users(conversation_id, &[bob.user_name()])
+        .await
+        .is_err());
+    let (epoch, pending_commit_hash) = simulation.group_state(conversation_id).await;
+    let pending_commit_hash = pending_commit_hash.expect("removal is pending");
+
+    // Charlie can't be invited while the removal is pending.
+    simulation.server.expect(
+        ENDPOINT_QS,
+        replies::qs_key_package_batch(
+            &qs_signing_key,
+            &charlie.user_name(),
+            vec![charlie.add_package()],
+        ),
+    );
+    assert!(simulation
+        .user
+        .invite_users(conversation_id, &[charlie.user_name()])
+        .await
+        .is_err());
+
+    // Back online, the client learns that the DS applied the removal.
+    simulation
+        .server
+        .expect(ENDPOINT_QS, replies::qs_empty_queue());
+    simulation.user.clean_up_stale_groups().await.unwrap();
+    simulation.clock.advance(Duration::hours(2));
+    simulation
+        .server
+        .expect(ENDPOINT_QS, replies::qs_empty_queue());
+    simulation.server.expect(
+        ENDPOINT_DS_GROUPS,
+        replies::ds_group_epoch_info(epoch + 1, pending_commit_hash),
+    );
+    simulation.user.clean_up_stale_groups().await.unwrap();
+    assert_eq!(
+        simulation.group_state(conversation_id).await,
+        (epoch + 1, None)
+    );
+    let members = simulation
+        .user
+        .conversation_participants(conversation_id)
+        .await
+        .unwrap();
+    assert!(!members.contains(&bob.user_name()));
+
+    // Now Charlie can be invited.
+    simulation.server.expect(
+        ENDPOINT_QS,
+        replies::qs_key_package_batch(
+            &qs_signing_key,
+            &charlie.user_name(),
+            vec![charlie.add_package()],
+        ),
+    );
+    simulation.server.expect(
+        ENDPOINT_DS_GROUPS,
+        replies::ds_fanout_timestamp(TimeStamp::now()),
+    );
+    simulation
+        .user
+        .invite_users(conversation_id, &[charlie.user_name()])
+        .await
+        .unwrap();
+    let members = simulation
+        .user
+        .conversation_participants(conversation_id)
+        .await
+        .unwrap();
+    assert!(members.contains(&charlie.user_name()));
+
+    simulation.server.assert_done();
+}
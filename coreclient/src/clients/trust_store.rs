@@ -0,0 +1,124 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{bail, Result};
+use phnxtypes::{identifiers::AsClientId, time::TimeStamp};
+use rusqlite::Connection;
+
+use crate::{
+    groups::client_auth_info::StorableClientCredential,
+    key_stores::as_credentials::AsCredentials,
+    trust_store::{
+        ClientTrust, ContactTrust, CredentialStatus, PinnedAsCredential, SignedTrustStore,
+        TrustStore, TrustStoreDiff,
+    },
+    Contact,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Export the local trust state as a signed JSON document, see
+    /// [`crate::trust_store`].
+    pub async fn export_trust_store(&self) -> Result<String> {
+        let trust_store = self.trust_store().await?;
+        let signed = SignedTrustStore::sign(trust_store, &self.inner.key_store.signing_key())?;
+        Ok(signed.to_json()?)
+    }
+
+    /// Compare the local trust state with the export of another device of the
+    /// user.
+    ///
+    /// The credential of the client that signed the export is verified
+    /// against the AS and has to belong to the same user.
+    pub async fn compare_trust_store(&self, export: &str) -> Result<TrustStoreDiff> {
+        let signed = SignedTrustStore::from_json(export)?;
+        let credential = AsCredentials::verify_client_credential(
+            self.inner.connection.clone(),
+            &self.inner.api_clients,
+            signed.signer_credential()?,
+        )
+        .await?;
+        if credential.identity().user_name() != self.user_name() {
+            bail!("The trust store was exported by a client of another user");
+        }
+        let other = signed.verify(&credential)?;
+        let local = self.trust_store().await?;
+        Ok(local.diff(&other))
+    }
+
+    async fn trust_store(&self) -> Result<TrustStore> {
+        let connection = self.inner.connection.lock().await;
+        let now = TimeStamp::now();
+        let as_intermediate_credentials = AsCredentials::load_all_intermediates(&connection)?
+            .into_iter()
+            .map(|credential| PinnedAsCredential {
+                domain: credential.domain().to_string(),
+                fingerprint: credential.fingerprint().to_string(),
+                as_credential_fingerprint: credential.signer_fingerprint().to_string(),
+                not_before: credential.expiration_data().not_before(),
+                not_after: credential.expiration_data().not_after(),
+            })
+            .collect();
+        let contacts = Contact::load_all(&connection)?
+            .into_iter()
+            .map(|contact| {
+                let clients = contact
+                    .clients
+                    .iter()
+                    .map(|client_id| client_trust(&connection, client_id, now))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(ContactTrust {
+                    user_name: contact.user_name.to_string(),
+                    clients,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(TrustStore::new(
+            self.user_name().to_string(),
+            self.as_client_id().to_string(),
+            as_intermediate_credentials,
+            contacts,
+        ))
+    }
+}
+
+/// As for the security status of a group, a credential counts as verified if
+/// the intermediate credential that signed it is still cached.
+fn client_trust(
+    connection: &Connection,
+    client_id: &AsClientId,
+    now: TimeStamp,
+) -> Result<ClientTrust> {
+    let Some(credential) = StorableClientCredential::load_by_client_id(connection, client_id)?
+    else {
+        return Ok(ClientTrust {
+            client_id: client_id.to_string(),
+            fingerprint: None,
+            signer_fingerprint: None,
+            status: CredentialStatus::Missing,
+        });
+    };
+    let signer = AsCredentials::load_intermediate(
+        connection,
+        credential.signer_fingerprint(),
+        &client_id.user_name().domain(),
+    )?;
+    let status = match signer {
+        None => CredentialStatus::UnknownSigner,
+        Some(signer)
+            if credential.expiration_data().not_after() < now
+                || signer.expiration_data().not_after() < now =>
+        {
+            CredentialStatus::Expired
+        }
+        Some(_) => CredentialStatus::Verified,
+    };
+    Ok(ClientTrust {
+        client_id: client_id.to_string(),
+        fingerprint: Some(credential.fingerprint().to_string()),
+        signer_fingerprint: Some(credential.signer_fingerprint().to_string()),
+        status,
+    })
+}
@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Profiles stored on the AS.
+//!
+//! The own profile is uploaded to the AS whenever it changes, encrypted under
+//! the key the user shares with its contacts in the friendship package.
+//! Contacts that didn't receive the key that way get it in a message in the
+//! connection conversation. The profiles of contacts are refreshed
+//! periodically. Each refresh sends the
+//! cached version of the profile, so that the AS only sends profiles that
+//! changed and only those have to be decrypted and stored again.
+
+use anyhow::Result;
+use phnxtypes::{
+    crypto::ear::{keys::UserProfileEarKey, EarDecryptable, EarEncryptable},
+    identifiers::QualifiedUserName,
+    messages::user_profiles::UserProfileFetch,
+    time::TimeStamp,
+};
+
+use crate::{
+    mimi_content::MimiContent, user_profiles::refresh::StaleUserProfile, Asset, Contact,
+    Conversation, ConversationId, NotificationType, ProfileRefreshPolicy, UserProfile,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Upload the own profile to the AS, such that contacts can refresh it.
+    pub(super) async fn upload_own_user_profile(&self) -> Result<()> {
+        let user_profile = self.own_user_profile().await?;
+        let encrypted = user_profile.encrypt(self.inner.key_store.user_profile_ear_key()?)?;
+        let version = self
+            .inner
+            .api_clients
+            .default_client()?
+            .as_upload_user_profile(encrypted, &self.inner.key_store.signing_key())
+            .await?;
+        let connection = self.inner.connection.lock().await;
+        UserProfile::mark_fetched(&connection, &self.user_name(), Some(version))?;
+        drop(connection);
+
+        if let Err(error) = self.share_own_user_profile_key().await {
+            log::warn!("Failed to share profile key: {error}");
+        }
+        Ok(())
+    }
+
+    /// Send the key of the own profile to the contacts that didn't receive it
+    /// yet. Failing to send it to a single contact is only logged: the key is
+    /// sent again with the next upload or refresh.
+    async fn share_own_user_profile_key(&self) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let contacts = Contact::load_without_own_user_profile_key(&connection)?;
+        drop(connection);
+
+        let key = self.inner.key_store.user_profile_ear_key()?;
+        for contact in contacts {
+            let content = MimiContent::user_profile_key(self.user_name().domain(), key.clone());
+            if let Err(error) = self
+                .send_unstored_content(contact.conversation_id, content)
+                .await
+            {
+                log::warn!(
+                    "Failed to share profile key with {}: {error}",
+                    contact.user_name
+                );
+                continue;
+            }
+            let connection = self.inner.connection.lock().await;
+            Contact::mark_own_user_profile_key_shared(&connection, &contact.user_name)?;
+        }
+        Ok(())
+    }
+
+    /// Store the profile key a contact sent in the connection conversation
+    /// with the given id, such that the contact's profile is fetched with the
+    /// next refresh.
+    pub(super) async fn handle_user_profile_key(
+        &self,
+        conversation_id: ConversationId,
+        sender: &QualifiedUserName,
+        key: &UserProfileEarKey,
+    ) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        if !Contact::update_user_profile_ear_key(&connection, sender, conversation_id, key)? {
+            log::warn!("Ignoring profile key of {sender}, who isn't the contact of the chat");
+            return Ok(());
+        }
+        UserProfile::mark_stale(&connection, sender)?;
+        Ok(())
+    }
+
+    /// Fetch the profiles of the contacts that are due according to the given
+    /// policy. Returns the users whose profiles changed.
+    ///
+    /// Failing to fetch the profile of a single contact is only logged, such
+    /// that the other profiles are still refreshed. The profile is fetched
+    /// again with the next refresh.
+    pub async fn refresh_user_profiles(
+        &self,
+        policy: &ProfileRefreshPolicy,
+    ) -> Result<Vec<QualifiedUserName>> {
+        if let Err(error) = self.share_own_user_profile_key().await {
+            log::warn!("Failed to share profile key: {error}");
+        }
+
+        let fetched_before = TimeStamp::from(*TimeStamp::now() - policy.max_age);
+        let connection = self.inner.connection.lock().await;
+        let stale = UserProfile::load_stale(&connection, fetched_before, policy.max_profiles)?;
+        drop(connection);
+
+        let mut changed = Vec::new();
+        for stale_profile in stale {
+            let user_name = stale_profile.user_name.clone();
+            match self.refresh_user_profile(stale_profile).await {
+                Ok(true) => changed.push(user_name),
+                Ok(false) => {}
+                Err(error) => log::warn!("Failed to refresh profile of {user_name}: {error}"),
+            }
+        }
+
+        if !changed.is_empty() {
+            self.store_notifier()
+                .notify(NotificationType::UserProfileChange(changed.clone()));
+        }
+        Ok(changed)
+    }
+
+    /// Returns `true` if the profile changed.
+    async fn refresh_user_profile(&self, stale_profile: StaleUserProfile) -> Result<bool> {
+        let StaleUserProfile {
+            user_name,
+            cached_version,
+            ear_key,
+            conversation_id,
+        } = stale_profile;
+        let fetch = self
+            .inner
+            .api_clients
+            .get(&user_name.domain())?
+            .as_user_profile(user_name.clone(), cached_version)
+            .await?;
+        let versioned = match fetch {
+            UserProfileFetch::NotFound => {
+                let connection = self.inner.connection.lock().await;
+                UserProfile::mark_fetched(&connection, &user_name, None)?;
+                return Ok(false);
+            }
+            UserProfileFetch::NotModified => {
+                let connection = self.inner.connection.lock().await;
+                UserProfile::mark_fetched(&connection, &user_name, cached_version)?;
+                return Ok(false);
+            }
+            UserProfileFetch::Modified(versioned) => versioned,
+        };
+
+        let user_profile = UserProfile::decrypt(&ear_key, &versioned.profile)?;
+        if user_profile.user_name() != &user_name {
+            anyhow::bail!("Profile of {user_name} is the one of another user");
+        }
+
+        let connection = self.inner.connection.lock().await;
+        user_profile.update(&connection)?;
+        UserProfile::mark_fetched(&connection, &user_name, Some(versioned.version))?;
+        // The picture of the connection conversation is the one of the
+        // contact.
+        if let Some(mut conversation) = Conversation::load(&connection, &conversation_id)? {
+            let picture = user_profile.profile_picture().map(|asset| match asset {
+                Asset::Value(value) => value.to_owned(),
+            });
+            conversation.set_conversation_picture(&connection, picture)?;
+            drop(connection);
+            self.store_notifier()
+                .notify(NotificationType::ConversationChange(conversation_id));
+        }
+        Ok(true)
+    }
+}
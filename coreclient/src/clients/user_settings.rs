@@ -0,0 +1,260 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Sync of the user settings between the clients of a user.
+//!
+//! The synced settings (see [`UserSetting::SYNCED`]) are stored as a single
+//! encrypted blob on the AS. To sync, a client downloads the blob, merges it
+//! into its own settings and uploads the result if it contains changes the
+//! blob doesn't have yet. Settings are merged per key: the value that was
+//! changed last wins, with ties broken by the value itself, so that all
+//! clients resolve conflicts the same way.
+//!
+//! Uploads are based on the version of the blob they were merged with. If
+//! another client uploaded in the meantime, the upload is rejected and the
+//! client merges again. After an upload, the other clients are notified with
+//! a [`SelfMessage`].
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use phnxtypes::{
+    crypto::ear::{EarDecryptable, EarEncryptable},
+    messages::{
+        self_message::SelfMessage,
+        user_settings::{SyncedUserSetting, UserSettings},
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    user_settings::{persistence::StorableUserSetting, UserSetting},
+    NotificationType,
+};
+
+use super::CoreUser;
+
+/// Number of times a sync is retried if other clients upload concurrently.
+const MAX_SYNC_ATTEMPTS: usize = 3;
+
+/// Version of the synced settings this client merged last.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct UserSettingsSyncState {
+    version: u64,
+}
+
+impl UserSetting for UserSettingsSyncState {
+    const KEY: &'static str = "user_settings_sync";
+}
+
+impl CoreUser {
+    /// Load the user setting of type `T`. Returns the default value if the
+    /// setting was never set.
+    pub async fn user_setting<T: UserSetting>(&self) -> Result<T> {
+        let connection = self.inner.connection.lock().await;
+        Ok(StorableUserSetting::load(&connection)?)
+    }
+
+    /// Store the user setting of type `T`.
+    ///
+    /// Synced settings are synced with the other clients of the user right
+    /// away. A failure to sync is only logged: the setting is synced with the
+    /// next sync.
+    pub async fn set_user_setting<T: UserSetting>(&self, value: &T) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        StorableUserSetting::store(&connection, value)?;
+        drop(connection);
+
+        if T::SYNCED {
+            if let Err(error) = self.sync_user_settings().await {
+                log::warn!("Failed to sync user settings: {error}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync the synced settings with the other clients of the user via the
+    /// AS.
+    ///
+    /// Settings changed by other clients are notified as
+    /// [`NotificationType::UserSettingsChange`].
+    pub async fn sync_user_settings(&self) -> Result<()> {
+        let api_client = self.inner.api_clients.default_client()?;
+        let key = self.inner.key_store.user_settings_ear_key()?;
+        for _ in 0..MAX_SYNC_ATTEMPTS {
+            let signing_key = self.inner.key_store.signing_key();
+            let (remote_version, remote) =
+                match api_client.as_get_user_settings(&signing_key).await? {
+                    Some(versioned) => (
+                        versioned.version,
+                        UserSettings::decrypt(key, &versioned.settings)?,
+                    ),
+                    None => (0, UserSettings::default()),
+                };
+
+            let mut connection = self.inner.connection.lock().await;
+            let transaction = connection.transaction()?;
+            let local = StorableUserSetting::load_synced(&transaction)?;
+            let merge = merge(local, &remote);
+            for (setting, value) in &merge.updated_locally {
+                StorableUserSetting::store_synced(&transaction, setting, value)?;
+            }
+            StorableUserSetting::store(
+                &transaction,
+                &UserSettingsSyncState {
+                    version: remote_version,
+                },
+            )?;
+            transaction.commit()?;
+            drop(connection);
+
+            if !merge.updated_locally.is_empty() {
+                let settings = merge.updated_locally.into_keys().collect();
+                self.store_notifier()
+                    .notify(NotificationType::UserSettingsChange(settings));
+            }
+            if merge.settings == remote {
+                return Ok(());
+            }
+
+            let encrypted = merge.settings.encrypt(key)?;
+            let version = match api_client
+                .as_upload_user_settings(remote_version, encrypted, &signing_key)
+                .await
+            {
+                Ok(version) => version,
+                // Another client uploaded in the meantime.
+                Err(error) if error.is_user_settings_conflict() => continue,
+                Err(error) => return Err(error.into()),
+            };
+            let connection = self.inner.connection.lock().await;
+            StorableUserSetting::store(&connection, &UserSettingsSyncState { version })?;
+            drop(connection);
+
+            let message = SelfMessage::UserSettingsChanged { version }
+                .encrypt(self.inner.key_store.self_message_ear_key()?)?;
+            api_client
+                .qs_enqueue_self_message(
+                    self.inner.qs_client_id.clone(),
+                    message,
+                    &self.inner.key_store.qs_client_signing_key,
+                )
+                .await?;
+            return Ok(());
+        }
+        bail!("User settings changed concurrently {MAX_SYNC_ATTEMPTS} times")
+    }
+
+    /// Another client of the user uploaded the given version of the synced
+    /// settings.
+    pub(super) async fn handle_user_settings_changed(&self, version: u64) -> Result<()> {
+        let connection = self.inner.connection.lock().await;
+        let state: UserSettingsSyncState = StorableUserSetting::load(&connection)?;
+        drop(connection);
+        if state.version >= version {
+            return Ok(());
+        }
+        self.sync_user_settings().await
+    }
+}
+
+struct Merge {
+    /// The merged settings
+    settings: UserSettings,
+    /// The settings of other clients that replace the local ones
+    updated_locally: BTreeMap<String, SyncedUserSetting>,
+}
+
+/// Merge the remote settings into the local ones. For each key, the value
+/// that was changed last wins.
+fn merge(local: BTreeMap<String, SyncedUserSetting>, remote: &UserSettings) -> Merge {
+    let mut settings = local;
+    let mut updated_locally = BTreeMap::new();
+    for (setting, remote_value) in &remote.settings {
+        let remote_wins = settings
+            .get(setting)
+            .is_none_or(|local_value| wins(remote_value, local_value));
+        if remote_wins {
+            settings.insert(setting.clone(), remote_value.clone());
+            updated_locally.insert(setting.clone(), remote_value.clone());
+        }
+    }
+    Merge {
+        settings: UserSettings { settings },
+        updated_locally,
+    }
+}
+
+fn wins(a: &SyncedUserSetting, b: &SyncedUserSetting) -> bool {
+    (a.modified_at, &a.value) > (b.modified_at, &b.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+
+    fn setting(value: u8, modified_at: &str) -> SyncedUserSetting {
+        let modified_at: DateTime<Utc> = modified_at.parse().unwrap();
+        SyncedUserSetting {
+            value: vec![value],
+            modified_at: modified_at.into(),
+        }
+    }
+
+    #[test]
+    fn merge_takes_latest_value_per_key() {
+        let local = BTreeMap::from([
+            ("a".to_owned(), setting(1, "2024-10-27T10:00:00Z")),
+            ("b".to_owned(), setting(1, "2024-10-27T12:00:00Z")),
+            ("c".to_owned(), setting(1, "2024-10-27T10:00:00Z")),
+        ]);
+        let remote = UserSettings {
+            settings: BTreeMap::from([
+                ("a".to_owned(), setting(2, "2024-10-27T11:00:00Z")),
+                ("b".to_owned(), setting(2, "2024-10-27T11:00:00Z")),
+                ("d".to_owned(), setting(2, "2024-10-27T11:00:00Z")),
+            ]),
+        };
+
+        let merge = merge(local, &remote);
+        let values: Vec<_> = merge
+            .settings
+            .settings
+            .iter()
+            .map(|(key, setting)| (key.as_str(), setting.value[0]))
+            .collect();
+        assert_eq!(values, [("a", 2), ("b", 1), ("c", 1), ("d", 2)]);
+        let updated: Vec<_> = merge.updated_locally.keys().map(String::as_str).collect();
+        assert_eq!(updated, ["a", "d"]);
+    }
+
+    #[test]
+    fn merge_is_symmetric_for_simultaneous_changes() {
+        let one = setting(1, "2024-10-27T10:00:00Z");
+        let two = setting(2, "2024-10-27T10:00:00Z");
+        let remote = |setting: &SyncedUserSetting| UserSettings {
+            settings: BTreeMap::from([("a".to_owned(), setting.clone())]),
+        };
+
+        let merged_here = merge(
+            BTreeMap::from([("a".to_owned(), one.clone())]),
+            &remote(&two),
+        );
+        let merged_there = merge(
+            BTreeMap::from([("a".to_owned(), two.clone())]),
+            &remote(&one),
+        );
+        assert_eq!(merged_here.settings, merged_there.settings);
+        assert_eq!(merged_here.settings.settings["a"], two);
+        // Unchanged settings are not updated again.
+        assert!(merge(
+            BTreeMap::from([("a".to_owned(), two.clone())]),
+            &remote(&two)
+        )
+        .updated_locally
+        .is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use anyhow::{anyhow, bail, ensure, Result};
+use phnxtypes::{
+    codec::PhnxCodec,
+    crypto::ear::{Ciphertext, EarKey},
+    messages::attachments::AttachmentDownload,
+    time::TimeStamp,
+};
+
+use crate::{
+    attachments::{
+        view_once::{ViewOnceRecord, ViewOnceState},
+        AttachmentHash,
+    },
+    conversations::messages::{ConversationMessage, Message},
+    mimi_content::{EncryptedAttachment, MimiContent},
+    ConversationId, ConversationMessageId,
+};
+
+use super::CoreUser;
+
+impl CoreUser {
+    /// Encrypt the given content under a fresh key, upload it to the DS of the
+    /// conversation with the given id and send it as a view-once attachment.
+    ///
+    /// The key is only shared with the members of the conversation as part of
+    /// the message, such that the DS never learns the content. The DS deletes
+    /// the attachment once the first recipient downloaded it.
+    pub async fn send_view_once_attachment(
+        &self,
+        conversation_id: ConversationId,
+        content: Vec<u8>,
+    ) -> Result<ConversationMessage> {
+        let attachment = self
+            .upload_encrypted_attachment(conversation_id, &content, true)
+            .await?;
+        let content = MimiContent::view_once_attachment(self.user_name().domain(), attachment);
+        let mimi_id = content.id().id();
+        let message = self.send_message(conversation_id, content).await?;
+
+        let connection = self.inner.connection.lock().await;
+        ViewOnceRecord::new(message.id(), conversation_id, mimi_id, true, None)
+            .store(&connection)?;
+        Ok(message)
+    }
+
+    /// Returns the view state of the view-once attachment of the message with
+    /// the given id.
+    ///
+    /// For attachments sent by this client, the state tells whether one of
+    /// the recipients viewed the attachment.
+    pub async fn view_once_state(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> Result<ViewOnceState> {
+        let connection = self.inner.connection.lock().await;
+        let state = ViewOnceRecord::load(&connection, message_id)?
+            .map(|record| record.state())
+            .unwrap_or(ViewOnceState::Unviewed);
+        Ok(state)
+    }
+
+    /// Download the view-once attachment of the received message with the
+    /// given id. Returns `None` if the attachment was already viewed.
+    ///
+    /// Once the attachment is downloaded successfully, it is marked as viewed
+    /// and the sender is notified with a receipt. The content is not stored
+    /// locally, so callers must not persist it either.
+    pub async fn open_view_once_attachment(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> Result<Option<AttachmentDownload>> {
+        let connection = self.inner.connection.lock().await;
+        let message = ConversationMessage::load(&connection, &message_id.to_uuid())?
+            .ok_or_else(|| anyhow!("Can't find message with id {}", message_id.to_uuid()))?;
+        if ViewOnceRecord::load(&connection, message_id)?.is_some() {
+            return Ok(None);
+        }
+        drop(connection);
+
+        let Message::Content(content_message) = message.message() else {
+            bail!("Message {} is not a content message", message_id.to_uuid());
+        };
+        if content_message.sender() == self.user_name().to_string() {
+            bail!("Can't open own view-once attachments");
+        }
+        let content = content_message.content();
+        let conversation_id = message.conversation_id();
+        let attachment_url = content.view_once_attachment_url().ok_or_else(|| {
+            anyhow!(
+                "Message {} has no view-once attachment",
+                message_id.to_uuid()
+            )
+        })?;
+
+        let download = self
+            .download_attachment(conversation_id, &attachment_url)
+            .await?;
+        let download = match (download, content.encrypted_view_once_attachment()) {
+            (AttachmentDownload::Blocked, _) => return Ok(Some(AttachmentDownload::Blocked)),
+            (AttachmentDownload::Available(ciphertext), Some(attachment)) => {
+                AttachmentDownload::Available(decrypt_attachment(attachment, &ciphertext)?)
+            }
+            (AttachmentDownload::Url(_), Some(_)) => {
+                bail!("View-once attachment {} wasn't fetched", attachment_url)
+            }
+            // Attachments shared by older clients aren't encrypted.
+            (download, None) => download,
+        };
+
+        let mimi_id = content.id().clone();
+        let connection = self.inner.connection.lock().await;
+        let record = ViewOnceRecord::new(
+            message_id,
+            conversation_id,
+            mimi_id.id(),
+            false,
+            Some(TimeStamp::now()),
+        );
+        // Another call might have opened the attachment in the meantime.
+        if !record.store(&connection)? {
+            return Ok(None);
+        }
+        drop(connection);
+
+        // The attachment was viewed regardless of whether the sender learns
+        // about it.
+        let receipt = MimiContent::seen_receipt(self.user_name().domain(), vec![mimi_id]);
+        if let Err(error) = self.send_unstored_content(conversation_id, receipt).await {
+            log::warn!("Failed to send view-once receipt: {}", error);
+        }
+        Ok(Some(download))
+    }
+
+    /// Process a receipt received in the conversation with the given id.
+    /// Returns true if any view-once attachments sent by this client were
+    /// marked as viewed.
+    pub(super) async fn handle_receipt(
+        &self,
+        conversation_id: ConversationId,
+        receipt: &MimiContent,
+    ) -> Result<bool> {
+        let mimi_ids: Vec<_> = receipt.last_seen.iter().map(|id| id.id()).collect();
+        let connection = self.inner.connection.lock().await;
+        let updated = ViewOnceRecord::mark_sent_as_viewed(
+            &connection,
+            conversation_id,
+            &mimi_ids,
+            receipt.timestamp,
+        )?;
+        Ok(updated > 0)
+    }
+}
+
+/// Decrypt the content of the given attachment after checking that the
+/// downloaded ciphertext is the one the sender uploaded.
+pub(super) fn decrypt_attachment(
+    attachment: &EncryptedAttachment,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    ensure!(
+        AttachmentHash::of(ciphertext)?.as_bytes() == attachment.ciphertext_hash,
+        "Attachment {} doesn't match its hash",
+        attachment.url
+    );
+    let ciphertext: Ciphertext = PhnxCodec::from_slice(ciphertext)?;
+    Ok(attachment.key.decrypt(&ciphertext)?)
+}
@@ -0,0 +1,191 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Wiping all local data of the device, e.g. after a panic gesture.
+//!
+//! Attachments, thumbnails and key material of a client are stored in its
+//! client database. Any other files of a client are named after its client id.
+//! The master key is stored in the phnx.db and wrapped with a key in the
+//! platform key store. The platform keys are deleted as well. See [`crate::utils::wipe`]
+//! for the limits of overwriting files.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+
+use crate::{
+    key_stores::master_key::PlatformKeyStore,
+    utils::{
+        persistence::{open_phnx_db, PHNX_DB_NAME},
+        wipe::{wipe_database, wipe_entries_with_prefix},
+    },
+};
+
+use super::{store::ClientRecord, CoreUser};
+
+impl CoreUser {
+    /// Wipe the local data of all clients on the device, including the
+    /// phnx.db and the key in the platform key store.
+    ///
+    /// The user's connection is closed first, so the user can't be used
+    /// anymore afterwards. Fails if any per-account file remains under
+    /// `db_path`. The phnx.db is only wiped once the files of all clients
+    /// are, such that a failed wipe can be retried.
+    pub async fn wipe_local_data(
+        &self,
+        db_path: &str,
+        key_store: &impl PlatformKeyStore,
+    ) -> Result<()> {
+        let mut connection = self.inner.connection.lock().await;
+        let client_connection = std::mem::replace(&mut *connection, Connection::open_in_memory()?);
+        if let Err((_, error)) = client_connection.close() {
+            log::error!("Failed to close client database: {error}");
+        }
+        wipe_device(db_path, key_store).await
+    }
+}
+
+/// Wipe the local data of all clients on the device. See
+/// [`CoreUser::wipe_local_data`].
+///
+/// All connections to the databases under `db_path` must be closed.
+pub async fn wipe_device(db_path: &str, key_store: &impl PlatformKeyStore) -> Result<()> {
+    // The platform keys only protect the master key, which is stored in the
+    // phnx.db. The client databases are not encrypted with it, so they are
+    // only protected by overwriting them below.
+    let mut key_deleted = Ok(());
+    for require_biometrics in [false, true] {
+        if let Err(error) = key_store.delete_key(require_biometrics).await {
+            log::error!("Failed to delete platform key: {error}");
+            key_deleted = Err(error);
+        }
+    }
+
+    // Client databases and any other files of the clients
+    let phnx_db_path = Path::new(db_path).join(PHNX_DB_NAME);
+    let client_ids = match load_client_ids(db_path, &phnx_db_path) {
+        Ok(client_ids) => client_ids,
+        Err(error) => {
+            // Without the client records, the files of the clients can't be
+            // found. The phnx.db is kept, such that the wipe can be retried.
+            log::error!("Failed to load client records: {error}");
+            bail!("Failed to find the client files to wipe");
+        }
+    };
+    let remaining = wipe_entries_with_prefix(Path::new(db_path), &client_ids)?;
+    if !remaining.is_empty() {
+        // Keep the client records for a retry
+        bail!("Failed to wipe {} client files", remaining.len());
+    }
+
+    // The phnx.db goes last, since it holds the client records.
+    wipe_database(&phnx_db_path)?;
+    key_deleted
+}
+
+/// The ids of all clients in the phnx.db, if there is one.
+fn load_client_ids(db_path: &str, phnx_db_path: &Path) -> Result<Vec<String>> {
+    if !phnx_db_path.exists() {
+        return Ok(Vec::new());
+    }
+    let phnx_db_connection = open_phnx_db(db_path)?;
+    let client_ids = ClientRecord::load_all(&phnx_db_connection)?
+        .into_iter()
+        .map(|record| record.as_client_id.to_string())
+        .collect();
+    Ok(client_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use phnxtypes::{
+        crypto::secrets::SecretBytes,
+        identifiers::{AsClientId, SafeTryInto},
+    };
+
+    use crate::utils::persistence::{client_db_name, Storable};
+
+    use super::*;
+
+    /// Platform key store that records whether its key was deleted.
+    #[derive(Default)]
+    struct DeletableKeyStore {
+        deleted: AtomicBool,
+    }
+
+    impl PlatformKeyStore for DeletableKeyStore {
+        async fn is_available(&self) -> bool {
+            true
+        }
+
+        async fn wrap_key(&self, key: &[u8], _require_biometrics: bool) -> Result<Vec<u8>> {
+            Ok(key.to_vec())
+        }
+
+        async fn unwrap_key(
+            &self,
+            wrapped_key: &[u8],
+            _require_biometrics: bool,
+        ) -> Result<SecretBytes> {
+            Ok(wrapped_key.to_vec().into())
+        }
+
+        async fn delete_key(&self, _require_biometrics: bool) -> Result<()> {
+            self.deleted.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn unreadable_client_records_fail_the_wipe() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+        let db_path = dir.to_str().unwrap();
+        std::fs::write(dir.join(PHNX_DB_NAME), b"not a database").unwrap();
+
+        let key_store = DeletableKeyStore::default();
+        let error = wipe_device(db_path, &key_store).await.unwrap_err();
+        assert!(error.to_string().contains("client files"));
+        assert!(key_store.deleted.load(Ordering::SeqCst));
+        // The phnx.db is needed to find the client files on a retry
+        assert!(dir.join(PHNX_DB_NAME).exists());
+
+        // Without a phnx.db there is nothing left to wipe
+        std::fs::remove_file(dir.join(PHNX_DB_NAME)).unwrap();
+        wipe_device(db_path, &key_store).await.unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn wipes_client_files_and_phnx_db() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+        let db_path = dir.to_str().unwrap();
+        let user_name = SafeTryInto::try_into("alice@example.com").unwrap();
+        let client_id = AsClientId::random(user_name).unwrap();
+        let connection = open_phnx_db(db_path).unwrap();
+        ClientRecord::create_table(&connection).unwrap();
+        ClientRecord::new(client_id.clone())
+            .store(&connection)
+            .unwrap();
+        drop(connection);
+        let client_db = dir.join(client_db_name(&client_id));
+        std::fs::write(&client_db, b"client data").unwrap();
+        let unrelated = dir.join("unrelated.txt");
+        std::fs::write(&unrelated, b"unrelated").unwrap();
+
+        let key_store = DeletableKeyStore::default();
+        wipe_device(db_path, &key_store).await.unwrap();
+        assert!(key_store.deleted.load(Ordering::SeqCst));
+        assert!(!client_db.exists());
+        assert!(!dir.join(PHNX_DB_NAME).exists());
+        assert!(unrelated.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
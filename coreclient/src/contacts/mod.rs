@@ -11,7 +11,7 @@ use phnxtypes::{
         ear::{
             keys::{
                 AddPackageEarKey, ClientCredentialEarKey, FriendshipPackageEarKey, SignatureEarKey,
-                SignatureEarKeyWrapperKey, WelcomeAttributionInfoEarKey,
+                SignatureEarKeyWrapperKey, UserProfileEarKey, WelcomeAttributionInfoEarKey,
             },
             EarDecryptable,
         },
@@ -20,6 +20,7 @@ use phnxtypes::{
     identifiers::{AsClientId, QualifiedUserName},
     keypackage_batch::{KeyPackageBatch, VERIFIED},
     messages::FriendshipToken,
+    time::TimeStamp,
 };
 
 use crate::{
@@ -45,6 +46,13 @@ pub struct Contact {
     pub(crate) signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
     // ID of the connection conversation with this contact.
     pub(crate) conversation_id: ConversationId,
+    // Key of the contact's profile on the AS. `None` for contacts whose
+    // friendship package predates profiles on the AS.
+    #[serde(default)]
+    pub(crate) user_profile_ear_key: Option<UserProfileEarKey>,
+    // Time at which the user marked the contact as verified.
+    #[serde(default)]
+    pub(crate) verified_at: Option<TimeStamp>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,6 +76,8 @@ impl Contact {
             client_credential_ear_key: friendship_package.client_credential_ear_key,
             signature_ear_key_wrapper_key: friendship_package.signature_ear_key_wrapper_key,
             conversation_id,
+            user_profile_ear_key: Some(friendship_package.user_profile_ear_key),
+            verified_at: None,
         }
     }
 
@@ -76,6 +86,12 @@ impl Contact {
         &self.user_name
     }
 
+    /// Time at which the user marked the contact as verified, e.g. after
+    /// comparing the fingerprints of its clients in person.
+    pub fn verified_at(&self) -> Option<TimeStamp> {
+        self.verified_at
+    }
+
     pub(crate) async fn fetch_add_infos(
         &self,
         connection_mutex: SqliteConnection,
@@ -91,6 +107,9 @@ impl Contact {
                 self.add_package_ear_key.clone(),
             )
             .await?;
+        if key_package_batch_response.availability.last_resort_used {
+            log::warn!("Adding {} using last-resort key packages", self.user_name);
+        }
         let key_packages: Vec<(KeyPackage, SignatureEarKey)> = key_package_batch_response
             .add_packages
             .into_iter()
@@ -127,6 +146,73 @@ impl Contact {
     }
 }
 
+/// Whether the user marked a contact as verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactVerification {
+    Verified,
+    Unverified,
+}
+
+/// Conditions for contact list queries. Only contacts that satisfy all
+/// conditions that are set are returned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContactFilter {
+    pub verification: Option<ContactVerification>,
+    pub blocked: Option<bool>,
+    /// Whether the connection conversation with the contact is active
+    pub active_chat: Option<bool>,
+    /// Alphabetical window of user names, e.g. `"a"` to `"d"` for the
+    /// contacts from A to C. The start is inclusive, the end exclusive. User
+    /// names are compared bytewise.
+    pub name_from: Option<String>,
+    pub name_until: Option<String>,
+}
+
+/// Position in a filtered contact list. Contacts are ordered by user name.
+///
+/// Start with [`ContactListCursor::new`] and continue with the cursor returned
+/// alongside each page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactListCursor {
+    pub(crate) filter: ContactFilter,
+    pub(crate) after: Option<QualifiedUserName>,
+}
+
+impl ContactListCursor {
+    pub fn new(filter: ContactFilter) -> Self {
+        Self {
+            filter,
+            after: None,
+        }
+    }
+
+    /// Continue a listing after the contact with the given user name, e.g.
+    /// with a cursor that was kept by the UI as the user name of the last
+    /// shown contact.
+    pub fn after(filter: ContactFilter, user_name: QualifiedUserName) -> Self {
+        Self {
+            filter,
+            after: Some(user_name),
+        }
+    }
+
+    pub fn filter(&self) -> &ContactFilter {
+        &self.filter
+    }
+
+    /// The user name of the last contact of the previous page, if any.
+    pub fn last_user_name(&self) -> Option<&QualifiedUserName> {
+        self.after.as_ref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactsPage {
+    pub contacts: Vec<Contact>,
+    /// `None` if this is the last page.
+    pub next_cursor: Option<ContactListCursor>,
+}
+
 /// Contact which has not yet accepted our connection request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PartialContact {
@@ -2,14 +2,19 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use phnxtypes::identifiers::{AsClientId, QualifiedUserName};
-use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use phnxtypes::{
+    identifiers::{AsClientId, QualifiedUserName},
+    time::TimeStamp,
+};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, ToSql, Transaction};
 
 use crate::{
     clients::connection_establishment::FriendshipPackage, utils::persistence::Storable, Contact,
     PartialContact,
 };
 
+use super::{ContactFilter, ContactVerification};
+
 pub(crate) const CONTACT_INSERT_TRIGGER: &str =
     "DROP TRIGGER IF EXISTS no_contact_overlap_on_insert;
 
@@ -35,6 +40,22 @@ pub(crate) const CONTACT_UPDATE_TRIGGER: &str =
         END;
     END;";
 
+pub(crate) const CONTACT_USER_PROFILE_EAR_KEY_COLUMN: &str =
+    "ALTER TABLE contacts ADD COLUMN user_profile_ear_key BLOB;";
+
+/// Whether the own profile key was shared with the contact. Contacts created
+/// before the key was sent in the friendship package get it in a separate
+/// message.
+pub(crate) const CONTACT_USER_PROFILE_KEY_SHARED_COLUMN: &str =
+    "ALTER TABLE contacts ADD COLUMN own_user_profile_key_shared INTEGER NOT NULL DEFAULT 0;";
+
+/// Contact list queries are ordered by user name and use the primary key for
+/// paging. Verified contacts are few, so they get their own index.
+pub(crate) const CONTACT_VERIFICATION_COLUMN: &str = "
+    ALTER TABLE contacts ADD COLUMN verified_at TEXT;
+    CREATE INDEX IF NOT EXISTS contacts_verified
+        ON contacts (user_name) WHERE verified_at IS NOT NULL;";
+
 impl Storable for Contact {
     const CREATE_TABLE_STATEMENT: &'static str = "
         CREATE TABLE IF NOT EXISTS contacts (
@@ -69,6 +90,8 @@ impl Storable for Contact {
         let add_package_ear_key = row.get(5)?;
         let client_credential_ear_key = row.get(6)?;
         let signature_ear_key_wrapper_key = row.get(7)?;
+        let user_profile_ear_key = row.get(8)?;
+        let verified_at = row.get(9)?;
 
         Ok(Contact {
             user_name,
@@ -79,6 +102,8 @@ impl Storable for Contact {
             client_credential_ear_key,
             signature_ear_key_wrapper_key,
             conversation_id,
+            user_profile_ear_key,
+            verified_at,
         })
     }
 }
@@ -98,6 +123,50 @@ impl Contact {
         rows.collect()
     }
 
+    /// Returns up to `limit` contacts matching the filter whose user names
+    /// come after `after`, ordered by user name.
+    pub(crate) fn load_filtered(
+        connection: &Connection,
+        filter: &ContactFilter,
+        after: Option<&QualifiedUserName>,
+        limit: u32,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let limit = i64::from(limit);
+        let (condition, mut params) = filter_condition(filter, after);
+        params.push(&limit);
+        let mut stmt = connection.prepare(&format!(
+            "SELECT * FROM contacts WHERE {condition} ORDER BY user_name LIMIT ?"
+        ))?;
+        let rows = stmt.query_map(params_from_iter(params), Self::from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn count_filtered(
+        connection: &Connection,
+        filter: &ContactFilter,
+    ) -> Result<u64, rusqlite::Error> {
+        let (condition, params) = filter_condition(filter, None);
+        connection.query_row(
+            &format!("SELECT COUNT(*) FROM contacts WHERE {condition}"),
+            params_from_iter(params),
+            |row| row.get(0),
+        )
+    }
+
+    /// Set or clear the time at which the user verified the contact. Returns
+    /// `false` if there is no such contact.
+    pub(crate) fn set_verified_at(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+        verified_at: Option<TimeStamp>,
+    ) -> Result<bool, rusqlite::Error> {
+        let updated = connection.execute(
+            "UPDATE contacts SET verified_at = ? WHERE user_name = ?",
+            params![verified_at, user_name],
+        )?;
+        Ok(updated > 0)
+    }
+
     pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
         let clients_str = self
             .clients
@@ -106,7 +175,8 @@ impl Contact {
             .collect::<Vec<_>>()
             .join(",");
         connection.execute(
-            "INSERT INTO contacts (user_name, conversation_id, clients, wai_ear_key, friendship_token, add_package_ear_key, client_credential_ear_key, signature_ear_key_wrapper_key) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            // The own profile key was sent in the friendship package.
+            "INSERT INTO contacts (user_name, conversation_id, clients, wai_ear_key, friendship_token, add_package_ear_key, client_credential_ear_key, signature_ear_key_wrapper_key, user_profile_ear_key, own_user_profile_key_shared) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1)",
             params![
                 self.user_name,
                 self.conversation_id,
@@ -116,12 +186,61 @@ impl Contact {
                 self.add_package_ear_key,
                 self.client_credential_ear_key,
                 self.signature_ear_key_wrapper_key,
+                self.user_profile_ear_key,
             ],
         )?;
         Ok(())
     }
 }
 
+/// SQL condition on the `contacts` table for the filter, with its parameters.
+fn filter_condition<'a>(
+    filter: &'a ContactFilter,
+    after: Option<&'a QualifiedUserName>,
+) -> (String, Vec<&'a dyn ToSql>) {
+    let mut conditions = vec!["TRUE"];
+    let mut params: Vec<&dyn ToSql> = Vec::new();
+    match filter.verification {
+        Some(ContactVerification::Verified) => conditions.push("verified_at IS NOT NULL"),
+        Some(ContactVerification::Unverified) => conditions.push("verified_at IS NULL"),
+        None => {}
+    }
+    match filter.blocked {
+        Some(true) => conditions
+            .push("EXISTS (SELECT 1 FROM blocked_users b WHERE b.user_name = contacts.user_name)"),
+        Some(false) => conditions.push(
+            "NOT EXISTS (SELECT 1 FROM blocked_users b WHERE b.user_name = contacts.user_name)",
+        ),
+        None => {}
+    }
+    match filter.active_chat {
+        Some(true) => conditions.push(
+            "EXISTS (SELECT 1 FROM conversations c
+                WHERE c.conversation_id = contacts.conversation_id
+                AND c.conversation_status = 'active')",
+        ),
+        Some(false) => conditions.push(
+            "NOT EXISTS (SELECT 1 FROM conversations c
+                WHERE c.conversation_id = contacts.conversation_id
+                AND c.conversation_status = 'active')",
+        ),
+        None => {}
+    }
+    if let Some(name_from) = &filter.name_from {
+        conditions.push("user_name >= ?");
+        params.push(name_from);
+    }
+    if let Some(name_until) = &filter.name_until {
+        conditions.push("user_name < ?");
+        params.push(name_until);
+    }
+    if let Some(after) = after {
+        conditions.push("user_name > ?");
+        params.push(after);
+    }
+    (conditions.join(" AND "), params)
+}
+
 pub(crate) const PARTIAL_CONTACT_INSERT_TRIGGER: &str =
     "DROP TRIGGER IF EXISTS no_partial_contact_overlap_on_insert;
 
@@ -197,7 +316,7 @@ impl PartialContact {
         Ok(())
     }
 
-    fn delete(self, connection: &Connection) -> Result<(), rusqlite::Error> {
+    pub(crate) fn delete(self, connection: &Connection) -> Result<(), rusqlite::Error> {
         connection.execute(
             "DELETE FROM partial_contacts WHERE user_name = ?",
             params![self.user_name],
@@ -227,6 +346,8 @@ impl PartialContact {
             client_credential_ear_key: friendship_package.client_credential_ear_key,
             signature_ear_key_wrapper_key: friendship_package.signature_ear_key_wrapper_key,
             conversation_id,
+            user_profile_ear_key: Some(friendship_package.user_profile_ear_key),
+            verified_at: None,
         };
         contact.store(&savepoint)?;
 
@@ -235,3 +356,133 @@ impl PartialContact {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        crypto::ear::keys::{
+            AddPackageEarKey, ClientCredentialEarKey, SignatureEarKeyWrapperKey,
+            WelcomeAttributionInfoEarKey,
+        },
+        identifiers::SafeTryInto,
+        messages::FriendshipToken,
+    };
+    use uuid::Uuid;
+
+    use crate::{user_handles::BlockedUser, utils::migration::run_migrations, ConversationId};
+
+    use super::*;
+
+    fn store_contact(connection: &Connection, user_name: &str) -> Contact {
+        let contact = Contact {
+            user_name: SafeTryInto::try_into(user_name).unwrap(),
+            clients: Vec::new(),
+            wai_ear_key: WelcomeAttributionInfoEarKey::random().unwrap(),
+            friendship_token: FriendshipToken::random().unwrap(),
+            add_package_ear_key: AddPackageEarKey::random().unwrap(),
+            client_credential_ear_key: ClientCredentialEarKey::random().unwrap(),
+            signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey::random().unwrap(),
+            conversation_id: ConversationId::from(Uuid::new_v4()),
+            user_profile_ear_key: None,
+            verified_at: None,
+        };
+        contact.store(connection).unwrap();
+        contact
+    }
+
+    fn user_names(contacts: &[Contact]) -> Vec<String> {
+        contacts
+            .iter()
+            .map(|contact| contact.user_name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn contacts_are_filtered_and_paged() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        store_contact(&connection, "alice@example.com");
+        let bob = store_contact(&connection, "bob@example.com");
+        let carol = store_contact(&connection, "carol@example.com");
+        let dave = store_contact(&connection, "dave@example.com");
+
+        // Only the connection conversation with bob is active.
+        connection
+            .execute(
+                "INSERT INTO conversations (conversation_id, conversation_title, group_id, last_read, conversation_status, conversation_type)
+                VALUES (?, 'bob', x'00', ?, 'active', 'connection:bob@example.com')",
+                params![bob.conversation_id, TimeStamp::now()],
+            )
+            .unwrap();
+        BlockedUser::block(&connection, &carol.user_name).unwrap();
+        assert!(
+            Contact::set_verified_at(&connection, &dave.user_name, Some(TimeStamp::now())).unwrap()
+        );
+
+        let load = |filter: &ContactFilter, after: Option<&QualifiedUserName>, limit| {
+            user_names(&Contact::load_filtered(&connection, filter, after, limit).unwrap())
+        };
+        let all = ContactFilter::default();
+        assert_eq!(
+            load(&all, None, 2),
+            ["alice@example.com", "bob@example.com"]
+        );
+        assert_eq!(
+            load(&all, Some(&bob.user_name), 2),
+            ["carol@example.com", "dave@example.com"]
+        );
+        assert!(load(&all, Some(&dave.user_name), 2).is_empty());
+
+        let not_blocked = ContactFilter {
+            blocked: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            load(&not_blocked, None, 10),
+            ["alice@example.com", "bob@example.com", "dave@example.com"]
+        );
+        let active_chat = ContactFilter {
+            active_chat: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(load(&active_chat, None, 10), ["bob@example.com"]);
+        let verified = ContactFilter {
+            verification: Some(ContactVerification::Verified),
+            ..Default::default()
+        };
+        assert_eq!(load(&verified, None, 10), ["dave@example.com"]);
+        let window = ContactFilter {
+            name_from: Some("b".to_owned()),
+            name_until: Some("d".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            load(&window, Some(&bob.user_name), 10),
+            ["carol@example.com"]
+        );
+
+        let unverified = ContactFilter {
+            verification: Some(ContactVerification::Unverified),
+            blocked: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            Contact::count_filtered(&connection, &unverified).unwrap(),
+            2
+        );
+        assert_eq!(Contact::count_filtered(&connection, &all).unwrap(), 4);
+
+        // Unverifying restores the contact to the unverified ones.
+        Contact::set_verified_at(&connection, &dave.user_name, None).unwrap();
+        assert_eq!(
+            Contact::count_filtered(&connection, &unverified).unwrap(),
+            3
+        );
+        assert!(!Contact::set_verified_at(
+            &connection,
+            &SafeTryInto::try_into("erin@example.com").unwrap(),
+            None
+        )
+        .unwrap());
+    }
+}
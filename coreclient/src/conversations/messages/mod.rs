@@ -2,9 +2,9 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::fmt::Formatter;
+use std::{collections::BTreeMap, fmt::Formatter};
 
-use openmls::framing::ApplicationMessage;
+use phnxtypes::{crypto::rng, messages::room_policy::RoomPolicyChange, time::Duration};
 
 use crate::mimi_content::MimiContent;
 
@@ -12,6 +12,10 @@ use super::*;
 
 pub(crate) mod persistence;
 
+/// Messages received later than this after the DS assigned their timestamp
+/// are considered delivered late, e.g. because the client was offline.
+pub const LATE_DELIVERY_THRESHOLD: Duration = Duration::minutes(5);
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct TimestampedMessage {
     timestamp: TimeStamp,
@@ -35,23 +39,22 @@ impl TimestampedMessage {
         }
     }
 
-    /// Create a new timestamped message from an incoming application message.
-    /// The message is marked as sent.
-    pub(crate) fn from_application_message(
-        application_message: ApplicationMessage,
+    /// Create a new timestamped message from the content of an incoming
+    /// application message. The message is marked as sent.
+    pub(crate) fn from_content(
+        content: MimiContent,
         ds_timestamp: TimeStamp,
         sender_name: QualifiedUserName,
-    ) -> Result<Self, tls_codec::Error> {
-        let content = MimiContent::tls_deserialize_exact_bytes(&application_message.into_bytes())?;
+    ) -> Self {
         let message = Message::Content(Box::new(ContentMessage::new(
             sender_name.to_string(),
             true,
             content,
         )));
-        Ok(Self {
+        Self {
             timestamp: ds_timestamp,
             message,
-        })
+        }
     }
 
     pub(crate) fn from_message_and_timestamp(message: Message, ds_timestamp: TimeStamp) -> Self {
@@ -70,7 +73,7 @@ impl TimestampedMessage {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct ConversationMessageId {
     uuid: Uuid,
 }
@@ -78,7 +81,7 @@ pub struct ConversationMessageId {
 impl ConversationMessageId {
     pub(crate) fn new() -> Self {
         Self {
-            uuid: Uuid::new_v4(),
+            uuid: rng::random_uuid(),
         }
     }
 
@@ -109,6 +112,11 @@ pub struct ConversationMessage {
     pub(super) conversation_id: ConversationId,
     pub(super) conversation_message_id: ConversationMessageId,
     pub(super) timestamped_message: TimestampedMessage,
+    /// The time at which the message was received and processed locally. For
+    /// own messages, the time at which they were created.
+    pub(super) received_at: TimeStamp,
+    /// Why the last attempt to send the message failed, if it did
+    pub(super) delivery_failure: Option<DeliveryFailure>,
 }
 
 impl ConversationMessage {
@@ -122,6 +130,8 @@ impl ConversationMessage {
             conversation_id,
             conversation_message_id: ConversationMessageId::new(),
             timestamped_message,
+            received_at: TimeStamp::now(),
+            delivery_failure: None,
         }
     }
 
@@ -131,25 +141,53 @@ impl ConversationMessage {
         content: MimiContent,
     ) -> ConversationMessage {
         let message = Message::Content(Box::new(ContentMessage::new(sender, false, content)));
-        let timestamped_message =
-            TimestampedMessage::from_message_and_timestamp(message, TimeStamp::now());
+        let now = TimeStamp::now();
+        let timestamped_message = TimestampedMessage::from_message_and_timestamp(message, now);
         ConversationMessage {
             conversation_id,
             conversation_message_id: ConversationMessageId::new(),
             timestamped_message,
+            received_at: now,
+            delivery_failure: None,
         }
     }
 
-    /// Mark the message as sent and update the timestamp.
+    /// Mark the message as sent and update the timestamp. Clears a previous
+    /// delivery failure.
     pub(crate) fn mark_as_sent(
         &mut self,
         connection: &Connection,
         ds_timestamp: TimeStamp,
     ) -> Result<(), rusqlite::Error> {
         self.timestamped_message.mark_as_sent(ds_timestamp);
+        self.delivery_failure = None;
         self.update_sent_status(connection, ds_timestamp, true)
     }
 
+    /// Replace the content of the unsent message, e.g. once its attachments
+    /// were uploaded.
+    #[cfg(feature = "attachments")]
+    pub(crate) fn set_content(
+        &mut self,
+        connection: &Connection,
+        content: MimiContent,
+    ) -> Result<(), rusqlite::Error> {
+        if let Message::Content(content_message) = &mut self.timestamped_message.message {
+            content_message.content = content;
+        }
+        self.update_content(connection)
+    }
+
+    /// Record why sending the message failed.
+    pub(crate) fn set_delivery_failure(
+        &mut self,
+        connection: &Connection,
+        delivery_failure: DeliveryFailure,
+    ) -> Result<(), rusqlite::Error> {
+        self.delivery_failure = Some(delivery_failure);
+        self.update_delivery_failure(connection)
+    }
+
     pub fn id_ref(&self) -> &ConversationMessageId {
         &self.conversation_message_id
     }
@@ -158,10 +196,24 @@ impl ConversationMessage {
         self.conversation_message_id
     }
 
+    /// The timestamp assigned by the DS, or the local creation time if the
+    /// message wasn't sent yet. The timeline is ordered by this timestamp.
     pub fn timestamp(&self) -> DateTime<Utc> {
         *self.timestamped_message.timestamp()
     }
 
+    /// The time at which the message was received locally. For own messages,
+    /// the time at which they were created.
+    pub fn received_at(&self) -> DateTime<Utc> {
+        *self.received_at
+    }
+
+    /// Returns `true` if the message was received more than
+    /// [`LATE_DELIVERY_THRESHOLD`] after the DS assigned its timestamp.
+    pub fn is_delivered_late(&self) -> bool {
+        self.received_at() - self.timestamp() > LATE_DELIVERY_THRESHOLD
+    }
+
     pub fn was_sent(&self) -> bool {
         if let Message::Content(content) = &self.timestamped_message.message {
             content.was_sent()
@@ -170,6 +222,12 @@ impl ConversationMessage {
         }
     }
 
+    /// Why the last attempt to send the message failed. `None` if the
+    /// message was sent or sending it wasn't attempted yet.
+    pub fn delivery_failure(&self) -> Option<DeliveryFailure> {
+        self.delivery_failure
+    }
+
     pub fn conversation_id(&self) -> ConversationId {
         self.conversation_id
     }
@@ -179,6 +237,57 @@ impl ConversationMessage {
     }
 }
 
+/// Reason why the DS didn't accept an own message.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum DeliveryFailure {
+    /// The message was sent in an outdated epoch. Resolved by processing the
+    /// pending messages of the group.
+    StaleEpoch,
+    /// The room policy doesn't allow the user to send messages.
+    NotAllowed,
+    /// The user sent too many messages.
+    RateLimited,
+    /// The DS couldn't be reached.
+    Network,
+    /// The server doesn't support this client version anymore.
+    UpgradeRequired,
+    /// The DS rejected the message for another reason.
+    Rejected,
+}
+
+impl DeliveryFailure {
+    /// Returns true if retrying can succeed without further action of the
+    /// user.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DeliveryFailure::StaleEpoch
+            | DeliveryFailure::RateLimited
+            | DeliveryFailure::Network => true,
+            DeliveryFailure::NotAllowed
+            | DeliveryFailure::UpgradeRequired
+            | DeliveryFailure::Rejected => false,
+        }
+    }
+}
+
+impl Display for DeliveryFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryFailure::StaleEpoch => write!(f, "The conversation changed while sending"),
+            DeliveryFailure::NotAllowed => {
+                write!(
+                    f,
+                    "You are not allowed to send messages in this conversation"
+                )
+            }
+            DeliveryFailure::RateLimited => write!(f, "Too many messages sent"),
+            DeliveryFailure::Network => write!(f, "The server couldn't be reached"),
+            DeliveryFailure::UpgradeRequired => write!(f, "The app must be updated"),
+            DeliveryFailure::Rejected => write!(f, "The server rejected the message"),
+        }
+    }
+}
+
 // WARNING: If this type is changed, a new `VersionedMessage` variant must be
 // introduced and the storage logic changed accordingly.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -189,23 +298,24 @@ pub enum Message {
 
 impl Message {
     /// Returns a string representation of the message for use in UI
-    /// notifications.
-    pub fn string_representation(&self, conversation_type: &ConversationType) -> String {
+    /// notifications. Returns `None` for system messages, whose display
+    /// strings are generated by the app in the language of the UI.
+    pub fn string_representation(&self, conversation_type: &ConversationType) -> Option<String> {
         match self {
             Message::Content(content_message) => match conversation_type {
                 ConversationType::Group => {
                     let sender = &content_message.sender;
                     let content = content_message.content.string_rendering();
-                    format!("{sender}: {content}")
+                    Some(format!("{sender}: {content}"))
                 }
                 ConversationType::Connection(_) | ConversationType::UnconfirmedConnection(_) => {
                     let content = content_message.content.string_rendering();
-                    content.to_string()
+                    Some(content.to_string())
                 }
             },
             Message::Event(event_message) => match &event_message {
-                EventMessage::System(system) => system.to_string(),
-                EventMessage::Error(error) => error.message().to_string(),
+                EventMessage::System(_) => None,
+                EventMessage::Error(error) => Some(error.message().to_string()),
             },
         }
     }
@@ -252,32 +362,74 @@ pub enum EventMessage {
 
 // WARNING: If this type is changed, a new `VersionedMessage` variant must be
 // introduced and the storage logic changed accordingly.
+/// A change of the conversation, described independently of any language.
+///
+/// The app generates the display strings when it shows the message, such that
+/// they follow the language of the UI. Users are sorted by name and not
+/// empty.
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum SystemMessage {
-    // The first UserName is the adder/remover the second is the added/removed.
-    Add(QualifiedUserName, QualifiedUserName),
-    Remove(QualifiedUserName, QualifiedUserName),
+    /// If the adder only added themselves, they joined the conversation.
+    Add {
+        adder: QualifiedUserName,
+        added: Vec<QualifiedUserName>,
+    },
+    /// If the remover only removed themselves, they left the conversation.
+    Remove {
+        remover: QualifiedUserName,
+        removed: Vec<QualifiedUserName>,
+    },
+    ChangeRoomPolicy {
+        admin: QualifiedUserName,
+        change: RoomPolicyChange,
+    },
+    /// An own change that was stuck because the response of the DS was lost
+    /// was found to be applied.
+    PendingChangeApplied,
+    /// An own change or proposals that were stuck were discarded.
+    PendingChangeDiscarded,
 }
 
-impl Display for SystemMessage {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SystemMessage::Add(adder, added) => {
-                if adder == added {
-                    write!(f, "{} joined the conversation", adder)
-                } else {
-                    write!(f, "{} added {} to the conversation", adder, added)
-                }
-            }
-            SystemMessage::Remove(remover, removed) => {
-                if remover == removed {
-                    write!(f, "{} left the conversation", remover)
-                } else {
-                    write!(f, "{} removed {} from the conversation", remover, removed)
-                }
-            }
-        }
+impl SystemMessage {
+    /// One message per adder, with the users each of them added.
+    pub(crate) fn adds(
+        adds: impl IntoIterator<Item = (QualifiedUserName, QualifiedUserName)>,
+    ) -> Vec<Self> {
+        group_by_actor(adds)
+            .into_iter()
+            .map(|(adder, added)| Self::Add { adder, added })
+            .collect()
+    }
+
+    /// One message per remover, with the users each of them removed.
+    pub(crate) fn removes(
+        removes: impl IntoIterator<Item = (QualifiedUserName, QualifiedUserName)>,
+    ) -> Vec<Self> {
+        group_by_actor(removes)
+            .into_iter()
+            .map(|(remover, removed)| Self::Remove { remover, removed })
+            .collect()
+    }
+}
+
+/// Groups the targets of the given actor/target pairs by actor. Actors and
+/// targets are sorted by name and targets are deduplicated.
+fn group_by_actor(
+    pairs: impl IntoIterator<Item = (QualifiedUserName, QualifiedUserName)>,
+) -> Vec<(QualifiedUserName, Vec<QualifiedUserName>)> {
+    let mut grouped: BTreeMap<String, (QualifiedUserName, BTreeMap<String, QualifiedUserName>)> =
+        BTreeMap::new();
+    for (actor, target) in pairs {
+        grouped
+            .entry(actor.to_string())
+            .or_insert_with(|| (actor, BTreeMap::new()))
+            .1
+            .insert(target.to_string(), target);
     }
+    grouped
+        .into_values()
+        .map(|(actor, targets)| (actor, targets.into_values().collect()))
+        .collect()
 }
 
 // WARNING: If this type is changed, the storage and loading logic in the
@@ -300,7 +452,11 @@ impl ErrorMessage {
 }
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum NotificationType {
     ConversationChange(ConversationId), // The id of the changed conversation.
     Message(ConversationMessage),
+    ActivityChange,                            // The activity feed changed.
+    UserSettingsChange(Vec<String>), // The keys of the settings changed by another client.
+    UserProfileChange(Vec<QualifiedUserName>), // The users whose profiles changed.
 }
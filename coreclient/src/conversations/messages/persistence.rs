@@ -5,24 +5,33 @@
 use phnxtypes::{codec::PhnxCodec, time::TimeStamp};
 use rusqlite::{
     params,
-    types::{FromSql, FromSqlError, Type},
+    types::{FromSql, FromSqlError, FromSqlResult, Type},
     Connection, OptionalExtension, ToSql,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    utils::persistence::Storable, ContentMessage, ConversationId, ConversationMessage, Message,
+    utils::persistence::Storable, ContentMessage, ConversationId, ConversationMessage,
+    DeliveryFailure, Message,
 };
 
 // When adding a variant to this enum, the new variant must be called
 // `CurrentVersion` and the current version must be renamed to `VX`, where `X`
 // is the next version number. The content type of the old `CurrentVersion` must
 // be renamed and otherwise preserved to ensure backwards compatibility.
+//
+// Serde identifies variants by name, so the stored names of existing variants
+// must be kept via `#[serde(rename)]`.
 #[derive(Serialize, Deserialize)]
 enum VersionedMessage {
+    // System messages with one actor and one target, see
+    // [`legacy::LegacySystemMessage`]. Content messages are unchanged.
+    #[serde(rename = "CurrentVersion")]
+    V1(Vec<u8>),
     // We store the message as bytes, because deserialization depends on
     // other parameters.
+    #[serde(rename = "V2")]
     CurrentVersion(Vec<u8>),
 }
 
@@ -47,31 +56,97 @@ enum MessageInputs {
 }
 
 enum VersionedMessageInputs {
+    V1(Vec<u8>, MessageInputs),
     CurrentVersion(Vec<u8>, MessageInputs),
 }
 
+mod legacy {
+    use phnxtypes::{identifiers::QualifiedUserName, messages::room_policy::RoomPolicyChange};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{ErrorMessage, EventMessage, SystemMessage};
+
+    /// [`EventMessage`] as stored in [`super::VersionedMessage::V1`].
+    #[derive(Serialize, Deserialize)]
+    pub(super) enum LegacyEventMessage {
+        System(LegacySystemMessage),
+        Error(ErrorMessage),
+    }
+
+    /// System messages before they were grouped by actor. The first user name
+    /// is the actor.
+    #[derive(Serialize, Deserialize)]
+    pub(super) enum LegacySystemMessage {
+        Add(QualifiedUserName, QualifiedUserName),
+        Remove(QualifiedUserName, QualifiedUserName),
+        ChangeRoomPolicy(QualifiedUserName, RoomPolicyChange),
+        PendingChangeApplied,
+        PendingChangeDiscarded,
+    }
+
+    impl From<LegacyEventMessage> for EventMessage {
+        fn from(legacy: LegacyEventMessage) -> Self {
+            match legacy {
+                LegacyEventMessage::System(system) => EventMessage::System(system.into()),
+                LegacyEventMessage::Error(error) => EventMessage::Error(error),
+            }
+        }
+    }
+
+    impl From<LegacySystemMessage> for SystemMessage {
+        fn from(legacy: LegacySystemMessage) -> Self {
+            match legacy {
+                LegacySystemMessage::Add(adder, added) => SystemMessage::Add {
+                    adder,
+                    added: vec![added],
+                },
+                LegacySystemMessage::Remove(remover, removed) => SystemMessage::Remove {
+                    remover,
+                    removed: vec![removed],
+                },
+                LegacySystemMessage::ChangeRoomPolicy(admin, change) => {
+                    SystemMessage::ChangeRoomPolicy { admin, change }
+                }
+                LegacySystemMessage::PendingChangeApplied => SystemMessage::PendingChangeApplied,
+                LegacySystemMessage::PendingChangeDiscarded => {
+                    SystemMessage::PendingChangeDiscarded
+                }
+            }
+        }
+    }
+}
+
 impl Message {
     // For future message types, the additional inputs to this function might
     // have to be adjusted.
     fn from_versioned_message(
         versioned_message: VersionedMessageInputs,
     ) -> Result<Self, phnxtypes::codec::Error> {
-        match versioned_message {
-            VersionedMessageInputs::CurrentVersion(message_bytes, inputs) => match inputs {
-                MessageInputs::System => {
-                    let event_message = PhnxCodec::from_slice(&message_bytes)?;
-                    Ok(Message::Event(event_message))
-                }
-                MessageInputs::User(sender, sent) => {
-                    let content = PhnxCodec::from_slice(&message_bytes)?;
-                    let content_message = ContentMessage {
-                        sender,
-                        sent,
-                        content,
-                    };
-                    Ok(Message::Content(Box::new(content_message)))
-                }
-            },
+        let (message_bytes, inputs, legacy) = match versioned_message {
+            VersionedMessageInputs::V1(message_bytes, inputs) => (message_bytes, inputs, true),
+            VersionedMessageInputs::CurrentVersion(message_bytes, inputs) => {
+                (message_bytes, inputs, false)
+            }
+        };
+        match inputs {
+            MessageInputs::System if legacy => {
+                let event_message: legacy::LegacyEventMessage =
+                    PhnxCodec::from_slice(&message_bytes)?;
+                Ok(Message::Event(event_message.into()))
+            }
+            MessageInputs::System => {
+                let event_message = PhnxCodec::from_slice(&message_bytes)?;
+                Ok(Message::Event(event_message))
+            }
+            MessageInputs::User(sender, sent) => {
+                let content = PhnxCodec::from_slice(&message_bytes)?;
+                let content_message = ContentMessage {
+                    sender,
+                    sent,
+                    content,
+                };
+                Ok(Message::Content(Box::new(content_message)))
+            }
         }
     }
 
@@ -86,6 +161,49 @@ impl Message {
 
 use super::TimestampedMessage;
 
+/// Column storing the local receive time of messages. Messages stored before
+/// get their timestamp as receive time.
+pub(crate) const CONVERSATION_MESSAGE_RECEIVED_AT_COLUMN: &str = "
+    ALTER TABLE conversation_messages ADD COLUMN received_at TEXT;
+    UPDATE conversation_messages SET received_at = timestamp;";
+
+/// Column storing why sending an own message failed, `NULL` if it didn't.
+pub(crate) const CONVERSATION_MESSAGE_DELIVERY_FAILURE_COLUMN: &str =
+    "ALTER TABLE conversation_messages ADD COLUMN delivery_failure TEXT;";
+
+impl DeliveryFailure {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryFailure::StaleEpoch => "stale_epoch",
+            DeliveryFailure::NotAllowed => "not_allowed",
+            DeliveryFailure::RateLimited => "rate_limited",
+            DeliveryFailure::Network => "network",
+            DeliveryFailure::UpgradeRequired => "upgrade_required",
+            DeliveryFailure::Rejected => "rejected",
+        }
+    }
+}
+
+impl ToSql for DeliveryFailure {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        self.as_str().to_sql()
+    }
+}
+
+impl FromSql for DeliveryFailure {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "stale_epoch" => Ok(DeliveryFailure::StaleEpoch),
+            "not_allowed" => Ok(DeliveryFailure::NotAllowed),
+            "rate_limited" => Ok(DeliveryFailure::RateLimited),
+            "network" => Ok(DeliveryFailure::Network),
+            "upgrade_required" => Ok(DeliveryFailure::UpgradeRequired),
+            "rejected" => Ok(DeliveryFailure::Rejected),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 impl Storable for ConversationMessage {
     const CREATE_TABLE_STATEMENT: &'static str = "
         CREATE TABLE IF NOT EXISTS conversation_messages (
@@ -106,23 +224,26 @@ impl Storable for ConversationMessage {
         let sender_str: String = row.get(3)?;
         let versioned_message: VersionedMessage = row.get(4)?;
         let sent = row.get(5)?;
+        let received_at = row.get(6)?;
+        let delivery_failure = row.get(7)?;
 
+        let inputs = match sender_str.as_str() {
+            "system" => MessageInputs::System,
+            user_str => {
+                let sender = user_str
+                    .strip_prefix("user:")
+                    .ok_or(rusqlite::Error::FromSqlConversionFailure(
+                        3,
+                        Type::Text,
+                        Box::new(FromSqlError::InvalidType),
+                    ))?
+                    .to_string();
+                MessageInputs::User(sender, sent)
+            }
+        };
         let versioned_message_inputs = match versioned_message {
+            VersionedMessage::V1(bytes) => VersionedMessageInputs::V1(bytes, inputs),
             VersionedMessage::CurrentVersion(bytes) => {
-                let inputs = match sender_str.as_str() {
-                    "system" => MessageInputs::System,
-                    user_str => {
-                        let sender = user_str
-                            .strip_prefix("user:")
-                            .ok_or(rusqlite::Error::FromSqlConversionFailure(
-                                3,
-                                Type::Text,
-                                Box::new(FromSqlError::InvalidType),
-                            ))?
-                            .to_string();
-                        MessageInputs::User(sender, sent)
-                    }
-                };
                 VersionedMessageInputs::CurrentVersion(bytes, inputs)
             }
         };
@@ -137,6 +258,8 @@ impl Storable for ConversationMessage {
             conversation_message_id,
             conversation_id,
             timestamped_message,
+            received_at,
+            delivery_failure,
         })
     }
 }
@@ -147,7 +270,7 @@ impl ConversationMessage {
         local_message_id: &Uuid,
     ) -> Result<Option<Self>, rusqlite::Error> {
         let mut statement = connection.prepare(
-            "SELECT message_id, conversation_id, timestamp, sender, content, sent FROM conversation_messages WHERE message_id = ?",
+            "SELECT message_id, conversation_id, timestamp, sender, content, sent, received_at, delivery_failure FROM conversation_messages WHERE message_id = ?",
         )?;
         statement
             .query_row(params![local_message_id], Self::from_row)
@@ -168,13 +291,15 @@ impl ConversationMessage {
                     timestamp,
                     sender,
                     content,
-                    sent
+                    sent,
+                    received_at,
+                    delivery_failure
                 FROM conversation_messages
                 WHERE conversation_id = ?
-                ORDER BY timestamp DESC
+                ORDER BY timestamp DESC, received_at DESC
                 LIMIT ?
             ) AS messages
-            ORDER BY timestamp ASC;",
+            ORDER BY timestamp ASC, received_at ASC;",
         )?;
         let messages = statement
             .query_map(params![conversation_id, number_of_messages], Self::from_row)?
@@ -191,7 +316,7 @@ impl ConversationMessage {
         };
         let content = self.timestamped_message.message.to_versioned_message()?;
         connection.execute(
-            "INSERT INTO conversation_messages (message_id, conversation_id, timestamp, sender, content, sent) VALUES (?, ?, ?, ?, ?, ?)",
+            "INSERT INTO conversation_messages (message_id, conversation_id, timestamp, sender, content, sent, received_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
             params![
                 self.conversation_message_id,
                 self.conversation_id,
@@ -202,6 +327,7 @@ impl ConversationMessage {
                     Message::Content(content_message) => content_message.sent,
                     Message::Event(_) => true,
                 },
+                self.received_at,
             ],
         )?;
         Ok(())
@@ -215,22 +341,242 @@ impl ConversationMessage {
         sent: bool,
     ) -> Result<(), rusqlite::Error> {
         connection.execute(
-            "UPDATE conversation_messages SET timestamp = ?, sent = ? WHERE message_id = ?",
+            "UPDATE conversation_messages SET timestamp = ?, sent = ?, delivery_failure = NULL
+            WHERE message_id = ?",
             params![timestamp, sent, self.conversation_message_id],
         )?;
         Ok(())
     }
 
+    /// Replace the stored content of the message.
+    #[cfg(feature = "attachments")]
+    pub(super) fn update_content(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        let content = self.timestamped_message.message.to_versioned_message()?;
+        connection.execute(
+            "UPDATE conversation_messages SET content = ? WHERE message_id = ?",
+            params![content, self.conversation_message_id],
+        )?;
+        Ok(())
+    }
+
+    pub(super) fn update_delivery_failure(
+        &self,
+        connection: &Connection,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE conversation_messages SET delivery_failure = ? WHERE message_id = ?",
+            params![self.delivery_failure, self.conversation_message_id],
+        )?;
+        Ok(())
+    }
+
+    /// Delete the message with the given id.
+    pub(crate) fn delete(
+        connection: &Connection,
+        message_id: ConversationMessageId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM conversation_messages WHERE message_id = ?",
+            params![message_id],
+        )?;
+        Ok(())
+    }
+
+    /// Load all content messages that haven't been sent yet, oldest first.
+    pub(crate) fn load_unsent(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT message_id, conversation_id, timestamp, sender, content, sent, received_at, delivery_failure FROM conversation_messages WHERE sent = 0 AND sender != 'system' ORDER BY timestamp ASC",
+        )?;
+        let messages = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    /// Number of content messages waiting to be sent, i.e. unsent messages
+    /// whose delivery didn't fail.
+    pub(crate) fn count_pending(connection: &Connection) -> Result<usize, rusqlite::Error> {
+        connection.query_row(
+            "SELECT COUNT(*) FROM conversation_messages
+            WHERE sent = 0 AND sender != 'system' AND delivery_failure IS NULL",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    /// Rewrite system messages stored as [`VersionedMessage::V1`] in the
+    /// current format. Returns the number of rewritten messages.
+    pub(crate) fn migrate_legacy_system_messages(
+        connection: &Connection,
+    ) -> Result<usize, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT message_id, conversation_id, timestamp, sender, content, sent, received_at, delivery_failure FROM conversation_messages WHERE sender = 'system'",
+        )?;
+        let legacy_messages = statement
+            .query_map([], |row| {
+                let versioned_message: VersionedMessage = row.get(4)?;
+                let is_legacy = matches!(versioned_message, VersionedMessage::V1(_));
+                is_legacy.then(|| Self::from_row(row)).transpose()
+            })?
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>, _>>()?;
+        let migrated = legacy_messages.len();
+        for message in legacy_messages {
+            let content = message.timestamped_message.message.to_versioned_message()?;
+            connection.execute(
+                "UPDATE conversation_messages SET content = ? WHERE message_id = ?",
+                params![content, message.conversation_message_id],
+            )?;
+        }
+        Ok(migrated)
+    }
+
     /// Get the last content message in the conversation.
     pub(crate) fn last_content_message(
         connection: &Connection,
         conversation_id: ConversationId,
     ) -> Result<Option<Self>, rusqlite::Error> {
         let mut statement = connection.prepare(
-            "SELECT message_id, conversation_id, timestamp, sender, content, sent FROM conversation_messages WHERE conversation_id = ? AND sender != 'system' ORDER BY timestamp DESC LIMIT 1",
+            "SELECT message_id, conversation_id, timestamp, sender, content, sent, received_at, delivery_failure FROM conversation_messages WHERE conversation_id = ? AND sender != 'system' ORDER BY timestamp DESC LIMIT 1",
         )?;
         statement
             .query_row(params![conversation_id], Self::from_row)
             .optional()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        identifiers::{Fqdn, QualifiedUserName, SafeTryInto},
+        time::Duration,
+    };
+
+    use crate::{utils::migration::run_migrations, EventMessage, MimiContent, SystemMessage};
+
+    use super::*;
+
+    #[test]
+    fn messages_are_ordered_by_ds_timestamp() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        let now = TimeStamp::now();
+        let on_time = ConversationMessage::from_timestamped_message(
+            conversation_id,
+            TimestampedMessage::system_message(
+                SystemMessage::PendingChangeApplied,
+                TimeStamp::from(*now - Duration::minutes(1)),
+            ),
+        );
+        on_time.store(&connection).unwrap();
+        // Received after the other message, but sent before it
+        let late = ConversationMessage::from_timestamped_message(
+            conversation_id,
+            TimestampedMessage::system_message(
+                SystemMessage::PendingChangeDiscarded,
+                TimeStamp::from(*now - Duration::hours(1)),
+            ),
+        );
+        late.store(&connection).unwrap();
+
+        let messages =
+            ConversationMessage::load_multiple(&connection, conversation_id, 10).unwrap();
+        assert_eq!(
+            messages.iter().map(|m| m.id()).collect::<Vec<_>>(),
+            vec![late.id(), on_time.id()]
+        );
+        assert!(messages[0].is_delivered_late());
+        assert!(!messages[1].is_delivered_late());
+        assert!(messages[0].received_at() >= messages[1].received_at());
+    }
+
+    #[test]
+    fn delivery_failures_are_stored_until_sent() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        let mut message = ConversationMessage::new_unsent_message(
+            "alice@example.com".to_owned(),
+            conversation_id,
+            MimiContent::simple_markdown_message(
+                Fqdn::try_from("example.com").unwrap(),
+                "Hi".to_owned(),
+            ),
+        );
+        message.store(&connection).unwrap();
+        message
+            .set_delivery_failure(&connection, DeliveryFailure::NotAllowed)
+            .unwrap();
+
+        let loaded = ConversationMessage::load(&connection, &message.id().to_uuid())
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.delivery_failure(), Some(DeliveryFailure::NotAllowed));
+
+        message.mark_as_sent(&connection, TimeStamp::now()).unwrap();
+        let loaded = ConversationMessage::load(&connection, &message.id().to_uuid())
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.delivery_failure(), None);
+        assert!(loaded.was_sent());
+
+        ConversationMessage::delete(&connection, message.id()).unwrap();
+        assert!(
+            ConversationMessage::load(&connection, &message.id().to_uuid())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn legacy_system_messages_are_migrated() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let legacy = legacy::LegacyEventMessage::System(legacy::LegacySystemMessage::Add(
+            alice.clone(),
+            bob.clone(),
+        ));
+        let content = VersionedMessage::V1(PhnxCodec::to_vec(&legacy).unwrap());
+        let message_id = Uuid::new_v4();
+        connection
+            .execute(
+                "INSERT INTO conversation_messages (message_id, conversation_id, timestamp, sender, content, sent, received_at) VALUES (?, ?, ?, 'system', ?, 1, ?)",
+                params![
+                    message_id,
+                    ConversationId::from(Uuid::new_v4()),
+                    TimeStamp::now(),
+                    content,
+                    TimeStamp::now()
+                ],
+            )
+            .unwrap();
+
+        let expected = Message::Event(EventMessage::System(SystemMessage::Add {
+            adder: alice,
+            added: vec![bob],
+        }));
+        let loaded = ConversationMessage::load(&connection, &message_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.message(), &expected);
+
+        assert_eq!(
+            ConversationMessage::migrate_legacy_system_messages(&connection).unwrap(),
+            1
+        );
+        assert_eq!(
+            ConversationMessage::migrate_legacy_system_messages(&connection).unwrap(),
+            0
+        );
+        let loaded = ConversationMessage::load(&connection, &message_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.message(), &expected);
+    }
+}
@@ -8,16 +8,17 @@ use chrono::{DateTime, Utc};
 use openmls::group::GroupId;
 use phnxtypes::{
     identifiers::{Fqdn, QualifiedGroupId, QualifiedUserName, SafeTryInto},
-    time::TimeStamp,
+    time::{self, TimeStamp},
 };
 use rusqlite::{
     types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
     Connection, ToSql,
 };
 use serde::{Deserialize, Serialize};
-use tls_codec::DeserializeBytes;
 use uuid::Uuid;
 
+use crate::{groups::membership_history::MembershipChange, ConversationMessage};
+
 pub(crate) mod messages;
 pub(crate) mod persistence;
 
@@ -49,6 +50,16 @@ impl ConversationId {
     pub fn as_uuid(&self) -> Uuid {
         self.uuid
     }
+
+    /// The id of the conversation of the given group. A conversation keeps
+    /// its id when its group is upgraded, so the id only matches the group id
+    /// if the conversation was never upgraded.
+    pub(crate) fn of_group(connection: &Connection, group_id: &GroupId) -> anyhow::Result<Self> {
+        match Conversation::load_by_group_id(connection, group_id)? {
+            Some(conversation) => Ok(conversation.id()),
+            None => Ok(Self::try_from(group_id)?),
+        }
+    }
 }
 
 impl From<Uuid> for ConversationId {
@@ -85,6 +96,10 @@ pub struct Conversation {
     last_read: DateTime<Utc>,
     status: ConversationStatus,
     conversation_type: ConversationType,
+    // Sensitive conversations are protected from screenshots and their
+    // notifications don't show any content. Only known locally.
+    #[serde(default)]
+    sensitive: bool,
     attributes: ConversationAttributes,
 }
 
@@ -99,9 +114,10 @@ impl Conversation {
         let conversation = Conversation {
             id: ConversationId::try_from(&group_id)?,
             group_id,
-            last_read: Utc::now(),
+            last_read: time::now(),
             status: ConversationStatus::Active,
             conversation_type: ConversationType::UnconfirmedConnection(user_name),
+            sensitive: false,
             attributes,
         };
         Ok(conversation)
@@ -115,9 +131,10 @@ impl Conversation {
         Self {
             id,
             group_id,
-            last_read: Utc::now(),
+            last_read: time::now(),
             status: ConversationStatus::Active,
             conversation_type: ConversationType::Group,
+            sensitive: false,
             attributes,
         }
     }
@@ -142,6 +159,10 @@ impl Conversation {
         &self.attributes
     }
 
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
+    }
+
     pub(crate) fn owner_domain(&self) -> Fqdn {
         let qgid = QualifiedGroupId::try_from(self.group_id.clone()).unwrap();
         qgid.owning_domain().clone()
@@ -158,6 +179,27 @@ impl Conversation {
         Ok(())
     }
 
+    pub(crate) fn set_sensitive(
+        &mut self,
+        connection: &Connection,
+        sensitive: bool,
+    ) -> Result<(), rusqlite::Error> {
+        self.update_sensitive(connection, sensitive)?;
+        self.sensitive = sensitive;
+        Ok(())
+    }
+
+    /// Move the conversation to the successor of its upgraded group.
+    pub(crate) fn set_group_id(
+        &mut self,
+        connection: &Connection,
+        group_id: GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        self.update_group_id(connection, &group_id)?;
+        self.group_id = group_id;
+        Ok(())
+    }
+
     pub(crate) fn set_inactive(
         &mut self,
         connection: &Connection,
@@ -181,6 +223,16 @@ impl Conversation {
     }
 }
 
+/// Everything stored locally about a conversation, as exported on the user's
+/// request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub conversation: Conversation,
+    pub messages: Vec<ConversationMessage>,
+    /// Membership changes, most recent first.
+    pub membership_history: Vec<MembershipChange>,
+}
+
 #[derive(Eq, PartialEq, Debug, Clone, Hash, Serialize, Deserialize)]
 pub enum ConversationStatus {
     Inactive(InactiveConversation),
@@ -11,6 +11,11 @@ use crate::{
     Conversation, ConversationAttributes, ConversationId, ConversationStatus, ConversationType,
 };
 
+/// Whether a conversation is marked as sensitive. Added after the initial
+/// table.
+pub(crate) const CONVERSATION_SENSITIVE_COLUMN: &str =
+    "ALTER TABLE conversations ADD COLUMN sensitive INTEGER NOT NULL DEFAULT 0;";
+
 impl Storable for Conversation {
     const CREATE_TABLE_STATEMENT: &'static str = "
         CREATE TABLE IF NOT EXISTS conversations (
@@ -31,6 +36,7 @@ impl Storable for Conversation {
         let last_read = row.get(4)?;
         let status = row.get(5)?;
         let conversation_type = row.get(6)?;
+        let sensitive = row.get(7)?;
 
         Ok(Conversation {
             id,
@@ -38,6 +44,7 @@ impl Storable for Conversation {
             last_read,
             status,
             conversation_type,
+            sensitive,
             attributes: ConversationAttributes {
                 title: conversation_title,
                 conversation_picture_option,
@@ -52,7 +59,7 @@ impl Conversation {
         log::info!("With title: {:?}", self.attributes().title());
         let group_id = GroupIdRefWrapper::from(&self.group_id);
         connection.execute(
-            "INSERT INTO conversations (conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO conversations (conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type, sensitive) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
             params![
                 self.id,
                 self.attributes().title(),
@@ -61,6 +68,7 @@ impl Conversation {
                 self.last_read,
                 self.status(),
                 self.conversation_type(),
+                self.sensitive,
             ],
         )?;
         Ok(())
@@ -70,7 +78,7 @@ impl Conversation {
         connection: &Connection,
         conversation_id: &ConversationId,
     ) -> Result<Option<Conversation>, rusqlite::Error> {
-        let mut stmt = connection.prepare("SELECT conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type FROM conversations WHERE conversation_id = ?")?;
+        let mut stmt = connection.prepare("SELECT conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type, sensitive FROM conversations WHERE conversation_id = ?")?;
         stmt.query_row(params![conversation_id], Self::from_row)
             .optional()
     }
@@ -80,12 +88,12 @@ impl Conversation {
         group_id: &GroupId,
     ) -> Result<Option<Conversation>, rusqlite::Error> {
         let group_id = GroupIdRefWrapper::from(group_id);
-        let mut stmt = connection.prepare("SELECT conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type FROM conversations WHERE group_id = ?")?;
+        let mut stmt = connection.prepare("SELECT conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type, sensitive FROM conversations WHERE group_id = ?")?;
         stmt.query_row(params![group_id], Self::from_row).optional()
     }
 
     pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Conversation>, rusqlite::Error> {
-        let mut stmt = connection.prepare("SELECT conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type FROM conversations")?;
+        let mut stmt = connection.prepare("SELECT conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type, sensitive FROM conversations")?;
         let rows = stmt.query_map([], Self::from_row)?;
         rows.collect()
     }
@@ -102,6 +110,31 @@ impl Conversation {
         Ok(())
     }
 
+    pub(super) fn update_sensitive(
+        &self,
+        connection: &Connection,
+        sensitive: bool,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "UPDATE conversations SET sensitive = ? WHERE conversation_id = ?",
+            params![sensitive, self.id],
+        )?;
+        Ok(())
+    }
+
+    pub(super) fn update_group_id(
+        &self,
+        connection: &Connection,
+        group_id: &GroupId,
+    ) -> rusqlite::Result<()> {
+        let group_id = GroupIdRefWrapper::from(group_id);
+        connection.execute(
+            "UPDATE conversations SET group_id = ? WHERE conversation_id = ?",
+            params![group_id, self.id],
+        )?;
+        Ok(())
+    }
+
     pub(super) fn update_status(
         &self,
         connection: &Connection,
@@ -127,27 +160,56 @@ impl Conversation {
 
     /// Set the `last_read` marker of all conversations with the given
     /// [`ConversationId`]s to the given timestamps. This is used to mark all
-    /// messages up to this timestamp as read.
+    /// messages up to this timestamp as read. Markers never move backwards.
+    ///
+    /// Returns the conversations whose marker moved, with their new marker.
     pub(crate) fn mark_as_read<T: IntoIterator<Item = (ConversationId, DateTime<Utc>)>>(
         transaction: &mut Transaction,
         mark_as_read_data: T,
-    ) -> Result<(), rusqlite::Error> {
+    ) -> Result<Vec<(ConversationId, DateTime<Utc>)>, rusqlite::Error> {
+        let mut advanced = Vec::new();
         for (conversation_id, timestamp) in mark_as_read_data.into_iter() {
-            transaction.execute(
+            let updated = transaction.execute(
                 "UPDATE conversations 
-                 SET last_read = CASE 
-                                    WHEN last_read < :timestamp 
-                                    THEN :timestamp 
-                                    ELSE last_read 
-                                 END 
-                 WHERE conversation_id = :conversation_id",
+                 SET last_read = :timestamp 
+                 WHERE conversation_id = :conversation_id 
+                    AND last_read < :timestamp",
                 named_params! {
                     ":timestamp": timestamp,
                     ":conversation_id": conversation_id,
                 },
             )?;
+            if updated > 0 {
+                advanced.push((conversation_id, timestamp));
+            }
         }
-        Ok(())
+        Ok(advanced)
+    }
+
+    /// The timestamp of the latest message of each conversation with unread
+    /// messages. Marking the conversations as read up to these timestamps
+    /// marks all messages as read.
+    pub(crate) fn latest_unread_timestamps(
+        connection: &Connection,
+    ) -> Result<Vec<(ConversationId, DateTime<Utc>)>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT 
+                cm.conversation_id, MAX(cm.timestamp) 
+            FROM 
+                conversation_messages cm 
+            JOIN 
+                conversations c 
+            ON 
+                c.conversation_id = cm.conversation_id 
+            WHERE 
+                cm.timestamp > c.last_read 
+            GROUP BY 
+                cm.conversation_id",
+        )?;
+        let timestamps = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(timestamps)
     }
 
     pub(crate) fn global_unread_message_count(
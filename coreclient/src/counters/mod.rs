@@ -0,0 +1,88 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Counters of the chats shown in chat lists and badges, e.g. the number of
+//! unread messages.
+//!
+//! The counters are kept in a table that triggers update whenever messages,
+//! read markers or activities change, so that loading the counters of all
+//! chats is a single query.
+
+use std::{collections::HashMap, ops::AddAssign};
+
+use crate::ConversationId;
+
+pub(crate) mod persistence;
+
+/// Counters of a chat, or of all chats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ChatCounters {
+    /// Content messages newer than the read marker of the chat
+    pub unread: u32,
+    /// Unread messages that mention the user
+    pub unread_mentions: u32,
+    /// Messages of the user that are waiting to be sent
+    pub pending: u32,
+    /// Messages of the user that couldn't be sent
+    pub failed: u32,
+}
+
+impl ChatCounters {
+    pub fn is_zero(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl AddAssign for ChatCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.unread += other.unread;
+        self.unread_mentions += other.unread_mentions;
+        self.pending += other.pending;
+        self.failed += other.failed;
+    }
+}
+
+/// Counters of all chats of the user.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Only chats with a non-zero counter
+    chats: HashMap<ConversationId, ChatCounters>,
+    total: ChatCounters,
+}
+
+impl Counters {
+    /// The counters of the given chat.
+    pub fn chat(&self, conversation_id: ConversationId) -> ChatCounters {
+        self.chats
+            .get(&conversation_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The chats with a non-zero counter and their counters.
+    pub fn chats(&self) -> impl Iterator<Item = (ConversationId, ChatCounters)> + '_ {
+        self.chats
+            .iter()
+            .map(|(conversation_id, counters)| (*conversation_id, *counters))
+    }
+
+    /// The sum of the counters of all chats, e.g. for the app badge.
+    pub fn total(&self) -> ChatCounters {
+        self.total
+    }
+}
+
+impl FromIterator<(ConversationId, ChatCounters)> for Counters {
+    fn from_iter<T: IntoIterator<Item = (ConversationId, ChatCounters)>>(iter: T) -> Self {
+        let mut counters = Self::default();
+        for (conversation_id, chat_counters) in iter {
+            if chat_counters.is_zero() {
+                continue;
+            }
+            counters.total += chat_counters;
+            *counters.chats.entry(conversation_id).or_default() += chat_counters;
+        }
+        counters
+    }
+}
@@ -0,0 +1,333 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use rusqlite::Connection;
+
+use crate::ConversationId;
+
+use super::{ChatCounters, Counters};
+
+pub(crate) const CONVERSATION_COUNTERS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS conversation_counters (
+        conversation_id BLOB PRIMARY KEY,
+        unread INTEGER NOT NULL DEFAULT 0,
+        unread_mentions INTEGER NOT NULL DEFAULT 0,
+        unsent INTEGER NOT NULL DEFAULT 0,
+        failed INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS activities_message_id ON activities (message_id);";
+
+/// Counts all counters of the conversations `c` from scratch. Unsent
+/// messages include the failed ones.
+const COUNT_CONVERSATION_COUNTERS: &str = "
+    SELECT
+        c.conversation_id,
+        (SELECT COUNT(*) FROM conversation_messages cm
+            WHERE cm.conversation_id = c.conversation_id
+            AND cm.sender != 'system' AND cm.timestamp > c.last_read),
+        (SELECT COUNT(*) FROM activities a
+            JOIN conversation_messages cm ON cm.message_id = a.message_id
+            WHERE a.kind = 'mention' AND cm.conversation_id = c.conversation_id
+            AND cm.timestamp > c.last_read),
+        (SELECT COUNT(*) FROM conversation_messages cm
+            WHERE cm.conversation_id = c.conversation_id
+            AND cm.sender != 'system' AND cm.sent = 0),
+        (SELECT COUNT(*) FROM activities a
+            JOIN conversation_messages cm ON cm.message_id = a.message_id
+            WHERE a.kind = 'failed_delivery' AND cm.conversation_id = c.conversation_id
+            AND cm.sent = 0)
+    FROM conversations c";
+
+/// Counts the counters of all existing conversations.
+pub(crate) fn backfill_conversation_counters() -> String {
+    format!(
+        "INSERT OR REPLACE INTO conversation_counters
+            (conversation_id, unread, unread_mentions, unsent, failed)
+        {COUNT_CONVERSATION_COUNTERS};"
+    )
+}
+
+/// Triggers that keep the counters up to date.
+///
+/// New, changed and deleted messages and activities adjust the counters of
+/// their conversation. Only moving the read marker of a conversation counts
+/// the conversation's counters from scratch.
+pub(crate) fn conversation_counters_triggers() -> String {
+    const LAST_READ: &str = "SELECT last_read FROM conversations WHERE conversation_id";
+    format!(
+        "DROP TRIGGER IF EXISTS conversation_counters_on_conversation_insert;
+        CREATE TRIGGER conversation_counters_on_conversation_insert
+        AFTER INSERT ON conversations
+        FOR EACH ROW
+        BEGIN
+            INSERT OR REPLACE INTO conversation_counters
+                (conversation_id, unread, unread_mentions, unsent, failed)
+            {COUNT_CONVERSATION_COUNTERS} WHERE c.conversation_id = NEW.conversation_id;
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_read;
+        CREATE TRIGGER conversation_counters_on_read
+        AFTER UPDATE OF last_read ON conversations
+        FOR EACH ROW WHEN NEW.last_read != OLD.last_read
+        BEGIN
+            INSERT OR REPLACE INTO conversation_counters
+                (conversation_id, unread, unread_mentions, unsent, failed)
+            {COUNT_CONVERSATION_COUNTERS} WHERE c.conversation_id = NEW.conversation_id;
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_conversation_delete;
+        CREATE TRIGGER conversation_counters_on_conversation_delete
+        AFTER DELETE ON conversations
+        FOR EACH ROW
+        BEGIN
+            DELETE FROM conversation_counters WHERE conversation_id = OLD.conversation_id;
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_message_insert;
+        CREATE TRIGGER conversation_counters_on_message_insert
+        AFTER INSERT ON conversation_messages
+        FOR EACH ROW WHEN NEW.sender != 'system'
+        BEGIN
+            UPDATE conversation_counters SET
+                unread = unread + (NEW.timestamp > ({LAST_READ} = NEW.conversation_id)),
+                unsent = unsent + (NEW.sent = 0)
+            WHERE conversation_id = NEW.conversation_id;
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_message_update;
+        CREATE TRIGGER conversation_counters_on_message_update
+        AFTER UPDATE OF timestamp, sent ON conversation_messages
+        FOR EACH ROW WHEN NEW.sender != 'system'
+        BEGIN
+            UPDATE conversation_counters SET
+                unread = unread
+                    - (OLD.timestamp > ({LAST_READ} = OLD.conversation_id))
+                    + (NEW.timestamp > ({LAST_READ} = NEW.conversation_id)),
+                unread_mentions = unread_mentions
+                    + ((NEW.timestamp > ({LAST_READ} = NEW.conversation_id))
+                        - (OLD.timestamp > ({LAST_READ} = OLD.conversation_id)))
+                    * (SELECT COUNT(*) FROM activities
+                        WHERE kind = 'mention' AND message_id = NEW.message_id),
+                unsent = unsent - (OLD.sent = 0) + (NEW.sent = 0),
+                failed = failed + ((NEW.sent = 0) - (OLD.sent = 0))
+                    * (SELECT COUNT(*) FROM activities
+                        WHERE kind = 'failed_delivery' AND message_id = NEW.message_id)
+            WHERE conversation_id = NEW.conversation_id;
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_message_delete;
+        CREATE TRIGGER conversation_counters_on_message_delete
+        AFTER DELETE ON conversation_messages
+        FOR EACH ROW WHEN OLD.sender != 'system'
+        BEGIN
+            UPDATE conversation_counters SET
+                unread = unread - (OLD.timestamp > ({LAST_READ} = OLD.conversation_id)),
+                unread_mentions = unread_mentions
+                    - (OLD.timestamp > ({LAST_READ} = OLD.conversation_id))
+                    * (SELECT COUNT(*) FROM activities
+                        WHERE kind = 'mention' AND message_id = OLD.message_id),
+                unsent = unsent - (OLD.sent = 0),
+                failed = failed - (OLD.sent = 0)
+                    * (SELECT COUNT(*) FROM activities
+                        WHERE kind = 'failed_delivery' AND message_id = OLD.message_id)
+            WHERE conversation_id = OLD.conversation_id;
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_activity_insert;
+        CREATE TRIGGER conversation_counters_on_activity_insert
+        AFTER INSERT ON activities
+        FOR EACH ROW WHEN NEW.kind IN ('mention', 'failed_delivery')
+        BEGIN
+            UPDATE conversation_counters SET
+                unread_mentions = unread_mentions + (NEW.kind = 'mention'
+                    AND (SELECT cm.timestamp > c.last_read FROM conversation_messages cm
+                        JOIN conversations c ON c.conversation_id = cm.conversation_id
+                        WHERE cm.message_id = NEW.message_id)),
+                failed = failed + (NEW.kind = 'failed_delivery'
+                    AND (SELECT sent = 0 FROM conversation_messages
+                        WHERE message_id = NEW.message_id))
+            WHERE conversation_id =
+                (SELECT conversation_id FROM conversation_messages WHERE message_id = NEW.message_id);
+        END;
+
+        DROP TRIGGER IF EXISTS conversation_counters_on_activity_delete;
+        CREATE TRIGGER conversation_counters_on_activity_delete
+        AFTER DELETE ON activities
+        FOR EACH ROW WHEN OLD.kind IN ('mention', 'failed_delivery')
+        BEGIN
+            UPDATE conversation_counters SET
+                unread_mentions = unread_mentions - (OLD.kind = 'mention'
+                    AND (SELECT cm.timestamp > c.last_read FROM conversation_messages cm
+                        JOIN conversations c ON c.conversation_id = cm.conversation_id
+                        WHERE cm.message_id = OLD.message_id)),
+                failed = failed - (OLD.kind = 'failed_delivery'
+                    AND (SELECT sent = 0 FROM conversation_messages
+                        WHERE message_id = OLD.message_id))
+            WHERE conversation_id =
+                (SELECT conversation_id FROM conversation_messages WHERE message_id = OLD.message_id);
+        END;"
+    )
+}
+
+impl Counters {
+    pub(crate) fn load(connection: &Connection) -> Result<Self, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, unread, unread_mentions, unsent, failed
+            FROM conversation_counters",
+        )?;
+        let counters = statement
+            .query_map([], |row| {
+                let conversation_id: ConversationId = row.get(0)?;
+                let unsent: u32 = row.get(3)?;
+                let failed: u32 = row.get(4)?;
+                let counters = ChatCounters {
+                    unread: row.get(1)?,
+                    unread_mentions: row.get(2)?,
+                    pending: unsent.saturating_sub(failed),
+                    failed,
+                };
+                Ok((conversation_id, counters))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls::prelude::GroupId;
+    use phnxtypes::{
+        identifiers::{QualifiedGroupId, QualifiedUserName, SafeTryInto},
+        time::TimeStamp,
+    };
+    use uuid::Uuid;
+
+    use crate::{
+        activity::{Activity, ActivityKind},
+        conversations::messages::TimestampedMessage,
+        utils::migration::run_migrations,
+        Conversation, ConversationAttributes, ConversationMessage, MimiContent,
+    };
+
+    use super::*;
+
+    /// The counters as counted from scratch
+    fn counted(connection: &Connection) -> Counters {
+        let mut statement = connection.prepare(COUNT_CONVERSATION_COUNTERS).unwrap();
+        statement
+            .query_map([], |row| {
+                let unsent: u32 = row.get(3)?;
+                let failed: u32 = row.get(4)?;
+                Ok((
+                    row.get(0)?,
+                    ChatCounters {
+                        unread: row.get(1)?,
+                        unread_mentions: row.get(2)?,
+                        pending: unsent - failed,
+                        failed,
+                    },
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn counters_are_maintained_by_triggers() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let group_id = GroupId::from(QualifiedGroupId::new(Uuid::new_v4(), alice.domain()));
+        let conversation = Conversation::new_group_conversation(
+            group_id,
+            ConversationAttributes::new("Group".to_owned(), None),
+        );
+        conversation.store(&connection).unwrap();
+        let conversation_id = conversation.id();
+        let content = || MimiContent::simple_markdown_message(alice.domain(), "Hi".to_owned());
+
+        let received: Vec<_> = (0..3)
+            .map(|_| {
+                let message = ConversationMessage::from_timestamped_message(
+                    conversation_id,
+                    TimestampedMessage::from_content(content(), TimeStamp::now(), bob.clone()),
+                );
+                message.store(&connection).unwrap();
+                message
+            })
+            .collect();
+        Activity::new(
+            ActivityKind::Mention {
+                sender: bob.clone(),
+                message_id: received[2].id(),
+            },
+            Some(conversation_id),
+        )
+        .store(&connection)
+        .unwrap();
+        let mut unsent: Vec<_> = (0..2)
+            .map(|_| {
+                let message = ConversationMessage::new_unsent_message(
+                    alice.to_string(),
+                    conversation_id,
+                    content(),
+                );
+                message.store(&connection).unwrap();
+                message
+            })
+            .collect();
+        Activity::new(
+            ActivityKind::FailedDelivery {
+                message_id: unsent[0].id(),
+            },
+            Some(conversation_id),
+        )
+        .store(&connection)
+        .unwrap();
+
+        let counters = Counters::load(&connection).unwrap();
+        assert_eq!(
+            counters.chat(conversation_id),
+            ChatCounters {
+                unread: 5,
+                unread_mentions: 1,
+                pending: 1,
+                failed: 1,
+            }
+        );
+        assert_eq!(counters.total(), counters.chat(conversation_id));
+        assert_eq!(counters, counted(&connection));
+
+        // Sending the failed message clears the failure.
+        unsent[0]
+            .mark_as_sent(&connection, TimeStamp::now())
+            .unwrap();
+        assert_eq!(Counters::load(&connection).unwrap(), counted(&connection));
+        assert_eq!(
+            Counters::load(&connection)
+                .unwrap()
+                .chat(conversation_id)
+                .failed,
+            0
+        );
+
+        // Reading up to the mention clears it.
+        let mut transaction = connection.transaction().unwrap();
+        Conversation::mark_as_read(
+            &mut transaction,
+            [(conversation_id, received[2].timestamp())],
+        )
+        .unwrap();
+        transaction.commit().unwrap();
+        let counters = Counters::load(&connection).unwrap();
+        assert_eq!(counters, counted(&connection));
+        assert_eq!(counters.chat(conversation_id).unread_mentions, 0);
+
+        Conversation::delete(&connection, conversation_id).unwrap();
+        assert_eq!(Counters::load(&connection).unwrap(), Counters::default());
+    }
+}
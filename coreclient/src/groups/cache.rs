@@ -0,0 +1,340 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! In-memory LRU cache of loaded groups.
+//!
+//! Loading a [`Group`] hydrates the full [`openmls::group::MlsGroup`] from
+//! SQLite, which dominates the latency of sending and receiving messages in
+//! busy conversations. The cache keeps recently used groups in memory.
+//!
+//! Groups are checked out of the cache while they are used and checked back
+//! in after their changes were committed to the database. Since groups are
+//! persisted on every change, the cache never holds state that is not in the
+//! database. A group is only cached if nobody else used it while it was
+//! checked out. If the database is modified by another connection (e.g. by a
+//! notification service extension), the whole cache is invalidated.
+
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use openmls::group::GroupId;
+use rusqlite::{Connection, Transaction};
+
+use super::Group;
+
+pub(crate) const DEFAULT_GROUP_CACHE_CAPACITY: usize = 32;
+
+#[derive(Clone)]
+pub(crate) struct GroupCache {
+    inner: Arc<Mutex<GroupCacheInner>>,
+}
+
+struct GroupCacheInner {
+    slots: CacheSlots<Group>,
+    /// Last observed `PRAGMA data_version`, which changes whenever another
+    /// connection commits to the database
+    data_version: Option<i64>,
+}
+
+impl Default for GroupCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_GROUP_CACHE_CAPACITY)
+    }
+}
+
+impl GroupCache {
+    /// A cache holding at most `capacity` groups. A capacity of 0 disables
+    /// the cache.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let inner = GroupCacheInner {
+            slots: CacheSlots::new(capacity),
+            data_version: None,
+        };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.lock().slots.set_capacity(capacity);
+    }
+
+    /// Check out the group with the given id, loading it from the database if
+    /// it is not cached.
+    pub(crate) fn load(
+        &self,
+        connection: &Connection,
+        group_id: &GroupId,
+    ) -> Result<Option<CachedGroup>, rusqlite::Error> {
+        let data_version = data_version(connection)?;
+        let cached = {
+            let mut inner = self.lock();
+            inner.observe_data_version(data_version);
+            inner.slots.check_out(group_id)
+        };
+        let loaded = match cached {
+            Some(group) => Ok(Some(group)),
+            None => Group::load(connection, group_id),
+        };
+        let group = match loaded {
+            Ok(Some(group)) => group,
+            Ok(None) => {
+                self.lock().slots.release(group_id, None);
+                return Ok(None);
+            }
+            Err(error) => {
+                self.lock().slots.release(group_id, None);
+                return Err(error);
+            }
+        };
+        Ok(Some(CachedGroup {
+            group: Some(group),
+            cache: self.clone(),
+        }))
+    }
+
+    /// Store the given new group. See [`Group::store`].
+    pub(crate) fn store(
+        &self,
+        connection: &Connection,
+        group: &Group,
+    ) -> Result<(), rusqlite::Error> {
+        group.store(connection)?;
+        self.invalidate(group.group_id());
+        Ok(())
+    }
+
+    /// Delete the group with the given id. See [`Group::delete_from_db`].
+    pub(crate) fn delete(
+        &self,
+        transaction: &mut Transaction,
+        group_id: &GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        Group::delete_from_db(transaction, group_id)?;
+        self.invalidate(group_id);
+        Ok(())
+    }
+
+    /// Drop the group with the given id from the cache. Groups that are
+    /// currently checked out are not checked back in.
+    pub(crate) fn invalidate(&self, group_id: &GroupId) {
+        self.lock().slots.invalidate(group_id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, GroupCacheInner> {
+        // The cache is always consistent, even if a thread panicked while
+        // holding the lock.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl GroupCacheInner {
+    fn observe_data_version(&mut self, data_version: i64) {
+        if self.data_version != Some(data_version) {
+            if self.data_version.is_some() {
+                log::debug!("Database was modified externally, invalidating group cache");
+            }
+            self.slots.clear();
+            self.data_version = Some(data_version);
+        }
+    }
+}
+
+fn data_version(connection: &Connection) -> Result<i64, rusqlite::Error> {
+    connection.query_row("PRAGMA data_version", [], |row| row.get(0))
+}
+
+/// A group checked out of the [`GroupCache`].
+///
+/// Must be checked back in with [`Self::check_in`] after all changes to the
+/// group were committed. Dropping it without checking it in removes the group
+/// from the cache.
+pub(crate) struct CachedGroup {
+    group: Option<Group>,
+    cache: GroupCache,
+}
+
+impl CachedGroup {
+    /// Return the group to the cache. Must be called after the changes to the
+    /// group were committed and before the connection is released.
+    pub(crate) fn check_in(mut self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        let Some(group) = self.group.take() else {
+            return Ok(());
+        };
+        let data_version = data_version(connection)?;
+        let mut inner = self.cache.lock();
+        let externally_modified = inner.data_version != Some(data_version);
+        inner.observe_data_version(data_version);
+        let group_id = group.group_id().clone();
+        let group = (!externally_modified).then_some(group);
+        inner.slots.release(&group_id, group);
+        Ok(())
+    }
+}
+
+impl Deref for CachedGroup {
+    type Target = Group;
+
+    fn deref(&self) -> &Self::Target {
+        self.group
+            .as_ref()
+            .expect("group is only taken when checked in")
+    }
+}
+
+impl DerefMut for CachedGroup {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.group
+            .as_mut()
+            .expect("group is only taken when checked in")
+    }
+}
+
+impl Drop for CachedGroup {
+    fn drop(&mut self) {
+        if let Some(group) = self.group.take() {
+            self.cache.lock().slots.release(group.group_id(), None);
+        }
+    }
+}
+
+/// Bookkeeping of cached and checked out values
+struct CacheSlots<V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<GroupId, (u64, V)>,
+    checkouts: HashMap<GroupId, Checkouts>,
+}
+
+#[derive(Default)]
+struct Checkouts {
+    count: usize,
+    /// Whether the value was used concurrently or invalidated since it was
+    /// checked out. In that case, the checked out values might not reflect
+    /// the state in the database.
+    contended: bool,
+}
+
+impl<V> CacheSlots<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            checkouts: HashMap::new(),
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Takes the cached value, if any. Every call must be followed by a call
+    /// to [`Self::release`].
+    fn check_out(&mut self, id: &GroupId) -> Option<V> {
+        let checkouts = self.checkouts.entry(id.clone()).or_default();
+        checkouts.count += 1;
+        if checkouts.count > 1 {
+            checkouts.contended = true;
+        }
+        self.entries.remove(id).map(|(_, value)| value)
+    }
+
+    /// Ends a checkout, caching the given value if there was no contention.
+    fn release(&mut self, id: &GroupId, value: Option<V>) {
+        let Some(checkouts) = self.checkouts.get_mut(id) else {
+            return;
+        };
+        checkouts.count -= 1;
+        let contended = checkouts.contended;
+        if checkouts.count == 0 {
+            self.checkouts.remove(id);
+        }
+        if let Some(value) = value.filter(|_| !contended) {
+            self.tick += 1;
+            self.entries.insert(id.clone(), (self.tick, value));
+            self.evict();
+        }
+    }
+
+    fn invalidate(&mut self, id: &GroupId) {
+        self.entries.remove(id);
+        if let Some(checkouts) = self.checkouts.get_mut(id) {
+            checkouts.contended = true;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        for checkouts in self.checkouts.values_mut() {
+            checkouts.contended = true;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some(least_recently_used) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&least_recently_used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: u8) -> GroupId {
+        GroupId::from_slice(&[id])
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut slots = CacheSlots::new(2);
+        for i in 0..3 {
+            assert_eq!(slots.check_out(&id(i)), None);
+            slots.release(&id(i), Some(i));
+        }
+        assert_eq!(slots.check_out(&id(0)), None);
+        slots.release(&id(0), None);
+        assert_eq!(slots.check_out(&id(1)), Some(1));
+        slots.release(&id(1), Some(1));
+        assert_eq!(slots.check_out(&id(2)), Some(2));
+        slots.release(&id(2), None);
+
+        slots.set_capacity(0);
+        assert!(slots.entries.is_empty());
+    }
+
+    #[test]
+    fn contended_values_are_not_cached() {
+        let mut slots = CacheSlots::new(2);
+        assert_eq!(slots.check_out(&id(0)), None);
+        assert_eq!(slots.check_out(&id(0)), None);
+        slots.release(&id(0), Some(1));
+        slots.release(&id(0), Some(2));
+        assert_eq!(slots.check_out(&id(0)), None);
+        slots.release(&id(0), Some(3));
+        assert_eq!(slots.check_out(&id(0)), Some(3));
+
+        // Invalidation while checked out
+        slots.invalidate(&id(0));
+        slots.release(&id(0), Some(4));
+        assert_eq!(slots.check_out(&id(0)), None);
+        slots.release(&id(0), None);
+        assert!(slots.checkouts.is_empty());
+    }
+}
@@ -63,6 +63,8 @@ impl StorableClientCredential {
         Self { client_credential }
     }
 
+    /// Decrypts and verifies the given client credential. If the credential
+    /// has been verified and stored before, it is loaded instead.
     pub(super) async fn decrypt_and_verify(
         connection: SqliteConnection,
         api_clients: &ApiClients,
@@ -70,11 +72,57 @@ impl StorableClientCredential {
         ecc: EncryptedClientCredential,
     ) -> Result<Self> {
         let verifiable_credential = VerifiableClientCredential::decrypt(ear_key, &ecc)?;
+        let locked_connection = connection.lock().await;
+        let stored_credential =
+            Self::load(&locked_connection, &verifiable_credential.fingerprint()?)?;
+        drop(locked_connection);
+        if let Some(client_credential) = stored_credential {
+            return Ok(client_credential);
+        }
         let client_credential =
             AsCredentials::verify_client_credential(connection, api_clients, verifiable_credential)
                 .await?;
         Ok(Self { client_credential })
     }
+
+    /// Verifies the given client credentials in bulk and returns them in the
+    /// given order. Credentials that have been verified and stored before are
+    /// loaded instead.
+    async fn verify_all(
+        connection: SqliteConnection,
+        api_clients: &ApiClients,
+        verifiable_credentials: Vec<VerifiableClientCredential>,
+    ) -> Result<Vec<Self>> {
+        let mut stored_credentials = Vec::with_capacity(verifiable_credentials.len());
+        let mut unverified_credentials = Vec::new();
+        let locked_connection = connection.lock().await;
+        for verifiable_credential in verifiable_credentials {
+            let stored_credential =
+                Self::load(&locked_connection, &verifiable_credential.fingerprint()?)?;
+            if stored_credential.is_none() {
+                unverified_credentials.push(verifiable_credential);
+            }
+            stored_credentials.push(stored_credential);
+        }
+        drop(locked_connection);
+
+        let mut verified_credentials = AsCredentials::verify_client_credentials(
+            connection,
+            api_clients,
+            unverified_credentials,
+        )
+        .await?
+        .into_iter()
+        .map(Self::new);
+        stored_credentials
+            .into_iter()
+            .map(|stored_credential| {
+                stored_credential
+                    .or_else(|| verified_credentials.next())
+                    .ok_or_else(|| anyhow!("Missing verified client credential"))
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -160,6 +208,9 @@ impl ClientAuthInfo {
     /// Decrypt and verify the given encrypted client auth info. The encrypted
     /// client auth info needs to be given s.t. the index of the client in the
     /// group corresponds to the index in the iterator.
+    ///
+    /// The client credentials are verified in bulk, s.t. each AS is queried at
+    /// most once, even for large groups.
     pub(super) async fn decrypt_and_verify_all(
         connection: SqliteConnection,
         api_clients: &ApiClients,
@@ -173,20 +224,35 @@ impl ClientAuthInfo {
             ),
         >,
     ) -> Result<Vec<Self>> {
-        let mut client_information = Vec::new();
-        for (leaf_index, encrypted_client_info) in encrypted_client_information {
-            let client_auth_info = Self::decrypt_and_verify(
-                connection.clone(),
-                api_clients,
-                group_id,
-                ear_key,
-                wrapper_key,
-                encrypted_client_info,
-                leaf_index,
-            )
-            .await?;
-            client_information.push(client_auth_info);
+        let mut verifiable_credentials = Vec::new();
+        let mut leaf_infos = Vec::new();
+        for (leaf_index, (ecc, esek)) in encrypted_client_information {
+            verifiable_credentials.push(VerifiableClientCredential::decrypt(ear_key, &ecc)?);
+            let signature_ear_key = SignatureEarKey::decrypt(wrapper_key, &esek)?;
+            leaf_infos.push((leaf_index, signature_ear_key));
         }
+
+        let client_credentials =
+            StorableClientCredential::verify_all(connection, api_clients, verifiable_credentials)
+                .await?;
+
+        let client_information = client_credentials
+            .into_iter()
+            .zip(leaf_infos)
+            .map(|(client_credential, (leaf_index, signature_ear_key))| {
+                let group_membership = GroupMembership::new(
+                    client_credential.identity(),
+                    group_id.clone(),
+                    leaf_index,
+                    signature_ear_key,
+                    client_credential.fingerprint()?,
+                );
+                Ok(ClientAuthInfo {
+                    client_credential,
+                    group_membership,
+                })
+            })
+            .collect::<Result<_>>()?;
         Ok(client_information)
     }
 
@@ -209,7 +275,7 @@ impl ClientAuthInfo {
             group_id.clone(),
             leaf_index,
             signature_ear_key,
-            client_credential.fingerprint(),
+            client_credential.fingerprint()?,
         );
         let client_auth_info = ClientAuthInfo {
             client_credential,
@@ -237,6 +303,14 @@ impl ClientAuthInfo {
         Ok(())
     }
 
+    /// Store the client information proposed by an update proposal until the
+    /// proposal is committed.
+    pub(super) fn store_proposed_update(&self, connection: &Connection) -> Result<()> {
+        self.client_credential.store(connection)?;
+        self.group_membership.store_proposed_update(connection)?;
+        Ok(())
+    }
+
     pub(super) fn stage_add(&self, connection: &Connection) -> Result<()> {
         self.client_credential.store(connection)?;
         self.group_membership.stage_add(connection)?;
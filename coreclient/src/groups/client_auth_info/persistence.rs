@@ -14,6 +14,21 @@ use crate::utils::persistence::{GroupIdRefWrapper, GroupIdWrapper, Storable};
 
 use super::{GroupMembership, StorableClientCredential};
 
+/// Memberships of clients that proposed to update their leaf. They are staged
+/// once an admin commits the proposal and discarded with the epoch otherwise.
+pub(crate) const PROPOSED_CLIENT_UPDATES_TABLE: &str = "
+        CREATE TABLE IF NOT EXISTS proposed_client_updates (
+            group_id BLOB NOT NULL,
+            leaf_index INTEGER NOT NULL,
+            client_credential_fingerprint BLOB NOT NULL,
+            client_uuid BLOB NOT NULL,
+            user_name TEXT NOT NULL,
+            signature_ear_key BLOB NOT NULL,
+            PRIMARY KEY (group_id, leaf_index),
+            FOREIGN KEY (group_id) REFERENCES groups(group_id) ON DELETE CASCADE,
+            FOREIGN KEY (client_credential_fingerprint) REFERENCES client_credentials(fingerprint)
+        );";
+
 impl Storable for StorableClientCredential {
     const CREATE_TABLE_STATEMENT: &'static str = "CREATE TABLE IF NOT EXISTS client_credentials (
                 fingerprint BLOB PRIMARY KEY,
@@ -58,7 +73,9 @@ impl StorableClientCredential {
 
     /// Stores the client credential in the database if it does not already exist.
     pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
-        let fingerprint = self.fingerprint();
+        let fingerprint = self
+            .fingerprint()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
         connection.execute(
             "INSERT OR IGNORE INTO client_credentials (fingerprint, client_id, client_credential) VALUES (?, ?, ?)",
             params![
@@ -117,6 +134,87 @@ impl GroupMembership {
         Ok(())
     }
 
+    /// Discard all staged group memberships for the given group id, e.g.
+    /// because the commit that staged them was discarded.
+    pub(in crate::groups) fn discard_staged_for_group(
+        connection: &Connection,
+        group_id: &GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(group_id);
+        connection.execute(
+            "DELETE FROM group_membership WHERE group_id = ? AND status IN ('staged_update', 'staged_add')",
+            params![group_id],
+        )?;
+        connection.execute(
+            "UPDATE group_membership SET status = 'merged' WHERE group_id = ? AND status = 'staged_removal'",
+            params![group_id],
+        )?;
+        Ok(())
+    }
+
+    /// Store the membership proposed by an update proposal of the client. It
+    /// replaces an earlier proposal of the client.
+    pub(super) fn store_proposed_update(
+        &self,
+        connection: &Connection,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO proposed_client_updates (group_id, leaf_index, client_credential_fingerprint, client_uuid, user_name, signature_ear_key) VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                self.sql_group_id(),
+                self.leaf_index.usize() as i64,
+                self.client_credential_fingerprint,
+                self.client_id.client_id(),
+                self.client_id.user_name(),
+                self.signature_ear_key.as_ref(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Stage the membership proposed by the client at the given index, since
+    /// its update proposal is committed. Returns `false` if the client didn't
+    /// propose an update.
+    pub(in crate::groups) fn stage_proposed_update(
+        connection: &Connection,
+        group_id: &GroupId,
+        leaf_index: LeafNodeIndex,
+    ) -> Result<bool, rusqlite::Error> {
+        let staged = connection.execute(
+            "INSERT INTO group_membership (client_uuid, user_name, group_id, leaf_index, signature_ear_key, client_credential_fingerprint, status)
+            SELECT client_uuid, user_name, group_id, leaf_index, signature_ear_key, client_credential_fingerprint, 'staged_update'
+            FROM proposed_client_updates WHERE group_id = ? AND leaf_index = ?",
+            params![GroupIdRefWrapper::from(group_id), leaf_index.u32()],
+        )?;
+        Ok(staged > 0)
+    }
+
+    /// Delete the memberships proposed in the given group, e.g. because the
+    /// epoch of the proposals ended.
+    pub(in crate::groups) fn delete_proposed_updates(
+        connection: &Connection,
+        group_id: &GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM proposed_client_updates WHERE group_id = ?",
+            params![GroupIdRefWrapper::from(group_id)],
+        )?;
+        Ok(())
+    }
+
+    /// Delete all group memberships for the given group id, e.g. because
+    /// they are recovered from the DS.
+    pub(in crate::groups) fn delete_for_group(
+        connection: &Connection,
+        group_id: &GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM group_membership WHERE group_id = ?",
+            params![GroupIdRefWrapper::from(group_id)],
+        )?;
+        Ok(())
+    }
+
     pub(in crate::groups) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
         connection.execute(
             "INSERT OR IGNORE INTO group_membership (client_uuid, user_name, group_id, leaf_index, signature_ear_key, client_credential_fingerprint, status) VALUES (?, ?, ?, ?, ?, ?, 'merged')",
@@ -174,6 +272,20 @@ impl GroupMembership {
         Ok(())
     }
 
+    /// Revert the staged removal of the group member at the given index, e.g.
+    /// because the member is only moved to a new leaf.
+    pub(in crate::groups) fn unstage_removal(
+        connection: &Connection,
+        group_id: &GroupId,
+        removed_index: LeafNodeIndex,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE group_membership SET status = 'merged' WHERE group_id = ? AND leaf_index = ? AND status = 'staged_removal'",
+            params![GroupIdRefWrapper::from(group_id), removed_index.u32()],
+        )?;
+        Ok(())
+    }
+
     /// Load the [`GroupMembership`] for the given group_id and leaf_index from the
     /// database. Only loads merged group memberships. Use `load_staged` to load
     /// an staged group membership.
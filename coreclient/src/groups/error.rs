@@ -7,7 +7,10 @@ use openmls::group::{
     AddMembersError, CreateMessageError, MergeCommitError, MergePendingCommitError,
     MlsGroupStateError, ProcessMessageError, WelcomeError,
 };
-use phnxtypes::crypto::errors::DecryptionError;
+use phnxtypes::{
+    codec,
+    crypto::{errors::DecryptionError, signatures::traits::SignatureVerificationError},
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -27,8 +30,6 @@ pub enum GroupOperationError {
     AddMembersError(#[from] AddMembersError<rusqlite::Error>),
     #[error(transparent)]
     MergePendingCommitError(#[from] MergePendingCommitError<rusqlite::Error>),
-    #[error("Missing key package in key store")]
-    MissingKeyPackage,
     #[error(transparent)]
     JoinerInfoDecryptionError(#[from] DecryptionError),
     #[error(transparent)]
@@ -36,3 +37,45 @@ pub enum GroupOperationError {
     #[error(transparent)]
     AssistedMessageError(#[from] AssistedMessageError),
 }
+
+/// Reasons why processing a welcome bundle received from the QS can fail.
+///
+/// Welcome bundles are remote input, so every failure mode is surfaced as an
+/// error instead of a panic.
+#[derive(Error, Debug)]
+pub enum WelcomeProcessingError {
+    #[error("Welcome bundle is too large ({size} bytes, at most {max} bytes allowed)")]
+    BundleTooLarge { size: usize, max: usize },
+    #[error("Missing key package in key store")]
+    MissingKeyPackage,
+    #[error("Could not decrypt joiner info: {0}")]
+    JoinerInfoDecryption(DecryptionError),
+    #[error("Invalid welcome: {0}")]
+    InvalidWelcome(#[from] WelcomeError<rusqlite::Error>),
+    #[error("Welcome is for a group that is still active")]
+    GroupStillActive,
+    #[error("Could not decrypt welcome attribution info: {0}")]
+    AttributionInfoDecryption(DecryptionError),
+    #[error("Unknown sender of welcome")]
+    UnknownSender,
+    #[error("Invalid signature of welcome attribution info: {0}")]
+    InvalidAttributionSignature(#[from] SignatureVerificationError),
+    #[error("Group has {members} members, but joiner info contains {infos} client infos")]
+    ClientInformationMismatch { members: usize, infos: usize },
+    #[error("Invalid client information: {0}")]
+    InvalidClientInformation(anyhow::Error),
+    #[error("Group has no own leaf node")]
+    MissingOwnLeaf,
+    #[error("Missing leaf keys for own leaf node")]
+    MissingLeafKeys,
+    #[error("Group has no group data")]
+    MissingGroupData,
+    #[error("Group data is too large ({size} bytes, at most {max} bytes allowed)")]
+    GroupDataTooLarge { size: usize, max: usize },
+    #[error("Invalid group data: {0}")]
+    InvalidGroupData(#[from] codec::Error),
+    #[error(transparent)]
+    TlsCodecError(#[from] tls_codec::Error),
+    #[error(transparent)]
+    StorageError(#[from] rusqlite::Error),
+}
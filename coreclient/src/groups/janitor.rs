@@ -0,0 +1,174 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Recovery of groups wedged by stale pending state.
+//!
+//! A commit stays pending if the client never learns whether the DS applied
+//! it, e.g. because the connection dropped before the response arrived. While
+//! a commit is pending, the client can't create new commits in the group.
+//! Proposals stay pending if no member commits them.
+//!
+//! The janitor resolves a commit that has been pending for longer than
+//! [`PENDING_COMMIT_MAX_AGE`] by comparing the epoch of the group at the DS
+//! with the own epoch:
+//!
+//! - If the DS is still in the own epoch, the commit never reached the DS and
+//!   is discarded together with the staged changes.
+//! - If the DS is exactly one epoch ahead and its confirmed transcript hash
+//!   matches the one of the pending commit, the commit was applied and is
+//!   merged.
+//! - Otherwise, e.g. if the DS applied a commit of another member instead,
+//!   the commit is left as is.
+//!
+//! Proposals pending for longer than [`STALE_PROPOSAL_THRESHOLD`] are
+//! discarded. Since the age of a pending commit is only known from the first
+//! time the janitor has seen it, a commit is resolved at the earliest on the
+//! first run after [`PENDING_COMMIT_MAX_AGE`] has passed. Proposals are never
+//! discarded by members that can't commit them themselves.
+
+use anyhow::Result;
+use openmls::prelude::OpenMlsProvider;
+use phnxtypes::{
+    messages::client_ds::GroupEpochInfo,
+    time::{Duration, TimeStamp},
+};
+use rusqlite::Connection;
+
+use crate::{conversations::messages::TimestampedMessage, SystemMessage};
+
+use super::{
+    client_auth_info::GroupMembership, openmls_provider::PhnxOpenMlsProvider,
+    security::STALE_PROPOSAL_THRESHOLD, Group,
+};
+
+/// Commits that have been pending for longer than this are resolved by the
+/// janitor. Regular commits are either merged or fail within seconds.
+pub(crate) const PENDING_COMMIT_MAX_AGE: Duration = Duration::hours(1);
+
+/// Stale pending state of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StalePendingState {
+    /// The pending commit needs to be resolved with the DS.
+    Commit,
+    /// There is no pending commit, but the pending proposals are stale.
+    Proposals,
+}
+
+impl Group {
+    /// Returns the stale pending state of the group, if any.
+    pub(crate) fn stale_pending_state(
+        &self,
+        connection: &Connection,
+    ) -> Result<Option<StalePendingState>> {
+        if self.mls_group().pending_commit().is_some() {
+            let pending_since = self.load_or_store_commit_pending_since(connection)?;
+            let is_stale = pending_since.has_expired(PENDING_COMMIT_MAX_AGE);
+            return Ok(is_stale.then_some(StalePendingState::Commit));
+        }
+        // In broadcast rooms, members wait for an admin to commit their
+        // proposals.
+        if self.mls_group().pending_proposals().next().is_none() || !self.own_can_commit()? {
+            return Ok(None);
+        }
+        let (_, proposals_pending_since) = self.load_security_timestamps(connection)?;
+        let is_stale = proposals_pending_since
+            .is_some_and(|since| since.has_expired(STALE_PROPOSAL_THRESHOLD));
+        Ok(is_stale.then_some(StalePendingState::Proposals))
+    }
+
+    /// Resolve the pending commit given the epoch of the group at the DS.
+    /// Returns `None` if the commit can't be resolved safely.
+    pub(crate) fn resolve_pending_commit(
+        &mut self,
+        connection: &Connection,
+        ds_epoch_info: &GroupEpochInfo,
+    ) -> Result<Option<Vec<TimestampedMessage>>> {
+        let own_epoch = self.mls_group().epoch().as_u64();
+        let ds_epoch = ds_epoch_info.epoch;
+        let now = TimeStamp::now();
+        let messages = if ds_epoch == own_epoch {
+            log::warn!("Discarding pending commit that never reached the DS");
+            self.discard_pending_state(connection)?;
+            vec![TimestampedMessage::system_message(
+                SystemMessage::PendingChangeDiscarded,
+                now,
+            )]
+        } else if ds_epoch == own_epoch + 1 && self.pending_commit_matches(ds_epoch_info) {
+            log::warn!("Merging pending commit that was applied by the DS");
+            let mut messages = self.merge_pending_commit(connection, None, now)?;
+            messages.push(TimestampedMessage::system_message(
+                SystemMessage::PendingChangeApplied,
+                now,
+            ));
+            messages
+        } else {
+            log::error!(
+                "Can't resolve pending commit in epoch {own_epoch}, the DS is in epoch {ds_epoch}"
+            );
+            return Ok(None);
+        };
+        Ok(Some(messages))
+    }
+
+    /// Returns `true` if the DS epoch was started by the pending commit.
+    fn pending_commit_matches(&self, ds_epoch_info: &GroupEpochInfo) -> bool {
+        self.mls_group()
+            .pending_commit()
+            .is_some_and(|staged_commit| {
+                staged_commit.group_context().confirmed_transcript_hash()
+                    == ds_epoch_info.confirmed_transcript_hash.as_slice()
+            })
+    }
+
+    /// The own epoch and the confirmed transcript hash of the pending commit,
+    /// if any, i.e. what the DS reports once it applied the commit.
+    #[cfg(test)]
+    pub(crate) fn epoch_and_pending_commit_hash(&self) -> (u64, Option<Vec<u8>>) {
+        let pending_commit_hash = self.mls_group().pending_commit().map(|staged_commit| {
+            staged_commit
+                .group_context()
+                .confirmed_transcript_hash()
+                .to_vec()
+        });
+        (self.mls_group().epoch().as_u64(), pending_commit_hash)
+    }
+
+    /// Discard the stale pending proposals.
+    pub(crate) fn discard_pending_proposals(
+        &mut self,
+        connection: &Connection,
+    ) -> Result<Vec<TimestampedMessage>> {
+        log::warn!("Discarding stale pending proposals");
+        self.discard_pending_state(connection)?;
+        Ok(vec![TimestampedMessage::system_message(
+            SystemMessage::PendingChangeDiscarded,
+            TimeStamp::now(),
+        )])
+    }
+
+    /// Discard a pending commit the DS rejected together with the staged
+    /// changes to the group. Pending proposals are kept.
+    pub(crate) fn discard_rejected_commit(&mut self, connection: &Connection) -> Result<()> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        self.mls_group.clear_pending_commit(provider.storage())?;
+        GroupMembership::discard_staged_for_group(connection, self.group_id())?;
+        self.pending_diff = None;
+        self.store_pending_diff(connection)?;
+        Ok(())
+    }
+
+    /// Discard the pending commit and proposals together with the staged
+    /// changes to the group.
+    fn discard_pending_state(&mut self, connection: &Connection) -> Result<()> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        self.mls_group.clear_pending_commit(provider.storage())?;
+        self.mls_group.clear_pending_proposals(provider.storage())?;
+        GroupMembership::discard_staged_for_group(connection, self.group_id())?;
+        GroupMembership::delete_proposed_updates(connection, self.group_id())?;
+        self.pending_diff = None;
+        self.store_pending_diff(connection)?;
+        self.clear_pending_since(connection)?;
+        Ok(())
+    }
+}
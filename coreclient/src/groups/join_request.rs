@@ -0,0 +1,254 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Join requests of non-members for rooms that allow knocking.
+//!
+//! Members that can invite others register a [`JoinRequestToken`] with the
+//! DS and share it out of band. It allows a non-member to send a signed
+//! [`JoinRequest`] to the group via the DS without learning any of the EAR
+//! keys of the group. The request is encrypted under a key derived from the
+//! secret of the token, which all members can derive from the credential EAR
+//! key of the group, such that only members learn the identity of the
+//! requesting user. Members that can invite others store the verified
+//! requests as [`PendingJoinRequest`]s until they are approved or declined.
+
+use openmls::group::GroupId;
+use phnxtypes::{
+    credentials::{ClientCredential, VerifiableClientCredential},
+    crypto::{
+        ear::{
+            keys::JoinRequestEarKey, EarDecryptable, EarEncryptable, GenericDeserializable,
+            GenericSerializable,
+        },
+        kdf::keys::{JoinRequestSecret, JoinRequestTokenId},
+        rng,
+        signatures::{
+            signable::{Signable, Signature, SignedStruct, Verifiable, VerifiedStruct},
+            traits::SignatureVerificationError,
+        },
+    },
+    identifiers::QualifiedUserName,
+    messages::join_request::EncryptedJoinRequest,
+    time::TimeStamp,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use tls_codec::{
+    DeserializeBytes, Serialize as TlsSerializeTrait, TlsDeserializeBytes, TlsSerialize, TlsSize,
+};
+use uuid::Uuid;
+
+use crate::{utils::persistence::Storable, ConversationId};
+
+/// Everything a non-member needs to ask to join a group. The token is only
+/// valid while it is registered with the DS.
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub(crate) struct JoinRequestToken {
+    pub(crate) group_id: GroupId,
+    pub(crate) token_id: JoinRequestTokenId,
+    pub(crate) secret: JoinRequestSecret,
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub(crate) struct JoinRequestTbs {
+    pub(crate) group_id: GroupId,
+    pub(crate) sender_client_credential: ClientCredential,
+    pub(crate) timestamp: TimeStamp,
+}
+
+impl Signable for JoinRequestTbs {
+    type SignedOutput = JoinRequest;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        "JoinRequestTBS"
+    }
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub(crate) struct JoinRequest {
+    payload: JoinRequestTbs,
+    signature: Signature,
+}
+
+impl SignedStruct<JoinRequestTbs> for JoinRequest {
+    fn from_payload(payload: JoinRequestTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+impl GenericSerializable for JoinRequest {
+    type Error = tls_codec::Error;
+
+    fn serialize(&self) -> Result<Vec<u8>, Self::Error> {
+        self.tls_serialize_detached()
+    }
+}
+
+impl EarEncryptable<JoinRequestEarKey, EncryptedJoinRequest> for JoinRequest {}
+
+mod private_mod {
+    #[derive(Default)]
+    pub struct Seal;
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub(crate) struct JoinRequestTbsIn {
+    pub(crate) group_id: GroupId,
+    pub(crate) sender_client_credential: VerifiableClientCredential,
+    pub(crate) timestamp: TimeStamp,
+}
+
+impl VerifiedStruct<JoinRequestIn> for JoinRequestTbsIn {
+    type SealingType = private_mod::Seal;
+
+    fn from_verifiable(verifiable: JoinRequestIn, _seal: Self::SealingType) -> Self {
+        verifiable.payload
+    }
+}
+
+#[derive(Debug, Clone, TlsDeserializeBytes, TlsSize)]
+pub(crate) struct JoinRequestIn {
+    payload: JoinRequestTbsIn,
+    signature: Signature,
+}
+
+impl GenericDeserializable for JoinRequestIn {
+    type Error = tls_codec::Error;
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::tls_deserialize_exact_bytes(bytes)
+    }
+}
+
+impl EarDecryptable<JoinRequestEarKey, EncryptedJoinRequest> for JoinRequestIn {}
+
+impl JoinRequestIn {
+    pub(crate) fn group_id(&self) -> &GroupId {
+        &self.payload.group_id
+    }
+
+    pub(crate) fn sender_credential(&self) -> &VerifiableClientCredential {
+        &self.payload.sender_client_credential
+    }
+
+    /// Verify the signature of the request with the given (already verified)
+    /// client credential of the sender.
+    pub(crate) fn verify_with_credential(
+        self,
+        client_credential: &ClientCredential,
+    ) -> Result<JoinRequestTbsIn, SignatureVerificationError> {
+        self.verify(client_credential.verifying_key())
+    }
+}
+
+impl Verifiable for JoinRequestIn {
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.payload.tls_serialize_detached()
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn label(&self) -> &str {
+        "JoinRequestTBS"
+    }
+}
+
+/// A verified join request that waits for approval by a member.
+#[derive(Debug, Clone)]
+pub struct PendingJoinRequest {
+    pub id: Uuid,
+    pub conversation_id: ConversationId,
+    pub user_name: QualifiedUserName,
+    pub received_at: TimeStamp,
+}
+
+impl Storable for PendingJoinRequest {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS join_requests (
+            id BLOB PRIMARY KEY,
+            conversation_id BLOB NOT NULL,
+            user_name TEXT NOT NULL,
+            received_at TEXT NOT NULL,
+            UNIQUE(conversation_id, user_name),
+            FOREIGN KEY (conversation_id) REFERENCES conversations(conversation_id) ON DELETE CASCADE
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let id = row.get(0)?;
+        let conversation_id = row.get(1)?;
+        let user_name = row.get(2)?;
+        let received_at = row.get(3)?;
+        Ok(Self {
+            id,
+            conversation_id,
+            user_name,
+            received_at,
+        })
+    }
+}
+
+impl PendingJoinRequest {
+    pub(crate) fn new(conversation_id: ConversationId, user_name: QualifiedUserName) -> Self {
+        Self {
+            id: rng::random_uuid(),
+            conversation_id,
+            user_name,
+            received_at: TimeStamp::now(),
+        }
+    }
+
+    /// Store the request. A repeated request of the same user replaces the
+    /// previous one.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO join_requests (id, conversation_id, user_name, received_at) VALUES (?, ?, ?, ?)",
+            params![self.id, self.conversation_id, self.user_name, self.received_at],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        user_name: &QualifiedUserName,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT id, conversation_id, user_name, received_at FROM join_requests WHERE conversation_id = ? AND user_name = ?",
+                params![conversation_id, user_name],
+                Self::from_row,
+            )
+            .optional()
+    }
+
+    pub(crate) fn load_all(
+        connection: &Connection,
+        conversation_id: ConversationId,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT id, conversation_id, user_name, received_at FROM join_requests WHERE conversation_id = ? ORDER BY received_at ASC",
+        )?;
+        let requests = statement
+            .query_map(params![conversation_id], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(requests)
+    }
+
+    pub(crate) fn delete(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        user_name: &QualifiedUserName,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM join_requests WHERE conversation_id = ? AND user_name = ?",
+            params![conversation_id, user_name],
+        )?;
+        Ok(())
+    }
+}
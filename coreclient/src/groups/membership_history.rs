@@ -0,0 +1,308 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Audit log of the membership changes of a conversation.
+//!
+//! Entries are derived from the staged commits the client processes, i.e. the
+//! identities and credential fingerprints are taken from client credentials
+//! that were verified end-to-end. The log is append-only and kept per
+//! conversation.
+
+use phnxtypes::{
+    credentials::CredentialFingerprint, identifiers::QualifiedUserName, time::TimeStamp,
+};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{utils::persistence::Storable, ConversationId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MembershipChangeKind {
+    Add,
+    Remove,
+}
+
+impl ToSql for MembershipChangeKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let kind = match self {
+            Self::Add => "add",
+            Self::Remove => "remove",
+        };
+        Ok(ToSqlOutput::Owned(Value::Text(kind.to_owned())))
+    }
+}
+
+impl FromSql for MembershipChangeKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "add" => Ok(Self::Add),
+            "remove" => Ok(Self::Remove),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A single client being added to or removed from a conversation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MembershipChange {
+    conversation_id: ConversationId,
+    epoch: u64,
+    timestamp: TimeStamp,
+    kind: MembershipChangeKind,
+    sender: QualifiedUserName,
+    sender_fingerprint: CredentialFingerprint,
+    member: QualifiedUserName,
+    member_fingerprint: CredentialFingerprint,
+}
+
+impl Storable for MembershipChange {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS membership_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            conversation_id BLOB NOT NULL,
+            epoch INTEGER NOT NULL,
+            timestamp TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            sender_fingerprint BLOB NOT NULL,
+            member TEXT NOT NULL,
+            member_fingerprint BLOB NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS membership_history_conversation_id
+            ON membership_history (conversation_id, id);";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            conversation_id: row.get(0)?,
+            epoch: row.get(1)?,
+            timestamp: row.get(2)?,
+            kind: row.get(3)?,
+            sender: row.get(4)?,
+            sender_fingerprint: row.get(5)?,
+            member: row.get(6)?,
+            member_fingerprint: row.get(7)?,
+        })
+    }
+}
+
+impl MembershipChange {
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn new(
+        conversation_id: ConversationId,
+        epoch: u64,
+        timestamp: TimeStamp,
+        kind: MembershipChangeKind,
+        sender: QualifiedUserName,
+        sender_fingerprint: CredentialFingerprint,
+        member: QualifiedUserName,
+        member_fingerprint: CredentialFingerprint,
+    ) -> Self {
+        Self {
+            conversation_id,
+            epoch,
+            timestamp,
+            kind,
+            sender,
+            sender_fingerprint,
+            member,
+            member_fingerprint,
+        }
+    }
+
+    pub fn conversation_id(&self) -> ConversationId {
+        self.conversation_id
+    }
+
+    /// The epoch of the group after the change was applied.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn timestamp(&self) -> TimeStamp {
+        self.timestamp
+    }
+
+    pub fn kind(&self) -> MembershipChangeKind {
+        self.kind
+    }
+
+    /// The user that made the change. Users that join with an external
+    /// commit add themselves.
+    pub fn sender(&self) -> &QualifiedUserName {
+        &self.sender
+    }
+
+    pub fn sender_fingerprint(&self) -> &CredentialFingerprint {
+        &self.sender_fingerprint
+    }
+
+    /// The user whose client was added or removed.
+    pub fn member(&self) -> &QualifiedUserName {
+        &self.member
+    }
+
+    pub fn member_fingerprint(&self) -> &CredentialFingerprint {
+        &self.member_fingerprint
+    }
+
+    pub(super) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT INTO membership_history (conversation_id, epoch, timestamp, kind, sender, sender_fingerprint, member, member_fingerprint) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                self.conversation_id,
+                self.epoch,
+                self.timestamp,
+                self.kind,
+                self.sender,
+                self.sender_fingerprint,
+                self.member,
+                self.member_fingerprint,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Load up to `limit` entries of the conversation's history, most recent
+    /// first, skipping the `offset` most recent ones.
+    pub(crate) fn load_page(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, epoch, timestamp, kind, sender, sender_fingerprint, member, member_fingerprint
+            FROM membership_history
+            WHERE conversation_id = ?
+            ORDER BY id DESC
+            LIMIT ? OFFSET ?",
+        )?;
+        let entries = statement
+            .query_map(params![conversation_id, limit, offset], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Returns the time at which the given user was last added to the
+    /// conversation, if the addition was recorded.
+    pub(crate) fn last_joined(
+        connection: &Connection,
+        conversation_id: ConversationId,
+        member: &QualifiedUserName,
+    ) -> Result<Option<TimeStamp>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT timestamp FROM membership_history
+                WHERE conversation_id = ? AND member = ? AND kind = ?
+                ORDER BY id DESC
+                LIMIT 1",
+                params![conversation_id, member, MembershipChangeKind::Add],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+}
+
+/// Position in the membership history of all conversations
+pub(crate) type MembershipHistoryCursor = i64;
+
+impl MembershipChange {
+    /// The position of the most recent entry, or 0 if there is none.
+    pub(crate) fn latest_cursor(
+        connection: &Connection,
+    ) -> Result<MembershipHistoryCursor, rusqlite::Error> {
+        connection.query_row(
+            "SELECT COALESCE(MAX(id), 0) FROM membership_history",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    /// Load the entries of all conversations recorded after the given
+    /// position, oldest first, together with the position of the last of
+    /// them.
+    pub(crate) fn load_after(
+        connection: &Connection,
+        cursor: MembershipHistoryCursor,
+    ) -> Result<(Vec<Self>, MembershipHistoryCursor), rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, epoch, timestamp, kind, sender, sender_fingerprint, member, member_fingerprint, id
+            FROM membership_history
+            WHERE id > ?
+            ORDER BY id ASC",
+        )?;
+        let mut latest = cursor;
+        let entries = statement
+            .query_map(params![cursor], |row| {
+                Ok((Self::from_row(row)?, row.get::<_, i64>(8)?))
+            })?
+            .map(|entry| {
+                let (entry, id) = entry?;
+                latest = id;
+                Ok(entry)
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        Ok((entries, latest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+    use tls_codec::DeserializeBytes;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn change(conversation_id: ConversationId, member: &str) -> MembershipChange {
+        let fingerprint = CredentialFingerprint::tls_deserialize_exact_bytes(&[1, 0xaa]).unwrap();
+        MembershipChange::new(
+            conversation_id,
+            1,
+            TimeStamp::now(),
+            MembershipChangeKind::Add,
+            SafeTryInto::try_into("alice@example.com").unwrap(),
+            fingerprint.clone(),
+            SafeTryInto::try_into(member).unwrap(),
+            fingerprint,
+        )
+    }
+
+    #[test]
+    fn load_after_cursor() {
+        let connection = Connection::open_in_memory().unwrap();
+        MembershipChange::create_table(&connection).unwrap();
+        assert_eq!(MembershipChange::latest_cursor(&connection).unwrap(), 0);
+
+        let first = ConversationId::from(Uuid::new_v4());
+        let second = ConversationId::from(Uuid::new_v4());
+        change(first, "bob@example.com").store(&connection).unwrap();
+        let cursor = MembershipChange::latest_cursor(&connection).unwrap();
+        change(second, "carol@example.com")
+            .store(&connection)
+            .unwrap();
+        change(first, "dave@example.com")
+            .store(&connection)
+            .unwrap();
+
+        let (changes, cursor) = MembershipChange::load_after(&connection, cursor).unwrap();
+        let members: Vec<String> = changes
+            .iter()
+            .map(|change| change.member().to_string())
+            .collect();
+        assert_eq!(members, ["carol@example.com", "dave@example.com"]);
+        assert_eq!(
+            cursor,
+            MembershipChange::latest_cursor(&connection).unwrap()
+        );
+
+        let (changes, unchanged) = MembershipChange::load_after(&connection, cursor).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(unchanged, cursor);
+    }
+}
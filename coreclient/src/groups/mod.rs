@@ -2,11 +2,19 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+pub(crate) mod cache;
 pub(crate) mod client_auth_info;
 pub(crate) mod diff;
 pub(crate) mod error;
+pub(crate) mod janitor;
+pub(crate) mod join_request;
+pub(crate) mod membership_history;
 pub(crate) mod openmls_provider;
 pub(crate) mod persistence;
+pub(crate) mod quarantine;
+pub(crate) mod repair;
+pub(crate) mod security;
+pub(crate) mod upgrade;
 
 pub(crate) use error::*;
 
@@ -15,9 +23,10 @@ use mls_assist::messages::AssistedMessageOut;
 use openmls_provider::PhnxOpenMlsProvider;
 use openmls_traits::storage::StorageProvider;
 use phnxtypes::{
+    codec::{self, PhnxCodec},
     credentials::{
         keys::{ClientSigningKey, InfraCredentialSigningKey},
-        ClientCredential, EncryptedClientCredential,
+        ClientCredential, CredentialFingerprint, EncryptedClientCredential,
     },
     crypto::{
         ear::{
@@ -34,7 +43,8 @@ use phnxtypes::{
         },
     },
     identifiers::{
-        AsClientId, QsClientReference, QualifiedUserName, QS_CLIENT_REFERENCE_EXTENSION_TYPE,
+        AsClientId, QsClientReference, QualifiedUserName, GROUP_DATA_EXTENSION_TYPE,
+        QS_CLIENT_REFERENCE_EXTENSION_TYPE,
     },
     keypackage_batch::{KeyPackageBatch, VERIFIED},
     messages::{
@@ -44,12 +54,20 @@ use phnxtypes::{
         },
         client_ds_out::{
             AddUsersParamsOut, CreateGroupParamsOut, DeleteGroupParamsOut, ExternalCommitInfoIn,
-            RemoveUsersParamsOut, SelfRemoveClientParamsOut, SendMessageParamsOut,
-            UpdateClientParamsOut,
+            ProposeClientUpdateParamsOut, RemoveUsersParamsOut, SelfRemoveClientParamsOut,
+            SendMessageParamsOut, UpdateClientParamsOut, UpdateGroupDataParamsOut,
+            UpdateRoomPolicyParamsOut,
+        },
+        room_policy::{
+            CommitRule, RoomPolicy, RoomPolicyChange, SendRule, ROOM_POLICY_EXTENSION_TYPE,
+        },
+        room_upgrade::{
+            added_tombstone, GROUP_PREDECESSOR_EXTENSION_TYPE, GROUP_TOMBSTONE_EXTENSION_TYPE,
         },
         welcome_attribution_info::{
             WelcomeAttributionInfo, WelcomeAttributionInfoPayload, WelcomeAttributionInfoTbs,
         },
+        QueuePriority,
     },
     time::TimeStamp,
 };
@@ -58,22 +76,28 @@ use serde::{Deserialize, Serialize};
 use tls_codec::DeserializeBytes as TlsDeserializeBytes;
 
 use crate::{
-    clients::api_clients::ApiClients, contacts::ContactAddInfos,
-    conversations::messages::TimestampedMessage, key_stores::leaf_keys::LeafKeys,
-    mimi_content::MimiContent, utils::persistence::SqliteConnection, SystemMessage,
+    chat_metadata::{validate_shared, ChatMetadata, SharedChatMetadata},
+    clients::api_clients::ApiClients,
+    contacts::ContactAddInfos,
+    conversations::messages::TimestampedMessage,
+    key_stores::leaf_keys::LeafKeys,
+    mimi_content::MimiContent,
+    utils::persistence::SqliteConnection,
+    ConversationAttributes, ConversationId, SystemMessage,
 };
 use std::collections::HashSet;
 
 use openmls::{
-    group::ProcessedWelcome,
+    group::{NewSignerBundle, ProcessedWelcome},
     key_packages::KeyPackageBundle,
     prelude::{
-        tls_codec::Serialize as TlsSerializeTrait, Capabilities, Ciphersuite, Credential,
-        CredentialType, CredentialWithKey, Extension, ExtensionType, Extensions, GroupId,
-        KeyPackage, LeafNodeIndex, MlsGroup, MlsGroupJoinConfig, MlsMessageOut, OpenMlsProvider,
-        ProcessedMessage, ProcessedMessageContent, Proposal, ProposalType, ProtocolMessage,
-        ProtocolVersion, QueuedProposal, RequiredCapabilitiesExtension, Sender, StagedCommit,
-        UnknownExtension, PURE_PLAINTEXT_WIRE_FORMAT_POLICY,
+        tls_codec::Serialize as TlsSerializeTrait, tls_codec::Size as TlsSizeTrait, Capabilities,
+        Ciphersuite, Credential, CredentialType, CredentialWithKey, Extension, ExtensionType,
+        Extensions, GroupContextExtensionProposal, GroupId, KeyPackage, LeafNodeIndex, MlsGroup,
+        MlsGroupJoinConfig, MlsMessageOut, OpenMlsProvider, ProcessedMessage,
+        ProcessedMessageContent, Proposal, ProposalType, ProtocolMessage, ProtocolVersion,
+        QueuedProposal, RequiredCapabilitiesExtension, Sender, StagedCommit, UnknownExtension,
+        PURE_PLAINTEXT_WIRE_FORMAT_POLICY,
     },
     treesync::{LeafNodeParameters, RatchetTree},
 };
@@ -81,10 +105,15 @@ use openmls::{
 use self::{
     client_auth_info::{ClientAuthInfo, GroupMembership, StorableClientCredential},
     diff::{GroupDiff, StagedGroupDiff},
+    membership_history::{MembershipChange, MembershipChangeKind},
 };
 
 pub const FRIENDSHIP_PACKAGE_PROPOSAL_TYPE: u16 = 0xff00;
-pub const GROUP_DATA_EXTENSION_TYPE: u16 = 0xff01;
+
+/// Maximum size of a welcome bundle received from the QS.
+pub const MAX_WELCOME_BUNDLE_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum size of the group data of a group that is joined.
+pub const MAX_GROUP_DATA_SIZE: usize = 1024 * 1024;
 
 pub const DEFAULT_MLS_VERSION: ProtocolVersion = ProtocolVersion::Mls10;
 pub const DEFAULT_CIPHERSUITE: Ciphersuite =
@@ -110,7 +139,14 @@ pub fn default_required_capabilities() -> RequiredCapabilitiesExtension {
 // Default capabilities for every leaf node we create.
 pub const SUPPORTED_PROTOCOL_VERSIONS: [ProtocolVersion; 1] = [DEFAULT_MLS_VERSION];
 pub const SUPPORTED_CIPHERSUITES: [Ciphersuite; 1] = [DEFAULT_CIPHERSUITE];
-pub const SUPPORTED_EXTENSIONS: [ExtensionType; 3] = REQUIRED_EXTENSION_TYPES;
+pub const SUPPORTED_EXTENSIONS: [ExtensionType; 6] = [
+    ExtensionType::Unknown(QS_CLIENT_REFERENCE_EXTENSION_TYPE),
+    ExtensionType::Unknown(GROUP_DATA_EXTENSION_TYPE),
+    ExtensionType::LastResort,
+    ExtensionType::Unknown(ROOM_POLICY_EXTENSION_TYPE),
+    ExtensionType::Unknown(GROUP_TOMBSTONE_EXTENSION_TYPE),
+    ExtensionType::Unknown(GROUP_PREDECESSOR_EXTENSION_TYPE),
+];
 pub const SUPPORTED_PROPOSALS: [ProposalType; 1] = REQUIRED_PROPOSAL_TYPES;
 pub const SUPPORTED_CREDENTIALS: [CredentialType; 1] = REQUIRED_CREDENTIAL_TYPES;
 
@@ -158,6 +194,97 @@ impl GroupData {
     pub(crate) fn bytes(&self) -> &[u8] {
         &self.bytes
     }
+
+    /// Encode the given [`ConversationAttributes`] as group data.
+    pub(crate) fn encode(attributes: &ConversationAttributes) -> Result<Self, codec::Error> {
+        Self::encode_with_metadata(attributes, &SharedChatMetadata::new())
+    }
+
+    /// Encode the given [`ConversationAttributes`] and shared chat metadata
+    /// as group data.
+    ///
+    /// Without shared metadata, the data is encoded as [`VersionedGroupData::V1`],
+    /// such that older clients can still decode it.
+    pub(crate) fn encode_with_metadata(
+        attributes: &ConversationAttributes,
+        shared_metadata: &SharedChatMetadata,
+    ) -> Result<Self, codec::Error> {
+        let versioned = if shared_metadata.is_empty() {
+            VersionedGroupData::V1(attributes.clone())
+        } else {
+            VersionedGroupData::V2(attributes.clone(), shared_metadata.clone())
+        };
+        Ok(PhnxCodec::to_vec(&versioned)?.into())
+    }
+
+    /// Decode the [`ConversationAttributes`] from the group data.
+    ///
+    /// Groups created before the group data was versioned contain the bare
+    /// attributes, which are still accepted.
+    pub(crate) fn decode(&self) -> Result<ConversationAttributes, codec::Error> {
+        self.decode_with_metadata()
+            .map(|(attributes, _shared_metadata)| attributes)
+    }
+
+    /// Decode the [`ConversationAttributes`] and the shared chat metadata
+    /// from the group data. The metadata isn't validated.
+    pub(crate) fn decode_with_metadata(
+        &self,
+    ) -> Result<(ConversationAttributes, SharedChatMetadata), codec::Error> {
+        match PhnxCodec::from_slice::<VersionedGroupData>(&self.bytes) {
+            Ok(VersionedGroupData::V1(attributes)) => Ok((attributes, SharedChatMetadata::new())),
+            Ok(VersionedGroupData::V2(attributes, shared_metadata)) => {
+                Ok((attributes, shared_metadata))
+            }
+            Err(error) => PhnxCodec::from_slice(&self.bytes)
+                .map(|attributes| (attributes, SharedChatMetadata::new()))
+                .map_err(|_| error),
+        }
+    }
+
+    /// The group data proposed by the group context extensions proposal of
+    /// the given commit, if any.
+    fn proposed(staged_commit: &StagedCommit) -> Option<Self> {
+        staged_commit
+            .queued_proposals()
+            .find_map(|queued_proposal| match queued_proposal.proposal() {
+                Proposal::GroupContextExtensions(extensions_proposal) => Some(extensions_proposal),
+                _ => None,
+            })?
+            .extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                Extension::Unknown(GROUP_DATA_EXTENSION_TYPE, extension_bytes) => {
+                    Some(Self::from(extension_bytes.0.clone()))
+                }
+                _ => None,
+            })
+    }
+}
+
+/// The group context extensions proposal of the given commit and its
+/// sender, if any.
+fn proposed_group_context_extensions(
+    staged_commit: &StagedCommit,
+) -> Option<(&GroupContextExtensionProposal, &Sender)> {
+    staged_commit
+        .queued_proposals()
+        .find_map(|queued_proposal| match queued_proposal.proposal() {
+            Proposal::GroupContextExtensions(extensions_proposal) => {
+                Some((extensions_proposal, queued_proposal.sender()))
+            }
+            _ => None,
+        })
+}
+
+/// Versioned room state shared with the members of a group via the group
+/// data extension. New versions are added as variants, such that clients
+/// can decode room state created by older clients.
+#[derive(Serialize, Deserialize)]
+enum VersionedGroupData {
+    V1(ConversationAttributes),
+    /// Adds the shared chat metadata, see [`crate::chat_metadata`].
+    V2(ConversationAttributes, SharedChatMetadata),
 }
 
 impl From<Vec<u8>> for GroupData {
@@ -194,12 +321,14 @@ impl Group {
             .build()
     }
 
-    /// Create a group.
+    /// Create a group with the given initial room policy. The admins of the
+    /// policy are replaced by the creator.
     pub(super) fn create_group(
         connection: &mut Connection,
         signer: &ClientSigningKey,
         group_id: GroupId,
         group_data: GroupData,
+        room_policy: RoomPolicy,
     ) -> Result<(Self, PartialCreateGroupParams)> {
         let credential_ear_key = ClientCredentialEarKey::random()?;
         let user_auth_key = UserAuthSigningKey::generate()?;
@@ -221,8 +350,16 @@ impl Group {
             GROUP_DATA_EXTENSION_TYPE,
             UnknownExtension(group_data.bytes),
         );
-        let gc_extensions =
-            Extensions::from_vec(vec![group_data_extension, required_capabilities])?;
+        let room_policy_extension = RoomPolicy {
+            admins: vec![user_auth_key.verifying_key().hash()],
+            ..room_policy
+        }
+        .to_extension()?;
+        let gc_extensions = Extensions::from_vec(vec![
+            group_data_extension,
+            required_capabilities,
+            room_policy_extension,
+        ])?;
 
         let transaction = connection.transaction()?;
         let provider = &PhnxOpenMlsProvider::new(&transaction);
@@ -257,7 +394,7 @@ impl Group {
             group_id.clone(),
             LeafNodeIndex::new(0), // We just created the group so we're at index 0.
             signature_ear_key,
-            signer.credential().fingerprint(),
+            signer.credential().fingerprint()?,
         )
         .store(&transaction)?;
 
@@ -281,7 +418,12 @@ impl Group {
     /// with the same ID, checks if that group is inactive and if so deletes the
     /// old group.
     ///
-    /// Returns the group name.
+    /// Returns the group and the client credential of the client that added
+    /// us.
+    ///
+    /// The welcome bundle is remote input, so this function must not panic on
+    /// malformed content. Every failure is reported as a
+    /// [`WelcomeProcessingError`].
     pub(super) async fn join_group(
         welcome_bundle: WelcomeBundle,
         // This is our own key that the sender uses to encrypt to us. We should
@@ -289,14 +431,21 @@ impl Group {
         welcome_attribution_info_ear_key: &WelcomeAttributionInfoEarKey,
         connection_mutex: SqliteConnection,
         api_clients: &ApiClients,
-    ) -> Result<Self> {
+    ) -> Result<(Self, ClientCredential), WelcomeProcessingError> {
+        let bundle_size = welcome_bundle.tls_serialized_len();
+        if bundle_size > MAX_WELCOME_BUNDLE_SIZE {
+            return Err(WelcomeProcessingError::BundleTooLarge {
+                size: bundle_size,
+                max: MAX_WELCOME_BUNDLE_SIZE,
+            });
+        }
         let serialized_welcome = welcome_bundle.welcome.tls_serialize_detached()?;
 
         let mls_group_config = Self::default_mls_group_join_config();
 
         // Phase 1: Fetch the right KeyPackageBundle from storage s.t. we can
         // decrypt the encrypted credentials
-        let (mls_group, joiner_info, welcome_attribution_info) = {
+        let (mls_group, joiner_info, welcome_attribution_info, sender_client_credential) = {
             let mut connection = connection_mutex.lock().await;
             let mut transaction = connection.transaction()?;
             let provider = PhnxOpenMlsProvider::new(&transaction);
@@ -312,7 +461,7 @@ impl Group {
                         _ => None,
                     }
                 })
-                .ok_or(GroupOperationError::MissingKeyPackage)?;
+                .ok_or(WelcomeProcessingError::MissingKeyPackage)?;
 
             let private_key = key_package_bundle.init_private_key();
             let info = &[];
@@ -326,7 +475,8 @@ impl Group {
                 &decryption_key,
                 info,
                 aad,
-            )?;
+            )
+            .map_err(WelcomeProcessingError::JoinerInfoDecryption)?;
 
             let processed_welcome = ProcessedWelcome::new_from_welcome(
                 &provider,
@@ -338,7 +488,7 @@ impl Group {
             if let Some(group) = Self::load(&transaction, &group_id)? {
                 // If the group is active, we can't join it.
                 if group.mls_group().is_active() {
-                    bail!("We can't join a group that is still active.");
+                    return Err(WelcomeProcessingError::GroupStillActive);
                 }
                 // Otherwise, we delete the old group.
                 Self::delete_from_db(&mut transaction, &group_id)?;
@@ -349,26 +499,40 @@ impl Group {
 
             let mls_group = staged_welcome.into_group(&provider)?;
 
+            // Every member needs exactly one entry of client information.
+            let member_count = mls_group.members().count();
+            let info_count = joiner_info.encrypted_client_information.len();
+            if member_count != info_count {
+                return Err(WelcomeProcessingError::ClientInformationMismatch {
+                    members: member_count,
+                    infos: info_count,
+                });
+            }
+
             // Decrypt WelcomeAttributionInfo
             let verifiable_attribution_info = WelcomeAttributionInfo::decrypt(
                 welcome_attribution_info_ear_key,
                 &welcome_bundle.encrypted_attribution_info,
-            )?
+            )
+            .map_err(WelcomeProcessingError::AttributionInfoDecryption)?
             .into_verifiable(mls_group.group_id().clone(), serialized_welcome);
 
             let sender_client_id = verifiable_attribution_info.sender();
             let sender_client_credential =
                 StorableClientCredential::load_by_client_id(&transaction, &sender_client_id)?
-                    .ok_or(anyhow!(
-                        "Could not find client credential of sender in database."
-                    ))?;
+                    .ok_or(WelcomeProcessingError::UnknownSender)?;
             transaction.commit()?;
             drop(connection);
 
             let welcome_attribution_info: WelcomeAttributionInfoPayload =
                 verifiable_attribution_info.verify(sender_client_credential.verifying_key())?;
 
-            (mls_group, joiner_info, welcome_attribution_info)
+            (
+                mls_group,
+                joiner_info,
+                welcome_attribution_info,
+                sender_client_credential.into(),
+            )
         };
 
         let encrypted_client_information = mls_group
@@ -386,22 +550,27 @@ impl Group {
             welcome_attribution_info.signature_ear_key_wrapper_key(),
             encrypted_client_information,
         )
-        .await?;
+        .await
+        .map_err(WelcomeProcessingError::InvalidClientInformation)?;
 
         let verifying_key = mls_group
             .own_leaf_node()
-            .ok_or(anyhow!("Group has no own leaf node"))?
+            .ok_or(WelcomeProcessingError::MissingOwnLeaf)?
             .signature_key();
 
         // Phase 3: Decrypt and verify the infra credentials.
         let connection = connection_mutex.lock().await;
         for (m, client_auth_info) in mls_group.members().zip(client_information.iter()) {
-            client_auth_info.verify_infra_credential(&m.credential)?;
-            client_auth_info.store(&connection)?;
+            client_auth_info
+                .verify_infra_credential(&m.credential)
+                .map_err(WelcomeProcessingError::InvalidClientInformation)?;
+            client_auth_info
+                .store(&connection)
+                .map_err(WelcomeProcessingError::InvalidClientInformation)?;
         }
 
         let leaf_keys = LeafKeys::load(&connection, verifying_key)?
-            .ok_or(anyhow!("Couldn't find matching leaf keys."))?;
+            .ok_or(WelcomeProcessingError::MissingLeafKeys)?;
         // Delete the leaf signer from the keys store as it now gets persisted as part of the group.
         LeafKeys::delete(&connection, verifying_key)?;
         drop(connection);
@@ -424,7 +593,7 @@ impl Group {
             pending_diff: None,
         };
 
-        Ok(group)
+        Ok((group, sender_client_credential))
     }
 
     /// Join a group using an external commit.
@@ -502,7 +671,7 @@ impl Group {
             group_info.group_context().group_id().clone(),
             LeafNodeIndex::new(own_index as u32),
             signature_ear_key.clone(),
-            own_client_credential.fingerprint(),
+            own_client_credential.fingerprint()?,
         );
 
         let own_auth_info =
@@ -579,12 +748,35 @@ impl Group {
                 };
                 return Ok((processed_message, false, sender_client_id));
             }
-            ProcessedMessageContent::ProposalMessage(_proposal) => {
+            ProcessedMessageContent::ProposalMessage(proposal) => {
                 // Proposals are just returned and can then be added to the
                 // proposal store after the caller has inspected them.
                 let Sender::Member(sender_index) = processed_message.sender() else {
                     bail!("Invalid sender type.")
                 };
+                // Update proposals carry the new client information of their
+                // sender, which is staged once the proposal is committed.
+                if let Proposal::Update(update_proposal) = proposal.proposal() {
+                    let InfraAadPayload::UpdateClient(update_client_payload) =
+                        InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())?
+                            .into_payload()
+                    else {
+                        bail!("Invalid update proposal payload.")
+                    };
+                    if let Some(client_auth_info) = self
+                        .updated_client_auth_info(
+                            connection_mutex.clone(),
+                            api_clients,
+                            *sender_index,
+                            update_proposal.leaf_node().credential(),
+                            update_client_payload,
+                        )
+                        .await?
+                    {
+                        let connection = connection_mutex.lock().await;
+                        client_auth_info.store_proposed_update(&connection)?;
+                    }
+                }
                 *sender_index
             }
             ProcessedMessageContent::StagedCommitMessage(staged_commit) => {
@@ -600,6 +792,17 @@ impl Group {
                         we_were_removed = true;
                     }
                 }
+                // Committed update proposals replace the client information
+                // of their senders with the one they proposed.
+                for update_proposal in staged_commit.update_proposals() {
+                    if let Sender::Member(proposer_index) = update_proposal.sender() {
+                        GroupMembership::stage_proposed_update(
+                            &connection,
+                            group_id,
+                            *proposer_index,
+                        )?;
+                    }
+                }
                 drop(connection);
 
                 // Phase 2: Process the AAD payload.
@@ -607,6 +810,19 @@ impl Group {
                 let aad_payload =
                     InfraAadMessage::tls_deserialize_exact_bytes(processed_message.aad())?
                         .into_payload();
+                // The DS only checks that the committer is an admin for room
+                // policy updates, so no other commit may change the policy.
+                match &aad_payload {
+                    InfraAadPayload::UpdateRoomPolicy => {}
+                    InfraAadPayload::UpdateGroupData | InfraAadPayload::UpgradeGroup(_) => {
+                        self.ensure_room_policy_unchanged(staged_commit)?
+                    }
+                    _ => {
+                        if proposed_group_context_extensions(staged_commit).is_some() {
+                            bail!("Only room policy updates can change the group context");
+                        }
+                    }
+                }
                 let sender_index = match processed_message.sender() {
                     Sender::Member(index) => index.to_owned(),
                     Sender::NewMemberCommit => {
@@ -675,83 +891,34 @@ impl Group {
                         drop(connection);
                     }
                     InfraAadPayload::UpdateClient(update_client_payload) => {
-                        let sender_index = if let Sender::Member(index) = processed_message.sender()
-                        {
-                            index
-                        } else {
+                        let Sender::Member(sender_index) = processed_message.sender() else {
                             bail!("Unsupported sender type.")
                         };
-                        // Check if the client has updated its leaf credential.
-                        let sender = self
-                            .mls_group
-                            .members()
-                            .find(|m| &m.index == sender_index)
-                            .ok_or(anyhow!("Could not find sender in group members"))?;
                         let new_sender_credential = staged_commit
                             .update_path_leaf_node()
                             .map(|ln| ln.credential())
                             .ok_or(anyhow!("Could not find sender leaf node"))?;
-                        if new_sender_credential != &sender.credential {
-                            // If so, then there has to be a new signature ear key.
-                            let Some(encrypted_signature_ear_key) =
-                                update_client_payload.option_encrypted_signature_ear_key
-                            else {
-                                bail!("Invalid update client payload.")
-                            };
-                            // Optionally, the client could have updated its
-                            // client credential.
-                            let client_auth_info = if let Some(ecc) =
-                                update_client_payload.option_encrypted_client_credential
-                            {
-                                ClientAuthInfo::decrypt_and_verify(
-                                    connection_mutex.clone(),
-                                    api_clients,
-                                    group_id,
-                                    &self.credential_ear_key,
-                                    &self.signature_ear_key_wrapper_key,
-                                    (ecc, encrypted_signature_ear_key),
-                                    *sender_index,
-                                )
-                                .await?
-                            } else {
-                                // If not, we decrypt the new EAR key and use
-                                // the existing client credential.
-                                let signature_ear_key = SignatureEarKey::decrypt(
-                                    &self.signature_ear_key_wrapper_key,
-                                    &encrypted_signature_ear_key,
-                                )?;
-                                let connection = connection_mutex.lock().await;
-                                let mut group_membership =
-                                    GroupMembership::load(&connection, group_id, *sender_index)?
-                                        .ok_or(anyhow!(
-                                            "Could not find group membership of sender in database"
-                                        ))?;
-                                group_membership.set_signature_ear_key(signature_ear_key);
-                                let client_credential = StorableClientCredential::load(
-                                    &connection,
-                                    group_membership.client_credential_fingerprint(),
-                                )?
-                                .ok_or(anyhow!(
-                                    "Could not find client credential of sender in database"
-                                ))?;
-                                drop(connection);
-
-                                ClientAuthInfo::new(client_credential, group_membership)
-                            };
-                            // Persist the updated client auth info.
+                        // Persist the updated client auth info if the client
+                        // has updated its leaf credential.
+                        if let Some(client_auth_info) = self
+                            .updated_client_auth_info(
+                                connection_mutex.clone(),
+                                api_clients,
+                                *sender_index,
+                                new_sender_credential,
+                                update_client_payload,
+                            )
+                            .await?
+                        {
                             let connection = connection_mutex.lock().await;
                             client_auth_info.stage_update(&connection)?;
                             drop(connection);
-                            // Verify the leaf credential
-                            client_auth_info.verify_infra_credential(new_sender_credential)?;
-                        };
+                        }
                         // TODO: Validation:
                         // * Check that the sender type fits.
                         // * Check that the client id is the same as before.
                         // * Check that the proposals fit the operation (i.e. in this
                         //   case that there are no proposals at all).
-
-                        // Verify a potential new leaf credential.
                     }
                     InfraAadPayload::JoinGroup(join_group_payload) => {
                         // JoinGroup Phase 1: Decrypt and verify the client
@@ -887,6 +1054,9 @@ impl Group {
                             .remove_proposal()
                             .removed();
                         let connection = connection_mutex.lock().await;
+                        // The client keeps its membership and only moves to
+                        // a new leaf.
+                        GroupMembership::unstage_removal(&connection, group_id, removed_index)?;
                         let mut client_auth_info =
                             ClientAuthInfo::load(&connection, group_id, removed_index)?.ok_or(
                                 anyhow!("Could not find client credential of resync sender"),
@@ -905,6 +1075,83 @@ impl Group {
                         we_were_removed = true;
                         // There is nothing else to do at this point.
                     }
+                    InfraAadPayload::UpdateRoomPolicy => {
+                        // The DS has checked that the sender is an admin and
+                        // that the new policy is valid. The resulting system
+                        // messages are emitted when the commit is merged.
+                    }
+                    InfraAadPayload::UpgradeGroup(authorization) => {
+                        // Don't rely on the DS alone: the commit must only
+                        // add the tombstone, which an admin must have signed
+                        // for this epoch. The upgrade is recorded when the
+                        // commit is merged.
+                        let mut proposals = staged_commit.queued_proposals();
+                        let (Some(_), None) = (proposals.next(), proposals.next()) else {
+                            bail!("Upgrade commit must consist of a single proposal");
+                        };
+                        let (extensions_proposal, _) =
+                            proposed_group_context_extensions(staged_commit)
+                                .ok_or(anyhow!("Upgrade commit doesn't add a tombstone"))?;
+                        let tombstone = added_tombstone(
+                            self.mls_group().extensions(),
+                            extensions_proposal.extensions(),
+                        )
+                        .ok_or(anyhow!("Upgrade commit changes more than the tombstone"))?;
+                        if !self
+                            .room_policy()?
+                            .is_admin(&authorization.admin_key.hash())
+                        {
+                            bail!("Upgrade wasn't authorized by an admin");
+                        }
+                        authorization.verify(
+                            self.group_id(),
+                            self.mls_group().epoch(),
+                            &tombstone,
+                        )?;
+                    }
+                    InfraAadPayload::JoinUpgradedGroup(join_upgraded_group_payload) => {
+                        // JoinUpgradedGroup Phase 1: Decrypt and verify the
+                        // client credential of the joiner. The DS has checked
+                        // that the joiner was a member of the upgraded group.
+                        let client_auth_info = ClientAuthInfo::decrypt_and_verify(
+                            connection_mutex.clone(),
+                            api_clients,
+                            group_id,
+                            &self.credential_ear_key,
+                            &self.signature_ear_key_wrapper_key,
+                            join_upgraded_group_payload.encrypted_client_information,
+                            sender_index,
+                        )
+                        .await?;
+                        // Validate the leaf credential.
+                        client_auth_info.verify_infra_credential(processed_message.credential())?;
+
+                        // JoinUpgradedGroup Phase 2: Persist the client auth info.
+                        let connection = connection_mutex.lock().await;
+                        client_auth_info.stage_add(&connection)?;
+                        drop(connection);
+                    }
+                    InfraAadPayload::UpdateGroupData => {
+                        // The DS has checked that only the shared chat
+                        // metadata in the group data changed, but it can't
+                        // validate the metadata. We check both, since the
+                        // attributes are only taken from the group data by
+                        // members that join later.
+                        let group_data = GroupData::proposed(staged_commit)
+                            .ok_or(anyhow!("Missing group data in group data update"))?;
+                        if group_data.bytes().len() > MAX_GROUP_DATA_SIZE {
+                            bail!("Group data exceeds the maximum size");
+                        }
+                        let (attributes, shared_metadata) = group_data.decode_with_metadata()?;
+                        let current_attributes = self
+                            .group_data()
+                            .ok_or(anyhow!("Missing group data"))?
+                            .decode()?;
+                        if attributes != current_attributes {
+                            bail!("Group data update changes the conversation attributes");
+                        }
+                        validate_shared(&shared_metadata)?;
+                    }
                 };
                 sender_index
             }
@@ -1007,19 +1254,8 @@ impl Group {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        // Stage removals
-        for remove in self
-            .mls_group()
-            .pending_commit()
-            .ok_or(anyhow!("No pending commit after commit operation"))?
-            .remove_proposals()
-        {
-            GroupMembership::stage_removal(
-                connection,
-                self.group_id(),
-                remove.remove_proposal().removed(),
-            )?;
-        }
+        // Stage the committed proposals
+        self.stage_committed_proposals(connection)?;
 
         // Stage the adds in the DB.
         let free_indices = GroupMembership::free_indices(connection, self.group_id())?;
@@ -1028,7 +1264,7 @@ impl Group {
                 .into_iter()
                 .zip(signature_ear_keys.into_iter()),
         ) {
-            let fingerprint = client_credential.fingerprint();
+            let fingerprint = client_credential.fingerprint()?;
             let group_membership = GroupMembership::new(
                 client_credential.identity(),
                 self.group_id.clone(),
@@ -1074,18 +1310,7 @@ impl Group {
         let group_info = group_info_option.ok_or(anyhow!("No group info after commit"))?;
         let commit = AssistedMessageOut::new(mls_message, Some(group_info.into()))?;
 
-        for remove in self
-            .mls_group()
-            .pending_commit()
-            .ok_or(anyhow!("No pending commit after commit operation"))?
-            .remove_proposals()
-        {
-            GroupMembership::stage_removal(
-                connection,
-                self.group_id(),
-                remove.remove_proposal().removed(),
-            )?;
-        }
+        self.stage_committed_proposals(connection)?;
 
         let params = RemoveUsersParamsOut {
             commit,
@@ -1115,34 +1340,288 @@ impl Group {
         let aad_payload = InfraAadPayload::DeleteGroup;
         let aad = InfraAadMessage::from(aad_payload).tls_serialize_detached()?;
         self.mls_group.set_aad(aad);
-        let (mls_message, _welcome_option, group_info_option) = self.mls_group.remove_members(
-            provider,
-            &self.leaf_signer,
-            remove_indices.as_slice(),
-        )?;
+        // If we are the only member, an empty commit deletes the group.
+        let (mls_message, _welcome_option, group_info_option) = if remove_indices.is_empty() {
+            self.mls_group
+                .commit_to_pending_proposals(provider, &self.leaf_signer)?
+        } else {
+            self.mls_group
+                .remove_members(provider, &self.leaf_signer, remove_indices.as_slice())?
+        };
+        debug_assert!(_welcome_option.is_none());
+        let group_info =
+            group_info_option.ok_or(anyhow!("No group info after commit operation"))?;
+        let commit = AssistedMessageOut::new(mls_message, Some(group_info.into()))?;
+
+        self.stage_committed_proposals(connection)?;
+
+        let params = DeleteGroupParamsOut {
+            commit,
+            sender: user_auth_key.verifying_key().hash(),
+        };
+        Ok(params)
+    }
+
+    /// Change the policy of the room. Only admins can change the policy.
+    ///
+    /// Returns `None` if the changes don't alter the current policy.
+    pub(super) fn update_room_policy(
+        &mut self,
+        connection: &Connection,
+        changes: Vec<RoomPolicyChange>,
+    ) -> Result<Option<UpdateRoomPolicyParamsOut>> {
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            bail!("No user auth key")
+        };
+        let own_user_key_hash = user_auth_key.verifying_key().hash();
+        if !self.room_policy()?.is_admin(&own_user_key_hash) {
+            bail!("Only admins can change the room policy")
+        }
+        self.room_policy_commit(connection, changes)
+    }
+
+    /// Create a commit that applies the given changes to the room policy,
+    /// without checking that the own user is an admin.
+    ///
+    /// Returns `None` if the changes don't alter the current policy.
+    pub(super) fn room_policy_commit(
+        &mut self,
+        connection: &Connection,
+        changes: Vec<RoomPolicyChange>,
+    ) -> Result<Option<UpdateRoomPolicyParamsOut>> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            bail!("No user auth key")
+        };
+        let own_user_key_hash = user_auth_key.verifying_key().hash();
+        let mut room_policy = self.room_policy()?;
+        let mut changed = false;
+        for change in changes {
+            changed |= room_policy.apply(change);
+        }
+        if !changed {
+            return Ok(None);
+        }
+
+        let room_policy_extension = room_policy.to_extension()?;
+        let extensions = self
+            .mls_group()
+            .extensions()
+            .iter()
+            .filter(|extension| {
+                !matches!(extension, Extension::Unknown(ROOM_POLICY_EXTENSION_TYPE, _))
+            })
+            .cloned()
+            .chain(Some(room_policy_extension))
+            .collect::<Vec<_>>();
+        let extensions = Extensions::from_vec(extensions)?;
+
+        let aad_payload = InfraAadPayload::UpdateRoomPolicy;
+        let aad = InfraAadMessage::from(aad_payload).tls_serialize_detached()?;
+        self.mls_group.set_aad(aad);
+        let (mls_message, _welcome_option, group_info_option) = self
+            .mls_group
+            .update_group_context_extensions(provider, extensions, &self.leaf_signer)?;
+        // There shouldn't be a welcome
         debug_assert!(_welcome_option.is_none());
         let group_info =
             group_info_option.ok_or(anyhow!("No group info after commit operation"))?;
         let commit = AssistedMessageOut::new(mls_message, Some(group_info.into()))?;
 
-        for remove in self
+        let params = UpdateRoomPolicyParamsOut {
+            commit,
+            sender: own_user_key_hash,
+        };
+        Ok(Some(params))
+    }
+
+    /// Replace the shared chat metadata in the group data. All members that
+    /// are allowed to commit can change it.
+    pub(super) fn update_shared_chat_metadata(
+        &mut self,
+        connection: &Connection,
+        shared_metadata: &SharedChatMetadata,
+    ) -> Result<UpdateGroupDataParamsOut> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        if !self.own_can_commit()? {
+            bail!("The room policy doesn't allow the own user to commit")
+        }
+        validate_shared(shared_metadata)?;
+        let (attributes, _shared_metadata) = self
+            .group_data()
+            .ok_or(anyhow!("Missing group data"))?
+            .decode_with_metadata()?;
+        let group_data = GroupData::encode_with_metadata(&attributes, shared_metadata)?;
+        let group_data_extension = Extension::Unknown(
+            GROUP_DATA_EXTENSION_TYPE,
+            UnknownExtension(group_data.bytes().to_vec()),
+        );
+        let extensions = self
             .mls_group()
+            .extensions()
+            .iter()
+            .filter(|extension| {
+                !matches!(extension, Extension::Unknown(GROUP_DATA_EXTENSION_TYPE, _))
+            })
+            .cloned()
+            .chain(Some(group_data_extension))
+            .collect::<Vec<_>>();
+        let extensions = Extensions::from_vec(extensions)?;
+
+        let aad_payload = InfraAadPayload::UpdateGroupData;
+        let aad = InfraAadMessage::from(aad_payload).tls_serialize_detached()?;
+        self.mls_group.set_aad(aad);
+        let (mls_message, _welcome_option, group_info_option) = self
+            .mls_group
+            .update_group_context_extensions(provider, extensions, &self.leaf_signer)?;
+        // There shouldn't be a welcome
+        debug_assert!(_welcome_option.is_none());
+        let group_info =
+            group_info_option.ok_or(anyhow!("No group info after commit operation"))?;
+        let commit = AssistedMessageOut::new(mls_message, Some(group_info.into()))?;
+
+        let params = UpdateGroupDataParamsOut {
+            commit,
+            sender: self.mls_group.own_leaf_index(),
+        };
+        Ok(params)
+    }
+
+    /// Record in the membership history of the given conversation that we
+    /// joined the group in its current epoch. If we were added, `adder` is
+    /// the credential of the client that added us. Otherwise, we joined with
+    /// an external commit and added ourselves.
+    pub(crate) fn store_own_join(
+        &self,
+        connection: &Connection,
+        conversation_id: ConversationId,
+        adder: Option<&ClientCredential>,
+        timestamp: TimeStamp,
+    ) -> Result<()> {
+        let own_auth_info =
+            ClientAuthInfo::load(connection, self.group_id(), self.mls_group.own_leaf_index())?
+                .ok_or(anyhow!("Could not find own client credential"))?;
+        let own_credential: &ClientCredential = own_auth_info.client_credential();
+        let adder = adder.unwrap_or(own_credential);
+        MembershipChange::new(
+            conversation_id,
+            self.mls_group.epoch().as_u64(),
+            timestamp,
+            MembershipChangeKind::Add,
+            adder.identity().user_name(),
+            adder.fingerprint()?,
+            own_credential.identity().user_name(),
+            own_credential.fingerprint()?,
+        )
+        .store(connection)?;
+        Ok(())
+    }
+
+    /// The leaf index of the client that joins the group with the given
+    /// commit, if it is an external commit of a new member. Clients that
+    /// resync with an external commit remove their old leaf and stay members.
+    fn external_joiner(&self, staged_commit: &StagedCommit) -> Result<Option<LeafNodeIndex>> {
+        let is_external = staged_commit
+            .queued_proposals()
+            .any(|queued_proposal| matches!(queued_proposal.proposal(), Proposal::ExternalInit(_)));
+        if !is_external || staged_commit.remove_proposals().next().is_some() {
+            return Ok(None);
+        }
+        Ok(Some(self.mls_group.ext_commit_sender_index(staged_commit)?))
+    }
+
+    /// Decrypt and verify the client information a member sends along with
+    /// an update of its leaf, be it by commit or by proposal. Returns `None`
+    /// if the leaf credential of the member didn't change.
+    async fn updated_client_auth_info(
+        &self,
+        connection_mutex: SqliteConnection,
+        api_clients: &ApiClients,
+        sender_index: LeafNodeIndex,
+        new_sender_credential: &Credential,
+        update_client_payload: UpdateClientParamsAad,
+    ) -> Result<Option<ClientAuthInfo>> {
+        let group_id = self.group_id();
+        // Check if the client has updated its leaf credential.
+        let sender = self
+            .mls_group
+            .members()
+            .find(|m| m.index == sender_index)
+            .ok_or(anyhow!("Could not find sender in group members"))?;
+        if new_sender_credential == &sender.credential {
+            return Ok(None);
+        }
+        // If so, then there has to be a new signature ear key.
+        let Some(encrypted_signature_ear_key) =
+            update_client_payload.option_encrypted_signature_ear_key
+        else {
+            bail!("Invalid update client payload.")
+        };
+        // Optionally, the client could have updated its client credential.
+        let client_auth_info =
+            if let Some(ecc) = update_client_payload.option_encrypted_client_credential {
+                ClientAuthInfo::decrypt_and_verify(
+                    connection_mutex.clone(),
+                    api_clients,
+                    group_id,
+                    &self.credential_ear_key,
+                    &self.signature_ear_key_wrapper_key,
+                    (ecc, encrypted_signature_ear_key),
+                    sender_index,
+                )
+                .await?
+            } else {
+                // If not, we decrypt the new EAR key and use the existing
+                // client credential.
+                let signature_ear_key = SignatureEarKey::decrypt(
+                    &self.signature_ear_key_wrapper_key,
+                    &encrypted_signature_ear_key,
+                )?;
+                let connection = connection_mutex.lock().await;
+                let mut group_membership =
+                    GroupMembership::load(&connection, group_id, sender_index)?.ok_or(anyhow!(
+                        "Could not find group membership of sender in database"
+                    ))?;
+                group_membership.set_signature_ear_key(signature_ear_key);
+                let client_credential = StorableClientCredential::load(
+                    &connection,
+                    group_membership.client_credential_fingerprint(),
+                )?
+                .ok_or(anyhow!(
+                    "Could not find client credential of sender in database"
+                ))?;
+                drop(connection);
+
+                ClientAuthInfo::new(client_credential, group_membership)
+            };
+        // Verify the leaf credential
+        client_auth_info.verify_infra_credential(new_sender_credential)?;
+        Ok(Some(client_auth_info))
+    }
+
+    /// Stage the changes of the proposals our own pending commit commits.
+    fn stage_committed_proposals(&self, connection: &Connection) -> Result<()> {
+        let staged_commit = self
+            .mls_group
             .pending_commit()
-            .ok_or(anyhow!("No pending commit after commit operation"))?
-            .remove_proposals()
-        {
+            .ok_or(anyhow!("No pending commit after commit operation"))?;
+        for remove in staged_commit.remove_proposals() {
             GroupMembership::stage_removal(
                 connection,
                 self.group_id(),
                 remove.remove_proposal().removed(),
             )?;
         }
-
-        let params = DeleteGroupParamsOut {
-            commit,
-            sender: user_auth_key.verifying_key().hash(),
-        };
-        Ok(params)
+        for update in staged_commit.update_proposals() {
+            if let Sender::Member(proposer_index) = update.sender() {
+                GroupMembership::stage_proposed_update(
+                    connection,
+                    self.group_id(),
+                    *proposer_index,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     /// If a [`StagedCommit`] is given, merge it and apply the pending group
@@ -1157,45 +1636,93 @@ impl Group {
         let provider = &PhnxOpenMlsProvider::new(connection);
         let free_indices = GroupMembership::free_indices(connection, self.group_id())?;
         let staged_commit_option: Option<StagedCommit> = staged_commit_option.into();
-
-        let event_messages = if let Some(staged_commit) = staged_commit_option {
+        let group_data_update = match &staged_commit_option {
+            Some(staged_commit) => GroupData::proposed(staged_commit),
+            None => self
+                .mls_group
+                .pending_commit()
+                .and_then(GroupData::proposed),
+        };
+        let upgrade = staged_commit_option
+            .as_ref()
+            .or(self.mls_group.pending_commit())
+            .map(|staged_commit| self.proposed_upgrade(connection, staged_commit))
+            .transpose()?
+            .flatten();
+
+        let (event_messages, membership_changes) = if let Some(staged_commit) = staged_commit_option
+        {
             // Compute the messages we want to emit from the staged commit and the
             // client info diff.
-            let staged_commit_messages = TimestampedMessage::from_staged_commit(
+            let (mut staged_commit_messages, membership_changes) =
+                TimestampedMessage::from_staged_commit(
+                    connection,
+                    self.group_id(),
+                    free_indices,
+                    &staged_commit,
+                    self.external_joiner(&staged_commit)?,
+                    ds_timestamp,
+                )?;
+            staged_commit_messages.extend(self.room_policy_messages(
                 connection,
-                self.group_id(),
-                free_indices,
                 &staged_commit,
                 ds_timestamp,
-            )?;
+            )?);
 
             self.mls_group
                 .merge_staged_commit(provider, staged_commit)?;
-            staged_commit_messages
+            (staged_commit_messages, membership_changes)
         } else {
             // If we're merging a pending commit, we need to check if we have
             // committed a remove proposal by reference. If we have, we need to
             // create a notification message.
-            let staged_commit_messages =
-                if let Some(staged_commit) = self.mls_group.pending_commit() {
-                    TimestampedMessage::from_staged_commit(
-                        connection,
-                        self.group_id(),
-                        free_indices,
-                        staged_commit,
-                        ds_timestamp,
-                    )?
-                } else {
-                    vec![]
-                };
+            let staged_commit_messages = if let Some(staged_commit) =
+                self.mls_group.pending_commit()
+            {
+                let (mut messages, membership_changes) = TimestampedMessage::from_staged_commit(
+                    connection,
+                    self.group_id(),
+                    free_indices,
+                    staged_commit,
+                    self.external_joiner(staged_commit)?,
+                    ds_timestamp,
+                )?;
+                messages.extend(self.room_policy_messages(
+                    connection,
+                    staged_commit,
+                    ds_timestamp,
+                )?);
+                (messages, membership_changes)
+            } else {
+                (vec![], vec![])
+            };
             self.mls_group.merge_pending_commit(provider)?;
             staged_commit_messages
         };
+        self.store_epoch_change(connection, ds_timestamp)?;
+
+        if let Some(group_data) = group_data_update {
+            let (_attributes, shared_metadata) = group_data.decode_with_metadata()?;
+            let conversation_id = ConversationId::of_group(connection, self.group_id())?;
+            ChatMetadata::store_shared(connection, conversation_id, &shared_metadata)?;
+        }
+        if let Some(upgrade) = upgrade {
+            upgrade.store(connection)?;
+        }
 
         // We now apply the diff (if present)
         if let Some(diff) = self.pending_diff.take() {
+            // A new leaf signer can also stem from an update proposal, in
+            // which case it only applies if the proposal was committed.
             if let Some(leaf_signer) = diff.leaf_signer {
-                self.leaf_signer = leaf_signer;
+                if self
+                    .mls_group
+                    .own_leaf_node()
+                    .map(|leaf| leaf.signature_key())
+                    == Some(leaf_signer.credential().verifying_key())
+                {
+                    self.leaf_signer = leaf_signer;
+                }
             }
             if let Some(signature_ear_key) = diff.signature_ear_key {
                 self.signature_ear_key_wrapper_key = signature_ear_key;
@@ -1212,7 +1739,13 @@ impl Group {
         }
 
         GroupMembership::merge_for_group(connection, self.group_id())?;
+        // Proposals that weren't committed are void in the new epoch.
+        GroupMembership::delete_proposed_updates(connection, self.group_id())?;
         self.pending_diff = None;
+        // Only record the membership changes once the commit is merged.
+        for membership_change in membership_changes {
+            membership_change.store(connection)?;
+        }
         // Debug sanity checks after merging.
         #[cfg(debug_assertions)]
         {
@@ -1243,11 +1776,13 @@ impl Group {
         Ok(event_messages)
     }
 
-    /// Send an application message to the group.
+    /// Send an application message to the group. The `priority` determines
+    /// the queue lane in which the message is delivered to the recipients.
     pub(super) fn create_message(
         &mut self,
         connection: &Connection,
         content: MimiContent,
+        priority: QueuePriority,
     ) -> Result<SendMessageParamsOut, GroupOperationError> {
         let provider = &PhnxOpenMlsProvider::new(connection);
         let mls_message = self.mls_group.create_message(
@@ -1261,6 +1796,7 @@ impl Group {
         let send_message_params = SendMessageParamsOut {
             sender: self.mls_group.own_leaf_index(),
             message,
+            priority,
         };
 
         Ok(send_message_params)
@@ -1327,6 +1863,9 @@ impl Group {
     }
 
     pub(super) fn update(&mut self, connection: &Connection) -> Result<UpdateClientParamsOut> {
+        if !self.own_can_self_update()? {
+            bail!("Only admins can commit the pending proposals in this room")
+        }
         let provider = &PhnxOpenMlsProvider::new(connection);
         // We don't expect there to be a welcome.
         let aad_payload = UpdateClientParamsAad {
@@ -1343,18 +1882,94 @@ impl Group {
             .into_messages();
         let group_info = group_info_option.ok_or(anyhow!("No group info after commit"))?;
 
-        for remove in self
-            .mls_group()
-            .pending_commit()
-            .ok_or(anyhow!("No pending commit after commit operation"))?
-            .remove_proposals()
-        {
-            GroupMembership::stage_removal(
-                connection,
-                self.group_id(),
-                remove.remove_proposal().removed(),
-            )?;
-        }
+        self.stage_committed_proposals(connection)?;
+        let commit = AssistedMessageOut::new(mls_message, Some(group_info))?;
+        Ok(UpdateClientParamsOut {
+            commit,
+            sender: self.mls_group.own_leaf_index(),
+            new_user_auth_key_option: None,
+        })
+    }
+
+    /// Returns true if the own client can update its leaf with a commit.
+    ///
+    /// Members that can't commit (see [`Self::own_can_commit`]) can only
+    /// update their leaf while there are no pending proposals, since the commit
+    /// would otherwise commit or discard them.
+    pub(crate) fn own_can_self_update(&self) -> Result<bool> {
+        Ok(self.own_can_commit()? || self.mls_group.pending_proposals().next().is_none())
+    }
+
+    /// Returns `true` if the own leaf in this group was issued under a client
+    /// credential other than the one with the given fingerprint.
+    pub(super) fn has_outdated_client_credential(
+        &self,
+        connection: &Connection,
+        current_fingerprint: &CredentialFingerprint,
+    ) -> Result<bool> {
+        let own_membership = GroupMembership::load(connection, self.group_id(), self.own_index())?
+            .ok_or(anyhow!("Could not find own group membership"))?;
+        Ok(own_membership.client_credential_fingerprint() != current_fingerprint)
+    }
+
+    /// Replace the own leaf credential with one issued under the given
+    /// (refreshed) client signing key and share the new client credential
+    /// with the other members.
+    pub(super) fn update_client_credential(
+        &mut self,
+        connection: &Connection,
+        signing_key: &ClientSigningKey,
+    ) -> Result<UpdateClientParamsOut> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        let leaf_keys = LeafKeys::generate(signing_key)?;
+        let signature_ear_key = leaf_keys.signature_ear_key().clone();
+        let client_credential = signing_key.credential().clone();
+        let aad_payload = UpdateClientParamsAad {
+            option_encrypted_signature_ear_key: Some(
+                signature_ear_key.encrypt(&self.signature_ear_key_wrapper_key)?,
+            ),
+            option_encrypted_client_credential: Some(
+                client_credential.encrypt(&self.credential_ear_key)?,
+            ),
+        };
+        let aad = InfraAadMessage::from(InfraAadPayload::UpdateClient(aad_payload))
+            .tls_serialize_detached()?;
+        self.mls_group.set_aad(aad);
+        let credential_with_key = leaf_keys.credential()?;
+        let new_leaf_signer = leaf_keys.into_leaf_signer();
+        let new_signer = NewSignerBundle {
+            signer: &new_leaf_signer,
+            credential_with_key,
+        };
+        let (mls_message, _welcome_option, group_info_option) = self
+            .mls_group
+            .self_update_with_new_signer(
+                provider,
+                &self.leaf_signer,
+                new_signer,
+                LeafNodeParameters::default(),
+            )
+            .map_err(|e| anyhow!("Error performing group update: {:?}", e))?
+            .into_messages();
+        let group_info = group_info_option.ok_or(anyhow!("No group info after commit"))?;
+
+        self.stage_committed_proposals(connection)?;
+
+        // Our own membership now points to the new credential.
+        let own_group_membership = GroupMembership::new(
+            client_credential.identity(),
+            self.group_id().clone(),
+            self.mls_group.own_leaf_index(),
+            signature_ear_key,
+            client_credential.fingerprint()?,
+        );
+        ClientAuthInfo::new(client_credential, own_group_membership).stage_update(connection)?;
+
+        let mut diff = GroupDiff::new();
+        diff.leaf_signer = Some(new_leaf_signer);
+        self.pending_diff = Some(diff.stage());
+        self.store_pending_diff(connection)?;
+
         let commit = AssistedMessageOut::new(mls_message, Some(group_info))?;
         Ok(UpdateClientParamsOut {
             commit,
@@ -1363,6 +1978,102 @@ impl Group {
         })
     }
 
+    /// Propose to replace the own leaf credential with one issued under the
+    /// given (refreshed) client signing key, for members that can't commit
+    /// (see [`Self::own_can_commit`]). The new leaf signer is only used once a
+    /// member that can commit has committed the proposal.
+    pub(super) fn propose_client_credential_update(
+        &mut self,
+        connection: &Connection,
+        signing_key: &ClientSigningKey,
+    ) -> Result<ProposeClientUpdateParamsOut> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        let leaf_keys = LeafKeys::generate(signing_key)?;
+        let signature_ear_key = leaf_keys.signature_ear_key().clone();
+        let client_credential = signing_key.credential().clone();
+        let aad_payload = UpdateClientParamsAad {
+            option_encrypted_signature_ear_key: Some(
+                signature_ear_key.encrypt(&self.signature_ear_key_wrapper_key)?,
+            ),
+            option_encrypted_client_credential: Some(
+                client_credential.encrypt(&self.credential_ear_key)?,
+            ),
+        };
+        let aad = InfraAadMessage::from(InfraAadPayload::UpdateClient(aad_payload))
+            .tls_serialize_detached()?;
+        self.mls_group.set_aad(aad);
+        let credential_with_key = leaf_keys.credential()?;
+        let new_leaf_signer = leaf_keys.into_leaf_signer();
+        let new_signer = NewSignerBundle {
+            signer: &new_leaf_signer,
+            credential_with_key,
+        };
+        let (mls_message, _proposal_ref) = self
+            .mls_group
+            .propose_self_update_with_new_signer(
+                provider,
+                &self.leaf_signer,
+                new_signer,
+                LeafNodeParameters::default(),
+            )
+            .map_err(|e| anyhow!("Error proposing group update: {:?}", e))?;
+
+        // Our own membership points to the new credential once the proposal
+        // is committed.
+        let own_group_membership = GroupMembership::new(
+            client_credential.identity(),
+            self.group_id().clone(),
+            self.mls_group.own_leaf_index(),
+            signature_ear_key,
+            client_credential.fingerprint()?,
+        );
+        ClientAuthInfo::new(client_credential, own_group_membership)
+            .store_proposed_update(connection)?;
+
+        let mut diff = GroupDiff::new();
+        diff.leaf_signer = Some(new_leaf_signer);
+        self.pending_diff = Some(diff.stage());
+        self.store_pending_diff(connection)?;
+        self.store_proposals_pending_since(connection)?;
+
+        let update_proposal = AssistedMessageOut::new(mls_message, None)?;
+        Ok(ProposeClientUpdateParamsOut {
+            update_proposal,
+            sender: self.mls_group.own_leaf_index(),
+        })
+    }
+
+    fn own_update_proposals(&self) -> impl Iterator<Item = &QueuedProposal> {
+        let own_index = self.own_index();
+        self.mls_group.pending_proposals().filter(move |proposal| {
+            matches!(proposal.proposal(), Proposal::Update(_))
+                && matches!(proposal.sender(), Sender::Member(index) if *index == own_index)
+        })
+    }
+
+    /// Returns true if the own client has proposed to update its leaf in the
+    /// current epoch.
+    pub(crate) fn has_own_pending_update(&self) -> bool {
+        self.own_update_proposals().next().is_some()
+    }
+
+    /// Discard the own update proposal together with the new leaf signer, e.g.
+    /// because the DS rejected it.
+    pub(crate) fn discard_own_update_proposal(&mut self, connection: &Connection) -> Result<()> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        let proposal_refs: Vec<_> = self
+            .own_update_proposals()
+            .map(|proposal| proposal.proposal_reference())
+            .collect();
+        for proposal_ref in proposal_refs {
+            self.mls_group
+                .remove_pending_proposal(provider.storage(), &proposal_ref)?;
+        }
+        self.pending_diff = None;
+        self.store_pending_diff(connection)?;
+        Ok(())
+    }
+
     /// Update or set the user's auth key in this group.
     pub(super) fn update_user_key(
         &mut self,
@@ -1383,18 +2094,7 @@ impl Group {
             .into_messages();
         let group_info = group_info_option.ok_or(anyhow!("No group info after commit"))?;
 
-        for remove in self
-            .mls_group()
-            .pending_commit()
-            .ok_or(anyhow!("No pending commit after commit operation"))?
-            .remove_proposals()
-        {
-            GroupMembership::stage_removal(
-                connection,
-                self.group_id(),
-                remove.remove_proposal().removed(),
-            )?;
-        }
+        self.stage_committed_proposals(connection)?;
 
         let mut diff = GroupDiff::new();
 
@@ -1403,6 +2103,7 @@ impl Group {
 
         diff.user_auth_key = Some(user_auth_signing_key);
         self.pending_diff = Some(diff.stage());
+        self.store_pending_diff(connection)?;
 
         let commit = AssistedMessageOut::new(commit, Some(group_info))?;
         let params = UpdateClientParamsOut {
@@ -1435,6 +2136,10 @@ impl Group {
         &self.leaf_signer
     }
 
+    pub(crate) fn own_index(&self) -> LeafNodeIndex {
+        self.mls_group().own_leaf_index()
+    }
+
     pub(super) fn store_proposal(
         &mut self,
         connection: &Connection,
@@ -1443,6 +2148,7 @@ impl Group {
         let provider = &PhnxOpenMlsProvider::new(connection);
         self.mls_group
             .store_pending_proposal(provider.storage(), proposal)?;
+        self.store_proposals_pending_since(connection)?;
         Ok(())
     }
 
@@ -1458,6 +2164,106 @@ impl Group {
             .collect()
     }
 
+    pub(crate) fn room_policy(&self) -> Result<RoomPolicy> {
+        Ok(RoomPolicy::from_extensions(self.mls_group().extensions())?)
+    }
+
+    /// Returns true if the own user is an admin of the room.
+    pub(crate) fn is_own_admin(&self) -> Result<bool> {
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            return Ok(false);
+        };
+        Ok(self
+            .room_policy()?
+            .is_admin(&user_auth_key.verifying_key().hash()))
+    }
+
+    /// Returns true if the room policy allows the own user to send messages.
+    pub(crate) fn can_own_send(&self) -> Result<bool> {
+        let room_policy = self.room_policy()?;
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            return Ok(room_policy.send_rule == SendRule::AllMembers);
+        };
+        Ok(room_policy.can_send(&user_auth_key.verifying_key().hash()))
+    }
+
+    /// Returns true if the room policy allows the own user to invite others.
+    pub(crate) fn own_can_invite(&self) -> Result<bool> {
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            return Ok(false);
+        };
+        Ok(self
+            .room_policy()?
+            .can_invite(&user_auth_key.verifying_key().hash()))
+    }
+
+    /// Returns true if the room policy allows the own user to commit.
+    pub(crate) fn own_can_commit(&self) -> Result<bool> {
+        let room_policy = self.room_policy()?;
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            return Ok(room_policy.commit_rule == CommitRule::AllMembers);
+        };
+        Ok(room_policy.can_commit(&user_auth_key.verifying_key().hash()))
+    }
+
+    /// Returns an error if the given staged commit changes the room policy.
+    fn ensure_room_policy_unchanged(&self, staged_commit: &StagedCommit) -> Result<()> {
+        let Some((extensions_proposal, _)) = proposed_group_context_extensions(staged_commit)
+        else {
+            return Ok(());
+        };
+        let new_policy = RoomPolicy::from_extensions(extensions_proposal.extensions())?;
+        if new_policy != self.room_policy()? {
+            bail!("Only room policy updates can change the room policy");
+        }
+        Ok(())
+    }
+
+    /// Returns system messages for the changes to the room policy made by
+    /// the given staged commit.
+    ///
+    /// Incoming commits that change the room policy without being room policy
+    /// updates are rejected in [`Self::process_message`], so all changes
+    /// reported here have been checked by the DS to come from an admin.
+    fn room_policy_messages(
+        &self,
+        connection: &Connection,
+        staged_commit: &StagedCommit,
+        ds_timestamp: TimeStamp,
+    ) -> Result<Vec<TimestampedMessage>> {
+        let Some((extensions_proposal, sender)) = proposed_group_context_extensions(staged_commit)
+        else {
+            return Ok(vec![]);
+        };
+        let Sender::Member(sender_index) = sender else {
+            bail!("Only member proposals are supported for now")
+        };
+        let old_policy = self.room_policy()?;
+        let new_policy = RoomPolicy::from_extensions(extensions_proposal.extensions())?;
+        let changes = old_policy.diff(&new_policy);
+        if changes.is_empty() {
+            return Ok(vec![]);
+        }
+        let sender_name = ClientAuthInfo::load(connection, self.group_id(), *sender_index)?
+            .ok_or(anyhow!("Could not find client credential of sender"))?
+            .client_credential()
+            .identity()
+            .user_name();
+        let messages = changes
+            .into_iter()
+            .map(|change| {
+                TimestampedMessage::system_message(
+                    SystemMessage::ChangeRoomPolicy {
+                        admin: sender_name.clone(),
+                        change,
+                    },
+                    ds_timestamp,
+                )
+            })
+            .collect();
+        Ok(messages)
+    }
+
     pub(crate) fn group_data(&self) -> Option<GroupData> {
         self.mls_group().extensions().iter().find_map(|e| match e {
             Extension::Unknown(GROUP_DATA_EXTENSION_TYPE, extension_bytes) => {
@@ -1466,21 +2272,69 @@ impl Group {
             _ => None,
         })
     }
+
+    /// Decode the [`ConversationAttributes`] from the group data of a joined
+    /// group.
+    pub(crate) fn conversation_attributes(
+        &self,
+    ) -> Result<ConversationAttributes, WelcomeProcessingError> {
+        let group_data = self
+            .group_data()
+            .ok_or(WelcomeProcessingError::MissingGroupData)?;
+        let size = group_data.bytes().len();
+        if size > MAX_GROUP_DATA_SIZE {
+            return Err(WelcomeProcessingError::GroupDataTooLarge {
+                size,
+                max: MAX_GROUP_DATA_SIZE,
+            });
+        }
+        Ok(group_data.decode()?)
+    }
+
+    /// The shared chat metadata from the group data of a joined group.
+    /// Invalid metadata is ignored, since it doesn't prevent joining.
+    pub(crate) fn shared_chat_metadata(&self) -> SharedChatMetadata {
+        let shared_metadata = self
+            .group_data()
+            .map(|group_data| group_data.decode_with_metadata())
+            .transpose()
+            .map(|decoded| decoded.map(|(_attributes, shared_metadata)| shared_metadata));
+        match shared_metadata {
+            Ok(Some(shared_metadata)) => match validate_shared(&shared_metadata) {
+                Ok(()) => shared_metadata,
+                Err(error) => {
+                    log::warn!("Ignoring invalid shared chat metadata: {error}");
+                    SharedChatMetadata::new()
+                }
+            },
+            Ok(None) | Err(_) => SharedChatMetadata::new(),
+        }
+    }
 }
 
 impl TimestampedMessage {
     /// Turn a staged commit into a list of messages based on the proposals it
-    /// includes.
+    /// includes, together with the membership changes the commit makes.
+    ///
+    /// The membership changes are only returned, such that they can be
+    /// stored once the commit was merged successfully.
     fn from_staged_commit(
         connection: &Connection,
         group_id: &GroupId,
         free_indices: impl Iterator<Item = LeafNodeIndex>,
         staged_commit: &StagedCommit,
+        external_joiner: Option<LeafNodeIndex>,
         ds_timestamp: TimeStamp,
-    ) -> Result<Vec<Self>> {
-        // Collect the remover/removed pairs into a set to avoid duplicates.
-        let removed_set = staged_commit
+    ) -> Result<(Vec<Self>, Vec<MembershipChange>)> {
+        let conversation_id = ConversationId::of_group(connection, group_id)?;
+        let epoch = staged_commit.group_context().epoch().as_u64();
+
+        // Record every removed client in the membership history.
+        let removals = staged_commit
             .remove_proposals()
+            // Clients that resync remove their old leaf with an external
+            // commit, but stay members.
+            .filter(|remove_proposal| !matches!(remove_proposal.sender(), Sender::NewMemberCommit))
             .map(|remove_proposal| {
                 let Sender::Member(sender_index) = remove_proposal.sender() else {
                     bail!("Only member proposals are supported for now")
@@ -1493,28 +2347,33 @@ impl TimestampedMessage {
                     // This is in case we removed ourselves.
                     ClientAuthInfo::load_staged(connection, group_id, *sender_index)?
                         .ok_or(anyhow!("Could not find client credential of remover"))?
-                }
-                .client_credential()
-                .identity()
-                .user_name();
+                };
                 let removed_index = remove_proposal.remove_proposal().removed();
                 let removed = ClientAuthInfo::load_staged(connection, group_id, removed_index)?
-                    .ok_or(anyhow!("Could not find client credential of removed"))?
-                    .client_credential()
-                    .identity()
-                    .user_name();
-                Ok((remover, removed))
+                    .ok_or(anyhow!("Could not find client credential of removed"))?;
+                Ok(MembershipChange::new(
+                    conversation_id,
+                    epoch,
+                    ds_timestamp,
+                    MembershipChangeKind::Remove,
+                    remover.client_credential().identity().user_name(),
+                    remover.client_credential().fingerprint()?,
+                    removed.client_credential().identity().user_name(),
+                    removed.client_credential().fingerprint()?,
+                ))
             })
-            .collect::<Result<HashSet<_>>>()?;
-        let remove_messages = removed_set.into_iter().map(|(remover, removed)| {
-            TimestampedMessage::system_message(
-                SystemMessage::Remove(remover, removed),
-                ds_timestamp,
-            )
-        });
+            .collect::<Result<Vec<_>>>()?;
+        // One message per remover, since a user can have several clients.
+        let removed_pairs = removals
+            .iter()
+            .map(|removal| (removal.sender().clone(), removal.member().clone()))
+            .collect();
+        let remove_messages = SystemMessage::removes(removed_pairs)
+            .into_iter()
+            .map(|message| TimestampedMessage::system_message(message, ds_timestamp));
 
-        // Collect adder and addee names and filter out duplicates
-        let adds_set = staged_commit
+        // Record every added client in the membership history.
+        let additions = staged_commit
             .add_proposals()
             .zip(free_indices)
             .map(|(staged_add_proposal, free_index)| {
@@ -1522,30 +2381,60 @@ impl TimestampedMessage {
                     // We don't support non-member adds.
                     bail!("Non-member add proposal")
                 };
-                // Get the name of the sender from the list of existing clients
-                let sender_name = ClientAuthInfo::load(connection, group_id, *sender_index)?
-                    .ok_or(anyhow!("Could not find client credential of sender"))?
-                    .client_credential()
-                    .identity()
-                    .user_name();
-                // Get the name of the added member from the diff containing
-                // the new clients.
-                let addee_name = ClientAuthInfo::load_staged(connection, group_id, free_index)?
-                    .ok_or(anyhow!(
+                // Get the sender from the list of existing clients
+                let sender = ClientAuthInfo::load(connection, group_id, *sender_index)?
+                    .ok_or(anyhow!("Could not find client credential of sender"))?;
+                // Get the added member from the diff containing the new
+                // clients.
+                let addee = ClientAuthInfo::load_staged(connection, group_id, free_index)?.ok_or(
+                    anyhow!(
                         "Could not find client credential of added client at index {}",
                         free_index
-                    ))?
-                    .client_credential()
-                    .identity()
-                    .user_name();
-                Ok((sender_name, addee_name))
+                    ),
+                )?;
+                Ok(MembershipChange::new(
+                    conversation_id,
+                    epoch,
+                    ds_timestamp,
+                    MembershipChangeKind::Add,
+                    sender.client_credential().identity().user_name(),
+                    sender.client_credential().fingerprint()?,
+                    addee.client_credential().identity().user_name(),
+                    addee.client_credential().fingerprint()?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // One message per adder, since a user can have several clients.
+        let added_pairs = additions
+            .iter()
+            .map(|addition| (addition.sender().clone(), addition.member().clone()))
+            .collect();
+        let add_messages = SystemMessage::adds(added_pairs)
+            .into_iter()
+            .map(|message| TimestampedMessage::system_message(message, ds_timestamp));
+
+        // A client that joins with an external commit adds itself.
+        let join = external_joiner
+            .map(|joiner_index| {
+                let joiner = ClientAuthInfo::load_staged(connection, group_id, joiner_index)?
+                    .ok_or(anyhow!("Could not find client credential of joiner"))?;
+                let user_name = joiner.client_credential().identity().user_name();
+                let fingerprint = joiner.client_credential().fingerprint()?;
+                anyhow::Ok(MembershipChange::new(
+                    conversation_id,
+                    epoch,
+                    ds_timestamp,
+                    MembershipChangeKind::Add,
+                    user_name.clone(),
+                    fingerprint.clone(),
+                    user_name,
+                    fingerprint,
+                ))
             })
-            .collect::<Result<HashSet<_>>>()?;
-        let add_messages = adds_set.into_iter().map(|(adder, addee)| {
-            TimestampedMessage::system_message(SystemMessage::Add(adder, addee), ds_timestamp)
-        });
+            .transpose()?;
 
         let event_messages = remove_messages.chain(add_messages).collect();
+        let membership_changes = removals.into_iter().chain(additions).chain(join).collect();
 
         // Emit log messages for updates.
         staged_commit
@@ -1568,6 +2457,49 @@ impl TimestampedMessage {
                 Ok(())
             })?;
 
-        Ok(event_messages)
+        Ok((event_messages, membership_changes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_data_codec() {
+        let attributes = ConversationAttributes::new("Title".to_owned(), Some(vec![1, 2, 3]));
+
+        let group_data = GroupData::encode(&attributes).unwrap();
+        assert_eq!(group_data.decode().unwrap(), attributes);
+
+        // Group data of groups created before versioning was introduced
+        let legacy_group_data = GroupData::from(PhnxCodec::to_vec(&attributes).unwrap());
+        assert_eq!(legacy_group_data.decode().unwrap(), attributes);
+
+        let invalid_group_data = GroupData::from(vec![1, 2, 3]);
+        assert!(invalid_group_data.decode().is_err());
+    }
+
+    #[test]
+    fn group_data_with_shared_metadata() {
+        let attributes = ConversationAttributes::new("Title".to_owned(), None);
+
+        // Without shared metadata, older clients must still be able to
+        // decode the group data.
+        let group_data =
+            GroupData::encode_with_metadata(&attributes, &SharedChatMetadata::new()).unwrap();
+        assert_eq!(
+            group_data.bytes(),
+            GroupData::encode(&attributes).unwrap().bytes()
+        );
+
+        let shared_metadata =
+            SharedChatMetadata::from([("color".to_owned(), "#ff0000".to_owned())]);
+        let group_data = GroupData::encode_with_metadata(&attributes, &shared_metadata).unwrap();
+        assert_eq!(group_data.decode().unwrap(), attributes);
+        assert_eq!(
+            group_data.decode_with_metadata().unwrap(),
+            (attributes, shared_metadata)
+        );
     }
 }
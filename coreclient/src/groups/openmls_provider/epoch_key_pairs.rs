@@ -84,4 +84,15 @@ impl<GroupId: Key<CURRENT_VERSION>> StorableGroupIdRef<'_, GroupId> {
         )?;
         Ok(())
     }
+
+    pub(super) fn delete_all_epoch_key_pairs(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM epoch_keys_pairs WHERE group_id = ?1",
+            params![KeyRefWrapper(self.0)],
+        )?;
+        Ok(())
+    }
 }
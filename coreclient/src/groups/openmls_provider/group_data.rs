@@ -134,4 +134,15 @@ impl<GroupId: Key<CURRENT_VERSION>> StorableGroupIdRef<'_, GroupId> {
         )?;
         Ok(())
     }
+
+    pub(super) fn delete_all_group_data(
+        &self,
+        connection: &Connection,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM group_data WHERE group_id = ?",
+            params![KeyRefWrapper(self.0)],
+        )?;
+        Ok(())
+    }
 }
@@ -4,8 +4,7 @@
 
 use openmls_rust_crypto::RustCrypto;
 use openmls_traits::random::OpenMlsRand;
-use rand::{RngCore, SeedableRng};
-use rand_chacha::ChaCha20Rng;
+use phnxtypes::crypto::rng;
 use storage_provider::SqliteStorageProvider;
 use thiserror::Error;
 
@@ -38,16 +37,17 @@ impl<'a> PhnxOpenMlsProvider<'a> {
 impl<'a> OpenMlsProvider for PhnxOpenMlsProvider<'a> {
     type StorageProvider = SqliteStorageProvider<'a>;
     type CryptoProvider = RustCrypto;
-    type RandProvider = RustCrypto;
+    type RandProvider = Self;
 
     /// Get the crypto provider.
     fn crypto(&self) -> &Self::CryptoProvider {
         &self.crypto
     }
 
-    /// Get the randomness provider.
+    /// Get the randomness provider. Randomness is drawn from the RNG of
+    /// phnxtypes, such that tests can seed it.
     fn rand(&self) -> &Self::RandProvider {
-        &self.crypto
+        self
     }
 
     fn storage(&self) -> &Self::StorageProvider {
@@ -59,19 +59,11 @@ impl OpenMlsRand for PhnxOpenMlsProvider<'_> {
     type Error = PhnxRandomnessError;
 
     fn random_array<const N: usize>(&self) -> std::result::Result<[u8; N], Self::Error> {
-        let mut rng = ChaCha20Rng::from_entropy();
-        let mut out = [0u8; N];
-        rng.try_fill_bytes(&mut out)
-            .map_err(|_| Self::Error::NotEnoughRandomness)?;
-        Ok(out)
+        rng::random_array().map_err(|_| Self::Error::NotEnoughRandomness)
     }
 
     fn random_vec(&self, len: usize) -> std::result::Result<Vec<u8>, Self::Error> {
-        let mut rng = ChaCha20Rng::from_entropy();
-        let mut out = vec![0u8; len];
-        rng.try_fill_bytes(&mut out)
-            .map_err(|_| Self::Error::NotEnoughRandomness)?;
-        Ok(out)
+        rng::random_vec(len).map_err(|_| Self::Error::NotEnoughRandomness)
     }
 }
 
@@ -87,6 +87,24 @@ impl<T: Entity<CURRENT_VERSION>> FromSql for EntityVecWrapper<T> {
 
 pub(super) struct StorableGroupIdRef<'a, GroupId: Key<CURRENT_VERSION>>(pub &'a GroupId);
 
+impl SqliteStorageProvider<'_> {
+    /// Delete the complete MLS state of the given group. Unlike
+    /// [`openmls::group::MlsGroup::delete`], this doesn't require the state to
+    /// be loadable.
+    pub(crate) fn delete_all_group_state<
+        GroupId: openmls_traits::storage::traits::GroupId<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        let group_id = StorableGroupIdRef(group_id);
+        group_id.delete_all_group_data(self.connection)?;
+        group_id.delete_all_proposals(self.connection)?;
+        group_id.delete_leaf_nodes(self.connection)?;
+        group_id.delete_all_epoch_key_pairs(self.connection)
+    }
+}
+
 impl StorageProvider<{ CURRENT_VERSION }> for SqliteStorageProvider<'_> {
     type Error = rusqlite::Error;
 
@@ -10,6 +10,7 @@ use phnxtypes::{
         ear::keys::{ClientCredentialEarKey, GroupStateEarKey, SignatureEarKeyWrapperKey},
         signatures::keys::UserAuthSigningKey,
     },
+    time::TimeStamp,
 };
 use rusqlite::{params, OptionalExtension, Transaction};
 
@@ -17,14 +18,24 @@ use crate::utils::persistence::{GroupIdRefWrapper, GroupIdWrapper, Storable};
 
 use super::{diff::StagedGroupDiff, openmls_provider::PhnxOpenMlsProvider, Group};
 
+/// Columns tracking the state relevant for the security status of a group,
+/// see [`super::security`].
+pub(crate) const GROUP_SECURITY_COLUMNS: &str = "
+    ALTER TABLE groups ADD COLUMN epoch_changed_at TEXT;
+    ALTER TABLE groups ADD COLUMN proposals_pending_since TEXT;";
+
+/// Column tracking since when a commit is pending, see [`super::janitor`].
+pub(crate) const GROUP_COMMIT_PENDING_COLUMN: &str =
+    "ALTER TABLE groups ADD COLUMN commit_pending_since TEXT;";
+
 pub(crate) struct StorableGroup {
-    group_id: GroupId,
-    leaf_signer: InfraCredentialSigningKey,
-    signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
-    credential_ear_key: ClientCredentialEarKey,
-    group_state_ear_key: GroupStateEarKey,
-    user_auth_signing_key_option: Option<UserAuthSigningKey>,
-    pending_diff: Option<StagedGroupDiff>,
+    pub(super) group_id: GroupId,
+    pub(super) leaf_signer: InfraCredentialSigningKey,
+    pub(super) signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
+    pub(super) credential_ear_key: ClientCredentialEarKey,
+    pub(super) group_state_ear_key: GroupStateEarKey,
+    pub(super) user_auth_signing_key_option: Option<UserAuthSigningKey>,
+    pub(super) pending_diff: Option<StagedGroupDiff>,
 }
 
 impl Storable for StorableGroup {
@@ -60,11 +71,46 @@ impl Storable for StorableGroup {
     }
 }
 
+impl StorableGroup {
+    /// Load the group without its MLS state, e.g. to rejoin the group after
+    /// the MLS state was lost.
+    pub(crate) fn load(
+        connection: &rusqlite::Connection,
+        group_id: &GroupId,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(group_id);
+        let mut stmt = connection.prepare("SELECT * FROM groups WHERE group_id = ?")?;
+        stmt.query_row(params![group_id], StorableGroup::from_row)
+            .optional()
+    }
+
+    pub(crate) fn group_state_ear_key(&self) -> &GroupStateEarKey {
+        &self.group_state_ear_key
+    }
+
+    pub(crate) fn user_auth_key(&self) -> Option<&UserAuthSigningKey> {
+        self.user_auth_signing_key_option.as_ref()
+    }
+
+    pub(super) fn into_group(self, mls_group: MlsGroup) -> Group {
+        Group {
+            group_id: self.group_id,
+            leaf_signer: self.leaf_signer,
+            signature_ear_key_wrapper_key: self.signature_ear_key_wrapper_key,
+            credential_ear_key: self.credential_ear_key,
+            group_state_ear_key: self.group_state_ear_key,
+            user_auth_signing_key_option: self.user_auth_signing_key_option,
+            pending_diff: self.pending_diff,
+            mls_group,
+        }
+    }
+}
+
 impl Group {
     pub(crate) fn store(&self, connection: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
         let group_id = GroupIdRefWrapper::from(&self.group_id);
         connection.execute(
-            "INSERT INTO groups (group_id, leaf_signer, signature_ear_key_wrapper_key, credential_ear_key, group_state_ear_key, user_auth_signing_key_option, pending_diff) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO groups (group_id, leaf_signer, signature_ear_key_wrapper_key, credential_ear_key, group_state_ear_key, user_auth_signing_key_option, pending_diff, epoch_changed_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
             params![
                 group_id,
                 self.leaf_signer,
@@ -73,6 +119,7 @@ impl Group {
                 self.group_state_ear_key,
                 self.user_auth_signing_key_option,
                 self.pending_diff,
+                TimeStamp::now(),
             ],
         )?;
         Ok(())
@@ -88,22 +135,8 @@ impl Group {
             println!("While loading, MlsGroup::load returned None");
             return Ok(None);
         };
-        let group_id = GroupIdRefWrapper::from(group_id);
-        let mut stmt = connection.prepare("SELECT * FROM groups WHERE group_id = ?")?;
-        stmt.query_row(params![group_id], StorableGroup::from_row)
-            .optional()
-            .map(|sg| {
-                sg.map(|sg| Group {
-                    group_id: sg.group_id,
-                    leaf_signer: sg.leaf_signer,
-                    signature_ear_key_wrapper_key: sg.signature_ear_key_wrapper_key,
-                    credential_ear_key: sg.credential_ear_key,
-                    group_state_ear_key: sg.group_state_ear_key,
-                    user_auth_signing_key_option: sg.user_auth_signing_key_option,
-                    pending_diff: sg.pending_diff,
-                    mls_group,
-                })
-            })
+        let group = StorableGroup::load(connection, group_id)?;
+        Ok(group.map(|group| group.into_group(mls_group)))
     }
 
     pub(crate) fn store_update(
@@ -126,6 +159,94 @@ impl Group {
         Ok(())
     }
 
+    /// Record that the group moved to a new epoch at the given time. Merging
+    /// a commit also clears all pending proposals.
+    pub(crate) fn store_epoch_change(
+        &self,
+        connection: &rusqlite::Connection,
+        changed_at: TimeStamp,
+    ) -> Result<(), rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(&self.group_id);
+        connection.execute(
+            "UPDATE groups SET epoch_changed_at = ?, proposals_pending_since = NULL, commit_pending_since = NULL WHERE group_id = ?",
+            params![changed_at, group_id],
+        )?;
+        Ok(())
+    }
+
+    /// Record that a proposal is pending, unless older proposals are already
+    /// pending.
+    pub(super) fn store_proposals_pending_since(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<(), rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(&self.group_id);
+        connection.execute(
+            "UPDATE groups SET proposals_pending_since = COALESCE(proposals_pending_since, ?) WHERE group_id = ?",
+            params![TimeStamp::now(), group_id],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the time of the last epoch change and since when proposals are
+    /// pending.
+    pub(super) fn load_security_timestamps(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<(Option<TimeStamp>, Option<TimeStamp>), rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(&self.group_id);
+        connection.query_row(
+            "SELECT epoch_changed_at, proposals_pending_since FROM groups WHERE group_id = ?",
+            params![group_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Persist the pending diff right away, such that it can be applied when
+    /// a commit is recovered after its response from the DS was lost.
+    pub(super) fn store_pending_diff(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<(), rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(&self.group_id);
+        connection.execute(
+            "UPDATE groups SET pending_diff = ? WHERE group_id = ?",
+            params![self.pending_diff, group_id],
+        )?;
+        Ok(())
+    }
+
+    /// Returns since when the pending commit of the group is known to be
+    /// pending. Records the current time if it was not known before.
+    pub(super) fn load_or_store_commit_pending_since(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<TimeStamp, rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(&self.group_id);
+        connection.execute(
+            "UPDATE groups SET commit_pending_since = COALESCE(commit_pending_since, ?) WHERE group_id = ?",
+            params![TimeStamp::now(), group_id],
+        )?;
+        connection.query_row(
+            "SELECT commit_pending_since FROM groups WHERE group_id = ?",
+            params![group_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Forget about pending commits and proposals after they were discarded.
+    pub(super) fn clear_pending_since(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<(), rusqlite::Error> {
+        let group_id = GroupIdRefWrapper::from(&self.group_id);
+        connection.execute(
+            "UPDATE groups SET proposals_pending_since = NULL, commit_pending_since = NULL WHERE group_id = ?",
+            params![group_id],
+        )?;
+        Ok(())
+    }
+
     pub(crate) fn delete_from_db(
         transaction: &mut Transaction,
         group_id: &GroupId,
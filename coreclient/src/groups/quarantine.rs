@@ -0,0 +1,172 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Welcome bundles that could not be processed are kept in quarantine, such
+//! that the failure can be diagnosed and processing can be retried later.
+//!
+//! Bundles larger than [`MAX_WELCOME_BUNDLE_SIZE`] are never processed, so
+//! only the error is kept for them. Quarantined welcomes are deleted after
+//! [`QUARANTINE_RETENTION`].
+
+use phnxtypes::{
+    crypto::rng,
+    time::{Duration, TimeStamp},
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::utils::persistence::Storable;
+
+use super::MAX_WELCOME_BUNDLE_SIZE;
+
+/// Time after which quarantined welcomes are deleted.
+pub(crate) const QUARANTINE_RETENTION: Duration = Duration::days(30);
+
+#[derive(Debug, Clone)]
+pub struct QuarantinedWelcome {
+    pub id: Uuid,
+    pub received_at: TimeStamp,
+    /// Description of the error that occurred while processing the welcome.
+    pub error: String,
+    /// The TLS-serialized welcome bundle. Empty if the bundle was too large
+    /// to be kept.
+    pub(crate) welcome_bundle: Vec<u8>,
+}
+
+impl Storable for QuarantinedWelcome {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS quarantined_welcomes (
+            id BLOB PRIMARY KEY,
+            received_at TEXT NOT NULL,
+            error TEXT NOT NULL,
+            welcome_bundle BLOB NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let id = row.get(0)?;
+        let received_at = row.get(1)?;
+        let error = row.get(2)?;
+        let welcome_bundle = row.get(3)?;
+        Ok(Self {
+            id,
+            received_at,
+            error,
+            welcome_bundle,
+        })
+    }
+}
+
+impl QuarantinedWelcome {
+    pub(crate) fn new(mut welcome_bundle: Vec<u8>, error: String) -> Self {
+        if welcome_bundle.len() > MAX_WELCOME_BUNDLE_SIZE {
+            welcome_bundle = Vec::new();
+        }
+        Self {
+            id: rng::random_uuid(),
+            received_at: TimeStamp::now(),
+            error,
+            welcome_bundle,
+        }
+    }
+
+    /// Returns `true` if the welcome bundle was kept, such that processing it
+    /// can be retried.
+    pub(crate) fn has_welcome_bundle(&self) -> bool {
+        !self.welcome_bundle.is_empty()
+    }
+
+    /// Store the quarantined welcome and delete the ones that were received
+    /// more than [`QUARANTINE_RETENTION`] before it.
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        let expired_before = TimeStamp::from(*self.received_at - QUARANTINE_RETENTION);
+        connection.execute(
+            "DELETE FROM quarantined_welcomes WHERE received_at < ?",
+            params![expired_before],
+        )?;
+        connection.execute(
+            "INSERT INTO quarantined_welcomes (id, received_at, error, welcome_bundle) VALUES (?, ?, ?, ?)",
+            params![self.id, self.received_at, self.error, self.welcome_bundle],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load(connection: &Connection, id: Uuid) -> Result<Option<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT id, received_at, error, welcome_bundle FROM quarantined_welcomes WHERE id = ?",
+        )?;
+        statement.query_row(params![id], Self::from_row).optional()
+    }
+
+    pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT id, received_at, error, welcome_bundle FROM quarantined_welcomes ORDER BY received_at ASC",
+        )?;
+        let welcomes = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(welcomes)
+    }
+
+    pub(crate) fn delete(connection: &Connection, id: Uuid) -> Result<(), rusqlite::Error> {
+        connection.execute("DELETE FROM quarantined_welcomes WHERE id = ?", params![id])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::migration::run_migrations;
+
+    use super::*;
+
+    #[test]
+    fn oversized_bundles_are_not_kept() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let welcome = QuarantinedWelcome::new(vec![1; 16], "Invalid".to_owned());
+        assert!(welcome.has_welcome_bundle());
+        welcome.store(&connection).unwrap();
+        let oversized =
+            QuarantinedWelcome::new(vec![1; MAX_WELCOME_BUNDLE_SIZE + 1], "Too large".to_owned());
+        assert!(!oversized.has_welcome_bundle());
+        oversized.store(&connection).unwrap();
+
+        let loaded = QuarantinedWelcome::load(&connection, oversized.id)
+            .unwrap()
+            .unwrap();
+        assert!(loaded.welcome_bundle.is_empty());
+        assert_eq!(loaded.error, "Too large");
+        let loaded = QuarantinedWelcome::load(&connection, welcome.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.welcome_bundle, vec![1; 16]);
+    }
+
+    #[test]
+    fn expired_welcomes_are_deleted() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+
+        let mut expired = QuarantinedWelcome::new(vec![1], "Expired".to_owned());
+        expired.received_at =
+            TimeStamp::from(*expired.received_at - QUARANTINE_RETENTION - Duration::days(1));
+        expired.store(&connection).unwrap();
+        let mut recent = QuarantinedWelcome::new(vec![2], "Recent".to_owned());
+        recent.received_at =
+            TimeStamp::from(*recent.received_at - QUARANTINE_RETENTION + Duration::days(1));
+        recent.store(&connection).unwrap();
+        assert_eq!(QuarantinedWelcome::load_all(&connection).unwrap().len(), 2);
+
+        // Storing a new welcome deletes the ones past the retention period
+        let new = QuarantinedWelcome::new(vec![3], "New".to_owned());
+        new.store(&connection).unwrap();
+        let ids: Vec<_> = QuarantinedWelcome::load_all(&connection)
+            .unwrap()
+            .into_iter()
+            .map(|welcome| welcome.id)
+            .collect();
+        assert_eq!(ids, vec![recent.id, new.id]);
+    }
+}
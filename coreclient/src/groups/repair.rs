@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Recovery of groups whose MLS state can't be loaded anymore.
+//!
+//! The client rejoins the group with an external commit on the group info
+//! provided by the DS. The keys of the group, i.e. the leaf signer and the
+//! EAR keys, are stored outside of the MLS state and are reused. Since the
+//! leaf has the same signature key as the client's old leaf, OpenMLS adds a
+//! remove proposal for the old leaf to the commit, which the DS and the
+//! other members process as a resync of the client.
+
+use anyhow::{anyhow, bail, Result};
+use mls_assist::messages::AssistedMessageOut;
+use openmls::{
+    group::{GroupId, MlsGroup},
+    prelude::{CredentialWithKey, OpenMlsProvider},
+};
+use phnxtypes::{
+    credentials::ClientCredential,
+    messages::client_ds::{ExternalCommitInfoIn, InfraAadMessage, InfraAadPayload},
+};
+use rusqlite::Connection;
+use tls_codec::Serialize as TlsSerializeTrait;
+
+use crate::{clients::api_clients::ApiClients, utils::persistence::SqliteConnection};
+
+use super::{
+    client_auth_info::{ClientAuthInfo, GroupMembership},
+    default_capabilities,
+    openmls_provider::PhnxOpenMlsProvider,
+    persistence::StorableGroup,
+    Group,
+};
+
+impl Group {
+    /// Rejoin the group with the given external commit info, replacing the
+    /// local MLS state and group memberships. Returns the group together with
+    /// the commit that has to be sent to the DS as a resync.
+    ///
+    /// The new state is stored right away. If the DS rejects the commit, it
+    /// has to be removed again with [`Self::discard_repair`].
+    pub(crate) async fn rejoin_externally(
+        connection_mutex: SqliteConnection,
+        api_clients: &ApiClients,
+        stored_group: StorableGroup,
+        external_commit_info: ExternalCommitInfoIn,
+        own_client_credential: &ClientCredential,
+    ) -> Result<(Self, AssistedMessageOut)> {
+        if stored_group.user_auth_signing_key_option.is_none() {
+            bail!("Can't resync without a user auth key");
+        }
+        let own_signature_key = stored_group
+            .leaf_signer
+            .credential()
+            .verifying_key()
+            .as_slice()
+            .to_vec();
+        let credential_with_key = CredentialWithKey {
+            credential: stored_group.leaf_signer.credential().try_into()?,
+            signature_key: stored_group
+                .leaf_signer
+                .credential()
+                .verifying_key()
+                .clone(),
+        };
+        let ExternalCommitInfoIn {
+            verifiable_group_info,
+            ratchet_tree_in,
+            encrypted_client_info,
+        } = external_commit_info;
+        let aad = InfraAadMessage::from(InfraAadPayload::ResyncClient);
+
+        // Phase 1: Replace the MLS state
+        let (mls_group, commit, group_info_option) = {
+            let connection = connection_mutex.lock().await;
+            let provider = PhnxOpenMlsProvider::new(&connection);
+            provider
+                .storage()
+                .delete_all_group_state(&stored_group.group_id)?;
+            let (mut mls_group, commit, group_info_option) = MlsGroup::join_by_external_commit(
+                &provider,
+                &stored_group.leaf_signer,
+                Some(ratchet_tree_in),
+                verifiable_group_info,
+                &Self::default_mls_group_join_config(),
+                Some(default_capabilities()),
+                None,
+                &aad.tls_serialize_detached()?,
+                credential_with_key,
+            )?;
+            mls_group.merge_pending_commit(&provider)?;
+            (mls_group, commit, group_info_option)
+        };
+        let group_info = group_info_option.ok_or(anyhow!("Commit didn't return a group info"))?;
+
+        // Phase 2: Decrypt and verify the client credentials. The client
+        // takes the leaf of its old client, so the members are the same as
+        // before the commit.
+        let encrypted_client_information = mls_group
+            .members()
+            .map(|m| m.index)
+            .zip(encrypted_client_info);
+        let client_information = ClientAuthInfo::decrypt_and_verify_all(
+            connection_mutex.clone(),
+            api_clients,
+            &stored_group.group_id,
+            &stored_group.credential_ear_key,
+            &stored_group.signature_ear_key_wrapper_key,
+            encrypted_client_information,
+        )
+        .await?;
+
+        // Phase 3: Verify and replace the group memberships
+        let connection = connection_mutex.lock().await;
+        let own_index = mls_group.own_leaf_index();
+        let members = mls_group.members().collect::<Vec<_>>();
+        if members.len() != client_information.len() {
+            bail!("The DS didn't provide the client information of all members");
+        }
+        let own_member = members
+            .iter()
+            .find(|member| member.index == own_index)
+            .ok_or(anyhow!("The own leaf is missing from the group"))?;
+        if own_member.signature_key != own_signature_key {
+            bail!("The own leaf doesn't hold the signature key of the client");
+        }
+        for (member, client_auth_info) in members.iter().zip(client_information.iter()) {
+            client_auth_info.verify_infra_credential(&member.credential)?;
+            if member.index == own_index
+                && client_auth_info.client_credential().identity()
+                    != own_client_credential.identity()
+            {
+                bail!("The client didn't rejoin at the leaf of its old client");
+            }
+        }
+        GroupMembership::delete_for_group(&connection, &stored_group.group_id)?;
+        for client_auth_info in &client_information {
+            client_auth_info.store(&connection)?;
+        }
+        drop(connection);
+
+        let commit = AssistedMessageOut::new(commit, Some(group_info.into()))?;
+        let mut group = stored_group.into_group(mls_group);
+        group.pending_diff = None;
+        Ok((group, commit))
+    }
+
+    /// Remove the MLS state of a rejoin that the DS didn't accept, so that
+    /// the repair can be retried.
+    pub(crate) fn discard_repair(connection: &Connection, group_id: &GroupId) -> Result<()> {
+        PhnxOpenMlsProvider::new(connection)
+            .storage()
+            .delete_all_group_state(group_id)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Summary of the encryption health of a group.
+//!
+//! A member's credential counts as revoked if it was signed by an AS
+//! intermediate credential that the AS no longer publishes. Intermediate
+//! credentials that aren't cached locally are fetched from the AS first, see
+//! [`Group::uncached_signer_domains`]. If the AS can't be reached, the status
+//! of the credential is reported as unknown.
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use phnxtypes::{
+    credentials::ClientCredential,
+    identifiers::{Fqdn, QualifiedUserName},
+    time::{Duration, TimeStamp},
+};
+use rusqlite::Connection;
+
+use crate::key_stores::as_credentials::AsCredentials;
+
+use super::{client_auth_info::ClientAuthInfo, Group, DEFAULT_CIPHERSUITE};
+
+/// Proposals that have been pending for longer than this are considered
+/// stale, since a member should have committed them in the meantime.
+pub const STALE_PROPOSAL_THRESHOLD: Duration = Duration::days(1);
+
+/// Encryption health of a conversation's group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversationSecurityStatus {
+    /// The time at which the group last moved to a new epoch, i.e. rotated
+    /// its keys. `None` for groups created before this was tracked.
+    pub last_key_rotation: Option<TimeStamp>,
+    /// Members with at least one client whose credential has expired.
+    pub members_with_expired_credentials: Vec<QualifiedUserName>,
+    /// Members with at least one client whose credential has been revoked.
+    pub members_with_revoked_credentials: Vec<QualifiedUserName>,
+    /// Members with at least one client whose credential couldn't be
+    /// checked, because the credentials of their AS couldn't be fetched.
+    pub members_with_unknown_credentials: Vec<QualifiedUserName>,
+    /// Number of proposals pending for longer than
+    /// [`STALE_PROPOSAL_THRESHOLD`].
+    pub stale_pending_proposals: usize,
+    /// Whether the group uses the default ciphersuite.
+    pub uses_default_ciphersuite: bool,
+}
+
+impl ConversationSecurityStatus {
+    /// Returns `true` if none of the checks found a problem. Credentials that
+    /// couldn't be checked don't count as a problem.
+    pub fn is_healthy(&self) -> bool {
+        self.members_with_expired_credentials.is_empty()
+            && self.members_with_revoked_credentials.is_empty()
+            && self.stale_pending_proposals == 0
+            && self.uses_default_ciphersuite
+    }
+}
+
+impl Group {
+    fn member_credentials(&self, connection: &Connection) -> Result<Vec<ClientCredential>> {
+        self.mls_group()
+            .members()
+            .map(|member| {
+                let client_auth_info =
+                    ClientAuthInfo::load(connection, self.group_id(), member.index)?.ok_or_else(
+                        || anyhow!("Missing client credential of member {:?}", member.index),
+                    )?;
+                Ok(client_auth_info.client_credential().clone().into())
+            })
+            .collect()
+    }
+
+    /// Domains of the ASs that signed the credential of a member with an
+    /// intermediate credential that isn't cached. Their credentials have to be
+    /// fetched before the status can be computed.
+    pub(crate) fn uncached_signer_domains(&self, connection: &Connection) -> Result<HashSet<Fqdn>> {
+        let mut domains = HashSet::new();
+        for credential in self.member_credentials(connection)? {
+            let domain = credential.identity().user_name().domain();
+            let signer = AsCredentials::load_intermediate(
+                connection,
+                credential.signer_fingerprint(),
+                &domain,
+            )?;
+            if signer.is_none() {
+                domains.insert(domain);
+            }
+        }
+        Ok(domains)
+    }
+
+    /// Computes the status from the local state. The credentials of members
+    /// whose AS is in `unreachable_domains` and whose signer isn't cached are
+    /// reported as unknown instead of revoked.
+    pub(crate) fn security_status(
+        &self,
+        connection: &Connection,
+        unreachable_domains: &HashSet<Fqdn>,
+    ) -> Result<ConversationSecurityStatus> {
+        let now = TimeStamp::now();
+        let mut expired = Vec::new();
+        let mut revoked = Vec::new();
+        let mut unknown = Vec::new();
+        for credential in self.member_credentials(connection)? {
+            let user_name = credential.identity().user_name();
+            let domain = user_name.domain();
+            let signer = AsCredentials::load_intermediate(
+                connection,
+                credential.signer_fingerprint(),
+                &domain,
+            )?;
+            // Members may have multiple clients
+            let Some(signer) = signer else {
+                let members = if unreachable_domains.contains(&domain) {
+                    &mut unknown
+                } else {
+                    &mut revoked
+                };
+                if !members.contains(&user_name) {
+                    members.push(user_name);
+                }
+                continue;
+            };
+            let has_expired = credential.expiration_data().not_after() < now
+                || signer.expiration_data().not_after() < now;
+            if has_expired && !expired.contains(&user_name) {
+                expired.push(user_name);
+            }
+        }
+
+        let (last_key_rotation, proposals_pending_since) =
+            self.load_security_timestamps(connection)?;
+        let stale_pending_proposals = match proposals_pending_since {
+            Some(since) if since.has_expired(STALE_PROPOSAL_THRESHOLD) => {
+                self.mls_group().pending_proposals().count()
+            }
+            _ => 0,
+        };
+
+        Ok(ConversationSecurityStatus {
+            last_key_rotation,
+            members_with_expired_credentials: expired,
+            members_with_revoked_credentials: revoked,
+            members_with_unknown_credentials: unknown,
+            stale_pending_proposals,
+            uses_default_ciphersuite: self.mls_group().ciphersuite() == DEFAULT_CIPHERSUITE,
+        })
+    }
+}
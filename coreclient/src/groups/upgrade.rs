@@ -0,0 +1,433 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Client side of room upgrades, see [`phnxtypes::messages::room_upgrade`].
+//!
+//! A client that merges the tombstone of a group derives the keys of the
+//! successor group and records a [`PendingGroupUpgrade`]. The upgrade is
+//! completed by joining the successor with an external commit, after which
+//! the conversation is moved to the successor and the old group is deleted.
+
+use anyhow::{anyhow, bail, Result};
+use mls_assist::messages::AssistedMessageOut;
+use openmls::prelude::{
+    tls_codec::Serialize as TlsSerializeTrait, Credential, CredentialWithKey, Extension,
+    Extensions, GroupId, LeafNodeIndex, MlsGroup, MlsMessageOut, OpenMlsProvider, Proposal,
+    StagedCommit, PURE_PLAINTEXT_WIRE_FORMAT_POLICY,
+};
+use phnxtypes::{
+    credentials::{
+        keys::{ClientSigningKey, InfraCredentialSigningKey},
+        ClientCredential,
+    },
+    crypto::{
+        ear::{
+            keys::{
+                ClientCredentialEarKey, GroupStateEarKey, SignatureEarKey,
+                SignatureEarKeyWrapperKey,
+            },
+            EarEncryptable,
+        },
+        secrets::Secret,
+        signatures::keys::UserAuthSigningKey,
+    },
+    messages::{
+        client_ds::{InfraAadMessage, InfraAadPayload, JoinUpgradedGroupParamsAad},
+        client_ds_out::{ExternalCommitInfoIn, UpgradeGroupParamsOut},
+        room_upgrade::{
+            GroupPredecessor, GroupTombstone, UpgradeAuthorization, UpgradeTarget,
+            GROUP_TOMBSTONE_EXTENSION_TYPE, GROUP_UPGRADE_EXPORTER_LABEL,
+        },
+    },
+};
+use rusqlite::{params, Connection};
+
+use crate::{
+    clients::api_clients::ApiClients,
+    utils::persistence::{GroupIdRefWrapper, GroupIdWrapper, SqliteConnection, Storable},
+};
+
+use super::{
+    client_auth_info::GroupMembership, default_capabilities, openmls_provider::PhnxOpenMlsProvider,
+    Group, PartialCreateGroupParams,
+};
+
+const SUCCESSOR_KEY_LENGTH: usize = 32;
+
+/// Keys of the successor of an upgraded group. They are derived from the
+/// exporter secret of the upgraded group, such that all of its members know
+/// them.
+#[derive(Debug, Clone)]
+pub(crate) struct SuccessorKeys {
+    pub(crate) group_state_ear_key: GroupStateEarKey,
+    pub(crate) credential_ear_key: ClientCredentialEarKey,
+    pub(crate) signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
+}
+
+impl SuccessorKeys {
+    fn derive(
+        mls_group: &MlsGroup,
+        provider: &impl OpenMlsProvider,
+        successor_group_id: &GroupId,
+    ) -> Result<Self> {
+        let exported = mls_group.export_secret(
+            provider.crypto(),
+            GROUP_UPGRADE_EXPORTER_LABEL,
+            successor_group_id.as_slice(),
+            3 * SUCCESSOR_KEY_LENGTH,
+        )?;
+        let mut keys = exported.chunks_exact(SUCCESSOR_KEY_LENGTH).map(|chunk| {
+            let bytes: [u8; SUCCESSOR_KEY_LENGTH] = chunk.try_into().unwrap_or_default();
+            Secret::from(bytes)
+        });
+        let mut next_key = || keys.next().ok_or(anyhow!("Exported secret is too short"));
+        Ok(Self {
+            group_state_ear_key: next_key()?.into(),
+            credential_ear_key: next_key()?.into(),
+            signature_ear_key_wrapper_key: next_key()?.into(),
+        })
+    }
+}
+
+/// An upgrade of a group whose successor the client hasn't joined yet.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingGroupUpgrade {
+    pub(crate) group_id: GroupId,
+    pub(crate) successor_group_id: GroupId,
+    pub(crate) keys: SuccessorKeys,
+}
+
+impl Storable for PendingGroupUpgrade {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS pending_group_upgrades (
+            group_id BLOB PRIMARY KEY,
+            successor_group_id BLOB NOT NULL,
+            group_state_ear_key BLOB NOT NULL,
+            credential_ear_key BLOB NOT NULL,
+            signature_ear_key_wrapper_key BLOB NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let group_id: GroupIdWrapper = row.get(0)?;
+        let successor_group_id: GroupIdWrapper = row.get(1)?;
+        let group_state_ear_key = row.get(2)?;
+        let credential_ear_key = row.get(3)?;
+        let signature_ear_key_wrapper_key = row.get(4)?;
+        Ok(Self {
+            group_id: group_id.into(),
+            successor_group_id: successor_group_id.into(),
+            keys: SuccessorKeys {
+                group_state_ear_key,
+                credential_ear_key,
+                signature_ear_key_wrapper_key,
+            },
+        })
+    }
+}
+
+impl PendingGroupUpgrade {
+    pub(crate) fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO pending_group_upgrades (group_id, successor_group_id, group_state_ear_key, credential_ear_key, signature_ear_key_wrapper_key) VALUES (?, ?, ?, ?, ?)",
+            params![
+                GroupIdRefWrapper::from(&self.group_id),
+                GroupIdRefWrapper::from(&self.successor_group_id),
+                self.keys.group_state_ear_key,
+                self.keys.credential_ear_key,
+                self.keys.signature_ear_key_wrapper_key,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT group_id, successor_group_id, group_state_ear_key, credential_ear_key, signature_ear_key_wrapper_key FROM pending_group_upgrades",
+        )?;
+        let upgrades = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(upgrades)
+    }
+
+    pub(crate) fn delete(
+        connection: &Connection,
+        group_id: &GroupId,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM pending_group_upgrades WHERE group_id = ?",
+            params![GroupIdRefWrapper::from(group_id)],
+        )?;
+        Ok(())
+    }
+}
+
+impl Group {
+    /// The tombstone of the group, if the group was upgraded.
+    pub(crate) fn tombstone(&self) -> Result<Option<GroupTombstone>> {
+        Ok(GroupTombstone::from_extensions(
+            self.mls_group().extensions(),
+        )?)
+    }
+
+    /// The first of the given targets (in order of preference) that all
+    /// members of the group support.
+    pub(crate) fn negotiate_upgrade_target(
+        &self,
+        preferences: &[UpgradeTarget],
+    ) -> Option<UpgradeTarget> {
+        let public_group = self.mls_group.public_group();
+        let leaves = self
+            .mls_group
+            .members()
+            .filter_map(|member| public_group.leaf(member.index))
+            .map(|leaf| leaf.capabilities());
+        UpgradeTarget::negotiate(preferences, leaves)
+    }
+
+    /// Derive the keys of the given successor from the current epoch.
+    pub(crate) fn successor_keys(
+        &self,
+        connection: &Connection,
+        successor_group_id: &GroupId,
+    ) -> Result<SuccessorKeys> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        SuccessorKeys::derive(&self.mls_group, provider, successor_group_id)
+    }
+
+    /// Create the successor of this group. It keeps the group data and the
+    /// room policy, as well as the user auth key of the own user, such that
+    /// the DS can link it to this group.
+    pub(crate) fn create_successor(
+        &self,
+        connection: &mut Connection,
+        signer: &ClientSigningKey,
+        successor_group_id: GroupId,
+        target: UpgradeTarget,
+        keys: SuccessorKeys,
+    ) -> Result<(Self, PartialCreateGroupParams)> {
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            bail!("No user auth key")
+        };
+        let SuccessorKeys {
+            group_state_ear_key,
+            credential_ear_key,
+            signature_ear_key_wrapper_key,
+        } = keys;
+
+        let signature_ear_key = SignatureEarKey::random()?;
+        let leaf_signer = InfraCredentialSigningKey::generate(signer, &signature_ear_key);
+        let credential_with_key = CredentialWithKey {
+            credential: Credential::try_from(leaf_signer.credential())?,
+            signature_key: leaf_signer.credential().verifying_key().clone(),
+        };
+
+        let predecessor_extension = GroupPredecessor {
+            predecessor: self.group_id.clone(),
+        }
+        .to_extension()?;
+        let gc_extensions = self
+            .mls_group()
+            .extensions()
+            .iter()
+            .filter(|extension| {
+                !matches!(
+                    extension,
+                    Extension::Unknown(GROUP_TOMBSTONE_EXTENSION_TYPE, _)
+                )
+            })
+            .cloned()
+            .chain(Some(predecessor_extension))
+            .collect::<Vec<_>>();
+        let gc_extensions = Extensions::from_vec(gc_extensions)?;
+
+        let transaction = connection.transaction()?;
+        let provider = &PhnxOpenMlsProvider::new(&transaction);
+        let mls_group = MlsGroup::builder()
+            .with_group_id(successor_group_id.clone())
+            .ciphersuite(target.ciphersuite)
+            .with_capabilities(default_capabilities())
+            .use_ratchet_tree_extension(true)
+            .with_group_context_extensions(gc_extensions)?
+            .with_wire_format_policy(PURE_PLAINTEXT_WIRE_FORMAT_POLICY)
+            .build(provider, &leaf_signer, credential_with_key)
+            .map_err(|e| anyhow!("Error while creating successor group: {:?}", e))?;
+
+        let encrypted_signature_ear_key =
+            signature_ear_key.encrypt(&signature_ear_key_wrapper_key)?;
+        let params = PartialCreateGroupParams {
+            group_id: successor_group_id.clone(),
+            ratchet_tree: mls_group.export_ratchet_tree(),
+            group_info: mls_group.export_group_info::<PhnxOpenMlsProvider>(
+                provider,
+                &leaf_signer,
+                true,
+            )?,
+            user_auth_key: user_auth_key.verifying_key().clone(),
+            encrypted_signature_ear_key,
+        };
+
+        GroupMembership::new(
+            signer.credential().identity(),
+            successor_group_id.clone(),
+            LeafNodeIndex::new(0),
+            signature_ear_key,
+            signer.credential().fingerprint()?,
+        )
+        .store(&transaction)?;
+
+        transaction.commit()?;
+
+        let group = Self {
+            group_id: successor_group_id,
+            leaf_signer,
+            signature_ear_key_wrapper_key,
+            mls_group,
+            credential_ear_key,
+            group_state_ear_key,
+            user_auth_signing_key_option: Some(user_auth_key.clone()),
+            pending_diff: None,
+        };
+
+        Ok((group, params))
+    }
+
+    /// Commit the tombstone pointing to the successor. Only admins can
+    /// upgrade the room.
+    pub(crate) fn upgrade(
+        &mut self,
+        connection: &Connection,
+        tombstone: &GroupTombstone,
+        successor_ear_key: GroupStateEarKey,
+    ) -> Result<UpgradeGroupParamsOut> {
+        let provider = &PhnxOpenMlsProvider::new(connection);
+        let Some(user_auth_key) = &self.user_auth_signing_key_option else {
+            bail!("No user auth key")
+        };
+        let own_user_key_hash = user_auth_key.verifying_key().hash();
+        if !self.room_policy()?.is_admin(&own_user_key_hash) {
+            bail!("Only admins can upgrade the room")
+        }
+        if self.tombstone()?.is_some() {
+            bail!("The room was already upgraded")
+        }
+
+        let extensions = self
+            .mls_group()
+            .extensions()
+            .iter()
+            .cloned()
+            .chain(Some(tombstone.to_extension()?))
+            .collect::<Vec<_>>();
+        let extensions = Extensions::from_vec(extensions)?;
+
+        let authorization = UpgradeAuthorization::sign(
+            user_auth_key,
+            self.group_id(),
+            self.mls_group().epoch(),
+            tombstone,
+        )?;
+        let aad_payload = InfraAadPayload::UpgradeGroup(authorization);
+        let aad = InfraAadMessage::from(aad_payload).tls_serialize_detached()?;
+        self.mls_group.set_aad(aad);
+        let (mls_message, _welcome_option, group_info_option) = self
+            .mls_group
+            .update_group_context_extensions(provider, extensions, &self.leaf_signer)?;
+        // There shouldn't be a welcome
+        debug_assert!(_welcome_option.is_none());
+        let group_info =
+            group_info_option.ok_or(anyhow!("No group info after commit operation"))?;
+        let commit = AssistedMessageOut::new(mls_message, Some(group_info.into()))?;
+
+        let params = UpgradeGroupParamsOut {
+            commit,
+            sender: own_user_key_hash,
+            successor_ear_key,
+        };
+        Ok(params)
+    }
+
+    /// Join the successor of an upgraded group with an external commit. The
+    /// own user keeps its user auth key, which the DS uses to check that the
+    /// user was a member of the upgraded group.
+    ///
+    /// Returns the successor group, the commit and the group info.
+    pub(crate) async fn join_successor(
+        connection_mutex: SqliteConnection,
+        api_clients: &ApiClients,
+        external_commit_info: ExternalCommitInfoIn,
+        upgrade: PendingGroupUpgrade,
+        user_auth_key: UserAuthSigningKey,
+        signer: &ClientSigningKey,
+        own_client_credential: &ClientCredential,
+    ) -> Result<(Self, MlsMessageOut, MlsMessageOut)> {
+        let SuccessorKeys {
+            group_state_ear_key,
+            credential_ear_key,
+            signature_ear_key_wrapper_key,
+        } = upgrade.keys;
+        let signature_ear_key = SignatureEarKey::random()?;
+        let leaf_signer = InfraCredentialSigningKey::generate(signer, &signature_ear_key);
+        let encrypted_client_information = (
+            own_client_credential.encrypt(&credential_ear_key)?,
+            signature_ear_key.encrypt(&signature_ear_key_wrapper_key)?,
+        );
+        let aad = InfraAadPayload::JoinUpgradedGroup(JoinUpgradedGroupParamsAad {
+            encrypted_client_information,
+        })
+        .into();
+
+        let (mut group, commit, group_info) = Self::join_group_externally(
+            connection_mutex,
+            api_clients,
+            external_commit_info,
+            leaf_signer,
+            signature_ear_key,
+            group_state_ear_key,
+            signature_ear_key_wrapper_key,
+            credential_ear_key,
+            aad,
+            own_client_credential,
+        )
+        .await?;
+        if group.group_id != upgrade.successor_group_id {
+            bail!("Joined the wrong successor group")
+        }
+        group.user_auth_signing_key_option = Some(user_auth_key);
+        Ok((group, commit, group_info))
+    }
+
+    /// The upgrade of this group to the successor in the tombstone proposed by
+    /// the given commit, if any. Has to be called before the commit is
+    /// merged, since the keys of the successor are derived from the current
+    /// epoch.
+    pub(super) fn proposed_upgrade(
+        &self,
+        connection: &Connection,
+        staged_commit: &StagedCommit,
+    ) -> Result<Option<PendingGroupUpgrade>> {
+        let Some(extensions_proposal) =
+            staged_commit
+                .queued_proposals()
+                .find_map(|queued_proposal| match queued_proposal.proposal() {
+                    Proposal::GroupContextExtensions(extensions_proposal) => {
+                        Some(extensions_proposal)
+                    }
+                    _ => None,
+                })
+        else {
+            return Ok(None);
+        };
+        let Some(tombstone) = GroupTombstone::from_extensions(extensions_proposal.extensions())?
+        else {
+            return Ok(None);
+        };
+        let keys = self.successor_keys(connection, &tombstone.successor)?;
+        Ok(Some(PendingGroupUpgrade {
+            group_id: self.group_id.clone(),
+            successor_group_id: tombstone.successor,
+            keys,
+        }))
+    }
+}
@@ -2,8 +2,9 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use futures_util::{stream, StreamExt, TryStreamExt};
 use phnxapiclient::as_api::AsRequestError;
 use phnxtypes::{
     credentials::{
@@ -12,17 +13,22 @@ use phnxtypes::{
     },
     crypto::signatures::{signable::Verifiable, traits::SignatureVerificationError},
     identifiers::Fqdn,
+    messages::client_as_out::AsCredentialsResponseIn,
 };
-use rusqlite::{params, OptionalExtension, ToSql};
+use rusqlite::{params, types::Type, OptionalExtension, ToSql};
 use thiserror::Error;
 
 use crate::{
-    clients::api_clients::ApiClientsError,
+    clients::{api_clients::ApiClientsError, credential_refresh::CREDENTIAL_REFRESH_THRESHOLD},
     utils::persistence::{SqliteConnection, Storable},
 };
 
 use super::*;
 
+/// Maximum number of ASs that are queried concurrently when verifying client
+/// credentials in bulk.
+const MAX_CONCURRENT_AS_QUERIES: usize = 8;
+
 pub(crate) enum AsCredentials {
     AsCredential(AsCredential),
     AsIntermediateCredential(AsIntermediateCredential),
@@ -42,13 +48,17 @@ impl Storable for AsCredentials {
         match credential_type.as_str() {
             "as_credential" => {
                 let body: AsCredentialBody = row.get(1)?;
-                Ok(AsCredentials::AsCredential(AsCredential::from(body)))
+                let credential = AsCredential::try_from(body).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(1, Type::Blob, e.into())
+                })?;
+                Ok(AsCredentials::AsCredential(credential))
             }
             "as_intermediate_credential" => {
                 let body: AsIntermediateCredentialBody = row.get(1)?;
-                Ok(AsCredentials::AsIntermediateCredential(
-                    AsIntermediateCredential::from(body),
-                ))
+                let credential = AsIntermediateCredential::try_from(body).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(1, Type::Blob, e.into())
+                })?;
+                Ok(AsCredentials::AsIntermediateCredential(credential))
             }
             _ => Err(rusqlite::Error::InvalidQuery),
         }
@@ -92,39 +102,151 @@ impl AsCredentials {
         Ok(())
     }
 
-    fn load_intermediate(
+    pub(crate) fn load_intermediate(
         connection: &Connection,
-        fingerprint_option: Option<&CredentialFingerprint>,
+        fingerprint: &CredentialFingerprint,
         domain: &Fqdn,
     ) -> Result<Option<AsIntermediateCredential>, rusqlite::Error> {
-        let mut query_string =
-            "SELECT credential_type, credential FROM as_credentials WHERE domain = ? AND credential_type = 'as_intermediate_credential'".to_owned();
-        if fingerprint_option.is_some() {
-            query_string.push_str(" AND fingerprint = ?");
-        }
-        let mut statement = connection.prepare(&query_string)?;
-        if let Some(fingerprint) = fingerprint_option {
-            statement.query_row(params![domain, fingerprint], Self::from_row)
-        } else {
-            statement.query_row(params![domain], Self::from_row)
-        }
-        .optional()
-        .map(|credential_type_option| {
-            credential_type_option.and_then(|credential| {
-                if let AsCredentials::AsIntermediateCredential(credential) = credential {
-                    Some(credential)
-                } else {
-                    None
-                }
+        connection
+            .query_row(
+                "SELECT credential_type, credential FROM as_credentials WHERE domain = ? AND credential_type = 'as_intermediate_credential' AND fingerprint = ?",
+                params![domain, fingerprint],
+                Self::from_row,
+            )
+            .optional()
+            .map(|credential_option| {
+                credential_option.and_then(|credential| {
+                    if let AsCredentials::AsIntermediateCredential(credential) = credential {
+                        Some(credential)
+                    } else {
+                        None
+                    }
+                })
+            })
+    }
+
+    /// Load the intermediate credentials of all domains.
+    pub(crate) fn load_all_intermediates(
+        connection: &Connection,
+    ) -> Result<Vec<AsIntermediateCredential>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT credential_type, credential FROM as_credentials WHERE credential_type = 'as_intermediate_credential'",
+        )?;
+        let credentials = statement
+            .query_map([], Self::from_row)?
+            .filter_map(|credential| match credential {
+                Ok(AsCredentials::AsIntermediateCredential(credential)) => Some(Ok(credential)),
+                Ok(AsCredentials::AsCredential(_)) => None,
+                Err(e) => Some(Err(e)),
             })
-        })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(credentials)
     }
 
-    async fn fetch_credentials(
+    /// Load the intermediate credential of the given domain that expires last.
+    /// After a rotation, this is the credential of the newly activated key.
+    fn load_latest_intermediate(
+        connection: &Connection,
+        domain: &Fqdn,
+    ) -> Result<Option<AsIntermediateCredential>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT credential_type, credential FROM as_credentials WHERE domain = ? AND credential_type = 'as_intermediate_credential'",
+        )?;
+        let credentials = statement
+            .query_map(params![domain], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let latest_credential = credentials
+            .into_iter()
+            .filter_map(|credential| match credential {
+                AsCredentials::AsIntermediateCredential(credential) => Some(credential),
+                AsCredentials::AsCredential(_) => None,
+            })
+            .max_by_key(|credential| *credential.expiration_data().not_after());
+        Ok(latest_credential)
+    }
+
+    /// Replace the cached intermediate credentials of the given domain with
+    /// the given ones. Credentials the AS no longer publishes, because their
+    /// signing key was rotated out, are removed.
+    fn replace_intermediates(
+        connection: &mut Connection,
+        domain: &Fqdn,
+        credentials: Vec<AsIntermediateCredential>,
+    ) -> Result<Vec<AsIntermediateCredential>, rusqlite::Error> {
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM as_credentials WHERE domain = ? AND credential_type = 'as_intermediate_credential'",
+            params![domain],
+        )?;
+        let credentials = credentials
+            .into_iter()
+            .map(|credential| {
+                let credential = AsCredentials::AsIntermediateCredential(credential);
+                credential.store(&transaction)?;
+                let AsCredentials::AsIntermediateCredential(credential) = credential else {
+                    unreachable!()
+                };
+                Ok(credential)
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        transaction.commit()?;
+        Ok(credentials)
+    }
+
+    /// Fetches the intermediate credentials of the AS with the given `domain`
+    /// and replaces the cached ones with them.
+    ///
+    /// The AS returns the credential of its active intermediate signing key
+    /// last.
+    pub(crate) async fn fetch_credentials(
+        connection_mutex: SqliteConnection,
         domain: &Fqdn,
         api_clients: &ApiClients,
     ) -> Result<Vec<AsIntermediateCredential>, AsCredentialStoreError> {
         let as_credentials_response = api_clients.get(domain)?.as_as_credentials().await?;
+        let as_inter_creds = Self::verify_response(domain, as_credentials_response)?;
+        let mut connection = connection_mutex.lock().await;
+        let as_inter_creds = Self::replace_intermediates(&mut connection, domain, as_inter_creds)?;
+        drop(connection);
+        Ok(as_inter_creds)
+    }
+
+    /// Fetches the intermediate credentials with the given `fingerprints` from
+    /// the AS with the given `domain` with a single request and adds them to
+    /// the cached ones.
+    async fn fetch_intermediates(
+        connection_mutex: SqliteConnection,
+        domain: &Fqdn,
+        api_clients: &ApiClients,
+        fingerprints: Vec<CredentialFingerprint>,
+    ) -> Result<Vec<AsIntermediateCredential>, AsCredentialStoreError> {
+        let as_credentials_response = api_clients
+            .get(domain)?
+            .as_intermediate_credentials(fingerprints)
+            .await?;
+        let as_inter_creds = Self::verify_response(domain, as_credentials_response)?;
+        let connection = connection_mutex.lock().await;
+        let as_inter_creds = as_inter_creds
+            .into_iter()
+            .map(|credential| {
+                let credential = AsCredentials::AsIntermediateCredential(credential);
+                credential.store(&connection)?;
+                let AsCredentials::AsIntermediateCredential(credential) = credential else {
+                    unreachable!()
+                };
+                Ok(credential)
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        drop(connection);
+        Ok(as_inter_creds)
+    }
+
+    /// Verifies the intermediate credentials in a response of the AS with the
+    /// given `domain` with the AS credentials in the same response.
+    fn verify_response(
+        domain: &Fqdn,
+        as_credentials_response: AsCredentialsResponseIn,
+    ) -> Result<Vec<AsIntermediateCredential>, AsCredentialStoreError> {
         let as_credentials: HashMap<CredentialFingerprint, AsCredential> = as_credentials_response
             .as_credentials
             .into_iter()
@@ -135,7 +257,13 @@ impl AsCredentials {
             let as_credential = as_credentials
                 .get(as_inter_cred.signer_fingerprint())
                 .ok_or(AsCredentialStoreError::AsCredentialNotFound)?;
-            let verified_credential = as_inter_cred.verify(as_credential.verifying_key())?;
+            let verified_body: AsIntermediateCredentialBody =
+                as_inter_cred.verify(as_credential.verifying_key())?;
+            let verified_credential = AsIntermediateCredential::try_from(verified_body)
+                .map_err(SignatureVerificationError::from)?;
+            if verified_credential.domain() != domain {
+                return Err(AsCredentialStoreError::AsIntermediateCredentialNotFound);
+            }
             as_inter_creds.push(verified_credential);
         }
         Ok(as_inter_creds)
@@ -143,6 +271,10 @@ impl AsCredentials {
 
     /// Fetches the credentials of the AS with the given `domain` if they are
     /// not already present in the store.
+    ///
+    /// An unknown fingerprint causes the cached credentials of the domain to
+    /// be refreshed, e.g. because the AS has rotated its intermediate signing
+    /// key.
     pub(crate) async fn get(
         connection_mutex: SqliteConnection,
         api_clients: &ApiClients,
@@ -152,30 +284,19 @@ impl AsCredentials {
         log::info!("Loading AS credential from db.");
         // Phase 1: Check if there is a credential in the database.
         let connection = connection_mutex.lock().await;
-        let credential_option =
-            AsCredentials::load_intermediate(&connection, Some(fingerprint), domain)?;
+        let credential_option = AsCredentials::load_intermediate(&connection, fingerprint, domain)?;
         drop(connection);
 
-        // Phase 2: If there is no credential in the database, fetch it from the AS.
+        // Phase 2: If there is no credential in the database, fetch the
+        // credentials from the AS.
         let credential = if let Some(credential) = credential_option {
             credential
         } else {
-            // Phase 2a: Fetch the credential.
-            let credential = Self::fetch_credentials(domain, api_clients)
+            Self::fetch_credentials(connection_mutex, domain, api_clients)
                 .await?
                 .into_iter()
                 .find(|credential| credential.fingerprint() == fingerprint)
-                .ok_or(AsCredentialStoreError::AsIntermediateCredentialNotFound)?;
-
-            // Phase 2b: Store it in the database.
-            let credential_type = AsCredentials::AsIntermediateCredential(credential);
-            let connection = connection_mutex.lock().await;
-            credential_type.store(&connection)?;
-            drop(connection);
-            let AsCredentials::AsIntermediateCredential(credential) = credential_type else {
-                unreachable!()
-            };
-            credential
+                .ok_or(AsCredentialStoreError::AsIntermediateCredentialNotFound)?
         };
         if credential.domain() != domain {
             return Err(AsCredentialStoreError::AsIntermediateCredentialNotFound);
@@ -183,18 +304,72 @@ impl AsCredentials {
         Ok(credential)
     }
 
+    /// Returns the intermediate credentials of the AS with the given `domain`
+    /// that match the given `fingerprints`.
+    ///
+    /// The ones that are not cached are fetched from the AS with a single
+    /// request.
+    async fn get_all(
+        connection_mutex: SqliteConnection,
+        api_clients: &ApiClients,
+        domain: Fqdn,
+        fingerprints: HashSet<CredentialFingerprint>,
+    ) -> Result<Vec<AsIntermediateCredential>, AsCredentialStoreError> {
+        let connection = connection_mutex.lock().await;
+        let mut credentials = Vec::with_capacity(fingerprints.len());
+        let mut missing_fingerprints = Vec::new();
+        for fingerprint in fingerprints {
+            match AsCredentials::load_intermediate(&connection, &fingerprint, &domain)? {
+                Some(credential) => credentials.push(credential),
+                None => missing_fingerprints.push(fingerprint),
+            }
+        }
+        drop(connection);
+        if missing_fingerprints.is_empty() {
+            return Ok(credentials);
+        }
+
+        let requested: HashSet<_> = missing_fingerprints.iter().cloned().collect();
+        let fetched_credentials =
+            Self::fetch_intermediates(connection_mutex, &domain, api_clients, missing_fingerprints)
+                .await?;
+        let cached_count = credentials.len();
+        credentials.extend(
+            fetched_credentials
+                .into_iter()
+                .filter(|credential| requested.contains(credential.fingerprint())),
+        );
+        if credentials.len() - cached_count != requested.len() {
+            return Err(AsCredentialStoreError::AsIntermediateCredentialNotFound);
+        }
+        Ok(credentials)
+    }
+
+    /// Returns the credential of the active intermediate signing key of the AS
+    /// with the given `domain`.
+    ///
+    /// The cached credentials are refreshed if the latest of them is about to
+    /// expire, because the AS has then likely rotated its intermediate signing
+    /// key.
     pub(crate) async fn get_intermediate_credential(
-        connection: SqliteConnection,
+        connection_mutex: SqliteConnection,
         api_clients: &ApiClients,
         domain: &Fqdn,
     ) -> Result<AsIntermediateCredential, AsCredentialStoreError> {
-        let connection = connection.lock().await;
-        let credential_option = AsCredentials::load_intermediate(&connection, None, domain)?;
+        let connection = connection_mutex.lock().await;
+        let credential_option = AsCredentials::load_latest_intermediate(&connection, domain)?;
         drop(connection);
         match credential_option {
-            Some(credential) => Ok(credential),
-            None => {
-                let mut credentials = Self::fetch_credentials(domain, api_clients).await?;
+            Some(credential)
+                if !credential
+                    .expiration_data()
+                    .expires_within(CREDENTIAL_REFRESH_THRESHOLD) =>
+            {
+                Ok(credential)
+            }
+            _ => {
+                let mut credentials =
+                    Self::fetch_credentials(connection_mutex, domain, api_clients).await?;
                 let credential = credentials
                     .pop()
                     .ok_or(AsCredentialStoreError::AsIntermediateCredentialNotFound)?;
@@ -219,6 +394,53 @@ impl AsCredentials {
             verifiable_client_credential.verify(as_intermediate_credential.verifying_key())?;
         Ok(client_credential)
     }
+
+    /// Verifies the given client credentials and returns them in the given
+    /// order.
+    ///
+    /// The intermediate credentials required for verification are resolved
+    /// once per AS, querying up to [`MAX_CONCURRENT_AS_QUERIES`] ASs
+    /// concurrently.
+    pub(crate) async fn verify_client_credentials(
+        connection_mutex: SqliteConnection,
+        api_clients: &ApiClients,
+        verifiable_client_credentials: Vec<VerifiableClientCredential>,
+    ) -> Result<Vec<ClientCredential>, AsCredentialStoreError> {
+        let mut signer_fingerprints: HashMap<Fqdn, HashSet<CredentialFingerprint>> = HashMap::new();
+        for credential in &verifiable_client_credentials {
+            signer_fingerprints
+                .entry(credential.domain())
+                .or_default()
+                .insert(credential.signer_fingerprint().clone());
+        }
+
+        let as_intermediate_credentials: HashMap<_, _> = stream::iter(signer_fingerprints)
+            .map(|(domain, fingerprints)| {
+                Self::get_all(connection_mutex.clone(), api_clients, domain, fingerprints)
+            })
+            .buffer_unordered(MAX_CONCURRENT_AS_QUERIES)
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .flatten()
+            .map(|credential| (credential.fingerprint().clone(), credential))
+            .collect();
+
+        verifiable_client_credentials
+            .into_iter()
+            .map(|verifiable_client_credential| {
+                let as_intermediate_credential = as_intermediate_credentials
+                    .get(verifiable_client_credential.signer_fingerprint())
+                    .filter(|credential| {
+                        credential.domain() == &verifiable_client_credential.domain()
+                    })
+                    .ok_or(AsCredentialStoreError::AsIntermediateCredentialNotFound)?;
+                let client_credential = verifiable_client_credential
+                    .verify(as_intermediate_credential.verifying_key())?;
+                Ok(client_credential)
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Error)]
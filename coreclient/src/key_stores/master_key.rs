@@ -0,0 +1,521 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! The master key of this device.
+//!
+//! The master key encrypts local data of the device (see
+//! [`MasterKey::encrypt`]). It is created once and persisted in the phnx.db,
+//! wrapped with a non-exportable key of the platform's hardware key store
+//! (Secure Enclave on iOS and macOS, Keystore on Android). If no hardware key
+//! store is available, the key is persisted as is and only protected by the
+//! platform's app sandbox. Changing the protection only re-wraps the master
+//! key, so data encrypted with it stays readable.
+//!
+//! The platform key store is accessed via a [`PlatformKeyStore`], which is
+//! implemented on the app side, e.g. via the FRB bridge.
+
+use std::future::Future;
+
+use anyhow::{anyhow, bail, Result};
+use phnxtypes::{
+    codec::PhnxCodec,
+    crypto::{
+        ear::{Ciphertext, EarKey},
+        secrets::{Secret, SecretBytes},
+    },
+};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+
+use crate::utils::persistence::{open_phnx_db, Storable};
+
+const MASTER_KEY_LENGTH: usize = 32;
+
+/// Access to a platform key store that can wrap keys with a non-exportable,
+/// hardware-backed key.
+///
+/// The platform keeps separate keys for wrapping with and without biometrics,
+/// such that one of them can be deleted while the other one is in use.
+pub trait PlatformKeyStore: Send + Sync {
+    /// Returns `true` if a hardware-backed key store is available on this
+    /// device.
+    fn is_available(&self) -> impl Future<Output = bool> + Send;
+
+    /// Wrap the given key. If `require_biometrics` is set, the platform must
+    /// only unwrap the key after the user authenticated biometrically.
+    fn wrap_key(
+        &self,
+        key: &[u8],
+        require_biometrics: bool,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Unwrap a key that was previously wrapped by [`Self::wrap_key`] with the
+    /// same `require_biometrics`. Fails if the user didn't pass the required
+    /// biometric authentication.
+    fn unwrap_key(
+        &self,
+        wrapped_key: &[u8],
+        require_biometrics: bool,
+    ) -> impl Future<Output = Result<SecretBytes>> + Send;
+
+    /// Delete the hardware-backed key used with the given
+    /// `require_biometrics`, so keys wrapped with it can't be unwrapped
+    /// anymore. Succeeds if there is no key.
+    fn delete_key(&self, require_biometrics: bool) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Platform key store for devices without a hardware key store.
+///
+/// Keys persisted with this store are only protected by the app sandbox.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoftwareKeyStore;
+
+impl PlatformKeyStore for SoftwareKeyStore {
+    async fn is_available(&self) -> bool {
+        false
+    }
+
+    async fn wrap_key(&self, _key: &[u8], _require_biometrics: bool) -> Result<Vec<u8>> {
+        bail!("No hardware key store available")
+    }
+
+    async fn unwrap_key(
+        &self,
+        _wrapped_key: &[u8],
+        _require_biometrics: bool,
+    ) -> Result<SecretBytes> {
+        bail!("No hardware key store available")
+    }
+
+    async fn delete_key(&self, _require_biometrics: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// How the persisted master key is protected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyProtection {
+    /// The key is persisted as is.
+    Software,
+    /// The key is wrapped with a hardware-backed key.
+    Hardware,
+    /// The key is wrapped with a hardware-backed key that can only be used
+    /// after biometric authentication.
+    HardwareBiometric,
+}
+
+impl KeyProtection {
+    pub fn is_hardware_backed(&self) -> bool {
+        !matches!(self, Self::Software)
+    }
+
+    fn requires_biometrics(&self) -> bool {
+        matches!(self, Self::HardwareBiometric)
+    }
+}
+
+impl ToSql for KeyProtection {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let protection = match self {
+            Self::Software => "software",
+            Self::Hardware => "hardware",
+            Self::HardwareBiometric => "hardware_biometric",
+        };
+        Ok(ToSqlOutput::Owned(Value::Text(protection.to_owned())))
+    }
+}
+
+impl FromSql for KeyProtection {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "software" => Ok(Self::Software),
+            "hardware" => Ok(Self::Hardware),
+            "hardware_biometric" => Ok(Self::HardwareBiometric),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// The unlocked master key.
+#[derive(Debug, Clone)]
+pub struct MasterKey {
+    key: SecretBytes,
+    protection: KeyProtection,
+}
+
+impl MasterKey {
+    /// Unlock the master key persisted in the phnx.db at the given path or
+    /// create a new one if there is none.
+    ///
+    /// A new key is wrapped with the given platform key store if it is
+    /// available. Unlocking a key that requires biometrics prompts the user
+    /// via the platform key store.
+    pub async fn unlock_or_create(
+        db_path: &str,
+        key_store: &impl PlatformKeyStore,
+        require_biometrics: bool,
+    ) -> Result<Self> {
+        if let Some(stored) = StoredMasterKey::load_from_phnx_db(db_path)? {
+            return stored.unlock(key_store).await;
+        }
+        let mut key = vec![0; MASTER_KEY_LENGTH];
+        OsRng.fill_bytes(&mut key);
+        let master_key = Self {
+            key: key.into(),
+            protection: KeyProtection::Software,
+        };
+        master_key
+            .protect(db_path, key_store, require_biometrics)
+            .await
+    }
+
+    /// Returns how the master key persisted in the phnx.db at the given path
+    /// is protected, if there is one. Doesn't unlock the key.
+    pub fn persisted_protection(db_path: &str) -> Result<Option<KeyProtection>> {
+        Ok(StoredMasterKey::load_from_phnx_db(db_path)?.map(|stored| stored.protection))
+    }
+
+    /// Wrap the key with the given platform key store and replace the
+    /// persisted key, e.g. to enable or disable the biometric unlock.
+    ///
+    /// Fails if biometrics are required, but the platform key store is not
+    /// available or fails to wrap the key. Otherwise, falls back to persisting
+    /// the key unwrapped. Once the new key is persisted, the platform key that
+    /// wrapped the previous one is deleted.
+    pub async fn protect(
+        mut self,
+        db_path: &str,
+        key_store: &impl PlatformKeyStore,
+        require_biometrics: bool,
+    ) -> Result<Self> {
+        let wrapped = if key_store.is_available().await {
+            match key_store
+                .wrap_key(self.key.expose_secret(), require_biometrics)
+                .await
+            {
+                Ok(wrapped_key) => Some(wrapped_key),
+                Err(error) if require_biometrics => {
+                    return Err(error.context("Failed to wrap master key with biometrics"));
+                }
+                Err(error) => {
+                    log::warn!("Failed to wrap master key, falling back to software: {error}");
+                    None
+                }
+            }
+        } else if require_biometrics {
+            bail!("Biometric protection requires a hardware key store");
+        } else {
+            None
+        };
+        let stored = match wrapped {
+            Some(wrapped_key) => StoredMasterKey {
+                wrapped_key,
+                protection: if require_biometrics {
+                    KeyProtection::HardwareBiometric
+                } else {
+                    KeyProtection::Hardware
+                },
+            },
+            None => StoredMasterKey {
+                wrapped_key: self.key.to_vec(),
+                protection: KeyProtection::Software,
+            },
+        };
+        let connection = open_phnx_db(db_path)?;
+        StoredMasterKey::create_table(&connection)?;
+        let previous = StoredMasterKey::load(&connection)?.map(|stored| stored.protection);
+        stored.store(&connection)?;
+        self.protection = stored.protection;
+
+        if let Some(previous) = previous
+            .filter(|previous| previous.is_hardware_backed() && *previous != self.protection)
+        {
+            // The new key is persisted, so failing here only leaves an unused
+            // platform key behind.
+            if let Err(error) = key_store.delete_key(previous.requires_biometrics()).await {
+                log::error!("Failed to delete previous platform key: {error}");
+            }
+        }
+        Ok(self)
+    }
+
+    /// Encrypt the given data with the master key. The ciphertext is bound to
+    /// the given label, which has to be passed again for decryption.
+    pub fn encrypt(&self, label: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let ciphertext = self
+            .data_key()
+            .encrypt_with_aad(plaintext, label.as_bytes())
+            .map_err(|error| anyhow!("Failed to encrypt with the master key: {error:?}"))?;
+        Ok(PhnxCodec::to_vec(&ciphertext)?)
+    }
+
+    /// Decrypt data encrypted by [`Self::encrypt`] with the same label.
+    pub fn decrypt(&self, label: &str, ciphertext: &[u8]) -> Result<SecretBytes> {
+        let ciphertext: Ciphertext = PhnxCodec::from_slice(ciphertext)?;
+        let plaintext = self
+            .data_key()
+            .decrypt_with_aad(&ciphertext, label.as_bytes())
+            .map_err(|error| anyhow!("Failed to decrypt with the master key: {error:?}"))?;
+        Ok(plaintext.into())
+    }
+
+    fn data_key(&self) -> MasterDataKey {
+        let mut key = [0; MASTER_KEY_LENGTH];
+        key.copy_from_slice(self.key.expose_secret());
+        MasterDataKey(Secret::from(key))
+    }
+
+    pub fn protection(&self) -> KeyProtection {
+        self.protection
+    }
+
+    pub fn expose_secret(&self) -> &[u8] {
+        self.key.expose_secret()
+    }
+}
+
+/// The master key as AEAD key for [`MasterKey::encrypt`].
+struct MasterDataKey(Secret<MASTER_KEY_LENGTH>);
+
+impl AsRef<Secret<MASTER_KEY_LENGTH>> for MasterDataKey {
+    fn as_ref(&self) -> &Secret<MASTER_KEY_LENGTH> {
+        &self.0
+    }
+}
+
+impl From<Secret<MASTER_KEY_LENGTH>> for MasterDataKey {
+    fn from(secret: Secret<MASTER_KEY_LENGTH>) -> Self {
+        Self(secret)
+    }
+}
+
+impl EarKey for MasterDataKey {}
+
+/// The master key as persisted in the phnx.db.
+struct StoredMasterKey {
+    /// The master key wrapped by the platform key store or the master key
+    /// itself if the protection is [`KeyProtection::Software`].
+    wrapped_key: Vec<u8>,
+    protection: KeyProtection,
+}
+
+impl StoredMasterKey {
+    async fn unlock(self, key_store: &impl PlatformKeyStore) -> Result<MasterKey> {
+        let key = match self.protection {
+            KeyProtection::Software => SecretBytes::from(self.wrapped_key),
+            KeyProtection::Hardware | KeyProtection::HardwareBiometric => {
+                key_store
+                    .unwrap_key(&self.wrapped_key, self.protection.requires_biometrics())
+                    .await?
+            }
+        };
+        if key.len() != MASTER_KEY_LENGTH {
+            bail!("Unwrapped master key has an invalid length");
+        }
+        Ok(MasterKey {
+            key,
+            protection: self.protection,
+        })
+    }
+
+    fn load_from_phnx_db(db_path: &str) -> Result<Option<Self>> {
+        let connection = open_phnx_db(db_path)?;
+        Self::create_table(&connection)?;
+        Ok(Self::load(&connection)?)
+    }
+}
+
+impl Storable for StoredMasterKey {
+    // There is at most one master key per device.
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS master_key (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            wrapped_key BLOB NOT NULL,
+            protection TEXT NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            wrapped_key: row.get(0)?,
+            protection: row.get(1)?,
+        })
+    }
+}
+
+impl StoredMasterKey {
+    fn store(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO master_key (id, wrapped_key, protection) VALUES (0, ?, ?)",
+            params![self.wrapped_key, self.protection],
+        )?;
+        Ok(())
+    }
+
+    fn load(connection: &Connection) -> Result<Option<Self>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT wrapped_key, protection FROM master_key WHERE id = 0",
+                [],
+                Self::from_row,
+            )
+            .optional()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+
+    use super::*;
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// Platform key store that "wraps" keys by flipping their bits.
+    #[derive(Default)]
+    struct TestKeyStore {
+        biometrics_passed: AtomicBool,
+        /// `require_biometrics` of the deleted keys
+        deleted_keys: Mutex<Vec<bool>>,
+    }
+
+    impl PlatformKeyStore for TestKeyStore {
+        async fn is_available(&self) -> bool {
+            true
+        }
+
+        async fn wrap_key(&self, key: &[u8], require_biometrics: bool) -> Result<Vec<u8>> {
+            let mut wrapped_key = vec![u8::from(require_biometrics)];
+            wrapped_key.extend(key.iter().map(|byte| !byte));
+            Ok(wrapped_key)
+        }
+
+        async fn unwrap_key(
+            &self,
+            wrapped_key: &[u8],
+            require_biometrics: bool,
+        ) -> Result<SecretBytes> {
+            let (wrapped_with_biometrics, key) = wrapped_key.split_first().unwrap();
+            assert_eq!(*wrapped_with_biometrics == 1, require_biometrics);
+            if require_biometrics && !self.biometrics_passed.load(Ordering::SeqCst) {
+                bail!("Biometric authentication failed");
+            }
+            Ok(key.iter().map(|byte| !byte).collect::<Vec<_>>().into())
+        }
+
+        async fn delete_key(&self, require_biometrics: bool) -> Result<()> {
+            self.deleted_keys.lock().unwrap().push(require_biometrics);
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn software_fallback() {
+        let dir = temp_dir();
+        let db_path = dir.to_str().unwrap();
+
+        // Biometrics can't be provided without a hardware key store
+        assert!(
+            MasterKey::unlock_or_create(db_path, &SoftwareKeyStore, true)
+                .await
+                .is_err()
+        );
+        assert_eq!(MasterKey::persisted_protection(db_path).unwrap(), None);
+
+        let key = MasterKey::unlock_or_create(db_path, &SoftwareKeyStore, false)
+            .await
+            .unwrap();
+        assert_eq!(key.protection(), KeyProtection::Software);
+        let unlocked = MasterKey::unlock_or_create(db_path, &SoftwareKeyStore, false)
+            .await
+            .unwrap();
+        assert_eq!(unlocked.expose_secret(), key.expose_secret());
+        assert!(unlocked
+            .protect(db_path, &SoftwareKeyStore, true)
+            .await
+            .is_err());
+        assert_eq!(
+            MasterKey::persisted_protection(db_path).unwrap(),
+            Some(KeyProtection::Software)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn biometric_unlock() {
+        let dir = temp_dir();
+        let db_path = dir.to_str().unwrap();
+        let key_store = TestKeyStore::default();
+
+        let key = MasterKey::unlock_or_create(db_path, &key_store, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            MasterKey::persisted_protection(db_path).unwrap(),
+            Some(KeyProtection::HardwareBiometric)
+        );
+        assert!(key_store.deleted_keys.lock().unwrap().is_empty());
+        assert!(MasterKey::unlock_or_create(db_path, &key_store, true)
+            .await
+            .is_err());
+
+        key_store.biometrics_passed.store(true, Ordering::SeqCst);
+        let unlocked = MasterKey::unlock_or_create(db_path, &key_store, true)
+            .await
+            .unwrap();
+        assert_eq!(unlocked.expose_secret(), key.expose_secret());
+
+        // Disable the biometric unlock, which deletes the biometric key
+        let key = unlocked.protect(db_path, &key_store, false).await.unwrap();
+        assert_eq!(key.protection(), KeyProtection::Hardware);
+        assert_eq!(*key_store.deleted_keys.lock().unwrap(), [true]);
+        key_store.biometrics_passed.store(false, Ordering::SeqCst);
+        let unlocked = MasterKey::unlock_or_create(db_path, &key_store, true)
+            .await
+            .unwrap();
+        assert_eq!(unlocked.expose_secret(), key.expose_secret());
+
+        // Protecting the key the same way again keeps the platform key
+        unlocked.protect(db_path, &key_store, false).await.unwrap();
+        assert_eq!(*key_store.deleted_keys.lock().unwrap(), [true]);
+    }
+
+    #[actix_rt::test]
+    async fn encrypted_data_survives_protection_changes() {
+        let dir = temp_dir();
+        let db_path = dir.to_str().unwrap();
+        let key_store = TestKeyStore::default();
+
+        let key = MasterKey::unlock_or_create(db_path, &key_store, false)
+            .await
+            .unwrap();
+        let ciphertext = key.encrypt("test", b"secret data").unwrap();
+        assert!(!ciphertext
+            .windows(b"secret data".len())
+            .any(|window| window == b"secret data"));
+        assert!(key.decrypt("other label", &ciphertext).is_err());
+
+        key.protect(db_path, &key_store, true).await.unwrap();
+        key_store.biometrics_passed.store(true, Ordering::SeqCst);
+        let unlocked = MasterKey::unlock_or_create(db_path, &key_store, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            unlocked
+                .decrypt("test", &ciphertext)
+                .unwrap()
+                .expose_secret(),
+            b"secret data"
+        );
+    }
+}
@@ -4,7 +4,7 @@
 
 use std::ops::Deref;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use leaf_keys::LeafKeys;
 use openmls::prelude::{
     CredentialWithKey, Extension, Extensions, KeyPackage, LastResortExtension, SignaturePublicKey,
@@ -18,7 +18,7 @@ use phnxtypes::{
         hpke::{ClientIdEncryptionKey, HpkeEncryptable},
     },
     identifiers::{
-        ClientConfig, QsClientId, QsClientReference, QS_CLIENT_REFERENCE_EXTENSION_TYPE,
+        AsClientId, ClientConfig, QsClientId, QsClientReference, QS_CLIENT_REFERENCE_EXTENSION_TYPE,
     },
     keypackage_batch::AddPackage,
 };
@@ -33,7 +33,8 @@ use phnxtypes::{
     credentials::keys::ClientSigningKey,
     crypto::{
         ear::keys::{
-            AddPackageEarKey, ClientCredentialEarKey, PushTokenEarKey, SignatureEarKeyWrapperKey,
+            AddPackageEarKey, ClientCredentialEarKey, PushTokenEarKey, SelfMessageEarKey,
+            SignatureEarKeyWrapperKey, UserProfileEarKey, UserSettingsEarKey,
             WelcomeAttributionInfoEarKey,
         },
         signatures::keys::{QsClientSigningKey, QsUserSigningKey},
@@ -43,17 +44,21 @@ use phnxtypes::{
 };
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use signing_key::RotatableSigningKey;
 
 pub(crate) mod as_credentials;
 pub(crate) mod leaf_keys;
+pub(crate) mod master_key;
 pub(crate) mod qs_verifying_keys;
 pub(crate) mod queue_ratchets;
+pub(crate) mod signing_key;
 
 // For now we persist the key store along with the user. Any key material that gets rotated in the future needs to be persisted separately.
 #[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct MemoryUserKeyStore {
-    // Client credential secret key
-    pub(super) signing_key: ClientSigningKey,
+    // Client credential secret key. Rotated when the client credential is
+    // refreshed, see [`signing_key`].
+    pub(super) signing_key: RotatableSigningKey,
     // AS-specific key material
     pub(super) as_queue_decryption_key: RatchetDecryptionKey,
     pub(super) connection_decryption_key: ConnectionDecryptionKey,
@@ -69,13 +74,62 @@ pub(crate) struct MemoryUserKeyStore {
     pub(super) client_credential_ear_key: ClientCredentialEarKey,
     pub(super) signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey,
     pub(super) wai_ear_key: WelcomeAttributionInfoEarKey,
+    // Shared by all clients of the user. Users created before profiles were
+    // stored on the AS get a key when they are loaded.
+    #[serde(default)]
+    pub(super) user_profile_ear_key: Option<UserProfileEarKey>,
+    // Shared by all clients of the user. Users created before their clients
+    // exchanged self messages don't have this key.
+    #[serde(default)]
+    pub(super) self_message_ear_key: Option<SelfMessageEarKey>,
+    // Shared by all clients of the user. Users created before their clients
+    // synced their settings don't have this key.
+    #[serde(default)]
+    pub(super) user_settings_ear_key: Option<UserSettingsEarKey>,
 }
 
 impl MemoryUserKeyStore {
+    /// The current client signing key.
+    pub(crate) fn signing_key(&self) -> ClientSigningKey {
+        self.signing_key.current()
+    }
+
+    /// Replace the client signing key after the client credential was
+    /// refreshed.
+    pub(crate) fn rotate_signing_key(&self, signing_key: ClientSigningKey) {
+        self.signing_key.replace(signing_key)
+    }
+
+    pub(crate) fn client_id(&self) -> &AsClientId {
+        self.signing_key.client_id()
+    }
+
+    /// The key of the self messages exchanged between the clients of the
+    /// user.
+    pub(crate) fn self_message_ear_key(&self) -> Result<&SelfMessageEarKey> {
+        self.self_message_ear_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("User has no self message EAR key"))
+    }
+
+    /// The key of the user settings synced via the AS.
+    pub(crate) fn user_settings_ear_key(&self) -> Result<&UserSettingsEarKey> {
+        self.user_settings_ear_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("User has no user settings EAR key"))
+    }
+
+    /// The key of the own profile stored on the AS.
+    pub(crate) fn user_profile_ear_key(&self) -> Result<&UserProfileEarKey> {
+        self.user_profile_ear_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("User has no user profile EAR key"))
+    }
+
     pub(crate) fn encrypt_client_credential(
         &self,
     ) -> Result<EncryptedClientCredential, EncryptionError> {
-        self.signing_key
+        self.signing_key()
             .credential()
             .encrypt(&self.client_credential_ear_key)
     }
@@ -90,12 +144,7 @@ impl MemoryUserKeyStore {
         }
         .encrypt(&self.qs_client_id_encryption_key, &[], &[]);
         QsClientReference {
-            client_homeserver_domain: self
-                .signing_key
-                .credential()
-                .identity_ref()
-                .user_name()
-                .domain(),
+            client_homeserver_domain: self.client_id().user_name().domain(),
             sealed_reference,
         }
     }
@@ -108,7 +157,7 @@ impl MemoryUserKeyStore {
         last_resort: bool,
     ) -> Result<AddPackage> {
         let provider = PhnxOpenMlsProvider::new(connection);
-        let leaf_keys = LeafKeys::generate(&self.signing_key)?;
+        let leaf_keys = LeafKeys::generate(&self.signing_key())?;
         leaf_keys.store(connection)?;
         let credential_with_key = leaf_keys.credential()?;
         let capabilities = default_capabilities();
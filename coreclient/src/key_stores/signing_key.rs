@@ -0,0 +1,171 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! The client's signing key.
+//!
+//! The key is created during registration and persisted as part of the
+//! [`MemoryUserKeyStore`](super::MemoryUserKeyStore). When the client
+//! credential is refreshed, the key is replaced in memory for all users of the
+//! key store and the new key is persisted separately. When loading the user,
+//! the separately persisted key takes precedence.
+//!
+//! Before the AS accepts the new key, the client has to confirm it. In the
+//! meantime, the key is persisted as pending, such that the confirmation can
+//! be retried if it fails.
+
+use std::sync::{Arc, PoisonError, RwLock};
+
+use phnxtypes::{codec::PhnxCodec, credentials::keys::ClientSigningKey, identifiers::AsClientId};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::utils::persistence::Storable;
+
+/// Shared, replaceable handle to the client's signing key.
+///
+/// Serializes like the contained [`ClientSigningKey`].
+#[derive(Clone)]
+pub(crate) struct RotatableSigningKey {
+    // The client id doesn't change when the key is rotated.
+    client_id: AsClientId,
+    key: Arc<RwLock<ClientSigningKey>>,
+}
+
+impl RotatableSigningKey {
+    pub(crate) fn new(key: ClientSigningKey) -> Self {
+        Self {
+            client_id: key.credential().identity(),
+            key: Arc::new(RwLock::new(key)),
+        }
+    }
+
+    pub(crate) fn client_id(&self) -> &AsClientId {
+        &self.client_id
+    }
+
+    /// A copy of the current key.
+    pub(crate) fn current(&self) -> ClientSigningKey {
+        self.key
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the key in memory. The new key must belong to the same client.
+    pub(crate) fn replace(&self, key: ClientSigningKey) {
+        debug_assert_eq!(key.credential().identity_ref(), &self.client_id);
+        *self.key.write().unwrap_or_else(PoisonError::into_inner) = key;
+    }
+}
+
+impl Serialize for RotatableSigningKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.key
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RotatableSigningKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ClientSigningKey::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// A signing key that replaced the one created during registration.
+pub(crate) struct StorableClientSigningKey(ClientSigningKey);
+
+impl ToSql for StorableClientSigningKey {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let bytes = PhnxCodec::to_vec(&self.0)?;
+        Ok(ToSqlOutput::Owned(Value::Blob(bytes)))
+    }
+}
+
+impl FromSql for StorableClientSigningKey {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let key = PhnxCodec::from_slice(value.as_blob()?)?;
+        Ok(Self(key))
+    }
+}
+
+impl Storable for StorableClientSigningKey {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS client_signing_key (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            signing_key BLOB NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        row.get(0)
+    }
+}
+
+/// Table holding a signing key whose client credential the AS has issued, but
+/// which the client hasn't confirmed yet.
+pub(crate) const PENDING_CLIENT_SIGNING_KEY_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS pending_client_signing_key (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        signing_key BLOB NOT NULL
+    );";
+
+impl StorableClientSigningKey {
+    pub(crate) fn load(
+        connection: &Connection,
+    ) -> Result<Option<ClientSigningKey>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT signing_key FROM client_signing_key WHERE id = 0",
+                [],
+                Self::from_row,
+            )
+            .optional()
+            .map(|key| key.map(|Self(key)| key))
+    }
+
+    pub(crate) fn store(
+        connection: &Connection,
+        signing_key: &ClientSigningKey,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO client_signing_key (id, signing_key) VALUES (0, ?)",
+            params![Self(signing_key.clone())],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load_pending(
+        connection: &Connection,
+    ) -> Result<Option<ClientSigningKey>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT signing_key FROM pending_client_signing_key WHERE id = 0",
+                [],
+                Self::from_row,
+            )
+            .optional()
+            .map(|key| key.map(|Self(key)| key))
+    }
+
+    pub(crate) fn store_pending(
+        connection: &Connection,
+        signing_key: &ClientSigningKey,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO pending_client_signing_key (id, signing_key) VALUES (0, ?)",
+            params![Self(signing_key.clone())],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_pending(connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection.execute("DELETE FROM pending_client_signing_key WHERE id = 0", [])?;
+        Ok(())
+    }
+}
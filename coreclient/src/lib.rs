@@ -4,27 +4,88 @@
 
 //! Implements the protocol logic of the client component
 
+mod activity;
+mod announcements;
+mod archive;
+mod attachments;
+#[cfg(feature = "bench")]
+pub mod bench_utils;
+mod broadcast_lists;
+mod chat_metadata;
 pub mod clients;
 mod contacts;
 mod conversations;
+mod counters;
 mod groups;
 mod key_stores;
 mod mimi_content;
+pub mod mimi_interop;
+mod presence;
+pub mod store;
+pub mod trust_store;
+mod user_handles;
 mod user_profiles;
+mod user_settings;
 mod utils;
 
 pub use crate::{
-    contacts::{Contact, PartialContact},
+    activity::{Activity, ActivityCursor, ActivityId, ActivityKind},
+    announcements::Announcement,
+    archive::{ArchiveUsage, MessageArchiving},
+    attachments::{
+        AttachmentHash, AttachmentKind, AutoDownloadCondition, AutoDownloadPolicy,
+        AutoDownloadRule, AutoDownloadRules, ConversationStorage, MediaRetention,
+        MessageAttachment, NetworkType, StorageBreakdown,
+    },
+    broadcast_lists::{BroadcastDelivery, BroadcastDeliveryStatus, BroadcastList, BroadcastListId},
+    chat_metadata::{ChatColor, ChatMetadata, ChatMetadataError, ChatMetadataKey},
+    clients::{
+        account_deletion::AccountErasure,
+        api_clients::ApiClientPoolStats,
+        connection_establishment::{ConnectionIntro, ConnectionIntroError},
+        conversations::{ChatCreationConfig, ChatVisibility},
+        forward::ForwardOutcome,
+        member_list::{ChatMember, ChatMemberRole, ChatMembersPage, MemberListCursor},
+        outbox::Outbox,
+        wipe::wipe_device,
+    },
+    contacts::{
+        Contact, ContactFilter, ContactListCursor, ContactVerification, ContactsPage,
+        PartialContact,
+    },
     conversations::{
         messages::{
-            ContentMessage, ConversationMessage, ConversationMessageId, ErrorMessage, EventMessage,
-            Message, NotificationType, SystemMessage,
+            ContentMessage, ConversationMessage, ConversationMessageId, DeliveryFailure,
+            ErrorMessage, EventMessage, Message, NotificationType, SystemMessage,
+            LATE_DELIVERY_THRESHOLD,
         },
-        Conversation, ConversationAttributes, ConversationId, ConversationStatus, ConversationType,
-        InactiveConversation,
+        Conversation, ConversationAttributes, ConversationExport, ConversationId,
+        ConversationStatus, ConversationType, InactiveConversation,
     },
-    mimi_content::{MessageId, MimiContent, ReplyToInfo, TopicId},
-    user_profiles::{Asset, DisplayName, DisplayNameError, UserProfile},
+    counters::{ChatCounters, Counters},
+    groups::{
+        join_request::PendingJoinRequest,
+        membership_history::{MembershipChange, MembershipChangeKind},
+        quarantine::QuarantinedWelcome,
+        security::{ConversationSecurityStatus, STALE_PROPOSAL_THRESHOLD},
+    },
+    key_stores::master_key::{KeyProtection, MasterKey, PlatformKeyStore, SoftwareKeyStore},
+    mimi_content::{ForwardedFrom, MessageId, MimiContent, ReplyToInfo, TopicId},
+    presence::{PresenceSettings, PRESENCE_CACHE_TTL},
+    user_handles::{
+        BlockedUser, FilterReason, MessageRequest, MessageRequestState,
+        MessageRequestTransitionError,
+    },
+    user_profiles::{Asset, DisplayName, DisplayNameError, ProfileRefreshPolicy, UserProfile},
+    user_settings::UserSetting,
 };
 
+#[cfg(feature = "attachments")]
+pub use crate::attachments::{
+    MediaCodec, MediaTranscoding, NoMediaCodec, VideoCodec, VideoTranscodeParams, ViewOnceState,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::clients::headless::{HeadlessSyncClient, SyncHooks, SyncSummary, SyncTrigger};
 pub use crate::utils::persistence::delete_databases;
+#[cfg(target_arch = "wasm32")]
+pub use crate::utils::persistence::delete_indexed_databases;
@@ -4,12 +4,27 @@
 
 use std::str::FromStr;
 
-use phnxtypes::identifiers::Fqdn;
-use tls_codec::{DeserializeBytes, Serialize, Size};
+use phnxtypes::{
+    crypto::ear::keys::{AttachmentEarKey, UserProfileEarKey},
+    identifiers::{AttachmentId, Fqdn},
+};
+use tls_codec::{DeserializeBytes, Serialize, Size, VLBytes};
 use url::Url;
 use uuid::Uuid;
 
-use super::{ContentType, ExternalPartUrl, MessageId, SinglePart, TlsStr, TlsStrOwned};
+use crate::attachments::AttachmentKind;
+
+use super::{
+    ContentType, Disposition, EncryptedAttachment, ExternalPart, ExternalPartUrl, ForwardedFrom,
+    Language, MessageId, MultiParts, NestablePart, Part, PartSemantics, SinglePart, TlsStr,
+    TlsStrOwned,
+};
+
+/// Maximum nesting depth of multipart bodies. Forwarded messages use one
+/// level of nesting.
+pub(super) const MAX_PART_DEPTH: usize = 4;
+/// Maximum number of parts in a single multipart body.
+pub(super) const MAX_PARTS: usize = 64;
 
 impl Size for TlsStr<'_> {
     fn tls_serialized_len(&self) -> usize {
@@ -105,10 +120,43 @@ impl DeserializeBytes for ExternalPartUrl {
     }
 }
 
+/// Vendor-specific content type of view-once attachments.
+const VIEW_ONCE_ATTACHMENT_CONTENT_TYPE: &str = "application/vnd.phnx.view-once-attachment";
+/// Vendor-specific content type of view-once attachments referred to by
+/// qualified URL.
+const VIEW_ONCE_ATTACHMENT_URL_CONTENT_TYPE: &str = "application/vnd.phnx.view-once-attachment-url";
+/// Vendor-specific content type of encrypted view-once attachments.
+const ENCRYPTED_VIEW_ONCE_ATTACHMENT_CONTENT_TYPE: &str =
+    "application/vnd.phnx.encrypted-view-once-attachment";
+/// Vendor-specific content type of encrypted attachments.
+const ENCRYPTED_ATTACHMENT_CONTENT_TYPE: &str = "application/vnd.phnx.encrypted-attachment";
+/// Vendor-specific content type of the attribution of forwarded messages.
+const FORWARDED_FROM_CONTENT_TYPE: &str = "application/vnd.phnx.forwarded-from";
+/// Vendor-specific content type of the profile key shared with a contact.
+const USER_PROFILE_KEY_CONTENT_TYPE: &str = "application/vnd.phnx.user-profile-key";
+
 impl Size for ContentType {
     fn tls_serialized_len(&self) -> usize {
         match self {
             ContentType::TextMarkdown => TlsStr::from("text/markdown").tls_serialized_len(),
+            ContentType::ViewOnceAttachment => {
+                TlsStr::from(VIEW_ONCE_ATTACHMENT_CONTENT_TYPE).tls_serialized_len()
+            }
+            ContentType::ViewOnceAttachmentUrl => {
+                TlsStr::from(VIEW_ONCE_ATTACHMENT_URL_CONTENT_TYPE).tls_serialized_len()
+            }
+            ContentType::EncryptedViewOnceAttachment => {
+                TlsStr::from(ENCRYPTED_VIEW_ONCE_ATTACHMENT_CONTENT_TYPE).tls_serialized_len()
+            }
+            ContentType::ForwardedFrom => {
+                TlsStr::from(FORWARDED_FROM_CONTENT_TYPE).tls_serialized_len()
+            }
+            ContentType::UserProfileKey => {
+                TlsStr::from(USER_PROFILE_KEY_CONTENT_TYPE).tls_serialized_len()
+            }
+            ContentType::EncryptedAttachment => {
+                TlsStr::from(ENCRYPTED_ATTACHMENT_CONTENT_TYPE).tls_serialized_len()
+            }
         }
     }
 }
@@ -120,6 +168,24 @@ impl Serialize for ContentType {
     ) -> Result<usize, tls_codec::Error> {
         match self {
             ContentType::TextMarkdown => TlsStr::from("text/markdown").tls_serialize(writer),
+            ContentType::ViewOnceAttachment => {
+                TlsStr::from(VIEW_ONCE_ATTACHMENT_CONTENT_TYPE).tls_serialize(writer)
+            }
+            ContentType::ViewOnceAttachmentUrl => {
+                TlsStr::from(VIEW_ONCE_ATTACHMENT_URL_CONTENT_TYPE).tls_serialize(writer)
+            }
+            ContentType::EncryptedViewOnceAttachment => {
+                TlsStr::from(ENCRYPTED_VIEW_ONCE_ATTACHMENT_CONTENT_TYPE).tls_serialize(writer)
+            }
+            ContentType::ForwardedFrom => {
+                TlsStr::from(FORWARDED_FROM_CONTENT_TYPE).tls_serialize(writer)
+            }
+            ContentType::UserProfileKey => {
+                TlsStr::from(USER_PROFILE_KEY_CONTENT_TYPE).tls_serialize(writer)
+            }
+            ContentType::EncryptedAttachment => {
+                TlsStr::from(ENCRYPTED_ATTACHMENT_CONTENT_TYPE).tls_serialize(writer)
+            }
         }
     }
 }
@@ -129,6 +195,16 @@ impl DeserializeBytes for ContentType {
         let (value, buffer) = TlsStrOwned::tls_deserialize_bytes(buffer)?;
         match value.value.as_str() {
             "text/markdown" => Ok((ContentType::TextMarkdown, buffer)),
+            VIEW_ONCE_ATTACHMENT_CONTENT_TYPE => Ok((ContentType::ViewOnceAttachment, buffer)),
+            VIEW_ONCE_ATTACHMENT_URL_CONTENT_TYPE => {
+                Ok((ContentType::ViewOnceAttachmentUrl, buffer))
+            }
+            ENCRYPTED_VIEW_ONCE_ATTACHMENT_CONTENT_TYPE => {
+                Ok((ContentType::EncryptedViewOnceAttachment, buffer))
+            }
+            FORWARDED_FROM_CONTENT_TYPE => Ok((ContentType::ForwardedFrom, buffer)),
+            USER_PROFILE_KEY_CONTENT_TYPE => Ok((ContentType::UserProfileKey, buffer)),
+            ENCRYPTED_ATTACHMENT_CONTENT_TYPE => Ok((ContentType::EncryptedAttachment, buffer)),
             _ => Err(tls_codec::Error::DecodingError(format!(
                 "Unknown content type: {}",
                 value.value
@@ -144,6 +220,29 @@ impl Size for SinglePart {
                 ContentType::TextMarkdown.tls_serialized_len()
                     + content.as_bytes().tls_serialized_len()
             }
+            SinglePart::ViewOnceAttachment(attachment_id) => {
+                ContentType::ViewOnceAttachment.tls_serialized_len()
+                    + attachment_id.tls_serialized_len()
+            }
+            SinglePart::ViewOnceAttachmentUrl(attachment_url) => {
+                ContentType::ViewOnceAttachmentUrl.tls_serialized_len()
+                    + TlsStr::from(attachment_url.to_string().as_str()).tls_serialized_len()
+            }
+            SinglePart::EncryptedViewOnceAttachment(attachment) => {
+                ContentType::EncryptedViewOnceAttachment.tls_serialized_len()
+                    + attachment.tls_serialized_len()
+            }
+            SinglePart::ForwardedFrom(forwarded_from) => {
+                ContentType::ForwardedFrom.tls_serialized_len()
+                    + forwarded_from.tls_serialized_len()
+            }
+            SinglePart::UserProfileKey(key) => {
+                ContentType::UserProfileKey.tls_serialized_len() + key.tls_serialized_len()
+            }
+            SinglePart::EncryptedAttachment(attachment) => {
+                ContentType::EncryptedAttachment.tls_serialized_len()
+                    + attachment.tls_serialized_len()
+            }
         }
     }
 }
@@ -159,6 +258,37 @@ impl Serialize for SinglePart {
                 written += content.as_bytes().tls_serialize(writer)?;
                 Ok(written)
             }
+            SinglePart::ViewOnceAttachment(attachment_id) => {
+                let mut written = ContentType::ViewOnceAttachment.tls_serialize(writer)?;
+                written += attachment_id.tls_serialize(writer)?;
+                Ok(written)
+            }
+            SinglePart::ViewOnceAttachmentUrl(attachment_url) => {
+                let mut written = ContentType::ViewOnceAttachmentUrl.tls_serialize(writer)?;
+                written +=
+                    TlsStr::from(attachment_url.to_string().as_str()).tls_serialize(writer)?;
+                Ok(written)
+            }
+            SinglePart::EncryptedViewOnceAttachment(attachment) => {
+                let mut written = ContentType::EncryptedViewOnceAttachment.tls_serialize(writer)?;
+                written += attachment.tls_serialize(writer)?;
+                Ok(written)
+            }
+            SinglePart::ForwardedFrom(forwarded_from) => {
+                let mut written = ContentType::ForwardedFrom.tls_serialize(writer)?;
+                written += forwarded_from.tls_serialize(writer)?;
+                Ok(written)
+            }
+            SinglePart::UserProfileKey(key) => {
+                let mut written = ContentType::UserProfileKey.tls_serialize(writer)?;
+                written += key.tls_serialize(writer)?;
+                Ok(written)
+            }
+            SinglePart::EncryptedAttachment(attachment) => {
+                let mut written = ContentType::EncryptedAttachment.tls_serialize(writer)?;
+                written += attachment.tls_serialize(writer)?;
+                Ok(written)
+            }
         }
     }
 }
@@ -171,6 +301,351 @@ impl DeserializeBytes for SinglePart {
                 let (content, buffer) = TlsStrOwned::tls_deserialize_bytes(buffer)?;
                 Ok((SinglePart::TextMarkdown(content.value), buffer))
             }
+            ContentType::ViewOnceAttachment => {
+                let (attachment_id, buffer) = AttachmentId::tls_deserialize_bytes(buffer)?;
+                Ok((SinglePart::ViewOnceAttachment(attachment_id), buffer))
+            }
+            ContentType::ViewOnceAttachmentUrl => {
+                let (attachment_url, buffer) = TlsStrOwned::tls_deserialize_bytes(buffer)?;
+                let attachment_url = attachment_url.value.parse().map_err(|e| {
+                    tls_codec::Error::DecodingError(format!("Invalid attachment URL: {}", e))
+                })?;
+                Ok((SinglePart::ViewOnceAttachmentUrl(attachment_url), buffer))
+            }
+            ContentType::EncryptedViewOnceAttachment => {
+                let (attachment, buffer) = EncryptedAttachment::tls_deserialize_bytes(buffer)?;
+                Ok((SinglePart::EncryptedViewOnceAttachment(attachment), buffer))
+            }
+            ContentType::ForwardedFrom => {
+                let (forwarded_from, buffer) = ForwardedFrom::tls_deserialize_bytes(buffer)?;
+                Ok((SinglePart::ForwardedFrom(forwarded_from), buffer))
+            }
+            ContentType::UserProfileKey => {
+                let (key, buffer) = UserProfileEarKey::tls_deserialize_bytes(buffer)?;
+                Ok((SinglePart::UserProfileKey(key), buffer))
+            }
+            ContentType::EncryptedAttachment => {
+                let (attachment, buffer) = EncryptedAttachment::tls_deserialize_bytes(buffer)?;
+                Ok((SinglePart::EncryptedAttachment(attachment), buffer))
+            }
         }
     }
 }
+
+impl Size for EncryptedAttachment {
+    fn tls_serialized_len(&self) -> usize {
+        TlsStr::from(self.url.to_string().as_str()).tls_serialized_len()
+            + self.key.tls_serialized_len()
+            + self.ciphertext_hash.as_slice().tls_serialized_len()
+            + attachment_kind_to_u8(self.kind).tls_serialized_len()
+            + self.size.tls_serialized_len()
+    }
+}
+
+impl Serialize for EncryptedAttachment {
+    fn tls_serialize<W: std::io::prelude::Write>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, tls_codec::Error> {
+        let mut written = TlsStr::from(self.url.to_string().as_str()).tls_serialize(writer)?;
+        written += self.key.tls_serialize(writer)?;
+        written += self.ciphertext_hash.as_slice().tls_serialize(writer)?;
+        written += attachment_kind_to_u8(self.kind).tls_serialize(writer)?;
+        written += self.size.tls_serialize(writer)?;
+        Ok(written)
+    }
+}
+
+impl DeserializeBytes for EncryptedAttachment {
+    fn tls_deserialize_bytes(buffer: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (url, buffer) = TlsStrOwned::tls_deserialize_bytes(buffer)?;
+        let url = url.value.parse().map_err(|e| {
+            tls_codec::Error::DecodingError(format!("Invalid attachment URL: {}", e))
+        })?;
+        let (key, buffer) = AttachmentEarKey::tls_deserialize_bytes(buffer)?;
+        let (ciphertext_hash, buffer) = VLBytes::tls_deserialize_bytes(buffer)?;
+        let (kind, buffer) = u8::tls_deserialize_bytes(buffer)?;
+        let (size, buffer) = u64::tls_deserialize_bytes(buffer)?;
+        Ok((
+            Self {
+                url,
+                key,
+                ciphertext_hash: ciphertext_hash.as_slice().to_vec(),
+                kind: attachment_kind_from_u8(kind),
+                size,
+            },
+            buffer,
+        ))
+    }
+}
+
+fn attachment_kind_to_u8(kind: AttachmentKind) -> u8 {
+    match kind {
+        AttachmentKind::Image => 0,
+        AttachmentKind::Video => 1,
+        AttachmentKind::Audio => 2,
+        AttachmentKind::File => 3,
+    }
+}
+
+/// Kinds added in the future are treated as files.
+fn attachment_kind_from_u8(kind: u8) -> AttachmentKind {
+    match kind {
+        0 => AttachmentKind::Image,
+        1 => AttachmentKind::Video,
+        2 => AttachmentKind::Audio,
+        _ => AttachmentKind::File,
+    }
+}
+
+impl DeserializeBytes for NestablePart {
+    fn tls_deserialize_bytes(buffer: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        NestablePart::deserialize_with_depth(buffer, 0)
+    }
+}
+
+impl NestablePart {
+    /// Deserialize a part that is nested in `depth` multipart bodies.
+    fn deserialize_with_depth(
+        buffer: &[u8],
+        depth: usize,
+    ) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (disposition, buffer) = Disposition::tls_deserialize_bytes(buffer)?;
+        let (languages, buffer) = Vec::<Language>::tls_deserialize_bytes(buffer)?;
+        let (part_index, buffer) = u16::tls_deserialize_bytes(buffer)?;
+        let (part_semantic, buffer) = PartSemantics::tls_deserialize_bytes(buffer)?;
+        let (part, buffer) = Part::deserialize_with_depth(buffer, depth)?;
+        let nestable_part = NestablePart {
+            disposition,
+            languages,
+            part_index,
+            part_semantic,
+            part,
+        };
+        Ok((nestable_part, buffer))
+    }
+}
+
+impl Part {
+    fn deserialize_with_depth(
+        buffer: &[u8],
+        depth: usize,
+    ) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (discriminant, buffer) = u16::tls_deserialize_bytes(buffer)?;
+        match discriminant {
+            0 => Ok((Part::Null, buffer)),
+            1 => {
+                let (single_part, buffer) = SinglePart::tls_deserialize_bytes(buffer)?;
+                Ok((Part::Single(single_part), buffer))
+            }
+            2 => {
+                let (external_part, buffer) = ExternalPart::tls_deserialize_bytes(buffer)?;
+                Ok((Part::External(external_part), buffer))
+            }
+            3 => {
+                let (multi_parts, buffer) = MultiParts::deserialize_with_depth(buffer, depth + 1)?;
+                Ok((Part::Multi(multi_parts), buffer))
+            }
+            _ => Err(tls_codec::Error::UnknownValue(discriminant.into())),
+        }
+    }
+}
+
+impl MultiParts {
+    /// Deserialize a multipart body at the given nesting depth. Fails if the
+    /// depth exceeds [`MAX_PART_DEPTH`] or the body contains more than
+    /// [`MAX_PARTS`] parts.
+    fn deserialize_with_depth(
+        buffer: &[u8],
+        depth: usize,
+    ) -> Result<(Self, &[u8]), tls_codec::Error> {
+        if depth > MAX_PART_DEPTH {
+            return Err(tls_codec::Error::DecodingError(format!(
+                "Multipart body nested deeper than {MAX_PART_DEPTH} levels"
+            )));
+        }
+        // Vectors are encoded with the same length prefix as byte vectors.
+        let (parts_bytes, buffer) = VLBytes::tls_deserialize_bytes(buffer)?;
+        let mut parts_buffer = parts_bytes.as_slice();
+        let mut pars = Vec::new();
+        while !parts_buffer.is_empty() {
+            if pars.len() == MAX_PARTS {
+                return Err(tls_codec::Error::DecodingError(format!(
+                    "Multipart body with more than {MAX_PARTS} parts"
+                )));
+            }
+            let (part, remainder) = NestablePart::deserialize_with_depth(parts_buffer, depth)?;
+            pars.push(part);
+            parts_buffer = remainder;
+        }
+        Ok((MultiParts { pars }, buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+    #[cfg(feature = "attachments")]
+    use phnxtypes::messages::attachments::QualifiedAttachmentUrl;
+
+    use crate::mimi_content::{builder::MimiContentBuilder, MimiContent};
+
+    use super::*;
+
+    fn nested_content(depth: usize) -> MimiContent {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let mut part = MimiContent::simple_markdown_message(domain.clone(), "Hi".to_owned()).body;
+        for _ in 0..depth {
+            part = NestablePart {
+                part_semantic: PartSemantics::ProcessAll,
+                part: Part::Multi(MultiParts { pars: vec![part] }),
+                ..Default::default()
+            };
+        }
+        MimiContentBuilder::new(domain, part).build()
+    }
+
+    #[test]
+    fn forwarded_content_roundtrips() {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let content = MimiContent::simple_markdown_message(domain.clone(), "Hi".to_owned());
+        let sender = SafeTryInto::try_into("alice@example.com").unwrap();
+        let forwarded = MimiContent::forwarded(domain, &content, sender);
+
+        let bytes = forwarded.tls_serialize_detached().unwrap();
+        let decoded = MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert_eq!(decoded, forwarded);
+    }
+
+    #[test]
+    #[cfg(feature = "profiles")]
+    fn user_profile_keys_roundtrip() {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let key = UserProfileEarKey::random().unwrap();
+        let content = MimiContent::user_profile_key(domain, key.clone());
+
+        let bytes = content.tls_serialize_detached().unwrap();
+        let decoded = MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert_eq!(decoded.shared_user_profile_key(), Some(&key));
+        assert!(!decoded.is_receipt());
+    }
+
+    #[test]
+    #[cfg(feature = "attachments")]
+    fn view_once_attachments_roundtrip() {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let attachment_id = AttachmentId::from(Uuid::new_v4());
+        let attachment_url = QualifiedAttachmentUrl::new(attachment_id);
+        let attachment = EncryptedAttachment {
+            url: attachment_url.clone(),
+            key: AttachmentEarKey::random().unwrap(),
+            ciphertext_hash: vec![7; 32],
+            kind: AttachmentKind::Image,
+            size: 1024,
+        };
+        let content = MimiContent::view_once_attachment(domain.clone(), attachment.clone());
+
+        let bytes = content.tls_serialize_detached().unwrap();
+        let decoded = MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert_eq!(decoded, content);
+        assert_eq!(
+            decoded.view_once_attachment_url(),
+            Some(attachment_url.clone())
+        );
+        assert_eq!(decoded.encrypted_view_once_attachment(), Some(&attachment));
+
+        // Attachments shared by older clients aren't encrypted
+        let unencrypted = NestablePart {
+            disposition: Disposition::Attachment,
+            part: Part::Single(SinglePart::ViewOnceAttachmentUrl(attachment_url.clone())),
+            ..Default::default()
+        };
+        let bytes = MimiContentBuilder::new(domain.clone(), unencrypted)
+            .build()
+            .tls_serialize_detached()
+            .unwrap();
+        let decoded = MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert!(decoded.is_view_once_attachment());
+        assert_eq!(decoded.encrypted_view_once_attachment(), None);
+        assert_eq!(decoded.view_once_attachment_url(), Some(attachment_url));
+
+        // Attachments shared by id have the legacy generation
+        let legacy = NestablePart {
+            disposition: Disposition::Attachment,
+            part: Part::Single(SinglePart::ViewOnceAttachment(attachment_id)),
+            ..Default::default()
+        };
+        let bytes = MimiContentBuilder::new(domain, legacy)
+            .build()
+            .tls_serialize_detached()
+            .unwrap();
+        let decoded = MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert!(decoded.is_view_once_attachment());
+        assert_eq!(
+            decoded.view_once_attachment_url(),
+            Some(QualifiedAttachmentUrl::legacy(attachment_id))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "attachments")]
+    fn attachments_roundtrip() {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let attachments: Vec<_> = (0..2)
+            .map(|_| EncryptedAttachment {
+                url: QualifiedAttachmentUrl::new(AttachmentId::from(Uuid::new_v4())),
+                key: AttachmentEarKey::random().unwrap(),
+                ciphertext_hash: vec![7; 32],
+                kind: AttachmentKind::Video,
+                size: 4096,
+            })
+            .collect();
+        let caption = MimiContent::simple_markdown_message(domain.clone(), "Caption".to_owned());
+        let content = caption.clone().with_attachments(attachments.clone());
+        assert_eq!(content.id(), caption.id());
+
+        let bytes = content.tls_serialize_detached().unwrap();
+        let decoded = MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert_eq!(decoded, content);
+        assert_eq!(
+            decoded.encrypted_attachments(),
+            attachments.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(decoded.string_rendering(), "Caption");
+        assert!(!decoded.is_view_once_attachment());
+        assert!(caption.encrypted_attachments().is_empty());
+    }
+
+    #[test]
+    fn nesting_depth_is_bounded() {
+        let content = nested_content(MAX_PART_DEPTH);
+        let bytes = content.tls_serialize_detached().unwrap();
+        assert_eq!(
+            MimiContent::tls_deserialize_exact_bytes(&bytes).unwrap(),
+            content
+        );
+
+        let bytes = nested_content(MAX_PART_DEPTH + 1)
+            .tls_serialize_detached()
+            .unwrap();
+        assert!(MimiContent::tls_deserialize_exact_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn number_of_parts_is_bounded() {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let part = MimiContent::simple_markdown_message(domain.clone(), "Hi".to_owned()).body;
+        let body = NestablePart {
+            part_semantic: PartSemantics::ProcessAll,
+            part: Part::Multi(MultiParts {
+                pars: vec![part; MAX_PARTS + 1],
+            }),
+            ..Default::default()
+        };
+        let bytes = MimiContentBuilder::new(domain, body)
+            .build()
+            .tls_serialize_detached()
+            .unwrap();
+        assert!(MimiContent::tls_deserialize_exact_bytes(&bytes).is_err());
+    }
+}
@@ -4,7 +4,12 @@
 
 use openmls::group::GroupId;
 use phnxtypes::{
-    identifiers::{AsClientId, Fqdn, QualifiedUserName},
+    crypto::{
+        ear::keys::{AttachmentEarKey, UserProfileEarKey},
+        rng,
+    },
+    identifiers::{AsClientId, AttachmentId, Fqdn, QualifiedUserName},
+    messages::attachments::QualifiedAttachmentUrl,
     time::TimeStamp,
 };
 use serde::{Deserialize, Serialize};
@@ -12,6 +17,8 @@ use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 use url::Url;
 use uuid::Uuid;
 
+use crate::attachments::AttachmentKind;
+
 use self::builder::MimiContentBuilder;
 
 mod builder;
@@ -46,7 +53,7 @@ pub struct MessageId {
 impl MessageId {
     pub(crate) fn new(domain: Fqdn) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id: rng::random_uuid(),
             domain,
         }
     }
@@ -96,6 +103,12 @@ pub struct ReplyToInfo {
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 enum ContentType {
     TextMarkdown,
+    ViewOnceAttachment,
+    ViewOnceAttachmentUrl,
+    EncryptedViewOnceAttachment,
+    ForwardedFrom,
+    UserProfileKey,
+    EncryptedAttachment,
     // Add more as needed
 }
 
@@ -103,9 +116,58 @@ enum ContentType {
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 enum SinglePart {
     TextMarkdown(String),
+    /// Attachment stored by the DS of the group that recipients may view
+    /// only once. Superseded by [`SinglePart::ViewOnceAttachmentUrl`], but
+    /// still received from older clients.
+    ViewOnceAttachment(AttachmentId),
+    /// Attachment stored by the DS of the given domain that recipients may
+    /// view only once. Superseded by
+    /// [`SinglePart::EncryptedViewOnceAttachment`], but still received from
+    /// older clients.
+    ViewOnceAttachmentUrl(QualifiedAttachmentUrl),
+    /// Encrypted attachment stored by the DS of the domain in its URL that
+    /// recipients may view only once.
+    EncryptedViewOnceAttachment(EncryptedAttachment),
+    /// Attribution of a forwarded message. Precedes the forwarded content in
+    /// a multipart body.
+    ForwardedFrom(ForwardedFrom),
+    /// Key of the profile of the sender, sent to contacts that didn't
+    /// receive it in the friendship package. Not shown as a message.
+    UserProfileKey(UserProfileEarKey),
+    /// Encrypted attachment stored by the DS of the domain in its URL.
+    /// Follows the caption in a multipart body.
+    EncryptedAttachment(EncryptedAttachment),
     // Add more as needed
 }
 
+/// Reference to an attachment that was encrypted under its own key before it
+/// was uploaded, such that the DS only ever stores the ciphertext.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct EncryptedAttachment {
+    pub(crate) url: QualifiedAttachmentUrl,
+    pub(crate) key: AttachmentEarKey,
+    /// SHA-256 hash of the uploaded ciphertext.
+    pub(crate) ciphertext_hash: Vec<u8>,
+    /// Kind and size in bytes of the plaintext, by which recipients decide
+    /// whether to download the attachment automatically. Defaults for
+    /// messages that were stored before they were shared.
+    #[serde(default)]
+    pub(crate) kind: AttachmentKind,
+    #[serde(default)]
+    pub(crate) size: u64,
+}
+
+/// Origin of a forwarded message.
+#[derive(
+    PartialEq, Eq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize, TlsDeserializeBytes,
+)]
+pub struct ForwardedFrom {
+    /// The user that sent the original message.
+    pub sender: QualifiedUserName,
+    /// The time the original message was created.
+    pub timestamp: TimeStamp,
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 struct ExternalPartUrl {
     url: Url,
@@ -137,9 +199,7 @@ struct ExternalPart {
     description: TlsStrOwned, // an optional text description
 }
 
-#[derive(
-    PartialEq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize, TlsDeserializeBytes,
-)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize)]
 struct MultiParts {
     pars: Vec<NestablePart>,
 }
@@ -156,9 +216,7 @@ enum PartSemantics {
     ProcessAll,
 }
 
-#[derive(
-    PartialEq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize, TlsDeserializeBytes,
-)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize)]
 #[repr(u16)]
 enum Part {
     Null,
@@ -190,9 +248,8 @@ struct Language {
     language: TlsStrOwned,
 }
 
-#[derive(
-    PartialEq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize, TlsDeserializeBytes,
-)]
+// Deserialized by hand in the `codec` module to bound the nesting depth.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TlsSize, TlsSerialize)]
 struct NestablePart {
     disposition: Disposition,
     languages: Vec<Language>,
@@ -258,12 +315,224 @@ impl MimiContent {
         MimiContentBuilder::new(sender_domain, nestable_part).build()
     }
 
+    /// Message with a view-once attachment that was encrypted and uploaded to
+    /// the DS at the URL of the given attachment.
+    #[cfg(feature = "attachments")]
+    pub(crate) fn view_once_attachment(
+        sender_domain: Fqdn,
+        attachment: EncryptedAttachment,
+    ) -> Self {
+        let nestable_part = NestablePart {
+            disposition: Disposition::Attachment,
+            languages: Vec::new(),
+            part_index: 0,
+            part_semantic: PartSemantics::SinglePart,
+            part: Part::Single(SinglePart::EncryptedViewOnceAttachment(attachment)),
+        };
+        MimiContentBuilder::new(sender_domain, nestable_part).build()
+    }
+
+    /// Copy of this content with the given attachments, which were encrypted
+    /// and uploaded to the DS at their URLs. The current body becomes the
+    /// caption of the attachments. The id of the content is kept.
+    ///
+    /// If the content was forwarded, the attachments are added to the
+    /// forwarded part, such that the attribution is kept.
+    #[cfg(feature = "attachments")]
+    pub(crate) fn with_attachments(mut self, attachments: Vec<EncryptedAttachment>) -> Self {
+        if self.forwarded_parts().is_some() {
+            if let Part::Multi(multi_parts) = &mut self.body.part {
+                let forwarded_part = &mut multi_parts.pars[1];
+                *forwarded_part = attach(std::mem::take(forwarded_part), attachments);
+                forwarded_part.part_index = 1;
+            }
+        } else {
+            self.body = attach(std::mem::take(&mut self.body), attachments);
+        }
+        self
+    }
+
+    /// Returns the encrypted attachments of this message. View-once
+    /// attachments are not included.
+    pub(crate) fn encrypted_attachments(&self) -> Vec<&EncryptedAttachment> {
+        let Part::Multi(multi_parts) = &self.content_part().part else {
+            return Vec::new();
+        };
+        multi_parts
+            .pars
+            .iter()
+            .filter_map(|part| match &part.part {
+                Part::Single(SinglePart::EncryptedAttachment(attachment)) => Some(attachment),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Message that shares the key of the sender's profile with a contact.
+    #[cfg(feature = "profiles")]
+    pub(crate) fn user_profile_key(sender_domain: Fqdn, key: UserProfileEarKey) -> Self {
+        let nestable_part = NestablePart {
+            disposition: Disposition::Profile,
+            languages: Vec::new(),
+            part_index: 0,
+            part_semantic: PartSemantics::SinglePart,
+            part: Part::Single(SinglePart::UserProfileKey(key)),
+        };
+        MimiContentBuilder::new(sender_domain, nestable_part).build()
+    }
+
+    /// Copy of the given content with a new id, attributed to the sender and
+    /// creation time of the original message. If the content was itself
+    /// forwarded, the attribution of the original message is kept.
+    ///
+    /// Attachments are stored by the DS of a single group, so they are not
+    /// copied. They have to be uploaded again to the target conversation.
+    pub(crate) fn forwarded(
+        sender_domain: Fqdn,
+        content: &MimiContent,
+        sender: QualifiedUserName,
+    ) -> Self {
+        let forwarded_from = content
+            .forwarded_from()
+            .cloned()
+            .unwrap_or_else(|| ForwardedFrom {
+                sender,
+                timestamp: content.timestamp,
+            });
+        let attribution = NestablePart {
+            disposition: Disposition::Unspecified,
+            languages: Vec::new(),
+            part_index: 0,
+            part_semantic: PartSemantics::SinglePart,
+            part: Part::Single(SinglePart::ForwardedFrom(forwarded_from)),
+        };
+        let mut forwarded_part = without_attachments(content.content_part().clone());
+        forwarded_part.part_index = 1;
+        let nestable_part = NestablePart {
+            disposition: forwarded_part.disposition.clone(),
+            languages: Vec::new(),
+            part_index: 0,
+            part_semantic: PartSemantics::ProcessAll,
+            part: Part::Multi(MultiParts {
+                pars: vec![attribution, forwarded_part],
+            }),
+        };
+        MimiContentBuilder::new(sender_domain, nestable_part).build()
+    }
+
+    /// Returns the attribution if this is a forwarded message.
+    pub fn forwarded_from(&self) -> Option<&ForwardedFrom> {
+        self.forwarded_parts()
+            .map(|(forwarded_from, _)| forwarded_from)
+    }
+
+    fn forwarded_parts(&self) -> Option<(&ForwardedFrom, &NestablePart)> {
+        let Part::Multi(multi_parts) = &self.body.part else {
+            return None;
+        };
+        match multi_parts.pars.as_slice() {
+            [NestablePart {
+                part: Part::Single(SinglePart::ForwardedFrom(forwarded_from)),
+                ..
+            }, forwarded_part] => Some((forwarded_from, forwarded_part)),
+            _ => None,
+        }
+    }
+
+    /// The part that holds the actual content, i.e. without the attribution
+    /// of forwarded messages.
+    fn content_part(&self) -> &NestablePart {
+        self.forwarded_parts()
+            .map(|(_, forwarded_part)| forwarded_part)
+            .unwrap_or(&self.body)
+    }
+
+    /// Receipt without a body that signals that the given messages were seen.
+    #[cfg(feature = "attachments")]
+    pub(crate) fn seen_receipt(sender_domain: Fqdn, seen: Vec<MessageId>) -> Self {
+        MimiContentBuilder::new(sender_domain, NestablePart::default())
+            .with_last_seen(seen)
+            .build()
+    }
+
+    /// Returns true if the message is a receipt, i.e. only signals which
+    /// messages were seen.
+    pub(crate) fn is_receipt(&self) -> bool {
+        matches!(self.body.part, Part::Null) && !self.last_seen.is_empty()
+    }
+
+    /// Returns the URL of the attachment if this is a view-once attachment
+    /// message.
+    pub fn view_once_attachment_url(&self) -> Option<QualifiedAttachmentUrl> {
+        match &self.content_part().part {
+            Part::Single(SinglePart::ViewOnceAttachment(attachment_id)) => {
+                Some(QualifiedAttachmentUrl::legacy(*attachment_id))
+            }
+            Part::Single(SinglePart::ViewOnceAttachmentUrl(attachment_url)) => {
+                Some(attachment_url.clone())
+            }
+            Part::Single(SinglePart::EncryptedViewOnceAttachment(attachment)) => {
+                Some(attachment.url.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the key and the ciphertext hash of the attachment if this is
+    /// a message with an encrypted view-once attachment. Attachments shared
+    /// by older clients aren't encrypted.
+    #[cfg(feature = "attachments")]
+    pub(crate) fn encrypted_view_once_attachment(&self) -> Option<&EncryptedAttachment> {
+        match &self.content_part().part {
+            Part::Single(SinglePart::EncryptedViewOnceAttachment(attachment)) => Some(attachment),
+            _ => None,
+        }
+    }
+
+    /// Returns the key of the sender's profile if this message shares it.
+    pub(crate) fn shared_user_profile_key(&self) -> Option<&UserProfileEarKey> {
+        match &self.body.part {
+            Part::Single(SinglePart::UserProfileKey(key)) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Returns true if this is a view-once attachment message.
+    pub fn is_view_once_attachment(&self) -> bool {
+        matches!(
+            &self.content_part().part,
+            Part::Single(
+                SinglePart::ViewOnceAttachment(_)
+                    | SinglePart::ViewOnceAttachmentUrl(_)
+                    | SinglePart::EncryptedViewOnceAttachment(_)
+            )
+        )
+    }
+
     pub fn string_rendering(&self) -> String {
         // For now, we only support SingleParts that contain markdown messages.
-        match &self.body.part {
+        match &self.content_part().part {
             Part::Single(single_part) => match single_part {
                 SinglePart::TextMarkdown(text) => text.clone(),
+                SinglePart::ViewOnceAttachment(_)
+                | SinglePart::ViewOnceAttachmentUrl(_)
+                | SinglePart::EncryptedViewOnceAttachment(_) => "View-once media".to_string(),
+                SinglePart::EncryptedAttachment(_) => "Attachment".to_string(),
+                SinglePart::ForwardedFrom(_) | SinglePart::UserProfileKey(_) => {
+                    "Unsupported content type".to_string()
+                }
             },
+            // The caption of the attachments comes first.
+            Part::Multi(multi_parts) if !self.encrypted_attachments().is_empty() => {
+                match multi_parts.pars.first().map(|part| &part.part) {
+                    Some(Part::Single(SinglePart::TextMarkdown(caption)))
+                        if !caption.is_empty() =>
+                    {
+                        caption.clone()
+                    }
+                    _ => "Attachment".to_string(),
+                }
+            }
             _ => "Unsupported content type".to_string(),
         }
     }
@@ -272,3 +541,47 @@ impl MimiContent {
         &self.id
     }
 }
+
+/// The given body with the given attachments. The body becomes the caption
+/// of the attachments.
+#[cfg(feature = "attachments")]
+fn attach(body: NestablePart, attachments: Vec<EncryptedAttachment>) -> NestablePart {
+    let mut parts = vec![body];
+    parts.extend(attachments.into_iter().map(|attachment| NestablePart {
+        disposition: Disposition::Attachment,
+        languages: Vec::new(),
+        part_index: 0,
+        part_semantic: PartSemantics::SinglePart,
+        part: Part::Single(SinglePart::EncryptedAttachment(attachment)),
+    }));
+    for (index, part) in parts.iter_mut().enumerate() {
+        part.part_index = index as u16;
+    }
+    NestablePart {
+        disposition: Disposition::Attachment,
+        languages: Vec::new(),
+        part_index: 0,
+        part_semantic: PartSemantics::ProcessAll,
+        part: Part::Multi(MultiParts { pars: parts }),
+    }
+}
+
+/// The given part without its encrypted attachments. If only the caption
+/// remains, it is returned on its own.
+fn without_attachments(part: NestablePart) -> NestablePart {
+    let Part::Multi(multi_parts) = part.part else {
+        return part;
+    };
+    let mut parts: Vec<_> = multi_parts
+        .pars
+        .into_iter()
+        .filter(|part| !matches!(part.part, Part::Single(SinglePart::EncryptedAttachment(_))))
+        .collect();
+    if parts.len() == 1 {
+        return parts.remove(0);
+    }
+    NestablePart {
+        part: Part::Multi(MultiParts { pars: parts }),
+        ..part
+    }
+}
@@ -0,0 +1,286 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Conformance checks of the MIMI content encoding and the room policy
+//! decisions against test vectors.
+//!
+//! A vector file contains content vectors and policy vectors:
+//!
+//! - A content vector is a hex-encoded [`MimiContent`]. Valid vectors must
+//!   decode without leftover bytes, encode back into the exact same bytes and,
+//!   if given, render into the expected text. Invalid vectors must be
+//!   rejected.
+//! - A policy vector is a hex-encoded [`RoomPolicy`], the hex-encoded
+//!   [`UserKeyHash`] of a member, an action and whether the policy allows the
+//!   member to perform the action.
+//!
+//! The vectors are published by other MIMI implementations and converted into
+//! this format; vectors generated with our own encoder would only show that
+//! it round-trips. Check them with the `conformance-report` binary, or with
+//! the tests of this module by pointing `MIMI_TEST_VECTORS` to the vector file
+//! and running the ignored tests.
+
+use std::{fmt, path::Path};
+
+use phnxtypes::{crypto::signatures::keys::UserKeyHash, messages::room_policy::RoomPolicy};
+use serde::Deserialize;
+use thiserror::Error;
+use tls_codec::{DeserializeBytes, Serialize};
+
+use crate::MimiContent;
+
+/// Environment variable with the path of the vector file checked by the
+/// tests
+pub const VECTORS_ENV: &str = "MIMI_TEST_VECTORS";
+
+/// Version of the format of vector files. Must be increased when the
+/// structure of the file changes, not when individual vectors change.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum InteropError {
+    #[error("Can't read vector file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid vector file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported vector file format version {0}")]
+    UnsupportedFormat(u32),
+}
+
+/// Test vectors of the MIMI content encoding and the room policy decisions.
+#[derive(Debug, Deserialize)]
+pub struct InteropVectors {
+    format_version: u32,
+    #[serde(default)]
+    content: Vec<ContentVector>,
+    #[serde(default)]
+    policy: Vec<PolicyVector>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentVector {
+    name: String,
+    /// Hex-encoded TLS serialization
+    encoding: String,
+    valid: bool,
+    #[serde(default)]
+    rendering: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PolicyVector {
+    name: String,
+    /// Hex-encoded TLS serialization of the policy
+    policy: String,
+    /// Hex-encoded TLS serialization of the user key hash of the member
+    user: String,
+    action: PolicyAction,
+    allowed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PolicyAction {
+    Administer,
+    Invite,
+    Send,
+    Commit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorKind {
+    Content,
+    Policy,
+}
+
+impl fmt::Display for VectorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorKind::Content => write!(f, "content"),
+            VectorKind::Policy => write!(f, "policy"),
+        }
+    }
+}
+
+/// Outcome of checking a single vector.
+#[derive(Debug, Clone)]
+pub struct VectorResult {
+    pub kind: VectorKind,
+    pub name: String,
+    /// Description of how our behavior diverges from the vector, if it does
+    pub divergence: Option<String>,
+}
+
+/// Outcome of checking all vectors of a file.
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceReport {
+    pub results: Vec<VectorResult>,
+}
+
+impl ConformanceReport {
+    /// Returns true if our behavior matches all vectors.
+    pub fn is_conformant(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| result.divergence.is_none())
+    }
+
+    pub fn divergences(&self) -> impl Iterator<Item = &VectorResult> {
+        self.results
+            .iter()
+            .filter(|result| result.divergence.is_some())
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            match &result.divergence {
+                None => writeln!(f, "PASS {} {}", result.kind, result.name)?,
+                Some(divergence) => {
+                    writeln!(f, "FAIL {} {}: {divergence}", result.kind, result.name)?
+                }
+            }
+        }
+        let failed = self.divergences().count();
+        write!(
+            f,
+            "{} of {} vectors passed",
+            self.results.len() - failed,
+            self.results.len()
+        )
+    }
+}
+
+impl InteropVectors {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, InteropError> {
+        let file = std::fs::read_to_string(path)?;
+        Self::parse(&file)
+    }
+
+    fn parse(file: &str) -> Result<Self, InteropError> {
+        let vectors: Self = serde_json::from_str(file)?;
+        if vectors.format_version != FORMAT_VERSION {
+            return Err(InteropError::UnsupportedFormat(vectors.format_version));
+        }
+        Ok(vectors)
+    }
+
+    /// Check our behavior against all vectors.
+    pub fn check(&self) -> ConformanceReport {
+        let content = self.content.iter().map(|vector| VectorResult {
+            kind: VectorKind::Content,
+            name: vector.name.clone(),
+            divergence: vector.check().err(),
+        });
+        let policy = self.policy.iter().map(|vector| VectorResult {
+            kind: VectorKind::Policy,
+            name: vector.name.clone(),
+            divergence: vector.check().err(),
+        });
+        ConformanceReport {
+            results: content.chain(policy).collect(),
+        }
+    }
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value).map_err(|e| format!("Invalid hex encoding: {e}"))
+}
+
+impl ContentVector {
+    fn check(&self) -> Result<(), String> {
+        let encoding = decode_hex(&self.encoding)?;
+        let content = match (
+            MimiContent::tls_deserialize_exact_bytes(&encoding),
+            self.valid,
+        ) {
+            (Ok(content), true) => content,
+            (Err(_), false) => return Ok(()),
+            (Ok(_), false) => return Err("Accepted invalid content".to_owned()),
+            (Err(e), true) => return Err(format!("Rejected valid content: {e:?}")),
+        };
+        let reencoding = content
+            .tls_serialize_detached()
+            .map_err(|e| format!("Can't re-encode content: {e:?}"))?;
+        if reencoding != encoding {
+            return Err(format!("Re-encoding differs: {}", hex::encode(reencoding)));
+        }
+        if let Some(rendering) = &self.rendering {
+            let actual = content.string_rendering();
+            if actual != *rendering {
+                return Err(format!("Rendered as {actual:?}, expected {rendering:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PolicyVector {
+    fn check(&self) -> Result<(), String> {
+        let policy = RoomPolicy::tls_deserialize_exact_bytes(&decode_hex(&self.policy)?)
+            .map_err(|e| format!("Can't decode policy: {e:?}"))?;
+        let user = UserKeyHash::tls_deserialize_exact_bytes(&decode_hex(&self.user)?)
+            .map_err(|e| format!("Can't decode user: {e:?}"))?;
+        let allowed = match self.action {
+            PolicyAction::Administer => policy.is_admin(&user),
+            PolicyAction::Invite => policy.can_invite(&user),
+            PolicyAction::Send => policy.can_send(&user),
+            PolicyAction::Commit => policy.can_commit(&user),
+        };
+        if allowed != self.allowed {
+            return Err(format!(
+                "{:?} is {}allowed, expected the opposite",
+                self.action,
+                if allowed { "" } else { "not " }
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs the published MIMI vectors, see MIMI_TEST_VECTORS"]
+    fn published_vectors_conform() {
+        let path = std::env::var(VECTORS_ENV).expect("MIMI_TEST_VECTORS is not set");
+        let report = InteropVectors::load(path).unwrap().check();
+        assert!(!report.results.is_empty());
+        assert!(report.is_conformant(), "{report}");
+    }
+
+    /// Tests the checks themselves, not our conformance.
+    #[test]
+    fn divergences_are_reported() {
+        let vectors = r#"{
+            "format_version": 1,
+            "content": [
+                { "name": "not_hex", "encoding": "zz", "valid": true },
+                { "name": "empty", "encoding": "", "valid": true }
+            ],
+            "policy": [
+                {
+                    "name": "without_admins",
+                    "policy": "0000000000",
+                    "user": "200202020202020202020202020202020202020202020202020202020202020202",
+                    "action": "administer",
+                    "allowed": false
+                }
+            ]
+        }"#;
+        let report = InteropVectors::parse(vectors).unwrap().check();
+        assert_eq!(report.results.len(), 3);
+        assert_eq!(report.divergences().count(), 3);
+        assert!(!report.is_conformant());
+
+        let unsupported = r#"{ "format_version": 2 }"#;
+        assert!(matches!(
+            InteropVectors::parse(unsupported),
+            Err(InteropError::UnsupportedFormat(2))
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Presence of the user and its contacts.
+//!
+//! The user publishes a coarse status to its QS, which reveals it to the
+//! user's contacts according to the [`PresenceVisibility`] stored in the
+//! [`PresenceSettings`]. Presence is only exchanged between users of the same
+//! server.
+//!
+//! The presence of contacts, whether fetched or pushed by the QS, is cached
+//! in memory for [`PRESENCE_CACHE_TTL`]. After that, it is unknown until it
+//! is fetched again.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use phnxtypes::{
+    identifiers::QualifiedUserName,
+    messages::presence::{Presence, PresenceStatus, PresenceVisibility},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{user_settings::UserSetting, utils::rt::Instant};
+
+/// Time after which a cached presence of a contact is considered unknown.
+/// Shorter than the TTL of a published presence, such that an online status
+/// is never shown longer than the QS would.
+pub const PRESENCE_CACHE_TTL: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceSettings {
+    pub visibility: PresenceVisibility,
+}
+
+impl UserSetting for PresenceSettings {
+    const KEY: &'static str = "presence";
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct PresenceCache {
+    inner: Arc<Mutex<PresenceCacheInner>>,
+}
+
+#[derive(Default)]
+struct PresenceCacheInner {
+    contacts: HashMap<QualifiedUserName, CachedPresence>,
+    /// Last status published by the user
+    own_status: PresenceStatus,
+}
+
+struct CachedPresence {
+    presence: Presence,
+    cached_at: Instant,
+}
+
+impl PresenceCache {
+    /// The cached presence of the given contact, if it didn't expire.
+    pub(crate) fn get(&self, user_name: &QualifiedUserName) -> Option<Presence> {
+        self.get_at(user_name, Instant::now())
+    }
+
+    fn get_at(&self, user_name: &QualifiedUserName, now: Instant) -> Option<Presence> {
+        let mut inner = self.lock();
+        let cached = inner.contacts.get(user_name)?;
+        if now.saturating_duration_since(cached.cached_at) < PRESENCE_CACHE_TTL {
+            Some(cached.presence)
+        } else {
+            inner.contacts.remove(user_name);
+            None
+        }
+    }
+
+    /// Cache the presence of the given contact. Returns whether it differs
+    /// from the previously cached presence.
+    pub(crate) fn insert(&self, user_name: QualifiedUserName, presence: Presence) -> bool {
+        self.insert_at(user_name, presence, Instant::now())
+    }
+
+    fn insert_at(&self, user_name: QualifiedUserName, presence: Presence, now: Instant) -> bool {
+        let cached = CachedPresence {
+            presence,
+            cached_at: now,
+        };
+        let previous = self.lock().contacts.insert(user_name, cached);
+        previous.is_none_or(|previous| previous.presence != presence)
+    }
+
+    pub(crate) fn own_status(&self) -> PresenceStatus {
+        self.lock().own_status
+    }
+
+    pub(crate) fn set_own_status(&self, status: PresenceStatus) {
+        self.lock().own_status = status;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PresenceCacheInner> {
+        // The cache only holds plain values, so it's consistent even if a
+        // thread panicked while holding the lock.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use super::*;
+
+    #[test]
+    fn cached_presence_expires() {
+        let cache = PresenceCache::default();
+        let user_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let online = Presence {
+            status: PresenceStatus::Online,
+            last_seen: None,
+        };
+        let now = Instant::now();
+
+        assert!(cache.insert_at(user_name.clone(), online, now));
+        assert!(!cache.insert_at(user_name.clone(), online, now));
+        assert_eq!(cache.get_at(&user_name, now), Some(online));
+        assert_eq!(cache.get_at(&user_name, now + PRESENCE_CACHE_TTL), None);
+        // Expired entries are removed
+        assert!(cache.insert_at(user_name, online, now));
+    }
+}
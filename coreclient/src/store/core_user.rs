@@ -0,0 +1,111 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use chrono::{DateTime, Utc};
+use phnxtypes::identifiers::QualifiedUserName;
+
+#[cfg(feature = "attachments")]
+use crate::AttachmentHash;
+use crate::{
+    clients::CoreUser, Contact, Conversation, ConversationId, ConversationMessage,
+    ConversationMessageId, Counters, MimiContent,
+};
+
+use super::{Store, StoreNotifications, StoreResult};
+
+impl Store for CoreUser {
+    async fn conversations(&self) -> StoreResult<Vec<Conversation>> {
+        Ok(CoreUser::conversations(self).await?)
+    }
+
+    async fn conversation(
+        &self,
+        conversation_id: ConversationId,
+    ) -> StoreResult<Option<Conversation>> {
+        Ok(CoreUser::conversation(self, &conversation_id).await)
+    }
+
+    async fn mark_as_read(
+        &self,
+        conversation_id: ConversationId,
+        until: DateTime<Utc>,
+    ) -> StoreResult<()> {
+        Ok(CoreUser::mark_as_read(self, [(conversation_id, until)]).await?)
+    }
+
+    async fn unread_messages_count(&self, conversation_id: ConversationId) -> StoreResult<u32> {
+        Ok(CoreUser::unread_messages_count(self, conversation_id).await)
+    }
+
+    async fn messages(
+        &self,
+        conversation_id: ConversationId,
+        limit: usize,
+    ) -> StoreResult<Vec<ConversationMessage>> {
+        self.get_messages(conversation_id, limit).await
+    }
+
+    async fn message(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> StoreResult<Option<ConversationMessage>> {
+        Ok(CoreUser::message(self, message_id).await)
+    }
+
+    async fn send_message(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> StoreResult<ConversationMessage> {
+        CoreUser::send_message(self, conversation_id, content).await
+    }
+
+    async fn contacts(&self) -> StoreResult<Vec<Contact>> {
+        Ok(CoreUser::contacts(self).await?)
+    }
+
+    async fn contact(&self, user_name: &QualifiedUserName) -> StoreResult<Option<Contact>> {
+        Ok(CoreUser::contact(self, user_name).await)
+    }
+
+    fn subscribe(&self) -> StoreNotifications {
+        self.store_notifier().subscribe()
+    }
+
+    async fn load_counters(&self) -> StoreResult<Counters> {
+        Ok(CoreUser::load_counters(self).await?)
+    }
+}
+
+#[cfg(feature = "experimental-store")]
+impl super::ExperimentalStore for CoreUser {
+    async fn global_unread_messages_count(&self) -> StoreResult<u32> {
+        Ok(CoreUser::global_unread_messages_count(self).await?)
+    }
+}
+
+#[cfg(feature = "attachments")]
+impl super::AttachmentStore for CoreUser {
+    async fn store_attachment(
+        &self,
+        message_id: ConversationMessageId,
+        content: Vec<u8>,
+    ) -> StoreResult<AttachmentHash> {
+        CoreUser::store_attachment(self, message_id, content).await
+    }
+
+    async fn attachment(&self, hash: &AttachmentHash) -> StoreResult<Option<Vec<u8>>> {
+        CoreUser::attachment(self, hash).await
+    }
+}
+
+#[cfg(all(feature = "experimental-store", feature = "attachments"))]
+impl super::ExperimentalAttachmentStore for CoreUser {
+    async fn message_attachments(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> StoreResult<Vec<crate::MessageAttachment>> {
+        CoreUser::message_attachments(self, message_id).await
+    }
+}
@@ -0,0 +1,470 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! In-memory [`Store`] for tests of embedders.
+//!
+//! Sent messages are only stored locally and never leave the store. Incoming
+//! messages and contacts are simulated with
+//! [`InMemoryStore::receive_message`] and [`InMemoryStore::add_contact`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use openmls::prelude::GroupId;
+use phnxtypes::{
+    crypto::ear::keys::{
+        AddPackageEarKey, ClientCredentialEarKey, SignatureEarKeyWrapperKey,
+        WelcomeAttributionInfoEarKey,
+    },
+    identifiers::{QualifiedGroupId, QualifiedUserName},
+    messages::FriendshipToken,
+    time::TimeStamp,
+};
+use uuid::Uuid;
+
+#[cfg(feature = "attachments")]
+use crate::AttachmentHash;
+use crate::{
+    conversations::messages::TimestampedMessage, ChatCounters, Contact, Conversation,
+    ConversationAttributes, ConversationId, ConversationMessage, ConversationMessageId, Counters,
+    Message, MimiContent, NotificationType,
+};
+
+use super::{Store, StoreNotifications, StoreNotifier, StoreResult};
+
+#[derive(Clone)]
+pub struct InMemoryStore {
+    user_name: QualifiedUserName,
+    inner: Arc<Mutex<InMemoryStoreInner>>,
+    notifier: StoreNotifier,
+}
+
+#[derive(Default)]
+struct InMemoryStoreInner {
+    conversations: Vec<Conversation>,
+    last_read: HashMap<ConversationId, DateTime<Utc>>,
+    messages: Vec<ConversationMessage>,
+    contacts: Vec<Contact>,
+    #[cfg(feature = "attachments")]
+    attachments: HashMap<AttachmentHash, (ConversationMessageId, Vec<u8>)>,
+}
+
+impl InMemoryStore {
+    /// An empty store of the given user.
+    pub fn new(user_name: QualifiedUserName) -> Self {
+        Self {
+            user_name,
+            inner: Default::default(),
+            notifier: Default::default(),
+        }
+    }
+
+    /// Add a group conversation with the given title.
+    pub fn add_group_conversation(&self, title: &str) -> ConversationId {
+        let conversation = Conversation::new_group_conversation(
+            self.new_group_id(),
+            ConversationAttributes::new(title.to_owned(), None),
+        );
+        self.insert_conversation(conversation)
+    }
+
+    /// Add a contact together with its connection conversation.
+    pub fn add_contact(&self, user_name: QualifiedUserName) -> Result<ConversationId> {
+        let conversation = Conversation::new_connection_conversation(
+            self.new_group_id(),
+            user_name.clone(),
+            ConversationAttributes::new(user_name.to_string(), None),
+        )?;
+        let contact = Contact {
+            user_name,
+            clients: Vec::new(),
+            wai_ear_key: WelcomeAttributionInfoEarKey::random()?,
+            friendship_token: FriendshipToken::random()?,
+            add_package_ear_key: AddPackageEarKey::random()?,
+            client_credential_ear_key: ClientCredentialEarKey::random()?,
+            signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey::random()?,
+            conversation_id: conversation.id(),
+            user_profile_ear_key: None,
+            verified_at: None,
+        };
+        self.lock().contacts.push(contact);
+        Ok(self.insert_conversation(conversation))
+    }
+
+    /// Simulate a message sent by another user.
+    pub fn receive_message(
+        &self,
+        conversation_id: ConversationId,
+        sender: QualifiedUserName,
+        content: MimiContent,
+    ) -> Result<ConversationMessage> {
+        self.ensure_conversation(conversation_id)?;
+        let message = ConversationMessage::from_timestamped_message(
+            conversation_id,
+            TimestampedMessage::from_content(content, TimeStamp::now(), sender),
+        );
+        self.insert_message(message.clone());
+        Ok(message)
+    }
+
+    fn new_group_id(&self) -> GroupId {
+        GroupId::from(QualifiedGroupId::new(
+            Uuid::new_v4(),
+            self.user_name.domain(),
+        ))
+    }
+
+    fn insert_conversation(&self, conversation: Conversation) -> ConversationId {
+        let conversation_id = conversation.id();
+        self.lock().conversations.push(conversation);
+        self.notifier
+            .notify(NotificationType::ConversationChange(conversation_id));
+        conversation_id
+    }
+
+    fn insert_message(&self, message: ConversationMessage) {
+        self.lock().messages.push(message.clone());
+        self.notifier.notify(NotificationType::Message(message));
+    }
+
+    fn ensure_conversation(&self, conversation_id: ConversationId) -> Result<()> {
+        self.lock()
+            .conversations
+            .iter()
+            .any(|conversation| conversation.id() == conversation_id)
+            .then_some(())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Can't find conversation with id {}",
+                    conversation_id.as_uuid()
+                )
+            })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, InMemoryStoreInner> {
+        // The store only holds plain values, so it's consistent even if a
+        // thread panicked while holding the lock.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Store for InMemoryStore {
+    async fn conversations(&self) -> StoreResult<Vec<Conversation>> {
+        Ok(self.lock().conversations.clone())
+    }
+
+    async fn conversation(
+        &self,
+        conversation_id: ConversationId,
+    ) -> StoreResult<Option<Conversation>> {
+        let inner = self.lock();
+        let conversation = inner
+            .conversations
+            .iter()
+            .find(|conversation| conversation.id() == conversation_id);
+        Ok(conversation.cloned())
+    }
+
+    async fn mark_as_read(
+        &self,
+        conversation_id: ConversationId,
+        until: DateTime<Utc>,
+    ) -> StoreResult<()> {
+        self.ensure_conversation(conversation_id)?;
+        let mut inner = self.lock();
+        let last_read = inner.last_read.entry(conversation_id).or_insert(until);
+        *last_read = until.max(*last_read);
+        drop(inner);
+        self.notifier
+            .notify(NotificationType::ConversationChange(conversation_id));
+        Ok(())
+    }
+
+    async fn unread_messages_count(&self, conversation_id: ConversationId) -> StoreResult<u32> {
+        let inner = self.lock();
+        let last_read = inner.last_read.get(&conversation_id).copied();
+        let count = inner
+            .messages
+            .iter()
+            .filter(|message| message.conversation_id() == conversation_id)
+            .filter(|message| matches!(message.message(), Message::Content(_)))
+            .filter(|message| last_read.is_none_or(|last_read| message.timestamp() > last_read))
+            .count();
+        Ok(count.try_into()?)
+    }
+
+    async fn messages(
+        &self,
+        conversation_id: ConversationId,
+        limit: usize,
+    ) -> StoreResult<Vec<ConversationMessage>> {
+        let inner = self.lock();
+        let messages: Vec<_> = inner
+            .messages
+            .iter()
+            .filter(|message| message.conversation_id() == conversation_id)
+            .collect();
+        let skip = messages.len().saturating_sub(limit);
+        Ok(messages.into_iter().skip(skip).cloned().collect())
+    }
+
+    async fn message(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> StoreResult<Option<ConversationMessage>> {
+        let inner = self.lock();
+        let message = inner
+            .messages
+            .iter()
+            .find(|message| message.id() == message_id);
+        Ok(message.cloned())
+    }
+
+    async fn send_message(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> StoreResult<ConversationMessage> {
+        self.ensure_conversation(conversation_id)?;
+        let message = ConversationMessage::new_unsent_message(
+            self.user_name.to_string(),
+            conversation_id,
+            content,
+        );
+        self.insert_message(message.clone());
+        Ok(message)
+    }
+
+    async fn contacts(&self) -> StoreResult<Vec<Contact>> {
+        Ok(self.lock().contacts.clone())
+    }
+
+    async fn contact(&self, user_name: &QualifiedUserName) -> StoreResult<Option<Contact>> {
+        let inner = self.lock();
+        let contact = inner
+            .contacts
+            .iter()
+            .find(|contact| &contact.user_name == user_name);
+        Ok(contact.cloned())
+    }
+
+    fn subscribe(&self) -> StoreNotifications {
+        self.notifier.subscribe()
+    }
+
+    /// Sent messages never leave the store, so they are counted as pending.
+    /// Mentions and failures are not simulated.
+    async fn load_counters(&self) -> StoreResult<Counters> {
+        let mut counters = Vec::new();
+        for conversation in self.conversations().await? {
+            let conversation_id = conversation.id();
+            let unread = self.unread_messages_count(conversation_id).await?;
+            let pending = self
+                .lock()
+                .messages
+                .iter()
+                .filter(|message| message.conversation_id() == conversation_id)
+                .filter(|message| !message.was_sent())
+                .count();
+            let chat_counters = ChatCounters {
+                unread,
+                pending: pending.try_into()?,
+                ..Default::default()
+            };
+            counters.push((conversation_id, chat_counters));
+        }
+        Ok(counters.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "experimental-store")]
+impl super::ExperimentalStore for InMemoryStore {
+    async fn global_unread_messages_count(&self) -> StoreResult<u32> {
+        let mut count = 0;
+        for conversation in self.conversations().await? {
+            count += self.unread_messages_count(conversation.id()).await?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(feature = "attachments")]
+impl super::AttachmentStore for InMemoryStore {
+    async fn store_attachment(
+        &self,
+        message_id: ConversationMessageId,
+        content: Vec<u8>,
+    ) -> StoreResult<AttachmentHash> {
+        let hash = AttachmentHash::of(&content)?;
+        let mut inner = self.lock();
+        if !inner
+            .messages
+            .iter()
+            .any(|message| message.id() == message_id)
+        {
+            return Err(anyhow!(
+                "Can't find message with id {}",
+                message_id.to_uuid()
+            ));
+        }
+        inner
+            .attachments
+            .insert(hash.clone(), (message_id, content));
+        Ok(hash)
+    }
+
+    async fn attachment(&self, hash: &AttachmentHash) -> StoreResult<Option<Vec<u8>>> {
+        let inner = self.lock();
+        Ok(inner
+            .attachments
+            .get(hash)
+            .map(|(_, content)| content.clone()))
+    }
+}
+
+#[cfg(all(feature = "experimental-store", feature = "attachments"))]
+impl super::ExperimentalAttachmentStore for InMemoryStore {
+    async fn message_attachments(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> StoreResult<Vec<crate::MessageAttachment>> {
+        let inner = self.lock();
+        let attachments = inner
+            .attachments
+            .iter()
+            .filter(|(_, (id, _))| *id == message_id)
+            .map(|(hash, _)| crate::MessageAttachment {
+                hash: hash.clone(),
+                media_deleted_at: None,
+                thumbnail: false,
+            })
+            .collect();
+        Ok(attachments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn in_memory_store() {
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let store = InMemoryStore::new(alice);
+        let mut notifications = store.subscribe();
+
+        let conversation_id = store.add_contact(bob.clone()).unwrap();
+        assert!(matches!(
+            notifications.recv().await,
+            Some(NotificationType::ConversationChange(id)) if id == conversation_id
+        ));
+        assert!(store.contact(&bob).await.unwrap().is_some());
+
+        let domain = bob.domain();
+        store
+            .receive_message(
+                conversation_id,
+                bob,
+                MimiContent::simple_markdown_message(domain.clone(), "Hi".to_owned()),
+            )
+            .unwrap();
+        let sent = store
+            .send_message(
+                conversation_id,
+                MimiContent::simple_markdown_message(domain, "Hello".to_owned()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store.unread_messages_count(conversation_id).await.unwrap(),
+            2
+        );
+
+        let messages = store.messages(conversation_id, 1).await.unwrap();
+        assert_eq!(messages, vec![sent.clone()]);
+
+        store
+            .mark_as_read(conversation_id, sent.timestamp())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.unread_messages_count(conversation_id).await.unwrap(),
+            0
+        );
+    }
+
+    #[cfg(feature = "attachments")]
+    #[actix_rt::test]
+    async fn in_memory_attachment_store() {
+        use crate::store::AttachmentStore;
+
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let store = InMemoryStore::new(alice);
+        let conversation_id = store.add_contact(bob.clone()).unwrap();
+        let sent = store
+            .send_message(
+                conversation_id,
+                MimiContent::simple_markdown_message(bob.domain(), "Hello".to_owned()),
+            )
+            .await
+            .unwrap();
+
+        let hash = store
+            .store_attachment(sent.id(), b"content".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.attachment(&hash).await.unwrap(),
+            Some(b"content".to_vec())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn counters_stream() {
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let store = InMemoryStore::new(alice);
+        let conversation_id = store.add_contact(bob.clone()).unwrap();
+        let mut counters = store.counters();
+        assert_eq!(counters.next().await.unwrap(), Counters::default());
+
+        let domain = bob.domain();
+        store
+            .receive_message(
+                conversation_id,
+                bob,
+                MimiContent::simple_markdown_message(domain.clone(), "Hi".to_owned()),
+            )
+            .unwrap();
+        store
+            .send_message(
+                conversation_id,
+                MimiContent::simple_markdown_message(domain, "Hello".to_owned()),
+            )
+            .await
+            .unwrap();
+        let expected = ChatCounters {
+            unread: 2,
+            pending: 1,
+            ..Default::default()
+        };
+        let latest = loop {
+            let latest = counters.next().await.unwrap();
+            if latest.chat(conversation_id) == expected {
+                break latest;
+            }
+        };
+        assert_eq!(latest.total(), expected);
+    }
+}
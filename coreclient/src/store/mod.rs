@@ -0,0 +1,276 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Public API for embedding the client logic.
+//!
+//! The [`Store`] trait gives access to the chats, messages and contacts of a
+//! user and notifies about changes to them. Attachments are accessed via
+//! `AttachmentStore`, which is only available with the `attachments`
+//! feature. Both are implemented by [`CoreUser`](crate::clients::CoreUser),
+//! and by [`InMemoryStore`](mock::InMemoryStore) with the `mock-store`
+//! feature, such that UIs and their tests can be written against the traits.
+//!
+//! # Stability
+//!
+//! The [`Store`] trait is versioned with [`STORE_API_VERSION`]. Within a
+//! version, methods are neither removed nor changed in incompatible ways;
+//! breaking changes increase the version and are released as a new major
+//! version of this crate. New methods may be added with a default
+//! implementation, and new variants may be added to the non-exhaustive
+//! [`NotificationType`].
+//!
+//! Methods of `ExperimentalStore` and `ExperimentalAttachmentStore` are only
+//! available with the `experimental-store` feature and may change in any
+//! release.
+
+use std::future::Future;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use phnxtypes::identifiers::QualifiedUserName;
+use tokio::sync::broadcast;
+
+#[cfg(feature = "attachments")]
+use crate::AttachmentHash;
+use crate::{
+    ChatCounters, Contact, Conversation, ConversationId, ConversationMessage,
+    ConversationMessageId, Counters, MimiContent, NotificationType,
+};
+
+mod core_user;
+#[cfg(any(test, feature = "mock-store"))]
+pub mod mock;
+
+/// Version of the [`Store`] API.
+pub const STORE_API_VERSION: u32 = 2;
+
+/// Number of notifications buffered per subscriber. Subscribers that lag
+/// behind further miss notifications, see [`StoreNotifications::recv`].
+const NOTIFICATION_BUFFER_SIZE: usize = 256;
+
+pub type StoreResult<T> = Result<T>;
+
+/// Chats, messages and contacts of a user.
+pub trait Store: Send + Sync {
+    // Chats
+
+    fn conversations(&self) -> impl Future<Output = StoreResult<Vec<Conversation>>> + Send;
+
+    fn conversation(
+        &self,
+        conversation_id: ConversationId,
+    ) -> impl Future<Output = StoreResult<Option<Conversation>>> + Send;
+
+    /// Mark all messages of the conversation up to the given time as read.
+    fn mark_as_read(
+        &self,
+        conversation_id: ConversationId,
+        until: DateTime<Utc>,
+    ) -> impl Future<Output = StoreResult<()>> + Send;
+
+    fn unread_messages_count(
+        &self,
+        conversation_id: ConversationId,
+    ) -> impl Future<Output = StoreResult<u32>> + Send;
+
+    // Messages
+
+    /// The last `limit` messages of the conversation, oldest first.
+    fn messages(
+        &self,
+        conversation_id: ConversationId,
+        limit: usize,
+    ) -> impl Future<Output = StoreResult<Vec<ConversationMessage>>> + Send;
+
+    fn message(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> impl Future<Output = StoreResult<Option<ConversationMessage>>> + Send;
+
+    fn send_message(
+        &self,
+        conversation_id: ConversationId,
+        content: MimiContent,
+    ) -> impl Future<Output = StoreResult<ConversationMessage>> + Send;
+
+    // Contacts
+
+    fn contacts(&self) -> impl Future<Output = StoreResult<Vec<Contact>>> + Send;
+
+    fn contact(
+        &self,
+        user_name: &QualifiedUserName,
+    ) -> impl Future<Output = StoreResult<Option<Contact>>> + Send;
+
+    // Notifications
+
+    /// Subscribe to changes of the store. Only changes after subscribing are
+    /// notified.
+    fn subscribe(&self) -> StoreNotifications;
+
+    // Counters
+
+    /// The unread, mention and send counters of all chats.
+    ///
+    /// The default implementation only counts unread messages, with one query
+    /// per chat.
+    fn load_counters(&self) -> impl Future<Output = StoreResult<Counters>> + Send {
+        async {
+            let mut counters = Vec::new();
+            for conversation in self.conversations().await? {
+                let unread = self.unread_messages_count(conversation.id()).await?;
+                let chat_counters = ChatCounters {
+                    unread,
+                    ..Default::default()
+                };
+                counters.push((conversation.id(), chat_counters));
+            }
+            Ok(counters.into_iter().collect())
+        }
+    }
+
+    /// Observe the counters of all chats, see [`CountersStream`].
+    fn counters(&self) -> CountersStream<Self>
+    where
+        Self: Clone + Sized,
+    {
+        CountersStream {
+            store: self.clone(),
+            notifications: self.subscribe(),
+            loaded: false,
+            last: None,
+        }
+    }
+}
+
+/// Attachments of the messages of a user.
+#[cfg(feature = "attachments")]
+pub trait AttachmentStore: Store {
+    /// Store the content of an attachment of the given message.
+    fn store_attachment(
+        &self,
+        message_id: ConversationMessageId,
+        content: Vec<u8>,
+    ) -> impl Future<Output = StoreResult<AttachmentHash>> + Send;
+
+    fn attachment(
+        &self,
+        hash: &AttachmentHash,
+    ) -> impl Future<Output = StoreResult<Option<Vec<u8>>>> + Send;
+}
+
+/// Methods that are not covered by the stability guarantees of [`Store`].
+#[cfg(feature = "experimental-store")]
+pub trait ExperimentalStore: Store {
+    fn global_unread_messages_count(&self) -> impl Future<Output = StoreResult<u32>> + Send;
+}
+
+/// Methods that are not covered by the stability guarantees of
+/// `AttachmentStore`.
+#[cfg(all(feature = "experimental-store", feature = "attachments"))]
+pub trait ExperimentalAttachmentStore: AttachmentStore {
+    /// All attachments of the message, including the ones whose media was
+    /// deleted.
+    fn message_attachments(
+        &self,
+        message_id: ConversationMessageId,
+    ) -> impl Future<Output = StoreResult<Vec<crate::MessageAttachment>>> + Send;
+}
+
+/// Sends notifications to all [`StoreNotifications`] subscribers.
+#[derive(Debug, Clone)]
+pub(crate) struct StoreNotifier {
+    tx: broadcast::Sender<NotificationType>,
+}
+
+impl Default for StoreNotifier {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(NOTIFICATION_BUFFER_SIZE);
+        Self { tx }
+    }
+}
+
+impl StoreNotifier {
+    pub(crate) fn notify(&self, notification: NotificationType) {
+        // Fails if there are no subscribers, which is fine.
+        let _ = self.tx.send(notification);
+    }
+
+    pub(crate) fn subscribe(&self) -> StoreNotifications {
+        StoreNotifications {
+            rx: self.tx.subscribe(),
+        }
+    }
+}
+
+/// Stream of changes of a [`Store`].
+#[derive(Debug)]
+pub struct StoreNotifications {
+    rx: broadcast::Receiver<NotificationType>,
+}
+
+impl StoreNotifications {
+    /// The next notification. Returns `None` if the store was dropped.
+    ///
+    /// Notifications that were missed because the subscriber lagged behind
+    /// are skipped.
+    pub async fn recv(&mut self) -> Option<NotificationType> {
+        loop {
+            match self.rx.recv().await {
+                Ok(notification) => return Some(notification),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Store subscriber missed {missed} notifications");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Skip the notifications that were already received.
+    fn skip_received(&mut self) {
+        loop {
+            match self.rx.try_recv() {
+                Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Stream of the [`Counters`] of a [`Store`].
+///
+/// The counters are loaded once when the stream starts and again after
+/// changes of the store. Only counters that differ from the previous ones
+/// are yielded.
+#[derive(Debug)]
+pub struct CountersStream<S> {
+    store: S,
+    notifications: StoreNotifications,
+    loaded: bool,
+    last: Option<Counters>,
+}
+
+impl<S: Store> CountersStream<S> {
+    /// The current counters on the first call, afterwards the next counters
+    /// that differ from the previous ones. Returns `None` if the store stopped
+    /// sending notifications.
+    pub async fn next(&mut self) -> Option<Counters> {
+        loop {
+            if self.loaded {
+                self.notifications.recv().await?;
+                // All changes received so far are covered by a single load.
+                self.notifications.skip_received();
+            }
+            self.loaded = true;
+            match self.store.load_counters().await {
+                Ok(counters) if self.last.as_ref() != Some(&counters) => {
+                    self.last = Some(counters.clone());
+                    return Some(counters);
+                }
+                Ok(_) => {}
+                Err(error) => log::warn!("Failed to load counters: {error}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,617 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Export of the local trust state of a client.
+//!
+//! The trust store lists the AS intermediate credentials the client has
+//! pinned and the fingerprints of the credentials of its contacts' clients,
+//! together with whether the client could verify them against a pinned
+//! intermediate credential. The export is a human-readable JSON document
+//! signed by the exporting client, such that the trust state of a user's
+//! devices can be compared to detect a device that was served different
+//! credentials.
+//!
+//! A client importing the export of another device verifies the credential
+//! of the exporting client against the AS, see
+//! [`CoreUser::compare_trust_store`](crate::clients::CoreUser::compare_trust_store).
+//! The `trust-store-diff` binary compares two exports offline. It can only
+//! check that each export is signed by the client it names.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use phnxtypes::{
+    credentials::{
+        keys::{ClientSigningKey, ClientVerifyingKey},
+        ClientCredential, VerifiableClientCredential,
+    },
+    crypto::signatures::{
+        signable::{SignContent, Signature},
+        traits::{SigningKeyBehaviour, VerifyingKeyBehaviour},
+    },
+    time::TimeStamp,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tls_codec::{DeserializeBytes, Serialize as TlsSerialize};
+
+/// Version of the format of exports. Must be increased when the structure of
+/// the document changes.
+const FORMAT_VERSION: u32 = 1;
+
+const TRUST_STORE_LABEL: &str = "Trust Store Export";
+
+#[derive(Debug, Error)]
+pub enum TrustStoreError {
+    #[error("Invalid trust store export: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported trust store format version {0}")]
+    UnsupportedFormat(u32),
+    #[error("Invalid encoding of the {0}")]
+    InvalidEncoding(&'static str),
+    #[error("The export is not signed by the client it names")]
+    SignerMismatch,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Failed to sign the export")]
+    SigningFailed,
+}
+
+/// The trust state of a client.
+///
+/// All lists are sorted, such that the exports of two devices with the same
+/// trust state only differ in the exporting client and the time of export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustStore {
+    pub format_version: u32,
+    pub user_name: String,
+    /// The client that exported the trust store
+    pub client_id: String,
+    pub exported_at: TimeStamp,
+    pub as_intermediate_credentials: Vec<PinnedAsCredential>,
+    pub contacts: Vec<ContactTrust>,
+}
+
+/// An AS intermediate credential pinned by the client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinnedAsCredential {
+    pub domain: String,
+    pub fingerprint: String,
+    /// Fingerprint of the AS credential that signed the intermediate
+    /// credential
+    pub as_credential_fingerprint: String,
+    pub not_before: TimeStamp,
+    pub not_after: TimeStamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactTrust {
+    pub user_name: String,
+    pub clients: Vec<ClientTrust>,
+}
+
+/// The credential of a contact's client as known to the exporting client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientTrust {
+    pub client_id: String,
+    /// `None` if the credential of the client isn't stored
+    pub fingerprint: Option<String>,
+    /// Fingerprint of the AS intermediate credential that signed the
+    /// credential
+    pub signer_fingerprint: Option<String>,
+    pub status: CredentialStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialStatus {
+    /// Signed by a pinned AS intermediate credential and not expired
+    Verified,
+    /// The credential or the intermediate credential that signed it has
+    /// expired
+    Expired,
+    /// Signed by an intermediate credential that is not pinned (anymore),
+    /// e.g. because the AS no longer publishes it
+    UnknownSigner,
+    /// The credential of the client isn't stored
+    Missing,
+}
+
+impl fmt::Display for CredentialStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialStatus::Verified => write!(f, "verified"),
+            CredentialStatus::Expired => write!(f, "expired"),
+            CredentialStatus::UnknownSigner => write!(f, "unknown signer"),
+            CredentialStatus::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+impl TrustStore {
+    pub(crate) fn new(
+        user_name: String,
+        client_id: String,
+        mut as_intermediate_credentials: Vec<PinnedAsCredential>,
+        mut contacts: Vec<ContactTrust>,
+    ) -> Self {
+        as_intermediate_credentials
+            .sort_by(|a, b| (&a.domain, &a.fingerprint).cmp(&(&b.domain, &b.fingerprint)));
+        contacts.sort_by(|a, b| a.user_name.cmp(&b.user_name));
+        for contact in &mut contacts {
+            contact
+                .clients
+                .sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        }
+        Self {
+            format_version: FORMAT_VERSION,
+            user_name,
+            client_id,
+            exported_at: TimeStamp::now(),
+            as_intermediate_credentials,
+            contacts,
+        }
+    }
+
+    /// Compare this trust store with the one of another device.
+    pub fn diff(&self, other: &TrustStore) -> TrustStoreDiff {
+        let mut differences = Vec::new();
+
+        let ours = self.as_credentials();
+        let theirs = other.as_credentials();
+        for entry @ (domain, fingerprint) in ours.symmetric_difference(&theirs) {
+            let side = if ours.contains(entry) {
+                Side::This
+            } else {
+                Side::Other
+            };
+            differences.push(TrustDifference::AsCredential {
+                domain: domain.to_string(),
+                fingerprint: fingerprint.to_string(),
+                side,
+            });
+        }
+
+        let ours = self.clients();
+        let theirs = other.clients();
+        let keys = ours.keys().chain(theirs.keys()).collect::<BTreeSet<_>>();
+        for key in keys {
+            let (user_name, client_id) = key;
+            let difference = match (ours.get(key), theirs.get(key)) {
+                (Some(_), None) => TrustDifference::ContactClient {
+                    user_name: user_name.to_string(),
+                    client_id: client_id.to_string(),
+                    side: Side::This,
+                },
+                (None, Some(_)) => TrustDifference::ContactClient {
+                    user_name: user_name.to_string(),
+                    client_id: client_id.to_string(),
+                    side: Side::Other,
+                },
+                (Some(this), Some(other)) if this.fingerprint != other.fingerprint => {
+                    TrustDifference::ClientFingerprint {
+                        user_name: user_name.to_string(),
+                        client_id: client_id.to_string(),
+                        this: this.fingerprint.clone(),
+                        other: other.fingerprint.clone(),
+                    }
+                }
+                (Some(this), Some(other)) if this.status != other.status => {
+                    TrustDifference::ClientStatus {
+                        user_name: user_name.to_string(),
+                        client_id: client_id.to_string(),
+                        this: this.status,
+                        other: other.status,
+                    }
+                }
+                _ => continue,
+            };
+            differences.push(difference);
+        }
+
+        TrustStoreDiff { differences }
+    }
+
+    fn as_credentials(&self) -> BTreeSet<(&str, &str)> {
+        self.as_intermediate_credentials
+            .iter()
+            .map(|credential| (credential.domain.as_str(), credential.fingerprint.as_str()))
+            .collect()
+    }
+
+    fn clients(&self) -> BTreeMap<(&str, &str), &ClientTrust> {
+        self.contacts
+            .iter()
+            .flat_map(|contact| {
+                contact.clients.iter().map(|client| {
+                    (
+                        (contact.user_name.as_str(), client.client_id.as_str()),
+                        client,
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Which of the two compared trust stores an entry is only contained in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    This,
+    Other,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::This => write!(f, "only in this export"),
+            Side::Other => write!(f, "only in the other export"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustDifference {
+    /// An AS intermediate credential is only pinned on one device
+    AsCredential {
+        domain: String,
+        fingerprint: String,
+        side: Side,
+    },
+    /// A client of a contact is only known on one device
+    ContactClient {
+        user_name: String,
+        client_id: String,
+        side: Side,
+    },
+    /// The devices know different credentials of the same client
+    ClientFingerprint {
+        user_name: String,
+        client_id: String,
+        this: Option<String>,
+        other: Option<String>,
+    },
+    /// The devices know the same credential, but came to different results
+    /// when verifying it
+    ClientStatus {
+        user_name: String,
+        client_id: String,
+        this: CredentialStatus,
+        other: CredentialStatus,
+    },
+}
+
+impl fmt::Display for TrustDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fingerprint =
+            |fingerprint: &Option<String>| fingerprint.clone().unwrap_or_else(|| "none".to_owned());
+        match self {
+            TrustDifference::AsCredential {
+                domain,
+                fingerprint,
+                side,
+            } => write!(f, "AS credential {fingerprint} of {domain}: {side}"),
+            TrustDifference::ContactClient {
+                user_name,
+                client_id,
+                side,
+            } => write!(f, "Client {client_id} of {user_name}: {side}"),
+            TrustDifference::ClientFingerprint {
+                user_name,
+                client_id,
+                this,
+                other,
+            } => write!(
+                f,
+                "Client {client_id} of {user_name}: credential {} in this export, {} in the other export",
+                fingerprint(this),
+                fingerprint(other)
+            ),
+            TrustDifference::ClientStatus {
+                user_name,
+                client_id,
+                this,
+                other,
+            } => write!(
+                f,
+                "Client {client_id} of {user_name}: {this} in this export, {other} in the other export"
+            ),
+        }
+    }
+}
+
+/// Differences between the trust stores of two devices.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustStoreDiff {
+    pub differences: Vec<TrustDifference>,
+}
+
+impl TrustStoreDiff {
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+impl fmt::Display for TrustStoreDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for difference in &self.differences {
+            writeln!(f, "{difference}")?;
+        }
+        match self.differences.len() {
+            0 => write!(f, "The trust stores match"),
+            n => write!(f, "{n} differences"),
+        }
+    }
+}
+
+/// A [`TrustStore`] signed by the client that exported it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedTrustStore {
+    pub trust_store: TrustStore,
+    /// Hex-encoded TLS serialization of the credential of the exporting
+    /// client
+    signer_credential: String,
+    /// Hex-encoded TLS serialization of the signature over the JSON
+    /// serialization of the trust store
+    signature: String,
+}
+
+impl SignedTrustStore {
+    pub(crate) fn sign(
+        trust_store: TrustStore,
+        signing_key: &ClientSigningKey,
+    ) -> Result<Self, TrustStoreError> {
+        let sign_content = sign_content(&trust_store)?;
+        let signature = signing_key
+            .sign(&sign_content)
+            .map_err(|_| TrustStoreError::SigningFailed)?;
+        let signer_credential = signing_key
+            .credential()
+            .tls_serialize_detached()
+            .map_err(|_| TrustStoreError::SigningFailed)?;
+        let signature = signature
+            .tls_serialize_detached()
+            .map_err(|_| TrustStoreError::SigningFailed)?;
+        Ok(Self {
+            trust_store,
+            signer_credential: hex::encode(signer_credential),
+            signature: hex::encode(signature),
+        })
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, TrustStoreError> {
+        let signed: Self = serde_json::from_str(json)?;
+        if signed.trust_store.format_version != FORMAT_VERSION {
+            return Err(TrustStoreError::UnsupportedFormat(
+                signed.trust_store.format_version,
+            ));
+        }
+        Ok(signed)
+    }
+
+    pub fn to_json(&self) -> Result<String, TrustStoreError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// The credential of the exporting client. It has to be verified against
+    /// the AS before the export can be trusted.
+    pub fn signer_credential(&self) -> Result<VerifiableClientCredential, TrustStoreError> {
+        let bytes = hex::decode(&self.signer_credential)
+            .map_err(|_| TrustStoreError::InvalidEncoding("signer credential"))?;
+        VerifiableClientCredential::tls_deserialize_exact_bytes(&bytes)
+            .map_err(|_| TrustStoreError::InvalidEncoding("signer credential"))
+    }
+
+    /// Verify the signature with the verified credential of the exporting
+    /// client.
+    pub fn verify(self, credential: &ClientCredential) -> Result<TrustStore, TrustStoreError> {
+        if credential.identity().to_string() != self.trust_store.client_id {
+            return Err(TrustStoreError::SignerMismatch);
+        }
+        self.verify_with_key(credential.verifying_key())
+    }
+
+    /// Verify the signature with the embedded credential of the exporting
+    /// client, without verifying that credential against the AS. This only
+    /// proves that the export wasn't modified after it was signed.
+    pub fn verify_self_signed(self) -> Result<TrustStore, TrustStoreError> {
+        let credential = self.signer_credential()?;
+        if credential.client_id().to_string() != self.trust_store.client_id {
+            return Err(TrustStoreError::SignerMismatch);
+        }
+        let verifying_key = credential.verifying_key().clone();
+        self.verify_with_key(&verifying_key)
+    }
+
+    fn verify_with_key(
+        self,
+        verifying_key: &ClientVerifyingKey,
+    ) -> Result<TrustStore, TrustStoreError> {
+        let bytes = hex::decode(&self.signature)
+            .map_err(|_| TrustStoreError::InvalidEncoding("signature"))?;
+        let signature = Signature::tls_deserialize_exact_bytes(&bytes)
+            .map_err(|_| TrustStoreError::InvalidEncoding("signature"))?;
+        let sign_content = sign_content(&self.trust_store)?;
+        verifying_key
+            .verify(&sign_content, &signature)
+            .map_err(|_| TrustStoreError::InvalidSignature)?;
+        Ok(self.trust_store)
+    }
+}
+
+/// The signature covers the compact JSON serialization of the trust store,
+/// which is the same before and after a roundtrip through the export.
+fn sign_content(trust_store: &TrustStore) -> Result<Vec<u8>, TrustStoreError> {
+    let payload = serde_json::to_vec(trust_store)?;
+    SignContent::from((TRUST_STORE_LABEL, payload.as_slice()))
+        .tls_serialize_detached()
+        .map_err(|_| TrustStoreError::SigningFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        credentials::{
+            keys::AsIntermediateSigningKey, AsCredential, AsIntermediateCredentialCsr,
+            ClientCredentialCsr, ClientCredentialPayload,
+        },
+        crypto::signatures::DEFAULT_SIGNATURE_SCHEME,
+        identifiers::{AsClientId, Fqdn, QualifiedUserName, SafeTryInto},
+    };
+
+    use super::*;
+
+    fn signing_key(user_name: &str) -> ClientSigningKey {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        let (_, as_signing_key) =
+            AsCredential::new(DEFAULT_SIGNATURE_SCHEME, domain.clone(), None).unwrap();
+        let (csr, prelim_signing_key) =
+            AsIntermediateCredentialCsr::new(DEFAULT_SIGNATURE_SCHEME, domain).unwrap();
+        let intermediate_credential = csr.sign(&as_signing_key, None).unwrap();
+        let intermediate_signing_key =
+            AsIntermediateSigningKey::from_prelim_key(prelim_signing_key, intermediate_credential)
+                .unwrap();
+
+        let user_name: QualifiedUserName = SafeTryInto::try_into(user_name).unwrap();
+        let client_id = AsClientId::random(user_name).unwrap();
+        let (client_csr, prelim_signing_key) =
+            ClientCredentialCsr::new(client_id, DEFAULT_SIGNATURE_SCHEME).unwrap();
+        let client_credential = ClientCredentialPayload::new(
+            client_csr,
+            None,
+            intermediate_signing_key.credential().fingerprint().clone(),
+        )
+        .sign_with_intermediate(&intermediate_signing_key)
+        .unwrap();
+        ClientSigningKey::from_prelim_key(prelim_signing_key, client_credential).unwrap()
+    }
+
+    fn client(client_id: &str, fingerprint: &str, status: CredentialStatus) -> ClientTrust {
+        ClientTrust {
+            client_id: client_id.to_owned(),
+            fingerprint: Some(fingerprint.to_owned()),
+            signer_fingerprint: Some("intermediate".to_owned()),
+            status,
+        }
+    }
+
+    fn trust_store(signing_key: &ClientSigningKey, contacts: Vec<ContactTrust>) -> TrustStore {
+        let client_id = signing_key.credential().identity();
+        let intermediate = PinnedAsCredential {
+            domain: "example.com".to_owned(),
+            fingerprint: "intermediate".to_owned(),
+            as_credential_fingerprint: "root".to_owned(),
+            not_before: TimeStamp::now(),
+            not_after: TimeStamp::now(),
+        };
+        TrustStore::new(
+            client_id.user_name().to_string(),
+            client_id.to_string(),
+            vec![intermediate],
+            contacts,
+        )
+    }
+
+    #[test]
+    fn signed_export_roundtrip() {
+        let signing_key = signing_key("alice@example.com");
+        let trust_store = trust_store(&signing_key, vec![]);
+        let json = SignedTrustStore::sign(trust_store.clone(), &signing_key)
+            .unwrap()
+            .to_json()
+            .unwrap();
+
+        let signed = SignedTrustStore::from_json(&json).unwrap();
+        assert_eq!(
+            signed.clone().verify(signing_key.credential()).unwrap(),
+            trust_store
+        );
+        assert_eq!(signed.verify_self_signed().unwrap(), trust_store);
+
+        // Tampering with the trust store invalidates the signature
+        let tampered = json.replace("\"root\"", "\"attacker\"");
+        assert!(matches!(
+            SignedTrustStore::from_json(&tampered)
+                .unwrap()
+                .verify_self_signed(),
+            Err(TrustStoreError::InvalidSignature)
+        ));
+
+        // The export must be signed by the client it names
+        let other_key = signing_key("alice@example.com");
+        let mut signed = SignedTrustStore::from_json(&json).unwrap();
+        signed.trust_store.client_id = other_key.credential().identity().to_string();
+        assert!(matches!(
+            signed.verify(signing_key.credential()),
+            Err(TrustStoreError::SignerMismatch)
+        ));
+    }
+
+    #[test]
+    fn diff_of_trust_stores() {
+        let signing_key = signing_key("alice@example.com");
+        let this = trust_store(
+            &signing_key,
+            vec![ContactTrust {
+                user_name: "bob@example.com".to_owned(),
+                clients: vec![
+                    client("bob-1", "aa", CredentialStatus::Verified),
+                    client("bob-2", "bb", CredentialStatus::Verified),
+                    client("bob-3", "cc", CredentialStatus::Verified),
+                ],
+            }],
+        );
+        assert!(this.diff(&this).is_empty());
+
+        let mut other = this.clone();
+        other.as_intermediate_credentials[0].fingerprint = "rotated".to_owned();
+        other.contacts[0].clients = vec![
+            client("bob-2", "dd", CredentialStatus::Verified),
+            client("bob-3", "cc", CredentialStatus::Expired),
+            client("bob-4", "ee", CredentialStatus::Verified),
+        ];
+
+        let diff = this.diff(&other);
+        let bob = || "bob@example.com".to_owned();
+        assert_eq!(
+            diff.differences,
+            vec![
+                TrustDifference::AsCredential {
+                    domain: "example.com".to_owned(),
+                    fingerprint: "intermediate".to_owned(),
+                    side: Side::This,
+                },
+                TrustDifference::AsCredential {
+                    domain: "example.com".to_owned(),
+                    fingerprint: "rotated".to_owned(),
+                    side: Side::Other,
+                },
+                TrustDifference::ContactClient {
+                    user_name: bob(),
+                    client_id: "bob-1".to_owned(),
+                    side: Side::This,
+                },
+                TrustDifference::ClientFingerprint {
+                    user_name: bob(),
+                    client_id: "bob-2".to_owned(),
+                    this: Some("bb".to_owned()),
+                    other: Some("dd".to_owned()),
+                },
+                TrustDifference::ClientStatus {
+                    user_name: bob(),
+                    client_id: "bob-3".to_owned(),
+                    this: CredentialStatus::Verified,
+                    other: CredentialStatus::Expired,
+                },
+                TrustDifference::ContactClient {
+                    user_name: bob(),
+                    client_id: "bob-4".to_owned(),
+                    side: Side::Other,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Users blocked by the user. Connection offers of blocked users are dropped
+//! in all builds, independently of the screening heuristics.
+
+use phnxtypes::{identifiers::QualifiedUserName, time::TimeStamp};
+use rusqlite::{params, Connection};
+
+use crate::utils::persistence::Storable;
+
+/// A user whose connection offers are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockedUser {
+    user_name: QualifiedUserName,
+    blocked_at: TimeStamp,
+}
+
+impl Storable for BlockedUser {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS blocked_users (
+            user_name TEXT PRIMARY KEY,
+            blocked_at TEXT NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            user_name: row.get(0)?,
+            blocked_at: row.get(1)?,
+        })
+    }
+}
+
+impl BlockedUser {
+    pub fn user_name(&self) -> &QualifiedUserName {
+        &self.user_name
+    }
+
+    pub fn blocked_at(&self) -> TimeStamp {
+        self.blocked_at
+    }
+
+    /// Block the given user. Blocking a blocked user again keeps the original
+    /// time.
+    pub(crate) fn block(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR IGNORE INTO blocked_users (user_name, blocked_at) VALUES (?, ?)",
+            params![user_name, TimeStamp::now()],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn unblock(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "DELETE FROM blocked_users WHERE user_name = ?",
+            params![user_name],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn is_blocked(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+    ) -> Result<bool, rusqlite::Error> {
+        connection.query_row(
+            "SELECT EXISTS(SELECT 1 FROM blocked_users WHERE user_name = ?)",
+            params![user_name],
+            |row| row.get(0),
+        )
+    }
+
+    /// All blocked users, most recently blocked first.
+    pub(crate) fn load_all(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection
+            .prepare("SELECT user_name, blocked_at FROM blocked_users ORDER BY blocked_at DESC")?;
+        let blocked_users = statement
+            .query_map([], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(blocked_users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+
+    use crate::utils::migration::run_migrations;
+
+    use super::*;
+
+    #[test]
+    fn block_and_unblock() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        assert!(!BlockedUser::is_blocked(&connection, &alice).unwrap());
+
+        BlockedUser::block(&connection, &alice).unwrap();
+        let blocked_at = BlockedUser::load_all(&connection).unwrap()[0].blocked_at();
+        BlockedUser::block(&connection, &alice).unwrap();
+        assert!(BlockedUser::is_blocked(&connection, &alice).unwrap());
+        let blocked_users = BlockedUser::load_all(&connection).unwrap();
+        assert_eq!(blocked_users.len(), 1);
+        assert_eq!(blocked_users[0].user_name(), &alice);
+        assert_eq!(blocked_users[0].blocked_at(), blocked_at);
+
+        BlockedUser::unblock(&connection, &alice).unwrap();
+        assert!(!BlockedUser::is_blocked(&connection, &alice).unwrap());
+        assert!(BlockedUser::load_all(&connection).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Conversations started from a user handle without a prior contact exchange.
+//!
+//! A user's handle is their [`QualifiedUserName`]. Instead of adding the user
+//! as a contact, a client can send a message request, which creates a
+//! connection group with the [`JoinRule::MessageRequest`] policy. The
+//! recipient keeps the request in a separate inbox until they accept it, which
+//! joins the group and upgrades it to a regular connection, or decline it,
+//! which discards the group secrets. Offers of suspicious senders end up in a
+//! separate bucket of filtered requests, see [`screening`].
+//!
+//! [`JoinRule::MessageRequest`]: phnxtypes::messages::room_policy::JoinRule::MessageRequest
+
+use phnxtypes::{identifiers::QualifiedUserName, time::TimeStamp};
+#[cfg(feature = "user-handles")]
+use rusqlite::OptionalExtension;
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, ToSql,
+};
+use thiserror::Error;
+
+use crate::{utils::persistence::Storable, ConversationId};
+
+pub use blocked_users::BlockedUser;
+
+mod blocked_users;
+#[cfg(feature = "spam-reporting")]
+pub(crate) mod screening;
+
+/// Columns storing the intro of the sender and why the request was filtered,
+/// `NULL` for requests that weren't filtered.
+pub(crate) const MESSAGE_REQUEST_SCREENING_COLUMNS: &str = "
+    ALTER TABLE message_requests ADD COLUMN intro TEXT;
+    ALTER TABLE message_requests ADD COLUMN filter_reason TEXT;";
+
+/// State of a received message request.
+///
+/// Requests start out as [`MessageRequestState::Pending`] and can move to
+/// either of the other two states exactly once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageRequestState {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid message request transition from {from:?} to {to:?}")]
+pub struct MessageRequestTransitionError {
+    from: MessageRequestState,
+    to: MessageRequestState,
+}
+
+impl MessageRequestState {
+    fn transition(self, to: Self) -> Result<Self, MessageRequestTransitionError> {
+        match (self, to) {
+            (Self::Pending, Self::Accepted) | (Self::Pending, Self::Declined) => Ok(to),
+            (from, to) => Err(MessageRequestTransitionError { from, to }),
+        }
+    }
+}
+
+impl ToSql for MessageRequestState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let state = match self {
+            Self::Pending => "pending",
+            Self::Accepted => "accepted",
+            Self::Declined => "declined",
+        };
+        Ok(ToSqlOutput::Owned(Value::Text(state.to_owned())))
+    }
+}
+
+impl FromSql for MessageRequestState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "pending" => Ok(Self::Pending),
+            "accepted" => Ok(Self::Accepted),
+            "declined" => Ok(Self::Declined),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Why a connection offer was put into the filtered requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    /// The sender registered recently.
+    NewSender,
+    /// The AS of the sender doesn't know the sender.
+    UnknownSender,
+    /// The user declined many requests from the sender's domain.
+    RejectedOffers,
+}
+
+impl ToSql for FilterReason {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let reason = match self {
+            Self::NewSender => "new_sender",
+            Self::UnknownSender => "unknown_sender",
+            Self::RejectedOffers => "rejected_offers",
+        };
+        Ok(ToSqlOutput::Owned(Value::Text(reason.to_owned())))
+    }
+}
+
+impl FromSql for FilterReason {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "new_sender" => Ok(Self::NewSender),
+            "unknown_sender" => Ok(Self::UnknownSender),
+            "rejected_offers" => Ok(Self::RejectedOffers),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A message request received from another user.
+#[derive(Debug, Clone)]
+pub struct MessageRequest {
+    conversation_id: ConversationId,
+    user_name: QualifiedUserName,
+    state: MessageRequestState,
+    received_at: TimeStamp,
+    /// Short intro the sender attached to the request
+    intro: Option<String>,
+    filter_reason: Option<FilterReason>,
+    // The verified connection establishment package. Only kept while the
+    // request is pending.
+    connection_package: Vec<u8>,
+}
+
+impl Storable for MessageRequest {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS message_requests (
+            conversation_id BLOB PRIMARY KEY,
+            user_name TEXT NOT NULL,
+            state TEXT NOT NULL,
+            received_at TEXT NOT NULL,
+            connection_package BLOB NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            conversation_id: row.get(0)?,
+            user_name: row.get(1)?,
+            state: row.get(2)?,
+            received_at: row.get(3)?,
+            connection_package: row.get(4)?,
+            intro: row.get(5)?,
+            filter_reason: row.get(6)?,
+        })
+    }
+}
+
+impl MessageRequest {
+    #[cfg(feature = "user-handles")]
+    pub(crate) fn new(
+        conversation_id: ConversationId,
+        user_name: QualifiedUserName,
+        intro: Option<String>,
+        filter_reason: Option<FilterReason>,
+        connection_package: Vec<u8>,
+    ) -> Self {
+        Self {
+            conversation_id,
+            user_name,
+            state: MessageRequestState::Pending,
+            received_at: TimeStamp::now(),
+            intro,
+            filter_reason,
+            connection_package,
+        }
+    }
+
+    pub fn conversation_id(&self) -> ConversationId {
+        self.conversation_id
+    }
+
+    pub fn user_name(&self) -> &QualifiedUserName {
+        &self.user_name
+    }
+
+    pub fn state(&self) -> MessageRequestState {
+        self.state
+    }
+
+    pub fn received_at(&self) -> TimeStamp {
+        self.received_at
+    }
+
+    pub fn intro(&self) -> Option<&str> {
+        self.intro.as_deref()
+    }
+
+    /// Why the request was put into the filtered requests, if it was.
+    pub fn filter_reason(&self) -> Option<FilterReason> {
+        self.filter_reason
+    }
+
+    #[cfg(feature = "user-handles")]
+    pub(crate) fn connection_package(&self) -> &[u8] {
+        &self.connection_package
+    }
+
+    /// Store the request. Returns `false` without storing anything if there
+    /// already is a request for the same conversation or if a previous
+    /// request of the same user was declined.
+    #[cfg(feature = "user-handles")]
+    pub(crate) fn store(&self, connection: &Connection) -> Result<bool, rusqlite::Error> {
+        let declined: bool = connection.query_row(
+            "SELECT EXISTS(SELECT 1 FROM message_requests WHERE user_name = ? AND state = ?)",
+            params![self.user_name, MessageRequestState::Declined],
+            |row| row.get(0),
+        )?;
+        if declined {
+            return Ok(false);
+        }
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO message_requests (conversation_id, user_name, state, received_at, connection_package, intro, filter_reason) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                self.conversation_id,
+                self.user_name,
+                self.state,
+                self.received_at,
+                self.connection_package,
+                self.intro,
+                self.filter_reason
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    #[cfg(feature = "user-handles")]
+    pub(crate) fn load(
+        connection: &Connection,
+        conversation_id: ConversationId,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT conversation_id, user_name, state, received_at, connection_package, intro, filter_reason FROM message_requests WHERE conversation_id = ?",
+                params![conversation_id],
+                Self::from_row,
+            )
+            .optional()
+    }
+
+    /// Pending requests that weren't filtered, most recent first.
+    #[cfg(feature = "user-handles")]
+    pub(crate) fn load_pending(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, user_name, state, received_at, connection_package, intro, filter_reason FROM message_requests WHERE state = ? AND filter_reason IS NULL ORDER BY received_at DESC",
+        )?;
+        let requests = statement
+            .query_map(params![MessageRequestState::Pending], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(requests)
+    }
+
+    /// Pending requests that were filtered, most recent first.
+    #[cfg(feature = "spam-reporting")]
+    pub(crate) fn load_filtered(connection: &Connection) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, user_name, state, received_at, connection_package, intro, filter_reason FROM message_requests WHERE state = ? AND filter_reason IS NOT NULL ORDER BY received_at DESC",
+        )?;
+        let requests = statement
+            .query_map(params![MessageRequestState::Pending], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(requests)
+    }
+
+    /// Pending requests of the given user, filtered or not.
+    pub(crate) fn load_pending_from(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT conversation_id, user_name, state, received_at, connection_package, intro, filter_reason FROM message_requests WHERE state = ? AND user_name = ?",
+        )?;
+        let requests = statement
+            .query_map(
+                params![MessageRequestState::Pending, user_name],
+                Self::from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(requests)
+    }
+
+    /// Move the request to the given state and discard the connection
+    /// package, which is no longer needed once the request is decided.
+    pub(crate) fn set_state(
+        &mut self,
+        connection: &Connection,
+        state: MessageRequestState,
+    ) -> anyhow::Result<()> {
+        self.state = self.state.transition(state)?;
+        self.connection_package.clear();
+        connection.execute(
+            "UPDATE message_requests SET state = ?, connection_package = ? WHERE conversation_id = ?",
+            params![self.state, self.connection_package, self.conversation_id],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_request_transitions() {
+        use MessageRequestState::*;
+        assert_eq!(Pending.transition(Accepted), Ok(Accepted));
+        assert_eq!(Pending.transition(Declined), Ok(Declined));
+        assert!(Accepted.transition(Declined).is_err());
+        assert!(Declined.transition(Accepted).is_err());
+        assert!(Pending.transition(Pending).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Screening of incoming connection offers.
+//!
+//! Before joining a connection group or storing a message request, the client
+//! checks the sender against a few heuristics. Suspicious offers are stored
+//! as message requests in a separate bucket of filtered requests, even if the
+//! sender asked for a regular connection, such that the user can still
+//! accept them. Offers of blocked users are dropped before screening, see
+//! [`super::BlockedUser`].
+//!
+//! The heuristics are:
+//! - The AS of the sender attests that the sender registered recently or that
+//!   it doesn't know the sender, see [`SenderReputation`]. The sender attaches
+//!   the attestation to the offer, such that the recipient doesn't have to
+//!   ask the sender's AS about the sender.
+//! - The user recently declined several message requests from users of the
+//!   sender's domain.
+//!
+//! Offers from users of the user's own domain are never filtered, since the
+//! own server vets its users.
+
+use phnxtypes::{
+    identifiers::{Fqdn, QualifiedUserName},
+    messages::client_as::SenderReputation,
+    time::{Duration, TimeStamp},
+};
+use rusqlite::{params, Connection};
+
+use super::{FilterReason, MessageRequestState};
+
+/// Number of declined requests from a foreign domain within
+/// [`REJECTED_OFFERS_PERIOD_DAYS`] after which further requests from that
+/// domain are filtered.
+const REJECTED_OFFERS_THRESHOLD: usize = 3;
+const REJECTED_OFFERS_PERIOD_DAYS: i64 = 30;
+
+fn filter_reason(
+    reputation: Option<SenderReputation>,
+    declined_from_domain: usize,
+) -> Option<FilterReason> {
+    match reputation {
+        Some(SenderReputation::New) => return Some(FilterReason::NewSender),
+        Some(SenderReputation::Unknown) => return Some(FilterReason::UnknownSender),
+        Some(SenderReputation::Established) | None => {}
+    }
+    (declined_from_domain >= REJECTED_OFFERS_THRESHOLD).then_some(FilterReason::RejectedOffers)
+}
+
+/// Screen an offer of the given sender. Returns why the offer should be
+/// filtered, if it should be. `reputation` is the reputation attested by the
+/// sender's AS, if the offer carries a valid attestation.
+pub(crate) fn screen(
+    connection: &Connection,
+    sender: &QualifiedUserName,
+    own_domain: &Fqdn,
+    reputation: Option<SenderReputation>,
+) -> Result<Option<FilterReason>, rusqlite::Error> {
+    let sender_domain = sender.domain();
+    if &sender_domain == own_domain {
+        return Ok(None);
+    }
+    let declined_from_domain = declined_requests_from_domain(connection, &sender_domain)?;
+    Ok(filter_reason(reputation, declined_from_domain))
+}
+
+fn declined_requests_from_domain(
+    connection: &Connection,
+    domain: &Fqdn,
+) -> Result<usize, rusqlite::Error> {
+    let since = TimeStamp::from(*TimeStamp::now() - Duration::days(REJECTED_OFFERS_PERIOD_DAYS));
+    let pattern = format!("%@{}", escape_like_pattern(&domain.to_string()));
+    connection.query_row(
+        "SELECT COUNT(*) FROM message_requests
+        WHERE state = ? AND user_name LIKE ? ESCAPE '\\' AND received_at > ?",
+        params![MessageRequestState::Declined, pattern, since],
+        |row| row.get(0),
+    )
+}
+
+/// Escape the wildcards of LIKE patterns, such that the value only matches
+/// itself. Domains may contain `_`.
+fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::identifiers::SafeTryInto;
+    use uuid::Uuid;
+
+    use crate::{user_handles::MessageRequest, utils::migration::run_migrations, ConversationId};
+
+    use super::*;
+
+    #[test]
+    fn suspicious_senders_are_filtered() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let own_domain = Fqdn::try_from("example.com").unwrap();
+        let user = |name: &str| -> QualifiedUserName { SafeTryInto::try_into(name).unwrap() };
+        let filter = |sender: &QualifiedUserName, reputation| {
+            screen(&connection, sender, &own_domain, reputation).unwrap()
+        };
+
+        let alice = user("alice@foreign.example");
+        assert_eq!(filter(&alice, None), None);
+        assert_eq!(filter(&alice, Some(SenderReputation::Established)), None);
+        assert_eq!(
+            filter(&alice, Some(SenderReputation::New)),
+            Some(FilterReason::NewSender)
+        );
+        assert_eq!(
+            filter(&alice, Some(SenderReputation::Unknown)),
+            Some(FilterReason::UnknownSender)
+        );
+
+        // Senders from the own domain are never filtered
+        let dave = user("dave@example.com");
+        assert_eq!(filter(&dave, Some(SenderReputation::New)), None);
+
+        // Declined requests from a foreign domain
+        for i in 0..REJECTED_OFFERS_THRESHOLD {
+            let mut request = MessageRequest::new(
+                ConversationId::from(Uuid::new_v4()),
+                user(&format!("spammer{i}@spam.example")),
+                None,
+                None,
+                vec![],
+            );
+            request.store(&connection).unwrap();
+            request
+                .set_state(&connection, MessageRequestState::Declined)
+                .unwrap();
+        }
+        assert_eq!(
+            filter(&user("bob@spam.example"), None),
+            Some(FilterReason::RejectedOffers)
+        );
+        assert_eq!(filter(&user("carol@other.example"), None), None);
+    }
+
+    #[test]
+    fn domains_are_matched_literally() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let user = |name: &str| -> QualifiedUserName { SafeTryInto::try_into(name).unwrap() };
+        for i in 0..REJECTED_OFFERS_THRESHOLD {
+            let mut request = MessageRequest::new(
+                ConversationId::from(Uuid::new_v4()),
+                user(&format!("spammer{i}@spamxexample.com")),
+                None,
+                None,
+                vec![],
+            );
+            request.store(&connection).unwrap();
+            request
+                .set_state(&connection, MessageRequestState::Declined)
+                .unwrap();
+        }
+
+        let domain = |domain: &str| Fqdn::try_from(domain).unwrap();
+        let declined = |domain| declined_requests_from_domain(&connection, &domain).unwrap();
+        assert_eq!(
+            declined(domain("spamxexample.com")),
+            REJECTED_OFFERS_THRESHOLD
+        );
+        assert_eq!(declined(domain("spam_example.com")), 0);
+        assert_eq!(declined(domain("xexample.com")), 0);
+    }
+}
@@ -4,16 +4,31 @@
 
 //! This module provides structs and functions to interact with users in the
 //! various groups an InfraClient is a member of.
+//!
+//! Users store their profile encrypted on the AS and share the key with their
+//! contacts. The client caches the profiles of its contacts together with the
+//! version fetched last and refreshes them according to a
+//! [`ProfileRefreshPolicy`], such that unchanged profiles are not downloaded
+//! again.
 
 use std::fmt::Display;
 
-use phnxtypes::identifiers::QualifiedUserName;
+use phnxtypes::{
+    crypto::ear::{keys::UserProfileEarKey, EarDecryptable, EarEncryptable},
+    identifiers::QualifiedUserName,
+    messages::user_profiles::EncryptedUserProfile,
+    time::Duration,
+};
 use rusqlite::{types::FromSql, ToSql};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
 pub(crate) mod persistence;
+#[cfg(feature = "profiles")]
+pub(crate) mod refresh;
+
+pub(crate) use persistence::USER_PROFILE_VERSION_COLUMNS;
 
 /// A user profile contains information about a user, such as their display name
 /// and profile picture.
@@ -58,6 +73,28 @@ impl UserProfile {
     }
 }
 
+impl EarEncryptable<UserProfileEarKey, EncryptedUserProfile> for UserProfile {}
+impl EarDecryptable<UserProfileEarKey, EncryptedUserProfile> for UserProfile {}
+
+/// Controls how often the profiles of contacts are fetched from the AS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileRefreshPolicy {
+    /// Profiles fetched more recently are not fetched again.
+    pub max_age: Duration,
+    /// Maximum number of profiles fetched per refresh. The profiles fetched
+    /// the longest time ago are fetched first.
+    pub max_profiles: usize,
+}
+
+impl Default for ProfileRefreshPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::hours(24),
+            max_profiles: 50,
+        }
+    }
+}
+
 /// A display name is a human-readable name that can be used to identify a user.
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct DisplayName {
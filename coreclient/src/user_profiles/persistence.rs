@@ -7,6 +7,13 @@ use rusqlite::{params, Connection, OptionalExtension};
 
 use crate::{utils::persistence::Storable, Asset, DisplayName, UserProfile};
 
+/// Version of the profile on the AS that was fetched last, and when it was
+/// fetched. Both are `NULL` until the profile is fetched from the AS for the
+/// first time.
+pub(crate) const USER_PROFILE_VERSION_COLUMNS: &str = "
+    ALTER TABLE users ADD COLUMN profile_version INTEGER;
+    ALTER TABLE users ADD COLUMN profile_fetched_at TEXT;";
+
 impl Storable for UserProfile {
     const CREATE_TABLE_STATEMENT: &'static str = "CREATE TABLE IF NOT EXISTS users (
                 user_name TEXT PRIMARY KEY,
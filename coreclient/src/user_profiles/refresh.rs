@@ -0,0 +1,250 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Bookkeeping of the profile keys shared with contacts and of the profiles of
+//! contacts that are due to be fetched from the AS, see
+//! [`ProfileRefreshPolicy`](super::ProfileRefreshPolicy).
+
+use phnxtypes::{
+    crypto::ear::keys::UserProfileEarKey, identifiers::QualifiedUserName, time::TimeStamp,
+};
+use rusqlite::{params, Connection};
+
+use crate::{utils::persistence::Storable, Contact, ConversationId, UserProfile};
+
+/// A contact whose profile is due to be fetched from the AS.
+#[derive(Debug)]
+pub(crate) struct StaleUserProfile {
+    pub(crate) user_name: QualifiedUserName,
+    pub(crate) cached_version: Option<u64>,
+    pub(crate) ear_key: UserProfileEarKey,
+    pub(crate) conversation_id: ConversationId,
+}
+
+impl UserProfile {
+    /// Record that the profile of the given user was fetched from the AS,
+    /// with the version of the profile, if the user uploaded one.
+    pub(crate) fn mark_fetched(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+        version: Option<u64>,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE users SET profile_version = ?2, profile_fetched_at = ?3 WHERE user_name = ?1",
+            params![
+                user_name.to_string(),
+                version.map(|version| version as i64),
+                TimeStamp::now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Make the profile of the given user due for the next refresh, e.g.
+    /// because the key of the profile changed.
+    pub(crate) fn mark_stale(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE users SET profile_version = NULL, profile_fetched_at = NULL
+            WHERE user_name = ?",
+            [user_name.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Contacts whose profiles were not fetched since `fetched_before`, the
+    /// ones fetched the longest time ago first. Contacts that didn't share
+    /// the key of their profile are skipped.
+    pub(crate) fn load_stale(
+        connection: &Connection,
+        fetched_before: TimeStamp,
+        limit: usize,
+    ) -> Result<Vec<StaleUserProfile>, rusqlite::Error> {
+        let mut statement = connection.prepare(
+            "SELECT c.user_name, u.profile_version, c.user_profile_ear_key, c.conversation_id
+            FROM contacts c
+            JOIN users u ON u.user_name = c.user_name
+            WHERE c.user_profile_ear_key IS NOT NULL
+                AND (u.profile_fetched_at IS NULL OR u.profile_fetched_at < ?1)
+            ORDER BY u.profile_fetched_at IS NOT NULL, u.profile_fetched_at
+            LIMIT ?2",
+        )?;
+        let stale = statement
+            .query_map(params![fetched_before, limit as i64], |row| {
+                let cached_version: Option<i64> = row.get(1)?;
+                Ok(StaleUserProfile {
+                    user_name: row.get(0)?,
+                    cached_version: cached_version.map(|version| version as u64),
+                    ear_key: row.get(2)?,
+                    conversation_id: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(stale)
+    }
+}
+
+impl Contact {
+    /// Contacts the own profile key wasn't shared with yet.
+    pub(crate) fn load_without_own_user_profile_key(
+        connection: &Connection,
+    ) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut stmt =
+            connection.prepare("SELECT * FROM contacts WHERE own_user_profile_key_shared = 0")?;
+        let rows = stmt.query_map([], Self::from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn mark_own_user_profile_key_shared(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "UPDATE contacts SET own_user_profile_key_shared = 1 WHERE user_name = ?",
+            [user_name],
+        )?;
+        Ok(())
+    }
+
+    /// Store the profile key the contact shared in the given connection
+    /// conversation. Returns `false` if the user isn't a contact of that
+    /// conversation.
+    pub(crate) fn update_user_profile_ear_key(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+        conversation_id: ConversationId,
+        user_profile_ear_key: &UserProfileEarKey,
+    ) -> Result<bool, rusqlite::Error> {
+        let updated = connection.execute(
+            "UPDATE contacts SET user_profile_ear_key = ?3
+            WHERE user_name = ?1 AND conversation_id = ?2",
+            params![user_name, conversation_id, user_profile_ear_key],
+        )?;
+        Ok(updated > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use phnxtypes::{
+        crypto::ear::keys::{
+            AddPackageEarKey, ClientCredentialEarKey, SignatureEarKeyWrapperKey,
+            WelcomeAttributionInfoEarKey,
+        },
+        identifiers::SafeTryInto,
+        messages::FriendshipToken,
+        time::Duration,
+    };
+    use uuid::Uuid;
+
+    use crate::utils::migration::run_migrations;
+
+    use super::*;
+
+    fn store_contact(
+        connection: &Connection,
+        user_name: &QualifiedUserName,
+        with_key: bool,
+    ) -> ConversationId {
+        UserProfile::new(user_name.clone(), None, None)
+            .store(connection)
+            .unwrap();
+        let conversation_id = ConversationId::from(Uuid::new_v4());
+        Contact {
+            user_name: user_name.clone(),
+            clients: Vec::new(),
+            wai_ear_key: WelcomeAttributionInfoEarKey::random().unwrap(),
+            friendship_token: FriendshipToken::random().unwrap(),
+            add_package_ear_key: AddPackageEarKey::random().unwrap(),
+            client_credential_ear_key: ClientCredentialEarKey::random().unwrap(),
+            signature_ear_key_wrapper_key: SignatureEarKeyWrapperKey::random().unwrap(),
+            conversation_id,
+            user_profile_ear_key: with_key.then(|| UserProfileEarKey::random().unwrap()),
+            verified_at: None,
+        }
+        .store(connection)
+        .unwrap();
+        conversation_id
+    }
+
+    #[test]
+    fn stale_profiles_are_refreshed_oldest_first() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let user = |name: &str| -> QualifiedUserName { SafeTryInto::try_into(name).unwrap() };
+        let alice = user("alice@example.com");
+        let bob = user("bob@example.com");
+        let carol = user("carol@example.com");
+        store_contact(&connection, &alice, true);
+        store_contact(&connection, &bob, true);
+        // Carol didn't share the key of her profile
+        store_contact(&connection, &carol, false);
+
+        let stale_names = |fetched_before: TimeStamp| -> Vec<(QualifiedUserName, Option<u64>)> {
+            UserProfile::load_stale(&connection, fetched_before, 10)
+                .unwrap()
+                .into_iter()
+                .map(|stale| (stale.user_name, stale.cached_version))
+                .collect()
+        };
+        let soon = || TimeStamp::from(*TimeStamp::now() + Duration::minutes(1));
+
+        let mut never_fetched = stale_names(TimeStamp::now());
+        never_fetched.sort_by_key(|(user_name, _)| user_name.to_string());
+        assert_eq!(never_fetched, [(alice.clone(), None), (bob.clone(), None)]);
+
+        let before_fetch = TimeStamp::now();
+        UserProfile::mark_fetched(&connection, &alice, Some(3)).unwrap();
+        // Only profiles fetched before the given time are stale
+        assert_eq!(stale_names(before_fetch), [(bob.clone(), None)]);
+        // Profiles that were never fetched come first
+        assert_eq!(
+            stale_names(soon()),
+            [(bob.clone(), None), (alice.clone(), Some(3))]
+        );
+        assert_eq!(
+            UserProfile::load_stale(&connection, soon(), 1)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn shared_profile_key_makes_the_profile_stale() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        let alice: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let bob: QualifiedUserName = SafeTryInto::try_into("bob@example.com").unwrap();
+        let alice_conversation = store_contact(&connection, &alice, true);
+        let bob_conversation = store_contact(&connection, &bob, false);
+        let before_fetch = TimeStamp::now();
+        UserProfile::mark_fetched(&connection, &alice, Some(3)).unwrap();
+        UserProfile::mark_fetched(&connection, &bob, None).unwrap();
+
+        // New contacts received the own key in the friendship package
+        assert!(Contact::load_without_own_user_profile_key(&connection)
+            .unwrap()
+            .is_empty());
+
+        // Keys are only accepted in the connection conversation of the contact
+        let key = UserProfileEarKey::random().unwrap();
+        assert!(
+            !Contact::update_user_profile_ear_key(&connection, &bob, alice_conversation, &key)
+                .unwrap()
+        );
+        assert!(
+            Contact::update_user_profile_ear_key(&connection, &bob, bob_conversation, &key)
+                .unwrap()
+        );
+        UserProfile::mark_stale(&connection, &bob).unwrap();
+
+        let stale = UserProfile::load_stale(&connection, before_fetch, 10).unwrap();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].user_name, bob);
+        assert_eq!(stale[0].ear_key, key);
+    }
+}
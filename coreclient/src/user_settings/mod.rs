@@ -0,0 +1,23 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Settings of the user that are stored in the client database.
+//!
+//! Each setting is a type implementing [`UserSetting`]. Settings that were
+//! never set are loaded as their default value. Synced settings are shared
+//! with the user's other clients, see
+//! [`crate::clients::CoreUser::sync_user_settings`].
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub(crate) mod persistence;
+
+pub trait UserSetting: Serialize + DeserializeOwned + Default {
+    /// Unique key under which the setting is stored.
+    const KEY: &'static str;
+    /// Whether the setting is synced between the clients of the user.
+    /// Settings that only apply to the device, e.g. the app lock, must not be
+    /// synced.
+    const SYNCED: bool = false;
+}
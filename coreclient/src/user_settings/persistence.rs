@@ -0,0 +1,110 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use phnxtypes::{codec::PhnxCodec, messages::user_settings::SyncedUserSetting, time};
+use rusqlite::{params, types::Type, Connection, OptionalExtension};
+
+use crate::utils::persistence::Storable;
+
+use super::UserSetting;
+
+/// Adds the sync metadata of the user settings. Synced settings that were set
+/// before are marked as synced.
+pub(crate) const USER_SETTINGS_SYNC_COLUMNS: &str = "
+    ALTER TABLE user_settings ADD COLUMN modified_at TEXT;
+    ALTER TABLE user_settings ADD COLUMN synced INTEGER NOT NULL DEFAULT 0;
+    UPDATE user_settings SET synced = 1
+        WHERE setting IN (
+            'do_not_disturb', 'chat_notifications', 'auto_download', 'media_retention'
+        );";
+
+pub(crate) struct StorableUserSetting {
+    key: String,
+    value: Vec<u8>,
+}
+
+impl Storable for StorableUserSetting {
+    const CREATE_TABLE_STATEMENT: &'static str = "
+        CREATE TABLE IF NOT EXISTS user_settings (
+            setting TEXT PRIMARY KEY,
+            value BLOB NOT NULL
+        );";
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let key = row.get(0)?;
+        let value = row.get(1)?;
+        Ok(Self { key, value })
+    }
+}
+
+impl StorableUserSetting {
+    pub(crate) fn load<T: UserSetting>(connection: &Connection) -> Result<T, rusqlite::Error> {
+        let mut statement =
+            connection.prepare("SELECT setting, value FROM user_settings WHERE setting = ?")?;
+        let Some(setting) = statement
+            .query_row(params![T::KEY], Self::from_row)
+            .optional()?
+        else {
+            return Ok(T::default());
+        };
+        PhnxCodec::from_slice(&setting.value).map_err(|e| {
+            log::error!("Failed to decode user setting {}: {}", setting.key, e);
+            rusqlite::Error::FromSqlConversionFailure(1, Type::Blob, Box::new(e))
+        })
+    }
+
+    pub(crate) fn store<T: UserSetting>(
+        connection: &Connection,
+        value: &T,
+    ) -> Result<(), rusqlite::Error> {
+        let setting = Self {
+            key: T::KEY.to_owned(),
+            value: PhnxCodec::to_vec(value)?,
+        };
+        connection.execute(
+            "INSERT OR REPLACE INTO user_settings (setting, value, modified_at, synced)
+            VALUES (?, ?, ?, ?)",
+            params![setting.key, setting.value, time::now(), T::SYNCED],
+        )?;
+        Ok(())
+    }
+
+    /// Loads all synced settings. Settings that were last changed before they
+    /// were synced count as changed at the beginning of time.
+    pub(crate) fn load_synced(
+        connection: &Connection,
+    ) -> Result<BTreeMap<String, SyncedUserSetting>, rusqlite::Error> {
+        let mut statement = connection
+            .prepare("SELECT setting, value, modified_at FROM user_settings WHERE synced = 1")?;
+        let settings = statement
+            .query_map([], |row| {
+                let key = row.get(0)?;
+                let modified_at: Option<DateTime<Utc>> = row.get(2)?;
+                let setting = SyncedUserSetting {
+                    value: row.get(1)?,
+                    modified_at: modified_at.unwrap_or(DateTime::UNIX_EPOCH).into(),
+                };
+                Ok((key, setting))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(settings)
+    }
+
+    /// Stores a synced setting received from another client of the user.
+    pub(crate) fn store_synced(
+        connection: &Connection,
+        key: &str,
+        setting: &SyncedUserSetting,
+    ) -> Result<(), rusqlite::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO user_settings (setting, value, modified_at, synced)
+            VALUES (?, ?, ?, 1)",
+            params![key, setting.value, *setting.modified_at],
+        )?;
+        Ok(())
+    }
+}
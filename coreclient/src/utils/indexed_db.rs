@@ -0,0 +1,241 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Persistence of the client DBs in the browser.
+//!
+//! There is no file system in the browser (`wasm32-unknown-unknown`), so the
+//! SQLite DBs are kept in memory and their pages are written to IndexedDB
+//! whenever the DB changes. Only the pages that changed since the last write
+//! are stored again, together with the number of pages of the DB, in a single
+//! IndexedDB transaction. Opening a DB restores it from its pages.
+//!
+//! The DBs use the `memdb` VFS, such that the pages can be read without
+//! copying the whole DB. The `db_path` of the client is used as the name of
+//! the IndexedDB database and the file name of each DB as the prefix of the
+//! keys of its pages.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use js_sys::Uint8Array;
+use rexie::{ObjectStore, Rexie, TransactionMode};
+use rusqlite::{hooks::Action, Connection, DatabaseName};
+use tokio::sync::Mutex;
+use wasm_bindgen::JsValue;
+
+/// Version of the IndexedDB databases. Version 1 stored a snapshot of the
+/// whole DB on every change.
+const DATABASE_VERSION: u32 = 2;
+const PAGE_STORE: &str = "pages";
+const PAGE_COUNT_STORE: &str = "page_counts";
+
+/// Location of the pages of a DB in IndexedDB.
+#[derive(Debug)]
+pub(crate) struct DbSnapshot {
+    database: String,
+    key: String,
+    /// Set by the update hook of the connection whenever a row changes.
+    changed: Arc<AtomicBool>,
+    /// Hashes of the pages as they were last handed off for writing. Cleared
+    /// if writing fails, such that all pages are written again.
+    page_hashes: Arc<std::sync::Mutex<Vec<u64>>>,
+    /// Pages are written in the background. The (fair) lock ensures that
+    /// they are written in the order they were read.
+    write_lock: Arc<Mutex<()>>,
+}
+
+/// Pages of a DB to write to IndexedDB.
+struct PageUpdate {
+    page_count: usize,
+    changed_pages: Vec<(usize, Vec<u8>)>,
+    removed_pages: Range<usize>,
+}
+
+impl DbSnapshot {
+    /// Open the DB with the given key in the given IndexedDB database and
+    /// restore it from its stored pages, if there are any.
+    pub(crate) async fn open(database: &str, key: &str) -> Result<(Connection, Self)> {
+        let rexie = open_database(database).await?;
+        let transaction =
+            rexie.transaction(&[PAGE_STORE, PAGE_COUNT_STORE], TransactionMode::ReadOnly)?;
+        let page_count = transaction
+            .store(PAGE_COUNT_STORE)?
+            .get(JsValue::from_str(key))
+            .await?
+            .and_then(|page_count| page_count.as_f64())
+            .map_or(0, |page_count| page_count as usize);
+        let page_store = transaction.store(PAGE_STORE)?;
+        let mut content = Vec::new();
+        let mut page_hashes = Vec::with_capacity(page_count);
+        for index in 0..page_count {
+            let page = page_store
+                .get(page_key(key, index))
+                .await?
+                .ok_or_else(|| anyhow!("Page {index} of {key} is missing in IndexedDB"))?;
+            let page = Uint8Array::new(&page).to_vec();
+            page_hashes.push(hash_page(&page));
+            content.extend_from_slice(&page);
+        }
+        transaction.done().await?;
+        rexie.close();
+
+        // Names without a leading slash are private to the connection.
+        let mut connection = Connection::open(format!("file:{key}?vfs=memdb"))?;
+        if !content.is_empty() {
+            connection.deserialize_read_exact(
+                DatabaseName::Main,
+                content.as_slice(),
+                content.len(),
+                false,
+            )?;
+        }
+
+        let changed = Arc::new(AtomicBool::new(false));
+        let hook_changed = changed.clone();
+        connection.update_hook(Some(
+            move |_action: Action, _database: &str, _table: &str, _row_id: i64| {
+                hook_changed.store(true, Ordering::Relaxed);
+            },
+        ));
+
+        let snapshot = Self {
+            database: database.to_owned(),
+            key: key.to_owned(),
+            changed,
+            page_hashes: Arc::new(std::sync::Mutex::new(page_hashes)),
+            write_lock: Arc::new(Mutex::new(())),
+        };
+        Ok((connection, snapshot))
+    }
+
+    /// Write the pages of the given connection that changed since the last
+    /// write to IndexedDB, if the DB changed. Changes inside of an open
+    /// transaction are picked up by the first call after the transaction
+    /// ends.
+    pub(crate) fn persist_if_changed(&self, connection: &Connection) {
+        if !connection.is_autocommit() || !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let update = match self.page_update(connection) {
+            Ok(update) => update,
+            Err(error) => {
+                log::error!("Failed to read the pages of {}: {error}", self.key);
+                self.changed.store(true, Ordering::Relaxed);
+                return;
+            }
+        };
+        if update.changed_pages.is_empty() && update.removed_pages.is_empty() {
+            return;
+        }
+        let database = self.database.clone();
+        let key = self.key.clone();
+        let changed = self.changed.clone();
+        let page_hashes = self.page_hashes.clone();
+        let write_lock = self.write_lock.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let _write_guard = write_lock.lock().await;
+            if let Err(error) = store_pages(&database, &key, update).await {
+                log::error!("Failed to persist {key} to IndexedDB: {error}");
+                page_hashes
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clear();
+                changed.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Compare the pages of the given connection with the hashes of the last
+    /// write and copy the ones that changed.
+    fn page_update(&self, connection: &Connection) -> rusqlite::Result<PageUpdate> {
+        let page_size: usize =
+            connection.pragma_query_value(None, "page_size", |row| row.get(0))?;
+        let content = connection.serialize(DatabaseName::Main)?;
+        let mut page_hashes = self
+            .page_hashes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let previous_page_count = page_hashes.len();
+        let page_count = content.len().div_ceil(page_size);
+        page_hashes.resize(page_count, 0);
+        let changed_pages = content
+            .chunks(page_size)
+            .enumerate()
+            .filter_map(|(index, page)| {
+                let hash = hash_page(page);
+                let changed = index >= previous_page_count || page_hashes[index] != hash;
+                page_hashes[index] = hash;
+                changed.then(|| (index, page.to_vec()))
+            })
+            .collect();
+        Ok(PageUpdate {
+            page_count,
+            changed_pages,
+            removed_pages: page_count..previous_page_count.max(page_count),
+        })
+    }
+}
+
+fn page_key(key: &str, index: usize) -> JsValue {
+    JsValue::from_str(&format!("{key}/{index}"))
+}
+
+fn hash_page(page: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    page.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn open_database(name: &str) -> Result<Rexie> {
+    Rexie::builder(name)
+        .version(DATABASE_VERSION)
+        .add_object_store(ObjectStore::new(PAGE_STORE))
+        .add_object_store(ObjectStore::new(PAGE_COUNT_STORE))
+        .build()
+        .await
+        .map_err(|error| anyhow!("Failed to open IndexedDB database {name}: {error}"))
+}
+
+async fn store_pages(database: &str, key: &str, update: PageUpdate) -> Result<()> {
+    let rexie = open_database(database).await?;
+    let transaction =
+        rexie.transaction(&[PAGE_STORE, PAGE_COUNT_STORE], TransactionMode::ReadWrite)?;
+    let page_store = transaction.store(PAGE_STORE)?;
+    for (index, page) in update.changed_pages {
+        page_store
+            .put(
+                &Uint8Array::from(page.as_slice()).into(),
+                Some(&page_key(key, index)),
+            )
+            .await?;
+    }
+    for index in update.removed_pages {
+        page_store.delete(page_key(key, index)).await?;
+    }
+    transaction
+        .store(PAGE_COUNT_STORE)?
+        .put(
+            &JsValue::from_f64(update.page_count as f64),
+            Some(&JsValue::from_str(key)),
+        )
+        .await?;
+    transaction.done().await?;
+    rexie.close();
+    Ok(())
+}
+
+/// Delete the pages of all DBs in the given IndexedDB database.
+pub(crate) async fn delete_snapshots(database: &str) -> Result<()> {
+    Rexie::delete(database)
+        .await
+        .map_err(|error| anyhow!("Failed to delete IndexedDB database {database}: {error}"))
+}
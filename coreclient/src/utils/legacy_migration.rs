@@ -0,0 +1,264 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! One-shot migration of client DBs written before the schema was versioned.
+//!
+//! Clients from before the embedded migrations created their tables directly
+//! from the `Storable` statements, so their DBs have no migration history and
+//! may lack columns added since or already have some of them. Running the
+//! migrations on such a DB would replay all of them on top of the existing
+//! tables, which fails as soon as a migration adds a column that exists.
+//!
+//! Instead, a legacy DB is copied into a freshly migrated DB: all rows of the
+//! tables that still exist are copied with the columns both schemas have in
+//! common, such that new columns get their default values. The copy is
+//! verified before it replaces the legacy DB, which is kept next to it with
+//! the [`LEGACY_DB_SUFFIX`] as a backup.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Result};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+
+use super::migration::run_migrations;
+
+/// Suffix of archived legacy DBs, followed by the time of the migration.
+pub(crate) const LEGACY_DB_SUFFIX: &str = ".legacy";
+
+const MIGRATION_HISTORY_TABLE: &str = "refinery_schema_history";
+
+/// Tables that exist in every client DB, even the oldest ones.
+const CLIENT_TABLES: [&str; 2] = ["own_client_info", "user_creation_state"];
+
+/// Migrate the client DB at the given path if it is a legacy DB.
+///
+/// Returns `true` if the DB was migrated. Afterwards, the DB at the given
+/// path is up to date and the legacy DB is archived.
+pub(crate) fn migrate_legacy_client_db(db_file: &Path) -> Result<bool> {
+    if !db_file.exists() {
+        return Ok(false);
+    }
+    let legacy = Connection::open(db_file)?;
+    if !is_legacy_db(&legacy)? {
+        return Ok(false);
+    }
+    log::info!("Migrating legacy client DB {}", db_file.display());
+    drop(legacy);
+
+    let migrated_file = with_suffix(db_file, ".migrating");
+    if migrated_file.exists() {
+        // Left over from an interrupted migration
+        fs::remove_file(&migrated_file)?;
+    }
+    if let Err(error) = copy_into_migrated_db(db_file, &migrated_file) {
+        let _ = fs::remove_file(&migrated_file);
+        return Err(error);
+    }
+
+    let archive = with_suffix(
+        db_file,
+        &format!("{LEGACY_DB_SUFFIX}-{}", Utc::now().format("%Y%m%d%H%M%S")),
+    );
+    fs::rename(db_file, &archive)?;
+    fs::rename(&migrated_file, db_file)?;
+    log::info!("Archived legacy client DB at {}", archive.display());
+    Ok(true)
+}
+
+fn is_legacy_db(connection: &Connection) -> Result<bool> {
+    let has_history = table_exists(connection, "main", MIGRATION_HISTORY_TABLE)?;
+    if has_history {
+        return Ok(false);
+    }
+    for table in CLIENT_TABLES {
+        if table_exists(connection, "main", table)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn copy_into_migrated_db(legacy_file: &Path, migrated_file: &Path) -> Result<()> {
+    let mut connection = Connection::open(migrated_file)?;
+    run_migrations(&mut connection)?;
+
+    // Rows are copied table by table, so references may point to rows that
+    // are not copied yet. They are checked once all rows are copied.
+    connection.pragma_update(None, "foreign_keys", false)?;
+    let Some(legacy_path) = legacy_file.to_str() else {
+        bail!("Invalid legacy DB path {}", legacy_file.display());
+    };
+    connection.execute("ATTACH DATABASE ? AS legacy", [legacy_path])?;
+
+    let transaction = connection.transaction()?;
+    let mut copied_tables = Vec::new();
+    for table in tables(&transaction, "legacy")? {
+        if !table_exists(&transaction, "main", &table)? {
+            log::warn!("Dropping legacy table {table}, which no longer exists");
+            continue;
+        }
+        let main_columns: HashSet<_> = columns(&transaction, "main", &table)?.into_iter().collect();
+        let common_columns: Vec<_> = columns(&transaction, "legacy", &table)?
+            .into_iter()
+            .filter(|column| main_columns.contains(column))
+            .map(|column| format!("\"{column}\""))
+            .collect();
+        if common_columns.is_empty() {
+            continue;
+        }
+        let common_columns = common_columns.join(", ");
+        transaction.execute(
+            &format!(
+                "INSERT INTO main.\"{table}\" ({common_columns})
+                SELECT {common_columns} FROM legacy.\"{table}\""
+            ),
+            [],
+        )?;
+        copied_tables.push(table);
+    }
+    transaction.commit()?;
+
+    verify(&connection, &copied_tables)?;
+    connection.execute("DETACH DATABASE legacy", [])?;
+    Ok(())
+}
+
+/// Check that all rows were copied and that the migrated DB is consistent.
+fn verify(connection: &Connection, copied_tables: &[String]) -> Result<()> {
+    for table in copied_tables {
+        let count = |schema: &str| -> rusqlite::Result<i64> {
+            connection.query_row(
+                &format!("SELECT COUNT(*) FROM {schema}.\"{table}\""),
+                [],
+                |row| row.get(0),
+            )
+        };
+        let (legacy_count, migrated_count) = (count("legacy")?, count("main")?);
+        ensure!(
+            legacy_count == migrated_count,
+            "Copied {migrated_count} of {legacy_count} rows of table {table}"
+        );
+    }
+
+    let integrity: String =
+        connection.query_row("PRAGMA main.integrity_check", [], |row| row.get(0))?;
+    ensure!(integrity == "ok", "Integrity check failed: {integrity}");
+
+    let violation: Option<String> = connection
+        .query_row("PRAGMA main.foreign_key_check", [], |row| row.get(0))
+        .optional()?;
+    if let Some(table) = violation {
+        bail!("Foreign key check failed for table {table}");
+    }
+    Ok(())
+}
+
+fn table_exists(connection: &Connection, schema: &str, table: &str) -> rusqlite::Result<bool> {
+    connection.query_row(
+        &format!(
+            "SELECT EXISTS (
+                SELECT 1 FROM {schema}.sqlite_master WHERE type = 'table' AND name = ?
+            )"
+        ),
+        [table],
+        |row| row.get(0),
+    )
+}
+
+fn tables(connection: &Connection, schema: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = connection.prepare(&format!(
+        "SELECT name FROM {schema}.sqlite_master
+        WHERE type = 'table' AND name NOT LIKE 'sqlite_%'"
+    ))?;
+    let tables = statement.query_map([], |row| row.get(0))?;
+    tables.collect()
+}
+
+fn columns(connection: &Connection, schema: &str, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = connection.prepare(&format!("PRAGMA {schema}.table_info(\"{table}\")"))?;
+    let columns = statement.query_map([], |row| row.get(1))?;
+    columns.collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls::group::GroupId;
+    use rusqlite::params;
+    use uuid::Uuid;
+
+    use crate::{
+        clients::own_client_info::OwnClientInfo,
+        utils::persistence::{GroupIdRefWrapper, Storable},
+        Conversation, ConversationAttributes,
+    };
+
+    use super::*;
+
+    #[test]
+    fn legacy_db_is_migrated_and_archived() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let db_file = dir.join("client.db");
+
+        // Legacy DB with the tables as created before the migrations
+        let legacy = Connection::open(&db_file).unwrap();
+        <OwnClientInfo as Storable>::create_table(&legacy).unwrap();
+        <Conversation as Storable>::create_table(&legacy).unwrap();
+        let group_id = GroupId::from_slice(b"group");
+        let conversation = Conversation::new_group_conversation(
+            group_id.clone(),
+            ConversationAttributes::new("Legacy".to_owned(), None),
+        );
+        legacy
+            .execute(
+                "INSERT INTO conversations (conversation_id, conversation_title, conversation_picture, group_id, last_read, conversation_status, conversation_type) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    conversation.id(),
+                    "Legacy",
+                    None::<Vec<u8>>,
+                    GroupIdRefWrapper::from(&group_id),
+                    Utc::now(),
+                    conversation.status(),
+                    conversation.conversation_type(),
+                ],
+            )
+            .unwrap();
+        drop(legacy);
+
+        assert!(migrate_legacy_client_db(&db_file).unwrap());
+
+        let migrated = Connection::open(&db_file).unwrap();
+        assert!(!is_legacy_db(&migrated).unwrap());
+        let conversations = Conversation::load_all(&migrated).unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].id(), conversation.id());
+        assert_eq!(conversations[0].attributes().title(), "Legacy");
+
+        let archived = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("client.db.legacy-")
+            });
+        assert!(archived);
+
+        // Migrated DBs are left alone
+        assert!(!migrate_legacy_client_db(&db_file).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,34 +5,85 @@
 use migrations::EmbeddedMigration;
 use refinery::Migration;
 
+use crate::ConversationMessage;
+
 refinery::embed_migrations!("migrations");
 
+/// Applies all pending migrations. Data conversions of the applied
+/// migrations run after all schema migrations, since they use the current
+/// code to load and store data.
 pub(crate) fn run_migrations(
     client_db_connection: &mut rusqlite::Connection,
-) -> Result<(), refinery::Error> {
-    for migration in migrations::runner().run_iter(client_db_connection) {
-        post_process(migration?);
+) -> anyhow::Result<()> {
+    let applied_migrations = migrations::runner()
+        .run_iter(client_db_connection)
+        .collect::<Result<Vec<_>, _>>()
+        .inspect_err(|e| log::error!("Failed to apply migrations: {}", e))?;
+    log::info!(
+        "Applied migrations successfully. Migrations applied: {}",
+        applied_migrations.len()
+    );
+    for migration in applied_migrations {
+        post_process(client_db_connection, migration)?;
     }
+    Ok(())
+}
 
-    match migrations::runner().run(client_db_connection) {
-        Ok(report) => {
-            log::info!(
-                "Applied migrations successfully. Migrations applied: {}",
-                report.applied_migrations().len()
-            );
-            Ok(())
-        }
-        Err(e) => {
-            log::error!("Failed to apply migrations: {}", e);
-            Err(e)
-        }
-    }
+/// Returns `true` if the database is missing migrations, e.g. because the app
+/// was updated but not started since.
+pub(crate) fn has_pending_migrations(
+    client_db_connection: &mut rusqlite::Connection,
+) -> Result<bool, refinery::Error> {
+    let runner = migrations::runner();
+    let latest = runner
+        .get_migrations()
+        .iter()
+        .map(|migration| migration.version())
+        .max();
+    let applied = runner
+        .get_last_applied_migration(client_db_connection)?
+        .map(|migration| migration.version());
+    Ok(applied < latest)
 }
 
-fn post_process(migration: Migration) {
+fn post_process(connection: &rusqlite::Connection, migration: Migration) -> anyhow::Result<()> {
     match migration.into() {
         EmbeddedMigration::CreateInitialTablesAndTriggers(_) => {
             // Perform post-processing for arbitrary migrations here.
         }
+        EmbeddedMigration::CreateAttachmentTables(_) => {}
+        EmbeddedMigration::CreateUserSettingsAndMediaDeletion(_) => {}
+        EmbeddedMigration::CreateQuarantinedWelcomesTable(_) => {}
+        EmbeddedMigration::CreateJoinRequestsTable(_) => {}
+        EmbeddedMigration::CreateMessageRequestsTable(_) => {}
+        EmbeddedMigration::CreateMembershipHistoryTable(_) => {}
+        EmbeddedMigration::CreateClientSigningKeyTable(_) => {}
+        EmbeddedMigration::CreateViewOnceAttachmentsTable(_) => {}
+        EmbeddedMigration::CreateBroadcastListTables(_) => {}
+        EmbeddedMigration::AddConversationSensitiveFlag(_) => {}
+        EmbeddedMigration::CreateAnnouncementsTable(_) => {}
+        EmbeddedMigration::AddGroupSecurityColumns(_) => {}
+        EmbeddedMigration::AddGroupCommitPendingColumn(_) => {}
+        EmbeddedMigration::AddMessageReceivedAtColumn(_) => {}
+        EmbeddedMigration::CreateActivitiesTable(_) => {}
+        EmbeddedMigration::AddUserSettingsSyncColumns(_) => {}
+        EmbeddedMigration::CreateConversationCounters(_) => {}
+        EmbeddedMigration::CreateChatMetadataTable(_) => {}
+        EmbeddedMigration::AddMessageDeliveryFailureColumn(_) => {}
+        EmbeddedMigration::AddConnectionScreening(_) => {}
+        EmbeddedMigration::MigrateLegacySystemMessages(_) => {
+            let migrated = ConversationMessage::migrate_legacy_system_messages(connection)?;
+            log::info!("Migrated {migrated} legacy system messages");
+        }
+        EmbeddedMigration::AddUserProfileVersions(_) => {}
+        EmbeddedMigration::CreatePendingGroupUpgradesTable(_) => {}
+        EmbeddedMigration::CreateMessageArchiveTables(_) => {}
+        EmbeddedMigration::AddContactVerification(_) => {}
+        EmbeddedMigration::CreatePendingClientSigningKeyTable(_) => {}
+        EmbeddedMigration::AddContactUserProfileKeyShared(_) => {}
+        EmbeddedMigration::CreatePendingConnectionsTable(_) => {}
+        EmbeddedMigration::AddAttachmentThumbnailColumn(_) => {}
+        EmbeddedMigration::CreateProposedClientUpdatesTable(_) => {}
     }
+    Ok(())
 }
@@ -2,6 +2,11 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+#[cfg(target_arch = "wasm32")]
+pub(crate) mod indexed_db;
+pub(crate) mod legacy_migration;
 #[allow(non_snake_case)]
 pub(crate) mod migration;
 pub(crate) mod persistence;
+pub(crate) mod rt;
+pub(crate) mod wipe;
@@ -7,38 +7,69 @@ use std::{
     ops::{Deref, DerefMut},
     path::Path,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{bail, Result};
 use openmls::group::GroupId;
 use phnxtypes::identifiers::AsClientId;
-use rusqlite::{types::FromSql, Connection, ToSql};
+use rusqlite::{types::FromSql, Connection, OpenFlags, ToSql};
 use tokio::sync::{Mutex, MutexGuard};
 
 use crate::clients::store::ClientRecord;
+#[cfg(target_arch = "wasm32")]
+use crate::utils::indexed_db::{delete_snapshots, DbSnapshot};
 
 pub(crate) const PHNX_DB_NAME: &str = "phnx.db";
 
+/// Shared connection to a client's SQLite DB.
+///
+/// In the browser (`wasm32-unknown-unknown`), the DB is kept in memory and
+/// persisted to IndexedDB (see [`indexed_db`]) whenever a guard is released
+/// after changing it.
+///
+/// [`indexed_db`]: crate::utils::indexed_db
 #[derive(Debug, Clone)]
 pub(crate) struct SqliteConnection {
     connection_mutex: Arc<Mutex<Connection>>,
+    #[cfg(target_arch = "wasm32")]
+    snapshot: Option<Arc<DbSnapshot>>,
 }
 
 impl SqliteConnection {
     pub fn new(connection: Connection) -> Self {
         Self {
             connection_mutex: Arc::new(Mutex::new(connection)),
+            #[cfg(target_arch = "wasm32")]
+            snapshot: None,
         }
     }
 
+    /// Open the DB with the given name and restore it from its snapshot in
+    /// the IndexedDB database named `db_path`.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) async fn open_snapshot(db_path: &str, db_name: &str) -> Result<Self> {
+        let (connection, snapshot) = DbSnapshot::open(db_path, db_name).await?;
+        Ok(Self {
+            connection_mutex: Arc::new(Mutex::new(connection)),
+            snapshot: Some(Arc::new(snapshot)),
+        })
+    }
+
     pub async fn lock(&self) -> SqliteConnectionGuard {
         let guard = self.connection_mutex.lock().await;
-        SqliteConnectionGuard { guard }
+        SqliteConnectionGuard {
+            guard,
+            #[cfg(target_arch = "wasm32")]
+            snapshot: self.snapshot.as_deref(),
+        }
     }
 }
 
 pub(crate) struct SqliteConnectionGuard<'a> {
     guard: MutexGuard<'a, Connection>,
+    #[cfg(target_arch = "wasm32")]
+    snapshot: Option<&'a DbSnapshot>,
 }
 
 impl Deref for SqliteConnectionGuard<'_> {
@@ -55,6 +86,15 @@ impl DerefMut for SqliteConnectionGuard<'_> {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
+impl Drop for SqliteConnectionGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot {
+            snapshot.persist_if_changed(&self.guard);
+        }
+    }
+}
+
 /// Open a connection to the DB that contains records for all clients on this
 /// device.
 pub(crate) fn open_phnx_db(client_db_path: &str) -> Result<Connection, rusqlite::Error> {
@@ -100,7 +140,20 @@ pub fn delete_databases(client_db_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn client_db_name(as_client_id: &AsClientId) -> String {
+/// Delete the IndexedDB database that contains the snapshots of the phnx.db
+/// and all client DBs.
+///
+/// WARNING: This will delete all APP-data from this browser profile!
+#[cfg(target_arch = "wasm32")]
+pub async fn delete_indexed_databases(client_db_path: &str) -> Result<()> {
+    delete_snapshots(client_db_path).await
+}
+
+/// Time a connection to a client DB waits for write transactions of other
+/// processes to finish.
+const CLIENT_DB_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) fn client_db_name(as_client_id: &AsClientId) -> String {
     format!("{}.db", as_client_id)
 }
 
@@ -111,9 +164,52 @@ pub(crate) fn open_client_db(
     let client_db_name = client_db_name(as_client_id);
     let full_db_path = format!("{}/{}", client_db_path, client_db_name);
     let conn = Connection::open(full_db_path)?;
+    // The DB is shared with the app extensions. In WAL mode, readers don't
+    // block the writer and vice versa, and writers wait for each other.
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.busy_timeout(CLIENT_DB_BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Open the DB of the given client without write access, e.g. in app
+/// extensions. The app keeps the DB in WAL mode, so reading doesn't block its
+/// writes.
+pub(crate) fn open_client_db_read_only(
+    as_client_id: &AsClientId,
+    client_db_path: &str,
+) -> Result<Connection, rusqlite::Error> {
+    let client_db_name = client_db_name(as_client_id);
+    let full_db_path = format!("{}/{}", client_db_path, client_db_name);
+    let conn = Connection::open_with_flags(
+        full_db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    conn.busy_timeout(CLIENT_DB_BUSY_TIMEOUT)?;
     Ok(conn)
 }
 
+/// Open the phnx.db and the DB of the given client. In the browser, both are
+/// restored from IndexedDB.
+pub(crate) async fn open_db_connections(
+    as_client_id: &AsClientId,
+    db_path: &str,
+) -> Result<(SqliteConnection, SqliteConnection)> {
+    #[cfg(not(target_arch = "wasm32"))]
+    let (phnx_db_connection, client_db_connection) = (
+        SqliteConnection::new(open_phnx_db(db_path)?),
+        SqliteConnection::new(open_client_db(as_client_id, db_path)?),
+    );
+    #[cfg(target_arch = "wasm32")]
+    let (phnx_db_connection, client_db_connection) = {
+        let phnx_db_connection = SqliteConnection::open_snapshot(db_path, PHNX_DB_NAME).await?;
+        ClientRecord::create_table(&phnx_db_connection.lock().await)?;
+        let client_db_connection =
+            SqliteConnection::open_snapshot(db_path, &client_db_name(as_client_id)).await?;
+        (phnx_db_connection, client_db_connection)
+    };
+    Ok((phnx_db_connection, client_db_connection))
+}
+
 /// Helper function to read one or more values from the database. If
 /// `number_of_entries` is set, it will load at most that number of entries.
 pub(crate) trait Storable {
@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Timers and blocking work independent of the executor.
+//!
+//! In the browser (`wasm32-unknown-unknown`), there is neither a tokio timer
+//! nor a blocking thread pool, and `std::time::Instant` panics. There, timers
+//! are backed by `setTimeout` and blocking work runs inline on the single
+//! thread of the page.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web_time::Instant;
+
+/// Wait until the given duration has elapsed.
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(duration).await;
+    #[cfg(target_arch = "wasm32")]
+    gloo_timers::future::sleep(duration).await;
+}
+
+/// Run the given CPU-bound closure without blocking the executor.
+pub(crate) async fn spawn_blocking<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    let result = tokio::task::spawn_blocking(f).await?;
+    #[cfg(target_arch = "wasm32")]
+    let result = f();
+    Ok(result)
+}
@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Best-effort secure deletion of local files.
+//!
+//! Files are overwritten with zeros and flushed to disk before they are
+//! removed. On flash storage with wear leveling and on copy-on-write file
+//! systems the overwrite may not reach the original blocks, so this only
+//! raises the bar for recovering the data. The actual protection of deleted
+//! data comes from destroying the keys that protect it.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const OVERWRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Suffixes of the files SQLite creates next to a database.
+const SQLITE_SIDECAR_SUFFIXES: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+/// Overwrite and remove the file at the given path. A missing file is not an
+/// error.
+pub(crate) fn wipe_file(path: &Path) -> io::Result<()> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut remaining = file.metadata()?.len() as usize;
+    let zeros = vec![0; OVERWRITE_CHUNK_SIZE];
+    while remaining > 0 {
+        let chunk_size = remaining.min(OVERWRITE_CHUNK_SIZE);
+        file.write_all(&zeros[..chunk_size])?;
+        remaining -= chunk_size;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+/// Wipe the SQLite database at the given path together with its journal and
+/// shared memory files.
+pub(crate) fn wipe_database(path: &Path) -> io::Result<()> {
+    for suffix in SQLITE_SIDECAR_SUFFIXES {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        wipe_file(Path::new(&sidecar))?;
+    }
+    wipe_file(path)
+}
+
+/// Wipe all files below the given directory and remove it.
+fn wipe_directory(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            wipe_directory(&entry.path())?;
+        } else {
+            wipe_file(&entry.path())?;
+        }
+    }
+    fs::remove_dir(path)
+}
+
+/// Entries directly below `directory` whose name starts with one of the given
+/// prefixes.
+pub(crate) fn entries_with_prefix(
+    directory: &Path,
+    prefixes: &[String],
+) -> io::Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if prefixes
+            .iter()
+            .any(|prefix| file_name.starts_with(prefix.as_str()))
+        {
+            entries.push(entry.path());
+        }
+    }
+    Ok(entries)
+}
+
+/// Wipe all files and directories below `directory` whose name starts with
+/// one of the given prefixes. Returns the entries that could not be wiped.
+pub(crate) fn wipe_entries_with_prefix(
+    directory: &Path,
+    prefixes: &[String],
+) -> io::Result<Vec<PathBuf>> {
+    for path in entries_with_prefix(directory, prefixes)? {
+        let result = if path.is_dir() {
+            wipe_directory(&path)
+        } else {
+            wipe_file(&path)
+        };
+        if let Err(error) = result {
+            log::error!("Failed to wipe {}: {error}", path.display());
+        }
+    }
+    entries_with_prefix(directory, prefixes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wipes_databases_and_prefixed_entries() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir(&dir).unwrap();
+        let db = dir.join("client.db");
+        fs::write(&db, b"secret").unwrap();
+        fs::write(dir.join("client.db-wal"), b"secret").unwrap();
+        fs::create_dir_all(dir.join("client_thumbnails/nested")).unwrap();
+        fs::write(dir.join("client_thumbnails/nested/a.png"), b"secret").unwrap();
+        fs::write(dir.join("other.db"), b"kept").unwrap();
+
+        wipe_database(&db).unwrap();
+        assert!(!db.exists());
+        assert!(!dir.join("client.db-wal").exists());
+
+        let prefixes = vec!["client".to_owned()];
+        let remaining = wipe_entries_with_prefix(&dir, &prefixes).unwrap();
+        assert!(remaining.is_empty());
+        assert!(dir.join("other.db").exists());
+
+        // Wiping missing files succeeds.
+        wipe_database(&db).unwrap();
+    }
+}
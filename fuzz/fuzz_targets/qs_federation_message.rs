@@ -0,0 +1,15 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Decoding of messages received from federated QSs
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use phnxbackend::messages::qs_qs::QsToQsMessage;
+use tls_codec::DeserializeBytes;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = QsToQsMessage::tls_deserialize_exact_bytes(data);
+});
@@ -0,0 +1,15 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Decoding of requests received by the QS endpoint
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use phnxtypes::messages::client_qs::VerifiableClientToQsMessage;
+use tls_codec::DeserializeBytes;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = VerifiableClientToQsMessage::tls_deserialize_exact_bytes(data);
+});
@@ -0,0 +1,35 @@
+#!/usr/bin/env bash
+
+# SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+#
+# SPDX-License-Identifier: AGPL-3.0-or-later
+
+# Runs the benchmarks against a baseline recorded with
+# `cargo bench -- --save-baseline <name>` and fails if the mean time of any
+# benchmark regressed by more than the given threshold.
+#
+# Usage: scripts/compare_bench_baseline.sh [baseline] [threshold_percent]
+
+set -euo pipefail
+
+BASELINE="${1:-main}"
+THRESHOLD_PERCENT="${2:-10}"
+CRITERION_DIR="${CARGO_TARGET_DIR:-target}/criterion"
+
+cargo bench -p phnxtypes -p phnxcoreclient --features phnxcoreclient/bench -- --baseline "$BASELINE"
+
+regressions=0
+while IFS= read -r estimates; do
+  change=$(jq '.mean.point_estimate * 100' "$estimates")
+  if (( $(echo "$change > $THRESHOLD_PERCENT" | bc -l) )); then
+    benchmark=$(dirname "$(dirname "${estimates#"$CRITERION_DIR"/}")")
+    printf "Regression in %s: %+.2f%%\n" "$benchmark" "$change"
+    regressions=$((regressions + 1))
+  fi
+done < <(find "$CRITERION_DIR" -path "*/change/estimates.json")
+
+if [ "$regressions" -gt 0 ]; then
+  echo "$regressions benchmark(s) regressed by more than $THRESHOLD_PERCENT% against baseline $BASELINE."
+  exit 1
+fi
+echo "No benchmark regressed by more than $THRESHOLD_PERCENT% against baseline $BASELINE."
@@ -0,0 +1,469 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Operator CLI to inspect and repair the databases, see the
+//! `airserver-admin` binary.
+//!
+//! The commands use the configuration of the server to connect to the
+//! databases of the AS, the DS and the QS. They only go through the
+//! operations the backend exposes in [`phnxbackend::admin`].
+
+use std::{io::BufRead, path::PathBuf};
+
+use base64::{engine::general_purpose, Engine as _};
+use config::ConfigError;
+use phnxbackend::{
+    admin::{AdminError, IntegrityIssue, IssueKind},
+    auth_service::AuthService,
+    ds::Ds,
+    infra_service::{InfraService, ServiceCreationError},
+    qs::Qs,
+    settings::Settings,
+};
+use phnxtypes::{
+    crypto::ear::keys::{GroupStateEarKey, GroupStateEarKeySecret},
+    identifiers::Fqdn,
+    time::Duration,
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::configurations::get_configuration;
+
+const CONFIGURATION_PREFIX: &str = "server/";
+
+/// File with the base64-encoded group state key of the inspected group, or
+/// [`STDIN_PATH`] to read the key from stdin. The key itself isn't accepted
+/// as argument, since arguments show up in process listings.
+const EAR_KEY_FILE_FLAG: &str = "--ear-key-file";
+/// Former flag that took the key as argument
+const EAR_KEY_FLAG: &str = "--ear-key";
+const STDIN_PATH: &str = "-";
+/// Number of days without activity after which a queue is considered stuck
+const INACTIVE_DAYS_FLAG: &str = "--inactive-days";
+/// Only print the issues a repair would fix
+const DRY_RUN_FLAG: &str = "--dry-run";
+
+const DEFAULT_INACTIVE_DAYS: i64 = 7;
+
+pub const USAGE: &str = "Usage: airserver-admin <command>
+
+Commands:
+  users                                   List the users of the AS
+  group <group-id> [--ear-key-file <file>]
+                                          Inspect the state of a group at the DS,
+                                          reading the key from the file or from
+                                          stdin if the file is -
+  stuck-queues [--inactive-days <days>]   List QS queues of inactive clients with messages
+  requeue <queue-id>                      Notify the client of a QS queue again
+  purge <queue-id>                        Delete all messages of a QS queue
+  check                                   Verify the integrity of all databases
+  repair <issue-kind> [--dry-run]         Repair all issues of the given kind
+
+Issue kinds: missing-queue, queue-counter-behind, expired-group";
+
+#[derive(Debug, Error)]
+pub enum AdminCliError {
+    #[error("{0}\n\n{USAGE}")]
+    Usage(String),
+    #[error("Failed to load the configuration: {0}")]
+    Configuration(#[from] ConfigError),
+    #[error("Invalid domain in the configuration")]
+    Domain,
+    #[error("Failed to connect to the database: {0}")]
+    Database(#[from] ServiceCreationError),
+    #[error("Failed to read the group state key: {0}")]
+    EarKeyFile(std::io::Error),
+    #[error(transparent)]
+    Admin(#[from] AdminError),
+}
+
+/// Where to read the group state key from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EarKeySource {
+    Stdin,
+    File(PathBuf),
+}
+
+impl EarKeySource {
+    fn new(path: &str) -> Self {
+        match path {
+            STDIN_PATH => Self::Stdin,
+            path => Self::File(path.into()),
+        }
+    }
+
+    /// Read the key. Stdin is read up to the end of the first line, such that
+    /// the key can also be typed in.
+    fn read(&self) -> Result<[u8; 32], AdminCliError> {
+        let value = match self {
+            Self::Stdin => {
+                let mut value = String::new();
+                std::io::stdin().lock().read_line(&mut value).map(|_| value)
+            }
+            Self::File(path) => std::fs::read_to_string(path),
+        }
+        .map_err(AdminCliError::EarKeyFile)?;
+        parse_ear_key(value.trim())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    ListUsers,
+    InspectGroup {
+        group_id: Uuid,
+        ear_key: Option<EarKeySource>,
+    },
+    StuckQueues {
+        inactive_days: i64,
+    },
+    Requeue {
+        queue_id: Uuid,
+    },
+    Purge {
+        queue_id: Uuid,
+    },
+    Check,
+    Repair {
+        kind: IssueKind,
+        dry_run: bool,
+    },
+}
+
+impl AdminCommand {
+    /// Parse the command from the arguments without the binary name.
+    pub fn parse(args: &[String]) -> Result<Self, AdminCliError> {
+        let (command, rest) = args
+            .split_first()
+            .ok_or_else(|| AdminCliError::Usage("Missing command".to_owned()))?;
+        let command = match command.as_str() {
+            "users" => AdminCommand::ListUsers,
+            "group" if rest.iter().any(|arg| arg == EAR_KEY_FLAG) => {
+                return Err(AdminCliError::Usage(format!(
+                    "{EAR_KEY_FLAG} is no longer supported, pass the key via {EAR_KEY_FILE_FLAG}"
+                )))
+            }
+            "group" => AdminCommand::InspectGroup {
+                group_id: parse_uuid(rest.first())?,
+                ear_key: flag_value(rest, EAR_KEY_FILE_FLAG).map(EarKeySource::new),
+            },
+            "stuck-queues" => AdminCommand::StuckQueues {
+                inactive_days: flag_value(rest, INACTIVE_DAYS_FLAG)
+                    .map(|days| {
+                        days.parse().map_err(|_| {
+                            AdminCliError::Usage(format!("Invalid number of days {days}"))
+                        })
+                    })
+                    .transpose()?
+                    .unwrap_or(DEFAULT_INACTIVE_DAYS),
+            },
+            "requeue" => AdminCommand::Requeue {
+                queue_id: parse_uuid(rest.first())?,
+            },
+            "purge" => AdminCommand::Purge {
+                queue_id: parse_uuid(rest.first())?,
+            },
+            "check" => AdminCommand::Check,
+            "repair" => AdminCommand::Repair {
+                kind: rest
+                    .first()
+                    .ok_or_else(|| AdminCliError::Usage("Missing issue kind".to_owned()))?
+                    .parse()
+                    .map_err(AdminCliError::Usage)?,
+                dry_run: rest.iter().any(|arg| arg == DRY_RUN_FLAG),
+            },
+            other => return Err(AdminCliError::Usage(format!("Unknown command {other}"))),
+        };
+        Ok(command)
+    }
+}
+
+fn parse_uuid(value: Option<&String>) -> Result<Uuid, AdminCliError> {
+    let value = value.ok_or_else(|| AdminCliError::Usage("Missing id".to_owned()))?;
+    value
+        .parse()
+        .map_err(|_| AdminCliError::Usage(format!("Invalid id {value}")))
+}
+
+fn parse_ear_key(value: &str) -> Result<[u8; 32], AdminCliError> {
+    general_purpose::STANDARD
+        .decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| AdminCliError::Usage("Invalid group state key".to_owned()))
+}
+
+/// Value of the given flag in the arguments, if any.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let mut args = args.iter().skip_while(|arg| *arg != flag);
+    args.next()?;
+    args.next().map(String::as_str)
+}
+
+/// Connections to the databases of the services.
+struct Services {
+    configuration: Settings,
+    base_db_name: String,
+    domain: Fqdn,
+}
+
+impl Services {
+    fn load() -> Result<Self, AdminCliError> {
+        let configuration = get_configuration(CONFIGURATION_PREFIX)?;
+        let domain = configuration
+            .application
+            .domain
+            .clone()
+            .try_into()
+            .map_err(|_| AdminCliError::Domain)?;
+        Ok(Self {
+            base_db_name: configuration.database.name.clone(),
+            configuration,
+            domain,
+        })
+    }
+
+    async fn auth_service(&mut self) -> Result<AuthService, AdminCliError> {
+        self.configuration.database.name = format!("{}_as", self.base_db_name);
+        Ok(AuthService::new(&self.configuration.database, self.domain.clone()).await?)
+    }
+
+    async fn ds(&mut self) -> Result<Ds, AdminCliError> {
+        self.configuration.database.name = format!("{}_ds", self.base_db_name);
+        Ok(Ds::new(&self.configuration.database, self.domain.clone()).await?)
+    }
+
+    async fn qs(&mut self) -> Result<Qs, AdminCliError> {
+        self.configuration.database.name = format!("{}_qs", self.base_db_name);
+        Ok(Qs::new(&self.configuration.database, self.domain.clone()).await?)
+    }
+}
+
+/// Run the command given in the arguments of the process.
+pub async fn run_admin_cli() -> Result<(), AdminCliError> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = AdminCommand::parse(&args)?;
+    let mut services = Services::load()?;
+    match command {
+        AdminCommand::ListUsers => {
+            let users = services.auth_service().await?.admin_list_users().await?;
+            for user in &users {
+                let last_activity = user
+                    .last_activity
+                    .map(|time| time.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_owned());
+                println!(
+                    "{}  clients: {}  last activity: {last_activity}",
+                    user.user_name, user.clients
+                );
+            }
+            println!("{} users", users.len());
+        }
+        AdminCommand::InspectGroup { group_id, ear_key } => {
+            let ear_key = ear_key
+                .map(|source| source.read())
+                .transpose()?
+                .map(|key| GroupStateEarKey::from(GroupStateEarKeySecret::from(key)));
+            let Some(group) = services
+                .ds()
+                .await?
+                .admin_group_summary(group_id, ear_key.as_ref())
+                .await?
+            else {
+                println!("Group {group_id} doesn't exist");
+                return Ok(());
+            };
+            println!("Group {}", group.group_id);
+            println!("  last used: {}", group.last_used.to_rfc3339());
+            println!("  expired: {}", group.expired);
+            println!("  encrypted state: {} bytes", group.state_size);
+            println!("  deleted queues: {}", group.deleted_queues);
+            match group.state {
+                Some(state) => {
+                    println!("  epoch: {}", state.epoch);
+                    println!("  members: {}", state.members);
+                    println!("  clients: {}", state.clients);
+                }
+                None => println!("  Pass {EAR_KEY_FILE_FLAG} to show the epoch and the members"),
+            }
+        }
+        AdminCommand::StuckQueues { inactive_days } => {
+            let queues = services
+                .qs()
+                .await?
+                .admin_stuck_queues(Duration::days(inactive_days))
+                .await?;
+            for queue in &queues {
+                println!(
+                    "{}  pending: {}  oldest: {}  last activity: {}",
+                    queue.queue_id,
+                    queue.pending_messages,
+                    queue.oldest_sequence_number,
+                    queue.last_activity.to_rfc3339()
+                );
+            }
+            println!("{} stuck queues", queues.len());
+        }
+        AdminCommand::Requeue { queue_id } => {
+            let cluster = services.configuration.dispatch.cluster.clone();
+            services
+                .qs()
+                .await?
+                .admin_requeue(&cluster, queue_id)
+                .await?;
+            println!("Notified the client of queue {queue_id}");
+        }
+        AdminCommand::Purge { queue_id } => {
+            let deleted = services.qs().await?.admin_purge_queue(queue_id).await?;
+            println!("Deleted {deleted} messages of queue {queue_id}");
+        }
+        AdminCommand::Check => {
+            let auth_service = services.auth_service().await?;
+            let ds = services.ds().await?;
+            let qs = services.qs().await?;
+            let issues = check(&auth_service, &ds, &qs).await?;
+            for issue in &issues {
+                println!("{issue}");
+            }
+            println!("{} issues", issues.len());
+        }
+        AdminCommand::Repair { kind, dry_run } => {
+            let auth_service = services.auth_service().await?;
+            let ds = services.ds().await?;
+            let qs = services.qs().await?;
+            let mut repaired = 0;
+            for issue in check(&auth_service, &ds, &qs)
+                .await?
+                .into_iter()
+                .filter(|issue| issue.kind() == kind)
+            {
+                if !issue.is_repairable() {
+                    println!("Can't repair {issue}");
+                    continue;
+                }
+                if dry_run {
+                    println!("Would repair {issue}");
+                    continue;
+                }
+                let done = auth_service.admin_repair(&issue).await?
+                    || ds.admin_repair(&issue).await?
+                    || qs.admin_repair(&issue).await?;
+                if done {
+                    println!("Repaired {issue}");
+                    repaired += 1;
+                }
+            }
+            println!("Repaired {repaired} issues");
+        }
+    }
+    Ok(())
+}
+
+async fn check(
+    auth_service: &AuthService,
+    ds: &Ds,
+    qs: &Qs,
+) -> Result<Vec<IntegrityIssue>, AdminError> {
+    let mut issues = auth_service.admin_check_integrity().await?;
+    issues.extend(ds.admin_check_integrity().await?);
+    issues.extend(qs.admin_check_integrity().await?);
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let group_id = Uuid::new_v4();
+        assert_eq!(
+            AdminCommand::parse(&args(&["group", &group_id.to_string()])).unwrap(),
+            AdminCommand::InspectGroup {
+                group_id,
+                ear_key: None
+            }
+        );
+        assert_eq!(
+            AdminCommand::parse(&args(&[
+                "group",
+                &group_id.to_string(),
+                EAR_KEY_FILE_FLAG,
+                STDIN_PATH
+            ]))
+            .unwrap(),
+            AdminCommand::InspectGroup {
+                group_id,
+                ear_key: Some(EarKeySource::Stdin)
+            }
+        );
+        assert_eq!(
+            AdminCommand::parse(&args(&[
+                "group",
+                &group_id.to_string(),
+                EAR_KEY_FILE_FLAG,
+                "key.txt"
+            ]))
+            .unwrap(),
+            AdminCommand::InspectGroup {
+                group_id,
+                ear_key: Some(EarKeySource::File("key.txt".into()))
+            }
+        );
+        assert_eq!(
+            AdminCommand::parse(&args(&["stuck-queues"])).unwrap(),
+            AdminCommand::StuckQueues {
+                inactive_days: DEFAULT_INACTIVE_DAYS
+            }
+        );
+        assert_eq!(
+            AdminCommand::parse(&args(&["repair", "expired-group", DRY_RUN_FLAG])).unwrap(),
+            AdminCommand::Repair {
+                kind: IssueKind::ExpiredGroup,
+                dry_run: true
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        let key = general_purpose::STANDARD.encode([7u8; 32]);
+        for invalid in [
+            &[][..],
+            &["unknown"],
+            &["purge", "not-a-uuid"],
+            &["repair", "everything"],
+            // Keys on the command line show up in process listings
+            &[
+                "group",
+                "00000000-0000-0000-0000-000000000000",
+                EAR_KEY_FLAG,
+                key.as_str(),
+            ],
+        ] {
+            assert!(matches!(
+                AdminCommand::parse(&args(invalid)),
+                Err(AdminCliError::Usage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn ear_key_is_read_from_file() {
+        let path = std::env::temp_dir().join(format!("phnx-admin-ear-key-{}", std::process::id()));
+        let source = EarKeySource::File(path.clone());
+        assert!(matches!(source.read(), Err(AdminCliError::EarKeyFile(_))));
+
+        std::fs::write(&path, general_purpose::STANDARD.encode([7u8; 32]) + "\n").unwrap();
+        assert_eq!(source.read().unwrap(), [7u8; 32]);
+        std::fs::write(&path, "c2hvcnQ=").unwrap();
+        assert!(matches!(source.read(), Err(AdminCliError::Usage(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Declarative per-endpoint authorization policy.
+//!
+//! Every route the server exposes must have an entry in [`ENDPOINT_POLICIES`].
+//! The [`enforce_endpoint_policy`] middleware runs before any handler and
+//! rejects requests for which no policy exists, so that a new endpoint can't
+//! be reached until its required authentication has been declared here.
+//!
+//! Most of the actual credential checks happen inside the backend services,
+//! because clients authenticate individual messages rather than connections.
+//! The policy table documents which mechanism each endpoint relies on and
+//! enforces the parts that are visible at the HTTP layer.
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::{ErrorBadRequest, ErrorNotFound, ErrorUnauthorized},
+    http::{header::AUTHORIZATION, Method},
+    middleware::Next,
+    Error,
+};
+use phnxtypes::endpoint_paths::{
+    ENDPOINT_ADMIN_LOG_FILTER, ENDPOINT_ADMIN_METRICS, ENDPOINT_AS, ENDPOINT_AS_ANNOUNCEMENTS,
+    ENDPOINT_DS_GROUPS, ENDPOINT_HEALTH_CHECK, ENDPOINT_QS, ENDPOINT_QS_FEDERATION,
+    ENDPOINT_QS_KEY_FINGERPRINT, ENDPOINT_QS_WS,
+};
+
+/// Name of the header carrying the signed parameters of a QS websocket
+/// connection.
+pub const QS_OPEN_WS_PARAMS_HEADER: &str = "QsOpenWsParams";
+
+/// The authentication an endpoint requires before its handler may run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointAuth {
+    /// The endpoint is publicly reachable.
+    None,
+    /// Each message carries a client signature (or an AS-specific auth
+    /// method) that the backend verifies before processing it.
+    ClientSignature,
+    /// Messages are authenticated via the DS sender variants, i.e. a leaf
+    /// signature, a user key signature or the group state EAR key.
+    GroupMember,
+    /// Messages originate from a federated QS and are verified against the
+    /// peer's published verifying key.
+    FederationPeer,
+    /// The connection is opened with parameters in the
+    /// [`QS_OPEN_WS_PARAMS_HEADER`] header, signed by the queue owner or
+    /// carrying a resumption ticket issued by the QS.
+    QueueOwner,
+    /// Operator endpoint that requires the bearer token configured in the
+    /// admin settings in the `Authorization` header.
+    AdminToken,
+}
+
+/// Authorization policy of a single endpoint.
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointPolicy {
+    pub path: &'static str,
+    pub method: PolicyMethod,
+    pub auth: EndpointAuth,
+}
+
+/// HTTP method an endpoint is served under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolicyMethod {
+    Get,
+    Post,
+}
+
+impl PolicyMethod {
+    fn matches(&self, method: &Method) -> bool {
+        match self {
+            PolicyMethod::Get => method == Method::GET,
+            PolicyMethod::Post => method == Method::POST,
+        }
+    }
+}
+
+/// The authorization policy of every endpoint served by [`crate::run`].
+pub const ENDPOINT_POLICIES: &[EndpointPolicy] = &[
+    EndpointPolicy {
+        path: ENDPOINT_HEALTH_CHECK,
+        method: PolicyMethod::Get,
+        auth: EndpointAuth::None,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_DS_GROUPS,
+        method: PolicyMethod::Post,
+        auth: EndpointAuth::GroupMember,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_QS,
+        method: PolicyMethod::Post,
+        auth: EndpointAuth::ClientSignature,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_QS_FEDERATION,
+        method: PolicyMethod::Post,
+        auth: EndpointAuth::FederationPeer,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_QS_KEY_FINGERPRINT,
+        method: PolicyMethod::Get,
+        auth: EndpointAuth::None,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_AS,
+        method: PolicyMethod::Post,
+        auth: EndpointAuth::ClientSignature,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_AS_ANNOUNCEMENTS,
+        method: PolicyMethod::Get,
+        auth: EndpointAuth::None,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_QS_WS,
+        method: PolicyMethod::Get,
+        auth: EndpointAuth::QueueOwner,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_ADMIN_LOG_FILTER,
+        method: PolicyMethod::Get,
+        auth: EndpointAuth::AdminToken,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_ADMIN_LOG_FILTER,
+        method: PolicyMethod::Post,
+        auth: EndpointAuth::AdminToken,
+    },
+    EndpointPolicy {
+        path: ENDPOINT_ADMIN_METRICS,
+        method: PolicyMethod::Get,
+        auth: EndpointAuth::AdminToken,
+    },
+];
+
+/// Returns the policy for the given path and method, if any.
+pub fn endpoint_policy(path: &str, method: &Method) -> Option<&'static EndpointPolicy> {
+    ENDPOINT_POLICIES
+        .iter()
+        .find(|policy| policy.path == path && policy.method.matches(method))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AuthorizationError {
+    UnknownEndpoint,
+    MissingWsParams,
+    MissingAdminToken,
+}
+
+fn authorize(
+    path: &str,
+    method: &Method,
+    has_ws_params: bool,
+    has_authorization: bool,
+) -> Result<EndpointAuth, AuthorizationError> {
+    let policy = endpoint_policy(path, method).ok_or(AuthorizationError::UnknownEndpoint)?;
+    match policy.auth {
+        EndpointAuth::QueueOwner if !has_ws_params => Err(AuthorizationError::MissingWsParams),
+        // The token itself is checked by the admin endpoints.
+        EndpointAuth::AdminToken if !has_authorization => {
+            Err(AuthorizationError::MissingAdminToken)
+        }
+        auth => Ok(auth),
+    }
+}
+
+/// Middleware that rejects requests to endpoints without a declared policy
+/// and enforces the HTTP-level part of the policy of all other endpoints.
+pub async fn enforce_endpoint_policy(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let has_ws_params = req.headers().contains_key(QS_OPEN_WS_PARAMS_HEADER);
+    let has_authorization = req.headers().contains_key(AUTHORIZATION);
+    match authorize(req.path(), req.method(), has_ws_params, has_authorization) {
+        Ok(auth) => {
+            tracing::trace!(path = req.path(), ?auth, "Endpoint policy satisfied");
+            next.call(req).await
+        }
+        Err(AuthorizationError::UnknownEndpoint) => {
+            tracing::warn!(
+                path = req.path(),
+                method = %req.method(),
+                "Rejected request to endpoint without policy"
+            );
+            Err(ErrorNotFound("Unknown endpoint"))
+        }
+        Err(AuthorizationError::MissingWsParams) => {
+            Err(ErrorBadRequest("No QsOpenWsParams header"))
+        }
+        Err(AuthorizationError::MissingAdminToken) => {
+            Err(ErrorUnauthorized("No Authorization header"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use actix_web::{
+        http::StatusCode,
+        middleware::from_fn,
+        test::{self, TestRequest},
+        App,
+    };
+
+    use crate::{
+        endpoints::qs::push_notification_provider::ProductionPushNotificationProvider,
+        enqueue_provider::SimpleEnqueueProvider, network_provider::MockNetworkProvider,
+    };
+
+    use super::*;
+
+    type TestQsConnector =
+        SimpleEnqueueProvider<MockNetworkProvider, ProductionPushNotificationProvider>;
+
+    /// The routes of [`crate::run`] behind the policy middleware. The services
+    /// are not configured, so requests that reach a handler fail with an
+    /// internal server error, while requests to unregistered routes that pass
+    /// the middleware get a 404.
+    macro_rules! policy_app {
+        () => {
+            test::init_service(
+                App::new()
+                    .wrap(from_fn(enforce_endpoint_policy))
+                    .configure(crate::global_routes)
+                    .configure(crate::tenant_routes::<TestQsConnector, MockNetworkProvider>),
+            )
+            .await
+        };
+    }
+
+    /// The status of the response to the request, also if the middleware
+    /// rejected it.
+    async fn status<S, R, B>(app: &S, request: R) -> StatusCode
+    where
+        S: actix_web::dev::Service<R, Response = ServiceResponse<B>, Error = Error>,
+    {
+        match test::try_call_service(app, request).await {
+            Ok(response) => response.status(),
+            Err(error) => error.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn every_policy_has_a_route() {
+        let app = policy_app!();
+        for policy in ENDPOINT_POLICIES {
+            let request = match policy.method {
+                PolicyMethod::Get => TestRequest::get(),
+                PolicyMethod::Post => TestRequest::post(),
+            }
+            .uri(policy.path)
+            .insert_header((QS_OPEN_WS_PARAMS_HEADER, "params"))
+            .insert_header((AUTHORIZATION, "Bearer token"));
+            assert_ne!(
+                status(&app, request.to_request()).await,
+                StatusCode::NOT_FOUND,
+                "no route for {:?} {}",
+                policy.method,
+                policy.path
+            );
+        }
+    }
+
+    #[test]
+    fn policies_are_unique() {
+        let mut seen = HashSet::new();
+        for policy in ENDPOINT_POLICIES {
+            assert!(
+                seen.insert((policy.path, policy.method)),
+                "duplicate policy for {:?} {}",
+                policy.method,
+                policy.path
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn unknown_endpoints_are_denied() {
+        let app = policy_app!();
+        let request = TestRequest::get().uri("/admin");
+        assert_eq!(
+            status(&app, request.to_request()).await,
+            StatusCode::NOT_FOUND
+        );
+        // Registered paths are denied for methods without a policy
+        let request = TestRequest::get().uri(ENDPOINT_AS);
+        assert_eq!(
+            status(&app, request.to_request()).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    async fn websocket_requires_params() {
+        let app = policy_app!();
+        let request = TestRequest::get().uri(ENDPOINT_QS_WS);
+        assert_eq!(
+            status(&app, request.to_request()).await,
+            StatusCode::BAD_REQUEST
+        );
+        let request = TestRequest::get()
+            .uri(ENDPOINT_QS_WS)
+            .insert_header((QS_OPEN_WS_PARAMS_HEADER, "params"));
+        assert_eq!(
+            status(&app, request.to_request()).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_rt::test]
+    async fn admin_endpoints_require_authorization() {
+        let app = policy_app!();
+        let request = TestRequest::post().uri(ENDPOINT_ADMIN_LOG_FILTER);
+        assert_eq!(
+            status(&app, request.to_request()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let request = TestRequest::post()
+            .uri(ENDPOINT_ADMIN_LOG_FILTER)
+            .insert_header((AUTHORIZATION, "Bearer token"));
+        assert_eq!(
+            status(&app, request.to_request()).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
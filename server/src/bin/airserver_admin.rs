@@ -0,0 +1,14 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Operator CLI to inspect and repair the databases of the server. Run
+//! without arguments to show the available commands.
+
+#[actix_web::main]
+async fn main() {
+    if let Err(e) = phnxserver::admin_cli::run_admin_cli().await {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
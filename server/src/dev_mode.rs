@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Single-binary development mode.
+//!
+//! Started with `cargo run -p phnxserver --features dev -- --dev`. Instead of
+//! connecting to an external Postgres server, the server downloads and runs
+//! an embedded Postgres instance and serves on `localhost`, such that app
+//! developers get a working backend without Docker.
+//!
+//! The server doesn't terminate TLS itself and clients connect via plain HTTP
+//! by default, so no certificates are needed in this mode.
+
+use phnxbackend::settings::Settings;
+use postgresql_embedded::PostgreSQL;
+use thiserror::Error;
+
+/// Command line flag that enables the dev mode.
+pub const DEV_MODE_FLAG: &str = "--dev";
+
+const DEV_DOMAIN: &str = "localhost";
+const DEV_HOST: &str = "127.0.0.1";
+
+#[derive(Debug, Error)]
+pub enum DevModeError {
+    #[error("Embedded Postgres error: {0}")]
+    Postgres(#[from] postgresql_embedded::Error),
+}
+
+/// Embedded Postgres instance. The instance is stopped when this is dropped.
+pub struct DevDatabase {
+    _postgresql: PostgreSQL,
+}
+
+/// Returns true if the server was started with the dev mode flag.
+pub fn dev_mode_requested() -> bool {
+    std::env::args().any(|arg| arg == DEV_MODE_FLAG)
+}
+
+/// Start an embedded Postgres instance and point the given configuration at
+/// it. Also configures the server to serve `localhost`.
+pub async fn start_dev_mode(configuration: &mut Settings) -> Result<DevDatabase, DevModeError> {
+    let mut postgresql = PostgreSQL::default();
+    postgresql.setup().await?;
+    postgresql.start().await?;
+
+    let postgres_settings = postgresql.settings();
+    configuration.database.host = postgres_settings.host.clone();
+    configuration.database.port = postgres_settings.port;
+    configuration.database.username = postgres_settings.username.clone();
+    configuration.database.password = postgres_settings.password.clone();
+    configuration.database.cacertpath = None;
+
+    configuration.application.domain = DEV_DOMAIN.to_owned();
+    configuration.application.host = DEV_HOST.to_owned();
+    // Push notifications require credentials that developers usually don't
+    // have.
+    configuration.apns = None;
+    configuration.fcm = None;
+
+    Ok(DevDatabase {
+        _postgresql: postgresql,
+    })
+}
+
+/// The server URL to paste into the client.
+pub fn dev_client_url(configuration: &Settings) -> String {
+    format!("http://{}:{}", DEV_DOMAIN, configuration.application.port)
+}
@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Endpoints for operators of the server.
+//!
+//! Admin endpoints are authenticated with the bearer token configured in
+//! [`AdminSettings`]. Without a configured token, they are disabled.
+
+use actix_web::{
+    http::header::AUTHORIZATION,
+    web::{self, Data},
+    HttpRequest, HttpResponse, Responder,
+};
+use phnxbackend::{
+    ds::{Ds, SpamThrottleMetricsSnapshot, WelcomeInfoCacheMetricsSnapshot},
+    settings::AdminSettings,
+};
+use serde::Serialize;
+
+use crate::{
+    endpoints::qs::ws::{DispatchMetricsSnapshot, DispatchWebsocketNotifier},
+    telemetry::{LogFilterError, LogFilterHandle},
+};
+
+/// State of the admin endpoints.
+#[derive(Clone, Default)]
+pub struct Admin {
+    token: Option<String>,
+    log_filter: Option<LogFilterHandle>,
+}
+
+impl Admin {
+    pub fn new(settings: AdminSettings) -> Self {
+        Self {
+            token: settings.token.filter(|token| !token.is_empty()),
+            log_filter: None,
+        }
+    }
+
+    /// Allow changing the given log filter via the admin endpoint.
+    pub fn with_log_filter(mut self, log_filter: LogFilterHandle) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    /// Checks the bearer token of the request. Returns the response to send
+    /// if the request is not authorized.
+    fn authorize(&self, request: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(token) = &self.token else {
+            return Err(HttpResponse::NotFound().finish());
+        };
+        let presented = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => {
+                tracing::warn!("Rejected admin request with invalid token");
+                Err(HttpResponse::Unauthorized().finish())
+            }
+        }
+    }
+}
+
+/// Compares the two byte strings in time independent of their content.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The directives of the current log filter, as plain text.
+#[tracing::instrument(name = "Get log filter", skip_all)]
+pub(crate) async fn get_log_filter(request: HttpRequest, admin: Data<Admin>) -> impl Responder {
+    if let Err(response) = admin.authorize(&request) {
+        return response;
+    }
+    let Some(log_filter) = &admin.log_filter else {
+        return HttpResponse::NotFound().finish();
+    };
+    match log_filter.current() {
+        Ok(directives) => HttpResponse::Ok().body(directives),
+        Err(e) => {
+            tracing::error!("Failed to read log filter: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Replace the log filter with the directives in the plain text body, e.g.
+/// `info,phnxbackend::qs=debug`. The change lasts until the server restarts.
+#[tracing::instrument(name = "Set log filter", skip_all)]
+pub(crate) async fn set_log_filter(
+    request: HttpRequest,
+    admin: Data<Admin>,
+    directives: web::Bytes,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&request) {
+        return response;
+    }
+    let Some(log_filter) = &admin.log_filter else {
+        return HttpResponse::NotFound().finish();
+    };
+    let Ok(directives) = std::str::from_utf8(&directives) else {
+        return HttpResponse::BadRequest().body("Log filter is not valid UTF-8");
+    };
+    match log_filter.set(directives.trim()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e @ LogFilterError::InvalidFilter(_)) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e) => {
+            tracing::error!("Failed to set log filter: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Metrics for tuning the limits and caches of the server.
+#[derive(Debug, Serialize)]
+struct Metrics {
+    /// The websocket dispatch is shared by all domains.
+    dispatch: DispatchMetricsSnapshot,
+    spam_throttle: SpamThrottleMetricsSnapshot,
+    welcome_info_cache: WelcomeInfoCacheMetricsSnapshot,
+}
+
+/// The metrics of the server and of the domain of the request, as JSON.
+#[tracing::instrument(name = "Get metrics", skip_all)]
+pub(crate) async fn get_metrics(
+    request: HttpRequest,
+    admin: Data<Admin>,
+    ds: Data<Ds>,
+    ws_dispatch_notifier: Data<DispatchWebsocketNotifier>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&request) {
+        return response;
+    }
+    let dispatch = match ws_dispatch_notifier.metrics().await {
+        Ok(dispatch) => dispatch,
+        Err(e) => {
+            tracing::error!("Failed to read dispatch metrics: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    HttpResponse::Ok().json(Metrics {
+        dispatch,
+        spam_throttle: ds.spam_throttle_metrics().await,
+        welcome_info_cache: ds.welcome_info_cache_metrics().await,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn admin_token_is_checked() {
+        let admin = Admin::new(AdminSettings {
+            token: Some("secret".to_owned()),
+        });
+        let request = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert!(admin.authorize(&request).is_ok());
+
+        let request = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer secre"))
+            .to_http_request();
+        assert!(admin.authorize(&request).is_err());
+        let request = TestRequest::default().to_http_request();
+        assert!(admin.authorize(&request).is_err());
+
+        // Without a token, the endpoints are disabled.
+        let admin = Admin::new(AdminSettings { token: None });
+        let request = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer "))
+            .to_http_request();
+        assert!(admin.authorize(&request).is_err());
+    }
+}
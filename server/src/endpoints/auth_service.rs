@@ -2,8 +2,13 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use actix_web::web::{self, Data};
+use actix_web::{
+    http::header::{ETAG, IF_NONE_MATCH},
+    web::{self, Data},
+    HttpRequest,
+};
 use phnxbackend::auth_service::{AuthService, VerifiableClientToAsMessage};
+use phnxtypes::messages::announcements::announcements_etag;
 use tls_codec::{DeserializeBytes, Serialize};
 
 use super::*;
@@ -11,18 +16,26 @@ use super::*;
 /// DS endpoint for all group-based functionalities.
 #[tracing::instrument(name = "Perform AS operation", skip_all)]
 pub(crate) async fn as_process_message(
+    request: HttpRequest,
     message: web::Bytes,
     auth_service: Data<AuthService>,
 ) -> impl Responder {
     // Create a new group on the DS.
     let message = match VerifiableClientToAsMessage::tls_deserialize_exact_bytes(&message) {
         Ok(message) => message,
-        Err(e) => {
-            tracing::warn!("Received invalid message: {:?}", e);
-            return HttpResponse::BadRequest().body(e.to_string());
-        }
+        Err(e) => return undecodable_request(e),
     };
-    match auth_service.process(message).await {
+    // Outdated clients can still find out which version they need.
+    if !message.is_server_info_request() {
+        if let Some(response) = reject_outdated_client(&request, auth_service.min_client_version())
+        {
+            return response;
+        }
+    }
+    match auth_service
+        .process(message, client_version(&request))
+        .await
+    {
         // If the message was processed successfully, return the response.
         Ok(response) => {
             tracing::trace!("Processed message successfully");
@@ -35,3 +48,35 @@ pub(crate) async fn as_process_message(
         }
     }
 }
+
+/// Announcements of the operator. Returns `304 Not Modified` if the
+/// `If-None-Match` header matches the current announcements.
+#[tracing::instrument(name = "Get AS announcements", skip_all)]
+pub(crate) async fn as_announcements(
+    request: HttpRequest,
+    auth_service: Data<AuthService>,
+) -> impl Responder {
+    let body = match auth_service
+        .current_announcements()
+        .tls_serialize_detached()
+    {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to serialize announcements: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let etag = announcements_etag(&body);
+    let not_modified = request
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+    if not_modified {
+        HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish()
+    } else {
+        HttpResponse::Ok().insert_header((ETAG, etag)).body(body)
+    }
+}
@@ -3,38 +3,84 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use actix_web::{
+    http::header,
     web::{self, Data},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder,
+};
+use phnxbackend::{auth_service::AuthService, ds::Ds, qs::QsConnector};
+use phnxtypes::{
+    errors::{DsProcessingError, GroupUpgradeError, RoomPolicyUpdateError},
+    messages::client_ds::DsMessageTypeIn,
 };
-use phnxbackend::{ds::Ds, qs::QsConnector};
-use phnxtypes::messages::client_ds::DsMessageTypeIn;
 use tls_codec::{DeserializeBytes, Serialize};
 
+use super::{client_version, reject_outdated_client, undecodable_request};
+
 /// DS endpoint for all group-based functionalities.
 #[tracing::instrument(name = "Perform DS operation", skip_all)]
 pub(crate) async fn ds_process_message<Qep: QsConnector>(
+    request: HttpRequest,
     message: web::Bytes,
     ds_storage_provider: Data<Ds>,
     qs_connector: Data<Qep>,
+    auth_service: Data<AuthService>,
 ) -> impl Responder {
+    if let Some(response) = reject_outdated_client(&request, auth_service.min_client_version()) {
+        return response;
+    }
     // Extract the storage provider.
     let storage_provider = ds_storage_provider.get_ref();
     let qs_connector = qs_connector.get_ref();
     // Create a new group on the DS.
     let message = match DsMessageTypeIn::tls_deserialize_exact_bytes(&message) {
         Ok(message) => message,
-        Err(e) => {
-            tracing::warn!("Received invalid message: {:?}", e);
-            return HttpResponse::BadRequest().body(e.to_string());
-        }
+        Err(e) => return undecodable_request(e),
     };
-    match Ds::process(storage_provider, qs_connector, message).await {
+    match Ds::process(
+        storage_provider,
+        qs_connector,
+        message,
+        client_version(&request),
+    )
+    .await
+    {
         // If the message was processed successfully, return the response.
         Ok(response) => {
             tracing::trace!("Processed message successfully");
             let serialized_response = response.tls_serialize_detached().unwrap();
             HttpResponse::Ok().body(serialized_response)
         }
+        // Tell rate limited senders when they can send again.
+        Err(DsProcessingError::RateLimited(retry_after)) => {
+            tracing::debug!(?retry_after, "Sender is rate limited");
+            let retry_after_secs = retry_after.as_secs_f64().ceil() as u64;
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after_secs.max(1)))
+                .body(DsProcessingError::RateLimited(retry_after).to_string())
+        }
+        Err(e @ DsProcessingError::SenderThrottled) => {
+            tracing::debug!("Sender is throttled");
+            HttpResponse::TooManyRequests().body(e.to_string())
+        }
+        // Tell senders that are behind to catch up before retrying.
+        Err(e @ DsProcessingError::StaleEpoch) => {
+            tracing::debug!("Message was sent in an outdated epoch");
+            HttpResponse::Conflict().body(e.to_string())
+        }
+        // Tell senders that the group moved to its successor.
+        Err(e @ DsProcessingError::GroupUpgraded) => {
+            tracing::debug!("Group was upgraded");
+            HttpResponse::Gone().body(e.to_string())
+        }
+        Err(
+            e @ (DsProcessingError::SendingNotAllowed
+            | DsProcessingError::CommittingNotAllowed
+            | DsProcessingError::RoomPolicyUpdateError(RoomPolicyUpdateError::NotAdmin)
+            | DsProcessingError::GroupUpgradeError(GroupUpgradeError::NotAdmin)),
+        ) => {
+            tracing::debug!("Room policy violation: {}", e);
+            HttpResponse::Forbidden().body(e.to_string())
+        }
         // If the message could not be processed, return an error.
         Err(e) => {
             tracing::warn!("DS failed to process message: {:?}", e);
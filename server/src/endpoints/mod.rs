@@ -2,12 +2,105 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use actix_web::{HttpResponse, Responder};
+use actix_web::{web::Data, HttpRequest, HttpResponse, Responder};
+use phnxtypes::messages::server_info::{ClientVersion, CLIENT_VERSION_HEADER};
 
+use self::qs::ws::DispatchWebsocketNotifier;
+
+pub mod admin;
 pub mod auth_service;
 pub(crate) mod ds;
 pub mod qs;
 
-pub(crate) async fn health_check() -> impl Responder {
-    HttpResponse::Ok()
+/// Healthy unless the replica lost the connection over which it receives the
+/// notifications of other replicas. The health of that connection is returned
+/// in the body, if the replicas share notifications via Postgres.
+pub(crate) async fn health_check(
+    ws_dispatch_notifier: Data<DispatchWebsocketNotifier>,
+) -> impl Responder {
+    match ws_dispatch_notifier.cluster_health() {
+        Some(health) if health.connected => HttpResponse::Ok().json(health),
+        Some(health) => HttpResponse::ServiceUnavailable().json(health),
+        None => HttpResponse::Ok().finish(),
+    }
+}
+
+/// The version the client announced in the [`CLIENT_VERSION_HEADER`], if
+/// any.
+pub(crate) fn client_version(request: &HttpRequest) -> Option<ClientVersion> {
+    request
+        .headers()
+        .get(CLIENT_VERSION_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Returns `426 Upgrade Required` if the request comes from a client older
+/// than the oldest version the server serves. Clients that don't send their
+/// version are served.
+pub(crate) fn reject_outdated_client(
+    request: &HttpRequest,
+    min_client_version: Option<ClientVersion>,
+) -> Option<HttpResponse> {
+    let min_client_version = min_client_version?;
+    let client_version = client_version(request)?;
+    (client_version < min_client_version).then(|| {
+        tracing::debug!(%client_version, %min_client_version, "Rejecting outdated client");
+        HttpResponse::UpgradeRequired().body(format!(
+            "Clients older than {min_client_version} must be upgraded"
+        ))
+    })
+}
+
+/// Response to a request that couldn't be decoded. Requests with an unknown
+/// type or protocol version come from clients the server doesn't serve and
+/// are answered with `501 Not Implemented`.
+pub(crate) fn undecodable_request(error: tls_codec::Error) -> HttpResponse {
+    tracing::warn!("Received invalid message: {:?}", error);
+    match error {
+        tls_codec::Error::UnknownValue(_) => HttpResponse::NotImplemented().body(error.to_string()),
+        _ => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+    use phnxbackend::auth_service::VerifiableClientToAsMessage;
+    use tls_codec::DeserializeBytes;
+
+    use super::*;
+
+    #[test]
+    fn outdated_clients_are_rejected() {
+        let min_version = Some(ClientVersion::new(0, 2, 0));
+        let request = |version: &str| {
+            TestRequest::default()
+                .insert_header((CLIENT_VERSION_HEADER, version))
+                .to_http_request()
+        };
+
+        let response = reject_outdated_client(&request("0.1.9"), min_version).unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert!(reject_outdated_client(&request("0.2.0"), min_version).is_none());
+        assert!(reject_outdated_client(&request("0.1.9"), None).is_none());
+        let request = TestRequest::default().to_http_request();
+        assert!(reject_outdated_client(&request, min_version).is_none());
+    }
+
+    #[test]
+    fn unknown_requests_are_not_implemented() {
+        // Known protocol version, unknown request type
+        let error =
+            VerifiableClientToAsMessage::tls_deserialize_exact_bytes(&[0, 0xff]).unwrap_err();
+        assert_eq!(
+            undecodable_request(error).status(),
+            StatusCode::NOT_IMPLEMENTED
+        );
+
+        let error = VerifiableClientToAsMessage::tls_deserialize_exact_bytes(&[0]).unwrap_err();
+        assert_eq!(undecodable_request(error).status(), StatusCode::BAD_REQUEST);
+    }
 }
@@ -4,7 +4,7 @@
 
 use actix_web::{
     web::{self, Data},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder,
 };
 use phnxbackend::{
     messages::qs_qs::QsToQsMessage,
@@ -14,12 +14,21 @@ use phnxtypes::{
     errors::qs::QsVerifyingKeyError, messages::client_qs::VerifiableClientToQsMessage,
 };
 use tls_codec::{DeserializeBytes, Serialize};
+use ws::DispatchWebsocketNotifier;
+
+use super::client_version;
 
 pub mod push_notification_provider;
 pub mod ws;
 
 #[tracing::instrument(name = "Process QS message", skip_all)]
-pub(crate) async fn qs_process_message(qs: Data<Qs>, message: web::Bytes) -> impl Responder {
+pub(crate) async fn qs_process_message<Qc: QsConnector>(
+    request: HttpRequest,
+    qs: Data<Qs>,
+    ws_dispatch_notifier: Data<DispatchWebsocketNotifier>,
+    qs_connector: Data<Qc>,
+    message: web::Bytes,
+) -> impl Responder {
     // Extract the storage provider.
 
     // Deserialize the message.
@@ -32,7 +41,16 @@ pub(crate) async fn qs_process_message(qs: Data<Qs>, message: web::Bytes) -> imp
     };
 
     // Process the message.
-    match qs.process(message).await {
+    let client_version = client_version(&request);
+    match qs
+        .process(
+            ws_dispatch_notifier.get_ref(),
+            qs_connector.get_ref(),
+            message,
+            client_version,
+        )
+        .await
+    {
         // If the message was processed successfully, return the response.
         Ok(response) => {
             tracing::trace!("Processed message successfully");
@@ -81,3 +99,16 @@ pub(crate) async fn qs_process_federated_message<
         }
     }
 }
+
+#[tracing::instrument(name = "Serve QS key fingerprint", skip_all)]
+pub(crate) async fn qs_key_fingerprint(qs: Data<Qs>) -> impl Responder {
+    match qs.federation_key_record().await {
+        Ok(record) => HttpResponse::Ok()
+            .content_type("text/plain")
+            .body(record.to_string()),
+        Err(e) => {
+            tracing::warn!("QS failed to load its key fingerprint: {:?}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
@@ -8,7 +8,10 @@ use phnxbackend::{
     qs::{PushNotificationError, PushNotificationProvider},
     settings::{ApnsSettings, FcmSettings},
 };
-use phnxtypes::messages::push_token::{PushToken, PushTokenOperator};
+use phnxtypes::{
+    crypto::secrets::SecretBytes,
+    messages::push_token::{PushToken, PushTokenOperator},
+};
 use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -87,7 +90,7 @@ struct FcmState {
 pub struct ApnsState {
     pub key_id: String,
     pub team_id: String,
-    pub private_key: Vec<u8>,
+    pub private_key: SecretBytes,
     token: Arc<Mutex<Option<ApnsToken>>>,
 }
 
@@ -137,13 +140,13 @@ impl ProductionPushNotificationProvider {
         let apns_state = if let Some(apns_settings) = apns_settings {
             // Read the private key
             let mut private_key_file = File::open(&apns_settings.privatekeypath)?;
-            let mut private_key_p8 = String::new();
-            private_key_file.read_to_string(&mut private_key_p8)?;
+            let mut private_key_p8 = Vec::new();
+            private_key_file.read_to_end(&mut private_key_p8)?;
 
             Some(ApnsState {
                 key_id: apns_settings.keyid,
                 team_id: apns_settings.teamid,
-                private_key: private_key_p8.into_bytes(),
+                private_key: SecretBytes::from(private_key_p8),
                 token: Arc::new(Mutex::new(None)),
             })
         } else {
@@ -266,7 +269,7 @@ impl ProductionPushNotificationProvider {
         let token = encode(
             &header,
             &claims,
-            &EncodingKey::from_ec_pem(&apns_state.private_key)?,
+            &EncodingKey::from_ec_pem(apns_state.private_key.expose_secret())?,
         )?;
 
         // Store the JWT and update the last issuance time
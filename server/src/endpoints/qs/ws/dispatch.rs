@@ -3,43 +3,141 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use super::{
-    messages::{Connect, Disconnect, NotifyMessage, NotifyMessageError},
+    messages::{
+        Connect, Disconnect, GetMetrics, NotifyMessage, NotifyMessageError, ResumeRequired,
+    },
     InternalQsWsMessage,
 };
 use actix::{
     prelude::{Actor, Context, Handler, Recipient},
     ResponseFuture,
 };
+use phnxbackend::settings::DispatchSettings;
 use phnxtypes::identifiers::QsClientId;
+use serde::Serialize;
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 enum NotifyClientError {
     ClientNotFound,
+    SlowConsumer,
+}
+
+/// Number of notifications that were sent to a websocket connection, but not
+/// yet handled by it.
+///
+/// The connection actor only handles notifications while its websocket is
+/// writable, so a growing buffer indicates a client that doesn't keep up.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionBuffer(Arc<AtomicUsize>);
+
+impl ConnectionBuffer {
+    fn occupancy(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn push(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by the connection after it handled a notification.
+    pub(crate) fn pop(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    fn is_same_connection(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+struct Session {
+    recipient: Recipient<InternalQsWsMessage>,
+    resume_recipient: Recipient<ResumeRequired>,
+    buffer: ConnectionBuffer,
+}
+
+/// Snapshot of the dispatch metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DispatchMetricsSnapshot {
+    /// Number of currently connected clients
+    pub connections: usize,
+    /// Total number of notifications buffered over all connections
+    pub buffered_notifications: usize,
+    /// Occupancy of the fullest connection buffer
+    pub max_buffer_occupancy: usize,
+    /// Number of connections closed because they didn't keep up since the
+    /// start of the server
+    pub slow_consumer_disconnects: u64,
 }
 
 /// Dispatch for all websocket connections. It keeps a list of all connected
 /// clients and can send messages to them.
+///
+/// Each connection has a bounded buffer. If a connection doesn't keep up with
+/// its notifications, it is dropped from the dispatch and closed with a
+/// resume-required status instead of buffering notifications indefinitely.
 #[derive(Default)]
 pub struct Dispatch {
-    sessions: HashMap<QsClientId, Recipient<InternalQsWsMessage>>,
+    sessions: HashMap<QsClientId, Session>,
+    settings: DispatchSettings,
+    slow_consumer_disconnects: u64,
 }
 
 impl Dispatch {
+    pub fn new(settings: DispatchSettings) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            settings,
+            slow_consumer_disconnects: 0,
+        }
+    }
+
     /// Notifies a connected client by sending a [`QsWsMessage::NewMessage`] to it.
     fn notify_client(
-        &self,
+        &mut self,
         queue_id: &QsClientId,
         message: InternalQsWsMessage,
     ) -> Result<(), NotifyClientError> {
-        if let Some(socket_recipient) = self.sessions.get(queue_id) {
-            socket_recipient.do_send(message);
-            Ok(())
-        } else {
+        let Some(session) = self.sessions.get(queue_id) else {
             // This is only rated "info", because not having a websocket open is
             // not irregular.
             tracing::info!("Failed to notify client via websocket.");
-            Err(NotifyClientError::ClientNotFound)
+            return Err(NotifyClientError::ClientNotFound);
+        };
+        if session.buffer.occupancy() >= self.settings.buffer_size {
+            tracing::warn!(
+                buffer_size = self.settings.buffer_size,
+                "Closing websocket of slow consumer"
+            );
+            if let Some(session) = self.sessions.remove(queue_id) {
+                session.resume_recipient.do_send(ResumeRequired);
+            }
+            self.slow_consumer_disconnects += 1;
+            return Err(NotifyClientError::SlowConsumer);
+        }
+        session.buffer.push();
+        session.recipient.do_send(message);
+        Ok(())
+    }
+
+    fn metrics(&self) -> DispatchMetricsSnapshot {
+        let occupancies = self
+            .sessions
+            .values()
+            .map(|session| session.buffer.occupancy());
+        DispatchMetricsSnapshot {
+            connections: self.sessions.len(),
+            buffered_notifications: occupancies.clone().sum(),
+            max_buffer_occupancy: occupancies.max().unwrap_or(0),
+            slow_consumer_disconnects: self.slow_consumer_disconnects,
         }
     }
 }
@@ -54,7 +152,12 @@ impl Handler<Connect> for Dispatch {
     type Result = ();
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        self.sessions.insert(msg.own_queue_id, msg.addr);
+        let session = Session {
+            recipient: msg.addr,
+            resume_recipient: msg.resume_addr,
+            buffer: msg.buffer,
+        };
+        self.sessions.insert(msg.own_queue_id, session);
     }
 }
 
@@ -63,7 +166,13 @@ impl Handler<Disconnect> for Dispatch {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        if self.sessions.remove(&msg.queue_id).is_some() {}
+        let is_current_connection = self
+            .sessions
+            .get(&msg.queue_id)
+            .is_some_and(|session| session.buffer.is_same_connection(&msg.buffer));
+        if is_current_connection {
+            self.sessions.remove(&msg.queue_id);
+        }
     }
 }
 
@@ -74,7 +183,133 @@ impl Handler<NotifyMessage> for Dispatch {
     fn handle(&mut self, msg: NotifyMessage, _ctx: &mut Context<Self>) -> Self::Result {
         match self.notify_client(&msg.queue_id, msg.payload) {
             Ok(_) => Box::pin(async { Ok(()) }),
-            Err(_) => Box::pin(async { Err(NotifyMessageError::ClientNotFound) }),
+            Err(NotifyClientError::ClientNotFound) => {
+                Box::pin(async { Err(NotifyMessageError::ClientNotFound) })
+            }
+            Err(NotifyClientError::SlowConsumer) => {
+                Box::pin(async { Err(NotifyMessageError::SlowConsumer) })
+            }
+        }
+    }
+}
+
+// Handle GetMetrics messages
+impl Handler<GetMetrics> for Dispatch {
+    type Result = DispatchMetricsSnapshot;
+
+    fn handle(&mut self, _msg: GetMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::{Addr, Message};
+    use phnxtypes::messages::client_ds::QsWsMessage;
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    /// Connection that never handles its notifications, like a connection
+    /// whose websocket isn't writable.
+    #[derive(Default)]
+    struct StalledConnection {
+        resume_required: bool,
+    }
+
+    impl Actor for StalledConnection {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<InternalQsWsMessage> for StalledConnection {
+        type Result = ();
+
+        fn handle(&mut self, _msg: InternalQsWsMessage, _ctx: &mut Context<Self>) {}
+    }
+
+    impl Handler<ResumeRequired> for StalledConnection {
+        type Result = ();
+
+        fn handle(&mut self, _msg: ResumeRequired, _ctx: &mut Context<Self>) {
+            self.resume_required = true;
         }
     }
+
+    #[derive(Message)]
+    #[rtype(result = "bool")]
+    struct IsResumeRequired;
+
+    impl Handler<IsResumeRequired> for StalledConnection {
+        type Result = bool;
+
+        fn handle(&mut self, _msg: IsResumeRequired, _ctx: &mut Context<Self>) -> bool {
+            self.resume_required
+        }
+    }
+
+    async fn notify(dispatch: &Addr<Dispatch>, queue_id: &QsClientId) -> bool {
+        dispatch
+            .send(NotifyMessage {
+                queue_id: queue_id.clone(),
+                payload: QsWsMessage::QueueUpdate.into(),
+            })
+            .await
+            .unwrap()
+            .is_ok()
+    }
+
+    #[actix_rt::test]
+    async fn slow_consumer_is_disconnected() {
+        let dispatch = Dispatch::new(DispatchSettings {
+            buffer_size: 2,
+            ..Default::default()
+        })
+        .start();
+        let connection = StalledConnection::default().start();
+        let queue_id = QsClientId::random(&mut OsRng);
+        let buffer = ConnectionBuffer::default();
+        dispatch
+            .send(Connect {
+                addr: connection.clone().recipient(),
+                resume_addr: connection.clone().recipient(),
+                own_queue_id: queue_id.clone(),
+                buffer: buffer.clone(),
+            })
+            .await
+            .unwrap();
+
+        assert!(notify(&dispatch, &queue_id).await);
+        assert!(notify(&dispatch, &queue_id).await);
+        let metrics = dispatch.send(GetMetrics).await.unwrap();
+        assert_eq!(metrics.connections, 1);
+        assert_eq!(metrics.buffered_notifications, 2);
+        assert_eq!(metrics.max_buffer_occupancy, 2);
+
+        // The buffer is full
+        assert!(!notify(&dispatch, &queue_id).await);
+        assert!(connection.send(IsResumeRequired).await.unwrap());
+        let metrics = dispatch.send(GetMetrics).await.unwrap();
+        assert_eq!(metrics.connections, 0);
+        assert_eq!(metrics.slow_consumer_disconnects, 1);
+
+        // A stale disconnect doesn't unregister a new connection
+        let new_buffer = ConnectionBuffer::default();
+        dispatch
+            .send(Connect {
+                addr: connection.clone().recipient(),
+                resume_addr: connection.clone().recipient(),
+                own_queue_id: queue_id.clone(),
+                buffer: new_buffer,
+            })
+            .await
+            .unwrap();
+        dispatch
+            .send(Disconnect {
+                queue_id: queue_id.clone(),
+                buffer,
+            })
+            .await
+            .unwrap();
+        assert!(notify(&dispatch, &queue_id).await);
+    }
 }
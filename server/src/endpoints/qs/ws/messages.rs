@@ -5,14 +5,19 @@
 use actix::prelude::{Message, Recipient};
 use phnxtypes::identifiers::QsClientId;
 
-use super::InternalQsWsMessage;
+use super::{
+    dispatch::{ConnectionBuffer, DispatchMetricsSnapshot},
+    InternalQsWsMessage,
+};
 
 /// Connect message for the [`Dispatch`] actor.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub addr: Recipient<InternalQsWsMessage>,
+    pub resume_addr: Recipient<ResumeRequired>,
     pub own_queue_id: QsClientId,
+    pub buffer: ConnectionBuffer,
 }
 
 /// Disconnect message for the [`Dispatch`] actor.
@@ -20,11 +25,15 @@ pub struct Connect {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub queue_id: QsClientId,
+    /// Identifies the connection, such that a stale connection doesn't
+    /// unregister a newer connection of the same client.
+    pub buffer: ConnectionBuffer,
 }
 
 #[derive(Debug)]
 pub enum NotifyMessageError {
     ClientNotFound,
+    SlowConsumer,
 }
 
 /// Notify message for the [`Dispatch`] actor. This message has a custom return
@@ -35,3 +44,15 @@ pub struct NotifyMessage {
     pub queue_id: QsClientId,
     pub payload: InternalQsWsMessage,
 }
+
+/// Requests a snapshot of the metrics of the [`Dispatch`] actor.
+#[derive(Message)]
+#[rtype(result = "DispatchMetricsSnapshot")]
+pub struct GetMetrics;
+
+/// Sent to a websocket connection that didn't keep up with its notifications.
+/// The connection is closed and the client has to resume by fetching its
+/// queue.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ResumeRequired;
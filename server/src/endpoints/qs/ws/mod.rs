@@ -5,27 +5,45 @@
 pub(crate) mod dispatch;
 pub(crate) mod messages;
 
+pub use dispatch::DispatchMetricsSnapshot;
+
+use std::sync::Arc;
+
 use actix::{
     clock::Instant, fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext,
     ContextFutureSpawner, Handler, Message, Running, StreamHandler, WrapFuture,
 };
 use actix_web::{
+    http::header::{HeaderName, HeaderValue},
     web::{self, Data},
     HttpRequest, HttpResponse, Responder,
 };
-use actix_web_actors::ws::{self};
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
 use async_trait::*;
 use base64::{engine::general_purpose, Engine as _};
 use dispatch::*;
 use messages::*;
-use phnxbackend::qs::{WebsocketNotifier, WebsocketNotifierError, WsNotification};
+use phnxbackend::{
+    pg_listen::PgListenHealth,
+    qs::{
+        cluster::{ClusterChannel, ClusterNotification},
+        listen_auth::QsListenAuthError,
+        Qs, WebsocketNotifier, WebsocketNotifierError, WsNotification,
+    },
+    settings::DispatchSettings,
+};
 use phnxtypes::{
     codec::PhnxCodec,
     identifiers::QsClientId,
-    messages::{client_ds::QsWsMessage, client_qs::QsOpenWsParams},
+    messages::{
+        client_ds::QsWsMessage,
+        client_qs::{QsOpenWsParams, QS_RESUMPTION_TICKET_HEADER, QS_WS_CLOSE_RESUME_REQUIRED},
+    },
 };
 use tls_codec::Serialize;
-use tokio::{self, time::Duration};
+use tokio::{self, sync::mpsc, time::Duration};
+
+use crate::authorization::QS_OPEN_WS_PARAMS_HEADER;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -48,6 +66,7 @@ impl From<WsNotification> for InternalQsWsMessage {
         match notification {
             WsNotification::QueueUpdate => QsWsMessage::QueueUpdate,
             WsNotification::Event(event) => QsWsMessage::Event(event),
+            WsNotification::Presence(presence) => QsWsMessage::Presence(presence),
         }
         .into()
     }
@@ -63,6 +82,7 @@ struct QsWsConnection {
     queue_id: QsClientId,
     heartbeat: Instant,
     dispatch_addr: Addr<Dispatch>,
+    buffer: ConnectionBuffer,
 }
 
 impl QsWsConnection {
@@ -71,6 +91,7 @@ impl QsWsConnection {
             queue_id,
             heartbeat: Instant::now(),
             dispatch_addr,
+            buffer: ConnectionBuffer::default(),
         }
     }
 
@@ -80,6 +101,7 @@ impl QsWsConnection {
                 tracing::info!("Disconnecting websocket because heartbeat failed");
                 act.dispatch_addr.do_send(Disconnect {
                     queue_id: act.queue_id.clone(),
+                    buffer: act.buffer.clone(),
                 });
                 ctx.stop();
                 return;
@@ -103,8 +125,10 @@ impl Actor for QsWsConnection {
         let addr = ctx.address();
         self.dispatch_addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                resume_addr: addr.recipient(),
                 own_queue_id: self.queue_id.clone(),
+                buffer: self.buffer.clone(),
             })
             .into_actor(self)
             .then(|res, _, ctx| {
@@ -125,6 +149,7 @@ impl Actor for QsWsConnection {
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         self.dispatch_addr.do_send(Disconnect {
             queue_id: self.queue_id.clone(),
+            buffer: self.buffer.clone(),
         });
         Running::Stop
     }
@@ -149,6 +174,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for QsWsConnection {
                     tracing::trace!("Received a close: {:?}", close_reason);
                     self.dispatch_addr.do_send(Disconnect {
                         queue_id: self.queue_id.clone(),
+                        buffer: self.buffer.clone(),
                     });
                     ctx.stop()
                 }
@@ -169,22 +195,40 @@ impl Handler<InternalQsWsMessage> for QsWsConnection {
         let serialized = msg.inner.tls_serialize_detached().unwrap();
         // Send the message to the client
         ctx.binary(serialized);
+        self.buffer.pop();
+    }
+}
+
+/// Handler for ResumeRequired
+impl Handler<ResumeRequired> for QsWsConnection {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ResumeRequired, ctx: &mut Self::Context) {
+        tracing::info!("Closing websocket because the client didn't keep up");
+        ctx.close(Some(CloseReason {
+            code: CloseCode::Other(QS_WS_CLOSE_RESUME_REQUIRED),
+            description: Some("resume required".to_owned()),
+        }));
+        ctx.stop();
     }
 }
 
 /// Upgrade a HTTP connection to a WebSocket connection.
-/// TODO: There is no authentication yet.
+///
+/// The client authenticates either with a signature or with a resumption
+/// ticket. The response carries a fresh ticket for the next connection.
 #[tracing::instrument(
     name = "Upgrade connection to web socket",
-    skip(req, stream, dispatch_data)
+    skip(req, stream, qs, dispatch_data)
 )]
 pub(crate) async fn upgrade_connection(
     req: HttpRequest,
     stream: web::Payload,
+    qs: Data<Qs>,
     dispatch_data: Data<DispatchWebsocketNotifier>,
 ) -> impl Responder {
     // Read parameter from the request
-    let header_value = match req.headers().get("QsOpenWsParams") {
+    let header_value = match req.headers().get(QS_OPEN_WS_PARAMS_HEADER) {
         Some(value) => value,
         None => {
             tracing::error!("No QsOpenWsParams header found");
@@ -216,15 +260,34 @@ pub(crate) async fn upgrade_connection(
     };
 
     // Extract the queue ID
-    let qs_ws_connection = QsWsConnection::new(
-        qs_open_ws_params.queue_id,
-        dispatch_data.get_ref().dispatch_addr.clone(),
-    );
+    let queue_id = qs_open_ws_params.queue_id.clone();
+
+    // Authenticate the client
+    let ticket = match qs.authenticate_listen(qs_open_ws_params).await {
+        Ok(ticket) => ticket,
+        Err(e @ (QsListenAuthError::StorageError | QsListenAuthError::LibraryError)) => {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+        Err(e) => {
+            tracing::warn!("Rejected websocket connection: {}", e);
+            return HttpResponse::Unauthorized().body(e.to_string());
+        }
+    };
+    let ticket_header = HeaderValue::from_str(&general_purpose::STANDARD.encode(ticket.as_bytes()))
+        .expect("base64 is a valid header value");
+
+    let qs_ws_connection =
+        QsWsConnection::new(queue_id, dispatch_data.get_ref().dispatch_addr.clone());
 
     // Upgrade the connection to a websocket connection
     tracing::trace!("Upgrading HTTP connection to websocket connection...");
     match ws::start(qs_ws_connection, &req, stream) {
-        Ok(res) => res,
+        Ok(mut res) => {
+            let ticket_header_name =
+                HeaderName::try_from(QS_RESUMPTION_TICKET_HEADER).expect("valid header name");
+            res.headers_mut().insert(ticket_header_name, ticket_header);
+            res
+        }
         Err(e) => {
             tracing::error!("Error upgrading connection: {}", e);
             HttpResponse::InternalServerError().body(format!("{}", e))
@@ -237,45 +300,76 @@ pub(crate) async fn upgrade_connection(
 #[derive(Clone, Debug)]
 pub struct DispatchWebsocketNotifier {
     pub dispatch_addr: Addr<Dispatch>,
+    cluster: Option<Arc<ClusterChannel>>,
 }
 
 impl DispatchWebsocketNotifier {
     /// Create a new instance
     pub fn new(dispatch_addr: Addr<Dispatch>) -> Self {
-        DispatchWebsocketNotifier { dispatch_addr }
+        DispatchWebsocketNotifier {
+            dispatch_addr,
+            cluster: None,
+        }
     }
 
     /// Create a new instance
     pub fn default_addr() -> Self {
-        let dispatch: Addr<Dispatch> = Dispatch::default().start();
-        DispatchWebsocketNotifier {
-            dispatch_addr: dispatch,
-        }
+        Self::with_settings(DispatchSettings::default())
     }
-}
 
-#[async_trait]
-impl WebsocketNotifier for DispatchWebsocketNotifier {
-    /// Notify a client that opened a websocket connection to the QS.
-    ///
-    /// # Arguments
-    /// queue_id - The queue ID of the client
-    /// ws_notification - The notification to send
-    ///
-    /// # Returns
-    ///
-    /// Returns `()` of the operation was successful and
-    /// `WebsocketNotifierError::ClientNotFound` if the client was not found.
-    async fn notify(
+    /// Create a new instance with the given settings
+    pub fn with_settings(settings: DispatchSettings) -> Self {
+        let dispatch: Addr<Dispatch> = Dispatch::new(settings).start();
+        Self::new(dispatch)
+    }
+
+    /// Publish notifications for clients that are not connected to this
+    /// replica on the given channel, and deliver the notifications received on
+    /// the channel to the local connections.
+    pub fn with_cluster_channel(
+        mut self,
+        channel: ClusterChannel,
+        mut notifications: mpsc::Receiver<ClusterNotification>,
+    ) -> Self {
+        let dispatch_addr = self.dispatch_addr.clone();
+        actix::spawn(async move {
+            while let Some(notification) = notifications.recv().await {
+                // Most notifications are for clients connected to other
+                // replicas, so failures are expected.
+                let _ = dispatch_addr
+                    .send(NotifyMessage {
+                        queue_id: notification.client_id,
+                        payload: notification.message.into(),
+                    })
+                    .await;
+            }
+        });
+        self.cluster = Some(Arc::new(channel));
+        self
+    }
+
+    /// Health of the listener for notifications of other replicas, if the
+    /// replicas share notifications via Postgres.
+    pub fn cluster_health(&self) -> Option<PgListenHealth> {
+        self.cluster.as_ref()?.health()
+    }
+
+    /// Returns a snapshot of the metrics of the dispatch, including the
+    /// occupancy of the connection buffers.
+    pub async fn metrics(&self) -> Result<DispatchMetricsSnapshot, actix::MailboxError> {
+        self.dispatch_addr.send(GetMetrics).await
+    }
+
+    async fn notify_local(
         &self,
         queue_id: &QsClientId,
-        ws_notification: WsNotification,
+        payload: InternalQsWsMessage,
     ) -> Result<(), WebsocketNotifierError> {
         // Send the notification message to the dispatch actor
         self.dispatch_addr
             .send(NotifyMessage {
                 queue_id: queue_id.clone(),
-                payload: ws_notification.into(),
+                payload,
             })
             .await
             // If the actor doesn't reply, we get a MailboxError
@@ -292,3 +386,39 @@ impl WebsocketNotifier for DispatchWebsocketNotifier {
                 WebsocketNotifierError::WebsocketNotFound}))
     }
 }
+
+#[async_trait]
+impl WebsocketNotifier for DispatchWebsocketNotifier {
+    /// Notify a client that opened a websocket connection to the QS.
+    ///
+    /// # Arguments
+    /// queue_id - The queue ID of the client
+    /// ws_notification - The notification to send
+    ///
+    /// # Returns
+    ///
+    /// Returns `()` of the operation was successful and
+    /// `WebsocketNotifierError::ClientNotFound` if the client was not found.
+    ///
+    /// If the client is not connected to this replica, the notification is
+    /// also published to the other replicas. Since it is unknown whether
+    /// another replica delivers it, the client is still reported as not found.
+    async fn notify(
+        &self,
+        queue_id: &QsClientId,
+        ws_notification: WsNotification,
+    ) -> Result<(), WebsocketNotifierError> {
+        let payload: InternalQsWsMessage = ws_notification.into();
+        let result = self.notify_local(queue_id, payload.clone()).await;
+        if let (Err(_), Some(cluster)) = (&result, &self.cluster) {
+            let notification = ClusterNotification {
+                client_id: queue_id.clone(),
+                message: payload.inner,
+            };
+            if let Err(error) = cluster.publish(&notification).await {
+                tracing::warn!(%error, "Failed to publish notification to other replicas");
+            }
+        }
+        result
+    }
+}
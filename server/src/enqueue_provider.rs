@@ -4,14 +4,16 @@
 
 use async_trait::async_trait;
 use phnxbackend::{
-    messages::intra_backend::DsFanOutMessage,
+    messages::intra_backend::{DsFanOutBatch, DsFanOutMessage},
     qs::{
         errors::QsEnqueueError, network_provider_trait::NetworkProvider, PushNotificationProvider,
         Qs, QsConnector,
     },
 };
 use phnxtypes::{
-    crypto::signatures::keys::QsVerifyingKey, errors::qs::QsVerifyingKeyError, identifiers::Fqdn,
+    crypto::signatures::keys::QsVerifyingKey,
+    errors::qs::QsVerifyingKeyError,
+    identifiers::{Fqdn, QsClientReference},
 };
 
 use crate::endpoints::qs::ws::DispatchWebsocketNotifier;
@@ -40,7 +42,31 @@ impl<N: NetworkProvider, P: PushNotificationProvider> QsConnector for SimpleEnqu
         .await
     }
 
+    async fn dispatch_batch(
+        &self,
+        batch: DsFanOutBatch,
+    ) -> Result<Vec<QsClientReference>, Self::EnqueueError> {
+        Qs::enqueue_batch(
+            &self.qs,
+            &self.notifier,
+            &self.push_notification_provider,
+            &self.network,
+            batch,
+        )
+        .await
+    }
+
     async fn verifying_key(&self, domain: Fqdn) -> Result<QsVerifyingKey, Self::VerifyingKeyError> {
         self.qs.verifying_key(&self.network, domain).await
     }
+
+    async fn wake_up(&self, client_reference: QsClientReference) -> Result<(), Self::EnqueueError> {
+        self.qs
+            .wake_up_client(
+                &self.notifier,
+                &self.push_notification_provider,
+                client_reference,
+            )
+            .await
+    }
 }
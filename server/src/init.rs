@@ -0,0 +1,426 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Bootstrap of a self-hosted server.
+//!
+//! Started with `phnxserver init`. Asks for the domain and the database
+//! connection (or takes them from flags with `--non-interactive`), writes
+//! the configuration file of the current environment, creates the databases
+//! of the DS, the QS and the AS, runs their migrations and generates the AS
+//! credentials. Finally, it prints the steps to verify the deployment.
+//!
+//! The database password is read from the `PHNX_DATABASE_PASSWORD`
+//! environment variable or asked for, such that it doesn't show up in
+//! process listings. The configuration file contains the password, so it is
+//! only readable by the owner, and an existing one is never overwritten.
+//!
+//! The server doesn't terminate TLS itself. With `--acme-email`, a Caddyfile
+//! is written next to the configuration, such that Caddy obtains
+//! certificates via ACME and forwards requests to the server.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use config::ConfigError;
+use phnxbackend::{
+    auth_service::AuthService,
+    ds::Ds,
+    errors::StorageError,
+    infra_service::{InfraService, ServiceCreationError},
+    qs::Qs,
+};
+use phnxtypes::{endpoint_paths::ENDPOINT_HEALTH_CHECK, identifiers::Fqdn};
+use thiserror::Error;
+
+use crate::configurations::{get_configuration, Environment};
+
+/// Subcommand that bootstraps a new server.
+pub const INIT_SUBCOMMAND: &str = "init";
+
+const DOMAIN_FLAG: &str = "--domain";
+const PORT_FLAG: &str = "--port";
+const DB_HOST_FLAG: &str = "--db-host";
+const DB_PORT_FLAG: &str = "--db-port";
+const DB_USER_FLAG: &str = "--db-user";
+const DB_NAME_FLAG: &str = "--db-name";
+const ACME_EMAIL_FLAG: &str = "--acme-email";
+/// Don't prompt, fail if a required value is missing.
+const NON_INTERACTIVE_FLAG: &str = "--non-interactive";
+/// Environment variable with the database password. The server reads the
+/// same variable to override the password of the configuration file.
+const DB_PASSWORD_ENV: &str = "PHNX_DATABASE_PASSWORD";
+
+const CONFIGURATION_PREFIX: &str = "server/";
+
+#[derive(Debug, Error)]
+pub enum InitError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Missing value for {0}")]
+    MissingValue(&'static str),
+    #[error("Invalid value for {flag}: {value}")]
+    InvalidValue { flag: &'static str, value: String },
+    #[error("{0} already exists. Remove it to generate a new configuration.")]
+    ConfigurationExists(PathBuf),
+    #[error("Invalid environment: {0}")]
+    Environment(String),
+    #[error("Failed to load the written configuration: {0}")]
+    Configuration(#[from] ConfigError),
+    #[error("Failed to set up the database: {0}")]
+    Database(#[from] ServiceCreationError),
+    #[error("Failed to load the AS credentials: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// Values of the generated configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct InitOptions {
+    domain: String,
+    port: u16,
+    db_host: String,
+    db_port: u16,
+    db_user: String,
+    db_password: String,
+    db_name: String,
+    acme_email: Option<String>,
+}
+
+/// Returns true if the server was started with the init subcommand.
+pub fn init_requested() -> bool {
+    std::env::args().nth(1).as_deref() == Some(INIT_SUBCOMMAND)
+}
+
+/// Generate the configuration, set up the databases and print the
+/// verification steps.
+pub async fn run_init() -> Result<(), InitError> {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let interactive = !args.iter().any(|arg| arg == NON_INTERACTIVE_FLAG);
+    let mut prompt = Prompt::new(interactive);
+
+    // Fail before asking for anything if the configuration exists.
+    let environment = Environment::from_env().map_err(InitError::Environment)?;
+    let configuration_directory = Path::new(CONFIGURATION_PREFIX).join("configuration");
+    let configuration_file = configuration_directory.join(format!("{}.yaml", environment.as_str()));
+    if configuration_file.exists() {
+        return Err(InitError::ConfigurationExists(configuration_file));
+    }
+
+    let db_password = std::env::var(DB_PASSWORD_ENV).ok();
+    let options = InitOptions::collect(&args, db_password, &mut prompt)?;
+    write_configuration(&configuration_file, &options.configuration_yaml())?;
+    println!("Wrote {}", configuration_file.display());
+
+    if let Some(caddyfile) = options.caddyfile() {
+        let caddyfile_path = configuration_directory.join("Caddyfile");
+        std::fs::write(&caddyfile_path, caddyfile)?;
+        println!("Wrote {}", caddyfile_path.display());
+    }
+
+    // Creating the services creates the databases, runs the migrations and
+    // generates the AS credentials.
+    let mut configuration = get_configuration(CONFIGURATION_PREFIX)?;
+    let domain: Fqdn = options
+        .domain
+        .clone()
+        .try_into()
+        .map_err(|_| InitError::InvalidValue {
+            flag: DOMAIN_FLAG,
+            value: options.domain.clone(),
+        })?;
+    let base_db_name = configuration.database.name.clone();
+    configuration.database.name = format!("{}_ds", base_db_name);
+    Ds::new(&configuration.database, domain.clone()).await?;
+    configuration.database.name = format!("{}_qs", base_db_name);
+    Qs::new(&configuration.database, domain.clone()).await?;
+    configuration.database.name = format!("{}_as", base_db_name);
+    let auth_service = AuthService::new(&configuration.database, domain).await?;
+    println!("Created the databases {base_db_name}_ds, {base_db_name}_qs and {base_db_name}_as");
+
+    let (as_fingerprints, intermediate_fingerprints) =
+        auth_service.credential_fingerprints().await?;
+    println!();
+    println!("AS credentials:");
+    for fingerprint in &as_fingerprints {
+        println!("  {fingerprint}");
+    }
+    println!("AS intermediate credentials:");
+    for fingerprint in &intermediate_fingerprints {
+        println!("  {fingerprint}");
+    }
+
+    println!();
+    println!("{}", options.verification_steps());
+    Ok(())
+}
+
+impl InitOptions {
+    /// Collect the values from the flags and the prompt. The password is
+    /// only taken from `db_password` or the prompt.
+    fn collect(
+        args: &[String],
+        db_password: Option<String>,
+        prompt: &mut Prompt,
+    ) -> Result<Self, InitError> {
+        let domain = prompt.value(args, DOMAIN_FLAG, "Domain of the server", None)?;
+        let port = parse(
+            PORT_FLAG,
+            prompt.value(args, PORT_FLAG, "Port to listen on", Some("9420"))?,
+        )?;
+        let db_host = prompt.value(args, DB_HOST_FLAG, "Postgres host", Some("127.0.0.1"))?;
+        let db_port = parse(
+            DB_PORT_FLAG,
+            prompt.value(args, DB_PORT_FLAG, "Postgres port", Some("5432"))?,
+        )?;
+        let db_user = prompt.value(args, DB_USER_FLAG, "Postgres user", Some("postgres"))?;
+        let db_password = match db_password {
+            Some(db_password) => db_password,
+            None => prompt.secret(DB_PASSWORD_ENV, "Postgres password")?,
+        };
+        let db_name = prompt.value(
+            args,
+            DB_NAME_FLAG,
+            "Prefix of the database names",
+            Some("phnx_db"),
+        )?;
+        let acme_email = prompt
+            .value(
+                args,
+                ACME_EMAIL_FLAG,
+                "Email for ACME certificates (empty to terminate TLS yourself)",
+                Some(""),
+            )?
+            .trim()
+            .to_owned();
+        Ok(Self {
+            domain,
+            port,
+            db_host,
+            db_port,
+            db_user,
+            db_password,
+            db_name,
+            acme_email: (!acme_email.is_empty()).then_some(acme_email),
+        })
+    }
+
+    /// Configuration file layered over `base.yaml`
+    fn configuration_yaml(&self) -> String {
+        format!(
+            "# Generated by `phnxserver {INIT_SUBCOMMAND}`\n\
+             application:\n  \
+               port: {port}\n  \
+               domain: {domain}\n\
+             database:\n  \
+               host: {db_host}\n  \
+               port: {db_port}\n  \
+               username: {db_user}\n  \
+               password: {db_password}\n  \
+               name: {db_name}\n",
+            port = self.port,
+            domain = yaml_string(&self.domain),
+            db_host = yaml_string(&self.db_host),
+            db_port = self.db_port,
+            db_user = yaml_string(&self.db_user),
+            db_password = yaml_string(&self.db_password),
+            db_name = yaml_string(&self.db_name),
+        )
+    }
+
+    /// Caddyfile obtaining certificates via ACME, if an email was given
+    fn caddyfile(&self) -> Option<String> {
+        let email = self.acme_email.as_ref()?;
+        Some(format!(
+            "{{\n\temail {email}\n}}\n\n{domain} {{\n\treverse_proxy 127.0.0.1:{port}\n}}\n",
+            domain = self.domain,
+            port = self.port,
+        ))
+    }
+
+    fn verification_steps(&self) -> String {
+        let url = match self.acme_email {
+            Some(_) => format!("https://{}", self.domain),
+            None => format!("http://{}:{}", self.domain, self.port),
+        };
+        let mut steps = vec!["Start the server with `phnxserver`.".to_owned()];
+        if self.acme_email.is_some() {
+            steps.push(
+                "Start Caddy with `caddy run --config server/configuration/Caddyfile`.".to_owned(),
+            );
+        }
+        steps.push(format!(
+            "Check that `curl {url}{ENDPOINT_HEALTH_CHECK}` succeeds."
+        ));
+        steps.push(format!(
+            "Register a user in the app with the server URL {url} and check that the AS \
+             credential shown in the app matches one of the fingerprints above."
+        ));
+        let mut text = "Next steps:".to_owned();
+        for (i, step) in steps.iter().enumerate() {
+            text.push_str(&format!("\n  {}. {step}", i + 1));
+        }
+        text
+    }
+}
+
+/// Write the configuration file, which is only readable by the owner, since
+/// it contains the database password. Fails if the file exists.
+fn write_configuration(path: &Path, contents: &str) -> Result<(), InitError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => InitError::ConfigurationExists(path.to_owned()),
+        _ => InitError::Io(e),
+    })?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(flag: &'static str, value: String) -> Result<T, InitError> {
+    value
+        .parse()
+        .map_err(|_| InitError::InvalidValue { flag, value })
+}
+
+/// Quotes a string for YAML. JSON strings are valid YAML strings.
+fn yaml_string(value: &str) -> String {
+    serde_json::to_string(value).expect("strings can be serialized")
+}
+
+/// Value of the given flag in the arguments, if any.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let mut args = args.iter().skip_while(|arg| *arg != flag);
+    args.next()?;
+    args.next().map(String::as_str)
+}
+
+/// Asks for values not given as flags, unless non-interactive.
+struct Prompt {
+    interactive: bool,
+}
+
+impl Prompt {
+    fn new(interactive: bool) -> Self {
+        Self { interactive }
+    }
+
+    fn value(
+        &mut self,
+        args: &[String],
+        flag: &'static str,
+        question: &str,
+        default: Option<&str>,
+    ) -> Result<String, InitError> {
+        if let Some(value) = flag_value(args, flag) {
+            return Ok(value.to_owned());
+        }
+        if !self.interactive {
+            return default
+                .map(ToOwned::to_owned)
+                .ok_or(InitError::MissingValue(flag));
+        }
+        loop {
+            match default {
+                Some(default) if !default.is_empty() => print!("{question} [{default}]: "),
+                _ => print!("{question}: "),
+            }
+            let answer = self.read_line()?;
+            match (answer.is_empty(), default) {
+                (false, _) => return Ok(answer),
+                (true, Some(default)) => return Ok(default.to_owned()),
+                (true, None) => println!("A value is required."),
+            }
+        }
+    }
+
+    /// Asks for a value that must not be passed as a flag. Without prompts,
+    /// it has to be set in the given environment variable.
+    fn secret(&mut self, env_var: &'static str, question: &str) -> Result<String, InitError> {
+        if !self.interactive {
+            return Err(InitError::MissingValue(env_var));
+        }
+        loop {
+            print!("{question}: ");
+            let answer = self.read_line()?;
+            if !answer.is_empty() {
+                return Ok(answer);
+            }
+            println!("A value is required.");
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, InitError> {
+        io::stdout().flush()?;
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok(line.trim().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn flags_are_used_without_prompting() {
+        let args = args(&[
+            DOMAIN_FLAG,
+            "example.com",
+            ACME_EMAIL_FLAG,
+            "ops@example.com",
+        ]);
+        let options =
+            InitOptions::collect(&args, Some("se\"cret".to_owned()), &mut Prompt::new(false))
+                .unwrap();
+        assert_eq!(options.domain, "example.com");
+        assert_eq!(options.port, 9420);
+        assert_eq!(options.db_user, "postgres");
+        assert_eq!(options.acme_email.as_deref(), Some("ops@example.com"));
+
+        let yaml = options.configuration_yaml();
+        assert!(yaml.contains("  domain: \"example.com\"\n"));
+        assert!(yaml.contains("  password: \"se\\\"cret\"\n"));
+        assert!(options
+            .caddyfile()
+            .unwrap()
+            .contains("reverse_proxy 127.0.0.1:9420"));
+    }
+
+    #[test]
+    fn missing_values_fail_without_prompting() {
+        let error = InitOptions::collect(
+            &args(&[DOMAIN_FLAG, "example.com"]),
+            None,
+            &mut Prompt::new(false),
+        )
+        .unwrap_err();
+        assert!(matches!(error, InitError::MissingValue(DB_PASSWORD_ENV)));
+    }
+
+    #[test]
+    fn configuration_is_private_and_not_overwritten() {
+        let path = std::env::temp_dir().join(format!("phnx-init-{}.yaml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        write_configuration(&path, "first").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let error = write_configuration(&path, "second").unwrap_err();
+        assert!(matches!(error, InitError::ConfigurationExists(_)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
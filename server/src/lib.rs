@@ -4,9 +4,14 @@
 
 //! Server that makes the logic implemented in the backend available to clients via a REST API
 
+pub mod admin_cli;
+pub mod authorization;
 pub mod configurations;
+#[cfg(feature = "dev")]
+pub mod dev_mode;
 pub mod endpoints;
 pub mod enqueue_provider;
+pub mod init;
 pub mod network_provider;
 pub mod telemetry;
 
@@ -14,6 +19,8 @@ use endpoints::{ds::*, qs::ws::DispatchWebsocketNotifier};
 
 use actix_web::{
     dev::Server,
+    guard,
+    middleware::from_fn,
     web::{self, Data},
     App, HttpServer,
 };
@@ -21,44 +28,70 @@ use phnxbackend::{
     auth_service::AuthService,
     ds::Ds,
     qs::{errors::QsEnqueueError, network_provider_trait::NetworkProvider, Qs, QsConnector},
+    settings::RequestLimitSettings,
 };
 use phnxtypes::{
     endpoint_paths::{
-        ENDPOINT_AS, ENDPOINT_DS_GROUPS, ENDPOINT_HEALTH_CHECK, ENDPOINT_QS,
-        ENDPOINT_QS_FEDERATION, ENDPOINT_QS_WS,
+        ENDPOINT_ADMIN_LOG_FILTER, ENDPOINT_ADMIN_METRICS, ENDPOINT_AS, ENDPOINT_AS_ANNOUNCEMENTS,
+        ENDPOINT_DS_GROUPS, ENDPOINT_HEALTH_CHECK, ENDPOINT_QS, ENDPOINT_QS_FEDERATION,
+        ENDPOINT_QS_KEY_FINGERPRINT, ENDPOINT_QS_WS,
     },
     errors::qs::QsVerifyingKeyError,
+    identifiers::Fqdn,
 };
 use std::net::TcpListener;
 use tracing_actix_web::TracingLogger;
 
 use crate::endpoints::{
-    auth_service::as_process_message,
+    admin::{get_log_filter, get_metrics, set_log_filter, Admin},
+    auth_service::{as_announcements, as_process_message},
     health_check,
-    qs::{qs_process_federated_message, qs_process_message, ws::upgrade_connection},
+    qs::{
+        qs_key_fingerprint, qs_process_federated_message, qs_process_message,
+        ws::upgrade_connection,
+    },
 };
 
+/// The services of one domain served by the server.
+pub struct TenantServices<Qc> {
+    pub domain: Fqdn,
+    pub ds: Ds,
+    pub auth_service: AuthService,
+    pub qs: Qs,
+    pub qs_connector: Qc,
+}
+
 /// Configure and run the server application.
-#[allow(clippy::too_many_arguments)]
+///
+/// If the server serves more than one domain, requests are routed to the
+/// services of the domain given in their `Host` header. Requests for other
+/// domains are rejected.
 pub fn run<
     Qc: QsConnector<EnqueueError = QsEnqueueError<Np>, VerifyingKeyError = QsVerifyingKeyError>,
     Np: NetworkProvider,
 >(
     listener: TcpListener,
-    ds: Ds,
-    auth_service: AuthService,
-    qs: Qs,
-    qs_connector: Qc,
+    tenants: Vec<TenantServices<Qc>>,
     network_provider: Np,
     ws_dispatch_notifier: DispatchWebsocketNotifier,
+    request_limits: RequestLimitSettings,
+    admin: Admin,
 ) -> Result<Server, std::io::Error> {
     // Wrap providers in a Data<T>
-    let ds_data = Data::new(ds);
-    let auth_service_data = Data::new(auth_service);
-    let qs_data = Data::new(qs);
-    let qs_connector_data = Data::new(qs_connector);
+    let tenants: Vec<_> = tenants
+        .into_iter()
+        .map(|tenant| TenantData {
+            domain: tenant.domain.to_string(),
+            ds: Data::new(tenant.ds),
+            auth_service: Data::new(tenant.auth_service),
+            qs: Data::new(tenant.qs),
+            qs_connector: Data::new(tenant.qs_connector),
+        })
+        .collect();
+    let multi_tenant = tenants.len() > 1;
     let network_provider_data = Data::new(network_provider);
     let ws_dispatch_notifier_data = Data::new(ws_dispatch_notifier);
+    let admin_data = Data::new(admin);
 
     tracing::info!(
         "Starting server, listening on {}:{}",
@@ -74,34 +107,97 @@ pub fn run<
 
     // Create & run the server
     let server = HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
+            // Deny requests to endpoints without a declared authorization policy
+            .wrap(from_fn(authorization::enforce_endpoint_policy))
             .wrap(TracingLogger::default())
-            .route(ENDPOINT_HEALTH_CHECK, web::get().to(health_check))
-            .app_data(ds_data.clone())
-            .app_data(auth_service_data.clone())
-            .app_data(qs_data.clone())
-            .app_data(qs_connector_data.clone())
+            // Reject oversized request bodies before reading them completely
+            .app_data(web::PayloadConfig::new(request_limits.max_request_bytes))
             .app_data(network_provider_data.clone())
             .app_data(ws_dispatch_notifier_data.clone())
-            // DS enpoint
-            .route(ENDPOINT_DS_GROUPS, web::post().to(ds_process_message::<Qc>))
-            // QS endpoint
-            .route(ENDPOINT_QS, web::post().to(qs_process_message))
-            // QS federationendpoint
-            .route(
-                ENDPOINT_QS_FEDERATION,
-                web::post().to(qs_process_federated_message::<Qc, Np>),
-            )
-            // QS endpoint
-            .route(ENDPOINT_AS, web::post().to(as_process_message))
-            // WS endpoint
-            .route(ENDPOINT_QS_WS, web::get().to(upgrade_connection))
+            .app_data(admin_data.clone())
+            .configure(global_routes);
+        for tenant in &tenants {
+            let mut scope = web::scope("")
+                .app_data(tenant.ds.clone())
+                .app_data(tenant.auth_service.clone())
+                .app_data(tenant.qs.clone())
+                .app_data(tenant.qs_connector.clone())
+                .configure(tenant_routes::<Qc, Np>);
+            if multi_tenant {
+                scope = scope.guard(guard::Host(&tenant.domain));
+            }
+            app = app.service(scope);
+        }
+        app
     })
     .listen(listener)?
     .run();
     Ok(server)
 }
 
+struct TenantData<Qc> {
+    domain: String,
+    ds: Data<Ds>,
+    auth_service: Data<AuthService>,
+    qs: Data<Qs>,
+    qs_connector: Data<Qc>,
+}
+
+// Not derived, since `Qc` doesn't need to be `Clone`.
+impl<Qc> Clone for TenantData<Qc> {
+    fn clone(&self) -> Self {
+        Self {
+            domain: self.domain.clone(),
+            ds: self.ds.clone(),
+            auth_service: self.auth_service.clone(),
+            qs: self.qs.clone(),
+            qs_connector: self.qs_connector.clone(),
+        }
+    }
+}
+
+/// Endpoints that don't depend on the domain.
+fn global_routes(config: &mut web::ServiceConfig) {
+    config
+        .route(ENDPOINT_HEALTH_CHECK, web::get().to(health_check))
+        // Admin endpoints
+        .route(ENDPOINT_ADMIN_LOG_FILTER, web::get().to(get_log_filter))
+        .route(ENDPOINT_ADMIN_LOG_FILTER, web::post().to(set_log_filter));
+}
+
+/// Endpoints served by the services of a domain.
+fn tenant_routes<
+    Qc: QsConnector<EnqueueError = QsEnqueueError<Np>, VerifyingKeyError = QsVerifyingKeyError>,
+    Np: NetworkProvider,
+>(
+    config: &mut web::ServiceConfig,
+) {
+    config
+        // DS enpoint
+        .route(ENDPOINT_DS_GROUPS, web::post().to(ds_process_message::<Qc>))
+        // QS endpoint
+        .route(ENDPOINT_QS, web::post().to(qs_process_message::<Qc>))
+        // QS federationendpoint
+        .route(
+            ENDPOINT_QS_FEDERATION,
+            web::post().to(qs_process_federated_message::<Qc, Np>),
+        )
+        // QS key fingerprint endpoint for federated peers
+        .route(
+            ENDPOINT_QS_KEY_FINGERPRINT,
+            web::get().to(qs_key_fingerprint),
+        )
+        // QS endpoint
+        .route(ENDPOINT_AS, web::post().to(as_process_message))
+        // AS announcements endpoint
+        .route(ENDPOINT_AS_ANNOUNCEMENTS, web::get().to(as_announcements))
+        // WS endpoint
+        .route(ENDPOINT_QS_WS, web::get().to(upgrade_connection))
+        // Admin endpoints that report on the services of the domain
+        .route(ENDPOINT_ADMIN_METRICS, web::get().to(get_metrics));
+}
+
 // QS endpoints
 
 // Create pseudonymous user record:
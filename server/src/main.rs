@@ -4,47 +4,137 @@
 
 use std::net::TcpListener;
 
-use phnxbackend::{auth_service::AuthService, ds::Ds, infra_service::InfraService, qs::Qs};
+use phnxbackend::{
+    auth_service::AuthService,
+    ds::{Ds, StorageBackend},
+    infra_service::{InfraService, ServiceCreationError},
+    qs::Qs,
+    settings::{DatabaseSettings, TenantSettings},
+};
 use phnxserver::{
     configurations::*,
-    endpoints::qs::{
-        push_notification_provider::ProductionPushNotificationProvider,
-        ws::DispatchWebsocketNotifier,
+    endpoints::{
+        admin::Admin,
+        qs::{
+            push_notification_provider::ProductionPushNotificationProvider,
+            ws::DispatchWebsocketNotifier,
+        },
     },
     enqueue_provider::SimpleEnqueueProvider,
     network_provider::MockNetworkProvider,
     run,
-    telemetry::{get_subscriber, init_subscriber},
+    telemetry::{get_configured_subscriber, init_subscriber},
+    TenantServices,
 };
-use phnxtypes::identifiers::Fqdn;
+use phnxtypes::{identifiers::Fqdn, messages::server_info::ServerFeature, time::Duration};
+
+/// Generates a new AS intermediate signing key, activates it and exits.
+const ROTATE_AS_INTERMEDIATE_FLAG: &str = "--rotate-as-intermediate";
+/// Number of days the replaced intermediate credential is still published
+/// after a rotation. Defaults to the remaining lifetime of the credential.
+const PUBLICATION_OVERLAP_DAYS_FLAG: &str = "--publication-overlap-days";
+
+fn publication_overlap() -> Option<Duration> {
+    let mut args = std::env::args().skip_while(|arg| arg != PUBLICATION_OVERLAP_DAYS_FLAG);
+    args.next()?;
+    let days = args
+        .next()
+        .and_then(|days| days.parse().ok())
+        .expect("Invalid number of publication overlap days.");
+    Some(Duration::days(days))
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Configure logging/trace subscription
-    let subscriber = get_subscriber("phnxserver".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
+    // Bootstrap a new server instead of starting it
+    if phnxserver::init::init_requested() {
+        return phnxserver::init::run_init()
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()));
+    }
 
     // Load configuration
     let mut configuration = get_configuration("server/").expect("Could not load configuration.");
 
+    // Configure logging/trace subscription
+    let (subscriber, log_filter) =
+        get_configured_subscriber("phnxserver".into(), &configuration.logging)?;
+    init_subscriber(subscriber);
+    let admin = Admin::new(configuration.admin.clone()).with_log_filter(log_filter);
+
+    // Keep the embedded database alive for the lifetime of the server.
+    #[cfg(feature = "dev")]
+    let _dev_database = if phnxserver::dev_mode::dev_mode_requested() {
+        tracing::info!("Starting in dev mode with an embedded Postgres server.");
+        let dev_database = phnxserver::dev_mode::start_dev_mode(&mut configuration)
+            .await
+            .expect("Could not start embedded Postgres server.");
+        println!(
+            "Dev server starting. Use this server URL in the client: {}",
+            phnxserver::dev_mode::dev_client_url(&configuration)
+        );
+        Some(dev_database)
+    } else {
+        None
+    };
+    #[cfg(not(feature = "dev"))]
+    if std::env::args().any(|arg| arg == "--dev") {
+        panic!("Dev mode is not available. Build the server with `--features dev`.");
+    }
+
     if configuration.application.domain.is_empty() {
         panic!("No domain name configured.");
     }
 
+    let domain: Fqdn = configuration
+        .application
+        .domain
+        .try_into()
+        .expect("Invalid domain.");
+
+    // Rotate the AS intermediate signing key instead of starting the server
+    if std::env::args().any(|arg| arg == ROTATE_AS_INTERMEDIATE_FLAG) {
+        configuration.database.name = format!("{}_as", configuration.database.name);
+        let auth_service = AuthService::new(&configuration.database, domain)
+            .await
+            .expect("Failed to connect to database.");
+        auth_service
+            .rotate_intermediate_signing_key(publication_overlap())
+            .await
+            .expect("Failed to rotate the AS intermediate signing key.");
+        return Ok(());
+    }
+
     // Port binding
     let address = format!(
         "{}:{}",
         configuration.application.host, configuration.application.port
     );
     let listener = TcpListener::bind(address).expect("Failed to bind to random port.");
-    let domain: Fqdn = configuration
-        .application
-        .domain
-        .try_into()
-        .expect("Invalid domain.");
     tracing::info!("Starting server with domain {}.", domain);
     let network_provider = MockNetworkProvider::new();
 
+    // Additional domains served by the server, with the primary domain first
+    let tenant_settings: Vec<TenantSettings> = std::iter::once(TenantSettings {
+        domain: domain.to_string(),
+        apns: configuration.apns.clone(),
+        fcm: configuration.fcm.clone(),
+        spam_throttle: Some(configuration.spam_throttle.clone()),
+        send_rate_limit: Some(configuration.send_rate_limit.clone()),
+    })
+    .chain(configuration.tenants.clone())
+    .collect();
+    let domains: Vec<Fqdn> = tenant_settings
+        .iter()
+        .map(|tenant| {
+            Fqdn::try_from(tenant.domain.as_str())
+                .unwrap_or_else(|_| panic!("Invalid tenant domain {}.", tenant.domain))
+        })
+        .collect();
+    for domain in &domains[1..] {
+        tracing::info!("Also serving domain {}.", domain);
+    }
+
     let base_db_name = configuration.database.name.clone();
     // DS storage provider
     configuration.database.name = format!("{}_ds", base_db_name);
@@ -53,7 +143,7 @@ async fn main() -> std::io::Result<()> {
         configuration.database.host
     );
     let mut counter = 0;
-    let mut ds_result = Ds::new(&configuration.database, domain.clone()).await;
+    let mut ds_result = new_services::<Ds>(&configuration.database, &domains).await;
 
     // Try again for 10 times each second in case the postgres server is coming up.
     while let Err(e) = ds_result {
@@ -63,42 +153,125 @@ async fn main() -> std::io::Result<()> {
         if counter > 10 {
             panic!("Database not ready after 10 seconds.");
         }
-        ds_result = Ds::new(&configuration.database, domain.clone()).await;
+        ds_result = new_services::<Ds>(&configuration.database, &domains).await;
     }
-    let ds = ds_result.unwrap();
+    let dss = ds_result.unwrap();
 
     // New database name for the QS provider
     configuration.database.name = format!("{}_qs", base_db_name);
     // QS storage provider
-    let qs = Qs::new(&configuration.database, domain.clone())
+    let qss = new_services::<Qs>(&configuration.database, &domains)
         .await
         .expect("Failed to connect to database.");
 
     // New database name for the AS provider
     configuration.database.name = format!("{}_as", base_db_name);
-    let auth_service = AuthService::new(&configuration.database, domain.clone())
+    let auth_services = new_services::<AuthService>(&configuration.database, &domains)
         .await
         .expect("Failed to connect to database.");
 
-    let ws_dispatch_notifier = DispatchWebsocketNotifier::default_addr();
-    let push_notification_provider =
-        ProductionPushNotificationProvider::new(configuration.fcm, configuration.apns)
-            .map_err(|e| std::io::Error::other(e.to_string()))?;
-    let qs_connector = SimpleEnqueueProvider {
-        qs: qs.clone(),
-        notifier: ws_dispatch_notifier.clone(),
-        push_notification_provider,
-        network: network_provider.clone(),
-    };
+    // Notifications are dispatched by client id, which is unique across
+    // domains, so all domains share the websocket connections.
+    let mut ws_dispatch_notifier =
+        DispatchWebsocketNotifier::with_settings(configuration.dispatch.clone());
+    if let Some((channel, notifications)) = qss[0]
+        .connect_cluster_channel(&configuration.dispatch.cluster)
+        .await
+        .expect("Failed to connect to the channel shared with other replicas.")
+    {
+        ws_dispatch_notifier = ws_dispatch_notifier.with_cluster_channel(channel, notifications);
+    }
+
+    // Attachments of all domains count towards the same storage.
+    let storage = StorageBackend::new(&configuration.storage)
+        .await
+        .expect("Failed to initialize attachment storage.");
+
+    let mut tenants = Vec::with_capacity(domains.len());
+    for ((((settings, domain), ds), qs), auth_service) in tenant_settings
+        .into_iter()
+        .zip(domains)
+        .zip(dss)
+        .zip(qss)
+        .zip(auth_services)
+    {
+        let spam_throttle = settings
+            .spam_throttle
+            .unwrap_or_else(|| configuration.spam_throttle.clone());
+        let send_rate_limit = settings
+            .send_rate_limit
+            .unwrap_or_else(|| configuration.send_rate_limit.clone());
+
+        let ds = ds
+            .with_spam_throttle(spam_throttle)
+            .with_send_rate_limit(send_rate_limit.clone())
+            .with_welcome_info_cache(configuration.welcome_info_cache.clone())
+            .with_attachment_scanning(configuration.attachment_scanning.clone())
+            .with_storage(&storage);
+
+        // Optional features published to clients
+        let features = [
+            (settings.apns.is_some(), ServerFeature::APPLE_PUSH),
+            (settings.fcm.is_some(), ServerFeature::GOOGLE_PUSH),
+            (
+                configuration.attachment_scanning.enabled,
+                ServerFeature::ATTACHMENT_SCANNING,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, feature)| enabled.then(|| ServerFeature::new(feature)))
+        .collect();
+
+        let auth_service = auth_service
+            .with_handle_policy(configuration.handle_policy.clone())
+            .with_server_info(configuration.server_info.clone(), features)
+            .with_send_rate_limit(&send_rate_limit)
+            .with_announcements(configuration.announcements.clone())
+            .with_data_export_settings(configuration.data_export.clone())
+            .with_connection_package_settings(configuration.connection_packages.clone());
+        auth_service.spawn_data_export_cleanup();
+        auth_service.spawn_connection_package_sweep();
+
+        let push_notification_provider =
+            ProductionPushNotificationProvider::new(settings.fcm, settings.apns)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+        qs.spawn_pending_fanout_retry(network_provider.clone());
+        let qs_connector = SimpleEnqueueProvider {
+            qs: qs.clone(),
+            notifier: ws_dispatch_notifier.clone(),
+            push_notification_provider,
+            network: network_provider.clone(),
+        };
+        tenants.push(TenantServices {
+            domain,
+            ds,
+            auth_service,
+            qs,
+            qs_connector,
+        });
+    }
+
     // Start the server
     run(
         listener,
-        ds,
-        auth_service,
-        qs,
-        qs_connector,
+        tenants,
         network_provider,
         ws_dispatch_notifier,
+        configuration.request_limits.clone(),
+        admin,
     )?
     .await
 }
+
+/// Create the service for each of the domains. A single domain is served
+/// with the owner connection, such that existing deployments are unaffected
+/// by the row-level security of multi-tenant deployments.
+async fn new_services<S: InfraService>(
+    database_settings: &DatabaseSettings,
+    domains: &[Fqdn],
+) -> Result<Vec<S>, ServiceCreationError> {
+    match domains {
+        [domain] => Ok(vec![S::new(database_settings, domain.clone()).await?]),
+        domains => S::new_tenants(database_settings, domains).await,
+    }
+}
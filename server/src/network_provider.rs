@@ -2,11 +2,23 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
 use async_trait::async_trait;
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
 use phnxbackend::qs::{network_provider_trait::NetworkProvider, qs_api::FederatedProcessingResult};
 use phnxtypes::{
-    endpoint_paths::ENDPOINT_QS_FEDERATION, identifiers::Fqdn, DEFAULT_PORT_HTTP,
-    DEFAULT_PORT_HTTPS,
+    crypto::signatures::keys::FederationKeyRecord,
+    endpoint_paths::{ENDPOINT_QS_FEDERATION, ENDPOINT_QS_KEY_FINGERPRINT},
+    identifiers::Fqdn,
+    DEFAULT_PORT_HTTP, DEFAULT_PORT_HTTPS,
 };
 use reqwest::Client;
 use thiserror::Error;
@@ -17,6 +29,12 @@ pub enum MockNetworkError {
     /// Malformed response
     #[error("Malformed response")]
     MalformedResponse,
+    /// Request failed
+    #[error("Request failed: {0}")]
+    RequestFailed(String),
+    /// DNS lookup failed
+    #[error("DNS lookup failed: {0}")]
+    DnsLookupFailed(String),
 }
 
 #[derive(Debug, Clone)]
@@ -25,10 +43,31 @@ pub enum TransportEncryption {
     Off,
 }
 
+/// Addresses of backends that run in the same process and host the domains
+/// of a test. Federation requests to these domains are sent to the given
+/// address instead of resolving the domain.
+#[derive(Debug, Clone, Default)]
+pub struct DomainRoutes(Arc<RwLock<HashMap<Fqdn, SocketAddr>>>);
+
+impl DomainRoutes {
+    pub fn insert(&self, domain: Fqdn, address: SocketAddr) {
+        // A poisoned lock only means that another insert panicked.
+        let mut routes = self.0.write().unwrap_or_else(|e| e.into_inner());
+        routes.insert(domain, address);
+    }
+
+    pub fn get(&self, domain: &Fqdn) -> Option<SocketAddr> {
+        let routes = self.0.read().unwrap_or_else(|e| e.into_inner());
+        routes.get(domain).copied()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MockNetworkProvider {
     client: Client,
+    resolver: TokioAsyncResolver,
     transport_encryption: TransportEncryption,
+    routes: DomainRoutes,
 }
 
 impl Default for MockNetworkProvider {
@@ -39,11 +78,62 @@ impl Default for MockNetworkProvider {
 
 impl MockNetworkProvider {
     pub fn new() -> Self {
+        // Fall back to public resolvers if the system configuration can't be
+        // read.
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|_| {
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+        });
         Self {
             client: Client::new(),
+            resolver,
             transport_encryption: TransportEncryption::Off,
+            routes: DomainRoutes::default(),
         }
     }
+
+    /// Route requests to the domains in `routes` to the backends in the same
+    /// process.
+    pub fn with_routes(routes: DomainRoutes) -> Self {
+        Self {
+            routes,
+            ..Self::new()
+        }
+    }
+
+    fn url(&self, destination: &Fqdn, path: &str) -> String {
+        if let Some(address) = self.routes.get(destination) {
+            return format!("http://{}{}", address, path);
+        }
+        let (transport_encryption, port) = match self.transport_encryption {
+            TransportEncryption::On => ("s", DEFAULT_PORT_HTTPS),
+            TransportEncryption::Off => ("", DEFAULT_PORT_HTTP),
+        };
+        format!(
+            "http{}://{}:{}{}",
+            transport_encryption, destination, port, path
+        )
+    }
+
+    /// Fetch the key record the given domain serves itself.
+    async fn fetch_published_key_record(
+        &self,
+        domain: &Fqdn,
+    ) -> Result<FederationKeyRecord, MockNetworkError> {
+        let url = self.url(domain, ENDPOINT_QS_KEY_FINGERPRINT);
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| MockNetworkError::RequestFailed(e.to_string()))?;
+        let text = response
+            .text()
+            .await
+            .map_err(|_| MockNetworkError::MalformedResponse)?;
+        text.parse()
+            .map_err(|_| MockNetworkError::MalformedResponse)
+    }
 }
 
 #[async_trait]
@@ -55,14 +145,7 @@ impl NetworkProvider for MockNetworkProvider {
         bytes: Vec<u8>,
         destination: Fqdn,
     ) -> Result<FederatedProcessingResult, Self::NetworkError> {
-        let (transport_encryption, port) = match self.transport_encryption {
-            TransportEncryption::On => ("s", DEFAULT_PORT_HTTPS),
-            TransportEncryption::Off => ("", DEFAULT_PORT_HTTP),
-        };
-        let url = format!(
-            "http{}://{}:{}{}",
-            transport_encryption, destination, port, ENDPOINT_QS_FEDERATION
-        );
+        let url = self.url(&destination, ENDPOINT_QS_FEDERATION);
         // Reqwest should resolve the hostname on its own.
         let result = match self.client.post(url).body(bytes).send().await {
             // For now we don't care about the response.
@@ -75,4 +158,25 @@ impl NetworkProvider for MockNetworkProvider {
         };
         Ok(result)
     }
+
+    async fn fetch_key_record(
+        &self,
+        domain: Fqdn,
+    ) -> Result<FederationKeyRecord, Self::NetworkError> {
+        // Backends in the same process have no DNS records. Since they are
+        // routed directly, there is no one in between to impersonate them.
+        if self.routes.get(&domain).is_some() {
+            return self.fetch_published_key_record(&domain).await;
+        }
+        let lookup = self
+            .resolver
+            .txt_lookup(FederationKeyRecord::dns_name(&domain))
+            .await
+            .map_err(|e| MockNetworkError::DnsLookupFailed(e.to_string()))?;
+        // The name might hold other TXT records as well.
+        lookup
+            .iter()
+            .find_map(|txt| txt.to_string().parse().ok())
+            .ok_or(MockNetworkError::MalformedResponse)
+    }
 }
@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Logging of the server.
+//!
+//! The server logs to stdout and, if configured, to rotated files, see
+//! [`rotation`]. Both outputs can be JSON or human-readable. The log filter
+//! can be changed at runtime via the [`LogFilterHandle`].
+
+use std::{io, sync::Mutex};
+
+use phnxbackend::settings::{LogFormat, LoggingSettings};
+use thiserror::Error;
+use tracing::{subscriber::set_global_default, Subscriber};
+use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_log::LogTracer;
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    layer::{Layered, SubscriberExt},
+    reload, EnvFilter, Layer, Registry,
+};
+
+use rotation::RotatingFile;
+
+pub mod rotation;
+
+/// The registry with the reloadable log filter, to which the output layers
+/// are added
+type FilteredRegistry =
+    Layered<JsonStorageLayer, Layered<reload::Layer<EnvFilter, Registry>, Registry>>;
+
+/// Helper function that allows silencing a number of chatty modules when using
+/// the info level "trace". This is meant to be used only for tests.
+#[cfg(test)]
+fn _silence_chatty_modules(env_filter: EnvFilter) -> EnvFilter {
+    env_filter
+        .add_directive("actix-web=info".parse().expect("error parsing directive"))
+        .add_directive(
+            "actix-web-actors=info"
+                .parse()
+                .expect("error parsing directive"),
+        )
+        .add_directive("actix=info".parse().expect("error parsing directive"))
+        .add_directive("tokio=info".parse().expect("error parsing directive"))
+        .add_directive(
+            "tokio-tungstenite=info"
+                .parse()
+                .expect("error parsing directive"),
+        )
+        .add_directive(
+            "tracing-actix-web=info"
+                .parse()
+                .expect("error parsing directive"),
+        )
+        .add_directive("tungstenite=info".parse().expect("error parsing directive"))
+        .add_directive("mio=info".parse().expect("error parsing directive"))
+        .add_directive("hyper=info".parse().expect("error parsing directive"))
+        .add_directive("want=info".parse().expect("error parsing directive"))
+}
+
+/// Build a subscriber for the server's tracing events from multiple layers.
+pub fn get_subscriber<Sink>(
+    name: String,
+    env_filter: String,
+    sink: Sink,
+) -> impl Subscriber + Send + Sync
+where
+    Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    // Default to "info" level logging.
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
+    // Silence a few very chatty modules, such that "trace" actualy becomes useful
+    // Write everything to stdout for now.
+    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    // Let's build the tracing subscriber.
+    Registry::default()
+        .with(env_filter)
+        .with(JsonStorageLayer)
+        .with(formatting_layer)
+}
+
+/// Build a subscriber for the server's tracing events according to the given
+/// settings. The returned handle changes the log filter at runtime.
+pub fn get_configured_subscriber(
+    name: String,
+    settings: &LoggingSettings,
+) -> io::Result<(impl Subscriber + Send + Sync, LogFilterHandle)> {
+    let env_filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(filter_directives(settings)))
+        .map_err(io::Error::other)?;
+    let (filter_layer, handle) = reload::Layer::new(env_filter);
+
+    let mut outputs: Vec<Box<dyn Layer<FilteredRegistry> + Send + Sync>> = Vec::new();
+    if settings.stdout {
+        outputs.push(output_layer(
+            name.clone(),
+            settings.stdout_format,
+            true,
+            std::io::stdout,
+        ));
+    }
+    if let Some(file_settings) = &settings.file {
+        let file = RotatingFile::open(file_settings)?;
+        outputs.push(output_layer(
+            name,
+            file_settings.format,
+            false,
+            Mutex::new(file),
+        ));
+    }
+
+    let subscriber = Registry::default()
+        .with(filter_layer)
+        .with(JsonStorageLayer)
+        .with(outputs);
+    Ok((subscriber, LogFilterHandle { handle }))
+}
+
+/// The directives of the log filter configured in the given settings, e.g.
+/// `info,phnxbackend::qs=debug`.
+fn filter_directives(settings: &LoggingSettings) -> String {
+    let mut directives = settings.level.clone();
+    for (module, level) in &settings.modules {
+        directives.push_str(&format!(",{module}={level}"));
+    }
+    directives
+}
+
+fn output_layer<W>(
+    name: String,
+    format: LogFormat,
+    ansi: bool,
+    writer: W,
+) -> Box<dyn Layer<FilteredRegistry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Json => BunyanFormattingLayer::new(name, writer).boxed(),
+        LogFormat::Human => tracing_subscriber::fmt::layer()
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LogFilterError {
+    #[error("Invalid log filter: {0}")]
+    InvalidFilter(#[from] tracing_subscriber::filter::ParseError),
+    #[error("Failed to replace the log filter: {0}")]
+    Reload(#[from] reload::Error),
+}
+
+/// Handle to inspect and replace the log filter of a running server.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilterHandle {
+    /// The directives of the current log filter.
+    pub fn current(&self) -> Result<String, LogFilterError> {
+        Ok(self.handle.with_current(|filter| filter.to_string())?)
+    }
+
+    /// Replace the log filter with the given directives, e.g.
+    /// `info,phnxbackend::qs=debug`.
+    pub fn set(&self, directives: &str) -> Result<(), LogFilterError> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.handle.reload(filter)?;
+        tracing::info!(directives, "Changed log filter");
+        Ok(())
+    }
+}
+
+/// Register a subscriber as global default to process span data.
+pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
+    LogTracer::init().expect("Failed to set logger");
+    set_global_default(subscriber).expect("Failed to set subscriber");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_levels_become_directives() {
+        let mut settings = LoggingSettings::default();
+        assert_eq!(filter_directives(&settings), "info");
+
+        settings
+            .modules
+            .insert("phnxbackend::qs".to_owned(), "debug".to_owned());
+        settings
+            .modules
+            .insert("sqlx".to_owned(), "warn".to_owned());
+        let directives = filter_directives(&settings);
+        assert_eq!(directives, "info,phnxbackend::qs=debug,sqlx=warn");
+        assert!(EnvFilter::try_new(directives).is_ok());
+    }
+}
@@ -0,0 +1,248 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Log files that are rotated by time and size.
+//!
+//! Logs are written to `<directory>/<file_name>`. When the rotation interval
+//! passes or the file would exceed its maximum size, the file is renamed to
+//! `<file_name>.<time of rotation>` and a new file is started. Only the
+//! configured number of rotated files is kept.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use phnxbackend::settings::{LogFileSettings, LogRotation};
+
+/// Format of the time appended to rotated files.
+const ROTATED_SUFFIX_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+pub struct RotatingFile {
+    directory: PathBuf,
+    file_name: String,
+    rotation: LogRotation,
+    max_file_bytes: Option<u64>,
+    max_files: Option<usize>,
+    file: File,
+    size: u64,
+    /// Rotation period the current file belongs to
+    period: String,
+}
+
+impl RotatingFile {
+    /// Open the log file, appending to it if it exists.
+    pub fn open(settings: &LogFileSettings) -> io::Result<Self> {
+        fs::create_dir_all(&settings.directory)?;
+        let path = settings.directory.join(&settings.file_name);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let modified: DateTime<Utc> = metadata.modified()?.into();
+        Ok(Self {
+            directory: settings.directory.clone(),
+            file_name: settings.file_name.clone(),
+            rotation: settings.rotation,
+            max_file_bytes: settings.max_file_bytes,
+            max_files: settings.max_files,
+            file,
+            size: metadata.len(),
+            period: period(settings.rotation, modified),
+        })
+    }
+
+    fn needs_rotation(&self, now: DateTime<Utc>, len: usize) -> bool {
+        let too_large = self
+            .max_file_bytes
+            .is_some_and(|max| self.size > 0 && self.size + len as u64 > max);
+        too_large || period(self.rotation, now) != self.period
+    }
+
+    /// Rename the current file and start a new one.
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        self.file.flush()?;
+        let path = self.directory.join(&self.file_name);
+        let suffix = now.format(ROTATED_SUFFIX_FORMAT).to_string();
+        let mut rotated = self.directory.join(format!("{}.{suffix}", self.file_name));
+        // Files rotated within the same second because of their size
+        let mut counter = 1;
+        while rotated.exists() {
+            rotated = self
+                .directory
+                .join(format!("{}.{suffix}.{counter}", self.file_name));
+            counter += 1;
+        }
+        fs::rename(&path, rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.size = 0;
+        self.period = period(self.rotation, now);
+        self.delete_old_files()
+    }
+
+    fn delete_old_files(&self) -> io::Result<()> {
+        let Some(max_files) = self.max_files else {
+            return Ok(());
+        };
+        let mut rotated: Vec<(RotationKey, PathBuf)> = fs::read_dir(&self.directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let key = rotation_key(&self.file_name, &entry.file_name().to_string_lossy())?;
+                Some((key, entry.path()))
+            })
+            .collect();
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(max_files);
+        for (_, path) in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Time of rotation and counter of a rotated file. Orders files
+/// chronologically.
+type RotationKey = (NaiveDateTime, u32);
+
+/// Parse the key from the name of a file rotated from `file_name`. Returns
+/// `None` for all other files.
+fn rotation_key(file_name: &str, name: &str) -> Option<RotationKey> {
+    let suffix = name.strip_prefix(file_name)?.strip_prefix('.')?;
+    let (time, counter) = match suffix.split_once('.') {
+        Some((time, counter)) => (time, counter.parse().ok()?),
+        None => (suffix, 0),
+    };
+    let time = NaiveDateTime::parse_from_str(time, ROTATED_SUFFIX_FORMAT).ok()?;
+    Some((time, counter))
+}
+
+impl Write for RotatingFile {
+    /// Writes the whole buffer to one file. Formatters write each event with
+    /// a single call, so events are never split across files.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Utc::now();
+        if self.needs_rotation(now, buf.len()) {
+            // Losing the rotation is better than losing the logs.
+            if let Err(error) = self.rotate(now) {
+                eprintln!("Failed to rotate log file: {error}");
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn period(rotation: LogRotation, time: DateTime<Utc>) -> String {
+    match rotation {
+        LogRotation::Never => String::new(),
+        LogRotation::Hourly => time.format("%Y-%m-%dT%H").to_string(),
+        LogRotation::Daily => time.format("%Y-%m-%d").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_files(settings: &LogFileSettings) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&settings.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_max_files() {
+        let directory =
+            std::env::temp_dir().join(format!("phnx-log-rotation-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let settings = LogFileSettings {
+            directory: directory.clone(),
+            file_name: "server.log".to_owned(),
+            rotation: LogRotation::Never,
+            max_file_bytes: Some(10),
+            max_files: Some(2),
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(&settings).unwrap();
+        for _ in 0..5 {
+            file.write_all(b"12345678\n").unwrap();
+        }
+
+        let names = log_files(&settings);
+        // The current file and the two latest rotated ones
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "server.log");
+        assert!(names[1..]
+            .iter()
+            .all(|name| name.starts_with("server.log.")));
+        let current = fs::read(directory.join("server.log")).unwrap();
+        assert_eq!(current, b"12345678\n");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn deletes_oldest_rotated_files() {
+        let directory =
+            std::env::temp_dir().join(format!("phnx-log-deletion-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let settings = LogFileSettings {
+            directory: directory.clone(),
+            file_name: "server.log".to_owned(),
+            rotation: LogRotation::Never,
+            max_files: Some(2),
+            ..Default::default()
+        };
+        let file = RotatingFile::open(&settings).unwrap();
+        for name in [
+            "server.log.2024-10-26T09-30-00.2",
+            "server.log.2024-10-26T09-30-00.10",
+            "server.log.2024-10-26T09-30-00",
+            "server.log.2024-10-25T23-00-00.11",
+            "server.log.backup",
+        ] {
+            fs::write(directory.join(name), b"").unwrap();
+        }
+        file.delete_old_files().unwrap();
+
+        // Files that weren't rotated are kept
+        assert_eq!(
+            log_files(&settings),
+            [
+                "server.log",
+                "server.log.2024-10-26T09-30-00.10",
+                "server.log.2024-10-26T09-30-00.2",
+                "server.log.backup",
+            ]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn periods() {
+        let time: DateTime<Utc> = "2024-10-26T09:30:00Z".parse().unwrap();
+        let later: DateTime<Utc> = "2024-10-26T10:00:00Z".parse().unwrap();
+        assert_eq!(
+            period(LogRotation::Never, time),
+            period(LogRotation::Never, later)
+        );
+        assert_eq!(
+            period(LogRotation::Daily, time),
+            period(LogRotation::Daily, later)
+        );
+        assert_ne!(
+            period(LogRotation::Hourly, time),
+            period(LogRotation::Hourly, later)
+        );
+    }
+}
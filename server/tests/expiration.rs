@@ -0,0 +1,38 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Tests of expiration flows. They fast-forward the process-wide clock and
+//! therefore live in their own test binary.
+
+use phnxserver_test_harness::utils::{clock::TestClock, setup::TestBackend};
+use phnxtypes::{
+    identifiers::{QualifiedUserName, SafeTryInto},
+    time::Duration,
+};
+
+const ALICE: &str = "alice@example.com";
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Client credential expiration test", skip_all)]
+async fn client_credential_refresh_after_fast_forward() {
+    let clock = TestClock::install();
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+
+    let alice_name: QualifiedUserName = SafeTryInto::try_into(ALICE).unwrap();
+    let alice = &setup.users.get(&alice_name).unwrap().user;
+    assert!(!alice.client_credential_needs_refresh().await.unwrap());
+
+    // The client credential is valid for 90 days and is due for renewal two
+    // weeks before it expires.
+    clock.advance(Duration::days(80));
+    assert!(alice.client_credential_needs_refresh().await.unwrap());
+    let expiration = alice.client_credential_expiration().await.unwrap();
+    assert!(*expiration > clock.now());
+
+    alice.refresh_client_credential().await.unwrap();
+    assert!(!alice.client_credential_needs_refresh().await.unwrap());
+    let renewed_expiration = alice.client_credential_expiration().await.unwrap();
+    assert!(renewed_expiration > expiration);
+}
@@ -0,0 +1,267 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Tests of the headless sync client. Its users keep their databases in a
+//! directory of their own instead of the working directory.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use opaque_ke::rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use phnxcoreclient::{
+    ConversationId, ConversationMessage, HeadlessSyncClient, MembershipChange,
+    MembershipChangeKind, Message, MimiContent, Outbox, SyncHooks, SyncTrigger,
+};
+use phnxserver_test_harness::utils::setup::TestBackend;
+use phnxtypes::identifiers::{QualifiedUserName, SafeTryInto};
+use tokio::sync::{mpsc, oneshot};
+
+const ALICE: &str = "alice@example.com";
+const BOB: &str = "bob@example.com";
+const BOT: &str = "bot@example.com";
+
+/// Seconds to wait for a hook to be called by the sync loop
+const HOOK_TIMEOUT: u64 = 10;
+
+#[derive(Debug)]
+enum SyncEvent {
+    NewConversation(ConversationId),
+    MembershipChange(MembershipChange),
+    Message(ConversationMessage),
+    SyncError(String),
+}
+
+/// Forwards the calls of the hooks to a channel.
+struct ChannelHooks(mpsc::UnboundedSender<SyncEvent>);
+
+impl SyncHooks for ChannelHooks {
+    fn on_new_conversation(&self, conversation_id: ConversationId) {
+        let _ = self.0.send(SyncEvent::NewConversation(conversation_id));
+    }
+
+    fn on_membership_change(&self, change: &MembershipChange) {
+        let _ = self.0.send(SyncEvent::MembershipChange(change.clone()));
+    }
+
+    fn on_message(&self, message: &ConversationMessage) {
+        let _ = self.0.send(SyncEvent::Message(message.clone()));
+    }
+
+    fn on_sync_error(&self, error: &anyhow::Error) {
+        let _ = self.0.send(SyncEvent::SyncError(error.to_string()));
+    }
+}
+
+/// A fresh data directory in the temporary directory of the system.
+fn data_dir() -> PathBuf {
+    let name: String = OsRng
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    std::env::temp_dir().join(format!("phnx-headless-{name}"))
+}
+
+async fn register_bot(
+    setup: &TestBackend,
+    data_dir: PathBuf,
+) -> (
+    HeadlessSyncClient<ChannelHooks>,
+    mpsc::UnboundedReceiver<SyncEvent>,
+) {
+    let (sender, events) = mpsc::unbounded_channel();
+    let server_url = format!("http://{}", setup.url().unwrap());
+    let bot = HeadlessSyncClient::register(data_dir, BOT, BOT, server_url, ChannelHooks(sender))
+        .await
+        .unwrap();
+    (bot, events)
+}
+
+/// Events reported since the last call.
+fn drain(events: &mut mpsc::UnboundedReceiver<SyncEvent>) -> Vec<SyncEvent> {
+    let mut drained = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        drained.push(event);
+    }
+    drained
+}
+
+/// Wait until the sync loop reports an event that matches `predicate`.
+async fn wait_for(
+    events: &mut mpsc::UnboundedReceiver<SyncEvent>,
+    predicate: impl Fn(&SyncEvent) -> bool,
+) -> SyncEvent {
+    let wait = async {
+        loop {
+            let event = events.recv().await.expect("Hooks were dropped");
+            if predicate(&event) {
+                return event;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(HOOK_TIMEOUT), wait)
+        .await
+        .expect("Hook wasn't called in time")
+}
+
+fn text_of(message: &ConversationMessage) -> Option<String> {
+    match message.message() {
+        Message::Content(content_message) => Some(content_message.content().string_rendering()),
+        Message::Event(_) => None,
+    }
+}
+
+fn user_name(user_name: &str) -> QualifiedUserName {
+    SafeTryInto::try_into(user_name).unwrap()
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Headless sync hooks test", skip_all)]
+async fn headless_sync_calls_hooks() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let data_dir = data_dir();
+    let (bot, mut events) = register_bot(&setup, data_dir.clone()).await;
+
+    // A connection shows up as a new conversation
+    let alice = &setup.get_user(ALICE).user;
+    let connection_id = alice.add_contact(BOT).await.unwrap();
+    let summary = bot.sync().await.unwrap();
+    assert_eq!(summary.new_conversations, 1);
+    assert!(drain(&mut events)
+        .iter()
+        .any(|event| matches!(event, SyncEvent::NewConversation(id) if *id == connection_id)));
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Received messages are reported once
+    let text = "Hello bot".to_owned();
+    let content = MimiContent::simple_markdown_message(alice.user_name().domain(), text.clone());
+    alice.send_message(connection_id, content).await.unwrap();
+    let summary = bot.sync().await.unwrap();
+    let messages: Vec<_> = drain(&mut events)
+        .into_iter()
+        .filter_map(|event| match event {
+            SyncEvent::Message(message) => Some(message),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(summary.new_messages, messages.len());
+    assert!(messages
+        .iter()
+        .any(|message| message.conversation_id() == connection_id
+            && text_of(message).as_deref() == Some(text.as_str())));
+    assert_eq!(bot.sync().await.unwrap().new_messages, 0);
+    assert!(!drain(&mut events)
+        .iter()
+        .any(|event| matches!(event, SyncEvent::Message(_))));
+
+    // Being invited to a group and changes of its members are reported
+    setup.connect_users(ALICE, BOB).await;
+    let alice = &setup.get_user(ALICE).user;
+    let group_id = alice.create_conversation("Bot group", None).await.unwrap();
+    alice
+        .invite_users(group_id, &[user_name(BOT)])
+        .await
+        .unwrap();
+    bot.sync().await.unwrap();
+    assert!(drain(&mut events)
+        .iter()
+        .any(|event| matches!(event, SyncEvent::NewConversation(id) if *id == group_id)));
+    alice
+        .invite_users(group_id, &[user_name(BOB)])
+        .await
+        .unwrap();
+    let summary = bot.sync().await.unwrap();
+    assert_eq!(summary.membership_changes, 1);
+    let changes: Vec<_> = drain(&mut events)
+        .into_iter()
+        .filter_map(|event| match event {
+            SyncEvent::MembershipChange(change) => Some(change),
+            _ => None,
+        })
+        .collect();
+    let [change] = changes.as_slice() else {
+        panic!("Expected a single membership change, got {changes:?}");
+    };
+    assert_eq!(change.conversation_id(), group_id);
+    assert_eq!(change.kind(), MembershipChangeKind::Add);
+    assert_eq!(change.sender(), &user_name(ALICE));
+    assert_eq!(change.member(), &user_name(BOB));
+
+    // Messages enqueued via the outbox are sent by the next sync
+    let db_path = data_dir.to_str().unwrap();
+    let outbox = Outbox::open(bot.user().as_client_id(), db_path).unwrap();
+    let reply = "Hello Alice".to_owned();
+    let content = MimiContent::simple_markdown_message(bot.user().user_name().domain(), reply);
+    outbox
+        .enqueue_message(connection_id, content)
+        .await
+        .unwrap();
+    let summary = bot.sync().await.unwrap();
+    assert_eq!(summary.sent_messages, 1);
+    assert_eq!(bot.user().pending_outbox_messages().await.unwrap(), 0);
+
+    drop(bot);
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Headless sync loop test", skip_all)]
+async fn headless_sync_loop_runs_until_shutdown() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    let data_dir = data_dir();
+    let (bot, mut events) = register_bot(&setup, data_dir.clone()).await;
+    let alice = &setup.get_user(ALICE).user;
+
+    let (shutdown_sender, shutdown) = oneshot::channel::<()>();
+    let trigger = SyncTrigger::Interval(Duration::from_millis(100));
+    let run = bot.run(trigger, async {
+        let _ = shutdown.await;
+    });
+    let drive = async {
+        let connection_id = alice.add_contact(BOT).await.unwrap();
+        wait_for(
+            &mut events,
+            |event| matches!(event, SyncEvent::NewConversation(id) if *id == connection_id),
+        )
+        .await;
+        let qs_messages = alice.qs_fetch_messages().await.unwrap();
+        alice.fully_process_qs_messages(qs_messages).await.unwrap();
+
+        let text = "Are you there?".to_owned();
+        let content =
+            MimiContent::simple_markdown_message(alice.user_name().domain(), text.clone());
+        alice.send_message(connection_id, content).await.unwrap();
+        wait_for(&mut events, |event| {
+            matches!(event, SyncEvent::Message(message)
+                if text_of(message).as_deref() == Some(text.as_str()))
+        })
+        .await;
+
+        // A sync that fails is reported and the loop keeps running
+        bot.user().delete_account(BOT).await.unwrap();
+        wait_for(&mut events, |event| {
+            matches!(event, SyncEvent::SyncError(_))
+        })
+        .await;
+        wait_for(&mut events, |event| {
+            matches!(event, SyncEvent::SyncError(_))
+        })
+        .await;
+
+        shutdown_sender.send(()).unwrap();
+    };
+
+    // `run` only returns once the shutdown future completed.
+    tokio::time::timeout(Duration::from_secs(4 * HOOK_TIMEOUT), async {
+        tokio::join!(run, drive)
+    })
+    .await
+    .expect("The sync loop didn't shut down");
+
+    drop(bot);
+    fs::remove_dir_all(data_dir).unwrap();
+}
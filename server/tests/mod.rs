@@ -4,19 +4,31 @@
 
 mod qs;
 
-use std::{fs, io::Cursor};
+use std::{collections::HashSet, fs, io::Cursor};
 
 use image::{ImageBuffer, Rgba};
 use opaque_ke::rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use phnxapiclient::ApiClient;
 
 use phnxcoreclient::{
-    clients::CoreUser, Asset, ConversationId, ConversationMessage, DisplayName, MimiContent,
-    UserProfile,
+    clients::CoreUser, Asset, ConversationId, ConversationMessage, DisplayName, FilterReason,
+    MembershipChangeKind, Message, MessageArchiving, MimiContent, UserProfile, UserSetting,
 };
 use phnxserver::network_provider::MockNetworkProvider;
-use phnxserver_test_harness::utils::{setup::TestBackend, spawn_app};
-use phnxtypes::identifiers::{Fqdn, QualifiedUserName, SafeTryInto};
+use phnxserver_test_harness::utils::{setup::TestBackend, spawn_app, spawn_multi_tenant_app};
+use phnxtypes::{
+    endpoint_paths::{ENDPOINT_HEALTH_CHECK, ENDPOINT_QS_KEY_FINGERPRINT},
+    identifiers::{Fqdn, QualifiedUserName, SafeTryInto},
+    messages::{
+        attachments::{AttachmentDownload, QualifiedAttachmentUrl},
+        client_as::SenderReputation,
+        data_export::DataExportStatus,
+        presence::{Presence, PresenceStatus, PresenceVisibility},
+        room_policy::{CommitRule, JoinRule, RoomPolicyChange, SendRule},
+        server_info::RegistrationPolicy,
+        QueuePriority,
+    },
+};
 use png::Encoder;
 
 #[actix_rt::test]
@@ -36,6 +48,23 @@ async fn health_check_works() {
     assert!(client.health_check().await);
 }
 
+#[actix_rt::test]
+#[tracing::instrument(name = "Server info test", skip_all)]
+async fn server_info_is_public() {
+    let network_provider = MockNetworkProvider::new();
+    let (address, _ws_dispatch) =
+        spawn_app(Fqdn::try_from("example.com").unwrap(), network_provider).await;
+    let client =
+        ApiClient::initialize(format!("http://{}", address)).expect("Failed to initialize client");
+
+    let server_info = client.as_server_info().await.unwrap();
+    assert!(server_info.negotiate_version().is_some());
+    assert_eq!(server_info.registration_policy, RegistrationPolicy::Open);
+    assert!(server_info.motd.is_none());
+    assert!(server_info.metrics_endpoint.is_none());
+    assert!(server_info.send_rate_limit.is_some());
+}
+
 const ALICE: &str = "alice@example.com";
 const BOB: &str = "bob@example.com";
 const CHARLIE: &str = "charlie@example.com";
@@ -182,6 +211,78 @@ async fn leave_group() {
     setup.leave_group(conversation_id, ALICE).await;
 }
 
+#[actix_rt::test]
+#[tracing::instrument(name = "Membership history test", skip_all)]
+async fn membership_history_records_all_commit_kinds() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.add_user(CHARLIE).await;
+
+    // Most recent changes first, as (kind, sender, member).
+    async fn history(
+        setup: &TestBackend,
+        user_name: &str,
+        conversation_id: ConversationId,
+    ) -> Vec<(MembershipChangeKind, String, String)> {
+        setup
+            .get_user(user_name)
+            .user
+            .membership_history(conversation_id, 0, u32::MAX)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|change| {
+                (
+                    change.kind(),
+                    change.sender().to_string(),
+                    change.member().to_string(),
+                )
+            })
+            .collect()
+    }
+    use MembershipChangeKind::{Add, Remove};
+    let change = |kind, sender: &str, member: &str| (kind, sender.to_owned(), member.to_owned());
+
+    // Bob joins the connection group with an external commit. He records
+    // his own join and so does Alice when she processes the commit.
+    let connection_id = setup.connect_users(ALICE, BOB).await;
+    setup.connect_users(ALICE, CHARLIE).await;
+    let bob_joined = vec![change(Add, BOB, BOB)];
+    assert_eq!(history(&setup, ALICE, connection_id).await, bob_joined);
+    assert_eq!(history(&setup, BOB, connection_id).await, bob_joined);
+
+    // Bob and Charlie join the group with a welcome.
+    let conversation_id = setup.create_group(ALICE).await;
+    setup
+        .invite_to_group(conversation_id, ALICE, vec![BOB, CHARLIE])
+        .await;
+    let alice_history = history(&setup, ALICE, conversation_id).await;
+    assert_eq!(alice_history.len(), 2);
+    assert!(alice_history.contains(&change(Add, ALICE, BOB)));
+    assert!(alice_history.contains(&change(Add, ALICE, CHARLIE)));
+    assert_eq!(
+        history(&setup, BOB, conversation_id).await,
+        vec![change(Add, ALICE, BOB)]
+    );
+
+    // Alice removes Charlie.
+    setup
+        .remove_from_group(conversation_id, ALICE, vec![CHARLIE])
+        .await;
+    assert_eq!(
+        history(&setup, BOB, conversation_id).await,
+        vec![change(Remove, ALICE, CHARLIE), change(Add, ALICE, BOB)]
+    );
+
+    // Bob leaves, which Alice commits.
+    setup.leave_group(conversation_id, BOB).await;
+    assert_eq!(
+        history(&setup, ALICE, conversation_id).await[..2],
+        [change(Remove, BOB, BOB), change(Remove, ALICE, CHARLIE)]
+    );
+}
+
 #[actix_rt::test]
 #[tracing::instrument(name = "Invite to group test", skip_all)]
 async fn delete_group() {
@@ -196,6 +297,76 @@ async fn delete_group() {
     setup.delete_group(conversation_id, BOB).await;
 }
 
+#[actix_rt::test]
+#[tracing::instrument(name = "Virtual domains test", skip_all)]
+async fn federated_group_operations() {
+    let domains: Vec<Fqdn> = ["alpha.example.com", "beta.example.com", "gamma.example.com"]
+        .into_iter()
+        .map(|domain| Fqdn::try_from(domain).unwrap())
+        .collect();
+    let mut setup = TestBackend::virtual_domains(&domains).await;
+    let (alice, bob, charlie) = (
+        format!("alice@{}", domains[0]),
+        format!("bob@{}", domains[1]),
+        format!("charlie@{}", domains[2]),
+    );
+    let (alice, bob, charlie) = (alice.as_str(), bob.as_str(), charlie.as_str());
+    setup.add_user(alice).await;
+    setup.add_user(bob).await;
+    setup.add_user(charlie).await;
+
+    // Connecting fetches the AS credentials of the other domain.
+    let conversation_id = setup.connect_users(alice, bob).await;
+    setup.send_message(conversation_id, alice, vec![bob]).await;
+    setup.connect_users(bob, charlie).await;
+
+    // The group lives on alice's domain and fans out to the other two.
+    let conversation_id = setup.create_group(alice).await;
+    setup
+        .invite_to_group(conversation_id, alice, vec![bob])
+        .await;
+    setup
+        .invite_to_group(conversation_id, bob, vec![charlie])
+        .await;
+    setup
+        .send_message(conversation_id, charlie, vec![alice, bob])
+        .await;
+    setup
+        .remove_from_group(conversation_id, charlie, vec![alice])
+        .await;
+    setup.leave_group(conversation_id, bob).await;
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Connection offer screening test", skip_all)]
+async fn offers_of_new_foreign_users_are_filtered() {
+    let domains: Vec<Fqdn> = ["alpha.example.com", "beta.example.com"]
+        .into_iter()
+        .map(|domain| Fqdn::try_from(domain).unwrap())
+        .collect();
+    let mut setup = TestBackend::virtual_domains(&domains).await;
+    let alice_name: QualifiedUserName =
+        SafeTryInto::try_into(format!("alice@{}", domains[0]).as_str()).unwrap();
+    let bob_name: QualifiedUserName =
+        SafeTryInto::try_into(format!("bob@{}", domains[1]).as_str()).unwrap();
+    setup.add_user(alice_name.clone()).await;
+    setup.add_user(bob_name.clone()).await;
+
+    let alice = &setup.users.get(&alice_name).unwrap().user;
+    alice.add_contact(bob_name.clone()).await.unwrap();
+
+    // Alice's offer carries the attestation of her AS that she registered
+    // recently.
+    let bob = &setup.users.get(&bob_name).unwrap().user;
+    let as_messages = bob.as_fetch_messages().await.unwrap();
+    bob.fully_process_as_messages(as_messages).await.unwrap();
+    assert!(bob.contacts().await.unwrap().is_empty());
+    let filtered = bob.filtered_message_requests().await.unwrap();
+    assert_eq!(filtered.len(), 1);
+    assert_eq!(filtered[0].user_name(), &alice_name);
+    assert_eq!(filtered[0].filter_reason(), Some(FilterReason::NewSender));
+}
+
 #[actix_rt::test]
 #[tracing::instrument(name = "Create user", skip_all)]
 async fn create_user() {
@@ -218,6 +389,54 @@ async fn inexistant_endpoint() {
     assert!(client.inexistant_endpoint().await);
 }
 
+#[actix_rt::test]
+#[tracing::instrument(name = "Tenants are routed by host", skip_all)]
+async fn tenants_are_routed_by_host() {
+    let domains = [
+        Fqdn::try_from("a.example.com").unwrap(),
+        Fqdn::try_from("b.example.com").unwrap(),
+    ];
+    let network_provider = MockNetworkProvider::new();
+    let (address, _ws_dispatch) = spawn_multi_tenant_app(&domains, network_provider).await;
+
+    let client = reqwest::Client::new();
+    let get = |host: &'static str, path: &'static str| {
+        client
+            .get(format!("http://{address}{path}"))
+            .header(reqwest::header::HOST, host)
+            .send()
+    };
+
+    // Each domain is served by its own QS with its own key.
+    let fingerprint_a = get("a.example.com", ENDPOINT_QS_KEY_FINGERPRINT)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let fingerprint_b = get("b.example.com", ENDPOINT_QS_KEY_FINGERPRINT)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_ne!(fingerprint_a, fingerprint_b);
+
+    // Unknown domains aren't served.
+    let response = get("c.example.com", ENDPOINT_QS_KEY_FINGERPRINT)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+    // The health check doesn't depend on the domain.
+    let response = get("c.example.com", ENDPOINT_HEALTH_CHECK).await.unwrap();
+    assert!(response.status().is_success());
+}
+
 #[actix_rt::test]
 #[tracing::instrument(name = "Full cycle", skip_all)]
 async fn full_cycle() {
@@ -660,3 +879,641 @@ async fn error_if_user_doesnt_exist() {
 
     assert!(res.is_err());
 }
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Refresh client credential test", skip_all)]
+async fn refresh_client_credential() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let conversation_id = setup.connect_users(ALICE, BOB).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    assert!(!alice.client_credential_needs_refresh().await.unwrap());
+    alice.refresh_client_credential().await.unwrap();
+
+    // Bob learns about Alice's new client credential via her update commit.
+    let bob = &mut setup
+        .users
+        .get_mut(&SafeTryInto::try_into(BOB).unwrap())
+        .unwrap()
+        .user;
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages)
+        .await
+        .expect("Error processing qs messages.");
+
+    // Alice's requests are signed with the new key from now on.
+    setup.send_message(conversation_id, ALICE, vec![BOB]).await;
+    setup.send_message(conversation_id, BOB, vec![ALICE]).await;
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Unconfirmed client credential refresh test", skip_all)]
+async fn unconfirmed_client_credential_refresh() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let conversation_id = setup.connect_users(ALICE, BOB).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    // The AS keeps accepting the old key until the new credential is
+    // confirmed.
+    alice.refresh_client_credential_unconfirmed().await.unwrap();
+    alice.as_fetch_messages().await.unwrap();
+
+    // The next refresh confirms the pending credential.
+    alice.refresh_client_credential().await.unwrap();
+    alice.as_fetch_messages().await.unwrap();
+
+    let bob = &mut setup
+        .users
+        .get_mut(&SafeTryInto::try_into(BOB).unwrap())
+        .unwrap()
+        .user;
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages)
+        .await
+        .expect("Error processing qs messages.");
+
+    setup.send_message(conversation_id, ALICE, vec![BOB]).await;
+    setup.send_message(conversation_id, BOB, vec![ALICE]).await;
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Data export test", skip_all)]
+async fn data_export() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    let (export_id, decryption_key) = alice.request_data_export().await.unwrap();
+
+    // The export is assembled in the background.
+    let mut status = alice.data_export_status(export_id).await.unwrap();
+    for _ in 0..50 {
+        if status != DataExportStatus::Pending {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        status = alice.data_export_status(export_id).await.unwrap();
+    }
+    assert!(matches!(status, DataExportStatus::Ready(_)));
+
+    let export = alice
+        .download_data_export(export_id, &decryption_key)
+        .await
+        .unwrap();
+    assert_eq!(export.user_name, alice.user_name());
+    assert_eq!(export.handles.len(), 1);
+    assert_eq!(export.handles[0].handle, alice.user_name());
+    assert_eq!(export.sender_reputation, SenderReputation::New);
+    assert_eq!(export.clients.len(), 1);
+    assert_eq!(export.clients[0].client_id, alice.as_client_id());
+}
+
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+struct SyncedTestSetting(u32);
+
+impl UserSetting for SyncedTestSetting {
+    const KEY: &'static str = "synced_test_setting";
+    const SYNCED: bool = true;
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "User settings sync test", skip_all)]
+async fn user_settings_sync() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    // Nothing to sync yet
+    alice.sync_user_settings().await.unwrap();
+
+    // Setting a synced setting uploads it.
+    alice.set_user_setting(&SyncedTestSetting(1)).await.unwrap();
+    alice.set_user_setting(&SyncedTestSetting(2)).await.unwrap();
+    alice.sync_user_settings().await.unwrap();
+    let setting: SyncedTestSetting = alice.user_setting().await.unwrap();
+    assert_eq!(setting, SyncedTestSetting(2));
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Delete account test", skip_all)]
+async fn delete_account() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let conversation_id = setup.connect_users(ALICE, BOB).await;
+
+    // Nothing is erased with a wrong password.
+    let alice_name: QualifiedUserName = SafeTryInto::try_into(ALICE).unwrap();
+    let alice = &setup.users.get(&alice_name).unwrap().user;
+    assert!(alice.delete_account("wrong password").await.is_err());
+    setup.send_message(conversation_id, ALICE, vec![BOB]).await;
+
+    let alice = &setup.users.get(&alice_name).unwrap().user;
+    let erasure = alice.delete_account(&alice_name.to_string()).await.unwrap();
+    assert_eq!(erasure.groups_left, 1);
+
+    // Bob learns that Alice left the connection group.
+    let bob = &mut setup
+        .users
+        .get_mut(&SafeTryInto::try_into(BOB).unwrap())
+        .unwrap()
+        .user;
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages)
+        .await
+        .expect("Error processing qs messages.");
+
+    // Alice can't be contacted anymore.
+    assert!(bob.add_contact(ALICE).await.is_err());
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Conversation security status test", skip_all)]
+async fn conversation_security_status() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let conversation_id = setup.connect_users(ALICE, BOB).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    let status = alice
+        .conversation_security_status(conversation_id)
+        .await
+        .unwrap();
+    assert!(status.is_healthy());
+    assert!(status.members_with_unknown_credentials.is_empty());
+    assert!(status.last_key_rotation.is_some());
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Contact presence test", skip_all)]
+async fn contact_presence() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.connect_users(ALICE, BOB).await;
+
+    let alice_name: QualifiedUserName = SafeTryInto::try_into(ALICE).unwrap();
+    let bob_name: QualifiedUserName = SafeTryInto::try_into(BOB).unwrap();
+    let alice = &setup.users.get(&alice_name).unwrap().user;
+    let bob = &setup.users.get(&bob_name).unwrap().user;
+
+    alice
+        .publish_presence(PresenceStatus::Online)
+        .await
+        .unwrap();
+    let changed = bob.refresh_contact_presence().await.unwrap();
+    assert_eq!(changed, vec![alice_name.clone()]);
+    let presence = bob.contact_presence(&alice_name).unwrap();
+    assert_eq!(presence.status, PresenceStatus::Online);
+
+    // Alice hides her presence, so Bob sees her as offline without knowing
+    // when she was last seen.
+    alice
+        .set_presence_visibility(PresenceVisibility::Nobody)
+        .await
+        .unwrap();
+    bob.refresh_contact_presence().await.unwrap();
+    assert_eq!(bob.contact_presence(&alice_name), Some(Presence::hidden()));
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "View-once attachment test", skip_all)]
+async fn view_once_attachments_are_encrypted() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let conversation_id = setup.connect_users(ALICE, BOB).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    let bob = &setup
+        .users
+        .get(&SafeTryInto::try_into(BOB).unwrap())
+        .unwrap()
+        .user;
+
+    let plaintext = b"view-once attachment content".repeat(8);
+    let message = alice
+        .send_view_once_attachment(conversation_id, plaintext.clone())
+        .await
+        .unwrap();
+    let Message::Content(content_message) = message.message() else {
+        panic!("Expected a content message");
+    };
+    let attachment_url = content_message
+        .content()
+        .view_once_attachment_url()
+        .unwrap();
+
+    // The DS only stores the ciphertext
+    let stored = alice
+        .download_attachment(conversation_id, &attachment_url)
+        .await
+        .unwrap();
+    let AttachmentDownload::Available(stored) = stored else {
+        panic!("Expected the attachment to be available");
+    };
+    assert_ne!(stored, plaintext);
+
+    // URLs of the previous format are fetched from the DS of the
+    // conversation, whichever domain they name
+    let url_with_domain: QualifiedAttachmentUrl = format!(
+        "phnx-attachment:v1/other.example.com/1/{}",
+        attachment_url.attachment_id()
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(
+        alice
+            .download_attachment(conversation_id, &url_with_domain)
+            .await
+            .unwrap(),
+        AttachmentDownload::Available(stored.clone())
+    );
+    assert!(!stored
+        .windows(16)
+        .any(|window| plaintext.windows(16).any(|chunk| chunk == window)));
+
+    // Bob decrypts the attachment with the key from the message
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    let processed = bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    let received = processed.new_messages.last().unwrap();
+    let download = bob.open_view_once_attachment(received.id()).await.unwrap();
+    assert_eq!(download, Some(AttachmentDownload::Available(plaintext)));
+    assert_eq!(
+        bob.open_view_once_attachment(received.id()).await.unwrap(),
+        None
+    );
+
+    // The DS deleted the attachment after Bob's download
+    assert!(bob
+        .download_attachment(conversation_id, &attachment_url)
+        .await
+        .is_err());
+    assert!(alice
+        .download_attachment(conversation_id, &attachment_url)
+        .await
+        .is_err());
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Message archive test", skip_all)]
+async fn messages_are_archived_and_restored() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    let conversation_id = setup.connect_users(ALICE, BOB).await;
+    for _ in 0..60 {
+        setup.send_message(conversation_id, ALICE, vec![BOB]).await;
+    }
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    let messages = alice.get_messages(conversation_id, 1000).await.unwrap();
+    let newest = messages.last().unwrap().timestamp();
+    alice
+        .mark_as_read([(conversation_id, newest)])
+        .await
+        .unwrap();
+    alice
+        .set_user_setting(&MessageArchiving {
+            archive_after_days: Some(0),
+            keep_recent: 10,
+        })
+        .await
+        .unwrap();
+
+    // The old messages are replaced by stubs
+    let archived = alice.archive_old_messages().await.unwrap();
+    assert!(archived >= 50);
+    let usage = alice.archive_usage().await.unwrap();
+    assert_eq!(usage.archive_count, 1);
+    assert_eq!(usage.archived_messages, archived as u64);
+
+    // Loading the conversation restores them from the DS
+    let restored = alice.get_messages(conversation_id, 1000).await.unwrap();
+    assert_eq!(
+        restored.iter().map(|m| m.id()).collect::<Vec<_>>(),
+        messages.iter().map(|m| m.id()).collect::<Vec<_>>()
+    );
+    let usage = alice.archive_usage().await.unwrap();
+    assert_eq!(usage.restored_count, 1);
+    assert_eq!(usage.archived_messages, 0);
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Join request test", skip_all)]
+async fn join_requests() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.connect_users(ALICE, BOB).await;
+    let conversation_id = setup.create_group(ALICE).await;
+
+    let alice = &setup
+        .users
+        .get(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    // Tokens can only be created for rooms that allow knocking.
+    assert!(alice.join_request_token(conversation_id).await.is_err());
+    alice
+        .update_room_policy(
+            conversation_id,
+            vec![RoomPolicyChange::SetJoinRule(JoinRule::Knock)],
+        )
+        .await
+        .unwrap();
+    let token = alice.join_request_token(conversation_id).await.unwrap();
+
+    // The DS rejects requests with tokens it didn't register.
+    let bob = &setup
+        .users
+        .get(&SafeTryInto::try_into(BOB).unwrap())
+        .unwrap()
+        .user;
+    let mut forged_token = token.clone();
+    *forged_token.last_mut().unwrap() ^= 1;
+    assert!(bob.request_to_join(&forged_token).await.is_err());
+
+    // Repeated requests of the same user result in a single pending request.
+    bob.request_to_join(&token).await.unwrap();
+    bob.request_to_join(&token).await.unwrap();
+
+    let alice = &mut setup
+        .users
+        .get_mut(&SafeTryInto::try_into(ALICE).unwrap())
+        .unwrap()
+        .user;
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let pending = alice.pending_join_requests(conversation_id).await.unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].user_name, SafeTryInto::try_into(BOB).unwrap());
+
+    alice
+        .approve_join_request(conversation_id, SafeTryInto::try_into(BOB).unwrap())
+        .await
+        .unwrap();
+    assert!(alice
+        .pending_join_requests(conversation_id)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Broadcast room commit test", skip_all)]
+async fn broadcast_room_commits() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.add_user(CHARLIE).await;
+    setup.connect_users(ALICE, BOB).await;
+    setup.connect_users(ALICE, CHARLIE).await;
+    let conversation_id = setup.create_group(ALICE).await;
+    setup
+        .invite_to_group(conversation_id, ALICE, vec![BOB, CHARLIE])
+        .await;
+
+    let alice = &setup.get_user(ALICE).user;
+    alice
+        .update_room_policy(
+            conversation_id,
+            vec![RoomPolicyChange::SetCommitRule(CommitRule::AdminsOnly)],
+        )
+        .await
+        .unwrap();
+    let bob = &setup.get_user(BOB).user;
+    let charlie = &setup.get_user(CHARLIE).user;
+    for user in [bob, charlie] {
+        let qs_messages = user.qs_fetch_messages().await.unwrap();
+        user.fully_process_qs_messages(qs_messages).await.unwrap();
+    }
+
+    // Members can update their own leaf while no proposals are pending.
+    bob.update(conversation_id).await.unwrap();
+    for user in [alice, charlie] {
+        let qs_messages = user.qs_fetch_messages().await.unwrap();
+        user.fully_process_qs_messages(qs_messages).await.unwrap();
+    }
+
+    // Charlie leaves by proposal. The DS rejects Bob's update, since it
+    // would commit or discard the proposal.
+    charlie.leave_conversation(conversation_id).await.unwrap();
+    let error = bob.update(conversation_id).await.unwrap_err();
+    assert!(format!("{error:#}").contains("not allowed to commit"));
+
+    // Once Bob knows about the proposal, he waits for an admin to commit it.
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert!(bob.update(conversation_id).await.is_err());
+
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    alice.commit_broadcast_room_changes().await.unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    let expected: HashSet<QualifiedUserName> = [ALICE, BOB]
+        .into_iter()
+        .map(|name| SafeTryInto::try_into(name).unwrap())
+        .collect();
+    assert_eq!(
+        bob.conversation_participants(conversation_id).await,
+        Some(expected)
+    );
+    bob.update(conversation_id).await.unwrap();
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Broadcast room credential refresh test", skip_all)]
+async fn broadcast_room_credential_refresh() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.add_user(CHARLIE).await;
+    setup.connect_users(ALICE, BOB).await;
+    setup.connect_users(ALICE, CHARLIE).await;
+    let conversation_id = setup.create_group(ALICE).await;
+    setup
+        .invite_to_group(conversation_id, ALICE, vec![BOB, CHARLIE])
+        .await;
+
+    let alice = &setup.get_user(ALICE).user;
+    alice
+        .update_room_policy(
+            conversation_id,
+            vec![RoomPolicyChange::SetCommitRule(CommitRule::AdminsOnly)],
+        )
+        .await
+        .unwrap();
+    let bob = &setup.get_user(BOB).user;
+    let charlie = &setup.get_user(CHARLIE).user;
+    for user in [bob, charlie] {
+        let qs_messages = user.qs_fetch_messages().await.unwrap();
+        user.fully_process_qs_messages(qs_messages).await.unwrap();
+    }
+
+    // While Charlie's leave is pending, Bob can't update his leaf by commit
+    // and proposes the refresh of his credential instead.
+    charlie.leave_conversation(conversation_id).await.unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    bob.refresh_client_credential().await.unwrap();
+    assert!(bob
+        .has_outdated_group_credential(conversation_id)
+        .await
+        .unwrap());
+
+    // Alice commits both proposals.
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    alice.commit_broadcast_room_changes().await.unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // Bob's leaf now uses the new credential and signer.
+    assert!(!bob
+        .has_outdated_group_credential(conversation_id)
+        .await
+        .unwrap());
+    bob.update(conversation_id).await.unwrap();
+    setup.send_message(conversation_id, BOB, vec![ALICE]).await;
+    setup.send_message(conversation_id, ALICE, vec![BOB]).await;
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Room policy update test", skip_all)]
+async fn room_policy_updates() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.connect_users(ALICE, BOB).await;
+    let conversation_id = setup.create_group(ALICE).await;
+    setup
+        .invite_to_group(conversation_id, ALICE, vec![BOB])
+        .await;
+
+    // The creator is the only admin.
+    let alice = &setup.get_user(ALICE).user;
+    let (_policy, alice_is_admin) = alice.room_policy(conversation_id).await.unwrap();
+    assert!(alice_is_admin);
+    alice
+        .update_room_policy(
+            conversation_id,
+            vec![RoomPolicyChange::SetSendRule(SendRule::AdminsOnly)],
+        )
+        .await
+        .unwrap();
+
+    let bob = &setup.get_user(BOB).user;
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    let (policy, bob_is_admin) = bob.room_policy(conversation_id).await.unwrap();
+    assert_eq!(policy.send_rule, SendRule::AdminsOnly);
+    assert!(!bob_is_admin);
+
+    // The DS rejects policy changes of members that aren't admins, even if
+    // their client doesn't check the policy.
+    let change = vec![RoomPolicyChange::SetSendRule(SendRule::AllMembers)];
+    assert!(bob
+        .update_room_policy(conversation_id, change.clone())
+        .await
+        .is_err());
+    let error = bob
+        .update_room_policy_unchecked(conversation_id, change.clone())
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("not an admin"));
+
+    // The policy stays unchanged and the admin can still change it.
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    let (policy, _) = alice.room_policy(conversation_id).await.unwrap();
+    assert_eq!(policy.send_rule, SendRule::AdminsOnly);
+    alice
+        .update_room_policy(conversation_id, change)
+        .await
+        .unwrap();
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+    let (policy, _) = bob.room_policy(conversation_id).await.unwrap();
+    assert_eq!(policy.send_rule, SendRule::AllMembers);
+}
+
+#[actix_rt::test]
+#[tracing::instrument(name = "Admins only send rule test", skip_all)]
+async fn admins_only_send_rule() {
+    let mut setup = TestBackend::single().await;
+    setup.add_user(ALICE).await;
+    setup.add_user(BOB).await;
+    setup.connect_users(ALICE, BOB).await;
+    let conversation_id = setup.create_group(ALICE).await;
+    setup
+        .invite_to_group(conversation_id, ALICE, vec![BOB])
+        .await;
+
+    let alice = &setup.get_user(ALICE).user;
+    alice
+        .update_room_policy(
+            conversation_id,
+            vec![RoomPolicyChange::SetSendRule(SendRule::AdminsOnly)],
+        )
+        .await
+        .unwrap();
+    let bob = &setup.get_user(BOB).user;
+    let qs_messages = bob.qs_fetch_messages().await.unwrap();
+    bob.fully_process_qs_messages(qs_messages).await.unwrap();
+
+    // The DS rejects chat content of members that aren't admins, even if
+    // their client doesn't check the policy.
+    let content = |text: &str| {
+        MimiContent::simple_markdown_message(bob.user_name().domain(), text.to_owned())
+    };
+    let error = bob
+        .send_content_unchecked(conversation_id, content("chat"), QueuePriority::High)
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("not allowed to send"));
+
+    // Receipts and profile keys are sent with a lower priority and are
+    // delivered regardless of the send rule.
+    bob.send_content_unchecked(conversation_id, content("receipt"), QueuePriority::Low)
+        .await
+        .unwrap();
+    let qs_messages = alice.qs_fetch_messages().await.unwrap();
+    let processed = alice.fully_process_qs_messages(qs_messages).await.unwrap();
+    assert_eq!(processed.failed_messages, 0);
+    assert_eq!(processed.new_messages.len(), 1);
+}
@@ -2,16 +2,38 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use opaque_ke::rand::rngs::OsRng;
 use phnxapiclient::{qs_api::ws::WsEvent, ApiClient};
 use phnxbackend::qs::{WebsocketNotifier, WsNotification};
 use phnxserver::network_provider::MockNetworkProvider;
 use phnxserver_test_harness::utils::spawn_app;
 use phnxtypes::{
+    crypto::{
+        kdf::keys::RatchetSecret,
+        signatures::keys::{QsClientSigningKey, QsUserSigningKey},
+        RatchetDecryptionKey,
+    },
     identifiers::{Fqdn, QsClientId},
-    messages::client_ds::QsWsMessage,
+    messages::{client_ds::QsWsMessage, FriendshipToken},
 };
 
+/// Create a user record on the QS and return the id and signing key of its
+/// client.
+async fn register_qs_client(client: &ApiClient) -> (QsClientId, QsClientSigningKey) {
+    let signing_key = QsClientSigningKey::random().unwrap();
+    let response = client
+        .qs_create_user(
+            FriendshipToken::random().unwrap(),
+            signing_key.verifying_key(),
+            RatchetDecryptionKey::generate().unwrap().encryption_key(),
+            None,
+            RatchetSecret::random().unwrap(),
+            &QsUserSigningKey::generate().unwrap(),
+        )
+        .await
+        .expect("Failed to create QS user");
+    (response.client_id, signing_key)
+}
+
 /// Test the websocket reconnect.
 #[actix_rt::test]
 #[tracing::instrument(name = "Test WS Reconnect", skip_all)]
@@ -20,8 +42,6 @@ async fn ws_reconnect() {
     let (address, _ws_dispatch) =
         spawn_app(Fqdn::try_from("example.com").unwrap(), network_provider).await;
 
-    let client_id = QsClientId::random(&mut OsRng);
-
     // Websocket parameters
     let timeout = 1;
     let retry_interval = 1;
@@ -31,9 +51,10 @@ async fn ws_reconnect() {
     // Initialize the client
     let address = format!("http://{}", address);
     let client = ApiClient::initialize(address).expect("Failed to initialize client");
+    let (client_id, signing_key) = register_qs_client(&client).await;
 
     let mut ws = client
-        .spawn_websocket(client_id, timeout, retry_interval)
+        .spawn_websocket(client_id, signing_key, timeout, retry_interval)
         .await
         .expect("Failed to execute request");
 
@@ -58,8 +79,6 @@ async fn ws_sending() {
     let (address, ws_dispatch) =
         spawn_app(Fqdn::try_from("example.com").unwrap(), network_provider).await;
 
-    let client_id = QsClientId::random(&mut OsRng);
-
     // Websocket parameters
     let timeout = 1;
     let retry_interval = 1;
@@ -69,9 +88,10 @@ async fn ws_sending() {
     // Initialize the client
     let address = format!("http://{}", address);
     let client = ApiClient::initialize(address).expect("Failed to initialize client");
+    let (client_id, signing_key) = register_qs_client(&client).await;
 
     let mut ws = client
-        .spawn_websocket(client_id.clone(), timeout, retry_interval)
+        .spawn_websocket(client_id.clone(), signing_key, timeout, retry_interval)
         .await
         .expect("Failed to execute request");
 
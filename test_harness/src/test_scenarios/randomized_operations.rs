@@ -30,7 +30,7 @@ pub async fn randomized_operations_runner(domains: &[Fqdn]) {
         // Pick a random domain
         let domain = domains.choose(&mut rng).unwrap();
         // Just count the users to avoid collisions
-        let user_name = format!("{}@{}", index, domain);
+        let user_name = format!("user{}@{}", index, domain);
         tracing::info!(
             random_operation = true,
             "Random operation: Creating user {}",
@@ -0,0 +1,71 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Controllable clock for testing expiration logic.
+//!
+//! The clock replaces the process-wide [`TimeProvider`], so it also affects
+//! servers spawned via [`spawn_app`](super::spawn_app) in the same process.
+//! Fast-forwarding the clock would break concurrently running tests that
+//! don't expect it, so tests using the clock should be placed in their own
+//! test binary.
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use chrono::{DateTime, Utc};
+use phnxtypes::time::{self, Duration, TimeProvider};
+
+/// Serializes the tests using the clock.
+static CLOCK_LOCK: Mutex<()> = Mutex::new(());
+
+/// A clock that runs with the system clock but can be fast-forwarded.
+#[derive(Debug, Default)]
+pub struct TestClock {
+    offset: Mutex<Duration>,
+}
+
+impl TimeProvider for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + *self.offset.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl TestClock {
+    /// Install a new test clock as the process-wide time provider.
+    ///
+    /// Blocks while another test clock is installed. The system clock is
+    /// restored when the returned guard is dropped.
+    pub fn install() -> TestClockGuard {
+        let lock = CLOCK_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let clock = Arc::new(TestClock::default());
+        time::set_time_provider(clock.clone());
+        TestClockGuard { clock, _lock: lock }
+    }
+}
+
+/// An installed [`TestClock`].
+pub struct TestClockGuard {
+    clock: Arc<TestClock>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TestClockGuard {
+    /// Move the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self
+            .clock
+            .offset
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+}
+
+impl Drop for TestClockGuard {
+    fn drop(&mut self) {
+        time::reset_time_provider();
+    }
+}
@@ -6,20 +6,28 @@
 
 use std::net::{SocketAddr, TcpListener};
 
+pub mod clock;
 pub mod setup;
 
 use once_cell::sync::Lazy;
-use phnxbackend::{auth_service::AuthService, ds::Ds, infra_service::InfraService, qs::Qs};
+use phnxbackend::{
+    auth_service::AuthService, ds::Ds, infra_service::InfraService, qs::Qs,
+    settings::RequestLimitSettings,
+};
 use phnxserver::{
     configurations::get_configuration,
-    endpoints::qs::{
-        push_notification_provider::ProductionPushNotificationProvider,
-        ws::DispatchWebsocketNotifier,
+    endpoints::{
+        admin::Admin,
+        qs::{
+            push_notification_provider::ProductionPushNotificationProvider,
+            ws::DispatchWebsocketNotifier,
+        },
     },
     enqueue_provider::SimpleEnqueueProvider,
     network_provider::MockNetworkProvider,
     run,
     telemetry::{get_subscriber, init_subscriber},
+    TenantServices,
 };
 use phnxtypes::identifiers::Fqdn;
 use uuid::Uuid;
@@ -91,15 +99,22 @@ pub async fn spawn_app(
         network: network_provider.clone(),
     };
 
-    // Start the server
-    let server = run(
-        listener,
+    let tenant = TenantServices {
+        domain,
         ds,
         auth_service,
         qs,
         qs_connector,
+    };
+
+    // Start the server
+    let server = run(
+        listener,
+        vec![tenant],
         network_provider,
         ws_dispatch_notifier.clone(),
+        RequestLimitSettings::default(),
+        Admin::default(),
     )
     .expect("Failed to bind to address.");
 
@@ -109,3 +124,72 @@ pub async fn spawn_app(
     // Return the address
     (address, ws_dispatch_notifier)
 }
+
+/// Start a server that serves all of the given domains from shared databases.
+/// Requests are routed to the domain given in their `Host` header.
+pub async fn spawn_multi_tenant_app(
+    domains: &[Fqdn],
+    network_provider: MockNetworkProvider,
+) -> (SocketAddr, DispatchWebsocketNotifier) {
+    // Initialize tracing subscription only once.
+    Lazy::force(&TRACING);
+
+    // Load configuration
+    let mut configuration = get_configuration("../server/").expect("Could not load configuration.");
+
+    // Port binding
+    let host = configuration.application.host;
+    let listener = TcpListener::bind(format!("{host}:0")).expect("Failed to bind to random port.");
+    let address = listener.local_addr().unwrap();
+
+    let ws_dispatch_notifier = DispatchWebsocketNotifier::default_addr();
+
+    configuration.database.name = Uuid::new_v4().to_string();
+    let dss = Ds::new_tenants(&configuration.database, domains)
+        .await
+        .expect("Failed to connect to database.");
+    configuration.database.name = Uuid::new_v4().to_string();
+    let auth_services = AuthService::new_tenants(&configuration.database, domains)
+        .await
+        .expect("Failed to connect to database.");
+    configuration.database.name = Uuid::new_v4().to_string();
+    let qss = Qs::new_tenants(&configuration.database, domains)
+        .await
+        .expect("Failed to connect to database.");
+
+    let tenants = domains
+        .iter()
+        .zip(dss)
+        .zip(auth_services)
+        .zip(qss)
+        .map(|(((domain, ds), auth_service), qs)| TenantServices {
+            domain: domain.clone(),
+            ds,
+            auth_service,
+            qs: qs.clone(),
+            qs_connector: SimpleEnqueueProvider {
+                qs,
+                notifier: ws_dispatch_notifier.clone(),
+                push_notification_provider: ProductionPushNotificationProvider::new(None, None)
+                    .unwrap(),
+                network: network_provider.clone(),
+            },
+        })
+        .collect();
+
+    // Start the server
+    let server = run(
+        listener,
+        tenants,
+        network_provider,
+        ws_dispatch_notifier.clone(),
+        RequestLimitSettings::default(),
+        Admin::default(),
+    )
+    .expect("Failed to bind to address.");
+
+    // Execute the server in the background
+    tokio::spawn(server);
+
+    (address, ws_dispatch_notifier)
+}
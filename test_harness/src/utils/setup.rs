@@ -5,7 +5,7 @@
 use std::collections::{HashMap, HashSet};
 
 use phnxcoreclient::{clients::CoreUser, ConversationId, ConversationStatus, ConversationType, *};
-use phnxserver::network_provider::MockNetworkProvider;
+use phnxserver::network_provider::{DomainRoutes, MockNetworkProvider};
 use phnxtypes::{
     identifiers::{Fqdn, QualifiedUserName, SafeTryInto},
     DEFAULT_PORT_HTTP,
@@ -75,6 +75,8 @@ impl TestUser {
 enum TestKind {
     SingleBackend(String), // url of the single backend
     Federated,
+    // addresses of the backends of the virtual domains
+    VirtualDomains(HashMap<Fqdn, String>),
 }
 
 pub struct TestBackend {
@@ -103,6 +105,26 @@ impl TestBackend {
         }
     }
 
+    /// Runs a backend for each of the given domains in this process. The
+    /// backends have separate databases and send federation requests to each
+    /// other directly, so that federation can be tested without a container
+    /// per domain.
+    pub async fn virtual_domains(domains: &[Fqdn]) -> Self {
+        let routes = DomainRoutes::default();
+        let mut addresses = HashMap::new();
+        for domain in domains {
+            let network_provider = MockNetworkProvider::with_routes(routes.clone());
+            let (address, _ws_dispatch) = spawn_app(domain.clone(), network_provider).await;
+            routes.insert(domain.clone(), address);
+            addresses.insert(domain.clone(), address.to_string());
+        }
+        Self {
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            kind: TestKind::VirtualDomains(addresses),
+        }
+    }
+
     pub fn url(&self) -> Option<String> {
         if let TestKind::SingleBackend(url) = &self.kind {
             Some(url.clone())
@@ -111,17 +133,44 @@ impl TestBackend {
         }
     }
 
+    /// The address of the backend of the given user, if it is not reached
+    /// via the user's domain.
+    fn user_url(&self, user_name: &QualifiedUserName) -> Option<String> {
+        match &self.kind {
+            TestKind::VirtualDomains(addresses) => {
+                let address = addresses
+                    .get(&user_name.domain())
+                    .unwrap_or_else(|| panic!("No virtual domain {}", user_name.domain()));
+                Some(address.clone())
+            }
+            _ => self.url(),
+        }
+    }
+
+    /// Route the requests of the user for other virtual domains to their
+    /// backends.
+    fn route_virtual_domains(&self, user: &TestUser) {
+        if let TestKind::VirtualDomains(addresses) = &self.kind {
+            for (domain, address) in addresses {
+                let url = format!("http://{address}");
+                user.user.route_domain(domain.clone(), url).unwrap();
+            }
+        }
+    }
+
     pub async fn add_persisted_user(&mut self, user_name: impl SafeTryInto<QualifiedUserName>) {
         let user_name = user_name.try_into().unwrap();
         tracing::info!("Creating {user_name}");
-        let user = TestUser::new_persisted(&user_name, self.url(), "./").await;
+        let user = TestUser::new_persisted(&user_name, self.user_url(&user_name), "./").await;
+        self.route_virtual_domains(&user);
         self.users.insert(user_name, user);
     }
 
     pub async fn add_user(&mut self, user_name: impl SafeTryInto<QualifiedUserName>) {
         let user_name = user_name.try_into().unwrap();
         tracing::info!("Creating {user_name}");
-        let user = TestUser::new(&user_name, self.url()).await;
+        let user = TestUser::new(&user_name, self.user_url(&user_name)).await;
+        self.route_virtual_domains(&user);
         self.users.insert(user_name, user);
     }
 
@@ -319,6 +368,21 @@ impl TestBackend {
         let as_messages = user2.as_fetch_messages().await.unwrap();
         tracing::info!("{} processes AS messages", user2_name);
         user2.fully_process_as_messages(as_messages).await.unwrap();
+        // Offers of users of other domains who registered recently are
+        // filtered, so user 2 accepts them explicitly.
+        let filtered_request = user2
+            .filtered_message_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|request| request.user_name() == &user1_name);
+        if let Some(request) = filtered_request {
+            assert_ne!(user1_name.domain(), user2.user_name().domain());
+            user2
+                .accept_message_request(request.conversation_id())
+                .await
+                .unwrap();
+        }
         // User 2 should have auto-accepted (for now at least) the connection request.
         let mut user2_contacts_after = user2.contacts().await.unwrap();
         tracing::info!("User 2 contacts after: {:?}", user2_contacts_after);
@@ -658,18 +722,26 @@ impl TestBackend {
             .await
             .expect("Error inviting users.");
 
-        let mut expected_messages = HashSet::new();
-        for invitee_name in &invitee_names {
-            let expected_message = format!(
-                "{} added {} to the conversation",
-                inviter_name, invitee_name,
-            );
-            expected_messages.insert(expected_message);
-        }
-
-        let invite_messages = display_messages_to_string_map(invite_messages);
+        let expected_message = SystemMessage::Add {
+            adder: inviter_name.clone(),
+            added: sorted_by_name(invitee_names.clone()),
+        };
+        assert_eq!(system_messages(invite_messages), vec![expected_message]);
 
-        assert_eq!(invite_messages, expected_messages);
+        // The invite is recorded in the membership history.
+        let history = inviter
+            .membership_history(conversation_id, 0, invitee_names.len() as u32)
+            .await
+            .expect("Error loading membership history.");
+        let added_members = history
+            .iter()
+            .map(|change| {
+                assert_eq!(change.kind(), MembershipChangeKind::Add);
+                assert_eq!(change.sender(), &inviter_name);
+                change.member()
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(added_members, invitee_names.iter().collect::<HashSet<_>>());
 
         let inviter_group_members_after = inviter
             .conversation_participants(conversation_id)
@@ -840,18 +912,11 @@ impl TestBackend {
             .await
             .expect("Error removing users.");
 
-        let mut expected_messages = HashSet::new();
-
-        for removed_name in &removed_names {
-            let expected_message = format!(
-                "{} removed {} from the conversation",
-                remover_name, removed_name,
-            );
-            expected_messages.insert(expected_message);
-        }
-
-        let remove_messages = display_messages_to_string_map(remove_messages);
-        assert_eq!(remove_messages, expected_messages);
+        let expected_message = SystemMessage::Remove {
+            remover: remover_name.clone(),
+            removed: sorted_by_name(removed_names.clone()),
+        };
+        assert_eq!(system_messages(remove_messages), vec![expected_message]);
 
         let remover_group_members_after = remover
             .conversation_participants(conversation_id)
@@ -1290,15 +1355,21 @@ impl TestBackend {
     }
 }
 
-fn display_messages_to_string_map(display_messages: Vec<ConversationMessage>) -> HashSet<String> {
+fn system_messages(display_messages: Vec<ConversationMessage>) -> Vec<SystemMessage> {
     display_messages
         .into_iter()
         .filter_map(|m| {
             if let Message::Event(EventMessage::System(system_message)) = m.message() {
-                Some(system_message.to_string())
+                Some(system_message.clone())
             } else {
                 None
             }
         })
         .collect()
 }
+
+/// Users in system messages are sorted by name.
+fn sorted_by_name(mut user_names: Vec<QualifiedUserName>) -> Vec<QualifiedUserName> {
+    user_names.sort_by_key(|user_name| user_name.to_string());
+    user_names
+}
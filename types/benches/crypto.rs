@@ -0,0 +1,93 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Benchmarks of the queue ratchet and of credential verification.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use phnxtypes::{
+    credentials::{
+        keys::AsIntermediateSigningKey, AsCredential, AsIntermediateCredentialCsr,
+        ClientCredential, ClientCredentialCsr, ClientCredentialPayload, VerifiableClientCredential,
+    },
+    crypto::signatures::{signable::Verifiable, DEFAULT_SIGNATURE_SCHEME},
+    identifiers::{AsClientId, Fqdn, QualifiedUserName, SafeTryInto},
+    messages::client_ds::{QsQueueMessagePayload, QsQueueMessageType, QsQueueRatchet},
+    time::TimeStamp,
+};
+use tls_codec::{DeserializeBytes, Serialize};
+
+const PAYLOAD_SIZE: usize = 1024;
+
+fn payload() -> QsQueueMessagePayload {
+    QsQueueMessagePayload {
+        timestamp: TimeStamp::now(),
+        message_type: QsQueueMessageType::MlsMessage,
+        payload: vec![0u8; PAYLOAD_SIZE],
+    }
+}
+
+fn queue_ratchet(c: &mut Criterion) {
+    let mut bench_group = c.benchmark_group("queue_ratchet");
+    bench_group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+
+    let mut sender = QsQueueRatchet::random().unwrap();
+    bench_group.bench_function("encrypt", |b| {
+        b.iter_batched(
+            payload,
+            |payload| sender.encrypt(payload).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let ratchet = QsQueueRatchet::random().unwrap();
+    bench_group.bench_function("decrypt", |b| {
+        b.iter_batched(
+            || {
+                let mut sender = ratchet.clone();
+                let message = sender.encrypt(payload()).unwrap();
+                (ratchet.clone(), message)
+            },
+            |(mut receiver, message)| receiver.decrypt(message).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    bench_group.finish();
+}
+
+fn credential_verification(c: &mut Criterion) {
+    let domain = Fqdn::try_from("example.com").unwrap();
+    let (_, as_signing_key) =
+        AsCredential::new(DEFAULT_SIGNATURE_SCHEME, domain.clone(), None).unwrap();
+    let (csr, prelim_signing_key) =
+        AsIntermediateCredentialCsr::new(DEFAULT_SIGNATURE_SCHEME, domain).unwrap();
+    let intermediate_credential = csr.sign(&as_signing_key, None).unwrap();
+    let intermediate_signing_key =
+        AsIntermediateSigningKey::from_prelim_key(prelim_signing_key, intermediate_credential)
+            .unwrap();
+
+    let user_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+    let client_id = AsClientId::random(user_name).unwrap();
+    let (client_csr, _) = ClientCredentialCsr::new(client_id, DEFAULT_SIGNATURE_SCHEME).unwrap();
+    let client_credential = ClientCredentialPayload::new(
+        client_csr,
+        None,
+        intermediate_signing_key.credential().fingerprint().clone(),
+    )
+    .sign_with_intermediate(&intermediate_signing_key)
+    .unwrap();
+    let client_credential_bytes = client_credential.tls_serialize_detached().unwrap();
+    let verifying_key = intermediate_signing_key.credential().verifying_key();
+
+    c.bench_function("client_credential_verification", |b| {
+        b.iter(|| {
+            let verifiable_credential =
+                VerifiableClientCredential::tls_deserialize_exact_bytes(&client_credential_bytes)
+                    .unwrap();
+            let _: ClientCredential = verifiable_credential.verify(verifying_key).unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, queue_ratchet, credential_verification);
+criterion_main!(benches);
@@ -3,12 +3,8 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use mls_assist::{
-    openmls::prelude::{Lifetime, OpenMlsProvider, SignatureScheme},
-    openmls_rust_crypto::OpenMlsRustCrypto,
-    openmls_traits::{
-        random::OpenMlsRand,
-        signatures::{Signer, SignerError},
-    },
+    openmls::prelude::{Lifetime, SignatureScheme},
+    openmls_traits::signatures::{Signer, SignerError},
 };
 #[cfg(feature = "sqlite")]
 use rusqlite::{types::ToSqlOutput, ToSql};
@@ -26,6 +22,7 @@ use crate::codec::PhnxCodec;
 use crate::crypto::{
     ear::{keys::SignatureEarKey, EarEncryptable},
     errors::KeyGenerationError,
+    rng,
     signatures::{
         private_keys::{SigningKey, VerifyingKey},
         signable::Signable,
@@ -206,7 +203,7 @@ pub(crate) const DEFAULT_INFRA_CREDENTIAL_LIFETIME: u64 = 30 * 24 * 60 * 60;
 impl InfraCredentialSigningKey {
     pub fn generate(client_signer: &ClientSigningKey, ear_key: &SignatureEarKey) -> Self {
         let signing_key = SigningKey::generate().unwrap();
-        let identity = OpenMlsRustCrypto::default().rand().random_vec(32).unwrap();
+        let identity = rng::random_vec(32).unwrap();
         let tbs = InfraCredentialTbs {
             identity,
             expiration_data: Lifetime::new(DEFAULT_INFRA_CREDENTIAL_LIFETIME),
@@ -16,8 +16,8 @@ use serde::{Deserialize, Serialize};
 use tls_codec::{Serialize as TlsSerialize, TlsDeserializeBytes, TlsSerialize, TlsSize};
 
 use keys::{
-    AsIntermediateVerifyingKey, AsSigningKey, AsVerifyingKey, PreliminaryAsIntermediateSigningKey,
-    PreliminaryClientSigningKey,
+    AsIntermediateSigningKey, AsIntermediateVerifyingKey, AsSigningKey, AsVerifyingKey,
+    PreliminaryAsIntermediateSigningKey, PreliminaryClientSigningKey,
 };
 
 use crate::{
@@ -68,16 +68,18 @@ impl std::fmt::Display for CredentialFingerprint {
 }
 
 impl CredentialFingerprint {
-    fn with_label(credential: &impl TlsSerialize, label: &str) -> Self {
+    fn with_label(credential: &impl TlsSerialize, label: &str) -> Result<Self, LibraryError> {
         let hash_label = format!("Infra Credential Fingerprint {}", label);
         let rust_crypto = OpenMlsRustCrypto::default();
-        let payload = credential.tls_serialize_detached().unwrap_or_default();
+        let payload = credential
+            .tls_serialize_detached()
+            .map_err(LibraryError::missing_bound_check)?;
         let input = [hash_label.as_bytes().to_vec(), payload].concat();
         let value = rust_crypto
             .crypto()
             .hash(HashType::Sha2_256, &input)
-            .unwrap_or_default();
-        Self(value)
+            .map_err(|_| LibraryError::unexpected_crypto_error("Failed to hash credential"))?;
+        Ok(Self(value))
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -111,10 +113,12 @@ pub struct AsCredential {
     fingerprint: CredentialFingerprint,
 }
 
-impl From<AsCredentialBody> for AsCredential {
-    fn from(body: AsCredentialBody) -> Self {
-        let fingerprint = body.hash();
-        Self { body, fingerprint }
+impl TryFrom<AsCredentialBody> for AsCredential {
+    type Error = LibraryError;
+
+    fn try_from(body: AsCredentialBody) -> Result<Self, Self::Error> {
+        let fingerprint = body.hash()?;
+        Ok(Self { body, fingerprint })
     }
 }
 
@@ -148,7 +152,7 @@ impl FromSql for AsCredentialBody {
 }
 
 impl AsCredentialBody {
-    fn hash(&self) -> CredentialFingerprint {
+    fn hash(&self) -> Result<CredentialFingerprint, LibraryError> {
         CredentialFingerprint::with_label(self, AS_CREDENTIAL_LABEL)
     }
 }
@@ -176,7 +180,9 @@ impl AsCredential {
             signature_scheme,
             verifying_key,
         };
-        let fingerprint = body.hash();
+        let fingerprint = body
+            .hash()
+            .map_err(|_| KeyGenerationError::KeypairGeneration)?;
         let credential = Self { body, fingerprint };
         let signing_key =
             AsSigningKey::from_private_key_and_credential(signing_key, credential.clone());
@@ -252,7 +258,7 @@ impl AsIntermediateCredentialCsr {
             expiration_data,
             signer_fingerprint,
         };
-        credential.sign(as_signing_key)
+        credential.sign(as_signing_key)?.try_into()
     }
 }
 
@@ -266,7 +272,7 @@ struct AsIntermediateCredentialPayload {
 pub const AS_INTERMEDIATE_CREDENTIAL_LABEL: &str = "MLS Infra AS Intermediate Credential";
 
 impl Signable for AsIntermediateCredentialPayload {
-    type SignedOutput = AsIntermediateCredential;
+    type SignedOutput = AsIntermediateCredentialBody;
 
     fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
         self.tls_serialize_detached()
@@ -304,7 +310,7 @@ impl FromSql for AsIntermediateCredentialBody {
 }
 
 impl AsIntermediateCredentialBody {
-    fn hash(&self) -> CredentialFingerprint {
+    fn hash(&self) -> Result<CredentialFingerprint, LibraryError> {
         CredentialFingerprint::with_label(self, AS_INTERMEDIATE_CREDENTIAL_LABEL)
     }
 }
@@ -315,10 +321,12 @@ pub struct AsIntermediateCredential {
     fingerprint: CredentialFingerprint,
 }
 
-impl From<AsIntermediateCredentialBody> for AsIntermediateCredential {
-    fn from(body: AsIntermediateCredentialBody) -> Self {
-        let fingerprint = body.hash();
-        Self { body, fingerprint }
+impl TryFrom<AsIntermediateCredentialBody> for AsIntermediateCredential {
+    type Error = LibraryError;
+
+    fn try_from(body: AsIntermediateCredentialBody) -> Result<Self, Self::Error> {
+        let fingerprint = body.hash()?;
+        Ok(Self { body, fingerprint })
     }
 }
 
@@ -347,19 +355,26 @@ impl AsIntermediateCredential {
         &self.body.credential.csr.as_domain
     }
 
+    pub fn expiration_data(&self) -> &ExpirationData {
+        &self.body.credential.expiration_data
+    }
+
+    /// Fingerprint of the [`AsCredential`] that signed this credential.
+    pub fn signer_fingerprint(&self) -> &CredentialFingerprint {
+        &self.body.credential.signer_fingerprint
+    }
+
     pub fn body(&self) -> &AsIntermediateCredentialBody {
         &self.body
     }
 }
 
-impl SignedStruct<AsIntermediateCredentialPayload> for AsIntermediateCredential {
+impl SignedStruct<AsIntermediateCredentialPayload> for AsIntermediateCredentialBody {
     fn from_payload(payload: AsIntermediateCredentialPayload, signature: Signature) -> Self {
-        let body = AsIntermediateCredentialBody {
+        Self {
             credential: payload,
             signature,
-        };
-        let fingerprint = body.hash();
-        Self { body, fingerprint }
+        }
     }
 }
 
@@ -389,7 +404,7 @@ impl Verifiable for VerifiableAsIntermediateCredential {
     }
 }
 
-impl VerifiedStruct<VerifiableAsIntermediateCredential> for AsIntermediateCredential {
+impl VerifiedStruct<VerifiableAsIntermediateCredential> for AsIntermediateCredentialBody {
     type SealingType = private_mod::Seal;
 
     fn from_verifiable(
@@ -470,6 +485,20 @@ impl ClientCredentialPayload {
         // TODO: Check uniqueness of client id
         self.expiration_data.validate()
     }
+
+    /// Sign the payload with the given AS intermediate signing key.
+    ///
+    /// The signer fingerprint requested by the client is replaced by the
+    /// fingerprint of the signing key's credential, because the client might
+    /// have requested the credential from an intermediate credential that has
+    /// since been rotated out.
+    pub fn sign_with_intermediate(
+        mut self,
+        signing_key: &AsIntermediateSigningKey,
+    ) -> Result<ClientCredential, LibraryError> {
+        self.signer_fingerprint = signing_key.credential().fingerprint().clone();
+        self.sign(signing_key)
+    }
 }
 
 impl Signable for ClientCredentialPayload {
@@ -516,9 +545,17 @@ impl ClientCredential {
         &self.payload.csr.verifying_key
     }
 
-    pub fn fingerprint(&self) -> CredentialFingerprint {
+    pub fn fingerprint(&self) -> Result<CredentialFingerprint, LibraryError> {
         CredentialFingerprint::with_label(self, CLIENT_CREDENTIAL_LABEL)
     }
+
+    pub fn expiration_data(&self) -> &ExpirationData {
+        self.payload.expiration_data()
+    }
+
+    pub fn signer_fingerprint(&self) -> &CredentialFingerprint {
+        &self.payload.signer_fingerprint
+    }
 }
 
 // When adding a variant to this enum, the new variant must be called
@@ -603,6 +640,27 @@ impl VerifiableClientCredential {
     pub fn client_id(&self) -> &AsClientId {
         &self.payload.csr.client_id
     }
+
+    /// The key of the client. It can only be trusted once this credential has
+    /// been verified.
+    pub fn verifying_key(&self) -> &ClientVerifyingKey {
+        &self.payload.csr.verifying_key
+    }
+
+    /// Returns the fingerprint of the [`ClientCredential`] resulting from a
+    /// successful verification of this credential.
+    pub fn fingerprint(&self) -> Result<CredentialFingerprint, LibraryError> {
+        CredentialFingerprint::with_label(self, CLIENT_CREDENTIAL_LABEL)
+    }
+}
+
+impl From<ClientCredential> for VerifiableClientCredential {
+    fn from(credential: ClientCredential) -> Self {
+        Self {
+            payload: credential.payload,
+            signature: credential.signature,
+        }
+    }
 }
 
 impl Verifiable for VerifiableClientCredential {
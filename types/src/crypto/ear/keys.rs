@@ -13,13 +13,19 @@ use rusqlite::types::FromSql;
 use serde::{Deserialize, Serialize};
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
-use crate::crypto::{
-    errors::RandomnessError,
-    kdf::{
-        keys::{InitialClientKdfKey, RatchetSecret, RosterKdfKey},
-        KdfDerivable,
+use crate::{
+    crypto::{
+        errors::RandomnessError,
+        kdf::{
+            keys::{
+                InitialClientKdfKey, JoinRequestSecret, RatchetSecret, RosterKdfKey, UserKeySecret,
+            },
+            Kdf, KdfDerivable,
+        },
+        secrets::Secret,
     },
-    secrets::Secret,
+    messages::presence::{PresenceToken, PRESENCE_TOKEN_SIZE},
+    LibraryError,
 };
 
 use super::{traits::EarKey, Ciphertext, EarDecryptable, EarEncryptable, AEAD_KEY_SIZE};
@@ -79,6 +85,96 @@ impl KdfDerivable<RosterKdfKey, GroupId, AEAD_KEY_SIZE> for GroupStateEarKey {
     const LABEL: &'static str = "roster kdf key";
 }
 
+/// Key with which the DS wraps the [`GroupStateEarKey`] of a group for a join
+/// request token. Holders of the token send it to the DS with their
+/// requests, but never learn the wrapped key.
+#[derive(Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct JoinRequestTokenKey {
+    key: Secret<AEAD_KEY_SIZE>,
+}
+
+impl JoinRequestTokenKey {
+    pub fn derive_from(
+        secret: &JoinRequestSecret,
+        group_id: &GroupId,
+    ) -> Result<Self, LibraryError> {
+        Self::derive(secret, group_id.clone())
+    }
+}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for JoinRequestTokenKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for JoinRequestTokenKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+impl EarKey for JoinRequestTokenKey {}
+
+impl KdfDerivable<JoinRequestSecret, GroupId, AEAD_KEY_SIZE> for JoinRequestTokenKey {
+    const LABEL: &'static str = "join request token key";
+}
+
+impl EarEncryptable<JoinRequestTokenKey, EncryptedGroupStateEarKey> for GroupStateEarKey {}
+impl EarDecryptable<JoinRequestTokenKey, EncryptedGroupStateEarKey> for GroupStateEarKey {}
+
+#[derive(Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct EncryptedGroupStateEarKey {
+    ciphertext: Ciphertext,
+}
+
+impl From<Ciphertext> for EncryptedGroupStateEarKey {
+    fn from(ciphertext: Ciphertext) -> Self {
+        Self { ciphertext }
+    }
+}
+
+impl AsRef<Ciphertext> for EncryptedGroupStateEarKey {
+    fn as_ref(&self) -> &Ciphertext {
+        &self.ciphertext
+    }
+}
+
+/// Key to encrypt join requests. Both the members of the group and the
+/// holders of a join request token can derive it from the
+/// [`JoinRequestSecret`] of the token.
+#[derive(Clone, Debug)]
+pub struct JoinRequestEarKey {
+    key: Secret<AEAD_KEY_SIZE>,
+}
+
+impl JoinRequestEarKey {
+    pub fn derive_from(
+        secret: &JoinRequestSecret,
+        group_id: &GroupId,
+    ) -> Result<Self, LibraryError> {
+        Self::derive(secret, group_id.clone())
+    }
+}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for JoinRequestEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for JoinRequestEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+impl EarKey for JoinRequestEarKey {}
+
+impl KdfDerivable<JoinRequestSecret, GroupId, AEAD_KEY_SIZE> for JoinRequestEarKey {
+    const LABEL: &'static str = "join request ear key";
+}
+
 pub type DeleteAuthKeyEarKeySecret = Secret<AEAD_KEY_SIZE>;
 
 pub type PushTokenEarKeySecret = Secret<AEAD_KEY_SIZE>;
@@ -113,6 +209,250 @@ impl From<Secret<AEAD_KEY_SIZE>> for PushTokenEarKey {
     }
 }
 
+pub type SelfMessageEarKeySecret = Secret<AEAD_KEY_SIZE>;
+
+/// EAR key for the [`crate::messages::self_message::SelfMessage`]s exchanged
+/// between the clients of a user. Shared by all clients of the user.
+#[derive(Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize, Serialize, Deserialize)]
+pub struct SelfMessageEarKey {
+    key: SelfMessageEarKeySecret,
+}
+
+impl SelfMessageEarKey {
+    pub fn random() -> Result<Self, RandomnessError> {
+        Ok(Self {
+            key: SelfMessageEarKeySecret::random()?,
+        })
+    }
+
+    pub fn derive_from(user_key_secret: &UserKeySecret) -> Result<Self, LibraryError> {
+        Self::derive(user_key_secret, Vec::new())
+    }
+}
+
+impl KdfDerivable<UserKeySecret, Vec<u8>, AEAD_KEY_SIZE> for SelfMessageEarKey {
+    const LABEL: &'static str = "self message ear key";
+}
+
+impl EarKey for SelfMessageEarKey {}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for SelfMessageEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for SelfMessageEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+pub type UserSettingsEarKeySecret = Secret<AEAD_KEY_SIZE>;
+
+/// EAR key for the settings of a user stored on the AS. Shared by all clients
+/// of the user, which derive it from the [`UserKeySecret`].
+#[derive(Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize, Serialize, Deserialize)]
+pub struct UserSettingsEarKey {
+    key: UserSettingsEarKeySecret,
+}
+
+impl UserSettingsEarKey {
+    pub fn random() -> Result<Self, RandomnessError> {
+        Ok(Self {
+            key: UserSettingsEarKeySecret::random()?,
+        })
+    }
+
+    pub fn derive_from(user_key_secret: &UserKeySecret) -> Result<Self, LibraryError> {
+        Self::derive(user_key_secret, Vec::new())
+    }
+}
+
+impl KdfDerivable<UserKeySecret, Vec<u8>, AEAD_KEY_SIZE> for UserSettingsEarKey {
+    const LABEL: &'static str = "user settings ear key";
+}
+
+impl EarKey for UserSettingsEarKey {}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for UserSettingsEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for UserSettingsEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+pub type UserProfileEarKeySecret = Secret<AEAD_KEY_SIZE>;
+
+/// EAR key for the profile of a user stored on the AS. Shared by all clients
+/// of the user, which derive it from the [`UserKeySecret`], and sent to
+/// contacts in the friendship package.
+#[derive(
+    Clone, Debug, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize, Serialize, Deserialize,
+)]
+pub struct UserProfileEarKey {
+    key: UserProfileEarKeySecret,
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::ToSql for UserProfileEarKey {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        self.key.to_sql()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::FromSql for UserProfileEarKey {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let key = UserProfileEarKeySecret::column_result(value)?;
+        Ok(Self { key })
+    }
+}
+
+impl UserProfileEarKey {
+    pub fn random() -> Result<Self, RandomnessError> {
+        Ok(Self {
+            key: UserProfileEarKeySecret::random()?,
+        })
+    }
+
+    pub fn derive_from(user_key_secret: &UserKeySecret) -> Result<Self, LibraryError> {
+        Self::derive(user_key_secret, Vec::new())
+    }
+}
+
+impl KdfDerivable<UserKeySecret, Vec<u8>, AEAD_KEY_SIZE> for UserProfileEarKey {
+    const LABEL: &'static str = "user profile ear key";
+}
+
+impl EarKey for UserProfileEarKey {}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for UserProfileEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for UserProfileEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+/// EAR key for attachments stored on disk by the DS.
+#[derive(Clone, Debug)]
+pub struct AttachmentStorageEarKey {
+    key: Secret<AEAD_KEY_SIZE>,
+}
+
+impl EarKey for AttachmentStorageEarKey {}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for AttachmentStorageEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for AttachmentStorageEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+impl TryFrom<&[u8]> for AttachmentStorageEarKey {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let key: [u8; AEAD_KEY_SIZE] = bytes.try_into()?;
+        Ok(Secret::from(key).into())
+    }
+}
+
+pub type MessageArchiveEarKeySecret = Secret<AEAD_KEY_SIZE>;
+
+/// EAR key for a batch of messages offloaded by a client to the DS. Each
+/// archive has its own key, which never leaves the client.
+#[derive(Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize, Serialize, Deserialize)]
+pub struct MessageArchiveEarKey {
+    key: MessageArchiveEarKeySecret,
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::ToSql for MessageArchiveEarKey {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        self.key.to_sql()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::types::FromSql for MessageArchiveEarKey {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let key = MessageArchiveEarKeySecret::column_result(value)?;
+        Ok(Self { key })
+    }
+}
+
+impl MessageArchiveEarKey {
+    pub fn random() -> Result<Self, RandomnessError> {
+        Ok(Self {
+            key: MessageArchiveEarKeySecret::random()?,
+        })
+    }
+}
+
+impl EarKey for MessageArchiveEarKey {}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for MessageArchiveEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for MessageArchiveEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+pub type AttachmentEarKeySecret = Secret<AEAD_KEY_SIZE>;
+
+/// EAR key for a single attachment uploaded to the DS. Each attachment has
+/// its own key, which is only shared with the members of the group as part
+/// of the message that refers to the attachment.
+#[derive(
+    Clone, Debug, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize, Serialize, Deserialize,
+)]
+pub struct AttachmentEarKey {
+    key: AttachmentEarKeySecret,
+}
+
+impl AttachmentEarKey {
+    pub fn random() -> Result<Self, RandomnessError> {
+        Ok(Self {
+            key: AttachmentEarKeySecret::random()?,
+        })
+    }
+}
+
+impl EarKey for AttachmentEarKey {}
+
+impl AsRef<Secret<AEAD_KEY_SIZE>> for AttachmentEarKey {
+    fn as_ref(&self) -> &Secret<AEAD_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl From<Secret<AEAD_KEY_SIZE>> for AttachmentEarKey {
+    fn from(secret: Secret<AEAD_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
 pub type AddPackageEarKeySecret = Secret<AEAD_KEY_SIZE>;
 
 // EAR key used to encrypt [`AddPackage`]s.
@@ -303,6 +643,22 @@ impl WelcomeAttributionInfoEarKey {
             key: WelcomeAttributionInfoEarKeySecret::random()?,
         })
     }
+
+    /// Derive the token under which the owner of this key reveals their
+    /// presence to the owner of `viewer_key`. Contacts know each other's key,
+    /// but the QS doesn't, so it can't tell whose contact a token belongs to.
+    pub fn presence_token(&self, viewer_key: &Self) -> Result<PresenceToken, LibraryError> {
+        let input_key_material = [
+            self.key.secret().as_slice(),
+            viewer_key.key.secret().as_slice(),
+        ]
+        .concat();
+        let kdf = Kdf::new(None, &input_key_material);
+        let mut token = [0u8; PRESENCE_TOKEN_SIZE];
+        kdf.expand(b"presence token", &mut token)
+            .map_err(|_| LibraryError)?;
+        Ok(PresenceToken::from(token))
+    }
 }
 
 impl EarKey for WelcomeAttributionInfoEarKey {}
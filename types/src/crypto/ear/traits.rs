@@ -7,7 +7,7 @@
 //! the [`EarEncryptable`] trait.
 
 use aes_gcm::{
-    aead::{Aead as AesGcmAead, Key, Nonce},
+    aead::{Aead as AesGcmAead, Key, Nonce, Payload},
     KeyInit,
 };
 use serde::de::DeserializeOwned;
@@ -29,6 +29,17 @@ pub trait EarKey: AsRef<Secret<AEAD_KEY_SIZE>> + From<Secret<AEAD_KEY_SIZE>> {
     // Encrypt the given plaintext under the given key. Generates a random nonce internally.
     #[instrument(level = "trace", skip_all, fields(key_type = std::any::type_name::<Self>()))]
     fn encrypt(&self, plaintext: &[u8]) -> Result<Ciphertext, EncryptionError> {
+        self.encrypt_with_aad(plaintext, &[])
+    }
+
+    // Encrypt the given plaintext under the given key and bind the ciphertext
+    // to the given additional data. Generates a random nonce internally.
+    #[instrument(level = "trace", skip_all, fields(key_type = std::any::type_name::<Self>()))]
+    fn encrypt_with_aad(
+        &self,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Ciphertext, EncryptionError> {
         // TODO: from_slice can potentially panic. However, we can rule this out
         // with a single test, since both the AEAD algorithm and the key size
         // are static.
@@ -42,7 +53,13 @@ pub trait EarKey: AsRef<Secret<AEAD_KEY_SIZE>> + From<Secret<AEAD_KEY_SIZE>> {
         // The Aead trait surfaces an error, but it's not clear under which
         // circumstances it would actually fail.
         let ciphertext = cipher
-            .encrypt(&nonce, plaintext)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
             .map_err(|_| EncryptionError::EncryptionError)?;
         Ok(Ciphertext {
             ciphertext,
@@ -53,14 +70,28 @@ pub trait EarKey: AsRef<Secret<AEAD_KEY_SIZE>> + From<Secret<AEAD_KEY_SIZE>> {
     // Decrypt the given ciphertext (including the nonce) using the given key.
     #[instrument(level = "trace", skip_all, fields(key_type = std::any::type_name::<Self>()))]
     fn decrypt(&self, ciphertext: &Ciphertext) -> Result<Vec<u8>, DecryptionError> {
+        self.decrypt_with_aad(ciphertext, &[])
+    }
+
+    // Decrypt the given ciphertext (including the nonce) using the given key.
+    // Fails if the ciphertext wasn't bound to the given additional data.
+    #[instrument(level = "trace", skip_all, fields(key_type = std::any::type_name::<Self>()))]
+    fn decrypt_with_aad(
+        &self,
+        ciphertext: &Ciphertext,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
         // TODO: from_slice can potentially panic. However, we can rule this out
         // with a single test, since both the AEAD algorithm and the key size
         // are static.
         let key = Key::<Aead>::from_slice(self.as_ref().secret());
         let cipher: Aead = Aead::new(key);
-        // TODO: Use a proper RNG provider instead.
+        let payload = Payload {
+            msg: ciphertext.ciphertext.as_slice(),
+            aad,
+        };
         cipher
-            .decrypt(&ciphertext.nonce.into(), ciphertext.ciphertext.as_slice())
+            .decrypt(&ciphertext.nonce.into(), payload)
             .map_err(|_| DecryptionError::DecryptionError)
     }
 }
@@ -10,7 +10,6 @@ use mls_assist::{
     openmls_rust_crypto::OpenMlsRustCrypto,
     openmls_traits::{
         crypto::OpenMlsCrypto,
-        random::OpenMlsRand,
         types::{HpkeCiphertext, HpkePrivateKey},
         OpenMlsProvider,
     },
@@ -23,6 +22,7 @@ use crate::identifiers::{ClientConfig, SealedClientReference};
 use super::{
     ear::{GenericDeserializable, GenericSerializable},
     errors::{DecryptionError, EncryptionError, RandomnessError},
+    rng,
     secrets::SecretBytes,
 };
 
@@ -89,12 +89,8 @@ impl DecryptionKey {
     }
 
     pub fn generate() -> Result<Self, RandomnessError> {
-        let provider = OpenMlsRustCrypto::default();
-        let key_seed = provider
-            .rand()
-            .random_array::<32>()
-            .map_err(|_| RandomnessError::InsufficientRandomness)?;
-        let keypair = provider
+        let key_seed = rng::random_array::<32>()?;
+        let keypair = OpenMlsRustCrypto::default()
             .crypto()
             .derive_hpke_keypair(HPKE_CONFIG, &key_seed)
             .map_err(|_| RandomnessError::InsufficientRandomness)?;
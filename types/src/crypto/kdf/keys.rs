@@ -11,9 +11,9 @@ use mls_assist::openmls::prelude::GroupEpoch;
 use serde::{Deserialize, Serialize};
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
-use crate::crypto::{errors::RandomnessError, secrets::Secret};
+use crate::crypto::{ear::keys::ClientCredentialEarKey, errors::RandomnessError, secrets::Secret};
 
-use super::{traits::KdfKey, KdfDerivable, KdfExtractable, KDF_KEY_SIZE};
+use super::{traits::KdfKey, Kdf, KdfDerivable, KdfExtractable, KDF_KEY_SIZE};
 
 /// A secret meant to be injected into the extraction of the new roster kdf key.
 #[derive(TlsSerialize, TlsSize, TlsDeserializeBytes, Clone, Debug)]
@@ -172,3 +172,97 @@ impl From<Secret<KDF_KEY_SIZE>> for FriendshipSecret {
         Self { key }
     }
 }
+
+/// Id of a join request token. The id is random and doesn't have to be kept
+/// secret, it is used as salt when extracting the [`JoinRequestSecret`].
+#[derive(Clone, Debug, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct JoinRequestTokenId {
+    id: Secret<KDF_KEY_SIZE>,
+}
+
+impl JoinRequestTokenId {
+    pub fn random() -> Result<Self, RandomnessError> {
+        let id = Secret::random()?;
+        Ok(Self { id })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.id.secret()
+    }
+}
+
+impl AsRef<Secret<KDF_KEY_SIZE>> for JoinRequestTokenId {
+    fn as_ref(&self) -> &Secret<KDF_KEY_SIZE> {
+        &self.id
+    }
+}
+
+/// Secret of a join request token. Members extract it from the id of the
+/// token and the credential EAR key of the group, such that they don't have
+/// to keep track of the tokens handed out by other members. Holders of the
+/// token can't learn the credential EAR key from it.
+#[derive(Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct JoinRequestSecret {
+    key: Secret<KDF_KEY_SIZE>,
+}
+
+impl JoinRequestSecret {
+    pub fn new(token_id: &JoinRequestTokenId, credential_ear_key: &ClientCredentialEarKey) -> Self {
+        Self::extract(token_id, credential_ear_key)
+    }
+}
+
+impl AsRef<Secret<KDF_KEY_SIZE>> for JoinRequestSecret {
+    fn as_ref(&self) -> &Secret<KDF_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl KdfKey for JoinRequestSecret {
+    const ADDITIONAL_LABEL: &'static str = "JoinRequestSecret";
+}
+
+impl From<Secret<KDF_KEY_SIZE>> for JoinRequestSecret {
+    fn from(key: Secret<KDF_KEY_SIZE>) -> Self {
+        Self { key }
+    }
+}
+
+impl KdfExtractable<JoinRequestTokenId, ClientCredentialEarKey> for JoinRequestSecret {}
+
+/// Secret shared by all clients of a user, from which the keys protecting the
+/// user's own data are derived.
+///
+/// It is extracted from the export key of the user's OPAQUE registration,
+/// which every client of the user obtains when authenticating with the
+/// password. It thus doesn't have to be distributed between the clients.
+#[derive(Debug)]
+pub struct UserKeySecret {
+    key: Secret<KDF_KEY_SIZE>,
+}
+
+impl UserKeySecret {
+    pub fn from_opaque_export_key(export_key: &[u8]) -> Self {
+        let (prk, _) = Kdf::extract(Some(Self::ADDITIONAL_LABEL.as_bytes()), export_key);
+        let key: [u8; KDF_KEY_SIZE] = prk.into();
+        Self {
+            key: Secret::from(key),
+        }
+    }
+}
+
+impl AsRef<Secret<KDF_KEY_SIZE>> for UserKeySecret {
+    fn as_ref(&self) -> &Secret<KDF_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl KdfKey for UserKeySecret {
+    const ADDITIONAL_LABEL: &'static str = "UserKeySecret";
+}
+
+impl From<Secret<KDF_KEY_SIZE>> for UserKeySecret {
+    fn from(key: Secret<KDF_KEY_SIZE>) -> Self {
+        Self { key }
+    }
+}
@@ -104,3 +104,24 @@ impl AsRef<Secret<MAC_KEY_SIZE>> for QueueUpdateAuthKey {
 }
 
 impl MacKey for QueueUpdateAuthKey {}
+
+/// A secret used by the QS to authenticate the resumption tickets it issues
+/// to clients listening to their queue.
+#[derive(Debug)]
+pub struct QsResumptionTicketKey {
+    key: Secret<MAC_KEY_SIZE>,
+}
+
+impl From<Secret<MAC_KEY_SIZE>> for QsResumptionTicketKey {
+    fn from(secret: Secret<MAC_KEY_SIZE>) -> Self {
+        Self { key: secret }
+    }
+}
+
+impl AsRef<Secret<MAC_KEY_SIZE>> for QsResumptionTicketKey {
+    fn as_ref(&self) -> &Secret<MAC_KEY_SIZE> {
+        &self.key
+    }
+}
+
+impl MacKey for QsResumptionTicketKey {}
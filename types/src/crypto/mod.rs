@@ -7,8 +7,6 @@
 //!
 //! TODO: Once const-generics allows the use of enums, we could get rid of a
 //! number of structs and boilerplate code.
-//! TODO: A proper RNG provider for use with all crypto functions that require
-//! randomness, i.e. mainly secret and nonce sampling.
 #![allow(unused_variables)]
 use std::marker::PhantomData;
 
@@ -39,6 +37,7 @@ pub mod kdf;
 pub mod mac;
 pub mod opaque;
 pub mod ratchet;
+pub mod rng;
 pub mod secrets;
 pub(super) mod serde_arrays;
 pub mod signatures;
@@ -104,3 +103,44 @@ impl ConnectionDecryptionKey {
         }
     }
 }
+
+/// Key to which the AS encrypts the data export of a user.
+#[derive(Debug, Clone, Serialize, Deserialize, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct DataExportEncryptionKey {
+    encryption_key: EncryptionPublicKey,
+}
+
+impl AsRef<EncryptionPublicKey> for DataExportEncryptionKey {
+    fn as_ref(&self) -> &EncryptionPublicKey {
+        &self.encryption_key
+    }
+}
+
+impl HpkeEncryptionKey for DataExportEncryptionKey {}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DataExportDecryptionKey {
+    decryption_key: DecryptionKey,
+}
+
+impl AsRef<DecryptionKey> for DataExportDecryptionKey {
+    fn as_ref(&self) -> &DecryptionKey {
+        &self.decryption_key
+    }
+}
+
+impl HpkeDecryptionKey for DataExportDecryptionKey {}
+
+impl DataExportDecryptionKey {
+    pub fn generate() -> Result<Self, RandomnessError> {
+        Ok(Self {
+            decryption_key: DecryptionKey::generate()?,
+        })
+    }
+
+    pub fn encryption_key(&self) -> DataExportEncryptionKey {
+        DataExportEncryptionKey {
+            encryption_key: self.decryption_key.public_key().clone(),
+        }
+    }
+}
@@ -4,10 +4,13 @@
 
 use argon2::Argon2;
 use opaque_ke::{
-    CipherSuite, CredentialFinalization, CredentialRequest, CredentialResponse,
+    CipherSuite, CredentialFinalization, CredentialRequest, CredentialResponse, Identifiers,
     RegistrationRequest, RegistrationResponse, RegistrationUpload,
 };
 use serde::{Deserialize, Serialize};
+use tls_codec::Serialize as _;
+
+use crate::identifiers::QualifiedUserName;
 
 mod codec;
 
@@ -62,6 +65,30 @@ pub(crate) const OPAQUE_LOGIN_RESPONSE_SIZE: usize =
 // The size of the KE3 struct
 pub(crate) const OPAQUE_LOGIN_FINISH_SIZE: usize = OPAQUE_NM;
 
+/// Identifiers that bind the OPAQUE registration and logins of a user to the
+/// user name and its domain. Registration and login must use the same
+/// identifiers.
+pub struct OpaqueIdentifiers {
+    client: Vec<u8>,
+    server: Vec<u8>,
+}
+
+impl OpaqueIdentifiers {
+    pub fn new(user_name: &QualifiedUserName) -> Result<Self, tls_codec::Error> {
+        Ok(Self {
+            client: user_name.tls_serialize_detached()?,
+            server: user_name.domain().tls_serialize_detached()?,
+        })
+    }
+
+    pub fn identifiers(&self) -> Identifiers<'_> {
+        Identifiers {
+            client: Some(&self.client),
+            server: Some(&self.server),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpaqueLoginRequest {
     pub client_message: CredentialRequest<OpaqueCiphersuite>,
@@ -6,6 +6,7 @@ use errors::{DecryptionError, EncryptionError};
 use serde::de::DeserializeOwned;
 
 use super::{errors::RandomnessError, *};
+use crate::messages::QueuePriority;
 
 #[cfg(test)]
 mod tests;
@@ -86,6 +87,7 @@ impl<Ciphertext: RatchetCiphertext, Payload: RatchetPayload<Ciphertext>>
         let queue_message = QueueMessage {
             sequence_number: self.sequence_number,
             ciphertext: ciphertext.as_ref().clone(),
+            priority: QueuePriority::default(),
         };
 
         self.ratchet_forward()?;
@@ -95,13 +97,32 @@ impl<Ciphertext: RatchetCiphertext, Payload: RatchetPayload<Ciphertext>>
 
     /// Decrypt the given payload.
     pub fn decrypt(&mut self, queue_message: QueueMessage) -> Result<Payload, DecryptionError> {
-        let ciphertext = queue_message.ciphertext.into();
-        let plaintext = Payload::decrypt(&self.key, &ciphertext)?;
+        let plaintext = Self::decrypt_with_key(&self.key, queue_message)?;
         self.ratchet_forward()
             .map_err(|_| DecryptionError::DecryptionError)?;
         Ok(plaintext)
     }
 
+    /// Return the key of the next message and ratchet forward.
+    ///
+    /// This allows decrypting messages independently of the ratchet (e.g. in
+    /// parallel) using [`Self::decrypt_with_key`].
+    pub fn next_key(&mut self) -> Result<RatchetKey, DecryptionError> {
+        let key = self.key.clone();
+        self.ratchet_forward()
+            .map_err(|_| DecryptionError::DecryptionError)?;
+        Ok(key)
+    }
+
+    /// Decrypt the given payload with a key returned by [`Self::next_key`].
+    pub fn decrypt_with_key(
+        key: &RatchetKey,
+        queue_message: QueueMessage,
+    ) -> Result<Payload, DecryptionError> {
+        let ciphertext = queue_message.ciphertext.into();
+        Payload::decrypt(key, &ciphertext)
+    }
+
     /// Sample some fresh entropy and inject it into the current key. Returns the entropy.
     pub fn update(&mut self) -> RatchetKeyUpdate {
         todo!()
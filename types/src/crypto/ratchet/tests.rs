@@ -3,7 +3,10 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use crate::{
-    messages::client_ds::{QsQueueMessagePayload, QsQueueMessageType},
+    messages::{
+        client_ds::{QsQueueMessagePayload, QsQueueMessageType},
+        EncryptedQsQueueMessage,
+    },
     time::TimeStamp,
 };
 
@@ -24,3 +27,36 @@ fn test_ratchet() {
     let plaintext: QsQueueMessagePayload = receiver_ratchtet.decrypt(encrypted_message).unwrap();
     assert_eq!(plaintext, message);
 }
+
+// Test that messages can be decrypted independently of the ratchet.
+#[test]
+fn test_decrypt_with_key() {
+    let ratchet_secret = RatchetSecret::random().unwrap();
+    let mut sender_ratchet = QueueRatchet::try_from(ratchet_secret.clone()).unwrap();
+    let mut receiver_ratchet: QueueRatchet<EncryptedQsQueueMessage, QsQueueMessagePayload> =
+        QueueRatchet::try_from(ratchet_secret).unwrap();
+    let messages: Vec<_> = (0..3u8)
+        .map(|i| QsQueueMessagePayload {
+            timestamp: TimeStamp::now(),
+            message_type: QsQueueMessageType::MlsMessage,
+            payload: vec![i],
+        })
+        .collect();
+    let encrypted_messages: Vec<_> = messages
+        .iter()
+        .map(|message| sender_ratchet.encrypt(message.clone()).unwrap())
+        .collect();
+    let keys: Vec<_> = (0..3)
+        .map(|_| receiver_ratchet.next_key().unwrap())
+        .collect();
+    // Decrypt in reverse order
+    for ((key, encrypted_message), message) in
+        keys.iter().zip(encrypted_messages).zip(&messages).rev()
+    {
+        let plaintext: QsQueueMessagePayload =
+            QueueRatchet::<EncryptedQsQueueMessage, _>::decrypt_with_key(key, encrypted_message)
+                .unwrap();
+        assert_eq!(&plaintext, message);
+    }
+    assert_eq!(receiver_ratchet.sequence_number(), 3);
+}
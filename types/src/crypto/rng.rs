@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Source of randomness for keys, nonces and identifiers.
+//!
+//! All randomness is drawn from [`PhnxRng`], which uses the OS RNG. With the
+//! `test_utils` feature, a deterministic RNG can be seeded via
+//! `set_rng_seed` instead. Seeding allows tests to replay flows that generate
+//! keys with the same keys each time.
+
+#[cfg(any(test, feature = "test_utils"))]
+use std::cell::RefCell;
+
+#[cfg(any(test, feature = "test_utils"))]
+use rand::SeedableRng;
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use rand_chacha::rand_core::impls;
+#[cfg(any(test, feature = "test_utils"))]
+use rand_chacha::ChaCha20Rng;
+use uuid::Uuid;
+
+use super::errors::RandomnessError;
+
+#[cfg(any(test, feature = "test_utils"))]
+thread_local! {
+    static SEEDED_RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+
+/// Replace the OS RNG of the current thread with a deterministic RNG seeded
+/// with the given seed.
+///
+/// Only intended for tests. Like the thread time provider (see
+/// [`set_thread_time_provider`](crate::time::set_thread_time_provider)) and
+/// unlike the process-wide one, the seed only applies to the current thread,
+/// such that tests running in parallel don't draw from each other's RNG.
+#[cfg(any(test, feature = "test_utils"))]
+pub fn set_rng_seed(seed: u64) {
+    SEEDED_RNG.with_borrow_mut(|rng| *rng = Some(ChaCha20Rng::seed_from_u64(seed)));
+}
+
+/// Go back to the OS RNG on the current thread.
+#[cfg(any(test, feature = "test_utils"))]
+pub fn reset_rng() {
+    SEEDED_RNG.with_borrow_mut(|rng| *rng = None);
+}
+
+/// Returns true if a deterministic RNG is seeded on the current thread.
+#[cfg(any(test, feature = "test_utils"))]
+pub fn is_seeded() -> bool {
+    SEEDED_RNG.with_borrow(|rng| rng.is_some())
+}
+
+/// Handle to the process-wide RNG, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhnxRng;
+
+impl RngCore for PhnxRng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if let Err(e) = self.try_fill_bytes(dest) {
+            panic!("Failed to collect randomness: {e}");
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        #[cfg(any(test, feature = "test_utils"))]
+        if SEEDED_RNG
+            .with_borrow_mut(|rng| rng.as_mut().map(|rng| rng.fill_bytes(dest)))
+            .is_some()
+        {
+            return Ok(());
+        }
+        OsRng.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for PhnxRng {}
+
+pub fn random_array<const N: usize>() -> Result<[u8; N], RandomnessError> {
+    let mut out = [0u8; N];
+    PhnxRng
+        .try_fill_bytes(&mut out)
+        .map_err(|_| RandomnessError::InsufficientRandomness)?;
+    Ok(out)
+}
+
+pub fn random_vec(len: usize) -> Result<Vec<u8>, RandomnessError> {
+    let mut out = vec![0u8; len];
+    PhnxRng
+        .try_fill_bytes(&mut out)
+        .map_err(|_| RandomnessError::InsufficientRandomness)?;
+    Ok(out)
+}
+
+/// A random (version 4) UUID. Panics if no randomness is available, like
+/// [`Uuid::new_v4`].
+pub fn random_uuid() -> Uuid {
+    let mut bytes = [0u8; 16];
+    PhnxRng.fill_bytes(&mut bytes);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_rng_is_deterministic() {
+        set_rng_seed(7);
+        let first = (random_vec(32).unwrap(), random_uuid());
+        set_rng_seed(7);
+        let second = (random_vec(32).unwrap(), random_uuid());
+        set_rng_seed(8);
+        let other = (random_vec(32).unwrap(), random_uuid());
+        reset_rng();
+        let unseeded = (random_vec(32).unwrap(), random_uuid());
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_ne!(first, unseeded);
+        assert_eq!(first.1.get_version_num(), 4);
+    }
+}
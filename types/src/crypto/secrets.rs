@@ -8,58 +8,130 @@
 //! and the type checker happy.
 use std::{fmt::Display, ops::Deref};
 
-use rand::{RngCore, SeedableRng};
+use rand::RngCore;
 #[cfg(feature = "sqlite")]
 use rusqlite::{types::FromSql, ToSql};
 use secrecy::{zeroize::ZeroizeOnDrop, CloneableSecret, DebugSecret, SerializableSecret, Zeroize};
 use serde::{Deserialize, Serialize};
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
-use super::RandomnessError;
+use super::{rng::PhnxRng, RandomnessError};
 
 /// Struct that contains a (symmetric) secret of fixed length LENGTH.
+///
+/// Like [`SecretBytes`], the secret is kept on the heap and its memory is
+/// locked where available.
 #[derive(TlsSerialize, TlsDeserializeBytes, TlsSize, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Secret<const LENGTH: usize> {
-    #[serde(with = "super::serde_arrays")]
-    secret: [u8; LENGTH],
+    secret: LockedArray<LENGTH>,
 }
 
 impl<const LENGTH: usize> From<[u8; LENGTH]> for Secret<LENGTH> {
     fn from(secret: [u8; LENGTH]) -> Self {
-        Self { secret }
+        Self {
+            secret: LockedArray::new(secret),
+        }
     }
 }
 
 impl<const LENGTH: usize> Secret<LENGTH> {
     /// Get the internal secret value
     pub(super) fn secret(&self) -> &[u8; LENGTH] {
-        &self.secret
+        &self.secret.0
     }
 
     pub(super) fn into_secret(self) -> [u8; LENGTH] {
-        self.secret
+        *self.secret.0
     }
 
     /// Generate a fresh, random secret.
     pub(super) fn random() -> Result<Self, RandomnessError> {
-        let mut secret = [0; LENGTH];
-        // TODO: Use a proper rng provider.
-        rand_chacha::ChaCha20Rng::from_entropy()
-            .try_fill_bytes(secret.as_mut_slice())
+        let mut secret = LockedArray::new([0; LENGTH]);
+        PhnxRng
+            .try_fill_bytes(secret.0.as_mut_slice())
             .map_err(|_| RandomnessError::InsufficientRandomness)?;
         Ok(Self { secret })
     }
 }
 
+/// Fixed-size array on the heap, such that its memory can be locked. The
+/// encodings are the ones of the array.
+#[derive(PartialEq)]
+struct LockedArray<const LENGTH: usize>(Box<[u8; LENGTH]>);
+
+impl<const LENGTH: usize> LockedArray<LENGTH> {
+    fn new(array: [u8; LENGTH]) -> Self {
+        let array = Box::new(array);
+        memory_lock::lock(array.as_slice());
+        Self(array)
+    }
+}
+
+impl<const LENGTH: usize> Clone for LockedArray<LENGTH> {
+    fn clone(&self) -> Self {
+        Self::new(*self.0)
+    }
+}
+
+impl<const LENGTH: usize> Drop for LockedArray<LENGTH> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        memory_lock::unlock(self.0.as_slice());
+    }
+}
+
+impl<const LENGTH: usize> Serialize for LockedArray<LENGTH> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        super::serde_arrays::serialize(&*self.0, serializer)
+    }
+}
+
+impl<'de, const LENGTH: usize> Deserialize<'de> for LockedArray<LENGTH> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut array: [u8; LENGTH] = super::serde_arrays::deserialize(deserializer)?;
+        let locked = Self::new(array);
+        array.zeroize();
+        Ok(locked)
+    }
+}
+
+impl<const LENGTH: usize> tls_codec::Size for LockedArray<LENGTH> {
+    fn tls_serialized_len(&self) -> usize {
+        tls_codec::Size::tls_serialized_len(&*self.0)
+    }
+}
+
+impl<const LENGTH: usize> tls_codec::Serialize for LockedArray<LENGTH> {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        tls_codec::Serialize::tls_serialize(&*self.0, writer)
+    }
+}
+
+impl<const LENGTH: usize> tls_codec::DeserializeBytes for LockedArray<LENGTH> {
+    fn tls_deserialize_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (mut array, remainder) =
+            <[u8; LENGTH] as tls_codec::DeserializeBytes>::tls_deserialize_bytes(bytes)?;
+        let locked = Self::new(array);
+        array.zeroize();
+        Ok((locked, remainder))
+    }
+}
+
 // Ensure that secrets are wiped from memory securely upon being dropped.
 impl<const LENGTH: usize> Zeroize for Secret<LENGTH> {
     fn zeroize(&mut self) {
-        self.secret.zeroize()
+        self.secret.0.zeroize()
     }
 }
 
 impl<const LENGTH: usize> ZeroizeOnDrop for Secret<LENGTH> {}
 
+impl<const LENGTH: usize> Drop for Secret<LENGTH> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 // Ensures that secrets are not printed in debug outputs.
 impl<const LENGTH: usize> std::fmt::Debug for Secret<LENGTH> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -77,7 +149,7 @@ impl<const LENGTH: usize> Display for Secret<LENGTH> {
 #[cfg(feature = "sqlite")]
 impl ToSql for Secret<32> {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
-        Ok(rusqlite::types::ToSqlOutput::from(self.secret.to_vec()))
+        Ok(rusqlite::types::ToSqlOutput::from(self.secret.0.to_vec()))
     }
 }
 
@@ -85,24 +157,58 @@ impl ToSql for Secret<32> {
 impl FromSql for Secret<32> {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
         let secret = value.as_blob()?;
-        let mut secret_bytes = [0u8; 32];
-        secret_bytes.copy_from_slice(secret);
+        let mut secret_bytes = LockedArray::new([0u8; 32]);
+        secret_bytes.0.copy_from_slice(secret);
         Ok(Secret {
             secret: secret_bytes,
         })
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
-pub(super) struct SecretBytes(Vec<u8>);
+/// Secret of variable length, e.g. a private key.
+///
+/// The secret is zeroized when dropped and can't be printed. Where available,
+/// the memory holding the secret is locked, such that it isn't swapped to
+/// disk. Locking is best effort: it fails silently if the process exceeds
+/// its limit of locked memory.
+#[derive(Serialize, Deserialize)]
+#[serde(from = "Vec<u8>")]
+pub struct SecretBytes(Vec<u8>);
 
 impl From<Vec<u8>> for SecretBytes {
     fn from(secret: Vec<u8>) -> Self {
+        memory_lock::lock(&secret);
         Self(secret)
     }
 }
 
+impl From<&[u8]> for SecretBytes {
+    fn from(secret: &[u8]) -> Self {
+        secret.to_vec().into()
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        self.0.clone().into()
+    }
+}
+
+impl SecretBytes {
+    pub fn expose_secret(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // Zeroizing truncates the vector, so the locked range has to be
+        // unlocked first.
+        memory_lock::unlock(&self.0);
+        self.zeroize();
+    }
+}
+
 impl Deref for SecretBytes {
     type Target = Vec<u8>;
 
@@ -136,3 +242,211 @@ impl Display for SecretBytes {
 impl SerializableSecret for SecretBytes {}
 impl CloneableSecret for SecretBytes {}
 impl DebugSecret for SecretBytes {}
+
+#[cfg(feature = "sqlx")]
+mod sqlx_impls {
+    use sqlx::{
+        encode::IsNull,
+        error::BoxDynError,
+        postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+        Decode, Encode, Postgres, Type,
+    };
+
+    use super::SecretBytes;
+
+    impl Type<Postgres> for SecretBytes {
+        fn type_info() -> PgTypeInfo {
+            <Vec<u8> as Type<Postgres>>::type_info()
+        }
+    }
+
+    impl Encode<'_, Postgres> for SecretBytes {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+            <&[u8] as Encode<Postgres>>::encode(self.expose_secret(), buf)
+        }
+    }
+
+    // Decode through `From`, such that the memory of the secret is locked.
+    impl<'r> Decode<'r, Postgres> for SecretBytes {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            <&[u8] as Decode<Postgres>>::decode(value).map(SecretBytes::from)
+        }
+    }
+}
+
+/// Locking of the memory pages that hold secrets.
+///
+/// `mlock` and `munlock` work on whole pages and don't nest, so several
+/// secrets on the same page share its lock. A page is only unlocked once the
+/// last secret on it is unlocked.
+mod memory_lock {
+    use std::{
+        collections::BTreeMap,
+        ops::Range,
+        sync::{Mutex, PoisonError},
+    };
+
+    /// Number of locked secrets on each page, by page number.
+    static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+    pub(super) fn lock(secret: &[u8]) {
+        if secret.is_empty() {
+            return;
+        }
+        let page_size = page_size();
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+        for page in acquire(&mut locked_pages, pages(secret, page_size)) {
+            lock_page(page, page_size);
+        }
+    }
+
+    pub(super) fn unlock(secret: &[u8]) {
+        if secret.is_empty() {
+            return;
+        }
+        let page_size = page_size();
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+        for page in release(&mut locked_pages, pages(secret, page_size)) {
+            unlock_page(page, page_size);
+        }
+    }
+
+    /// Numbers of the pages that hold the given non-empty secret.
+    fn pages(secret: &[u8], page_size: usize) -> Range<usize> {
+        let start = secret.as_ptr() as usize;
+        start / page_size..(start + secret.len() - 1) / page_size + 1
+    }
+
+    /// Count a secret on the given pages. Returns the pages that weren't
+    /// locked before.
+    fn acquire(locked_pages: &mut BTreeMap<usize, usize>, pages: Range<usize>) -> Vec<usize> {
+        pages
+            .filter(|page| {
+                let count = locked_pages.entry(*page).or_default();
+                *count += 1;
+                *count == 1
+            })
+            .collect()
+    }
+
+    /// Stop counting a secret on the given pages. Returns the pages that
+    /// don't hold any other locked secret.
+    fn release(locked_pages: &mut BTreeMap<usize, usize>, pages: Range<usize>) -> Vec<usize> {
+        pages
+            .filter(|page| match locked_pages.get_mut(page) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                Some(_) => {
+                    locked_pages.remove(page);
+                    true
+                }
+                None => false,
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn page_size() -> usize {
+        // SAFETY: `sysconf` has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        usize::try_from(page_size).unwrap_or(4096)
+    }
+
+    #[cfg(unix)]
+    fn lock_page(page: usize, page_size: usize) {
+        // SAFETY: The page is mapped, since it holds a live secret. Locking
+        // doesn't modify the memory. Failing to lock is not an error.
+        unsafe { libc::mlock((page * page_size) as *const libc::c_void, page_size) };
+    }
+
+    #[cfg(unix)]
+    fn unlock_page(page: usize, page_size: usize) {
+        // SAFETY: See `lock_page`.
+        unsafe { libc::munlock((page * page_size) as *const libc::c_void, page_size) };
+    }
+
+    #[cfg(not(unix))]
+    fn page_size() -> usize {
+        4096
+    }
+
+    #[cfg(not(unix))]
+    fn lock_page(_page: usize, _page_size: usize) {}
+
+    #[cfg(not(unix))]
+    fn unlock_page(_page: usize, _page_size: usize) {}
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn pages_stay_locked_while_they_hold_a_secret() {
+            let mut locked_pages = BTreeMap::new();
+            // Two secrets share page 1
+            assert_eq!(acquire(&mut locked_pages, 0..2), [0, 1]);
+            assert_eq!(acquire(&mut locked_pages, 1..3), [2]);
+
+            assert_eq!(release(&mut locked_pages, 0..2), [0]);
+            assert_eq!(release(&mut locked_pages, 1..3), [1, 2]);
+            assert!(locked_pages.is_empty());
+
+            // Releasing pages that were never locked doesn't unlock them
+            assert!(release(&mut locked_pages, 0..1).is_empty());
+        }
+
+        #[test]
+        fn pages_of_secrets() {
+            let secret = [0u8; 10];
+            let start = secret.as_ptr() as usize;
+            let pages = pages(&secret, 4);
+            assert_eq!(pages.start, start / 4);
+            assert_eq!(pages.end, (start + 9) / 4 + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let secret = SecretBytes::from(b"very secret".to_vec());
+        assert!(!format!("{secret:?}").contains("very"));
+        assert!(!format!("{secret}").contains("very"));
+        assert_eq!(secret.expose_secret(), b"very secret");
+
+        let secret = Secret::from([7u8; 32]);
+        assert!(!format!("{secret:?}").contains('7'));
+    }
+
+    #[test]
+    fn secret_bytes_serde_is_transparent() {
+        let secret = SecretBytes::from(vec![1, 2, 3]);
+        let serialized = serde_json::to_string(&secret).unwrap();
+        assert_eq!(serialized, serde_json::to_string(&vec![1u8, 2, 3]).unwrap());
+        let deserialized: SecretBytes = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.expose_secret(), secret.expose_secret());
+    }
+
+    #[test]
+    fn locked_secrets_keep_the_array_encodings() {
+        use tls_codec::{DeserializeBytes, Serialize as _};
+
+        let secret = Secret::from([7u8; 32]);
+        let tls_encoded = secret.tls_serialize_detached().unwrap();
+        assert_eq!(tls_encoded, [7u8; 32].tls_serialize_detached().unwrap());
+        let (decoded, _) = Secret::<32>::tls_deserialize_bytes(&tls_encoded).unwrap();
+        assert_eq!(decoded, secret);
+
+        let serialized = serde_json::to_string(&secret).unwrap();
+        let array = [7u8; 32];
+        let expected = serde_json::json!({ "secret": array }).to_string();
+        assert_eq!(serialized, expected);
+        let deserialized: Secret<32> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, secret);
+    }
+}
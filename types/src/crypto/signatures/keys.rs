@@ -7,12 +7,13 @@ use mls_assist::{
     openmls_rust_crypto::OpenMlsRustCrypto,
 };
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize, VLBytes};
 
 #[cfg(feature = "sqlite")]
 use crate::codec::PhnxCodec;
 
-use crate::crypto::errors::KeyGenerationError;
+use crate::{crypto::errors::KeyGenerationError, identifiers::Fqdn, LibraryError};
 
 use super::{
     private_keys::{SigningKey, VerifyingKey},
@@ -251,3 +252,153 @@ impl AsRef<VerifyingKey> for QsVerifyingKey {
 }
 
 impl VerifyingKeyBehaviour for QsVerifyingKey {}
+
+const QS_VERIFYING_KEY_FINGERPRINT_LABEL: &str = "Infra QS Verifying Key Fingerprint";
+
+impl QsVerifyingKey {
+    /// Compute the fingerprint of this key. Servers publish the fingerprint of
+    /// their QS verifying key out-of-band (see [`FederationKeyRecord`]), such
+    /// that federated peers can check the key on first contact.
+    pub fn fingerprint(&self) -> Result<QsVerifyingKeyFingerprint, LibraryError> {
+        let input = [
+            QS_VERIFYING_KEY_FINGERPRINT_LABEL.as_bytes(),
+            self.0.as_slice(),
+        ]
+        .concat();
+        let value = OpenMlsRustCrypto::default()
+            .crypto()
+            .hash(HashType::Sha2_256, &input)
+            .map_err(|_| LibraryError::unexpected_crypto_error("Failed to hash QS key"))?;
+        Ok(QsVerifyingKeyFingerprint(value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct QsVerifyingKeyFingerprint(Vec<u8>);
+
+impl QsVerifyingKeyFingerprint {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for QsVerifyingKeyFingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+impl std::str::FromStr for QsVerifyingKeyFingerprint {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(hex::decode(s)?))
+    }
+}
+
+/// Record with which a server publishes the fingerprint of its QS verifying
+/// key. Operators publish it as DNS TXT record at `_phnx-federation.<domain>`
+/// (see [`Self::dns_name`]), such that peers can check the key independently
+/// of the server that presents it. The server also serves its record at
+/// [`ENDPOINT_QS_KEY_FINGERPRINT`](crate::endpoint_paths::ENDPOINT_QS_KEY_FINGERPRINT),
+/// from where operators can copy it.
+///
+/// `v=phnx1; qs=<hex encoded fingerprint>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationKeyRecord {
+    qs_key_fingerprint: QsVerifyingKeyFingerprint,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum FederationKeyRecordError {
+    /// Record has an unknown version
+    #[error("Unsupported record version")]
+    UnsupportedVersion,
+    /// Record doesn't contain a QS key fingerprint
+    #[error("Missing QS key fingerprint")]
+    MissingFingerprint,
+    /// Fingerprint is not valid hex
+    #[error("Invalid QS key fingerprint: {0}")]
+    InvalidFingerprint(#[from] hex::FromHexError),
+}
+
+impl FederationKeyRecord {
+    const VERSION: &'static str = "phnx1";
+    const DNS_LABEL: &'static str = "_phnx-federation";
+
+    pub fn new(qs_key_fingerprint: QsVerifyingKeyFingerprint) -> Self {
+        Self { qs_key_fingerprint }
+    }
+
+    pub fn qs_key_fingerprint(&self) -> &QsVerifyingKeyFingerprint {
+        &self.qs_key_fingerprint
+    }
+
+    /// Fully qualified name of the DNS TXT record of the given domain.
+    pub fn dns_name(domain: &Fqdn) -> String {
+        format!("{}.{}.", Self::DNS_LABEL, domain)
+    }
+}
+
+impl std::fmt::Display for FederationKeyRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v={}; qs={}", Self::VERSION, self.qs_key_fingerprint)
+    }
+}
+
+impl std::str::FromStr for FederationKeyRecord {
+    type Err = FederationKeyRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut version = None;
+        let mut fingerprint = None;
+        for field in s.trim().split(';') {
+            match field.trim().split_once('=') {
+                Some(("v", value)) => version = Some(value.trim()),
+                Some(("qs", value)) => fingerprint = Some(value.trim()),
+                // Ignore unknown fields for forward compatibility.
+                _ => {}
+            }
+        }
+        if version != Some(Self::VERSION) {
+            return Err(FederationKeyRecordError::UnsupportedVersion);
+        }
+        let qs_key_fingerprint = fingerprint
+            .ok_or(FederationKeyRecordError::MissingFingerprint)?
+            .parse()?;
+        Ok(Self { qs_key_fingerprint })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn federation_key_record_roundtrip() {
+        let key = QsSigningKey::generate().unwrap().verifying_key();
+        let fingerprint = key.fingerprint().unwrap();
+        let record = FederationKeyRecord::new(fingerprint.clone());
+        let parsed: FederationKeyRecord = record.to_string().parse().unwrap();
+        assert_eq!(parsed, record);
+        assert_eq!(parsed.qs_key_fingerprint(), &fingerprint);
+    }
+
+    #[test]
+    fn federation_key_record_rejects_unknown_version() {
+        let result = "v=phnx0; qs=00ff".parse::<FederationKeyRecord>();
+        assert_eq!(result, Err(FederationKeyRecordError::UnsupportedVersion));
+        let result = "v=phnx1".parse::<FederationKeyRecord>();
+        assert_eq!(result, Err(FederationKeyRecordError::MissingFingerprint));
+    }
+
+    #[test]
+    fn federation_key_record_dns_name() {
+        let domain = Fqdn::try_from("example.com").unwrap();
+        assert_eq!(
+            FederationKeyRecord::dns_name(&domain),
+            "_phnx-federation.example.com."
+        );
+    }
+}
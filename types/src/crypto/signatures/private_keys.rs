@@ -10,6 +10,8 @@ use mls_assist::{
 use serde::{Deserialize, Serialize};
 use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
 
+#[cfg(any(test, feature = "test_utils"))]
+use crate::crypto::rng;
 use crate::crypto::{errors::KeyGenerationError, secrets::SecretBytes};
 
 use super::DEFAULT_SIGNATURE_SCHEME;
@@ -52,6 +54,19 @@ pub struct SigningKey {
 
 impl SigningKey {
     pub fn generate() -> Result<SigningKey, KeyGenerationError> {
+        // The crypto provider uses its own RNG. If the RNG is seeded in tests,
+        // the key is derived from the seeded RNG instead, encoded like the
+        // provider's ed25519 keys.
+        #[cfg(any(test, feature = "test_utils"))]
+        if rng::is_seeded() {
+            let seed =
+                rng::random_array::<32>().map_err(|_| KeyGenerationError::KeypairGeneration)?;
+            let signing_key = ed25519_dalek::SigningKey::from_bytes(&seed);
+            return Ok(Self {
+                signing_key: SecretBytes::from(signing_key.to_keypair_bytes().to_vec()),
+                verifying_key: VerifyingKey(signing_key.verifying_key().to_bytes().to_vec()),
+            });
+        }
         let (private_key, public_key) = OpenMlsRustCrypto::default()
             .crypto()
             .signature_key_gen(DEFAULT_SIGNATURE_SCHEME)
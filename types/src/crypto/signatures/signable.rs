@@ -36,12 +36,19 @@
 
 use std::vec;
 
+use chrono::Duration;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tls_codec::{Serialize as TlsSerializeTrait, TlsDeserializeBytes, TlsSerialize, TlsSize};
 
 use crate::{
-    crypto::ear::{keys::SignatureEarKey, Ciphertext, EarDecryptable, EarEncryptable},
+    crypto::{
+        ear::{keys::SignatureEarKey, Ciphertext, EarDecryptable, EarEncryptable},
+        rng::PhnxRng,
+    },
     messages::FriendshipToken,
+    time::TimeStamp,
     LibraryError,
 };
 
@@ -93,6 +100,81 @@ impl AsRef<Ciphertext> for EncryptedSignature {
 impl EarEncryptable<SignatureEarKey, EncryptedSignature> for Signature {}
 impl EarDecryptable<SignatureEarKey, EncryptedSignature> for Signature {}
 
+pub const FRESHNESS_NONCE_SIZE: usize = 16;
+
+/// Maximum age of a signed payload before it is rejected as stale.
+pub const FRESHNESS_MAX_AGE: Duration = Duration::minutes(5);
+
+/// Maximum amount a payload's time stamp may lie in the future to account for
+/// clock skew between client and server.
+pub const FRESHNESS_MAX_CLOCK_SKEW: Duration = Duration::minutes(1);
+
+/// Freshness information included in signed client payloads. The time stamp
+/// bounds the window in which a payload is accepted and the nonce allows the
+/// server to reject replays within that window.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+pub struct Freshness {
+    timestamp: TimeStamp,
+    nonce: [u8; FRESHNESS_NONCE_SIZE],
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FreshnessError {
+    #[error("Payload is older than the freshness window")]
+    Stale,
+    #[error("Payload time stamp lies in the future")]
+    FromTheFuture,
+}
+
+impl Freshness {
+    /// Create freshness information for a payload signed now.
+    pub fn new() -> Self {
+        let mut nonce = [0u8; FRESHNESS_NONCE_SIZE];
+        PhnxRng.fill_bytes(&mut nonce);
+        Self {
+            timestamp: TimeStamp::now(),
+            nonce,
+        }
+    }
+
+    pub fn timestamp(&self) -> &TimeStamp {
+        &self.timestamp
+    }
+
+    pub fn nonce(&self) -> &[u8; FRESHNESS_NONCE_SIZE] {
+        &self.nonce
+    }
+
+    /// Check that the time stamp lies within the freshness window. This does
+    /// not check the nonce, which is up to the verifier's replay cache.
+    pub fn validate(&self) -> Result<(), FreshnessError> {
+        if self.timestamp.has_expired(FRESHNESS_MAX_AGE) {
+            return Err(FreshnessError::Stale);
+        }
+        if !self.timestamp.has_expired(-FRESHNESS_MAX_CLOCK_SKEW) {
+            return Err(FreshnessError::FromTheFuture);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Freshness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// This trait must be implemented by all structs that contain a self-signature.
 pub trait SignedStruct<T> {
     /// Build a signed struct version from the payload struct.
@@ -9,9 +9,15 @@ pub const ENDPOINT_DS_GROUPS: &str = "/ds_groups";
 pub const ENDPOINT_QS: &str = "/qs";
 pub const ENDPOINT_QS_FEDERATION: &str = "/qs_federation";
 pub const ENDPOINT_QS_WS: &str = "/qs/ws";
+pub const ENDPOINT_QS_KEY_FINGERPRINT: &str = "/.well-known/phnx/qs-key-fingerprint";
 
 /// AS endpoints
 pub const ENDPOINT_AS: &str = "/as";
+pub const ENDPOINT_AS_ANNOUNCEMENTS: &str = "/as/announcements";
 
 /// Health check endpoint
 pub const ENDPOINT_HEALTH_CHECK: &str = "/health_check";
+
+/// Admin endpoints
+pub const ENDPOINT_ADMIN_LOG_FILTER: &str = "/admin/log_filter";
+pub const ENDPOINT_ADMIN_METRICS: &str = "/admin/metrics";
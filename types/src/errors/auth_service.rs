@@ -40,6 +40,40 @@ pub enum InitUserRegistrationError {
     /// Error during OPAQUE registration
     #[error("Error during OPAQUE registration")]
     OpaqueRegistrationFailed,
+    /// The user name violates the handle policy of the AS
+    #[error("User name rejected: {0}")]
+    HandleRejected(HandleRejectionReason),
+    /// The operator closed the registration of new users
+    #[error("Registration is closed")]
+    RegistrationClosed,
+    /// The user name belongs to a domain not served by the AS
+    #[error("User name belongs to another domain")]
+    ForeignDomain,
+}
+
+/// Reason why the AS refused to register a user name.
+#[derive(Error, Debug, Clone, PartialEq, Eq, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum HandleRejectionReason {
+    /// The user name is reserved by the operator
+    #[error("The user name is reserved")]
+    Reserved,
+    /// The user name is shorter than the minimum length
+    #[error("The user name must be at least {0} characters long")]
+    TooShort(u32),
+    /// The user name is longer than the maximum length
+    #[error("The user name must be at most {0} characters long")]
+    TooLong(u32),
+    /// The user name doesn't start with a letter
+    #[error("The user name must start with a letter")]
+    InvalidFirstCharacter,
+    /// The user name contains characters that are not allowed
+    #[error("The user name contains characters that are not allowed")]
+    InvalidCharacters,
+    /// The user name was released recently and can't be registered before
+    /// the given time
+    #[error("The user name was released recently and is available again at {0:?}")]
+    RecentlyReleased(TimeStamp),
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
@@ -120,6 +154,86 @@ pub enum DeleteClientError {
     StorageError,
 }
 
+#[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum RefreshClientCredentialError {
+    /// Library error
+    #[error("Library error")]
+    LibraryError,
+    /// Could not find signing key
+    #[error("Could not find signing key")]
+    SigningKeyNotFound,
+    /// Storage provider error
+    #[error("Storage provider error")]
+    StorageError,
+    /// Unknown client
+    #[error("Unknown client")]
+    UnknownClient,
+    /// The CSR is for a different client than the sender
+    #[error("The CSR is for a different client than the sender")]
+    ClientIdMismatch,
+    /// Invalid CSR
+    #[error("Invalid CSR: Time now: {0:?}, not valid before: {1:?}, not valid after: {2:?}")]
+    InvalidCsr(TimeStamp, TimeStamp, TimeStamp),
+    /// No pending credential matches the confirmation
+    #[error("No pending credential matches the confirmation")]
+    NoPendingCredential,
+}
+
+#[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum DataExportError {
+    /// Library error
+    #[error("Library error")]
+    LibraryError,
+    /// Storage provider error
+    #[error("Storage provider error")]
+    StorageError,
+    /// Unknown client
+    #[error("Unknown client")]
+    UnknownClient,
+    /// The export doesn't exist, has expired or belongs to another user
+    #[error("Unknown data export")]
+    UnknownExport,
+    /// The export is still being assembled or has failed
+    #[error("Data export is not ready")]
+    NotReady,
+    /// Another export of the user is still being assembled
+    #[error("Another data export is in progress")]
+    ExportInProgress,
+}
+
+#[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum UserSettingsError {
+    /// Library error
+    #[error("Library error")]
+    LibraryError,
+    /// Storage provider error
+    #[error("Storage provider error")]
+    StorageError,
+    /// The settings are based on an outdated version
+    #[error("User settings were changed by another client")]
+    VersionConflict,
+    /// The settings exceed the maximum size
+    #[error("User settings are too large")]
+    TooLarge,
+}
+
+#[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum UserProfileError {
+    /// Library error
+    #[error("Library error")]
+    LibraryError,
+    /// Storage provider error
+    #[error("Storage provider error")]
+    StorageError,
+    /// The profile exceeds the maximum size
+    #[error("User profile is too large")]
+    TooLarge,
+}
+
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
 #[repr(u8)]
 pub enum PublishConnectionPackageError {
@@ -129,6 +243,9 @@ pub enum PublishConnectionPackageError {
     /// Invalid KeyPackage
     #[error("Invalid KeyPackage")]
     InvalidKeyPackage,
+    /// The lifetime of a connection package is invalid or exceeds the policy
+    #[error("Invalid connection package lifetime")]
+    InvalidLifetime,
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
@@ -137,6 +254,9 @@ pub enum ClientKeyPackageError {
     /// Storage provider error
     #[error("Storage provider error")]
     StorageError,
+    /// All connection packages of the client have expired
+    #[error("No valid connection packages")]
+    NoValidConnectionPackages,
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
@@ -148,6 +268,9 @@ pub enum UserConnectionPackagesError {
     /// Storage provider error
     #[error("Storage provider error")]
     StorageError,
+    /// All connection packages of the user's clients have expired
+    #[error("No valid connection packages")]
+    NoValidConnectionPackages,
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
@@ -162,6 +285,10 @@ pub enum EnqueueMessageError {
     /// Client not found
     #[error("Client not found")]
     ClientNotFound,
+    /// The reservation of the client's connection package is unknown or
+    /// expired. The connection packages have to be fetched again.
+    #[error("Connection package reservation expired")]
+    ReservationExpired,
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
@@ -195,6 +322,23 @@ pub enum Init2FactorAuthError {
     OpaqueLoginFailed,
 }
 
+#[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum SenderReputationError {
+    /// Storage provider error
+    #[error("Storage provider error")]
+    StorageError,
+    /// Library error
+    #[error("Library error")]
+    LibraryError,
+    /// Could not find signing key
+    #[error("Could not find signing key")]
+    SigningKeyNotFound,
+    /// The user of the requesting client doesn't exist
+    #[error("Unknown user")]
+    UnknownUser,
+}
+
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
 #[repr(u8)]
 pub enum AsCredentialsError {
@@ -218,6 +362,9 @@ pub enum AsVerificationError {
     /// Could not authenticate message
     #[error("Could not authenticate message")]
     AuthenticationFailed,
+    /// Request is outside the freshness window or was replayed
+    #[error("Request is stale or was replayed")]
+    ReplayedRequest,
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsSize, TlsDeserializeBytes)]
@@ -256,4 +403,14 @@ pub enum AsProcessingError {
     Init2FactorAuthError(#[from] Init2FactorAuthError),
     #[error(transparent)]
     AsCredentialsError(#[from] AsCredentialsError),
+    #[error(transparent)]
+    RefreshClientCredentialError(#[from] RefreshClientCredentialError),
+    #[error(transparent)]
+    DataExportError(#[from] DataExportError),
+    #[error(transparent)]
+    UserSettingsError(#[from] UserSettingsError),
+    #[error(transparent)]
+    SenderReputationError(#[from] SenderReputationError),
+    #[error(transparent)]
+    UserProfileError(#[from] UserProfileError),
 }
@@ -74,6 +74,9 @@ pub enum AddUsersError {
     IncompleteWelcome,
     #[error("Error merging commit: {0}")]
     MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
+    /// The room policy doesn't allow the sender to invite users.
+    #[error("Sender is not allowed to invite users.")]
+    NotAllowedToInvite,
 }
 
 /// Potential errors when updating a client.
@@ -92,6 +95,9 @@ pub enum ClientUpdateError {
     /// Unknown sender.
     #[error("Unknown sender.")]
     UnknownSender,
+    /// The room policy doesn't allow the sender to commit proposals.
+    #[error("Sender is not allowed to commit proposals in this group.")]
+    CommittingNotAllowed,
     #[error("Error merging commit: {0}")]
     MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
 }
@@ -166,6 +172,60 @@ pub enum DsProcessingError {
     /// Error deleting group.
     #[error(transparent)]
     GroupDeletionError(#[from] GroupDeletionError),
+    /// The sender exceeded the sending limits and is temporarily throttled.
+    #[error("Sender is temporarily throttled.")]
+    SenderThrottled,
+    #[error(transparent)]
+    RoomPolicyUpdateError(#[from] RoomPolicyUpdateError),
+    #[error(transparent)]
+    GroupDataUpdateError(#[from] GroupDataUpdateError),
+    /// The room policy doesn't allow join requests.
+    #[error("Join requests are not allowed in this group.")]
+    JoinRequestsNotAllowed,
+    /// Too many join requests were sent to the group recently.
+    #[error("Too many join requests.")]
+    TooManyJoinRequests,
+    /// The requested attachment doesn't exist.
+    #[error("Attachment not found.")]
+    AttachmentNotFound,
+    /// The attachment scanner could not be reached or failed.
+    #[error("Could not scan attachment.")]
+    AttachmentScanFailed,
+    /// The DS has no space left for attachments.
+    #[error("Attachment storage quota exceeded.")]
+    AttachmentQuotaExceeded,
+    /// The room policy of a new group is invalid.
+    #[error("Invalid room policy.")]
+    InvalidRoomPolicy,
+    /// The room policy doesn't allow the sender to send messages.
+    #[error("Sender is not allowed to send messages in this group.")]
+    SendingNotAllowed,
+    /// The room policy doesn't allow the sender to commit.
+    #[error("Sender is not allowed to commit in this group.")]
+    CommittingNotAllowed,
+    /// The message was sent in an epoch other than the current epoch of the
+    /// group, i.e. the sender hasn't processed all commits yet.
+    #[error("Message was sent in an outdated epoch.")]
+    StaleEpoch,
+    /// The sender exceeded the send rate limit and can send again after the
+    /// given time.
+    #[error("Send rate limit exceeded, retry after {0:?}.")]
+    RateLimited(std::time::Duration),
+    /// The group was upgraded and only its members can still join the
+    /// successor group.
+    #[error("Group was upgraded.")]
+    GroupUpgraded,
+    #[error(transparent)]
+    GroupUpgradeError(#[from] GroupUpgradeError),
+    #[error(transparent)]
+    JoinUpgradedGroupError(#[from] JoinUpgradedGroupError),
+    /// The join request token is unknown, expired or belongs to another
+    /// group.
+    #[error("Invalid join request token.")]
+    InvalidJoinRequestToken,
+    /// Request is outside the freshness window or was replayed
+    #[error("Request is stale or was replayed.")]
+    ReplayedRequest,
 }
 
 /// Potential errors when joining a group.
@@ -271,6 +331,102 @@ pub enum GroupDeletionError {
     MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
 }
 
+/// Potential errors when updating the policy of a room.
+#[derive(Debug, Error)]
+#[repr(u8)]
+pub enum RoomPolicyUpdateError {
+    /// Unrecoverable implementation error
+    #[error("Library Error")]
+    LibraryError,
+    /// Invalid assisted message.
+    #[error("Invalid assisted message.")]
+    InvalidMessage,
+    /// Error processing message.
+    #[error("Error processing message.")]
+    ProcessingError,
+    /// The sender is not an admin of the room.
+    #[error("Sender is not an admin of the room.")]
+    NotAdmin,
+    /// The room policy is malformed.
+    #[error("Invalid room policy.")]
+    InvalidPolicy,
+    #[error("Error merging commit: {0}")]
+    MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
+}
+
+/// Potential errors when updating the group data of a room.
+#[derive(Debug, Error)]
+#[repr(u8)]
+pub enum GroupDataUpdateError {
+    /// Unrecoverable implementation error
+    #[error("Library Error")]
+    LibraryError,
+    /// Invalid assisted message.
+    #[error("Invalid assisted message.")]
+    InvalidMessage,
+    /// Error processing message.
+    #[error("Error processing message.")]
+    ProcessingError,
+    /// The commit changes group context extensions other than the group data.
+    #[error("Commit changes more than the group data.")]
+    InvalidExtensions,
+    /// The commit changes the conversation attributes in the group data.
+    #[error("Commit changes the conversation attributes.")]
+    AttributesChanged,
+    #[error("Error merging commit: {0}")]
+    MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
+}
+
+/// Potential errors when upgrading a room to a successor group.
+#[derive(Debug, Error)]
+#[repr(u8)]
+pub enum GroupUpgradeError {
+    /// Unrecoverable implementation error
+    #[error("Library Error")]
+    LibraryError,
+    /// Invalid assisted message.
+    #[error("Invalid assisted message.")]
+    InvalidMessage,
+    /// Error processing message.
+    #[error("Error processing message.")]
+    ProcessingError,
+    /// The sender is not an admin of the room.
+    #[error("Sender is not an admin of the room.")]
+    NotAdmin,
+    /// Not all members support the protocol version and ciphersuite of the
+    /// successor group.
+    #[error("Upgrade target is not supported by all members.")]
+    UnsupportedTarget,
+    /// The successor group doesn't exist or doesn't match the upgrade.
+    #[error("Invalid successor group.")]
+    InvalidSuccessor,
+    /// The group was already upgraded.
+    #[error("Group was already upgraded.")]
+    AlreadyUpgraded,
+    #[error("Error merging commit: {0}")]
+    MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
+}
+
+/// Potential errors when joining the successor of an upgraded group.
+#[derive(Debug, Error)]
+#[repr(u8)]
+pub enum JoinUpgradedGroupError {
+    /// Unrecoverable implementation error
+    #[error("Library Error")]
+    LibraryError,
+    /// Invalid assisted message.
+    #[error("Invalid assisted message.")]
+    InvalidMessage,
+    /// Error processing message.
+    #[error("Error processing message.")]
+    ProcessingError,
+    /// The sender wasn't a member of the upgraded group.
+    #[error("Unknown sender.")]
+    UnknownSender,
+    #[error("Error merging commit: {0}")]
+    MergeCommitError(#[from] MergeCommitError<StorageError<CborMlsAssistStorage>>),
+}
+
 /// Potential errors when processing a self remove proposal.
 #[derive(Debug, Error)]
 #[repr(u8)]
@@ -144,6 +144,12 @@ pub enum QsVerifyingKeyError {
     /// Invalid response from remote QS
     #[error("Invalid response from remote QS")]
     InvalidResponse,
+    /// Remote QS key doesn't match the fingerprint published by its domain
+    #[error("Remote QS key doesn't match the published fingerprint")]
+    UnpublishedKey,
+    /// Remote QS key differs from the key pinned on first contact
+    #[error("Remote QS key differs from the pinned key")]
+    PinnedKeyMismatch,
 }
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
@@ -157,6 +163,32 @@ pub enum QsEncryptionKeyError {
     StorageError,
 }
 
+// === Presence ===
+
+#[derive(Error, Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum QsPresenceError {
+    /// Error storing or loading presence
+    #[error("Storage error")]
+    StorageError,
+    /// Too many contacts in a single request
+    #[error("Too many contacts")]
+    TooManyContacts,
+}
+
+// === Self messages ===
+
+#[derive(Error, Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum QsSelfMessageError {
+    /// Error storing or loading client records or queues
+    #[error("Storage error")]
+    StorageError,
+    /// Unrecoverable implementation error
+    #[error("Library Error")]
+    LibraryError,
+}
+
 // === Other errors ===
 
 #[derive(Error, Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
@@ -215,4 +247,13 @@ pub enum QsProcessError {
     /// Encryption key error
     #[error("Encryption key error")]
     QsEncryptionKeyError(#[from] QsEncryptionKeyError),
+    /// Request is outside the freshness window or was replayed
+    #[error("Request is stale or was replayed")]
+    ReplayedRequest,
+    /// Presence error
+    #[error("Presence error")]
+    QsPresenceError(#[from] QsPresenceError),
+    /// Self message error
+    #[error("Self message error")]
+    QsSelfMessageError(#[from] QsSelfMessageError),
 }
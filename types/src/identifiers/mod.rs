@@ -23,13 +23,17 @@ use crate::crypto::{
     ear::keys::PushTokenEarKey,
     errors::RandomnessError,
     hpke::{ClientIdDecryptionKey, ClientIdEncryptionKey, HpkeDecryptable, HpkeEncryptable},
+    rng,
 };
 
 use super::*;
 
-mod tls_codec_impls;
+pub(crate) mod tls_codec_impls;
 
 pub const QS_CLIENT_REFERENCE_EXTENSION_TYPE: u16 = 0xff00;
+/// Extension type of the group data, i.e. the room state that is opaque to
+/// the DS, in the group context.
+pub const GROUP_DATA_EXTENSION_TYPE: u16 = 0xff01;
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
 pub struct Fqdn {
@@ -408,7 +412,7 @@ impl AsClientId {
     }
 
     pub fn random(user_name: QualifiedUserName) -> Result<Self, RandomnessError> {
-        Ok(Self::new(user_name, Uuid::new_v4()))
+        Ok(Self::new(user_name, rng::random_uuid()))
     }
 
     pub fn user_name(&self) -> QualifiedUserName {
@@ -566,6 +570,107 @@ impl From<Uuid> for QsClientId {
     }
 }
 
+/// Identifier of an attachment stored by the DS.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct AttachmentId(TlsUuid);
+
+impl AttachmentId {
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for AttachmentId {
+    fn from(value: Uuid) -> Self {
+        Self(TlsUuid(value))
+    }
+}
+
+impl std::fmt::Display for AttachmentId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_uuid())
+    }
+}
+
+/// Identifier of a reservation of connection packages on the AS.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct ConnectionReservationId(TlsUuid);
+
+impl ConnectionReservationId {
+    pub fn random() -> Self {
+        Self(TlsUuid(rng::random_uuid()))
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+/// Identifier of a data export job on the AS.
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct DataExportId(TlsUuid);
+
+impl DataExportId {
+    pub fn random() -> Self {
+        rng::random_uuid().into()
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for DataExportId {
+    fn from(value: Uuid) -> Self {
+        Self(TlsUuid(value))
+    }
+}
+
+impl std::fmt::Display for DataExportId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_uuid())
+    }
+}
+
 #[derive(
     Clone,
     Debug,
@@ -603,7 +708,7 @@ impl From<Uuid> for QsUserId {
 
 impl QsUserId {
     pub fn random() -> Self {
-        Uuid::new_v4().into()
+        rng::random_uuid().into()
     }
 
     pub fn as_uuid(&self) -> &Uuid {
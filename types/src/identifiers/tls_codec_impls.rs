@@ -65,7 +65,7 @@ impl Serialize for TlsStr<'_> {
 #[derive(Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Debug)]
 #[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
 #[serde(transparent)]
-pub(super) struct TlsString(pub String);
+pub(crate) struct TlsString(pub String);
 
 impl std::fmt::Display for TlsString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
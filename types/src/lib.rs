@@ -22,6 +22,8 @@ pub mod identifiers;
 pub mod keypackage_batch;
 pub mod messages;
 pub mod time;
+#[cfg(test)]
+mod wire_tests;
 
 pub const DEFAULT_PORT_HTTP: u16 = 9420;
 pub const DEFAULT_PORT_HTTPS: u16 = 443;
@@ -0,0 +1,98 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Announcements of the operator of a server, e.g. maintenance windows or
+//! policy changes.
+//!
+//! Clients poll the announcements with a GET request. The response carries an
+//! `ETag` header (see [`announcements_etag`]), which clients send back in the
+//! `If-None-Match` header, such that the server answers with `304 Not
+//! Modified` as long as the announcements didn't change.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::{identifiers::tls_codec_impls::TlsString, time::TimeStamp};
+
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct AnnouncementsResponse {
+    pub announcements: Vec<Announcement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct Announcement {
+    id: TlsString,
+    kind: AnnouncementKind,
+    title: TlsString,
+    body: TlsString,
+    published_at: TimeStamp,
+}
+
+impl Announcement {
+    pub fn new(
+        id: String,
+        kind: AnnouncementKind,
+        title: String,
+        body: String,
+        published_at: TimeStamp,
+    ) -> Self {
+        Self {
+            id: TlsString(id),
+            kind,
+            title: TlsString(title),
+            body: TlsString(body),
+            published_at,
+        }
+    }
+
+    /// Id chosen by the operator, unique per server
+    pub fn id(&self) -> &str {
+        &self.id.0
+    }
+
+    pub fn kind(&self) -> AnnouncementKind {
+        self.kind
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title.0
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body.0
+    }
+
+    pub fn published_at(&self) -> TimeStamp {
+        self.published_at
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum AnnouncementKind {
+    #[default]
+    Info,
+    Maintenance,
+    PolicyChange,
+}
+
+/// Entity tag of the serialized [`AnnouncementsResponse`], including quotes.
+pub fn announcements_etag(serialized_response: &[u8]) -> String {
+    let digest = Sha256::digest(serialized_response);
+    format!("\"{}\"", hex::encode(&digest[..16]))
+}
@@ -0,0 +1,267 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Attachments stored by the DS.
+//!
+//! Members of a group upload attachments to the DS of the group and share the
+//! returned [`AttachmentId`] in their messages. Other members download the
+//! attachment by id. The DS may scan uploaded attachments and block them, in
+//! which case downloads return [`AttachmentDownload::Blocked`] and the
+//! uploader is notified via an [`AttachmentBlocked`] queue message.
+//!
+//! Depending on the storage provider of the DS, downloads may return an
+//! [`AttachmentUrl`] instead of the content, from which the content can be
+//! fetched directly for a limited time.
+//!
+//! The DS remembers which user uploaded an attachment, so that a user can
+//! delete all of their attachments in a group, e.g. when deleting their
+//! account.
+//!
+//! Messages refer to attachments by [`QualifiedAttachmentUrl`], which names
+//! the storage generation of the attachment. Requests for attachments are
+//! authenticated with the keys of the group, so attachments are always
+//! stored by and downloaded from the DS of the group.
+
+use std::{fmt, str::FromStr};
+
+use mls_assist::openmls::prelude::{GroupId, LeafNodeIndex};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+use uuid::Uuid;
+
+use crate::{
+    crypto::signatures::keys::UserKeyHash,
+    identifiers::{tls_codec_impls::TlsString, AttachmentId, Fqdn, FqdnError},
+};
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct UploadAttachmentParams {
+    pub group_id: GroupId,
+    pub sender: LeafNodeIndex,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct DownloadAttachmentParams {
+    pub group_id: GroupId,
+    pub sender: LeafNodeIndex,
+    pub attachment_id: AttachmentId,
+}
+
+/// Delete all attachments the sender uploaded to the group.
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct DeleteAttachmentsParams {
+    pub group_id: GroupId,
+    pub sender: UserKeyHash,
+}
+
+/// Result of downloading an attachment.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum AttachmentDownload {
+    Available(Vec<u8>),
+    /// The attachment was flagged by the scanner and is quarantined.
+    Blocked,
+    /// The content can be fetched from the given URL until it expires.
+    Url(AttachmentUrl),
+}
+
+/// Short-lived URL from which the content of an attachment can be fetched
+/// without going through the DS.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct AttachmentUrl(TlsString);
+
+impl AttachmentUrl {
+    pub fn new(url: String) -> Self {
+        Self(TlsString(url))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0 .0
+    }
+}
+
+/// Notification sent to the uploader of an attachment that was blocked.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct AttachmentBlocked {
+    pub group_id: GroupId,
+    pub attachment_id: AttachmentId,
+}
+
+/// Scheme of [`QualifiedAttachmentUrl`]s
+const ATTACHMENT_URL_SCHEME: &str = "phnx-attachment";
+/// Version of the [`QualifiedAttachmentUrl`] format
+const ATTACHMENT_URL_VERSION: &str = "v2";
+/// Version of the format that also named the domain whose DS stores the
+/// attachment
+const ATTACHMENT_URL_VERSION_WITH_DOMAIN: &str = "v1";
+
+/// Reference to an attachment stored by the DS of a group, as shared in
+/// messages.
+///
+/// The URL has the form `phnx-attachment:v2/<generation>/<id>`. The storage
+/// generation tells the client how to fetch the attachment from the DS.
+/// Attachments stored by the current DS storage have generation
+/// [`Self::CURRENT_GENERATION`].
+///
+/// URLs of the previous format `phnx-attachment:v1/<domain>/<generation>/<id>`
+/// are still accepted, but their domain is ignored: only the DS of the group
+/// can authenticate a download, so the attachment is always fetched from the
+/// owner domain of the conversation.
+///
+/// Before URLs were qualified, attachments were referred to by their bare id.
+/// Such references are parsed as well and have generation
+/// [`Self::LEGACY_GENERATION`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QualifiedAttachmentUrl {
+    generation: u32,
+    attachment_id: AttachmentId,
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum AttachmentUrlError {
+    #[error("Not an attachment URL")]
+    InvalidScheme,
+    #[error("Unsupported attachment URL version {0}")]
+    UnsupportedVersion(String),
+    #[error("Malformed attachment URL")]
+    Malformed,
+    #[error("Invalid domain: {0}")]
+    InvalidDomain(#[from] FqdnError),
+}
+
+impl QualifiedAttachmentUrl {
+    /// Generation of attachments referred to by bare id
+    pub const LEGACY_GENERATION: u32 = 0;
+    /// Generation of attachments uploaded to the current DS storage
+    pub const CURRENT_GENERATION: u32 = 1;
+
+    pub fn new(attachment_id: AttachmentId) -> Self {
+        Self {
+            generation: Self::CURRENT_GENERATION,
+            attachment_id,
+        }
+    }
+
+    /// Reference to an attachment that was shared by its bare id
+    pub fn legacy(attachment_id: AttachmentId) -> Self {
+        Self {
+            generation: Self::LEGACY_GENERATION,
+            attachment_id,
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn attachment_id(&self) -> AttachmentId {
+        self.attachment_id
+    }
+}
+
+impl fmt::Display for QualifiedAttachmentUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{ATTACHMENT_URL_SCHEME}:{ATTACHMENT_URL_VERSION}/{}/{}",
+            self.generation, self.attachment_id
+        )
+    }
+}
+
+impl FromStr for QualifiedAttachmentUrl {
+    type Err = AttachmentUrlError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(uuid) = Uuid::parse_str(value) {
+            return Ok(Self::legacy(uuid.into()));
+        }
+        let path = value
+            .strip_prefix(ATTACHMENT_URL_SCHEME)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .ok_or(AttachmentUrlError::InvalidScheme)?;
+        let (version, path) = path.split_once('/').ok_or(AttachmentUrlError::Malformed)?;
+        let path = match version {
+            ATTACHMENT_URL_VERSION => path,
+            ATTACHMENT_URL_VERSION_WITH_DOMAIN => {
+                let (domain, path) = path.split_once('/').ok_or(AttachmentUrlError::Malformed)?;
+                Fqdn::try_from(domain)?;
+                path
+            }
+            _ => return Err(AttachmentUrlError::UnsupportedVersion(version.to_owned())),
+        };
+        let mut segments = path.split('/');
+        let (Some(generation), Some(attachment_id), None) =
+            (segments.next(), segments.next(), segments.next())
+        else {
+            return Err(AttachmentUrlError::Malformed);
+        };
+        Ok(Self {
+            generation: generation
+                .parse()
+                .map_err(|_| AttachmentUrlError::Malformed)?,
+            attachment_id: Uuid::parse_str(attachment_id)
+                .map_err(|_| AttachmentUrlError::Malformed)?
+                .into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachment_url_round_trip() {
+        let attachment_id = AttachmentId::from(Uuid::new_v4());
+        let url = QualifiedAttachmentUrl::new(attachment_id);
+        assert_eq!(
+            url.to_string(),
+            format!("phnx-attachment:v2/1/{attachment_id}")
+        );
+        assert_eq!(
+            url.to_string().parse::<QualifiedAttachmentUrl>().unwrap(),
+            url
+        );
+    }
+
+    #[test]
+    fn attachment_url_with_domain() {
+        let attachment_id = AttachmentId::from(Uuid::new_v4());
+        let parsed = format!("phnx-attachment:v1/example.com/1/{attachment_id}")
+            .parse::<QualifiedAttachmentUrl>()
+            .unwrap();
+        assert_eq!(parsed, QualifiedAttachmentUrl::new(attachment_id));
+        assert!(matches!(
+            format!("phnx-attachment:v1/127.0.0.1/1/{attachment_id}")
+                .parse::<QualifiedAttachmentUrl>(),
+            Err(AttachmentUrlError::InvalidDomain(_))
+        ));
+    }
+
+    #[test]
+    fn legacy_attachment_id() {
+        let attachment_id = AttachmentId::from(Uuid::new_v4());
+        let parsed = attachment_id
+            .to_string()
+            .parse::<QualifiedAttachmentUrl>()
+            .unwrap();
+        assert_eq!(
+            parsed.generation(),
+            QualifiedAttachmentUrl::LEGACY_GENERATION
+        );
+        assert_eq!(parsed.attachment_id(), attachment_id);
+
+        assert!(matches!(
+            "phnx-attachment:v3/1/x".parse::<QualifiedAttachmentUrl>(),
+            Err(AttachmentUrlError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            "https://example.com/attachment".parse::<QualifiedAttachmentUrl>(),
+            Err(AttachmentUrlError::InvalidScheme)
+        ));
+    }
+}
@@ -28,11 +28,13 @@ use crate::{
             OpaqueRegistrationRequest, OpaqueRegistrationResponse,
         },
         ratchet::QueueRatchet,
-        signatures::signable::{Signable, Signature, SignedStruct, Verifiable, VerifiedStruct},
-        ConnectionEncryptionKey, RatchetEncryptionKey,
+        signatures::signable::{
+            Freshness, Signable, Signature, SignedStruct, Verifiable, VerifiedStruct,
+        },
+        ConnectionEncryptionKey, DataExportEncryptionKey, RatchetEncryptionKey,
     },
-    identifiers::{AsClientId, QualifiedUserName},
-    time::ExpirationData,
+    identifiers::{AsClientId, ConnectionReservationId, DataExportId, QualifiedUserName},
+    time::{Duration, ExpirationData, TimeStamp},
 };
 
 use super::{
@@ -40,6 +42,10 @@ use super::{
         ConnectionPackageIn, FinishUserRegistrationParamsIn, FinishUserRegistrationParamsTbsIn,
         VerifiableConnectionPackage,
     },
+    data_export::{DataExportStatus, EncryptedDataExport},
+    server_info::ClientVersion,
+    user_profiles::{EncryptedUserProfile, UserProfileFetch},
+    user_settings::{EncryptedUserSettings, VersionedUserSettings},
     AsTokenType, EncryptedAsQueueMessage, MlsInfraVersion,
 };
 
@@ -113,6 +119,7 @@ where
 pub struct Init2FactorAuthParamsTbs {
     pub client_id: AsClientId,
     pub opaque_ke1: OpaqueLoginRequest,
+    pub freshness: Freshness,
 }
 
 impl Signable for Init2FactorAuthParamsTbs {
@@ -188,8 +195,8 @@ impl ConnectionPackageTbs {
 
 #[derive(Debug, Clone, TlsSerialize, TlsSize, Serialize, Deserialize)]
 pub struct ConnectionPackage {
-    payload: ConnectionPackageTbs,
-    signature: Signature,
+    pub(super) payload: ConnectionPackageTbs,
+    pub(super) signature: Signature,
 }
 
 impl ConnectionPackage {
@@ -197,6 +204,10 @@ impl ConnectionPackage {
         &self.payload.client_credential
     }
 
+    pub fn lifetime(&self) -> &ExpirationData {
+        &self.payload.lifetime
+    }
+
     pub fn encryption_key(&self) -> &ConnectionEncryptionKey {
         &self.payload.encryption_key
     }
@@ -258,6 +269,7 @@ pub struct FinishUserRegistrationParamsTbs {
     pub initial_ratchet_secret: RatchetSecret,
     pub connection_packages: Vec<ConnectionPackage>,
     pub opaque_registration_record: OpaqueRegistrationRecord,
+    pub freshness: Freshness,
 }
 
 impl Signable for FinishUserRegistrationParamsTbs {
@@ -289,6 +301,7 @@ pub struct DeleteUserParamsTbs {
     pub user_name: QualifiedUserName,
     pub client_id: AsClientId,
     pub opaque_finish: OpaqueLoginFinish,
+    pub freshness: Freshness,
 }
 
 impl Signable for DeleteUserParamsTbs {
@@ -385,7 +398,10 @@ impl FinishClientAdditionParams {
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
-pub struct DeleteClientParamsTbs(pub AsClientId);
+pub struct DeleteClientParamsTbs {
+    pub client_id: AsClientId,
+    pub freshness: Freshness,
+}
 
 impl Signable for DeleteClientParamsTbs {
     type SignedOutput = DeleteClientParams;
@@ -415,7 +431,7 @@ impl ClientCredentialAuthenticator for DeleteClientParams {
     type Tbs = DeleteClientParamsTbs;
 
     fn client_id(&self) -> AsClientId {
-        self.payload.0.clone()
+        self.payload.client_id.clone()
     }
 
     fn into_payload(self) -> VerifiedAsRequestParams {
@@ -434,6 +450,7 @@ pub struct DequeueMessagesParamsTbs {
     pub sender: AsClientId,
     pub sequence_number_start: u64,
     pub max_message_number: u64,
+    pub freshness: Freshness,
 }
 
 impl Signable for DequeueMessagesParamsTbs {
@@ -495,7 +512,7 @@ impl From<Ciphertext> for EncryptedFriendshipPackage {
     }
 }
 
-#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+#[derive(Debug, Clone, TlsDeserializeBytes, TlsSerialize, TlsSize)]
 pub struct EncryptedConnectionEstablishmentPackage {
     ciphertext: HpkeCiphertext,
 }
@@ -518,6 +535,31 @@ pub type AsQueueRatchet = QueueRatchet<EncryptedAsQueueMessage, AsQueueMessagePa
 #[repr(u8)]
 pub enum AsQueueMessageType {
     EncryptedConnectionEstablishmentPackage,
+    /// The AS ran out of valid connection packages of the client.
+    ConnectionPackageRefreshRequest,
+}
+
+impl AsQueueMessageType {
+    /// Oldest client version that can decode messages of this type. Older
+    /// clients fail on message types they don't know, so the AS only enqueues
+    /// these messages for clients that announced at least this version.
+    pub fn min_client_version(&self) -> Option<ClientVersion> {
+        match self {
+            Self::EncryptedConnectionEstablishmentPackage => None,
+            Self::ConnectionPackageRefreshRequest => Some(ClientVersion::new(0, 2, 0)),
+        }
+    }
+
+    /// Whether a client that announced the given version can decode messages
+    /// of this type. Clients that didn't announce their version are treated
+    /// as the oldest ones.
+    pub fn supported_by(&self, client_version: Option<&ClientVersion>) -> bool {
+        match (self.min_client_version(), client_version) {
+            (None, _) => true,
+            (Some(min_version), Some(client_version)) => *client_version >= min_version,
+            (Some(_), None) => false,
+        }
+    }
 }
 
 #[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize, Clone)]
@@ -535,9 +577,20 @@ impl AsQueueMessagePayload {
                 )?;
                 ExtractedAsQueueMessagePayload::EncryptedConnectionEstablishmentPackage(cep)
             }
+            AsQueueMessageType::ConnectionPackageRefreshRequest => {
+                ExtractedAsQueueMessagePayload::ConnectionPackageRefreshRequest
+            }
         };
         Ok(message)
     }
+
+    /// Ask the client to publish fresh connection packages.
+    pub fn connection_package_refresh_request() -> Self {
+        Self {
+            message_type: AsQueueMessageType::ConnectionPackageRefreshRequest,
+            payload: Vec::new(),
+        }
+    }
 }
 
 impl TryFrom<EncryptedConnectionEstablishmentPackage> for AsQueueMessagePayload {
@@ -569,6 +622,7 @@ impl GenericSerializable for AsQueueMessagePayload {
 
 pub enum ExtractedAsQueueMessagePayload {
     EncryptedConnectionEstablishmentPackage(EncryptedConnectionEstablishmentPackage),
+    ConnectionPackageRefreshRequest,
 }
 
 impl EarEncryptable<RatchetKey, EncryptedAsQueueMessage> for AsQueueMessagePayload {}
@@ -578,6 +632,7 @@ impl EarDecryptable<RatchetKey, EncryptedAsQueueMessage> for AsQueueMessagePaylo
 pub struct AsPublishConnectionPackagesParamsTbs {
     pub client_id: AsClientId,
     pub connection_packages: Vec<ConnectionPackageIn>,
+    pub freshness: Freshness,
 }
 
 impl Signable for AsPublishConnectionPackagesParamsTbs {
@@ -623,7 +678,10 @@ impl ClientCredentialAuthenticator for AsPublishConnectionPackagesParams {
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
-pub struct ClientConnectionPackageParamsTbs(pub AsClientId);
+pub struct ClientConnectionPackageParamsTbs {
+    pub client_id: AsClientId,
+    pub freshness: Freshness,
+}
 
 impl Signable for ClientConnectionPackageParamsTbs {
     type SignedOutput = AsClientConnectionPackageParams;
@@ -653,7 +711,7 @@ impl ClientCredentialAuthenticator for AsClientConnectionPackageParams {
     type Tbs = AsClientId;
 
     fn client_id(&self) -> AsClientId {
-        self.payload.0.clone()
+        self.payload.client_id.clone()
     }
 
     fn into_payload(self) -> VerifiedAsRequestParams {
@@ -704,12 +762,39 @@ impl NoAuth for UserConnectionPackagesParams {
 #[derive(Debug, TlsSerialize, TlsSize)]
 pub struct UserConnectionPackagesResponse {
     pub key_packages: Vec<ConnectionPackage>,
+    pub reservation: ConnectionReservation,
+}
+
+/// Reservation of the connection packages served in a
+/// [`UserConnectionPackagesResponse`].
+///
+/// Until the reservation expires, a connection establishment package can be
+/// enqueued for each client whose connection package was served. Enqueueing
+/// is idempotent, such that it can be retried, e.g. after the app was
+/// suspended.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+pub struct ConnectionReservation {
+    pub id: ConnectionReservationId,
+    pub expires_at: TimeStamp,
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
 pub struct EnqueueMessageParams {
     pub client_id: AsClientId,
     pub connection_establishment_ctxt: EncryptedConnectionEstablishmentPackage,
+    /// Reservation of the connection package of the client
+    pub reservation_id: ConnectionReservationId,
 }
 
 impl NoAuth for EnqueueMessageParams {
@@ -718,6 +803,40 @@ impl NoAuth for EnqueueMessageParams {
     }
 }
 
+/// Connection establishment package for one of the clients of a reservation
+#[derive(Debug, Clone, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct ReservedConnectionEstablishmentPackage {
+    pub client_id: AsClientId,
+    pub connection_establishment_ctxt: EncryptedConnectionEstablishmentPackage,
+}
+
+/// Enqueue the connection establishment packages for all clients of a user
+/// in a single request.
+///
+/// Like [`EnqueueMessageParams`], enqueueing is idempotent per client, such
+/// that the whole batch can be retried if it failed part way.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct EnqueueMessagesParams {
+    /// Reservation of the connection packages of the clients
+    pub reservation_id: ConnectionReservationId,
+    pub packages: Vec<ReservedConnectionEstablishmentPackage>,
+}
+
+/// Response to [`EnqueueMessagesParams`].
+#[derive(Debug, Clone, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct EnqueueMessagesResponse {
+    /// Clients whose packages weren't enqueued, because the reservation
+    /// expired or doesn't include them. All other clients of the request
+    /// received their package under the reservation.
+    pub expired_clients: Vec<AsClientId>,
+}
+
+impl NoAuth for EnqueueMessagesParams {
+    fn into_verified(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::EnqueueMessages(self)
+    }
+}
+
 #[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
 pub struct AsCredentialsParams {}
 
@@ -727,6 +846,198 @@ impl NoAuth for AsCredentialsParams {
     }
 }
 
+/// Request for the intermediate credentials with the given fingerprints, e.g.
+/// to verify the client credentials of all members of a group at once. The
+/// response is an [`AsCredentialsResponse`] that only contains the requested
+/// intermediate credentials that the AS knows and the AS credentials that
+/// signed them.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct IntermediateCredentialsParams {
+    pub fingerprints: Vec<CredentialFingerprint>,
+}
+
+impl NoAuth for IntermediateCredentialsParams {
+    fn into_verified(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::IntermediateCredentials(self)
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct ServerInfoParams {}
+
+impl NoAuth for ServerInfoParams {
+    fn into_verified(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::ServerInfo(self)
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct CompatibilityInfoParams {}
+
+impl NoAuth for CompatibilityInfoParams {
+    fn into_verified(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::CompatibilityInfo(self)
+    }
+}
+
+/// Coarse hint about the sender of a connection request, based on what the
+/// AS knows about the sender's account.
+///
+/// The hint is deliberately coarse, such that it doesn't reveal when exactly
+/// a user registered.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum SenderReputation {
+    /// The AS doesn't know the user, e.g. because it was deleted.
+    Unknown,
+    /// The user registered recently.
+    New,
+    Established,
+}
+
+/// Request for an attestation of the reputation of the sending user.
+///
+/// Only users can request their own reputation. They attach the attestation
+/// to their connection requests, such that recipients don't have to query the
+/// sender's AS about the sender.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct SenderReputationParamsTbs {
+    pub client_id: AsClientId,
+    pub freshness: Freshness,
+}
+
+impl Signable for SenderReputationParamsTbs {
+    type SignedOutput = SenderReputationParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        SenderReputationParams::LABEL
+    }
+}
+
+impl SignedStruct<SenderReputationParamsTbs> for SenderReputationParams {
+    fn from_payload(payload: SenderReputationParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct SenderReputationParams {
+    payload: SenderReputationParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for SenderReputationParams {
+    type Tbs = SenderReputationParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::SenderReputation(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Sender Reputation Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct SenderReputationResponse {
+    pub attestation: SenderReputationAttestation,
+}
+
+/// Reputation of a user as attested by the AS of the user.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct SenderReputationAttestationTbs {
+    pub user_name: QualifiedUserName,
+    pub reputation: SenderReputation,
+    pub issued_at: TimeStamp,
+    /// Fingerprint of the intermediate credential of the signing key
+    pub signer_fingerprint: CredentialFingerprint,
+}
+
+impl SenderReputationAttestationTbs {
+    /// Attestations older than this are ignored by recipients.
+    pub const MAX_AGE_DAYS: i64 = 7;
+
+    pub fn has_expired(&self) -> bool {
+        self.issued_at
+            .has_expired(Duration::days(Self::MAX_AGE_DAYS))
+    }
+}
+
+impl Signable for SenderReputationAttestationTbs {
+    type SignedOutput = SenderReputationAttestation;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        SenderReputationAttestation::LABEL
+    }
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct SenderReputationAttestation {
+    payload: SenderReputationAttestationTbs,
+    signature: Signature,
+}
+
+impl SenderReputationAttestation {
+    const LABEL: &'static str = "Sender Reputation Attestation";
+
+    pub fn signer_fingerprint(&self) -> &CredentialFingerprint {
+        &self.payload.signer_fingerprint
+    }
+}
+
+impl SignedStruct<SenderReputationAttestationTbs> for SenderReputationAttestation {
+    fn from_payload(payload: SenderReputationAttestationTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+impl Verifiable for SenderReputationAttestation {
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.payload.tls_serialize_detached()
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn label(&self) -> &str {
+        Self::LABEL
+    }
+}
+
+impl VerifiedStruct<SenderReputationAttestation> for SenderReputationAttestationTbs {
+    type SealingType = private_mod::Seal;
+
+    fn from_verifiable(verifiable: SenderReputationAttestation, _seal: Self::SealingType) -> Self {
+        verifiable.payload
+    }
+}
+
 #[derive(Debug, TlsSerialize, TlsSize)]
 pub struct AsCredentialsResponse {
     pub as_credentials: Vec<AsCredential>,
@@ -741,6 +1052,7 @@ pub struct IssueTokensParamsTbs {
     pub client_id: AsClientId,
     pub token_type: AsTokenType,
     pub token_request: TokenRequest,
+    pub freshness: Freshness,
 }
 
 impl DeserializeBytes for IssueTokensParamsTbs {
@@ -756,11 +1068,13 @@ impl DeserializeBytes for IssueTokensParamsTbs {
         let bytes = bytes
             .get(token_request.tls_serialized_len()..)
             .ok_or(tls_codec::Error::EndOfStream)?;
+        let (freshness, bytes) = Freshness::tls_deserialize_bytes(bytes)?;
         Ok((
             Self {
                 client_id,
                 token_type,
                 token_request,
+                freshness,
             },
             bytes,
         ))
@@ -828,6 +1142,486 @@ impl DeserializeBytes for IssueTokensResponse {
     }
 }
 
+// === Credential refresh ===
+
+/// Request to re-issue the client credential of the sending client ahead of
+/// its expiration. The request is signed with the current (still valid)
+/// client credential, while the payload contains the CSR for the new one.
+///
+/// The new credential only replaces the current one once the client has
+/// confirmed it via [`ConfirmClientCredentialParams`].
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct RefreshClientCredentialParamsTbs {
+    pub client_id: AsClientId,
+    pub client_credential_payload: ClientCredentialPayload,
+    pub freshness: Freshness,
+}
+
+impl Signable for RefreshClientCredentialParamsTbs {
+    type SignedOutput = RefreshClientCredentialParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        RefreshClientCredentialParams::LABEL
+    }
+}
+
+impl SignedStruct<RefreshClientCredentialParamsTbs> for RefreshClientCredentialParams {
+    fn from_payload(payload: RefreshClientCredentialParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct RefreshClientCredentialParams {
+    payload: RefreshClientCredentialParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for RefreshClientCredentialParams {
+    type Tbs = RefreshClientCredentialParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::RefreshClientCredential(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Refresh Client Credential Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct RefreshClientCredentialResponse {
+    pub client_credential: ClientCredential,
+}
+
+/// Confirmation that the sending client holds the signing key of the
+/// credential issued in response to its last [`RefreshClientCredentialParams`].
+/// The request is signed with the new key. Until the AS has processed it, the
+/// previous credential remains valid.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct ConfirmClientCredentialParamsTbs {
+    pub client_id: AsClientId,
+    pub credential_fingerprint: CredentialFingerprint,
+    pub freshness: Freshness,
+}
+
+impl Signable for ConfirmClientCredentialParamsTbs {
+    type SignedOutput = ConfirmClientCredentialParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        ConfirmClientCredentialParams::LABEL
+    }
+}
+
+impl SignedStruct<ConfirmClientCredentialParamsTbs> for ConfirmClientCredentialParams {
+    fn from_payload(payload: ConfirmClientCredentialParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct ConfirmClientCredentialParams {
+    payload: ConfirmClientCredentialParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for ConfirmClientCredentialParams {
+    type Tbs = ConfirmClientCredentialParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::ConfirmClientCredential(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Confirm Client Credential Parameters";
+}
+
+// === Data export ===
+
+/// Request to export the personal data the AS holds about the sending user.
+/// The export is encrypted to the given key.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct RequestDataExportParamsTbs {
+    pub client_id: AsClientId,
+    pub encryption_key: DataExportEncryptionKey,
+    pub freshness: Freshness,
+}
+
+impl Signable for RequestDataExportParamsTbs {
+    type SignedOutput = RequestDataExportParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        RequestDataExportParams::LABEL
+    }
+}
+
+impl SignedStruct<RequestDataExportParamsTbs> for RequestDataExportParams {
+    fn from_payload(payload: RequestDataExportParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct RequestDataExportParams {
+    payload: RequestDataExportParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for RequestDataExportParams {
+    type Tbs = RequestDataExportParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::RequestDataExport(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Request Data Export Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct RequestDataExportResponse {
+    pub export_id: DataExportId,
+}
+
+/// Request for the status of a data export of the sending user.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct DataExportStatusParamsTbs {
+    pub client_id: AsClientId,
+    pub export_id: DataExportId,
+    pub freshness: Freshness,
+}
+
+impl Signable for DataExportStatusParamsTbs {
+    type SignedOutput = DataExportStatusParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        DataExportStatusParams::LABEL
+    }
+}
+
+impl SignedStruct<DataExportStatusParamsTbs> for DataExportStatusParams {
+    fn from_payload(payload: DataExportStatusParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct DataExportStatusParams {
+    payload: DataExportStatusParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for DataExportStatusParams {
+    type Tbs = DataExportStatusParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::DataExportStatus(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Data Export Status Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct DataExportStatusResponse {
+    pub status: DataExportStatus,
+}
+
+/// Request to download a completed data export of the sending user.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct DownloadDataExportParamsTbs {
+    pub client_id: AsClientId,
+    pub export_id: DataExportId,
+    pub freshness: Freshness,
+}
+
+impl Signable for DownloadDataExportParamsTbs {
+    type SignedOutput = DownloadDataExportParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        DownloadDataExportParams::LABEL
+    }
+}
+
+impl SignedStruct<DownloadDataExportParamsTbs> for DownloadDataExportParams {
+    fn from_payload(payload: DownloadDataExportParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct DownloadDataExportParams {
+    payload: DownloadDataExportParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for DownloadDataExportParams {
+    type Tbs = DownloadDataExportParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::DownloadDataExport(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Download Data Export Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct DownloadDataExportResponse {
+    pub export: EncryptedDataExport,
+}
+
+// === User settings ===
+
+/// Request for the synced settings of the sending user.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct GetUserSettingsParamsTbs {
+    pub client_id: AsClientId,
+    pub freshness: Freshness,
+}
+
+impl Signable for GetUserSettingsParamsTbs {
+    type SignedOutput = GetUserSettingsParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        GetUserSettingsParams::LABEL
+    }
+}
+
+impl SignedStruct<GetUserSettingsParamsTbs> for GetUserSettingsParams {
+    fn from_payload(payload: GetUserSettingsParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct GetUserSettingsParams {
+    payload: GetUserSettingsParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for GetUserSettingsParams {
+    type Tbs = GetUserSettingsParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::GetUserSettings(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Get User Settings Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct GetUserSettingsResponse {
+    /// `None` if no client of the user uploaded settings yet.
+    pub settings: Option<VersionedUserSettings>,
+}
+
+/// Request to replace the synced settings of the sending user.
+///
+/// The AS only accepts the settings if `base_version` is the version of the
+/// stored settings, or `0` if there are none. Otherwise it responds with
+/// [`crate::errors::auth_service::UserSettingsError::VersionConflict`].
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct UploadUserSettingsParamsTbs {
+    pub client_id: AsClientId,
+    pub base_version: u64,
+    pub settings: EncryptedUserSettings,
+    pub freshness: Freshness,
+}
+
+impl Signable for UploadUserSettingsParamsTbs {
+    type SignedOutput = UploadUserSettingsParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        UploadUserSettingsParams::LABEL
+    }
+}
+
+impl SignedStruct<UploadUserSettingsParamsTbs> for UploadUserSettingsParams {
+    fn from_payload(payload: UploadUserSettingsParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct UploadUserSettingsParams {
+    payload: UploadUserSettingsParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for UploadUserSettingsParams {
+    type Tbs = UploadUserSettingsParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::UploadUserSettings(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Upload User Settings Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct UploadUserSettingsResponse {
+    /// The version of the uploaded settings
+    pub version: u64,
+}
+
+// === User profiles ===
+
+/// Request to replace the profile of the sending user.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct UploadUserProfileParamsTbs {
+    pub client_id: AsClientId,
+    pub profile: EncryptedUserProfile,
+    pub freshness: Freshness,
+}
+
+impl Signable for UploadUserProfileParamsTbs {
+    type SignedOutput = UploadUserProfileParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        UploadUserProfileParams::LABEL
+    }
+}
+
+impl SignedStruct<UploadUserProfileParamsTbs> for UploadUserProfileParams {
+    fn from_payload(payload: UploadUserProfileParamsTbs, signature: Signature) -> Self {
+        Self { payload, signature }
+    }
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct UploadUserProfileParams {
+    payload: UploadUserProfileParamsTbs,
+    signature: Signature,
+}
+
+impl ClientCredentialAuthenticator for UploadUserProfileParams {
+    type Tbs = UploadUserProfileParamsTbs;
+
+    fn client_id(&self) -> AsClientId {
+        self.payload.client_id.clone()
+    }
+
+    fn into_payload(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::UploadUserProfile(self.payload)
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    const LABEL: &'static str = "Upload User Profile Parameters";
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct UploadUserProfileResponse {
+    /// The version of the uploaded profile
+    pub version: u64,
+}
+
+/// Request for the profile of a user.
+///
+/// The profile is encrypted, so anyone may fetch it. The AS only responds
+/// with the profile if its version differs from `cached_version`.
+#[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
+pub struct GetUserProfileParams {
+    pub user_name: QualifiedUserName,
+    pub cached_version: Option<u64>,
+}
+
+impl NoAuth for GetUserProfileParams {
+    fn into_verified(self) -> VerifiedAsRequestParams {
+        VerifiedAsRequestParams::GetUserProfile(self)
+    }
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct GetUserProfileResponse {
+    pub profile: UserProfileFetch,
+}
+
 // === Auth & Framing ===
 
 #[derive(Debug, TlsSerialize, TlsSize)]
@@ -864,6 +1658,20 @@ pub enum AsRequestParams {
     EnqueueMessage(EnqueueMessageParams),
     AsCredentials(AsCredentialsParams),
     IssueTokens(IssueTokensParams),
+    RefreshClientCredential(RefreshClientCredentialParams),
+    ServerInfo(ServerInfoParams),
+    RequestDataExport(RequestDataExportParams),
+    DataExportStatus(DataExportStatusParams),
+    DownloadDataExport(DownloadDataExportParams),
+    EnqueueMessages(EnqueueMessagesParams),
+    GetUserSettings(GetUserSettingsParams),
+    UploadUserSettings(UploadUserSettingsParams),
+    SenderReputation(SenderReputationParams),
+    UploadUserProfile(UploadUserProfileParams),
+    GetUserProfile(GetUserProfileParams),
+    ConfirmClientCredential(ConfirmClientCredentialParams),
+    CompatibilityInfo(CompatibilityInfoParams),
+    IntermediateCredentials(IntermediateCredentialsParams),
 }
 
 #[derive(Debug, TlsSerialize, TlsSize)]
@@ -885,6 +1693,58 @@ pub enum VerifiedAsRequestParams {
     AsCredentials(AsCredentialsParams),
     EnqueueMessage(EnqueueMessageParams),
     InitUserRegistration(InitUserRegistrationParams),
+    RefreshClientCredential(RefreshClientCredentialParamsTbs),
+    ServerInfo(ServerInfoParams),
+    RequestDataExport(RequestDataExportParamsTbs),
+    DataExportStatus(DataExportStatusParamsTbs),
+    DownloadDataExport(DownloadDataExportParamsTbs),
+    EnqueueMessages(EnqueueMessagesParams),
+    GetUserSettings(GetUserSettingsParamsTbs),
+    UploadUserSettings(UploadUserSettingsParamsTbs),
+    SenderReputation(SenderReputationParamsTbs),
+    UploadUserProfile(UploadUserProfileParamsTbs),
+    GetUserProfile(GetUserProfileParams),
+    ConfirmClientCredential(ConfirmClientCredentialParamsTbs),
+    CompatibilityInfo(CompatibilityInfoParams),
+    IntermediateCredentials(IntermediateCredentialsParams),
+}
+
+impl VerifiedAsRequestParams {
+    /// Freshness information of requests authenticated via signatures.
+    pub fn freshness(&self) -> Option<&Freshness> {
+        match self {
+            VerifiedAsRequestParams::Initiate2FaAuthentication(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::FinishUserRegistration(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::DeleteUser(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::DeleteClient(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::DequeueMessages(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::PublishConnectionPackages(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::ClientConnectionPackage(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::IssueTokens(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::RefreshClientCredential(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::RequestDataExport(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::DataExportStatus(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::DownloadDataExport(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::GetUserSettings(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::UploadUserSettings(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::SenderReputation(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::UploadUserProfile(params) => Some(&params.freshness),
+            VerifiedAsRequestParams::ConfirmClientCredential(params) => Some(&params.freshness),
+            // OPAQUE-authenticated and unauthenticated requests
+            VerifiedAsRequestParams::FinishClientAddition(_)
+            | VerifiedAsRequestParams::UserConnectionPackages(_)
+            | VerifiedAsRequestParams::InitiateClientAddition(_)
+            | VerifiedAsRequestParams::UserClients(_)
+            | VerifiedAsRequestParams::AsCredentials(_)
+            | VerifiedAsRequestParams::IntermediateCredentials(_)
+            | VerifiedAsRequestParams::EnqueueMessage(_)
+            | VerifiedAsRequestParams::EnqueueMessages(_)
+            | VerifiedAsRequestParams::InitUserRegistration(_)
+            | VerifiedAsRequestParams::ServerInfo(_)
+            | VerifiedAsRequestParams::CompatibilityInfo(_)
+            | VerifiedAsRequestParams::GetUserProfile(_) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -906,6 +1766,15 @@ impl ClientCredentialAuth {
             VerifiedAsRequestParams::FinishUserRegistration(_)
         )
     }
+
+    /// Confirmation requests are signed with the key of the client's pending
+    /// credential rather than its current one.
+    pub fn is_confirm_client_credential_request(&self) -> bool {
+        matches!(
+            self.payload.as_ref(),
+            VerifiedAsRequestParams::ConfirmClientCredential(_)
+        )
+    }
 }
 
 impl Verifiable for ClientCredentialAuth {
@@ -923,6 +1792,19 @@ impl Verifiable for ClientCredentialAuth {
                 params.tls_serialize_detached()
             }
             VerifiedAsRequestParams::IssueTokens(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::RefreshClientCredential(params) => {
+                params.tls_serialize_detached()
+            }
+            VerifiedAsRequestParams::RequestDataExport(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::DataExportStatus(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::DownloadDataExport(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::GetUserSettings(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::UploadUserSettings(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::SenderReputation(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::UploadUserProfile(params) => params.tls_serialize_detached(),
+            VerifiedAsRequestParams::ConfirmClientCredential(params) => {
+                params.tls_serialize_detached()
+            }
             VerifiedAsRequestParams::FinishUserRegistration(params) => {
                 params.tls_serialize_detached()
             }
@@ -933,8 +1815,13 @@ impl Verifiable for ClientCredentialAuth {
             | VerifiedAsRequestParams::InitiateClientAddition(_)
             | VerifiedAsRequestParams::UserClients(_)
             | VerifiedAsRequestParams::AsCredentials(_)
+            | VerifiedAsRequestParams::IntermediateCredentials(_)
             | VerifiedAsRequestParams::EnqueueMessage(_)
-            | VerifiedAsRequestParams::InitUserRegistration(_) => Ok(vec![]),
+            | VerifiedAsRequestParams::EnqueueMessages(_)
+            | VerifiedAsRequestParams::InitUserRegistration(_)
+            | VerifiedAsRequestParams::ServerInfo(_)
+            | VerifiedAsRequestParams::CompatibilityInfo(_)
+            | VerifiedAsRequestParams::GetUserProfile(_) => Ok(vec![]),
         }
     }
 
@@ -13,7 +13,7 @@ use crate::{
         kdf::keys::RatchetSecret,
         opaque::{OpaqueLoginResponse, OpaqueRegistrationRecord, OpaqueRegistrationResponse},
         signatures::{
-            signable::{Signature, Verifiable},
+            signable::{Freshness, Signature, Verifiable},
             traits::SignatureVerificationError,
         },
         ConnectionEncryptionKey, RatchetEncryptionKey,
@@ -26,13 +26,22 @@ use super::{
     client_as::{
         AsAuthMethod, AsClientConnectionPackageParams, AsCredentialsParams,
         AsDequeueMessagesParams, AsPublishConnectionPackagesParams, ClientCredentialAuthenticator,
-        ConnectionPackage, ConnectionPackageTbs, DeleteClientParams, DeleteUserParams,
-        EnqueueMessageParams, FinishClientAdditionParams, Init2FactorAuthResponse,
-        InitUserRegistrationParams, Initiate2FaAuthenticationParams, InitiateClientAdditionParams,
-        IssueTokensParams, IssueTokensResponse, NoAuth, TwoFactorAuthenticator, UserClientsParams,
-        UserConnectionPackagesParams, VerifiedAsRequestParams,
+        CompatibilityInfoParams, ConfirmClientCredentialParams, ConnectionPackage,
+        ConnectionPackageTbs, ConnectionReservation, DataExportStatusParams,
+        DataExportStatusResponse, DeleteClientParams, DeleteUserParams, DownloadDataExportParams,
+        DownloadDataExportResponse, EnqueueMessageParams, EnqueueMessagesParams,
+        EnqueueMessagesResponse, FinishClientAdditionParams, GetUserProfileParams,
+        GetUserProfileResponse, GetUserSettingsParams, GetUserSettingsResponse,
+        Init2FactorAuthResponse, InitUserRegistrationParams, Initiate2FaAuthenticationParams,
+        InitiateClientAdditionParams, IntermediateCredentialsParams, IssueTokensParams,
+        IssueTokensResponse, NoAuth, RefreshClientCredentialParams, RequestDataExportParams,
+        RequestDataExportResponse, SenderReputationParams, SenderReputationResponse,
+        ServerInfoParams, TwoFactorAuthenticator, UploadUserProfileParams,
+        UploadUserProfileResponse, UploadUserSettingsParams, UploadUserSettingsResponse,
+        UserClientsParams, UserConnectionPackagesParams, VerifiedAsRequestParams,
     },
-    client_qs::DequeueMessagesResponse,
+    client_qs::{DequeueMessagesResponse, DequeueMessagesResponseV1},
+    server_info::{CompatibilityInfoResponse, ServerInfoResponse},
     MlsInfraVersion,
 };
 
@@ -44,6 +53,7 @@ pub struct AsClientConnectionPackageResponseIn {
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
 pub struct UserConnectionPackagesResponseIn {
     pub connection_packages: Vec<ConnectionPackageIn>,
+    pub reservation: ConnectionReservation,
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
@@ -72,12 +82,17 @@ pub struct InitUserRegistrationResponseIn {
     pub opaque_registration_response: OpaqueRegistrationResponse,
 }
 
+#[derive(Debug, TlsDeserializeBytes, TlsSize)]
+pub struct RefreshClientCredentialResponseIn {
+    pub client_credential: VerifiableClientCredential,
+}
+
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
 #[repr(u8)]
 pub enum AsProcessResponseIn {
     Ok,
     Init2FactorAuth(Init2FactorAuthResponse),
-    DequeueMessages(DequeueMessagesResponse),
+    DequeueMessages(DequeueMessagesResponseV1),
     ClientConnectionPackage(AsClientConnectionPackageResponseIn),
     IssueTokens(IssueTokensResponse),
     UserConnectionPackages(UserConnectionPackagesResponseIn),
@@ -85,6 +100,20 @@ pub enum AsProcessResponseIn {
     UserClients(UserClientsResponseIn),
     AsCredentials(AsCredentialsResponseIn),
     InitUserRegistration(InitUserRegistrationResponseIn),
+    RefreshClientCredential(RefreshClientCredentialResponseIn),
+    ServerInfo(ServerInfoResponse),
+    RequestDataExport(RequestDataExportResponse),
+    DataExportStatus(DataExportStatusResponse),
+    DownloadDataExport(DownloadDataExportResponse),
+    GetUserSettings(GetUserSettingsResponse),
+    UploadUserSettings(UploadUserSettingsResponse),
+    SenderReputation(SenderReputationResponse),
+    UploadUserProfile(UploadUserProfileResponse),
+    GetUserProfile(GetUserProfileResponse),
+    CompatibilityInfo(CompatibilityInfoResponse),
+    DequeueMessagesV2(DequeueMessagesResponse),
+    EnqueueMessages(EnqueueMessagesResponse),
+    IntermediateCredentials(AsCredentialsResponseIn),
 }
 
 #[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
@@ -128,6 +157,21 @@ impl ConnectionPackageIn {
     }
 }
 
+impl From<ConnectionPackage> for ConnectionPackageIn {
+    fn from(connection_package: ConnectionPackage) -> Self {
+        let ConnectionPackage { payload, signature } = connection_package;
+        Self {
+            payload: ConnectionPackageTbsIn {
+                protocol_version: payload.protocol_version,
+                encryption_key: payload.encryption_key,
+                lifetime: payload.lifetime,
+                client_credential: payload.client_credential.into(),
+            },
+            signature,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct VerifiableConnectionPackage {
     pub(super) payload: ConnectionPackageTbs,
@@ -155,6 +199,7 @@ pub struct FinishUserRegistrationParamsTbsIn {
     pub initial_ratchet_secret: RatchetSecret,
     pub connection_packages: Vec<ConnectionPackageIn>,
     pub opaque_registration_record: OpaqueRegistrationRecord,
+    pub freshness: Freshness,
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
@@ -199,6 +244,15 @@ impl ClientToAsMessageIn {
     pub fn auth_method(self) -> AsAuthMethod {
         self.body.auth_method()
     }
+
+    /// Returns `true` if the message requests the information clients need
+    /// to check whether they must be upgraded.
+    pub fn is_server_info_request(&self) -> bool {
+        matches!(
+            self.body,
+            AsRequestParamsIn::ServerInfo(_) | AsRequestParamsIn::CompatibilityInfo(_)
+        )
+    }
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
@@ -219,6 +273,20 @@ pub enum AsRequestParamsIn {
     EnqueueMessage(EnqueueMessageParams),
     AsCredentials(AsCredentialsParams),
     IssueTokens(IssueTokensParams),
+    RefreshClientCredential(RefreshClientCredentialParams),
+    ServerInfo(ServerInfoParams),
+    RequestDataExport(RequestDataExportParams),
+    DataExportStatus(DataExportStatusParams),
+    DownloadDataExport(DownloadDataExportParams),
+    EnqueueMessages(EnqueueMessagesParams),
+    GetUserSettings(GetUserSettingsParams),
+    UploadUserSettings(UploadUserSettingsParams),
+    SenderReputation(SenderReputationParams),
+    UploadUserProfile(UploadUserProfileParams),
+    GetUserProfile(GetUserProfileParams),
+    ConfirmClientCredential(ConfirmClientCredentialParams),
+    CompatibilityInfo(CompatibilityInfoParams),
+    IntermediateCredentials(IntermediateCredentialsParams),
 }
 
 impl AsRequestParamsIn {
@@ -251,6 +319,34 @@ impl AsRequestParamsIn {
             Self::IssueTokens(params) => {
                 AsAuthMethod::ClientCredential(params.credential_auth_info())
             }
+            Self::RefreshClientCredential(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::RequestDataExport(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::DataExportStatus(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::DownloadDataExport(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::GetUserSettings(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::UploadUserSettings(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::SenderReputation(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            Self::UploadUserProfile(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
+            // Signed by the client's pending client credential
+            Self::ConfirmClientCredential(params) => {
+                AsAuthMethod::ClientCredential(params.credential_auth_info())
+            }
             // We verify user registration finish requests like a
             // ClientCredentialAuth request and then additionally complete the
             // OPAQUE registration afterwards.
@@ -261,9 +357,14 @@ impl AsRequestParamsIn {
             Self::UserClients(params) => AsAuthMethod::None(params.into_verified()),
             Self::UserConnectionPackages(params) => AsAuthMethod::None(params.into_verified()),
             Self::EnqueueMessage(params) => AsAuthMethod::None(params.into_verified()),
+            Self::EnqueueMessages(params) => AsAuthMethod::None(params.into_verified()),
             Self::InitUserRegistration(params) => AsAuthMethod::None(params.into_verified()),
             Self::InitiateClientAddition(params) => AsAuthMethod::None(params.into_verified()),
             Self::AsCredentials(params) => AsAuthMethod::None(params.into_verified()),
+            Self::IntermediateCredentials(params) => AsAuthMethod::None(params.into_verified()),
+            Self::ServerInfo(params) => AsAuthMethod::None(params.into_verified()),
+            Self::CompatibilityInfo(params) => AsAuthMethod::None(params.into_verified()),
+            Self::GetUserProfile(params) => AsAuthMethod::None(params.into_verified()),
         }
     }
 }
@@ -11,17 +11,19 @@ use mls_assist::{
     messages::{AssistedMessageIn, AssistedWelcome, SerializedMlsMessage},
     openmls::{
         prelude::{
-            GroupEpoch, GroupId, LeafNodeIndex, MlsMessageIn, RatchetTreeIn, Sender,
+            GroupEpoch, GroupId, HashType, LeafNodeIndex, MlsMessageBodyIn, MlsMessageIn,
+            OpenMlsCrypto, OpenMlsProvider, ProtocolMessage, RatchetTreeIn, Sender,
             SignaturePublicKey,
         },
         treesync::RatchetTree,
     },
+    openmls_rust_crypto::OpenMlsRustCrypto,
     openmls_traits::types::HpkeCiphertext,
 };
 use serde::{Deserialize, Serialize};
 use tls_codec::{
     DeserializeBytes, Serialize as TlsSerializeTrait, Size, TlsDeserializeBytes, TlsSerialize,
-    TlsSize,
+    TlsSize, VLBytes,
 };
 
 use crate::{
@@ -37,7 +39,7 @@ use crate::{
         ratchet::QueueRatchet,
         signatures::{
             keys::{UserAuthVerifyingKey, UserKeyHash},
-            signable::{Signature, Verifiable, VerifiedStruct},
+            signable::{Freshness, Signature, Verifiable, VerifiedStruct},
         },
     },
     identifiers::QsClientReference,
@@ -46,9 +48,18 @@ use crate::{
 };
 
 use super::{
+    attachments::{
+        AttachmentBlocked, DeleteAttachmentsParams, DownloadAttachmentParams,
+        UploadAttachmentParams,
+    },
     client_as::EncryptedFriendshipPackage,
-    welcome_attribution_info::EncryptedWelcomeAttributionInfo, EncryptedQsQueueMessage,
-    MlsInfraVersion,
+    join_request::{JoinRequestParams, RegisterJoinRequestTokenParams},
+    member_profiles::MemberProfilesParams,
+    presence::ContactPresence,
+    room_upgrade::UpgradeAuthorization,
+    self_message::EncryptedSelfMessage,
+    welcome_attribution_info::EncryptedWelcomeAttributionInfo,
+    EncryptedQsQueueMessage, MlsInfraVersion, QueuePriority,
 };
 
 mod private_mod {
@@ -73,6 +84,9 @@ pub type QsQueueRatchet = QueueRatchet<EncryptedQsQueueMessage, QsQueueMessagePa
 pub enum QsQueueMessageType {
     WelcomeBundle,
     MlsMessage,
+    JoinRequest,
+    AttachmentBlocked,
+    SelfMessage,
 }
 
 #[derive(
@@ -95,12 +109,49 @@ impl QsQueueMessagePayload {
                 let message = MlsMessageIn::tls_deserialize_exact_bytes(self.payload.as_slice())?;
                 ExtractedQsQueueMessagePayload::MlsMessage(Box::new(message))
             }
+            QsQueueMessageType::JoinRequest => {
+                let join_request = JoinRequestParams::tls_deserialize_exact_bytes(&self.payload)?;
+                ExtractedQsQueueMessagePayload::JoinRequest(join_request)
+            }
+            QsQueueMessageType::AttachmentBlocked => {
+                let blocked = AttachmentBlocked::tls_deserialize_exact_bytes(&self.payload)?;
+                ExtractedQsQueueMessagePayload::AttachmentBlocked(blocked)
+            }
+            QsQueueMessageType::SelfMessage => {
+                let message = EncryptedSelfMessage::tls_deserialize_exact_bytes(&self.payload)?;
+                ExtractedQsQueueMessagePayload::SelfMessage(message)
+            }
         };
         Ok(ExtractedQsQueueMessage {
             timestamp: self.timestamp,
             payload,
         })
     }
+
+    /// Returns the id of the group the message belongs to. Welcome bundles
+    /// don't reveal their group id and self messages don't belong to a group,
+    /// so this returns `None` for them.
+    pub fn group_id(&self) -> Result<Option<GroupId>, tls_codec::Error> {
+        match self.message_type {
+            QsQueueMessageType::WelcomeBundle | QsQueueMessageType::SelfMessage => return Ok(None),
+            QsQueueMessageType::JoinRequest => {
+                let join_request = JoinRequestParams::tls_deserialize_exact_bytes(&self.payload)?;
+                return Ok(Some(join_request.group_id));
+            }
+            QsQueueMessageType::AttachmentBlocked => {
+                let blocked = AttachmentBlocked::tls_deserialize_exact_bytes(&self.payload)?;
+                return Ok(Some(blocked.group_id));
+            }
+            QsQueueMessageType::MlsMessage => {}
+        }
+        let message = MlsMessageIn::tls_deserialize_exact_bytes(self.payload.as_slice())?;
+        let protocol_message: ProtocolMessage = match message.extract() {
+            MlsMessageBodyIn::PublicMessage(message) => message.into(),
+            MlsMessageBodyIn::PrivateMessage(message) => message.into(),
+            _ => return Ok(None),
+        };
+        Ok(Some(protocol_message.group_id().clone()))
+    }
 }
 
 #[derive(Debug)]
@@ -113,6 +164,9 @@ pub struct ExtractedQsQueueMessage {
 pub enum ExtractedQsQueueMessagePayload {
     WelcomeBundle(WelcomeBundle),
     MlsMessage(Box<MlsMessageIn>),
+    JoinRequest(JoinRequestParams),
+    AttachmentBlocked(AttachmentBlocked),
+    SelfMessage(EncryptedSelfMessage),
 }
 
 impl TryFrom<WelcomeBundle> for QsQueueMessagePayload {
@@ -128,6 +182,45 @@ impl TryFrom<WelcomeBundle> for QsQueueMessagePayload {
     }
 }
 
+impl TryFrom<JoinRequestParams> for QsQueueMessagePayload {
+    type Error = tls_codec::Error;
+
+    fn try_from(join_request: JoinRequestParams) -> Result<Self, Self::Error> {
+        let payload = join_request.tls_serialize_detached()?;
+        Ok(Self {
+            timestamp: TimeStamp::now(),
+            message_type: QsQueueMessageType::JoinRequest,
+            payload,
+        })
+    }
+}
+
+impl TryFrom<AttachmentBlocked> for QsQueueMessagePayload {
+    type Error = tls_codec::Error;
+
+    fn try_from(attachment_blocked: AttachmentBlocked) -> Result<Self, Self::Error> {
+        let payload = attachment_blocked.tls_serialize_detached()?;
+        Ok(Self {
+            timestamp: TimeStamp::now(),
+            message_type: QsQueueMessageType::AttachmentBlocked,
+            payload,
+        })
+    }
+}
+
+impl TryFrom<EncryptedSelfMessage> for QsQueueMessagePayload {
+    type Error = tls_codec::Error;
+
+    fn try_from(self_message: EncryptedSelfMessage) -> Result<Self, Self::Error> {
+        let payload = self_message.tls_serialize_detached()?;
+        Ok(Self {
+            timestamp: TimeStamp::now(),
+            message_type: QsQueueMessageType::SelfMessage,
+            payload,
+        })
+    }
+}
+
 impl From<SerializedMlsMessage> for QsQueueMessagePayload {
     fn from(value: SerializedMlsMessage) -> Self {
         Self {
@@ -178,9 +271,15 @@ pub enum InfraAadPayload {
     RemoveClients,
     ResyncClient,
     DeleteGroup,
+    UpdateRoomPolicy,
+    UpdateGroupData,
+    UpgradeGroup(UpgradeAuthorization),
+    JoinUpgradedGroup(JoinUpgradedGroupParamsAad),
     // There is no SelfRemoveClient entry, since that message consists of a
     // single proposal and since we don't otherwise support individual
-    // proposals, there is not need to signal it explicitly.
+    // proposals, there is not need to signal it explicitly. Update proposals
+    // carry an `UpdateClient` payload, since they change the sender's leaf
+    // just like an update commit.
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
@@ -188,6 +287,7 @@ pub enum InfraAadPayload {
 pub enum QsWsMessage {
     QueueUpdate,
     Event(DsEventMessage),
+    Presence(ContactPresence),
 }
 
 #[derive(
@@ -268,6 +368,22 @@ pub struct ExternalCommitInfoParams {
     pub sender: UserKeyHash,
 }
 
+/// Request for the epoch of a group at the DS, which allows a member to find
+/// out whether the DS applied its pending commit.
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct GroupEpochInfoParams {
+    pub group_id: GroupId,
+    pub sender: UserKeyHash,
+}
+
+/// The epoch of a group at the DS together with the confirmed transcript hash
+/// of the commit that started it.
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct GroupEpochInfo {
+    pub epoch: u64,
+    pub confirmed_transcript_hash: VLBytes,
+}
+
 #[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
 pub struct ConnectionGroupInfoParams {
     pub group_id: GroupId,
@@ -367,10 +483,17 @@ pub struct SelfRemoveClientParams {
     pub sender: UserKeyHash,
 }
 
+#[derive(Debug, TlsDeserializeBytes, TlsSize)]
+pub struct ProposeClientUpdateParams {
+    pub update_proposal: AssistedMessageIn,
+    pub sender: LeafNodeIndex,
+}
+
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
 pub struct SendMessageParams {
     pub message: AssistedMessageIn,
     pub sender: LeafNodeIndex,
+    pub priority: QueuePriority,
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
@@ -385,6 +508,39 @@ pub struct DeleteGroupParams {
     pub sender: UserKeyHash,
 }
 
+#[derive(Debug, TlsDeserializeBytes, TlsSize)]
+pub struct UpdateRoomPolicyParams {
+    pub commit: AssistedMessageIn,
+    pub sender: UserKeyHash,
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSize)]
+pub struct UpdateGroupDataParams {
+    pub commit: AssistedMessageIn,
+    pub sender: LeafNodeIndex,
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSize)]
+pub struct UpgradeGroupParams {
+    pub commit: AssistedMessageIn,
+    pub sender: UserKeyHash,
+    /// Key of the successor group, which the DS needs to link the groups.
+    pub successor_ear_key: GroupStateEarKey,
+}
+
+#[derive(Debug, TlsDeserializeBytes, TlsSize)]
+pub struct JoinUpgradedGroupParams {
+    pub external_commit: AssistedMessageIn,
+    /// Hash of the user auth key the user had in the upgraded group.
+    pub sender: UserKeyHash,
+    pub qs_client_reference: QsClientReference,
+}
+
+#[derive(TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct JoinUpgradedGroupParamsAad {
+    pub encrypted_client_information: (EncryptedClientCredential, EncryptedSignatureEarKey),
+}
+
 /// This enum contains variants for each DS endpoint.
 #[expect(clippy::large_enum_variant)]
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
@@ -407,6 +563,24 @@ pub enum DsRequestParams {
     SendMessage(SendMessageParams),
     DeleteGroup(DeleteGroupParams),
     DispatchEvent(DispatchEventParams),
+    UpdateRoomPolicy(UpdateRoomPolicyParams),
+    JoinRequest(JoinRequestParams),
+    UploadAttachment(UploadAttachmentParams),
+    DownloadAttachment(DownloadAttachmentParams),
+    MemberProfiles(MemberProfilesParams),
+    DeleteAttachments(DeleteAttachmentsParams),
+    UpdateGroupData(UpdateGroupDataParams),
+    UpgradeGroup(UpgradeGroupParams),
+    JoinUpgradedGroup(JoinUpgradedGroupParams),
+    /// Upload a message archive, which is kept separately from attachments.
+    UploadArchive(UploadAttachmentParams),
+    RegisterJoinRequestToken(RegisterJoinRequestTokenParams),
+    GroupEpochInfo(GroupEpochInfoParams),
+    /// Upload an attachment that is deleted once it was downloaded.
+    UploadViewOnceAttachment(UploadAttachmentParams),
+    /// Propose to update the sender's leaf, e.g. because the room policy
+    /// doesn't allow the sender to commit.
+    ProposeClientUpdate(ProposeClientUpdateParams),
 }
 
 impl DsRequestParams {
@@ -446,6 +620,9 @@ impl DsRequestParams {
             DsRequestParams::SelfRemoveClient(self_remove_client_params) => {
                 self_remove_client_params.remove_proposal.group_id()
             }
+            DsRequestParams::ProposeClientUpdate(propose_client_update_params) => {
+                propose_client_update_params.update_proposal.group_id()
+            }
             DsRequestParams::SendMessage(send_message_params) => {
                 send_message_params.message.group_id()
             }
@@ -455,6 +632,27 @@ impl DsRequestParams {
             DsRequestParams::DispatchEvent(dispatch_event_params) => {
                 dispatch_event_params.event.group_id()
             }
+            DsRequestParams::UpdateRoomPolicy(update_room_policy_params) => {
+                update_room_policy_params.commit.group_id()
+            }
+            DsRequestParams::JoinRequest(join_request_params) => &join_request_params.group_id,
+            DsRequestParams::UploadAttachment(params)
+            | DsRequestParams::UploadArchive(params)
+            | DsRequestParams::UploadViewOnceAttachment(params) => &params.group_id,
+            DsRequestParams::DownloadAttachment(params) => &params.group_id,
+            DsRequestParams::MemberProfiles(params) => &params.group_id,
+            DsRequestParams::DeleteAttachments(params) => &params.group_id,
+            DsRequestParams::UpdateGroupData(update_group_data_params) => {
+                update_group_data_params.commit.group_id()
+            }
+            DsRequestParams::UpgradeGroup(upgrade_group_params) => {
+                upgrade_group_params.commit.group_id()
+            }
+            DsRequestParams::JoinUpgradedGroup(join_upgraded_group_params) => {
+                join_upgraded_group_params.external_commit.group_id()
+            }
+            DsRequestParams::RegisterJoinRequestToken(params) => &params.group_id,
+            DsRequestParams::GroupEpochInfo(params) => &params.group_id,
         }
     }
 
@@ -488,15 +686,39 @@ impl DsRequestParams {
             DsRequestParams::SelfRemoveClient(self_remove_client_params) => {
                 self_remove_client_params.remove_proposal.sender()
             }
+            DsRequestParams::ProposeClientUpdate(propose_client_update_params) => {
+                propose_client_update_params.update_proposal.sender()
+            }
             DsRequestParams::DeleteGroup(delete_group_params) => {
                 delete_group_params.commit.sender()
             }
+            DsRequestParams::UpdateRoomPolicy(update_room_policy_params) => {
+                update_room_policy_params.commit.sender()
+            }
+            DsRequestParams::UpdateGroupData(update_group_data_params) => {
+                update_group_data_params.commit.sender()
+            }
+            DsRequestParams::UpgradeGroup(upgrade_group_params) => {
+                upgrade_group_params.commit.sender()
+            }
+            DsRequestParams::JoinUpgradedGroup(join_upgraded_group_params) => {
+                join_upgraded_group_params.external_commit.sender()
+            }
             DsRequestParams::DispatchEvent(_) => {
                 None
             }
             DsRequestParams::WelcomeInfo(_)
             | DsRequestParams::ExternalCommitInfo(_)
+            | DsRequestParams::GroupEpochInfo(_)
             | DsRequestParams::ConnectionGroupInfo(_)
+            | DsRequestParams::JoinRequest(_)
+            | DsRequestParams::RegisterJoinRequestToken(_)
+            | DsRequestParams::UploadAttachment(_)
+            | DsRequestParams::UploadArchive(_)
+            | DsRequestParams::UploadViewOnceAttachment(_)
+            | DsRequestParams::DownloadAttachment(_)
+            | DsRequestParams::MemberProfiles(_)
+            | DsRequestParams::DeleteAttachments(_)
             | DsRequestParams::CreateGroupParams(_)
             // Since we're leaking the leaf index in the header, we could
             // technically return the MLS sender here.
@@ -523,6 +745,7 @@ impl DsRequestParams {
             DsRequestParams::ExternalCommitInfo(external_commit_info_params) => {
                 DsSender::UserKeyHash(external_commit_info_params.sender.clone())
             }
+            DsRequestParams::GroupEpochInfo(params) => DsSender::UserKeyHash(params.sender.clone()),
             DsRequestParams::RemoveUsers(remove_users_params) => {
                 DsSender::UserKeyHash(remove_users_params.sender.clone())
             }
@@ -547,6 +770,9 @@ impl DsRequestParams {
             DsRequestParams::SelfRemoveClient(self_remove_client_params) => {
                 DsSender::UserKeyHash(self_remove_client_params.sender.clone())
             }
+            DsRequestParams::ProposeClientUpdate(propose_client_update_params) => {
+                DsSender::LeafIndex(propose_client_update_params.sender)
+            }
             DsRequestParams::SendMessage(send_message_params) => {
                 DsSender::LeafIndex(send_message_params.sender)
             }
@@ -556,7 +782,34 @@ impl DsRequestParams {
             DsRequestParams::DispatchEvent(dispatch_event_params) => {
                 DsSender::LeafIndex(dispatch_event_params.event.sender_index())
             }
-            DsRequestParams::ConnectionGroupInfo(_) => DsSender::Anonymous,
+            DsRequestParams::UpdateRoomPolicy(update_room_policy_params) => {
+                DsSender::UserKeyHash(update_room_policy_params.sender.clone())
+            }
+            DsRequestParams::UploadAttachment(params)
+            | DsRequestParams::UploadArchive(params)
+            | DsRequestParams::UploadViewOnceAttachment(params) => {
+                DsSender::LeafIndex(params.sender)
+            }
+            DsRequestParams::DownloadAttachment(params) => DsSender::LeafIndex(params.sender),
+            DsRequestParams::MemberProfiles(params) => DsSender::LeafIndex(params.sender),
+            DsRequestParams::DeleteAttachments(params) => {
+                DsSender::UserKeyHash(params.sender.clone())
+            }
+            DsRequestParams::UpdateGroupData(update_group_data_params) => {
+                DsSender::LeafIndex(update_group_data_params.sender)
+            }
+            DsRequestParams::UpgradeGroup(upgrade_group_params) => {
+                DsSender::UserKeyHash(upgrade_group_params.sender.clone())
+            }
+            DsRequestParams::JoinUpgradedGroup(join_upgraded_group_params) => {
+                DsSender::UserKeyHash(join_upgraded_group_params.sender.clone())
+            }
+            DsRequestParams::RegisterJoinRequestToken(params) => {
+                DsSender::UserKeyHash(params.sender.clone())
+            }
+            DsRequestParams::ConnectionGroupInfo(_) | DsRequestParams::JoinRequest(_) => {
+                DsSender::Anonymous
+            }
         }
     }
 }
@@ -570,10 +823,40 @@ pub enum DsSender {
     Anonymous,
 }
 
+/// Key that identifies a request to the DS across retries.
+///
+/// The key is derived from the request body, such that a client that retries
+/// a request (e.g. after a timeout) automatically sends the same key. The DS
+/// uses it to detect group operations that it has already applied.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct IdempotencyKey(Vec<u8>);
+
+impl IdempotencyKey {
+    /// Derive the key from the serialized request body. Returns an empty key
+    /// if hashing fails, which disables deduplication for the request.
+    pub fn derive(serialized_body: &[u8]) -> Self {
+        let hash = OpenMlsRustCrypto::default()
+            .crypto()
+            .hash(HashType::Sha2_256, serialized_body)
+            .unwrap_or_default();
+        Self(hash)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
 pub(crate) struct ClientToDsMessageTbs {
     _version: MlsInfraVersion,
     group_state_ear_key: GroupStateEarKey,
+    idempotency_key: IdempotencyKey,
+    freshness: Freshness,
     // This essentially includes the wire format.
     body: DsRequestParams,
 }
@@ -637,6 +920,14 @@ impl VerifiableClientToDsMessage {
         self.message.payload.sender()
     }
 
+    pub fn idempotency_key(&self) -> &IdempotencyKey {
+        &self.message.payload.idempotency_key
+    }
+
+    pub fn freshness(&self) -> &Freshness {
+        &self.message.payload.freshness
+    }
+
     /// If the message contains a group creation request, return a reference to
     /// the group creation parameters. Otherwise return None.
     ///
@@ -661,6 +952,16 @@ impl VerifiableClientToDsMessage {
         }
     }
 
+    /// If the message contains a join request, return the join request
+    /// parameters. The DS needs them to unwrap the group state EAR key before
+    /// it can decrypt the group state.
+    pub fn join_request_params(&self) -> Option<&JoinRequestParams> {
+        match &self.message.payload.body {
+            DsRequestParams::JoinRequest(params) => Some(params),
+            _ => None,
+        }
+    }
+
     /// This returns the payload without any verification. Can only be used with
     /// payloads that have an `Anonymous` sender.
     pub fn extract_without_verification(self) -> Option<DsRequestParams> {
@@ -24,21 +24,27 @@ use crate::{
         ear::keys::{EncryptedSignatureEarKey, GroupStateEarKey},
         signatures::{
             keys::{UserAuthVerifyingKey, UserKeyHash},
-            signable::{Signable, Signature, SignedStruct},
+            signable::{Freshness, Signable, Signature, SignedStruct},
         },
     },
-    identifiers::QsClientReference,
+    identifiers::{AttachmentId, QsClientReference},
     keypackage_batch::{KeyPackageBatch, VERIFIED},
     time::TimeStamp,
 };
 
 use super::{
+    attachments::{
+        AttachmentDownload, DeleteAttachmentsParams, DownloadAttachmentParams,
+        UploadAttachmentParams,
+    },
     client_ds::{
-        ConnectionGroupInfoParams, ExternalCommitInfoParams, UpdateQsClientReferenceParams,
-        WelcomeInfoParams,
+        ConnectionGroupInfoParams, ExternalCommitInfoParams, GroupEpochInfo, GroupEpochInfoParams,
+        IdempotencyKey, UpdateQsClientReferenceParams, WelcomeInfoParams,
     },
+    join_request::{JoinRequestParams, RegisterJoinRequestTokenParams},
+    member_profiles::{MemberProfilesPage, MemberProfilesParams},
     welcome_attribution_info::EncryptedWelcomeAttributionInfo,
-    MlsInfraVersion,
+    MlsInfraVersion, QueuePriority,
 };
 
 #[derive(TlsSize, TlsDeserializeBytes)]
@@ -57,6 +63,15 @@ pub enum DsProcessResponseIn {
     WelcomeInfo(RatchetTreeIn),
     ExternalCommitInfo(ExternalCommitInfoIn),
     GroupId(GroupId),
+    /// The DS has already applied the request. Contains the fanout timestamp
+    /// of the original request.
+    AlreadyApplied(TimeStamp),
+    AttachmentUploaded(AttachmentId),
+    Attachment(AttachmentDownload),
+    MemberProfiles(MemberProfilesPage),
+    /// Number of deleted attachments
+    AttachmentsDeleted(u32),
+    GroupEpochInfo(GroupEpochInfo),
 }
 
 #[derive(Debug, TlsSerialize, TlsSize)]
@@ -135,10 +150,17 @@ pub struct SelfRemoveClientParamsOut {
     pub sender: UserKeyHash,
 }
 
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct ProposeClientUpdateParamsOut {
+    pub update_proposal: AssistedMessageOut,
+    pub sender: LeafNodeIndex,
+}
+
 #[derive(Debug, TlsSerialize, TlsSize)]
 pub struct SendMessageParamsOut {
     pub message: AssistedMessageOut,
     pub sender: LeafNodeIndex,
+    pub priority: QueuePriority,
 }
 
 #[derive(Debug, TlsSerialize, TlsSize)]
@@ -147,6 +169,32 @@ pub struct DeleteGroupParamsOut {
     pub sender: UserKeyHash,
 }
 
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct UpdateRoomPolicyParamsOut {
+    pub commit: AssistedMessageOut,
+    pub sender: UserKeyHash,
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct UpdateGroupDataParamsOut {
+    pub commit: AssistedMessageOut,
+    pub sender: LeafNodeIndex,
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct UpgradeGroupParamsOut {
+    pub commit: AssistedMessageOut,
+    pub sender: UserKeyHash,
+    pub successor_ear_key: GroupStateEarKey,
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct JoinUpgradedGroupParamsOut {
+    pub external_commit: AssistedMessageOut,
+    pub sender: UserKeyHash,
+    pub qs_client_reference: QsClientReference,
+}
+
 #[expect(clippy::large_enum_variant)]
 #[derive(Debug, TlsSerialize, TlsSize)]
 #[repr(u8)]
@@ -167,6 +215,36 @@ pub enum DsRequestParamsOut {
     SelfRemoveClient(SelfRemoveClientParamsOut),
     SendMessage(SendMessageParamsOut),
     DeleteGroup(DeleteGroupParamsOut),
+    // Skips the discriminant of `DsRequestParams::DispatchEvent`, which
+    // clients don't send.
+    #[tls_codec(discriminant = 17)]
+    UpdateRoomPolicy(UpdateRoomPolicyParamsOut),
+    #[tls_codec(discriminant = 18)]
+    JoinRequest(JoinRequestParams),
+    #[tls_codec(discriminant = 19)]
+    UploadAttachment(UploadAttachmentParams),
+    #[tls_codec(discriminant = 20)]
+    DownloadAttachment(DownloadAttachmentParams),
+    #[tls_codec(discriminant = 21)]
+    MemberProfiles(MemberProfilesParams),
+    #[tls_codec(discriminant = 22)]
+    DeleteAttachments(DeleteAttachmentsParams),
+    #[tls_codec(discriminant = 23)]
+    UpdateGroupData(UpdateGroupDataParamsOut),
+    #[tls_codec(discriminant = 24)]
+    UpgradeGroup(UpgradeGroupParamsOut),
+    #[tls_codec(discriminant = 25)]
+    JoinUpgradedGroup(JoinUpgradedGroupParamsOut),
+    #[tls_codec(discriminant = 26)]
+    UploadArchive(UploadAttachmentParams),
+    #[tls_codec(discriminant = 27)]
+    RegisterJoinRequestToken(RegisterJoinRequestTokenParams),
+    #[tls_codec(discriminant = 28)]
+    GroupEpochInfo(GroupEpochInfoParams),
+    #[tls_codec(discriminant = 29)]
+    UploadViewOnceAttachment(UploadAttachmentParams),
+    #[tls_codec(discriminant = 30)]
+    ProposeClientUpdate(ProposeClientUpdateParamsOut),
 }
 
 impl Signable for ClientToDsMessageTbsOut {
@@ -185,17 +263,25 @@ impl Signable for ClientToDsMessageTbsOut {
 pub struct ClientToDsMessageTbsOut {
     _version: MlsInfraVersion,
     group_state_ear_key: GroupStateEarKey,
+    idempotency_key: IdempotencyKey,
+    freshness: Freshness,
     // This essentially includes the wire format.
     body: DsRequestParamsOut,
 }
 
 impl ClientToDsMessageTbsOut {
-    pub fn new(group_state_ear_key: GroupStateEarKey, body: DsRequestParamsOut) -> Self {
-        Self {
+    pub fn new(
+        group_state_ear_key: GroupStateEarKey,
+        body: DsRequestParamsOut,
+    ) -> Result<Self, tls_codec::Error> {
+        let idempotency_key = IdempotencyKey::derive(&body.tls_serialize_detached()?);
+        Ok(Self {
             _version: MlsInfraVersion::default(),
             group_state_ear_key,
+            idempotency_key,
+            freshness: Freshness::new(),
             body,
-        }
+        })
     }
 }
 
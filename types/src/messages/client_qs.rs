@@ -18,8 +18,8 @@ use crate::{
         kdf::keys::RatchetSecret,
         signatures::keys::QsClientVerifyingKey,
         signatures::{
-            keys::{QsUserVerifyingKey, QsVerifyingKey},
-            signable::{Signature, Verifiable, VerifiedStruct},
+            keys::{QsClientSigningKey, QsUserVerifyingKey, QsVerifyingKey},
+            signable::{Freshness, Signable, Signature, SignedStruct, Verifiable, VerifiedStruct},
         },
         RatchetEncryptionKey,
     },
@@ -27,13 +27,178 @@ use crate::{
     keypackage_batch::{
         AddPackage, AddPackageIn, KeyPackageBatch, QsEncryptedAddPackage, UNVERIFIED, VERIFIED,
     },
+    LibraryError,
 };
 
-use super::{push_token::EncryptedPushToken, FriendshipToken, MlsInfraVersion, QueueMessage};
+use super::{
+    presence::{FetchPresenceParams, FetchPresenceResponse, PublishPresenceParams},
+    push_token::EncryptedPushToken,
+    self_message::EnqueueSelfMessageParams,
+    server_info::ClientVersion,
+    FriendshipToken, MlsInfraVersion, QueueMessage,
+};
+
+/// Websocket close code sent by the QS when it closed the connection because
+/// the client didn't keep up with its notifications. Notifications might have
+/// been dropped, so the client has to fetch its queue after reconnecting.
+pub const QS_WS_CLOSE_RESUME_REQUIRED: u16 = 4000;
+
+/// Header in which the QS returns a [`QsResumptionTicket`] when it accepts a
+/// websocket connection.
+pub const QS_RESUMPTION_TICKET_HEADER: &str = "QsResumptionTicket";
+
+/// Maximum number of messages the AS and the QS return in a single dequeue
+/// response. Clients requesting more get a continuation instead.
+pub const MAX_DEQUEUE_BATCH_SIZE: u64 = 1_000;
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct QsOpenWsParams {
     pub queue_id: QsClientId,
+    pub auth: QsOpenWsAuth,
+}
+
+impl QsOpenWsParams {
+    /// Authenticate the listener with a signature by the client's QS auth key.
+    pub fn signed(
+        queue_id: QsClientId,
+        signing_key: &QsClientSigningKey,
+    ) -> Result<Self, LibraryError> {
+        QsOpenWsTbs {
+            queue_id,
+            freshness: Freshness::new(),
+        }
+        .sign(signing_key)
+    }
+
+    /// Authenticate the listener with a ticket issued by the QS when the
+    /// client last opened a websocket.
+    pub fn with_ticket(queue_id: QsClientId, ticket: QsResumptionTicket) -> Self {
+        Self {
+            queue_id,
+            auth: QsOpenWsAuth::Ticket(ticket),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum QsOpenWsAuth {
+    Signature {
+        freshness: Freshness,
+        signature: Signature,
+    },
+    Ticket(QsResumptionTicket),
+}
+
+impl QsOpenWsAuth {
+    /// Returns the signed payload, if the listener authenticated with a
+    /// signature.
+    pub fn into_verifiable(self, queue_id: QsClientId) -> Option<VerifiableQsOpenWs> {
+        match self {
+            QsOpenWsAuth::Signature {
+                freshness,
+                signature,
+            } => Some(VerifiableQsOpenWs {
+                payload: QsOpenWsTbs {
+                    queue_id,
+                    freshness,
+                },
+                signature,
+            }),
+            QsOpenWsAuth::Ticket(_) => None,
+        }
+    }
+}
+
+/// Opaque ticket allowing a client to reopen its websocket without a
+/// signature. Only the QS can interpret the ticket.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct QsResumptionTicket(Vec<u8>);
+
+impl QsResumptionTicket {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, TlsSerialize, TlsSize)]
+pub struct QsOpenWsTbs {
+    queue_id: QsClientId,
+    freshness: Freshness,
+}
+
+impl Signable for QsOpenWsTbs {
+    type SignedOutput = QsOpenWsParams;
+
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.tls_serialize_detached()
+    }
+
+    fn label(&self) -> &str {
+        "QsOpenWsParams"
+    }
+}
+
+impl SignedStruct<QsOpenWsTbs> for QsOpenWsParams {
+    fn from_payload(payload: QsOpenWsTbs, signature: Signature) -> Self {
+        Self {
+            queue_id: payload.queue_id,
+            auth: QsOpenWsAuth::Signature {
+                freshness: payload.freshness,
+                signature,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifiableQsOpenWs {
+    payload: QsOpenWsTbs,
+    signature: Signature,
+}
+
+impl Verifiable for VerifiableQsOpenWs {
+    fn unsigned_payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        self.payload.tls_serialize_detached()
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn label(&self) -> &str {
+        "QsOpenWsParams"
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifiedQsOpenWs {
+    queue_id: QsClientId,
+    freshness: Freshness,
+}
+
+impl VerifiedQsOpenWs {
+    pub fn queue_id(&self) -> &QsClientId {
+        &self.queue_id
+    }
+
+    pub fn freshness(&self) -> &Freshness {
+        &self.freshness
+    }
+}
+
+impl VerifiedStruct<VerifiableQsOpenWs> for VerifiedQsOpenWs {
+    type SealingType = private_mod::Seal;
+
+    fn from_verifiable(verifiable: VerifiableQsOpenWs, _seal: Self::SealingType) -> Self {
+        Self {
+            queue_id: verifiable.payload.queue_id,
+            freshness: verifiable.payload.freshness,
+        }
+    }
 }
 
 mod private_mod {
@@ -174,16 +339,30 @@ pub struct KeyPackageBatchParams {
     pub friendship_ear_key: AddPackageEarKey,
 }
 
+/// Key package stock of the clients of a user after a key package batch was
+/// fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct KeyPackageAvailability {
+    /// Smallest number of regular key packages left for any of the user's
+    /// clients.
+    pub remaining: u32,
+    /// Whether the batch contains at least one last-resort key package, i.e.
+    /// whether a key package is being reused.
+    pub last_resort_used: bool,
+}
+
 #[derive(Debug, TlsSerialize, TlsSize)]
 pub struct KeyPackageBatchResponse {
     pub add_packages: Vec<AddPackage>,
     pub key_package_batch: KeyPackageBatch<VERIFIED>,
+    pub availability: KeyPackageAvailability,
 }
 
 #[derive(Debug, TlsSize, TlsDeserializeBytes)]
 pub struct KeyPackageBatchResponseIn {
     pub add_packages: Vec<AddPackageIn>,
     pub key_package_batch: KeyPackageBatch<UNVERIFIED>,
+    pub availability: KeyPackageAvailability,
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSerialize, TlsSize)]
@@ -207,6 +386,73 @@ pub struct DequeueMessagesParams {
 pub struct DequeueMessagesResponse {
     pub messages: Vec<QueueMessage>,
     pub remaining_messages_number: u64,
+    /// Set by the QS if the client is running low on key packages and should
+    /// publish new ones.
+    pub replenish_key_packages: bool,
+    /// Sequence number at which the next batch starts, if messages remain in
+    /// the queue.
+    pub continuation: Option<u64>,
+}
+
+impl DequeueMessagesResponse {
+    /// Oldest client version that can decode responses with a continuation.
+    /// Older clients, and clients that don't announce their version, get a
+    /// [`DequeueMessagesResponseV1`].
+    pub const MIN_CLIENT_VERSION: ClientVersion = ClientVersion::new(0, 2, 0);
+
+    /// Whether a client that announced the given version can decode this
+    /// version of the response.
+    pub fn supported_by(client_version: Option<&ClientVersion>) -> bool {
+        client_version.is_some_and(|version| *version >= Self::MIN_CLIENT_VERSION)
+    }
+
+    /// Build a response for a batch read from a queue with the given number
+    /// of remaining messages.
+    pub fn new(
+        messages: Vec<QueueMessage>,
+        remaining_messages_number: u64,
+        replenish_key_packages: bool,
+    ) -> Self {
+        let continuation = messages
+            .last()
+            .filter(|_| remaining_messages_number > 0)
+            .map(|message| message.sequence_number + 1);
+        Self {
+            messages,
+            remaining_messages_number,
+            replenish_key_packages,
+            continuation,
+        }
+    }
+}
+
+/// Dequeue response without a continuation, as understood by clients older
+/// than [`DequeueMessagesResponse::MIN_CLIENT_VERSION`].
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct DequeueMessagesResponseV1 {
+    pub messages: Vec<QueueMessage>,
+    pub remaining_messages_number: u64,
+    pub replenish_key_packages: bool,
+}
+
+impl From<DequeueMessagesResponse> for DequeueMessagesResponseV1 {
+    fn from(response: DequeueMessagesResponse) -> Self {
+        Self {
+            messages: response.messages,
+            remaining_messages_number: response.remaining_messages_number,
+            replenish_key_packages: response.replenish_key_packages,
+        }
+    }
+}
+
+impl From<DequeueMessagesResponseV1> for DequeueMessagesResponse {
+    fn from(response: DequeueMessagesResponseV1) -> Self {
+        Self::new(
+            response.messages,
+            response.remaining_messages_number,
+            response.replenish_key_packages,
+        )
+    }
 }
 
 #[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
@@ -234,6 +480,10 @@ impl VerifiableClientToQsMessage {
         self.message.sender()
     }
 
+    pub fn freshness(&self) -> &Freshness {
+        &self.message.payload.freshness
+    }
+
     // Verifies that the token matches the one in the message and returns the message.
     pub fn verify_with_token(
         self,
@@ -299,6 +549,7 @@ impl ClientToQsMessage {
 #[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
 pub struct ClientToQsMessageTbs {
     version: MlsInfraVersion,
+    freshness: Freshness,
     // This essentially includes the wire format.
     body: QsRequestParams,
 }
@@ -330,6 +581,11 @@ pub enum QsRequestParams {
     // Key material
     VerifyingKey,
     EncryptionKey,
+    // Presence
+    PublishPresence(PublishPresenceParams),
+    FetchPresence(FetchPresenceParams),
+    // Self messages
+    EnqueueSelfMessage(EnqueueSelfMessageParams),
 }
 
 impl QsRequestParams {
@@ -350,6 +606,9 @@ impl QsRequestParams {
             }
             QsRequestParams::DequeueMessages(params) => QsSender::Client(params.sender.clone()),
             QsRequestParams::EncryptionKey | QsRequestParams::VerifyingKey => QsSender::Anonymous,
+            QsRequestParams::PublishPresence(params) => QsSender::User(params.sender.clone()),
+            QsRequestParams::FetchPresence(params) => QsSender::User(params.sender.clone()),
+            QsRequestParams::EnqueueSelfMessage(params) => QsSender::Client(params.sender.clone()),
         }
     }
 }
@@ -362,9 +621,11 @@ pub enum QsProcessResponse {
     CreateClient(CreateClientRecordResponse),
     ClientKeyPackage(ClientKeyPackageResponse),
     KeyPackageBatch(KeyPackageBatchResponse),
-    DequeueMessages(DequeueMessagesResponse),
+    DequeueMessages(DequeueMessagesResponseV1),
     VerifyingKey(VerifyingKeyResponse),
     EncryptionKey(EncryptionKeyResponse),
+    FetchPresence(FetchPresenceResponse),
+    DequeueMessagesV2(DequeueMessagesResponse),
 }
 
 #[derive(Debug, TlsDeserializeBytes, TlsSize)]
@@ -375,9 +636,11 @@ pub enum QsProcessResponseIn {
     CreateClient(CreateClientRecordResponse),
     ClientKeyPackage(ClientKeyPackageResponse),
     KeyPackageBatch(KeyPackageBatchResponseIn),
-    DequeueMessages(DequeueMessagesResponse),
+    DequeueMessages(DequeueMessagesResponseV1),
     VerifyingKey(VerifyingKeyResponse),
     EncryptionKey(EncryptionKeyResponse),
+    FetchPresence(FetchPresenceResponse),
+    DequeueMessagesV2(DequeueMessagesResponse),
 }
 
 #[derive(Debug)]
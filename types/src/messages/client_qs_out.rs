@@ -10,7 +10,7 @@ use crate::{
         kdf::keys::RatchetSecret,
         signatures::{
             keys::{QsClientVerifyingKey, QsUserVerifyingKey},
-            signable::{Signable, Signature, SignedStruct},
+            signable::{Freshness, Signable, Signature, SignedStruct},
         },
         RatchetEncryptionKey,
     },
@@ -24,7 +24,9 @@ use super::{
         DequeueMessagesParams, KeyPackageBatchParams, UpdateClientRecordParams,
         UpdateUserRecordParams,
     },
+    presence::{FetchPresenceParams, PublishPresenceParams},
     push_token::EncryptedPushToken,
+    self_message::EnqueueSelfMessageParams,
     FriendshipToken, MlsInfraVersion,
 };
 
@@ -50,6 +52,7 @@ impl ClientToQsMessageOut {
 #[derive(Debug, TlsSerialize, TlsSize)]
 pub struct ClientToQsMessageTbsOut {
     version: MlsInfraVersion,
+    freshness: Freshness,
     // This essentially includes the wire format.
     body: QsRequestParamsOut,
 }
@@ -58,6 +61,7 @@ impl ClientToQsMessageTbsOut {
     pub fn new(body: QsRequestParamsOut) -> Self {
         Self {
             version: MlsInfraVersion::default(),
+            freshness: Freshness::new(),
             body,
         }
     }
@@ -128,4 +132,9 @@ pub enum QsRequestParamsOut {
     // Key material
     QsVerifyingKey,
     QsEncryptionKey,
+    // Presence
+    PublishPresence(PublishPresenceParams),
+    FetchPresence(FetchPresenceParams),
+    // Self messages
+    EnqueueSelfMessage(EnqueueSelfMessageParams),
 }
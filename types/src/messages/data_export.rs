@@ -0,0 +1,140 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Export of the personal data the AS holds about a user.
+//!
+//! A client requests an export job together with a fresh
+//! [`DataExportEncryptionKey`]. The AS assembles the [`DataExport`] in the
+//! background and only stores it encrypted to that key. The client polls the
+//! status of the job and downloads the [`EncryptedDataExport`] once it is
+//! ready. Completed exports are deleted after the retention period configured
+//! by the operator.
+
+use mls_assist::openmls_traits::types::HpkeCiphertext;
+use serde::{Deserialize, Serialize};
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::{
+    crypto::{
+        hpke::{HpkeDecryptable, HpkeEncryptable},
+        DataExportDecryptionKey, DataExportEncryptionKey,
+    },
+    identifiers::{AsClientId, QualifiedUserName},
+    messages::client_as::SenderReputation,
+    time::TimeStamp,
+};
+
+/// Personal data the AS holds about a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataExport {
+    pub exported_at: TimeStamp,
+    /// User name the user registered with
+    pub user_name: QualifiedUserName,
+    pub handles: Vec<HandleDataExport>,
+    /// Reputation the AS reports to clients that screen connection offers of
+    /// the user. The AS doesn't receive abuse reports, blocking and reporting
+    /// users happens on the clients.
+    pub sender_reputation: SenderReputation,
+    pub clients: Vec<ClientDataExport>,
+}
+
+/// Handle under which the user can be found by other users. Currently, this
+/// is always the user name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandleDataExport {
+    pub handle: QualifiedUserName,
+    pub registered_at: TimeStamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientDataExport {
+    pub client_id: AsClientId,
+    pub credential_not_before: TimeStamp,
+    pub credential_not_after: TimeStamp,
+    pub last_activity: TimeStamp,
+    pub remaining_tokens: u32,
+    pub queue: QueueDataExport,
+}
+
+/// Metadata of the AS queue of a client. The messages themselves are
+/// encrypted to the client and not part of the export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDataExport {
+    pub sequence_number: u64,
+    pub pending_messages: u64,
+}
+
+impl HpkeEncryptable<DataExportEncryptionKey, EncryptedDataExport> for DataExport {}
+impl HpkeDecryptable<DataExportDecryptionKey, EncryptedDataExport> for DataExport {}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct EncryptedDataExport {
+    ciphertext: HpkeCiphertext,
+}
+
+impl AsRef<HpkeCiphertext> for EncryptedDataExport {
+    fn as_ref(&self) -> &HpkeCiphertext {
+        &self.ciphertext
+    }
+}
+
+impl From<HpkeCiphertext> for EncryptedDataExport {
+    fn from(ciphertext: HpkeCiphertext) -> Self {
+        Self { ciphertext }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum DataExportStatus {
+    /// The export is being assembled.
+    Pending,
+    /// The export can be downloaded until the given time.
+    Ready(TimeStamp),
+    /// Assembling the export failed. The client can request a new one.
+    Failed,
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::identifiers::SafeTryInto;
+
+    use super::*;
+
+    #[test]
+    fn encrypted_export_roundtrip() {
+        let user_name: QualifiedUserName = SafeTryInto::try_into("alice@example.com").unwrap();
+        let export = DataExport {
+            exported_at: TimeStamp::now(),
+            user_name: user_name.clone(),
+            handles: vec![HandleDataExport {
+                handle: user_name.clone(),
+                registered_at: TimeStamp::now(),
+            }],
+            sender_reputation: SenderReputation::New,
+            clients: vec![ClientDataExport {
+                client_id: AsClientId::new(user_name, Uuid::new_v4()),
+                credential_not_before: TimeStamp::now(),
+                credential_not_after: TimeStamp::now(),
+                last_activity: TimeStamp::now(),
+                remaining_tokens: 100,
+                queue: QueueDataExport {
+                    sequence_number: 7,
+                    pending_messages: 2,
+                },
+            }],
+        };
+
+        let decryption_key = DataExportDecryptionKey::generate().unwrap();
+        let encrypted = export.encrypt(&decryption_key.encryption_key(), &[], &[]);
+        let decrypted = DataExport::decrypt(encrypted, &decryption_key, &[], &[]).unwrap();
+        assert_eq!(decrypted, export);
+
+        let other_key = DataExportDecryptionKey::generate().unwrap();
+        let encrypted = export.encrypt(&decryption_key.encryption_key(), &[], &[]);
+        assert!(DataExport::decrypt(encrypted, &other_key, &[], &[]).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Structure of the group data, i.e. the room state in the group data
+//! extension.
+//!
+//! The room state is defined by the clients. The group data is a
+//! [`PhnxCodec`]-encoded, versioned enum whose first field holds the
+//! conversation attributes (title and picture). Group data of groups created
+//! before it was versioned holds the bare attributes. This module only knows
+//! about this structure, such that the DS can check that the attributes stay
+//! the same when the rest of the room state is updated.
+
+use ciborium::Value;
+use serde::Deserialize;
+
+use crate::codec::PhnxCodec;
+
+#[derive(Deserialize)]
+enum VersionedGroupData {
+    V1(Value),
+    V2(Value, Value),
+}
+
+/// The conversation attributes in the given group data, in their generic
+/// CBOR representation. Returns `None` if the group data can't be decoded.
+fn attributes(group_data: &[u8]) -> Option<Value> {
+    match PhnxCodec::from_slice::<VersionedGroupData>(group_data) {
+        Ok(VersionedGroupData::V1(attributes) | VersionedGroupData::V2(attributes, _)) => {
+            Some(attributes)
+        }
+        Err(_) => PhnxCodec::from_slice(group_data).ok(),
+    }
+}
+
+/// Returns true if both group data decode and hold the same conversation
+/// attributes.
+pub fn attributes_unchanged(current_group_data: &[u8], new_group_data: &[u8]) -> bool {
+    match (attributes(current_group_data), attributes(new_group_data)) {
+        (Some(current), Some(new)) => current == new,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Attributes {
+        title: String,
+    }
+
+    #[derive(Serialize)]
+    enum Versioned {
+        V1(Attributes),
+        V2(Attributes, Vec<u8>),
+    }
+
+    fn encode(value: &impl Serialize) -> Vec<u8> {
+        PhnxCodec::to_vec(value).unwrap()
+    }
+
+    #[test]
+    fn attributes_must_stay_the_same() {
+        let title = |title: &str| Attributes {
+            title: title.to_owned(),
+        };
+        let legacy = encode(&title("Room"));
+        let v1 = encode(&Versioned::V1(title("Room")));
+        let v2 = encode(&Versioned::V2(title("Room"), vec![1]));
+        let renamed = encode(&Versioned::V2(title("Renamed"), vec![1]));
+
+        assert!(attributes_unchanged(&legacy, &v2));
+        assert!(attributes_unchanged(&v1, &v2));
+        assert!(!attributes_unchanged(&v1, &renamed));
+        assert!(!attributes_unchanged(&v1, &[1, 2, 3]));
+    }
+}
@@ -0,0 +1,57 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Requests of non-members to join a room.
+//!
+//! If the room policy allows knocking, members that can invite others can
+//! register join request tokens with the DS. The DS keeps the group state EAR
+//! key of the group wrapped under a key derived from the token. A non-member
+//! that holds a token can send a join request to the DS, which unwraps the
+//! EAR key and distributes the request to the members of the group. The
+//! request is encrypted such that only the members can see who wants to join.
+//! The token doesn't reveal any of the EAR keys of the group.
+
+use mls_assist::openmls::prelude::GroupId;
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::crypto::{
+    ear::{keys::JoinRequestTokenKey, Ciphertext},
+    kdf::keys::JoinRequestTokenId,
+    signatures::keys::UserKeyHash,
+};
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct EncryptedJoinRequest {
+    ciphertext: Ciphertext,
+}
+
+impl AsRef<Ciphertext> for EncryptedJoinRequest {
+    fn as_ref(&self) -> &Ciphertext {
+        &self.ciphertext
+    }
+}
+
+impl From<Ciphertext> for EncryptedJoinRequest {
+    fn from(ciphertext: Ciphertext) -> Self {
+        Self { ciphertext }
+    }
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct JoinRequestParams {
+    pub group_id: GroupId,
+    pub token_id: JoinRequestTokenId,
+    /// Key with which the DS unwraps the group state EAR key registered for
+    /// the token.
+    pub token_key: JoinRequestTokenKey,
+    pub encrypted_join_request: EncryptedJoinRequest,
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct RegisterJoinRequestTokenParams {
+    pub group_id: GroupId,
+    pub sender: UserKeyHash,
+    pub token_id: JoinRequestTokenId,
+    pub token_key: JoinRequestTokenKey,
+}
@@ -0,0 +1,57 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Member profiles kept by the DS.
+//!
+//! The DS records for each client of a group when it was last active. Members
+//! can query this information page by page, sorted by role or by recent
+//! activity, e.g. to render the member list of a large group without loading
+//! all members at once. Since the DS doesn't know the identities of the
+//! members, profiles refer to members by the leaf indices of their clients.
+
+use mls_assist::openmls::prelude::{GroupEpoch, GroupId, LeafNodeIndex};
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::time::TimeStamp;
+
+/// Maximum number of member profiles the DS returns per page.
+pub const MAX_MEMBER_PROFILES_PAGE_SIZE: u32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum MemberOrder {
+    /// Admins first, then by recent activity.
+    Role,
+    /// Most recently active members first.
+    RecentActivity,
+}
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct MemberProfilesParams {
+    pub group_id: GroupId,
+    pub sender: LeafNodeIndex,
+    pub order: MemberOrder,
+    pub offset: u32,
+    /// Capped at [`MAX_MEMBER_PROFILES_PAGE_SIZE`] by the DS.
+    pub limit: u32,
+}
+
+/// Profile of a single user in a group.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct MemberProfile {
+    /// The leaf indices of the user's clients in ascending order.
+    pub clients: Vec<LeafNodeIndex>,
+    pub is_admin: bool,
+    /// Latest activity of any of the user's clients.
+    pub last_activity_time: TimeStamp,
+    /// Epoch of the group at the time of the latest activity.
+    pub last_activity_epoch: GroupEpoch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct MemberProfilesPage {
+    pub members: Vec<MemberProfile>,
+    /// Offset of the next page, if there is one.
+    pub next_offset: Option<u32>,
+}
@@ -2,22 +2,33 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use mls_assist::{
-    openmls::prelude::OpenMlsRand, openmls_rust_crypto::OpenMlsRustCrypto,
-    openmls_traits::OpenMlsProvider,
-};
 use serde::{Deserialize, Serialize};
-use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+use tls_codec::{
+    DeserializeBytes as _, Serialize as _, Size as _, TlsDeserializeBytes, TlsSerialize, TlsSize,
+};
 
-use crate::crypto::{ear::Ciphertext, errors::RandomnessError};
+use crate::crypto::{ear::Ciphertext, errors::RandomnessError, rng};
 
+pub mod announcements;
+pub mod attachments;
 pub mod client_as;
 pub mod client_as_out;
 pub mod client_ds;
 pub mod client_ds_out;
 pub mod client_qs;
 pub mod client_qs_out;
+pub mod data_export;
+pub mod group_data;
+pub mod join_request;
+pub mod member_profiles;
+pub mod presence;
 pub mod push_token;
+pub mod room_policy;
+pub mod room_upgrade;
+pub mod self_message;
+pub mod server_info;
+pub mod user_profiles;
+pub mod user_settings;
 pub mod welcome_attribution_info;
 
 #[derive(
@@ -44,10 +55,7 @@ impl rusqlite::types::FromSql for FriendshipToken {
 
 impl FriendshipToken {
     pub fn random() -> Result<Self, RandomnessError> {
-        let token = OpenMlsRustCrypto::default()
-            .rand()
-            .random_vec(32)
-            .map_err(|_| RandomnessError::InsufficientRandomness)?;
+        let token = rng::random_vec(32)?;
 
         Ok(Self(token))
     }
@@ -59,12 +67,34 @@ impl FriendshipToken {
 
 /// Enum encoding the version of the MlsInfra protocol that was used to create
 /// the given message.
-#[derive(
-    Debug, TlsSerialize, TlsDeserializeBytes, TlsSize, Clone, Copy, Serialize, Deserialize,
-)]
-#[repr(u8)]
+///
+/// Encoded as a single byte. Versions introduced after this implementation
+/// decode to [`MlsInfraVersion::Unknown`], so that e.g. the server info of a
+/// newer server can still be read. They are never in
+/// [`MlsInfraVersion::SUPPORTED`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MlsInfraVersion {
     Alpha,
+    Unknown(u8),
+}
+
+impl MlsInfraVersion {
+    /// Versions supported by this implementation, from oldest to newest.
+    pub const SUPPORTED: &'static [MlsInfraVersion] = &[MlsInfraVersion::Alpha];
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Alpha => 0,
+            Self::Unknown(version) => version,
+        }
+    }
+
+    fn from_u8(version: u8) -> Self {
+        match version {
+            0 => Self::Alpha,
+            version => Self::Unknown(version),
+        }
+    }
 }
 
 impl Default for MlsInfraVersion {
@@ -73,14 +103,80 @@ impl Default for MlsInfraVersion {
     }
 }
 
+impl tls_codec::Size for MlsInfraVersion {
+    fn tls_serialized_len(&self) -> usize {
+        self.to_u8().tls_serialized_len()
+    }
+}
+
+impl tls_codec::Serialize for MlsInfraVersion {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        self.to_u8().tls_serialize(writer)
+    }
+}
+
+impl tls_codec::DeserializeBytes for MlsInfraVersion {
+    fn tls_deserialize_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (version, rest) = u8::tls_deserialize_bytes(bytes)?;
+        Ok((Self::from_u8(version), rest))
+    }
+}
+
 // === Queue ===
 
+/// Priority lane of a message in a client's queue. Messages in higher lanes
+/// are processed first by the client, such that, for example, a burst of
+/// receipts doesn't delay actual chat messages.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum QueuePriority {
+    /// Chat messages and calls
+    High = 0,
+    /// Receipts and profile updates
+    #[default]
+    Normal = 1,
+    /// Housekeeping
+    Low = 2,
+}
+
+impl TryFrom<i16> for QueuePriority {
+    type Error = i16;
+
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::High),
+            1 => Ok(Self::Normal),
+            2 => Ok(Self::Low),
+            _ => Err(value),
+        }
+    }
+}
+
 #[derive(
     Clone, Debug, PartialEq, Serialize, Deserialize, TlsSerialize, TlsDeserializeBytes, TlsSize,
 )]
 pub struct QueueMessage {
     pub sequence_number: u64,
     pub ciphertext: Ciphertext,
+    /// The priority is stored alongside the message by the queue and is not
+    /// part of the encoded message itself.
+    #[serde(skip)]
+    pub priority: QueuePriority,
 }
 
 #[derive(
@@ -0,0 +1,283 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Coarse presence of users as published to and fanned out by the QS.
+//!
+//! Clients publish their presence together with a [`PresenceToken`] for each
+//! of their contacts. The QS only reveals the presence to the users holding
+//! these tokens, or to nobody, depending on the [`PresenceVisibility`] of the
+//! user. The tokens are derived per pair of contacts, so the QS doesn't learn
+//! the contacts of a user from the stored tokens. The time a user was last
+//! seen is only revealed as a [`LastSeen`] bucket.
+
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::identifiers::QsUserId;
+
+use super::FriendshipToken;
+
+/// Time after which a published online or away presence is considered
+/// outdated and the user is shown as offline. Clients republish their
+/// presence before it expires.
+pub const PRESENCE_TTL: Duration = Duration::minutes(5);
+
+/// Maximum number of contacts in a single presence request.
+pub const MAX_PRESENCE_CONTACTS: usize = 1_000;
+
+pub const PRESENCE_TOKEN_SIZE: usize = 32;
+
+/// Token under which a user reveals their presence to one of their contacts,
+/// see [`crate::crypto::ear::keys::WelcomeAttributionInfoEarKey::presence_token`].
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+pub struct PresenceToken([u8; PRESENCE_TOKEN_SIZE]);
+
+impl PresenceToken {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; PRESENCE_TOKEN_SIZE]> for PresenceToken {
+    fn from(token: [u8; PRESENCE_TOKEN_SIZE]) -> Self {
+        Self(token)
+    }
+}
+
+/// Status a user publishes.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum PresenceStatus {
+    /// The app is in the foreground
+    Online = 0,
+    /// The app is in the background
+    Away = 1,
+    #[default]
+    Offline = 2,
+}
+
+impl TryFrom<i16> for PresenceStatus {
+    type Error = i16;
+
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Online),
+            1 => Ok(Self::Away),
+            2 => Ok(Self::Offline),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Who can see the presence of a user.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum PresenceVisibility {
+    Nobody = 0,
+    /// Only the contacts of the user
+    #[default]
+    Contacts = 1,
+    /// Everybody who knows the friendship token of the user
+    Everyone = 2,
+}
+
+impl TryFrom<i16> for PresenceVisibility {
+    type Error = i16;
+
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Nobody),
+            1 => Ok(Self::Contacts),
+            2 => Ok(Self::Everyone),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Coarse time since an offline user was last online or away.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum LastSeen {
+    /// Within the last hour
+    Recently,
+    /// Within the last day
+    Today,
+    /// Within the last week
+    ThisWeek,
+    LongAgo,
+}
+
+impl LastSeen {
+    pub fn from_elapsed(elapsed: Duration) -> Self {
+        if elapsed < Duration::hours(1) {
+            Self::Recently
+        } else if elapsed < Duration::days(1) {
+            Self::Today
+        } else if elapsed < Duration::weeks(1) {
+            Self::ThisWeek
+        } else {
+            Self::LongAgo
+        }
+    }
+}
+
+/// Presence of a user as seen by others.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    /// Only set if the user is offline and was seen before.
+    pub last_seen: Option<LastSeen>,
+}
+
+impl Presence {
+    /// The presence of a user who hides it.
+    pub fn hidden() -> Self {
+        Self::default()
+    }
+}
+
+/// Presence of the user with the given friendship token. Sent to the clients
+/// of a user's contacts when the user's presence changes.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TlsSerialize, TlsDeserializeBytes, TlsSize,
+)]
+pub struct ContactPresence {
+    pub friendship_token: FriendshipToken,
+    pub presence: Presence,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct PublishPresenceParams {
+    pub sender: QsUserId,
+    pub status: PresenceStatus,
+    pub visibility: PresenceVisibility,
+    /// Presence tokens of the contacts that can see the presence if the
+    /// visibility is [`PresenceVisibility::Contacts`]. Replaces the
+    /// previously published ones.
+    pub viewers: Vec<PresenceToken>,
+    /// Friendship tokens of the contacts whose connected clients are notified
+    /// of changes. They are only used for the notification and not stored.
+    pub contacts: Vec<FriendshipToken>,
+}
+
+/// The contact whose presence is requested, together with the token under
+/// which the contact reveals the presence to the sender.
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct PresenceQuery {
+    pub friendship_token: FriendshipToken,
+    pub presence_token: PresenceToken,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct FetchPresenceParams {
+    pub sender: QsUserId,
+    pub contacts: Vec<PresenceQuery>,
+}
+
+/// Presences of the requested contacts. Contacts that are unknown to the QS
+/// are omitted.
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct FetchPresenceResponse {
+    pub presences: Vec<ContactPresence>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::ear::keys::WelcomeAttributionInfoEarKey;
+
+    use super::*;
+
+    #[test]
+    fn presence_tokens_are_per_pair_and_direction() {
+        let alice = WelcomeAttributionInfoEarKey::random().unwrap();
+        let bob = WelcomeAttributionInfoEarKey::random().unwrap();
+        let charlie = WelcomeAttributionInfoEarKey::random().unwrap();
+
+        let alice_to_bob = alice.presence_token(&bob).unwrap();
+        assert_eq!(alice_to_bob, alice.presence_token(&bob).unwrap());
+        assert_ne!(alice_to_bob, bob.presence_token(&alice).unwrap());
+        assert_ne!(alice_to_bob, alice.presence_token(&charlie).unwrap());
+    }
+
+    #[test]
+    fn last_seen_buckets() {
+        assert_eq!(
+            LastSeen::from_elapsed(Duration::minutes(59)),
+            LastSeen::Recently
+        );
+        assert_eq!(LastSeen::from_elapsed(Duration::hours(23)), LastSeen::Today);
+        assert_eq!(
+            LastSeen::from_elapsed(Duration::days(6)),
+            LastSeen::ThisWeek
+        );
+        assert_eq!(
+            LastSeen::from_elapsed(Duration::days(30)),
+            LastSeen::LongAgo
+        );
+    }
+}
@@ -0,0 +1,353 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Policy of a room (group) that governs how users can join it and who can
+//! administer it.
+//!
+//! The policy is part of the group context, such that all members and the DS
+//! agree on it. Changes to the policy are committed as group context
+//! extension proposals and validated by the DS.
+
+use mls_assist::openmls::prelude::{Extension, Extensions, UnknownExtension};
+use serde::{Deserialize, Serialize};
+use tls_codec::{DeserializeBytes, Serialize as _, TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::crypto::signatures::keys::UserKeyHash;
+
+pub const ROOM_POLICY_EXTENSION_TYPE: u16 = 0xff02;
+
+/// Determines how non-members can join the room.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum JoinRule {
+    /// Users can only join if they are invited by a member.
+    #[default]
+    InviteOnly,
+    /// Users can additionally ask to join the room.
+    Knock,
+    /// The room is a pending 1:1 message request. Only the requested user
+    /// can join (by accepting the request) and nobody can be invited.
+    MessageRequest,
+}
+
+/// Determines which members can invite new members.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum InviteRule {
+    #[default]
+    AllMembers,
+    AdminsOnly,
+}
+
+/// Determines which members can send messages.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum SendRule {
+    #[default]
+    AllMembers,
+    /// Only admins can send chat messages, e.g. in announcement rooms. All
+    /// members can still send receipts and profile keys.
+    AdminsOnly,
+}
+
+/// Determines which members can create commits.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum CommitRule {
+    #[default]
+    AllMembers,
+    /// Broadcast mode for rooms with thousands of members. Only admins can
+    /// commit proposals. Members leave by proposal and the admins commit the
+    /// pending membership changes in batches. Other members can only update
+    /// their own leaf by commit while no proposals are pending. Otherwise,
+    /// they propose the update and it is committed with the next batch.
+    ///
+    /// The batching is driven by the clients of the admins, so pending
+    /// changes stay uncommitted while no admin client is online.
+    AdminsOnly,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[repr(u8)]
+pub enum RoomPolicyChange {
+    SetJoinRule(JoinRule),
+    SetInviteRule(InviteRule),
+    SetSendRule(SendRule),
+    SetCommitRule(CommitRule),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct RoomPolicy {
+    pub join_rule: JoinRule,
+    pub invite_rule: InviteRule,
+    pub send_rule: SendRule,
+    pub commit_rule: CommitRule,
+    /// Users that can change the policy, identified by the hash of their user
+    /// auth key. Rooms created before room policies were introduced have no
+    /// admins. Their policy can't be changed and lets all members invite,
+    /// send and commit.
+    pub admins: Vec<UserKeyHash>,
+}
+
+impl RoomPolicy {
+    pub fn new(admin: UserKeyHash) -> Self {
+        Self {
+            admins: vec![admin],
+            ..Default::default()
+        }
+    }
+
+    pub fn is_admin(&self, user_key_hash: &UserKeyHash) -> bool {
+        self.admins.contains(user_key_hash)
+    }
+
+    pub fn can_invite(&self, user_key_hash: &UserKeyHash) -> bool {
+        if self.join_rule == JoinRule::MessageRequest {
+            return false;
+        }
+        match self.invite_rule {
+            InviteRule::AllMembers => true,
+            InviteRule::AdminsOnly => self.is_admin(user_key_hash),
+        }
+    }
+
+    pub fn can_send(&self, user_key_hash: &UserKeyHash) -> bool {
+        match self.send_rule {
+            SendRule::AllMembers => true,
+            SendRule::AdminsOnly => self.is_admin(user_key_hash),
+        }
+    }
+
+    pub fn can_commit(&self, user_key_hash: &UserKeyHash) -> bool {
+        match self.commit_rule {
+            CommitRule::AllMembers => true,
+            CommitRule::AdminsOnly => self.is_admin(user_key_hash),
+        }
+    }
+
+    /// Returns true if the room is in broadcast mode, see
+    /// [`CommitRule::AdminsOnly`].
+    pub fn is_broadcast(&self) -> bool {
+        self.commit_rule == CommitRule::AdminsOnly
+    }
+
+    /// Returns true if the policy is valid for a room that was just created
+    /// by the user with the given key, i.e. if the creator is the only admin.
+    pub fn is_valid_initial_policy(&self, creator: &UserKeyHash) -> bool {
+        matches!(self.admins.as_slice(), [admin] if admin == creator)
+    }
+
+    /// Apply the given change. Returns `false` if the change doesn't alter
+    /// the policy.
+    pub fn apply(&mut self, change: RoomPolicyChange) -> bool {
+        match change {
+            RoomPolicyChange::SetJoinRule(join_rule) => {
+                let changed = self.join_rule != join_rule;
+                self.join_rule = join_rule;
+                changed
+            }
+            RoomPolicyChange::SetInviteRule(invite_rule) => {
+                let changed = self.invite_rule != invite_rule;
+                self.invite_rule = invite_rule;
+                changed
+            }
+            RoomPolicyChange::SetSendRule(send_rule) => {
+                let changed = self.send_rule != send_rule;
+                self.send_rule = send_rule;
+                changed
+            }
+            RoomPolicyChange::SetCommitRule(commit_rule) => {
+                let changed = self.commit_rule != commit_rule;
+                self.commit_rule = commit_rule;
+                changed
+            }
+        }
+    }
+
+    /// Returns the changes that turn `self` into `other`.
+    pub fn diff(&self, other: &RoomPolicy) -> Vec<RoomPolicyChange> {
+        let mut changes = vec![];
+        if self.join_rule != other.join_rule {
+            changes.push(RoomPolicyChange::SetJoinRule(other.join_rule));
+        }
+        if self.invite_rule != other.invite_rule {
+            changes.push(RoomPolicyChange::SetInviteRule(other.invite_rule));
+        }
+        if self.send_rule != other.send_rule {
+            changes.push(RoomPolicyChange::SetSendRule(other.send_rule));
+        }
+        if self.commit_rule != other.commit_rule {
+            changes.push(RoomPolicyChange::SetCommitRule(other.commit_rule));
+        }
+        changes
+    }
+
+    /// Read the policy from the given group context extensions. Returns the
+    /// default policy if the extensions don't contain a policy.
+    pub fn from_extensions(extensions: &Extensions) -> Result<Self, tls_codec::Error> {
+        let Some(extension_bytes) = extensions.iter().find_map(|extension| match extension {
+            Extension::Unknown(ROOM_POLICY_EXTENSION_TYPE, UnknownExtension(bytes)) => Some(bytes),
+            _ => None,
+        }) else {
+            return Ok(Self::default());
+        };
+        Self::tls_deserialize_exact_bytes(extension_bytes)
+    }
+
+    pub fn to_extension(&self) -> Result<Extension, tls_codec::Error> {
+        Ok(Extension::Unknown(
+            ROOM_POLICY_EXTENSION_TYPE,
+            UnknownExtension(self.tls_serialize_detached()?),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_roundtrip_and_diff() {
+        let admin = UserKeyHash::new(vec![1; 32]);
+        let other = UserKeyHash::new(vec![2; 32]);
+
+        // Without the extension, no member is an admin, but all members can
+        // invite, send and commit.
+        let default_policy = RoomPolicy::from_extensions(&Extensions::empty()).unwrap();
+        assert!(!default_policy.is_admin(&other));
+        assert!(default_policy.can_invite(&other));
+        assert!(default_policy.can_send(&other));
+        assert!(default_policy.can_commit(&other));
+
+        let policy = RoomPolicy::new(admin.clone());
+        assert!(policy.is_admin(&admin));
+        assert!(!policy.is_admin(&other));
+        assert!(policy.can_invite(&other));
+
+        let mut new_policy = policy.clone();
+        assert!(new_policy.apply(RoomPolicyChange::SetInviteRule(InviteRule::AdminsOnly)));
+        assert!(!new_policy.apply(RoomPolicyChange::SetJoinRule(JoinRule::InviteOnly)));
+        assert!(!new_policy.can_invite(&other));
+        assert_eq!(
+            policy.diff(&new_policy),
+            vec![RoomPolicyChange::SetInviteRule(InviteRule::AdminsOnly)]
+        );
+
+        let extensions = Extensions::single(new_policy.to_extension().unwrap());
+        let decoded = RoomPolicy::from_extensions(&extensions).unwrap();
+        assert_eq!(decoded, new_policy);
+
+        let message_request = RoomPolicy {
+            join_rule: JoinRule::MessageRequest,
+            ..RoomPolicy::new(admin.clone())
+        };
+        assert!(!message_request.can_invite(&admin));
+    }
+
+    #[test]
+    fn send_rule_and_initial_policy() {
+        let admin = UserKeyHash::new(vec![1; 32]);
+        let other = UserKeyHash::new(vec![2; 32]);
+
+        let mut policy = RoomPolicy::new(admin.clone());
+        assert!(policy.is_valid_initial_policy(&admin));
+        assert!(!policy.is_valid_initial_policy(&other));
+        assert!(policy.can_send(&other));
+
+        assert!(policy.apply(RoomPolicyChange::SetSendRule(SendRule::AdminsOnly)));
+        assert!(policy.can_send(&admin));
+        assert!(!policy.can_send(&other));
+
+        // Rooms without admins can't be created.
+        assert!(!RoomPolicy::default().is_valid_initial_policy(&admin));
+    }
+
+    #[test]
+    fn commit_rule() {
+        let admin = UserKeyHash::new(vec![1; 32]);
+        let other = UserKeyHash::new(vec![2; 32]);
+
+        let policy = RoomPolicy::new(admin.clone());
+        assert!(!policy.is_broadcast());
+        assert!(policy.can_commit(&other));
+
+        let mut broadcast_policy = policy.clone();
+        assert!(broadcast_policy.apply(RoomPolicyChange::SetCommitRule(CommitRule::AdminsOnly)));
+        assert!(broadcast_policy.is_broadcast());
+        assert!(broadcast_policy.can_commit(&admin));
+        assert!(!broadcast_policy.can_commit(&other));
+        assert_eq!(
+            policy.diff(&broadcast_policy),
+            vec![RoomPolicyChange::SetCommitRule(CommitRule::AdminsOnly)]
+        );
+
+        let extensions = Extensions::single(broadcast_policy.to_extension().unwrap());
+        let decoded = RoomPolicy::from_extensions(&extensions).unwrap();
+        assert_eq!(decoded, broadcast_policy);
+    }
+}
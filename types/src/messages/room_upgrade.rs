@@ -0,0 +1,367 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Upgrades of rooms (groups) to a new MLS protocol version or ciphersuite.
+//!
+//! The ciphersuite and protocol version of an MLS group are fixed. To move a
+//! room to a new one, an admin creates a successor group with the new
+//! parameters and then commits a [`GroupTombstone`] to the group context of
+//! the old group. The tombstone points to the successor, which in turn points
+//! back to the old group via its [`GroupPredecessor`] extension. The DS links
+//! the two groups and lets the members of the old group join the successor,
+//! while all other operations on the old group are rejected from then on.
+//!
+//! The keys of the successor group are derived from the exporter secret of
+//! the old group in the epoch before the tombstone, such that every member
+//! that processes the tombstone can join the successor without any further
+//! key distribution.
+
+use mls_assist::openmls::prelude::{
+    Capabilities, Ciphersuite, Extension, Extensions, GroupEpoch, GroupId, ProtocolVersion,
+    UnknownExtension,
+};
+use tls_codec::{DeserializeBytes, Serialize as _, TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::{
+    crypto::signatures::{
+        keys::{UserAuthSigningKey, UserAuthVerifyingKey},
+        signable::Signature,
+        traits::{SignatureVerificationError, SigningKeyBehaviour, VerifyingKeyBehaviour},
+    },
+    LibraryError,
+};
+
+pub const GROUP_TOMBSTONE_EXTENSION_TYPE: u16 = 0xff03;
+pub const GROUP_PREDECESSOR_EXTENSION_TYPE: u16 = 0xff04;
+
+/// Exporter label for the keys of the successor group. The group id of the
+/// successor is used as the exporter context.
+pub const GROUP_UPGRADE_EXPORTER_LABEL: &str = "phnx group upgrade";
+
+/// Protocol version and ciphersuite of the successor of an upgraded group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct UpgradeTarget {
+    pub version: ProtocolVersion,
+    pub ciphersuite: Ciphersuite,
+}
+
+impl UpgradeTarget {
+    /// Returns true if a leaf with the given capabilities supports the
+    /// target.
+    pub fn supported_by(&self, capabilities: &Capabilities) -> bool {
+        capabilities.versions().contains(&self.version)
+            && capabilities
+                .ciphersuites()
+                .contains(&self.ciphersuite.into())
+    }
+
+    /// Returns the first of the given targets (in order of preference) that
+    /// is supported by all members.
+    pub fn negotiate<'a>(
+        preferences: &[UpgradeTarget],
+        members: impl IntoIterator<Item = &'a Capabilities>,
+    ) -> Option<UpgradeTarget> {
+        let members: Vec<_> = members.into_iter().collect();
+        preferences
+            .iter()
+            .find(|target| {
+                members
+                    .iter()
+                    .all(|capabilities| target.supported_by(capabilities))
+            })
+            .copied()
+    }
+}
+
+/// Marks a group as upgraded. Once committed, the group only serves as a
+/// pointer to its successor.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct GroupTombstone {
+    pub successor: GroupId,
+    pub target: UpgradeTarget,
+}
+
+impl GroupTombstone {
+    /// Read the tombstone from the given group context extensions. Returns
+    /// `None` if the group wasn't upgraded.
+    pub fn from_extensions(extensions: &Extensions) -> Result<Option<Self>, tls_codec::Error> {
+        find_extension(extensions, GROUP_TOMBSTONE_EXTENSION_TYPE)
+            .map(Self::tls_deserialize_exact_bytes)
+            .transpose()
+    }
+
+    pub fn to_extension(&self) -> Result<Extension, tls_codec::Error> {
+        Ok(Extension::Unknown(
+            GROUP_TOMBSTONE_EXTENSION_TYPE,
+            UnknownExtension(self.tls_serialize_detached()?),
+        ))
+    }
+}
+
+const UPGRADE_AUTHORIZATION_LABEL: &str = "UpgradeAuthorization";
+
+/// Proof that the committer of a [`GroupTombstone`] is an admin of the
+/// upgraded group. Members only know the hashes of the admins' user auth
+/// keys, so the committer reveals its key and signs the tombstone together
+/// with the group and the epoch it is committed in.
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct UpgradeAuthorization {
+    pub admin_key: UserAuthVerifyingKey,
+    signature: Signature,
+}
+
+#[derive(TlsSerialize, TlsSize)]
+struct UpgradeAuthorizationTbs {
+    group_id: GroupId,
+    epoch: GroupEpoch,
+    tombstone: GroupTombstone,
+}
+
+impl UpgradeAuthorizationTbs {
+    fn payload(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        let mut payload = UPGRADE_AUTHORIZATION_LABEL.as_bytes().to_vec();
+        self.tls_serialize(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+impl UpgradeAuthorization {
+    pub fn sign(
+        signing_key: &UserAuthSigningKey,
+        group_id: &GroupId,
+        epoch: GroupEpoch,
+        tombstone: &GroupTombstone,
+    ) -> Result<Self, LibraryError> {
+        let payload = UpgradeAuthorizationTbs {
+            group_id: group_id.clone(),
+            epoch,
+            tombstone: tombstone.clone(),
+        }
+        .payload()
+        .map_err(|_| LibraryError)?;
+        Ok(Self {
+            admin_key: signing_key.verifying_key(),
+            signature: signing_key.sign(&payload)?,
+        })
+    }
+
+    /// Verify that the admin key signed the given tombstone for the given
+    /// group and epoch. Whether the key belongs to an admin has to be checked
+    /// separately.
+    pub fn verify(
+        &self,
+        group_id: &GroupId,
+        epoch: GroupEpoch,
+        tombstone: &GroupTombstone,
+    ) -> Result<(), SignatureVerificationError> {
+        let payload = UpgradeAuthorizationTbs {
+            group_id: group_id.clone(),
+            epoch,
+            tombstone: tombstone.clone(),
+        }
+        .payload()
+        .map_err(|_| LibraryError)?;
+        self.admin_key.verify(&payload, &self.signature)
+    }
+}
+
+/// Returns the tombstone if the given extensions only differ from the
+/// current ones by an added tombstone.
+pub fn added_tombstone(
+    current_extensions: &Extensions,
+    new_extensions: &Extensions,
+) -> Option<GroupTombstone> {
+    let other_extensions_unchanged = new_extensions.iter().count()
+        == current_extensions.iter().count() + 1
+        && new_extensions
+            .iter()
+            .filter(|extension| {
+                !matches!(
+                    extension,
+                    Extension::Unknown(GROUP_TOMBSTONE_EXTENSION_TYPE, _)
+                )
+            })
+            .all(|extension| current_extensions.iter().any(|e| e == extension));
+    if !other_extensions_unchanged
+        || find_extension(current_extensions, GROUP_TOMBSTONE_EXTENSION_TYPE).is_some()
+    {
+        return None;
+    }
+    GroupTombstone::from_extensions(new_extensions)
+        .ok()
+        .flatten()
+}
+
+/// Points from the successor of an upgraded group back to the old group.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct GroupPredecessor {
+    pub predecessor: GroupId,
+}
+
+impl GroupPredecessor {
+    /// Read the pointer from the given group context extensions. Returns
+    /// `None` if the group isn't the successor of another group.
+    pub fn from_extensions(extensions: &Extensions) -> Result<Option<Self>, tls_codec::Error> {
+        find_extension(extensions, GROUP_PREDECESSOR_EXTENSION_TYPE)
+            .map(Self::tls_deserialize_exact_bytes)
+            .transpose()
+    }
+
+    pub fn to_extension(&self) -> Result<Extension, tls_codec::Error> {
+        Ok(Extension::Unknown(
+            GROUP_PREDECESSOR_EXTENSION_TYPE,
+            UnknownExtension(self.tls_serialize_detached()?),
+        ))
+    }
+}
+
+fn find_extension(extensions: &Extensions, extension_type: u16) -> Option<&[u8]> {
+    extensions.iter().find_map(|extension| match extension {
+        Extension::Unknown(t, UnknownExtension(bytes)) if *t == extension_type => {
+            Some(bytes.as_slice())
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURRENT: UpgradeTarget = UpgradeTarget {
+        version: ProtocolVersion::Mls10,
+        ciphersuite: Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519,
+    };
+    const NEXT: UpgradeTarget = UpgradeTarget {
+        version: ProtocolVersion::Mls10,
+        ciphersuite: Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+    };
+
+    fn capabilities(ciphersuites: &[Ciphersuite]) -> Capabilities {
+        Capabilities::new(
+            Some(&[ProtocolVersion::Mls10]),
+            Some(ciphersuites),
+            None,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn negotiate_target_supported_by_all_members() {
+        let old_member = capabilities(&[CURRENT.ciphersuite]);
+        let new_member = capabilities(&[CURRENT.ciphersuite, NEXT.ciphersuite]);
+
+        assert!(NEXT.supported_by(&new_member));
+        assert!(!NEXT.supported_by(&old_member));
+        assert_eq!(
+            UpgradeTarget::negotiate(&[NEXT, CURRENT], [&new_member, &new_member]),
+            Some(NEXT)
+        );
+        assert_eq!(
+            UpgradeTarget::negotiate(&[NEXT, CURRENT], [&new_member, &old_member]),
+            Some(CURRENT)
+        );
+        assert_eq!(UpgradeTarget::negotiate(&[NEXT], [&old_member]), None);
+    }
+
+    #[test]
+    fn extension_roundtrip() {
+        let old_group = GroupId::from_slice(&[1; 16]);
+        let new_group = GroupId::from_slice(&[2; 16]);
+
+        assert_eq!(
+            GroupTombstone::from_extensions(&Extensions::empty()).unwrap(),
+            None
+        );
+        assert_eq!(
+            GroupPredecessor::from_extensions(&Extensions::empty()).unwrap(),
+            None
+        );
+
+        let tombstone = GroupTombstone {
+            successor: new_group,
+            target: NEXT,
+        };
+        let predecessor = GroupPredecessor {
+            predecessor: old_group,
+        };
+        let extensions = Extensions::from_vec(vec![
+            tombstone.to_extension().unwrap(),
+            predecessor.to_extension().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            GroupTombstone::from_extensions(&extensions).unwrap(),
+            Some(tombstone)
+        );
+        assert_eq!(
+            GroupPredecessor::from_extensions(&extensions).unwrap(),
+            Some(predecessor)
+        );
+    }
+
+    #[test]
+    fn upgrade_authorization_is_bound_to_group_and_epoch() {
+        let group_id = GroupId::from_slice(&[1; 16]);
+        let tombstone = GroupTombstone {
+            successor: GroupId::from_slice(&[2; 16]),
+            target: NEXT,
+        };
+        let signing_key = UserAuthSigningKey::generate().unwrap();
+        let authorization =
+            UpgradeAuthorization::sign(&signing_key, &group_id, GroupEpoch::from(3), &tombstone)
+                .unwrap();
+
+        assert!(authorization
+            .verify(&group_id, GroupEpoch::from(3), &tombstone)
+            .is_ok());
+        assert!(authorization
+            .verify(&group_id, GroupEpoch::from(4), &tombstone)
+            .is_err());
+        assert!(authorization
+            .verify(
+                &GroupId::from_slice(&[3; 16]),
+                GroupEpoch::from(3),
+                &tombstone
+            )
+            .is_err());
+        let other_tombstone = GroupTombstone {
+            successor: GroupId::from_slice(&[4; 16]),
+            target: NEXT,
+        };
+        assert!(authorization
+            .verify(&group_id, GroupEpoch::from(3), &other_tombstone)
+            .is_err());
+    }
+
+    #[test]
+    fn only_added_tombstones_are_accepted() {
+        let tombstone = GroupTombstone {
+            successor: GroupId::from_slice(&[2; 16]),
+            target: NEXT,
+        };
+        let predecessor = GroupPredecessor {
+            predecessor: GroupId::from_slice(&[1; 16]),
+        };
+        let current = Extensions::single(predecessor.to_extension().unwrap());
+        let with_tombstone = Extensions::from_vec(vec![
+            predecessor.to_extension().unwrap(),
+            tombstone.to_extension().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            added_tombstone(&current, &with_tombstone),
+            Some(tombstone.clone())
+        );
+        // Other extensions must not change
+        let without_predecessor = Extensions::single(tombstone.to_extension().unwrap());
+        assert_eq!(added_tombstone(&current, &without_predecessor), None);
+        // A group can only be upgraded once
+        assert_eq!(added_tombstone(&with_tombstone, &with_tombstone), None);
+        // No tombstone, no upgrade
+        assert_eq!(added_tombstone(&current, &current), None);
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Messages between the clients of a user.
+//!
+//! Clients keep state that isn't part of any group in sync, such as how far
+//! the user has read each chat, by sending [`SelfMessage`]s to the queues of
+//! the user's other clients via the QS. Self messages are encrypted under the
+//! [`SelfMessageEarKey`] shared by the clients of the user, so the QS only
+//! learns that a client of the user sent one.
+
+use mls_assist::openmls::prelude::GroupId;
+use serde::{Deserialize, Serialize};
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::{
+    crypto::ear::{keys::SelfMessageEarKey, Ciphertext, EarDecryptable, EarEncryptable},
+    identifiers::QsClientId,
+    time::TimeStamp,
+};
+
+/// Maximum number of read markers in a single [`SelfMessage`]. Clients split
+/// larger batches into several messages.
+pub const MAX_READ_MARKERS: usize = 500;
+
+/// The user read the chat with the given group id up to the given time.
+///
+/// Markers only ever move forward: a client applies a marker only if it is
+/// later than its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadMarker {
+    pub group_id: GroupId,
+    pub read_until: TimeStamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelfMessage {
+    ReadMarkers(Vec<ReadMarker>),
+    /// The sender uploaded the given version of the user's settings to the
+    /// AS.
+    UserSettingsChanged {
+        version: u64,
+    },
+}
+
+#[derive(
+    Serialize, Deserialize, PartialEq, Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize,
+)]
+pub struct EncryptedSelfMessage(Ciphertext);
+
+impl AsRef<Ciphertext> for EncryptedSelfMessage {
+    fn as_ref(&self) -> &Ciphertext {
+        &self.0
+    }
+}
+
+impl From<Ciphertext> for EncryptedSelfMessage {
+    fn from(ctxt: Ciphertext) -> Self {
+        Self(ctxt)
+    }
+}
+
+impl EarEncryptable<SelfMessageEarKey, EncryptedSelfMessage> for SelfMessage {}
+impl EarDecryptable<SelfMessageEarKey, EncryptedSelfMessage> for SelfMessage {}
+
+/// Enqueue a self message in the queues of all other clients of the sender's
+/// user.
+#[derive(Debug, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct EnqueueSelfMessageParams {
+    pub sender: QsClientId,
+    pub message: EncryptedSelfMessage,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_markers_roundtrip() {
+        let key = SelfMessageEarKey::random().unwrap();
+        let message = SelfMessage::ReadMarkers(vec![ReadMarker {
+            group_id: GroupId::from_slice(b"group"),
+            read_until: TimeStamp::now(),
+        }]);
+        let encrypted = message.encrypt(&key).unwrap();
+        assert_eq!(SelfMessage::decrypt(&key, &encrypted).unwrap(), message);
+
+        let other_key = SelfMessageEarKey::random().unwrap();
+        assert!(SelfMessage::decrypt(&other_key, &encrypted).is_err());
+    }
+}
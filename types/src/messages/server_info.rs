@@ -0,0 +1,248 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Information about a server that clients can query without an account,
+//! e.g. to check a server entered by the user before registering with it.
+
+use std::{fmt, str::FromStr};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::identifiers::tls_codec_impls::TlsString;
+
+use super::MlsInfraVersion;
+
+#[derive(Debug, Clone, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct ServerInfoResponse {
+    /// Protocol versions the server supports
+    pub supported_versions: Vec<MlsInfraVersion>,
+    pub registration_policy: RegistrationPolicy,
+    pub features: Vec<ServerFeature>,
+    /// Message of the day configured by the operator
+    pub motd: Option<ServerMotd>,
+    /// Endpoint to which clients upload their metrics if the user opted in
+    pub metrics_endpoint: Option<MetricsEndpoint>,
+    /// Rate at which the DS accepts messages of a single sender
+    pub send_rate_limit: Option<SendRateLimit>,
+}
+
+impl ServerInfoResponse {
+    /// Returns the newest protocol version supported by both the server and
+    /// this client, if any.
+    pub fn negotiate_version(&self) -> Option<MlsInfraVersion> {
+        MlsInfraVersion::SUPPORTED
+            .iter()
+            .rev()
+            .find(|version| self.supported_versions.contains(version))
+            .copied()
+    }
+}
+
+/// Versions of clients and features the server serves.
+///
+/// Queried separately from [`ServerInfoResponse`], whose layout must not
+/// change, because clients decode it exactly.
+#[derive(Debug, Clone, Default, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct CompatibilityInfoResponse {
+    /// Oldest client version the server still serves. Older clients must be
+    /// upgraded before they can use the server.
+    pub min_client_version: Option<ClientVersion>,
+    /// Features the server still supports, but will remove in the future.
+    /// Clients relying on them should be upgraded.
+    pub deprecated_features: Vec<ServerFeature>,
+}
+
+/// Name of the header in which clients send their [`ClientVersion`]. The
+/// server answers requests of clients older than its minimum version with
+/// `426 Upgrade Required`.
+pub const CLIENT_VERSION_HEADER: &str = "phnx-client-version";
+
+/// Semantic version of a client, compared component-wise.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+    TlsSize,
+)]
+#[serde(try_from = "String")]
+pub struct ClientVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ClientVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid client version {0:?}, expected major.minor.patch")]
+pub struct ClientVersionParseError(String);
+
+impl FromStr for ClientVersion {
+    type Err = ClientVersionParseError;
+
+    /// Parses `major.minor.patch`, ignoring pre-release and build metadata
+    /// suffixes such as `-beta.1` or `+42`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ClientVersionParseError(s.to_owned());
+        let core = s.split(['-', '+']).next().unwrap_or_default();
+        let mut components = core.split('.').map(|component| component.parse::<u32>());
+        let (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) = (
+            components.next(),
+            components.next(),
+            components.next(),
+            components.next(),
+        ) else {
+            return Err(error());
+        };
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl TryFrom<String> for ClientVersion {
+    type Error = ClientVersionParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Whether new users can register with the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum RegistrationPolicy {
+    Open,
+    Closed,
+}
+
+/// Optional feature of a server, identified by name, such that clients can
+/// ignore features they don't know.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct ServerFeature(TlsString);
+
+impl ServerFeature {
+    /// Push notifications via APNs
+    pub const APPLE_PUSH: &'static str = "apple_push";
+    /// Push notifications via FCM
+    pub const GOOGLE_PUSH: &'static str = "google_push";
+    /// Uploaded attachments are scanned for malware
+    pub const ATTACHMENT_SCANNING: &'static str = "attachment_scanning";
+
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(TlsString(name.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0 .0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct ServerMotd(TlsString);
+
+impl ServerMotd {
+    pub fn new(motd: String) -> Self {
+        Self(TlsString(motd))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0 .0
+    }
+}
+
+/// URL of the endpoint collecting client metrics, configured by the operator.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct MetricsEndpoint(TlsString);
+
+impl MetricsEndpoint {
+    pub fn new(url: String) -> Self {
+        Self(TlsString(url))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0 .0
+    }
+}
+
+/// Token bucket with which the DS limits the messages of a single sender.
+///
+/// A sender can send `burst` messages at once, after which it can send
+/// `messages_per_second` messages per second. Messages exceeding the limit
+/// are rejected with HTTP status 429.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct SendRateLimit {
+    pub messages_per_second: u32,
+    pub burst: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_versions_are_parsed() {
+        assert_eq!(
+            "1.2.3".parse::<ClientVersion>().unwrap(),
+            ClientVersion::new(1, 2, 3)
+        );
+        assert_eq!(
+            "0.10.0-beta.1+42".parse::<ClientVersion>().unwrap(),
+            ClientVersion::new(0, 10, 0)
+        );
+        assert!("1.2".parse::<ClientVersion>().is_err());
+        assert!("1.2.3.4".parse::<ClientVersion>().is_err());
+        assert!("1.x.3".parse::<ClientVersion>().is_err());
+    }
+
+    #[test]
+    fn client_versions_are_ordered() {
+        assert!(ClientVersion::new(0, 9, 9) < ClientVersion::new(0, 10, 0));
+        assert!(ClientVersion::new(1, 0, 0) > ClientVersion::new(0, 99, 99));
+    }
+
+    #[test]
+    fn unknown_protocol_versions_are_skipped() {
+        use tls_codec::{DeserializeBytes, Serialize};
+
+        // A newer server that supports the current version and a future one
+        let response = ServerInfoResponse {
+            supported_versions: vec![MlsInfraVersion::Alpha, MlsInfraVersion::Unknown(7)],
+            registration_policy: RegistrationPolicy::Open,
+            features: Vec::new(),
+            motd: None,
+            metrics_endpoint: None,
+            send_rate_limit: None,
+        };
+        let bytes = response.tls_serialize_detached().unwrap();
+        let response = ServerInfoResponse::tls_deserialize_exact_bytes(&bytes).unwrap();
+        assert_eq!(
+            response.supported_versions,
+            [MlsInfraVersion::Alpha, MlsInfraVersion::Unknown(7)]
+        );
+        assert_eq!(response.negotiate_version(), Some(MlsInfraVersion::Alpha));
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Profiles of users stored on the AS.
+//!
+//! A user's clients upload the user's profile encrypted under the
+//! [`UserProfileEarKey`], which the user shares with its contacts. Each
+//! upload increments the version of the profile. Contacts fetch the profile
+//! together with the version they have cached and only receive the profile
+//! if it changed since, which keeps refreshing the profiles of the members of
+//! large groups cheap.
+
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::crypto::ear::{keys::UserProfileEarKey, Ciphertext};
+
+/// Maximum size of the encrypted profile of a user.
+pub const MAX_USER_PROFILE_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct EncryptedUserProfile(Ciphertext);
+
+impl AsRef<Ciphertext> for EncryptedUserProfile {
+    fn as_ref(&self) -> &Ciphertext {
+        &self.0
+    }
+}
+
+impl From<Ciphertext> for EncryptedUserProfile {
+    fn from(ctxt: Ciphertext) -> Self {
+        Self(ctxt)
+    }
+}
+
+/// The encrypted profile of a user as stored on the AS.
+#[derive(Debug, Clone, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct VersionedUserProfile {
+    /// Starts at 1 with the first upload.
+    pub version: u64,
+    pub profile: EncryptedUserProfile,
+}
+
+/// Result of fetching the profile of a user.
+#[derive(Debug, Clone, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+#[repr(u8)]
+pub enum UserProfileFetch {
+    /// The user didn't upload a profile yet.
+    NotFound,
+    /// The profile still has the version cached by the client.
+    NotModified,
+    Modified(VersionedUserProfile),
+}
+
+impl UserProfileFetch {
+    /// The fetch result for the given stored profile and the version cached
+    /// by the client.
+    pub fn new(stored: Option<VersionedUserProfile>, cached_version: Option<u64>) -> Self {
+        match stored {
+            None => Self::NotFound,
+            Some(stored) if Some(stored.version) == cached_version => Self::NotModified,
+            Some(stored) => Self::Modified(stored),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::ear::{EarDecryptable, EarEncryptable};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Profile(String);
+
+    impl EarEncryptable<UserProfileEarKey, EncryptedUserProfile> for Profile {}
+    impl EarDecryptable<UserProfileEarKey, EncryptedUserProfile> for Profile {}
+
+    #[test]
+    fn fetch_is_not_modified_for_cached_version() {
+        let key = UserProfileEarKey::random().unwrap();
+        let profile = Profile("Alice".to_owned()).encrypt(&key).unwrap();
+        let stored = VersionedUserProfile {
+            version: 2,
+            profile,
+        };
+
+        assert_eq!(
+            UserProfileFetch::new(None, Some(2)),
+            UserProfileFetch::NotFound
+        );
+        assert_eq!(
+            UserProfileFetch::new(Some(stored.clone()), Some(2)),
+            UserProfileFetch::NotModified
+        );
+        for cached_version in [None, Some(1)] {
+            let UserProfileFetch::Modified(fetched) =
+                UserProfileFetch::new(Some(stored.clone()), cached_version)
+            else {
+                panic!("Expected a modified profile");
+            };
+            assert_eq!(fetched.version, 2);
+            assert_eq!(
+                Profile::decrypt(&key, &fetched.profile).unwrap(),
+                Profile("Alice".to_owned())
+            );
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Settings of a user that are synced between the user's clients.
+//!
+//! The clients of a user store the synced settings as a single blob on the
+//! AS, encrypted under the [`UserSettingsEarKey`] shared by the clients, so
+//! the AS can't read them. Each upload increments the version of the blob. A
+//! client uploads a new blob together with the version it is based on, and
+//! the AS rejects the upload if another client uploaded a newer version in
+//! the meantime. The client then merges the newer blob into its settings and
+//! tries again.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::{
+    crypto::ear::{keys::UserSettingsEarKey, Ciphertext, EarDecryptable, EarEncryptable},
+    time::TimeStamp,
+};
+
+/// Maximum size of the encrypted settings of a user.
+pub const MAX_USER_SETTINGS_BYTES: usize = 64 * 1024;
+
+/// The synced settings of a user by their key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserSettings {
+    pub settings: BTreeMap<String, SyncedUserSetting>,
+}
+
+/// The serialized value of a setting and when it was last changed.
+///
+/// Settings are merged per key: the value that was changed last wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncedUserSetting {
+    pub value: Vec<u8>,
+    pub modified_at: TimeStamp,
+}
+
+#[derive(
+    Serialize, Deserialize, PartialEq, Clone, Debug, TlsSerialize, TlsDeserializeBytes, TlsSize,
+)]
+pub struct EncryptedUserSettings(Ciphertext);
+
+impl AsRef<Ciphertext> for EncryptedUserSettings {
+    fn as_ref(&self) -> &Ciphertext {
+        &self.0
+    }
+}
+
+impl From<Ciphertext> for EncryptedUserSettings {
+    fn from(ctxt: Ciphertext) -> Self {
+        Self(ctxt)
+    }
+}
+
+impl EarEncryptable<UserSettingsEarKey, EncryptedUserSettings> for UserSettings {}
+impl EarDecryptable<UserSettingsEarKey, EncryptedUserSettings> for UserSettings {}
+
+/// The encrypted settings of a user as stored on the AS.
+#[derive(Debug, Clone, PartialEq, TlsSerialize, TlsDeserializeBytes, TlsSize)]
+pub struct VersionedUserSettings {
+    /// Starts at 1 with the first upload.
+    pub version: u64,
+    pub settings: EncryptedUserSettings,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::kdf::keys::UserKeySecret;
+
+    use super::*;
+
+    #[test]
+    fn user_settings_roundtrip() {
+        let key = UserSettingsEarKey::random().unwrap();
+        let settings = UserSettings {
+            settings: [(
+                "do_not_disturb".to_owned(),
+                SyncedUserSetting {
+                    value: vec![1, 2, 3],
+                    modified_at: TimeStamp::now(),
+                },
+            )]
+            .into(),
+        };
+        let encrypted = settings.encrypt(&key).unwrap();
+        assert_eq!(UserSettings::decrypt(&key, &encrypted).unwrap(), settings);
+
+        let other_key = UserSettingsEarKey::random().unwrap();
+        assert!(UserSettings::decrypt(&other_key, &encrypted).is_err());
+    }
+
+    #[test]
+    fn clients_of_a_user_derive_the_same_key() {
+        let export_key = [7u8; 64];
+        let first =
+            UserSettingsEarKey::derive_from(&UserKeySecret::from_opaque_export_key(&export_key))
+                .unwrap();
+        let second =
+            UserSettingsEarKey::derive_from(&UserKeySecret::from_opaque_export_key(&export_key))
+                .unwrap();
+
+        let settings = UserSettings::default();
+        let encrypted = settings.encrypt(&first).unwrap();
+        assert_eq!(
+            UserSettings::decrypt(&second, &encrypted).unwrap(),
+            settings
+        );
+    }
+}
@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use std::ops::Deref;
+#[cfg(any(test, feature = "test_utils"))]
+use std::sync::{Arc, PoisonError, RwLock};
 
 use chrono::{DateTime, TimeZone, Utc};
 #[cfg(feature = "sqlite")]
@@ -12,8 +14,89 @@ use super::*;
 
 pub use chrono::Duration;
 
+/// Source of the current time.
+///
+/// All expiration checks get the current time from [`now`], which uses the
+/// [`SystemClock`]. With the `test_utils` feature, tests can install a
+/// controllable clock to fast-forward time.
+pub trait TimeProvider: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl TimeProvider for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[cfg(any(test, feature = "test_utils"))]
+static TIME_PROVIDER: RwLock<Option<Arc<dyn TimeProvider>>> = RwLock::new(None);
+
+/// Replace the process-wide time provider.
+///
+/// Only intended for tests. Note that the provider is shared by all threads of
+/// the process, including the ones of a server spawned in the same process.
+#[cfg(any(test, feature = "test_utils"))]
+pub fn set_time_provider(provider: Arc<dyn TimeProvider>) {
+    *TIME_PROVIDER
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(provider);
+}
+
+/// Go back to the [`SystemClock`].
+#[cfg(any(test, feature = "test_utils"))]
+pub fn reset_time_provider() {
+    *TIME_PROVIDER
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+#[cfg(any(test, feature = "test_utils"))]
+thread_local! {
+    static THREAD_TIME_PROVIDER: std::cell::RefCell<Option<Arc<dyn TimeProvider>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Replace the time provider of the current thread only, which takes
+/// precedence over the process-wide one. Pass `None` to go back to the
+/// process-wide provider.
+///
+/// Only intended for tests that run on a single thread, e.g. with a
+/// current-thread runtime. Unlike [`set_time_provider`], it doesn't affect
+/// tests running in parallel.
+#[cfg(any(test, feature = "test_utils"))]
+pub fn set_thread_time_provider(provider: Option<Arc<dyn TimeProvider>>) {
+    THREAD_TIME_PROVIDER.with_borrow_mut(|thread_provider| *thread_provider = provider);
+}
+
+/// The current time. With the `test_utils` feature, this is the time
+/// according to the time provider of the current thread, if there is one, or
+/// the process-wide time provider.
+pub fn now() -> DateTime<Utc> {
+    #[cfg(any(test, feature = "test_utils"))]
+    {
+        if let Some(now) = THREAD_TIME_PROVIDER
+            .with_borrow(|provider| provider.as_ref().map(|provider| provider.now()))
+        {
+            return now;
+        }
+        if let Some(provider) = TIME_PROVIDER
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            return provider.now();
+        }
+    }
+    Utc::now()
+}
+
 /// A time stamp that can be used to represent a point in time.
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Eq, Hash, Copy)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Eq, Hash, Copy, PartialOrd, Ord)]
 #[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
 pub struct TimeStamp(DateTime<Utc>);
 
@@ -116,12 +199,12 @@ impl FromSql for TimeStamp {
 
 impl TimeStamp {
     pub fn now() -> Self {
-        Utc::now().into()
+        now().into()
     }
 
     /// Checks if this time stamp is more than `expiration` in the past.
     pub fn has_expired(&self, expiration: Duration) -> bool {
-        let time_left = Utc::now() - expiration;
+        let time_left = now() - expiration;
         time_left >= self.0
     }
 
@@ -159,7 +242,7 @@ impl ExpirationData {
     /// Create a new instance of [`ExpirationData`] that expires in `lifetime`
     /// days and the validity of which starts now.
     pub fn new(lifetime: Duration) -> Self {
-        let not_before = Utc::now() - Duration::minutes(15);
+        let not_before = now() - Duration::minutes(15);
         Self {
             not_before: TimeStamp::from(not_before),
             not_after: TimeStamp::from(not_before + lifetime),
@@ -180,4 +263,25 @@ impl ExpirationData {
     pub fn not_after(&self) -> TimeStamp {
         self.not_after
     }
+
+    /// Return true if the `not_after` date is less than `duration` in the
+    /// future or has already passed.
+    pub fn expires_within(&self, duration: Duration) -> bool {
+        now() + duration >= self.not_after.0
+    }
+}
+
+#[cfg(test)]
+mod expiration {
+    use super::*;
+
+    #[test]
+    fn expires_within() {
+        let expiration_data = ExpirationData::new(Duration::days(90));
+        assert!(!expiration_data.expires_within(Duration::days(14)));
+        assert!(expiration_data.expires_within(Duration::days(90)));
+
+        let expired = ExpirationData::new(Duration::zero());
+        assert!(expired.expires_within(Duration::zero()));
+    }
 }
@@ -0,0 +1,305 @@
+// SPDX-FileCopyrightText: 2024 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Golden test vectors for the TLS wire formats of the types in this crate.
+//!
+//! The vectors are stored in `test_vectors/wire_formats.json`, such that other
+//! implementations can check their encoding against them. Every vector must
+//! deserialize into its type without leftover bytes and serialize back into
+//! the exact same bytes. Vectors of types that can be constructed
+//! deterministically are additionally compared against a freshly serialized
+//! sample.
+//!
+//! If an encoding is changed on purpose, regenerate the sampled vectors with
+//! `PHNX_UPDATE_WIRE_VECTORS=1 cargo test -p phnxtypes wire_tests` and update
+//! the remaining vectors by hand. CI regenerates the sampled vectors and fails
+//! if the file changes, so vectors can't go stale silently.
+
+use std::collections::BTreeMap;
+
+use mls_assist::openmls::prelude::{GroupEpoch, LeafNodeIndex};
+use serde::{Deserialize, Serialize};
+use tls_codec::{DeserializeBytes, Serialize as TlsSerializeTrait};
+use uuid::Uuid;
+
+use crate::{
+    credentials::{
+        AsCredential, CredentialFingerprint, VerifiableAsIntermediateCredential,
+        VerifiableClientCredential,
+    },
+    crypto::signatures::{keys::UserKeyHash, signable::Freshness},
+    identifiers::{
+        AsClientId, AttachmentId, Fqdn, QualifiedGroupId, QualifiedUserName, SafeTryInto,
+    },
+    messages::{
+        attachments::{AttachmentBlocked, AttachmentDownload, AttachmentUrl},
+        client_ds::{
+            DsEventMessage, IdempotencyKey, InfraAadMessage, InfraAadPayload,
+            QsQueueMessagePayload, QsWsMessage,
+        },
+        member_profiles::{MemberProfile, MemberProfilesPage},
+        room_policy::{CommitRule, InviteRule, JoinRule, RoomPolicy, RoomPolicyChange, SendRule},
+        QueueMessage, QueuePriority,
+    },
+    time::{ExpirationData, TimeStamp},
+};
+
+const VECTOR_FILE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/test_vectors/wire_formats.json"
+);
+const UPDATE_VECTORS_VAR: &str = "PHNX_UPDATE_WIRE_VECTORS";
+
+/// Version of the format of the vector file. Must be increased when the
+/// structure of the file changes, not when individual vectors change.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct VectorFile {
+    format_version: u32,
+    vectors: Vec<TestVector>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TestVector {
+    name: String,
+    type_name: String,
+    /// Hex-encoded TLS serialization
+    encoding: String,
+}
+
+type RoundTrip = fn(&[u8]) -> Result<Vec<u8>, tls_codec::Error>;
+type Sample = fn() -> Vec<u8>;
+
+struct WireCase {
+    type_name: &'static str,
+    round_trip: RoundTrip,
+    sample: Option<Sample>,
+}
+
+fn round_trip<T: DeserializeBytes + TlsSerializeTrait>(
+    bytes: &[u8],
+) -> Result<Vec<u8>, tls_codec::Error> {
+    T::tls_deserialize_exact_bytes(bytes)?.tls_serialize_detached()
+}
+
+fn case<T: DeserializeBytes + TlsSerializeTrait>(sample: Option<Sample>) -> WireCase {
+    WireCase {
+        type_name: std::any::type_name::<T>(),
+        round_trip: round_trip::<T>,
+        sample,
+    }
+}
+
+fn serialize(value: impl TlsSerializeTrait) -> Vec<u8> {
+    value.tls_serialize_detached().unwrap()
+}
+
+fn sample_uuid() -> Uuid {
+    Uuid::from_u128(0x00112233_4455_6677_8899_aabbccddeeff)
+}
+
+fn sample_user_name() -> QualifiedUserName {
+    SafeTryInto::try_into("alice@example.com").unwrap()
+}
+
+fn sample_domain() -> Fqdn {
+    Fqdn::try_from("example.com").unwrap()
+}
+
+fn wire_cases() -> BTreeMap<&'static str, WireCase> {
+    BTreeMap::from([
+        (
+            "time_stamp",
+            case::<TimeStamp>(Some(|| {
+                serialize(TimeStamp::from(1_700_000_000_000_000_000i64))
+            })),
+        ),
+        ("expiration_data", case::<ExpirationData>(None)),
+        (
+            "qualified_user_name",
+            case::<QualifiedUserName>(Some(|| serialize(sample_user_name()))),
+        ),
+        (
+            "as_client_id",
+            case::<AsClientId>(Some(|| {
+                serialize(AsClientId::new(sample_user_name(), sample_uuid()))
+            })),
+        ),
+        (
+            "qualified_group_id",
+            case::<QualifiedGroupId>(Some(|| {
+                serialize(QualifiedGroupId::new(sample_uuid(), sample_domain()))
+            })),
+        ),
+        (
+            "attachment_id",
+            case::<AttachmentId>(Some(|| serialize(AttachmentId::from(sample_uuid())))),
+        ),
+        (
+            "credential_fingerprint",
+            case::<CredentialFingerprint>(None),
+        ),
+        ("as_credential", case::<AsCredential>(None)),
+        (
+            "as_intermediate_credential",
+            case::<VerifiableAsIntermediateCredential>(None),
+        ),
+        (
+            "client_credential",
+            case::<VerifiableClientCredential>(None),
+        ),
+        (
+            "queue_priority",
+            case::<QueuePriority>(Some(|| serialize(QueuePriority::Low))),
+        ),
+        ("queue_message", case::<QueueMessage>(None)),
+        (
+            "qs_queue_message_payload",
+            case::<QsQueueMessagePayload>(None),
+        ),
+        (
+            "qs_ws_message_queue_update",
+            case::<QsWsMessage>(Some(|| serialize(QsWsMessage::QueueUpdate))),
+        ),
+        ("ds_event_message", case::<DsEventMessage>(None)),
+        (
+            "idempotency_key",
+            case::<IdempotencyKey>(Some(|| {
+                serialize(IdempotencyKey::derive(b"phnx wire test vector"))
+            })),
+        ),
+        (
+            "infra_aad_message_remove_users",
+            case::<InfraAadMessage>(Some(|| {
+                serialize(InfraAadMessage::from(InfraAadPayload::RemoveUsers))
+            })),
+        ),
+        (
+            "infra_aad_message_update_client",
+            case::<InfraAadMessage>(None),
+        ),
+        (
+            "attachment_download_url",
+            case::<AttachmentDownload>(Some(|| {
+                serialize(AttachmentDownload::Url(AttachmentUrl::new(
+                    "https://files.example.com/attachment".to_owned(),
+                )))
+            })),
+        ),
+        ("attachment_blocked", case::<AttachmentBlocked>(None)),
+        (
+            "room_policy",
+            case::<RoomPolicy>(Some(|| {
+                serialize(RoomPolicy {
+                    join_rule: JoinRule::Knock,
+                    invite_rule: InviteRule::AdminsOnly,
+                    send_rule: SendRule::AllMembers,
+                    commit_rule: CommitRule::AdminsOnly,
+                    admins: vec![UserKeyHash::new(vec![0x44; 32])],
+                })
+            })),
+        ),
+        (
+            "room_policy_change_set_commit_rule",
+            case::<RoomPolicyChange>(Some(|| {
+                serialize(RoomPolicyChange::SetCommitRule(CommitRule::AdminsOnly))
+            })),
+        ),
+        (
+            "member_profiles_page",
+            case::<MemberProfilesPage>(Some(|| {
+                serialize(MemberProfilesPage {
+                    members: vec![MemberProfile {
+                        clients: vec![LeafNodeIndex::new(1), LeafNodeIndex::new(3)],
+                        is_admin: true,
+                        last_activity_time: TimeStamp::from(1_700_000_000_000_000_000i64),
+                        last_activity_epoch: GroupEpoch::from(7),
+                    }],
+                    next_offset: Some(500),
+                })
+            })),
+        ),
+        ("freshness", case::<Freshness>(None)),
+    ])
+}
+
+fn load_vectors() -> VectorFile {
+    let file = std::fs::read_to_string(VECTOR_FILE).expect("Can't read wire format vectors");
+    serde_json::from_str(&file).expect("Invalid wire format vector file")
+}
+
+fn update_vectors(vector_file: &mut VectorFile, cases: &BTreeMap<&'static str, WireCase>) {
+    for (name, case) in cases {
+        let Some(sample) = case.sample else {
+            continue;
+        };
+        let encoding = hex::encode(sample());
+        match vector_file
+            .vectors
+            .iter_mut()
+            .find(|vector| vector.name == *name)
+        {
+            Some(vector) => {
+                vector.type_name = case.type_name.to_owned();
+                vector.encoding = encoding;
+            }
+            None => vector_file.vectors.push(TestVector {
+                name: name.to_string(),
+                type_name: case.type_name.to_owned(),
+                encoding,
+            }),
+        }
+    }
+    let file = serde_json::to_string_pretty(vector_file).unwrap();
+    std::fs::write(VECTOR_FILE, file + "\n").expect("Can't write wire format vectors");
+}
+
+#[test]
+fn wire_formats_match_vectors() {
+    let cases = wire_cases();
+    let mut vector_file = load_vectors();
+    if std::env::var_os(UPDATE_VECTORS_VAR).is_some() {
+        update_vectors(&mut vector_file, &cases);
+    }
+    assert_eq!(vector_file.format_version, FORMAT_VERSION);
+
+    let mut vector_names = vec![];
+    for vector in &vector_file.vectors {
+        let case = cases
+            .get(vector.name.as_str())
+            .unwrap_or_else(|| panic!("No wire case for vector {}", vector.name));
+        assert_eq!(
+            vector.type_name, case.type_name,
+            "Type of vector {} changed",
+            vector.name
+        );
+        let encoding = hex::decode(&vector.encoding).unwrap();
+
+        let reencoding = (case.round_trip)(&encoding)
+            .unwrap_or_else(|e| panic!("Can't decode vector {}: {e:?}", vector.name));
+        assert_eq!(
+            hex::encode(reencoding),
+            vector.encoding,
+            "Re-encoding of vector {} differs",
+            vector.name
+        );
+
+        if let Some(sample) = case.sample {
+            assert_eq!(
+                hex::encode(sample()),
+                vector.encoding,
+                "Encoding of {} changed",
+                vector.name
+            );
+        }
+        vector_names.push(vector.name.as_str());
+    }
+
+    let missing: Vec<_> = cases
+        .keys()
+        .filter(|name| !vector_names.contains(*name))
+        .collect();
+    assert!(missing.is_empty(), "Missing vectors: {missing:?}");
+}